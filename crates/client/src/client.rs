@@ -0,0 +1,274 @@
+//! JSON Lines client for the sandbox's STDIO API.
+//!
+//! [`StdioClient`] writes each request as a [`RequestEnvelope`] with a
+//! request ID of its own and hands the matching [`ResponseEnvelope`] back to
+//! the caller that sent it, so any number of requests can be awaited at
+//! once. Events arrive as [`EventEnvelope`]s on every subscription from
+//! [`StdioClient::subscribe_events`]. The client runs over a spawned sandbox
+//! binary or any pair of streams, such as a socket to `--socket-path`.
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use codeagent_stdio::protocol::{
+    AgentExecutePayload, SessionStartPayload, UndoHistoryPayload, UndoRollbackPayload,
+};
+use codeagent_stdio::{EventEnvelope, RequestEnvelope, ResponseEnvelope};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::error::ClientError;
+
+type Subscribers = Mutex<Vec<mpsc::UnboundedSender<EventEnvelope>>>;
+
+type Pending = HashMap<String, oneshot::Sender<ResponseEnvelope>>;
+
+/// State shared with the task reading the sandbox's output.
+struct Shared {
+    /// Requests awaiting their response, by request ID; `None` once the
+    /// output has closed.
+    pending: Mutex<Option<Pending>>,
+    subscribers: Subscribers,
+}
+
+/// The `type` of an output line, read before the rest of it.
+#[derive(Deserialize)]
+struct MessageType {
+    #[serde(rename = "type")]
+    message_type: String,
+}
+
+/// A connection to a sandbox speaking the STDIO API.
+pub struct StdioClient {
+    writer: tokio::sync::Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    shared: Arc<Shared>,
+    next_request: AtomicU64,
+    timeout: Option<Duration>,
+    child: Option<Child>,
+    reader: JoinHandle<()>,
+}
+
+impl StdioClient {
+    /// Run `command` (the sandbox binary and its arguments) with piped
+    /// stdin and stdout and connect to it. Its stderr, where the sandbox
+    /// logs, is left as `command` has it.
+    pub fn spawn(mut command: Command) -> Result<Self, ClientError> {
+        command.stdin(Stdio::piped()).stdout(Stdio::piped()).kill_on_drop(true);
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut client = Self::connect(stdout, stdin);
+        client.child = Some(child);
+        Ok(client)
+    }
+
+    /// Connect over `reader`, the sandbox's output, and `writer`, its
+    /// input. Must be called within a tokio runtime.
+    pub fn connect<R, W>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(Some(HashMap::new())),
+            subscribers: Mutex::default(),
+        });
+        let reader = tokio::spawn(read_output(reader, Arc::clone(&shared)));
+        Self {
+            writer: tokio::sync::Mutex::new(Box::new(writer)),
+            shared,
+            next_request: AtomicU64::new(1),
+            timeout: None,
+            child: None,
+            reader,
+        }
+    }
+
+    /// Fail requests not answered within `timeout` with
+    /// [`ClientError::Timeout`]. Without one, requests wait as long as the
+    /// connection lasts.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// A new stream of the sandbox's events, from now on. It ends when the
+    /// connection closes; dropping the receiver ends the subscription.
+    pub fn subscribe_events(&self) -> mpsc::UnboundedReceiver<EventEnvelope> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.shared.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Send a `message_type` request (e.g. `fs.read`) with `payload` and
+    /// return the payload of its response, or `null` if it has none.
+    pub async fn request(
+        &self,
+        message_type: &str,
+        payload: &impl Serialize,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.request_for(None, message_type, payload).await
+    }
+
+    /// [`request`](Self::request) for the session `session_id` of a sandbox
+    /// serving several.
+    pub async fn request_for(
+        &self,
+        session_id: Option<&str>,
+        message_type: &str,
+        payload: &impl Serialize,
+    ) -> Result<serde_json::Value, ClientError> {
+        let request_id = format!("req-{}", self.next_request.fetch_add(1, Ordering::Relaxed));
+        let envelope = RequestEnvelope {
+            message_type: message_type.to_string(),
+            request_id: request_id.clone(),
+            session_id: session_id.map(str::to_string),
+            payload: serde_json::to_value(payload)?,
+        };
+        let mut line = serde_json::to_vec(&envelope)?;
+        line.push(b'\n');
+
+        // Registered before the request is written, so an early response
+        // still finds it.
+        let (sender, receiver) = oneshot::channel();
+        self.shared
+            .pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok_or(ClientError::Closed)?
+            .insert(request_id.clone(), sender);
+        if let Err(e) = self.write_line(&line).await {
+            self.forget(&request_id);
+            return Err(e);
+        }
+
+        let response = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, receiver).await {
+                Ok(response) => response,
+                Err(_) => {
+                    self.forget(&request_id);
+                    return Err(ClientError::Timeout { request_id });
+                }
+            },
+            None => receiver.await,
+        };
+        let response = response.map_err(|_| ClientError::Closed)?;
+        match (response.status.as_str(), response.error) {
+            ("ok", _) => Ok(response.payload.unwrap_or(serde_json::Value::Null)),
+            (_, Some(detail)) => Err(ClientError::Response(detail)),
+            (status, None) => Err(ClientError::InvalidResponse {
+                request_id,
+                message: format!("status {status} without an error"),
+            }),
+        }
+    }
+
+    fn forget(&self, request_id: &str) {
+        if let Some(pending) = self.shared.pending.lock().unwrap().as_mut() {
+            pending.remove(request_id);
+        }
+    }
+
+    async fn write_line(&self, line: &[u8]) -> Result<(), ClientError> {
+        let mut writer = self.writer.lock().await;
+        writer.write_all(line).await.map_err(|_| ClientError::Closed)?;
+        writer.flush().await.map_err(|_| ClientError::Closed)
+    }
+
+    /// `session.start`.
+    pub async fn session_start(
+        &self,
+        payload: &SessionStartPayload,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.request("session.start", payload).await
+    }
+
+    /// `session.stop`.
+    pub async fn session_stop(&self) -> Result<serde_json::Value, ClientError> {
+        self.request("session.stop", &serde_json::json!({})).await
+    }
+
+    /// `agent.execute`.
+    pub async fn agent_execute(
+        &self,
+        payload: &AgentExecutePayload,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.request("agent.execute", payload).await
+    }
+
+    /// `undo.rollback`.
+    pub async fn undo_rollback(
+        &self,
+        payload: &UndoRollbackPayload,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.request("undo.rollback", payload).await
+    }
+
+    /// `undo.history`.
+    pub async fn undo_history(
+        &self,
+        payload: &UndoHistoryPayload,
+    ) -> Result<serde_json::Value, ClientError> {
+        self.request("undo.history", payload).await
+    }
+
+    /// Close the sandbox's input, which ends its session, and wait for a
+    /// spawned sandbox to exit.
+    pub async fn shutdown(mut self) -> Result<(), ClientError> {
+        self.writer.get_mut().shutdown().await?;
+        if let Some(child) = self.child.as_mut() {
+            child.wait().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StdioClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Route each line of `reader` to the request it answers or to the event
+/// subscribers. Once the output closes, waiting requests fail with
+/// [`ClientError::Closed`] and subscriptions end.
+async fn read_output(reader: impl AsyncRead + Unpin, shared: Arc<Shared>) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Ok(MessageType { message_type }) = serde_json::from_str(&line) else {
+            continue;
+        };
+        if message_type == "response" {
+            let Ok(response) = serde_json::from_str::<ResponseEnvelope>(&line) else {
+                continue;
+            };
+            let waiter = shared
+                .pending
+                .lock()
+                .unwrap()
+                .as_mut()
+                .and_then(|pending| pending.remove(&response.request_id));
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(response);
+            }
+        } else if message_type.starts_with("event.") {
+            let Ok(event) = serde_json::from_str::<EventEnvelope>(&line) else {
+                continue;
+            };
+            shared
+                .subscribers
+                .lock()
+                .unwrap()
+                .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+    shared.pending.lock().unwrap().take();
+    shared.subscribers.lock().unwrap().clear();
+}
@@ -0,0 +1,37 @@
+use codeagent_stdio::ErrorDetail;
+
+/// Errors returned by [`StdioClient`](crate::StdioClient) requests.
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// The sandbox answered the request with an error.
+    #[error("{}: {}", .0.code, .0.message)]
+    Response(ErrorDetail),
+
+    #[error("no response to request {request_id} in time")]
+    Timeout { request_id: String },
+
+    /// The sandbox stopped reading requests or closed its output before
+    /// answering.
+    #[error("the sandbox closed the connection")]
+    Closed,
+
+    #[error("invalid response to request {request_id}: {message}")]
+    InvalidResponse { request_id: String, message: String },
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl ClientError {
+    /// The error's `code` (e.g. `session_not_active`) when the sandbox
+    /// answered with one.
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            ClientError::Response(detail) => Some(&detail.code),
+            _ => None,
+        }
+    }
+}
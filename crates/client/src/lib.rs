@@ -0,0 +1,5 @@
+pub mod client;
+mod error;
+
+pub use client::StdioClient;
+pub use error::ClientError;
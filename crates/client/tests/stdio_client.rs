@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use codeagent_client::{ClientError, StdioClient};
+use codeagent_stdio::protocol::UndoHistoryPayload;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines};
+
+/// A client connected to the other end of in-memory pipes, standing in for
+/// the sandbox.
+fn connect() -> (StdioClient, Lines<BufReader<DuplexStream>>, DuplexStream) {
+    let (client_output, server_input) = tokio::io::duplex(64 * 1024);
+    let (server_output, client_input) = tokio::io::duplex(64 * 1024);
+    let client = StdioClient::connect(client_input, client_output);
+    (client, BufReader::new(server_input).lines(), server_output)
+}
+
+async fn next_request(requests: &mut Lines<BufReader<DuplexStream>>) -> Value {
+    serde_json::from_str(&requests.next_line().await.unwrap().unwrap()).unwrap()
+}
+
+async fn write_line(output: &mut DuplexStream, line: Value) {
+    output.write_all(format!("{line}\n").as_bytes()).await.unwrap();
+}
+
+// -----------------------------------------------------------------------
+// CL-01: responses reach the request they answer, events every subscriber
+// -----------------------------------------------------------------------
+#[tokio::test]
+async fn cl_01_responses_are_matched_and_events_streamed() {
+    let (client, mut requests, mut output) = connect();
+    let mut first_events = client.subscribe_events();
+    let mut second_events = client.subscribe_events();
+
+    let server = async {
+        let history = next_request(&mut requests).await;
+        assert_eq!(history["type"], "undo.history");
+        assert!(history.get("session_id").is_none());
+        let stop = next_request(&mut requests).await;
+        assert_eq!(stop["type"], "session.stop");
+
+        write_line(&mut output, json!({
+            "type": "event.warning", "seq": 1, "payload": { "code": "vm_not_configured" },
+        }))
+        .await;
+        // Answered in the opposite order.
+        write_line(&mut output, json!({
+            "type": "response", "request_id": stop["request_id"], "status": "ok",
+        }))
+        .await;
+        write_line(&mut output, json!({
+            "type": "response", "request_id": history["request_id"], "status": "ok",
+            "payload": { "steps": [] },
+        }))
+        .await;
+    };
+    let payload = UndoHistoryPayload::default();
+    let (history, stop, ()) = tokio::join!(
+        client.undo_history(&payload),
+        client.session_stop(),
+        server,
+    );
+    assert_eq!(history.unwrap(), json!({ "steps": [] }));
+    assert_eq!(stop.unwrap(), Value::Null);
+
+    for events in [&mut first_events, &mut second_events] {
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.event_type, "event.warning");
+        assert_eq!(event.seq, Some(1));
+        assert_eq!(event.payload["code"], "vm_not_configured");
+    }
+}
+
+// -----------------------------------------------------------------------
+// CL-02: error responses, timeouts and a closed connection
+// -----------------------------------------------------------------------
+#[tokio::test]
+async fn cl_02_errors_timeouts_and_closing() {
+    let (client, mut requests, mut output) = connect();
+    let client = client.with_timeout(Duration::from_millis(200));
+    let mut events = client.subscribe_events();
+
+    let server = async {
+        let request = next_request(&mut requests).await;
+        assert_eq!(request["session_id"], "s1");
+        write_line(&mut output, json!({
+            "type": "response", "request_id": request["request_id"], "status": "error",
+            "error": { "code": "session_not_active", "message": "no session", "retryable": false },
+        }))
+        .await;
+    };
+    let empty = json!({});
+    let (result, ()) =
+        tokio::join!(client.request_for(Some("s1"), "session.status", &empty), server);
+    let error = result.unwrap_err();
+    assert_eq!(error.code(), Some("session_not_active"));
+
+    // Read but never answered.
+    let unanswered = client.request("session.status", &json!({})).await;
+    assert!(matches!(unanswered, Err(ClientError::Timeout { .. })), "{unanswered:?}");
+    next_request(&mut requests).await;
+
+    drop(output);
+    assert!(events.recv().await.is_none());
+    let closed = client.request("session.status", &json!({})).await;
+    assert!(matches!(closed, Err(ClientError::Closed)), "{closed:?}");
+}
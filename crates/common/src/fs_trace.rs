@@ -0,0 +1,166 @@
+//! Per-operation trace of the filesystem requests a share serves.
+//!
+//! A backend with a [`FsTrace`] records one [`TraceRecord`] per request
+//! while the trace is enabled: the operation, the path it resolved to, its
+//! size, how long it took and the step it was attributed to. The trace keeps
+//! the newest records only, so it can stay on for a whole session; `fs.trace`
+//! turns it on and off and reads it back.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+use crate::StepId;
+
+/// Records a trace keeps by default before dropping the oldest.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// One operation served.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TraceRecord {
+    /// Position in the trace, from 1. Never reused, so a reader can ask
+    /// for what came after the last record it saw.
+    pub seq: u64,
+    /// FUSE operation name (`lookup`, `write`, `rename`, ...).
+    pub op: &'static str,
+    /// Forward-slash path relative to the share root, if it resolved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Bytes read or written, or the length set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    pub latency_us: u64,
+    /// Guest process that issued the request.
+    pub pid: u32,
+    /// Step the operation was recorded in, if any was open.
+    pub step_id: Option<StepId>,
+}
+
+/// A bounded ring of [`TraceRecord`]s, disabled until turned on.
+pub struct FsTrace {
+    enabled: AtomicBool,
+    capacity: usize,
+    records: Mutex<Ring>,
+}
+
+struct Ring {
+    entries: VecDeque<TraceRecord>,
+    next_seq: u64,
+}
+
+impl Default for FsTrace {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl FsTrace {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            capacity: capacity.max(1),
+            records: Mutex::new(Ring {
+                entries: VecDeque::new(),
+                next_seq: 1,
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Append `record`, numbering it and dropping the oldest record if the
+    /// trace is full. Ignored while the trace is disabled.
+    pub fn record(&self, mut record: TraceRecord) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut ring = self.records.lock().unwrap();
+        record.seq = ring.next_seq;
+        ring.next_seq += 1;
+        if ring.entries.len() == self.capacity {
+            ring.entries.pop_front();
+        }
+        ring.entries.push_back(record);
+    }
+
+    /// Up to `limit` records with a `seq` above `after`, oldest first.
+    pub fn records_after(&self, after: u64, limit: usize) -> Vec<TraceRecord> {
+        let ring = self.records.lock().unwrap();
+        ring.entries
+            .iter()
+            .filter(|record| record.seq > after)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Records no longer in the trace, dropped for space or cleared.
+    pub fn dropped(&self) -> u64 {
+        let ring = self.records.lock().unwrap();
+        ring.next_seq - 1 - ring.entries.len() as u64
+    }
+
+    /// Forget every record. Numbering carries on where it was.
+    pub fn clear(&self) {
+        self.records.lock().unwrap().entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(op: &'static str) -> TraceRecord {
+        TraceRecord {
+            seq: 0,
+            op,
+            path: Some("src/lib.rs".to_string()),
+            size: None,
+            latency_us: 5,
+            pid: 42,
+            step_id: Some(1),
+        }
+    }
+
+    #[test]
+    fn records_are_kept_only_while_enabled() {
+        let trace = FsTrace::default();
+        trace.record(record("lookup"));
+        assert!(trace.records_after(0, 10).is_empty());
+
+        trace.set_enabled(true);
+        trace.record(record("lookup"));
+        trace.record(record("write"));
+        let records = trace.records_after(0, 10);
+        assert_eq!(records.iter().map(|r| (r.seq, r.op)).collect::<Vec<_>>(), [
+            (1, "lookup"),
+            (2, "write"),
+        ]);
+        assert_eq!(trace.records_after(1, 10).len(), 1);
+        assert_eq!(trace.records_after(0, 1).len(), 1);
+    }
+
+    #[test]
+    fn a_full_trace_drops_its_oldest_records() {
+        let trace = FsTrace::with_capacity(2);
+        trace.set_enabled(true);
+        for op in ["lookup", "open", "write"] {
+            trace.record(record(op));
+        }
+        let records = trace.records_after(0, 10);
+        assert_eq!(records.iter().map(|r| r.seq).collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(trace.dropped(), 1);
+
+        trace.clear();
+        trace.record(record("flush"));
+        assert_eq!(trace.records_after(0, 10)[0].seq, 4);
+    }
+}
@@ -3,6 +3,11 @@ use std::path::PathBuf;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+pub mod fs_trace;
+pub mod metrics;
+pub mod schema;
+pub mod time;
+
 /// Identifies an undo step. Positive IDs are command steps; negative IDs are ambient steps.
 pub type StepId = i64;
 
@@ -12,10 +17,58 @@ pub type StepId = i64;
 /// in response to protocol events.
 pub trait StepManager: Send + Sync {
     fn open_step(&self, id: StepId) -> Result<()>;
+    /// Open a command step that may run alongside other command steps.
+    /// Managers that keep a single step slot open it like any other step.
+    fn open_concurrent_step(&self, id: StepId) -> Result<()> {
+        self.open_step(id)
+    }
     fn close_step(&self, id: StepId) -> Result<Vec<StepId>>;
     fn current_step(&self) -> Option<StepId>;
     /// Store the command string associated with the current step in the manifest.
     fn set_step_command(&self, _id: StepId, _command: String) {}
+    /// Store the exit code of the command that ran in the current step.
+    fn set_step_exit_code(&self, _id: StepId, _exit_code: i32) {}
+}
+
+/// Maps the guest process behind a filesystem operation to the command step
+/// it belongs to, when several command steps are open at once.
+pub trait StepAttributor: Send + Sync {
+    /// The step of the command that guest process `pid` belongs to. `None`
+    /// when it cannot be told, leaving the choice to the undo interceptor.
+    fn step_for_pid(&self, pid: u32) -> Option<StepId>;
+
+    /// The name of guest process `pid` (its `comm`), if it can be told.
+    fn process_name(&self, _pid: u32) -> Option<String> {
+        None
+    }
+}
+
+/// Follows a long-running operation, such as a rollback of many steps, and
+/// may cancel it.
+///
+/// Operations check for cancellation between units of work (a step), so a
+/// cancelled operation never leaves one half done.
+pub trait OperationMonitor: Send + Sync {
+    fn is_cancelled(&self) -> bool;
+    /// The operation is `percent` (0–100) done, now at `current_path`
+    /// (relative to its working directory) if it works on one.
+    fn progress(&self, percent: u8, current_path: Option<&str>);
+}
+
+/// A monitor that never cancels and ignores progress.
+pub struct Unmonitored;
+
+impl OperationMonitor for Unmonitored {
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+
+    fn progress(&self, _percent: u8, _current_path: Option<&str>) {}
+}
+
+/// `done` of `total` as a percentage, 100 when there is nothing to do.
+pub fn percent_of(done: usize, total: usize) -> u8 {
+    (done.min(total) * 100).checked_div(total).map_or(100, |percent| percent as u8)
 }
 
 /// Identifies an undo barrier. Monotonically increasing within a session.
@@ -36,9 +89,22 @@ pub enum StepType {
 pub struct StepInfo {
     pub id: StepId,
     pub step_type: StepType,
+    #[serde(with = "time::rfc3339")]
     pub timestamp: DateTime<Utc>,
     pub command: Option<String>,
     pub affected_paths: Vec<PathBuf>,
+    /// Wall-clock time from opening to closing the step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Exit code of the step's command, if it ran to completion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// Number of paths touched (created, modified or deleted) by the step.
+    #[serde(default)]
+    pub file_count: usize,
+    /// Compressed size of the preimage data stored for the step.
+    #[serde(default)]
+    pub preimage_bytes: u64,
 }
 
 /// Policy for handling external modifications to the working directory.
@@ -50,6 +116,34 @@ pub enum ExternalModificationPolicy {
     Barrier,
     /// Emit a warning but do not create a barrier.
     Warn,
+    /// Neither create a barrier nor report the modification.
+    Ignore,
+}
+
+/// Maps a glob pattern (matched against the forward-slash path relative to
+/// the working root) to an external modification policy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalModificationRule {
+    pub pattern: String,
+    pub policy: ExternalModificationPolicy,
+}
+
+/// Per-path handling of external modifications. Each affected path gets the
+/// policy of the first matching rule, or `default_policy` if none match.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExternalModificationConfig {
+    pub default_policy: ExternalModificationPolicy,
+    pub rules: Vec<ExternalModificationRule>,
+}
+
+impl From<ExternalModificationPolicy> for ExternalModificationConfig {
+    fn from(default_policy: ExternalModificationPolicy) -> Self {
+        Self {
+            default_policy,
+            rules: Vec::new(),
+        }
+    }
 }
 
 /// Controls how the undo interceptor handles symlinks.
@@ -73,6 +167,95 @@ pub enum SymlinkPolicy {
     ReadWrite,
 }
 
+/// Whether paths under a working root name files case-sensitively.
+///
+/// On a case-insensitive filesystem (the default on macOS and Windows)
+/// `Foo.txt` and `foo.txt` are the same file, so the undo interceptor and
+/// the inode map compare paths case-folded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaseSensitivity {
+    /// Probe the filesystem of each working root.
+    #[default]
+    Auto,
+    Sensitive,
+    Insensitive,
+}
+
+/// How the undo interceptor obtains a consistent preimage of a file that may
+/// be mid-transaction (e.g. SQLite or LevelDB databases).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoherentCaptureStrategy {
+    /// Hold a shared advisory lock (POSIX `fcntl` read lock on the whole file)
+    /// while reading, so a writer holding a conflicting lock cannot be
+    /// observed mid-transaction.
+    AdvisoryLock,
+    /// Re-read the file until its size and mtime are unchanged across the read.
+    RetryUntilQuiescent,
+}
+
+/// Maps a glob pattern (matched against the forward-slash relative path) to a
+/// coherent capture strategy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoherentCaptureRule {
+    pub pattern: String,
+    pub strategy: CoherentCaptureStrategy,
+}
+
+/// Default number of capture attempts before giving up on a coherent capture.
+pub const DEFAULT_COHERENT_CAPTURE_ATTEMPTS: u32 = 5;
+
+/// Default delay between coherent capture attempts, in milliseconds.
+pub const DEFAULT_COHERENT_CAPTURE_RETRY_INTERVAL_MS: u64 = 50;
+
+/// Configuration for coherent preimage capture. Paths that match no rule are
+/// captured with a plain read. The first matching rule wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CoherentCaptureConfig {
+    pub rules: Vec<CoherentCaptureRule>,
+    /// Attempts to acquire the lock or observe a quiescent file before the
+    /// capture proceeds without a coherence guarantee.
+    pub max_attempts: u32,
+    /// Delay between attempts, in milliseconds.
+    pub retry_interval_ms: u64,
+}
+
+impl Default for CoherentCaptureConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            max_attempts: DEFAULT_COHERENT_CAPTURE_ATTEMPTS,
+            retry_interval_ms: DEFAULT_COHERENT_CAPTURE_RETRY_INTERVAL_MS,
+        }
+    }
+}
+
+/// Default ref the git mirror commits closed steps to.
+pub const DEFAULT_GIT_MIRROR_REF: &str = "refs/codeagent/history";
+
+/// Mirroring of closed steps as commits on a git ref of the working
+/// directory's repository. Needs the interceptor's `git-mirror` feature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GitMirrorConfig {
+    pub enabled: bool,
+    /// Ref the commits are made on. Branches, the index and HEAD are left
+    /// alone.
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+}
+
+impl Default for GitMirrorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ref_name: DEFAULT_GIT_MIRROR_REF.to_string(),
+        }
+    }
+}
+
 /// Why a barrier was created.
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -82,6 +265,11 @@ pub enum BarrierReason {
     /// An external modification was detected during the session.
     #[default]
     ExternalModification,
+    /// The guest shim crashed and was restarted. Commands it was running
+    /// may have written files the host never saw the end of.
+    ShimRestarted,
+    /// The VM exited during the session, taking its running commands with it.
+    VmCrashed,
 }
 
 /// What kind of filesystem change was detected.
@@ -128,6 +316,7 @@ pub struct BarrierInfo {
     /// The most recently completed step when this barrier was created.
     /// Rolling back this step would cross the barrier.
     pub after_step_id: StepId,
+    #[serde(with = "time::rfc3339")]
     pub timestamp: DateTime<Utc>,
     pub affected_paths: Vec<AffectedPath>,
     /// Why this barrier was created.
@@ -138,10 +327,66 @@ pub struct BarrierInfo {
 /// Result of a successful rollback operation.
 #[derive(Debug, Clone)]
 pub struct RollbackResult {
-    /// Number of steps that were rolled back.
+    /// Number of steps the caller asked to roll back.
+    pub steps_requested: usize,
+    /// Number of steps that were rolled back. Smaller than `steps_requested`
+    /// when the history was shorter (non-strict rollback only).
     pub steps_rolled_back: usize,
+    /// IDs of the rolled-back steps, most recent first.
+    pub rolled_back_step_ids: Vec<StepId>,
     /// Barriers that were crossed (only non-empty when `force: true` was used).
     pub barriers_crossed: Vec<BarrierInfo>,
+    /// Files merged instead of restored (only non-empty in
+    /// [`RollbackMode::Merge`]).
+    pub merged: Vec<MergedPath>,
+    /// The rollback was cancelled before all of its steps were rolled back.
+    pub cancelled: bool,
+}
+
+/// Result of squashing consecutive steps into one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SquashResult {
+    /// The merged step, which keeps the id of the oldest squashed step.
+    pub step_id: StepId,
+    /// IDs of the steps merged into it, oldest first.
+    pub squashed_step_ids: Vec<StepId>,
+    /// Captures dropped because an older step captured the same path.
+    pub entries_dropped: usize,
+    /// Undo log bytes freed by the squash.
+    pub bytes_reclaimed: u64,
+}
+
+/// How rollback treats files that changed after the rolled-back step closed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RollbackMode {
+    /// Restore every file to its preimage, discarding later edits.
+    #[default]
+    Restore,
+    /// Take the step's change out of text files edited since the step closed
+    /// with a three-way merge, leaving conflict markers where the step and
+    /// the later edits touched the same lines.
+    Merge,
+}
+
+/// A file that a merge-mode rollback merged rather than restored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergedPath {
+    /// Path relative to the working directory.
+    pub path: PathBuf,
+    /// Number of conflict regions left in the file (0 = merged cleanly).
+    pub conflicts: usize,
+}
+
+/// How `fs.read` and the `read_file` tool return a file's bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadEncoding {
+    /// As text. Fails on bytes that are not UTF-8.
+    #[default]
+    Utf8,
+    /// Standard base64 with padding, for binary files.
+    Base64,
 }
 
 /// The kind of safeguard that was triggered.
@@ -160,11 +405,99 @@ pub enum SafeguardKind {
         source: String,
         destination: String,
     },
+    /// The number of distinct existing files overwritten in a step reached the
+    /// configured threshold.
+    OverwriteCountThreshold { count: u64, threshold: u64 },
+    /// A step is changing a path that matches a protected pattern.
+    ProtectedPath {
+        path: String,
+        pattern: String,
+        operation: PathOperation,
+    },
+    /// A step has been open longer than `max_step_duration_seconds`.
+    /// Denying it cancels the step's command.
+    StepDuration {
+        elapsed_seconds: u64,
+        limit_seconds: u64,
+    },
+    /// A step made more than `max_step_operations` filesystem operations.
+    /// Denying it cancels the step's command.
+    StepOperationCount { count: u64, threshold: u64 },
+    /// A step started an operation announced with `undo.expect`. Asked once,
+    /// before the first change the operation makes.
+    ExpectedOperation {
+        op: ExpectedOperation,
+        path_count: u64,
+        estimated_bytes: u64,
+    },
+}
+
+impl SafeguardKind {
+    /// Whether the kind bounds the step as a whole rather than one
+    /// operation, so denying it should stop the command driving the step.
+    pub fn limits_step(&self) -> bool {
+        matches!(
+            self,
+            SafeguardKind::StepDuration { .. } | SafeguardKind::StepOperationCount { .. }
+        )
+    }
+}
+
+/// Mutating operation reported by [`SafeguardKind::ProtectedPath`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathOperation {
+    Write,
+    Delete,
+    Rename,
+}
+
+/// What an [`Expectation`] announces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpectedOperation {
+    /// Deleting the paths.
+    Delete,
+    /// Overwriting existing files under the paths.
+    Rewrite,
+}
+
+/// A heavy operation announced before the step that performs it.
+///
+/// It applies to the next step to open. When the step first deletes or
+/// overwrites something under `paths`, the safeguard handler is asked once
+/// about the whole operation. If allowed, the step's matching count
+/// thresholds are waived and its byte budgets and
+/// `max_single_step_size_bytes` are raised by `estimated_bytes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expectation {
+    /// Forward-slash paths relative to the working directory; everything
+    /// below a directory is covered. `.` covers the whole tree.
+    pub paths: Vec<String>,
+    pub op: ExpectedOperation,
+    pub estimated_bytes: u64,
+}
+
+/// A per-step byte budget from [`SafeguardConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepBudget {
+    /// `max_deleted_bytes_per_step`.
+    DeletedBytes,
+    /// `max_overwritten_bytes_per_step`.
+    OverwrittenBytes,
+}
+
+impl std::fmt::Display for StepBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StepBudget::DeletedBytes => f.write_str("deleted bytes"),
+            StepBudget::OverwrittenBytes => f.write_str("overwritten bytes"),
+        }
+    }
 }
 
 /// Configuration for undo log resource limits. Each limit is optional — `None` means
 /// no limit is enforced for that dimension.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ResourceLimitsConfig {
     /// Maximum total size of the undo log in bytes. When exceeded, oldest steps
     /// are evicted (FIFO) until the log fits within budget.
@@ -188,6 +521,25 @@ pub struct SafeguardConfig {
     pub overwrite_file_size_threshold: Option<u64>,
     /// Trigger when a rename would overwrite an existing destination file.
     pub rename_over_existing: bool,
+    /// Maximum number of distinct existing files overwritten in a single step
+    /// before triggering. Renames onto an existing file count as overwrites.
+    pub overwrite_count_threshold: Option<u64>,
+    /// Glob patterns, relative to the working directory (e.g. `.git/**`,
+    /// `*.env`). Writing, deleting or renaming a matching path triggers.
+    pub protected_paths: Vec<String>,
+    /// Trigger when a step is still open this many seconds after it opened,
+    /// busy or not. Allowing once grants another period of the same size.
+    pub max_step_duration_seconds: Option<u64>,
+    /// Maximum number of filesystem operations in a single step before
+    /// triggering. Allowing once grants another batch of the same size.
+    pub max_step_operations: Option<u64>,
+    /// Maximum total size of the files deleted in a single step. Going over
+    /// rolls the step back without asking the handler.
+    pub max_deleted_bytes_per_step: Option<u64>,
+    /// Maximum total size of the existing files overwritten in a single
+    /// step, each counted once at its size before the step. Going over rolls
+    /// the step back without asking the handler.
+    pub max_overwritten_bytes_per_step: Option<u64>,
 }
 
 /// Information about a triggered safeguard, sent to the handler for a decision.
@@ -203,10 +555,317 @@ pub struct SafeguardEvent {
 /// The user's decision in response to a safeguard trigger.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SafeguardDecision {
-    Allow,
+    /// Allow this operation only; the next trigger of the same kind asks again.
+    AllowOnce,
+    /// Allow, and stop asking about this safeguard kind until the step ends.
+    AllowForStep,
+    /// Allow, and stop asking about this safeguard kind for the rest of the session.
+    AllowForSession,
     Deny,
 }
 
+/// How much attention a warning needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningSeverity {
+    Info,
+    Warning,
+    /// Undo protection is lost for part of the session.
+    Critical,
+}
+
+/// Catalog of warnings the sandbox reports. Serializes with a stable `code`
+/// tag next to the variant's fields.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum SandboxWarning {
+    /// No kernel/initrd configured; commands run on the host, outside undo
+    /// interception.
+    VmNotConfigured { missing: Vec<String> },
+    /// The VM failed to start; commands run on the host, outside undo
+    /// interception.
+    VmLaunchFailed { reason: String },
+    /// The filesystem watcher could not start or watch a directory, so host
+    /// edits there go undetected.
+    FileWatcherFailed {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        reason: String,
+    },
+    /// The OS dropped watch events; a path-less barrier stands in for them.
+    FileWatcherOverflow,
+    /// The undo log on disk has an unsupported version, so undo is off for
+    /// that working directory.
+    UndoDisabled {
+        working_dir: String,
+        expected_version: String,
+        found_version: String,
+    },
+    /// The guest shim exited abnormally and its supervisor started a new
+    /// one. Commands it was running were lost, and each working directory
+    /// got a barrier.
+    ShimRestarted {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exit_code: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signal: Option<i32>,
+        /// The last lines the shim wrote to stderr, oldest first.
+        stderr: Vec<String>,
+        /// Restarts since the VM booted, this one included.
+        restarts: u32,
+    },
+    /// A working directory shared with a VM booted ahead of its session
+    /// could not be mounted in the guest; commands cannot reach it.
+    ShareNotMounted { mount_name: String, reason: String },
+    /// A persistent session's VM state could not be saved at
+    /// `session.stop`; the next `session.start` boots the VM afresh.
+    VmStateSaveFailed { reason: String },
+    /// The saved VM state could not be resumed, so it was discarded and the
+    /// VM booted afresh.
+    VmStateRestoreFailed { reason: String },
+}
+
+impl SandboxWarning {
+    /// Stable wire code, matching the serialized `code` tag.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SandboxWarning::VmNotConfigured { .. } => "vm_not_configured",
+            SandboxWarning::VmLaunchFailed { .. } => "vm_launch_failed",
+            SandboxWarning::FileWatcherFailed { .. } => "file_watcher_failed",
+            SandboxWarning::FileWatcherOverflow => "file_watcher_overflow",
+            SandboxWarning::UndoDisabled { .. } => "undo_disabled",
+            SandboxWarning::ShimRestarted { .. } => "shim_restarted",
+            SandboxWarning::ShareNotMounted { .. } => "share_not_mounted",
+            SandboxWarning::VmStateSaveFailed { .. } => "vm_state_save_failed",
+            SandboxWarning::VmStateRestoreFailed { .. } => "vm_state_restore_failed",
+        }
+    }
+
+    pub fn severity(&self) -> WarningSeverity {
+        match self {
+            SandboxWarning::VmNotConfigured { .. } => WarningSeverity::Info,
+            SandboxWarning::VmLaunchFailed { .. }
+            | SandboxWarning::FileWatcherFailed { .. }
+            | SandboxWarning::FileWatcherOverflow
+            | SandboxWarning::ShimRestarted { .. }
+            | SandboxWarning::ShareNotMounted { .. }
+            | SandboxWarning::VmStateSaveFailed { .. }
+            | SandboxWarning::VmStateRestoreFailed { .. } => WarningSeverity::Warning,
+            SandboxWarning::UndoDisabled { .. } => WarningSeverity::Critical,
+        }
+    }
+
+    /// Whether the condition lasts for the rest of the session (listed by
+    /// `session.warnings`) rather than describing a one-off occurrence.
+    pub fn is_persistent(&self) -> bool {
+        !matches!(
+            self,
+            SandboxWarning::FileWatcherOverflow
+                | SandboxWarning::ShimRestarted { .. }
+                | SandboxWarning::VmStateSaveFailed { .. }
+                | SandboxWarning::VmStateRestoreFailed { .. }
+        )
+    }
+
+    /// Human-readable description.
+    pub fn message(&self) -> String {
+        match self {
+            SandboxWarning::VmNotConfigured { missing } => format!(
+                "VM not configured (missing: {}), running in host-only mode. \
+                 Pass --kernel-path and --initrd-path to enable VM mode.",
+                missing.join(", ")
+            ),
+            SandboxWarning::VmLaunchFailed { reason } => {
+                format!("VM launch failed, falling back to host-only mode: {reason}")
+            }
+            SandboxWarning::FileWatcherFailed { path: Some(path), reason } => {
+                format!("Failed to watch directory {path}: {reason}")
+            }
+            SandboxWarning::FileWatcherFailed { path: None, reason } => {
+                format!("Filesystem watcher failed to initialize: {reason}")
+            }
+            SandboxWarning::FileWatcherOverflow => {
+                "Filesystem watcher dropped events; external changes may have been missed"
+                    .to_string()
+            }
+            SandboxWarning::UndoDisabled {
+                working_dir,
+                expected_version,
+                found_version,
+            } => format!(
+                "Undo disabled for {working_dir}: undo log version {found_version} \
+                 is not supported (expected {expected_version})"
+            ),
+            SandboxWarning::ShimRestarted {
+                exit_code,
+                signal,
+                stderr,
+                ..
+            } => {
+                let status = match (exit_code, signal) {
+                    (_, Some(signal)) => format!("was killed by signal {signal}"),
+                    (Some(code), None) => format!("exited with status {code}"),
+                    (None, None) => "exited".to_string(),
+                };
+                let mut message = format!(
+                    "Guest shim {status} and was restarted; running commands were lost \
+                     and an undo barrier was placed"
+                );
+                if let Some(last) = stderr.last() {
+                    message.push_str(&format!(" (last stderr: {last})"));
+                }
+                message
+            }
+            SandboxWarning::ShareNotMounted { mount_name, reason } => {
+                format!("Working directory {mount_name} is not mounted in the VM: {reason}")
+            }
+            SandboxWarning::VmStateSaveFailed { reason } => format!(
+                "VM state was not saved; the next session will boot the VM afresh: {reason}"
+            ),
+            SandboxWarning::VmStateRestoreFailed { reason } => {
+                format!("Saved VM state could not be resumed; booted the VM afresh: {reason}")
+            }
+        }
+    }
+}
+
+/// Stable, machine-readable error codes. The STDIO API reports them as
+/// `error.code` and the MCP server as `error.data.code`, both with
+/// `retryable`, so clients can tell errors apart without parsing messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // Requests the server could not accept.
+    MalformedJson,
+    InvalidRequest,
+    UnknownOperation,
+    MissingField,
+    InvalidField,
+    MissingRequestId,
+    OversizedMessage,
+    UnsupportedProtocolVersion,
+    PathOutsideRoot,
+    CapabilityUnavailable,
+    NotImplemented,
+
+    // Session lifecycle.
+    SessionNotActive,
+    SessionAlreadyActive,
+    SessionLimitReached,
+    NoSessionToResume,
+    InvalidWorkingDir,
+    WorkingDirInUse,
+    UndoDirectoryOverlap,
+    InvalidCloneTarget,
+    UnsupportedBackend,
+
+    // The VM and the services around it.
+    QemuUnavailable,
+    QemuSpawnFailed,
+    ControlChannelFailed,
+    VmState,
+    VirtiofsFailed,
+    FileWatcherFailed,
+    AgentBackendFailed,
+
+    // Undo.
+    UndoDisabled,
+    NoActiveStep,
+    StepNotActive,
+    StepAlreadyActive,
+    StepWaitTimeout,
+    StepUnprotected,
+    StepBudgetExceeded,
+    SafeguardDenied,
+    RollbackBlocked,
+    RollbackFailed,
+    InsufficientHistory,
+    InvalidStepRange,
+    /// The undo log could not be read: a manifest, preimage or WAL is
+    /// missing or corrupt.
+    UndoLogCorrupt,
+
+    IoError,
+    Internal,
+}
+
+impl ErrorCode {
+    /// The code as serialized.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::MalformedJson => "malformed_json",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::UnknownOperation => "unknown_operation",
+            ErrorCode::MissingField => "missing_field",
+            ErrorCode::InvalidField => "invalid_field",
+            ErrorCode::MissingRequestId => "missing_request_id",
+            ErrorCode::OversizedMessage => "oversized_message",
+            ErrorCode::UnsupportedProtocolVersion => "unsupported_protocol_version",
+            ErrorCode::PathOutsideRoot => "path_outside_root",
+            ErrorCode::CapabilityUnavailable => "capability_unavailable",
+            ErrorCode::NotImplemented => "not_implemented",
+            ErrorCode::SessionNotActive => "session_not_active",
+            ErrorCode::SessionAlreadyActive => "session_already_active",
+            ErrorCode::SessionLimitReached => "session_limit_reached",
+            ErrorCode::NoSessionToResume => "no_session_to_resume",
+            ErrorCode::InvalidWorkingDir => "invalid_working_dir",
+            ErrorCode::WorkingDirInUse => "working_dir_in_use",
+            ErrorCode::UndoDirectoryOverlap => "undo_directory_overlap",
+            ErrorCode::InvalidCloneTarget => "invalid_clone_target",
+            ErrorCode::UnsupportedBackend => "unsupported_backend",
+            ErrorCode::QemuUnavailable => "qemu_unavailable",
+            ErrorCode::QemuSpawnFailed => "qemu_spawn_failed",
+            ErrorCode::ControlChannelFailed => "control_channel_failed",
+            ErrorCode::VmState => "vm_state",
+            ErrorCode::VirtiofsFailed => "virtiofs_failed",
+            ErrorCode::FileWatcherFailed => "file_watcher_failed",
+            ErrorCode::AgentBackendFailed => "agent_backend_failed",
+            ErrorCode::UndoDisabled => "undo_disabled",
+            ErrorCode::NoActiveStep => "no_active_step",
+            ErrorCode::StepNotActive => "step_not_active",
+            ErrorCode::StepAlreadyActive => "step_already_active",
+            ErrorCode::StepWaitTimeout => "step_wait_timeout",
+            ErrorCode::StepUnprotected => "step_unprotected",
+            ErrorCode::StepBudgetExceeded => "step_budget_exceeded",
+            ErrorCode::SafeguardDenied => "safeguard_denied",
+            ErrorCode::RollbackBlocked => "rollback_blocked",
+            ErrorCode::RollbackFailed => "rollback_failed",
+            ErrorCode::InsufficientHistory => "insufficient_history",
+            ErrorCode::InvalidStepRange => "invalid_step_range",
+            ErrorCode::UndoLogCorrupt => "undo_log_corrupt",
+            ErrorCode::IoError => "io_error",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    /// Whether the same request may succeed if sent again unchanged: the
+    /// error comes from a transient state (a step still closing, a VM still
+    /// starting, a directory or session slot held by another session)
+    /// rather than from the request or the host. Everything else is
+    /// permanent until the client changes something.
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::SessionLimitReached
+                | ErrorCode::WorkingDirInUse
+                | ErrorCode::QemuSpawnFailed
+                | ErrorCode::ControlChannelFailed
+                | ErrorCode::VmState
+                | ErrorCode::VirtiofsFailed
+                | ErrorCode::AgentBackendFailed
+                | ErrorCode::StepAlreadyActive
+                | ErrorCode::StepWaitTimeout
+                | ErrorCode::IoError
+        )
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum CodeAgentError {
     #[error("I/O error: {source}")]
@@ -224,6 +883,9 @@ pub enum CodeAgentError {
     #[error("step {step_id} already active")]
     StepAlreadyActive { step_id: StepId },
 
+    #[error("timed out after {waited_ms}ms waiting for step {step_id} to close")]
+    StepWaitTimeout { step_id: StepId, waited_ms: u64 },
+
     #[error("manifest error: {message}")]
     Manifest { message: String },
 
@@ -257,14 +919,57 @@ pub enum CodeAgentError {
         step_id: StepId,
     },
 
+    #[error("step {step_id} rolled back: {used} {budget} exceeds the per-step budget of {limit}")]
+    StepBudgetExceeded {
+        step_id: StepId,
+        budget: StepBudget,
+        used: u64,
+        limit: u64,
+    },
+
     #[error("step {step_id} is unprotected (preimage capture exceeded size limit)")]
     StepUnprotected { step_id: StepId },
 
+    #[error("insufficient history: requested {requested} step(s), {available} available")]
+    InsufficientHistory { requested: usize, available: usize },
+
+    #[error("steps {from}..{to} are not a range of the undo history")]
+    InvalidStepRange { from: StepId, to: StepId },
+
     #[error("undo disabled: version mismatch (expected {expected_version}, found {found_version})")]
     UndoDisabled {
         expected_version: String,
         found_version: String,
     },
+
+    #[error("git mirror unavailable: {message}")]
+    GitMirror { message: String },
+}
+
+impl CodeAgentError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            CodeAgentError::Io { .. } => ErrorCode::IoError,
+            CodeAgentError::StepNotActive { .. } => ErrorCode::StepNotActive,
+            CodeAgentError::NoActiveStep => ErrorCode::NoActiveStep,
+            CodeAgentError::StepAlreadyActive { .. } => ErrorCode::StepAlreadyActive,
+            CodeAgentError::StepWaitTimeout { .. } => ErrorCode::StepWaitTimeout,
+            CodeAgentError::Rollback { .. } => ErrorCode::RollbackFailed,
+            CodeAgentError::Manifest { .. }
+            | CodeAgentError::Preimage { .. }
+            | CodeAgentError::Serialization { .. }
+            | CodeAgentError::Decompression { .. }
+            | CodeAgentError::Recovery { .. } => ErrorCode::UndoLogCorrupt,
+            CodeAgentError::RollbackBlocked { .. } => ErrorCode::RollbackBlocked,
+            CodeAgentError::SafeguardDenied { .. } => ErrorCode::SafeguardDenied,
+            CodeAgentError::StepBudgetExceeded { .. } => ErrorCode::StepBudgetExceeded,
+            CodeAgentError::StepUnprotected { .. } => ErrorCode::StepUnprotected,
+            CodeAgentError::InsufficientHistory { .. } => ErrorCode::InsufficientHistory,
+            CodeAgentError::InvalidStepRange { .. } => ErrorCode::InvalidStepRange,
+            CodeAgentError::UndoDisabled { .. } => ErrorCode::UndoDisabled,
+            CodeAgentError::GitMirror { .. } => ErrorCode::CapabilityUnavailable,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, CodeAgentError>;
@@ -273,6 +978,60 @@ pub type Result<T> = std::result::Result<T, CodeAgentError>;
 mod tests {
     use super::*;
 
+    #[test]
+    fn warning_code_matches_serialized_tag() {
+        let warnings = [
+            SandboxWarning::VmNotConfigured { missing: vec!["kernel".into()] },
+            SandboxWarning::VmLaunchFailed { reason: "boom".into() },
+            SandboxWarning::FileWatcherFailed { path: None, reason: "limit".into() },
+            SandboxWarning::FileWatcherOverflow,
+            SandboxWarning::UndoDisabled {
+                working_dir: "/w".into(),
+                expected_version: "1".into(),
+                found_version: "2".into(),
+            },
+            SandboxWarning::ShimRestarted {
+                exit_code: None,
+                signal: Some(11),
+                stderr: vec!["segfault".into()],
+                restarts: 1,
+            },
+            SandboxWarning::ShareNotMounted {
+                mount_name: "app".into(),
+                reason: "no such tag".into(),
+            },
+            SandboxWarning::VmStateSaveFailed { reason: "blocked".into() },
+            SandboxWarning::VmStateRestoreFailed { reason: "mismatch".into() },
+        ];
+        for warning in warnings {
+            let json = serde_json::to_value(&warning).unwrap();
+            assert_eq!(json["code"], warning.code());
+            let round_trip: SandboxWarning = serde_json::from_value(json).unwrap();
+            assert_eq!(round_trip, warning);
+        }
+    }
+
+    #[test]
+    fn error_code_matches_serialized_name() {
+        let codes = [
+            ErrorCode::MalformedJson,
+            ErrorCode::SessionNotActive,
+            ErrorCode::QemuUnavailable,
+            ErrorCode::VirtiofsFailed,
+            ErrorCode::UndoLogCorrupt,
+            ErrorCode::InvalidStepRange,
+            ErrorCode::IoError,
+        ];
+        for code in codes {
+            assert_eq!(serde_json::to_value(code).unwrap(), code.as_str());
+        }
+        assert!(ErrorCode::StepWaitTimeout.is_retryable());
+        assert!(!ErrorCode::SessionNotActive.is_retryable());
+
+        let error = CodeAgentError::Decompression { message: "bad frame".into() };
+        assert_eq!(error.code(), ErrorCode::UndoLogCorrupt);
+    }
+
     #[test]
     fn step_type_serde_round_trip() {
         for variant in [StepType::Command, StepType::Ambient, StepType::Api] {
@@ -290,6 +1049,10 @@ mod tests {
             timestamp: Utc::now(),
             command: Some("npm install".to_string()),
             affected_paths: vec![PathBuf::from("package-lock.json")],
+            duration_ms: Some(1500),
+            exit_code: Some(0),
+            file_count: 1,
+            preimage_bytes: 2048,
         };
         let json = serde_json::to_string_pretty(&info).unwrap();
         let deserialized: StepInfo = serde_json::from_str(&json).unwrap();
@@ -297,6 +1060,20 @@ mod tests {
         assert_eq!(info.step_type, deserialized.step_type);
         assert_eq!(info.command, deserialized.command);
         assert_eq!(info.affected_paths, deserialized.affected_paths);
+        assert_eq!(info.duration_ms, deserialized.duration_ms);
+        assert_eq!(info.exit_code, deserialized.exit_code);
+        assert_eq!(info.file_count, deserialized.file_count);
+        assert_eq!(info.preimage_bytes, deserialized.preimage_bytes);
+    }
+
+    #[test]
+    fn step_info_deserializes_without_metadata() {
+        let json = r#"{"id":1,"step_type":"ambient","timestamp":"2024-01-01T00:00:00Z","command":null,"affected_paths":[]}"#;
+        let info: StepInfo = serde_json::from_str(json).unwrap();
+        assert_eq!(info.duration_ms, None);
+        assert_eq!(info.exit_code, None);
+        assert_eq!(info.file_count, 0);
+        assert_eq!(info.preimage_bytes, 0);
     }
 
     #[test]
@@ -352,7 +1129,12 @@ mod tests {
 
     #[test]
     fn barrier_reason_serde_round_trip() {
-        for variant in [BarrierReason::SessionStart, BarrierReason::ExternalModification] {
+        for variant in [
+            BarrierReason::SessionStart,
+            BarrierReason::ExternalModification,
+            BarrierReason::ShimRestarted,
+            BarrierReason::VmCrashed,
+        ] {
             let json = serde_json::to_string(&variant).unwrap();
             let deserialized: BarrierReason = serde_json::from_str(&json).unwrap();
             assert_eq!(variant, deserialized);
@@ -367,6 +1149,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn external_modification_config_deserializes_partial() {
+        let json = r#"{"rules":[{"pattern":"target/**","policy":"ignore"}]}"#;
+        let config: ExternalModificationConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.default_policy, ExternalModificationPolicy::Barrier);
+        assert_eq!(
+            config.rules,
+            vec![ExternalModificationRule {
+                pattern: "target/**".to_string(),
+                policy: ExternalModificationPolicy::Ignore,
+            }]
+        );
+    }
+
     #[test]
     fn rollback_blocked_error_display() {
         let err = CodeAgentError::RollbackBlocked {
@@ -382,6 +1178,10 @@ mod tests {
         assert_eq!(config.delete_threshold, None);
         assert_eq!(config.overwrite_file_size_threshold, None);
         assert!(!config.rename_over_existing);
+        assert_eq!(config.overwrite_count_threshold, None);
+        assert!(config.protected_paths.is_empty());
+        assert_eq!(config.max_step_duration_seconds, None);
+        assert_eq!(config.max_step_operations, None);
     }
 
     #[test]
@@ -430,6 +1230,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn case_sensitivity_defaults_to_auto() {
+        assert_eq!(CaseSensitivity::default(), CaseSensitivity::Auto);
+        let parsed: CaseSensitivity = serde_json::from_str("\"insensitive\"").unwrap();
+        assert_eq!(parsed, CaseSensitivity::Insensitive);
+    }
+
+    #[test]
+    fn coherent_capture_config_default_has_no_rules() {
+        let config = CoherentCaptureConfig::default();
+        assert!(config.rules.is_empty());
+        assert_eq!(config.max_attempts, DEFAULT_COHERENT_CAPTURE_ATTEMPTS);
+        assert_eq!(config.retry_interval_ms, DEFAULT_COHERENT_CAPTURE_RETRY_INTERVAL_MS);
+    }
+
+    #[test]
+    fn coherent_capture_rule_serde_round_trip() {
+        let rule = CoherentCaptureRule {
+            pattern: "**/*.sqlite".to_string(),
+            strategy: CoherentCaptureStrategy::AdvisoryLock,
+        };
+        let json = serde_json::to_string(&rule).unwrap();
+        assert!(json.contains("advisory_lock"));
+        let deserialized: CoherentCaptureRule = serde_json::from_str(&json).unwrap();
+        assert_eq!(rule, deserialized);
+    }
+
     #[test]
     fn safeguard_denied_error_display() {
         let err = CodeAgentError::SafeguardDenied {
@@ -0,0 +1,203 @@
+//! Counters of what the sandbox process did, and their text exposition.
+//!
+//! The undo interceptor, the control channel handler and the orchestrator
+//! count into process-wide [`Counter`]s as things happen; counters only
+//! grow and cover every session of the process. [`Gauges`] are sampled
+//! from a session when a report is asked for: `session.metrics` returns
+//! both as JSON ([`report`]), and the metrics endpoint serves them in the
+//! Prometheus text format ([`prometheus_text`]).
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    /// Undo steps opened, of any type.
+    StepsOpened,
+    /// Undo steps closed, including those discarded for touching nothing.
+    StepsClosed,
+    /// Bytes of preimages captured before files were changed.
+    PreimageBytes,
+    /// Rollbacks that rolled back at least one step.
+    Rollbacks,
+    /// Steps removed by those rollbacks.
+    StepsRolledBack,
+    /// Safeguards triggered, whatever the decision.
+    SafeguardTriggers,
+    /// Steps evicted for resource limits.
+    StepsEvicted,
+    /// Commands the guest started.
+    CommandsStarted,
+    /// Commands the guest reported complete.
+    CommandsCompleted,
+    /// Restarts of the guest shim.
+    ShimRestarts,
+    /// VMs launched, including restarts after a crash.
+    VmLaunches,
+    /// VMs that exited without being stopped.
+    VmCrashes,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 12] = [
+        Counter::StepsOpened,
+        Counter::StepsClosed,
+        Counter::PreimageBytes,
+        Counter::Rollbacks,
+        Counter::StepsRolledBack,
+        Counter::SafeguardTriggers,
+        Counter::StepsEvicted,
+        Counter::CommandsStarted,
+        Counter::CommandsCompleted,
+        Counter::ShimRestarts,
+        Counter::VmLaunches,
+        Counter::VmCrashes,
+    ];
+
+    /// Name in `session.metrics`; the Prometheus name adds a `codeagent_`
+    /// prefix and a `_total` suffix.
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::StepsOpened => "steps_opened",
+            Counter::StepsClosed => "steps_closed",
+            Counter::PreimageBytes => "preimage_bytes",
+            Counter::Rollbacks => "rollbacks",
+            Counter::StepsRolledBack => "steps_rolled_back",
+            Counter::SafeguardTriggers => "safeguard_triggers",
+            Counter::StepsEvicted => "steps_evicted",
+            Counter::CommandsStarted => "commands_started",
+            Counter::CommandsCompleted => "commands_completed",
+            Counter::ShimRestarts => "shim_restarts",
+            Counter::VmLaunches => "vm_launches",
+            Counter::VmCrashes => "vm_crashes",
+        }
+    }
+
+    fn help(self) -> &'static str {
+        match self {
+            Counter::StepsOpened => "Undo steps opened.",
+            Counter::StepsClosed => "Undo steps closed.",
+            Counter::PreimageBytes => "Bytes of preimages captured.",
+            Counter::Rollbacks => "Rollbacks of at least one step.",
+            Counter::StepsRolledBack => "Steps removed by rollbacks.",
+            Counter::SafeguardTriggers => "Safeguards triggered.",
+            Counter::StepsEvicted => "Steps evicted for resource limits.",
+            Counter::CommandsStarted => "Commands started in the guest.",
+            Counter::CommandsCompleted => "Commands completed in the guest.",
+            Counter::ShimRestarts => "Restarts of the guest shim.",
+            Counter::VmLaunches => "VMs launched.",
+            Counter::VmCrashes => "VMs that exited without being stopped.",
+        }
+    }
+}
+
+static COUNTS: [AtomicU64; Counter::ALL.len()] = [const { AtomicU64::new(0) }; Counter::ALL.len()];
+
+pub fn increment(counter: Counter) {
+    add(counter, 1);
+}
+
+pub fn add(counter: Counter, amount: u64) {
+    COUNTS[counter as usize].fetch_add(amount, Ordering::Relaxed);
+}
+
+pub fn get(counter: Counter) -> u64 {
+    COUNTS[counter as usize].load(Ordering::Relaxed)
+}
+
+/// Values of a session at the time they are sampled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Gauges {
+    /// Bytes of the undo logs of the session's working directories.
+    pub undo_log_bytes: u64,
+    /// Filesystem operations the VM has in flight.
+    pub in_flight_operations: u64,
+    /// Memory given to the session's VM, or 0 without one.
+    pub vm_memory_bytes: u64,
+}
+
+impl Gauges {
+    /// Gauges of several sessions together.
+    pub fn sum(self, other: Gauges) -> Gauges {
+        Gauges {
+            undo_log_bytes: self.undo_log_bytes + other.undo_log_bytes,
+            in_flight_operations: self.in_flight_operations + other.in_flight_operations,
+            vm_memory_bytes: self.vm_memory_bytes + other.vm_memory_bytes,
+        }
+    }
+
+    fn values(&self) -> [(&'static str, &'static str, u64); 3] {
+        [
+            ("undo_log_bytes", "Bytes of undo logs.", self.undo_log_bytes),
+            (
+                "in_flight_operations",
+                "Filesystem operations in flight.",
+                self.in_flight_operations,
+            ),
+            ("vm_memory_bytes", "Memory given to VMs.", self.vm_memory_bytes),
+        ]
+    }
+}
+
+/// The counters and `gauges` as `{"counters": {..}, "gauges": {..}}`.
+pub fn report(gauges: &Gauges) -> serde_json::Value {
+    let counters: serde_json::Map<String, serde_json::Value> = Counter::ALL
+        .iter()
+        .map(|&counter| (counter.name().to_string(), get(counter).into()))
+        .collect();
+    serde_json::json!({ "counters": counters, "gauges": gauges })
+}
+
+/// The counters and `gauges` in the Prometheus text exposition format.
+pub fn prometheus_text(gauges: &Gauges) -> String {
+    let mut text = String::new();
+    for counter in Counter::ALL {
+        let name = format!("codeagent_{}_total", counter.name());
+        let _ = writeln!(text, "# HELP {name} {}", counter.help());
+        let _ = writeln!(text, "# TYPE {name} counter");
+        let _ = writeln!(text, "{name} {}", get(counter));
+    }
+    for (name, help, value) in gauges.values() {
+        let _ = writeln!(text, "# HELP codeagent_{name} {help}");
+        let _ = writeln!(text, "# TYPE codeagent_{name} gauge");
+        let _ = writeln!(text, "codeagent_{name} {value}");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_reported_by_name() {
+        let before = get(Counter::VmCrashes);
+        increment(Counter::VmCrashes);
+        add(Counter::VmCrashes, 2);
+        assert!(get(Counter::VmCrashes) >= before + 3);
+
+        let gauges = Gauges {
+            undo_log_bytes: 4096,
+            ..Gauges::default()
+        };
+        let report = report(&gauges);
+        assert!(report["counters"]["vm_crashes"].as_u64().unwrap() >= before + 3);
+        assert_eq!(report["counters"].as_object().unwrap().len(), Counter::ALL.len());
+        assert_eq!(report["gauges"]["undo_log_bytes"], 4096);
+    }
+
+    #[test]
+    fn prometheus_text_declares_every_metric() {
+        let text = prometheus_text(&Gauges {
+            vm_memory_bytes: 512 << 20,
+            ..Gauges::default()
+        });
+        assert!(text.contains("# TYPE codeagent_steps_opened_total counter\n"));
+        assert!(text.contains("# TYPE codeagent_undo_log_bytes gauge\n"));
+        assert!(text.contains("codeagent_vm_memory_bytes 536870912\n"));
+        let samples = text.lines().filter(|line| !line.starts_with('#')).count();
+        assert_eq!(samples, Counter::ALL.len() + 3);
+    }
+}
@@ -0,0 +1,389 @@
+//! JSON Schema descriptions of wire types.
+//!
+//! The STDIO API and the control channel describe their messages with
+//! [`JsonSchema`], so frontends can generate types from them and check the
+//! messages they exchange. Structs implement it with [`object_schema!`],
+//! which names every field with its type and stops compiling when the struct
+//! changes without the list; enums of unit variants use [`enum_schema!`],
+//! which takes the wire names from serde.
+//!
+//! Schemas are draft 2020-12 and keep to the keywords [`validate`] checks.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use serde::Serialize;
+pub use serde_json::Value;
+use serde_json::json;
+
+use crate::{
+    CaseSensitivity, CoherentCaptureConfig, CoherentCaptureRule, CoherentCaptureStrategy,
+    ExpectedOperation, ExternalModificationConfig, ExternalModificationPolicy,
+    ExternalModificationRule, GitMirrorConfig, ReadEncoding, RollbackMode, SymlinkPolicy,
+};
+
+/// `$schema` of the documents built from these schemas.
+pub const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// A type with a JSON Schema for its serde form.
+pub trait JsonSchema {
+    fn json_schema() -> Value;
+}
+
+macro_rules! primitive_schema {
+    ($schema:tt: $($ty:ty),*) => {
+        $(impl JsonSchema for $ty {
+            fn json_schema() -> Value {
+                json!($schema)
+            }
+        })*
+    };
+}
+
+primitive_schema!({ "type": "string" }: String, PathBuf);
+primitive_schema!({ "type": "boolean" }: bool);
+primitive_schema!({ "type": "integer", "minimum": 0 }: u8, u16, u32, u64, usize);
+primitive_schema!({ "type": "integer" }: i32, i64);
+primitive_schema!({ "type": "number" }: f64);
+primitive_schema!({}: Value);
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    fn json_schema() -> Value {
+        json!({ "anyOf": [T::json_schema(), { "type": "null" }] })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+    fn json_schema() -> Value {
+        json!({ "type": "array", "items": T::json_schema() })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for HashMap<String, T> {
+    fn json_schema() -> Value {
+        json!({ "type": "object", "additionalProperties": T::json_schema() })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for BTreeMap<String, T> {
+    fn json_schema() -> Value {
+        HashMap::<String, T>::json_schema()
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Box<T> {
+    fn json_schema() -> Value {
+        T::json_schema()
+    }
+}
+
+/// An object named `title` with `properties`, of which `required` must be
+/// present. Other properties are allowed, as serde ignores them.
+pub fn object(title: &str, properties: Vec<(&str, Value)>, required: &[&str]) -> Value {
+    let properties: serde_json::Map<String, Value> = properties
+        .into_iter()
+        .map(|(name, schema)| (name.to_string(), schema))
+        .collect();
+    json!({
+        "title": title,
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+/// One of the strings `values`.
+pub fn string_enum(title: &str, values: &[String]) -> Value {
+    json!({ "title": title, "type": "string", "enum": values })
+}
+
+/// Exactly `value`, e.g. the `type` of a message.
+pub fn constant(value: &str) -> Value {
+    json!({ "const": value })
+}
+
+/// The string serde writes for `variant`.
+pub fn wire_name(variant: &impl Serialize) -> String {
+    match serde_json::to_value(variant) {
+        Ok(Value::String(name)) => name,
+        other => panic!("{other:?} is not a unit variant"),
+    }
+}
+
+/// The wire name of a field: its `rename`, or its own name.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_field_name {
+    ($field:ident) => {
+        stringify!($field)
+    };
+    ($field:ident $wire:literal) => {
+        $wire
+    };
+}
+
+/// Implement [`JsonSchema`](crate::schema::JsonSchema) for a struct from its
+/// fields, split into those a message must carry and those serde defaults.
+/// A field with a serde `rename` gives its wire name after `as`.
+///
+/// ```ignore
+/// object_schema!(GitMirrorConfig {
+///     optional { enabled: bool, ref_name as "ref": String }
+/// });
+/// ```
+#[macro_export]
+macro_rules! object_schema {
+    ($ty:ident {
+        $(required { $($req:ident $(as $req_wire:literal)?: $req_ty:ty),* $(,)? })?
+        $(optional { $($opt:ident $(as $opt_wire:literal)?: $opt_ty:ty),* $(,)? })?
+    }) => {
+        impl $crate::schema::JsonSchema for $ty {
+            fn json_schema() -> $crate::schema::Value {
+                // Stops compiling when a field is added, removed or retyped
+                // without updating the schema.
+                let _ = |value: &$ty| {
+                    let $ty { $($($req,)*)? $($($opt,)*)? } = value;
+                    $($(let _: &$req_ty = $req;)*)?
+                    $($(let _: &$opt_ty = $opt;)*)?
+                };
+                $crate::schema::object(
+                    stringify!($ty),
+                    vec![
+                        $($((
+                            $crate::__schema_field_name!($req $($req_wire)?),
+                            <$req_ty as $crate::schema::JsonSchema>::json_schema(),
+                        ),)*)?
+                        $($((
+                            $crate::__schema_field_name!($opt $($opt_wire)?),
+                            <$opt_ty as $crate::schema::JsonSchema>::json_schema(),
+                        ),)*)?
+                    ],
+                    &[$($($crate::__schema_field_name!($req $($req_wire)?),)*)?],
+                )
+            }
+        }
+    };
+}
+
+/// Implement [`JsonSchema`](crate::schema::JsonSchema) for an enum of unit
+/// variants, serialized as strings. Every variant must be listed.
+#[macro_export]
+macro_rules! enum_schema {
+    ($ty:ident { $($variant:ident),* $(,)? }) => {
+        impl $crate::schema::JsonSchema for $ty {
+            fn json_schema() -> $crate::schema::Value {
+                let _ = |value: &$ty| match value {
+                    $($ty::$variant => {})*
+                };
+                $crate::schema::string_enum(
+                    stringify!($ty),
+                    &[$($crate::schema::wire_name(&$ty::$variant)),*],
+                )
+            }
+        }
+    };
+}
+
+enum_schema!(SymlinkPolicy { Ignore, ReadOnly, ReadWrite });
+enum_schema!(CaseSensitivity { Auto, Sensitive, Insensitive });
+enum_schema!(RollbackMode { Restore, Merge });
+enum_schema!(ReadEncoding { Utf8, Base64 });
+enum_schema!(ExpectedOperation { Delete, Rewrite });
+enum_schema!(ExternalModificationPolicy { Barrier, Warn, Ignore });
+enum_schema!(CoherentCaptureStrategy { AdvisoryLock, RetryUntilQuiescent });
+
+object_schema!(ExternalModificationRule {
+    required { pattern: String, policy: ExternalModificationPolicy }
+});
+
+object_schema!(ExternalModificationConfig {
+    optional { default_policy: ExternalModificationPolicy, rules: Vec<ExternalModificationRule> }
+});
+
+object_schema!(CoherentCaptureRule {
+    required { pattern: String, strategy: CoherentCaptureStrategy }
+});
+
+object_schema!(CoherentCaptureConfig {
+    optional { rules: Vec<CoherentCaptureRule>, max_attempts: u32, retry_interval_ms: u64 }
+});
+
+object_schema!(GitMirrorConfig {
+    optional { enabled: bool, ref_name as "ref": String }
+});
+
+/// Check `value` against `schema`, naming the first mismatch by its JSON
+/// pointer. `strict` also rejects object properties the schema does not
+/// list, which catches a type writing fields its schema lacks.
+pub fn validate(schema: &Value, value: &Value, strict: bool) -> Result<(), String> {
+    check(schema, value, strict, "")
+}
+
+fn check(schema: &Value, value: &Value, strict: bool, at: &str) -> Result<(), String> {
+    let at_or_root = if at.is_empty() { "/" } else { at };
+    let fail = |message: String| Err(format!("{at_or_root}: {message}"));
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            return fail(format!("expected {expected}, got {value}"));
+        }
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        if !values.contains(value) {
+            return fail(format!("{value} is not one of {values:?}"));
+        }
+    }
+    if let Some(options) = schema.get("anyOf").and_then(Value::as_array) {
+        if !options.iter().any(|option| check(option, value, strict, at).is_ok()) {
+            return fail(format!("{value} matches none of the alternatives"));
+        }
+    }
+    if let Some(options) = schema.get("oneOf").and_then(Value::as_array) {
+        let matching = options
+            .iter()
+            .filter(|option| check(option, value, strict, at).is_ok())
+            .count();
+        if matching != 1 {
+            return fail(format!("{value} matches {matching} alternatives, not one"));
+        }
+    }
+    if let Some(kind) = schema.get("type").and_then(Value::as_str) {
+        let matches = match kind {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "boolean" => value.is_boolean(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !matches {
+            return fail(format!("expected {kind}, got {value}"));
+        }
+    }
+    if let (Some(minimum), Some(number)) =
+        (schema.get("minimum").and_then(Value::as_f64), value.as_f64())
+    {
+        if number < minimum {
+            return fail(format!("{number} is below {minimum}"));
+        }
+    }
+    if let (Some(items), Some(elements)) = (schema.get("items"), value.as_array()) {
+        for (index, element) in elements.iter().enumerate() {
+            check(items, element, strict, &format!("{at}/{index}"))?;
+        }
+    }
+    if let Some(fields) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for name in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+            if let Some(name) = name.as_str() {
+                if !fields.contains_key(name) {
+                    return fail(format!("missing `{name}`"));
+                }
+            }
+        }
+        for (name, field) in fields {
+            let at = format!("{at}/{name}");
+            match (properties.and_then(|p| p.get(name)), schema.get("additionalProperties")) {
+                (Some(property), _) => check(property, field, strict, &at)?,
+                (None, Some(additional)) => check(additional, field, strict, &at)?,
+                (None, None) if strict && properties.is_some() => {
+                    return Err(format!("{at}: not in the schema"));
+                }
+                (None, None) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The smallest value `schema` accepts: required properties only, empty
+/// arrays, the first of each set of alternatives.
+pub fn example(schema: &Value) -> Value {
+    if let Some(value) = schema.get("const") {
+        return value.clone();
+    }
+    if let Some(value) = schema.get("enum").and_then(|values| values.get(0)) {
+        return value.clone();
+    }
+    if let Some(option) = ["anyOf", "oneOf"].iter().find_map(|key| schema.get(key)?.get(0)) {
+        return example(option);
+    }
+    match schema.get("type").and_then(Value::as_str) {
+        Some("object") => {
+            let properties = schema.get("properties");
+            let fields: serde_json::Map<String, Value> = schema
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .map(|name| {
+                    let property = properties.and_then(|p| p.get(name)).unwrap_or(&Value::Null);
+                    (name.to_string(), example(property))
+                })
+                .collect();
+            Value::Object(fields)
+        }
+        Some("array") => json!([]),
+        Some("string") => json!("a"),
+        Some("boolean") => json!(false),
+        Some("integer") | Some("number") => json!(1),
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Shade {
+        Light,
+        DarkGrey,
+    }
+
+    enum_schema!(Shade { Light, DarkGrey });
+
+    struct Paint {
+        name: String,
+        shade: Option<Shade>,
+        coats: u32,
+    }
+
+    object_schema!(Paint {
+        required { name: String }
+        optional { shade: Option<Shade>, coats as "layers": u32 }
+    });
+
+    #[test]
+    fn objects_list_their_fields_and_enums_their_wire_names() {
+        let schema = Paint::json_schema();
+        assert_eq!(schema["title"], "Paint");
+        assert_eq!(schema["required"], json!(["name"]));
+        assert_eq!(schema["properties"]["layers"], json!({ "type": "integer", "minimum": 0 }));
+        assert_eq!(
+            schema["properties"]["shade"]["anyOf"][0],
+            json!({ "title": "Shade", "type": "string", "enum": ["light", "dark_grey"] }),
+        );
+        assert_eq!(example(&schema), json!({ "name": "a" }));
+    }
+
+    #[test]
+    fn validation_reports_the_first_mismatch() {
+        let schema = Paint::json_schema();
+        validate(&schema, &json!({ "name": "white", "shade": null, "layers": 2 }), true).unwrap();
+        validate(&schema, &json!({ "name": "white", "gloss": true }), false).unwrap();
+
+        for (value, error) in [
+            (json!({ "layers": 2 }), "/: missing `name`"),
+            (json!({ "name": "white", "layers": -1 }), "/layers: -1 is below 0"),
+            (json!({ "name": "white", "shade": "dark" }), "/shade: \"dark\" matches none"),
+            (json!({ "name": "white", "gloss": true }), "/gloss: not in the schema"),
+        ] {
+            let message = validate(&schema, &value, true).unwrap_err();
+            assert!(message.starts_with(error), "{message}");
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Timestamps and durations as they appear on the wire.
+//!
+//! Every timestamp the sandbox reports is RFC 3339 in UTC with millisecond
+//! precision and a `Z` suffix, e.g. `2025-03-01T12:00:01.234Z`. Every
+//! duration is a whole number of milliseconds in a field ending in `_ms`.
+
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+/// Format a timestamp in the protocol's format.
+pub fn format_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// The current time in the protocol's format.
+pub fn now_timestamp() -> String {
+    format_timestamp(&Utc::now())
+}
+
+/// Parse an RFC 3339 timestamp with any offset, such as one written before
+/// the format was fixed, and convert it to UTC.
+pub fn parse_timestamp(text: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(text)
+        .ok()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+/// A duration in whole milliseconds, saturating at `u64::MAX`.
+pub fn duration_ms(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// `#[serde(with = "codeagent_common::time::rfc3339")]` for `DateTime<Utc>`
+/// fields.
+pub mod rfc3339 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        timestamp: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::format_timestamp(timestamp))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        super::parse_timestamp(&text)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid RFC 3339 timestamp: {text}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_are_utc_millis_and_any_offset_parses() {
+        let timestamp = parse_timestamp("2025-03-01T14:00:01.234567+02:00").unwrap();
+        assert_eq!(format_timestamp(&timestamp), "2025-03-01T12:00:01.234Z");
+        assert_eq!(parse_timestamp("yesterday"), None);
+
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Stamped {
+            #[serde(with = "rfc3339")]
+            at: DateTime<Utc>,
+        }
+        let json = serde_json::to_string(&Stamped { at: timestamp }).unwrap();
+        assert_eq!(json, r#"{"at":"2025-03-01T12:00:01.234Z"}"#);
+        let parsed: Stamped = serde_json::from_str(r#"{"at":"2025-03-01T12:00:01Z"}"#).unwrap();
+        assert_eq!(parsed.at, parse_timestamp("2025-03-01T12:00:01+00:00").unwrap());
+        assert!(serde_json::from_str::<Stamped>(r#"{"at":"noon"}"#).is_err());
+
+        assert_eq!(duration_ms(Duration::from_micros(1_999)), 1);
+        assert_eq!(duration_ms(Duration::MAX), u64::MAX);
+    }
+}
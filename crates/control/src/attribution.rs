@@ -0,0 +1,181 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use codeagent_common::{StepAttributor, StepId};
+
+use crate::lane::LaneSender;
+use crate::protocol::HostMessage;
+
+/// How long a filesystem operation waits for the shim to say which command
+/// its process belongs to before it is recorded in the oldest open step.
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// What the shim said about a guest process.
+#[derive(Clone, Default)]
+struct Answer {
+    /// The command it belongs to; `None` for processes of no running command.
+    id: Option<u64>,
+    /// Its name, if it was still running.
+    name: Option<String>,
+}
+
+#[derive(Default)]
+struct AttributionState {
+    /// Command steps currently open, from `step_started` until the step
+    /// closes after its quiescence window.
+    commands: HashSet<u64>,
+    /// Answers from the shim.
+    pids: HashMap<u32, Answer>,
+    /// Pids asked about and not answered yet.
+    pending: HashSet<u32>,
+}
+
+/// Tells which command a guest process belongs to, and its name, by asking
+/// the shim.
+///
+/// Steps are only looked up while two or more command steps are open; with
+/// one, every operation goes to it anyway. Answers are cached until the
+/// command they name closes. Filesystem backends call
+/// [`step_for_pid`](Self::step_for_pid) and
+/// [`process_name`](Self::process_name) from their worker threads and block
+/// until the answer arrives or [`RESOLVE_TIMEOUT`] passes.
+#[derive(Default)]
+pub struct PidAttribution {
+    state: Mutex<AttributionState>,
+    answered: Condvar,
+    writer: OnceLock<LaneSender<HostMessage>>,
+}
+
+impl PidAttribution {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send `resolve_pid` questions through the control channel writer.
+    /// Until this is called every lookup returns `None`.
+    pub fn connect(&self, writer: LaneSender<HostMessage>) {
+        let _ = self.writer.set(writer);
+    }
+
+    /// The step of the command guest process `pid` belongs to.
+    pub fn step_for_pid(&self, pid: u32) -> Option<StepId> {
+        // Operations the kernel issues on its own, e.g. writeback, carry no pid.
+        if pid == 0 {
+            return None;
+        }
+        let state = self.state.lock().unwrap();
+        if state.commands.len() < 2 {
+            return None;
+        }
+        self.answer(state, pid)?.id.map(|id| id as StepId)
+    }
+
+    /// The name of guest process `pid`, as the shim read it.
+    pub fn process_name(&self, pid: u32) -> Option<String> {
+        if pid == 0 {
+            return None;
+        }
+        self.answer(self.state.lock().unwrap(), pid)?.name
+    }
+
+    /// The cached answer for `pid`, or the shim's once it arrives. `None`
+    /// until [`connect`](Self::connect) is called.
+    fn answer(&self, mut state: MutexGuard<'_, AttributionState>, pid: u32) -> Option<Answer> {
+        if let Some(answer) = state.pids.get(&pid) {
+            return Some(answer.clone());
+        }
+        let writer = self.writer.get()?;
+        if state.pending.insert(pid) {
+            let _ = writer.send(HostMessage::ResolvePid { pid });
+        }
+
+        let (mut state, waited) = self
+            .answered
+            .wait_timeout_while(state, RESOLVE_TIMEOUT, |state| !state.pids.contains_key(&pid))
+            .unwrap();
+        if waited.timed_out() {
+            // Do not hold up later operations of the same process.
+            state.pending.remove(&pid);
+            state.pids.insert(pid, Answer::default());
+        }
+        Some(state.pids[&pid].clone())
+    }
+
+    /// The shim answered a `resolve_pid` question.
+    pub fn resolved(&self, pid: u32, id: Option<u64>, name: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.remove(&pid);
+        state.pids.insert(pid, Answer { id, name });
+        drop(state);
+        self.answered.notify_all();
+    }
+
+    /// Command `id` opened its step. Processes that belonged to no command
+    /// may belong to it now, or their pids may have been reused for it.
+    pub fn command_started(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.commands.insert(id);
+        state.pids.retain(|_, answer| answer.id.is_some());
+    }
+
+    /// Command `id` closed its step; its pids may be reused.
+    pub fn command_closed(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.commands.remove(&id);
+        state.pids.retain(|_, answer| answer.id != Some(id));
+    }
+}
+
+impl StepAttributor for PidAttribution {
+    fn step_for_pid(&self, pid: u32) -> Option<StepId> {
+        PidAttribution::step_for_pid(self, pid)
+    }
+
+    fn process_name(&self, pid: u32) -> Option<String> {
+        PidAttribution::process_name(self, pid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::lane::lane_channel;
+
+    #[test]
+    fn asks_the_shim_only_when_commands_overlap() {
+        let attribution = Arc::new(PidAttribution::new());
+        let (writer, mut questions) = lane_channel();
+        attribution.connect(writer);
+
+        attribution.command_started(1);
+        assert_eq!(attribution.step_for_pid(40), None);
+        assert!(questions.try_recv().is_none());
+
+        attribution.command_started(2);
+        let answering = Arc::clone(&attribution);
+        let responder = std::thread::spawn(move || {
+            let question = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(questions.recv());
+            assert_eq!(question, Some(HostMessage::ResolvePid { pid: 40 }));
+            answering.resolved(40, Some(2), Some("cc1".to_string()));
+            questions
+        });
+        assert_eq!(attribution.step_for_pid(40), Some(2));
+        let mut questions = responder.join().unwrap();
+
+        // Cached until the command closes.
+        assert_eq!(attribution.step_for_pid(40), Some(2));
+        assert_eq!(attribution.process_name(40).as_deref(), Some("cc1"));
+        assert!(questions.try_recv().is_none());
+        attribution.command_started(3);
+        attribution.command_closed(2);
+        assert_eq!(attribution.step_for_pid(40), None);
+        assert!(questions.try_recv().is_some());
+        assert_eq!(attribution.step_for_pid(0), None);
+    }
+}
@@ -0,0 +1,147 @@
+//! Time as seen by the sandbox's timers.
+//!
+//! Quiescence windows, ambient step timeouts and safeguard timeouts all wait
+//! on a [`Clock`] rather than on tokio directly. [`TokioClock`] is the real
+//! thing (and follows `tokio::time::pause`); [`ManualClock`] only moves when
+//! told to, so a test or a simulation decides exactly when each timer fires,
+//! on any runtime.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// A future that completes when a [`Clock`] reaches a deadline.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// A source of time and timers.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once the clock reaches `deadline`. The deadline is taken
+    /// when this is called, not when the future is first polled.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+/// Tokio's timers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+#[derive(Debug)]
+struct ManualState {
+    now: Instant,
+    sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+/// A clock that stands still until [`advance`](Self::advance)d.
+#[derive(Debug)]
+pub struct ManualClock {
+    state: Mutex<ManualState>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(ManualState {
+                now: Instant::now(),
+                sleepers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Move the clock forward, waking every sleep whose deadline it passes.
+    pub fn advance(&self, duration: Duration) {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            state.now += duration;
+            let now = state.now;
+            let (due, waiting) = std::mem::take(&mut state.sleepers)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+            state.sleepers = waiting;
+            due
+        };
+        for (_, waker) in due {
+            let _ = waker.send(());
+        }
+    }
+
+    /// How many sleeps are waiting, so a test can tell a timer is armed
+    /// before it advances past it.
+    pub fn sleepers(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.sleepers.retain(|(_, waker)| !waker.is_closed());
+        state.sleepers.len()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let mut state = self.state.lock().unwrap();
+        if deadline <= state.now {
+            return Box::pin(std::future::ready(()));
+        }
+        state.sleepers.retain(|(_, waker)| !waker.is_closed());
+        let (waker, woken) = oneshot::channel();
+        state.sleepers.push((deadline, waker));
+        Box::pin(async move {
+            let _ = woken.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn manual_sleeps_wake_only_when_advanced_past() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut short = clock.sleep(Duration::from_secs(1));
+        let long = clock.sleep(Duration::from_secs(5));
+        assert_eq!(clock.sleepers(), 2);
+
+        clock.advance(Duration::from_millis(999));
+        assert!(futures_poll(&mut short).is_pending());
+        clock.advance(Duration::from_millis(1));
+        short.await;
+        assert_eq!(clock.now(), start + Duration::from_secs(1));
+
+        drop(long);
+        assert_eq!(clock.sleepers(), 0);
+        clock.sleep(Duration::ZERO).await;
+    }
+
+    fn futures_poll(sleep: &mut Sleep) -> std::task::Poll<()> {
+        let waker = std::task::Waker::noop();
+        sleep.as_mut().poll(&mut std::task::Context::from_waker(waker))
+    }
+}
@@ -29,4 +29,7 @@ pub enum ControlChannelError {
 
     #[error("cancel for unknown command {id}")]
     CancelUnknownCommand { id: u64 },
+
+    #[error("input for command {id}, which is not running")]
+    InputUnknownCommand { id: u64 },
 }
@@ -1,13 +1,20 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
 use tokio::sync::{Mutex, Notify, mpsc};
+use tokio::time::Instant;
 
-use codeagent_common::{StepId, StepManager};
+use codeagent_common::{metrics, StepId, StepManager};
 
-use crate::in_flight::InFlightTracker;
-use crate::protocol::{HostMessage, OutputStream, VmMessage};
+use crate::attribution::PidAttribution;
+use crate::clock::{Clock, TokioClock};
+use crate::error::ControlChannelError;
+use crate::in_flight::{ActivityMark, DrainScope, InFlightTracker};
+use crate::protocol::{
+    HostMessage, MountFailure, OutputStream, ResourceLimit, ResourceLimits, VmMessage,
+};
 use crate::state_machine::{ControlChannelState, ControlEvent};
 
 /// Configuration for quiescence and ambient step timeouts.
@@ -22,6 +29,12 @@ pub struct QuiescenceConfig {
     /// Inactivity timeout for ambient steps. An ambient step auto-closes
     /// after this duration with no new writes. Default: 5s.
     pub ambient_inactivity_timeout: Duration,
+    /// Longest an ambient step stays open, however steadily writes keep
+    /// arriving; the next write opens another. Default: 60s.
+    pub ambient_max_duration: Duration,
+    /// Most distinct files an ambient step records; a write to one more
+    /// opens another. Default: 1000.
+    pub ambient_max_files: usize,
 }
 
 impl Default for QuiescenceConfig {
@@ -30,6 +43,8 @@ impl Default for QuiescenceConfig {
             idle_timeout: Duration::from_millis(100),
             max_timeout: Duration::from_secs(2),
             ambient_inactivity_timeout: Duration::from_secs(5),
+            ambient_max_duration: Duration::from_secs(60),
+            ambient_max_files: 1000,
         }
     }
 }
@@ -53,6 +68,10 @@ pub enum HandlerEvent {
         exit_code: i32,
         cancelled: bool,
         evicted_steps: Vec<StepId>,
+        /// The command's output was cut short at its `max_output_bytes`.
+        output_truncated: bool,
+        /// The resource limit that stopped the command, if one did.
+        limit_exceeded: Option<ResourceLimit>,
     },
     /// An ambient step was opened due to a write outside a command step.
     AmbientStepOpened { step_id: StepId },
@@ -61,6 +80,18 @@ pub enum HandlerEvent {
         step_id: StepId,
         evicted_steps: Vec<StepId>,
     },
+    /// The guest shim crashed and was restarted. `lost_steps` are the steps
+    /// of the commands it was running; each still gets its `StepCompleted`,
+    /// with exit code -1, once its quiescence window ends.
+    ShimRestarted {
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        stderr: Vec<String>,
+        restarts: u32,
+        lost_steps: Vec<StepId>,
+    },
+    /// Shares the shim was asked to mount that it could not mount.
+    MountsFailed { failed: Vec<MountFailure> },
     /// A protocol violation was detected but the channel remains operational.
     ProtocolError { error: String },
 }
@@ -70,13 +101,37 @@ struct HandlerState {
     protocol: ControlChannelState,
     /// Counter for ambient step IDs (decrements: -1, -2, -3, ...)
     next_ambient_id: StepId,
-    /// The currently active command step (between step_started and step close).
-    active_command_step: Option<StepId>,
-    /// Whether we are in a quiescence window (between step_completed and
+    /// Command steps that are running (between step_started and
+    /// step_completed). Several run at once when commands overlap.
+    running_command_steps: HashSet<StepId>,
+    /// Filesystem activity when each running command step started, to tell
+    /// which working roots its quiescence window waits for.
+    activity_marks: HashMap<StepId, ActivityMark>,
+    /// Command steps in their quiescence window (between step_completed and
     /// the undo step actually closing).
-    in_quiescence: bool,
-    /// The currently open ambient step, if any.
-    ambient_step_id: Option<StepId>,
+    quiescing_steps: HashSet<StepId>,
+    /// The open ambient steps, by the working root of their writes (`None`
+    /// for writes whose root is not known).
+    ambient_steps: BTreeMap<Option<usize>, AmbientStep>,
+}
+
+/// An open ambient step.
+struct AmbientStep {
+    step_id: StepId,
+    opened_at: Instant,
+    last_write: Instant,
+    /// Files written in the step, counted against `ambient_max_files`.
+    files: HashSet<PathBuf>,
+    /// Timeouts of the step, from the configuration when it opened.
+    inactivity_timeout: Duration,
+    max_duration: Duration,
+}
+
+impl AmbientStep {
+    /// When the step closes unless written to before.
+    fn deadline(&self) -> Instant {
+        (self.last_write + self.inactivity_timeout).min(self.opened_at + self.max_duration)
+    }
 }
 
 /// Integrates the control channel protocol state machine with the undo
@@ -87,18 +142,30 @@ struct HandlerState {
 /// - Opens/closes undo steps at the right times
 /// - Implements quiescence windows after `step_completed`
 /// - Manages ambient step lifecycle for writes outside command steps
-pub struct ControlChannelHandler<S: StepManager> {
+///
+/// Each command gets its own step, opened with
+/// [`StepManager::open_concurrent_step`], so overlapping commands are
+/// recorded separately. Which of them a write belongs to is told by
+/// [`attribution`](Self::attribution).
+pub struct ControlChannelHandler<S: StepManager + ?Sized> {
     step_manager: Arc<S>,
+    /// Step managers of the working roots, by index, for the ambient steps
+    /// of writes in them. Empty: every step goes to `step_manager`.
+    root_step_managers: Vec<Arc<S>>,
+    attribution: Arc<PidAttribution>,
     in_flight: InFlightTracker,
-    config: QuiescenceConfig,
+    /// Read by each quiescence window and ambient step as it starts.
+    config: std::sync::Mutex<QuiescenceConfig>,
     state: Arc<Mutex<HandlerState>>,
     event_sender: mpsc::UnboundedSender<HandlerEvent>,
     /// Notifies the ambient timeout task that a new write arrived,
     /// so it should reset its deadline.
     ambient_reset_notify: Arc<Notify>,
+    /// Times quiescence windows and ambient steps.
+    clock: Arc<dyn Clock>,
 }
 
-impl<S: StepManager + 'static> ControlChannelHandler<S> {
+impl<S: StepManager + ?Sized + 'static> ControlChannelHandler<S> {
     /// Create a new handler.
     ///
     /// Returns the handler and a receiver for [`HandlerEvent`]s.
@@ -110,34 +177,68 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
         let (event_sender, event_receiver) = mpsc::unbounded_channel();
         let handler = Self {
             step_manager,
+            root_step_managers: Vec::new(),
+            attribution: Arc::new(PidAttribution::new()),
             in_flight,
-            config,
+            config: std::sync::Mutex::new(config),
             state: Arc::new(Mutex::new(HandlerState {
                 protocol: ControlChannelState::new(),
                 next_ambient_id: -1,
-                active_command_step: None,
-                in_quiescence: false,
-                ambient_step_id: None,
+                running_command_steps: HashSet::new(),
+                activity_marks: HashMap::new(),
+                quiescing_steps: HashSet::new(),
+                ambient_steps: BTreeMap::new(),
             })),
             event_sender,
             ambient_reset_notify: Arc::new(Notify::new()),
+            clock: Arc::new(TokioClock),
         };
         (handler, event_receiver)
     }
 
+    /// Time quiescence windows and ambient steps with `clock` instead of
+    /// tokio's timers.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Record the ambient steps of writes in each working root, by index,
+    /// with that root's step manager instead of the one given to `new`.
+    pub fn with_root_step_managers(mut self, step_managers: Vec<Arc<S>>) -> Self {
+        self.root_step_managers = step_managers;
+        self
+    }
+
+    /// The quiescence and ambient step timeouts in use.
+    pub fn config(&self) -> QuiescenceConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Use `config` from now on. Quiescence windows and ambient steps
+    /// already under way keep the timeouts they started with.
+    pub fn set_config(&self, config: QuiescenceConfig) {
+        *self.config.lock().unwrap() = config;
+    }
+
     /// Register a command to be sent to the VM.
     ///
     /// Returns the [`HostMessage::Exec`] for the caller to serialize and send
     /// over the control channel transport.
+    #[allow(clippy::too_many_arguments)]
     pub async fn send_exec(
         &self,
         id: u64,
         command: String,
         env: Option<HashMap<String, String>>,
         cwd: Option<String>,
+        isolate_fs: bool,
+        pty: bool,
+        max_output_bytes: Option<u64>,
+        limits: ResourceLimits,
     ) -> HostMessage {
-        // If an ambient step is open, close it first
-        self.close_ambient_step_if_open().await;
+        // If ambient steps are open, close them first
+        self.close_ambient_steps().await;
 
         let mut state = self.state.lock().await;
         state.protocol.command_sent(id, command.clone());
@@ -147,7 +248,25 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
             command,
             env,
             cwd,
+            isolate_fs,
+            pty,
+            max_output_bytes,
+            limits,
+        }
+    }
+
+    /// Returns the [`HostMessage::Input`] for input to command `id`, which
+    /// must have been sent and not yet completed or been cancelled.
+    pub async fn send_input(
+        &self,
+        id: u64,
+        data: String,
+        eof: bool,
+    ) -> Result<HostMessage, ControlChannelError> {
+        if !self.state.lock().await.protocol.accepts_input(id) {
+            return Err(ControlChannelError::InputUnknownCommand { id });
         }
+        Ok(HostMessage::Input { id, data, eof })
     }
 
     /// Process a VM message through the state machine and perform
@@ -162,10 +281,10 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
             ControlEvent::StepStarted { id, command } => {
                 let step_id = id as StepId;
 
-                // Close any open ambient step first
-                self.close_ambient_step_if_open().await;
+                // Close any open ambient steps first
+                self.close_ambient_steps().await;
 
-                if let Err(error) = self.step_manager.open_step(step_id) {
+                if let Err(error) = self.step_manager.open_concurrent_step(step_id) {
                     self.emit(HandlerEvent::ProtocolError {
                         error: format!("failed to open step {step_id}: {error}"),
                     });
@@ -176,8 +295,11 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
 
                 {
                     let mut state = self.state.lock().await;
-                    state.active_command_step = Some(step_id);
+                    state.running_command_steps.insert(step_id);
+                    state.activity_marks.insert(step_id, self.in_flight.mark());
                 }
+                self.attribution.command_started(id);
+                metrics::increment(metrics::Counter::CommandsStarted);
 
                 self.emit(HandlerEvent::StepStarted {
                     step_id,
@@ -195,16 +317,54 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
                 id,
                 exit_code,
                 cancelled,
+                output_truncated,
+                limit_exceeded,
             } => {
                 let step_id = id as StepId;
+                metrics::increment(metrics::Counter::CommandsCompleted);
 
                 {
                     let mut state = self.state.lock().await;
-                    state.active_command_step = None;
-                    state.in_quiescence = true;
+                    state.running_command_steps.remove(&step_id);
+                    state.quiescing_steps.insert(step_id);
                 }
 
-                self.spawn_quiescence_task(step_id, exit_code, cancelled);
+                self.spawn_quiescence_task(
+                    step_id,
+                    exit_code,
+                    cancelled,
+                    output_truncated,
+                    limit_exceeded,
+                );
+            }
+            ControlEvent::PidResolved { pid, id, name } => {
+                self.attribution.resolved(pid, id, name);
+            }
+            ControlEvent::Mounted { failed } => {
+                if !failed.is_empty() {
+                    self.emit(HandlerEvent::MountsFailed { failed });
+                }
+            }
+            ControlEvent::ShimRestarted {
+                exit_code,
+                signal,
+                stderr,
+                restarts,
+                lost_pending,
+                lost_active,
+            } => {
+                metrics::increment(metrics::Counter::ShimRestarts);
+                let lost_steps = self.lose_commands(lost_pending, lost_active).await;
+                self.emit(HandlerEvent::ShimRestarted {
+                    exit_code,
+                    signal,
+                    stderr,
+                    restarts,
+                    lost_steps: lost_steps.clone(),
+                });
+                for step_id in lost_steps {
+                    self.spawn_quiescence_task(step_id, -1, false, false, None);
+                }
             }
             ControlEvent::ProtocolError { error } => {
                 self.emit(HandlerEvent::ProtocolError { error });
@@ -212,31 +372,105 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
         }
     }
 
+    /// The guest is gone, e.g. the VM exited: complete every command it
+    /// was running with exit code -1, as for a shim restart. Returns the
+    /// steps of the commands that had started.
+    pub async fn abandon_commands(&self) -> Vec<StepId> {
+        let (lost_pending, lost_active) = self.state.lock().await.protocol.abandon_all();
+        let lost_steps = self.lose_commands(lost_pending, lost_active).await;
+        for &step_id in &lost_steps {
+            self.spawn_quiescence_task(step_id, -1, false, false, None);
+        }
+        lost_steps
+    }
+
+    /// Complete commands that will never report back. Those that never
+    /// started have no step to close, as when a pending command is
+    /// cancelled; the steps of the others are moved to quiescing, for the
+    /// caller to close. Returns those steps.
+    async fn lose_commands(&self, lost_pending: Vec<u64>, lost_active: Vec<u64>) -> Vec<StepId> {
+        for id in lost_pending {
+            self.emit(HandlerEvent::StepCompleted {
+                step_id: id as StepId,
+                exit_code: -1,
+                cancelled: false,
+                evicted_steps: vec![],
+                output_truncated: false,
+                limit_exceeded: None,
+            });
+        }
+        let lost_steps: Vec<StepId> = lost_active.iter().map(|&id| id as StepId).collect();
+        let mut state = self.state.lock().await;
+        for step_id in &lost_steps {
+            state.running_command_steps.remove(step_id);
+            state.quiescing_steps.insert(*step_id);
+        }
+        lost_steps
+    }
+
     /// Notify the handler that a filesystem write occurred.
     ///
     /// If no command step or quiescence window is active, this opens
     /// (or extends) an ambient step.
     pub async fn notify_fs_write(&self) {
-        let should_open_ambient = {
-            let state = self.state.lock().await;
-            state.active_command_step.is_none()
-                && !state.in_quiescence
-                && state.ambient_step_id.is_none()
-        };
+        self.notify_fs_write_at(None, None).await;
+    }
 
-        let should_reset_ambient = {
-            let state = self.state.lock().await;
-            state.active_command_step.is_none()
-                && !state.in_quiescence
-                && state.ambient_step_id.is_some()
+    /// Notify the handler that `path` was written in working root `root`,
+    /// as far as the backend can tell.
+    ///
+    /// Outside commands, the writes of each root go to an ambient step of
+    /// their own, recorded by that root's step manager (see
+    /// [`with_root_step_managers`](Self::with_root_step_managers)); those of
+    /// an unknown root share one. A step open for `ambient_max_duration`,
+    /// or holding `ambient_max_files` other files, is closed and the write
+    /// opens the next.
+    pub async fn notify_fs_write_at(&self, root: Option<usize>, path: Option<&Path>) {
+        let config = self.config();
+        let now = self.clock.now();
+        let (full, ambient_id) = {
+            let mut state = self.state.lock().await;
+            if !state.running_command_steps.is_empty() || !state.quiescing_steps.is_empty() {
+                return;
+            }
+            let extended = state.ambient_steps.get_mut(&root).is_some_and(|step| {
+                let new_file = path.is_some_and(|path| !step.files.contains(path));
+                if (new_file && step.files.len() >= config.ambient_max_files)
+                    || now >= step.opened_at + step.max_duration
+                {
+                    return false;
+                }
+                step.last_write = now;
+                step.files.extend(path.map(Path::to_path_buf));
+                true
+            });
+            if extended {
+                drop(state);
+                // Reset the ambient inactivity timer
+                self.ambient_reset_notify.notify_waiters();
+                return;
+            }
+
+            let full = state.ambient_steps.remove(&root);
+            let id = state.next_ambient_id;
+            state.next_ambient_id -= 1;
+            state.ambient_steps.insert(root, AmbientStep {
+                step_id: id,
+                opened_at: now,
+                last_write: now,
+                files: path.map(Path::to_path_buf).into_iter().collect(),
+                inactivity_timeout: config.ambient_inactivity_timeout,
+                max_duration: config.ambient_max_duration,
+            });
+            (full, id)
         };
 
-        if should_open_ambient {
-            self.open_ambient_step().await;
-        } else if should_reset_ambient {
-            // Reset the ambient inactivity timer
+        if let Some(full) = full {
+            // Wakes its timeout task, which finds it gone and exits
             self.ambient_reset_notify.notify_waiters();
+            close_ambient_step(&**self.step_manager_for(root), &self.event_sender, full.step_id);
         }
+        self.open_ambient_step(root, ambient_id).await;
     }
 
     /// Cancel a pending or active command.
@@ -252,6 +486,8 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
                     id,
                     exit_code,
                     cancelled,
+                    output_truncated,
+                    limit_exceeded,
                 } => {
                     let step_id = id as StepId;
                     self.emit(HandlerEvent::StepCompleted {
@@ -259,6 +495,8 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
                         exit_code,
                         cancelled,
                         evicted_steps: vec![],
+                        output_truncated,
+                        limit_exceeded,
                     });
                 }
                 ControlEvent::ProtocolError { error } => {
@@ -274,55 +512,97 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
         }
     }
 
-    /// Returns `true` if the handler is currently in a quiescence window.
+    /// Returns `true` if any command step is in its quiescence window.
     pub async fn in_quiescence(&self) -> bool {
-        self.state.lock().await.in_quiescence
+        !self.state.lock().await.quiescing_steps.is_empty()
+    }
+
+    /// Returns the IDs of command steps that are running, in no particular
+    /// order.
+    pub async fn running_command_steps(&self) -> Vec<StepId> {
+        self.state.lock().await.running_command_steps.iter().copied().collect()
     }
 
-    /// Returns the currently open ambient step ID, if any.
+    /// Tells filesystem backends which command a guest process belongs to.
+    /// Connect it to the control channel writer once that exists.
+    pub fn attribution(&self) -> Arc<PidAttribution> {
+        Arc::clone(&self.attribution)
+    }
+
+    /// Returns the ID of an open ambient step, if any, that of writes of an
+    /// unknown root first.
     pub async fn ambient_step_id(&self) -> Option<StepId> {
-        self.state.lock().await.ambient_step_id
+        self.state.lock().await.ambient_steps.values().next().map(|step| step.step_id)
+    }
+
+    /// Returns the open ambient step of writes in working root `root`
+    /// (`None`: in an unknown root), if any.
+    pub async fn ambient_step_in(&self, root: Option<usize>) -> Option<StepId> {
+        self.state.lock().await.ambient_steps.get(&root).map(|step| step.step_id)
     }
 
-    fn spawn_quiescence_task(&self, step_id: StepId, exit_code: i32, cancelled: bool) {
+    /// The step manager recording the ambient steps of `root`.
+    fn step_manager_for(&self, root: Option<usize>) -> &Arc<S> {
+        root.and_then(|root| self.root_step_managers.get(root)).unwrap_or(&self.step_manager)
+    }
+
+    fn spawn_quiescence_task(
+        &self,
+        step_id: StepId,
+        exit_code: i32,
+        cancelled: bool,
+        output_truncated: bool,
+        limit_exceeded: Option<ResourceLimit>,
+    ) {
         let step_manager = Arc::clone(&self.step_manager);
+        let attribution = Arc::clone(&self.attribution);
         let in_flight = self.in_flight.clone();
-        let config = self.config.clone();
+        let config = self.config();
         let state = Arc::clone(&self.state);
         let event_sender = self.event_sender.clone();
+        let clock = Arc::clone(&self.clock);
 
         tokio::spawn(async move {
-            let max_deadline = tokio::time::Instant::now() + config.max_timeout;
+            let max_deadline = clock.now() + config.max_timeout;
+            // Only the roots the command was active in can still be finishing
+            // its writes; operations elsewhere belong to something else.
+            let scope = match state.lock().await.activity_marks.remove(&step_id) {
+                Some(mark) => in_flight.active_since(&mark),
+                None => DrainScope::All,
+            };
 
             loop {
-                let now = tokio::time::Instant::now();
+                let now = clock.now();
                 let remaining = max_deadline.saturating_duration_since(now);
                 if remaining.is_zero() {
                     break;
                 }
 
                 // Wait for in-flight operations to drain
-                if !in_flight.wait_for_drain(remaining).await {
+                if !in_flight.wait_for_drain_in_on(&*clock, &scope, remaining).await {
                     break; // max timeout reached
                 }
 
                 // In-flight is zero — wait for idle period
-                let now = tokio::time::Instant::now();
+                let now = clock.now();
                 let remaining = max_deadline.saturating_duration_since(now);
                 if remaining.is_zero() {
                     break;
                 }
                 let idle_wait = remaining.min(config.idle_timeout);
-                tokio::time::sleep(idle_wait).await;
+                clock.sleep(idle_wait).await;
 
                 // Check if still drained after idle period
-                if in_flight.count() == 0 {
+                if in_flight.count_in(&scope) == 0 {
                     break; // quiescence achieved
                 }
                 // A new write started during idle — loop again
             }
 
             // Close the step
+            if !cancelled {
+                step_manager.set_step_exit_code(step_id, exit_code);
+            }
             let evicted = match step_manager.close_step(step_id) {
                 Ok(evicted) => evicted,
                 Err(error) => {
@@ -338,33 +618,31 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
 
             {
                 let mut state = state.lock().await;
-                state.in_quiescence = false;
+                state.quiescing_steps.remove(&step_id);
             }
+            attribution.command_closed(step_id as u64);
 
             let _ = event_sender.send(HandlerEvent::StepCompleted {
                 step_id,
                 exit_code,
                 cancelled,
                 evicted_steps: evicted,
+                output_truncated,
+                limit_exceeded,
             });
         });
     }
 
-    async fn open_ambient_step(&self) {
-        let ambient_id = {
-            let mut state = self.state.lock().await;
-            let id = state.next_ambient_id;
-            state.next_ambient_id -= 1;
-            state.ambient_step_id = Some(id);
-            id
-        };
-
-        if let Err(error) = self.step_manager.open_step(ambient_id) {
+    /// Open ambient step `ambient_id`, already in the state, for `root`.
+    async fn open_ambient_step(&self, root: Option<usize>, ambient_id: StepId) {
+        if let Err(error) = self.step_manager_for(root).open_step(ambient_id) {
             self.emit(HandlerEvent::ProtocolError {
                 error: format!("failed to open ambient step {ambient_id}: {error}"),
             });
             let mut state = self.state.lock().await;
-            state.ambient_step_id = None;
+            if state.ambient_steps.get(&root).is_some_and(|step| step.step_id == ambient_id) {
+                state.ambient_steps.remove(&root);
+            }
             return;
         }
 
@@ -372,94 +650,57 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
             step_id: ambient_id,
         });
 
-        self.spawn_ambient_timeout_task(ambient_id);
+        self.spawn_ambient_timeout_task(root, ambient_id);
     }
 
-    fn spawn_ambient_timeout_task(&self, ambient_id: StepId) {
-        let step_manager = Arc::clone(&self.step_manager);
-        let config = self.config.clone();
+    fn spawn_ambient_timeout_task(&self, root: Option<usize>, ambient_id: StepId) {
+        let step_manager = Arc::clone(self.step_manager_for(root));
         let state = Arc::clone(&self.state);
         let event_sender = self.event_sender.clone();
         let reset_notify = Arc::clone(&self.ambient_reset_notify);
+        let clock = Arc::clone(&self.clock);
 
         tokio::spawn(async move {
-            let mut deadline =
-                tokio::time::Instant::now() + config.ambient_inactivity_timeout;
-
             loop {
+                let deadline = match state.lock().await.ambient_steps.get(&root) {
+                    Some(step) if step.step_id == ambient_id => step.deadline(),
+                    _ => return, // ambient step was closed (e.g., by exec)
+                };
+
                 tokio::select! {
-                    _ = tokio::time::sleep_until(deadline) => {
-                        // Check that this ambient step is still the active one
+                    _ = clock.sleep_until(deadline) => {
+                        // Check that this ambient step is still the active one,
+                        // and was not written to since the deadline was read
                         let mut state = state.lock().await;
-                        if state.ambient_step_id != Some(ambient_id) {
-                            return; // ambient step was already closed
-                        }
-                        state.ambient_step_id = None;
-                        drop(state);
-
-                        let evicted = match step_manager.close_step(ambient_id) {
-                            Ok(evicted) => evicted,
-                            Err(error) => {
-                                eprintln!(
-                                    "{{\"level\":\"error\",\"component\":\"control\",\"message\":\"failed to close ambient step {ambient_id}: {error}\"}}"
-                                );
-                                let _ = event_sender.send(HandlerEvent::ProtocolError {
-                                    error: format!("failed to close ambient step {ambient_id}: {error}"),
-                                });
-                                vec![]
-                            }
-                        };
-
-                        let _ = event_sender.send(HandlerEvent::AmbientStepClosed {
-                            step_id: ambient_id,
-                            evicted_steps: evicted,
+                        let due = state.ambient_steps.get(&root).is_some_and(|step| {
+                            step.step_id == ambient_id && step.deadline() <= clock.now()
                         });
-                        return;
-                    }
-                    _ = reset_notify.notified() => {
-                        // Check that this ambient step is still the active one
-                        let state = state.lock().await;
-                        if state.ambient_step_id != Some(ambient_id) {
-                            return; // ambient step was closed (e.g., by exec)
+                        if !due {
+                            continue;
                         }
+                        state.ambient_steps.remove(&root);
                         drop(state);
 
-                        // Reset deadline and loop
-                        deadline = tokio::time::Instant::now()
-                            + config.ambient_inactivity_timeout;
+                        close_ambient_step(&*step_manager, &event_sender, ambient_id);
+                        return;
                     }
+                    // A write arrived somewhere: read the deadline again
+                    _ = reset_notify.notified() => {}
                 }
             }
         });
     }
 
-    async fn close_ambient_step_if_open(&self) {
-        let ambient_id = {
-            let mut state = self.state.lock().await;
-            state.ambient_step_id.take()
-        };
-
-        if let Some(ambient_id) = ambient_id {
-            // Notify the ambient timeout task so it exits
-            self.ambient_reset_notify.notify_waiters();
-
-            let evicted = match self.step_manager.close_step(ambient_id) {
-                Ok(evicted) => evicted,
-                Err(error) => {
-                    eprintln!(
-                        "{{\"level\":\"error\",\"component\":\"control\",\"message\":\"failed to close ambient step {ambient_id}: {error}\"}}"
-                    );
-                    self.emit(HandlerEvent::ProtocolError {
-                        error: format!("failed to close ambient step {ambient_id}: {error}"),
-                    });
-                    vec![]
-                }
-            };
+    async fn close_ambient_steps(&self) {
+        let ambient_steps = std::mem::take(&mut self.state.lock().await.ambient_steps);
+        if ambient_steps.is_empty() {
+            return;
+        }
 
-            self.emit(HandlerEvent::AmbientStepClosed {
-                step_id: ambient_id,
-                evicted_steps: evicted,
-            });
+        // Notify the ambient timeout tasks so they exit
+        self.ambient_reset_notify.notify_waiters();
+        for (root, step) in ambient_steps {
+            close_ambient_step(&**self.step_manager_for(root), &self.event_sender, step.step_id);
         }
     }
 
@@ -467,3 +708,28 @@ impl<S: StepManager + 'static> ControlChannelHandler<S> {
         let _ = self.event_sender.send(event);
     }
 }
+
+/// Close ambient step `ambient_id` and report it.
+fn close_ambient_step<S: StepManager + ?Sized>(
+    step_manager: &S,
+    event_sender: &mpsc::UnboundedSender<HandlerEvent>,
+    ambient_id: StepId,
+) {
+    let evicted = match step_manager.close_step(ambient_id) {
+        Ok(evicted) => evicted,
+        Err(error) => {
+            eprintln!(
+                "{{\"level\":\"error\",\"component\":\"control\",\"message\":\"failed to close ambient step {ambient_id}: {error}\"}}"
+            );
+            let _ = event_sender.send(HandlerEvent::ProtocolError {
+                error: format!("failed to close ambient step {ambient_id}: {error}"),
+            });
+            vec![]
+        }
+    };
+
+    let _ = event_sender.send(HandlerEvent::AmbientStepClosed {
+        step_id: ambient_id,
+        evicted_steps: evicted,
+    });
+}
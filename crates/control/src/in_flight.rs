@@ -1,9 +1,12 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use tokio::sync::Notify;
 
+use crate::clock::{Clock, TokioClock};
+
 /// Tracks the number of in-flight filesystem operations.
 ///
 /// The filesystem backend calls [`begin_operation`] when it starts handling a
@@ -13,32 +16,85 @@ use tokio::sync::Notify;
 ///
 /// This type is cheaply cloneable — the handler and filesystem backend each
 /// hold their own clone, sharing the same underlying counter and notifier.
+///
+/// A clone made with [`for_root`](Self::for_root) also counts its operations
+/// against one working root, so a wait can cover only the roots a step was
+/// active in ([`DrainScope::Roots`]). Operations of an unscoped tracker are
+/// covered by every wait.
 #[derive(Clone)]
 pub struct InFlightTracker {
     count: Arc<AtomicUsize>,
     drain_notify: Arc<Notify>,
+    roots: Arc<Mutex<BTreeMap<usize, Arc<RootCounter>>>>,
+    /// The root this clone's operations count against, if any.
+    root: Option<Arc<RootCounter>>,
+}
+
+/// Operations of one working root.
+#[derive(Default)]
+struct RootCounter {
+    in_flight: AtomicUsize,
+    /// Operations ever begun, to tell which roots were active when.
+    started: AtomicU64,
+}
+
+/// Which in-flight operations a wait for drain covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DrainScope {
+    /// Every operation.
+    All,
+    /// Operations in these working roots, and those of no root.
+    Roots(Vec<usize>),
 }
 
+/// The operations begun in each working root up to some point, from
+/// [`InFlightTracker::mark`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActivityMark(BTreeMap<usize, u64>);
+
 impl InFlightTracker {
     pub fn new() -> Self {
         Self {
             count: Arc::new(AtomicUsize::new(0)),
             drain_notify: Arc::new(Notify::new()),
+            roots: Arc::new(Mutex::new(BTreeMap::new())),
+            root: None,
+        }
+    }
+
+    /// A clone whose operations also count against working root `root`
+    /// (the index of its working directory), for that root's backend.
+    pub fn for_root(&self, root: usize) -> Self {
+        let counter = Arc::clone(self.roots.lock().unwrap().entry(root).or_default());
+        Self {
+            root: Some(counter),
+            ..self.clone()
         }
     }
 
     /// Called by the filesystem backend when it begins handling a request.
     pub fn begin_operation(&self) {
+        // The total counts an operation before its root does and stops
+        // after, so it is never below the roots' sum.
         self.count.fetch_add(1, Ordering::SeqCst);
+        if let Some(root) = &self.root {
+            root.started.fetch_add(1, Ordering::SeqCst);
+            root.in_flight.fetch_add(1, Ordering::SeqCst);
+        }
     }
 
     /// Called by the filesystem backend when it finishes handling a request.
     ///
-    /// If the count reaches zero, any task waiting in [`wait_for_drain`] is woken.
+    /// If the count, or that of the operation's root, reaches zero, any task
+    /// waiting in [`wait_for_drain`] is woken.
     pub fn end_operation(&self) {
+        let root_drained = self
+            .root
+            .as_ref()
+            .is_some_and(|root| root.in_flight.fetch_sub(1, Ordering::SeqCst) == 1);
         let previous = self.count.fetch_sub(1, Ordering::SeqCst);
         debug_assert!(previous > 0, "end_operation called more times than begin_operation");
-        if previous == 1 {
+        if previous == 1 || root_drained {
             self.drain_notify.notify_waiters();
         }
     }
@@ -48,29 +104,94 @@ impl InFlightTracker {
         self.count.load(Ordering::SeqCst)
     }
 
+    /// Returns the number of in-flight operations `scope` covers.
+    pub fn count_in(&self, scope: &DrainScope) -> usize {
+        let DrainScope::Roots(relevant) = scope else {
+            return self.count();
+        };
+        let roots = self.roots.lock().unwrap();
+        let mut rooted = 0;
+        let mut covered = 0;
+        for (root, counter) in roots.iter() {
+            let in_flight = counter.in_flight.load(Ordering::SeqCst);
+            rooted += in_flight;
+            if relevant.contains(root) {
+                covered += in_flight;
+            }
+        }
+        // An operation ending between the reads may have left the total
+        // but still be in `rooted`.
+        covered + self.count().saturating_sub(rooted)
+    }
+
+    /// The operations begun in each root so far, for [`active_since`].
+    ///
+    /// [`active_since`]: Self::active_since
+    pub fn mark(&self) -> ActivityMark {
+        let roots = self.roots.lock().unwrap();
+        ActivityMark(
+            roots
+                .iter()
+                .map(|(root, counter)| (*root, counter.started.load(Ordering::SeqCst)))
+                .collect(),
+        )
+    }
+
+    /// The roots an operation began in since `mark`.
+    pub fn active_since(&self, mark: &ActivityMark) -> DrainScope {
+        let roots = self.roots.lock().unwrap();
+        DrainScope::Roots(
+            roots
+                .iter()
+                .filter(|(root, counter)| {
+                    counter.started.load(Ordering::SeqCst)
+                        > mark.0.get(root).copied().unwrap_or(0)
+                })
+                .map(|(root, _)| *root)
+                .collect(),
+        )
+    }
+
     /// Wait until the in-flight count reaches zero or `timeout` elapses.
     ///
     /// Returns `true` if the count drained to zero, `false` on timeout.
     /// Compatible with `tokio::time::pause()` for deterministic tests.
     pub async fn wait_for_drain(&self, timeout: Duration) -> bool {
-        if self.count() == 0 {
+        self.wait_for_drain_on(&TokioClock, timeout).await
+    }
+
+    /// [`wait_for_drain`](Self::wait_for_drain), timed by `clock`.
+    pub async fn wait_for_drain_on(&self, clock: &dyn Clock, timeout: Duration) -> bool {
+        self.wait_for_drain_in_on(clock, &DrainScope::All, timeout).await
+    }
+
+    /// [`wait_for_drain_on`](Self::wait_for_drain_on), for only the
+    /// operations `scope` covers.
+    pub async fn wait_for_drain_in_on(
+        &self,
+        clock: &dyn Clock,
+        scope: &DrainScope,
+        timeout: Duration,
+    ) -> bool {
+        if self.count_in(scope) == 0 {
             return true;
         }
 
         tokio::select! {
-            _ = self.wait_for_zero() => true,
-            _ = tokio::time::sleep(timeout) => self.count() == 0,
+            _ = self.wait_for_zero(scope) => true,
+            _ = clock.sleep(timeout) => self.count_in(scope) == 0,
         }
     }
 
-    /// Internal: loops until count reaches zero, using Notify to avoid polling.
-    async fn wait_for_zero(&self) {
+    /// Internal: loops until the count of `scope` reaches zero, using Notify
+    /// to avoid polling.
+    async fn wait_for_zero(&self, scope: &DrainScope) {
         loop {
-            if self.count() == 0 {
+            if self.count_in(scope) == 0 {
                 return;
             }
             self.drain_notify.notified().await;
-            if self.count() == 0 {
+            if self.count_in(scope) == 0 {
                 return;
             }
         }
@@ -150,6 +271,62 @@ mod tests {
         assert!(drained);
     }
 
+    #[test]
+    fn root_clones_count_against_their_root() {
+        let tracker = InFlightTracker::new();
+        let first = tracker.for_root(0);
+        let second = tracker.for_root(1);
+        first.begin_operation();
+        second.begin_operation();
+        tracker.begin_operation();
+        assert_eq!(tracker.count(), 3);
+        assert_eq!(tracker.count_in(&DrainScope::Roots(vec![0])), 2);
+        assert_eq!(tracker.count_in(&DrainScope::Roots(vec![])), 1);
+        tracker.end_operation();
+        first.end_operation();
+        assert_eq!(tracker.count_in(&DrainScope::Roots(vec![0])), 0);
+        assert_eq!(tracker.count_in(&DrainScope::All), 1);
+        second.end_operation();
+    }
+
+    #[test]
+    fn active_since_lists_roots_with_new_operations() {
+        let tracker = InFlightTracker::new();
+        let first = tracker.for_root(0);
+        let second = tracker.for_root(1);
+        second.begin_operation();
+        let mark = tracker.mark();
+        assert_eq!(tracker.active_since(&mark), DrainScope::Roots(vec![]));
+        first.begin_operation();
+        first.end_operation();
+        let third = tracker.for_root(2);
+        third.begin_operation();
+        assert_eq!(tracker.active_since(&mark), DrainScope::Roots(vec![0, 2]));
+        second.end_operation();
+        third.end_operation();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scoped_drain_ignores_other_roots() {
+        let tracker = InFlightTracker::new();
+        let first = tracker.for_root(0);
+        let second = tracker.for_root(1);
+        first.begin_operation();
+        second.begin_operation();
+
+        let scope = DrainScope::Roots(vec![0]);
+        let waiter = tracker.clone();
+        let handle = tokio::spawn(async move {
+            waiter.wait_for_drain_in_on(&TokioClock, &scope, Duration::from_secs(10)).await
+        });
+        tokio::task::yield_now().await;
+
+        first.end_operation();
+        assert!(handle.await.unwrap());
+        assert_eq!(tracker.count(), 1);
+        second.end_operation();
+    }
+
     #[tokio::test(start_paused = true)]
     async fn wait_for_drain_times_out() {
         let tracker = InFlightTracker::new();
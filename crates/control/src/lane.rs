@@ -0,0 +1,155 @@
+//! Priority lanes for control channel writers.
+//!
+//! Both ends of the channel write from a single queue. Without lanes, a
+//! `cancel` queued behind megabytes of `input`, or a `pid_resolved` behind a
+//! command's output backlog, waits until all of it is written. Each writer
+//! instead drains two queues: the priority lane whenever it has anything,
+//! the bulk lane otherwise. Messages keep their order within a lane.
+//!
+//! Only messages whose order relative to bulk traffic does not matter are
+//! priority: `step_completed` stays behind its command's output, and `input`
+//! behind the `exec` it follows is guaranteed by `exec` being priority.
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+
+use crate::protocol::{HostMessage, VmMessage};
+
+/// A message that knows which lane it travels in.
+pub trait Prioritized {
+    fn is_priority(&self) -> bool;
+}
+
+impl Prioritized for HostMessage {
+    fn is_priority(&self) -> bool {
+        !matches!(self, HostMessage::Input { .. })
+    }
+}
+
+impl Prioritized for VmMessage {
+    fn is_priority(&self) -> bool {
+        !matches!(self, VmMessage::Output { .. } | VmMessage::StepCompleted { .. })
+    }
+}
+
+/// The sending half of a pair of lanes. Clones feed the same lanes.
+#[derive(Debug)]
+pub struct LaneSender<T> {
+    priority: mpsc::UnboundedSender<T>,
+    bulk: mpsc::UnboundedSender<T>,
+}
+
+impl<T> Clone for LaneSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            priority: self.priority.clone(),
+            bulk: self.bulk.clone(),
+        }
+    }
+}
+
+impl<T: Prioritized> LaneSender<T> {
+    /// Queue `message` in the lane it belongs to.
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        if message.is_priority() {
+            self.priority.send(message)
+        } else {
+            self.bulk.send(message)
+        }
+    }
+}
+
+impl<T> LaneSender<T> {
+    /// Whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.priority.is_closed()
+    }
+}
+
+/// The receiving half of a pair of lanes.
+#[derive(Debug)]
+pub struct LaneReceiver<T> {
+    priority: mpsc::UnboundedReceiver<T>,
+    bulk: mpsc::UnboundedReceiver<T>,
+}
+
+impl<T> LaneReceiver<T> {
+    /// The next message, from the priority lane if it has one. `None` once
+    /// every sender is dropped and both lanes are empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        tokio::select! {
+            biased;
+            Some(message) = self.priority.recv() => Some(message),
+            Some(message) = self.bulk.recv() => Some(message),
+            else => None,
+        }
+    }
+
+    /// The next message if one is queued, without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.priority.try_recv().or_else(|_| self.bulk.try_recv()).ok()
+    }
+}
+
+/// Create a pair of unbounded lanes.
+pub fn lane_channel<T>() -> (LaneSender<T>, LaneReceiver<T>) {
+    let (priority, priority_receiver) = mpsc::unbounded_channel();
+    let (bulk, bulk_receiver) = mpsc::unbounded_channel();
+    (
+        LaneSender { priority, bulk },
+        LaneReceiver {
+            priority: priority_receiver,
+            bulk: bulk_receiver,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::OutputStream;
+
+    #[tokio::test]
+    async fn priority_messages_overtake_bulk() {
+        let (sender, mut receiver) = lane_channel();
+        for n in 0..3 {
+            sender
+                .send(HostMessage::Input {
+                    id: 1,
+                    data: n.to_string(),
+                    eof: false,
+                })
+                .unwrap();
+        }
+        sender.send(HostMessage::Cancel { id: 1 }).unwrap();
+
+        assert_eq!(receiver.recv().await, Some(HostMessage::Cancel { id: 1 }));
+        for n in 0..3 {
+            let Some(HostMessage::Input { data, .. }) = receiver.recv().await else {
+                panic!("expected input {n}");
+            };
+            assert_eq!(data, n.to_string());
+        }
+        drop(sender);
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[test]
+    fn completion_stays_behind_output() {
+        let output = VmMessage::Output {
+            id: 1,
+            stream: OutputStream::Stdout,
+            data: String::new(),
+        };
+        assert!(!output.is_priority());
+        let completed = VmMessage::StepCompleted {
+            id: 1,
+            exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
+        };
+        assert!(!completed.is_priority());
+        assert!(VmMessage::StepStarted { id: 1 }.is_priority());
+        assert!(VmMessage::PidResolved { pid: 2, id: None, name: None }.is_priority());
+    }
+}
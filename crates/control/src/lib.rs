@@ -1,14 +1,26 @@
+pub mod attribution;
+pub mod clock;
 mod error;
 pub mod handler;
 pub mod in_flight;
+pub mod lane;
+pub mod link;
 mod parser;
 mod protocol;
+pub mod schema;
 mod state_machine;
 
+pub use attribution::PidAttribution;
+pub use clock::{Clock, ManualClock, TokioClock};
 pub use error::ControlChannelError;
 pub use codeagent_common::StepManager;
 pub use handler::{ControlChannelHandler, HandlerEvent, QuiescenceConfig};
-pub use in_flight::InFlightTracker;
+pub use in_flight::{ActivityMark, DrainScope, InFlightTracker};
+pub use lane::{LaneReceiver, LaneSender, Prioritized, lane_channel};
+pub use link::{Incoming, Link, LinkState};
 pub use parser::{parse_host_message, parse_vm_message, MAX_MESSAGE_SIZE};
-pub use protocol::{HostMessage, OutputStream, VmMessage};
+pub use protocol::{
+    Frame, Hello, HostMessage, MountFailure, OutputStream, PROTOCOL_VERSIONS, ResourceLimit,
+    ResourceLimits, VmMessage,
+};
 pub use state_machine::{ActiveCommand, ControlChannelState, ControlEvent, PendingCommand};
@@ -0,0 +1,505 @@
+//! Sequenced delivery over the control channel (protocol version 2).
+//!
+//! A line lost on the way through virtio-serial used to take its `output` or
+//! `step_completed` message with it, and nothing noticed. Once both ends
+//! agree on version 2 (see [`Hello`]), every message travels in a [`Frame`]
+//! with a sequence number. The receiver delivers frames in order, drops
+//! duplicates, and asks for a resend from the first missing number when a
+//! later frame shows a gap. The sender keeps unacknowledged frames in a
+//! bounded window and sends all of them again when nothing has been
+//! acknowledged for [`RESEND_TIMEOUT`], which also covers a lost last line.
+//!
+//! Whatever was negotiated, receivers accept bare version 1 lines as well as
+//! frames, so lines written while the two ends switch are never misread.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::error::ControlChannelError;
+use crate::lane::LaneReceiver;
+use crate::parser::{MAX_MESSAGE_SIZE, parse_message, truncate_for_display};
+use crate::protocol::{Frame, Hello, PROTOCOL_VERSIONS};
+
+/// How long unacknowledged frames wait before they are all sent again.
+pub const RESEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a receiver waits for an outgoing frame to carry its
+/// acknowledgement before sending one on its own.
+pub const ACK_DELAY: Duration = Duration::from_millis(10);
+
+/// How many bytes of unacknowledged frames a sender keeps. Older frames are
+/// dropped past this and can no longer be sent again.
+pub const RESEND_WINDOW_BYTES: usize = 8 * 1024 * 1024;
+
+/// Room for the frame around a message of [`MAX_MESSAGE_SIZE`].
+const FRAME_OVERHEAD: usize = 128;
+
+const HELLO_PREFIX: &str = r#"{"type":"hello""#;
+const FRAME_PREFIXES: [&str; 3] = [r#"{"seq""#, r#"{"ack""#, r#"{"resend""#];
+
+/// What a received line amounts to.
+#[derive(Debug)]
+pub enum Incoming<T> {
+    /// The next message, in order.
+    Message(T),
+    /// Nothing to deliver: a line for the link itself, a duplicate, or a
+    /// frame past a gap that will be sent again.
+    Nothing,
+    /// A line that could not be read.
+    Invalid(ControlChannelError),
+}
+
+/// One end's side of the link, without any I/O.
+#[derive(Debug)]
+pub struct LinkState {
+    version: u32,
+    /// Whether this end offered versions and is waiting for the answer.
+    offered: bool,
+    next_seq: u64,
+    /// Sent frames not yet acknowledged, oldest first.
+    window: VecDeque<(u64, String)>,
+    window_bytes: usize,
+    /// When the window last moved, or was last sent again.
+    last_progress: Instant,
+    /// The sequence number of the next frame to deliver.
+    expected: u64,
+    ack_due: Option<Instant>,
+    /// The gap already asked about, so each is asked about once.
+    resend_requested: Option<u64>,
+    /// Lines to write before the next message.
+    pending: Vec<String>,
+}
+
+impl Default for LinkState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkState {
+    pub fn new() -> Self {
+        Self {
+            version: 1,
+            offered: false,
+            next_seq: 1,
+            window: VecDeque::new(),
+            window_bytes: 0,
+            last_progress: Instant::now(),
+            expected: 1,
+            ack_due: None,
+            resend_requested: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The version in use.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Start over at version 1 and queue an offer of every version.
+    pub fn offer(&mut self) {
+        *self = Self::new();
+        self.offered = true;
+        self.pending.push(hello_line(PROTOCOL_VERSIONS.to_vec()));
+    }
+
+    /// The lines that send `message`: whatever the link has queued, then the
+    /// message itself, framed once version 2 is in use.
+    pub fn encode<T: Serialize>(
+        &mut self,
+        message: &T,
+        now: Instant,
+    ) -> Result<Vec<String>, serde_json::Error> {
+        let mut lines = std::mem::take(&mut self.pending);
+        if self.version < 2 {
+            lines.push(serde_json::to_string(message)?);
+            return Ok(lines);
+        }
+
+        let seq = self.next_seq;
+        let line = serde_json::to_string(&Frame {
+            seq: Some(seq),
+            ack: self.take_ack(),
+            resend: None,
+            msg: Some(message),
+        })?;
+        self.next_seq += 1;
+        if self.window.is_empty() {
+            self.last_progress = now;
+        }
+        self.window_bytes += line.len();
+        self.window.push_back((seq, line.clone()));
+        while self.window_bytes > RESEND_WINDOW_BYTES && self.window.len() > 1 {
+            if let Some((_, dropped)) = self.window.pop_front() {
+                self.window_bytes -= dropped.len();
+            }
+        }
+        lines.push(line);
+        Ok(lines)
+    }
+
+    /// Read one received line.
+    pub fn decode<T: DeserializeOwned>(&mut self, line: &str, now: Instant) -> Incoming<T> {
+        // The guest supervisor starts its messages with a newline.
+        if line.is_empty() {
+            return Incoming::Nothing;
+        }
+        if line.starts_with(HELLO_PREFIX) {
+            return match serde_json::from_str::<Hello>(line) {
+                Ok(hello) => {
+                    self.receive_hello(hello);
+                    Incoming::Nothing
+                }
+                Err(source) => Incoming::Invalid(ControlChannelError::MalformedJson { source }),
+            };
+        }
+        if !FRAME_PREFIXES.iter().any(|prefix| line.starts_with(prefix)) {
+            return match parse_message(line) {
+                Ok(message) => Incoming::Message(message),
+                Err(error) => Incoming::Invalid(error),
+            };
+        }
+
+        if line.len() > MAX_MESSAGE_SIZE + FRAME_OVERHEAD {
+            return Incoming::Invalid(ControlChannelError::OversizedMessage {
+                max_size: MAX_MESSAGE_SIZE,
+                actual_size: line.len(),
+            });
+        }
+        let frame: Frame<serde_json::Value> = match serde_json::from_str(line) {
+            Ok(frame) => frame,
+            Err(source) => return Incoming::Invalid(ControlChannelError::MalformedJson { source }),
+        };
+        if let Some(ack) = frame.ack {
+            self.acknowledged(ack, now);
+        }
+        if let Some(from) = frame.resend {
+            self.resend_from(from, now);
+        }
+        let Some(seq) = frame.seq else {
+            return Incoming::Nothing;
+        };
+
+        if seq < self.expected {
+            // Sent again before our acknowledgement got there.
+            self.ack_soon(now);
+            return Incoming::Nothing;
+        }
+        if seq > self.expected {
+            if self.version >= 2 && self.resend_requested != Some(self.expected) {
+                self.resend_requested = Some(self.expected);
+                let ack = self.take_ack();
+                self.pending.push(frame_line(Frame::control(ack, Some(self.expected))));
+            }
+            return Incoming::Nothing;
+        }
+
+        self.expected += 1;
+        self.resend_requested = None;
+        self.ack_soon(now);
+        let Some(message) = frame.msg else {
+            return Incoming::Nothing;
+        };
+        match T::deserialize(&message) {
+            Ok(message) => Incoming::Message(message),
+            Err(_) if message.get("type").is_some() => {
+                Incoming::Invalid(ControlChannelError::UnknownMessageType {
+                    line: truncate_for_display(line),
+                })
+            }
+            Err(source) => Incoming::Invalid(ControlChannelError::MalformedJson { source }),
+        }
+    }
+
+    /// Lines the link itself needs written by `now`: answers, resends, and
+    /// acknowledgements that no message has carried yet.
+    pub fn control_lines(&mut self, now: Instant) -> Vec<String> {
+        let mut lines = std::mem::take(&mut self.pending);
+        if self.version < 2 {
+            return lines;
+        }
+        if !self.window.is_empty() && now >= self.last_progress + RESEND_TIMEOUT {
+            lines.extend(self.window.iter().map(|(_, line)| line.clone()));
+            self.last_progress = now;
+        }
+        if self.ack_due.is_some_and(|due| now >= due) {
+            let ack = self.take_ack();
+            lines.push(frame_line(Frame::control(ack, None)));
+        }
+        lines
+    }
+
+    /// When [`control_lines`](Self::control_lines) next has something to
+    /// write, if ever.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if !self.pending.is_empty() {
+            return Some(Instant::now());
+        }
+        if self.version < 2 {
+            return None;
+        }
+        let resend = (!self.window.is_empty()).then(|| self.last_progress + RESEND_TIMEOUT);
+        match (resend, self.ack_due) {
+            (Some(resend), Some(ack)) => Some(resend.min(ack)),
+            (resend, ack) => resend.or(ack),
+        }
+    }
+
+    /// How many sent frames are still unacknowledged.
+    pub fn unacknowledged(&self) -> usize {
+        self.window.len()
+    }
+
+    fn receive_hello(&mut self, hello: Hello) {
+        let chosen = hello
+            .versions
+            .iter()
+            .copied()
+            .filter(|version| PROTOCOL_VERSIONS.contains(version))
+            .max()
+            .unwrap_or(1);
+        let answer = !self.offered;
+        let pending = std::mem::take(&mut self.pending);
+        *self = Self::new();
+        self.version = chosen;
+        self.pending = pending;
+        if answer {
+            self.pending.push(hello_line(vec![chosen]));
+        }
+    }
+
+    fn acknowledged(&mut self, ack: u64, now: Instant) {
+        let mut moved = false;
+        while self.window.front().is_some_and(|(seq, _)| *seq <= ack) {
+            if let Some((_, line)) = self.window.pop_front() {
+                self.window_bytes -= line.len();
+                moved = true;
+            }
+        }
+        if moved {
+            self.last_progress = now;
+        }
+    }
+
+    fn resend_from(&mut self, from: u64, now: Instant) {
+        if self.version < 2 {
+            return;
+        }
+        let lines = self.window.iter().filter(|(seq, _)| *seq >= from);
+        self.pending.extend(lines.map(|(_, line)| line.clone()));
+        self.last_progress = now;
+    }
+
+    fn ack_soon(&mut self, now: Instant) {
+        if self.version >= 2 && self.ack_due.is_none() {
+            self.ack_due = Some(now + ACK_DELAY);
+        }
+    }
+
+    fn take_ack(&mut self) -> Option<u64> {
+        self.ack_due = None;
+        (self.expected > 1).then(|| self.expected - 1)
+    }
+}
+
+fn hello_line(versions: Vec<u32>) -> String {
+    serde_json::to_string(&Hello { versions }).expect("hello serializes")
+}
+
+fn frame_line(frame: Frame<()>) -> String {
+    serde_json::to_string(&frame).expect("control frame serializes")
+}
+
+/// One end of the link, shared by the task that writes the channel and the
+/// one that reads it.
+#[derive(Debug, Clone, Default)]
+pub struct Link {
+    state: Arc<Mutex<LinkState>>,
+    /// Tells the writer the reader queued lines for it.
+    wake: Arc<Notify>,
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The version in use.
+    pub fn version(&self) -> u32 {
+        self.state.lock().unwrap().version()
+    }
+
+    /// Offer every version to the other end, starting over at version 1
+    /// until it answers.
+    pub fn offer(&self) {
+        self.state.lock().unwrap().offer();
+        self.wake.notify_one();
+    }
+
+    /// Read one received line, waking the writer if the link now has
+    /// something to send.
+    pub fn decode<T: DeserializeOwned>(&self, line: &str) -> Incoming<T> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.next_deadline();
+        let incoming = state.decode(line, Instant::now());
+        if state.next_deadline() != before {
+            self.wake.notify_one();
+        }
+        incoming
+    }
+
+    /// Write `messages` to `writer` until every sender is gone, along with
+    /// whatever the link needs to send.
+    pub async fn run_writer<T, W>(&self, mut messages: LaneReceiver<T>, writer: W) -> io::Result<()>
+    where
+        T: Serialize,
+        W: AsyncWrite + Unpin,
+    {
+        let mut writer = BufWriter::new(writer);
+        loop {
+            let deadline = self.state.lock().unwrap().next_deadline();
+            let lines = tokio::select! {
+                biased;
+                _ = self.wake.notified() => {
+                    self.state.lock().unwrap().control_lines(Instant::now())
+                }
+                _ = sleep_until(deadline) => {
+                    self.state.lock().unwrap().control_lines(Instant::now())
+                }
+                message = messages.recv() => match message {
+                    Some(message) => self
+                        .state
+                        .lock()
+                        .unwrap()
+                        .encode(&message, Instant::now())
+                        .map_err(io::Error::other)?,
+                    None => return Ok(()),
+                },
+            };
+            if lines.is_empty() {
+                continue;
+            }
+            for line in &lines {
+                writer.write_all(line.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+            }
+            writer.flush().await?;
+        }
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{HostMessage, VmMessage};
+
+    /// A host and a shim that have agreed on version 2.
+    fn negotiated(now: Instant) -> (LinkState, LinkState) {
+        let mut host = LinkState::new();
+        let mut shim = LinkState::new();
+        host.offer();
+        for line in host.control_lines(now) {
+            assert!(matches!(shim.decode::<HostMessage>(&line, now), Incoming::Nothing));
+        }
+        for line in shim.control_lines(now) {
+            assert!(matches!(host.decode::<VmMessage>(&line, now), Incoming::Nothing));
+        }
+        assert_eq!((host.version(), shim.version()), (2, 2));
+        (host, shim)
+    }
+
+    fn cancel(id: u64) -> HostMessage {
+        HostMessage::Cancel { id }
+    }
+
+    #[test]
+    fn unanswered_offers_stay_at_version_one() {
+        let now = Instant::now();
+        let mut host = LinkState::new();
+        host.offer();
+        let lines = host.encode(&cancel(1), now).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], r#"{"type":"cancel","id":1}"#);
+        assert_eq!(host.next_deadline(), None);
+
+        // A version 1 shim reports the offer and reads the message.
+        assert!(crate::parse_host_message(&lines[0]).is_err());
+        assert_eq!(crate::parse_host_message(&lines[1]).unwrap(), cancel(1));
+    }
+
+    #[test]
+    fn gaps_are_filled_in_order() {
+        let now = Instant::now();
+        let (mut host, mut shim) = negotiated(now);
+        let lines: Vec<String> = (1..=3)
+            .flat_map(|id| host.encode(&cancel(id), now).unwrap())
+            .collect();
+
+        let first = shim.decode::<HostMessage>(&lines[0], now);
+        assert!(matches!(first, Incoming::Message(message) if message == cancel(1)));
+        // The second line is lost.
+        assert!(matches!(shim.decode::<HostMessage>(&lines[2], now), Incoming::Nothing));
+        let request = shim.control_lines(now);
+        assert_eq!(request, vec![r#"{"ack":1,"resend":2}"#.to_string()]);
+
+        host.decode::<VmMessage>(&request[0], now);
+        assert_eq!(host.unacknowledged(), 2);
+        let resent = host.control_lines(now);
+        assert_eq!(resent, lines[1..].to_vec());
+        let delivered: Vec<HostMessage> = resent
+            .iter()
+            .filter_map(|line| match shim.decode(line, now) {
+                Incoming::Message(message) => Some(message),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(delivered, vec![cancel(2), cancel(3)]);
+
+        // A late duplicate is dropped and acknowledged.
+        assert!(matches!(shim.decode::<HostMessage>(&lines[2], now), Incoming::Nothing));
+        let ack = shim.control_lines(now + ACK_DELAY);
+        assert_eq!(ack, vec![r#"{"ack":3}"#.to_string()]);
+        host.decode::<VmMessage>(&ack[0], now);
+        assert_eq!(host.unacknowledged(), 0);
+        assert_eq!(host.next_deadline(), None);
+    }
+
+    #[test]
+    fn unacknowledged_frames_are_sent_again() {
+        let now = Instant::now();
+        let (mut host, _shim) = negotiated(now);
+        let sent = host.encode(&cancel(1), now).unwrap();
+        assert_eq!(host.next_deadline(), Some(now + RESEND_TIMEOUT));
+        assert!(host.control_lines(now).is_empty());
+        assert_eq!(host.control_lines(now + RESEND_TIMEOUT), sent);
+    }
+
+    #[test]
+    fn unknown_framed_messages_keep_their_place() {
+        let now = Instant::now();
+        let (_host, mut shim) = negotiated(now);
+        let line = r#"{"seq":1,"msg":{"type":"teleport","id":1}}"#;
+        assert!(matches!(
+            shim.decode::<HostMessage>(line, now),
+            Incoming::Invalid(ControlChannelError::UnknownMessageType { .. })
+        ));
+        let next = r#"{"seq":2,"msg":{"type":"cancel","id":1}}"#;
+        assert!(matches!(shim.decode::<HostMessage>(next, now), Incoming::Message(_)));
+    }
+}
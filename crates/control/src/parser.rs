@@ -1,3 +1,5 @@
+use serde::de::DeserializeOwned;
+
 use crate::error::ControlChannelError;
 use crate::protocol::{HostMessage, VmMessage};
 
@@ -9,28 +11,18 @@ pub const MAX_MESSAGE_SIZE: usize = 1_048_576;
 /// Rejects oversized messages before attempting deserialization (CC-04).
 /// Returns `UnknownMessageType` for valid JSON with an unrecognized `type` field (CC-03).
 pub fn parse_vm_message(line: &str) -> Result<VmMessage, ControlChannelError> {
-    if line.len() > MAX_MESSAGE_SIZE {
-        return Err(ControlChannelError::OversizedMessage {
-            max_size: MAX_MESSAGE_SIZE,
-            actual_size: line.len(),
-        });
-    }
-
-    serde_json::from_str::<VmMessage>(line).map_err(|source| {
-        if is_unknown_type_error(line, &source) {
-            ControlChannelError::UnknownMessageType {
-                line: truncate_for_display(line),
-            }
-        } else {
-            ControlChannelError::MalformedJson { source }
-        }
-    })
+    parse_message(line)
 }
 
 /// Parse a single JSONL line as a host→VM message.
 ///
 /// Rejects oversized messages before attempting deserialization.
 pub fn parse_host_message(line: &str) -> Result<HostMessage, ControlChannelError> {
+    parse_message(line)
+}
+
+/// Parse a single JSONL line as a message of either direction.
+pub(crate) fn parse_message<T: DeserializeOwned>(line: &str) -> Result<T, ControlChannelError> {
     if line.len() > MAX_MESSAGE_SIZE {
         return Err(ControlChannelError::OversizedMessage {
             max_size: MAX_MESSAGE_SIZE,
@@ -38,7 +30,7 @@ pub fn parse_host_message(line: &str) -> Result<HostMessage, ControlChannelError
         });
     }
 
-    serde_json::from_str::<HostMessage>(line).map_err(|source| {
+    serde_json::from_str::<T>(line).map_err(|source| {
         if is_unknown_type_error(line, &source) {
             ControlChannelError::UnknownMessageType {
                 line: truncate_for_display(line),
@@ -63,7 +55,7 @@ fn is_unknown_type_error(line: &str, _source: &serde_json::Error) -> bool {
 }
 
 /// Truncate a line for inclusion in error messages, to avoid storing oversized input.
-fn truncate_for_display(line: &str) -> String {
+pub(crate) fn truncate_for_display(line: &str) -> String {
     const MAX_DISPLAY_LEN: usize = 200;
     if line.len() <= MAX_DISPLAY_LEN {
         line.to_string()
@@ -75,7 +67,7 @@ fn truncate_for_display(line: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::protocol::OutputStream;
+    use crate::protocol::{OutputStream, ResourceLimits};
 
     #[test]
     fn parse_valid_vm_step_started() {
@@ -102,7 +94,15 @@ mod tests {
     fn parse_valid_vm_step_completed() {
         let line = r#"{"type":"step_completed","id":42,"exit_code":1}"#;
         let msg = parse_vm_message(line).unwrap();
-        assert_eq!(msg, VmMessage::StepCompleted { id: 42, exit_code: 1 });
+        assert_eq!(
+            msg,
+            VmMessage::StepCompleted {
+                id: 42,
+                exit_code: 1,
+                output_truncated: false,
+                limit_exceeded: None,
+            }
+        );
     }
 
     #[test]
@@ -137,6 +137,10 @@ mod tests {
                 command: "ls -la".to_string(),
                 env: None,
                 cwd: Some("/tmp".to_string()),
+                isolate_fs: false,
+                pty: false,
+                max_output_bytes: None,
+                limits: ResourceLimits::default(),
             }
         );
     }
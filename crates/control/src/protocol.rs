@@ -18,6 +18,33 @@ pub enum HostMessage {
         env: Option<HashMap<String, String>>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         cwd: Option<String>,
+        /// Run in a private overlay over the working directory that is
+        /// merged back only if the command exits with status 0.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        isolate_fs: bool,
+        /// Run on a pseudo-terminal instead of pipes, for interactive tools.
+        /// All output is then reported as stdout.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pty: bool,
+        /// Stop forwarding output once the command has produced this many
+        /// bytes across stdout and stderr. The rest is read and dropped,
+        /// and `step_completed` reports `output_truncated`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_output_bytes: Option<u64>,
+        /// Resources the command may use; unlimited when empty.
+        #[serde(default, skip_serializing_if = "ResourceLimits::is_empty")]
+        limits: ResourceLimits,
+    },
+
+    /// Write to the stdin (or terminal) of a running command.
+    #[serde(rename = "input")]
+    Input {
+        id: u64,
+        data: String,
+        /// Close stdin after `data`. On a terminal this sends the
+        /// end-of-file character instead.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        eof: bool,
     },
 
     /// Cancel a running command (SIGTERM → SIGKILL).
@@ -27,6 +54,18 @@ pub enum HostMessage {
     /// Inform the VM-side agent that a rollback occurred.
     #[serde(rename = "rollback_notify")]
     RollbackNotify { step_id: u64 },
+
+    /// Ask which running command guest process `pid` belongs to, so its
+    /// filesystem operations can be recorded in that command's step.
+    #[serde(rename = "resolve_pid")]
+    ResolvePid { pid: u32 },
+
+    /// Mount the virtiofs shares `tags` at `/mnt/working/<tag>`. Sent to a
+    /// VM booted without working directories (see the sandbox's warm pool)
+    /// once the shares are hot-plugged; commands sent after it find them
+    /// mounted.
+    #[serde(rename = "mount")]
+    Mount { tags: Vec<String> },
 }
 
 /// Messages sent from VM to host over the control channel.
@@ -49,7 +88,150 @@ pub enum VmMessage {
 
     /// Command finished — host should close the current undo step.
     #[serde(rename = "step_completed")]
-    StepCompleted { id: u64, exit_code: i32 },
+    StepCompleted {
+        id: u64,
+        exit_code: i32,
+        /// Output was cut short at the `max_output_bytes` of `exec`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        output_truncated: bool,
+        /// The command was stopped by one of the `limits` of `exec`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit_exceeded: Option<ResourceLimit>,
+    },
+
+    /// Answer to `resolve_pid`: the command `pid` belongs to, if any, and
+    /// the process's name (`/proc/<pid>/comm`) if it still runs.
+    #[serde(rename = "pid_resolved")]
+    PidResolved {
+        pid: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+
+    /// Sent by the guest supervisor, not the shim: the shim exited
+    /// abnormally and a new one was started. Every command the old shim was
+    /// running is gone.
+    #[serde(rename = "shim_restarted")]
+    ShimRestarted {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exit_code: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signal: Option<i32>,
+        /// The last lines the shim wrote to stderr, oldest first.
+        #[serde(default)]
+        stderr: Vec<String>,
+        /// Restarts since the VM booted, this one included.
+        restarts: u32,
+    },
+
+    /// Answer to `mount`: the shares that could not be mounted.
+    #[serde(rename = "mounted")]
+    Mounted {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        failed: Vec<MountFailure>,
+    },
+}
+
+/// A share `mount` could not mount, and why.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MountFailure {
+    pub tag: String,
+    pub reason: String,
+}
+
+/// Per-command resource limits for `exec`.
+///
+/// The shim enforces them with a cgroup v2 group per command where the
+/// guest kernel allows it, and falls back to `setrlimit` otherwise.
+/// `cpu_seconds` always applies to each process on its own.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// CPU time each process may use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<u64>,
+    /// Memory the command's processes may use together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    /// How many processes the command may run at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_processes: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Which of a command's [`ResourceLimits`] it ran into.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceLimit {
+    Cpu,
+    Memory,
+    Processes,
+}
+
+impl ResourceLimit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResourceLimit::Cpu => "cpu",
+            ResourceLimit::Memory => "memory",
+            ResourceLimit::Processes => "processes",
+        }
+    }
+}
+
+/// Protocol versions this side can speak, oldest first.
+///
+/// Version 1 is one bare message per line. Version 2 wraps each message in
+/// a [`Frame`] carrying a sequence number, so a line lost on the way is
+/// noticed and sent again.
+pub const PROTOCOL_VERSIONS: &[u32] = &[1, 2];
+
+/// Version negotiation, sent as an ordinary JSON Line before any frame.
+///
+/// The host offers every version it speaks when the channel opens; the shim
+/// answers with the one it picked. A version 1 shim rejects the offer as an
+/// unknown message type and never answers, so the link stays at version 1.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename = "hello")]
+pub struct Hello {
+    pub versions: Vec<u32>,
+}
+
+/// A version 2 line.
+///
+/// A frame with a `seq` carries a message; frames without one only
+/// acknowledge or ask for a resend. `ack` is the highest sequence number
+/// received in order, and `resend` the first one the receiver is missing.
+/// Fields are written in declaration order, which makes a frame recognisable
+/// by its first key.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct Frame<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ack: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resend: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg: Option<T>,
+}
+
+impl<T> Frame<T> {
+    /// A frame carrying no message.
+    pub fn control(ack: Option<u64>, resend: Option<u64>) -> Self {
+        Self {
+            seq: None,
+            ack,
+            resend,
+            msg: None,
+        }
+    }
 }
 
 /// Which output stream a terminal output chunk came from.
@@ -71,8 +253,20 @@ mod tests {
             command: "npm install".to_string(),
             env: None,
             cwd: Some("/mnt/working".to_string()),
+            isolate_fs: true,
+            pty: true,
+            max_output_bytes: Some(65536),
+            limits: ResourceLimits {
+                cpu_seconds: Some(30),
+                memory_bytes: None,
+                max_processes: Some(256),
+            },
         };
         let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains(r#""isolate_fs":true"#), "{json}");
+        assert!(json.contains(r#""pty":true"#), "{json}");
+        assert!(json.contains(r#""max_output_bytes":65536"#), "{json}");
+        assert!(json.contains(r#""limits":{"cpu_seconds":30,"max_processes":256}"#), "{json}");
         let parsed: HostMessage = serde_json::from_str(&json).unwrap();
         assert_eq!(msg, parsed);
     }
@@ -86,8 +280,15 @@ mod tests {
             command: "echo $PATH".to_string(),
             env: Some(env),
             cwd: None,
+            isolate_fs: false,
+            pty: false,
+            max_output_bytes: None,
+            limits: ResourceLimits::default(),
         };
         let json = serde_json::to_string(&msg).unwrap();
+        assert!(!json.contains("isolate_fs"), "{json}");
+        assert!(!json.contains("pty"), "{json}");
+        assert!(!json.contains("limits"), "{json}");
         let parsed: HostMessage = serde_json::from_str(&json).unwrap();
         assert_eq!(msg, parsed);
     }
@@ -100,6 +301,30 @@ mod tests {
         assert_eq!(msg, parsed);
     }
 
+    #[test]
+    fn host_message_input_round_trip() {
+        let msg = HostMessage::Input {
+            id: 42,
+            data: "print(1)\n".to_string(),
+            eof: false,
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"type":"input","id":42,"data":"print(1)\n"}"#);
+        let parsed: HostMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(msg, parsed);
+
+        let json = r#"{"type":"input","id":42,"data":"","eof":true}"#;
+        let parsed: HostMessage = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            parsed,
+            HostMessage::Input {
+                id: 42,
+                data: String::new(),
+                eof: true,
+            }
+        );
+    }
+
     #[test]
     fn host_message_rollback_notify_round_trip() {
         let msg = HostMessage::RollbackNotify { step_id: 5 };
@@ -108,6 +333,73 @@ mod tests {
         assert_eq!(msg, parsed);
     }
 
+    #[test]
+    fn pid_resolution_round_trip() {
+        let msg = HostMessage::ResolvePid { pid: 812 };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"type":"resolve_pid","pid":812}"#);
+        let parsed: HostMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(msg, parsed);
+
+        for (id, name) in [(Some(7), Some("gcc".to_string())), (None, None)] {
+            let msg = VmMessage::PidResolved { pid: 812, id, name };
+            let json = serde_json::to_string(&msg).unwrap();
+            let parsed: VmMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(msg, parsed);
+        }
+        let msg: VmMessage = serde_json::from_str(r#"{"type":"pid_resolved","pid":9}"#).unwrap();
+        assert_eq!(msg, VmMessage::PidResolved { pid: 9, id: None, name: None });
+    }
+
+    #[test]
+    fn mount_round_trip() {
+        let msg = HostMessage::Mount {
+            tags: vec!["app".to_string(), "lib".to_string()],
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"type":"mount","tags":["app","lib"]}"#);
+        let parsed: HostMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(msg, parsed);
+
+        let msg = VmMessage::Mounted {
+            failed: vec![MountFailure {
+                tag: "lib".to_string(),
+                reason: "no such tag".to_string(),
+            }],
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        let parsed: VmMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(msg, parsed);
+        let msg: VmMessage = serde_json::from_str(r#"{"type":"mounted"}"#).unwrap();
+        assert_eq!(msg, VmMessage::Mounted { failed: vec![] });
+    }
+
+    #[test]
+    fn shim_restarted_round_trip() {
+        let msg = VmMessage::ShimRestarted {
+            exit_code: Some(101),
+            signal: None,
+            stderr: vec!["thread 'main' panicked".to_string()],
+            restarts: 2,
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(!json.contains("signal"), "{json}");
+        let parsed: VmMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(msg, parsed);
+
+        let msg: VmMessage =
+            serde_json::from_str(r#"{"type":"shim_restarted","signal":9,"restarts":1}"#).unwrap();
+        assert_eq!(
+            msg,
+            VmMessage::ShimRestarted {
+                exit_code: None,
+                signal: Some(9),
+                stderr: vec![],
+                restarts: 1,
+            }
+        );
+    }
+
     #[test]
     fn vm_message_step_started_round_trip() {
         let msg = VmMessage::StepStarted { id: 42 };
@@ -133,10 +425,32 @@ mod tests {
         let msg = VmMessage::StepCompleted {
             id: 42,
             exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
         };
         let json = serde_json::to_string(&msg).unwrap();
+        assert!(!json.contains("output_truncated"), "{json}");
         let parsed: VmMessage = serde_json::from_str(&json).unwrap();
         assert_eq!(msg, parsed);
+
+        let truncated = VmMessage::StepCompleted {
+            id: 42,
+            exit_code: 0,
+            output_truncated: true,
+            limit_exceeded: None,
+        };
+        let json = serde_json::to_string(&truncated).unwrap();
+        assert_eq!(serde_json::from_str::<VmMessage>(&json).unwrap(), truncated);
+
+        let limited = VmMessage::StepCompleted {
+            id: 42,
+            exit_code: 137,
+            output_truncated: false,
+            limit_exceeded: Some(ResourceLimit::Memory),
+        };
+        let json = serde_json::to_string(&limited).unwrap();
+        assert!(json.contains(r#""limit_exceeded":"memory""#), "{json}");
+        assert_eq!(serde_json::from_str::<VmMessage>(&json).unwrap(), limited);
     }
 
     #[test]
@@ -158,6 +472,10 @@ mod tests {
                 command: "npm install".to_string(),
                 env: None,
                 cwd: Some("/mnt/working".to_string()),
+                isolate_fs: false,
+                pty: false,
+                max_output_bytes: None,
+                limits: ResourceLimits::default(),
             }
         );
     }
@@ -182,6 +500,33 @@ mod tests {
 
         let json = r#"{"type":"step_completed","id":42,"exit_code":0}"#;
         let msg: VmMessage = serde_json::from_str(json).unwrap();
-        assert_eq!(msg, VmMessage::StepCompleted { id: 42, exit_code: 0 });
+        assert_eq!(
+            msg,
+            VmMessage::StepCompleted {
+                id: 42,
+                exit_code: 0,
+                output_truncated: false,
+                limit_exceeded: None,
+            }
+        );
+    }
+
+    #[test]
+    fn frames_start_with_their_first_field() {
+        let frame = Frame {
+            seq: Some(3),
+            ack: Some(7),
+            resend: None,
+            msg: Some(HostMessage::Cancel { id: 1 }),
+        };
+        let json = serde_json::to_string(&frame).unwrap();
+        assert_eq!(json, r#"{"seq":3,"ack":7,"msg":{"type":"cancel","id":1}}"#);
+        let parsed: Frame<HostMessage> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, frame);
+
+        let json = serde_json::to_string(&Frame::<HostMessage>::control(None, Some(4))).unwrap();
+        assert_eq!(json, r#"{"resend":4}"#);
+        let hello = serde_json::to_string(&Hello { versions: vec![1, 2] }).unwrap();
+        assert_eq!(hello, r#"{"type":"hello","versions":[1,2]}"#);
     }
 }
@@ -0,0 +1,232 @@
+//! JSON Schema of the control channel.
+//!
+//! [`control_schema`] describes the lines host and shim exchange: the
+//! [`HostMessage`]s and [`VmMessage`]s, the [`Hello`] that negotiates a
+//! version and the [`Frame`]s that carry messages from version 2 on.
+//! `sandbox --print-schema control` prints the document.
+
+use codeagent_common::schema::{DRAFT, JsonSchema, Value, constant, object};
+use codeagent_common::{enum_schema, object_schema};
+use serde_json::json;
+
+use crate::protocol::{
+    Frame, Hello, HostMessage, MountFailure, OutputStream, PROTOCOL_VERSIONS, ResourceLimit,
+    ResourceLimits, VmMessage,
+};
+
+enum_schema!(OutputStream { Stdout, Stderr });
+enum_schema!(ResourceLimit { Cpu, Memory, Processes });
+
+object_schema!(ResourceLimits {
+    optional { cpu_seconds: Option<u64>, memory_bytes: Option<u64>, max_processes: Option<u64> }
+});
+
+object_schema!(MountFailure {
+    required { tag: String, reason: String }
+});
+
+/// Implement [`JsonSchema`] for an enum tagged by `type`, one object per
+/// variant. Like `object_schema!`, it stops compiling when a variant or
+/// field changes without the list.
+macro_rules! tagged_schema {
+    ($ty:ident {
+        $($variant:ident as $tag:literal {
+            $(required { $($req:ident: $req_ty:ty),* $(,)? })?
+            $(optional { $($opt:ident: $opt_ty:ty),* $(,)? })?
+        })*
+    }) => {
+        impl JsonSchema for $ty {
+            fn json_schema() -> Value {
+                let _ = |value: &$ty| match value {
+                    $($ty::$variant { $($($req,)*)? $($($opt,)*)? } => {
+                        $($(let _: &$req_ty = $req;)*)?
+                        $($(let _: &$opt_ty = $opt;)*)?
+                    })*
+                };
+                let variants = vec![$(
+                    object($tag, vec![
+                        ("type", constant($tag)),
+                        $($((stringify!($req), <$req_ty as JsonSchema>::json_schema()),)*)?
+                        $($((stringify!($opt), <$opt_ty as JsonSchema>::json_schema()),)*)?
+                    ], &["type", $($(stringify!($req),)*)?]),
+                )*];
+                json!({ "title": stringify!($ty), "oneOf": variants })
+            }
+        }
+    };
+}
+
+tagged_schema!(HostMessage {
+    Exec as "exec" {
+        required { id: u64, command: String }
+        optional {
+            env: Option<std::collections::HashMap<String, String>>,
+            cwd: Option<String>,
+            isolate_fs: bool,
+            pty: bool,
+            max_output_bytes: Option<u64>,
+            limits: ResourceLimits,
+        }
+    }
+    Input as "input" {
+        required { id: u64, data: String }
+        optional { eof: bool }
+    }
+    Cancel as "cancel" {
+        required { id: u64 }
+    }
+    RollbackNotify as "rollback_notify" {
+        required { step_id: u64 }
+    }
+    ResolvePid as "resolve_pid" {
+        required { pid: u32 }
+    }
+    Mount as "mount" {
+        required { tags: Vec<String> }
+    }
+});
+
+tagged_schema!(VmMessage {
+    StepStarted as "step_started" {
+        required { id: u64 }
+    }
+    Output as "output" {
+        required { id: u64, stream: OutputStream, data: String }
+    }
+    StepCompleted as "step_completed" {
+        required { id: u64, exit_code: i32 }
+        optional { output_truncated: bool, limit_exceeded: Option<ResourceLimit> }
+    }
+    PidResolved as "pid_resolved" {
+        required { pid: u32 }
+        optional { id: Option<u64>, name: Option<String> }
+    }
+    ShimRestarted as "shim_restarted" {
+        required { restarts: u32 }
+        optional { exit_code: Option<i32>, signal: Option<i32>, stderr: Vec<String> }
+    }
+    Mounted as "mounted" {
+        optional { failed: Vec<MountFailure> }
+    }
+});
+
+impl JsonSchema for Hello {
+    fn json_schema() -> Value {
+        let _ = |value: &Hello| {
+            let Hello { versions } = value;
+            let _: &Vec<u32> = versions;
+        };
+        object("Hello", vec![
+            ("type", constant("hello")),
+            ("versions", Vec::<u32>::json_schema()),
+        ], &["type", "versions"])
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Frame<T> {
+    fn json_schema() -> Value {
+        let _ = |value: &Frame<T>| {
+            let Frame { seq, ack, resend, msg } = value;
+            let _: [&Option<u64>; 3] = [seq, ack, resend];
+            let _: &Option<T> = msg;
+        };
+        object("Frame", vec![
+            ("seq", Option::<u64>::json_schema()),
+            ("ack", Option::<u64>::json_schema()),
+            ("resend", Option::<u64>::json_schema()),
+            ("msg", Option::<T>::json_schema()),
+        ], &[])
+    }
+}
+
+/// The schema document. Its `$defs` are the messages each side sends, bare
+/// as in version 1 and framed as in version 2, and the `Hello` both send
+/// first.
+pub fn control_schema() -> Value {
+    json!({
+        "$schema": DRAFT,
+        "title": "codeagent-sandbox control channel",
+        "protocol_versions": PROTOCOL_VERSIONS,
+        "$defs": {
+            "Hello": Hello::json_schema(),
+            "HostMessage": HostMessage::json_schema(),
+            "VmMessage": VmMessage::json_schema(),
+            "HostFrame": Frame::<HostMessage>::json_schema(),
+            "VmFrame": Frame::<VmMessage>::json_schema(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use codeagent_common::schema::validate;
+
+    use super::*;
+
+    fn assert_fits(definition: &str, message: &impl serde::Serialize) {
+        let document = control_schema();
+        let value = serde_json::to_value(message).unwrap();
+        validate(&document["$defs"][definition], &value, true)
+            .unwrap_or_else(|e| panic!("{definition} {value}: {e}"));
+    }
+
+    #[test]
+    fn every_host_message_fits_the_schema() {
+        let messages = [
+            HostMessage::Exec {
+                id: 1,
+                command: "make".to_string(),
+                env: Some([("CI".to_string(), "1".to_string())].into()),
+                cwd: Some("/mnt/working/0".to_string()),
+                isolate_fs: true,
+                pty: true,
+                max_output_bytes: Some(1024),
+                limits: ResourceLimits { memory_bytes: Some(1 << 30), ..Default::default() },
+            },
+            HostMessage::Input { id: 1, data: "y\n".to_string(), eof: true },
+            HostMessage::Cancel { id: 1 },
+            HostMessage::RollbackNotify { step_id: 1 },
+            HostMessage::ResolvePid { pid: 42 },
+            HostMessage::Mount { tags: vec!["0".to_string()] },
+        ];
+        let variants = HostMessage::json_schema()["oneOf"].as_array().unwrap().len();
+        assert_eq!(messages.len(), variants);
+        for message in &messages {
+            assert_fits("HostMessage", message);
+        }
+        let frame =
+            Frame { seq: Some(3), ack: Some(2), resend: None, msg: Some(messages[2].clone()) };
+        assert_fits("HostFrame", &frame);
+        assert_fits("Hello", &Hello { versions: PROTOCOL_VERSIONS.to_vec() });
+    }
+
+    #[test]
+    fn every_vm_message_fits_the_schema() {
+        let messages = [
+            VmMessage::StepStarted { id: 1 },
+            VmMessage::Output { id: 1, stream: OutputStream::Stderr, data: "warn".to_string() },
+            VmMessage::StepCompleted {
+                id: 1,
+                exit_code: 137,
+                output_truncated: true,
+                limit_exceeded: Some(ResourceLimit::Memory),
+            },
+            VmMessage::PidResolved { pid: 42, id: Some(1), name: Some("make".to_string()) },
+            VmMessage::ShimRestarted {
+                exit_code: None,
+                signal: Some(11),
+                stderr: vec!["segfault".to_string()],
+                restarts: 1,
+            },
+            VmMessage::Mounted {
+                failed: vec![MountFailure { tag: "1".to_string(), reason: "busy".to_string() }],
+            },
+        ];
+        let variants = VmMessage::json_schema()["oneOf"].as_array().unwrap().len();
+        assert_eq!(messages.len(), variants);
+        for message in &messages {
+            assert_fits("VmMessage", message);
+        }
+        assert_fits("VmFrame", &Frame::<VmMessage>::control(Some(4), Some(5)));
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use crate::error::ControlChannelError;
-use crate::protocol::{OutputStream, VmMessage};
+use crate::protocol::{MountFailure, OutputStream, ResourceLimit, VmMessage};
 
 /// A command that has been sent to the VM but hasn't started executing yet.
 #[derive(Debug, Clone)]
@@ -35,6 +35,26 @@ pub enum ControlEvent {
         id: u64,
         exit_code: i32,
         cancelled: bool,
+        /// The shim dropped output past the command's `max_output_bytes`.
+        output_truncated: bool,
+        /// The resource limit that stopped the command, if one did.
+        limit_exceeded: Option<ResourceLimit>,
+    },
+    /// The shim told which command guest process `pid` belongs to, and its
+    /// name.
+    PidResolved { pid: u32, id: Option<u64>, name: Option<String> },
+    /// The shim answered `mount`; `failed` are the shares it could not mount.
+    Mounted { failed: Vec<MountFailure> },
+    /// The shim crashed and was restarted. `lost_pending` were sent but never
+    /// started, `lost_active` had started; neither will complete, and the
+    /// state machine has forgotten both. Ids are in ascending order.
+    ShimRestarted {
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        stderr: Vec<String>,
+        restarts: u32,
+        lost_pending: Vec<u64>,
+        lost_active: Vec<u64>,
     },
     /// A protocol violation was detected. The channel remains operational,
     /// but the caller should log this error.
@@ -79,6 +99,8 @@ impl ControlChannelState {
                 id,
                 exit_code: -1,
                 cancelled: true,
+                output_truncated: false,
+                limit_exceeded: None,
             });
         }
 
@@ -100,12 +122,46 @@ impl ControlChannelState {
         match msg {
             VmMessage::StepStarted { id } => self.handle_step_started(id),
             VmMessage::Output { id, stream, data } => self.handle_output(id, stream, data),
-            VmMessage::StepCompleted { id, exit_code } => {
-                self.handle_step_completed(id, exit_code)
+            VmMessage::StepCompleted {
+                id,
+                exit_code,
+                output_truncated,
+                limit_exceeded,
+            } => self.handle_step_completed(id, exit_code, output_truncated, limit_exceeded),
+            VmMessage::PidResolved { pid, id, name } => {
+                ControlEvent::PidResolved { pid, id, name }
+            }
+            VmMessage::Mounted { failed } => ControlEvent::Mounted { failed },
+            VmMessage::ShimRestarted {
+                exit_code,
+                signal,
+                stderr,
+                restarts,
+            } => {
+                let (lost_pending, lost_active) = self.abandon_all();
+                ControlEvent::ShimRestarted {
+                    exit_code,
+                    signal,
+                    stderr,
+                    restarts,
+                    lost_pending,
+                    lost_active,
+                }
             }
         }
     }
 
+    /// Forget every command, as when whatever was running them is gone.
+    /// Returns the ids of the pending and of the active ones, each in
+    /// ascending order.
+    pub fn abandon_all(&mut self) -> (Vec<u64>, Vec<u64>) {
+        let mut pending: Vec<u64> = self.pending.drain().map(|(id, _)| id).collect();
+        let mut active: Vec<u64> = self.active.drain().map(|(id, _)| id).collect();
+        pending.sort_unstable();
+        active.sort_unstable();
+        (pending, active)
+    }
+
     /// Returns the number of pending commands (sent but not yet started).
     pub fn pending_count(&self) -> usize {
         self.pending.len()
@@ -116,6 +172,13 @@ impl ControlChannelState {
         self.active.len()
     }
 
+    /// Whether command `id` can still be sent input: it was sent and has
+    /// neither completed nor been cancelled.
+    pub fn accepts_input(&self, id: u64) -> bool {
+        self.pending.contains_key(&id)
+            || self.active.get(&id).is_some_and(|active| !active.cancelled)
+    }
+
     /// Returns a reference to an active command by ID, if it exists.
     pub fn get_active(&self, id: u64) -> Option<&ActiveCommand> {
         self.active.get(&id)
@@ -160,12 +223,20 @@ impl ControlChannelState {
         }
     }
 
-    fn handle_step_completed(&mut self, id: u64, exit_code: i32) -> ControlEvent {
+    fn handle_step_completed(
+        &mut self,
+        id: u64,
+        exit_code: i32,
+        output_truncated: bool,
+        limit_exceeded: Option<ResourceLimit>,
+    ) -> ControlEvent {
         if let Some(active) = self.active.remove(&id) {
             ControlEvent::StepCompleted {
                 id,
                 exit_code,
                 cancelled: active.cancelled,
+                output_truncated,
+                limit_exceeded,
             }
         } else {
             ControlEvent::ProtocolError {
@@ -220,6 +291,8 @@ mod tests {
         let event = state.process_vm_message(VmMessage::StepCompleted {
             id: 1,
             exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
         });
         assert_eq!(
             event,
@@ -227,6 +300,8 @@ mod tests {
                 id: 1,
                 exit_code: 0,
                 cancelled: false,
+                output_truncated: false,
+                limit_exceeded: None,
             }
         );
         assert_eq!(state.active_count(), 0);
@@ -281,6 +356,68 @@ mod tests {
         assert!(active.cancelled);
     }
 
+    #[test]
+    fn input_accepted_until_cancel_or_completion() {
+        let mut state = ControlChannelState::new();
+        state.command_sent(1, "python3".to_string());
+        assert!(state.accepts_input(1));
+        state.process_vm_message(VmMessage::StepStarted { id: 1 });
+        assert!(state.accepts_input(1));
+        state.cancel_command(1).unwrap();
+        assert!(!state.accepts_input(1));
+
+        state.command_sent(2, "cat".to_string());
+        state.process_vm_message(VmMessage::StepStarted { id: 2 });
+        state.process_vm_message(VmMessage::StepCompleted {
+            id: 2,
+            exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
+        });
+        assert!(!state.accepts_input(2));
+        assert!(!state.accepts_input(3));
+    }
+
+    #[test]
+    fn shim_restart_forgets_every_command() {
+        let mut state = ControlChannelState::new();
+        state.command_sent(3, "make".to_string());
+        state.command_sent(1, "npm test".to_string());
+        state.command_sent(2, "cargo build".to_string());
+        state.process_vm_message(VmMessage::StepStarted { id: 2 });
+        state.process_vm_message(VmMessage::StepStarted { id: 1 });
+
+        let event = state.process_vm_message(VmMessage::ShimRestarted {
+            exit_code: None,
+            signal: Some(11),
+            stderr: vec![],
+            restarts: 1,
+        });
+        assert_eq!(
+            event,
+            ControlEvent::ShimRestarted {
+                exit_code: None,
+                signal: Some(11),
+                stderr: vec![],
+                restarts: 1,
+                lost_pending: vec![3],
+                lost_active: vec![1, 2],
+            }
+        );
+        assert_eq!(state.pending_count(), 0);
+        assert_eq!(state.active_count(), 0);
+        let late_completion = VmMessage::StepCompleted {
+            id: 1,
+            exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
+        };
+        assert!(matches!(
+            state.process_vm_message(late_completion),
+            ControlEvent::ProtocolError { .. }
+        ));
+    }
+
     #[test]
     fn cancel_unknown_command_returns_error() {
         let mut state = ControlChannelState::new();
@@ -66,6 +66,8 @@ fn cc01_valid_step_lifecycle() {
             id: 42,
             exit_code: 0,
             cancelled: false,
+            output_truncated: false,
+            limit_exceeded: None,
         }
     );
     assert_eq!(state.active_count(), 0);
@@ -83,6 +85,8 @@ fn cc01_sequential_commands() {
     state.process_vm_message(VmMessage::StepCompleted {
         id: 1,
         exit_code: 0,
+        output_truncated: false,
+        limit_exceeded: None,
     });
     assert_eq!(state.active_count(), 0);
 
@@ -99,6 +103,8 @@ fn cc01_sequential_commands() {
     let event = state.process_vm_message(VmMessage::StepCompleted {
         id: 2,
         exit_code: 0,
+        output_truncated: false,
+        limit_exceeded: None,
     });
     assert_eq!(
         event,
@@ -106,6 +112,8 @@ fn cc01_sequential_commands() {
             id: 2,
             exit_code: 0,
             cancelled: false,
+            output_truncated: false,
+            limit_exceeded: None,
         }
     );
 }
@@ -235,6 +243,8 @@ fn cc05_step_completed_without_step_started() {
     let event = state.process_vm_message(VmMessage::StepCompleted {
         id: 99,
         exit_code: 0,
+        output_truncated: false,
+        limit_exceeded: None,
     });
     assert!(
         matches!(event, ControlEvent::ProtocolError { .. }),
@@ -257,6 +267,8 @@ fn cc05_step_completed_for_pending_command() {
     let event = state.process_vm_message(VmMessage::StepCompleted {
         id: 1,
         exit_code: 0,
+        output_truncated: false,
+        limit_exceeded: None,
     });
     assert!(
         matches!(event, ControlEvent::ProtocolError { .. }),
@@ -289,6 +301,8 @@ fn cc06_duplicate_step_started() {
     let event = state.process_vm_message(VmMessage::StepCompleted {
         id: 1,
         exit_code: 0,
+        output_truncated: false,
+        limit_exceeded: None,
     });
     assert_eq!(
         event,
@@ -296,6 +310,8 @@ fn cc06_duplicate_step_started() {
             id: 1,
             exit_code: 0,
             cancelled: false,
+            output_truncated: false,
+            limit_exceeded: None,
         }
     );
 }
@@ -323,6 +339,8 @@ fn cc07_cancellation_mid_step() {
     let event = state.process_vm_message(VmMessage::StepCompleted {
         id: 1,
         exit_code: -9,
+        output_truncated: false,
+        limit_exceeded: None,
     });
     assert_eq!(
         event,
@@ -330,6 +348,8 @@ fn cc07_cancellation_mid_step() {
             id: 1,
             exit_code: -9,
             cancelled: true,
+            output_truncated: false,
+            limit_exceeded: None,
         }
     );
     assert_eq!(state.active_count(), 0);
@@ -396,6 +416,8 @@ fn nonzero_exit_code() {
     let event = state.process_vm_message(VmMessage::StepCompleted {
         id: 1,
         exit_code: 1,
+        output_truncated: false,
+        limit_exceeded: None,
     });
     assert_eq!(
         event,
@@ -403,6 +425,8 @@ fn nonzero_exit_code() {
             id: 1,
             exit_code: 1,
             cancelled: false,
+            output_truncated: false,
+            limit_exceeded: None,
         }
     );
 }
@@ -1,12 +1,13 @@
-//! CC-08 through CC-12: Control channel integration tests with fake shim.
+//! CC-08 through CC-13: Control channel integration tests with fake shim.
 //!
 //! These L3 tests verify that the `ControlChannelHandler` correctly integrates
 //! the protocol state machine with undo step lifecycle management, including
 //! quiescence window behavior and ambient step handling.
 //!
-//! All tests use `tokio::time::pause()` (via `start_paused = true`) for
-//! deterministic time control.
+//! All tests but CC-13 use `tokio::time::pause()` (via `start_paused = true`)
+//! for deterministic time control; CC-13 injects a `ManualClock` instead.
 
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -14,7 +15,7 @@ use tokio::sync::mpsc;
 
 use codeagent_common::StepId;
 use codeagent_control::{
-    ControlChannelHandler, HandlerEvent, HostMessage, InFlightTracker, OutputStream,
+    ControlChannelHandler, HandlerEvent, HostMessage, InFlightTracker, ManualClock, OutputStream,
     QuiescenceConfig, StepManager, VmMessage,
 };
 
@@ -31,12 +32,17 @@ enum StepManagerCall {
 #[derive(Default)]
 struct MockStepManager {
     calls: Mutex<Vec<StepManagerCall>>,
+    exit_codes: Mutex<Vec<(StepId, i32)>>,
 }
 
 impl MockStepManager {
     fn calls(&self) -> Vec<StepManagerCall> {
         self.calls.lock().unwrap().clone()
     }
+
+    fn exit_codes(&self) -> Vec<(StepId, i32)> {
+        self.exit_codes.lock().unwrap().clone()
+    }
 }
 
 impl StepManager for MockStepManager {
@@ -56,6 +62,10 @@ impl StepManager for MockStepManager {
         Ok(vec![])
     }
 
+    fn set_step_exit_code(&self, id: StepId, exit_code: i32) {
+        self.exit_codes.lock().unwrap().push((id, exit_code));
+    }
+
     fn current_step(&self) -> Option<StepId> {
         let calls = self.calls.lock().unwrap();
         match calls.last() {
@@ -122,7 +132,7 @@ async fn run_exec_through_completed(
 ) {
     harness
         .handler
-        .send_exec(id, command.to_string(), None, None)
+        .send_exec(id, command.to_string(), None, None, false, false, None, Default::default())
         .await;
 
     harness
@@ -143,7 +153,12 @@ async fn run_exec_through_completed(
 
     harness
         .handler
-        .handle_vm_message(VmMessage::StepCompleted { id, exit_code })
+        .handle_vm_message(VmMessage::StepCompleted {
+            id,
+            exit_code,
+            output_truncated: false,
+            limit_exceeded: None,
+        })
         .await;
 
     // Yield so the spawned quiescence task gets its first poll and
@@ -164,7 +179,7 @@ async fn cc08_normal_exec_cycle() {
     // Send exec command
     let host_msg = harness
         .handler
-        .send_exec(1, "echo hello".to_string(), None, None)
+        .send_exec(1, "echo hello".to_string(), None, None, false, false, None, Default::default())
         .await;
 
     // Verify the returned HostMessage
@@ -219,6 +234,8 @@ async fn cc08_normal_exec_cycle() {
         .handle_vm_message(VmMessage::StepCompleted {
             id: 1,
             exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
         })
         .await;
 
@@ -252,6 +269,8 @@ async fn cc08_normal_exec_cycle() {
             exit_code: 0,
             cancelled: false,
             evicted_steps: vec![],
+            output_truncated: false,
+            limit_exceeded: None,
         }
     );
 }
@@ -476,6 +495,57 @@ async fn cc12_ambient_writes_after_step_close() {
     advance_and_settle(Duration::from_secs(5)).await;
 }
 
+// ---------------------------------------------------------------------------
+// CC-13: An injected clock drives quiescence and ambient timeouts
+// ---------------------------------------------------------------------------
+
+/// Yield until `clock` has `count` armed timers.
+async fn wait_for_sleepers(clock: &ManualClock, count: usize) {
+    while clock.sleepers() < count {
+        tokio::task::yield_now().await;
+    }
+}
+
+/// CC-13: With a manual clock, timers fire when the clock is advanced and
+/// never on their own, without pausing tokio.
+#[tokio::test]
+async fn cc13_manual_clock_drives_timers() {
+    let clock = Arc::new(ManualClock::new());
+    let mut harness = default_harness();
+    harness.handler = harness.handler.with_clock(clock.clone());
+
+    run_exec_through_completed(&harness, 1, "make", &[], 0).await;
+    drain_events(&mut harness.events);
+    wait_for_sleepers(&clock, 1).await;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(harness.handler.in_quiescence().await);
+
+    clock.advance(Duration::from_millis(100));
+    assert!(matches!(
+        harness.events.recv().await,
+        Some(HandlerEvent::StepCompleted { step_id: 1, .. })
+    ));
+
+    harness.handler.notify_fs_write().await;
+    assert_eq!(
+        harness.events.recv().await,
+        Some(HandlerEvent::AmbientStepOpened { step_id: -1 })
+    );
+    wait_for_sleepers(&clock, 1).await;
+    clock.advance(Duration::from_secs(4));
+    tokio::task::yield_now().await;
+    assert_eq!(harness.handler.ambient_step_id().await, Some(-1));
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(
+        harness.events.recv().await,
+        Some(HandlerEvent::AmbientStepClosed {
+            step_id: -1,
+            evicted_steps: vec![],
+        })
+    );
+}
+
 // ---------------------------------------------------------------------------
 // Edge case tests
 // ---------------------------------------------------------------------------
@@ -517,7 +587,7 @@ async fn no_ambient_during_active_command() {
     // Start exec, get step_started
     harness
         .handler
-        .send_exec(1, "cargo build".to_string(), None, None)
+        .send_exec(1, "cargo build".to_string(), None, None, false, false, None, Default::default())
         .await;
     harness
         .handler
@@ -539,6 +609,8 @@ async fn no_ambient_during_active_command() {
         .handle_vm_message(VmMessage::StepCompleted {
             id: 1,
             exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
         })
         .await;
     advance_and_settle(Duration::from_millis(100)).await;
@@ -581,7 +653,7 @@ async fn exec_closes_ambient_step() {
     // Send exec command — ambient step should be closed first
     harness
         .handler
-        .send_exec(1, "echo hi".to_string(), None, None)
+        .send_exec(1, "echo hi".to_string(), None, None, false, false, None, Default::default())
         .await;
 
     let events = drain_events(&mut harness.events);
@@ -612,6 +684,8 @@ async fn exec_closes_ambient_step() {
         .handle_vm_message(VmMessage::StepCompleted {
             id: 1,
             exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
         })
         .await;
     advance_and_settle(Duration::from_millis(100)).await;
@@ -643,3 +717,387 @@ async fn sequential_exec_commands() {
         ]
     );
 }
+
+/// The command's exit code is recorded on the step before it closes.
+#[tokio::test(start_paused = true)]
+async fn exit_code_recorded_before_close() {
+    let mut harness = default_harness();
+
+    run_exec_through_completed(&harness, 1, "false", &[], 1).await;
+    tokio::task::yield_now().await;
+    assert!(harness.step_manager.exit_codes().is_empty());
+
+    advance_and_settle(Duration::from_millis(100)).await;
+    drain_events(&mut harness.events);
+    assert_eq!(harness.step_manager.exit_codes(), vec![(1, 1)]);
+    assert_eq!(
+        harness.step_manager.calls(),
+        vec![StepManagerCall::OpenStep(1), StepManagerCall::CloseStep(1)]
+    );
+}
+
+/// Overlapping commands each get their own step, closed on their own
+/// schedule, and no ambient step opens while either is open.
+#[tokio::test(start_paused = true)]
+async fn overlapping_commands_get_separate_steps() {
+    let mut harness = default_harness();
+
+    for (id, command) in [(1, "make"), (2, "npm test")] {
+        harness
+            .handler
+            .send_exec(id, command.to_string(), None, None, false, false, None, Default::default())
+            .await;
+        harness
+            .handler
+            .handle_vm_message(VmMessage::StepStarted { id })
+            .await;
+    }
+    let mut running = harness.handler.running_command_steps().await;
+    running.sort();
+    assert_eq!(running, vec![1, 2]);
+
+    harness
+        .handler
+        .handle_vm_message(VmMessage::PidResolved { pid: 51, id: Some(2), name: None })
+        .await;
+    assert_eq!(harness.handler.attribution().step_for_pid(51), Some(2));
+
+    harness
+        .handler
+        .handle_vm_message(VmMessage::StepCompleted {
+            id: 1,
+            exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
+        })
+        .await;
+    tokio::task::yield_now().await;
+    advance_and_settle(Duration::from_millis(100)).await;
+    harness.handler.notify_fs_write().await;
+    assert_eq!(harness.handler.ambient_step_id().await, None);
+    assert_eq!(harness.handler.running_command_steps().await, vec![2]);
+
+    harness
+        .handler
+        .handle_vm_message(VmMessage::StepCompleted {
+            id: 2,
+            exit_code: 1,
+            output_truncated: false,
+            limit_exceeded: None,
+        })
+        .await;
+    tokio::task::yield_now().await;
+    advance_and_settle(Duration::from_millis(100)).await;
+
+    let completed: Vec<StepId> = drain_events(&mut harness.events)
+        .into_iter()
+        .filter_map(|event| match event {
+            HandlerEvent::StepCompleted { step_id, .. } => Some(step_id),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(completed, vec![1, 2]);
+    assert_eq!(
+        harness.step_manager.calls(),
+        vec![
+            StepManagerCall::OpenStep(1),
+            StepManagerCall::OpenStep(2),
+            StepManagerCall::CloseStep(1),
+            StepManagerCall::CloseStep(2),
+        ]
+    );
+    assert_eq!(harness.step_manager.exit_codes(), vec![(1, 0), (2, 1)]);
+}
+
+/// Input is forwarded only to commands that are still running.
+#[tokio::test(start_paused = true)]
+async fn input_only_for_running_commands() {
+    let harness = default_harness();
+    harness
+        .handler
+        .send_exec(1, "python3".to_string(), None, None, false, true, None, Default::default())
+        .await;
+
+    let input = harness
+        .handler
+        .send_input(1, "print(1)\n".to_string(), false)
+        .await
+        .unwrap();
+    assert_eq!(
+        input,
+        HostMessage::Input {
+            id: 1,
+            data: "print(1)\n".to_string(),
+            eof: false,
+        }
+    );
+    assert!(harness.handler.send_input(2, String::new(), true).await.is_err());
+
+    harness.handler.cancel(1).await;
+    assert!(harness.handler.send_input(1, "more\n".to_string(), false).await.is_err());
+}
+
+/// A shim restart ends every command it was running: started ones have
+/// their steps closed after the quiescence window, with exit code -1, and
+/// ones that never started complete at once.
+#[tokio::test(start_paused = true)]
+async fn shim_restart_closes_lost_steps() {
+    let mut harness = default_harness();
+    run_exec_through_completed(&harness, 1, "true", &[], 0).await;
+    advance_and_settle(Duration::from_millis(100)).await;
+    drain_events(&mut harness.events);
+
+    for id in [2, 3] {
+        harness
+            .handler
+            .send_exec(
+                id,
+                "sleep 100".to_string(),
+                None,
+                None,
+                false,
+                false,
+                None,
+                Default::default(),
+            )
+            .await;
+    }
+    harness
+        .handler
+        .handle_vm_message(VmMessage::StepStarted { id: 2 })
+        .await;
+    drain_events(&mut harness.events);
+
+    harness
+        .handler
+        .handle_vm_message(VmMessage::ShimRestarted {
+            exit_code: Some(101),
+            signal: None,
+            stderr: vec!["panicked at executor.rs".to_string()],
+            restarts: 1,
+        })
+        .await;
+    assert!(harness.handler.running_command_steps().await.is_empty());
+    assert!(harness.handler.in_quiescence().await);
+    assert_eq!(
+        drain_events(&mut harness.events),
+        vec![
+            HandlerEvent::StepCompleted {
+                step_id: 3,
+                exit_code: -1,
+                cancelled: false,
+                evicted_steps: vec![],
+                output_truncated: false,
+                limit_exceeded: None,
+            },
+            HandlerEvent::ShimRestarted {
+                exit_code: Some(101),
+                signal: None,
+                stderr: vec!["panicked at executor.rs".to_string()],
+                restarts: 1,
+                lost_steps: vec![2],
+            },
+        ]
+    );
+
+    tokio::task::yield_now().await;
+    advance_and_settle(Duration::from_millis(100)).await;
+    assert!(matches!(
+        drain_events(&mut harness.events).as_slice(),
+        [HandlerEvent::StepCompleted { step_id: 2, exit_code: -1, .. }]
+    ));
+    assert_eq!(harness.step_manager.exit_codes(), vec![(1, 0), (2, -1)]);
+    assert_eq!(
+        harness.step_manager.calls().last(),
+        Some(&StepManagerCall::CloseStep(2))
+    );
+}
+
+/// Abandoning the commands of a guest that is gone ends them as a shim
+/// restart does, without reporting one.
+#[tokio::test(start_paused = true)]
+async fn abandoned_commands_close_their_steps() {
+    let mut harness = default_harness();
+    for id in [1, 2] {
+        harness
+            .handler
+            .send_exec(
+                id,
+                "sleep 100".to_string(),
+                None,
+                None,
+                false,
+                false,
+                None,
+                Default::default(),
+            )
+            .await;
+    }
+    harness
+        .handler
+        .handle_vm_message(VmMessage::StepStarted { id: 1 })
+        .await;
+    drain_events(&mut harness.events);
+
+    assert_eq!(harness.handler.abandon_commands().await, vec![1]);
+    assert!(harness.handler.running_command_steps().await.is_empty());
+    assert!(matches!(
+        drain_events(&mut harness.events).as_slice(),
+        [HandlerEvent::StepCompleted { step_id: 2, exit_code: -1, .. }]
+    ));
+
+    tokio::task::yield_now().await;
+    advance_and_settle(Duration::from_millis(100)).await;
+    assert!(matches!(
+        drain_events(&mut harness.events).as_slice(),
+        [HandlerEvent::StepCompleted { step_id: 1, exit_code: -1, .. }]
+    ));
+    assert_eq!(harness.step_manager.exit_codes(), vec![(1, -1)]);
+    assert!(harness.handler.send_input(2, "x".to_string(), false).await.is_err());
+}
+
+/// The quiescence window of a command waits only for the working roots it
+/// was active in: a long write in another root does not hold its step open.
+#[tokio::test(start_paused = true)]
+async fn quiescence_ignores_operations_in_other_roots() {
+    let mut harness = default_harness();
+    let first = harness.in_flight.for_root(0);
+    let second = harness.in_flight.for_root(1);
+    // An unrelated write in the second root spans the whole command.
+    second.begin_operation();
+
+    harness
+        .handler
+        .send_exec(1, "make".to_string(), None, None, false, false, None, Default::default())
+        .await;
+    harness
+        .handler
+        .handle_vm_message(VmMessage::StepStarted { id: 1 })
+        .await;
+    first.begin_operation();
+    first.end_operation();
+    harness
+        .handler
+        .handle_vm_message(VmMessage::StepCompleted {
+            id: 1,
+            exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
+        })
+        .await;
+    tokio::task::yield_now().await;
+
+    // A late write in the command's root still extends the window.
+    first.begin_operation();
+    advance_and_settle(Duration::from_millis(150)).await;
+    assert!(harness.handler.in_quiescence().await);
+    first.end_operation();
+    tokio::task::yield_now().await;
+    advance_and_settle(Duration::from_millis(100)).await;
+
+    assert!(!harness.handler.in_quiescence().await);
+    assert_eq!(harness.in_flight.count(), 1);
+    assert!(drain_events(&mut harness.events)
+        .iter()
+        .any(|event| matches!(event, HandlerEvent::StepCompleted { step_id: 1, .. })));
+    second.end_operation();
+}
+
+/// A new configuration applies to the quiescence windows that start after it.
+#[tokio::test(start_paused = true)]
+async fn set_config_applies_to_later_windows() {
+    let mut harness = default_harness();
+    harness.handler.set_config(QuiescenceConfig {
+        idle_timeout: Duration::from_millis(500),
+        ..QuiescenceConfig::default()
+    });
+    assert_eq!(harness.handler.config().idle_timeout, Duration::from_millis(500));
+
+    run_exec_through_completed(&harness, 1, "cargo build", &[], 0).await;
+    drain_events(&mut harness.events);
+    advance_and_settle(Duration::from_millis(400)).await;
+    assert!(harness.handler.in_quiescence().await);
+    advance_and_settle(Duration::from_millis(100)).await;
+    assert!(!harness.handler.in_quiescence().await);
+}
+
+/// An ambient step holds at most `ambient_max_files` files and stays open
+/// at most `ambient_max_duration`; the write past either opens the next.
+#[tokio::test(start_paused = true)]
+async fn ambient_steps_roll_over_at_their_limits() {
+    let mut harness = create_test_harness(QuiescenceConfig {
+        ambient_max_duration: Duration::from_secs(8),
+        ambient_max_files: 2,
+        ..QuiescenceConfig::default()
+    });
+    for name in ["a.txt", "b.txt", "a.txt"] {
+        harness.handler.notify_fs_write_at(None, Some(Path::new(name))).await;
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(
+        drain_events(&mut harness.events),
+        vec![HandlerEvent::AmbientStepOpened { step_id: -1 }]
+    );
+
+    harness.handler.notify_fs_write_at(None, Some(Path::new("c.txt"))).await;
+    tokio::task::yield_now().await;
+    assert_eq!(
+        drain_events(&mut harness.events),
+        vec![
+            HandlerEvent::AmbientStepClosed { step_id: -1, evicted_steps: vec![] },
+            HandlerEvent::AmbientStepOpened { step_id: -2 },
+        ]
+    );
+
+    // Steady writes keep the step open only until its maximum duration.
+    for _ in 0..2 {
+        advance_and_settle(Duration::from_secs(3)).await;
+        harness.handler.notify_fs_write_at(None, Some(Path::new("c.txt"))).await;
+    }
+    assert_eq!(harness.handler.ambient_step_id().await, Some(-2));
+    advance_and_settle(Duration::from_secs(2)).await;
+    assert_eq!(harness.handler.ambient_step_id().await, None);
+    assert_eq!(
+        drain_events(&mut harness.events),
+        vec![HandlerEvent::AmbientStepClosed { step_id: -2, evicted_steps: vec![] }]
+    );
+}
+
+/// Writes of different working roots go to separate ambient steps, each
+/// recorded by its root's step manager and timed on its own.
+#[tokio::test(start_paused = true)]
+async fn ambient_steps_are_kept_per_root() {
+    let mut harness = default_harness();
+    let roots = [Arc::new(MockStepManager::default()), Arc::new(MockStepManager::default())];
+    harness.handler = harness.handler.with_root_step_managers(roots.to_vec());
+
+    harness.handler.notify_fs_write_at(Some(0), Some(Path::new("src/lib.rs"))).await;
+    harness.handler.notify_fs_write_at(Some(1), Some(Path::new("notes.md"))).await;
+    tokio::task::yield_now().await;
+    assert_eq!(harness.handler.ambient_step_in(Some(0)).await, Some(-1));
+    assert_eq!(harness.handler.ambient_step_in(Some(1)).await, Some(-2));
+    assert_eq!(roots[0].calls(), vec![StepManagerCall::OpenStep(-1)]);
+    assert_eq!(roots[1].calls(), vec![StepManagerCall::OpenStep(-2)]);
+    assert!(harness.step_manager.calls().is_empty());
+
+    // A write in the first root extends only its step.
+    advance_and_settle(Duration::from_secs(3)).await;
+    harness.handler.notify_fs_write_at(Some(0), Some(Path::new("src/lib.rs"))).await;
+    advance_and_settle(Duration::from_secs(2)).await;
+    assert_eq!(harness.handler.ambient_step_in(Some(0)).await, Some(-1));
+    assert_eq!(harness.handler.ambient_step_in(Some(1)).await, None);
+    assert_eq!(roots[1].calls().last(), Some(&StepManagerCall::CloseStep(-2)));
+
+    // A command closes every ambient step.
+    harness
+        .handler
+        .send_exec(1, "make".to_string(), None, None, false, false, None, Default::default())
+        .await;
+    assert_eq!(harness.handler.ambient_step_id().await, None);
+    assert_eq!(roots[0].calls().last(), Some(&StepManagerCall::CloseStep(-1)));
+    let closed: Vec<_> = drain_events(&mut harness.events)
+        .into_iter()
+        .filter(|event| matches!(event, HandlerEvent::AmbientStepClosed { .. }))
+        .collect();
+    assert_eq!(closed.len(), 2);
+}
@@ -0,0 +1,113 @@
+//! FR-01 through FR-02: Protocol version 2 framing over a real byte stream.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+use codeagent_control::{
+    HostMessage, Incoming, Link, OutputStream, VmMessage, lane_channel, parse_host_message,
+};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Copy lines from `from` to `to`, leaving out the `drop`th message frame.
+async fn lossy_relay(from: DuplexStream, mut to: DuplexStream, drop: usize) {
+    let mut lines = BufReader::new(from).lines();
+    let mut frames = 0;
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.starts_with(r#"{"seq""#) {
+            frames += 1;
+            if frames == drop {
+                continue;
+            }
+        }
+        if to.write_all(format!("{line}\n").as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn output(n: usize) -> VmMessage {
+    VmMessage::Output {
+        id: 1,
+        stream: OutputStream::Stdout,
+        data: format!("line {n}\n"),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// FR-01: A line lost in version 2 is sent again, in order
+// ---------------------------------------------------------------------------
+#[tokio::test]
+async fn fr_01_lost_line_is_sent_again() {
+    // Each stream is used in one direction only.
+    let (host_write, shim_read) = tokio::io::duplex(64 * 1024);
+    let (shim_write, relay_read) = tokio::io::duplex(64 * 1024);
+    let (relay_write, host_read) = tokio::io::duplex(64 * 1024);
+
+    // The relay drops the shim's second frame.
+    tokio::spawn(lossy_relay(relay_read, relay_write, 2));
+
+    let host = Link::new();
+    let shim = Link::new();
+    let (host_sender, host_messages) = lane_channel::<HostMessage>();
+    let (shim_sender, shim_messages) = lane_channel::<VmMessage>();
+    host.offer();
+    let writer = host.clone();
+    tokio::spawn(async move { writer.run_writer(host_messages, host_write).await });
+    let writer = shim.clone();
+    tokio::spawn(async move { writer.run_writer(shim_messages, shim_write).await });
+
+    // The shim answers the offer before it sends anything.
+    let shim_reader = shim.clone();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(shim_read).lines();
+        let mut sent = false;
+        while let Ok(Some(line)) = lines.next_line().await {
+            shim_reader.decode::<HostMessage>(&line);
+            if shim_reader.version() == 2 && !sent {
+                sent = true;
+                for n in 0..4 {
+                    shim_sender.send(output(n)).unwrap();
+                }
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(host_read).lines();
+    let mut received = Vec::new();
+    while received.len() < 4 {
+        let line = tokio::time::timeout(WAIT, lines.next_line())
+            .await
+            .expect("timed out waiting for output")
+            .unwrap()
+            .expect("channel closed");
+        if let Incoming::Message(message) = host.decode::<VmMessage>(&line) {
+            received.push(message);
+        }
+    }
+    assert_eq!(host.version(), 2);
+    assert_eq!(received, (0..4).map(output).collect::<Vec<_>>());
+    drop(host_sender);
+}
+
+// ---------------------------------------------------------------------------
+// FR-02: A version 1 shim ignores the offer and reads bare lines
+// ---------------------------------------------------------------------------
+#[tokio::test]
+async fn fr_02_version_one_peer_reads_bare_lines() {
+    let (host_side, shim_side) = tokio::io::duplex(64 * 1024);
+    let host = Link::new();
+    let (sender, messages) = lane_channel::<HostMessage>();
+    host.offer();
+    let writer = host.clone();
+    tokio::spawn(async move { writer.run_writer(messages, host_side).await });
+
+    sender.send(HostMessage::Cancel { id: 7 }).unwrap();
+    let mut lines = BufReader::new(shim_side).lines();
+    let offer = lines.next_line().await.unwrap().unwrap();
+    assert!(parse_host_message(&offer).is_err());
+    let message = tokio::time::timeout(WAIT, lines.next_line()).await.unwrap().unwrap().unwrap();
+    assert_eq!(parse_host_message(&message).unwrap(), HostMessage::Cancel { id: 7 });
+    assert_eq!(host.version(), 1);
+}
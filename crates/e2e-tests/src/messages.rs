@@ -145,6 +145,16 @@ pub fn safeguard_confirm(safeguard_id: &str, action: &str) -> (Value, String) {
     (msg, id)
 }
 
+/// Create a `system.cleanup` request.
+pub fn system_cleanup() -> (Value, String) {
+    let id = next_request_id();
+    let msg = json!({
+        "type": "system.cleanup",
+        "request_id": &id
+    });
+    (msg, id)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
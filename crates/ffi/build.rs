@@ -0,0 +1,17 @@
+//! Renders `codeagent_sandbox.h` into `OUT_DIR` from `src/header.rs`. The
+//! copy under `include/` is checked in, and a unit test keeps it current.
+
+use std::path::PathBuf;
+
+#[allow(dead_code)]
+mod header {
+    include!("src/header.rs");
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/header.rs");
+
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    std::fs::write(out_dir.join("codeagent_sandbox.h"), header::render())
+        .expect("failed to write the C header");
+}
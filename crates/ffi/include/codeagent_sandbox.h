@@ -0,0 +1,72 @@
+/* Generated from crates/ffi/src/header.rs by its build script. Do not edit. */
+
+#ifndef CODEAGENT_SANDBOX_H
+#define CODEAGENT_SANDBOX_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Payloads and results are UTF-8 JSON in the shapes of the STDIO API.
+ * Returned strings belong to the caller and are released with
+ * codeagent_string_free. On failure, a non-null `error_out` receives an
+ * error detail ({"code", "message", "field", "retryable"}),
+ * and null on success.
+ */
+
+/* An embedded sandbox with a started session. Opaque. */
+typedef struct CodeagentSandbox CodeagentSandbox;
+
+/*
+ * Start an embedded sandbox and its session. `config_json` is
+ * {"args": [...], "session": {...}}: `args` are the sandbox's command
+ * line flags without the program name, `session` a session.start payload.
+ * Returns null on failure.
+ */
+CodeagentSandbox *codeagent_sandbox_start(const char *config_json, char **error_out);
+
+/*
+ * agent.execute with an agent.execute payload. Blocks until the sandbox
+ * responds and returns the response data, or null on failure.
+ */
+char *codeagent_sandbox_execute(CodeagentSandbox *sandbox, const char *payload_json, char **error_out);
+
+/*
+ * undo.rollback with an undo.rollback payload. Blocks until the sandbox
+ * responds and returns the response data, or null on failure.
+ */
+char *codeagent_sandbox_rollback(CodeagentSandbox *sandbox, const char *payload_json, char **error_out);
+
+/*
+ * The next event envelope, stamped with seq, emitted_at and origin.
+ * Waits up to `timeout_ms` (0 only checks) and returns null when no
+ * event arrived. Events emitted while the session started are not kept.
+ */
+char *codeagent_sandbox_poll_event(CodeagentSandbox *sandbox, uint32_t timeout_ms);
+
+/*
+ * session.stop. The handle stays valid: it still needs
+ * codeagent_sandbox_free. Returns null on failure.
+ */
+char *codeagent_sandbox_stop(CodeagentSandbox *sandbox, char **error_out);
+
+/*
+ * Release a handle from codeagent_sandbox_start, dropping events not yet
+ * polled. Null is ignored.
+ */
+void codeagent_sandbox_free(CodeagentSandbox *sandbox);
+
+/*
+ * Release a string returned by this library, including the errors
+ * stored in `error_out`. Null is ignored.
+ */
+void codeagent_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CODEAGENT_SANDBOX_H */
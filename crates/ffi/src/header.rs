@@ -0,0 +1,109 @@
+// The C declarations of the exported functions, rendered into
+// `codeagent_sandbox.h` by the build script. The script includes this file
+// with `include!`, so it must not depend on anything outside std.
+
+/// One exported function as the header declares it.
+pub struct Declaration {
+    /// Comment lines above the prototype.
+    pub doc: &'static [&'static str],
+    /// The C prototype, without the trailing `;`.
+    pub prototype: &'static str,
+}
+
+impl Declaration {
+    /// The exported symbol: the identifier in front of the parameter list.
+    pub fn name(&self) -> &'static str {
+        let before_params = &self.prototype[..self.prototype.find('(').unwrap_or(0)];
+        before_params.rsplit([' ', '*']).next().unwrap_or_default()
+    }
+}
+
+/// Guard macro of the header.
+pub const INCLUDE_GUARD: &str = "CODEAGENT_SANDBOX_H";
+
+/// Every `extern "C"` function of the library, in header order.
+pub const DECLARATIONS: &[Declaration] = &[
+    Declaration {
+        doc: &[
+            "Start an embedded sandbox and its session. `config_json` is",
+            "{\"args\": [...], \"session\": {...}}: `args` are the sandbox's command",
+            "line flags without the program name, `session` a session.start payload.",
+            "Returns null on failure.",
+        ],
+        prototype: "CodeagentSandbox *codeagent_sandbox_start(const char *config_json, char **error_out)",
+    },
+    Declaration {
+        doc: &[
+            "agent.execute with an agent.execute payload. Blocks until the sandbox",
+            "responds and returns the response data, or null on failure.",
+        ],
+        prototype: "char *codeagent_sandbox_execute(CodeagentSandbox *sandbox, const char *payload_json, char **error_out)",
+    },
+    Declaration {
+        doc: &[
+            "undo.rollback with an undo.rollback payload. Blocks until the sandbox",
+            "responds and returns the response data, or null on failure.",
+        ],
+        prototype: "char *codeagent_sandbox_rollback(CodeagentSandbox *sandbox, const char *payload_json, char **error_out)",
+    },
+    Declaration {
+        doc: &[
+            "The next event envelope, stamped with seq, emitted_at and origin.",
+            "Waits up to `timeout_ms` (0 only checks) and returns null when no",
+            "event arrived. Events emitted while the session started are not kept.",
+        ],
+        prototype: "char *codeagent_sandbox_poll_event(CodeagentSandbox *sandbox, uint32_t timeout_ms)",
+    },
+    Declaration {
+        doc: &[
+            "session.stop. The handle stays valid: it still needs",
+            "codeagent_sandbox_free. Returns null on failure.",
+        ],
+        prototype: "char *codeagent_sandbox_stop(CodeagentSandbox *sandbox, char **error_out)",
+    },
+    Declaration {
+        doc: &[
+            "Release a handle from codeagent_sandbox_start, dropping events not yet",
+            "polled. Null is ignored.",
+        ],
+        prototype: "void codeagent_sandbox_free(CodeagentSandbox *sandbox)",
+    },
+    Declaration {
+        doc: &[
+            "Release a string returned by this library, including the errors",
+            "stored in `error_out`. Null is ignored.",
+        ],
+        prototype: "void codeagent_string_free(char *string)",
+    },
+];
+
+/// The whole header.
+pub fn render() -> String {
+    let mut header = String::new();
+    header.push_str("/* Generated from crates/ffi/src/header.rs by its build script. Do not edit. */\n\n");
+    header.push_str(&format!("#ifndef {INCLUDE_GUARD}\n#define {INCLUDE_GUARD}\n\n"));
+    header.push_str("#include <stdint.h>\n\n");
+    header.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+    header.push_str(concat!(
+        "/*\n",
+        " * Payloads and results are UTF-8 JSON in the shapes of the STDIO API.\n",
+        " * Returned strings belong to the caller and are released with\n",
+        " * codeagent_string_free. On failure, a non-null `error_out` receives an\n",
+        " * error detail ({\"code\", \"message\", \"field\", \"retryable\"}),\n",
+        " * and null on success.\n",
+        " */\n\n",
+    ));
+    header.push_str("/* An embedded sandbox with a started session. Opaque. */\n");
+    header.push_str("typedef struct CodeagentSandbox CodeagentSandbox;\n");
+    for declaration in DECLARATIONS {
+        header.push_str("\n/*\n");
+        for line in declaration.doc {
+            header.push_str(&format!(" * {line}\n"));
+        }
+        header.push_str(" */\n");
+        header.push_str(&format!("{};\n", declaration.prototype));
+    }
+    header.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n");
+    header.push_str(&format!("#endif /* {INCLUDE_GUARD} */\n"));
+    header
+}
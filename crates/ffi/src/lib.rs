@@ -0,0 +1,326 @@
+//! C bindings for embedding the sandbox.
+//!
+//! A thin `extern "C"` layer over [`SandboxClient`] for hosts that cannot
+//! link Rust, such as a VS Code native module or a JetBrains plugin over
+//! JNI. Payloads, responses and events cross the boundary as UTF-8 JSON in
+//! the shapes of the STDIO API, so a host speaks the protocol it already
+//! knows without spawning the binary. Each handle owns a tokio runtime that
+//! its calls block on.
+//!
+//! The build script renders `codeagent_sandbox.h` from [`header`] into
+//! `OUT_DIR`. Tests keep the declarations in step with the functions below
+//! and the checked-in `include/codeagent_sandbox.h` in step with both.
+
+pub mod header;
+
+use std::ffi::{CStr, CString, c_char};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use clap::Parser;
+use codeagent_common::ErrorCode;
+use codeagent_sandbox::cli::CliArgs;
+use codeagent_sandbox::client::{SandboxClient, SandboxConfig};
+use codeagent_sandbox::config::load_config;
+use codeagent_stdio::protocol::SessionStartPayload;
+use codeagent_stdio::{Event, EventHub, StdioError};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+/// Program name put in front of the `args` of a start config for clap.
+const PROGRAM_NAME: &str = "sandbox";
+
+/// An embedded sandbox with a started session.
+pub struct CodeagentSandbox {
+    client: SandboxClient,
+    events: Mutex<EventStream>,
+    runtime: Runtime,
+}
+
+/// The subscription `codeagent_sandbox_poll_event` drains, with the hub that
+/// stamps what it hands out. One lock keeps `seq` in delivery order.
+struct EventStream {
+    receiver: mpsc::UnboundedReceiver<Event>,
+    hub: EventHub,
+}
+
+/// The `config_json` of `codeagent_sandbox_start`.
+#[derive(Deserialize)]
+struct StartConfig {
+    /// Command line flags, without the program name.
+    #[serde(default)]
+    args: Vec<String>,
+    session: SessionStartPayload,
+}
+
+/// Start an embedded sandbox and its session.
+///
+/// # Safety
+///
+/// `config_json` is null or a NUL-terminated string; `error_out` is null or
+/// points to writable storage for a pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn codeagent_sandbox_start(
+    config_json: *const c_char,
+    error_out: *mut *mut c_char,
+) -> *mut CodeagentSandbox {
+    let start = || -> Result<_, StdioError> {
+        let StartConfig { args, session } = unsafe { parse_json(config_json, "config_json") }?;
+        let args = std::iter::once(PROGRAM_NAME.to_string()).chain(args);
+        let args = CliArgs::try_parse_from(args).map_err(|e| StdioError::InvalidField {
+            field: "args".to_string(),
+            message: e.to_string(),
+        })?;
+        let settings = load_config(args.config_file.as_deref());
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+        let client =
+            runtime.block_on(SandboxClient::start(SandboxConfig { args, settings, session }))?;
+        let receiver = client.subscribe_events();
+        Ok(Box::into_raw(Box::new(CodeagentSandbox {
+            client,
+            events: Mutex::new(EventStream { receiver, hub: EventHub::new() }),
+            runtime,
+        })))
+    };
+    unsafe { guarded(error_out, start) }.unwrap_or(ptr::null_mut())
+}
+
+/// `agent.execute`.
+///
+/// # Safety
+///
+/// `sandbox` is null or a live handle from [`codeagent_sandbox_start`];
+/// `payload_json` and `error_out` as for [`codeagent_sandbox_start`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn codeagent_sandbox_execute(
+    sandbox: *mut CodeagentSandbox,
+    payload_json: *const c_char,
+    error_out: *mut *mut c_char,
+) -> *mut c_char {
+    let execute = || {
+        let sandbox = unsafe { sandbox_ref(sandbox) }?;
+        let payload = unsafe { parse_json(payload_json, "payload_json") }?;
+        sandbox.runtime.block_on(sandbox.client.execute(payload))
+    };
+    unsafe { respond(error_out, execute) }
+}
+
+/// `undo.rollback`.
+///
+/// # Safety
+///
+/// As for [`codeagent_sandbox_execute`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn codeagent_sandbox_rollback(
+    sandbox: *mut CodeagentSandbox,
+    payload_json: *const c_char,
+    error_out: *mut *mut c_char,
+) -> *mut c_char {
+    let rollback = || {
+        let sandbox = unsafe { sandbox_ref(sandbox) }?;
+        let payload = unsafe { parse_json(payload_json, "payload_json") }?;
+        sandbox.runtime.block_on(sandbox.client.rollback(payload))
+    };
+    unsafe { respond(error_out, rollback) }
+}
+
+/// The next event as a stamped envelope, or null when none arrives within
+/// `timeout_ms`.
+///
+/// # Safety
+///
+/// `sandbox` is null or a live handle from [`codeagent_sandbox_start`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn codeagent_sandbox_poll_event(
+    sandbox: *mut CodeagentSandbox,
+    timeout_ms: u32,
+) -> *mut c_char {
+    let poll = || {
+        let sandbox = unsafe { sandbox_ref(sandbox) }?;
+        let mut events = sandbox.events.lock().unwrap();
+        let EventStream { receiver, hub } = &mut *events;
+        // `timeout` polls the receiver once before looking at the clock, so
+        // a timeout of 0 still returns an event that is already queued.
+        let event = sandbox.runtime.block_on(async {
+            tokio::time::timeout(Duration::from_millis(timeout_ms.into()), receiver.recv())
+                .await
+                .ok()
+                .flatten()
+        });
+        let Some(event) = event else {
+            return Ok(None);
+        };
+        let mut envelope = event.to_envelope();
+        hub.stamp(&mut envelope);
+        serde_json::to_string(&envelope)
+            .map(Some)
+            .map_err(|source| StdioError::MalformedJson { source })
+    };
+    unsafe { guarded(ptr::null_mut(), poll) }
+        .flatten()
+        .map_or(ptr::null_mut(), into_c_string)
+}
+
+/// `session.stop`.
+///
+/// # Safety
+///
+/// `sandbox` is null or a live handle from [`codeagent_sandbox_start`];
+/// `error_out` as for [`codeagent_sandbox_start`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn codeagent_sandbox_stop(
+    sandbox: *mut CodeagentSandbox,
+    error_out: *mut *mut c_char,
+) -> *mut c_char {
+    let stop = || {
+        let sandbox = unsafe { sandbox_ref(sandbox) }?;
+        sandbox.runtime.block_on(sandbox.client.stop())
+    };
+    unsafe { respond(error_out, stop) }
+}
+
+/// Release a handle.
+///
+/// # Safety
+///
+/// `sandbox` is null or a handle from [`codeagent_sandbox_start`] that no
+/// other thread is using; it is invalid afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn codeagent_sandbox_free(sandbox: *mut CodeagentSandbox) {
+    if sandbox.is_null() {
+        return;
+    }
+    let CodeagentSandbox { client, events, runtime } = *unsafe { Box::from_raw(sandbox) };
+    let _ = panic::catch_unwind(AssertUnwindSafe(move || {
+        // The client's tasks belong to the runtime: drop them inside it,
+        // then shut it down.
+        let context = runtime.enter();
+        drop(client);
+        drop(events);
+        drop(context);
+        drop(runtime);
+    }));
+}
+
+/// Release a string this library returned.
+///
+/// # Safety
+///
+/// `string` is null or a string from this library that has not been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn codeagent_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Run `body`, reporting its error, or a panic, which must not unwind into
+/// the host, through `error_out`. Clears `error_out` on success.
+///
+/// # Safety
+///
+/// `error_out` is null or points to writable storage for a pointer.
+unsafe fn guarded<T>(
+    error_out: *mut *mut c_char,
+    body: impl FnOnce() -> Result<T, StdioError>,
+) -> Option<T> {
+    let result = panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(StdioError::Failed {
+            code: ErrorCode::Internal,
+            message: format!("request panicked: {message}"),
+        })
+    });
+    let (value, error) = match result {
+        Ok(value) => (Some(value), ptr::null_mut()),
+        Err(error) => {
+            let detail = serde_json::to_string(&error.to_error_detail()).unwrap_or_default();
+            (None, into_c_string(detail))
+        }
+    };
+    if !error_out.is_null() {
+        unsafe { *error_out = error };
+    } else if !error.is_null() {
+        drop(unsafe { CString::from_raw(error) });
+    }
+    value
+}
+
+/// [`guarded`] for calls that return response data.
+///
+/// # Safety
+///
+/// As for [`guarded`].
+unsafe fn respond(
+    error_out: *mut *mut c_char,
+    body: impl FnOnce() -> Result<serde_json::Value, StdioError>,
+) -> *mut c_char {
+    unsafe { guarded(error_out, body) }
+        .map_or(ptr::null_mut(), |value| into_c_string(value.to_string()))
+}
+
+/// # Safety
+///
+/// `sandbox` is null or a live handle.
+unsafe fn sandbox_ref<'a>(sandbox: *mut CodeagentSandbox) -> Result<&'a CodeagentSandbox, StdioError> {
+    unsafe { sandbox.as_ref() }.ok_or_else(|| StdioError::MissingField {
+        field: "sandbox".to_string(),
+    })
+}
+
+/// Decode the JSON string argument `field`.
+///
+/// # Safety
+///
+/// `json` is null or a NUL-terminated string.
+unsafe fn parse_json<T: DeserializeOwned>(json: *const c_char, field: &str) -> Result<T, StdioError> {
+    if json.is_null() {
+        return Err(StdioError::MissingField { field: field.to_string() });
+    }
+    let text = unsafe { CStr::from_ptr(json) }.to_str().map_err(|e| StdioError::InvalidField {
+        field: field.to_string(),
+        message: e.to_string(),
+    })?;
+    serde_json::from_str(text).map_err(|source| StdioError::MalformedJson { source })
+}
+
+/// Hand `text` to the host. Serialized JSON escapes NUL, so this only
+/// returns null for text that did not come from `serde_json`.
+fn into_c_string(text: String) -> *mut c_char {
+    CString::new(text).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::header::{DECLARATIONS, render};
+
+    #[test]
+    fn header_declares_every_exported_function() {
+        let source = include_str!("lib.rs");
+        let exported: Vec<&str> = source
+            .split("pub unsafe extern \"C\" fn ")
+            .skip(1)
+            .map(|rest| &rest[..rest.find('(').unwrap()])
+            .collect();
+        let declared: Vec<&str> = DECLARATIONS.iter().map(|d| d.name()).collect();
+        assert_eq!(exported, declared);
+    }
+
+    #[test]
+    fn checked_in_header_is_current() {
+        let generated = concat!(env!("OUT_DIR"), "/codeagent_sandbox.h");
+        assert_eq!(
+            include_str!("../include/codeagent_sandbox.h"),
+            render(),
+            "include/codeagent_sandbox.h is stale; copy {generated} over it"
+        );
+    }
+}
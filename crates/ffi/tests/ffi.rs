@@ -0,0 +1,108 @@
+use std::ffi::{CStr, CString, c_char};
+use std::ptr;
+
+use codeagent_ffi::{
+    codeagent_sandbox_free, codeagent_sandbox_poll_event, codeagent_sandbox_rollback,
+    codeagent_sandbox_start, codeagent_sandbox_stop, codeagent_string_free,
+};
+use serde_json::{Value, json};
+use tempfile::TempDir;
+
+/// Take ownership of a string the library returned and decode it.
+fn take_json(string: *mut c_char) -> Value {
+    assert!(!string.is_null());
+    let value = serde_json::from_str(unsafe { CStr::from_ptr(string) }.to_str().unwrap()).unwrap();
+    unsafe { codeagent_string_free(string) };
+    value
+}
+
+fn start_config(working: &TempDir, undo: &TempDir) -> CString {
+    let config = json!({
+        "args": [
+            "--working-dir", working.path(),
+            "--undo-dir", undo.path(),
+            "--config-file", working.path().join("absent.toml"),
+        ],
+        "session": {
+            "working_directories": [{ "path": working.path() }],
+            "network_policy": "disabled",
+            "vm_mode": "ephemeral",
+        },
+    });
+    CString::new(config.to_string()).unwrap()
+}
+
+// -----------------------------------------------------------------------
+// FF-01: a session started, rolled back, polled and stopped through C
+// -----------------------------------------------------------------------
+#[test]
+fn ff_01_drives_a_session_through_the_c_functions() {
+    let working = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    let config = start_config(&working, &undo);
+    let mut error = ptr::null_mut();
+
+    let sandbox = unsafe { codeagent_sandbox_start(config.as_ptr(), &mut error) };
+    assert!(error.is_null());
+    assert!(!sandbox.is_null());
+
+    // Nothing recorded yet: the error comes back as an error detail.
+    let rollback = CString::new(json!({ "count": 1, "strict": true }).to_string()).unwrap();
+    let response = unsafe { codeagent_sandbox_rollback(sandbox, rollback.as_ptr(), &mut error) };
+    assert!(response.is_null());
+    assert_eq!(take_json(error)["code"], "insufficient_history");
+
+    let mut seqs = Vec::new();
+    loop {
+        let event = unsafe { codeagent_sandbox_poll_event(sandbox, 0) };
+        if event.is_null() {
+            break;
+        }
+        let event = take_json(event);
+        assert!(event["emitted_at"].is_string());
+        assert!(event["origin"].is_string());
+        seqs.push(event["seq"].as_u64().unwrap());
+    }
+    assert!(seqs.iter().enumerate().all(|(index, &seq)| seq == index as u64 + 1));
+
+    let stop = unsafe { codeagent_sandbox_stop(sandbox, &mut error) };
+    assert!(error.is_null());
+    take_json(stop);
+    unsafe { codeagent_sandbox_free(sandbox) };
+}
+
+// -----------------------------------------------------------------------
+// FF-02: bad arguments fail with an error detail instead of crashing
+// -----------------------------------------------------------------------
+#[test]
+fn ff_02_bad_arguments_are_reported() {
+    let mut error = ptr::null_mut();
+
+    let sandbox = unsafe { codeagent_sandbox_start(ptr::null(), &mut error) };
+    assert!(sandbox.is_null());
+    let detail = take_json(error);
+    assert_eq!(detail["code"], "missing_field");
+    assert_eq!(detail["field"], "config_json");
+
+    let malformed = CString::new("{").unwrap();
+    let sandbox = unsafe { codeagent_sandbox_start(malformed.as_ptr(), &mut error) };
+    assert!(sandbox.is_null());
+    assert_eq!(take_json(error)["code"], "malformed_json");
+
+    let working = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    let mut config: Value =
+        serde_json::from_str(start_config(&working, &undo).to_str().unwrap()).unwrap();
+    config["args"] = json!(["--no-such-flag"]);
+    let config = CString::new(config.to_string()).unwrap();
+    let sandbox = unsafe { codeagent_sandbox_start(config.as_ptr(), &mut error) };
+    assert!(sandbox.is_null());
+    assert_eq!(take_json(error)["field"], "args");
+
+    // A null handle is an error too, and a null error_out is allowed.
+    let stop = unsafe { codeagent_sandbox_stop(ptr::null_mut(), ptr::null_mut()) };
+    assert!(stop.is_null());
+    assert!(unsafe { codeagent_sandbox_poll_event(ptr::null_mut(), 0) }.is_null());
+    unsafe { codeagent_sandbox_free(ptr::null_mut()) };
+    unsafe { codeagent_string_free(ptr::null_mut()) };
+}
@@ -0,0 +1,169 @@
+//! Compressed preimage blobs kept for reuse.
+//!
+//! Agents tend to rewrite the same few files step after step, so the same
+//! contents get captured and compressed over and over. The cache remembers
+//! the compressed blob of recently captured contents under the blake3 hash
+//! of those contents, as a hard link in `{undo_dir}/blobs/`. A capture whose
+//! contents hash to a remembered blob links (or, across filesystems, copies)
+//! it into the step instead of compressing again.
+//!
+//! Blobs are never modified in place -- every `.dat` is written to a temp
+//! file and renamed over -- so a step sharing an inode with the cache cannot
+//! see it change. Dropping a blob from the cache removes only the cache's own
+//! link; steps keep theirs.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// How many blobs the cache keeps by default.
+pub const DEFAULT_BLOB_CACHE_ENTRIES: usize = 64;
+
+#[derive(Default)]
+struct CacheEntries {
+    /// Content hashes, least recently used first.
+    order: VecDeque<String>,
+    /// Compressed size of each cached blob.
+    sizes: HashMap<String, u64>,
+}
+
+/// Recently captured compressed contents, keyed by content hash.
+pub struct PreimageBlobCache {
+    dir: PathBuf,
+    capacity: usize,
+    entries: Mutex<CacheEntries>,
+}
+
+impl PreimageBlobCache {
+    /// A cache of up to `capacity` blobs in `dir`. Blobs left there by an
+    /// earlier session are discarded, since nothing records what they hold.
+    pub fn new(dir: PathBuf, capacity: usize) -> Self {
+        let _ = fs::remove_dir_all(&dir);
+        Self {
+            dir,
+            capacity,
+            entries: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    /// Hash `contents` for use as a cache key.
+    pub fn content_hash(contents: &[u8]) -> String {
+        blake3::hash(contents).to_hex().to_string()
+    }
+
+    /// Place the cached blob for `content_hash` at `data_path`. Returns its
+    /// compressed size, or `None` if the blob is not cached.
+    pub fn reuse(&self, content_hash: &str, data_path: &Path) -> Option<u64> {
+        let mut entries = self.entries.lock().unwrap();
+        let size = *entries.sizes.get(content_hash)?;
+        let blob = self.blob_path(content_hash);
+        let _ = fs::remove_file(data_path);
+        if fs::hard_link(&blob, data_path).is_err() && fs::copy(&blob, data_path).is_err() {
+            // The blob went missing, e.g. when the undo log was discarded.
+            entries.sizes.remove(content_hash);
+            entries.order.retain(|hash| hash != content_hash);
+            return None;
+        }
+        entries.order.retain(|hash| hash != content_hash);
+        entries.order.push_back(content_hash.to_string());
+        Some(size)
+    }
+
+    /// Remember the blob just written to `data_path` as the compressed form
+    /// of `content_hash`. Failures only mean the next capture compresses.
+    pub fn insert(&self, content_hash: &str, data_path: &Path, size: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.sizes.contains_key(content_hash) {
+            return;
+        }
+        let blob = self.blob_path(content_hash);
+        if fs::create_dir_all(&self.dir).is_err() || fs::hard_link(data_path, &blob).is_err() {
+            return;
+        }
+        entries.sizes.insert(content_hash.to_string(), size);
+        entries.order.push_back(content_hash.to_string());
+        while entries.order.len() > self.capacity {
+            if let Some(evicted) = entries.order.pop_front() {
+                entries.sizes.remove(&evicted);
+                let _ = fs::remove_file(self.blob_path(&evicted));
+            }
+        }
+    }
+
+    /// Forget every blob, e.g. after the undo log they were captured into
+    /// is discarded.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        *entries = CacheEntries::default();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+
+    /// Number of blobs currently cached.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn blob_path(&self, content_hash: &str) -> PathBuf {
+        self.dir.join(format!("{content_hash}.dat"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn reused_blobs_match_the_original() {
+        let dir = TempDir::new().unwrap();
+        let cache = PreimageBlobCache::new(dir.path().join("blobs"), 4);
+        let original = dir.path().join("a.dat");
+        fs::write(&original, b"compressed").unwrap();
+        let hash = PreimageBlobCache::content_hash(b"contents");
+
+        let copy = dir.path().join("b.dat");
+        assert_eq!(cache.reuse(&hash, &copy), None);
+        cache.insert(&hash, &original, 10);
+        assert_eq!(cache.reuse(&hash, &copy), Some(10));
+        assert_eq!(fs::read(&copy).unwrap(), b"compressed");
+
+        // Removing every step's link leaves the cache's.
+        fs::remove_file(&original).unwrap();
+        fs::remove_file(&copy).unwrap();
+        assert_eq!(cache.reuse(&hash, &copy), Some(10));
+    }
+
+    #[test]
+    fn least_recently_used_blobs_are_dropped() {
+        let dir = TempDir::new().unwrap();
+        let blobs = dir.path().join("blobs");
+        let cache = PreimageBlobCache::new(blobs.clone(), 2);
+        let hashes: Vec<String> = (0..3u8)
+            .map(|n| {
+                let data = dir.path().join(format!("{n}.dat"));
+                fs::write(&data, [n]).unwrap();
+                let hash = PreimageBlobCache::content_hash(&[n]);
+                cache.insert(&hash, &data, 1);
+                if n == 1 {
+                    // Touch the first blob so the second is the oldest.
+                    let first = PreimageBlobCache::content_hash(&[0]);
+                    assert!(cache.reuse(&first, &dir.path().join("x")).is_some());
+                }
+                hash
+            })
+            .collect();
+
+        assert_eq!(cache.len(), 2);
+        assert!(!blobs.join(format!("{}.dat", hashes[1])).exists());
+        assert!(cache.reuse(&hashes[0], &dir.path().join("y")).is_some());
+        assert!(cache.reuse(&hashes[1], &dir.path().join("z")).is_none());
+    }
+}
@@ -0,0 +1,150 @@
+use std::path::{Component, Path, PathBuf};
+
+/// Canonical form of a working root, used to check that paths reached
+/// through symlinks stay inside it.
+///
+/// Preimage capture must not read content from outside the working root, and
+/// rollback must not write outside it, even when a directory component or a
+/// restored symlink points elsewhere.
+#[derive(Debug, Clone)]
+pub struct WorkingRootBoundary {
+    root: PathBuf,
+    canonical_root: PathBuf,
+}
+
+impl WorkingRootBoundary {
+    pub fn new(working_root: &Path) -> Self {
+        Self {
+            root: working_root.to_path_buf(),
+            canonical_root: working_root
+                .canonicalize()
+                .unwrap_or_else(|_| working_root.to_path_buf()),
+        }
+    }
+
+    /// Whether the directory containing `path` resolves inside the working
+    /// root. The final component is not followed, so a symlink at `path`
+    /// itself is fine. Missing directories are judged by their nearest
+    /// existing ancestor.
+    pub fn contains_parent_of(&self, path: &Path) -> bool {
+        let Some(parent) = path.parent() else {
+            return false;
+        };
+        if parent.components().any(|c| c == Component::ParentDir) {
+            return false;
+        }
+        parent
+            .ancestors()
+            .find_map(|ancestor| ancestor.canonicalize().ok())
+            .is_some_and(|resolved| resolved.starts_with(&self.canonical_root))
+    }
+
+    /// Whether any existing directory component between the working root and
+    /// `path` (exclusive) is a symlink.
+    pub fn parent_traverses_symlink(&self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        let mut current = self.root.clone();
+        let mut components = relative.components().peekable();
+        while let Some(component) = components.next() {
+            if components.peek().is_none() {
+                break;
+            }
+            current.push(component);
+            match current.symlink_metadata() {
+                Ok(metadata) if metadata.file_type().is_symlink() => return true,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        false
+    }
+
+    /// Whether a symlink at `link_path` pointing to `target` would resolve
+    /// inside the working root. The target is resolved lexically against the
+    /// link's (real) parent directory, since it may not exist.
+    pub fn contains_symlink_target(&self, link_path: &Path, target: &Path) -> bool {
+        let base = if target.is_absolute() {
+            PathBuf::new()
+        } else {
+            let Some(parent) = link_path.parent() else {
+                return false;
+            };
+            parent
+                .canonicalize()
+                .unwrap_or_else(|_| parent.to_path_buf())
+        };
+        let mut resolved = base;
+        for component in target.components() {
+            match component {
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::CurDir => {}
+                other => resolved.push(other),
+            }
+        }
+        resolved.starts_with(&self.canonical_root) || resolved.starts_with(&self.root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn plain_paths_are_contained() {
+        let tmp = TempDir::new().unwrap();
+        let boundary = WorkingRootBoundary::new(tmp.path());
+        std::fs::create_dir(tmp.path().join("src")).unwrap();
+
+        assert!(boundary.contains_parent_of(&tmp.path().join("src").join("main.rs")));
+        assert!(boundary.contains_parent_of(&tmp.path().join("new").join("file.txt")));
+        assert!(!boundary.parent_traverses_symlink(&tmp.path().join("src").join("main.rs")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directory_outside_root_escapes() {
+        let tmp = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        std::os::unix::fs::symlink(outside.path(), tmp.path().join("escape")).unwrap();
+        let boundary = WorkingRootBoundary::new(tmp.path());
+
+        let path = tmp.path().join("escape").join("secret.txt");
+        assert!(!boundary.contains_parent_of(&path));
+        assert!(boundary.parent_traverses_symlink(&path));
+        assert!(!boundary.contains_parent_of(&tmp.path().join("escape").join("new").join("f")));
+        // The link itself lives inside the root.
+        assert!(boundary.contains_parent_of(&tmp.path().join("escape")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directory_inside_root_is_contained() {
+        let tmp = TempDir::new().unwrap();
+        std::fs::create_dir(tmp.path().join("real")).unwrap();
+        std::os::unix::fs::symlink(tmp.path().join("real"), tmp.path().join("alias")).unwrap();
+        let boundary = WorkingRootBoundary::new(tmp.path());
+
+        let path = tmp.path().join("alias").join("file.txt");
+        assert!(boundary.contains_parent_of(&path));
+        assert!(boundary.parent_traverses_symlink(&path));
+    }
+
+    #[test]
+    fn symlink_targets_resolve_lexically() {
+        let tmp = TempDir::new().unwrap();
+        std::fs::create_dir(tmp.path().join("dir")).unwrap();
+        let boundary = WorkingRootBoundary::new(tmp.path());
+        let link = tmp.path().join("dir").join("link");
+
+        assert!(boundary.contains_symlink_target(&link, Path::new("../other.txt")));
+        assert!(boundary.contains_symlink_target(&link, Path::new("./missing/file")));
+        assert!(!boundary.contains_symlink_target(&link, Path::new("../../outside")));
+        assert!(!boundary.contains_symlink_target(&link, Path::new("/etc/passwd")));
+        assert!(boundary.contains_symlink_target(&link, &tmp.path().join("abs.txt")));
+    }
+}
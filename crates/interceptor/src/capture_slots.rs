@@ -0,0 +1,111 @@
+//! First captures in progress, so preimages are read and compressed outside
+//! the interceptor lock.
+//!
+//! A thread capturing a path claims it here (and the inode, for regular
+//! files) under the interceptor lock, marks it touched, and releases the lock
+//! for the I/O. Another operation on the same path or inode finds the claim
+//! and waits for its release before going on, so nothing mutates a file
+//! whose preimage is still being read. Claims are sharded by key: captures
+//! of different paths neither contend on one lock nor wake each other.
+
+use std::collections::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Condvar, Mutex};
+
+use codeagent_common::StepId;
+
+/// Number of independently locked shards.
+const SHARD_COUNT: usize = 16;
+
+#[derive(Default)]
+struct Shard {
+    claimed: Mutex<HashSet<(StepId, String)>>,
+    released: Condvar,
+}
+
+/// Keys of the captures in progress, per step.
+pub struct CaptureSlots {
+    shards: Vec<Shard>,
+}
+
+impl Default for CaptureSlots {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARD_COUNT).map(|_| Shard::default()).collect(),
+        }
+    }
+}
+
+impl CaptureSlots {
+    /// Key under which the capture of the inode `(dev, inode)` is claimed.
+    /// Never a relative path, which has no NUL.
+    pub fn inode_key((dev, inode): (u64, u64)) -> String {
+        format!("\0inode:{dev}:{inode}")
+    }
+
+    fn shard(&self, step_id: StepId, key: &str) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        (step_id, key).hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Claim `key` in `step_id`. False if it is already claimed.
+    pub fn try_claim(&self, step_id: StepId, key: &str) -> bool {
+        let shard = self.shard(step_id, key);
+        shard.claimed.lock().unwrap().insert((step_id, key.to_string()))
+    }
+
+    pub fn is_claimed(&self, step_id: StepId, key: &str) -> bool {
+        let shard = self.shard(step_id, key);
+        shard.claimed.lock().unwrap().contains(&(step_id, key.to_string()))
+    }
+
+    /// Release a claim, waking the threads waiting for it.
+    pub fn release(&self, step_id: StepId, key: &str) {
+        let shard = self.shard(step_id, key);
+        shard.claimed.lock().unwrap().remove(&(step_id, key.to_string()));
+        shard.released.notify_all();
+    }
+
+    /// Block until `key` is not claimed in `step_id`.
+    pub fn wait_released(&self, step_id: StepId, key: &str) {
+        let shard = self.shard(step_id, key);
+        let entry = (step_id, key.to_string());
+        let mut claimed = shard.claimed.lock().unwrap();
+        while claimed.contains(&entry) {
+            claimed = shard.released.wait(claimed).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn claims_are_exclusive_per_step_and_waited_for() {
+        let slots = Arc::new(CaptureSlots::default());
+        assert!(slots.try_claim(1, "src/lib.rs"));
+        assert!(!slots.try_claim(1, "src/lib.rs"));
+        assert!(slots.try_claim(2, "src/lib.rs"));
+        assert!(slots.try_claim(1, &CaptureSlots::inode_key((7, 42))));
+
+        let released = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let (slots, released) = (Arc::clone(&slots), Arc::clone(&released));
+            std::thread::spawn(move || {
+                slots.wait_released(1, "src/lib.rs");
+                released.load(Ordering::SeqCst)
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        released.store(true, Ordering::SeqCst);
+        slots.release(1, "src/lib.rs");
+        assert!(waiter.join().unwrap());
+        assert!(!slots.is_claimed(1, "src/lib.rs"));
+        assert!(slots.is_claimed(2, "src/lib.rs"));
+    }
+}
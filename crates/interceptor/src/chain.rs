@@ -0,0 +1,256 @@
+//! Hash chain over closed step manifests, for `undo.attest`.
+//!
+//! Each manifest records `chain_prev`, the hash of the manifest of the step
+//! closed before it, and `{undo_dir}/chain_head.json` records the newest
+//! hash. Editing, inserting or deleting a manifest breaks a link; deleting
+//! the newest steps leaves the head pointing past the end of the chain.
+//! Rollback and eviction update the head file, so only changes made behind
+//! the interceptor's back show up as breaks.
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use codeagent_common::StepId;
+
+use crate::manifest::StepManifest;
+
+/// `chain_prev` of the first step closed in a fresh undo directory.
+pub const GENESIS_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+const HEAD_FILE: &str = "chain_head.json";
+
+/// Contents of `chain_head.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainHead {
+    /// Expected `chain_prev` of the oldest chained step still on disk. Moves
+    /// forward as old steps are evicted.
+    pub anchor: String,
+    /// Manifest hash of the newest step, or `anchor` when no chained step is left.
+    pub head: String,
+    pub head_step: Option<StepId>,
+}
+
+impl Default for ChainHead {
+    fn default() -> Self {
+        Self {
+            anchor: GENESIS_HASH.to_string(),
+            head: GENESIS_HASH.to_string(),
+            head_step: None,
+        }
+    }
+}
+
+impl ChainHead {
+    /// Read the head file. `Ok(None)` if there is none yet.
+    pub fn read_from(undo_dir: &Path) -> codeagent_common::Result<Option<Self>> {
+        let path = undo_dir.join(HEAD_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json = fs::read_to_string(path)?;
+        Ok(Some(serde_json::from_str(&json)?))
+    }
+
+    /// Replace the head file atomically.
+    pub fn write_to(&self, undo_dir: &Path) -> codeagent_common::Result<()> {
+        let tmp = undo_dir.join(format!("{HEAD_FILE}.tmp"));
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, undo_dir.join(HEAD_FILE))?;
+        Ok(())
+    }
+
+    /// A step was closed with the given manifest hash.
+    pub fn advance(&mut self, step_id: StepId, hash: String) {
+        self.head = hash;
+        self.head_step = Some(step_id);
+    }
+
+    /// The newest step was rolled back; `previous_step` is the step before it.
+    pub fn rewind(&mut self, link: &ChainLink, previous_step: Option<StepId>) {
+        self.head = link.prev.clone();
+        self.head_step = if self.head == self.anchor {
+            None
+        } else {
+            previous_step
+        };
+    }
+
+    /// The oldest chained step was evicted.
+    pub fn drop_oldest(&mut self, link: &ChainLink) {
+        self.anchor = link.hash.clone();
+        if self.anchor == self.head {
+            self.head_step = None;
+        }
+    }
+}
+
+/// A step's position in the chain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainLink {
+    pub prev: String,
+    pub hash: String,
+}
+
+/// Hex blake3 hash of `manifest.json` in `step_dir`, exactly as stored.
+pub fn manifest_hash(step_dir: &Path) -> codeagent_common::Result<String> {
+    let bytes = fs::read(step_dir.join("manifest.json"))?;
+    Ok(blake3::hash(&bytes).to_hex().to_string())
+}
+
+/// The step's link, or `None` if its manifest is unreadable or predates the
+/// chain.
+pub fn read_link(step_dir: &Path) -> Option<ChainLink> {
+    let bytes = fs::read(step_dir.join("manifest.json")).ok()?;
+    let manifest: StepManifest = serde_json::from_slice(&bytes).ok()?;
+    Some(ChainLink {
+        prev: manifest.chain_prev?,
+        hash: blake3::hash(&bytes).to_hex().to_string(),
+    })
+}
+
+/// Result of [`verify_chain`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChainAttestation {
+    /// From the head file; `None` if there is none.
+    pub head: Option<String>,
+    pub head_step: Option<StepId>,
+    pub anchor: Option<String>,
+    pub verified: bool,
+    pub chained_steps: usize,
+    /// Steps closed before the chain existed (no `chain_prev`). They can only
+    /// precede the chained steps.
+    pub unchained_steps: usize,
+    /// First step whose link does not hold. `None` with `verified: false`
+    /// means the head file does not match the steps on disk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broken_at: Option<StepId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Walk `steps/` oldest first and check every link against the head file.
+pub fn verify_chain(undo_dir: &Path) -> codeagent_common::Result<ChainAttestation> {
+    let head = ChainHead::read_from(undo_dir);
+    let mut attestation = ChainAttestation {
+        head: None,
+        head_step: None,
+        anchor: None,
+        verified: false,
+        chained_steps: 0,
+        unchained_steps: 0,
+        broken_at: None,
+        reason: None,
+    };
+    let head = match head {
+        Ok(head) => head,
+        Err(error) => {
+            attestation.reason = Some(format!("chain head file is unreadable: {error}"));
+            return Ok(attestation);
+        }
+    };
+    if let Some(head) = &head {
+        attestation.head = Some(head.head.clone());
+        attestation.head_step = head.head_step;
+        attestation.anchor = Some(head.anchor.clone());
+    }
+
+    let mut step_ids: Vec<StepId> = Vec::new();
+    let steps_dir = undo_dir.join("steps");
+    if steps_dir.is_dir() {
+        for entry in fs::read_dir(&steps_dir)? {
+            let entry = entry?;
+            // Step 0 only ever holds pre-step barriers.
+            if let Ok(id) = entry.file_name().to_string_lossy().parse::<StepId>() {
+                if id > 0 && entry.path().is_dir() {
+                    step_ids.push(id);
+                }
+            }
+        }
+    }
+    step_ids.sort_unstable();
+
+    let broken = |mut attestation: ChainAttestation, step: Option<StepId>, reason: &str| {
+        attestation.broken_at = step;
+        attestation.reason = Some(reason.to_string());
+        Ok(attestation)
+    };
+
+    // Hash of the last chained manifest seen.
+    let mut tip: Option<String> = None;
+    let mut last_chained: Option<StepId> = None;
+    for id in step_ids {
+        let Ok(bytes) = fs::read(steps_dir.join(id.to_string()).join("manifest.json")) else {
+            return broken(attestation, Some(id), "manifest is missing");
+        };
+        let Ok(manifest) = serde_json::from_slice::<StepManifest>(&bytes) else {
+            return broken(attestation, Some(id), "manifest is not valid");
+        };
+        match (manifest.chain_prev, &tip) {
+            (None, None) => {
+                attestation.unchained_steps += 1;
+                continue;
+            }
+            (None, Some(_)) => {
+                return broken(attestation, Some(id), "manifest has no chain link");
+            }
+            (Some(prev), None) => match &head {
+                None => return broken(attestation, None, "chain head file is missing"),
+                Some(head) if prev != head.anchor => {
+                    return broken(attestation, Some(id), "oldest step does not link to the anchor");
+                }
+                Some(_) => {}
+            },
+            (Some(prev), Some(expected)) if prev != *expected => {
+                return broken(attestation, Some(id), "step does not link to the previous step");
+            }
+            (Some(_), Some(_)) => {}
+        }
+        tip = Some(blake3::hash(&bytes).to_hex().to_string());
+        last_chained = Some(id);
+        attestation.chained_steps += 1;
+    }
+
+    if let Some(head) = &head {
+        let tip = tip.as_ref().unwrap_or(&head.anchor);
+        if *tip != head.head || last_chained != head.head_step {
+            return broken(attestation, None, "chain head does not match the newest step");
+        }
+    }
+    attestation.verified = true;
+    Ok(attestation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn head_round_trip_and_moves() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(ChainHead::read_from(dir.path()).unwrap(), None);
+
+        let mut head = ChainHead::default();
+        head.advance(1, "a".to_string());
+        head.advance(2, "b".to_string());
+        head.write_to(dir.path()).unwrap();
+        assert_eq!(ChainHead::read_from(dir.path()).unwrap(), Some(head.clone()));
+
+        let second = ChainLink {
+            prev: "a".to_string(),
+            hash: "b".to_string(),
+        };
+        let first = ChainLink {
+            prev: GENESIS_HASH.to_string(),
+            hash: "a".to_string(),
+        };
+        head.drop_oldest(&first);
+        assert_eq!(head.anchor, "a");
+        assert_eq!(head.head_step, Some(2));
+        head.rewind(&second, Some(1));
+        assert_eq!(head.head, "a");
+        assert_eq!(head.head_step, None);
+    }
+}
@@ -0,0 +1,250 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use codeagent_common::{CoherentCaptureConfig, CoherentCaptureStrategy};
+
+use crate::glob_rules::{self, GlobRules};
+
+/// Compiled coherent capture rules.
+///
+/// Files such as SQLite or LevelDB databases can be captured mid-transaction
+/// if another process is writing them while the preimage is read. Matching
+/// paths are read under the configured strategy; when coherence cannot be
+/// guaranteed the capture still proceeds (so undo keeps working) and the
+/// caller records a warning.
+pub struct CoherentCaptureMatcher {
+    config: CoherentCaptureConfig,
+    rules: GlobRules<CoherentCaptureStrategy>,
+    max_attempts: u32,
+    retry_interval: Duration,
+}
+
+/// What the rules are called in messages.
+const KIND: &str = "coherent capture";
+
+/// Contents returned by a coherent read.
+#[derive(Debug)]
+pub struct CoherentRead {
+    pub contents: Vec<u8>,
+    /// `None` when the read is known to be consistent; otherwise a
+    /// human-readable reason why it could not be guaranteed.
+    pub incoherent_reason: Option<String>,
+}
+
+/// Check that the pattern of every rule in `config` is a valid glob, naming
+/// the first that is not.
+pub fn validate_rules(config: &CoherentCaptureConfig) -> Result<(), String> {
+    glob_rules::validate(KIND, config.rules.iter().map(|rule| rule.pattern.as_str()))
+}
+
+impl CoherentCaptureMatcher {
+    /// Compile the rules in `config`. Invalid glob patterns are skipped;
+    /// requests reject them up front with [`validate_rules`].
+    pub fn new(config: &CoherentCaptureConfig) -> Self {
+        let rules = config.rules.iter().map(|rule| (rule.pattern.as_str(), rule.strategy));
+        Self {
+            config: config.clone(),
+            rules: GlobRules::new(KIND, rules),
+            max_attempts: config.max_attempts.max(1),
+            retry_interval: Duration::from_millis(config.retry_interval_ms),
+        }
+    }
+
+    /// The configuration the rules were compiled from.
+    pub fn config(&self) -> &CoherentCaptureConfig {
+        &self.config
+    }
+
+    /// Whether any rules are configured.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Return the strategy of the first rule matching a forward-slash
+    /// relative path, if any.
+    pub fn strategy_for(&self, relative_path: &str) -> Option<CoherentCaptureStrategy> {
+        self.rules.first_match(relative_path)
+    }
+
+    /// Read `path` using `strategy`.
+    pub fn read(&self, path: &Path, strategy: CoherentCaptureStrategy) -> io::Result<CoherentRead> {
+        match strategy {
+            CoherentCaptureStrategy::AdvisoryLock => self.read_with_lock(path),
+            CoherentCaptureStrategy::RetryUntilQuiescent => self.read_until_quiescent(path),
+        }
+    }
+
+    fn read_with_lock(&self, path: &Path) -> io::Result<CoherentRead> {
+        for attempt in 0..self.max_attempts {
+            let mut file = File::open(path)?;
+            if try_lock_shared(&file)? {
+                // The lock is released when `file` is closed.
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents)?;
+                return Ok(CoherentRead {
+                    contents,
+                    incoherent_reason: None,
+                });
+            }
+            if attempt + 1 < self.max_attempts {
+                std::thread::sleep(self.retry_interval);
+            }
+        }
+
+        Ok(CoherentRead {
+            contents: fs::read(path)?,
+            incoherent_reason: Some(format!(
+                "advisory lock could not be acquired after {} attempt(s)",
+                self.max_attempts
+            )),
+        })
+    }
+
+    fn read_until_quiescent(&self, path: &Path) -> io::Result<CoherentRead> {
+        let mut contents = Vec::new();
+        for attempt in 0..self.max_attempts {
+            let before = fingerprint(path)?;
+            contents = fs::read(path)?;
+            let after = fingerprint(path)?;
+            if before == after {
+                return Ok(CoherentRead {
+                    contents,
+                    incoherent_reason: None,
+                });
+            }
+            if attempt + 1 < self.max_attempts {
+                std::thread::sleep(self.retry_interval);
+            }
+        }
+
+        Ok(CoherentRead {
+            contents,
+            incoherent_reason: Some(format!(
+                "file was still changing after {} attempt(s)",
+                self.max_attempts
+            )),
+        })
+    }
+}
+
+/// Size and mtime, used to detect concurrent modification during a read.
+fn fingerprint(path: &Path) -> io::Result<(u64, Option<SystemTime>)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified().ok()))
+}
+
+/// Try to take a non-blocking shared POSIX record lock over the whole file.
+/// Returns `Ok(false)` when another process holds a conflicting lock.
+#[cfg(unix)]
+fn try_lock_shared(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `flock` is a plain C struct; all-zero is a valid value
+    // (l_start = 0, l_len = 0 covers the whole file).
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_RDLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+
+    // SAFETY: the fd is valid for the lifetime of `file` and `lock` is a
+    // properly initialized `flock` struct.
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) };
+    if result == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EAGAIN) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+/// Advisory record locks are not available; the capture is never considered
+/// coherent so the caller records a warning.
+#[cfg(not(unix))]
+fn try_lock_shared(_file: &File) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codeagent_common::CoherentCaptureRule;
+    use tempfile::TempDir;
+
+    fn config_with(rules: Vec<(&str, CoherentCaptureStrategy)>) -> CoherentCaptureConfig {
+        CoherentCaptureConfig {
+            rules: rules
+                .into_iter()
+                .map(|(pattern, strategy)| CoherentCaptureRule {
+                    pattern: pattern.to_string(),
+                    strategy,
+                })
+                .collect(),
+            max_attempts: 2,
+            retry_interval_ms: 1,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let matcher = CoherentCaptureMatcher::new(&config_with(vec![
+            ("data/*.db", CoherentCaptureStrategy::AdvisoryLock),
+            ("*.db", CoherentCaptureStrategy::RetryUntilQuiescent),
+        ]));
+
+        assert_eq!(
+            matcher.strategy_for("data/app.db"),
+            Some(CoherentCaptureStrategy::AdvisoryLock)
+        );
+        assert_eq!(
+            matcher.strategy_for("app.db"),
+            Some(CoherentCaptureStrategy::RetryUntilQuiescent)
+        );
+        assert_eq!(matcher.strategy_for("src/main.rs"), None);
+    }
+
+    #[test]
+    fn invalid_patterns_are_skipped() {
+        let matcher = CoherentCaptureMatcher::new(&config_with(vec![(
+            "[unclosed",
+            CoherentCaptureStrategy::AdvisoryLock,
+        )]));
+        assert!(matcher.is_empty());
+
+        let valid = config_with(vec![("*.db", CoherentCaptureStrategy::AdvisoryLock)]);
+        assert!(validate_rules(&valid).is_ok());
+        let invalid = config_with(vec![("[unclosed", CoherentCaptureStrategy::AdvisoryLock)]);
+        assert!(validate_rules(&invalid).unwrap_err().contains("[unclosed"));
+    }
+
+    #[test]
+    fn quiescent_read_of_stable_file_is_coherent() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("stable.db");
+        fs::write(&path, b"stable").unwrap();
+
+        let matcher = CoherentCaptureMatcher::new(&config_with(vec![]));
+        let read = matcher
+            .read(&path, CoherentCaptureStrategy::RetryUntilQuiescent)
+            .unwrap();
+        assert_eq!(read.contents, b"stable");
+        assert!(read.incoherent_reason.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unlocked_file_is_read_under_lock() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("unlocked.db");
+        fs::write(&path, b"contents").unwrap();
+
+        let matcher = CoherentCaptureMatcher::new(&config_with(vec![]));
+        let read = matcher
+            .read(&path, CoherentCaptureStrategy::AdvisoryLock)
+            .unwrap();
+        assert_eq!(read.contents, b"contents");
+        assert!(read.incoherent_reason.is_none());
+    }
+}
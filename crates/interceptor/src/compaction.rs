@@ -0,0 +1,261 @@
+//! Compaction of closed steps, for idle-time maintenance.
+//!
+//! Preimages are compressed at a fast zstd level while a step runs. Once the
+//! step is closed, every data file of its preimages can be decompressed,
+//! checked against the hash recorded at capture, and recompressed at a
+//! higher level ([`compact_data_file`]). Data files holding the same
+//! contents as one already compacted become hard links to it, so each
+//! distinct blob is stored once. Files are replaced by writing a temp file
+//! and renaming it over, like every other `.dat`, so a step or the blob
+//! cache sharing the old inode never sees it change.
+//!
+//! A compacted step gets `compacted.json`, recording the level and the
+//! content hash of each data file; later runs skip the step and link new
+//! duplicates to its files. A data file that fails its check is left alone
+//! and reported.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::blob_cache::PreimageBlobCache;
+use crate::preimage::PreimageMetadata;
+use crate::rollback;
+
+/// Marker of a compacted step, in its step directory.
+pub const COMPACTED_FILE: &str = "compacted.json";
+
+/// Suffix of the temp files compaction writes next to a data file.
+const TEMP_SUFFIX: &str = "compact.tmp";
+
+/// What to compact, and how hard.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionOptions {
+    /// zstd level data files are recompressed at.
+    pub level: i32,
+    /// Newest steps left as they are: the likeliest to be rolled back soon.
+    pub keep_recent_steps: usize,
+}
+
+impl Default for CompactionOptions {
+    fn default() -> Self {
+        Self {
+            level: 19,
+            keep_recent_steps: 1,
+        }
+    }
+}
+
+/// Outcome of one compaction run over an undo directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub steps_compacted: usize,
+    pub files_recompressed: usize,
+    pub blobs_deduplicated: usize,
+    /// Bytes the data files shrank by, counting a file replaced by a link
+    /// to a duplicate as its whole size.
+    pub bytes_saved: u64,
+    /// Paths whose stored contents failed the checksum recorded at capture
+    /// or did not decompress.
+    pub corrupt_paths: Vec<String>,
+    /// The run stopped early; the next one resumes where it did.
+    pub paused: bool,
+}
+
+/// Contents of `compacted.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactedStep {
+    pub level: i32,
+    /// Content hash of each data file, by file name in `preimages/`.
+    pub blobs: BTreeMap<String, String>,
+}
+
+impl CompactedStep {
+    /// Read the marker of `step_dir`; `None` if it was never compacted.
+    pub fn read_from(step_dir: &Path) -> Option<Self> {
+        let json = fs::read_to_string(step_dir.join(COMPACTED_FILE)).ok()?;
+        serde_json::from_str(&json).ok()
+    }
+
+    /// Write the marker of `step_dir` atomically.
+    pub fn write_to(&self, step_dir: &Path) -> codeagent_common::Result<()> {
+        let tmp = step_dir.join(format!("{COMPACTED_FILE}.tmp"));
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, step_dir.join(COMPACTED_FILE))?;
+        Ok(())
+    }
+}
+
+/// Compacted data files by content hash, to link duplicates to.
+#[derive(Debug, Default)]
+pub struct BlobIndex {
+    blobs: HashMap<String, PathBuf>,
+}
+
+impl BlobIndex {
+    /// Add the data files of the step compacted in `step_dir`.
+    pub fn add_step(&mut self, step_dir: &Path, compacted: &CompactedStep) {
+        let preimage_dir = step_dir.join("preimages");
+        for (name, hash) in &compacted.blobs {
+            self.blobs.entry(hash.clone()).or_insert_with(|| preimage_dir.join(name));
+        }
+    }
+
+    /// Remember `path` as holding `hash`, unless a file still there does.
+    fn insert(&mut self, hash: &str, path: &Path) {
+        if !self.blobs.get(hash).is_some_and(|known| known.is_file()) {
+            self.blobs.insert(hash.to_string(), path.to_path_buf());
+        }
+    }
+}
+
+/// What compacting one data file did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileCompaction {
+    /// Recompressed (or already as small), holding contents with `hash`.
+    Recompressed { hash: String, saved: u64 },
+    /// Replaced by a link to a compacted file with the same contents.
+    Deduplicated { hash: String, saved: u64 },
+    /// The contents failed their check; the file was left alone.
+    Corrupt,
+}
+
+/// Path hashes of the preimages in `preimage_dir`, sorted.
+pub fn preimage_hashes(preimage_dir: &Path) -> Vec<String> {
+    let mut hashes: Vec<String> = fs::read_dir(preimage_dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    name.strip_suffix(".meta.json").map(String::from)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    hashes.sort();
+    hashes
+}
+
+/// The data files of the preimage stored under `path_hash`, with the hash
+/// each should hold: `.dat` or the range patches, and the postimage.
+pub fn data_files(
+    preimage_dir: &Path,
+    path_hash: &str,
+    meta: &PreimageMetadata,
+) -> Vec<(PathBuf, Option<String>)> {
+    let mut files = match &meta.range_patches {
+        Some(patches) => patches
+            .iter()
+            .enumerate()
+            .map(|(index, patch)| {
+                let name = format!("{path_hash}.range.{index}.dat");
+                (preimage_dir.join(name), patch.content_hash.clone())
+            })
+            .collect(),
+        None => vec![(preimage_dir.join(format!("{path_hash}.dat")), meta.content_hash.clone())],
+    };
+    files.push((preimage_dir.join(format!("{path_hash}.post.dat")), None));
+    files.retain(|(path, _)| path.is_file());
+    files
+}
+
+/// Check the data file at `path` against `expected` and replace it with a
+/// link to a compacted duplicate from `index`, or with its contents
+/// recompressed at `level` if that is smaller. Adds the file to `index`.
+pub fn compact_data_file(
+    path: &Path,
+    expected: Option<&str>,
+    level: i32,
+    index: &mut BlobIndex,
+) -> codeagent_common::Result<FileCompaction> {
+    let compressed = fs::read(path)?;
+    let Ok(contents) = zstd::decode_all(compressed.as_slice()) else {
+        return Ok(FileCompaction::Corrupt);
+    };
+    let hash = PreimageBlobCache::content_hash(&contents);
+    if expected.is_some_and(|expected| expected != hash) {
+        return Ok(FileCompaction::Corrupt);
+    }
+    let old_size = compressed.len() as u64;
+    let tmp = path.with_extension(TEMP_SUFFIX);
+    let _ = fs::remove_file(&tmp);
+
+    if let Some(duplicate) = index.blobs.get(&hash) {
+        let same_inode = rollback::file_id(duplicate)
+            .is_some_and(|id| rollback::file_id(path) == Some(id));
+        if same_inode {
+            // Already one blob, e.g. linked by a squash.
+            return Ok(FileCompaction::Deduplicated { hash, saved: 0 });
+        }
+        if duplicate.is_file() && fs::hard_link(duplicate, &tmp).is_ok() {
+            fs::rename(&tmp, path)?;
+            return Ok(FileCompaction::Deduplicated { hash, saved: old_size });
+        }
+    }
+
+    let recompressed = zstd::encode_all(contents.as_slice(), level)?;
+    let new_size = recompressed.len() as u64;
+    if new_size < old_size {
+        fs::write(&tmp, &recompressed)?;
+        fs::rename(&tmp, path)?;
+    }
+    index.insert(&hash, path);
+    Ok(FileCompaction::Recompressed {
+        hash,
+        saved: old_size.saturating_sub(new_size),
+    })
+}
+
+/// Remove the temp files a compaction cut short left in `preimage_dir`.
+pub fn remove_temp_files(preimage_dir: &Path) {
+    let Ok(entries) = fs::read_dir(preimage_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn data_files_are_recompressed_deduplicated_and_checked() {
+        let dir = TempDir::new().unwrap();
+        let contents: String =
+            (0..4000).map(|i| format!("let v{i} = f({}, {});\n", i * 7 % 97, i % 13)).collect();
+        let hash = PreimageBlobCache::content_hash(contents.as_bytes());
+        let fast = zstd::encode_all(contents.as_bytes(), 1).unwrap();
+        let first = dir.path().join("a.dat");
+        let second = dir.path().join("b.dat");
+        let corrupt = dir.path().join("c.dat");
+        fs::write(&first, &fast).unwrap();
+        fs::write(&second, &fast).unwrap();
+        fs::write(&corrupt, zstd::encode_all(&b"bit rot"[..], 1).unwrap()).unwrap();
+
+        let mut index = BlobIndex::default();
+        let outcome = compact_data_file(&first, Some(&hash), 19, &mut index).unwrap();
+        assert!(matches!(outcome, FileCompaction::Recompressed { saved, .. } if saved > 0));
+        let recompressed = fs::read(&first).unwrap();
+        assert_eq!(zstd::decode_all(recompressed.as_slice()).unwrap(), contents.as_bytes());
+
+        let outcome = compact_data_file(&second, None, 19, &mut index).unwrap();
+        assert_eq!(
+            outcome,
+            FileCompaction::Deduplicated { hash: hash.clone(), saved: fast.len() as u64 }
+        );
+        assert_eq!(fs::read(&second).unwrap(), fs::read(&first).unwrap());
+
+        let outcome = compact_data_file(&corrupt, Some(&hash), 19, &mut index).unwrap();
+        assert_eq!(outcome, FileCompaction::Corrupt);
+        remove_temp_files(dir.path());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+}
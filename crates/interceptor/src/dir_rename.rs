@@ -0,0 +1,189 @@
+//! Directory renames recorded as single operations.
+//!
+//! Renaming a directory to a name that does not exist records the rename
+//! in the step manifest (see [`RenameRecord`]) instead of capturing every
+//! path under it. Manifest entries keep the names paths had before the
+//! step's renames: a path touched later under the new name is captured
+//! from where it is but recorded under its original name
+//! ([`original_path`]). Rollback renames the directories back, newest
+//! first ([`undo_renames`]), then restores the entries.
+//!
+//! A path created at a name a rename vacated has no original name and is
+//! not recorded; undoing the rename removes whatever is there first.
+//!
+//! The renames of an open step are also kept in `renames.json` in its WAL
+//! directory, so a step interrupted by a crash still has them rolled back.
+
+use std::fs;
+use std::path::Path;
+
+use crate::manifest::RenameRecord;
+use crate::path_case;
+use crate::rollback;
+
+/// Renames of an open step, next to its preimages in the WAL directory.
+pub const RENAMES_FILE: &str = "renames.json";
+
+/// The name `path` had before `renames`, or `None` if it names something
+/// created at a name one of them vacated. With `fold_case` the names are
+/// compared case-insensitively.
+pub fn original_path(renames: &[RenameRecord], path: &str, fold_case: bool) -> Option<String> {
+    let mut path = path.to_string();
+    for rename in renames.iter().rev() {
+        if let Some(rest) = strip_prefix(&path, &rename.to, fold_case) {
+            path = format!("{}{rest}", rename.from);
+        } else if strip_prefix(&path, &rename.from, fold_case).is_some() {
+            return None;
+        }
+    }
+    Some(path)
+}
+
+/// The name `path`, a name from before `renames`, has after them.
+pub fn current_path(renames: &[RenameRecord], path: &str) -> String {
+    let mut path = path.to_string();
+    for rename in renames {
+        if let Some(rest) = strip_prefix(&path, &rename.from, false) {
+            path = format!("{}{rest}", rename.to);
+        }
+    }
+    path
+}
+
+/// Rename the directories of `renames` back, newest first. A rename whose
+/// destination is gone or is no longer the renamed directory is skipped;
+/// whatever was created at the vacated name since is removed. Renames
+/// `writable` rejects either name of are skipped.
+pub fn undo_renames(
+    renames: &[RenameRecord],
+    working_root: &Path,
+    writable: &dyn Fn(&Path) -> bool,
+) -> codeagent_common::Result<()> {
+    for rename in renames.iter().rev() {
+        let from = working_root.join(&rename.from);
+        let to = working_root.join(&rename.to);
+        if !writable(&from) || !writable(&to) || rollback::file_id(&to) != Some(rename.file_id) {
+            continue;
+        }
+        match from.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&from)?,
+            Ok(_) => fs::remove_file(&from)?,
+            Err(_) => {
+                if let Some(parent) = from.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+        }
+        fs::rename(&to, &from)?;
+    }
+    Ok(())
+}
+
+/// The renames that put the directories back where they were before
+/// [`undo_renames`] moved them, for the rollback journal.
+pub fn redo_renames(renames: &[RenameRecord]) -> Vec<RenameRecord> {
+    renames
+        .iter()
+        .rev()
+        .map(|rename| RenameRecord {
+            from: rename.to.clone(),
+            to: rename.from.clone(),
+            file_id: rename.file_id,
+        })
+        .collect()
+}
+
+/// The names [`undo_renames`] clears before moving each directory back,
+/// as they are before the undo begins.
+pub fn vacated_paths(renames: &[RenameRecord]) -> Vec<String> {
+    renames
+        .iter()
+        .enumerate()
+        .map(|(index, rename)| current_path(&renames[index + 1..], &rename.from))
+        .collect()
+}
+
+/// Write the renames of an open step to its WAL directory.
+pub fn write_log(wal_dir: &Path, renames: &[RenameRecord]) -> codeagent_common::Result<()> {
+    let tmp = wal_dir.join(format!("{RENAMES_FILE}.tmp"));
+    fs::write(&tmp, serde_json::to_string_pretty(renames)?)?;
+    fs::rename(&tmp, wal_dir.join(RENAMES_FILE))?;
+    Ok(())
+}
+
+/// Read the renames of an interrupted step from its WAL directory; empty if
+/// it has none or the file cannot be read.
+pub fn read_log(wal_dir: &Path) -> Vec<RenameRecord> {
+    fs::read_to_string(wal_dir.join(RENAMES_FILE))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// What follows `prefix` in `path` (empty or starting with `/`), if `path`
+/// is `prefix` or under it.
+fn strip_prefix<'a>(path: &'a str, prefix: &str, fold_case: bool) -> Option<&'a str> {
+    let head = path.get(..prefix.len())?;
+    let matches = if fold_case {
+        path_case::fold(head) == path_case::fold(prefix)
+    } else {
+        head == prefix
+    };
+    let rest = &path[prefix.len()..];
+    (matches && (rest.is_empty() || rest.starts_with('/'))).then_some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn rename(from: &str, to: &str) -> RenameRecord {
+        RenameRecord {
+            from: from.to_string(),
+            to: to.to_string(),
+            file_id: (0, 0),
+        }
+    }
+
+    #[test]
+    fn paths_are_translated_through_the_renames() {
+        let renames = [rename("old", "new"), rename("new/sub", "moved")];
+        assert_eq!(original_path(&renames, "new/a.txt", false).as_deref(), Some("old/a.txt"));
+        assert_eq!(original_path(&renames, "moved/b.txt", false).as_deref(), Some("old/sub/b.txt"));
+        assert_eq!(original_path(&renames, "newer.txt", false).as_deref(), Some("newer.txt"));
+        assert_eq!(original_path(&renames, "old/created.txt", false), None);
+        assert_eq!(original_path(&renames, "NEW/a.txt", true).as_deref(), Some("old/a.txt"));
+
+        assert_eq!(current_path(&renames, "old/a.txt"), "new/a.txt");
+        assert_eq!(current_path(&renames, "old/sub/b.txt"), "moved/b.txt");
+        assert_eq!(vacated_paths(&renames), ["old", "new/sub"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn undoing_renames_moves_the_directories_back() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("new/sub")).unwrap();
+        fs::write(root.join("new/sub/file.txt"), "kept").unwrap();
+        fs::create_dir(root.join("old")).unwrap();
+        fs::write(root.join("old/created.txt"), "made after the rename").unwrap();
+
+        let renamed = RenameRecord {
+            file_id: rollback::file_id(&root.join("new")).unwrap(),
+            ..rename("old", "new")
+        };
+        // Neither the missing destination nor the other file is moved.
+        let renames = [renamed, rename("gone", "missing"), rename("elsewhere", "old")];
+        undo_renames(&renames, root, &|_| true).unwrap();
+        assert_eq!(fs::read_to_string(root.join("old/sub/file.txt")).unwrap(), "kept");
+        assert!(!root.join("old/created.txt").exists());
+        assert!(!root.join("new").exists());
+        assert!(!root.join("gone").exists());
+        assert!(!root.join("elsewhere").exists());
+
+        undo_renames(&redo_renames(&renames), root, &|_| true).unwrap();
+        assert_eq!(fs::read_to_string(root.join("new/sub/file.txt")).unwrap(), "kept");
+    }
+}
@@ -0,0 +1,94 @@
+use codeagent_common::{ExternalModificationConfig, ExternalModificationPolicy};
+
+use crate::glob_rules::{self, GlobRules};
+
+/// Compiled external modification rules.
+///
+/// Build output and editor state (`target/`, `.idea/`) changing behind the
+/// agent's back is harmless to undo, while edits under `src/` must block
+/// rollback. Rules let each path pick its own policy.
+pub struct ExternalModificationMatcher {
+    default_policy: ExternalModificationPolicy,
+    rules: GlobRules<ExternalModificationPolicy>,
+}
+
+/// What the rules are called in messages.
+const KIND: &str = "external modification";
+
+/// Check that the pattern of every rule in `config` is a valid glob, naming
+/// the first that is not.
+pub fn validate_rules(config: &ExternalModificationConfig) -> Result<(), String> {
+    glob_rules::validate(KIND, config.rules.iter().map(|rule| rule.pattern.as_str()))
+}
+
+impl ExternalModificationMatcher {
+    /// Compile the rules in `config`. Invalid glob patterns are skipped;
+    /// requests reject them up front with [`validate_rules`].
+    pub fn new(config: &ExternalModificationConfig) -> Self {
+        let rules = config.rules.iter().map(|rule| (rule.pattern.as_str(), rule.policy));
+        Self {
+            default_policy: config.default_policy,
+            rules: GlobRules::new(KIND, rules),
+        }
+    }
+
+    /// The policy used when no paths are affected (e.g. session-start barriers).
+    pub fn default_policy(&self) -> ExternalModificationPolicy {
+        self.default_policy
+    }
+
+    /// Return the policy of the first rule matching a forward-slash relative
+    /// path, falling back to the default policy.
+    pub fn policy_for(&self, relative_path: &str) -> ExternalModificationPolicy {
+        self.rules.first_match(relative_path).unwrap_or(self.default_policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codeagent_common::ExternalModificationRule;
+
+    fn rule(pattern: &str, policy: ExternalModificationPolicy) -> ExternalModificationRule {
+        ExternalModificationRule {
+            pattern: pattern.to_string(),
+            policy,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let matcher = ExternalModificationMatcher::new(&ExternalModificationConfig {
+            default_policy: ExternalModificationPolicy::Warn,
+            rules: vec![
+                rule("target/**", ExternalModificationPolicy::Ignore),
+                rule("src/*", ExternalModificationPolicy::Barrier),
+                rule("src/generated/*", ExternalModificationPolicy::Ignore),
+            ],
+        });
+
+        assert_eq!(matcher.policy_for("target/debug/app"), ExternalModificationPolicy::Ignore);
+        assert_eq!(matcher.policy_for("src/generated/api.rs"), ExternalModificationPolicy::Barrier);
+        assert_eq!(matcher.policy_for("README.md"), ExternalModificationPolicy::Warn);
+    }
+
+    #[test]
+    fn invalid_patterns_are_skipped() {
+        let matcher = ExternalModificationMatcher::new(&ExternalModificationConfig {
+            default_policy: ExternalModificationPolicy::Barrier,
+            rules: vec![
+                rule("[", ExternalModificationPolicy::Ignore),
+                rule(".idea/**", ExternalModificationPolicy::Ignore),
+            ],
+        });
+
+        assert_eq!(matcher.policy_for(".idea/workspace.xml"), ExternalModificationPolicy::Ignore);
+        assert_eq!(matcher.policy_for("["), ExternalModificationPolicy::Barrier);
+
+        let mut config = ExternalModificationConfig::from(ExternalModificationPolicy::Warn);
+        config.rules.push(rule(".idea/**", ExternalModificationPolicy::Ignore));
+        assert!(validate_rules(&config).is_ok());
+        config.rules.push(rule("[", ExternalModificationPolicy::Ignore));
+        assert!(validate_rules(&config).unwrap_err().contains("'['"));
+    }
+}
@@ -0,0 +1,165 @@
+//! Mirror of closed steps as git commits (feature `git-mirror`).
+//!
+//! With the mirror on, closing a step commits the paths it affected, as
+//! they are once it closed, to a dedicated ref of the repository holding
+//! the working directory (`refs/codeagent/history` by default), with the
+//! step's command as the message. Commits are made with git plumbing and a
+//! private index file in the undo directory, so the user's HEAD, index and
+//! branches are left alone. The first commit has HEAD as its parent.
+//!
+//! The mirror is a view only: rollback restores from the undo log, and
+//! rolled-back steps stay in the mirrored history.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use codeagent_common::{CodeAgentError, Result, StepId};
+
+/// Scratch index the commits are built in, in the undo directory.
+const INDEX_FILE_NAME: &str = "git-mirror.index";
+
+/// Author and committer of the mirrored commits.
+const IDENTITY_NAME: &str = "codeagent";
+const IDENTITY_EMAIL: &str = "codeagent@localhost";
+
+pub struct GitMirror {
+    working_root: PathBuf,
+    ref_name: String,
+    index_file: PathBuf,
+}
+
+impl GitMirror {
+    /// A mirror of `working_root` onto `ref_name`. Fails if `git` cannot
+    /// run, `working_root` is not inside a git work tree, or `ref_name` is
+    /// not a valid ref under `refs/`.
+    pub fn open(working_root: &Path, undo_dir: &Path, ref_name: &str) -> Result<Self> {
+        let mirror = Self {
+            working_root: working_root.to_path_buf(),
+            ref_name: ref_name.to_string(),
+            index_file: undo_dir.join(INDEX_FILE_NAME),
+        };
+        if mirror.git(&["rev-parse", "--is-inside-work-tree"])? != "true" {
+            return Err(mirror_error(format!(
+                "{} is not in a git work tree",
+                working_root.display()
+            )));
+        }
+        if !ref_name.starts_with("refs/")
+            || mirror.git(&["check-ref-format", ref_name]).is_err()
+        {
+            return Err(mirror_error(format!("'{ref_name}' is not a valid ref")));
+        }
+        Ok(mirror)
+    }
+
+    pub fn ref_name(&self) -> &str {
+        &self.ref_name
+    }
+
+    /// Commit `paths` (relative to the working root) as they are now on top
+    /// of the ref, and move the ref to the commit. Paths that no longer
+    /// exist are removed from the tree. Returns the commit id.
+    pub fn commit_step(
+        &self,
+        step_id: StepId,
+        command: Option<&str>,
+        paths: &[String],
+    ) -> Result<String> {
+        let previous = self.resolve(&self.ref_name);
+        let parent = previous.clone().or_else(|| self.resolve("HEAD"));
+        match &parent {
+            Some(parent) => self.git(&["read-tree", parent])?,
+            None => self.git(&["read-tree", "--empty"])?,
+        };
+
+        let (present, missing): (Vec<&str>, Vec<&str>) = paths
+            .iter()
+            .map(String::as_str)
+            .partition(|path| self.working_root.join(path).symlink_metadata().is_ok());
+        if !missing.is_empty() {
+            let mut args = vec!["rm", "-r", "-q", "--cached", "--ignore-unmatch", "--"];
+            args.extend(&missing);
+            self.git(&args)?;
+        }
+        if !present.is_empty() {
+            // Ignored paths the step touched are mirrored too.
+            let mut args = vec!["add", "-A", "-f", "--"];
+            args.extend(&present);
+            self.git(&args)?;
+        }
+        let tree = self.git(&["write-tree"])?;
+
+        let message = format!(
+            "{}\n\nCodeagent-Step: {step_id}\n",
+            command.unwrap_or(&format!("step {step_id}"))
+        );
+        let mut args = vec!["commit-tree", tree.as_str(), "-m", message.as_str()];
+        if let Some(parent) = &parent {
+            args.extend(["-p", parent.as_str()]);
+        }
+        let commit = self.git(&args)?;
+        // The old value guards against another writer moving the ref.
+        let old = previous.unwrap_or_default();
+        self.git(&["update-ref", &self.ref_name, &commit, &old])?;
+        Ok(commit)
+    }
+
+    /// The commit `rev` names, or `None` if it does not exist.
+    fn resolve(&self, rev: &str) -> Option<String> {
+        self.git(&["rev-parse", "--verify", "--quiet", &format!("{rev}^{{commit}}")])
+            .ok()
+    }
+
+    /// Run `git` in the working root with the mirror's index, returning its
+    /// trimmed stdout.
+    fn git(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&self.working_root)
+            .args(args)
+            .env("GIT_INDEX_FILE", &self.index_file)
+            .env("GIT_AUTHOR_NAME", IDENTITY_NAME)
+            .env("GIT_AUTHOR_EMAIL", IDENTITY_EMAIL)
+            .env("GIT_COMMITTER_NAME", IDENTITY_NAME)
+            .env("GIT_COMMITTER_EMAIL", IDENTITY_EMAIL)
+            .output()
+            .map_err(|error| mirror_error(format!("cannot run git: {error}")))?;
+        if !output.status.success() {
+            return Err(mirror_error(format!(
+                "git {} failed: {}",
+                args.first().unwrap_or(&""),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+fn mirror_error(message: String) -> CodeAgentError {
+    CodeAgentError::GitMirror { message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn open_rejects_directories_outside_git_and_invalid_refs() {
+        let dir = TempDir::new().unwrap();
+        let error = GitMirror::open(dir.path(), dir.path(), "refs/codeagent/history")
+            .err()
+            .unwrap();
+        assert!(matches!(error, CodeAgentError::GitMirror { .. }), "{error}");
+
+        let status = Command::new("git")
+            .args(["init", "-q"])
+            .arg(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(GitMirror::open(dir.path(), dir.path(), "refs/codeagent/history").is_ok());
+        assert!(GitMirror::open(dir.path(), dir.path(), "codeagent").is_err());
+        assert!(GitMirror::open(dir.path(), dir.path(), "refs/bad..ref").is_err());
+    }
+}
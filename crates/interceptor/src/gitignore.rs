@@ -1,24 +1,163 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
 
+/// A [`build_gitignore`] matcher that can be rebuilt when the ignore files
+/// change during a session, plus extra patterns set at runtime.
+///
+/// Whoever sees an ignore file being modified calls
+/// [`invalidate`](Self::invalidate); the next [`is_ignored`](Self::is_ignored)
+/// rebuilds the matcher from disk first. [`reload`](Self::reload) rebuilds it
+/// right away.
+///
+/// Extra patterns use gitignore syntax relative to the working root and
+/// come after every ignore file, so they win over them: `!keep.log`
+/// captures a file a `.gitignore` ignores.
+#[derive(Debug)]
+pub struct GitignoreFilter {
+    working_root: PathBuf,
+    /// False for a filter of extra patterns only, with the ignore files
+    /// not read.
+    reads_ignore_files: bool,
+    patterns: RwLock<Vec<String>>,
+    matcher: RwLock<Option<Gitignore>>,
+    stale: AtomicBool,
+}
+
+impl GitignoreFilter {
+    pub fn build(working_root: &Path) -> Self {
+        Self {
+            working_root: working_root.to_path_buf(),
+            reads_ignore_files: true,
+            patterns: RwLock::new(Vec::new()),
+            matcher: RwLock::new(build_gitignore(working_root)),
+            stale: AtomicBool::new(false),
+        }
+    }
+
+    /// A filter that ignores nothing until [`set_patterns`](Self::set_patterns)
+    /// is called, and never reads the ignore files.
+    pub fn patterns_only(working_root: &Path) -> Self {
+        Self {
+            working_root: working_root.to_path_buf(),
+            reads_ignore_files: false,
+            patterns: RwLock::new(Vec::new()),
+            matcher: RwLock::new(None),
+            stale: AtomicBool::new(false),
+        }
+    }
+
+    pub fn working_root(&self) -> &Path {
+        &self.working_root
+    }
+
+    pub fn reads_ignore_files(&self) -> bool {
+        self.reads_ignore_files
+    }
+
+    pub fn patterns(&self) -> Vec<String> {
+        self.patterns.read().unwrap().clone()
+    }
+
+    /// Replace the extra patterns and rebuild the matcher. Check them with
+    /// [`validate_patterns`] first: invalid ones are skipped.
+    pub fn set_patterns(&self, patterns: Vec<String>) {
+        *self.patterns.write().unwrap() = patterns;
+        self.reload();
+    }
+
+    /// Whether `relative` (a `/`-separated path under the working root), or
+    /// one of its parents, is ignored.
+    pub fn is_ignored(&self, relative: &str, is_dir: bool) -> bool {
+        if self.stale.swap(false, Ordering::AcqRel) {
+            self.reload();
+        }
+        self.matcher
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|matcher| matcher.matched_path_or_any_parents(relative, is_dir).is_ignore())
+    }
+
+    /// Rebuild the matcher before the next lookup.
+    pub fn invalidate(&self) {
+        self.stale.store(true, Ordering::Release);
+    }
+
+    /// Rebuild the matcher from the ignore files now on disk.
+    pub fn reload(&self) {
+        let patterns = self.patterns.read().unwrap();
+        let matcher = build_matcher(&self.working_root, self.reads_ignore_files, &patterns);
+        *self.matcher.write().unwrap() = matcher;
+    }
+}
+
+/// Check that every one of `patterns` is a valid gitignore line, naming the
+/// first that is not.
+pub fn validate_patterns(patterns: &[String]) -> Result<(), String> {
+    let mut builder = GitignoreBuilder::new("");
+    for pattern in patterns {
+        if let Err(error) = builder.add_line(None, pattern) {
+            return Err(format!("invalid ignore pattern '{pattern}': {error}"));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `relative` (a `/`-separated path under the working root) is one of
+/// the files [`build_gitignore`] reads, so that changing it changes the rules.
+pub fn is_ignore_source(relative: &str) -> bool {
+    if relative == ".git/info/exclude" {
+        return true;
+    }
+    let Some(parent) = relative.strip_suffix(".gitignore") else {
+        return false;
+    };
+    if parent.is_empty() {
+        return true;
+    }
+    let Some(parent) = parent.strip_suffix('/') else {
+        return false;
+    };
+    // Files under skipped directories are never loaded.
+    !parent.split('/').any(|component| SKIP_DIRS.contains(&component))
+}
+
 /// Build a compiled gitignore matcher by discovering `.gitignore` files under
 /// `working_root` and loading `.git/info/exclude` if present.
 ///
 /// Returns `None` when no gitignore sources are found (nothing to filter).
 pub fn build_gitignore(working_root: &Path) -> Option<Gitignore> {
+    build_matcher(working_root, true, &[])
+}
+
+/// [`build_gitignore`] when `read_ignore_files`, followed by `patterns`.
+fn build_matcher(
+    working_root: &Path,
+    read_ignore_files: bool,
+    patterns: &[String],
+) -> Option<Gitignore> {
     let mut builder = GitignoreBuilder::new(working_root);
     let mut found_sources = false;
 
-    // .git/info/exclude
-    let exclude_path = working_root.join(".git").join("info").join("exclude");
-    if exclude_path.is_file() {
-        builder.add(&exclude_path);
-        found_sources = true;
+    if read_ignore_files {
+        // .git/info/exclude
+        let exclude_path = working_root.join(".git").join("info").join("exclude");
+        if exclude_path.is_file() {
+            builder.add(&exclude_path);
+            found_sources = true;
+        }
+
+        // Walk the tree to discover all .gitignore files
+        discover_gitignore_files(working_root, &mut builder, &mut found_sources);
     }
 
-    // Walk the tree to discover all .gitignore files
-    discover_gitignore_files(working_root, &mut builder, &mut found_sources);
+    // Added last, so that they take precedence.
+    for pattern in patterns {
+        found_sources |= builder.add_line(None, pattern).is_ok();
+    }
 
     if !found_sources {
         return None;
@@ -83,3 +222,42 @@ fn discover_gitignore_files(
         discover_gitignore_files(&path, builder, found_sources);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_sources_are_the_files_that_get_loaded() {
+        assert!(is_ignore_source(".gitignore"));
+        assert!(is_ignore_source("src/nested/.gitignore"));
+        assert!(is_ignore_source(".git/info/exclude"));
+        assert!(!is_ignore_source("node_modules/pkg/.gitignore"));
+        assert!(!is_ignore_source("src/not.gitignore"));
+        assert!(!is_ignore_source(".gitignore.bak"));
+        assert!(!is_ignore_source(".git/info/attributes"));
+    }
+
+    #[test]
+    fn extra_patterns_apply_after_the_ignore_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join(".gitignore"), "*.log\n").unwrap();
+        let filter = GitignoreFilter::build(dir.path());
+        assert!(filter.is_ignored("debug.log", false));
+        assert!(!filter.is_ignored("cache/data.bin", false));
+
+        filter.set_patterns(vec!["cache/".to_string(), "!keep.log".to_string()]);
+        assert!(filter.is_ignored("cache/data.bin", false));
+        assert!(filter.is_ignored("debug.log", false));
+        assert!(!filter.is_ignored("keep.log", false));
+
+        let only = GitignoreFilter::patterns_only(dir.path());
+        assert!(!only.is_ignored("debug.log", false));
+        only.set_patterns(vec!["*.bin".to_string()]);
+        assert!(only.is_ignored("cache/data.bin", false));
+        assert!(!only.is_ignored("debug.log", false));
+
+        assert!(validate_patterns(&["*.tmp".to_string()]).is_ok());
+        assert!(validate_patterns(&["[z-a]".to_string()]).unwrap_err().contains("[z-a]"));
+    }
+}
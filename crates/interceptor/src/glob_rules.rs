@@ -0,0 +1,51 @@
+//! Per-path rules keyed by glob patterns, as used by the external
+//! modification policies and the coherent capture strategies: the first
+//! rule whose pattern matches a forward-slash relative path applies.
+
+/// Check that every pattern is a valid glob, naming the first that is not.
+/// `kind` names the rules in the message, e.g. "coherent capture".
+pub fn validate<'a>(
+    kind: &str,
+    patterns: impl IntoIterator<Item = &'a str>,
+) -> Result<(), String> {
+    for pattern in patterns {
+        if let Err(error) = glob::Pattern::new(pattern) {
+            return Err(format!("invalid {kind} pattern '{pattern}': {error}"));
+        }
+    }
+    Ok(())
+}
+
+/// Compiled `(pattern, value)` rules.
+pub struct GlobRules<T> {
+    rules: Vec<(glob::Pattern, T)>,
+}
+
+impl<T: Copy> GlobRules<T> {
+    /// Compile `rules`. Invalid glob patterns are skipped with a warning;
+    /// requests reject them up front with [`validate`].
+    pub fn new<'a>(kind: &str, rules: impl IntoIterator<Item = (&'a str, T)>) -> Self {
+        let mut compiled = Vec::new();
+        for (pattern, value) in rules {
+            match glob::Pattern::new(pattern) {
+                Ok(glob) => compiled.push((glob, value)),
+                Err(error) => eprintln!(
+                    "{{\"level\":\"warn\",\"component\":\"undo\",\"message\":\"ignoring invalid {kind} pattern '{pattern}': {error}\"}}"
+                ),
+            }
+        }
+        Self { rules: compiled }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The value of the first rule matching `relative_path`, if any.
+    pub fn first_match(&self, relative_path: &str) -> Option<T> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(relative_path))
+            .map(|(_, value)| *value)
+    }
+}
@@ -5,7 +5,8 @@ use serde::Serialize;
 
 use codeagent_common::{BarrierInfo, StepId};
 
-use crate::manifest::StepManifest;
+use crate::manifest::{ManifestWarning, StepManifest};
+use crate::step_attribution::GuestProcess;
 use crate::undo_interceptor::{read_step_barriers, synthesize_barrier_id};
 
 /// A single entry in a step's manifest (file that was touched).
@@ -14,6 +15,8 @@ pub struct FileDetail {
     pub path: String,
     pub existed_before: bool,
     pub file_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_by: Option<GuestProcess>,
 }
 
 /// An undo step as read from disk.
@@ -25,6 +28,13 @@ pub struct StepDetail {
     pub file_count: usize,
     pub files: Vec<FileDetail>,
     pub unprotected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    pub preimage_bytes: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<ManifestWarning>,
 }
 
 /// The full undo history data read from a single undo directory.
@@ -94,6 +104,7 @@ pub fn read_undo_history(undo_dir: &Path) -> codeagent_common::Result<UndoHistor
                 path: path.clone(),
                 existed_before: entry.existed_before,
                 file_type: entry.file_type.clone(),
+                modified_by: entry.modified_by.clone(),
             })
             .collect();
 
@@ -104,6 +115,10 @@ pub fn read_undo_history(undo_dir: &Path) -> codeagent_common::Result<UndoHistor
             file_count: files.len(),
             files,
             unprotected: manifest.unprotected,
+            duration_ms: manifest.duration_ms,
+            exit_code: manifest.exit_code,
+            preimage_bytes: manifest.preimage_bytes,
+            warnings: manifest.warnings,
         });
     }
 
@@ -0,0 +1,125 @@
+//! Markers of rollbacks and evictions in progress, for crash recovery.
+//!
+//! Rolling back or evicting several steps removes their directories one at
+//! a time, so a crash can leave the history between two states. Before
+//! starting, the interceptor writes the steps it is about to remove, with
+//! their chain links, to `rollback_in_progress.json` or
+//! `eviction_in_progress.json` in the undo directory, and removes the file
+//! once done. At the next startup, `UndoInterceptor::recover` completes an
+//! operation whose file is still there: it removes the listed steps that
+//! are still on disk and moves the chain head past all of them. The
+//! rollback of a single step is made atomic by its own journal
+//! ([`crate::rollback_journal`]); this file only says which steps remain.
+//!
+//! Squashing steps builds the merged step next to them first, then lists
+//! the steps in `squash_in_progress.json` while it swaps the merged step in
+//! for them; recovery finishes the swap.
+
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use codeagent_common::{RollbackMode, StepId};
+
+use crate::chain::ChainLink;
+
+pub const ROLLBACK_MARKER_FILE: &str = "rollback_in_progress.json";
+pub const EVICTION_JOURNAL_FILE: &str = "eviction_in_progress.json";
+pub const SQUASH_JOURNAL_FILE: &str = "squash_in_progress.json";
+
+/// A step listed in a marker, with its chain link read before removal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournaledStep {
+    pub step_id: StepId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<ChainLink>,
+}
+
+/// Contents of `rollback_in_progress.json`: the steps being rolled back,
+/// newest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RollbackMarker {
+    pub mode: RollbackMode,
+    pub steps: Vec<JournaledStep>,
+}
+
+/// Contents of `eviction_in_progress.json`: the steps being evicted,
+/// oldest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvictionJournal {
+    pub steps: Vec<JournaledStep>,
+}
+
+/// Contents of `squash_in_progress.json`: the steps being squashed, oldest
+/// first. The merged step takes the id of the first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SquashJournal {
+    pub steps: Vec<StepId>,
+}
+
+/// Write `contents` to `file_name` in `undo_dir` atomically.
+pub fn write<T: Serialize>(
+    undo_dir: &Path,
+    file_name: &str,
+    contents: &T,
+) -> codeagent_common::Result<()> {
+    let tmp = undo_dir.join(format!("{file_name}.tmp"));
+    fs::write(&tmp, serde_json::to_string_pretty(contents)?)?;
+    fs::rename(&tmp, undo_dir.join(file_name))?;
+    Ok(())
+}
+
+/// Read `file_name` from `undo_dir`; `Ok(None)` if there is none.
+pub fn read<T: DeserializeOwned>(
+    undo_dir: &Path,
+    file_name: &str,
+) -> codeagent_common::Result<Option<T>> {
+    let path = undo_dir.join(file_name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let json = fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&json)?))
+}
+
+/// Remove `file_name` from `undo_dir`, if it is there.
+pub fn remove(undo_dir: &Path, file_name: &str) -> codeagent_common::Result<()> {
+    match fs::remove_file(undo_dir.join(file_name)) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn markers_round_trip_and_are_removed() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(read::<RollbackMarker>(dir.path(), ROLLBACK_MARKER_FILE).unwrap(), None);
+
+        let marker = RollbackMarker {
+            mode: RollbackMode::Merge,
+            steps: vec![
+                JournaledStep {
+                    step_id: 4,
+                    link: Some(ChainLink {
+                        prev: "a".to_string(),
+                        hash: "b".to_string(),
+                    }),
+                },
+                JournaledStep { step_id: 3, link: None },
+            ],
+        };
+        write(dir.path(), ROLLBACK_MARKER_FILE, &marker).unwrap();
+        assert_eq!(read(dir.path(), ROLLBACK_MARKER_FILE).unwrap(), Some(marker));
+
+        remove(dir.path(), ROLLBACK_MARKER_FILE).unwrap();
+        remove(dir.path(), ROLLBACK_MARKER_FILE).unwrap();
+        assert!(!dir.path().join(ROLLBACK_MARKER_FILE).exists());
+    }
+}
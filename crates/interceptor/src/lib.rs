@@ -1,9 +1,27 @@
+pub mod blob_cache;
+pub mod boundary;
+pub mod capture_slots;
+pub mod chain;
+pub mod coherent_capture;
+pub mod compaction;
+pub mod dir_rename;
+pub mod external_modification;
+#[cfg(feature = "git-mirror")]
+pub mod git_mirror;
 pub mod gitignore;
+pub mod glob_rules;
 pub mod history;
+pub mod history_journal;
 pub mod manifest;
+pub mod merge;
+pub mod passthrough;
+pub mod path_case;
 pub mod preimage;
 pub mod resource_limits;
 pub mod rollback;
+pub mod rollback_journal;
 pub mod safeguard;
+pub mod squash;
+pub mod step_attribution;
 pub mod undo_interceptor;
 pub mod write_interceptor;
@@ -1,10 +1,12 @@
 use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
-use codeagent_common::StepId;
+use codeagent_common::{StepId, StepInfo, StepType};
+
+use crate::step_attribution::GuestProcess;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StepManifest {
@@ -16,6 +18,28 @@ pub struct StepManifest {
     /// limit). The step cannot be rolled back.
     #[serde(default)]
     pub unprotected: bool,
+    /// Non-fatal problems recorded while capturing this step's preimages.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<ManifestWarning>,
+    /// Absent in manifests written before step types were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step_type: Option<StepType>,
+    /// Wall-clock time from opening to closing the step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Exit code of the command that ran in the step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// Compressed size of the preimage data stored for the step.
+    #[serde(default)]
+    pub preimage_bytes: u64,
+    /// Manifest hash of the step closed before this one (see [`crate::chain`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_prev: Option<String>,
+    /// Directories renamed in the step, in order. Entry paths are the names
+    /// from before these renames (see [`crate::dir_rename`]).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renames: Vec<RenameRecord>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,16 +47,100 @@ pub struct ManifestEntry {
     pub existed_before: bool,
     pub path_hash: String,
     pub file_type: String,
+    /// Set for regular files that had more than one hard link when captured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hard_link: Option<HardLinkInfo>,
+    /// Guest process whose operation first changed the path in the step,
+    /// when the backend could tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_by: Option<GuestProcess>,
+}
+
+/// Inode identity of a hard-linked file at capture time (Unix only).
+///
+/// The first path of an inode captured in a step holds the preimage; later
+/// paths of the same inode name it in `same_inode_as` and are re-linked to it
+/// on rollback instead of being restored as independent copies.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardLinkInfo {
+    pub dev: u64,
+    pub inode: u64,
+    pub nlink: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub same_inode_as: Option<String>,
+}
+
+impl HardLinkInfo {
+    /// `(dev, inode)` pair identifying the file.
+    pub fn file_id(&self) -> (u64, u64) {
+        (self.dev, self.inode)
+    }
 }
 
+/// A directory rename recorded as one operation, with relative paths as
+/// they were when it happened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RenameRecord {
+    pub from: String,
+    pub to: String,
+    /// `(dev, inode)` of the directory. Rollback leaves a destination that
+    /// is another file by then alone.
+    pub file_id: (u64, u64),
+}
+
+/// A non-fatal problem attached to a manifest entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestWarning {
+    pub path: String,
+    /// Machine-readable warning code (e.g. `incoherent_capture`).
+    pub code: String,
+    pub message: String,
+}
+
+/// Warning code recorded when a coherent capture rule matched but the capture
+/// could not be guaranteed consistent.
+pub const WARNING_INCOHERENT_CAPTURE: &str = "incoherent_capture";
+
+/// Warning code recorded when a path was first changed while another open
+/// step had already captured it.
+pub const WARNING_CONCURRENT_WRITE: &str = "concurrent_write";
+
 impl StepManifest {
     pub fn new(step_id: StepId) -> Self {
         Self {
             step_id,
-            timestamp: chrono::Utc::now().to_rfc3339(),
+            timestamp: codeagent_common::time::now_timestamp(),
             command: None,
             entries: BTreeMap::new(),
             unprotected: false,
+            warnings: Vec::new(),
+            step_type: Some(if step_id < 0 { StepType::Ambient } else { StepType::Command }),
+            duration_ms: None,
+            exit_code: None,
+            preimage_bytes: 0,
+            chain_prev: None,
+            renames: Vec::new(),
+        }
+    }
+
+    /// Summarize the step for history listings.
+    pub fn step_info(&self) -> StepInfo {
+        StepInfo {
+            id: self.step_id,
+            step_type: self.step_type.unwrap_or(StepType::Command),
+            timestamp: codeagent_common::time::parse_timestamp(&self.timestamp)
+                .unwrap_or_default(),
+            command: self.command.clone(),
+            affected_paths: self
+                .entries
+                .keys()
+                .chain(self.renames.iter().map(|rename| &rename.to))
+                .map(PathBuf::from)
+                .collect(),
+            duration_ms: self.duration_ms,
+            exit_code: self.exit_code,
+            file_count: self.entries.len(),
+            preimage_bytes: self.preimage_bytes,
         }
     }
 
@@ -49,10 +157,35 @@ impl StepManifest {
                 existed_before,
                 path_hash: path_hash.to_string(),
                 file_type: file_type.to_string(),
+                hard_link: None,
+                modified_by: None,
             },
         );
     }
 
+    /// Attach hard-link identity to an existing entry.
+    pub fn set_hard_link(&mut self, relative_path: &str, hard_link: Option<HardLinkInfo>) {
+        if let Some(entry) = self.entries.get_mut(relative_path) {
+            entry.hard_link = hard_link;
+        }
+    }
+
+    /// Attach the issuing guest process to an existing entry.
+    pub fn set_modified_by(&mut self, relative_path: &str, process: Option<GuestProcess>) {
+        if let Some(entry) = self.entries.get_mut(relative_path) {
+            entry.modified_by = process;
+        }
+    }
+
+    /// Record a non-fatal warning for a path.
+    pub fn add_warning(&mut self, relative_path: &str, code: &str, message: String) {
+        self.warnings.push(ManifestWarning {
+            path: relative_path.to_string(),
+            code: code.to_string(),
+            message,
+        });
+    }
+
     /// Write manifest to the given directory as manifest.json.
     pub fn write_to(&self, dir: &Path) -> codeagent_common::Result<()> {
         let path = dir.join("manifest.json");
@@ -98,6 +231,35 @@ mod tests {
         assert!(!loaded.entries["new_file.txt"].existed_before);
     }
 
+    #[test]
+    fn manifest_step_info_summarizes_metadata() {
+        let mut manifest = StepManifest::new(3);
+        manifest.command = Some("cargo build".to_string());
+        manifest.duration_ms = Some(1200);
+        manifest.exit_code = Some(101);
+        manifest.preimage_bytes = 512;
+        manifest.add_entry("Cargo.lock", "abc", true, "regular");
+        manifest.add_entry("target", "def", false, "directory");
+
+        let info = manifest.step_info();
+        assert_eq!(info.id, 3);
+        assert_eq!(info.step_type, StepType::Command);
+        assert_eq!(info.file_count, 2);
+        assert_eq!((info.duration_ms, info.exit_code), (Some(1200), Some(101)));
+        assert_eq!(info.preimage_bytes, 512);
+        assert_eq!(info.affected_paths, vec![PathBuf::from("Cargo.lock"), PathBuf::from("target")]);
+    }
+
+    #[test]
+    fn manifest_without_metadata_deserializes() {
+        let json = r#"{"step_id":1,"timestamp":"2024-01-01T00:00:00Z","command":null,"entries":{}}"#;
+        let manifest: StepManifest = serde_json::from_str(json).unwrap();
+        assert_eq!(manifest.step_type, None);
+        assert_eq!(manifest.duration_ms, None);
+        assert_eq!(manifest.exit_code, None);
+        assert_eq!(manifest.preimage_bytes, 0);
+    }
+
     #[test]
     fn manifest_contains_path() {
         let mut manifest = StepManifest::new(1);
@@ -143,5 +305,58 @@ mod tests {
 
         let loaded = StepManifest::read_from(dir.path()).unwrap();
         assert!(!loaded.unprotected);
+        assert!(loaded.warnings.is_empty());
+    }
+
+    #[test]
+    fn manifest_hard_link_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let mut manifest = StepManifest::new(1);
+        manifest.add_entry("a.txt", "hash_a", true, "regular");
+        manifest.add_entry("b.txt", "hash_b", true, "regular");
+        manifest.add_entry("c.txt", "hash_c", true, "regular");
+        let info = HardLinkInfo {
+            dev: 1,
+            inode: 42,
+            nlink: 2,
+            same_inode_as: None,
+        };
+        manifest.set_hard_link("a.txt", Some(info.clone()));
+        manifest.set_hard_link(
+            "b.txt",
+            Some(HardLinkInfo {
+                same_inode_as: Some("a.txt".to_string()),
+                ..info.clone()
+            }),
+        );
+
+        manifest.write_to(dir.path()).unwrap();
+        let loaded = StepManifest::read_from(dir.path()).unwrap();
+
+        assert_eq!(loaded.entries["a.txt"].hard_link, Some(info));
+        assert_eq!(
+            loaded.entries["b.txt"].hard_link.as_ref().unwrap().same_inode_as.as_deref(),
+            Some("a.txt")
+        );
+        assert!(loaded.entries["c.txt"].hard_link.is_none());
+    }
+
+    #[test]
+    fn manifest_warnings_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let mut manifest = StepManifest::new(1);
+        manifest.add_entry("app.db", "hash", true, "regular");
+        manifest.add_warning(
+            "app.db",
+            WARNING_INCOHERENT_CAPTURE,
+            "file kept changing".to_string(),
+        );
+
+        manifest.write_to(dir.path()).unwrap();
+        let loaded = StepManifest::read_from(dir.path()).unwrap();
+
+        assert_eq!(loaded.warnings.len(), 1);
+        assert_eq!(loaded.warnings[0].path, "app.db");
+        assert_eq!(loaded.warnings[0].code, WARNING_INCOHERENT_CAPTURE);
     }
 }
@@ -0,0 +1,248 @@
+//! Line-based three-way merge used by merge-mode rollback.
+//!
+//! `base` is the common ancestor; `ours` and `theirs` are two edits of it.
+//! Regions changed on only one side take that side, identical changes are
+//! taken once, and regions changed differently on both sides are written
+//! with git-style conflict markers.
+
+/// Beyond this many inserted plus deleted lines between two versions the
+/// diff gives up and treats the differing region as one changed block.
+const MAX_EDIT_DISTANCE: usize = 4096;
+
+/// Result of [`merge`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeOutcome {
+    pub text: String,
+    /// Number of conflict regions written to `text`.
+    pub conflicts: usize,
+}
+
+/// Whether `bytes` can be merged as text: valid UTF-8 without NUL bytes.
+pub fn is_text(bytes: &[u8]) -> bool {
+    !bytes.contains(&0) && std::str::from_utf8(bytes).is_ok()
+}
+
+/// Merge `ours` and `theirs`, both derived from `base`. Conflict regions
+/// are labelled `<<<<<<< {ours_label}` and `>>>>>>> {theirs_label}`.
+pub fn merge(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+    ours_label: &str,
+    theirs_label: &str,
+) -> MergeOutcome {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+    let to_ours = matching_lines(&base, &ours);
+    let to_theirs = matching_lines(&base, &theirs);
+
+    let mut outcome = MergeOutcome {
+        text: String::new(),
+        conflicts: 0,
+    };
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        // The next base line kept unchanged on both sides ends the current
+        // (possibly empty) changed region.
+        let stable = (b..base.len()).find_map(|i| Some((i, to_ours[i]?, to_theirs[i]?)));
+        let (b_end, o_end, t_end) = stable.unwrap_or((base.len(), ours.len(), theirs.len()));
+        outcome.resolve(
+            &base[b..b_end],
+            &ours[o..o_end],
+            &theirs[t..t_end],
+            ours_label,
+            theirs_label,
+        );
+        let Some((b_end, o_end, t_end)) = stable else {
+            break;
+        };
+        outcome.text.push_str(base[b_end]);
+        (b, o, t) = (b_end + 1, o_end + 1, t_end + 1);
+    }
+    outcome
+}
+
+impl MergeOutcome {
+    fn resolve(
+        &mut self,
+        base: &[&str],
+        ours: &[&str],
+        theirs: &[&str],
+        ours_label: &str,
+        theirs_label: &str,
+    ) {
+        if ours == theirs || theirs == base {
+            self.text.extend(ours.iter().copied());
+        } else if ours == base {
+            self.text.extend(theirs.iter().copied());
+        } else {
+            self.conflicts += 1;
+            self.text.push_str(&format!("<<<<<<< {ours_label}\n"));
+            self.push_block(ours);
+            self.text.push_str("=======\n");
+            self.push_block(theirs);
+            self.text.push_str(&format!(">>>>>>> {theirs_label}\n"));
+        }
+    }
+
+    /// Append lines inside a conflict, ending them with a newline so the
+    /// following marker starts on its own line.
+    fn push_block(&mut self, lines: &[&str]) {
+        self.text.extend(lines.iter().copied());
+        if !self.text.ends_with('\n') {
+            self.text.push('\n');
+        }
+    }
+}
+
+/// For each line of `a`, the index of the line of `b` it is kept as in a
+/// shortest edit script, or `None` if it was deleted or changed.
+fn matching_lines(a: &[&str], b: &[&str]) -> Vec<Option<usize>> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let mut matches = vec![None; a.len()];
+    for (i, slot) in matches.iter_mut().enumerate().take(prefix) {
+        *slot = Some(i);
+    }
+    for i in 0..suffix {
+        matches[a.len() - 1 - i] = Some(b.len() - 1 - i);
+    }
+    for (x, y) in myers(&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]) {
+        matches[prefix + x] = Some(prefix + y);
+    }
+    matches
+}
+
+/// Matched `(a, b)` line pairs of a shortest edit script (Myers' greedy
+/// algorithm). Empty when the edit distance exceeds [`MAX_EDIT_DISTANCE`].
+fn myers(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m).min(MAX_EDIT_DISTANCE as isize);
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // trace[d][k + d] is the furthest x reached on diagonal k after d edits.
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                return backtrack(&trace, n, m);
+            }
+        }
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+    }
+    Vec::new()
+}
+
+fn backtrack(trace: &[Vec<isize>], n: isize, m: isize) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (1..=trace.len() as isize).rev() {
+        let previous = &trace[d as usize - 1];
+        let at = |k: isize| previous[(k + d - 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((x as usize, y as usize));
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    while x > 0 && y > 0 {
+        x -= 1;
+        y -= 1;
+        pairs.push((x as usize, y as usize));
+    }
+    pairs.reverse();
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(base: &str, ours: &str, theirs: &str) -> MergeOutcome {
+        merge(base, ours, theirs, "ours", "theirs")
+    }
+
+    #[test]
+    fn changes_on_different_lines_combine() {
+        let outcome = merged("a\nb\nc\nd\ne\n", "a\nB\nc\nd\ne\n", "a\nb\nc\nd\nE\nf\n");
+        assert_eq!(outcome.text, "a\nB\nc\nd\nE\nf\n");
+        assert_eq!(outcome.conflicts, 0);
+    }
+
+    #[test]
+    fn one_sided_and_identical_changes() {
+        assert_eq!(merged("a\nb\n", "a\nb\n", "x\nb\n").text, "x\nb\n");
+        assert_eq!(merged("a\nb\n", "x\nb\n", "a\nb\n").text, "x\nb\n");
+        assert_eq!(merged("a\nb\n", "a\nx\n", "a\nx\n").text, "a\nx\n");
+        assert_eq!(merged("", "", "new\n").text, "new\n");
+    }
+
+    #[test]
+    fn overlapping_changes_conflict() {
+        let outcome = merged("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n");
+        assert_eq!(outcome.conflicts, 1);
+        assert_eq!(
+            outcome.text,
+            "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\n",
+        );
+    }
+
+    #[test]
+    fn conflict_markers_start_on_their_own_line() {
+        let outcome = merged("a", "b", "c");
+        assert_eq!(outcome.text, "<<<<<<< ours\nb\n=======\nc\n>>>>>>> theirs\n");
+    }
+
+    #[test]
+    fn deletions_are_merged() {
+        let outcome = merged("a\nb\nc\nd\n", "a\nc\nd\n", "a\nb\nc\n");
+        assert_eq!(outcome.text, "a\nc\n");
+        assert_eq!(outcome.conflicts, 0);
+    }
+
+    #[test]
+    fn matching_lines_finds_interior_matches() {
+        let a = ["x", "a", "b", "y", "c"];
+        let b = ["a", "z", "b", "c", "w"];
+        assert_eq!(
+            matching_lines(&a, &b),
+            vec![None, Some(0), Some(2), None, Some(3)],
+        );
+    }
+
+    #[test]
+    fn text_detection() {
+        assert!(is_text(b"fn main() {}\n"));
+        assert!(!is_text(b"\x00\x01binary"));
+        assert!(!is_text(&[0xff, 0xfe]));
+    }
+}
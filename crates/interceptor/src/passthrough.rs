@@ -0,0 +1,112 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use codeagent_common::{Result, StepId, StepManager};
+
+use crate::write_interceptor::WriteInterceptor;
+
+/// Interceptor for sessions started with undo disabled.
+///
+/// Every write hook is a no-op, so backends pass operations straight
+/// through to the host filesystem. Steps are still tracked so the control
+/// channel can report which command is running, but closing a step records
+/// nothing and never evicts anything.
+#[derive(Debug, Default)]
+pub struct PassthroughInterceptor {
+    current_step: Mutex<Option<StepId>>,
+}
+
+impl PassthroughInterceptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StepManager for PassthroughInterceptor {
+    fn open_step(&self, id: StepId) -> Result<()> {
+        *self.current_step.lock().unwrap() = Some(id);
+        Ok(())
+    }
+
+    fn close_step(&self, id: StepId) -> Result<Vec<StepId>> {
+        let mut current = self.current_step.lock().unwrap();
+        if *current == Some(id) {
+            *current = None;
+        }
+        Ok(Vec::new())
+    }
+
+    fn current_step(&self) -> Option<StepId> {
+        *self.current_step.lock().unwrap()
+    }
+}
+
+impl WriteInterceptor for PassthroughInterceptor {
+    fn pre_write(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn pre_unlink(&self, _path: &Path, _is_dir: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn pre_rename(&self, _from: &Path, _to: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn post_create(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn post_mkdir(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn pre_setattr(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn pre_link(&self, _target: &Path, _link_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn post_symlink(&self, _target: &Path, _link_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn pre_xattr(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn pre_open_trunc(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn pre_fallocate(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn pre_copy_file_range(&self, _dst_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn current_step(&self) -> Option<StepId> {
+        *self.current_step.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_are_tracked_without_recording() {
+        let interceptor = PassthroughInterceptor::new();
+        interceptor.open_step(3).unwrap();
+        assert_eq!(StepManager::current_step(&interceptor), Some(3));
+        assert_eq!(WriteInterceptor::current_step(&interceptor), Some(3));
+        interceptor.pre_write(Path::new("/anywhere")).unwrap();
+        assert!(interceptor.close_step(3).unwrap().is_empty());
+        assert_eq!(StepManager::current_step(&interceptor), None);
+    }
+}
@@ -0,0 +1,107 @@
+//! Case sensitivity of working roots.
+//!
+//! On a case-insensitive filesystem, `Foo.txt` and `foo.txt` are one file.
+//! The undo interceptor then keys the paths a step touched by their folded
+//! spelling ([`fold`]), so a file touched under two spellings is captured
+//! once, and records a rename that only changes case as the creation of the
+//! new spelling: rollback removes it and restores the old one.
+
+use std::fs;
+use std::path::Path;
+
+use codeagent_common::CaseSensitivity;
+
+/// Whether paths under `root` compare case-insensitively, probing the
+/// filesystem for [`CaseSensitivity::Auto`].
+pub fn resolve(sensitivity: CaseSensitivity, root: &Path) -> bool {
+    match sensitivity {
+        CaseSensitivity::Auto => detect_case_insensitive(root),
+        CaseSensitivity::Sensitive => false,
+        CaseSensitivity::Insensitive => true,
+    }
+}
+
+/// Probe whether the filesystem of `root` is case-insensitive, without
+/// writing to it: the first entry of `root` (or `root` itself) with a cased
+/// name is looked up with its case swapped. Reports case-sensitive when
+/// there is nothing to look up.
+pub fn detect_case_insensitive(root: &Path) -> bool {
+    let entries = fs::read_dir(root).into_iter().flatten().flatten().map(|entry| entry.path());
+    for path in entries.chain(std::iter::once(root.to_path_buf())) {
+        let Some(swapped) = path.file_name().and_then(|name| name.to_str()).and_then(swap_case)
+        else {
+            continue;
+        };
+        let Ok(original) = path.symlink_metadata() else {
+            continue;
+        };
+        return path
+            .with_file_name(swapped)
+            .symlink_metadata()
+            .is_ok_and(|other| same_file(&original, &other));
+    }
+    false
+}
+
+/// The case-folded spelling paths are compared by on a case-insensitive
+/// filesystem.
+pub fn fold(path: &str) -> String {
+    path.to_lowercase()
+}
+
+/// `name` with the case of its ASCII letters swapped, or `None` if it has
+/// none.
+fn swap_case(name: &str) -> Option<String> {
+    let swapped: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_lowercase() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect();
+    (swapped != name).then_some(swapped)
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn explicit_settings_skip_the_probe() {
+        let dir = TempDir::new().unwrap();
+        assert!(resolve(CaseSensitivity::Insensitive, dir.path()));
+        assert!(!resolve(CaseSensitivity::Sensitive, dir.path()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn probe_reports_case_sensitive_linux_directories() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("Readme.md"), "").unwrap();
+        fs::write(dir.path().join("rEADME.MD"), "").unwrap();
+        assert!(!detect_case_insensitive(dir.path()));
+        assert!(!detect_case_insensitive(&dir.path().join("missing")));
+    }
+
+    #[test]
+    fn swapping_needs_a_cased_letter() {
+        assert_eq!(swap_case("Foo-1.txt").as_deref(), Some("fOO-1.TXT"));
+        assert_eq!(swap_case("123_-"), None);
+        assert_eq!(fold("Src/Main.RS"), "src/main.rs");
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::BTreeMap;
 use std::fs;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use std::time::UNIX_EPOCH;
 
@@ -7,6 +8,9 @@ use serde::{Deserialize, Serialize};
 
 use codeagent_common::CodeAgentError;
 
+use crate::blob_cache::PreimageBlobCache;
+use crate::manifest::HardLinkInfo;
+
 /// Compute a hex-encoded blake3 hash of a relative path string,
 /// used as the filename for preimage storage on disk.
 /// Normalizes path separators to forward slashes for cross-platform consistency.
@@ -43,9 +47,47 @@ pub struct PreimageMetadata {
     pub mode: u32,
     /// mtime in nanoseconds since Unix epoch.
     pub mtime_ns: i128,
+    /// atime in nanoseconds since Unix epoch, for preimages recorded since
+    /// it was kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime_ns: Option<i128>,
+    /// Owner and group ids (Unix only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
     pub size: u64,
     pub symlink_target: Option<String>,
     pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// Set when only the byte ranges about to be overwritten were captured
+    /// instead of the whole file. Patch `i` is stored in
+    /// `{path_hash}.range.{i}.dat`; `size` is the original length.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range_patches: Option<Vec<RangePatch>>,
+    /// Inode identity when the file had more than one hard link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hard_link: Option<HardLinkInfo>,
+    /// Hex blake3 hash of the uncompressed contents in `{path_hash}.dat`,
+    /// checked by [`verify_preimage`] before rollback. Absent for range
+    /// preimages, whose patches carry their own, and for preimages written
+    /// before hashes were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// Set when only the attributes were captured, before a change that
+    /// leaves the contents alone (chmod, chown, utimes, xattrs). No data
+    /// file is stored, and rollback restores the attributes only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metadata_only: bool,
+}
+
+/// A byte range of original file contents saved by a range capture.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RangePatch {
+    pub offset: u64,
+    pub len: u64,
+    /// Hex blake3 hash of the uncompressed bytes saved for this range.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
 }
 
 /// Capture the preimage of an existing path: metadata + compressed contents.
@@ -59,19 +101,324 @@ pub fn capture_preimage(
     working_root: &Path,
     preimage_dir: &Path,
 ) -> codeagent_common::Result<(PreimageMetadata, u64)> {
-    let relative = file_path.strip_prefix(working_root).map_err(|_| {
-        CodeAgentError::Preimage {
-            path: file_path.to_path_buf(),
-            message: "path is not under working root".to_string(),
-        }
-    })?;
+    capture_preimage_with(file_path, working_root, preimage_dir, |path| fs::read(path))
+}
+
+/// Like [`capture_preimage`], but regular file contents are obtained through
+/// `read_contents` instead of a plain `fs::read`. Used by coherent capture to
+/// read under an advisory lock or retry until the file is quiescent.
+pub fn capture_preimage_with<F>(
+    file_path: &Path,
+    working_root: &Path,
+    preimage_dir: &Path,
+    read_contents: F,
+) -> codeagent_common::Result<(PreimageMetadata, u64)>
+where
+    F: FnOnce(&Path) -> std::io::Result<Vec<u8>>,
+{
+    let relative = relative_to_root(file_path, working_root)?;
+    capture_preimage_cached(file_path, relative, preimage_dir, None, read_contents)
+}
+
+/// Like [`capture_preimage_with`], but contents whose compressed blob is in
+/// `cache` are linked from it instead of being compressed again, and newly
+/// compressed contents are added to it. The returned data size is the blob's
+/// size either way, since the step keeps the blob after the cache drops it.
+///
+/// The preimage is recorded under `relative`, normally `file_path` relative
+/// to the working root (see [`crate::dir_rename`] for when it is not); the
+/// other capture functions taking a relative path do the same.
+pub fn capture_preimage_cached<F>(
+    file_path: &Path,
+    relative: &Path,
+    preimage_dir: &Path,
+    cache: Option<&PreimageBlobCache>,
+    read_contents: F,
+) -> codeagent_common::Result<(PreimageMetadata, u64)>
+where
+    F: FnOnce(&Path) -> std::io::Result<Vec<u8>>,
+{
 
     let hash = path_hash(relative);
-    let meta_path = preimage_dir.join(format!("{hash}.meta.json"));
-    let meta_tmp = preimage_dir.join(format!("{hash}.meta.json.tmp"));
     let data_path = preimage_dir.join(format!("{hash}.dat"));
     let data_tmp = preimage_dir.join(format!("{hash}.dat.tmp"));
 
+    let mut preimage_meta = existing_path_metadata(file_path, relative)?;
+    let contents = if preimage_meta.file_type == PreimageFileType::Regular {
+        let contents = read_contents(file_path)?;
+        preimage_meta.content_hash = Some(PreimageBlobCache::content_hash(&contents));
+        Some(contents)
+    } else {
+        None
+    };
+    write_preimage_metadata(preimage_dir, &hash, &preimage_meta)?;
+
+    let mut data_bytes_written: u64 = 0;
+    if let (Some(contents), Some(content_hash)) = (contents, &preimage_meta.content_hash) {
+        let reused = cache.and_then(|cache| cache.reuse(content_hash, &data_path));
+        if let Some(size) = reused {
+            data_bytes_written = size;
+        } else {
+            let compressed = compress(file_path, &contents)?;
+            data_bytes_written = compressed.len() as u64;
+            fs::write(&data_tmp, &compressed)?;
+            fs::rename(&data_tmp, &data_path)?;
+            if let Some(cache) = cache {
+                cache.insert(content_hash, &data_path, data_bytes_written);
+            }
+        }
+    }
+
+    Ok((preimage_meta, data_bytes_written))
+}
+
+/// Capture a metadata-only preimage of an existing path: its attributes
+/// without its contents. Writes only `{path_hash}.meta.json`.
+pub fn capture_metadata_preimage(
+    file_path: &Path,
+    relative: &Path,
+    preimage_dir: &Path,
+) -> codeagent_common::Result<PreimageMetadata> {
+
+    let mut preimage_meta = existing_path_metadata(file_path, relative)?;
+    preimage_meta.metadata_only = true;
+    write_preimage_metadata(preimage_dir, &path_hash(relative), &preimage_meta)?;
+
+    Ok(preimage_meta)
+}
+
+/// Add the contents to a metadata-only preimage before they are first
+/// changed. The contents are still the original ones, since nothing but the
+/// attributes changed since capture; the recorded attributes are kept.
+///
+/// Returns the number of data bytes written for the `.dat` file.
+pub fn promote_metadata_preimage(
+    file_path: &Path,
+    preimage_dir: &Path,
+    preimage_meta: &mut PreimageMetadata,
+) -> codeagent_common::Result<u64> {
+    let hash = path_hash(Path::new(&preimage_meta.relative_path));
+    preimage_meta.metadata_only = false;
+    if preimage_meta.file_type != PreimageFileType::Regular {
+        write_preimage_metadata(preimage_dir, &hash, preimage_meta)?;
+        return Ok(0);
+    }
+
+    let contents = fs::read(file_path)?;
+    preimage_meta.content_hash = Some(PreimageBlobCache::content_hash(&contents));
+    let compressed = compress(file_path, &contents)?;
+    let data_path = preimage_dir.join(format!("{hash}.dat"));
+    let data_tmp = preimage_dir.join(format!("{hash}.dat.tmp"));
+    fs::write(&data_tmp, &compressed)?;
+    fs::rename(&data_tmp, &data_path)?;
+    write_preimage_metadata(preimage_dir, &hash, preimage_meta)?;
+
+    Ok(compressed.len() as u64)
+}
+
+/// Capture a range preimage of an existing regular file: metadata plus the
+/// original bytes in `[offset, offset + len)` that lie within the file.
+/// Appends beyond the end of the file store no data at all -- rollback only
+/// needs to truncate back to the original size.
+///
+/// Returns the metadata (with `range_patches` set) and the number of data
+/// bytes written.
+pub fn capture_range_preimage(
+    file_path: &Path,
+    relative: &Path,
+    preimage_dir: &Path,
+    offset: u64,
+    len: u64,
+) -> codeagent_common::Result<(PreimageMetadata, u64)> {
+    let mut preimage_meta = existing_path_metadata(file_path, relative)?;
+    preimage_meta.range_patches = Some(Vec::new());
+    let data_bytes_written =
+        append_range_patch(file_path, preimage_dir, &mut preimage_meta, offset, len)?;
+    if preimage_meta.range_patches.as_ref().is_some_and(Vec::is_empty) {
+        // Pure append: no patch was stored, so the metadata is not on disk yet.
+        let hash = path_hash(relative);
+        write_preimage_metadata(preimage_dir, &hash, &preimage_meta)?;
+    }
+
+    Ok((preimage_meta, data_bytes_written))
+}
+
+/// Save another byte range of a range-captured file before it is overwritten.
+///
+/// Ranges already modified earlier in the step are saved again with their
+/// current contents; rollback applies patches newest-first so the earliest
+/// (original) bytes win. Bytes at or beyond the original size are never
+/// saved. Returns the number of data bytes written (0 if nothing was saved).
+pub fn append_range_patch(
+    file_path: &Path,
+    preimage_dir: &Path,
+    preimage_meta: &mut PreimageMetadata,
+    offset: u64,
+    len: u64,
+) -> codeagent_common::Result<u64> {
+    let end = offset.saturating_add(len).min(preimage_meta.size);
+    if offset >= end {
+        return Ok(0);
+    }
+
+    let mut contents = Vec::with_capacity((end - offset) as usize);
+    let mut file = fs::File::open(file_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.take(end - offset).read_to_end(&mut contents)?;
+
+    let hash = path_hash(Path::new(&preimage_meta.relative_path));
+    let patches = preimage_meta.range_patches.get_or_insert_with(Vec::new);
+    let index = patches.len();
+    let data_path = preimage_dir.join(format!("{hash}.range.{index}.dat"));
+    let data_tmp = preimage_dir.join(format!("{hash}.range.{index}.dat.tmp"));
+
+    let compressed = compress(file_path, &contents)?;
+    fs::write(&data_tmp, &compressed)?;
+    fs::rename(&data_tmp, &data_path)?;
+
+    patches.push(RangePatch {
+        offset,
+        len: contents.len() as u64,
+        content_hash: Some(PreimageBlobCache::content_hash(&contents)),
+    });
+    write_preimage_metadata(preimage_dir, &hash, preimage_meta)?;
+
+    Ok(compressed.len() as u64)
+}
+
+/// Convert a range preimage into a full one by reconstructing the original
+/// contents from the current file and the stored patches. Needed before an
+/// operation the patches cannot describe (truncate, unlink, rename).
+///
+/// Returns the number of data bytes written for the full `.dat` file.
+pub fn promote_range_preimage(
+    file_path: &Path,
+    preimage_dir: &Path,
+    preimage_meta: &mut PreimageMetadata,
+) -> codeagent_common::Result<u64> {
+    let contents = fs::read(file_path)?;
+    promote_range_preimage_onto(file_path, contents, preimage_dir, preimage_meta)
+}
+
+/// Like [`promote_range_preimage`], with `contents` standing in for the
+/// current file: the stored patches are applied to them instead.
+pub fn promote_range_preimage_onto(
+    file_path: &Path,
+    mut contents: Vec<u8>,
+    preimage_dir: &Path,
+    preimage_meta: &mut PreimageMetadata,
+) -> codeagent_common::Result<u64> {
+    let hash = path_hash(Path::new(&preimage_meta.relative_path));
+    let patches = preimage_meta.range_patches.take().unwrap_or_default();
+
+    for (patch, original) in read_range_patches(preimage_dir, &hash, &patches)?.iter().rev() {
+        let start = patch.offset as usize;
+        let end = start + original.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(original);
+    }
+    contents.resize(preimage_meta.size as usize, 0);
+    preimage_meta.content_hash = Some(PreimageBlobCache::content_hash(&contents));
+
+    let compressed = compress(file_path, &contents)?;
+    let data_path = preimage_dir.join(format!("{hash}.dat"));
+    let data_tmp = preimage_dir.join(format!("{hash}.dat.tmp"));
+    fs::write(&data_tmp, &compressed)?;
+    fs::rename(&data_tmp, &data_path)?;
+    write_preimage_metadata(preimage_dir, &hash, preimage_meta)?;
+
+    for index in 0..patches.len() {
+        let _ = fs::remove_file(preimage_dir.join(format!("{hash}.range.{index}.dat")));
+    }
+
+    Ok(compressed.len() as u64)
+}
+
+/// Load and decompress the stored patches of a range preimage, in capture order.
+pub fn read_range_patches(
+    preimage_dir: &Path,
+    path_hash: &str,
+    patches: &[RangePatch],
+) -> codeagent_common::Result<Vec<(RangePatch, Vec<u8>)>> {
+    let mut result = Vec::with_capacity(patches.len());
+    for (index, patch) in patches.iter().enumerate() {
+        let compressed = fs::read(preimage_dir.join(format!("{path_hash}.range.{index}.dat")))?;
+        let original = zstd::decode_all(compressed.as_slice()).map_err(|e| {
+            CodeAgentError::Decompression {
+                message: format!("failed to decompress range patch {index} for {path_hash}: {e}"),
+            }
+        })?;
+        result.push((patch.clone(), original));
+    }
+    Ok(result)
+}
+
+/// Check that the stored contents of a regular-file preimage are intact:
+/// every data file decompresses (else `Decompression`) and matches the hash
+/// recorded at capture (else `Preimage`).
+/// Rollback verifies each preimage of a step before restoring any, so a
+/// corrupt or truncated one fails the rollback instead of half-restoring
+/// the step. Other file types and metadata-only preimages have no contents
+/// and always pass.
+pub fn verify_preimage(
+    preimage_dir: &Path,
+    path_hash: &str,
+    preimage_meta: &PreimageMetadata,
+) -> codeagent_common::Result<()> {
+    if preimage_meta.file_type != PreimageFileType::Regular
+        || !preimage_meta.existed_before
+        || preimage_meta.metadata_only
+    {
+        return Ok(());
+    }
+    let relative_path = &preimage_meta.relative_path;
+    let check = |data_file: String, expected: Option<&str>| {
+        let compressed = fs::read(preimage_dir.join(&data_file))?;
+        let contents = zstd::decode_all(compressed.as_slice()).map_err(|e| {
+            CodeAgentError::Decompression {
+                message: format!("failed to decompress preimage for {relative_path}: {e}"),
+            }
+        })?;
+        match expected {
+            Some(expected) if PreimageBlobCache::content_hash(&contents) != expected => {
+                Err(CodeAgentError::Preimage {
+                    path: relative_path.into(),
+                    message: format!("{data_file} does not match the checksum recorded at capture"),
+                })
+            }
+            _ => Ok(()),
+        }
+    };
+
+    match &preimage_meta.range_patches {
+        Some(patches) => {
+            for (index, patch) in patches.iter().enumerate() {
+                check(format!("{path_hash}.range.{index}.dat"), patch.content_hash.as_deref())?;
+            }
+            Ok(())
+        }
+        None => check(format!("{path_hash}.dat"), preimage_meta.content_hash.as_deref()),
+    }
+}
+
+/// `file_path` relative to `working_root`.
+pub fn relative_to_root<'a>(
+    file_path: &'a Path,
+    working_root: &Path,
+) -> codeagent_common::Result<&'a Path> {
+    file_path.strip_prefix(working_root).map_err(|_| CodeAgentError::Preimage {
+        path: file_path.to_path_buf(),
+        message: "path is not under working root".to_string(),
+    })
+}
+
+/// Build the preimage metadata of an existing path.
+fn existing_path_metadata(
+    file_path: &Path,
+    relative: &Path,
+) -> codeagent_common::Result<PreimageMetadata> {
     let metadata = fs::symlink_metadata(file_path)?;
 
     let (file_type, symlink_target) = if metadata.is_symlink() {
@@ -86,54 +433,52 @@ pub fn capture_preimage(
         (PreimageFileType::Regular, None)
     };
 
-    let mode = read_mode(&metadata);
-    let mtime_ns = read_mtime_ns(&metadata);
-    let size = metadata.len();
-    let xattrs = read_xattrs(file_path);
-
-    let preimage_meta = PreimageMetadata {
+    Ok(PreimageMetadata {
         relative_path: relative.to_string_lossy().replace('\\', "/"),
         existed_before: true,
         file_type,
-        mode,
-        mtime_ns,
-        size,
+        mode: read_mode(&metadata),
+        mtime_ns: read_mtime_ns(&metadata),
+        atime_ns: Some(read_atime_ns(&metadata)),
+        uid: read_owner(&metadata).map(|(uid, _)| uid),
+        gid: read_owner(&metadata).map(|(_, gid)| gid),
+        size: metadata.len(),
         symlink_target,
-        xattrs,
-    };
+        xattrs: read_xattrs(file_path),
+        range_patches: None,
+        hard_link: read_hard_link(&metadata),
+        content_hash: None,
+        metadata_only: false,
+    })
+}
 
-    let meta_json = serde_json::to_string_pretty(&preimage_meta)?;
+/// Atomically write `{path_hash}.meta.json`.
+pub fn write_preimage_metadata(
+    preimage_dir: &Path,
+    path_hash: &str,
+    preimage_meta: &PreimageMetadata,
+) -> codeagent_common::Result<()> {
+    let meta_path = preimage_dir.join(format!("{path_hash}.meta.json"));
+    let meta_tmp = preimage_dir.join(format!("{path_hash}.meta.json.tmp"));
+    let meta_json = serde_json::to_string_pretty(preimage_meta)?;
     fs::write(&meta_tmp, meta_json)?;
     fs::rename(&meta_tmp, &meta_path)?;
+    Ok(())
+}
 
-    let mut data_bytes_written: u64 = 0;
-    if file_type == PreimageFileType::Regular {
-        let contents = fs::read(file_path)?;
-        let compressed = zstd::encode_all(contents.as_slice(), 3)
-            .map_err(|e| CodeAgentError::Preimage {
-                path: file_path.to_path_buf(),
-                message: format!("zstd compression failed: {e}"),
-            })?;
-        data_bytes_written = compressed.len() as u64;
-        fs::write(&data_tmp, &compressed)?;
-        fs::rename(&data_tmp, &data_path)?;
-    }
-
-    Ok((preimage_meta, data_bytes_written))
+fn compress(file_path: &Path, contents: &[u8]) -> codeagent_common::Result<Vec<u8>> {
+    zstd::encode_all(contents, 3).map_err(|e| CodeAgentError::Preimage {
+        path: file_path.to_path_buf(),
+        message: format!("zstd compression failed: {e}"),
+    })
 }
 
 /// Capture a "not existed" preimage marker for newly created paths.
 pub fn capture_creation_marker(
     file_path: &Path,
-    working_root: &Path,
+    relative: &Path,
     preimage_dir: &Path,
 ) -> codeagent_common::Result<PreimageMetadata> {
-    let relative = file_path.strip_prefix(working_root).map_err(|_| {
-        CodeAgentError::Preimage {
-            path: file_path.to_path_buf(),
-            message: "path is not under working root".to_string(),
-        }
-    })?;
 
     let hash = path_hash(relative);
     let meta_path = preimage_dir.join(format!("{hash}.meta.json"));
@@ -152,9 +497,16 @@ pub fn capture_creation_marker(
         file_type,
         mode: 0,
         mtime_ns: 0,
+        atime_ns: None,
+        uid: None,
+        gid: None,
         size: 0,
         symlink_target: None,
         xattrs: BTreeMap::new(),
+        range_patches: None,
+        hard_link: None,
+        content_hash: None,
+        metadata_only: false,
     };
 
     let meta_json = serde_json::to_string_pretty(&preimage_meta)?;
@@ -165,6 +517,35 @@ pub fn capture_creation_marker(
     Ok(preimage_meta)
 }
 
+/// Capture a metadata-only preimage for another name of an inode already
+/// captured in this step as `primary_relative`. No contents are stored:
+/// rollback re-links this path to the primary instead.
+pub fn capture_hard_link_alias(
+    file_path: &Path,
+    relative: &Path,
+    preimage_dir: &Path,
+    primary_relative: &str,
+) -> codeagent_common::Result<PreimageMetadata> {
+
+    let mut preimage_meta = existing_path_metadata(file_path, relative)?;
+    // The link count may have dropped to 1 if other names were already
+    // removed in this step, so record the identity unconditionally.
+    preimage_meta.hard_link =
+        read_inode_info(&fs::symlink_metadata(file_path)?).map(|info| HardLinkInfo {
+            same_inode_as: Some(primary_relative.to_string()),
+            ..info
+        });
+    write_preimage_metadata(preimage_dir, &path_hash(relative), &preimage_meta)?;
+
+    Ok(preimage_meta)
+}
+
+/// Return the `(dev, inode)` pair of a regular file. Always `None` on
+/// non-Unix platforms.
+pub fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    read_inode_info(metadata).map(|info| info.file_id())
+}
+
 /// Read a PreimageMetadata from a `{path_hash}.meta.json` file.
 pub fn read_preimage_metadata(
     preimage_dir: &Path,
@@ -176,6 +557,54 @@ pub fn read_preimage_metadata(
     Ok(meta)
 }
 
+/// Largest file whose post-step contents are kept for merge-mode rollback.
+pub const MAX_POSTIMAGE_BYTES: u64 = 1024 * 1024;
+
+/// Store the contents a step left in a text file as `{path_hash}.post.dat`
+/// (zstd-compressed), so merge-mode rollback can tell the step's own change
+/// apart from later edits. Binary files, files larger than
+/// [`MAX_POSTIMAGE_BYTES`] and anything but a regular file are skipped.
+///
+/// Returns the number of data bytes written.
+pub fn capture_postimage(
+    file_path: &Path,
+    preimage_dir: &Path,
+    path_hash: &str,
+) -> codeagent_common::Result<u64> {
+    let metadata = fs::symlink_metadata(file_path)?;
+    if !metadata.is_file() || metadata.len() > MAX_POSTIMAGE_BYTES {
+        return Ok(0);
+    }
+    let contents = fs::read(file_path)?;
+    if !crate::merge::is_text(&contents) {
+        return Ok(0);
+    }
+    let compressed = compress(file_path, &contents)?;
+    let data_path = preimage_dir.join(format!("{path_hash}.post.dat"));
+    let data_tmp = preimage_dir.join(format!("{path_hash}.post.dat.tmp"));
+    fs::write(&data_tmp, &compressed)?;
+    fs::rename(&data_tmp, &data_path)?;
+    Ok(compressed.len() as u64)
+}
+
+/// Read the contents stored by [`capture_postimage`], if any.
+pub fn read_postimage(
+    preimage_dir: &Path,
+    path_hash: &str,
+) -> codeagent_common::Result<Option<Vec<u8>>> {
+    let compressed = match fs::read(preimage_dir.join(format!("{path_hash}.post.dat"))) {
+        Ok(compressed) => compressed,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    let contents = zstd::decode_all(compressed.as_slice()).map_err(|e| {
+        CodeAgentError::Decompression {
+            message: format!("failed to decompress postimage for {path_hash}: {e}"),
+        }
+    })?;
+    Ok(Some(contents))
+}
+
 #[cfg(unix)]
 fn read_mode(metadata: &fs::Metadata) -> u32 {
     use std::os::unix::fs::MetadataExt;
@@ -191,9 +620,41 @@ fn read_mode(metadata: &fs::Metadata) -> u32 {
     }
 }
 
+/// Inode identity of a regular file with more than one hard link.
+fn read_hard_link(metadata: &fs::Metadata) -> Option<HardLinkInfo> {
+    read_inode_info(metadata).filter(|info| info.nlink > 1)
+}
+
+#[cfg(unix)]
+fn read_inode_info(metadata: &fs::Metadata) -> Option<HardLinkInfo> {
+    use std::os::unix::fs::MetadataExt;
+    if !metadata.is_file() {
+        return None;
+    }
+    Some(HardLinkInfo {
+        dev: metadata.dev(),
+        inode: metadata.ino(),
+        nlink: metadata.nlink(),
+        same_inode_as: None,
+    })
+}
+
+#[cfg(not(unix))]
+fn read_inode_info(_metadata: &fs::Metadata) -> Option<HardLinkInfo> {
+    None
+}
+
 fn read_mtime_ns(metadata: &fs::Metadata) -> i128 {
-    match metadata.modified() {
-        Ok(mtime) => match mtime.duration_since(UNIX_EPOCH) {
+    nanos_since_epoch(metadata.modified())
+}
+
+fn read_atime_ns(metadata: &fs::Metadata) -> i128 {
+    nanos_since_epoch(metadata.accessed())
+}
+
+fn nanos_since_epoch(time: std::io::Result<std::time::SystemTime>) -> i128 {
+    match time {
+        Ok(time) => match time.duration_since(UNIX_EPOCH) {
             Ok(duration) => duration.as_nanos() as i128,
             Err(err) => -(err.duration().as_nanos() as i128),
         },
@@ -201,6 +662,17 @@ fn read_mtime_ns(metadata: &fs::Metadata) -> i128 {
     }
 }
 
+#[cfg(unix)]
+fn read_owner(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn read_owner(_metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
 #[cfg(target_os = "linux")]
 fn read_xattrs(path: &Path) -> BTreeMap<String, Vec<u8>> {
     let mut result = BTreeMap::new();
@@ -262,6 +734,55 @@ mod tests {
         assert!(preimages.join(format!("{hash}.dat")).exists());
     }
 
+    #[test]
+    fn verify_preimage_detects_changed_and_truncated_contents() {
+        let dir = TempDir::new().unwrap();
+        let working = dir.path().join("working");
+        let preimages = dir.path().join("preimages");
+        fs::create_dir_all(&working).unwrap();
+        fs::create_dir_all(&preimages).unwrap();
+        let file_path = working.join("data.txt");
+        fs::write(&file_path, "original contents").unwrap();
+
+        let (meta, _) = capture_preimage(&file_path, &working, &preimages).unwrap();
+        let hash = path_hash(Path::new("data.txt"));
+        assert_eq!(meta.content_hash, Some(PreimageBlobCache::content_hash(b"original contents")));
+        assert_eq!(read_preimage_metadata(&preimages, &hash).unwrap(), meta);
+        verify_preimage(&preimages, &hash, &meta).unwrap();
+
+        let data = preimages.join(format!("{hash}.dat"));
+        fs::write(&data, zstd::encode_all(&b"other contents"[..], 3).unwrap()).unwrap();
+        let error = verify_preimage(&preimages, &hash, &meta).unwrap_err();
+        assert!(error.to_string().contains("checksum"), "{error}");
+
+        let compressed = zstd::encode_all(&b"original contents"[..], 3).unwrap();
+        fs::write(&data, &compressed[..compressed.len() / 2]).unwrap();
+        assert!(matches!(
+            verify_preimage(&preimages, &hash, &meta),
+            Err(CodeAgentError::Decompression { .. })
+        ));
+    }
+
+    #[test]
+    fn postimages_are_kept_for_text_files_only() {
+        let dir = TempDir::new().unwrap();
+        let preimages = dir.path().join("preimages");
+        fs::create_dir_all(&preimages).unwrap();
+        let text = dir.path().join("notes.txt");
+        let binary = dir.path().join("image.bin");
+        fs::write(&text, "after the step\n").unwrap();
+        fs::write(&binary, [0u8, 159, 146, 150]).unwrap();
+
+        assert!(capture_postimage(&text, &preimages, "text").unwrap() > 0);
+        assert_eq!(capture_postimage(&binary, &preimages, "binary").unwrap(), 0);
+
+        assert_eq!(
+            read_postimage(&preimages, "text").unwrap().as_deref(),
+            Some(&b"after the step\n"[..]),
+        );
+        assert_eq!(read_postimage(&preimages, "binary").unwrap(), None);
+    }
+
     #[test]
     fn capture_preimage_directory() {
         let dir = TempDir::new().unwrap();
@@ -302,6 +823,57 @@ mod tests {
         assert_eq!(String::from_utf8(decompressed).unwrap(), original_content);
     }
 
+    #[test]
+    fn promote_range_preimage_reconstructs_original() {
+        let dir = TempDir::new().unwrap();
+        let working = dir.path().join("working");
+        let preimages = dir.path().join("preimages");
+        fs::create_dir_all(&working).unwrap();
+        fs::create_dir_all(&preimages).unwrap();
+
+        let file_path = working.join("data.bin");
+        fs::write(&file_path, "0123456789").unwrap();
+
+        let (mut meta, _) =
+            capture_range_preimage(&file_path, Path::new("data.bin"), &preimages, 2, 3).unwrap();
+        fs::write(&file_path, "01xxx56789appended").unwrap();
+        append_range_patch(&file_path, &preimages, &mut meta, 3, 4).unwrap();
+        fs::write(&file_path, "01xyyyy789appended").unwrap();
+
+        promote_range_preimage(&file_path, &preimages, &mut meta).unwrap();
+        assert!(meta.range_patches.is_none());
+
+        let hash = path_hash(Path::new("data.bin"));
+        let compressed = fs::read(preimages.join(format!("{hash}.dat"))).unwrap();
+        let decompressed = zstd::decode_all(compressed.as_slice()).unwrap();
+        assert_eq!(decompressed, b"0123456789");
+        assert!(!preimages.join(format!("{hash}.range.0.dat")).exists());
+    }
+
+    #[test]
+    fn metadata_preimage_stores_no_contents_until_promoted() {
+        let dir = TempDir::new().unwrap();
+        let working = dir.path().join("working");
+        let preimages = dir.path().join("preimages");
+        fs::create_dir_all(&working).unwrap();
+        fs::create_dir_all(&preimages).unwrap();
+        let file_path = working.join("data.txt");
+        fs::write(&file_path, "original").unwrap();
+
+        let mut meta =
+            capture_metadata_preimage(&file_path, Path::new("data.txt"), &preimages).unwrap();
+        let hash = path_hash(Path::new("data.txt"));
+        assert!(meta.metadata_only);
+        assert_eq!(read_preimage_metadata(&preimages, &hash).unwrap(), meta);
+        assert!(!preimages.join(format!("{hash}.dat")).exists());
+        verify_preimage(&preimages, &hash, &meta).unwrap();
+
+        assert!(promote_metadata_preimage(&file_path, &preimages, &mut meta).unwrap() > 0);
+        assert!(!meta.metadata_only);
+        assert_eq!(read_preimage_metadata(&preimages, &hash).unwrap(), meta);
+        verify_preimage(&preimages, &hash, &meta).unwrap();
+    }
+
     #[test]
     fn capture_creation_marker() {
         let dir = TempDir::new().unwrap();
@@ -314,7 +886,8 @@ mod tests {
         fs::write(&file_path, "new").unwrap();
 
         let meta =
-            super::capture_creation_marker(&file_path, &working, &preimages).unwrap();
+            super::capture_creation_marker(&file_path, Path::new("new_file.txt"), &preimages)
+                .unwrap();
 
         assert!(!meta.existed_before);
         assert_eq!(meta.relative_path, "new_file.txt");
@@ -1,38 +1,136 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-use codeagent_common::SymlinkPolicy;
+use codeagent_common::{MergedPath, StepId, SymlinkPolicy};
 
-use crate::manifest::StepManifest;
-use crate::preimage::{PreimageFileType, PreimageMetadata, read_preimage_metadata};
+use crate::boundary::WorkingRootBoundary;
+use crate::dir_rename;
+use crate::manifest::{HardLinkInfo, StepManifest};
+use crate::merge;
+use crate::rollback_journal;
+use crate::preimage::{
+    PreimageFileType, PreimageMetadata, read_postimage, read_preimage_metadata, read_range_patches,
+    verify_preimage,
+};
 
 /// Execute rollback for a single step.
 ///
+/// Directories the step renamed are renamed back first, and the entries,
+/// recorded under the names from before the renames, restored after (see
+/// [`crate::dir_rename`]).
+///
 /// Two-pass algorithm per the spec (testing-plan §1.2):
 /// 1. Delete created paths (deepest-first), recreate dirs (shallowest-first),
 ///    then restore file contents and metadata.
 /// 2. Restore directory metadata (deepest-first) so child operations don't
 ///    clobber parent mtime.
+///
+/// Metadata-only preimages restore the attributes (mode, ownership,
+/// timestamps, xattrs) without rewriting the contents.
+///
+/// Hard-linked files are restored onto a surviving link of the original inode
+/// when one exists, and other names of an inode captured in the same step are
+/// re-linked to it rather than restored as independent copies.
+///
+/// Every preimage to be restored is verified against its recorded checksum
+/// first; a mismatch fails the rollback before it changes anything, and the
+/// journal puts back what it moved aside.
+/// The rollback is all-or-nothing: if it fails midway, the paths it changed
+/// are put back from a journal (see [`crate::rollback_journal`]).
+///
+/// Entries whose parent directory resolves outside the working root are
+/// skipped, as are entries reached through a symlinked directory unless the
+/// policy is `ReadWrite`. Symlinks are only restored under `ReadWrite`, and
+/// only when their target stays inside the working root.
 pub fn rollback_step(
     step_dir: &Path,
     working_root: &Path,
     symlink_policy: SymlinkPolicy,
 ) -> codeagent_common::Result<()> {
+    rollback_step_with(step_dir, working_root, symlink_policy, false, &mut |_, _, _| {})
+        .map(|_| ())
+}
+
+/// Like [`rollback_step`], but a text file that changed since the step
+/// closed is three-way merged: the step's change (postimage → preimage) is
+/// applied to the current contents, keeping the later edits. Files without
+/// a stored postimage, binary files and range preimages are restored as
+/// usual. Merged files keep their current mtime.
+///
+/// Returns the merged files.
+pub fn rollback_step_merging(
+    step_dir: &Path,
+    working_root: &Path,
+    symlink_policy: SymlinkPolicy,
+) -> codeagent_common::Result<Vec<MergedPath>> {
+    rollback_step_with(step_dir, working_root, symlink_policy, true, &mut |_, _, _| {})
+}
+
+/// [`rollback_step`], or [`rollback_step_merging`] with `merge_changed`.
+/// `on_file(done, total, path)` is called before each file is restored.
+pub(crate) fn rollback_step_with(
+    step_dir: &Path,
+    working_root: &Path,
+    symlink_policy: SymlinkPolicy,
+    merge_changed: bool,
+    on_file: &mut dyn FnMut(usize, usize, &str),
+) -> codeagent_common::Result<Vec<MergedPath>> {
+    rollback_journal::revert_interrupted(step_dir, working_root)?;
+    rollback_journal::record(step_dir, working_root, symlink_policy)?;
+    match restore_step(step_dir, working_root, symlink_policy, merge_changed, on_file) {
+        Ok(merged) => {
+            rollback_journal::discard(step_dir)?;
+            Ok(merged)
+        }
+        Err(error) => {
+            // If reverting fails too, the journal stays for crash recovery
+            // or the next rollback of this step.
+            let _ = rollback_journal::revert_interrupted(step_dir, working_root);
+            Err(error)
+        }
+    }
+}
+
+/// The rollback itself, without a journal.
+pub(crate) fn restore_step(
+    step_dir: &Path,
+    working_root: &Path,
+    symlink_policy: SymlinkPolicy,
+    merge_changed: bool,
+    on_file: &mut dyn FnMut(usize, usize, &str),
+) -> codeagent_common::Result<Vec<MergedPath>> {
     let manifest = StepManifest::read_from(step_dir)?;
     let preimage_dir = step_dir.join("preimages");
+    let boundary = WorkingRootBoundary::new(working_root);
+    let writable = |full_path: &Path| {
+        boundary.contains_parent_of(full_path)
+            && (symlink_policy == SymlinkPolicy::ReadWrite
+                || !boundary.parent_traverses_symlink(full_path))
+    };
 
     // Classify entries
     let mut dirs_to_restore: Vec<(String, String)> = Vec::new(); // (rel_path, path_hash)
     let mut files_to_restore: Vec<(String, String)> = Vec::new();
     let mut paths_to_delete: Vec<(String, String)> = Vec::new();
+    // Hard-linked files holding a preimage, and other names re-linked to them.
+    let mut hard_links: HashMap<String, HardLinkInfo> = HashMap::new();
+    let mut link_aliases: Vec<(String, String)> = Vec::new(); // (rel_path, primary)
 
     for (rel_path, entry) in &manifest.entries {
         if entry.existed_before {
-            match entry.file_type.as_str() {
-                "directory" => {
+            match (entry.file_type.as_str(), &entry.hard_link) {
+                ("directory", _) => {
                     dirs_to_restore.push((rel_path.clone(), entry.path_hash.clone()));
                 }
-                _ => {
+                (_, Some(HardLinkInfo { same_inode_as: Some(primary), .. })) => {
+                    link_aliases.push((rel_path.clone(), primary.clone()));
+                }
+                (_, hard_link) => {
+                    if let Some(info) = hard_link {
+                        hard_links.insert(rel_path.clone(), info.clone());
+                    }
                     files_to_restore.push((rel_path.clone(), entry.path_hash.clone()));
                 }
             }
@@ -41,11 +139,24 @@ pub fn rollback_step(
         }
     }
 
+    // --- Pass 0: Verify the preimages before changing anything ---
+    for (rel_path, hash) in &files_to_restore {
+        if writable(&working_root.join(rel_path)) {
+            verify_preimage(&preimage_dir, hash, &read_preimage_metadata(&preimage_dir, hash)?)?;
+        }
+    }
+
+    // --- Pass 0b: Rename directories back (newest first) ---
+    dir_rename::undo_renames(&manifest.renames, working_root, &writable)?;
+
     // --- Pass 1a: Delete paths that were created during this step (deepest-first) ---
-    paths_to_delete.sort_by(|a, b| path_depth(&b.0).cmp(&path_depth(&a.0)));
+    paths_to_delete.sort_by_key(|b| std::cmp::Reverse(path_depth(&b.0)));
 
     for (rel_path, _) in &paths_to_delete {
         let full_path = working_root.join(rel_path);
+        if !writable(&full_path) {
+            continue;
+        }
         if full_path.symlink_metadata().is_ok() {
             if full_path.is_dir() {
                 let _ = fs::remove_dir_all(&full_path);
@@ -56,19 +167,33 @@ pub fn rollback_step(
     }
 
     // --- Pass 1b: Recreate directories (shallowest-first) ---
-    dirs_to_restore.sort_by(|a, b| path_depth(&a.0).cmp(&path_depth(&b.0)));
+    dirs_to_restore.sort_by_key(|a| path_depth(&a.0));
 
     for (rel_path, _) in &dirs_to_restore {
         let full_path = working_root.join(rel_path);
-        if !full_path.exists() {
+        if writable(&full_path) && !full_path.exists() {
             fs::create_dir_all(&full_path)?;
         }
     }
 
+    // Hard-linked files that no longer point at their original inode are
+    // re-attached to a surviving link of it, found with a single tree walk.
+    let detached: HashSet<(u64, u64)> = hard_links
+        .iter()
+        .filter(|(rel_path, info)| file_id(&working_root.join(rel_path)) != Some(info.file_id()))
+        .map(|(_, info)| info.file_id())
+        .collect();
+    let survivors = find_paths_by_file_id(working_root, &detached);
+    let mut merged = Vec::new();
+
     // --- Pass 1c: Restore file contents + metadata ---
-    for (rel_path, hash) in &files_to_restore {
+    for (done, (rel_path, hash)) in files_to_restore.iter().enumerate() {
+        on_file(done, files_to_restore.len(), rel_path);
         let meta = read_preimage_metadata(&preimage_dir, hash)?;
         let full_path = working_root.join(rel_path);
+        if !writable(&full_path) {
+            continue;
+        }
 
         if let Some(parent) = full_path.parent() {
             if !parent.exists() {
@@ -77,20 +202,36 @@ pub fn rollback_step(
         }
 
         if meta.file_type == PreimageFileType::Symlink
-            && symlink_policy != SymlinkPolicy::ReadWrite
+            && (symlink_policy != SymlinkPolicy::ReadWrite
+                || meta.symlink_target.as_deref().is_some_and(|target| {
+                    !boundary.contains_symlink_target(&full_path, Path::new(target))
+                }))
         {
             continue;
         }
 
         match meta.file_type {
+            PreimageFileType::Regular if meta.metadata_only => {}
             PreimageFileType::Regular => {
-                let compressed = fs::read(preimage_dir.join(format!("{hash}.dat")))?;
-                let contents = zstd::decode_all(compressed.as_slice()).map_err(|e| {
-                    codeagent_common::CodeAgentError::Decompression {
-                        message: format!("failed to decompress preimage for {rel_path}: {e}"),
+                if merge_changed {
+                    let conflicts =
+                        merge_contents(&full_path, &preimage_dir, hash, &meta, manifest.step_id)?;
+                    if let Some(conflicts) = conflicts {
+                        restore_attributes(&full_path, &meta)?;
+                        merged.push(MergedPath {
+                            path: PathBuf::from(rel_path),
+                            conflicts,
+                        });
+                        continue;
                     }
-                })?;
-                fs::write(&full_path, contents)?;
+                }
+                let survivor = hard_links
+                    .get(rel_path)
+                    .and_then(|info| survivors.get(&info.file_id()));
+                if let Some(survivor) = survivor {
+                    replace_with_hard_link(survivor, &full_path)?;
+                }
+                restore_contents(&full_path, &preimage_dir, hash, &meta, rel_path)?;
             }
             PreimageFileType::Symlink => {
                 // Remove existing file/symlink at this path if present
@@ -119,17 +260,184 @@ pub fn rollback_step(
         restore_metadata(&full_path, &meta)?;
     }
 
+    // --- Pass 1d: Re-link other names of hard-linked files ---
+    // The inode's contents and metadata were restored through the primary.
+    for (rel_path, primary) in &link_aliases {
+        let full_path = working_root.join(rel_path);
+        let primary_path = working_root.join(primary);
+        if !writable(&full_path) {
+            continue;
+        }
+        if file_id(&full_path).is_some() && file_id(&full_path) == file_id(&primary_path) {
+            continue;
+        }
+        if let Some(parent) = full_path.parent() {
+            if !parent.exists() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        replace_with_hard_link(&primary_path, &full_path)?;
+    }
+
     // --- Pass 2: Restore directory metadata (deepest-first) ---
-    dirs_to_restore.sort_by(|a, b| path_depth(&b.0).cmp(&path_depth(&a.0)));
+    dirs_to_restore.sort_by_key(|b| std::cmp::Reverse(path_depth(&b.0)));
 
     for (_, hash) in &dirs_to_restore {
         let meta = read_preimage_metadata(&preimage_dir, hash)?;
         let full_path = working_root.join(&meta.relative_path);
-        if full_path.exists() {
+        if writable(&full_path) && full_path.exists() {
             restore_metadata(&full_path, &meta)?;
         }
     }
 
+    Ok(merged)
+}
+
+/// Merge the step's change out of a file edited since the step closed and
+/// return the number of conflicts, or `None` if the file should be restored
+/// from its preimage instead (unchanged since the step, or not mergeable).
+fn merge_contents(
+    path: &Path,
+    preimage_dir: &Path,
+    hash: &str,
+    meta: &PreimageMetadata,
+    step_id: StepId,
+) -> codeagent_common::Result<Option<usize>> {
+    if meta.range_patches.is_some()
+        || meta.metadata_only
+        || !path.symlink_metadata().is_ok_and(|m| m.is_file())
+    {
+        return Ok(None);
+    }
+    let Some(step_result) = read_postimage(preimage_dir, hash)? else {
+        return Ok(None);
+    };
+    let current = fs::read(path)?;
+    if current == step_result || !merge::is_text(&current) {
+        return Ok(None);
+    }
+    let preimage = read_full_preimage(preimage_dir, hash, &meta.relative_path)?;
+    if !merge::is_text(&preimage) {
+        return Ok(None);
+    }
+    let (Ok(base), Ok(ours), Ok(theirs)) = (
+        std::str::from_utf8(&step_result),
+        std::str::from_utf8(&preimage),
+        std::str::from_utf8(&current),
+    ) else {
+        return Ok(None);
+    };
+    let outcome = merge::merge(base, ours, theirs, &format!("before step {step_id}"), "current");
+    fs::write(path, outcome.text)?;
+    Ok(Some(outcome.conflicts))
+}
+
+/// Restore the contents of a regular file from its full or range preimage.
+/// Existing files are rewritten in place, so other hard links to the same
+/// inode see the restored contents too.
+fn restore_contents(
+    path: &Path,
+    preimage_dir: &Path,
+    hash: &str,
+    meta: &PreimageMetadata,
+    rel_path: &str,
+) -> codeagent_common::Result<()> {
+    if meta.range_patches.is_some() {
+        return restore_range_patches(path, preimage_dir, hash, meta);
+    }
+    fs::write(path, read_full_preimage(preimage_dir, hash, rel_path)?)?;
+    Ok(())
+}
+
+pub(crate) fn read_full_preimage(
+    preimage_dir: &Path,
+    hash: &str,
+    rel_path: &str,
+) -> codeagent_common::Result<Vec<u8>> {
+    let compressed = fs::read(preimage_dir.join(format!("{hash}.dat")))?;
+    zstd::decode_all(compressed.as_slice()).map_err(|e| {
+        codeagent_common::CodeAgentError::Decompression {
+            message: format!("failed to decompress preimage for {rel_path}: {e}"),
+        }
+    })
+}
+
+/// Replace whatever is at `link_path` with a hard link to `existing`.
+fn replace_with_hard_link(existing: &Path, link_path: &Path) -> codeagent_common::Result<()> {
+    if link_path.symlink_metadata().is_ok() {
+        let _ = fs::remove_file(link_path);
+    }
+    fs::hard_link(existing, link_path)?;
+    Ok(())
+}
+
+/// Walk `root` once and return one path for each wanted `(dev, inode)` pair.
+/// Symlinks are not followed.
+fn find_paths_by_file_id(
+    root: &Path,
+    wanted: &HashSet<(u64, u64)>,
+) -> HashMap<(u64, u64), PathBuf> {
+    let mut found = HashMap::new();
+    if wanted.is_empty() {
+        return found;
+    }
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = path.symlink_metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(path);
+            } else if metadata.is_file() {
+                if let Some(id) = file_id(&path).filter(|id| wanted.contains(id)) {
+                    found.entry(id).or_insert(path);
+                    if found.len() == wanted.len() {
+                        return found;
+                    }
+                }
+            }
+        }
+    }
+    found
+}
+
+/// `(dev, inode)` of the file at `path`, without following symlinks.
+#[cfg(unix)]
+pub(crate) fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    path.symlink_metadata()
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Write the original bytes of a range preimage back in place (newest patch
+/// first, so the earliest capture of an overlapping range wins) and truncate
+/// the file to its original size.
+fn restore_range_patches(
+    path: &Path,
+    preimage_dir: &Path,
+    hash: &str,
+    meta: &PreimageMetadata,
+) -> codeagent_common::Result<()> {
+    let patches = meta.range_patches.as_deref().unwrap_or_default();
+    let originals = read_range_patches(preimage_dir, hash, patches)?;
+
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    for (patch, original) in originals.iter().rev() {
+        file.seek(SeekFrom::Start(patch.offset))?;
+        file.write_all(original)?;
+    }
+    file.set_len(meta.size)?;
     Ok(())
 }
 
@@ -137,6 +445,23 @@ fn restore_metadata(
     path: &Path,
     meta: &PreimageMetadata,
 ) -> codeagent_common::Result<()> {
+    restore_attributes(path, meta)?;
+
+    // Restore timestamps last so xattr changes don't clobber them
+    restore_times(path, meta)?;
+
+    Ok(())
+}
+
+/// Restore ownership, mode and xattrs, leaving the timestamps alone.
+fn restore_attributes(
+    path: &Path,
+    meta: &PreimageMetadata,
+) -> codeagent_common::Result<()> {
+    // Restore ownership before the mode, since chown clears setuid bits
+    #[cfg(unix)]
+    restore_owner(path, meta)?;
+
     // Restore mode (Unix only)
     #[cfg(unix)]
     {
@@ -160,20 +485,45 @@ fn restore_metadata(
         }
     }
 
-    // Restore mtime last so xattr changes don't clobber it
-    restore_mtime(path, meta.mtime_ns)?;
-
     Ok(())
 }
 
-fn restore_mtime(path: &Path, mtime_ns: i128) -> codeagent_common::Result<()> {
-    let secs = (mtime_ns / 1_000_000_000) as i64;
-    let nanos = (mtime_ns % 1_000_000_000) as u32;
-    let ft = filetime::FileTime::from_unix_time(secs, nanos);
-    filetime::set_file_mtime(path, ft)?;
+/// Give `path` back its recorded owner and group if either changed.
+/// Without the privilege to do so (chown to another user), the current
+/// ownership is kept.
+#[cfg(unix)]
+fn restore_owner(path: &Path, meta: &PreimageMetadata) -> codeagent_common::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let Some(current) = path.symlink_metadata().ok() else {
+        return Ok(());
+    };
+    let uid = meta.uid.filter(|&uid| uid != current.uid());
+    let gid = meta.gid.filter(|&gid| gid != current.gid());
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    match std::os::unix::fs::lchown(path, uid, gid) {
+        Err(error) if error.kind() == std::io::ErrorKind::PermissionDenied => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Restore the mtime, and the atime when the preimage recorded one.
+fn restore_times(path: &Path, meta: &PreimageMetadata) -> codeagent_common::Result<()> {
+    let mtime = file_time(meta.mtime_ns);
+    match meta.atime_ns {
+        Some(atime_ns) => filetime::set_file_times(path, file_time(atime_ns), mtime)?,
+        None => filetime::set_file_mtime(path, mtime)?,
+    }
     Ok(())
 }
 
+fn file_time(ns: i128) -> filetime::FileTime {
+    let secs = (ns / 1_000_000_000) as i64;
+    let nanos = (ns % 1_000_000_000) as u32;
+    filetime::FileTime::from_unix_time(secs, nanos)
+}
+
 fn path_depth(path: &str) -> usize {
     path.chars()
         .filter(|&c| c == '/' || c == '\\')
@@ -232,7 +582,7 @@ mod tests {
         fs::write(&file, "new content").unwrap();
 
         let hash = crate::preimage::path_hash(Path::new("new.txt"));
-        capture_creation_marker(&file, &working, &preimage_dir).unwrap();
+        capture_creation_marker(&file, Path::new("new.txt"), &preimage_dir).unwrap();
 
         let mut manifest = StepManifest::new(1);
         manifest.add_entry("new.txt", &hash, false, "regular");
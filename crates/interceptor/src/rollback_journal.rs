@@ -0,0 +1,300 @@
+//! Journal that makes rolling back a step all-or-nothing.
+//!
+//! Before a step is rolled back, [`record`] captures the current state of
+//! every path the rollback may change into `{step_dir}/rollback/`, laid out
+//! like a step itself (`manifest.json` plus `preimages/`). If the rollback
+//! fails midway, the journal is rolled back in turn, putting the working
+//! tree back as it was before the rollback began; once the rollback
+//! completes, the journal is removed. A journal left behind by a crash is
+//! reverted by [`revert_interrupted`], which crash recovery runs for every
+//! step and which also runs before the same step is rolled back again.
+//!
+//! Only what the rollback overwrites in place is copied. What it removes
+//! whole, the files and trees the step created and whatever sits at the
+//! names undoing its renames clears, is renamed into `moved/` instead and
+//! renamed back on revert, so a large build tree costs no copy. The moves
+//! are listed in `moves.json` before any is made. A path that cannot be
+//! renamed there (the undo directory on another filesystem) is copied, as
+//! is one holding a directory a rename of the step moved, which the
+//! rollback still has to find in place.
+//!
+//! Directory renames the rollback undoes are journaled as the renames that
+//! redo them, and captured paths under the renamed directories by their
+//! names before the undo, which is where reverting puts them back.
+//!
+//! The journal manifest is written last: a journal without one was cut short
+//! while recording, before the rollback began, and is discarded once the
+//! moves made so far are put back.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use codeagent_common::SymlinkPolicy;
+
+use crate::boundary::WorkingRootBoundary;
+use crate::dir_rename;
+use crate::manifest::StepManifest;
+use crate::preimage::{capture_creation_marker, capture_preimage, path_hash, relative_to_root};
+use crate::rollback;
+
+/// Directory of the journal inside the step directory being rolled back.
+pub const JOURNAL_DIR_NAME: &str = "rollback";
+
+/// The paths renamed into the journal, relative to the working root, the
+/// one at index `i` as `moved/{i}`.
+const MOVES_FILE: &str = "moves.json";
+const MOVED_DIR: &str = "moved";
+
+pub fn journal_dir(step_dir: &Path) -> PathBuf {
+    step_dir.join(JOURNAL_DIR_NAME)
+}
+
+/// Capture the current state of the paths that rolling back `step_dir`
+/// may change: its entries, the missing parent directories restoring them
+/// recreates, everything under the directories it deletes, and what undoing
+/// its renames removes from the vacated names. Replaces a
+/// journal already there, so revert that first.
+pub fn record(
+    step_dir: &Path,
+    working_root: &Path,
+    symlink_policy: SymlinkPolicy,
+) -> codeagent_common::Result<()> {
+    let manifest = StepManifest::read_from(step_dir)?;
+    let journal = journal_dir(step_dir);
+    if journal.exists() {
+        fs::remove_dir_all(&journal)?;
+    }
+    let preimage_dir = journal.join("preimages");
+    fs::create_dir_all(&preimage_dir)?;
+
+    let boundary = WorkingRootBoundary::new(working_root);
+    let skipped = |full_path: &Path| {
+        !boundary.contains_parent_of(full_path)
+            || (symlink_policy != SymlinkPolicy::ReadWrite
+                && boundary.parent_traverses_symlink(full_path))
+    };
+    let mut paths = BTreeSet::new();
+    // What the rollback removes whole.
+    let mut doomed = BTreeSet::new();
+    for (rel_path, entry) in &manifest.entries {
+        let full_path =
+            working_root.join(dir_rename::current_path(&manifest.renames, rel_path));
+        if skipped(&full_path) {
+            continue;
+        }
+        if !entry.existed_before && full_path.symlink_metadata().is_ok() {
+            doomed.insert(full_path);
+            continue;
+        }
+        for ancestor in full_path.ancestors().skip(1) {
+            if ancestor == working_root || ancestor.symlink_metadata().is_ok() {
+                break;
+            }
+            paths.insert(ancestor.to_path_buf());
+        }
+        paths.insert(full_path);
+    }
+    for vacated in dir_rename::vacated_paths(&manifest.renames) {
+        let full_path = working_root.join(vacated);
+        if skipped(&full_path) || full_path.symlink_metadata().is_err() {
+            continue;
+        }
+        doomed.insert(full_path);
+    }
+
+    // A doomed path under another moves with it.
+    let doomed: Vec<PathBuf> = doomed
+        .iter()
+        .filter(|path| !path.ancestors().skip(1).any(|ancestor| doomed.contains(ancestor)))
+        .cloned()
+        .collect();
+    let renamed = renamed_dirs(&manifest, working_root);
+    let mut moves = Vec::new();
+    for path in doomed {
+        if renamed.iter().any(|dir| dir.starts_with(&path)) {
+            collect_tree(&path, &mut paths);
+            paths.insert(path);
+        } else {
+            moves.push(path);
+        }
+    }
+    paths.retain(|path| !moves.iter().any(|moved| path.starts_with(moved)));
+
+    let relative_moves = moves
+        .iter()
+        .map(|path| relative_to_root(path, working_root))
+        .collect::<codeagent_common::Result<Vec<_>>>()?;
+    fs::write(journal.join(MOVES_FILE), serde_json::to_vec(&relative_moves)?)?;
+    let moved_dir = journal.join(MOVED_DIR);
+    fs::create_dir_all(&moved_dir)?;
+    for (index, path) in moves.iter().enumerate() {
+        if fs::rename(path, moved_dir.join(index.to_string())).is_err() {
+            collect_tree(path, &mut paths);
+            paths.insert(path.clone());
+        }
+    }
+
+    let mut journal_manifest = StepManifest::new(manifest.step_id);
+    journal_manifest.renames = dir_rename::redo_renames(&manifest.renames);
+    for path in &paths {
+        let meta = if path.symlink_metadata().is_ok() {
+            capture_preimage(path, working_root, &preimage_dir)?.0
+        } else {
+            let relative = relative_to_root(path, working_root)?;
+            capture_creation_marker(path, relative, &preimage_dir)?
+        };
+        journal_manifest.add_entry(
+            &meta.relative_path,
+            &path_hash(Path::new(&meta.relative_path)),
+            meta.existed_before,
+            meta.file_type.as_str(),
+        );
+    }
+    journal_manifest.write_to(&journal)
+}
+
+/// Where the directories the step renamed are before its rollback, by
+/// either name, for moves to leave them in place.
+fn renamed_dirs(manifest: &StepManifest, working_root: &Path) -> Vec<PathBuf> {
+    let renames = &manifest.renames;
+    renames
+        .iter()
+        .enumerate()
+        .flat_map(|(index, rename)| {
+            [
+                working_root.join(&rename.to),
+                working_root.join(dir_rename::current_path(&renames[index + 1..], &rename.to)),
+            ]
+        })
+        .collect()
+}
+
+/// Remove the journal of a rollback that completed.
+pub fn discard(step_dir: &Path) -> codeagent_common::Result<()> {
+    match fs::remove_dir_all(journal_dir(step_dir)) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Put the working tree back as it was before an unfinished rollback of
+/// `step_dir`, then remove its journal. Returns the number of paths
+/// reverted, or `None` if there was no journal.
+pub fn revert_interrupted(
+    step_dir: &Path,
+    working_root: &Path,
+) -> codeagent_common::Result<Option<usize>> {
+    let journal = journal_dir(step_dir);
+    if !journal.exists() {
+        return Ok(None);
+    }
+    let mut reverted = 0;
+    if journal.join("manifest.json").exists() {
+        reverted = StepManifest::read_from(&journal)?.entries.len();
+        // Symlinks the rollback removed are restored whatever the policy.
+        rollback::restore_step(
+            &journal,
+            working_root,
+            SymlinkPolicy::ReadWrite,
+            false,
+            &mut |_, _, _| {},
+        )?;
+    }
+    reverted += move_back(&journal, working_root)?;
+    fs::remove_dir_all(&journal)?;
+    Ok(Some(reverted))
+}
+
+/// Rename what [`record`] moved into `journal` back to where it was,
+/// replacing whatever the rollback left there. Returns how many it moved.
+fn move_back(journal: &Path, working_root: &Path) -> codeagent_common::Result<usize> {
+    let moves: Vec<PathBuf> = match fs::read(journal.join(MOVES_FILE)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error.into()),
+    };
+    let mut moved_back = 0;
+    for (index, relative) in moves.iter().enumerate() {
+        let moved = journal.join(MOVED_DIR).join(index.to_string());
+        if moved.symlink_metadata().is_err() {
+            continue;
+        }
+        let path = working_root.join(relative);
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&path)?,
+            Ok(_) => fs::remove_file(&path)?,
+            Err(_) => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+        }
+        fs::rename(&moved, &path)?;
+        moved_back += 1;
+    }
+    Ok(moved_back)
+}
+
+/// Add everything under `dir` to `paths`, without following symlinks.
+fn collect_tree(dir: &Path, paths: &mut BTreeSet<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            collect_tree(&path, paths);
+        }
+        paths.insert(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn reverting_a_journal_restores_the_tree_before_the_rollback() {
+        let dir = TempDir::new().unwrap();
+        let working = dir.path().join("working");
+        let step_dir = dir.path().join("step");
+        fs::create_dir_all(working.join("created/nested")).unwrap();
+        fs::create_dir_all(&step_dir).unwrap();
+        fs::write(working.join("kept.txt"), "after the step").unwrap();
+        fs::write(working.join("created/nested/file.txt"), "made by the step").unwrap();
+
+        let mut manifest = StepManifest::new(3);
+        manifest.add_entry("kept.txt", &path_hash(Path::new("kept.txt")), true, "regular");
+        manifest.add_entry("created", &path_hash(Path::new("created")), false, "directory");
+        manifest.add_entry("gone/file.txt", &path_hash(Path::new("gone/file.txt")), true, "regular");
+        manifest.write_to(&step_dir).unwrap();
+
+        record(&step_dir, &working, SymlinkPolicy::Ignore).unwrap();
+        // The tree the step created is moved aside, not copied.
+        assert!(!working.join("created").exists());
+        let moved = journal_dir(&step_dir).join(MOVED_DIR).join("0");
+        assert_eq!(
+            fs::read_to_string(moved.join("nested/file.txt")).unwrap(),
+            "made by the step"
+        );
+        let journal = StepManifest::read_from(&journal_dir(&step_dir)).unwrap();
+        assert!(!journal.entries.keys().any(|path| path.starts_with("created")));
+
+        // What a rollback stopped halfway might leave.
+        fs::write(working.join("kept.txt"), "before the step").unwrap();
+        fs::create_dir_all(working.join("gone")).unwrap();
+        fs::write(working.join("gone/file.txt"), "restored").unwrap();
+
+        assert_eq!(revert_interrupted(&step_dir, &working).unwrap(), Some(4));
+        assert_eq!(fs::read_to_string(working.join("kept.txt")).unwrap(), "after the step");
+        assert_eq!(
+            fs::read_to_string(working.join("created/nested/file.txt")).unwrap(),
+            "made by the step"
+        );
+        assert!(!working.join("gone").exists());
+        assert!(!journal_dir(&step_dir).exists());
+        assert_eq!(revert_interrupted(&step_dir, &working).unwrap(), None);
+    }
+}
@@ -1,7 +1,10 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc;
+use std::time::Duration;
 
 use codeagent_common::{
-    SafeguardConfig, SafeguardDecision, SafeguardEvent, SafeguardId, SafeguardKind, StepId,
+    Expectation, ExpectedOperation, PathOperation, SafeguardConfig, SafeguardDecision,
+    SafeguardEvent, SafeguardId, SafeguardKind, StepBudget, StepId,
 };
 
 /// Handler called when a safeguard threshold is crossed.
@@ -12,62 +15,305 @@ pub trait SafeguardHandler: Send + Sync {
     fn on_safeguard_triggered(&self, event: SafeguardEvent) -> SafeguardDecision;
 }
 
+/// A triggered safeguard handed out by a [`ChannelSafeguardHandler`],
+/// holding up the operation until [`decide`](Self::decide) is called.
+pub struct SafeguardRequest {
+    pub event: SafeguardEvent,
+    responder: mpsc::SyncSender<SafeguardDecision>,
+}
+
+impl SafeguardRequest {
+    /// Deliver `decision` to the waiting operation. Returns false if it
+    /// stopped waiting (timed out) first.
+    pub fn decide(self, decision: SafeguardDecision) -> bool {
+        self.responder.send(decision).is_ok()
+    }
+}
+
+/// A safeguard handler for frontends that decide elsewhere: a UI thread or
+/// an async task.
+///
+/// Each triggered safeguard is passed to `deliver` as a [`SafeguardRequest`]
+/// and the filesystem thread waits for its decision. Dropping the request
+/// undecided, or leaving it past `timeout`, denies the safeguard. `deliver`
+/// runs on the filesystem thread and should only hand the request on, e.g.
+/// into a channel.
+pub struct ChannelSafeguardHandler {
+    deliver: Box<dyn Fn(SafeguardRequest) + Send + Sync>,
+    timeout: Duration,
+}
+
+impl ChannelSafeguardHandler {
+    pub fn new(
+        timeout: Duration,
+        deliver: impl Fn(SafeguardRequest) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            deliver: Box::new(deliver),
+            timeout,
+        }
+    }
+
+    /// A handler delivering requests into the returned receiver.
+    pub fn channel(timeout: Duration) -> (Self, mpsc::Receiver<SafeguardRequest>) {
+        let (sender, receiver) = mpsc::channel();
+        let handler = Self::new(timeout, move |request| {
+            let _ = sender.send(request);
+        });
+        (handler, receiver)
+    }
+}
+
+impl SafeguardHandler for ChannelSafeguardHandler {
+    fn on_safeguard_triggered(&self, event: SafeguardEvent) -> SafeguardDecision {
+        let (responder, decision) = mpsc::sync_channel(1);
+        (self.deliver)(SafeguardRequest { event, responder });
+        decision
+            .recv_timeout(self.timeout)
+            .unwrap_or(SafeguardDecision::Deny)
+    }
+}
+
+/// Check that every one of `patterns` is a valid glob, naming the first that
+/// is not.
+pub fn validate_protected_paths(patterns: &[String]) -> Result<(), String> {
+    for pattern in patterns {
+        if let Err(error) = glob::Pattern::new(pattern) {
+            return Err(format!("invalid protected path pattern '{pattern}': {error}"));
+        }
+    }
+    Ok(())
+}
+
+/// Compiled `protected_paths` patterns.
+pub struct ProtectedPathMatcher {
+    patterns: Vec<glob::Pattern>,
+}
+
+impl ProtectedPathMatcher {
+    /// Compile `patterns`. Invalid glob patterns are skipped; requests
+    /// reject them up front with [`validate_protected_paths`].
+    pub fn new(patterns: &[String]) -> Self {
+        let mut compiled = Vec::with_capacity(patterns.len());
+        for pattern in patterns {
+            match glob::Pattern::new(pattern) {
+                Ok(pattern) => compiled.push(pattern),
+                Err(error) => eprintln!(
+                    "{{\"level\":\"warn\",\"component\":\"safeguard\",\"message\":\"ignoring invalid protected path pattern '{pattern}': {error}\"}}"
+                ),
+            }
+        }
+        Self { patterns: compiled }
+    }
+
+    /// The first pattern matching a forward-slash relative path.
+    pub fn matching(&self, relative_path: &str) -> Option<&str> {
+        self.patterns
+            .iter()
+            .find(|pattern| pattern.matches(relative_path))
+            .map(glob::Pattern::as_str)
+    }
+}
+
+/// A per-step budget that an operation pushed over its limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetOverrun {
+    pub budget: StepBudget,
+    pub used: u64,
+    pub limit: u64,
+}
+
 /// Tracks per-step safeguard counters and checks thresholds.
+///
+/// Counters are kept for each open step, so steps open at the same time
+/// never add up each other's deletes or overwrites.
 pub struct SafeguardTracker {
     config: SafeguardConfig,
+    protected_paths: ProtectedPathMatcher,
     /// Monotonically increasing ID for safeguard events.
     next_safeguard_id: SafeguardId,
-    /// Delete operations counted in the current step.
+    /// Keys allowed with `AllowForSession`. Outlive every step.
+    session_allowed_kinds: HashSet<String>,
+    /// Announced operations waiting for the next step.
+    pending_expectations: Vec<Expectation>,
+    /// Counters of each open step.
+    steps: HashMap<StepId, StepCounters>,
+}
+
+/// Safeguard state of one step.
+#[derive(Default)]
+struct StepCounters {
+    /// Delete operations counted so far.
     delete_count: u64,
-    /// Paths deleted so far in the current step (for sample_paths in events).
+    /// Paths deleted so far (for sample_paths in events).
     deleted_paths: Vec<String>,
-    /// Safeguard kinds that have already been allowed for the current step,
-    /// keyed by a discriminant string. Prevents re-triggering after Allow.
+    /// Distinct existing files overwritten so far, in the order first seen
+    /// (for sample_paths in events).
+    overwritten_paths: Vec<String>,
+    /// Set view of `overwritten_paths`, so repeated writes count once.
+    overwritten: HashSet<String>,
+    /// Bytes deleted so far.
+    deleted_bytes: u64,
+    /// Bytes of existing files overwritten so far.
+    overwritten_bytes: u64,
+    /// Safeguard kinds already allowed for the step, keyed by a
+    /// discriminant string. Prevents re-triggering after `AllowForStep`.
     allowed_kinds: HashSet<String>,
+    /// Operations announced for the step, with whether the handler has been
+    /// asked about each.
+    expectations: Vec<(Expectation, bool)>,
+    /// Bytes added to the step's budgets by allowed expectations.
+    granted_deleted_bytes: u64,
+    granted_overwritten_bytes: u64,
+    /// Filesystem operations counted so far.
+    operation_count: u64,
+    /// `operation_count` when the current operation window began: 0, or
+    /// the count at the last `AllowOnce` of the operation limit.
+    operation_window_start: u64,
+    /// Time since the step opened when the current duration window began.
+    duration_window_start: Duration,
+    /// Set by an `AllowOnce` of the duration limit: the next check starts
+    /// a new window.
+    restart_duration_window: bool,
+}
+
+impl StepCounters {
+    /// Remember that `path` is overwritten in this step. `true` the first time.
+    fn note_overwritten(&mut self, path: &str) -> bool {
+        let first = self.overwritten.insert(path.to_string());
+        if first {
+            self.overwritten_paths.push(path.to_string());
+        }
+        first
+    }
 }
 
 impl SafeguardTracker {
     pub fn new(config: SafeguardConfig) -> Self {
         Self {
+            protected_paths: ProtectedPathMatcher::new(&config.protected_paths),
             config,
             next_safeguard_id: 1,
-            delete_count: 0,
-            deleted_paths: Vec::new(),
-            allowed_kinds: HashSet::new(),
+            session_allowed_kinds: HashSet::new(),
+            pending_expectations: Vec::new(),
+            steps: HashMap::new(),
+        }
+    }
+
+    /// Replace the thresholds and limits. The counters of open steps and the
+    /// allow decisions are kept, so the new values apply to the rest of an
+    /// active step.
+    pub fn set_config(&mut self, config: SafeguardConfig) {
+        self.protected_paths = ProtectedPathMatcher::new(&config.protected_paths);
+        self.config = config;
+    }
+
+    /// Announce an operation of the next step. Paths are normalized to
+    /// forward-slash relative form.
+    pub fn expect(&mut self, mut expectation: Expectation) {
+        for path in &mut expectation.paths {
+            let normalized = path.replace('\\', "/");
+            let normalized = normalized.trim_start_matches("./").trim_end_matches('/');
+            *path = match normalized {
+                "" => ".".to_string(),
+                other => other.to_string(),
+            };
         }
+        self.pending_expectations.push(expectation);
+    }
+
+    /// Number of announced operations waiting for the next step.
+    pub fn pending_expectations(&self) -> usize {
+        self.pending_expectations.len()
+    }
+
+    /// Bytes allowed expectations added to `step_id`, also granted on top of
+    /// `max_single_step_size_bytes`.
+    pub fn granted_bytes(&self, step_id: StepId) -> u64 {
+        self.steps.get(&step_id).map_or(0, |step| {
+            step.granted_deleted_bytes
+                .saturating_add(step.granted_overwritten_bytes)
+        })
+    }
+
+    /// Start counting for a newly opened step and hand it the pending
+    /// expectations.
+    pub fn begin_step(&mut self, step_id: StepId) {
+        let expectations = self
+            .pending_expectations
+            .drain(..)
+            .map(|expectation| (expectation, false))
+            .collect();
+        self.steps.insert(
+            step_id,
+            StepCounters {
+                expectations,
+                ..StepCounters::default()
+            },
+        );
     }
 
-    /// Reset per-step counters. Called when a new step is opened.
-    pub fn reset(&mut self) {
-        self.delete_count = 0;
-        self.deleted_paths.clear();
-        self.allowed_kinds.clear();
+    /// Drop the counters of a step that closed or was rolled back.
+    pub fn end_step(&mut self, step_id: StepId) {
+        self.steps.remove(&step_id);
+    }
+
+    /// Charge `bytes` deleted against the step's deleted-bytes budget.
+    pub fn charge_delete(&mut self, step_id: StepId, bytes: u64) -> Option<BudgetOverrun> {
+        let limit = self.config.max_deleted_bytes_per_step;
+        let step = self.step(step_id);
+        step.deleted_bytes += bytes;
+        over_budget(
+            StepBudget::DeletedBytes,
+            step.deleted_bytes,
+            limit.map(|limit| limit.saturating_add(step.granted_deleted_bytes)),
+        )
+    }
+
+    /// Charge overwriting the existing file at `path`, `bytes` long, against
+    /// the step's overwritten-bytes budget. Each path is charged once per step.
+    pub fn charge_overwrite(
+        &mut self,
+        step_id: StepId,
+        path: &str,
+        bytes: u64,
+    ) -> Option<BudgetOverrun> {
+        let limit = self.config.max_overwritten_bytes_per_step;
+        let step = self.step(step_id);
+        if step.note_overwritten(path) {
+            step.overwritten_bytes += bytes;
+        }
+        over_budget(
+            StepBudget::OverwrittenBytes,
+            step.overwritten_bytes,
+            limit.map(|limit| limit.saturating_add(step.granted_overwritten_bytes)),
+        )
     }
 
     /// Record a delete operation and check the threshold.
     /// Returns `Some(event)` if the threshold was just reached.
     pub fn check_delete(&mut self, path: &str, step_id: StepId) -> Option<SafeguardEvent> {
-        self.delete_count += 1;
-        self.deleted_paths.push(path.to_string());
+        let threshold = self.config.delete_threshold;
+        let step = self.step(step_id);
+        step.delete_count += 1;
+        step.deleted_paths.push(path.to_string());
+        let (count, sample_paths) = (step.delete_count, step.deleted_paths.clone());
 
-        let threshold = self.config.delete_threshold?;
+        let threshold = threshold?;
 
-        if self.delete_count < threshold {
+        if count < threshold {
             return None;
         }
 
-        if self.allowed_kinds.contains("delete_threshold") {
+        if self.is_allowed(step_id, "delete_threshold") {
             return None;
         }
 
         let event = SafeguardEvent {
             safeguard_id: self.next_id(),
             step_id,
-            kind: SafeguardKind::DeleteThreshold {
-                count: self.delete_count,
-                threshold,
-            },
-            sample_paths: self.deleted_paths.clone(),
+            kind: SafeguardKind::DeleteThreshold { count, threshold },
+            sample_paths,
         };
         Some(event)
     }
@@ -85,7 +331,7 @@ impl SafeguardTracker {
             return None;
         }
 
-        if self.allowed_kinds.contains("overwrite_large_file") {
+        if self.is_allowed(step_id, "overwrite_large_file") {
             return None;
         }
 
@@ -102,6 +348,86 @@ impl SafeguardTracker {
         Some(event)
     }
 
+    /// Record that an existing file is being overwritten and check the
+    /// overwrite count threshold. Each path counts once per step.
+    /// Returns `Some(event)` if the threshold was reached.
+    pub fn check_overwrite_count(
+        &mut self,
+        path: &str,
+        step_id: StepId,
+    ) -> Option<SafeguardEvent> {
+        let step = self.step(step_id);
+        step.note_overwritten(path);
+        let count = step.overwritten_paths.len() as u64;
+
+        let threshold = self.config.overwrite_count_threshold?;
+
+        if count < threshold {
+            return None;
+        }
+
+        if self.is_allowed(step_id, "overwrite_count_threshold") {
+            return None;
+        }
+
+        let event = SafeguardEvent {
+            safeguard_id: self.next_id(),
+            step_id,
+            kind: SafeguardKind::OverwriteCountThreshold { count, threshold },
+            sample_paths: self.step(step_id).overwritten_paths.clone(),
+        };
+        Some(event)
+    }
+
+    /// Count a filesystem operation of a step and check its operation count
+    /// limit.
+    pub fn check_step_operations(&mut self, step_id: StepId) -> Option<SafeguardEvent> {
+        let threshold = self.config.max_step_operations;
+        let step = self.step(step_id);
+        step.operation_count += 1;
+        let (count, window_start) = (step.operation_count, step.operation_window_start);
+
+        let threshold = threshold?;
+        if count - window_start <= threshold || self.is_allowed(step_id, "step_operation_count") {
+            return None;
+        }
+        Some(SafeguardEvent {
+            safeguard_id: self.next_id(),
+            step_id,
+            kind: SafeguardKind::StepOperationCount { count, threshold },
+            sample_paths: Vec::new(),
+        })
+    }
+
+    /// Check the duration limit of a step that opened `elapsed` ago.
+    pub fn check_step_duration(
+        &mut self,
+        step_id: StepId,
+        elapsed: Duration,
+    ) -> Option<SafeguardEvent> {
+        let limit_seconds = self.config.max_step_duration_seconds?;
+        let step = self.step(step_id);
+        if step.restart_duration_window {
+            step.restart_duration_window = false;
+            step.duration_window_start = elapsed;
+        }
+        let window_start = step.duration_window_start;
+        if elapsed.saturating_sub(window_start) <= Duration::from_secs(limit_seconds)
+            || self.is_allowed(step_id, "step_duration")
+        {
+            return None;
+        }
+        Some(SafeguardEvent {
+            safeguard_id: self.next_id(),
+            step_id,
+            kind: SafeguardKind::StepDuration {
+                elapsed_seconds: elapsed.as_secs(),
+                limit_seconds,
+            },
+            sample_paths: Vec::new(),
+        })
+    }
+
     /// Check whether a rename-over-existing triggers the safeguard.
     pub fn check_rename_over(
         &mut self,
@@ -113,7 +439,7 @@ impl SafeguardTracker {
             return None;
         }
 
-        if self.allowed_kinds.contains("rename_over_existing") {
+        if self.is_allowed(step_id, "rename_over_existing") {
             return None;
         }
 
@@ -129,14 +455,144 @@ impl SafeguardTracker {
         Some(event)
     }
 
-    /// Mark a safeguard kind as allowed for the current step (prevents re-triggering).
-    pub fn mark_allowed(&mut self, kind: &SafeguardKind) {
+    /// Check whether changing `path` touches a protected pattern. Allowing
+    /// one protected path does not allow the others.
+    pub fn check_protected_path(
+        &mut self,
+        path: &str,
+        operation: PathOperation,
+        step_id: StepId,
+    ) -> Option<SafeguardEvent> {
+        let pattern = self.protected_paths.matching(path)?.to_string();
+
+        if self.is_allowed(step_id, &protected_path_key(path)) {
+            return None;
+        }
+
+        let event = SafeguardEvent {
+            safeguard_id: self.next_id(),
+            step_id,
+            kind: SafeguardKind::ProtectedPath {
+                path: path.to_string(),
+                pattern,
+                operation,
+            },
+            sample_paths: vec![path.to_string()],
+        };
+        Some(event)
+    }
+
+    /// Check whether `op` on `path` starts an announced operation of this
+    /// step. Each expectation triggers at most once.
+    pub fn check_expected(
+        &mut self,
+        path: &str,
+        op: ExpectedOperation,
+        step_id: StepId,
+    ) -> Option<SafeguardEvent> {
+        let (expectation, asked) = self
+            .step(step_id)
+            .expectations
+            .iter_mut()
+            .find(|(expectation, asked)| {
+                !*asked && expectation.op == op && covers(&expectation.paths, path)
+            })?;
+        *asked = true;
+        let kind = SafeguardKind::ExpectedOperation {
+            op,
+            path_count: expectation.paths.len() as u64,
+            estimated_bytes: expectation.estimated_bytes,
+        };
+        let sample_paths = expectation.paths.clone();
+        Some(SafeguardEvent {
+            safeguard_id: self.next_id(),
+            step_id,
+            kind,
+            sample_paths,
+        })
+    }
+
+    /// Record an allow decision for `kind` in `step_id`. `AllowOnce` records
+    /// nothing, so the next check of the same kind triggers again, except for
+    /// the step limits, where it starts a new window; `Deny` is ignored.
+    pub fn mark_allowed(
+        &mut self,
+        step_id: StepId,
+        kind: &SafeguardKind,
+        decision: SafeguardDecision,
+    ) {
         let key = match kind {
-            SafeguardKind::DeleteThreshold { .. } => "delete_threshold",
-            SafeguardKind::OverwriteLargeFile { .. } => "overwrite_large_file",
-            SafeguardKind::RenameOverExisting { .. } => "rename_over_existing",
+            SafeguardKind::DeleteThreshold { .. } => "delete_threshold".to_string(),
+            SafeguardKind::OverwriteLargeFile { .. } => "overwrite_large_file".to_string(),
+            SafeguardKind::RenameOverExisting { .. } => "rename_over_existing".to_string(),
+            SafeguardKind::OverwriteCountThreshold { .. } => {
+                "overwrite_count_threshold".to_string()
+            }
+            SafeguardKind::ProtectedPath { path, .. } => protected_path_key(path),
+            SafeguardKind::StepDuration { .. } => "step_duration".to_string(),
+            SafeguardKind::StepOperationCount { .. } => "step_operation_count".to_string(),
+            SafeguardKind::ExpectedOperation {
+                op,
+                estimated_bytes,
+                ..
+            } => {
+                // Any allow covers the announced operation, which is limited
+                // to its step.
+                if decision != SafeguardDecision::Deny {
+                    self.grant_expected(step_id, *op, *estimated_bytes);
+                }
+                return;
+            }
         };
-        self.allowed_kinds.insert(key.to_string());
+        match decision {
+            SafeguardDecision::AllowForStep => {
+                self.step(step_id).allowed_kinds.insert(key);
+            }
+            SafeguardDecision::AllowForSession => {
+                self.session_allowed_kinds.insert(key);
+            }
+            SafeguardDecision::AllowOnce => {
+                // A step limit stays crossed, so allowing once grants a new
+                // window of the same size rather than a single operation.
+                let step = self.step(step_id);
+                match kind {
+                    SafeguardKind::StepDuration { .. } => step.restart_duration_window = true,
+                    SafeguardKind::StepOperationCount { count, .. } => {
+                        step.operation_window_start = *count;
+                    }
+                    _ => {}
+                }
+            }
+            SafeguardDecision::Deny => {}
+        }
+    }
+
+    /// Waive the count thresholds of `op` for this step and raise its budget.
+    fn grant_expected(&mut self, step_id: StepId, op: ExpectedOperation, estimated_bytes: u64) {
+        let step = self.step(step_id);
+        let (granted, kinds): (&mut u64, &[&str]) = match op {
+            ExpectedOperation::Delete => (&mut step.granted_deleted_bytes, &["delete_threshold"]),
+            ExpectedOperation::Rewrite => (
+                &mut step.granted_overwritten_bytes,
+                &["overwrite_large_file", "overwrite_count_threshold"],
+            ),
+        };
+        *granted = granted.saturating_add(estimated_bytes);
+        step.allowed_kinds
+            .extend(kinds.iter().map(|kind| kind.to_string()));
+    }
+
+    /// Counters of `step_id`, starting empty for a step that was never begun.
+    fn step(&mut self, step_id: StepId) -> &mut StepCounters {
+        self.steps.entry(step_id).or_default()
+    }
+
+    fn is_allowed(&self, step_id: StepId, key: &str) -> bool {
+        self.session_allowed_kinds.contains(key)
+            || self
+                .steps
+                .get(&step_id)
+                .is_some_and(|step| step.allowed_kinds.contains(key))
     }
 
     fn next_id(&mut self) -> SafeguardId {
@@ -145,3 +601,170 @@ impl SafeguardTracker {
         id
     }
 }
+
+fn over_budget(budget: StepBudget, used: u64, limit: Option<u64>) -> Option<BudgetOverrun> {
+    let limit = limit?;
+    (used > limit).then_some(BudgetOverrun {
+        budget,
+        used,
+        limit,
+    })
+}
+
+/// Whether `path` is one of `paths` or below one of them.
+fn covers(paths: &[String], path: &str) -> bool {
+    paths.iter().any(|covered| {
+        covered == "."
+            || path
+                .strip_prefix(covered.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+fn protected_path_key(path: &str) -> String {
+    format!("protected_path:{path}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protected_patterns_match_relative_paths() {
+        let matcher = ProtectedPathMatcher::new(&[
+            ".git/**".to_string(),
+            "*.env".to_string(),
+            "secrets/*".to_string(),
+            "[".to_string(),
+        ]);
+
+        assert_eq!(matcher.matching(".git/HEAD"), Some(".git/**"));
+        assert_eq!(matcher.matching(".env"), Some("*.env"));
+        assert_eq!(matcher.matching("config/prod.env"), Some("*.env"));
+        assert_eq!(matcher.matching("secrets/token"), Some("secrets/*"));
+        assert_eq!(matcher.matching("src/main.rs"), None);
+
+        assert!(validate_protected_paths(&["*.env".to_string()]).is_ok());
+        assert!(validate_protected_paths(&["[".to_string()]).unwrap_err().contains("'['"));
+    }
+
+    #[test]
+    fn step_duration_triggers_past_the_limit_until_allowed() {
+        let mut tracker = SafeguardTracker::new(SafeguardConfig {
+            max_step_duration_seconds: Some(10),
+            ..SafeguardConfig::default()
+        });
+        tracker.begin_step(1);
+        assert!(tracker.check_step_duration(1, Duration::from_secs(10)).is_none());
+        let event = tracker.check_step_duration(1, Duration::from_secs(11)).unwrap();
+        assert_eq!(
+            event.kind,
+            SafeguardKind::StepDuration { elapsed_seconds: 11, limit_seconds: 10 }
+        );
+
+        tracker.mark_allowed(1, &event.kind, SafeguardDecision::AllowForStep);
+        assert!(tracker.check_step_duration(1, Duration::from_secs(60)).is_none());
+
+        // A new step is measured afresh.
+        tracker.end_step(1);
+        tracker.begin_step(2);
+        assert!(tracker.check_step_duration(2, Duration::from_secs(11)).is_some());
+    }
+
+    #[test]
+    fn allow_once_grants_a_new_step_limit_window() {
+        let mut tracker = SafeguardTracker::new(SafeguardConfig {
+            max_step_operations: Some(2),
+            ..SafeguardConfig::default()
+        });
+        tracker.begin_step(1);
+        assert!(tracker.check_step_operations(1).is_none());
+        assert!(tracker.check_step_operations(1).is_none());
+        let event = tracker.check_step_operations(1).unwrap();
+        assert_eq!(
+            event.kind,
+            SafeguardKind::StepOperationCount { count: 3, threshold: 2 }
+        );
+
+        tracker.mark_allowed(1, &event.kind, SafeguardDecision::AllowOnce);
+        assert!(tracker.check_step_operations(1).is_none());
+        assert!(tracker.check_step_operations(1).is_none());
+        assert!(tracker.check_step_operations(1).is_some());
+
+        let mut tracker = SafeguardTracker::new(SafeguardConfig {
+            max_step_duration_seconds: Some(10),
+            ..SafeguardConfig::default()
+        });
+        tracker.begin_step(2);
+        let event = tracker.check_step_duration(2, Duration::from_secs(11)).unwrap();
+        tracker.mark_allowed(2, &event.kind, SafeguardDecision::AllowOnce);
+        assert!(tracker.check_step_duration(2, Duration::from_secs(30)).is_none());
+        assert!(tracker.check_step_duration(2, Duration::from_secs(41)).is_some());
+    }
+
+    #[test]
+    fn budgets_charge_each_overwritten_path_once_per_step() {
+        let mut tracker = SafeguardTracker::new(SafeguardConfig {
+            max_deleted_bytes_per_step: Some(100),
+            max_overwritten_bytes_per_step: Some(100),
+            ..SafeguardConfig::default()
+        });
+        tracker.begin_step(1);
+        tracker.begin_step(2);
+        assert_eq!(tracker.charge_overwrite(1, "a", 60), None);
+        assert_eq!(tracker.charge_overwrite(1, "a", 60), None);
+        assert_eq!(tracker.charge_overwrite(2, "b", 60), None);
+        assert_eq!(
+            tracker.charge_overwrite(1, "b", 41),
+            Some(BudgetOverrun {
+                budget: StepBudget::OverwrittenBytes,
+                used: 101,
+                limit: 100,
+            })
+        );
+        assert_eq!(tracker.charge_delete(1, 100), None);
+        assert!(tracker.charge_delete(1, 1).is_some());
+        assert_eq!(tracker.charge_delete(2, 100), None);
+
+        tracker.end_step(1);
+        tracker.begin_step(3);
+        assert_eq!(tracker.charge_delete(3, 100), None);
+    }
+
+    #[test]
+    fn allowed_expectation_waives_thresholds_and_raises_budget() {
+        let mut tracker = SafeguardTracker::new(SafeguardConfig {
+            delete_threshold: Some(1),
+            max_deleted_bytes_per_step: Some(100),
+            ..SafeguardConfig::default()
+        });
+        tracker.expect(Expectation {
+            paths: vec!["./target/".to_string()],
+            op: ExpectedOperation::Delete,
+            estimated_bytes: 1_000,
+        });
+        assert!(tracker.check_expected("target/a", ExpectedOperation::Delete, 1).is_none());
+        assert_eq!(tracker.pending_expectations(), 1);
+
+        tracker.begin_step(2);
+        assert_eq!(tracker.pending_expectations(), 0);
+        assert!(tracker.check_expected("targets", ExpectedOperation::Delete, 2).is_none());
+        assert!(tracker.check_expected("target/a", ExpectedOperation::Rewrite, 2).is_none());
+        let event = tracker
+            .check_expected("target/debug/a", ExpectedOperation::Delete, 2)
+            .unwrap();
+        assert_eq!(event.sample_paths, vec!["target".to_string()]);
+        assert!(tracker.check_expected("target/b", ExpectedOperation::Delete, 2).is_none());
+
+        tracker.mark_allowed(2, &event.kind, SafeguardDecision::AllowOnce);
+        assert_eq!(tracker.granted_bytes(2), 1_000);
+        assert!(tracker.check_delete("target/a", 2).is_none());
+        assert_eq!(tracker.charge_delete(2, 1_100), None);
+        assert!(tracker.charge_delete(2, 1).is_some());
+
+        tracker.end_step(2);
+        tracker.begin_step(3);
+        assert_eq!(tracker.granted_bytes(3), 0);
+        assert!(tracker.check_delete("target/a", 3).is_some());
+    }
+}
@@ -0,0 +1,236 @@
+//! Squashing consecutive steps into one, for `undo.squash`.
+//!
+//! [`build_squashed_step`] merges the directories of consecutive steps,
+//! oldest first, into a new step directory. A path captured by several of
+//! the steps keeps the preimage of the oldest one and the later captures
+//! are dropped, except where the oldest holds only part of the file: a
+//! metadata-only preimage takes its contents from the next capture, and a
+//! range preimage is completed with it. The postimage kept is the newest.
+//!
+//! Entry names of a later step are translated through the directory renames
+//! of the steps before it to their names before the first step (see
+//! [`crate::dir_rename`]). Those created at a name a rename vacated are
+//! dropped: undoing the rename removes them. The renames, warnings and
+//! barriers of all the steps are kept, in order.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::dir_rename;
+use crate::manifest::{ManifestWarning, StepManifest};
+use crate::preimage::{
+    PreimageFileType, path_hash, promote_range_preimage_onto, read_preimage_metadata,
+    write_preimage_metadata,
+};
+use crate::rollback;
+use crate::undo_interceptor::{read_step_barriers, write_step_barriers};
+
+/// Build the step merging `step_dirs` (oldest first) in `target`, named
+/// after the first of them. Returns the number of entries dropped. The
+/// step directories themselves are left as they are: data files are hard
+/// links to theirs, or copies where linking fails.
+pub fn build_squashed_step(
+    step_dirs: &[PathBuf],
+    target: &Path,
+    fold_case: bool,
+) -> codeagent_common::Result<usize> {
+    let preimage_dir = target.join("preimages");
+    fs::create_dir_all(&preimage_dir)?;
+    let mut squashed: Option<StepManifest> = None;
+    let mut barriers = Vec::new();
+    let mut dropped = 0;
+
+    for step_dir in step_dirs {
+        let manifest = StepManifest::read_from(step_dir)?;
+        let source_dir = step_dir.join("preimages");
+        let squashed = squashed.get_or_insert_with(|| StepManifest {
+            command: None,
+            entries: BTreeMap::new(),
+            warnings: Vec::new(),
+            duration_ms: None,
+            exit_code: None,
+            renames: Vec::new(),
+            ..manifest.clone()
+        });
+        squashed.command = match (squashed.command.take(), &manifest.command) {
+            (Some(earlier), Some(command)) => Some(format!("{earlier}; {command}")),
+            (earlier, command) => earlier.or_else(|| command.clone()),
+        };
+        squashed.duration_ms = match (squashed.duration_ms, manifest.duration_ms) {
+            (Some(earlier), Some(duration)) => Some(earlier + duration),
+            (earlier, duration) => earlier.or(duration),
+        };
+        squashed.exit_code = manifest.exit_code.or(squashed.exit_code);
+        if squashed.chain_prev.is_none() {
+            squashed.chain_prev = manifest.chain_prev.clone();
+        }
+
+        for (rel_path, entry) in &manifest.entries {
+            let Some(name) = dir_rename::original_path(&squashed.renames, rel_path, fold_case)
+            else {
+                dropped += 1;
+                continue;
+            };
+            if let Some(kept) = squashed.entries.get(&name) {
+                fold_later_capture(&source_dir, &entry.path_hash, &preimage_dir, &kept.path_hash)?;
+                dropped += 1;
+                continue;
+            }
+            let hash = path_hash(Path::new(&name));
+            copy_capture(&source_dir, &entry.path_hash, &preimage_dir, &hash, &name)?;
+            squashed.add_entry(&name, &hash, entry.existed_before, &entry.file_type);
+            let hard_link = entry.hard_link.clone().map(|mut info| {
+                info.same_inode_as = info.same_inode_as.map(|primary| {
+                    dir_rename::original_path(&squashed.renames, &primary, fold_case)
+                        .unwrap_or(primary)
+                });
+                info
+            });
+            squashed.set_hard_link(&name, hard_link);
+            squashed.set_modified_by(&name, entry.modified_by.clone());
+        }
+        for warning in &manifest.warnings {
+            let path = dir_rename::original_path(&squashed.renames, &warning.path, fold_case)
+                .unwrap_or_else(|| warning.path.clone());
+            squashed.warnings.push(ManifestWarning { path, ..warning.clone() });
+        }
+        squashed.renames.extend(manifest.renames.iter().cloned());
+        barriers.extend(read_step_barriers(step_dir));
+    }
+
+    let Some(mut squashed) = squashed else {
+        return Ok(0);
+    };
+    squashed.preimage_bytes = stored_bytes(&preimage_dir);
+    if !barriers.is_empty() {
+        write_step_barriers(target, &barriers)?;
+    }
+    squashed.write_to(target)?;
+    Ok(dropped)
+}
+
+/// Copy the capture stored under `source_hash` to `target_hash`, for the
+/// entry `name`.
+fn copy_capture(
+    source_dir: &Path,
+    source_hash: &str,
+    target_dir: &Path,
+    target_hash: &str,
+    name: &str,
+) -> codeagent_common::Result<()> {
+    let Ok(mut meta) = read_preimage_metadata(source_dir, source_hash) else {
+        return Ok(());
+    };
+    meta.relative_path = name.to_string();
+    write_preimage_metadata(target_dir, target_hash, &meta)?;
+    let mut suffixes = vec!["dat".to_string(), "post.dat".to_string()];
+    let patch_count = meta.range_patches.as_ref().map_or(0, Vec::len);
+    suffixes.extend((0..patch_count).map(|index| format!("range.{index}.dat")));
+    for suffix in suffixes {
+        link_data_file(
+            &source_dir.join(format!("{source_hash}.{suffix}")),
+            &target_dir.join(format!("{target_hash}.{suffix}")),
+        )?;
+    }
+    Ok(())
+}
+
+/// Fold a later capture of a path into the one kept under `kept_hash`: its
+/// postimage replaces the kept one, and it fills in a kept preimage that
+/// holds only the attributes or some ranges of a regular file.
+fn fold_later_capture(
+    source_dir: &Path,
+    source_hash: &str,
+    target_dir: &Path,
+    kept_hash: &str,
+) -> codeagent_common::Result<()> {
+    let postimage = |hash: &str, dir: &Path| dir.join(format!("{hash}.post.dat"));
+    let _ = fs::remove_file(postimage(kept_hash, target_dir));
+    link_data_file(&postimage(source_hash, source_dir), &postimage(kept_hash, target_dir))?;
+
+    let (Ok(mut kept), Ok(later)) = (
+        read_preimage_metadata(target_dir, kept_hash),
+        read_preimage_metadata(source_dir, source_hash),
+    ) else {
+        return Ok(());
+    };
+    let regular = |existed: bool, file_type: PreimageFileType| {
+        existed && file_type == PreimageFileType::Regular
+    };
+    if !regular(kept.existed_before, kept.file_type)
+        || !regular(later.existed_before, later.file_type)
+        || later.metadata_only
+    {
+        return Ok(());
+    }
+
+    if kept.metadata_only {
+        // The contents were untouched until the later capture.
+        let patch_count = later.range_patches.as_ref().map_or(0, Vec::len);
+        let mut suffixes = vec!["dat".to_string()];
+        suffixes.extend((0..patch_count).map(|index| format!("range.{index}.dat")));
+        for suffix in suffixes {
+            link_data_file(
+                &source_dir.join(format!("{source_hash}.{suffix}")),
+                &target_dir.join(format!("{kept_hash}.{suffix}")),
+            )?;
+        }
+        kept.metadata_only = false;
+        kept.size = later.size;
+        kept.content_hash = later.content_hash;
+        kept.range_patches = later.range_patches;
+        return write_preimage_metadata(target_dir, kept_hash, &kept);
+    }
+
+    let Some(patches) = &mut kept.range_patches else {
+        return Ok(());
+    };
+    match later.range_patches {
+        // Rollback applies the patches newest first, so the later ones
+        // go after the kept ones.
+        Some(later_patches) => {
+            for (index, patch) in later_patches.into_iter().enumerate() {
+                link_data_file(
+                    &source_dir.join(format!("{source_hash}.range.{index}.dat")),
+                    &target_dir.join(format!("{kept_hash}.range.{}.dat", patches.len())),
+                )?;
+                patches.push(patch);
+            }
+            write_preimage_metadata(target_dir, kept_hash, &kept)
+        }
+        None => {
+            let contents =
+                rollback::read_full_preimage(source_dir, source_hash, &later.relative_path)?;
+            let path = PathBuf::from(&kept.relative_path);
+            promote_range_preimage_onto(&path, contents, target_dir, &mut kept)?;
+            Ok(())
+        }
+    }
+}
+
+/// Hard-link `source` to `target`, copying it if linking fails. Nothing to
+/// do if there is no `source`.
+fn link_data_file(source: &Path, target: &Path) -> codeagent_common::Result<()> {
+    if !source.exists() {
+        return Ok(());
+    }
+    if fs::hard_link(source, target).is_err() {
+        fs::copy(source, target)?;
+    }
+    Ok(())
+}
+
+/// Bytes of the data files in `preimage_dir`, postimages included.
+fn stored_bytes(preimage_dir: &Path) -> u64 {
+    fs::read_dir(preimage_dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|entry| !entry.file_name().to_string_lossy().ends_with(".meta.json"))
+                .filter_map(|entry| entry.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
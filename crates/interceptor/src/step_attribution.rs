@@ -0,0 +1,126 @@
+//! Which open step a filesystem operation is recorded in.
+//!
+//! Backends handle each operation on one thread from start to finish, so the
+//! step is carried in a thread-local for the duration of the hook calls
+//! rather than through every [`WriteInterceptor`](crate::write_interceptor::WriteInterceptor)
+//! method. Operations without a scope, or scoped to a step that is not open,
+//! go to the oldest open step. The guest process that issued the operation
+//! travels the same way, for the manifest to say who changed each path.
+
+use std::cell::RefCell;
+
+use codeagent_common::StepId;
+use serde::{Deserialize, Serialize};
+
+/// The guest process a filesystem operation came from, as the FUSE request
+/// names it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestProcess {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// The process's command name in the guest (`/proc/<pid>/comm`), when
+    /// the guest could tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Default)]
+struct Attribution {
+    step: Option<StepId>,
+    process: Option<GuestProcess>,
+}
+
+thread_local! {
+    static ATTRIBUTION: RefCell<Attribution> = const {
+        RefCell::new(Attribution { step: None, process: None })
+    };
+}
+
+/// Restores the previous attribution of the thread when dropped.
+#[must_use = "the attribution ends when the scope is dropped"]
+pub struct AttributionScope {
+    previous: Attribution,
+}
+
+/// Record operations on this thread in `step` until the returned scope is
+/// dropped. `None` clears any attribution made by an enclosing scope. The
+/// issuing process is left as the enclosing scope set it.
+pub fn attribute_to(step: Option<StepId>) -> AttributionScope {
+    let previous = current();
+    let process = previous.process.clone();
+    ATTRIBUTION.with(|cell| cell.replace(Attribution { step, process }));
+    AttributionScope { previous }
+}
+
+/// Record operations on this thread in `step`, as issued by `process`,
+/// until the returned scope is dropped.
+pub fn attribute_operation(
+    step: Option<StepId>,
+    process: Option<GuestProcess>,
+) -> AttributionScope {
+    AttributionScope {
+        previous: ATTRIBUTION.with(|cell| cell.replace(Attribution { step, process })),
+    }
+}
+
+fn current() -> Attribution {
+    ATTRIBUTION.with(|cell| cell.borrow().clone())
+}
+
+/// The step operations on this thread are currently attributed to.
+pub fn attributed_step() -> Option<StepId> {
+    ATTRIBUTION.with(|cell| cell.borrow().step)
+}
+
+/// The guest process operations on this thread were issued by, if known.
+pub fn attributed_process() -> Option<GuestProcess> {
+    current().process
+}
+
+impl Drop for AttributionScope {
+    fn drop(&mut self) {
+        let previous = std::mem::take(&mut self.previous);
+        ATTRIBUTION.with(|cell| cell.replace(previous));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_nest_and_stay_on_their_thread() {
+        assert_eq!(attributed_step(), None);
+        let outer = attribute_to(Some(3));
+        {
+            let _inner = attribute_to(Some(4));
+            assert_eq!(attributed_step(), Some(4));
+            std::thread::spawn(|| assert_eq!(attributed_step(), None))
+                .join()
+                .unwrap();
+        }
+        assert_eq!(attributed_step(), Some(3));
+        drop(outer);
+        assert_eq!(attributed_step(), None);
+    }
+
+    #[test]
+    fn step_scopes_keep_the_issuing_process() {
+        let process = GuestProcess {
+            pid: 1234,
+            uid: 1000,
+            gid: 1000,
+            name: Some("gcc".to_string()),
+        };
+        let operation = attribute_operation(Some(2), Some(process.clone()));
+        {
+            let _api = attribute_to(None);
+            assert_eq!(attributed_step(), None);
+            assert_eq!(attributed_process(), Some(process));
+        }
+        assert_eq!(attributed_step(), Some(2));
+        drop(operation);
+        assert_eq!(attributed_process(), None);
+    }
+}
@@ -1,29 +1,63 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Utc};
 use codeagent_common::{
-    AffectedPath, BarrierInfo, BarrierReason, CodeAgentError, ExternalModificationPolicy,
-    ResourceLimitsConfig, Result, RollbackResult, SafeguardConfig, SafeguardDecision,
-    SafeguardEvent, StepId, StepManager, SymlinkPolicy,
+    percent_of, AffectedPath, BarrierInfo, BarrierReason, CaseSensitivity, CodeAgentError,
+    CoherentCaptureConfig,
+    metrics, MergedPath, OperationMonitor, Unmonitored,
+    Expectation, ExpectedOperation, ExternalModificationConfig, ExternalModificationPolicy, GitMirrorConfig, PathOperation, ResourceLimitsConfig, Result, RollbackMode, RollbackResult, SafeguardConfig, SafeguardDecision,
+    SafeguardEvent, SquashResult, StepId, StepInfo, StepManager, StepType, SymlinkPolicy,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::gitignore::build_gitignore;
-use ignore::gitignore::Gitignore;
-use crate::manifest::StepManifest;
-use crate::preimage::{capture_creation_marker, capture_preimage, path_hash};
+use crate::blob_cache::{DEFAULT_BLOB_CACHE_ENTRIES, PreimageBlobCache};
+use crate::boundary::WorkingRootBoundary;
+use crate::capture_slots::CaptureSlots;
+use crate::coherent_capture::CoherentCaptureMatcher;
+use crate::compaction::{
+    self, BlobIndex, CompactedStep, CompactionOptions, CompactionReport, FileCompaction,
+};
+use crate::dir_rename;
+use crate::external_modification::ExternalModificationMatcher;
+#[cfg(feature = "git-mirror")]
+use crate::git_mirror::GitMirror;
+use crate::gitignore::{GitignoreFilter, is_ignore_source};
+use crate::history_journal::{
+    self, EVICTION_JOURNAL_FILE, EvictionJournal, JournaledStep, ROLLBACK_MARKER_FILE,
+    RollbackMarker, SQUASH_JOURNAL_FILE, SquashJournal,
+};
+use crate::chain::{self, ChainAttestation, ChainHead};
+use crate::path_case;
+use crate::manifest::{
+    RenameRecord, StepManifest, WARNING_CONCURRENT_WRITE, WARNING_INCOHERENT_CAPTURE,
+};
+use crate::preimage::{
+    PreimageMetadata, append_range_patch, capture_creation_marker, capture_hard_link_alias,
+    capture_metadata_preimage, capture_postimage, capture_preimage_cached, capture_range_preimage,
+    file_id, path_hash, promote_metadata_preimage, promote_range_preimage, read_preimage_metadata,
+};
 use crate::resource_limits;
 use crate::rollback;
-use crate::safeguard::{SafeguardHandler, SafeguardTracker};
+use crate::rollback_journal;
+use crate::safeguard::{BudgetOverrun, SafeguardHandler, SafeguardTracker};
+use crate::squash;
+use crate::step_attribution;
 use crate::write_interceptor::WriteInterceptor;
 
 /// The current on-disk format version. Compared against the `version` file
 /// inside the undo directory on startup.
 const CURRENT_VERSION: &str = "1";
 
+/// Extension of a step directory being deleted, out of the history.
+const REMOVING_EXTENSION: &str = "removing";
+
+/// Extension of the merged step a squash builds next to the steps it replaces.
+const SQUASHING_EXTENSION: &str = "squashing";
+
 /// A single barrier entry stored in a step's `barriers.json` file.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct BarrierEntry {
@@ -46,7 +80,7 @@ pub(crate) fn read_step_barriers(step_dir: &Path) -> Vec<BarrierEntry> {
 }
 
 /// Write barrier entries to a step directory's `barriers.json`.
-fn write_step_barriers(step_dir: &Path, entries: &[BarrierEntry]) -> Result<()> {
+pub(crate) fn write_step_barriers(step_dir: &Path, entries: &[BarrierEntry]) -> Result<()> {
     let path = step_dir.join("barriers.json");
     let json = serde_json::to_string_pretty(entries)?;
     fs::write(&path, json)?;
@@ -91,12 +125,113 @@ fn load_barriers_for_steps(steps_dir: &Path, step_ids: &[StepId]) -> Vec<Barrier
 /// ```
 #[derive(Default)]
 pub struct UndoConfig {
-    pub policy: ExternalModificationPolicy,
+    /// Per-path policies for modifications made outside the sandbox.
+    pub external_modification: ExternalModificationConfig,
     pub safeguard_config: SafeguardConfig,
     pub safeguard_handler: Option<Box<dyn SafeguardHandler>>,
     pub resource_limits: ResourceLimitsConfig,
     pub symlink_policy: SymlinkPolicy,
     pub gitignore: bool,
+    /// Glob → strategy rules for capturing files that may be mid-transaction.
+    pub coherent_capture: CoherentCaptureConfig,
+    /// Whether the working root compares paths case-insensitively; probed
+    /// by default.
+    pub case_sensitivity: CaseSensitivity,
+}
+
+/// Chained construction of an [`UndoInterceptor`], for callers that pick
+/// options one at a time. Each setter fills in the matching [`UndoConfig`]
+/// field; options not set keep their defaults.
+/// ```ignore
+/// let interceptor = UndoInterceptor::builder(root, dir)
+///     .gitignore(true)
+///     .safeguards(config, Box::new(handler))
+///     .resource_limits(limits)
+///     .build();
+/// ```
+#[must_use = "the interceptor is only created by build()"]
+pub struct UndoInterceptorBuilder {
+    working_root: PathBuf,
+    undo_dir: PathBuf,
+    config: UndoConfig,
+}
+
+impl UndoInterceptorBuilder {
+    pub fn external_modification(mut self, config: ExternalModificationConfig) -> Self {
+        self.config.external_modification = config;
+        self
+    }
+
+    /// Check `config`'s thresholds, asking `handler` when one is crossed.
+    pub fn safeguards(
+        mut self,
+        config: SafeguardConfig,
+        handler: Box<dyn SafeguardHandler>,
+    ) -> Self {
+        self.config.safeguard_config = config;
+        self.config.safeguard_handler = Some(handler);
+        self
+    }
+
+    pub fn resource_limits(mut self, limits: ResourceLimitsConfig) -> Self {
+        self.config.resource_limits = limits;
+        self
+    }
+
+    pub fn symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.config.symlink_policy = policy;
+        self
+    }
+
+    pub fn gitignore(mut self, respect: bool) -> Self {
+        self.config.gitignore = respect;
+        self
+    }
+
+    pub fn coherent_capture(mut self, config: CoherentCaptureConfig) -> Self {
+        self.config.coherent_capture = config;
+        self
+    }
+
+    pub fn case_sensitivity(mut self, case_sensitivity: CaseSensitivity) -> Self {
+        self.config.case_sensitivity = case_sensitivity;
+        self
+    }
+
+    pub fn build(self) -> UndoInterceptor {
+        UndoInterceptor::new(self.working_root, self.undo_dir, self.config)
+    }
+}
+
+/// Wait-time counters for [`UndoInterceptor::open_step_when_free`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StepWaitStats {
+    /// Steps opened through `open_step_when_free`.
+    pub acquisitions: u64,
+    /// Acquisitions that had to wait for another step to close first.
+    pub contended: u64,
+    /// Waits that gave up when the timeout elapsed.
+    pub timeouts: u64,
+    /// Total time spent waiting, including timed-out waits.
+    pub total_wait_ms: u64,
+    /// Longest single wait.
+    pub max_wait_ms: u64,
+}
+
+impl StepWaitStats {
+    fn record(&mut self, waited: Duration, contended: bool, timed_out: bool) {
+        if timed_out {
+            self.timeouts += 1;
+        } else {
+            self.acquisitions += 1;
+            self.contended += u64::from(contended);
+        }
+        if contended {
+            let waited_ms = waited.as_millis() as u64;
+            self.total_wait_ms += waited_ms;
+            self.max_wait_ms = self.max_wait_ms.max(waited_ms);
+        }
+    }
 }
 
 /// Information about a crash recovery that was performed on startup.
@@ -108,16 +243,61 @@ pub struct RecoveryInfo {
     pub paths_deleted: usize,
     /// Whether the manifest was present and parseable.
     pub manifest_valid: bool,
+    /// Step rollbacks cut short by the crash and reverted from their
+    /// journals; their paths count in `paths_restored`.
+    pub rollbacks_reverted: usize,
+    /// Steps rolled back to complete a rollback the crash interrupted.
+    pub steps_rolled_back: usize,
+    /// Steps removed to complete an eviction the crash interrupted.
+    pub steps_evicted: usize,
+    /// Steps merged away to complete a squash the crash interrupted.
+    pub steps_squashed: usize,
+}
+
+impl RecoveryInfo {
+    fn empty() -> Self {
+        Self {
+            paths_restored: 0,
+            paths_deleted: 0,
+            manifest_valid: true,
+            rollbacks_reverted: 0,
+            steps_rolled_back: 0,
+            steps_evicted: 0,
+            steps_squashed: 0,
+        }
+    }
+
+    fn add(self, other: RecoveryInfo) -> Self {
+        Self {
+            paths_restored: self.paths_restored + other.paths_restored,
+            paths_deleted: self.paths_deleted + other.paths_deleted,
+            manifest_valid: self.manifest_valid && other.manifest_valid,
+            rollbacks_reverted: self.rollbacks_reverted + other.rollbacks_reverted,
+            steps_rolled_back: self.steps_rolled_back + other.steps_rolled_back,
+            steps_evicted: self.steps_evicted + other.steps_evicted,
+            steps_squashed: self.steps_squashed + other.steps_squashed,
+        }
+    }
 }
 
 pub struct UndoInterceptor {
     working_root: PathBuf,
     undo_dir: PathBuf,
-    policy: ExternalModificationPolicy,
+    external_modification: Mutex<ExternalModificationMatcher>,
     resource_limits: Mutex<ResourceLimitsConfig>,
-    safeguard_handler: Option<Box<dyn SafeguardHandler>>,
-    symlink_policy: SymlinkPolicy,
-    gitignore_filter: Option<Gitignore>,
+    safeguard_handler: Mutex<Option<Arc<dyn SafeguardHandler>>>,
+    symlink_policy: Mutex<SymlinkPolicy>,
+    boundary: WorkingRootBoundary,
+    /// Paths under the working root compare case-insensitively.
+    case_insensitive: bool,
+    /// Ignore files (when gitignore filtering is on) and extra patterns.
+    gitignore_filter: GitignoreFilter,
+    /// Shared so a capture reads under the rules it started with while
+    /// they are replaced.
+    coherent_capture: Mutex<Arc<CoherentCaptureMatcher>>,
+    /// Compressed contents of recent full captures, reused when a later
+    /// capture finds the same contents.
+    blob_cache: PreimageBlobCache,
     /// When true, undo operations are disabled due to a version mismatch.
     undo_disabled: Mutex<bool>,
     /// (expected, found) version strings when a mismatch is detected.
@@ -125,24 +305,198 @@ pub struct UndoInterceptor {
     /// Counter for assigning sequential step IDs at close time, so that
     /// read-only commands (empty steps) don't create gaps in numbering.
     next_step_id: Mutex<StepId>,
+    /// In-memory copy of `chain_head.json`. Locked before `inner` whenever
+    /// both are held.
+    chain: Mutex<ChainHead>,
     inner: Mutex<UndoInterceptorInner>,
+    /// Signalled (with `inner`) whenever a step finishes closing.
+    step_freed: Condvar,
+    /// First captures whose I/O runs with `inner` released.
+    capture_slots: CaptureSlots,
+    /// Signalled (with `inner`) whenever such a capture finishes.
+    capture_finished: Condvar,
+    step_wait_stats: Mutex<StepWaitStats>,
+    /// Commits closed steps to git when set (see [`crate::git_mirror`]).
+    #[cfg(feature = "git-mirror")]
+    git_mirror: Mutex<Option<GitMirror>>,
 }
 
 struct UndoInterceptorInner {
-    /// The currently active (in-progress) step, if any.
-    active_step: Option<StepId>,
+    /// Steps in progress, oldest first. Only steps opened with
+    /// `open_concurrent_step` are ever open together.
+    open_steps: Vec<OpenStep>,
+    /// Steps that are no longer open but whose WAL is still being promoted
+    /// or rolled back. Waiters must not take the slot, nor new steps the
+    /// unsuffixed WAL, until they clear.
+    finalizing_steps: Vec<StepId>,
     /// Completed step IDs in chronological order.
     completed_steps: Vec<StepId>,
-    /// Relative paths already captured in the current step (first-touch guard).
-    touched_paths: HashSet<String>,
-    /// Manifest for the current in-progress step.
-    current_manifest: Option<StepManifest>,
-    /// Per-step safeguard counter and threshold tracker.
+    /// History ID of each non-empty step closed in this session, keyed by
+    /// the ID it was opened with.
+    history_ids: HashMap<StepId, StepId>,
+    /// Safeguard counters and thresholds of the open steps.
     safeguard_tracker: SafeguardTracker,
-    /// Cumulative compressed preimage data size for the current step.
-    current_step_data_size: u64,
-    /// Set when the current step exceeds `max_single_step_size_bytes`.
-    step_unprotected: bool,
+}
+
+/// Capture state of one in-progress step.
+struct OpenStep {
+    id: StepId,
+    /// Opened with `open_concurrent_step`, so other such steps may be open.
+    concurrent: bool,
+    /// WAL directory holding the step's preimages and manifest.
+    wal_dir: PathBuf,
+    /// Case-fold the keys below (case-insensitive working root).
+    fold_case: bool,
+    /// Relative paths already captured in this step (first-touch guard),
+    /// keyed by [`OpenStep::touch_key`].
+    touched_paths: HashSet<String>,
+    /// Touched paths whose preimage holds only byte-range patches so far,
+    /// keyed like `touched_paths`.
+    range_captures: HashMap<String, PreimageMetadata>,
+    /// Touched paths whose preimage holds only their attributes so far,
+    /// keyed like `touched_paths`.
+    metadata_captures: HashMap<String, PreimageMetadata>,
+    /// First path captured in this step for each hard-linked inode, keyed
+    /// by `(dev, inode)`.
+    link_primaries: HashMap<(u64, u64), String>,
+    /// Other touched names of inodes in `link_primaries`, mapped to the
+    /// primary path that holds their preimage.
+    link_aliases: HashMap<String, String>,
+    /// Directory renames `pre_rename` left to record once they are done,
+    /// as `(from, to)` relative paths.
+    pending_renames: HashSet<(String, String)>,
+    manifest: StepManifest,
+    /// Cumulative compressed preimage data size.
+    data_size: u64,
+    /// Set when the step exceeds `max_single_step_size_bytes`.
+    unprotected: bool,
+    /// First captures claimed in `capture_slots` and not yet recorded. The
+    /// step is not closed or rolled back until they are.
+    captures_in_flight: usize,
+    /// When the step was opened, for its recorded duration.
+    started_at: Instant,
+}
+
+impl OpenStep {
+    fn new(id: StepId, concurrent: bool, wal_dir: PathBuf, fold_case: bool) -> Self {
+        Self {
+            id,
+            concurrent,
+            wal_dir,
+            fold_case,
+            touched_paths: HashSet::new(),
+            range_captures: HashMap::new(),
+            metadata_captures: HashMap::new(),
+            link_primaries: HashMap::new(),
+            link_aliases: HashMap::new(),
+            pending_renames: HashSet::new(),
+            manifest: StepManifest::new(id),
+            data_size: 0,
+            unprotected: false,
+            captures_in_flight: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    fn preimage_dir(&self) -> PathBuf {
+        self.wal_dir.join("preimages")
+    }
+
+    /// Key of `relative_str` in the per-step path sets: its case-folded
+    /// spelling on a case-insensitive working root, the path itself
+    /// otherwise.
+    fn touch_key(&self, relative_str: &str) -> String {
+        if self.fold_case {
+            path_case::fold(relative_str)
+        } else {
+            relative_str.to_string()
+        }
+    }
+
+    fn is_touched(&self, relative_str: &str) -> bool {
+        self.touched_paths.contains(&self.touch_key(relative_str))
+    }
+
+    fn touch(&mut self, relative_str: &str) {
+        self.touched_paths.insert(self.touch_key(relative_str));
+    }
+
+    /// Record the first capture of `relative_str` in the manifest, with the
+    /// guest process the operation on this thread came from.
+    fn add_entry(&mut self, relative_str: &str, hash: &str, existed_before: bool, file_type: &str) {
+        self.manifest.add_entry(relative_str, hash, existed_before, file_type);
+        self.manifest.set_modified_by(relative_str, step_attribution::attributed_process());
+    }
+
+    /// Key under which the capture state of `relative_str` is kept: the
+    /// primary path for hard-link aliases, the path itself otherwise.
+    fn capture_key(&self, relative_str: &str) -> String {
+        let key = self.touch_key(relative_str);
+        match self.link_aliases.get(&key) {
+            Some(primary) => self.touch_key(primary),
+            None => key,
+        }
+    }
+
+    /// Name `relative_str` is recorded under: its name from before the
+    /// directory renames of this step, `None` if it was created at a name
+    /// one of them vacated (see [`dir_rename`]).
+    fn original_path(&self, relative_str: &str) -> Option<String> {
+        dir_rename::original_path(&self.manifest.renames, relative_str, self.fold_case)
+    }
+
+    /// Primary path of an inode already captured in this step, if `metadata`
+    /// describes another name of it. The current link count is irrelevant:
+    /// it drops as names are removed during the step.
+    fn link_primary_for(&self, metadata: &fs::Metadata) -> Option<String> {
+        file_id(metadata).and_then(|id| self.link_primaries.get(&id).cloned())
+    }
+}
+
+impl UndoInterceptorInner {
+    fn step(&self, id: StepId) -> Option<&OpenStep> {
+        self.open_steps.iter().find(|step| step.id == id)
+    }
+
+    fn step_mut(&mut self, id: StepId) -> Option<&mut OpenStep> {
+        self.open_steps.iter_mut().find(|step| step.id == id)
+    }
+
+    /// Remove `id` from the open steps and mark it finalizing.
+    fn take_step(&mut self, id: StepId) -> Option<OpenStep> {
+        let index = self.open_steps.iter().position(|step| step.id == id)?;
+        let step = self.open_steps.remove(index);
+        self.finalizing_steps.push(id);
+        self.safeguard_tracker.end_step(id);
+        Some(step)
+    }
+
+    /// A step currently holding the slot, open or still finalizing.
+    fn step_holding_slot(&self) -> Option<StepId> {
+        self.open_steps
+            .first()
+            .map(|step| step.id)
+            .or_else(|| self.finalizing_steps.first().copied())
+    }
+
+    /// The open step an operation on this thread is recorded in: the one it
+    /// is attributed to (see [`step_attribution`]), otherwise the oldest.
+    fn target_step(&self) -> Option<StepId> {
+        step_attribution::attributed_step()
+            .filter(|id| self.step(*id).is_some())
+            .or_else(|| self.open_steps.first().map(|step| step.id))
+    }
+
+    /// The other open step that already captured `relative_str`, if any.
+    fn concurrent_toucher(&self, id: StepId, relative_str: &str) -> Option<StepId> {
+        self.open_steps
+            .iter()
+            .find(|step| {
+                step.id != id
+                    && step.original_path(relative_str).is_some_and(|key| step.is_touched(&key))
+            })
+            .map(|step| step.id)
+    }
 }
 
 impl UndoInterceptor {
@@ -156,14 +510,25 @@ impl UndoInterceptor {
         Self::build(working_root, undo_dir, UndoConfig::default())
     }
 
+    /// Start configuring an `UndoInterceptor` option by option.
+    pub fn builder(working_root: PathBuf, undo_dir: PathBuf) -> UndoInterceptorBuilder {
+        UndoInterceptorBuilder {
+            working_root,
+            undo_dir,
+            config: UndoConfig::default(),
+        }
+    }
+
     fn build(working_root: PathBuf, undo_dir: PathBuf, config: UndoConfig) -> Self {
         let UndoConfig {
-            policy,
+            external_modification,
             safeguard_config,
             safeguard_handler,
             resource_limits,
             symlink_policy,
             gitignore: respect_gitignore,
+            coherent_capture,
+            case_sensitivity,
         } = config;
         let mut undo_disabled = false;
         let mut version_mismatch_info = None;
@@ -214,32 +579,51 @@ impl UndoInterceptor {
             migrate_global_barriers(&undo_dir);
         }
 
+        // An unreadable head file keeps failing verification; new steps
+        // start a fresh chain from the genesis hash.
+        let chain_head = ChainHead::read_from(&undo_dir)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+
         let gitignore_filter = if respect_gitignore {
-            build_gitignore(&working_root)
+            GitignoreFilter::build(&working_root)
         } else {
-            None
+            GitignoreFilter::patterns_only(&working_root)
         };
+        let boundary = WorkingRootBoundary::new(&working_root);
+        let case_insensitive = path_case::resolve(case_sensitivity, &working_root);
+        let blob_cache = PreimageBlobCache::new(undo_dir.join("blobs"), DEFAULT_BLOB_CACHE_ENTRIES);
 
         Self {
             working_root,
             undo_dir,
-            policy,
+            external_modification: Mutex::new(ExternalModificationMatcher::new(&external_modification)),
             resource_limits: Mutex::new(resource_limits),
-            safeguard_handler,
-            symlink_policy,
+            safeguard_handler: Mutex::new(safeguard_handler.map(Arc::from)),
+            symlink_policy: Mutex::new(symlink_policy),
+            boundary,
+            case_insensitive,
             gitignore_filter,
+            coherent_capture: Mutex::new(Arc::new(CoherentCaptureMatcher::new(&coherent_capture))),
+            blob_cache,
             undo_disabled: Mutex::new(undo_disabled),
             version_mismatch_info: Mutex::new(version_mismatch_info),
             next_step_id: Mutex::new(max_step_id + 1),
+            chain: Mutex::new(chain_head),
             inner: Mutex::new(UndoInterceptorInner {
-                active_step: None,
+                open_steps: Vec::new(),
+                finalizing_steps: Vec::new(),
                 completed_steps,
-                touched_paths: HashSet::new(),
-                current_manifest: None,
+                history_ids: HashMap::new(),
                 safeguard_tracker: SafeguardTracker::new(safeguard_config),
-                current_step_data_size: 0,
-                step_unprotected: false,
             }),
+            step_freed: Condvar::new(),
+            capture_slots: CaptureSlots::default(),
+            capture_finished: Condvar::new(),
+            step_wait_stats: Mutex::new(StepWaitStats::default()),
+            #[cfg(feature = "git-mirror")]
+            git_mirror: Mutex::new(None),
         }
     }
 
@@ -258,41 +642,158 @@ impl UndoInterceptor {
     }
 
     /// Open a new undo step.
+    ///
+    /// Fails immediately with `StepAlreadyActive` if another step is open.
+    /// Use [`open_step_when_free`](Self::open_step_when_free) to wait instead.
     pub fn open_step(&self, id: StepId) -> Result<()> {
         self.check_undo_enabled()?;
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(active) = inner.open_steps.first() {
+            return Err(CodeAgentError::StepAlreadyActive { step_id: active.id });
+        }
+        self.begin_step(&mut inner, id, false)
+    }
+
+    /// Open a command step alongside any other command steps opened this
+    /// way. Each gets its own WAL; operations are recorded in the step they
+    /// are attributed to (see [`step_attribution`]), or in the oldest open
+    /// step.
+    ///
+    /// Fails with `StepAlreadyActive` if `id` is already open or a step
+    /// opened with [`open_step`](Self::open_step) holds the slot.
+    pub fn open_concurrent_step(&self, id: StepId) -> Result<()> {
+        self.check_undo_enabled()?;
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(holder) = inner
+            .open_steps
+            .iter()
+            .find(|step| step.id == id || !step.concurrent)
+        {
+            return Err(CodeAgentError::StepAlreadyActive { step_id: holder.id });
+        }
+        self.begin_step(&mut inner, id, true)
+    }
+
+    /// Open a new undo step, waiting up to `timeout` for the current step
+    /// (command, ambient, or another API step) to close first.
+    ///
+    /// Blocks the calling thread. Returns how long the call waited. Asking
+    /// for the step that is already active fails immediately rather than
+    /// waiting on itself; otherwise the wait is bounded by `timeout` and ends
+    /// with `StepWaitTimeout`.
+    pub fn open_step_when_free(&self, id: StepId, timeout: Duration) -> Result<Duration> {
+        self.check_undo_enabled()?;
+        let start = Instant::now();
+        let mut contended = false;
+        let mut inner = self.inner.lock().unwrap();
+        while let Some(holder) = inner.step_holding_slot() {
+            if inner.step(id).is_some() {
+                return Err(CodeAgentError::StepAlreadyActive { step_id: id });
+            }
+            contended = true;
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                drop(inner);
+                let waited = start.elapsed();
+                self.step_wait_stats
+                    .lock()
+                    .unwrap()
+                    .record(waited, contended, true);
+                return Err(CodeAgentError::StepWaitTimeout {
+                    step_id: holder,
+                    waited_ms: waited.as_millis() as u64,
+                });
+            }
+            inner = self.step_freed.wait_timeout(inner, remaining).unwrap().0;
+        }
+        self.begin_step(&mut inner, id, false)?;
+        drop(inner);
+
+        let waited = start.elapsed();
+        self.step_wait_stats
+            .lock()
+            .unwrap()
+            .record(waited, contended, false);
+        Ok(waited)
+    }
 
-        // Create WAL directory BEFORE marking the step as active in the
-        // inner state. If filesystem setup fails, the state remains clean and
-        // subsequent open_step calls won't fail with StepAlreadyActive.
-        let wal_dir = self.wal_in_progress_dir();
+    /// Wait-time counters accumulated by `open_step_when_free`.
+    pub fn step_wait_stats(&self) -> StepWaitStats {
+        *self.step_wait_stats.lock().unwrap()
+    }
+
+    /// Prepare a WAL and open `id`. The caller has checked that the step
+    /// may be opened.
+    ///
+    /// A step opened while no other step is open uses `wal/in_progress`;
+    /// steps opened alongside others use `wal/in_progress.{id}`, so the
+    /// unsuffixed WAL always belongs to the oldest of them. The WAL directory is created BEFORE
+    /// the step is opened. If filesystem setup fails, the state remains
+    /// clean and subsequent open_step calls won't fail with
+    /// StepAlreadyActive.
+    fn begin_step(
+        &self,
+        inner: &mut MutexGuard<'_, UndoInterceptorInner>,
+        id: StepId,
+        concurrent: bool,
+    ) -> Result<()> {
+        let wal_dir = if inner.step_holding_slot().is_some() {
+            self.undo_dir.join("wal").join(format!("in_progress.{id}"))
+        } else {
+            self.wal_in_progress_dir()
+        };
         if wal_dir.exists() {
             fs::remove_dir_all(&wal_dir)?;
         }
         fs::create_dir_all(wal_dir.join("preimages"))?;
 
+        inner.open_steps.push(OpenStep::new(id, concurrent, wal_dir, self.case_insensitive));
+        inner.safeguard_tracker.begin_step(id);
+        metrics::increment(metrics::Counter::StepsOpened);
+
+        Ok(())
+    }
+
+    /// Mark `id` done once its WAL has been promoted or removed, and wake
+    /// any `open_step_when_free` waiters.
+    fn finish_step(&self, id: StepId) {
         let mut inner = self.inner.lock().unwrap();
-        if let Some(active) = inner.active_step {
-            return Err(CodeAgentError::StepAlreadyActive { step_id: active });
+        if let Some(index) = inner.finalizing_steps.iter().position(|step| *step == id) {
+            inner.finalizing_steps.remove(index);
         }
-        inner.active_step = Some(id);
-        inner.touched_paths.clear();
-        inner.current_manifest = Some(StepManifest::new(id));
-        inner.safeguard_tracker.reset();
-        inner.current_step_data_size = 0;
-        inner.step_unprotected = false;
+        drop(inner);
+        self.step_freed.notify_all();
+    }
 
-        Ok(())
+    /// Update the manifest of open step `id`, or of the step operations on
+    /// this thread go to when `id` is `None`.
+    fn update_manifest(&self, id: Option<StepId>, update: impl FnOnce(&mut StepManifest)) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(id) = id.or_else(|| inner.target_step()) else {
+            return;
+        };
+        if let Some(step) = inner.step_mut(id) {
+            update(&mut step.manifest);
+        }
     }
 
     /// Store the command string associated with the current step in the manifest.
     pub fn set_step_command(&self, command: String) {
-        let mut inner = self.inner.lock().unwrap();
-        if let Some(ref mut manifest) = inner.current_manifest {
-            manifest.command = Some(command);
-        }
+        self.update_manifest(None, |manifest| manifest.command = Some(command));
+    }
+
+    /// Store the exit code of the command that ran in the current step.
+    pub fn set_step_exit_code(&self, exit_code: i32) {
+        self.update_manifest(None, |manifest| manifest.exit_code = Some(exit_code));
     }
 
-    /// Close the current step, promoting WAL to steps/.
+    /// Override the step type recorded for the current step. Steps default to
+    /// `Ambient` for negative IDs and `Command` otherwise.
+    pub fn set_step_type(&self, step_type: StepType) {
+        self.update_manifest(None, |manifest| manifest.step_type = Some(step_type));
+    }
+
+    /// Close step `id`, promoting its WAL to steps/.
     ///
     /// Steps that touched no files (read-only commands) are silently discarded:
     /// the WAL is cleaned up, no step ID is consumed, and nothing is persisted.
@@ -304,35 +805,31 @@ impl UndoInterceptor {
     /// the counter).
     ///
     /// Returns the list of step IDs that were evicted due to resource limits.
-    pub fn close_step(&self, _id: StepId) -> Result<Vec<StepId>> {
+    pub fn close_step(&self, id: StepId) -> Result<Vec<StepId>> {
         // Check if the step has any manifest entries (files touched).
         // If empty, discard the step: cancel without adding to completed list,
         // clean up WAL, and don't consume a step ID.
         {
-            let mut inner = self.inner.lock().unwrap();
-            let is_empty = inner
-                .current_manifest
-                .as_ref()
-                .is_none_or(|m| m.entries.is_empty());
-            if is_empty {
-                if inner.active_step.is_none() {
-                    return Err(CodeAgentError::NoActiveStep);
-                }
-                inner.active_step = None;
-                inner.touched_paths.clear();
-                inner.current_manifest = None;
-                inner.current_step_data_size = 0;
-                inner.step_unprotected = false;
+            let mut inner = self.wait_for_captures(self.inner.lock().unwrap(), id);
+            let Some(step) = inner.step(id) else {
+                return Err(CodeAgentError::NoActiveStep);
+            };
+            if step.manifest.entries.is_empty() {
+                let wal_dir = step.wal_dir.clone();
+                inner.take_step(id);
                 drop(inner);
 
-                let wal_dir = self.wal_in_progress_dir();
                 if wal_dir.exists() {
                     let _ = fs::remove_dir_all(&wal_dir);
                 }
+                self.finish_step(id);
+                metrics::increment(metrics::Counter::StepsClosed);
                 return Ok(vec![]);
             }
         }
 
+        self.capture_postimages(id);
+
         let final_id = {
             let mut counter = self.next_step_id.lock().unwrap();
             let allocated = *counter;
@@ -342,36 +839,36 @@ impl UndoInterceptor {
 
         // Update the manifest's step_id to the final ID before writing,
         // then close the active step and record as completed.
-        let completed_steps_snapshot = {
-            let mut inner = self.inner.lock().unwrap();
-            if let Some(ref mut manifest) = inner.current_manifest {
-                manifest.step_id = final_id;
-                let mut manifest_to_write = manifest.clone();
-                if inner.step_unprotected {
-                    manifest_to_write.unprotected = true;
-                }
-                manifest_to_write.write_to(&self.wal_in_progress_dir())?;
+        let mut chain_head = self.chain.lock().unwrap();
+        let (wal_dir, completed_steps_snapshot) = {
+            let mut inner = self.wait_for_captures(self.inner.lock().unwrap(), id);
+            let Some(step) = inner.step_mut(id) else {
+                return Err(CodeAgentError::NoActiveStep);
+            };
+            step.manifest.step_id = final_id;
+            let mut manifest_to_write = step.manifest.clone();
+            manifest_to_write.duration_ms = Some(step.started_at.elapsed().as_millis() as u64);
+            manifest_to_write.preimage_bytes = step.data_size;
+            if step.unprotected {
+                manifest_to_write.unprotected = true;
             }
-            // Close the active step and clear inner state BEFORE filesystem
+            manifest_to_write.chain_prev = Some(chain_head.head.clone());
+            manifest_to_write.write_to(&step.wal_dir)?;
+            let wal_dir = step.wal_dir.clone();
+            // Close the step and clear its state BEFORE filesystem
             // promotion. If fs::rename fails, the step is recorded as completed
             // (so subsequent open_step calls succeed) but missing on disk -- the
             // next session won't find it, which is a harmless loss.
-            if inner.active_step.is_none() {
-                return Err(CodeAgentError::NoActiveStep);
-            }
-            inner.active_step = None;
+            inner.take_step(id);
             inner.completed_steps.push(final_id);
-            inner.touched_paths.clear();
-            inner.current_manifest = None;
-            inner.current_step_data_size = 0;
-            inner.step_unprotected = false;
-            inner.completed_steps.clone()
+            inner.history_ids.insert(id, final_id);
+            (wal_dir, inner.completed_steps.clone())
         };
 
         // Promote WAL to steps/{final_id}/
-        let wal_dir = self.wal_in_progress_dir();
         let steps_parent = self.undo_dir.join("steps");
         let step_dir = self.step_dir(final_id);
+        let manifest_hash = chain::manifest_hash(&wal_dir);
         if wal_dir.exists() {
             // Ensure parent directory exists (may have been removed externally).
             if let Err(error) = fs::create_dir_all(&steps_parent) {
@@ -387,12 +884,22 @@ impl UndoInterceptor {
                     );
                 }
             }
-            if let Err(error) = fs::rename(&wal_dir, &step_dir) {
-                eprintln!(
+            match (fs::rename(&wal_dir, &step_dir), manifest_hash) {
+                (Err(error), _) => eprintln!(
                     "{{\"level\":\"error\",\"component\":\"undo\",\"message\":\"failed to promote WAL to step {final_id}: {error}\"}}",
-                );
+                ),
+                (Ok(()), Ok(hash)) => {
+                    chain_head.advance(final_id, hash);
+                    self.store_chain_head(&chain_head);
+                }
+                (Ok(()), Err(_)) => {}
             }
         }
+        drop(chain_head);
+        self.finish_step(id);
+        metrics::increment(metrics::Counter::StepsClosed);
+        #[cfg(feature = "git-mirror")]
+        self.mirror_step(&step_dir);
 
         // Run eviction after step promotion
         let evicted = self.evict_if_needed(&completed_steps_snapshot)?;
@@ -406,8 +913,60 @@ impl UndoInterceptor {
     /// and the target, the rollback is rejected with `RollbackBlocked`.
     /// If `force` is true, barriers are crossed and removed.
     /// If any step in the rollback range is unprotected, returns `StepUnprotected`.
+    /// Rolls back fewer than `count` steps when the history is shorter.
     pub fn rollback(&self, count: usize, force: bool) -> Result<RollbackResult> {
+        self.rollback_steps(count, force, false, RollbackMode::Restore, &Unmonitored)
+    }
+
+    /// Like [`rollback`](Self::rollback), but fails with
+    /// `InsufficientHistory` instead of rolling back fewer than `count` steps.
+    pub fn rollback_strict(&self, count: usize, force: bool) -> Result<RollbackResult> {
+        self.rollback_steps(count, force, true, RollbackMode::Restore, &Unmonitored)
+    }
+
+    /// Roll back with an explicit [`RollbackMode`]. `Merge` keeps edits made
+    /// to text files after a step closed (see
+    /// [`rollback::rollback_step_merging`]); barriers still need `force`.
+    pub fn rollback_with_mode(
+        &self,
+        count: usize,
+        force: bool,
+        strict: bool,
+        mode: RollbackMode,
+    ) -> Result<RollbackResult> {
+        self.rollback_steps(count, force, strict, mode, &Unmonitored)
+    }
+
+    /// Like [`rollback_with_mode`](Self::rollback_with_mode), reporting
+    /// progress to `monitor` file by file. A cancelled rollback stops before
+    /// the next step and returns the steps rolled back so far, with
+    /// `cancelled` set.
+    pub fn rollback_monitored(
+        &self,
+        count: usize,
+        force: bool,
+        strict: bool,
+        mode: RollbackMode,
+        monitor: &dyn OperationMonitor,
+    ) -> Result<RollbackResult> {
+        self.rollback_steps(count, force, strict, mode, monitor)
+    }
+
+    fn rollback_steps(
+        &self,
+        count: usize,
+        force: bool,
+        strict: bool,
+        mode: RollbackMode,
+        monitor: &dyn OperationMonitor,
+    ) -> Result<RollbackResult> {
         let completed = self.inner.lock().unwrap().completed_steps.clone();
+        if strict && completed.len() < count {
+            return Err(CodeAgentError::InsufficientHistory {
+                requested: count,
+                available: completed.len(),
+            });
+        }
         let steps_to_rollback: Vec<StepId> =
             completed.iter().rev().take(count).copied().collect();
 
@@ -435,133 +994,681 @@ impl UndoInterceptor {
         }
 
         // Perform the rollback (inner lock is NOT held during filesystem I/O).
-        // fs::remove_dir_all deletes the step dir including any barriers.json.
-        for step_id in &steps_to_rollback {
-            let step_dir = self.step_dir(*step_id);
-            if step_dir.exists() {
-                rollback::rollback_step(&step_dir, &self.working_root, self.symlink_policy)?;
-                fs::remove_dir_all(&step_dir)?;
-            }
-        }
+        // The marker lists the steps so that a crash midway is completed at
+        // the next startup.
+        let marker = RollbackMarker {
+            mode,
+            steps: steps_to_rollback
+                .iter()
+                .map(|&step_id| JournaledStep {
+                    step_id,
+                    link: chain::read_link(&self.step_dir(step_id)),
+                })
+                .collect(),
+        };
+        history_journal::write(&self.undo_dir, ROLLBACK_MARKER_FILE, &marker)?;
+        let mut rolled_back: Vec<StepId> = Vec::with_capacity(steps_to_rollback.len());
+        let outcome = self.roll_back_step_dirs(
+            &steps_to_rollback,
+            &completed,
+            mode,
+            monitor,
+            &mut rolled_back,
+        );
 
         // Batch-remove rolled-back steps from the in-memory list
         {
             let mut inner = self.inner.lock().unwrap();
-            inner
-                .completed_steps
-                .retain(|s| !steps_to_rollback.contains(s));
+            inner.completed_steps.retain(|s| !rolled_back.contains(s));
+            inner.history_ids.retain(|_, final_id| !rolled_back.contains(final_id));
         }
-
+        // A step that failed to roll back was reverted and stays; so do the
+        // steps after it.
+        history_journal::remove(&self.undo_dir, ROLLBACK_MARKER_FILE)?;
+        let merged = outcome?;
+        let total = steps_to_rollback.len();
+
+        if !rolled_back.is_empty() {
+            metrics::increment(metrics::Counter::Rollbacks);
+            metrics::add(metrics::Counter::StepsRolledBack, rolled_back.len() as u64);
+        }
+        let cancelled = rolled_back.len() < total;
+        let mut blocking = blocking;
+        blocking.retain(|barrier| rolled_back.contains(&barrier.after_step_id));
         Ok(RollbackResult {
-            steps_rolled_back: steps_to_rollback.len(),
+            steps_requested: count,
+            steps_rolled_back: rolled_back.len(),
+            rolled_back_step_ids: rolled_back,
             barriers_crossed: blocking,
+            merged,
+            cancelled,
         })
     }
 
-    /// Get the list of completed step IDs.
-    pub fn completed_steps(&self) -> Vec<StepId> {
-        self.inner.lock().unwrap().completed_steps.clone()
-    }
-
-    /// Record an external modification, optionally creating an undo barrier.
-    ///
-    /// Under `Barrier` policy, creates a barrier and returns it.
-    /// Under `Warn` policy, returns `None` (no barrier created).
-    pub fn notify_external_modification(
+    /// Roll back `steps`, newest first, removing each step directory and
+    /// rewinding the chain head past it; `completed` is the history before
+    /// the rollback. Stops before the next step if `monitor` is cancelled.
+    /// Steps rolled back are pushed to `rolled_back`, also on error.
+    fn roll_back_step_dirs(
         &self,
-        affected_paths: Vec<AffectedPath>,
-        reason: BarrierReason,
-    ) -> Result<Option<BarrierInfo>> {
-        match self.policy {
-            ExternalModificationPolicy::Barrier => {
-                let completed = self.inner.lock().unwrap().completed_steps.clone();
-                // Use step 0 as sentinel when no steps exist yet, so
-                // pre-step host modifications are still visible in history.
-                let after_step_id = completed.last().copied().unwrap_or(0);
-
-                let step_dir = self.step_dir(after_step_id);
-                // Ensure the step directory exists (step 0 is never
-                // opened via open_step, so its directory may not exist).
-                if !step_dir.exists() {
-                    fs::create_dir_all(&step_dir)?;
-                }
-                let mut entries = read_step_barriers(&step_dir);
-
-                // Merge into the last barrier if it has the same reason.
-                // This coalesces watcher ticks between the same VM steps into
-                // one barrier instead of creating a separate barrier per tick.
-                if let Some(last) = entries.last_mut() {
-                    if last.reason == reason {
-                        for ap in &affected_paths {
-                            if !last.affected_paths.iter().any(|existing| existing.path == ap.path) {
-                                last.affected_paths.push(ap.clone());
-                            }
-                        }
-                        last.timestamp = Utc::now();
-                        write_step_barriers(&step_dir, &entries)?;
-
-                        let index = entries.len() - 1;
-                        return Ok(Some(BarrierInfo {
-                            barrier_id: synthesize_barrier_id(after_step_id, index),
-                            after_step_id,
-                            timestamp: entries[index].timestamp,
-                            affected_paths: entries[index].affected_paths.clone(),
-                            reason,
-                        }));
+        steps: &[StepId],
+        completed: &[StepId],
+        mode: RollbackMode,
+        monitor: &dyn OperationMonitor,
+        rolled_back: &mut Vec<StepId>,
+    ) -> Result<Vec<MergedPath>> {
+        let mut chain_head = self.chain.lock().unwrap();
+        let mut merged: Vec<MergedPath> = Vec::new();
+        let total = steps.len();
+        for (index, step_id) in steps.iter().enumerate() {
+            if monitor.is_cancelled() {
+                break;
+            }
+            let step_dir = self.step_dir(*step_id);
+            if step_dir.exists() {
+                let mut on_file = |done: usize, files: usize, path: &str| {
+                    let percent = percent_of(index * files + done, total * files);
+                    monitor.progress(percent, Some(path));
+                };
+                let step_merged = rollback::rollback_step_with(
+                    &step_dir,
+                    &self.working_root,
+                    self.symlink_policy(),
+                    mode == RollbackMode::Merge,
+                    &mut on_file,
+                )?;
+                // A file merged again for an older step is listed once.
+                for file in step_merged {
+                    match merged.iter_mut().find(|m| m.path == file.path) {
+                        Some(existing) => existing.conflicts += file.conflicts,
+                        None => merged.push(file),
                     }
                 }
+                let link = chain::read_link(&step_dir);
+                self.remove_step_dir(&step_dir)?;
+                if let Some(link) = link {
+                    let previous_step =
+                        completed.iter().take_while(|id| *id != step_id).last().copied();
+                    chain_head.rewind(&link, previous_step);
+                    self.store_chain_head(&chain_head);
+                }
+            }
+            rolled_back.push(*step_id);
+            monitor.progress(percent_of(index + 1, total), None);
+        }
+        Ok(merged)
+    }
 
-                let index = entries.len();
-                entries.push(BarrierEntry {
-                    timestamp: Utc::now(),
-                    affected_paths: affected_paths.clone(),
-                    reason,
-                });
-                write_step_barriers(&step_dir, &entries)?;
+    /// Remove a step directory, barriers.json included. It is renamed out
+    /// of the history first, so a crash while deleting it cannot leave a
+    /// partial step behind; recovery deletes what remains.
+    fn remove_step_dir(&self, step_dir: &Path) -> Result<()> {
+        let removing = step_dir.with_extension(REMOVING_EXTENSION);
+        fs::rename(step_dir, &removing)?;
+        fs::remove_dir_all(&removing)?;
+        Ok(())
+    }
 
-                let barrier = BarrierInfo {
-                    barrier_id: synthesize_barrier_id(after_step_id, index),
-                    after_step_id,
-                    timestamp: entries[index].timestamp,
-                    affected_paths,
-                    reason,
-                };
-                Ok(Some(barrier))
+    /// Merge the completed steps from `from` to `to` into one step with the
+    /// id of `from`, keeping the oldest preimage of each path (see
+    /// [`squash`]). Rolling back the merged step undoes all of them.
+    ///
+    /// Fails with `InvalidStepRange` unless both ends are in the history
+    /// and `from` comes first, and with `StepUnprotected` if one of the steps
+    /// cannot be rolled back. The manifests of the steps after the range are
+    /// re-linked into the hash chain.
+    pub fn squash(&self, from: StepId, to: StepId) -> Result<SquashResult> {
+        self.check_undo_enabled()?;
+        // Holding the chain keeps steps from closing, rolling back or being
+        // evicted meanwhile.
+        let mut chain_head = self.chain.lock().unwrap();
+        let completed = self.completed_steps();
+        if from > to || !completed.contains(&from) || !completed.contains(&to) {
+            return Err(CodeAgentError::InvalidStepRange { from, to });
+        }
+        let steps: Vec<StepId> =
+            completed.iter().copied().filter(|id| (from..=to).contains(id)).collect();
+        let step_dirs: Vec<PathBuf> = steps.iter().map(|&id| self.step_dir(id)).collect();
+        for (step_id, step_dir) in steps.iter().zip(&step_dirs) {
+            if StepManifest::read_from(step_dir)?.unprotected {
+                return Err(CodeAgentError::StepUnprotected { step_id: *step_id });
             }
-            ExternalModificationPolicy::Warn => Ok(None),
         }
-    }
+        let mut result = SquashResult {
+            step_id: from,
+            squashed_step_ids: steps.clone(),
+            entries_dropped: 0,
+            bytes_reclaimed: 0,
+        };
+        if steps.len() < 2 {
+            return Ok(result);
+        }
 
-    /// Return all current undo barriers.
-    pub fn barriers(&self) -> Vec<BarrierInfo> {
-        let completed = self.inner.lock().unwrap().completed_steps.clone();
         let steps_dir = self.undo_dir.join("steps");
-        let mut barriers = Vec::new();
-        // Include pre-step barriers (step 0 sentinel) if any exist.
-        let step_0_dir = steps_dir.join("0");
-        if step_0_dir.exists() {
-            for (i, entry) in read_step_barriers(&step_0_dir).into_iter().enumerate() {
-                barriers.push(BarrierInfo {
-                    barrier_id: synthesize_barrier_id(0, i),
-                    after_step_id: 0,
-                    timestamp: entry.timestamp,
-                    affected_paths: entry.affected_paths,
-                    reason: entry.reason,
-                });
+        let size_before = resource_limits::calculate_total_log_size(&steps_dir, &steps)?;
+        let staging = self.step_dir(from).with_extension(SQUASHING_EXTENSION);
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        match squash::build_squashed_step(&step_dirs, &staging, self.case_insensitive) {
+            Ok(dropped) => result.entries_dropped = dropped,
+            Err(error) => {
+                let _ = fs::remove_dir_all(&staging);
+                return Err(error);
             }
         }
-        barriers.extend(load_barriers_for_steps(&steps_dir, &completed));
-        barriers
+        history_journal::write(
+            &self.undo_dir,
+            SQUASH_JOURNAL_FILE,
+            &SquashJournal { steps: steps.clone() },
+        )?;
+        self.finish_squash(&steps, &mut chain_head)?;
+        history_journal::remove(&self.undo_dir, SQUASH_JOURNAL_FILE)?;
+
+        let size_after = resource_limits::calculate_step_size(&self.step_dir(from))?;
+        result.bytes_reclaimed = size_before.saturating_sub(size_after);
+        Ok(result)
     }
 
-    /// Whether undo is disabled due to a version mismatch.
-    pub fn is_undo_disabled(&self) -> bool {
-        *self.undo_disabled.lock().unwrap()
+    /// Swap the merged step staged for `steps` in for them and re-link the
+    /// chain from it on. Safe to run again over a swap already done.
+    fn finish_squash(&self, steps: &[StepId], chain_head: &mut ChainHead) -> Result<()> {
+        let target = self.step_dir(steps[0]);
+        let staging = target.with_extension(SQUASHING_EXTENSION);
+        if staging.exists() {
+            for &step_id in steps {
+                let step_dir = self.step_dir(step_id);
+                if step_dir.exists() {
+                    self.remove_step_dir(&step_dir)?;
+                }
+            }
+            fs::rename(&staging, &target)?;
+        }
+        let relinked = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.completed_steps.retain(|id| !steps[1..].contains(id));
+            for final_id in inner.history_ids.values_mut() {
+                if steps.contains(final_id) {
+                    *final_id = steps[0];
+                }
+            }
+            inner
+                .completed_steps
+                .iter()
+                .copied()
+                .filter(|id| *id >= steps[0])
+                .collect::<Vec<_>>()
+        };
+        self.relink_chain(&relinked, chain_head)
     }
 
-    /// Returns the version mismatch info if undo is disabled, as (expected, found).
-    pub fn version_mismatch(&self) -> Option<(String, String)> {
-        self.version_mismatch_info.lock().unwrap().clone()
+    /// Point the chained manifests of `steps` (oldest first) at the hash of
+    /// the chained step before them, the first one staying as it is, and
+    /// move the chain head to the last.
+    fn relink_chain(&self, steps: &[StepId], chain_head: &mut ChainHead) -> Result<()> {
+        let mut prev: Option<(StepId, String)> = None;
+        for &step_id in steps {
+            let step_dir = self.step_dir(step_id);
+            let mut manifest = StepManifest::read_from(&step_dir)?;
+            if manifest.chain_prev.is_none() {
+                continue;
+            }
+            if let Some((_, hash)) = prev {
+                manifest.chain_prev = Some(hash);
+                manifest.write_to(&step_dir)?;
+            }
+            prev = Some((step_id, chain::manifest_hash(&step_dir)?));
+        }
+        if let Some((step_id, hash)) = prev {
+            chain_head.advance(step_id, hash);
+            self.store_chain_head(chain_head);
+        }
+        Ok(())
+    }
+
+    /// Recompress the data files of closed steps at `options.level`, link
+    /// duplicate blobs together and check every stored checksum (see
+    /// [`compaction`]). The newest `options.keep_recent_steps` steps and
+    /// those compacted at that level already are left alone.
+    ///
+    /// Each data file is compacted while holding the chain, so a step
+    /// closing, rolling back or being evicted waits for one file at most.
+    /// Stops between files once `monitor` is cancelled, reporting `paused`;
+    /// the next run starts the step it stopped in over.
+    pub fn compact(
+        &self,
+        options: &CompactionOptions,
+        monitor: &dyn OperationMonitor,
+    ) -> Result<CompactionReport> {
+        self.check_undo_enabled()?;
+        let completed = self.completed_steps();
+        let old_steps = &completed[..completed.len().saturating_sub(options.keep_recent_steps)];
+        let mut index = BlobIndex::default();
+        let mut pending = Vec::new();
+        for &step_id in old_steps {
+            let step_dir = self.step_dir(step_id);
+            match CompactedStep::read_from(&step_dir) {
+                Some(compacted) if compacted.level >= options.level => {
+                    index.add_step(&step_dir, &compacted);
+                }
+                _ => {
+                    let hashes = compaction::preimage_hashes(&step_dir.join("preimages"));
+                    pending.push((step_id, hashes));
+                }
+            }
+        }
+
+        let mut report = CompactionReport::default();
+        let total: usize = pending.iter().map(|(_, hashes)| hashes.len()).sum();
+        let mut done = 0;
+        for (step_id, hashes) in pending {
+            let step_dir = self.step_dir(step_id);
+            let preimage_dir = step_dir.join("preimages");
+            // A squash meanwhile replaces the directory under the same id.
+            let dir_id = rollback::file_id(&step_dir);
+            compaction::remove_temp_files(&preimage_dir);
+            let mut compacted = CompactedStep {
+                level: options.level,
+                ..CompactedStep::default()
+            };
+            for hash in hashes {
+                if monitor.is_cancelled() {
+                    report.paused = true;
+                    return Ok(report);
+                }
+                let _chain = self.chain.lock().unwrap();
+                if !self.completed_steps().contains(&step_id) {
+                    break;
+                }
+                let Ok(meta) = read_preimage_metadata(&preimage_dir, &hash) else {
+                    continue;
+                };
+                monitor.progress(percent_of(done, total), Some(&meta.relative_path));
+                done += 1;
+                for (path, expected) in compaction::data_files(&preimage_dir, &hash, &meta) {
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    let outcome = compaction::compact_data_file(
+                        &path,
+                        expected.as_deref(),
+                        options.level,
+                        &mut index,
+                    )?;
+                    match outcome {
+                        FileCompaction::Recompressed { hash, saved } => {
+                            report.files_recompressed += 1;
+                            report.bytes_saved += saved;
+                            compacted.blobs.insert(name, hash);
+                        }
+                        FileCompaction::Deduplicated { hash, saved } => {
+                            report.blobs_deduplicated += 1;
+                            report.bytes_saved += saved;
+                            compacted.blobs.insert(name, hash);
+                        }
+                        FileCompaction::Corrupt => {
+                            eprintln!(
+                                "{{\"level\":\"error\",\"component\":\"undo\",\"message\":\"step {step_id}: {name} of {} fails its checksum\"}}",
+                                meta.relative_path
+                            );
+                            if !report.corrupt_paths.contains(&meta.relative_path) {
+                                report.corrupt_paths.push(meta.relative_path.clone());
+                            }
+                        }
+                    }
+                }
+            }
+            let _chain = self.chain.lock().unwrap();
+            if self.completed_steps().contains(&step_id) && rollback::file_id(&step_dir) == dir_id {
+                compacted.write_to(&step_dir)?;
+                report.steps_compacted += 1;
+            }
+        }
+        monitor.progress(100, None);
+        Ok(report)
+    }
+
+    /// Bytes of the completed steps on disk.
+    pub fn undo_log_size(&self) -> Result<u64> {
+        let completed = self.completed_steps();
+        resource_limits::calculate_total_log_size(&self.undo_dir.join("steps"), &completed)
+    }
+
+    /// Get the list of completed step IDs.
+    pub fn completed_steps(&self) -> Vec<StepId> {
+        self.inner.lock().unwrap().completed_steps.clone()
+    }
+
+    /// IDs of the steps currently open, oldest first.
+    pub fn open_step_ids(&self) -> Vec<StepId> {
+        let inner = self.inner.lock().unwrap();
+        inner.open_steps.iter().map(|step| step.id).collect()
+    }
+
+    /// The ID under which the step opened as `id` was stored in the history.
+    /// `None` if it was empty, is still open, has left the history (rolled
+    /// back or evicted), or was opened in an earlier session.
+    pub fn history_step_id(&self, id: StepId) -> Option<StepId> {
+        self.inner.lock().unwrap().history_ids.get(&id).copied()
+    }
+
+    /// Like [`completed_steps`](Self::completed_steps), but with the metadata
+    /// recorded in each step's manifest (oldest first). Steps whose manifest
+    /// cannot be read are left out.
+    pub fn completed_step_info(&self) -> Vec<StepInfo> {
+        self.completed_steps()
+            .into_iter()
+            .filter_map(|id| StepManifest::read_from(&self.step_dir(id)).ok())
+            .map(|manifest| manifest.step_info())
+            .collect()
+    }
+
+    /// Record an external modification, optionally creating an undo barrier.
+    ///
+    /// Each affected path is classified by the external modification rules.
+    /// Paths under `Barrier` policy are recorded in a barrier, which is
+    /// returned; if there are none, returns `None`. An empty path list (e.g. at
+    /// session start) follows the default policy.
+    pub fn notify_external_modification(
+        &self,
+        affected_paths: Vec<AffectedPath>,
+        reason: BarrierReason,
+    ) -> Result<Option<BarrierInfo>> {
+        let Some(affected_paths) = self.barrier_paths(affected_paths) else {
+            return Ok(None);
+        };
+
+        let completed = self.inner.lock().unwrap().completed_steps.clone();
+        // Use step 0 as sentinel when no steps exist yet, so
+        // pre-step host modifications are still visible in history.
+        let after_step_id = completed.last().copied().unwrap_or(0);
+
+        let step_dir = self.step_dir(after_step_id);
+        // Ensure the step directory exists (step 0 is never
+        // opened via open_step, so its directory may not exist).
+        if !step_dir.exists() {
+            fs::create_dir_all(&step_dir)?;
+        }
+        let mut entries = read_step_barriers(&step_dir);
+
+        // Merge into the last barrier if it has the same reason.
+        // This coalesces watcher ticks between the same VM steps into
+        // one barrier instead of creating a separate barrier per tick.
+        if let Some(last) = entries.last_mut() {
+            if last.reason == reason {
+                for ap in &affected_paths {
+                    if !last.affected_paths.iter().any(|existing| existing.path == ap.path) {
+                        last.affected_paths.push(ap.clone());
+                    }
+                }
+                last.timestamp = Utc::now();
+                write_step_barriers(&step_dir, &entries)?;
+
+                let index = entries.len() - 1;
+                return Ok(Some(BarrierInfo {
+                    barrier_id: synthesize_barrier_id(after_step_id, index),
+                    after_step_id,
+                    timestamp: entries[index].timestamp,
+                    affected_paths: entries[index].affected_paths.clone(),
+                    reason,
+                }));
+            }
+        }
+
+        let index = entries.len();
+        entries.push(BarrierEntry {
+            timestamp: Utc::now(),
+            affected_paths: affected_paths.clone(),
+            reason,
+        });
+        write_step_barriers(&step_dir, &entries)?;
+
+        let barrier = BarrierInfo {
+            barrier_id: synthesize_barrier_id(after_step_id, index),
+            after_step_id,
+            timestamp: entries[index].timestamp,
+            affected_paths,
+            reason,
+        };
+        Ok(Some(barrier))
+    }
+
+    /// The paths of `affected_paths` that must be recorded in a barrier, or
+    /// `None` if no barrier is needed.
+    fn barrier_paths(&self, affected_paths: Vec<AffectedPath>) -> Option<Vec<AffectedPath>> {
+        if affected_paths.is_empty() {
+            let default_policy = self.external_modification_default_policy();
+            return (default_policy == ExternalModificationPolicy::Barrier).then_some(affected_paths);
+        }
+        let barrier_paths: Vec<AffectedPath> = affected_paths
+            .into_iter()
+            .filter(|ap| {
+                self.external_modification_policy(ap) == ExternalModificationPolicy::Barrier
+            })
+            .collect();
+        (!barrier_paths.is_empty()).then_some(barrier_paths)
+    }
+
+    /// The policy for external changes whose paths are unknown.
+    pub fn external_modification_default_policy(&self) -> ExternalModificationPolicy {
+        self.external_modification.lock().unwrap().default_policy()
+    }
+
+    /// The policy that applies to an external change of `affected`. Paths may
+    /// be absolute or relative to the working root. A rename takes the
+    /// stricter policy of its source and destination.
+    pub fn external_modification_policy(&self, affected: &AffectedPath) -> ExternalModificationPolicy {
+        let matcher = self.external_modification.lock().unwrap();
+        let policy_for = |path: &Path| {
+            let relative = path.strip_prefix(&self.working_root).unwrap_or(path);
+            matcher.policy_for(&normalized_relative_path(relative))
+        };
+        let policy = policy_for(&affected.path);
+        match affected.renamed_from.as_deref().map(policy_for) {
+            Some(from_policy) => stricter_policy(policy, from_policy),
+            None => policy,
+        }
+    }
+
+    /// Turn the git mirror on or off (see [`crate::git_mirror`]). Fails if
+    /// the working directory is not in a git work tree, or when turning it
+    /// on in a build without the `git-mirror` feature.
+    pub fn set_git_mirror(&self, config: &GitMirrorConfig) -> Result<()> {
+        #[cfg(feature = "git-mirror")]
+        {
+            let mirror = if config.enabled {
+                Some(GitMirror::open(&self.working_root, &self.undo_dir, &config.ref_name)?)
+            } else {
+                None
+            };
+            *self.git_mirror.lock().unwrap() = mirror;
+            Ok(())
+        }
+        #[cfg(not(feature = "git-mirror"))]
+        if config.enabled {
+            Err(CodeAgentError::GitMirror {
+                message: "built without the git-mirror feature".to_string(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Commit a closed step to the git mirror, if it is on. A failed commit
+    /// is logged; the step is closed regardless.
+    #[cfg(feature = "git-mirror")]
+    fn mirror_step(&self, step_dir: &Path) {
+        let mirror = self.git_mirror.lock().unwrap();
+        let Some(mirror) = mirror.as_ref() else {
+            return;
+        };
+        let Ok(manifest) = StepManifest::read_from(step_dir) else {
+            return;
+        };
+        // A renamed directory is committed under its new name and removed
+        // under the old one.
+        let renamed = manifest.renames.iter().flat_map(|rename| [&rename.from, &rename.to]);
+        let paths: Vec<String> = manifest.entries.keys().chain(renamed).cloned().collect();
+        if let Err(error) =
+            mirror.commit_step(manifest.step_id, manifest.command.as_deref(), &paths)
+        {
+            eprintln!(
+                "{{\"level\":\"error\",\"component\":\"undo\",\"message\":\"failed to mirror step {} to {}: {error}\"}}",
+                manifest.step_id,
+                mirror.ref_name()
+            );
+        }
+    }
+
+    /// Replace the external modification rules. Barriers already recorded
+    /// are kept.
+    pub fn set_external_modification_config(&self, config: &ExternalModificationConfig) {
+        *self.external_modification.lock().unwrap() = ExternalModificationMatcher::new(config);
+    }
+
+    /// The coherent capture rules.
+    pub fn coherent_capture_config(&self) -> CoherentCaptureConfig {
+        self.coherent_capture.lock().unwrap().config().clone()
+    }
+
+    /// Replace the coherent capture rules. A capture already reading keeps
+    /// the rules it started with.
+    pub fn set_coherent_capture_config(&self, config: &CoherentCaptureConfig) {
+        *self.coherent_capture.lock().unwrap() = Arc::new(CoherentCaptureMatcher::new(config));
+    }
+
+    /// Return all current undo barriers.
+    pub fn barriers(&self) -> Vec<BarrierInfo> {
+        let completed = self.inner.lock().unwrap().completed_steps.clone();
+        let steps_dir = self.undo_dir.join("steps");
+        let mut barriers = Vec::new();
+        // Include pre-step barriers (step 0 sentinel) if any exist.
+        let step_0_dir = steps_dir.join("0");
+        if step_0_dir.exists() {
+            for (i, entry) in read_step_barriers(&step_0_dir).into_iter().enumerate() {
+                barriers.push(BarrierInfo {
+                    barrier_id: synthesize_barrier_id(0, i),
+                    after_step_id: 0,
+                    timestamp: entry.timestamp,
+                    affected_paths: entry.affected_paths,
+                    reason: entry.reason,
+                });
+            }
+        }
+        barriers.extend(load_barriers_for_steps(&steps_dir, &completed));
+        barriers
+    }
+
+    /// The working directory this undo log records.
+    pub fn working_root(&self) -> &Path {
+        &self.working_root
+    }
+
+    /// The symlink policy applied to captures, creations and rollbacks.
+    pub fn symlink_policy(&self) -> SymlinkPolicy {
+        *self.symlink_policy.lock().unwrap()
+    }
+
+    /// Change the symlink policy. Affects steps rolled back and paths
+    /// captured from now on, including the rest of an active step.
+    pub fn set_symlink_policy(&self, policy: SymlinkPolicy) {
+        *self.symlink_policy.lock().unwrap() = policy;
+    }
+
+    /// Replace the handler asked about triggered safeguards, or remove it
+    /// with `None` so safeguards are allowed unasked. A safeguard already
+    /// waiting on the previous handler keeps waiting on it.
+    pub fn set_safeguard_handler(&self, handler: Option<Arc<dyn SafeguardHandler>>) {
+        *self.safeguard_handler.lock().unwrap() = handler;
+    }
+
+    /// Replace the safeguard thresholds and limits. Counts already made in
+    /// an active step stand, so a lowered threshold can trigger on its
+    /// next operation.
+    pub fn set_safeguard_config(&self, config: SafeguardConfig) {
+        self.inner.lock().unwrap().safeguard_tracker.set_config(config);
+    }
+
+    /// Run the step duration safeguard for the open step `step_id`, which
+    /// the caller's clock says opened `elapsed` ago. Filesystem operations do
+    /// not run it, so a step sitting idle is caught too: the caller polls.
+    /// Blocks while the handler decides; a denied step is rolled back.
+    pub fn check_step_duration(&self, step_id: StepId, elapsed: Duration) -> Result<()> {
+        let event = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.step(step_id).is_none() {
+                return Ok(());
+            }
+            inner.safeguard_tracker.check_step_duration(step_id, elapsed)
+        };
+        self.handle_safeguard_event(event)
+    }
+
+    /// Rebuild the gitignore filter from the ignore files now on disk.
+    /// Changes made through the hooks are picked up on their own; this is
+    /// for files changed behind the interceptor's back. Returns false when
+    /// gitignore filtering is off.
+    pub fn reload_gitignore(&self) -> bool {
+        self.gitignore_filter.reload();
+        self.gitignore_filter.reads_ignore_files()
+    }
+
+    /// Extra gitignore-syntax patterns of paths not to capture (see
+    /// [`GitignoreFilter`]). They apply whether or not gitignore filtering
+    /// is on.
+    pub fn ignore_patterns(&self) -> Vec<String> {
+        self.gitignore_filter.patterns()
+    }
+
+    /// Replace the extra ignore patterns; validate them with
+    /// [`crate::gitignore::validate_patterns`] first. Paths already captured
+    /// in the open step stay captured.
+    pub fn set_ignore_patterns(&self, patterns: Vec<String>) {
+        self.gitignore_filter.set_patterns(patterns);
+    }
+
+    /// The resource limits enforced on this undo log.
+    pub fn resource_limits(&self) -> ResourceLimitsConfig {
+        self.resource_limits.lock().unwrap().clone()
+    }
+
+    /// Replace the resource limits and evict completed steps that no longer
+    /// fit. The single-step budget also applies to the rest of an active step.
+    ///
+    /// Returns the list of evicted step IDs.
+    pub fn set_resource_limits(&self, limits: ResourceLimitsConfig) -> Result<Vec<StepId>> {
+        self.set_resource_limits_monitored(limits, &Unmonitored)
+    }
+
+    /// Like [`set_resource_limits`](Self::set_resource_limits), reporting
+    /// eviction progress to `monitor`. A cancelled eviction stops before
+    /// the next step, leaving the log over its limits until the next step
+    /// closes.
+    pub fn set_resource_limits_monitored(
+        &self,
+        limits: ResourceLimitsConfig,
+        monitor: &dyn OperationMonitor,
+    ) -> Result<Vec<StepId>> {
+        *self.resource_limits.lock().unwrap() = limits;
+        let completed = self.completed_steps();
+        self.evict_monitored(&completed, monitor)
+    }
+
+    /// Announce a heavy operation of the next step to open (see
+    /// [`Expectation`]). Returns how many announcements are now waiting for
+    /// that step.
+    pub fn expect(&self, expectation: Expectation) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.safeguard_tracker.expect(expectation);
+        inner.safeguard_tracker.pending_expectations()
+    }
+
+    /// Whether undo is disabled due to a version mismatch.
+    pub fn is_undo_disabled(&self) -> bool {
+        *self.undo_disabled.lock().unwrap()
+    }
+
+    /// Returns the version mismatch info if undo is disabled, as (expected, found).
+    pub fn version_mismatch(&self) -> Option<(String, String)> {
+        self.version_mismatch_info.lock().unwrap().clone()
     }
 
     /// Discard the entire undo log and reinitialize with the current version.
@@ -581,16 +1688,20 @@ impl UndoInterceptor {
         // Clear in-memory state (step tracking + per-step state in one lock)
         {
             let mut inner = self.inner.lock().unwrap();
-            inner.active_step = None;
+            for step in std::mem::take(&mut inner.open_steps) {
+                inner.safeguard_tracker.end_step(step.id);
+            }
+            inner.finalizing_steps.clear();
             inner.completed_steps.clear();
-            inner.touched_paths.clear();
-            inner.current_manifest = None;
-            inner.current_step_data_size = 0;
-            inner.step_unprotected = false;
+            inner.history_ids.clear();
         }
+        self.blob_cache.clear();
+
+        self.step_freed.notify_all();
 
-        // Reset step ID counter
+        // Reset step ID counter and start a new chain
         *self.next_step_id.lock().unwrap() = 1;
+        *self.chain.lock().unwrap() = ChainHead::default();
 
         // Re-enable undo
         *self.undo_disabled.lock().unwrap() = false;
@@ -599,15 +1710,184 @@ impl UndoInterceptor {
         Ok(())
     }
 
-    /// Recover from a crash by rolling back any incomplete step in the WAL.
-    /// Returns `None` if no recovery was needed, or `Some(RecoveryInfo)` with details.
+    /// Recover from a crash by rolling back any incomplete steps in the WAL.
+    /// Returns `None` if no recovery was needed, or `Some(RecoveryInfo)` with
+    /// details summed over the steps.
+    ///
+    /// The rollback of a completed step that the crash interrupted is
+    /// reverted first, from its journal. Steps that were open together are
+    /// rolled back from the one opened last to `wal/in_progress`, which was
+    /// opened first. Then a rollback or eviction of several steps that the
+    /// crash cut short is completed (see [`crate::history_journal`]).
     pub fn recover(&self) -> Result<Option<RecoveryInfo>> {
-        let wal_dir = self.wal_in_progress_dir();
+        let mut recovered: Option<RecoveryInfo> = None;
+        let steps_dir = self.undo_dir.join("steps");
+        let step_dirs = fs::read_dir(&steps_dir)
+            .map(|entries| entries.flatten().map(|entry| entry.path()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        for step_dir in step_dirs {
+            let extension = step_dir.extension().and_then(|ext| ext.to_str());
+            if extension == Some(REMOVING_EXTENSION) {
+                // Already out of the history; the crash came while deleting it.
+                fs::remove_dir_all(&step_dir)?;
+            } else if extension == Some(SQUASHING_EXTENSION) {
+                // Without a journal, the crash came while building it.
+                if !self.undo_dir.join(SQUASH_JOURNAL_FILE).exists() {
+                    fs::remove_dir_all(&step_dir)?;
+                }
+            } else if let Some(reverted) =
+                rollback_journal::revert_interrupted(&step_dir, &self.working_root)?
+            {
+                let info = RecoveryInfo {
+                    paths_restored: reverted,
+                    rollbacks_reverted: 1,
+                    ..RecoveryInfo::empty()
+                };
+                recovered = Some(recovered.map_or(info.clone(), |total| total.add(info)));
+            }
+        }
 
-        if !wal_dir.exists() {
-            return Ok(None);
+        let mut concurrent: Vec<(StepId, PathBuf)> = fs::read_dir(self.undo_dir.join("wal"))
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|entry| {
+                        let name = entry.file_name();
+                        let id = name.to_str()?.strip_prefix("in_progress.")?.parse().ok()?;
+                        Some((id, entry.path()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        concurrent.sort_by_key(|(id, _)| std::cmp::Reverse(*id));
+        let wal_dirs = concurrent
+            .into_iter()
+            .map(|(_, dir)| dir)
+            .chain(Some(self.wal_in_progress_dir()).filter(|dir| dir.exists()));
+
+        for wal_dir in wal_dirs {
+            let info = self.recover_wal(&wal_dir)?;
+            recovered = Some(recovered.map_or(info.clone(), |total| total.add(info)));
         }
 
+        let steps_rolled_back = self.recover_interrupted_rollback()?;
+        let steps_evicted = self.recover_interrupted_eviction()?;
+        let steps_squashed = self.recover_interrupted_squash()?;
+        if steps_rolled_back > 0 || steps_evicted > 0 || steps_squashed > 0 {
+            let info = RecoveryInfo {
+                steps_rolled_back,
+                steps_evicted,
+                steps_squashed,
+                ..RecoveryInfo::empty()
+            };
+            recovered = Some(recovered.map_or(info.clone(), |total| total.add(info)));
+        }
+        Ok(recovered)
+    }
+
+    /// Complete a rollback of several steps that a crash cut short: roll
+    /// back the listed steps still in the history and rewind the chain head
+    /// past those already removed. Returns the number of steps rolled back.
+    fn recover_interrupted_rollback(&self) -> Result<usize> {
+        let Some(marker) =
+            history_journal::read::<RollbackMarker>(&self.undo_dir, ROLLBACK_MARKER_FILE)?
+        else {
+            return Ok(0);
+        };
+        let completed = self.completed_steps();
+        let remaining: Vec<StepId> = marker
+            .steps
+            .iter()
+            .map(|step| step.step_id)
+            .filter(|id| completed.contains(id))
+            .collect();
+        {
+            // Rewinding again is harmless if the crash came after the head moved.
+            let mut chain_head = self.chain.lock().unwrap();
+            for step in marker.steps.iter().filter(|step| !remaining.contains(&step.step_id)) {
+                if let Some(link) = &step.link {
+                    let previous_step =
+                        completed.iter().rfind(|id| **id < step.step_id).copied();
+                    chain_head.rewind(link, previous_step);
+                }
+            }
+            self.store_chain_head(&chain_head);
+        }
+
+        let mut rolled_back = Vec::with_capacity(remaining.len());
+        let outcome = self.roll_back_step_dirs(
+            &remaining,
+            &completed,
+            marker.mode,
+            &Unmonitored,
+            &mut rolled_back,
+        );
+        self.inner
+            .lock()
+            .unwrap()
+            .completed_steps
+            .retain(|id| !rolled_back.contains(id));
+        outcome?;
+        history_journal::remove(&self.undo_dir, ROLLBACK_MARKER_FILE)?;
+        Ok(rolled_back.len())
+    }
+
+    /// Complete an eviction that a crash cut short: remove the listed steps
+    /// still on disk and move the chain anchor past all of them. Returns
+    /// the number of steps removed.
+    fn recover_interrupted_eviction(&self) -> Result<usize> {
+        let Some(journal) =
+            history_journal::read::<EvictionJournal>(&self.undo_dir, EVICTION_JOURNAL_FILE)?
+        else {
+            return Ok(0);
+        };
+        let steps_dir = self.undo_dir.join("steps");
+        let mut evicted = 0;
+        {
+            let mut chain_head = self.chain.lock().unwrap();
+            for step in &journal.steps {
+                let step_dir = steps_dir.join(step.step_id.to_string());
+                if step_dir.exists() {
+                    self.remove_step_dir(&step_dir)?;
+                    evicted += 1;
+                }
+                // Moving the anchor again is harmless if it already moved.
+                if let Some(link) = &step.link {
+                    chain_head.drop_oldest(link);
+                }
+            }
+            self.store_chain_head(&chain_head);
+        }
+        self.inner
+            .lock()
+            .unwrap()
+            .completed_steps
+            .retain(|id| !journal.steps.iter().any(|step| step.step_id == *id));
+        history_journal::remove(&self.undo_dir, EVICTION_JOURNAL_FILE)?;
+        Ok(evicted)
+    }
+
+    /// Complete a squash that a crash cut short once the merged step was
+    /// built: swap it in for the listed steps still on disk and re-link the
+    /// chain. Returns the number of steps merged away.
+    fn recover_interrupted_squash(&self) -> Result<usize> {
+        let Some(journal) =
+            history_journal::read::<SquashJournal>(&self.undo_dir, SQUASH_JOURNAL_FILE)?
+        else {
+            return Ok(0);
+        };
+        if journal.steps.is_empty() {
+            return history_journal::remove(&self.undo_dir, SQUASH_JOURNAL_FILE).map(|()| 0);
+        }
+        let mut chain_head = self.chain.lock().unwrap();
+        self.finish_squash(&journal.steps, &mut chain_head)?;
+        drop(chain_head);
+        history_journal::remove(&self.undo_dir, SQUASH_JOURNAL_FILE)?;
+        Ok(journal.steps.len() - 1)
+    }
+
+    /// Roll back and remove one incomplete step's WAL.
+    fn recover_wal(&self, wal_dir: &Path) -> Result<RecoveryInfo> {
         let preimage_dir = wal_dir.join("preimages");
         let manifest_path = wal_dir.join("manifest.json");
 
@@ -619,17 +1899,16 @@ impl UndoInterceptor {
 
         // Empty WAL entry (step opened but no operations before crash)
         if !has_preimages && !has_manifest {
-            fs::remove_dir_all(&wal_dir)?;
-            return Ok(Some(RecoveryInfo {
-                paths_restored: 0,
-                paths_deleted: 0,
+            fs::remove_dir_all(wal_dir)?;
+            return Ok(RecoveryInfo {
                 manifest_valid: false,
-            }));
+                ..RecoveryInfo::empty()
+            });
         }
 
         // Try to load or reconstruct the manifest
-        let (manifest, manifest_valid) = if has_manifest {
-            match StepManifest::read_from(&wal_dir) {
+        let (mut manifest, manifest_valid) = if has_manifest {
+            match StepManifest::read_from(wal_dir) {
                 Ok(m) => (m, true),
                 Err(_) => {
                     let m = self.rebuild_manifest_from_preimages(&preimage_dir)?;
@@ -640,6 +1919,9 @@ impl UndoInterceptor {
             let m = self.rebuild_manifest_from_preimages(&preimage_dir)?;
             (m, false)
         };
+        if !manifest_valid {
+            manifest.renames = dir_rename::read_log(wal_dir);
+        }
 
         let paths_restored = manifest.entries.values().filter(|e| e.existed_before).count();
         let paths_deleted = manifest.entries.values().filter(|e| !e.existed_before).count();
@@ -647,18 +1929,19 @@ impl UndoInterceptor {
         if !manifest.entries.is_empty() {
             // Write the reconstructed manifest so rollback_step can read it
             if !manifest_valid {
-                manifest.write_to(&wal_dir)?;
+                manifest.write_to(wal_dir)?;
             }
-            rollback::rollback_step(&wal_dir, &self.working_root, self.symlink_policy)?;
+            rollback::rollback_step(wal_dir, &self.working_root, self.symlink_policy())?;
         }
 
-        fs::remove_dir_all(&wal_dir)?;
+        fs::remove_dir_all(wal_dir)?;
 
-        Ok(Some(RecoveryInfo {
+        Ok(RecoveryInfo {
             paths_restored,
             paths_deleted,
             manifest_valid,
-        }))
+            ..RecoveryInfo::empty()
+        })
     }
 
     /// Reconstruct a StepManifest by scanning preimage metadata files.
@@ -698,6 +1981,7 @@ impl UndoInterceptor {
                         meta.existed_before,
                         meta.file_type.as_str(),
                     );
+                    manifest.set_hard_link(&meta.relative_path, meta.hard_link);
                 }
                 Err(_) => {
                     continue;
@@ -712,35 +1996,35 @@ impl UndoInterceptor {
     /// Used when an error occurs mid-step or when a safeguard denies the
     /// current operation -- undoes all operations already applied in this step.
     pub fn rollback_current_step(&self) -> Result<()> {
-        let wal_dir = self.wal_in_progress_dir();
+        let Some(step_id) = self.inner.lock().unwrap().target_step() else {
+            return Err(CodeAgentError::NoActiveStep);
+        };
+        self.rollback_open_step(step_id)
+    }
 
-        // Write manifest, cancel the active step, and clear inner state.
+    /// Roll back and cancel open step `id`, leaving any other open steps be.
+    fn rollback_open_step(&self, id: StepId) -> Result<()> {
+        // Write manifest, cancel the step, and clear its state.
         // The lock is released before filesystem I/O (rollback + WAL removal).
-        {
-            let mut inner = self.inner.lock().unwrap();
-            if inner.active_step.is_none() {
+        let wal_dir = {
+            let mut inner = self.wait_for_captures(self.inner.lock().unwrap(), id);
+            let Some(step) = inner.take_step(id) else {
                 return Err(CodeAgentError::NoActiveStep);
-            }
-            if let Some(ref manifest) = inner.current_manifest {
-                let _ = manifest.write_to(&wal_dir);
-            }
-            inner.active_step = None;
-            inner.touched_paths.clear();
-            inner.current_manifest = None;
-            inner.safeguard_tracker.reset();
-            inner.current_step_data_size = 0;
-            inner.step_unprotected = false;
-        }
+            };
+            let _ = step.manifest.write_to(&step.wal_dir);
+            step.wal_dir
+        };
 
         // Best-effort rollback using the WAL data. Even if this fails the step
         // is already cancelled so subsequent operations are not blocked.
         let mut rollback_error = None;
         if wal_dir.exists() {
-            if let Err(e) = rollback::rollback_step(&wal_dir, &self.working_root, self.symlink_policy) {
+            if let Err(e) = rollback::rollback_step(&wal_dir, &self.working_root, self.symlink_policy()) {
                 rollback_error = Some(e);
             }
             let _ = fs::remove_dir_all(&wal_dir);
         }
+        self.finish_step(id);
 
         match rollback_error {
             Some(e) => Err(e),
@@ -756,8 +2040,10 @@ impl UndoInterceptor {
             Some(e) => e,
             None => return Ok(()),
         };
+        metrics::increment(metrics::Counter::SafeguardTriggers);
 
-        let handler = match &self.safeguard_handler {
+        // Cloned out so the handler can block without holding the lock.
+        let handler = match self.safeguard_handler.lock().unwrap().clone() {
             Some(h) => h,
             None => return Ok(()),
         };
@@ -769,13 +2055,15 @@ impl UndoInterceptor {
         let decision = handler.on_safeguard_triggered(event);
 
         match decision {
-            SafeguardDecision::Allow => {
+            SafeguardDecision::AllowOnce
+            | SafeguardDecision::AllowForStep
+            | SafeguardDecision::AllowForSession => {
                 let mut inner = self.inner.lock().unwrap();
-                inner.safeguard_tracker.mark_allowed(&kind);
+                inner.safeguard_tracker.mark_allowed(step_id, &kind, decision);
                 Ok(())
             }
             SafeguardDecision::Deny => {
-                self.rollback_current_step()?;
+                self.rollback_open_step(step_id)?;
                 Err(CodeAgentError::SafeguardDenied {
                     safeguard_id,
                     step_id,
@@ -784,74 +2072,431 @@ impl UndoInterceptor {
         }
     }
 
+    /// Roll back the step if an operation went over a per-step budget.
+    /// Unlike a safeguard, the handler is not asked.
+    fn enforce_budget(&self, overrun: Option<BudgetOverrun>, step_id: StepId) -> Result<()> {
+        let Some(overrun) = overrun else {
+            return Ok(());
+        };
+        self.rollback_open_step(step_id)?;
+        Err(CodeAgentError::StepBudgetExceeded {
+            step_id,
+            budget: overrun.budget,
+            used: overrun.used,
+            limit: overrun.limit,
+        })
+    }
+
+    /// Run the safeguards for overwriting an existing file: the overwritten
+    /// bytes budget, the large-file check, then the per-step overwrite count.
+    fn check_overwrite_safeguards(
+        &self,
+        path: &Path,
+        file_size: u64,
+        step_id: StepId,
+    ) -> Result<()> {
+        let relative = self.relative_path_str(path);
+        let overrun = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.safeguard_tracker.charge_overwrite(step_id, &relative, file_size)
+        };
+        self.enforce_budget(overrun, step_id)?;
+
+        let event = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.safeguard_tracker.check_overwrite(&relative, file_size, step_id)
+        };
+        self.handle_safeguard_event(event)?;
+
+        let event = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.safeguard_tracker.check_overwrite_count(&relative, step_id)
+        };
+        self.handle_safeguard_event(event)
+    }
+
+    /// Ask about an announced operation when `path` is its first change.
+    /// Runs before preimage capture and the other safeguards, so an allow
+    /// raises the limits they check.
+    fn check_expected(&self, path: &Path, op: ExpectedOperation, step_id: StepId) -> Result<()> {
+        let relative = self.relative_path_str(path);
+        let event = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.safeguard_tracker.check_expected(&relative, op, step_id)
+        };
+        self.handle_safeguard_event(event)
+    }
+
+    /// Run the protected path safeguard for an operation on `path`.
+    fn check_protected_path(
+        &self,
+        path: &Path,
+        operation: PathOperation,
+        step_id: StepId,
+    ) -> Result<()> {
+        let relative = self.relative_path_str(path);
+        let event = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.safeguard_tracker.check_protected_path(&relative, operation, step_id)
+        };
+        self.handle_safeguard_event(event)
+    }
+
+    /// Count an operation of `step_id` and run the operation count
+    /// safeguard.
+    fn check_step_operations(&self, step_id: StepId) -> Result<()> {
+        let event = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.safeguard_tracker.check_step_operations(step_id)
+        };
+        self.handle_safeguard_event(event)
+    }
+
     /// Evict oldest completed steps to satisfy resource limits.
     ///
     /// Takes a snapshot of completed steps (caller must not hold inner lock).
     /// Returns the list of evicted step IDs and removes them from the in-memory list.
     fn evict_if_needed(&self, completed_steps: &[StepId]) -> Result<Vec<StepId>> {
+        self.evict_monitored(completed_steps, &Unmonitored)
+    }
+
+    fn evict_monitored(
+        &self,
+        completed_steps: &[StepId],
+        monitor: &dyn OperationMonitor,
+    ) -> Result<Vec<StepId>> {
         let limits = self.resource_limits.lock().unwrap().clone();
         let steps_dir = self.undo_dir.join("steps");
-        let mut evicted: Vec<StepId> = Vec::new();
+        let mut chain_head = self.chain.lock().unwrap();
+        let mut to_evict: Vec<StepId> = Vec::new();
         let mut remaining = completed_steps.to_vec();
 
-        // Phase 1: Evict by step count
-        if let Some(max_count) = limits.max_step_count {
-            while remaining.len() > max_count {
-                let oldest = remaining[0];
-                let step_dir = steps_dir.join(oldest.to_string());
-                if step_dir.exists() {
-                    fs::remove_dir_all(&step_dir)?;
-                }
-                remaining.remove(0);
-                evicted.push(oldest);
-            }
+        // Phase 1: Evict by step count
+        if let Some(max_count) = limits.max_step_count {
+            while remaining.len() > max_count {
+                to_evict.push(remaining.remove(0));
+            }
+        }
+
+        // Phase 2: Evict by total log size
+        if let Some(max_size) = limits.max_log_size_bytes {
+            let mut current_size =
+                resource_limits::calculate_total_log_size(&steps_dir, &remaining)?;
+            while current_size > max_size && !remaining.is_empty() {
+                let oldest = remaining.remove(0);
+                let step_size =
+                    resource_limits::calculate_step_size(&steps_dir.join(oldest.to_string()))?;
+                current_size = current_size.saturating_sub(step_size);
+                to_evict.push(oldest);
+            }
+        }
+
+        // The journal stays if removing a step fails, and the next startup
+        // completes the eviction.
+        if !to_evict.is_empty() {
+            let journal = EvictionJournal {
+                steps: to_evict
+                    .iter()
+                    .map(|&step_id| JournaledStep {
+                        step_id,
+                        link: chain::read_link(&steps_dir.join(step_id.to_string())),
+                    })
+                    .collect(),
+            };
+            history_journal::write(&self.undo_dir, EVICTION_JOURNAL_FILE, &journal)?;
+        }
+        let mut evicted: Vec<StepId> = Vec::with_capacity(to_evict.len());
+        for (index, step_id) in to_evict.iter().enumerate() {
+            if monitor.is_cancelled() {
+                break;
+            }
+            let step_dir = steps_dir.join(step_id.to_string());
+            if step_dir.exists() {
+                self.evict_step_dir(&step_dir, &mut chain_head)?;
+            }
+            evicted.push(*step_id);
+            monitor.progress(percent_of(index + 1, to_evict.len()), None);
+        }
+        drop(chain_head);
+        if !to_evict.is_empty() {
+            history_journal::remove(&self.undo_dir, EVICTION_JOURNAL_FILE)?;
+        }
+
+        metrics::add(metrics::Counter::StepsEvicted, evicted.len() as u64);
+
+        // Remove evicted steps from the in-memory list
+        if !evicted.is_empty() {
+            let mut inner = self.inner.lock().unwrap();
+            inner.completed_steps.retain(|s| !evicted.contains(s));
+            inner.history_ids.retain(|_, final_id| !evicted.contains(final_id));
+        }
+
+        Ok(evicted)
+    }
+
+    /// Remove an evicted step's directory, moving the chain anchor past it.
+    fn evict_step_dir(&self, step_dir: &Path, chain_head: &mut ChainHead) -> Result<()> {
+        let link = chain::read_link(step_dir);
+        self.remove_step_dir(step_dir)?;
+        if let Some(link) = link {
+            chain_head.drop_oldest(&link);
+            self.store_chain_head(chain_head);
+        }
+        Ok(())
+    }
+
+    /// Persist the chain head. A failed write shows up in `attest()`.
+    fn store_chain_head(&self, chain_head: &ChainHead) {
+        if let Err(error) = chain_head.write_to(&self.undo_dir) {
+            eprintln!(
+                "{{\"level\":\"error\",\"component\":\"undo\",\"message\":\"failed to write chain head: {error}\"}}",
+            );
+        }
+    }
+
+    /// Verify the manifest hash chain of the steps on disk against the head
+    /// file.
+    pub fn attest(&self) -> Result<ChainAttestation> {
+        self.check_undo_enabled()?;
+        let _chain_head = self.chain.lock().unwrap();
+        chain::verify_chain(&self.undo_dir)
+    }
+
+    /// Whether `path` may be captured or recorded: its directory must resolve
+    /// inside the working root, and under `Ignore` it must not be reached
+    /// through a symlinked directory either.
+    fn within_capture_boundary(&self, path: &Path) -> bool {
+        self.boundary.contains_parent_of(path)
+            && (self.symlink_policy() != SymlinkPolicy::Ignore
+                || !self.boundary.parent_traverses_symlink(path))
+    }
+
+    /// Get the forward-slash-normalized relative path string for a path.
+    fn relative_path_str(&self, path: &Path) -> String {
+        path.strip_prefix(&self.working_root)
+            .map(|r| r.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default()
+    }
+
+    fn wal_in_progress_dir(&self) -> PathBuf {
+        self.undo_dir.join("wal").join("in_progress")
+    }
+
+    fn step_dir(&self, id: StepId) -> PathBuf {
+        self.undo_dir.join("steps").join(id.to_string())
+    }
+
+    /// Whether gitignore filtering skips `relative_str`. A hook touching an
+    /// ignore file is about to change the rules, so the filter is rebuilt
+    /// at the next lookup, after the change has landed.
+    fn is_gitignored(&self, relative_str: &str, is_dir: impl FnOnce() -> bool) -> bool {
+        let filter = &self.gitignore_filter;
+        let ignored = filter.is_ignored(relative_str, is_dir());
+        if is_ignore_source(relative_str) {
+            filter.invalidate();
+        }
+        ignored
+    }
+
+    /// Ensure the preimage for an existing path is captured in `step_id` on
+    /// first touch. Returns true if this was the first touch (preimage was
+    /// captured). Skips capture if the step is already marked unprotected.
+    fn ensure_preimage(&self, step_id: StepId, file_path: &Path) -> Result<bool> {
+        let relative = file_path.strip_prefix(&self.working_root).map_err(|_| {
+            CodeAgentError::Preimage {
+                path: file_path.to_path_buf(),
+                message: "path outside working root".to_string(),
+            }
+        })?;
+        let relative_str = normalized_relative_path(relative);
+
+        if self.is_gitignored(&relative_str, || {
+            file_path.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false)
+        }) {
+            return Ok(false);
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let granted = inner.safeguard_tracker.granted_bytes(step_id);
+        let other_toucher = inner.concurrent_toucher(step_id, &relative_str);
+        let Some(step) = inner.step_mut(step_id) else {
+            return Ok(false);
+        };
+
+        // Skip if step is already unprotected (exceeded size limit)
+        if step.unprotected {
+            return Ok(false);
+        }
+        let Some(relative_str) = step.original_path(&relative_str) else {
+            return Ok(false);
+        };
+
+        // Path must exist to capture a preimage
+        let symlink_meta = file_path.symlink_metadata().ok();
+
+        // Another thread may be capturing this path or inode right now.
+        if let Some(claim) = self.pending_capture(step, &relative_str, symlink_meta.as_ref()) {
+            drop(inner);
+            self.capture_slots.wait_released(step_id, &claim);
+            return self.ensure_preimage(step_id, file_path);
+        }
+
+        // First-touch check. A range- or metadata-captured path is promoted
+        // to a full preimage here, because the caller is about to mutate it
+        // in a way the partial capture cannot describe.
+        if step.is_touched(&relative_str) {
+            let capture_key = step.capture_key(&relative_str);
+            self.promote_partial_capture(step, granted, &capture_key, file_path)?;
+            return Ok(false);
+        }
+
+        let Some(symlink_meta) = symlink_meta else {
+            return Ok(false);
+        };
+
+        // Skip symlinks when policy is Ignore
+        if self.symlink_policy() == SymlinkPolicy::Ignore && symlink_meta.is_symlink() {
+            return Ok(false);
+        }
+        if !self.within_capture_boundary(file_path) {
+            return Ok(false);
+        }
+
+        let wal_preimage_dir = step.preimage_dir();
+        let hash = path_hash(Path::new(&relative_str));
+
+        // Another name of an inode already captured in this step shares the
+        // primary's preimage.
+        if let Some(primary) = step.link_primary_for(&symlink_meta) {
+            self.promote_partial_capture(step, granted, &primary, file_path)?;
+            self.record_hard_link_alias(step, file_path, &relative_str, &hash, primary)?;
+            return Ok(true);
+        }
+
+        // Claim the path and its inode, then read and compress the preimage
+        // with the lock released so captures of other paths go on meanwhile.
+        let touch_key = step.touch_key(&relative_str);
+        let inode_key = file_id(&symlink_meta).map(CaptureSlots::inode_key);
+        self.capture_slots.try_claim(step_id, &touch_key);
+        if let Some(inode_key) = &inode_key {
+            self.capture_slots.try_claim(step_id, inode_key);
         }
+        step.touch(&relative_str);
+        step.captures_in_flight += 1;
+        drop(inner);
+
+        let coherent_capture = Arc::clone(&self.coherent_capture.lock().unwrap());
+        let coherent_strategy = coherent_capture.strategy_for(&normalized_relative_path(relative));
+        let mut incoherent_reason = None;
+        let captured = match coherent_strategy {
+            Some(strategy) => capture_preimage_cached(
+                file_path,
+                Path::new(&relative_str),
+                &wal_preimage_dir,
+                Some(&self.blob_cache),
+                |path| {
+                    let read = coherent_capture.read(path, strategy)?;
+                    incoherent_reason = read.incoherent_reason;
+                    Ok(read.contents)
+                },
+            ),
+            None => capture_preimage_cached(
+                file_path,
+                Path::new(&relative_str),
+                &wal_preimage_dir,
+                Some(&self.blob_cache),
+                |path| fs::read(path),
+            ),
+        };
 
-        // Phase 2: Evict by total log size
-        if let Some(max_size) = limits.max_log_size_bytes {
-            let mut current_size =
-                resource_limits::calculate_total_log_size(&steps_dir, &remaining)?;
-            while current_size > max_size && !remaining.is_empty() {
-                let oldest = remaining[0];
-                let step_dir = steps_dir.join(oldest.to_string());
-                let step_size = resource_limits::calculate_step_size(&step_dir)?;
-                if step_dir.exists() {
-                    fs::remove_dir_all(&step_dir)?;
-                }
-                remaining.remove(0);
-                current_size = current_size.saturating_sub(step_size);
-                evicted.push(oldest);
+        let mut inner = self.inner.lock().unwrap();
+        let granted = inner.safeguard_tracker.granted_bytes(step_id);
+        let step = inner
+            .step_mut(step_id)
+            .expect("a step is not taken while its captures are in flight");
+        step.captures_in_flight -= 1;
+        self.capture_slots.release(step_id, &touch_key);
+        if let Some(inode_key) = &inode_key {
+            self.capture_slots.release(step_id, inode_key);
+        }
+        self.capture_finished.notify_all();
+        let (meta, data_size) = match captured {
+            Ok(captured) => captured,
+            Err(error) => {
+                step.touched_paths.remove(&touch_key);
+                return Err(error);
             }
+        };
+        step.add_entry(&relative_str, &hash, true, meta.file_type.as_str());
+        step.manifest.set_hard_link(&relative_str, meta.hard_link.clone());
+        if let Some(reason) = incoherent_reason {
+            step.manifest.add_warning(&relative_str, WARNING_INCOHERENT_CAPTURE, reason);
         }
-
-        // Remove evicted steps from the in-memory list
-        if !evicted.is_empty() {
-            let mut inner = self.inner.lock().unwrap();
-            inner.completed_steps.retain(|s| !evicted.contains(s));
+        if let Some(other) = other_toucher {
+            step.manifest.add_warning(
+                &relative_str,
+                WARNING_CONCURRENT_WRITE,
+                concurrent_write_reason(other),
+            );
+        }
+        if let Some(ref hard_link) = meta.hard_link {
+            step.link_primaries.insert(hard_link.file_id(), relative_str.clone());
         }
 
-        Ok(evicted)
-    }
+        self.track_step_data_size(step, granted, data_size);
 
-    /// Get the forward-slash-normalized relative path string for a path.
-    fn relative_path_str(&self, path: &Path) -> String {
-        path.strip_prefix(&self.working_root)
-            .map(|r| r.to_string_lossy().replace('\\', "/"))
-            .unwrap_or_default()
+        Ok(true)
     }
 
-    fn wal_in_progress_dir(&self) -> PathBuf {
-        self.undo_dir.join("wal").join("in_progress")
+    /// The claim in `capture_slots` another thread holds on `relative_str`
+    /// (already translated to its original name) or the inode of `metadata`
+    /// in `step`, if any. The caller must release `inner`, wait for it and
+    /// start over: the capture is about to change what `step` records.
+    fn pending_capture(
+        &self,
+        step: &OpenStep,
+        relative_str: &str,
+        metadata: Option<&fs::Metadata>,
+    ) -> Option<String> {
+        if step.captures_in_flight == 0 {
+            return None;
+        }
+        let touch_key = step.touch_key(relative_str);
+        if self.capture_slots.is_claimed(step.id, &touch_key) {
+            return Some(touch_key);
+        }
+        metadata
+            .and_then(file_id)
+            .map(CaptureSlots::inode_key)
+            .filter(|inode_key| self.capture_slots.is_claimed(step.id, inode_key))
     }
 
-    fn step_dir(&self, id: StepId) -> PathBuf {
-        self.undo_dir.join("steps").join(id.to_string())
+    /// Wait, with `inner` released, until no capture of open step `id` is
+    /// in flight, so the step can be taken.
+    fn wait_for_captures<'a>(
+        &self,
+        mut inner: MutexGuard<'a, UndoInterceptorInner>,
+        id: StepId,
+    ) -> MutexGuard<'a, UndoInterceptorInner> {
+        while inner.step(id).is_some_and(|step| step.captures_in_flight > 0) {
+            inner = self.capture_finished.wait(inner).unwrap();
+        }
+        inner
     }
 
-    /// Ensure the preimage for an existing path is captured on first touch.
-    /// Returns true if this was the first touch (preimage was captured).
-    /// Skips capture if the step is already marked unprotected.
-    fn ensure_preimage(&self, file_path: &Path) -> Result<bool> {
+    /// Capture in `step_id` only the bytes in `[offset, offset + len)` of an
+    /// existing regular file before a positional write.
+    ///
+    /// Falls back to `ensure_preimage` for anything a range capture cannot
+    /// describe: non-regular files, writes covering the whole file, and
+    /// paths under a coherent capture rule (which need a full coherent read).
+    fn ensure_range_preimage(
+        &self,
+        step_id: StepId,
+        file_path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> Result<()> {
         let relative = file_path.strip_prefix(&self.working_root).map_err(|_| {
             CodeAgentError::Preimage {
                 path: file_path.to_path_buf(),
@@ -860,64 +2505,282 @@ impl UndoInterceptor {
         })?;
         let relative_str = normalized_relative_path(relative);
 
-        if let Some(ref filter) = self.gitignore_filter {
-            let is_dir = file_path.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false);
-            if filter.matched_path_or_any_parents(&relative_str, is_dir).is_ignore() {
-                return Ok(false);
-            }
+        let file_meta = match file_path.symlink_metadata() {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return self.ensure_preimage(step_id, file_path).map(|_| ()),
+        };
+        let covers_whole_file = offset == 0 && len >= file_meta.len();
+        let coherent = self
+            .coherent_capture
+            .lock()
+            .unwrap()
+            .strategy_for(&relative_str)
+            .is_some();
+        if covers_whole_file || coherent {
+            return self.ensure_preimage(step_id, file_path).map(|_| ());
+        }
+
+        if self.is_gitignored(&relative_str, || false) {
+            return Ok(());
         }
 
         let mut inner = self.inner.lock().unwrap();
+        let granted = inner.safeguard_tracker.granted_bytes(step_id);
+        let other_toucher = inner.concurrent_toucher(step_id, &relative_str);
+        let Some(step) = inner.step_mut(step_id) else {
+            return Ok(());
+        };
+        if step.unprotected {
+            return Ok(());
+        }
+        let Some(relative_str) = step.original_path(&relative_str) else {
+            return Ok(());
+        };
+        if let Some(claim) = self.pending_capture(step, &relative_str, Some(&file_meta)) {
+            drop(inner);
+            self.capture_slots.wait_released(step_id, &claim);
+            return self.ensure_range_preimage(step_id, file_path, offset, len);
+        }
 
-        // Skip if step is already unprotected (exceeded size limit)
-        if inner.step_unprotected {
-            return Ok(false);
+        let wal_preimage_dir = step.preimage_dir();
+
+        let capture_key = step.capture_key(&relative_str);
+        if step.metadata_captures.contains_key(&capture_key) {
+            // Only the attributes were captured so far.
+            return self.promote_partial_capture(step, granted, &capture_key, file_path);
+        }
+        if let Some(meta) = step.range_captures.get_mut(&capture_key) {
+            let data_size = append_range_patch(file_path, &wal_preimage_dir, meta, offset, len)?;
+            self.track_step_data_size(step, granted, data_size);
+            return Ok(());
+        }
+        if step.is_touched(&relative_str) {
+            // Already fully captured.
+            return Ok(());
+        }
+        if !self.within_capture_boundary(file_path) {
+            return Ok(());
         }
 
-        // First-touch check
-        if inner.touched_paths.contains(&relative_str) {
-            return Ok(false);
+        let hash = path_hash(Path::new(&relative_str));
+        if let Some(primary) = step.link_primary_for(&file_meta) {
+            if let Some(meta) = step.range_captures.get_mut(&step.touch_key(&primary)) {
+                let data_size =
+                    append_range_patch(file_path, &wal_preimage_dir, meta, offset, len)?;
+                self.track_step_data_size(step, granted, data_size);
+            } else {
+                self.promote_partial_capture(step, granted, &primary, file_path)?;
+            }
+            return self.record_hard_link_alias(step, file_path, &relative_str, &hash, primary);
         }
 
-        // Path must exist to capture a preimage
-        let symlink_meta = file_path.symlink_metadata();
-        if symlink_meta.is_err() {
-            return Ok(false);
+        let (meta, data_size) = capture_range_preimage(
+            file_path,
+            Path::new(&relative_str),
+            &wal_preimage_dir,
+            offset,
+            len,
+        )?;
+        step.add_entry(&relative_str, &hash, true, meta.file_type.as_str());
+        step.manifest.set_hard_link(&relative_str, meta.hard_link.clone());
+        if let Some(other) = other_toucher {
+            step.manifest.add_warning(
+                &relative_str,
+                WARNING_CONCURRENT_WRITE,
+                concurrent_write_reason(other),
+            );
+        }
+        if let Some(ref hard_link) = meta.hard_link {
+            step.link_primaries.insert(hard_link.file_id(), relative_str.clone());
         }
 
-        // Skip symlinks when policy is Ignore
-        if self.symlink_policy == SymlinkPolicy::Ignore
-            && symlink_meta.unwrap().is_symlink()
+        step.touch(&relative_str);
+        step.range_captures.insert(step.touch_key(&relative_str), meta);
+        self.track_step_data_size(step, granted, data_size);
+
+        Ok(())
+    }
+
+    /// Capture in `step_id` only the attributes of an existing regular file
+    /// (mode, ownership, timestamps, xattrs) before a change that leaves its
+    /// contents alone. Should the contents change later in the step, the
+    /// capture is promoted to a full preimage first.
+    ///
+    /// Falls back to `ensure_preimage` for other file types, which store no
+    /// contents anyway, and for paths under a coherent capture rule.
+    fn ensure_metadata_preimage(&self, step_id: StepId, file_path: &Path) -> Result<()> {
+        let relative = file_path.strip_prefix(&self.working_root).map_err(|_| {
+            CodeAgentError::Preimage {
+                path: file_path.to_path_buf(),
+                message: "path outside working root".to_string(),
+            }
+        })?;
+        let relative_str = normalized_relative_path(relative);
+
+        let file_meta = match file_path.symlink_metadata() {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return self.ensure_preimage(step_id, file_path).map(|_| ()),
+        };
+        if self.coherent_capture.lock().unwrap().strategy_for(&relative_str).is_some() {
+            return self.ensure_preimage(step_id, file_path).map(|_| ());
+        }
+
+        if self.is_gitignored(&relative_str, || false) {
+            return Ok(());
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let other_toucher = inner.concurrent_toucher(step_id, &relative_str);
+        let Some(step) = inner.step_mut(step_id) else {
+            return Ok(());
+        };
+        let Some(relative_str) = step.original_path(&relative_str) else {
+            return Ok(());
+        };
+        if let Some(claim) = self.pending_capture(step, &relative_str, Some(&file_meta)) {
+            drop(inner);
+            self.capture_slots.wait_released(step_id, &claim);
+            return self.ensure_metadata_preimage(step_id, file_path);
+        }
+        if step.unprotected
+            || step.is_touched(&relative_str)
+            || !self.within_capture_boundary(file_path)
         {
-            return Ok(false);
+            return Ok(());
         }
 
-        let wal_preimage_dir = self.wal_in_progress_dir().join("preimages");
-        let hash = path_hash(relative);
+        let hash = path_hash(Path::new(&relative_str));
+        if let Some(primary) = step.link_primary_for(&file_meta) {
+            return self.record_hard_link_alias(step, file_path, &relative_str, &hash, primary);
+        }
 
-        let (meta, data_size) = capture_preimage(file_path, &self.working_root, &wal_preimage_dir)?;
-        if let Some(ref mut manifest) = inner.current_manifest {
-            manifest.add_entry(&relative_str, &hash, true, meta.file_type.as_str());
+        let meta = capture_metadata_preimage(
+            file_path,
+            Path::new(&relative_str),
+            &step.preimage_dir(),
+        )?;
+        step.add_entry(&relative_str, &hash, true, meta.file_type.as_str());
+        step.manifest.set_hard_link(&relative_str, meta.hard_link.clone());
+        if let Some(other) = other_toucher {
+            step.manifest.add_warning(
+                &relative_str,
+                WARNING_CONCURRENT_WRITE,
+                concurrent_write_reason(other),
+            );
+        }
+        if let Some(ref hard_link) = meta.hard_link {
+            step.link_primaries.insert(hard_link.file_id(), relative_str.clone());
         }
 
-        inner.touched_paths.insert(relative_str);
+        step.touch(&relative_str);
+        step.metadata_captures.insert(step.touch_key(&relative_str), meta);
+
+        Ok(())
+    }
+
+    /// Turn the range or metadata-only capture kept under `capture_key` into
+    /// a full preimage, read from `file_path`. No-op for other paths.
+    fn promote_partial_capture(
+        &self,
+        step: &mut OpenStep,
+        granted: u64,
+        capture_key: &str,
+        file_path: &Path,
+    ) -> Result<()> {
+        let preimage_dir = step.preimage_dir();
+        let key = step.touch_key(capture_key);
+        let data_size = if let Some(mut meta) = step.range_captures.remove(&key) {
+            promote_range_preimage(file_path, &preimage_dir, &mut meta)?
+        } else if let Some(mut meta) = step.metadata_captures.remove(&key) {
+            promote_metadata_preimage(file_path, &preimage_dir, &mut meta)?
+        } else {
+            return Ok(());
+        };
+        self.track_step_data_size(step, granted, data_size);
+        Ok(())
+    }
+
+    /// Record `file_path` as another name of the inode whose preimage is held
+    /// by `primary`. Rollback re-links it to the restored primary.
+    fn record_hard_link_alias(
+        &self,
+        step: &mut OpenStep,
+        file_path: &Path,
+        relative_str: &str,
+        hash: &str,
+        primary: String,
+    ) -> Result<()> {
+        let meta = capture_hard_link_alias(
+            file_path,
+            Path::new(relative_str),
+            &step.preimage_dir(),
+            &primary,
+        )?;
+        step.add_entry(relative_str, hash, true, meta.file_type.as_str());
+        step.manifest.set_hard_link(relative_str, meta.hard_link);
+        step.touch(relative_str);
+        step.link_aliases.insert(step.touch_key(relative_str), primary);
+        Ok(())
+    }
+
+    /// Keep what the closing step left in each existing text file it
+    /// modified, for merge-mode rollback. The bytes count towards the step's
+    /// size but never make it unprotected; a failed capture only means that
+    /// file is restored rather than merged.
+    fn capture_postimages(&self, step_id: StepId) {
+        let (modified, preimage_dir): (Vec<(String, String)>, PathBuf) = {
+            let inner = self.inner.lock().unwrap();
+            let Some(step) = inner.step(step_id) else {
+                return;
+            };
+            let modified = step
+                .manifest
+                .entries
+                .iter()
+                .filter(|(rel_path, entry)| {
+                    entry.existed_before
+                        && entry.file_type == "regular"
+                        && !step.metadata_captures.contains_key(&step.touch_key(rel_path))
+                        && entry.hard_link.as_ref().is_none_or(|link| link.same_inode_as.is_none())
+                })
+                .map(|(rel_path, entry)| {
+                    let current = dir_rename::current_path(&step.manifest.renames, rel_path);
+                    (current, entry.path_hash.clone())
+                })
+                .collect();
+            (modified, step.preimage_dir())
+        };
+        let written: u64 = modified
+            .iter()
+            .map(|(rel_path, hash)| {
+                capture_postimage(&self.working_root.join(rel_path), &preimage_dir, hash)
+                    .unwrap_or(0)
+            })
+            .sum();
+        if let Some(step) = self.inner.lock().unwrap().step_mut(step_id) {
+            step.data_size += written;
+        }
+    }
 
-        // Track cumulative data size and check threshold
-        inner.current_step_data_size += data_size;
+    /// Add captured preimage bytes to the step's total and mark the step
+    /// unprotected once it exceeds `max_single_step_size_bytes` plus the
+    /// `granted` bytes of allowed expectations.
+    fn track_step_data_size(&self, step: &mut OpenStep, granted: u64, data_size: u64) {
+        step.data_size += data_size;
+        metrics::add(metrics::Counter::PreimageBytes, data_size);
         let limits = self.resource_limits.lock().unwrap();
         if let Some(max_size) = limits.max_single_step_size_bytes {
-            if inner.current_step_data_size > max_size {
-                inner.step_unprotected = true;
+            if step.data_size > max_size.saturating_add(granted) {
+                step.unprotected = true;
             }
         }
-
-        Ok(true)
     }
 
-    /// Record that a path was newly created (did not exist before the step).
-    fn record_creation(&self, file_path: &Path) -> Result<()> {
+    /// Record in `step_id` that a path was newly created (did not exist
+    /// before the step).
+    fn record_creation(&self, step_id: StepId, file_path: &Path) -> Result<()> {
         // Skip symlinks when policy is Ignore
-        if self.symlink_policy == SymlinkPolicy::Ignore
+        if self.symlink_policy() == SymlinkPolicy::Ignore
             && file_path
                 .symlink_metadata()
                 .map(|m| m.is_symlink())
@@ -925,6 +2788,9 @@ impl UndoInterceptor {
         {
             return Ok(());
         }
+        if !self.within_capture_boundary(file_path) {
+            return Ok(());
+        }
 
         let relative = file_path.strip_prefix(&self.working_root).map_err(|_| {
             CodeAgentError::Preimage {
@@ -934,39 +2800,177 @@ impl UndoInterceptor {
         })?;
         let relative_str = normalized_relative_path(relative);
 
-        if let Some(ref filter) = self.gitignore_filter {
-            let is_dir = file_path.is_dir();
-            if filter.matched_path_or_any_parents(&relative_str, is_dir).is_ignore() {
-                return Ok(());
-            }
+        if self.is_gitignored(&relative_str, || file_path.is_dir()) {
+            return Ok(());
         }
 
         let mut inner = self.inner.lock().unwrap();
+        let other_toucher = inner.concurrent_toucher(step_id, &relative_str);
+        let Some(step) = inner.step_mut(step_id) else {
+            return Ok(());
+        };
 
         // Skip if step is already unprotected
-        if inner.step_unprotected {
+        if step.unprotected {
+            return Ok(());
+        }
+        let Some(relative_str) = step.original_path(&relative_str) else {
+            return Ok(());
+        };
+
+        if step.is_touched(&relative_str) {
+            return Ok(());
+        }
+
+        let hash = path_hash(Path::new(&relative_str));
+
+        let meta = capture_creation_marker(
+            file_path,
+            Path::new(&relative_str),
+            &step.preimage_dir(),
+        )?;
+
+        step.add_entry(&relative_str, &hash, false, meta.file_type.as_str());
+        if let Some(other) = other_toucher {
+            step.manifest.add_warning(
+                &relative_str,
+                WARNING_CONCURRENT_WRITE,
+                concurrent_write_reason(other),
+            );
+        }
+
+        step.touch(&relative_str);
+        Ok(())
+    }
+
+    /// Whether renaming `from` to `to` only changes the case of the path on
+    /// a case-insensitive working root, so both name the same file.
+    fn is_case_only_rename(&self, from: &Path, to: &Path) -> bool {
+        self.case_insensitive
+            && from != to
+            && path_case::fold(&self.relative_path_str(from))
+                == path_case::fold(&self.relative_path_str(to))
+    }
+
+    /// Record the new spelling of a case-only rename as created in
+    /// `step_id`, so rollback removes it before restoring the old spelling
+    /// from its preimage. The folded key is already touched by the source,
+    /// so only an exact manifest entry stops the record.
+    fn record_case_only_rename(&self, step_id: StepId, to: &Path, is_dir: bool) -> Result<()> {
+        if !self.within_capture_boundary(to) {
+            return Ok(());
+        }
+        let relative = to.strip_prefix(&self.working_root).map_err(|_| {
+            CodeAgentError::Preimage {
+                path: to.to_path_buf(),
+                message: "path outside working root".to_string(),
+            }
+        })?;
+        let relative_str = normalized_relative_path(relative);
+        if self.is_gitignored(&relative_str, || is_dir) {
             return Ok(());
         }
 
-        if inner.touched_paths.contains(&relative_str) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(step) = inner.step_mut(step_id) else {
+            return Ok(());
+        };
+        if step.unprotected || step.manifest.entries.contains_key(&relative_str) {
             return Ok(());
         }
+        capture_creation_marker(to, relative, &step.preimage_dir())?;
+        let file_type = if is_dir { "directory" } else { "regular" };
+        step.add_entry(&relative_str, &path_hash(relative), false, file_type);
+        Ok(())
+    }
 
-        let wal_preimage_dir = self.wal_in_progress_dir().join("preimages");
-        let hash = path_hash(relative);
+    /// Leave the rename of directory `from` to the free name `to` for
+    /// `post_rename` to record as one operation (see [`dir_rename`]) instead
+    /// of capturing the tree. Returns false, leaving nothing pending, when
+    /// the rename cannot be undone that way: `from` is a symlink or has no
+    /// file id, either name is gitignored or outside the capture boundary,
+    /// or a path at or under `to` was already recorded in this step.
+    fn defer_dir_rename(&self, step_id: StepId, from: &Path, to: &Path) -> bool {
+        if !from.symlink_metadata().is_ok_and(|metadata| metadata.is_dir())
+            || rollback::file_id(from).is_none()
+            || !self.within_capture_boundary(from)
+            || !self.within_capture_boundary(to)
+        {
+            return false;
+        }
+        let from_rel = self.relative_path_str(from);
+        let to_rel = self.relative_path_str(to);
+        if from_rel.is_empty()
+            || to_rel.is_empty()
+            || self.is_gitignored(&from_rel, || true)
+            || self.is_gitignored(&to_rel, || true)
+        {
+            return false;
+        }
 
-        let meta = capture_creation_marker(file_path, &self.working_root, &wal_preimage_dir)?;
+        let mut inner = self.inner.lock().unwrap();
+        let Some(step) = inner.step_mut(step_id) else {
+            return false;
+        };
+        if step.unprotected {
+            return false;
+        }
+        // Paths recorded there would be mistaken for the ones moved there.
+        if let Some(to_key) = step.original_path(&to_rel) {
+            let to_key = step.touch_key(&to_key);
+            let under = format!("{to_key}/");
+            if step.touched_paths.iter().any(|key| *key == to_key || key.starts_with(&under)) {
+                return false;
+            }
+        }
+        step.pending_renames.insert((from_rel, to_rel));
+        true
+    }
 
-        if let Some(ref mut manifest) = inner.current_manifest {
-            manifest.add_entry(&relative_str, &hash, false, meta.file_type.as_str());
+    /// Record in `step_id` the directory rename `defer_dir_rename` left
+    /// pending, now that it is done. Returns false if none was pending.
+    fn record_dir_rename(&self, step_id: StepId, from: &Path, to: &Path) -> Result<bool> {
+        let pending = (self.relative_path_str(from), self.relative_path_str(to));
+        let mut inner = self.inner.lock().unwrap();
+        let Some(step) = inner.step_mut(step_id) else {
+            return Ok(false);
+        };
+        if !step.pending_renames.remove(&pending) {
+            return Ok(false);
+        }
+        if let Some(file_id) = rollback::file_id(to) {
+            let (from, to) = pending;
+            step.manifest.renames.push(RenameRecord { from, to, file_id });
+            dir_rename::write_log(&step.wal_dir, &step.manifest.renames)?;
         }
+        Ok(true)
+    }
 
-        inner.touched_paths.insert(relative_str);
+    /// Record in `step_id` the destination of a rename captured path by
+    /// path, and everything under it, as created: the source was captured
+    /// in `pre_rename`, so rollback removes the moved copy. Names already
+    /// recorded (an overwritten destination) are left as they are.
+    fn record_tree_creation(&self, step_id: StepId, path: &Path) -> Result<()> {
+        self.record_creation(step_id, path)?;
+        if !path.symlink_metadata().is_ok_and(|metadata| metadata.is_dir()) {
+            return Ok(());
+        }
+        for entry in fs::read_dir(path)? {
+            let child = entry?.path();
+            if let Ok(relative) = child.strip_prefix(&self.working_root) {
+                let relative_str = normalized_relative_path(relative);
+                if self.gitignore_filter.is_ignored(&relative_str, child.is_dir()) {
+                    continue;
+                }
+            }
+            self.record_tree_creation(step_id, &child)?;
+        }
         Ok(())
     }
 
-    /// Recursively capture preimages for all entries under a directory.
-    fn capture_tree_preimages(&self, dir_path: &Path) -> Result<()> {
+    /// Recursively capture preimages in `step_id` for all entries under a
+    /// directory.
+    fn capture_tree_preimages(&self, step_id: StepId, dir_path: &Path) -> Result<()> {
         if !dir_path.is_dir() {
             return Ok(());
         }
@@ -975,7 +2979,7 @@ impl UndoInterceptor {
             let path = entry.path();
 
             // Skip symlinks when policy is Ignore
-            if self.symlink_policy == SymlinkPolicy::Ignore
+            if self.symlink_policy() == SymlinkPolicy::Ignore
                 && path
                     .symlink_metadata()
                     .map(|m| m.is_symlink())
@@ -985,24 +2989,30 @@ impl UndoInterceptor {
             }
 
             // Skip ignored subtrees early to avoid unnecessary I/O
-            if let Some(ref filter) = self.gitignore_filter {
-                if let Ok(relative) = path.strip_prefix(&self.working_root) {
-                    let relative_str = normalized_relative_path(relative);
-                    if filter.matched_path_or_any_parents(&relative_str, path.is_dir()).is_ignore() {
-                        continue;
-                    }
+            if let Ok(relative) = path.strip_prefix(&self.working_root) {
+                let relative_str = normalized_relative_path(relative);
+                if self.gitignore_filter.is_ignored(&relative_str, path.is_dir()) {
+                    continue;
                 }
             }
 
-            self.ensure_preimage(&path)?;
+            self.ensure_preimage(step_id, &path)?;
             if path.is_dir() {
-                self.capture_tree_preimages(&path)?;
+                self.capture_tree_preimages(step_id, &path)?;
             }
         }
         Ok(())
     }
 }
 
+/// Warning text for a path another open step captured first. That step's
+/// preimage holds the original state, this step's holds what it left.
+fn concurrent_write_reason(other: StepId) -> String {
+    format!(
+        "step {other} was open and had already changed this path; rolling back this step restores the state step {other} left"
+    )
+}
+
 /// Migrate a legacy global `barriers.json` to per-step barrier files.
 ///
 /// If `{undo_dir}/barriers.json` exists, reads all entries, distributes
@@ -1065,13 +3075,46 @@ impl StepManager for UndoInterceptor {
         UndoInterceptor::close_step(self, id)
     }
 
+    fn open_concurrent_step(&self, id: StepId) -> Result<()> {
+        UndoInterceptor::open_concurrent_step(self, id)
+    }
+
     fn current_step(&self) -> Option<StepId> {
-        self.inner.lock().unwrap().active_step
+        self.inner.lock().unwrap().open_steps.first().map(|step| step.id)
+    }
+
+    fn set_step_command(&self, id: StepId, command: String) {
+        self.update_manifest(Some(id), |manifest| manifest.command = Some(command));
+    }
+
+    fn set_step_exit_code(&self, id: StepId, exit_code: i32) {
+        self.update_manifest(Some(id), |manifest| manifest.exit_code = Some(exit_code));
     }
+}
+
+fn stricter_policy(
+    a: ExternalModificationPolicy,
+    b: ExternalModificationPolicy,
+) -> ExternalModificationPolicy {
+    use ExternalModificationPolicy::{Barrier, Ignore, Warn};
+    match (a, b) {
+        (Barrier, _) | (_, Barrier) => Barrier,
+        (Warn, _) | (_, Warn) => Warn,
+        (Ignore, Ignore) => Ignore,
+    }
+}
 
-    fn set_step_command(&self, _id: StepId, command: String) {
-        UndoInterceptor::set_step_command(self, command);
+/// Total size of the files at or under `path`, not following symlinks.
+fn tree_size(path: &Path) -> u64 {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
     }
+    fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| tree_size(&entry.path())).sum())
+        .unwrap_or(0)
 }
 
 /// Normalize path separators to forward slashes for consistent comparison.
@@ -1081,56 +3124,123 @@ fn normalized_relative_path(relative: &Path) -> String {
 
 impl WriteInterceptor for UndoInterceptor {
     fn pre_write(&self, path: &Path) -> Result<()> {
-        let active = self.inner.lock().unwrap().active_step;
+        let active = self.inner.lock().unwrap().target_step();
         if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
             let file_size = path.metadata().map(|m| m.len()).ok();
-            self.ensure_preimage(path)?;
+            if file_size.is_some() {
+                self.check_expected(path, ExpectedOperation::Rewrite, step_id)?;
+            }
+            self.ensure_preimage(step_id, path)?;
 
             if let Some(size) = file_size {
-                let relative = self.relative_path_str(path);
-                let event = {
-                    let mut inner = self.inner.lock().unwrap();
-                    inner.safeguard_tracker.check_overwrite(&relative, size, step_id)
-                };
-                self.handle_safeguard_event(event)?;
+                self.check_overwrite_safeguards(path, size, step_id)?;
+            }
+            self.check_protected_path(path, PathOperation::Write, step_id)?;
+        }
+        Ok(())
+    }
+
+    fn pre_write_range(&self, path: &Path, offset: u64, len: u64) -> Result<()> {
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            let file_size = path.metadata().map(|m| m.len()).ok();
+            if file_size.is_some_and(|size| offset < size) {
+                self.check_expected(path, ExpectedOperation::Rewrite, step_id)?;
+            }
+            self.ensure_range_preimage(step_id, path, offset, len)?;
+
+            // Pure appends do not overwrite existing data.
+            if let Some(size) = file_size.filter(|&size| offset < size) {
+                self.check_overwrite_safeguards(path, size, step_id)?;
             }
+            self.check_protected_path(path, PathOperation::Write, step_id)?;
         }
         Ok(())
     }
 
+    fn pre_append(&self, path: &Path) -> Result<()> {
+        // Nothing before the current end is overwritten, so a range capture
+        // there stores no data, only the size to truncate back to.
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_file() => self.pre_write_range(path, metadata.len(), 0),
+            _ => self.pre_write(path),
+        }
+    }
+
     fn pre_unlink(&self, path: &Path, is_dir: bool) -> Result<()> {
-        let active = self.inner.lock().unwrap().active_step;
+        let active = self.inner.lock().unwrap().target_step();
         if let Some(step_id) = active {
-            self.ensure_preimage(path)?;
+            self.check_step_operations(step_id)?;
+            self.check_expected(path, ExpectedOperation::Delete, step_id)?;
+            self.ensure_preimage(step_id, path)?;
             if is_dir {
-                self.capture_tree_preimages(path)?;
+                self.capture_tree_preimages(step_id, path)?;
             }
 
+            let overrun = {
+                let bytes = tree_size(path);
+                let mut inner = self.inner.lock().unwrap();
+                inner.safeguard_tracker.charge_delete(step_id, bytes)
+            };
+            self.enforce_budget(overrun, step_id)?;
+
             let relative = self.relative_path_str(path);
             let event = {
                 let mut inner = self.inner.lock().unwrap();
                 inner.safeguard_tracker.check_delete(&relative, step_id)
             };
             self.handle_safeguard_event(event)?;
+            self.check_protected_path(path, PathOperation::Delete, step_id)?;
         }
         Ok(())
     }
 
     fn pre_rename(&self, from: &Path, to: &Path) -> Result<()> {
-        let active = self.inner.lock().unwrap().active_step;
+        let active = self.inner.lock().unwrap().target_step();
         if let Some(step_id) = active {
-            let destination_exists = to.symlink_metadata().is_ok();
-            self.ensure_preimage(from)?;
+            self.check_step_operations(step_id)?;
+            // A case-only rename finds its own source at the destination.
+            let case_only = self.is_case_only_rename(from, to);
+            let destination = (!case_only).then(|| to.symlink_metadata().ok()).flatten();
+            let destination_exists = destination.is_some();
+            if destination_exists {
+                self.check_expected(to, ExpectedOperation::Rewrite, step_id)?;
+            }
+            self.ensure_preimage(step_id, from)?;
             if destination_exists {
-                self.ensure_preimage(to)?;
+                self.ensure_preimage(step_id, to)?;
+            }
+            // A backend replacing a directory destination (a file renamed
+            // over it, or a directory that was not empty) removes its whole
+            // tree, so all of it is captured, not just the directory.
+            if destination.as_ref().is_some_and(|metadata| metadata.is_dir()) {
+                self.capture_tree_preimages(step_id, to)?;
             }
-            if from.is_dir() {
-                self.capture_tree_preimages(from)?;
+            // Symlinks to directories move as links, without their target.
+            let is_dir = from.symlink_metadata().is_ok_and(|metadata| metadata.is_dir());
+            let deferred = is_dir
+                && !destination_exists
+                && !case_only
+                && self.defer_dir_rename(step_id, from, to);
+            if is_dir && !deferred {
+                self.capture_tree_preimages(step_id, from)?;
+            }
+            if case_only {
+                self.record_case_only_rename(step_id, to, is_dir)?;
             }
 
             if destination_exists {
                 let source_rel = self.relative_path_str(from);
                 let dest_rel = self.relative_path_str(to);
+                let overrun = {
+                    let bytes = tree_size(to);
+                    let mut inner = self.inner.lock().unwrap();
+                    inner.safeguard_tracker.charge_overwrite(step_id, &dest_rel, bytes)
+                };
+                self.enforce_budget(overrun, step_id)?;
+
                 let event = {
                     let mut inner = self.inner.lock().unwrap();
                     inner
@@ -1138,100 +3248,146 @@ impl WriteInterceptor for UndoInterceptor {
                         .check_rename_over(&source_rel, &dest_rel, step_id)
                 };
                 self.handle_safeguard_event(event)?;
+
+                let event = {
+                    let mut inner = self.inner.lock().unwrap();
+                    inner.safeguard_tracker.check_overwrite_count(&dest_rel, step_id)
+                };
+                self.handle_safeguard_event(event)?;
+            }
+            self.check_protected_path(from, PathOperation::Rename, step_id)?;
+            self.check_protected_path(to, PathOperation::Rename, step_id)?;
+        }
+        Ok(())
+    }
+
+    fn post_rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            if !self.record_dir_rename(step_id, from, to)? {
+                self.record_tree_creation(step_id, to)?;
             }
         }
         Ok(())
     }
 
     fn post_create(&self, path: &Path) -> Result<()> {
-        let has_active = self.inner.lock().unwrap().active_step.is_some();
-        if has_active {
-            self.record_creation(path)?;
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            self.record_creation(step_id, path)?;
+            self.check_protected_path(path, PathOperation::Write, step_id)?;
         }
         Ok(())
     }
 
     fn post_mkdir(&self, path: &Path) -> Result<()> {
-        let has_active = self.inner.lock().unwrap().active_step.is_some();
-        if has_active {
-            self.record_creation(path)?;
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            self.record_creation(step_id, path)?;
         }
         Ok(())
     }
 
     fn pre_setattr(&self, path: &Path) -> Result<()> {
-        let has_active = self.inner.lock().unwrap().active_step.is_some();
-        if has_active {
-            self.ensure_preimage(path)?;
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            self.ensure_preimage(step_id, path)?;
+        }
+        Ok(())
+    }
+
+    fn pre_setattr_metadata(&self, path: &Path) -> Result<()> {
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            self.ensure_metadata_preimage(step_id, path)?;
         }
         Ok(())
     }
 
-    fn pre_link(&self, target: &Path, _link_path: &Path) -> Result<()> {
-        if self.symlink_policy == SymlinkPolicy::Ignore {
+    fn pre_link(&self, target: &Path, link_path: &Path) -> Result<()> {
+        if self.symlink_policy() == SymlinkPolicy::Ignore {
             return Ok(());
         }
-        let has_active = self.inner.lock().unwrap().active_step.is_some();
-        if has_active {
-            self.ensure_preimage(target)?;
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            self.ensure_preimage(step_id, target)?;
+            // The new name did not exist before the step, so rollback removes it.
+            if link_path.symlink_metadata().is_err() {
+                self.record_creation(step_id, link_path)?;
+            }
         }
         Ok(())
     }
 
     fn post_symlink(&self, _target: &Path, link_path: &Path) -> Result<()> {
-        if self.symlink_policy == SymlinkPolicy::Ignore {
+        if self.symlink_policy() == SymlinkPolicy::Ignore {
             return Ok(());
         }
-        let has_active = self.inner.lock().unwrap().active_step.is_some();
-        if has_active {
-            self.record_creation(link_path)?;
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            self.record_creation(step_id, link_path)?;
         }
         Ok(())
     }
 
     fn pre_xattr(&self, path: &Path) -> Result<()> {
-        let has_active = self.inner.lock().unwrap().active_step.is_some();
-        if has_active {
-            self.ensure_preimage(path)?;
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            self.ensure_metadata_preimage(step_id, path)?;
         }
         Ok(())
     }
 
     fn pre_open_trunc(&self, path: &Path) -> Result<()> {
-        let active = self.inner.lock().unwrap().active_step;
+        let active = self.inner.lock().unwrap().target_step();
         if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
             let file_size = path.metadata().map(|m| m.len()).ok();
-            self.ensure_preimage(path)?;
+            if file_size.is_some() {
+                self.check_expected(path, ExpectedOperation::Rewrite, step_id)?;
+            }
+            self.ensure_preimage(step_id, path)?;
 
             if let Some(size) = file_size {
-                let relative = self.relative_path_str(path);
-                let event = {
-                    let mut inner = self.inner.lock().unwrap();
-                    inner.safeguard_tracker.check_overwrite(&relative, size, step_id)
-                };
-                self.handle_safeguard_event(event)?;
+                self.check_overwrite_safeguards(path, size, step_id)?;
             }
+            self.check_protected_path(path, PathOperation::Write, step_id)?;
         }
         Ok(())
     }
 
     fn pre_fallocate(&self, path: &Path) -> Result<()> {
-        let has_active = self.inner.lock().unwrap().active_step.is_some();
-        if has_active {
-            self.ensure_preimage(path)?;
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            self.ensure_preimage(step_id, path)?;
+            self.check_protected_path(path, PathOperation::Write, step_id)?;
         }
         Ok(())
     }
 
     fn pre_copy_file_range(&self, dst_path: &Path) -> Result<()> {
-        let has_active = self.inner.lock().unwrap().active_step.is_some();
-        if has_active {
-            self.ensure_preimage(dst_path)?;
+        let active = self.inner.lock().unwrap().target_step();
+        if let Some(step_id) = active {
+            self.check_step_operations(step_id)?;
+            self.ensure_preimage(step_id, dst_path)?;
+            self.check_protected_path(dst_path, PathOperation::Write, step_id)?;
         }
         Ok(())
     }
 
     fn current_step(&self) -> Option<StepId> {
-        self.inner.lock().unwrap().active_step
+        self.inner.lock().unwrap().target_step()
+    }
+
+    fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
     }
 }
@@ -11,12 +11,37 @@ pub trait WriteInterceptor: Send + Sync {
     /// Called before a file is written or truncated.
     fn pre_write(&self, path: &Path) -> Result<()>;
 
+    /// Called before `len` bytes are written at `offset`. Implementations may
+    /// capture only the affected byte range instead of the whole file. The
+    /// default falls back to a whole-file `pre_write`.
+    fn pre_write_range(&self, path: &Path, offset: u64, len: u64) -> Result<()> {
+        let _ = (offset, len);
+        self.pre_write(path)
+    }
+
+    /// Called before a write through a handle opened with O_APPEND, which
+    /// lands at the end of the file whatever offset it carries.
+    /// Implementations may record only the original length, for rollback
+    /// to truncate back to. The default falls back to a whole-file
+    /// `pre_write`.
+    fn pre_append(&self, path: &Path) -> Result<()> {
+        self.pre_write(path)
+    }
+
     /// Called before a file or directory is deleted.
     fn pre_unlink(&self, path: &Path, is_dir: bool) -> Result<()>;
 
     /// Called before a rename. Records state of both source and destination.
     fn pre_rename(&self, from: &Path, to: &Path) -> Result<()>;
 
+    /// Called after a rename succeeded. Implementations may record a
+    /// directory rename here instead of capturing the tree in `pre_rename`,
+    /// so callers of `pre_rename` must call this too. Defaults to no-op.
+    fn post_rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let _ = (from, to);
+        Ok(())
+    }
+
     /// Called after a file is created (genuinely new inode).
     fn post_create(&self, path: &Path) -> Result<()>;
 
@@ -26,6 +51,13 @@ pub trait WriteInterceptor: Send + Sync {
     /// Called before attributes are changed (chmod, chown, truncate, utimes).
     fn pre_setattr(&self, path: &Path) -> Result<()>;
 
+    /// Called before attributes are changed without truncating (chmod,
+    /// chown, utimes). Implementations may capture only the attributes
+    /// instead of the contents. The default falls back to `pre_setattr`.
+    fn pre_setattr_metadata(&self, path: &Path) -> Result<()> {
+        self.pre_setattr(path)
+    }
+
     /// Called before a hard link is created.
     fn pre_link(&self, target: &Path, link_path: &Path) -> Result<()>;
 
@@ -46,4 +78,10 @@ pub trait WriteInterceptor: Send + Sync {
 
     /// Query the current active step.
     fn current_step(&self) -> Option<StepId>;
+
+    /// Whether paths under the working root compare case-insensitively, for
+    /// backends that keep their own path tables. Defaults to false.
+    fn is_case_insensitive(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,81 @@
+use std::fs;
+use std::path::Path;
+
+use codeagent_interceptor::preimage::path_hash;
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_test_support::snapshot::assert_tree_eq;
+use codeagent_test_support::workspace::TempWorkspace;
+
+mod common;
+use common::{OperationApplier, compare_opts};
+
+fn step_blob(ws: &TempWorkspace, step_id: u64, relative: &str) -> std::path::PathBuf {
+    let hash = path_hash(Path::new(relative));
+    ws.undo_dir
+        .join("steps")
+        .join(step_id.to_string())
+        .join("preimages")
+        .join(format!("{hash}.dat"))
+}
+
+// ---------------------------------------------------------------------------
+// BC-01: Unchanged contents captured again reuse the earlier blob
+// ---------------------------------------------------------------------------
+#[test]
+fn bc_01_rewritten_file_reuses_blob() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("generated.rs");
+    let contents = "pub const TABLE: &[u8] = &[1, 2, 3];\n".repeat(200);
+    fs::write(&target, &contents).unwrap();
+    let before = ws.snapshot();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    // Each step regenerates the file with the same contents.
+    for step in 1..=3 {
+        interceptor.open_step(step).unwrap();
+        ops.write_file(&target, contents.as_bytes());
+        interceptor.close_step(step).unwrap();
+    }
+
+    let first = fs::read(step_blob(&ws, 1, "generated.rs")).unwrap();
+    for step in 2..=3 {
+        assert_eq!(fs::read(step_blob(&ws, step, "generated.rs")).unwrap(), first);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |step| fs::metadata(step_blob(&ws, step, "generated.rs")).unwrap().ino();
+        assert_eq!(inode(1), inode(3));
+    }
+
+    interceptor.rollback(3, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// BC-02: Discarding the undo log leaves nothing stale in the cache
+// ---------------------------------------------------------------------------
+#[test]
+fn bc_02_discard_drops_cached_blobs() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("config.toml");
+    fs::write(&target, "answer = 42\n").unwrap();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&target, b"answer = 42\n");
+    interceptor.close_step(1).unwrap();
+    interceptor.discard().unwrap();
+
+    // Step IDs start over after a discard.
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&target, b"answer = 43\n");
+    interceptor.close_step(1).unwrap();
+
+    let blob = fs::read(step_blob(&ws, 1, "config.toml")).unwrap();
+    assert_eq!(zstd::decode_all(blob.as_slice()).unwrap(), b"answer = 42\n");
+}
@@ -0,0 +1,163 @@
+use std::fs;
+
+use codeagent_common::{CoherentCaptureConfig, CoherentCaptureRule, CoherentCaptureStrategy};
+use codeagent_interceptor::manifest::{StepManifest, WARNING_INCOHERENT_CAPTURE};
+use codeagent_interceptor::undo_interceptor::{UndoConfig, UndoInterceptor};
+use codeagent_test_support::workspace::TempWorkspace;
+
+mod common;
+use common::OperationApplier;
+
+/// Read the step manifest back from the completed steps directory.
+fn read_step_manifest(ws: &TempWorkspace, step_id: u64) -> StepManifest {
+    let step_dir = ws.undo_dir.join("steps").join(step_id.to_string());
+    StepManifest::read_from(&step_dir).unwrap()
+}
+
+fn interceptor_with_rule(
+    ws: &TempWorkspace,
+    pattern: &str,
+    strategy: CoherentCaptureStrategy,
+) -> UndoInterceptor {
+    UndoInterceptor::new(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+        UndoConfig {
+            coherent_capture: CoherentCaptureConfig {
+                rules: vec![CoherentCaptureRule {
+                    pattern: pattern.to_string(),
+                    strategy,
+                }],
+                max_attempts: 2,
+                retry_interval_ms: 1,
+            },
+            ..Default::default()
+        },
+    )
+}
+
+/// Hold an open-file-description write lock on `file`. OFD locks conflict
+/// with traditional POSIX record locks even within the same process, which
+/// lets a single test process simulate a concurrent database writer.
+#[cfg(target_os = "linux")]
+fn hold_ofd_write_lock(file: &fs::File) {
+    use std::os::unix::io::AsRawFd;
+
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &lock) };
+    assert_eq!(result, 0, "failed to take OFD lock: {}", std::io::Error::last_os_error());
+}
+
+// ---------------------------------------------------------------------------
+// CO-01: Quiescent file matching a rule is captured without warnings
+// ---------------------------------------------------------------------------
+#[test]
+fn co_01_quiescent_file_captured_without_warning() {
+    let ws = TempWorkspace::new();
+    let database = ws.working_dir.join("app.sqlite");
+    fs::write(&database, b"original pages").unwrap();
+
+    let interceptor =
+        interceptor_with_rule(&ws, "*.sqlite", CoherentCaptureStrategy::RetryUntilQuiescent);
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&database, b"modified pages");
+    interceptor.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert!(manifest.contains_path("app.sqlite"));
+    assert!(manifest.warnings.is_empty());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&database).unwrap(), b"original pages");
+}
+
+// ---------------------------------------------------------------------------
+// CO-02: Lock contention records a warning but still captures the preimage
+// ---------------------------------------------------------------------------
+#[cfg(target_os = "linux")]
+#[test]
+fn co_02_lock_contention_records_warning() {
+    let ws = TempWorkspace::new();
+    let database = ws.working_dir.join("app.db");
+    fs::write(&database, b"original pages").unwrap();
+
+    let interceptor = interceptor_with_rule(&ws, "*.db", CoherentCaptureStrategy::AdvisoryLock);
+    let ops = OperationApplier::new(&interceptor);
+
+    let writer = fs::OpenOptions::new().read(true).write(true).open(&database).unwrap();
+    hold_ofd_write_lock(&writer);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&database, b"modified pages");
+    interceptor.close_step(1).unwrap();
+    drop(writer);
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert_eq!(manifest.warnings.len(), 1);
+    assert_eq!(manifest.warnings[0].path, "app.db");
+    assert_eq!(manifest.warnings[0].code, WARNING_INCOHERENT_CAPTURE);
+
+    // The capture still happened, so the step remains undoable.
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&database).unwrap(), b"original pages");
+}
+
+// ---------------------------------------------------------------------------
+// CO-03: Paths that match no rule are captured with a plain read
+// ---------------------------------------------------------------------------
+#[cfg(target_os = "linux")]
+#[test]
+fn co_03_non_matching_path_ignores_locks() {
+    let ws = TempWorkspace::new();
+    let source = ws.working_dir.join("main.rs");
+    fs::write(&source, b"fn main() {}").unwrap();
+
+    let interceptor = interceptor_with_rule(&ws, "*.db", CoherentCaptureStrategy::AdvisoryLock);
+    let ops = OperationApplier::new(&interceptor);
+
+    let writer = fs::OpenOptions::new().read(true).write(true).open(&source).unwrap();
+    hold_ofd_write_lock(&writer);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&source, b"fn main() { todo!() }");
+    interceptor.close_step(1).unwrap();
+    drop(writer);
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert!(manifest.contains_path("main.rs"));
+    assert!(manifest.warnings.is_empty());
+}
+
+// ---------------------------------------------------------------------------
+// CO-04: Rules match nested paths via glob patterns
+// ---------------------------------------------------------------------------
+#[test]
+fn co_04_nested_path_matches_rule() {
+    let ws = TempWorkspace::new();
+    let leveldb_dir = ws.working_dir.join("data").join("leveldb");
+    fs::create_dir_all(&leveldb_dir).unwrap();
+    let log_file = leveldb_dir.join("000003.log");
+    fs::write(&log_file, b"log records").unwrap();
+
+    let interceptor = interceptor_with_rule(
+        &ws,
+        "data/leveldb/*",
+        CoherentCaptureStrategy::RetryUntilQuiescent,
+    );
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&log_file, b"more log records");
+    interceptor.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert!(manifest.contains_path("data/leveldb/000003.log"));
+    assert!(manifest.warnings.is_empty());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&log_file).unwrap(), b"log records");
+}
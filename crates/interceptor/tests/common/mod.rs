@@ -3,7 +3,9 @@
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::Path;
+use std::sync::Mutex;
 
+use codeagent_common::OperationMonitor;
 use codeagent_interceptor::undo_interceptor::UndoInterceptor;
 use codeagent_interceptor::write_interceptor::WriteInterceptor;
 use codeagent_test_support::snapshot::SnapshotCompareOptions;
@@ -27,6 +29,25 @@ impl<'a> OperationApplier<'a> {
         fs::write(path, contents).unwrap();
     }
 
+    /// Write `contents` at `offset` in an existing file without truncating it
+    /// (positional write, as issued by a guest `pwrite`).
+    pub fn write_range(&self, path: &Path, offset: u64, contents: &[u8]) {
+        use std::io::{Seek, SeekFrom};
+        self.interceptor
+            .pre_write_range(path, offset, contents.len() as u64)
+            .unwrap();
+        let mut file = File::options().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(contents).unwrap();
+    }
+
+    /// Append `contents` to an existing file through an O_APPEND handle.
+    pub fn append(&self, path: &Path, contents: &[u8]) {
+        self.interceptor.pre_append(path).unwrap();
+        let mut file = File::options().append(true).open(path).unwrap();
+        file.write_all(contents).unwrap();
+    }
+
     /// Create a brand-new file (that didn't exist before) and write contents.
     pub fn create_file(&self, path: &Path, contents: &[u8]) {
         if let Some(parent) = path.parent() {
@@ -58,16 +79,23 @@ impl<'a> OperationApplier<'a> {
 
     /// Rename a file or directory.
     pub fn rename(&self, from: &Path, to: &Path) {
-        let is_dir = from.is_dir();
         self.interceptor.pre_rename(from, to).unwrap();
         fs::rename(from, to).unwrap();
+        self.interceptor.post_rename(from, to).unwrap();
+    }
 
-        // Record destination entries as created (they are new at those paths).
-        if is_dir {
-            self.record_tree_creation(to);
-        } else {
-            self.interceptor.post_create(to).unwrap();
+    /// Rename a file or directory over a destination of any type, removing
+    /// it first, as a backend emulating replace semantics does where
+    /// `rename(2)` would refuse (a non-empty directory, a type mismatch).
+    pub fn rename_replacing(&self, from: &Path, to: &Path) {
+        self.interceptor.pre_rename(from, to).unwrap();
+        match to.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(to).unwrap(),
+            Ok(_) => fs::remove_file(to).unwrap(),
+            Err(_) => {}
         }
+        fs::rename(from, to).unwrap();
+        self.interceptor.post_rename(from, to).unwrap();
     }
 
     /// Open an existing file with O_TRUNC (truncates to zero length).
@@ -87,10 +115,16 @@ impl<'a> OperationApplier<'a> {
     #[cfg(unix)]
     pub fn chmod(&self, path: &Path, mode: u32) {
         use std::os::unix::fs::PermissionsExt;
-        self.interceptor.pre_setattr(path).unwrap();
+        self.interceptor.pre_setattr_metadata(path).unwrap();
         fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
     }
 
+    /// Set the access and modification times of a file (utimes).
+    pub fn set_times(&self, path: &Path, atime: filetime::FileTime, mtime: filetime::FileTime) {
+        self.interceptor.pre_setattr_metadata(path).unwrap();
+        filetime::set_file_times(path, atime, mtime).unwrap();
+    }
+
     /// Extend a file to a larger size via fallocate.
     pub fn fallocate(&self, path: &Path, new_len: u64) {
         self.interceptor.pre_fallocate(path).unwrap();
@@ -121,6 +155,12 @@ impl<'a> OperationApplier<'a> {
         xattr::remove(path, key).unwrap();
     }
 
+    /// Create a hard link to `target` at `link_path`.
+    pub fn hard_link(&self, target: &Path, link_path: &Path) {
+        self.interceptor.pre_link(target, link_path).unwrap();
+        fs::hard_link(target, link_path).unwrap();
+    }
+
     /// Create a symlink pointing to `target` at `link_path`.
     pub fn create_symlink(&self, target: &Path, link_path: &Path) {
         #[cfg(unix)]
@@ -129,20 +169,6 @@ impl<'a> OperationApplier<'a> {
         std::os::windows::fs::symlink_file(target, link_path).unwrap();
         self.interceptor.post_symlink(target, link_path).unwrap();
     }
-
-    /// Recursively record all entries under a directory as newly created.
-    pub fn record_tree_creation(&self, dir: &Path) {
-        self.interceptor.post_mkdir(dir).unwrap();
-        for entry in fs::read_dir(dir).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_dir() {
-                self.record_tree_creation(&path);
-            } else {
-                self.interceptor.post_create(&path).unwrap();
-            }
-        }
-    }
 }
 
 /// Snapshot comparison options that ignore mtime (filesystem operations alter mtimes
@@ -153,3 +179,29 @@ pub fn compare_opts() -> SnapshotCompareOptions {
         ..SnapshotCompareOptions::default()
     }
 }
+
+/// Records progress, and reports itself cancelled once it has seen
+/// `cancel_after` progress reports.
+pub struct CancellingMonitor {
+    cancel_after: usize,
+    pub reports: Mutex<Vec<(u8, Option<String>)>>,
+}
+
+impl CancellingMonitor {
+    pub fn new(cancel_after: usize) -> Self {
+        Self {
+            cancel_after,
+            reports: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl OperationMonitor for CancellingMonitor {
+    fn is_cancelled(&self) -> bool {
+        self.reports.lock().unwrap().len() >= self.cancel_after
+    }
+
+    fn progress(&self, percent: u8, current_path: Option<&str>) {
+        self.reports.lock().unwrap().push((percent, current_path.map(String::from)));
+    }
+}
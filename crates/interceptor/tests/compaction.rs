@@ -0,0 +1,195 @@
+//! Undo log compaction tests (CP-01..CP-04).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use codeagent_common::{OperationMonitor, Unmonitored};
+use codeagent_interceptor::compaction::{COMPACTED_FILE, CompactedStep, CompactionOptions};
+use codeagent_interceptor::preimage::path_hash;
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_test_support::snapshot::assert_tree_eq;
+use codeagent_test_support::workspace::TempWorkspace;
+
+mod common;
+use common::{OperationApplier, compare_opts};
+
+/// Source text that compresses noticeably better at high zstd levels.
+fn source_text(seed: usize) -> String {
+    (0..1500)
+        .map(|i| format!("let value_{i} = compute({}, \"{}\");\n", i * seed % 97, i % 13))
+        .collect()
+}
+
+fn step_dir(ws: &TempWorkspace, step_id: i64) -> PathBuf {
+    ws.undo_dir.join("steps").join(step_id.to_string())
+}
+
+fn step_blob(ws: &TempWorkspace, step_id: i64, relative: &str) -> PathBuf {
+    let hash = path_hash(Path::new(relative));
+    step_dir(ws, step_id).join("preimages").join(format!("{hash}.dat"))
+}
+
+/// Cancels once `after` files have been reported.
+struct PauseAfter {
+    after: usize,
+    seen: AtomicUsize,
+}
+
+impl OperationMonitor for PauseAfter {
+    fn is_cancelled(&self) -> bool {
+        self.seen.load(Ordering::SeqCst) >= self.after
+    }
+
+    fn progress(&self, _percent: u8, current_path: Option<&str>) {
+        if current_path.is_some() {
+            self.seen.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// CP-01: Old steps are recompressed and still roll back
+// ---------------------------------------------------------------------------
+#[test]
+fn cp_01_old_steps_are_recompressed() {
+    let ws = TempWorkspace::new();
+    fs::write(ws.working_dir.join("lib.rs"), source_text(3)).unwrap();
+    fs::write(ws.working_dir.join("main.rs"), source_text(5)).unwrap();
+    let before = ws.snapshot();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    for (id, name) in [(1, "lib.rs"), (2, "main.rs"), (3, "lib.rs")] {
+        interceptor.open_step(id).unwrap();
+        ops.write_file(&ws.working_dir.join(name), source_text(id as usize + 10).as_bytes());
+        interceptor.close_step(id).unwrap();
+    }
+    let blob_size = fs::metadata(step_blob(&ws, 1, "lib.rs")).unwrap().len();
+
+    let report = interceptor.compact(&CompactionOptions::default(), &Unmonitored).unwrap();
+    assert_eq!(report.steps_compacted, 2);
+    assert!(report.files_recompressed >= 2);
+    assert!(report.bytes_saved > 0);
+    assert!(report.corrupt_paths.is_empty() && !report.paused);
+    assert!(fs::metadata(step_blob(&ws, 1, "lib.rs")).unwrap().len() < blob_size);
+    assert_eq!(CompactedStep::read_from(&step_dir(&ws, 2)).unwrap().level, 19);
+    assert!(!step_dir(&ws, 3).join(COMPACTED_FILE).exists());
+
+    let again = interceptor.compact(&CompactionOptions::default(), &Unmonitored).unwrap();
+    assert_eq!((again.steps_compacted, again.files_recompressed), (0, 0));
+
+    interceptor.rollback(3, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// CP-02: Identical blobs of different steps are stored once
+// ---------------------------------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn cp_02_identical_blobs_are_linked() {
+    use std::os::unix::fs::MetadataExt;
+
+    let ws = TempWorkspace::new();
+    fs::write(ws.working_dir.join("a.rs"), source_text(7)).unwrap();
+    fs::write(ws.working_dir.join("b.rs"), source_text(7)).unwrap();
+    let before = ws.snapshot();
+    {
+        let interceptor =
+            UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        let ops = OperationApplier::new(&interceptor);
+        interceptor.open_step(1).unwrap();
+        ops.write_file(&ws.working_dir.join("a.rs"), b"rewritten");
+        interceptor.close_step(1).unwrap();
+    }
+    // A new interceptor starts with an empty blob cache, so the same
+    // contents are compressed into a blob of their own.
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    interceptor.recover().unwrap();
+    let ops = OperationApplier::new(&interceptor);
+    for (id, name) in [(2, "b.rs"), (3, "a.rs")] {
+        interceptor.open_step(id).unwrap();
+        ops.write_file(&ws.working_dir.join(name), b"rewritten again");
+        interceptor.close_step(id).unwrap();
+    }
+
+    let report = interceptor.compact(&CompactionOptions::default(), &Unmonitored).unwrap();
+    assert_eq!(report.blobs_deduplicated, 1);
+    let first = fs::metadata(step_blob(&ws, 1, "a.rs")).unwrap();
+    let second = fs::metadata(step_blob(&ws, 2, "b.rs")).unwrap();
+    assert_eq!(first.ino(), second.ino());
+
+    interceptor.rollback(3, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// CP-03: Blobs failing their checksum are reported and left alone
+// ---------------------------------------------------------------------------
+#[test]
+fn cp_03_corrupt_blobs_are_reported() {
+    let ws = TempWorkspace::new();
+    fs::write(ws.working_dir.join("kept.rs"), source_text(2)).unwrap();
+    fs::write(ws.working_dir.join("rotten.rs"), source_text(4)).unwrap();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("kept.rs"), b"changed");
+    ops.write_file(&ws.working_dir.join("rotten.rs"), b"changed");
+    interceptor.close_step(1).unwrap();
+    let rotten = step_blob(&ws, 1, "rotten.rs");
+    let bit_rot = zstd::encode_all(&b"bit rot"[..], 3).unwrap();
+    fs::write(&rotten, &bit_rot).unwrap();
+
+    let options = CompactionOptions {
+        keep_recent_steps: 0,
+        ..CompactionOptions::default()
+    };
+    let report = interceptor.compact(&options, &Unmonitored).unwrap();
+    assert_eq!(report.corrupt_paths, vec!["rotten.rs".to_string()]);
+    assert_eq!(report.steps_compacted, 1);
+    assert_eq!(fs::read(&rotten).unwrap(), bit_rot);
+    let compacted = CompactedStep::read_from(&step_dir(&ws, 1)).unwrap();
+    let kept_blob = format!("{}.dat", path_hash(Path::new("kept.rs")));
+    assert!(compacted.blobs.contains_key(&kept_blob));
+    assert!(!compacted.blobs.contains_key(rotten.file_name().unwrap().to_str().unwrap()));
+}
+
+// ---------------------------------------------------------------------------
+// CP-04: A paused run is resumed by the next one
+// ---------------------------------------------------------------------------
+#[test]
+fn cp_04_paused_compaction_resumes() {
+    let ws = TempWorkspace::new();
+    let names = ["one.rs", "two.rs", "three.rs"];
+    for (seed, name) in names.iter().enumerate() {
+        fs::write(ws.working_dir.join(name), source_text(seed + 1)).unwrap();
+    }
+    let before = ws.snapshot();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    interceptor.open_step(1).unwrap();
+    for name in names {
+        ops.write_file(&ws.working_dir.join(name), b"changed");
+    }
+    interceptor.close_step(1).unwrap();
+    let options = CompactionOptions {
+        keep_recent_steps: 0,
+        ..CompactionOptions::default()
+    };
+
+    let monitor = PauseAfter { after: 1, seen: AtomicUsize::new(0) };
+    let report = interceptor.compact(&options, &monitor).unwrap();
+    assert!(report.paused);
+    // The preimage and postimage of the first path.
+    assert_eq!((report.steps_compacted, report.files_recompressed), (0, 2));
+    assert!(!step_dir(&ws, 1).join(COMPACTED_FILE).exists());
+
+    let report = interceptor.compact(&options, &Unmonitored).unwrap();
+    assert!(!report.paused);
+    // The three postimages are the same "changed": two become links.
+    assert_eq!((report.steps_compacted, report.files_recompressed), (1, 4));
+    assert_eq!(report.blobs_deduplicated, 2);
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
@@ -0,0 +1,117 @@
+use codeagent_common::{CodeAgentError, GitMirrorConfig};
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_test_support::workspace::TempWorkspace;
+
+#[cfg(feature = "git-mirror")]
+mod common;
+
+fn enabled() -> GitMirrorConfig {
+    GitMirrorConfig {
+        enabled: true,
+        ..GitMirrorConfig::default()
+    }
+}
+
+/// Run git in `dir`, returning its trimmed stdout.
+#[cfg(feature = "git-mirror")]
+fn git(dir: &std::path::Path, args: &[&str]) -> String {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .env("GIT_AUTHOR_NAME", "test")
+        .env("GIT_AUTHOR_EMAIL", "test@localhost")
+        .env("GIT_COMMITTER_NAME", "test")
+        .env("GIT_COMMITTER_EMAIL", "test@localhost")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {args:?}: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// A workspace whose working directory is a git repository with one commit
+/// of `tracked.txt`.
+#[cfg(feature = "git-mirror")]
+fn git_workspace() -> TempWorkspace {
+    let ws = TempWorkspace::new();
+    git(&ws.working_dir, &["init", "-q"]);
+    std::fs::write(ws.working_dir.join("tracked.txt"), "committed").unwrap();
+    git(&ws.working_dir, &["add", "tracked.txt"]);
+    git(&ws.working_dir, &["commit", "-q", "-m", "initial"]);
+    ws
+}
+
+// ---------------------------------------------------------------------------
+// GM-01: Each closed step is committed to the mirror ref with its command
+// ---------------------------------------------------------------------------
+#[cfg(feature = "git-mirror")]
+#[test]
+fn gm_01_closed_steps_are_committed_to_the_ref() {
+    use common::OperationApplier;
+
+    let ws = git_workspace();
+    let head_before = git(&ws.working_dir, &["rev-parse", "HEAD"]);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    interceptor.set_git_mirror(&enabled()).unwrap();
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    interceptor.set_step_command("make build".to_string());
+    ops.create_file(&ws.working_dir.join("out.txt"), b"built");
+    ops.write_file(&ws.working_dir.join("tracked.txt"), b"edited");
+    interceptor.close_step(1).unwrap();
+
+    interceptor.open_step(2).unwrap();
+    ops.delete_file(&ws.working_dir.join("out.txt"));
+    interceptor.close_step(2).unwrap();
+
+    let log = git(&ws.working_dir, &["log", "--format=%s", "refs/codeagent/history"]);
+    assert_eq!(log.lines().collect::<Vec<_>>(), ["step 2", "make build", "initial"]);
+    let first = "refs/codeagent/history~1";
+    assert_eq!(git(&ws.working_dir, &["show", &format!("{first}:out.txt")]), "built");
+    assert_eq!(git(&ws.working_dir, &["show", &format!("{first}:tracked.txt")]), "edited");
+    let files = git(&ws.working_dir, &["ls-tree", "--name-only", "refs/codeagent/history"]);
+    assert_eq!(files, "tracked.txt");
+
+    // HEAD, the branch and the user's index are left alone.
+    assert_eq!(git(&ws.working_dir, &["rev-parse", "HEAD"]), head_before);
+    assert_eq!(git(&ws.working_dir, &["diff", "--cached", "--name-only"]), "");
+}
+
+// ---------------------------------------------------------------------------
+// GM-02: Rollback leaves the mirror alone; a disabled mirror stops committing
+// ---------------------------------------------------------------------------
+#[cfg(feature = "git-mirror")]
+#[test]
+fn gm_02_rollback_and_disabling_leave_the_mirror() {
+    use common::OperationApplier;
+
+    let ws = git_workspace();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    interceptor.set_git_mirror(&enabled()).unwrap();
+    let ops = OperationApplier::new(&interceptor);
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("tracked.txt"), b"edited");
+    interceptor.close_step(1).unwrap();
+    let mirrored = git(&ws.working_dir, &["rev-parse", "refs/codeagent/history"]);
+
+    interceptor.rollback(1, false).unwrap();
+    interceptor.set_git_mirror(&GitMirrorConfig::default()).unwrap();
+    interceptor.open_step(2).unwrap();
+    ops.write_file(&ws.working_dir.join("tracked.txt"), b"unmirrored");
+    interceptor.close_step(2).unwrap();
+
+    assert_eq!(git(&ws.working_dir, &["rev-parse", "refs/codeagent/history"]), mirrored);
+}
+
+// ---------------------------------------------------------------------------
+// GM-03: Turning on the mirror fails outside a git work tree
+// ---------------------------------------------------------------------------
+#[test]
+fn gm_03_mirror_needs_a_git_work_tree() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let error = interceptor.set_git_mirror(&enabled()).unwrap_err();
+    assert!(matches!(error, CodeAgentError::GitMirror { .. }), "{error}");
+    interceptor.set_git_mirror(&GitMirrorConfig::default()).unwrap();
+}
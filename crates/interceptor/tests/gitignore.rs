@@ -308,3 +308,151 @@ fn gi_08_gitignore_disabled_by_default() {
         "with gitignore disabled, ignored files SHOULD be captured"
     );
 }
+
+// ---------------------------------------------------------------------------
+// GI-09: Editing .gitignore through the hooks updates the filter mid-step
+// ---------------------------------------------------------------------------
+#[test]
+fn gi_09_gitignore_edit_reloads_filter() {
+    let ws = TempWorkspace::new();
+    write_gitignore(&ws.working_dir, "*.log\n");
+    fs::write(ws.working_dir.join("debug.log"), b"old log").unwrap();
+    fs::write(ws.working_dir.join("scratch.tmp"), b"old tmp").unwrap();
+
+    let interceptor =
+        UndoInterceptor::new(ws.working_dir.clone(), ws.undo_dir.clone(), UndoConfig {
+        gitignore: true,
+        ..Default::default()
+    });
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join(".gitignore"), b"*.tmp\n");
+    ops.write_file(&ws.working_dir.join("debug.log"), b"new log");
+    ops.write_file(&ws.working_dir.join("scratch.tmp"), b"new tmp");
+    interceptor.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert!(manifest.contains_path(".gitignore"));
+    assert!(
+        manifest.contains_path("debug.log"),
+        "no longer ignored after the edit, so it should be captured"
+    );
+    assert!(
+        !manifest.contains_path("scratch.tmp"),
+        "ignored by the new rules, so it should not be captured"
+    );
+}
+
+// ---------------------------------------------------------------------------
+// GI-10: Ignore files created or renamed into place are picked up too
+// ---------------------------------------------------------------------------
+#[test]
+fn gi_10_created_and_renamed_ignore_files_reload_filter() {
+    let ws = TempWorkspace::new();
+    let sub = ws.working_dir.join("sub");
+    fs::create_dir_all(&sub).unwrap();
+    fs::write(sub.join("cache.bin"), b"old cache").unwrap();
+    fs::write(ws.working_dir.join("secret.key"), b"old key").unwrap();
+    fs::write(ws.working_dir.join("exclude.new"), b"secret.*\n").unwrap();
+    fs::create_dir_all(ws.working_dir.join(".git").join("info")).unwrap();
+
+    let interceptor =
+        UndoInterceptor::new(ws.working_dir.clone(), ws.undo_dir.clone(), UndoConfig {
+        gitignore: true,
+        ..Default::default()
+    });
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.create_file(&sub.join(".gitignore"), b"*.bin\n");
+    ops.rename(
+        &ws.working_dir.join("exclude.new"),
+        &ws.working_dir.join(".git").join("info").join("exclude"),
+    );
+    ops.write_file(&sub.join("cache.bin"), b"new cache");
+    ops.write_file(&ws.working_dir.join("secret.key"), b"new key");
+    interceptor.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert!(!manifest.contains_path("sub/cache.bin"));
+    assert!(!manifest.contains_path("secret.key"));
+}
+
+// ---------------------------------------------------------------------------
+// GI-11: reload_gitignore picks up edits made outside the hooks
+// ---------------------------------------------------------------------------
+#[test]
+fn gi_11_explicit_reload_picks_up_outside_edits() {
+    let ws = TempWorkspace::new();
+    fs::write(ws.working_dir.join("debug.log"), b"old log").unwrap();
+
+    let interceptor =
+        UndoInterceptor::new(ws.working_dir.clone(), ws.undo_dir.clone(), UndoConfig {
+        gitignore: true,
+        ..Default::default()
+    });
+    let ops = OperationApplier::new(&interceptor);
+
+    // Not seen by the interceptor, so the filter still has no rules.
+    write_gitignore(&ws.working_dir, "*.log\n");
+    assert!(interceptor.reload_gitignore());
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("debug.log"), b"new log");
+    ops.create_file(&ws.working_dir.join("notes.txt"), b"notes");
+    interceptor.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert!(!manifest.contains_path("debug.log"));
+
+    let default = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    assert!(!default.reload_gitignore());
+}
+
+// ---------------------------------------------------------------------------
+// GI-12: Extra ignore patterns apply after .gitignore, and without it
+// ---------------------------------------------------------------------------
+#[test]
+fn gi_12_extra_patterns_merge_with_gitignore() {
+    let ws = TempWorkspace::new();
+    write_gitignore(&ws.working_dir, "*.log\n");
+    for name in ["debug.log", "keep.log", "data.bin"] {
+        fs::write(ws.working_dir.join(name), b"old").unwrap();
+    }
+
+    let interceptor =
+        UndoInterceptor::new(ws.working_dir.clone(), ws.undo_dir.clone(), UndoConfig {
+        gitignore: true,
+        ..Default::default()
+    });
+    let ops = OperationApplier::new(&interceptor);
+    interceptor.set_ignore_patterns(vec!["*.bin".to_string(), "!keep.log".to_string()]);
+    assert_eq!(interceptor.ignore_patterns(), ["*.bin", "!keep.log"]);
+
+    interceptor.open_step(1).unwrap();
+    for name in ["debug.log", "keep.log", "data.bin"] {
+        ops.write_file(&ws.working_dir.join(name), b"new");
+    }
+    interceptor.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert!(!manifest.contains_path("debug.log"));
+    assert!(!manifest.contains_path("data.bin"));
+    assert!(manifest.contains_path("keep.log"));
+
+    // Without gitignore filtering, only the patterns apply.
+    let other = TempWorkspace::new();
+    write_gitignore(&other.working_dir, "*.log\n");
+    let default = UndoInterceptor::new_default(other.working_dir.clone(), other.undo_dir.clone());
+    default.set_ignore_patterns(vec!["*.bin".to_string()]);
+    let ops = OperationApplier::new(&default);
+    default.open_step(1).unwrap();
+    ops.create_file(&other.working_dir.join("trace.log"), b"log");
+    ops.create_file(&other.working_dir.join("blob.bin"), b"bin");
+    default.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&other, 1);
+    assert!(manifest.contains_path("trace.log"));
+    assert!(!manifest.contains_path("blob.bin"));
+}
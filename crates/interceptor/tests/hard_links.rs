@@ -0,0 +1,194 @@
+//! Hard-link aware rollback tests (HL-01..HL-06).
+//!
+//! Inode identity is only tracked on Unix.
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use codeagent_common::SymlinkPolicy;
+use codeagent_interceptor::manifest::StepManifest;
+use codeagent_interceptor::undo_interceptor::{UndoConfig, UndoInterceptor};
+use codeagent_test_support::snapshot::assert_tree_eq;
+use codeagent_test_support::workspace::TempWorkspace;
+
+mod common;
+use common::{OperationApplier, compare_opts};
+
+fn same_inode(a: &Path, b: &Path) -> bool {
+    let a = fs::metadata(a).unwrap();
+    let b = fs::metadata(b).unwrap();
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+/// Create `a.txt` and a second name `b.txt` for the same inode.
+fn linked_pair(ws: &TempWorkspace) -> (std::path::PathBuf, std::path::PathBuf) {
+    let a = ws.working_dir.join("a.txt");
+    let b = ws.working_dir.join("b.txt");
+    fs::write(&a, b"shared contents").unwrap();
+    fs::hard_link(&a, &b).unwrap();
+    (a, b)
+}
+
+// ---------------------------------------------------------------------------
+// HL-01: Deleting one name is restored as a link to the surviving name
+// ---------------------------------------------------------------------------
+#[test]
+fn hl_01_deleted_name_relinked_to_survivor() {
+    let ws = TempWorkspace::new();
+    let (a, b) = linked_pair(&ws);
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.delete_file(&a);
+    interceptor.close_step(1).unwrap();
+
+    let manifest = StepManifest::read_from(&ws.undo_dir.join("steps").join("1")).unwrap();
+    let hard_link = manifest.entries["a.txt"].hard_link.as_ref().unwrap();
+    assert_eq!(hard_link.nlink, 2);
+
+    interceptor.rollback(1, false).unwrap();
+    assert!(same_inode(&a, &b));
+    assert_eq!(fs::metadata(&a).unwrap().nlink(), 2);
+    assert_eq!(fs::read(&a).unwrap(), b"shared contents");
+}
+
+// ---------------------------------------------------------------------------
+// HL-02: Deleting every name after a write restores one shared inode
+// ---------------------------------------------------------------------------
+#[test]
+fn hl_02_all_names_deleted_restored_as_links() {
+    let ws = TempWorkspace::new();
+    let (a, b) = linked_pair(&ws);
+    let before = ws.snapshot();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&a, b"rewritten through a");
+    ops.delete_file(&b);
+    ops.delete_file(&a);
+    interceptor.close_step(1).unwrap();
+
+    let manifest = StepManifest::read_from(&ws.undo_dir.join("steps").join("1")).unwrap();
+    let alias = manifest.entries["b.txt"].hard_link.as_ref().unwrap();
+    assert_eq!(alias.same_inode_as.as_deref(), Some("a.txt"));
+
+    interceptor.rollback(1, false).unwrap();
+    assert!(same_inode(&a, &b));
+    assert_eq!(fs::read(&b).unwrap(), b"shared contents");
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// HL-03: Rename over one name re-attaches it to the original inode
+// ---------------------------------------------------------------------------
+#[test]
+fn hl_03_rename_over_link_restores_link() {
+    let ws = TempWorkspace::new();
+    let (a, b) = linked_pair(&ws);
+    let replacement = ws.working_dir.join("replacement.txt");
+    fs::write(&replacement, b"replacement").unwrap();
+    let before = ws.snapshot();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.rename(&replacement, &a);
+    interceptor.close_step(1).unwrap();
+    assert!(!same_inode(&a, &b));
+
+    interceptor.rollback(1, false).unwrap();
+    assert!(same_inode(&a, &b));
+    assert!(!same_inode(&replacement, &b));
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// HL-04: A link created during the step is removed on rollback
+// ---------------------------------------------------------------------------
+#[test]
+fn hl_04_created_link_removed() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("target.txt");
+    fs::write(&target, b"content").unwrap();
+    let before = ws.snapshot();
+
+    // pre_link is a no-op under the default Ignore symlink policy.
+    let interceptor = UndoInterceptor::new(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+        UndoConfig {
+            symlink_policy: SymlinkPolicy::ReadWrite,
+            ..Default::default()
+        },
+    );
+    let ops = OperationApplier::new(&interceptor);
+
+    let link = ws.working_dir.join("link.txt");
+    interceptor.open_step(1).unwrap();
+    ops.hard_link(&target, &link);
+    interceptor.close_step(1).unwrap();
+    assert_eq!(fs::metadata(&target).unwrap().nlink(), 2);
+
+    interceptor.rollback(1, false).unwrap();
+    assert!(!link.exists());
+    assert_eq!(fs::metadata(&target).unwrap().nlink(), 1);
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// HL-05: Positional writes through both names are undone
+// ---------------------------------------------------------------------------
+#[test]
+fn hl_05_range_writes_through_both_names() {
+    let ws = TempWorkspace::new();
+    let (a, b) = linked_pair(&ws);
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_range(&a, 0, b"SHARED");
+    ops.write_range(&b, 7, b"CONTENTS");
+    ops.write_range(&b, 15, b" and a tail");
+    interceptor.close_step(1).unwrap();
+    assert_eq!(fs::read(&a).unwrap(), b"SHARED CONTENTS and a tail");
+
+    interceptor.rollback(1, false).unwrap();
+    assert!(same_inode(&a, &b));
+    assert_eq!(fs::read(&a).unwrap(), b"shared contents");
+}
+
+// ---------------------------------------------------------------------------
+// HL-06: Crash recovery rebuilds hard-link info from preimage metadata
+// ---------------------------------------------------------------------------
+#[test]
+fn hl_06_crash_recovery_restores_links() {
+    let ws = TempWorkspace::new();
+    let (a, b) = linked_pair(&ws);
+    let before = ws.snapshot();
+
+    {
+        let interceptor =
+            UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        let ops = OperationApplier::new(&interceptor);
+
+        interceptor.open_step(1).unwrap();
+        ops.delete_file(&a);
+        ops.delete_file(&b);
+        // Do NOT call close_step — simulate crash
+    }
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let info = interceptor.recover().unwrap().unwrap();
+    assert!(!info.manifest_valid);
+
+    assert!(same_inode(&a, &b));
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
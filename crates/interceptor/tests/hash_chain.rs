@@ -0,0 +1,161 @@
+//! Manifest hash chain tests (HC-01..HC-05).
+
+use std::fs;
+
+use codeagent_common::ResourceLimitsConfig;
+use codeagent_interceptor::chain::{ChainAttestation, GENESIS_HASH};
+use codeagent_interceptor::manifest::StepManifest;
+use codeagent_interceptor::undo_interceptor::{UndoConfig, UndoInterceptor};
+use codeagent_test_support::workspace::TempWorkspace;
+
+mod common;
+use common::OperationApplier;
+
+/// Close one step per name, each creating `{name}.txt`.
+fn close_steps(interceptor: &UndoInterceptor, ws: &TempWorkspace, names: &[&str]) {
+    let ops = OperationApplier::new(interceptor);
+    for (index, name) in names.iter().enumerate() {
+        let id = index as i64 + 100;
+        interceptor.open_step(id).unwrap();
+        ops.create_file(&ws.working_dir.join(format!("{name}.txt")), name.as_bytes());
+        interceptor.close_step(id).unwrap();
+    }
+}
+
+fn manifest_path(ws: &TempWorkspace, step_id: i64) -> std::path::PathBuf {
+    ws.undo_dir
+        .join("steps")
+        .join(step_id.to_string())
+        .join("manifest.json")
+}
+
+fn assert_broken(attestation: &ChainAttestation, broken_at: Option<i64>) {
+    assert!(!attestation.verified, "{attestation:?}");
+    assert_eq!(attestation.broken_at, broken_at, "{attestation:?}");
+}
+
+// ---------------------------------------------------------------------------
+// HC-01: Closed steps link to each other and to the head file
+// ---------------------------------------------------------------------------
+#[test]
+fn hc_01_closed_steps_are_chained() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    close_steps(&interceptor, &ws, &["a", "b", "c"]);
+
+    let first = StepManifest::read_from(&ws.undo_dir.join("steps").join("1")).unwrap();
+    assert_eq!(first.chain_prev.as_deref(), Some(GENESIS_HASH));
+
+    let attestation = interceptor.attest().unwrap();
+    assert!(attestation.verified, "{attestation:?}");
+    assert_eq!(attestation.chained_steps, 3);
+    assert_eq!(attestation.head_step, Some(3));
+    assert_ne!(attestation.head.as_deref(), Some(GENESIS_HASH));
+}
+
+// ---------------------------------------------------------------------------
+// HC-02: Editing a manifest breaks the link of the next step, or the head
+// ---------------------------------------------------------------------------
+#[test]
+fn hc_02_edited_manifest_is_detected() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    close_steps(&interceptor, &ws, &["a", "b", "c"]);
+
+    let path = manifest_path(&ws, 2);
+    let original = fs::read_to_string(&path).unwrap();
+    fs::write(&path, original.replace("b.txt", "hidden.txt")).unwrap();
+    assert_broken(&interceptor.attest().unwrap(), Some(3));
+
+    fs::write(&path, original).unwrap();
+    assert!(interceptor.attest().unwrap().verified);
+
+    let path = manifest_path(&ws, 3);
+    let original = fs::read_to_string(&path).unwrap();
+    fs::write(&path, original.replace("c.txt", "hidden.txt")).unwrap();
+    assert_broken(&interceptor.attest().unwrap(), None);
+}
+
+// ---------------------------------------------------------------------------
+// HC-03: Deleting the newest, oldest or a middle step is detected
+// ---------------------------------------------------------------------------
+#[test]
+fn hc_03_deleted_steps_are_detected() {
+    for (deleted, broken_at) in [(3, None), (1, Some(2)), (2, Some(3))] {
+        let ws = TempWorkspace::new();
+        let interceptor =
+            UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        close_steps(&interceptor, &ws, &["a", "b", "c"]);
+
+        fs::remove_dir_all(ws.undo_dir.join("steps").join(deleted.to_string())).unwrap();
+        assert_broken(&interceptor.attest().unwrap(), broken_at);
+    }
+}
+
+// ---------------------------------------------------------------------------
+// HC-04: Rollback and eviction keep the chain valid
+// ---------------------------------------------------------------------------
+#[test]
+fn hc_04_rollback_and_eviction_keep_chain_valid() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+        UndoConfig {
+            resource_limits: ResourceLimitsConfig {
+                max_step_count: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    close_steps(&interceptor, &ws, &["a", "b", "c"]);
+    assert_eq!(interceptor.completed_steps(), vec![2, 3]);
+    let attestation = interceptor.attest().unwrap();
+    assert!(attestation.verified, "{attestation:?}");
+    assert_ne!(attestation.anchor.as_deref(), Some(GENESIS_HASH));
+
+    interceptor.rollback(1, false).unwrap();
+    let attestation = interceptor.attest().unwrap();
+    assert!(attestation.verified, "{attestation:?}");
+    assert_eq!(attestation.head_step, Some(2));
+
+    close_steps(&interceptor, &ws, &["d"]);
+    assert!(interceptor.attest().unwrap().verified);
+
+    interceptor.rollback(2, false).unwrap();
+    let attestation = interceptor.attest().unwrap();
+    assert!(attestation.verified, "{attestation:?}");
+    assert_eq!(attestation.chained_steps, 0);
+    assert_eq!(attestation.head_step, None);
+}
+
+// ---------------------------------------------------------------------------
+// HC-05: Steps from before the chain verify as unchained; lost head file
+// ---------------------------------------------------------------------------
+#[test]
+fn hc_05_unchained_steps_and_missing_head() {
+    let ws = TempWorkspace::new();
+    {
+        let interceptor =
+            UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        close_steps(&interceptor, &ws, &["a"]);
+    }
+    // Rewrite step 1 as if it predated the chain.
+    let step_dir = ws.undo_dir.join("steps").join("1");
+    let mut manifest = StepManifest::read_from(&step_dir).unwrap();
+    manifest.chain_prev = None;
+    manifest.write_to(&step_dir).unwrap();
+    fs::remove_file(ws.undo_dir.join("chain_head.json")).unwrap();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    close_steps(&interceptor, &ws, &["b"]);
+    let attestation = interceptor.attest().unwrap();
+    assert!(attestation.verified, "{attestation:?}");
+    assert_eq!((attestation.unchained_steps, attestation.chained_steps), (1, 1));
+
+    fs::remove_file(ws.undo_dir.join("chain_head.json")).unwrap();
+    let attestation = interceptor.attest().unwrap();
+    assert_broken(&attestation, None);
+    assert_eq!(attestation.reason.as_deref(), Some("chain head file is missing"));
+}
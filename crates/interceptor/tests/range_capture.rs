@@ -0,0 +1,247 @@
+use std::fs;
+use std::path::Path;
+
+use codeagent_interceptor::preimage::{path_hash, read_preimage_metadata};
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_test_support::snapshot::assert_tree_eq;
+use codeagent_test_support::workspace::TempWorkspace;
+
+mod common;
+use common::{OperationApplier, compare_opts};
+
+/// 64 KB of non-repeating data so full captures are clearly larger than patches.
+fn large_contents() -> Vec<u8> {
+    let mut state: u32 = 12345;
+    (0..64 * 1024)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+fn step_preimage_dir(ws: &TempWorkspace, step_id: u64) -> std::path::PathBuf {
+    ws.undo_dir
+        .join("steps")
+        .join(step_id.to_string())
+        .join("preimages")
+}
+
+// ---------------------------------------------------------------------------
+// RC-01: In-place write stores only the overwritten range
+// ---------------------------------------------------------------------------
+#[test]
+fn rc_01_in_place_write_captures_range_only() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("artifact.rlib");
+    fs::write(&target, large_contents()).unwrap();
+    let before = ws.snapshot();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_range(&target, 4096, &[0xAA; 4096]);
+    interceptor.close_step(1).unwrap();
+
+    let preimages = step_preimage_dir(&ws, 1);
+    let hash = path_hash(Path::new("artifact.rlib"));
+    let meta = read_preimage_metadata(&preimages, &hash).unwrap();
+    let patches = meta.range_patches.expect("expected a range preimage");
+    assert_eq!(patches.len(), 1);
+    assert_eq!((patches[0].offset, patches[0].len), (4096, 4096));
+    assert!(!preimages.join(format!("{hash}.dat")).exists());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// RC-02: Append stores no data and rollback truncates to the original size
+// ---------------------------------------------------------------------------
+#[test]
+fn rc_02_append_rolls_back_by_truncation() {
+    let ws = TempWorkspace::new();
+    let log = ws.working_dir.join("build.log");
+    fs::write(&log, b"line 1\n").unwrap();
+    let before = ws.snapshot();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_range(&log, 7, b"line 2\n");
+    ops.write_range(&log, 14, b"line 3\n");
+    interceptor.close_step(1).unwrap();
+
+    let preimages = step_preimage_dir(&ws, 1);
+    let hash = path_hash(Path::new("build.log"));
+    let meta = read_preimage_metadata(&preimages, &hash).unwrap();
+    assert_eq!(meta.range_patches, Some(vec![]));
+    assert_eq!(meta.size, 7);
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&log).unwrap(), b"line 1\n");
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// RC-03: Overlapping writes within a step restore the original bytes
+// ---------------------------------------------------------------------------
+#[test]
+fn rc_03_overlapping_ranges_restore_original() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("data.bin");
+    let original = large_contents();
+    fs::write(&target, &original).unwrap();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_range(&target, 100, &[1; 200]);
+    ops.write_range(&target, 250, &[2; 200]);
+    ops.write_range(&target, 0, &[3; 120]);
+    interceptor.close_step(1).unwrap();
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&target).unwrap(), original);
+}
+
+// ---------------------------------------------------------------------------
+// RC-04: Truncate after a range write promotes to a full preimage
+// ---------------------------------------------------------------------------
+#[test]
+fn rc_04_truncate_promotes_to_full_preimage() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("data.bin");
+    let original = large_contents();
+    fs::write(&target, &original).unwrap();
+    let before = ws.snapshot();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_range(&target, 1000, &[9; 500]);
+    ops.setattr_truncate(&target, 800);
+    interceptor.close_step(1).unwrap();
+
+    let preimages = step_preimage_dir(&ws, 1);
+    let hash = path_hash(Path::new("data.bin"));
+    let meta = read_preimage_metadata(&preimages, &hash).unwrap();
+    assert!(meta.range_patches.is_none());
+    assert!(preimages.join(format!("{hash}.dat")).exists());
+    assert!(!preimages.join(format!("{hash}.range.0.dat")).exists());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&target).unwrap(), original);
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// RC-05: Delete after a range write restores the original file
+// ---------------------------------------------------------------------------
+#[test]
+fn rc_05_delete_after_range_write() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("data.bin");
+    let original = large_contents();
+    fs::write(&target, &original).unwrap();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_range(&target, 10, b"patched");
+    ops.write_range(&target, original.len() as u64, b"appended");
+    ops.delete_file(&target);
+    interceptor.close_step(1).unwrap();
+    assert!(!target.exists());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&target).unwrap(), original);
+}
+
+// ---------------------------------------------------------------------------
+// RC-06: Write covering the whole file falls back to a full preimage
+// ---------------------------------------------------------------------------
+#[test]
+fn rc_06_whole_file_write_uses_full_preimage() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("small.txt");
+    fs::write(&target, b"tiny").unwrap();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_range(&target, 0, b"replaced contents");
+    interceptor.close_step(1).unwrap();
+
+    let preimages = step_preimage_dir(&ws, 1);
+    let hash = path_hash(Path::new("small.txt"));
+    let meta = read_preimage_metadata(&preimages, &hash).unwrap();
+    assert!(meta.range_patches.is_none());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&target).unwrap(), b"tiny");
+}
+
+// ---------------------------------------------------------------------------
+// RC-07: Crash recovery applies range patches from the WAL
+// ---------------------------------------------------------------------------
+#[test]
+fn rc_07_crash_recovery_applies_range_patches() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("data.bin");
+    fs::write(&target, large_contents()).unwrap();
+    let before = ws.snapshot();
+
+    {
+        let interceptor =
+            UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        let ops = OperationApplier::new(&interceptor);
+
+        interceptor.open_step(1).unwrap();
+        ops.write_range(&target, 2048, &[0; 1024]);
+        ops.write_range(&target, 64 * 1024, b"tail");
+        // Do NOT call close_step — simulate crash
+    }
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let info = interceptor.recover().unwrap().unwrap();
+    assert_eq!(info.paths_restored, 1);
+
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// RC-08: O_APPEND writes record only the original length
+// ---------------------------------------------------------------------------
+#[test]
+fn rc_08_o_append_records_only_the_length() {
+    let ws = TempWorkspace::new();
+    let log = ws.working_dir.join("server.log");
+    fs::write(&log, large_contents()).unwrap();
+    let before = ws.snapshot();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    for line in 0..50 {
+        ops.append(&log, format!("request {line}\n").as_bytes());
+    }
+    interceptor.close_step(1).unwrap();
+
+    let preimages = step_preimage_dir(&ws, 1);
+    let hash = path_hash(Path::new("server.log"));
+    let meta = read_preimage_metadata(&preimages, &hash).unwrap();
+    assert_eq!(meta.range_patches, Some(vec![]));
+    assert_eq!(meta.size, 64 * 1024);
+    assert!(!preimages.join(format!("{hash}.dat")).exists());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
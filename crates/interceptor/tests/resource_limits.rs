@@ -9,7 +9,7 @@ use codeagent_test_support::snapshot::assert_tree_eq;
 use codeagent_test_support::workspace::TempWorkspace;
 
 mod common;
-use common::{compare_opts, OperationApplier};
+use common::{compare_opts, CancellingMonitor, OperationApplier};
 
 // ---------------------------------------------------------------------------
 // UI-16: Multi-step — rollback(1) restores to post-step-1 state
@@ -150,11 +150,14 @@ fn ui_18_fifo_eviction_by_step_count() {
 
     // Only steps 2 and 3 should be in the completed list
     assert_eq!(interceptor.completed_steps(), vec![2, 3]);
+    assert_eq!(interceptor.history_step_id(1), None);
+    assert_eq!(interceptor.history_step_id(2), Some(2));
 
     // Rollback of step 3 should work
     interceptor.rollback(1, false).unwrap();
     assert!(!ws.working_dir.join("file_3.txt").exists());
     assert_eq!(interceptor.completed_steps(), vec![2]);
+    assert_eq!(interceptor.history_step_id(3), None);
 }
 
 // ---------------------------------------------------------------------------
@@ -467,3 +470,70 @@ fn ul_08_corrupt_preimage_rollback_error() {
         "expected decompression-related error, got: {msg}"
     );
 }
+
+// ---------------------------------------------------------------------------
+// UL-09: set_resource_limits evicts immediately and governs later steps
+// ---------------------------------------------------------------------------
+#[test]
+fn ul_09_set_resource_limits_at_runtime() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    for i in 1..=3 {
+        interceptor.open_step(i).unwrap();
+        ops.create_file(&ws.working_dir.join(format!("file_{i}.txt")), b"content");
+        interceptor.close_step(i).unwrap();
+    }
+    let completed = interceptor.completed_steps();
+    assert_eq!(completed.len(), 3);
+
+    let limits = ResourceLimitsConfig {
+        max_step_count: Some(2),
+        ..Default::default()
+    };
+    let evicted = interceptor.set_resource_limits(limits.clone()).unwrap();
+    assert_eq!(evicted, vec![completed[0]]);
+    assert_eq!(interceptor.resource_limits(), limits);
+    assert!(!ws.undo_dir.join("steps").join(completed[0].to_string()).exists());
+
+    interceptor.open_step(4).unwrap();
+    ops.create_file(&ws.working_dir.join("file_4.txt"), b"content");
+    let evicted = interceptor.close_step(4).unwrap();
+    assert_eq!(evicted, vec![completed[1]]);
+    assert_eq!(interceptor.completed_steps().len(), 2);
+}
+
+// ---------------------------------------------------------------------------
+// UL-10: Cancelled eviction keeps the steps it has not reached
+// ---------------------------------------------------------------------------
+#[test]
+fn ul_10_cancelled_eviction_stops_between_steps() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    for i in 1..=4 {
+        interceptor.open_step(i).unwrap();
+        ops.create_file(&ws.working_dir.join(format!("file_{i}.txt")), b"content");
+        interceptor.close_step(i).unwrap();
+    }
+    let completed = interceptor.completed_steps();
+
+    let limits = ResourceLimitsConfig {
+        max_step_count: Some(1),
+        ..Default::default()
+    };
+    let monitor = CancellingMonitor::new(1);
+    let evicted = interceptor
+        .set_resource_limits_monitored(limits.clone(), &monitor)
+        .unwrap();
+    assert_eq!(evicted, vec![completed[0]]);
+    assert_eq!(interceptor.completed_steps(), completed[1..]);
+    assert_eq!(monitor.reports.lock().unwrap()[0], (33, None));
+    assert_eq!(interceptor.resource_limits(), limits);
+
+    // The next eviction catches up.
+    let evicted = interceptor.set_resource_limits(limits).unwrap();
+    assert_eq!(evicted, completed[1..3]);
+}
@@ -2,12 +2,13 @@ mod common;
 
 use std::fs;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use codeagent_common::{
-    CodeAgentError, ExternalModificationPolicy, SafeguardConfig, SafeguardDecision,
-    SafeguardEvent, SafeguardKind,
+    CodeAgentError, Expectation, ExpectedOperation, ExternalModificationPolicy, SafeguardConfig,
+    SafeguardDecision, PathOperation, SafeguardEvent, SafeguardKind, StepBudget,
 };
-use codeagent_interceptor::safeguard::SafeguardHandler;
+use codeagent_interceptor::safeguard::{ChannelSafeguardHandler, SafeguardHandler};
 use codeagent_interceptor::undo_interceptor::{UndoConfig, UndoInterceptor};
 use codeagent_interceptor::write_interceptor::WriteInterceptor;
 use codeagent_test_support::snapshot::{assert_tree_eq, TreeSnapshot};
@@ -55,7 +56,7 @@ fn make_interceptor(
         ws.working_dir.clone(),
         ws.undo_dir.clone(),
         UndoConfig {
-            policy: ExternalModificationPolicy::Barrier,
+            external_modification: ExternalModificationPolicy::Barrier.into(),
             safeguard_config: config,
             safeguard_handler: Some(handler),
             ..Default::default()
@@ -87,7 +88,7 @@ fn sg_01_delete_threshold_triggers_safeguard() {
     let ws = TempWorkspace::new();
     create_files(&ws, &["a.txt", "b.txt", "c.txt", "d.txt", "e.txt"], 10);
 
-    let (handler, events) = ImmediateHandler::new(SafeguardDecision::Allow);
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
     let config = SafeguardConfig {
         delete_threshold: Some(3),
         ..SafeguardConfig::default()
@@ -132,7 +133,7 @@ fn sg_02_allow_step_commits_and_undo_works() {
     create_files(&ws, &["a.txt", "b.txt", "c.txt"], 10);
     let before = snapshot(&ws);
 
-    let (handler, _events) = ImmediateHandler::new(SafeguardDecision::Allow);
+    let (handler, _events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
     let config = SafeguardConfig {
         delete_threshold: Some(2),
         ..SafeguardConfig::default()
@@ -239,7 +240,7 @@ fn sg_05_overwrite_large_file_triggers_safeguard() {
     let ws = TempWorkspace::new();
     create_files(&ws, &["big.dat"], 500);
 
-    let (handler, events) = ImmediateHandler::new(SafeguardDecision::Allow);
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
     let config = SafeguardConfig {
         overwrite_file_size_threshold: Some(100),
         ..SafeguardConfig::default()
@@ -277,7 +278,7 @@ fn sg_06_rename_over_existing_triggers_safeguard() {
     let ws = TempWorkspace::new();
     create_files(&ws, &["source.txt", "dest.txt"], 10);
 
-    let (handler, events) = ImmediateHandler::new(SafeguardDecision::Allow);
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
     let config = SafeguardConfig {
         rename_over_existing: true,
         ..SafeguardConfig::default()
@@ -312,6 +313,171 @@ fn sg_06_rename_over_existing_triggers_safeguard() {
     interceptor.close_step(1).unwrap();
 }
 
+// ---------------------------------------------------------------------------
+// SG-07: Overwrite-count threshold (distinct files, renames included)
+// ---------------------------------------------------------------------------
+
+#[test]
+fn sg_07_overwrite_count_triggers_once_per_step() {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &["a.txt", "b.txt", "c.txt", "d.txt", "c.tmp"], 10);
+
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
+    let config = SafeguardConfig {
+        overwrite_count_threshold: Some(3),
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+
+    // Two distinct files, one written twice: below threshold
+    ops.write_file(&ws.working_dir.join("a.txt"), b"a1");
+    ops.write_file(&ws.working_dir.join("a.txt"), b"a2");
+    ops.write_file(&ws.working_dir.join("b.txt"), b"b");
+    assert_eq!(events.lock().unwrap().len(), 0);
+
+    // Replacing c.txt by a rename (as `sed -i` does) is the third overwrite
+    ops.rename(
+        &ws.working_dir.join("c.tmp"),
+        &ws.working_dir.join("c.txt"),
+    );
+    {
+        let recorded = events.lock().unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert!(matches!(
+            &recorded[0].kind,
+            SafeguardKind::OverwriteCountThreshold {
+                count: 3,
+                threshold: 3
+            }
+        ));
+        assert_eq!(recorded[0].sample_paths, vec!["a.txt", "b.txt", "c.txt"]);
+    }
+
+    // Allowed for this step: further overwrites do not re-trigger
+    ops.write_file(&ws.working_dir.join("d.txt"), b"d");
+    assert_eq!(events.lock().unwrap().len(), 1);
+
+    interceptor.close_step(1).unwrap();
+}
+
+// ---------------------------------------------------------------------------
+// SG-14: Protected path patterns trigger on write, delete and rename
+// ---------------------------------------------------------------------------
+
+#[test]
+fn sg_14_protected_paths_trigger_per_path() {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &[".git/HEAD", "prod.env", "src/main.rs", "old.txt"], 10);
+
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
+    let config = SafeguardConfig {
+        protected_paths: vec![".git/**".to_string(), "*.env".to_string()],
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("src/main.rs"), b"fn main() {}");
+    ops.write_file(&ws.working_dir.join(".git/HEAD"), b"ref: a");
+    // Allowed for this path only
+    ops.write_file(&ws.working_dir.join(".git/HEAD"), b"ref: b");
+    ops.delete_file(&ws.working_dir.join("prod.env"));
+    ops.rename(&ws.working_dir.join("old.txt"), &ws.working_dir.join("new.env"));
+    interceptor.close_step(1).unwrap();
+
+    let recorded = events.lock().unwrap();
+    let triggered: Vec<_> = recorded
+        .iter()
+        .map(|event| match &event.kind {
+            SafeguardKind::ProtectedPath {
+                path,
+                pattern,
+                operation,
+            } => (path.as_str(), pattern.as_str(), *operation),
+            other => panic!("unexpected safeguard kind: {other:?}"),
+        })
+        .collect();
+    assert_eq!(
+        triggered,
+        vec![
+            (".git/HEAD", ".git/**", PathOperation::Write),
+            ("prod.env", "*.env", PathOperation::Delete),
+            ("new.env", "*.env", PathOperation::Rename),
+        ]
+    );
+}
+
+#[test]
+fn sg_14_protected_path_deny_blocks_write() {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &["secrets/token", "notes.txt"], 10);
+    let before = snapshot(&ws);
+
+    let (handler, _events) = ImmediateHandler::new(SafeguardDecision::Deny);
+    let config = SafeguardConfig {
+        protected_paths: vec!["secrets/*".to_string()],
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("notes.txt"), b"edited");
+    let result = interceptor.pre_write(&ws.working_dir.join("secrets/token"));
+    assert!(matches!(result, Err(CodeAgentError::SafeguardDenied { .. })));
+
+    assert_tree_eq(&before, &snapshot(&ws), &compare_opts());
+    assert!(interceptor.completed_steps().is_empty());
+}
+
+// ---------------------------------------------------------------------------
+// SG-15: Allow scopes — once, for the step, for the session
+// ---------------------------------------------------------------------------
+
+/// Delete four files per step for two steps with a threshold of 2 and
+/// return how many times the handler was asked.
+fn delete_twice_per_step(decision: SafeguardDecision) -> usize {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &["a", "b", "c", "d", "e", "f", "g", "h"], 10);
+
+    let (handler, events) = ImmediateHandler::new(decision);
+    let config = SafeguardConfig {
+        delete_threshold: Some(2),
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let ops = OperationApplier::new(&interceptor);
+
+    for (step, names) in [(1, ["a", "b", "c", "d"]), (2, ["e", "f", "g", "h"])] {
+        interceptor.open_step(step).unwrap();
+        for name in names {
+            ops.delete_file(&ws.working_dir.join(name));
+        }
+        interceptor.close_step(step).unwrap();
+    }
+    events.lock().unwrap().len()
+}
+
+#[test]
+fn sg_15_allow_once_asks_again() {
+    // Deletes 2, 3 and 4 of each step reach the threshold.
+    assert_eq!(delete_twice_per_step(SafeguardDecision::AllowOnce), 6);
+}
+
+#[test]
+fn sg_15_allow_for_step_asks_once_per_step() {
+    assert_eq!(delete_twice_per_step(SafeguardDecision::AllowForStep), 2);
+}
+
+#[test]
+fn sg_15_allow_for_session_asks_once() {
+    assert_eq!(delete_twice_per_step(SafeguardDecision::AllowForSession), 1);
+}
+
 // ---------------------------------------------------------------------------
 // Edge case: Allow does not re-trigger same kind in same step
 // ---------------------------------------------------------------------------
@@ -321,7 +487,7 @@ fn sg_allow_does_not_retrigger_same_kind() {
     let ws = TempWorkspace::new();
     create_files(&ws, &["a.txt", "b.txt", "c.txt", "d.txt"], 10);
 
-    let (handler, events) = ImmediateHandler::new(SafeguardDecision::Allow);
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
     let config = SafeguardConfig {
         delete_threshold: Some(2),
         ..SafeguardConfig::default()
@@ -349,7 +515,7 @@ fn sg_no_trigger_below_threshold() {
     let ws = TempWorkspace::new();
     create_files(&ws, &["a.txt", "b.txt"], 10);
 
-    let (handler, events) = ImmediateHandler::new(SafeguardDecision::Allow);
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
     let config = SafeguardConfig {
         delete_threshold: Some(5),
         ..SafeguardConfig::default()
@@ -395,7 +561,7 @@ fn sg_small_file_overwrite_no_trigger() {
     let ws = TempWorkspace::new();
     create_files(&ws, &["small.txt"], 10);
 
-    let (handler, events) = ImmediateHandler::new(SafeguardDecision::Allow);
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
     let config = SafeguardConfig {
         overwrite_file_size_threshold: Some(100),
         ..SafeguardConfig::default()
@@ -410,6 +576,150 @@ fn sg_small_file_overwrite_no_trigger() {
     assert_eq!(events.lock().unwrap().len(), 0);
 }
 
+// ---------------------------------------------------------------------------
+// SG-16: Exceeding a per-step byte budget rolls the step back unasked
+// ---------------------------------------------------------------------------
+
+#[test]
+fn sg_16_deleted_bytes_budget_rolls_back_step() {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &["a.txt", "b.txt", "dir/c.txt", "dir/d.txt"], 10);
+    let before = snapshot(&ws);
+
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
+    let config = SafeguardConfig {
+        max_deleted_bytes_per_step: Some(35),
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.delete_file(&ws.working_dir.join("a.txt"));
+    ops.delete_file(&ws.working_dir.join("b.txt"));
+    let result = interceptor.pre_unlink(&ws.working_dir.join("dir"), true);
+    match result {
+        Err(CodeAgentError::StepBudgetExceeded {
+            step_id,
+            budget,
+            used,
+            limit,
+        }) => {
+            assert_eq!((step_id, budget, used, limit), (1, StepBudget::DeletedBytes, 40, 35));
+        }
+        other => panic!("expected StepBudgetExceeded, got {other:?}"),
+    }
+
+    assert_tree_eq(&before, &snapshot(&ws), &compare_opts());
+    assert!(interceptor.current_step().is_none());
+    assert!(interceptor.completed_steps().is_empty());
+    assert!(events.lock().unwrap().is_empty());
+}
+
+#[test]
+fn sg_16_overwritten_bytes_budget_counts_each_file_once() {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &["a.txt", "b.txt"], 10);
+    let before = snapshot(&ws);
+
+    let (handler, _events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
+    let config = SafeguardConfig {
+        max_overwritten_bytes_per_step: Some(15),
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("a.txt"), b"first");
+    ops.write_file(&ws.working_dir.join("a.txt"), b"second");
+    let result = interceptor.pre_write(&ws.working_dir.join("b.txt"));
+    assert!(matches!(
+        result,
+        Err(CodeAgentError::StepBudgetExceeded {
+            budget: StepBudget::OverwrittenBytes,
+            used: 20,
+            ..
+        })
+    ));
+    assert_tree_eq(&before, &snapshot(&ws), &compare_opts());
+
+    // The budget is per step.
+    interceptor.open_step(2).unwrap();
+    ops.write_file(&ws.working_dir.join("b.txt"), b"fine");
+    interceptor.close_step(2).unwrap();
+}
+
+// ---------------------------------------------------------------------------
+// SG-17: An expected operation is asked about once, before it starts
+// ---------------------------------------------------------------------------
+
+type Events = Arc<Mutex<Vec<SafeguardEvent>>>;
+
+fn expect_delete_of_dir(decision: SafeguardDecision) -> (TempWorkspace, UndoInterceptor, Events) {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &["a.txt", "dir/b.txt", "dir/c.txt", "dir/d.txt"], 10);
+    let (handler, events) = ImmediateHandler::new(decision);
+    let config = SafeguardConfig {
+        delete_threshold: Some(2),
+        max_deleted_bytes_per_step: Some(15),
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let pending = interceptor.expect(Expectation {
+        paths: vec!["dir".to_string()],
+        op: ExpectedOperation::Delete,
+        estimated_bytes: 30,
+    });
+    assert_eq!(pending, 1);
+    (ws, interceptor, events)
+}
+
+#[test]
+fn sg_17_allowed_expectation_raises_step_limits() {
+    let (ws, interceptor, events) = expect_delete_of_dir(SafeguardDecision::AllowOnce);
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.delete_file(&ws.working_dir.join("a.txt"));
+    for name in ["dir/b.txt", "dir/c.txt", "dir/d.txt"] {
+        ops.delete_file(&ws.working_dir.join(name));
+    }
+    interceptor.close_step(1).unwrap();
+
+    // One prompt for the whole operation; the delete threshold is waived
+    // and the 15-byte budget grew by the announced 30 bytes.
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0].kind,
+        SafeguardKind::ExpectedOperation {
+            op: ExpectedOperation::Delete,
+            path_count: 1,
+            estimated_bytes: 30,
+        }
+    );
+    assert_eq!(events[0].sample_paths, vec!["dir".to_string()]);
+    assert_eq!(interceptor.completed_steps(), vec![1]);
+}
+
+#[test]
+fn sg_17_denied_expectation_stops_before_first_change() {
+    let (ws, interceptor, events) = expect_delete_of_dir(SafeguardDecision::Deny);
+    let before = snapshot(&ws);
+
+    interceptor.open_step(1).unwrap();
+    let result = interceptor.pre_unlink(&ws.working_dir.join("dir"), true);
+    assert!(matches!(result, Err(CodeAgentError::SafeguardDenied { step_id: 1, .. })));
+    assert_tree_eq(&before, &snapshot(&ws), &compare_opts());
+    assert_eq!(events.lock().unwrap().len(), 1);
+
+    // The expectation belonged to step 1; step 2 gets the normal limits.
+    interceptor.open_step(2).unwrap();
+    let result = interceptor.pre_unlink(&ws.working_dir.join("dir"), true);
+    assert!(matches!(result, Err(CodeAgentError::StepBudgetExceeded { .. })));
+}
+
 // ---------------------------------------------------------------------------
 // Edge case: Safeguard counters reset between steps
 // ---------------------------------------------------------------------------
@@ -419,7 +729,7 @@ fn sg_counters_reset_between_steps() {
     let ws = TempWorkspace::new();
     create_files(&ws, &["a.txt", "b.txt", "c.txt", "d.txt"], 10);
 
-    let (handler, events) = ImmediateHandler::new(SafeguardDecision::Allow);
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
     let config = SafeguardConfig {
         delete_threshold: Some(2),
         ..SafeguardConfig::default()
@@ -446,3 +756,145 @@ fn sg_counters_reset_between_steps() {
     interceptor.close_step(3).unwrap();
     assert_eq!(events.lock().unwrap().len(), 1);
 }
+
+// ---------------------------------------------------------------------------
+// SG-12: A channel handler attached after construction decides elsewhere
+// ---------------------------------------------------------------------------
+
+#[test]
+fn sg_12_channel_handler_set_after_construction() {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &["a.txt", "b.txt", "c.txt", "d.txt"], 10);
+    let interceptor = UndoInterceptor::new(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+        UndoConfig {
+            safeguard_config: SafeguardConfig {
+                delete_threshold: Some(2),
+                ..SafeguardConfig::default()
+            },
+            ..Default::default()
+        },
+    );
+    let ops = OperationApplier::new(&interceptor);
+
+    let (handler, requests) = ChannelSafeguardHandler::channel(Duration::from_secs(5));
+    interceptor.set_safeguard_handler(Some(Arc::new(handler)));
+    let frontend = std::thread::spawn(move || {
+        let request = requests.recv().unwrap();
+        let step_id = request.event.step_id;
+        assert!(request.decide(SafeguardDecision::AllowForStep));
+        step_id
+    });
+    interceptor.open_step(1).unwrap();
+    ops.delete_file(&ws.working_dir.join("a.txt"));
+    ops.delete_file(&ws.working_dir.join("b.txt"));
+    assert_eq!(frontend.join().unwrap(), 1);
+    interceptor.close_step(1).unwrap();
+
+    // Nobody answers: the safeguard is denied once the timeout passes.
+    let before = snapshot(&ws);
+    let (handler, _requests) = ChannelSafeguardHandler::channel(Duration::from_millis(50));
+    interceptor.set_safeguard_handler(Some(Arc::new(handler)));
+    interceptor.open_step(2).unwrap();
+    ops.delete_file(&ws.working_dir.join("c.txt"));
+    let result = interceptor.pre_unlink(&ws.working_dir.join("d.txt"), false);
+    assert!(matches!(result, Err(CodeAgentError::SafeguardDenied { step_id: 2, .. })));
+    assert_tree_eq(&before, &snapshot(&ws), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// SG-18: A step going over its operation or time budget asks to continue
+// ---------------------------------------------------------------------------
+
+#[test]
+fn sg_18_denied_operation_budget_rolls_back_step() {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &["a.txt"], 10);
+    let before = snapshot(&ws);
+
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::Deny);
+    let config = SafeguardConfig {
+        max_step_operations: Some(3),
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("a.txt"), b"changed");
+    ops.create_file(&ws.working_dir.join("b.txt"), b"new");
+    ops.mkdir(&ws.working_dir.join("dir"));
+    let result = interceptor.pre_unlink(&ws.working_dir.join("a.txt"), false);
+    assert!(matches!(result, Err(CodeAgentError::SafeguardDenied { step_id: 1, .. })));
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0].kind,
+        SafeguardKind::StepOperationCount { count: 4, threshold: 3 }
+    );
+    assert!(events[0].kind.limits_step());
+    assert_tree_eq(&before, &snapshot(&ws), &compare_opts());
+    assert!(interceptor.current_step().is_none());
+}
+
+#[test]
+fn sg_18_operation_budget_allowed_for_step_asks_once() {
+    let ws = TempWorkspace::new();
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::AllowForStep);
+    let config = SafeguardConfig {
+        max_step_operations: Some(2),
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    for index in 0..6 {
+        ops.create_file(&ws.working_dir.join(format!("{index}.txt")), b"x");
+    }
+    interceptor.close_step(1).unwrap();
+    assert_eq!(events.lock().unwrap().len(), 1);
+
+    // The budget applies to each step afresh.
+    interceptor.open_step(2).unwrap();
+    for index in 0..3 {
+        ops.write_file(&ws.working_dir.join(format!("{index}.txt")), b"y");
+    }
+    interceptor.close_step(2).unwrap();
+    assert_eq!(events.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn sg_18_idle_step_over_its_duration_is_rolled_back_when_denied() {
+    let ws = TempWorkspace::new();
+    create_files(&ws, &["a.txt"], 10);
+    let before = snapshot(&ws);
+
+    let (handler, events) = ImmediateHandler::new(SafeguardDecision::Deny);
+    let config = SafeguardConfig {
+        max_step_duration_seconds: Some(10),
+        ..SafeguardConfig::default()
+    };
+    let interceptor = make_interceptor(&ws, config, Box::new(handler));
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("a.txt"), b"changed");
+    assert_eq!(interceptor.open_step_ids(), vec![1]);
+    // Nothing happens in the step after its write; the caller's clock moves on.
+    interceptor.check_step_duration(1, Duration::from_secs(10)).unwrap();
+    let result = interceptor.check_step_duration(1, Duration::from_secs(11));
+    assert!(matches!(result, Err(CodeAgentError::SafeguardDenied { step_id: 1, .. })));
+
+    let events = events.lock().unwrap();
+    assert_eq!(
+        events[0].kind,
+        SafeguardKind::StepDuration { elapsed_seconds: 11, limit_seconds: 10 }
+    );
+    assert_tree_eq(&before, &snapshot(&ws), &compare_opts());
+    assert!(interceptor.open_step_ids().is_empty());
+    // A step no longer open is not checked.
+    interceptor.check_step_duration(1, Duration::from_secs(60)).unwrap();
+}
@@ -0,0 +1,207 @@
+//! Step squashing tests (SQ-01..SQ-05).
+
+use std::fs;
+use std::path::PathBuf;
+
+use codeagent_common::{BarrierReason, CodeAgentError};
+use codeagent_interceptor::manifest::StepManifest;
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_test_support::fixtures;
+use codeagent_test_support::snapshot::assert_tree_eq;
+use codeagent_test_support::workspace::TempWorkspace;
+
+mod common;
+use common::{OperationApplier, compare_opts};
+
+/// 64 KB of non-repeating data, large enough for in-place writes to be
+/// captured as ranges.
+fn large_contents() -> Vec<u8> {
+    let mut state: u32 = 977;
+    (0..64 * 1024)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+fn step_manifest(ws: &TempWorkspace, step_id: i64) -> StepManifest {
+    StepManifest::read_from(&ws.undo_dir.join("steps").join(step_id.to_string())).unwrap()
+}
+
+// ---------------------------------------------------------------------------
+// SQ-01: Squashed steps keep the oldest preimage and roll back as one
+// ---------------------------------------------------------------------------
+#[test]
+fn sq_01_squashed_steps_roll_back_as_one() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let before = ws.snapshot();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    interceptor.set_step_command("one".to_string());
+    ops.write_file(&ws.working_dir.join("small.txt"), b"one");
+    interceptor.close_step(1).unwrap();
+    interceptor.open_step(2).unwrap();
+    interceptor.set_step_command("two".to_string());
+    ops.write_file(&ws.working_dir.join("small.txt"), b"two");
+    ops.create_file(&ws.working_dir.join("new.txt"), b"created");
+    interceptor.close_step(2).unwrap();
+    interceptor.open_step(3).unwrap();
+    interceptor.set_step_command("three".to_string());
+    ops.delete_file(&ws.working_dir.join("medium.txt"));
+    ops.write_file(&ws.working_dir.join("new.txt"), b"edited");
+    interceptor.close_step(3).unwrap();
+    let size_before = interceptor.undo_log_size().unwrap();
+
+    let result = interceptor.squash(1, 3).unwrap();
+    assert_eq!(result.step_id, 1);
+    assert_eq!(result.squashed_step_ids, vec![1, 2, 3]);
+    assert_eq!(result.entries_dropped, 2);
+    assert!(result.bytes_reclaimed > 0);
+    assert_eq!(interceptor.undo_log_size().unwrap(), size_before - result.bytes_reclaimed);
+    assert_eq!(interceptor.completed_steps(), vec![1]);
+    assert!(!ws.undo_dir.join("steps").join("2").exists());
+
+    let manifest = step_manifest(&ws, 1);
+    assert_eq!(manifest.command.as_deref(), Some("one; two; three"));
+    let paths: Vec<&str> = manifest.entries.keys().map(String::as_str).collect();
+    assert_eq!(paths, ["medium.txt", "new.txt", "small.txt"]);
+    assert!(!manifest.entries["new.txt"].existed_before);
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// SQ-02: Steps around the squashed range stay in the chain and roll back
+// ---------------------------------------------------------------------------
+#[test]
+fn sq_02_later_steps_are_relinked() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let before = ws.snapshot();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    let mut snapshots = Vec::new();
+    for (id, contents) in [(1, "a"), (2, "b"), (3, "c"), (4, "d")] {
+        interceptor.open_step(id).unwrap();
+        ops.write_file(&ws.working_dir.join("small.txt"), contents.as_bytes());
+        ops.create_file(&ws.working_dir.join(format!("{contents}.txt")), b"step");
+        interceptor.close_step(id).unwrap();
+        snapshots.push(ws.snapshot());
+    }
+
+    interceptor.squash(2, 3).unwrap();
+    assert_eq!(interceptor.completed_steps(), vec![1, 2, 4]);
+    let attestation = interceptor.attest().unwrap();
+    assert!(attestation.verified, "{attestation:?}");
+    assert_eq!((attestation.chained_steps, attestation.head_step), (3, Some(4)));
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&snapshots[2], &ws.snapshot(), &compare_opts());
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&snapshots[0], &ws.snapshot(), &compare_opts());
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+    assert!(interceptor.attest().unwrap().verified);
+}
+
+// ---------------------------------------------------------------------------
+// SQ-03: A range preimage is completed by the later captures of its file
+// ---------------------------------------------------------------------------
+#[test]
+fn sq_03_range_preimages_are_completed() {
+    let ws = TempWorkspace::new();
+    let rewritten = ws.working_dir.join("rewritten.rlib");
+    let patched = ws.working_dir.join("patched.rlib");
+    fs::write(&rewritten, large_contents()).unwrap();
+    fs::write(&patched, large_contents()).unwrap();
+    let before = ws.snapshot();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_range(&rewritten, 4096, &[0xAA; 4096]);
+    ops.write_range(&patched, 4096, &[0xAA; 4096]);
+    interceptor.close_step(1).unwrap();
+    interceptor.open_step(2).unwrap();
+    ops.write_file(&rewritten, b"rewritten");
+    ops.write_range(&patched, 8192, &[0xBB; 8192]);
+    ops.write_range(&patched, 6144, &[0xCC; 4096]);
+    interceptor.close_step(2).unwrap();
+
+    interceptor.squash(1, 2).unwrap();
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// SQ-04: Attribute-only preimages and directory renames are squashed
+// ---------------------------------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn sq_04_attribute_captures_and_renames_are_squashed() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let before = ws.snapshot();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    let moved = ws.working_dir.join("moved");
+
+    interceptor.open_step(1).unwrap();
+    ops.chmod(&ws.working_dir.join("run.sh"), 0o600);
+    ops.rename(&ws.working_dir.join("src"), &moved);
+    interceptor.close_step(1).unwrap();
+    interceptor.open_step(2).unwrap();
+    ops.write_file(&ws.working_dir.join("run.sh"), b"#!/bin/sh\necho changed");
+    ops.write_file(&moved.join("main.rs"), b"fn main() { changed() }");
+    ops.create_file(&moved.join("new.rs"), b"made under the new name");
+    interceptor.close_step(2).unwrap();
+
+    interceptor.squash(1, 2).unwrap();
+    let manifest = step_manifest(&ws, 1);
+    assert_eq!(manifest.renames.len(), 1);
+    let paths: Vec<&str> = manifest.entries.keys().map(String::as_str).collect();
+    assert_eq!(paths, ["run.sh", "src", "src/main.rs", "src/new.rs"]);
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// SQ-05: Invalid ranges are rejected; barriers of squashed steps are kept
+// ---------------------------------------------------------------------------
+#[test]
+fn sq_05_ranges_are_checked_and_barriers_kept() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    for id in 1..=3 {
+        interceptor.open_step(id).unwrap();
+        ops.write_file(&ws.working_dir.join("small.txt"), format!("step {id}").as_bytes());
+        interceptor.close_step(id).unwrap();
+        if id == 1 {
+            interceptor
+                .notify_external_modification(
+                    vec![PathBuf::from("README.md").into()],
+                    BarrierReason::ExternalModification,
+                )
+                .unwrap();
+        }
+    }
+
+    for (from, to) in [(3, 1), (1, 9), (0, 2)] {
+        let error = interceptor.squash(from, to).unwrap_err();
+        assert!(matches!(error, CodeAgentError::InvalidStepRange { .. }), "{error}");
+    }
+    let result = interceptor.squash(3, 3).unwrap();
+    assert_eq!((result.squashed_step_ids, result.bytes_reclaimed), (vec![3], 0));
+
+    interceptor.squash(1, 2).unwrap();
+    let barriers = interceptor.barriers();
+    assert_eq!(barriers.len(), 1);
+    assert_eq!(barriers[0].after_step_id, 1);
+    interceptor.rollback(1, false).unwrap();
+    let error = interceptor.rollback(1, false).unwrap_err();
+    assert!(matches!(error, CodeAgentError::RollbackBlocked { .. }), "{error}");
+}
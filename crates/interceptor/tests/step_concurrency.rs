@@ -0,0 +1,378 @@
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use codeagent_common::CodeAgentError;
+use codeagent_interceptor::manifest::{StepManifest, WARNING_CONCURRENT_WRITE};
+use codeagent_interceptor::step_attribution::{self, GuestProcess};
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_interceptor::write_interceptor::WriteInterceptor;
+use codeagent_test_support::workspace::TempWorkspace;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Spawn a thread that waits for the step slot and opens `id`, then return
+/// once it is blocked (or has already acquired the slot).
+fn spawn_waiter(
+    interceptor: &Arc<UndoInterceptor>,
+    id: i64,
+) -> thread::JoinHandle<codeagent_common::Result<Duration>> {
+    let interceptor = Arc::clone(interceptor);
+    let handle = thread::spawn(move || interceptor.open_step_when_free(id, WAIT));
+    thread::sleep(Duration::from_millis(50));
+    handle
+}
+
+// ---------------------------------------------------------------------------
+// SC-01: A waiter opens its step once the active step closes
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_01_waiter_acquires_after_close() {
+    let ws = TempWorkspace::new();
+    let interceptor = Arc::new(UndoInterceptor::new_default(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+    ));
+
+    interceptor.open_step(-1).unwrap();
+    let waiter = spawn_waiter(&interceptor, 1_000_000);
+    assert_eq!(interceptor.current_step(), Some(-1));
+
+    interceptor.close_step(-1).unwrap();
+    let waited = waiter.join().unwrap().unwrap();
+    assert!(waited > Duration::ZERO);
+    assert_eq!(interceptor.current_step(), Some(1_000_000));
+
+    let stats = interceptor.step_wait_stats();
+    assert_eq!(stats.acquisitions, 1);
+    assert_eq!(stats.contended, 1);
+    assert_eq!(stats.timeouts, 0);
+    assert!(stats.max_wait_ms >= 40);
+}
+
+// ---------------------------------------------------------------------------
+// SC-02: A free slot is acquired immediately without counting as contended
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_02_free_slot_acquired_immediately() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+
+    let waited = interceptor.open_step_when_free(1, WAIT).unwrap();
+    assert!(waited < WAIT);
+    assert_eq!(interceptor.current_step(), Some(1));
+
+    let stats = interceptor.step_wait_stats();
+    assert_eq!((stats.acquisitions, stats.contended), (1, 0));
+    assert_eq!(stats.total_wait_ms, 0);
+}
+
+// ---------------------------------------------------------------------------
+// SC-03: The wait is bounded by the timeout
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_03_wait_times_out() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+
+    interceptor.open_step(1).unwrap();
+    let error = interceptor
+        .open_step_when_free(2, Duration::from_millis(30))
+        .unwrap_err();
+    assert!(
+        matches!(error, CodeAgentError::StepWaitTimeout { step_id: 1, waited_ms } if waited_ms >= 30),
+        "unexpected error: {error:?}"
+    );
+    assert_eq!(interceptor.current_step(), Some(1));
+
+    let stats = interceptor.step_wait_stats();
+    assert_eq!((stats.acquisitions, stats.timeouts), (0, 1));
+}
+
+// ---------------------------------------------------------------------------
+// SC-04: Waiting for the step that is already active fails immediately
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_04_same_step_does_not_wait_on_itself() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+
+    interceptor.open_step(7).unwrap();
+    let error = interceptor.open_step_when_free(7, WAIT).unwrap_err();
+    assert!(matches!(error, CodeAgentError::StepAlreadyActive { step_id: 7 }));
+    assert_eq!(interceptor.step_wait_stats().timeouts, 0);
+}
+
+// ---------------------------------------------------------------------------
+// SC-05: Cancelling the active step wakes the waiter
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_05_rollback_current_step_wakes_waiter() {
+    let ws = TempWorkspace::new();
+    let file = ws.working_dir.join("file.txt");
+    fs::write(&file, b"original").unwrap();
+    let interceptor = Arc::new(UndoInterceptor::new_default(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+    ));
+
+    interceptor.open_step(1).unwrap();
+    interceptor.pre_write(&file).unwrap();
+    fs::write(&file, b"modified").unwrap();
+    let waiter = spawn_waiter(&interceptor, 2);
+
+    interceptor.rollback_current_step().unwrap();
+    waiter.join().unwrap().unwrap();
+    assert_eq!(interceptor.current_step(), Some(2));
+    assert_eq!(fs::read(&file).unwrap(), b"original");
+}
+
+// ---------------------------------------------------------------------------
+// SC-06: The closed step's WAL is promoted before the waiter reuses it
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_06_closed_step_survives_queued_open() {
+    let ws = TempWorkspace::new();
+    let file = ws.working_dir.join("file.txt");
+    fs::write(&file, b"original").unwrap();
+    let interceptor = Arc::new(UndoInterceptor::new_default(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+    ));
+
+    interceptor.open_step(1).unwrap();
+    interceptor.pre_write(&file).unwrap();
+    fs::write(&file, b"modified").unwrap();
+    let waiter = spawn_waiter(&interceptor, 2);
+
+    interceptor.close_step(1).unwrap();
+    waiter.join().unwrap().unwrap();
+    interceptor.close_step(2).unwrap();
+
+    assert_eq!(interceptor.completed_steps(), vec![1]);
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&file).unwrap(), b"original");
+}
+
+fn read_step_manifest(ws: &TempWorkspace, id: i64) -> StepManifest {
+    StepManifest::read_from(&ws.undo_dir.join("steps").join(id.to_string())).unwrap()
+}
+
+// ---------------------------------------------------------------------------
+// SC-07: Concurrent steps record attributed writes separately
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_07_concurrent_steps_record_attributed_writes() {
+    let ws = TempWorkspace::new();
+    let first = ws.working_dir.join("first.txt");
+    let second = ws.working_dir.join("second.txt");
+    fs::write(&first, b"first original").unwrap();
+    fs::write(&second, b"second original").unwrap();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+
+    interceptor.open_concurrent_step(1).unwrap();
+    interceptor.open_concurrent_step(2).unwrap();
+    {
+        let _scope = step_attribution::attribute_to(Some(2));
+        interceptor.pre_write(&second).unwrap();
+        fs::write(&second, b"second modified").unwrap();
+    }
+    {
+        let _scope = step_attribution::attribute_to(Some(1));
+        interceptor.pre_write(&first).unwrap();
+        fs::write(&first, b"first modified").unwrap();
+    }
+
+    interceptor.close_step(2).unwrap();
+    interceptor.close_step(1).unwrap();
+    assert_eq!(interceptor.completed_steps(), vec![1, 2]);
+    assert_eq!(interceptor.history_step_id(2), Some(1));
+    assert!(read_step_manifest(&ws, 1).entries.contains_key("second.txt"));
+    assert!(read_step_manifest(&ws, 2).entries.contains_key("first.txt"));
+
+    // Rolling back the step closed last leaves the other one's write.
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&first).unwrap(), b"first original");
+    assert_eq!(fs::read(&second).unwrap(), b"second modified");
+}
+
+// ---------------------------------------------------------------------------
+// SC-08: Unattributed writes go to the oldest step; exclusive steps exclude
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_08_unattributed_writes_go_to_oldest_step() {
+    let ws = TempWorkspace::new();
+    let file = ws.working_dir.join("file.txt");
+    fs::write(&file, b"original").unwrap();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+
+    interceptor.open_concurrent_step(5).unwrap();
+    interceptor.open_concurrent_step(6).unwrap();
+    assert!(matches!(
+        interceptor.open_step(7),
+        Err(CodeAgentError::StepAlreadyActive { step_id: 5 })
+    ));
+    assert!(matches!(
+        interceptor.open_concurrent_step(6),
+        Err(CodeAgentError::StepAlreadyActive { step_id: 6 })
+    ));
+
+    // Attribution to a step that is not open falls back as well.
+    let _scope = step_attribution::attribute_to(Some(9));
+    interceptor.pre_write(&file).unwrap();
+    fs::write(&file, b"modified").unwrap();
+    interceptor.close_step(6).unwrap();
+    interceptor.close_step(5).unwrap();
+    assert_eq!(interceptor.history_step_id(5), Some(1));
+    assert_eq!(interceptor.history_step_id(6), None);
+
+    interceptor.open_step(7).unwrap();
+    assert!(matches!(
+        interceptor.open_concurrent_step(8),
+        Err(CodeAgentError::StepAlreadyActive { step_id: 7 })
+    ));
+}
+
+// ---------------------------------------------------------------------------
+// SC-09: A path changed by two open steps is flagged in the later one
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_09_shared_path_warns_concurrent_write() {
+    let ws = TempWorkspace::new();
+    let file = ws.working_dir.join("shared.txt");
+    fs::write(&file, b"original").unwrap();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+
+    interceptor.open_concurrent_step(1).unwrap();
+    interceptor.open_concurrent_step(2).unwrap();
+    for (step, contents) in [(1, b"one"), (2, b"two")] {
+        let _scope = step_attribution::attribute_to(Some(step));
+        interceptor.pre_write(&file).unwrap();
+        fs::write(&file, contents).unwrap();
+    }
+    interceptor.close_step(1).unwrap();
+    interceptor.close_step(2).unwrap();
+
+    assert!(read_step_manifest(&ws, 1).warnings.is_empty());
+    let warnings = read_step_manifest(&ws, 2).warnings;
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].path, "shared.txt");
+    assert_eq!(warnings[0].code, WARNING_CONCURRENT_WRITE);
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read(&file).unwrap(), b"one");
+}
+
+// ---------------------------------------------------------------------------
+// SC-10: Recovery rolls back every step that was open at the crash
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_10_recovery_rolls_back_all_open_steps() {
+    let ws = TempWorkspace::new();
+    let shared = ws.working_dir.join("shared.txt");
+    let created = ws.working_dir.join("created.txt");
+    fs::write(&shared, b"original").unwrap();
+
+    {
+        let interceptor =
+            UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        interceptor.open_concurrent_step(1).unwrap();
+        interceptor.open_concurrent_step(2).unwrap();
+        {
+            let _scope = step_attribution::attribute_to(Some(1));
+            interceptor.pre_write(&shared).unwrap();
+            fs::write(&shared, b"one").unwrap();
+        }
+        let _scope = step_attribution::attribute_to(Some(2));
+        interceptor.pre_write(&shared).unwrap();
+        fs::write(&shared, b"two").unwrap();
+        fs::write(&created, b"new").unwrap();
+        interceptor.post_create(&created).unwrap();
+        // Dropped without closing either step.
+    }
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let info = interceptor.recover().unwrap().unwrap();
+    assert_eq!((info.paths_restored, info.paths_deleted), (2, 1));
+    assert_eq!(fs::read(&shared).unwrap(), b"original");
+    assert!(!created.exists());
+    assert!(interceptor.recover().unwrap().is_none());
+}
+
+// ---------------------------------------------------------------------------
+// SC-11: Threads writing the same files capture each preimage once, intact
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_11_parallel_first_touches_capture_originals() {
+    let ws = TempWorkspace::new();
+    let originals: Vec<(std::path::PathBuf, Vec<u8>)> = (0..24)
+        .map(|index| {
+            let path = ws.working_dir.join(format!("obj{index}.o"));
+            let contents = format!("object {index}\n").repeat(4096).into_bytes();
+            fs::write(&path, &contents).unwrap();
+            (path, contents)
+        })
+        .collect();
+    let interceptor = Arc::new(UndoInterceptor::new_default(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+    ));
+
+    interceptor.open_step(1).unwrap();
+    let writers: Vec<_> = (0..8)
+        .map(|thread_index| {
+            let interceptor = Arc::clone(&interceptor);
+            let paths: Vec<_> = originals.iter().map(|(path, _)| path.clone()).collect();
+            thread::spawn(move || {
+                for offset in 0..paths.len() {
+                    let path = &paths[(offset + thread_index * 3) % paths.len()];
+                    interceptor.pre_write(path).unwrap();
+                    fs::write(path, format!("written by {thread_index}")).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    interceptor.close_step(1).unwrap();
+    assert_eq!(read_step_manifest(&ws, 1).entries.len(), originals.len());
+
+    interceptor.rollback(1, false).unwrap();
+    for (path, contents) in &originals {
+        assert_eq!(&fs::read(path).unwrap(), contents, "{}", path.display());
+    }
+}
+
+// ---------------------------------------------------------------------------
+// SC-12: Manifest entries name the guest process that first changed them
+// ---------------------------------------------------------------------------
+#[test]
+fn sc_12_manifest_entries_name_the_issuing_process() {
+    let ws = TempWorkspace::new();
+    let built = ws.working_dir.join("main.o");
+    let edited = ws.working_dir.join("main.c");
+    fs::write(&edited, b"int main;").unwrap();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let gcc = GuestProcess { pid: 1234, uid: 1000, gid: 1000, name: Some("gcc".to_string()) };
+
+    interceptor.open_step(1).unwrap();
+    {
+        let _scope = step_attribution::attribute_operation(None, Some(gcc.clone()));
+        fs::write(&built, b"\x7fELF").unwrap();
+        interceptor.post_create(&built).unwrap();
+    }
+    {
+        // A later process touching the path again does not replace the first.
+        let other = GuestProcess { pid: 99, ..gcc.clone() };
+        let _scope = step_attribution::attribute_operation(None, Some(other));
+        interceptor.pre_write(&built).unwrap();
+    }
+    interceptor.pre_write(&edited).unwrap();
+    interceptor.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert_eq!(manifest.entries["main.o"].modified_by, Some(gcc));
+    assert_eq!(manifest.entries["main.c"].modified_by, None);
+}
@@ -325,3 +325,152 @@ fn sy_08_ignore_policy_ensure_preimage_skips_symlinks() {
     let step_dir = ws.undo_dir.join("steps").join("1");
     assert!(!step_dir.exists(), "empty step should not be persisted");
 }
+
+// ---------------------------------------------------------------------------
+// SY-09: Paths reached through a symlinked directory outside the root are
+// not captured
+// ---------------------------------------------------------------------------
+#[test]
+fn sy_09_capture_skips_paths_escaping_working_root() {
+    let ws = TempWorkspace::new();
+    let outside = tempfile::TempDir::new().unwrap();
+    let secret = outside.path().join("secret.txt");
+    fs::write(&secret, "outside content").unwrap();
+
+    let escape = ws.working_dir.join("escape");
+    if !try_create_symlink(outside.path(), &escape) {
+        eprintln!("skipping: symlink creation not supported");
+        return;
+    }
+
+    let interceptor = UndoInterceptor::new(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+        UndoConfig {
+            symlink_policy: SymlinkPolicy::ReadWrite,
+            ..Default::default()
+        },
+    );
+
+    interceptor.open_step(1).unwrap();
+    interceptor.pre_write(&escape.join("secret.txt")).unwrap();
+    interceptor.post_create(&escape.join("new.txt")).unwrap();
+    interceptor.close_step(1).unwrap();
+
+    let step_dir = ws.undo_dir.join("steps").join("1");
+    assert!(!step_dir.exists(), "nothing outside the working root should be captured");
+}
+
+// ---------------------------------------------------------------------------
+// SY-10: ReadWrite policy — symlinks pointing outside the root are not restored
+// ---------------------------------------------------------------------------
+#[test]
+fn sy_10_read_write_policy_skips_escaping_symlink_restore() {
+    let ws = TempWorkspace::new();
+    let outside = tempfile::TempDir::new().unwrap();
+
+    let inside_link = ws.working_dir.join("inside.txt");
+    let escaping_link = ws.working_dir.join("escaping.txt");
+    fs::write(ws.working_dir.join("target.txt"), "target").unwrap();
+    if !try_create_symlink(Path::new("target.txt"), &inside_link)
+        || !try_create_symlink(&outside.path().join("file.txt"), &escaping_link)
+    {
+        eprintln!("skipping: symlink creation not supported");
+        return;
+    }
+
+    let interceptor = UndoInterceptor::new(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+        UndoConfig {
+            symlink_policy: SymlinkPolicy::ReadWrite,
+            ..Default::default()
+        },
+    );
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.delete_file(&inside_link);
+    ops.delete_file(&escaping_link);
+    interceptor.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert!(manifest.contains_path("escaping.txt"));
+
+    interceptor.rollback(1, false).unwrap();
+    assert!(inside_link.symlink_metadata().unwrap().is_symlink());
+    assert!(
+        escaping_link.symlink_metadata().is_err(),
+        "symlink pointing outside the working root should not be restored"
+    );
+}
+
+// ---------------------------------------------------------------------------
+// SY-11: Rollback does not write through a directory swapped for a symlink
+// that leaves the root
+// ---------------------------------------------------------------------------
+#[test]
+fn sy_11_rollback_does_not_write_outside_working_root() {
+    let ws = TempWorkspace::new();
+    let outside = tempfile::TempDir::new().unwrap();
+    let dir = ws.working_dir.join("dir");
+    fs::create_dir(&dir).unwrap();
+    let file = dir.join("file.txt");
+    fs::write(&file, "original").unwrap();
+    let outside_file = outside.path().join("file.txt");
+    fs::write(&outside_file, "outside").unwrap();
+
+    let interceptor = UndoInterceptor::new(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+        UndoConfig {
+            symlink_policy: SymlinkPolicy::ReadWrite,
+            ..Default::default()
+        },
+    );
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&file, b"modified");
+    interceptor.close_step(1).unwrap();
+
+    // Replace the directory with a symlink to somewhere outside the root.
+    fs::remove_dir_all(&dir).unwrap();
+    if !try_create_symlink(outside.path(), &dir) {
+        eprintln!("skipping: symlink creation not supported");
+        return;
+    }
+
+    interceptor.rollback(1, true).unwrap();
+    assert_eq!(fs::read_to_string(&outside_file).unwrap(), "outside");
+}
+
+// ---------------------------------------------------------------------------
+// SY-12: The symlink policy can be changed at runtime
+// ---------------------------------------------------------------------------
+#[test]
+fn sy_12_policy_change_applies_to_later_operations() {
+    let ws = TempWorkspace::new();
+    let target = ws.working_dir.join("target.txt");
+    fs::write(&target, "content").unwrap();
+
+    let link = ws.working_dir.join("link.txt");
+    if !try_create_symlink(&target, &link) {
+        eprintln!("skipping: symlink creation not supported");
+        return;
+    }
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    assert_eq!(interceptor.symlink_policy(), SymlinkPolicy::Ignore);
+    interceptor.set_symlink_policy(SymlinkPolicy::ReadWrite);
+
+    interceptor.open_step(1).unwrap();
+    interceptor.post_symlink(&target, &link).unwrap();
+    interceptor.close_step(1).unwrap();
+
+    let manifest = read_step_manifest(&ws, 1);
+    assert!(manifest.contains_path("link.txt"));
+
+    interceptor.rollback(1, false).unwrap();
+    assert!(link.symlink_metadata().is_err(), "created symlink should be removed");
+}
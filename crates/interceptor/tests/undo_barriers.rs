@@ -1,7 +1,11 @@
 use std::fs;
 use std::path::PathBuf;
 
-use codeagent_common::{AffectedPath, BarrierReason, CodeAgentError, ExternalModificationPolicy};
+use codeagent_common::{
+    AffectedPath, BarrierReason, CodeAgentError, ExternalModificationConfig,
+    ExternalModificationPolicy, ExternalModificationRule, FileChangeKind, MergedPath,
+    RollbackMode,
+};
 use codeagent_interceptor::undo_interceptor::{UndoConfig, UndoInterceptor};
 use codeagent_test_support::fixtures;
 use codeagent_test_support::snapshot::assert_tree_eq;
@@ -295,7 +299,7 @@ fn eb_08_warn_policy_no_barrier() {
         ws.working_dir.clone(),
         ws.undo_dir.clone(),
         UndoConfig {
-            policy: ExternalModificationPolicy::Warn,
+            external_modification: ExternalModificationPolicy::Warn.into(),
             ..Default::default()
         },
     );
@@ -495,3 +499,243 @@ fn eb_13_pre_step_barrier_does_not_block_rollback() {
     assert_eq!(result.steps_rolled_back, 1);
     assert!(result.barriers_crossed.is_empty());
 }
+
+fn interceptor_with_rules(
+    ws: &TempWorkspace,
+    default_policy: ExternalModificationPolicy,
+    rules: &[(&str, ExternalModificationPolicy)],
+) -> UndoInterceptor {
+    UndoInterceptor::new(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+        UndoConfig {
+            external_modification: ExternalModificationConfig {
+                default_policy,
+                rules: rules
+                    .iter()
+                    .map(|(pattern, policy)| ExternalModificationRule {
+                        pattern: pattern.to_string(),
+                        policy: *policy,
+                    })
+                    .collect(),
+            },
+            ..Default::default()
+        },
+    )
+}
+
+// ---------------------------------------------------------------------------
+// EB-14: only paths whose rule is `barrier` are recorded in the barrier
+// ---------------------------------------------------------------------------
+#[test]
+fn eb_14_rules_select_barrier_paths() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = interceptor_with_rules(
+        &ws,
+        ExternalModificationPolicy::Barrier,
+        &[
+            ("target/**", ExternalModificationPolicy::Ignore),
+            ("*.log", ExternalModificationPolicy::Warn),
+        ],
+    );
+
+    let barrier = interceptor
+        .notify_external_modification(
+            vec![
+                PathBuf::from("target/debug/app").into(),
+                PathBuf::from("build.log").into(),
+                ws.working_dir.join("src/main.rs").into(),
+            ],
+            BarrierReason::ExternalModification,
+        )
+        .unwrap()
+        .expect("src/main.rs should create a barrier");
+
+    assert_eq!(
+        barrier.affected_paths,
+        vec![AffectedPath::from(ws.working_dir.join("src/main.rs"))]
+    );
+}
+
+// ---------------------------------------------------------------------------
+// EB-15: batches with no barrier paths create no barrier
+// ---------------------------------------------------------------------------
+#[test]
+fn eb_15_ignored_and_warned_paths_create_no_barrier() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = interceptor_with_rules(
+        &ws,
+        ExternalModificationPolicy::Barrier,
+        &[
+            (".idea/**", ExternalModificationPolicy::Ignore),
+            ("*.log", ExternalModificationPolicy::Warn),
+        ],
+    );
+
+    let result = interceptor
+        .notify_external_modification(
+            vec![
+                ws.working_dir.join(".idea/workspace.xml").into(),
+                PathBuf::from("build.log").into(),
+            ],
+            BarrierReason::ExternalModification,
+        )
+        .unwrap();
+    assert!(result.is_none());
+    assert!(interceptor.barriers().is_empty());
+
+    assert_eq!(
+        interceptor.external_modification_policy(&PathBuf::from(".idea/workspace.xml").into()),
+        ExternalModificationPolicy::Ignore
+    );
+    assert_eq!(
+        interceptor.external_modification_policy(&PathBuf::from("build.log").into()),
+        ExternalModificationPolicy::Warn
+    );
+}
+
+// ---------------------------------------------------------------------------
+// EB-16: a rename takes the stricter policy of its two ends
+// ---------------------------------------------------------------------------
+#[test]
+fn eb_16_rename_uses_stricter_policy() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = interceptor_with_rules(
+        &ws,
+        ExternalModificationPolicy::Warn,
+        &[
+            ("target/**", ExternalModificationPolicy::Ignore),
+            ("src/**", ExternalModificationPolicy::Barrier),
+        ],
+    );
+
+    let moved_out = AffectedPath {
+        path: PathBuf::from("target/lib.rs"),
+        kind: FileChangeKind::Renamed,
+        renamed_from: Some(PathBuf::from("src/lib.rs")),
+    };
+    assert_eq!(
+        interceptor.external_modification_policy(&moved_out),
+        ExternalModificationPolicy::Barrier
+    );
+
+    let within_target = AffectedPath {
+        path: PathBuf::from("target/b"),
+        kind: FileChangeKind::Renamed,
+        renamed_from: Some(PathBuf::from("target/a")),
+    };
+    assert_eq!(
+        interceptor.external_modification_policy(&within_target),
+        ExternalModificationPolicy::Ignore
+    );
+}
+
+// ---------------------------------------------------------------------------
+// EB-17: rules can be replaced at runtime; session-start barriers follow the
+// default policy
+// ---------------------------------------------------------------------------
+#[test]
+fn eb_17_rules_replaced_at_runtime() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+
+    interceptor.set_external_modification_config(&ExternalModificationConfig {
+        default_policy: ExternalModificationPolicy::Ignore,
+        rules: vec![ExternalModificationRule {
+            pattern: "src/**".to_string(),
+            policy: ExternalModificationPolicy::Barrier,
+        }],
+    });
+
+    let result = interceptor
+        .notify_external_modification(vec![], BarrierReason::SessionStart)
+        .unwrap();
+    assert!(result.is_none(), "default policy ignore should skip the session-start barrier");
+
+    let result = interceptor
+        .notify_external_modification(
+            vec![PathBuf::from("src/main.rs").into()],
+            BarrierReason::ExternalModification,
+        )
+        .unwrap();
+    assert!(result.is_some());
+}
+
+// ---------------------------------------------------------------------------
+// EB-18: merge-mode rollback keeps external edits to other lines
+// ---------------------------------------------------------------------------
+#[test]
+fn eb_18_merge_rollback_keeps_external_edits() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let notes = ws.working_dir.join("notes.txt");
+    fs::write(&notes, "one\ntwo\nthree\nfour\nfive\n").unwrap();
+    let small_before = fs::read(ws.working_dir.join("small.txt")).unwrap();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&notes, b"one\nTWO\nthree\nfour\nfive\n");
+    ops.write_file(&ws.working_dir.join("small.txt"), b"step 1");
+    interceptor.close_step(1).unwrap();
+
+    fs::write(&notes, "one\nTWO\nthree\nfour\nFIVE\n").unwrap();
+    interceptor
+        .notify_external_modification(
+            vec![PathBuf::from("notes.txt").into()],
+            BarrierReason::ExternalModification,
+        )
+        .unwrap();
+
+    let result = interceptor
+        .rollback_with_mode(1, true, false, RollbackMode::Merge)
+        .unwrap();
+
+    assert_eq!(result.barriers_crossed.len(), 1);
+    assert_eq!(
+        result.merged,
+        vec![MergedPath {
+            path: PathBuf::from("notes.txt"),
+            conflicts: 0,
+        }],
+    );
+    assert_eq!(fs::read_to_string(&notes).unwrap(), "one\ntwo\nthree\nfour\nFIVE\n");
+    assert_eq!(fs::read(ws.working_dir.join("small.txt")).unwrap(), small_before);
+}
+
+// ---------------------------------------------------------------------------
+// EB-19: merge-mode rollback leaves conflict markers and still needs force
+// ---------------------------------------------------------------------------
+#[test]
+fn eb_19_merge_rollback_conflicts() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let notes = ws.working_dir.join("notes.txt");
+    fs::write(&notes, "alpha\nbeta\n").unwrap();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&notes, b"alpha\nstep\n");
+    interceptor.close_step(1).unwrap();
+
+    fs::write(&notes, "alpha\nuser\n").unwrap();
+    interceptor
+        .notify_external_modification(
+            vec![PathBuf::from("notes.txt").into()],
+            BarrierReason::ExternalModification,
+        )
+        .unwrap();
+
+    let blocked = interceptor.rollback_with_mode(1, false, false, RollbackMode::Merge);
+    assert!(matches!(blocked, Err(CodeAgentError::RollbackBlocked { .. })));
+
+    let result = interceptor
+        .rollback_with_mode(1, true, false, RollbackMode::Merge)
+        .unwrap();
+
+    assert_eq!(result.merged.len(), 1);
+    assert_eq!(result.merged[0].conflicts, 1);
+    assert_eq!(
+        fs::read_to_string(&notes).unwrap(),
+        "alpha\n<<<<<<< before step 1\nbeta\n=======\nuser\n>>>>>>> current\n",
+    );
+}
@@ -1,12 +1,19 @@
 use std::fs;
-
-use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use std::time::Duration;
+
+use codeagent_common::{
+    CaseSensitivity, CodeAgentError, ResourceLimitsConfig, RollbackMode, SafeguardConfig, StepType,
+};
+use codeagent_interceptor::manifest::StepManifest;
+use codeagent_interceptor::safeguard::ChannelSafeguardHandler;
+use codeagent_interceptor::undo_interceptor::{UndoConfig, UndoInterceptor};
+use codeagent_interceptor::write_interceptor::WriteInterceptor;
 use codeagent_test_support::fixtures;
 use codeagent_test_support::snapshot::assert_tree_eq;
 use codeagent_test_support::workspace::TempWorkspace;
 
 mod common;
-use common::{OperationApplier, compare_opts};
+use common::{CancellingMonitor, OperationApplier, compare_opts};
 
 // ---------------------------------------------------------------------------
 // UI-01: Write same file 3x in one step
@@ -480,3 +487,468 @@ fn ui_15_copy_file_range() {
     );
     assert_tree_eq(&before, &after, &compare_opts());
 }
+
+// ---------------------------------------------------------------------------
+// UI-25: Closed steps record duration, exit code and preimage size
+// ---------------------------------------------------------------------------
+#[test]
+fn ui_25_step_metadata_recorded() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    interceptor.set_step_command("make".to_string());
+    ops.write_file(&ws.working_dir.join("small.txt"), b"rewritten");
+    ops.create_file(&ws.working_dir.join("new.txt"), b"created");
+    interceptor.set_step_exit_code(2);
+    interceptor.close_step(1).unwrap();
+
+    // An empty step is not stored and takes no history ID.
+    interceptor.open_step(7).unwrap();
+    interceptor.close_step(7).unwrap();
+
+    interceptor.open_step(9).unwrap();
+    interceptor.set_step_type(StepType::Api);
+    ops.write_file(&ws.working_dir.join("small.txt"), b"again");
+    interceptor.close_step(9).unwrap();
+    assert_eq!(interceptor.history_step_id(7), None);
+    assert_eq!(interceptor.history_step_id(9), Some(2));
+
+    let manifest = StepManifest::read_from(&ws.undo_dir.join("steps").join("1")).unwrap();
+    assert_eq!(manifest.exit_code, Some(2));
+    assert!(manifest.duration_ms.is_some());
+    assert!(manifest.preimage_bytes > 0);
+
+    let info = interceptor.completed_step_info();
+    assert_eq!(info.len(), 2);
+    assert_eq!(info[0].id, 1);
+    assert_eq!(info[0].step_type, StepType::Command);
+    assert_eq!(info[0].command.as_deref(), Some("make"));
+    assert_eq!(info[0].exit_code, Some(2));
+    assert_eq!(info[0].file_count, 2);
+    assert_eq!(info[0].preimage_bytes, manifest.preimage_bytes);
+    assert_eq!(info[1].step_type, StepType::Api);
+    assert_eq!(info[1].exit_code, None);
+    assert_eq!(info[1].file_count, 1);
+}
+
+// ---------------------------------------------------------------------------
+// UI-26: Strict rollback fails when the history is too short
+// ---------------------------------------------------------------------------
+#[test]
+fn ui_26_strict_rollback_insufficient_history() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    let target = ws.working_dir.join("small.txt");
+
+    for (id, contents) in [(1, b"one"), (2, b"two")] {
+        interceptor.open_step(id).unwrap();
+        ops.write_file(&target, contents);
+        interceptor.close_step(id).unwrap();
+    }
+    let completed = interceptor.completed_steps();
+
+    let error = interceptor.rollback_strict(3, false).unwrap_err();
+    assert!(matches!(
+        error,
+        CodeAgentError::InsufficientHistory { requested: 3, available: 2 }
+    ));
+    assert_eq!(interceptor.completed_steps(), completed);
+    assert_eq!(fs::read_to_string(&target).unwrap(), "two");
+
+    let result = interceptor.rollback_strict(1, false).unwrap();
+    assert_eq!((result.steps_requested, result.steps_rolled_back), (1, 1));
+    assert_eq!(result.rolled_back_step_ids, vec![completed[1]]);
+
+    let result = interceptor.rollback(5, false).unwrap();
+    assert_eq!((result.steps_requested, result.steps_rolled_back), (5, 1));
+    assert_eq!(result.rolled_back_step_ids, vec![completed[0]]);
+    assert_eq!(fs::read_to_string(&target).unwrap(), "hello world");
+}
+
+// ---------------------------------------------------------------------------
+// UI-27: Monitored rollback reports progress and stops when cancelled
+// ---------------------------------------------------------------------------
+#[test]
+fn ui_27_cancelled_rollback_keeps_remaining_steps() {
+    let ws = TempWorkspace::new();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    for id in 1..=3 {
+        let file = ws.working_dir.join(format!("file_{id}.txt"));
+        fs::write(&file, b"original").unwrap();
+        interceptor.open_step(id).unwrap();
+        ops.write_file(&file, b"changed");
+        interceptor.close_step(id).unwrap();
+    }
+    let completed = interceptor.completed_steps();
+
+    // Cancelled once the newest step's file has been reported.
+    let monitor = CancellingMonitor::new(1);
+    let result = interceptor
+        .rollback_monitored(3, false, false, RollbackMode::Restore, &monitor)
+        .unwrap();
+    assert!(result.cancelled);
+    assert_eq!(result.steps_requested, 3);
+    assert_eq!(result.rolled_back_step_ids, vec![completed[2]]);
+    assert_eq!(interceptor.completed_steps(), completed[..2]);
+    assert_eq!(fs::read_to_string(ws.working_dir.join("file_3.txt")).unwrap(), "original");
+    assert_eq!(fs::read_to_string(ws.working_dir.join("file_2.txt")).unwrap(), "changed");
+
+    let reports = monitor.reports.lock().unwrap().clone();
+    assert_eq!(reports[0], (0, Some("file_3.txt".to_string())));
+    assert_eq!(reports.last().unwrap().0, 33);
+
+    // Unmonitored rollbacks run to the end.
+    let result = interceptor.rollback(2, false).unwrap();
+    assert!(!result.cancelled);
+    assert_eq!(result.steps_rolled_back, 2);
+}
+
+// ---------------------------------------------------------------------------
+// UI-28: A corrupt preimage fails the rollback before anything is restored
+// ---------------------------------------------------------------------------
+#[test]
+fn ui_28_corrupt_preimage_fails_rollback_untouched() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("small.txt"), b"changed");
+    ops.write_file(&ws.working_dir.join("src/main.rs"), b"changed");
+    ops.create_file(&ws.working_dir.join("created.txt"), b"created");
+    interceptor.close_step(1).unwrap();
+    let before = ws.snapshot();
+
+    let preimages = ws.undo_dir.join("steps").join("1").join("preimages");
+    let hash = codeagent_interceptor::preimage::path_hash(std::path::Path::new("src/main.rs"));
+    let data = preimages.join(format!("{hash}.dat"));
+    fs::write(&data, zstd::encode_all(&b"bit rot"[..], 3).unwrap()).unwrap();
+
+    let error = interceptor.rollback(1, false).unwrap_err();
+    assert!(matches!(error, CodeAgentError::Preimage { .. }), "{error}");
+    assert!(error.to_string().contains("src/main.rs"));
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+    assert_eq!(interceptor.completed_steps().len(), 1);
+}
+
+// ---------------------------------------------------------------------------
+// UI-29: A rollback that fails midway puts back what it had restored
+// ---------------------------------------------------------------------------
+#[test]
+fn ui_29_failed_rollback_is_reverted() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("small.txt"), b"changed");
+    ops.create_file(&ws.working_dir.join("added.txt"), b"added");
+    ops.delete_file(&ws.working_dir.join("src/components/app.rs"));
+    interceptor.close_step(1).unwrap();
+
+    // small.txt is restored before app.rs, which fails: its directory is
+    // now a file.
+    fs::remove_dir(ws.working_dir.join("src/components")).unwrap();
+    fs::write(ws.working_dir.join("src/components"), "in the way").unwrap();
+    let before = ws.snapshot();
+
+    assert!(interceptor.rollback(1, false).is_err());
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+    assert_eq!(interceptor.completed_steps().len(), 1);
+    let step_dir = ws.undo_dir.join("steps").join("1");
+    assert!(!codeagent_interceptor::rollback_journal::journal_dir(&step_dir).exists());
+
+    fs::remove_file(ws.working_dir.join("src/components")).unwrap();
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read_to_string(ws.working_dir.join("small.txt")).unwrap(), "hello world");
+    assert!(ws.working_dir.join("src/components/app.rs").exists());
+    assert!(!ws.working_dir.join("added.txt").exists());
+}
+
+// ---------------------------------------------------------------------------
+// UI-30: Attribute-only changes store no contents and are rolled back fully
+// ---------------------------------------------------------------------------
+#[cfg(target_os = "linux")]
+#[test]
+fn ui_30_attribute_changes_roll_back_without_contents() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use filetime::FileTime;
+
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+
+    let target = ws.working_dir.join("run.sh");
+    xattr::set(&target, "user.kept", b"original").unwrap();
+    let atime = FileTime::from_unix_time(1_000_000, 0);
+    let mtime = FileTime::from_unix_time(2_000_000, 500);
+    filetime::set_file_times(&target, atime, mtime).unwrap();
+    let contents = fs::read(&target).unwrap();
+    filetime::set_file_times(&target, atime, mtime).unwrap();
+
+    interceptor.open_step(1).unwrap();
+    ops.chmod(&target, 0o600);
+    ops.set_xattr(&target, "user.kept", b"changed");
+    ops.set_xattr(&target, "user.added", b"new");
+    ops.set_times(&target, FileTime::from_unix_time(5, 0), FileTime::from_unix_time(6, 0));
+    interceptor.close_step(1).unwrap();
+
+    let preimages = ws.undo_dir.join("steps").join("1").join("preimages");
+    let hash = codeagent_interceptor::preimage::path_hash(std::path::Path::new("run.sh"));
+    assert!(!preimages.join(format!("{hash}.dat")).exists());
+    let meta = codeagent_interceptor::preimage::read_preimage_metadata(&preimages, &hash).unwrap();
+    assert!(meta.metadata_only);
+    assert_eq!(meta.uid, Some(fs::metadata(&target).unwrap().uid()));
+
+    interceptor.rollback(1, false).unwrap();
+
+    let restored = fs::metadata(&target).unwrap();
+    assert_eq!(restored.permissions().mode() & 0o7777, 0o755);
+    assert_eq!(FileTime::from_last_modification_time(&restored), mtime);
+    assert_eq!(FileTime::from_last_access_time(&restored), atime);
+    assert_eq!(xattr::get(&target, "user.kept").unwrap(), Some(b"original".to_vec()));
+    assert_eq!(xattr::get(&target, "user.added").unwrap(), None);
+    assert_eq!(fs::read(&target).unwrap(), contents);
+}
+
+// ---------------------------------------------------------------------------
+// UI-31: Writing a file after changing its attributes captures its contents
+// ---------------------------------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn ui_31_write_after_chmod_captures_contents() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    let before = ws.snapshot();
+
+    interceptor.open_step(1).unwrap();
+    let script = ws.working_dir.join("run.sh");
+    ops.chmod(&script, 0o700);
+    ops.write_file(&script, b"#!/bin/sh\necho changed\n");
+    let medium = ws.working_dir.join("medium.txt");
+    ops.chmod(&medium, 0o600);
+    ops.write_range(&medium, 10, b"patched");
+    interceptor.close_step(1).unwrap();
+
+    let preimages = ws.undo_dir.join("steps").join("1").join("preimages");
+    let hash = codeagent_interceptor::preimage::path_hash(std::path::Path::new("run.sh"));
+    assert!(preimages.join(format!("{hash}.dat")).exists());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::metadata(&script).unwrap().permissions().mode() & 0o7777, 0o755);
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// UI-32: Case-insensitive roots capture one spelling and undo case-only renames
+// ---------------------------------------------------------------------------
+#[test]
+fn ui_32_case_insensitive_root_undoes_case_only_rename() {
+    use codeagent_interceptor::write_interceptor::WriteInterceptor;
+
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new(
+        ws.working_dir.clone(),
+        ws.undo_dir.clone(),
+        UndoConfig {
+            case_sensitivity: CaseSensitivity::Insensitive,
+            ..Default::default()
+        },
+    );
+    assert!(interceptor.is_case_insensitive());
+    let ops = OperationApplier::new(&interceptor);
+    let before = ws.snapshot();
+
+    interceptor.open_step(1).unwrap();
+    ops.write_file(&ws.working_dir.join("small.txt"), b"changed");
+    // Another spelling of a touched path is not captured again.
+    interceptor.pre_write(&ws.working_dir.join("SMALL.TXT")).unwrap();
+    ops.rename(&ws.working_dir.join("small.txt"), &ws.working_dir.join("Small.txt"));
+    interceptor.close_step(1).unwrap();
+
+    let manifest = StepManifest::read_from(&ws.undo_dir.join("steps").join("1")).unwrap();
+    let entries: Vec<(&str, bool)> = manifest
+        .entries
+        .iter()
+        .map(|(path, entry)| (path.as_str(), entry.existed_before))
+        .collect();
+    assert_eq!(entries, [("Small.txt", false), ("small.txt", true)]);
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// UI-33: A directory rename is recorded as one operation, not a tree copy
+// ---------------------------------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn ui_33_directory_rename_is_recorded_without_copying_the_tree() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    let before = ws.snapshot();
+    let src = ws.working_dir.join("src");
+    let moved = ws.working_dir.join("moved");
+
+    interceptor.open_step(1).unwrap();
+    ops.rename(&src, &moved);
+    ops.write_file(&moved.join("main.rs"), b"fn main() { changed() }");
+    ops.create_file(&moved.join("new.rs"), b"new");
+    ops.delete_file(&moved.join("components/app.rs"));
+    // The vacated name is used again.
+    ops.mkdir(&src);
+    ops.create_file(&src.join("other.rs"), b"other");
+    interceptor.close_step(1).unwrap();
+
+    let manifest = StepManifest::read_from(&ws.undo_dir.join("steps").join("1")).unwrap();
+    let renames: Vec<(&str, &str)> = manifest
+        .renames
+        .iter()
+        .map(|rename| (rename.from.as_str(), rename.to.as_str()))
+        .collect();
+    assert_eq!(renames, [("src", "moved")]);
+    let entries: Vec<&str> = manifest.entries.keys().map(String::as_str).collect();
+    assert_eq!(entries, ["src", "src/components/app.rs", "src/main.rs", "src/new.rs"]);
+    assert!(manifest.step_info().affected_paths.contains(&"moved".into()));
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// UI-34: Renames over an existing destination capture the tree instead
+// ---------------------------------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn ui_34_rename_over_existing_directory_captures_the_tree() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    fs::create_dir(ws.working_dir.join("target")).unwrap();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    let before = ws.snapshot();
+
+    interceptor.open_step(1).unwrap();
+    ops.rename(&ws.working_dir.join("src"), &ws.working_dir.join("target"));
+    ops.rename(&ws.working_dir.join("small.txt"), &ws.working_dir.join("renamed.txt"));
+    interceptor.close_step(1).unwrap();
+
+    let manifest = StepManifest::read_from(&ws.undo_dir.join("steps").join("1")).unwrap();
+    assert!(manifest.renames.is_empty());
+    assert!(manifest.entries["src/main.rs"].existed_before);
+    assert!(!manifest.entries["target/main.rs"].existed_before);
+    assert!(!manifest.entries["renamed.txt"].existed_before);
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// UI-35: Replacing a directory destination captures its tree; rollback
+// restores both sides
+// ---------------------------------------------------------------------------
+#[test]
+fn ui_35_rename_replacing_a_directory_restores_both_sides() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let target = ws.working_dir.join("target");
+    fs::create_dir_all(target.join("debug")).unwrap();
+    fs::write(target.join("debug/app"), "binary").unwrap();
+    fs::write(target.join("main.rs"), "stale").unwrap();
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    let before = ws.snapshot();
+
+    interceptor.open_step(1).unwrap();
+    ops.rename_replacing(&ws.working_dir.join("src"), &target);
+    interceptor.close_step(1).unwrap();
+
+    let manifest = StepManifest::read_from(&ws.undo_dir.join("steps").join("1")).unwrap();
+    assert!(manifest.entries["target/debug/app"].existed_before);
+    assert!(manifest.entries["target/main.rs"].existed_before);
+    assert!(!target.join("debug").exists());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+
+    // A file renamed over the directory, and back over the file again.
+    interceptor.open_step(2).unwrap();
+    ops.rename_replacing(&ws.working_dir.join("small.txt"), &target);
+    assert!(target.is_file());
+    ops.rename_replacing(&ws.working_dir.join("src"), &target);
+    interceptor.close_step(2).unwrap();
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// UI-36: The builder combines gitignore, safeguards and resource limits
+// ---------------------------------------------------------------------------
+#[test]
+fn ui_36_builder_combines_options() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    fs::write(ws.working_dir.join(".gitignore"), "build/\n").unwrap();
+    fs::create_dir(ws.working_dir.join("build")).unwrap();
+    let interceptor = UndoInterceptor::builder(ws.working_dir.clone(), ws.undo_dir.clone())
+        .gitignore(true)
+        .safeguards(
+            SafeguardConfig { delete_threshold: Some(2), ..Default::default() },
+            // Nobody answers, so every safeguard is denied.
+            Box::new(ChannelSafeguardHandler::new(Duration::ZERO, |_| {})),
+        )
+        .resource_limits(ResourceLimitsConfig { max_step_count: Some(1), ..Default::default() })
+        .build();
+    let ops = OperationApplier::new(&interceptor);
+
+    interceptor.open_step(1).unwrap();
+    ops.create_file(&ws.working_dir.join("build/out.o"), b"object");
+    ops.write_file(&ws.working_dir.join("small.txt"), b"first");
+    interceptor.close_step(1).unwrap();
+    let manifest = StepManifest::read_from(&ws.undo_dir.join("steps").join("1")).unwrap();
+    assert!(manifest.entries.contains_key("small.txt"));
+    assert!(!manifest.entries.contains_key("build/out.o"));
+
+    interceptor.open_step(2).unwrap();
+    ops.write_file(&ws.working_dir.join("small.txt"), b"second");
+    interceptor.close_step(2).unwrap();
+    assert_eq!(interceptor.completed_steps(), vec![2]);
+
+    interceptor.open_step(3).unwrap();
+    ops.delete_file(&ws.working_dir.join("small.txt"));
+    let denied = interceptor.pre_unlink(&ws.working_dir.join("src/main.rs"), false);
+    assert!(matches!(denied, Err(CodeAgentError::SafeguardDenied { step_id: 3, .. })));
+    assert_eq!(fs::read(ws.working_dir.join("small.txt")).unwrap(), b"second");
+}
+
+// ---------------------------------------------------------------------------
+// UI-37: A directory created by the step and holding one it renamed there is
+// left in place by the rollback journal, so the rename is undone
+// ---------------------------------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn ui_37_rollback_undoes_a_rename_into_a_created_directory() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    let before = ws.snapshot();
+    let vendor = ws.working_dir.join("vendor");
+
+    interceptor.open_step(1).unwrap();
+    ops.mkdir(&vendor);
+    ops.rename(&ws.working_dir.join("src"), &vendor.join("src"));
+    ops.create_file(&vendor.join("lock"), b"lock");
+    ops.create_file(&ws.working_dir.join("made.txt"), b"made");
+    interceptor.close_step(1).unwrap();
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
@@ -1,5 +1,13 @@
 use std::fs;
 
+use codeagent_common::{RollbackMode, SymlinkPolicy};
+use codeagent_interceptor::chain;
+use codeagent_interceptor::history_journal::{
+    self, EVICTION_JOURNAL_FILE, EvictionJournal, JournaledStep, ROLLBACK_MARKER_FILE,
+    RollbackMarker, SQUASH_JOURNAL_FILE, SquashJournal,
+};
+use codeagent_interceptor::rollback_journal;
+use codeagent_interceptor::squash;
 use codeagent_interceptor::undo_interceptor::UndoInterceptor;
 use codeagent_interceptor::write_interceptor::WriteInterceptor;
 use codeagent_test_support::fixtures;
@@ -307,3 +315,229 @@ fn step_reconstruction_from_disk() {
         "step 1"
     );
 }
+
+// ---------------------------------------------------------------------------
+// CR-08: Crash during a rollback
+// The journal recorded before the rollback puts the working tree back at
+// recovery; the step stays in the history and can be rolled back again.
+// ---------------------------------------------------------------------------
+#[test]
+fn cr_08_interrupted_rollback_is_reverted() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let step_dir = ws.undo_dir.join("steps").join("1");
+
+    // Phase 1: Close a step, then "crash" partway through rolling it back
+    {
+        let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        let ops = OperationApplier::new(&interceptor);
+        interceptor.open_step(1).unwrap();
+        ops.write_file(&ws.working_dir.join("small.txt"), b"step contents");
+        ops.create_file(&ws.working_dir.join("made.txt"), b"made by the step");
+        interceptor.close_step(1).unwrap();
+    }
+    let after_step = ws.snapshot();
+    rollback_journal::record(&step_dir, &ws.working_dir, SymlinkPolicy::Ignore).unwrap();
+    // The journal moved the file the rollback deletes aside already.
+    assert!(!ws.working_dir.join("made.txt").exists());
+    fs::write(ws.working_dir.join("small.txt"), "hello world").unwrap();
+
+    // Phase 2: Restart and recover
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let info = interceptor.recover().unwrap().expect("journal reverted");
+    assert_eq!((info.paths_restored, info.rollbacks_reverted), (2, 1));
+    assert_tree_eq(&after_step, &ws.snapshot(), &compare_opts());
+    assert!(!rollback_journal::journal_dir(&step_dir).exists());
+    assert!(interceptor.recover().unwrap().is_none());
+
+    assert_eq!(interceptor.completed_steps(), vec![1]);
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read_to_string(ws.working_dir.join("small.txt")).unwrap(), "hello world");
+}
+
+/// Close steps 1..=3, each writing its number to `small.txt`.
+fn close_numbered_steps(ws: &TempWorkspace) {
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let ops = OperationApplier::new(&interceptor);
+    for step_id in 1..=3 {
+        interceptor.open_step(step_id).unwrap();
+        ops.write_file(&ws.working_dir.join("small.txt"), step_id.to_string().as_bytes());
+        interceptor.close_step(step_id).unwrap();
+    }
+}
+
+fn journaled(ws: &TempWorkspace, step_id: i64) -> JournaledStep {
+    JournaledStep {
+        step_id,
+        link: chain::read_link(&ws.undo_dir.join("steps").join(step_id.to_string())),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// CR-09: Crash during a rollback of two steps, after the first was removed
+// Recovery rolls back the step still listed and rewinds the chain.
+// ---------------------------------------------------------------------------
+#[test]
+fn cr_09_interrupted_multi_step_rollback_is_completed() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    close_numbered_steps(&ws);
+    let marker = RollbackMarker {
+        mode: RollbackMode::Restore,
+        steps: vec![journaled(&ws, 3), journaled(&ws, 2)],
+    };
+
+    // Phase 1: Step 3 is rolled back and its directory half deleted
+    {
+        let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        interceptor.rollback(1, false).unwrap();
+    }
+    history_journal::write(&ws.undo_dir, ROLLBACK_MARKER_FILE, &marker).unwrap();
+    let removing = ws.undo_dir.join("steps").join("3.removing");
+    fs::create_dir_all(removing.join("preimages")).unwrap();
+
+    // Phase 2: Restart and recover
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let info = interceptor.recover().unwrap().expect("rollback completed");
+    assert_eq!((info.steps_rolled_back, info.steps_evicted), (1, 0));
+    assert_eq!(fs::read_to_string(ws.working_dir.join("small.txt")).unwrap(), "1");
+    assert_eq!(interceptor.completed_steps(), vec![1]);
+    assert!(!ws.undo_dir.join(ROLLBACK_MARKER_FILE).exists());
+    assert!(!removing.exists());
+    assert!(interceptor.attest().unwrap().verified);
+    assert!(interceptor.recover().unwrap().is_none());
+}
+
+// ---------------------------------------------------------------------------
+// CR-10: Crash during an eviction of two steps, after the first was removed
+// Recovery removes the step still on disk and moves the chain anchor past
+// both.
+// ---------------------------------------------------------------------------
+#[test]
+fn cr_10_interrupted_eviction_is_completed() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    close_numbered_steps(&ws);
+    let journal = EvictionJournal {
+        steps: vec![journaled(&ws, 1), journaled(&ws, 2)],
+    };
+    history_journal::write(&ws.undo_dir, EVICTION_JOURNAL_FILE, &journal).unwrap();
+    fs::remove_dir_all(ws.undo_dir.join("steps").join("1")).unwrap();
+
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let info = interceptor.recover().unwrap().expect("eviction completed");
+    assert_eq!((info.steps_rolled_back, info.steps_evicted), (0, 1));
+    assert_eq!(interceptor.completed_steps(), vec![3]);
+    assert!(!ws.undo_dir.join(EVICTION_JOURNAL_FILE).exists());
+    assert!(interceptor.attest().unwrap().verified);
+    assert_eq!(fs::read_to_string(ws.working_dir.join("small.txt")).unwrap(), "3");
+
+    interceptor.rollback(1, false).unwrap();
+    assert_eq!(fs::read_to_string(ws.working_dir.join("small.txt")).unwrap(), "2");
+}
+
+// ---------------------------------------------------------------------------
+// CR-11: Crash mid-step after a directory rename
+// The rename, recorded in the WAL as it happened, is undone at recovery.
+// ---------------------------------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn cr_11_crash_after_directory_rename_renames_it_back() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let before = ws.snapshot();
+
+    // Phase 1: Rename a directory, edit under its new name, "crash"
+    {
+        let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        let ops = OperationApplier::new(&interceptor);
+        interceptor.open_step(1).unwrap();
+        ops.rename(&ws.working_dir.join("src"), &ws.working_dir.join("moved"));
+        ops.write_file(&ws.working_dir.join("moved/main.rs"), b"corrupted");
+    }
+
+    // Phase 2: Restart and recover
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let info = interceptor.recover().unwrap().expect("step rolled back");
+    assert!(!info.manifest_valid);
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// CR-12: Crash while rolling back a step that renamed a directory
+// The journal moves the directory back where the step left it.
+// ---------------------------------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn cr_12_interrupted_rollback_of_a_rename_is_reverted() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let before = ws.snapshot();
+    let step_dir = ws.undo_dir.join("steps").join("1");
+    let src = ws.working_dir.join("src");
+    let moved = ws.working_dir.join("moved");
+
+    // Phase 1: Close a step, then "crash" after the rollback renamed back
+    {
+        let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        let ops = OperationApplier::new(&interceptor);
+        interceptor.open_step(1).unwrap();
+        ops.rename(&src, &moved);
+        ops.write_file(&moved.join("main.rs"), b"step contents");
+        ops.mkdir(&src);
+        ops.create_file(&src.join("other.rs"), b"made at the old name");
+        interceptor.close_step(1).unwrap();
+    }
+    let after_step = ws.snapshot();
+    rollback_journal::record(&step_dir, &ws.working_dir, SymlinkPolicy::Ignore).unwrap();
+    // The journal moved the directory at the old name aside already.
+    assert!(!src.exists());
+    fs::rename(&moved, &src).unwrap();
+
+    // Phase 2: Restart and recover
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let info = interceptor.recover().unwrap().expect("journal reverted");
+    assert_eq!(info.rollbacks_reverted, 1);
+    assert_tree_eq(&after_step, &ws.snapshot(), &compare_opts());
+
+    interceptor.rollback(1, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
+
+// ---------------------------------------------------------------------------
+// CR-13: Crash while swapping in a squashed step
+// Recovery removes the steps it replaces and re-links the chain.
+// ---------------------------------------------------------------------------
+#[test]
+fn cr_13_interrupted_squash_is_completed() {
+    let ws = TempWorkspace::with_fixture(fixtures::small_tree);
+    let before = ws.snapshot();
+    let steps_dir = ws.undo_dir.join("steps");
+
+    // Phase 1: Close three steps, stage the squash of the first two, "crash"
+    {
+        let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+        let ops = OperationApplier::new(&interceptor);
+        for id in 1..=3 {
+            interceptor.open_step(id).unwrap();
+            ops.write_file(&ws.working_dir.join("small.txt"), format!("step {id}").as_bytes());
+            interceptor.close_step(id).unwrap();
+        }
+    }
+    squash::build_squashed_step(
+        &[steps_dir.join("1"), steps_dir.join("2")],
+        &steps_dir.join("1.squashing"),
+        false,
+    )
+    .unwrap();
+    let journal = SquashJournal { steps: vec![1, 2] };
+    history_journal::write(&ws.undo_dir, SQUASH_JOURNAL_FILE, &journal).unwrap();
+    fs::remove_dir_all(steps_dir.join("2")).unwrap();
+
+    // Phase 2: Restart and recover
+    let interceptor = UndoInterceptor::new_default(ws.working_dir.clone(), ws.undo_dir.clone());
+    let info = interceptor.recover().unwrap().expect("squash completed");
+    assert_eq!(info.steps_squashed, 1);
+    assert_eq!(interceptor.completed_steps(), vec![1, 3]);
+    assert!(!steps_dir.join("1.squashing").exists());
+    assert!(!ws.undo_dir.join(SQUASH_JOURNAL_FILE).exists());
+    assert!(interceptor.attest().unwrap().verified);
+
+    interceptor.rollback(2, false).unwrap();
+    assert_tree_eq(&before, &ws.snapshot(), &compare_opts());
+}
@@ -0,0 +1,11 @@
+//! Bearer token checks shared by the MCP transports that take a token.
+
+/// Compare in time independent of where the inputs first differ.
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
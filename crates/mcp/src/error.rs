@@ -1,3 +1,4 @@
+use codeagent_common::ErrorCode;
 use serde::{Deserialize, Serialize};
 
 // JSON-RPC 2.0 standard error codes.
@@ -9,12 +10,15 @@ pub const INTERNAL_ERROR: i32 = -32603;
 
 // Application-specific error codes (within -32000..-32099 server error range).
 pub const PATH_OUTSIDE_ROOT: i32 = -32001;
-#[allow(dead_code)]
 pub const ROLLBACK_BLOCKED: i32 = -32002;
-#[allow(dead_code)]
 pub const SAFEGUARD_DENIED: i32 = -32003;
+/// Any other failure of a tool; `data.code` says which.
+pub const OPERATION_FAILED: i32 = -32004;
 
 /// Structured JSON-RPC 2.0 error object sent in error responses.
+///
+/// `data` always carries the [`ErrorCode`] as `code` and whether the call
+/// may succeed if repeated as `retryable`, next to any other details.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct JsonRpcError {
     pub code: i32,
@@ -50,6 +54,10 @@ pub enum McpError {
     #[error("internal error: {message}")]
     InternalError { message: String },
 
+    /// A tool failed; `code` says why.
+    #[error("{message}")]
+    Failed { code: ErrorCode, message: String },
+
     #[error("message exceeds maximum size of {max_size} bytes (got {actual_size})")]
     OversizedMessage { max_size: usize, actual_size: usize },
 
@@ -61,59 +69,74 @@ pub enum McpError {
 }
 
 impl McpError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            McpError::ParseError { .. } => ErrorCode::MalformedJson,
+            McpError::InvalidRequest { .. } => ErrorCode::InvalidRequest,
+            McpError::MethodNotFound { .. } => ErrorCode::UnknownOperation,
+            McpError::InvalidParams { .. } => ErrorCode::InvalidField,
+            McpError::MissingField { .. } => ErrorCode::MissingField,
+            McpError::PathOutsideRoot { .. } => ErrorCode::PathOutsideRoot,
+            McpError::InternalError { .. } => ErrorCode::Internal,
+            McpError::Failed { code, .. } => *code,
+            McpError::OversizedMessage { .. } => ErrorCode::OversizedMessage,
+            McpError::Io { .. } => ErrorCode::IoError,
+        }
+    }
+
     /// Convert to a JSON-RPC 2.0 error object for wire transmission.
     pub fn to_jsonrpc_error(&self) -> JsonRpcError {
-        match self {
-            McpError::ParseError { source } => JsonRpcError {
-                code: PARSE_ERROR,
-                message: format!("Parse error: {source}"),
-                data: None,
-            },
-            McpError::InvalidRequest { message } => JsonRpcError {
-                code: INVALID_REQUEST,
-                message: message.clone(),
-                data: None,
-            },
-            McpError::MethodNotFound { method } => JsonRpcError {
-                code: METHOD_NOT_FOUND,
-                message: format!("Method not found: {method}"),
-                data: None,
-            },
-            McpError::InvalidParams { message } => JsonRpcError {
-                code: INVALID_PARAMS,
-                message: message.clone(),
-                data: None,
-            },
-            McpError::MissingField { field } => JsonRpcError {
-                code: INVALID_PARAMS,
-                message: format!("Missing required parameter: {field}"),
-                data: Some(serde_json::json!({ "field": field })),
-            },
-            McpError::PathOutsideRoot { path } => JsonRpcError {
-                code: PATH_OUTSIDE_ROOT,
-                message: format!("Path outside root: {path}"),
-                data: None,
-            },
-            McpError::InternalError { message } => JsonRpcError {
-                code: INTERNAL_ERROR,
-                message: message.clone(),
-                data: None,
-            },
+        let (code, message, mut data) = match self {
+            McpError::ParseError { source } => {
+                (PARSE_ERROR, format!("Parse error: {source}"), serde_json::json!({}))
+            }
+            McpError::InvalidRequest { message } => {
+                (INVALID_REQUEST, message.clone(), serde_json::json!({}))
+            }
+            McpError::MethodNotFound { method } => {
+                (METHOD_NOT_FOUND, format!("Method not found: {method}"), serde_json::json!({}))
+            }
+            McpError::InvalidParams { message } => {
+                (INVALID_PARAMS, message.clone(), serde_json::json!({}))
+            }
+            McpError::MissingField { field } => (
+                INVALID_PARAMS,
+                format!("Missing required parameter: {field}"),
+                serde_json::json!({ "field": field }),
+            ),
+            McpError::PathOutsideRoot { path } => {
+                (PATH_OUTSIDE_ROOT, format!("Path outside root: {path}"), serde_json::json!({}))
+            }
+            McpError::InternalError { message } => {
+                (INTERNAL_ERROR, message.clone(), serde_json::json!({}))
+            }
+            McpError::Failed { code, message } => {
+                let rpc_code = match code {
+                    ErrorCode::RollbackBlocked => ROLLBACK_BLOCKED,
+                    ErrorCode::SafeguardDenied => SAFEGUARD_DENIED,
+                    _ => OPERATION_FAILED,
+                };
+                (rpc_code, message.clone(), serde_json::json!({}))
+            }
             McpError::OversizedMessage {
                 max_size,
                 actual_size,
-            } => JsonRpcError {
-                code: INVALID_REQUEST,
-                message: format!(
-                    "Message exceeds maximum size of {max_size} bytes (got {actual_size})"
-                ),
-                data: None,
-            },
-            McpError::Io { source } => JsonRpcError {
-                code: INTERNAL_ERROR,
-                message: format!("I/O error: {source}"),
-                data: None,
-            },
+            } => (
+                INVALID_REQUEST,
+                format!("Message exceeds maximum size of {max_size} bytes (got {actual_size})"),
+                serde_json::json!({}),
+            ),
+            McpError::Io { source } => {
+                (INTERNAL_ERROR, format!("I/O error: {source}"), serde_json::json!({}))
+            }
+        };
+        let error_code = self.code();
+        data["code"] = serde_json::json!(error_code);
+        data["retryable"] = serde_json::json!(error_code.is_retryable());
+        JsonRpcError {
+            code,
+            message,
+            data: Some(data),
         }
     }
 }
@@ -150,6 +173,27 @@ mod tests {
         assert_eq!(rpc_err.data.unwrap()["field"], "command");
     }
 
+    #[test]
+    fn failed_tools_report_their_error_code() {
+        let err = McpError::Failed {
+            code: ErrorCode::SessionNotActive,
+            message: "no active session".to_string(),
+        };
+        let rpc_err = err.to_jsonrpc_error();
+        assert_eq!(rpc_err.code, OPERATION_FAILED);
+        let data = rpc_err.data.unwrap();
+        assert_eq!(data["code"], "session_not_active");
+        assert_eq!(data["retryable"], false);
+
+        let err = McpError::Failed {
+            code: ErrorCode::RollbackBlocked,
+            message: "rollback blocked by 1 undo barrier(s)".to_string(),
+        };
+        assert_eq!(err.to_jsonrpc_error().code, ROLLBACK_BLOCKED);
+        let data = McpError::MethodNotFound { method: "x".into() }.to_jsonrpc_error().data;
+        assert_eq!(data.unwrap()["code"], "unknown_operation");
+    }
+
     #[test]
     fn path_outside_root_has_correct_code() {
         let err = McpError::PathOutsideRoot {
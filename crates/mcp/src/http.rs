@@ -0,0 +1,234 @@
+//! Streamable HTTP transport, behind the `http` feature.
+//!
+//! Serves the MCP streamable HTTP transport on a single `/mcp` endpoint,
+//! without sessions, so web-based agent UIs can talk to the same
+//! [`McpRouter`] as stdio clients:
+//!
+//! - `POST /mcp` with one JSON-RPC message. A request gets its response as
+//!   `application/json`; a notification, or a response to the server, gets
+//!   `202 Accepted`. Batches are not accepted.
+//! - `GET /mcp` opens a `text/event-stream` carrying the server's
+//!   notifications, one `message` event each, until the client disconnects
+//!   or the server shuts down.
+//!
+//! Requests whose `Origin` is not a loopback host are refused, against DNS
+//! rebinding. With a token, every request must carry
+//! `Authorization: Bearer <token>`.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use futures_util::stream::{self, Stream};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, watch};
+
+use crate::auth::tokens_match;
+use crate::error::McpError;
+use crate::parser::{extract_id, parse_jsonrpc, MAX_MESSAGE_SIZE};
+use crate::protocol::{JsonRpcNotification, JsonRpcResponse};
+use crate::router::McpRouter;
+
+/// Path of the MCP endpoint.
+pub const ENDPOINT: &str = "/mcp";
+
+#[derive(Clone)]
+struct HttpState {
+    router: Arc<McpRouter>,
+    token: Option<Arc<str>>,
+    notifications: broadcast::Sender<JsonRpcNotification>,
+    shutdown: watch::Receiver<bool>,
+}
+
+/// Serve the endpoint on `listener` until `shutdown` receives a value.
+/// `notifications` reaches every open event stream.
+pub async fn serve_http(
+    listener: TcpListener,
+    router: Arc<McpRouter>,
+    token: Option<String>,
+    notifications: broadcast::Sender<JsonRpcNotification>,
+    shutdown: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let state = HttpState {
+        router,
+        token: token.map(Arc::from),
+        notifications,
+        shutdown: shutdown.clone(),
+    };
+    let app = axum::Router::new()
+        .route(ENDPOINT, get(open_stream).post(post_message))
+        .layer(DefaultBodyLimit::max(MAX_MESSAGE_SIZE))
+        .with_state(state);
+    let mut shutdown = shutdown;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|stopped| *stopped).await;
+        })
+        .await
+}
+
+/// The refusal for a request whose origin is not local or whose token does
+/// not match, if any.
+fn refusal(state: &HttpState, headers: &HeaderMap) -> Option<Response> {
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        let local = origin.to_str().is_ok_and(is_loopback_origin);
+        if !local {
+            return Some((StatusCode::FORBIDDEN, "origin not allowed").into_response());
+        }
+    }
+    if let Some(token) = &state.token {
+        let given = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !given.is_some_and(|given| tokens_match(given, token)) {
+            return Some((
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "missing or wrong bearer token",
+            )
+                .into_response());
+        }
+    }
+    None
+}
+
+fn is_loopback_origin(origin: &str) -> bool {
+    let Some((_, rest)) = origin.split_once("://") else {
+        return false;
+    };
+    let host = match rest.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => rest.split(':').next().unwrap_or_default(),
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "::1")
+}
+
+fn json_response(status: StatusCode, response: &JsonRpcResponse) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/json")],
+        serde_json::to_string(response).unwrap(),
+    )
+        .into_response()
+}
+
+async fn post_message(
+    State(state): State<HttpState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Some(refused) = refusal(&state, &headers) {
+        return refused;
+    }
+    let Ok(body) = String::from_utf8(body.to_vec()) else {
+        let error = McpError::InvalidRequest {
+            message: "body is not UTF-8".to_string(),
+        };
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            &JsonRpcResponse::error(None, error.to_jsonrpc_error()),
+        );
+    };
+
+    let request = match parse_jsonrpc(&body) {
+        Ok(request) => request,
+        Err(error) => {
+            // A client's response to the server has no method to dispatch.
+            let value: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+            if value.get("id").is_some()
+                && (value.get("result").is_some() || value.get("error").is_some())
+            {
+                return StatusCode::ACCEPTED.into_response();
+            }
+            let error = match value {
+                serde_json::Value::Array(_) => McpError::InvalidRequest {
+                    message: "batches are not supported".to_string(),
+                }
+                .to_jsonrpc_error(),
+                _ => error.to_jsonrpc_error(),
+            };
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                &JsonRpcResponse::error(extract_id(&body), error),
+            );
+        }
+    };
+
+    // Tool calls block for as long as their command runs.
+    let router = Arc::clone(&state.router);
+    let dispatched = tokio::task::spawn_blocking(move || router.dispatch(request)).await;
+    match dispatched {
+        Ok(Some(response)) => json_response(StatusCode::OK, &response),
+        Ok(None) => StatusCode::ACCEPTED.into_response(),
+        Err(e) => {
+            let error = McpError::InternalError {
+                message: format!("request handler failed: {e}"),
+            };
+            json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &JsonRpcResponse::error(extract_id(&body), error.to_jsonrpc_error()),
+            )
+        }
+    }
+}
+
+async fn open_stream(State(state): State<HttpState>, headers: HeaderMap) -> Response {
+    if let Some(refused) = refusal(&state, &headers) {
+        return refused;
+    }
+    let accepts_events = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    if !accepts_events {
+        return (StatusCode::NOT_ACCEPTABLE, "GET needs Accept: text/event-stream")
+            .into_response();
+    }
+    let events = notification_events(state.notifications.subscribe(), state.shutdown.clone());
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// Notifications from `receiver` as SSE events, ending at shutdown.
+fn notification_events(
+    receiver: broadcast::Receiver<JsonRpcNotification>,
+    shutdown: watch::Receiver<bool>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold((receiver, shutdown), |(mut receiver, mut shutdown)| async move {
+        loop {
+            let received = tokio::select! {
+                _ = shutdown.wait_for(|stopped| *stopped) => return None,
+                received = receiver.recv() => received,
+            };
+            match received {
+                Ok(notification) => {
+                    let data = serde_json::to_string(&notification).unwrap();
+                    let event = Event::default().event("message").data(data);
+                    return Some((Ok(event), (receiver, shutdown)));
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_loopback_origins_are_local() {
+        assert!(is_loopback_origin("http://localhost:3000"));
+        assert!(is_loopback_origin("http://127.0.0.1"));
+        assert!(is_loopback_origin("https://[::1]:8443"));
+        assert!(!is_loopback_origin("https://evil.example"));
+        assert!(!is_loopback_origin("http://localhost.evil.example"));
+        assert!(!is_loopback_origin("null"));
+    }
+}
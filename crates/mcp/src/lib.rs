@@ -1,11 +1,15 @@
+mod auth;
 mod error;
 mod parser;
 mod path_validation;
 
+#[cfg(feature = "http")]
+pub mod http;
 pub mod protocol;
 pub mod router;
 pub mod server;
 
+pub use auth::tokens_match;
 pub use error::{JsonRpcError, McpError};
 pub use parser::{parse_jsonrpc, MAX_MESSAGE_SIZE};
 pub use path_validation::validate_path;
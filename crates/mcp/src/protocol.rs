@@ -1,3 +1,4 @@
+use codeagent_common::ReadEncoding;
 use serde::{Deserialize, Serialize};
 
 use crate::error::JsonRpcError;
@@ -134,6 +135,12 @@ pub struct BashArgs {
 #[derive(Debug, Clone, Deserialize)]
 pub struct ReadFileArgs {
     pub path: String,
+    #[serde(default)]
+    pub encoding: ReadEncoding,
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default)]
+    pub length: Option<u64>,
 }
 
 /// Arguments for the `write_file` tool.
@@ -150,6 +157,8 @@ pub struct UndoArgs {
     pub count: u32,
     #[serde(default)]
     pub force: bool,
+    #[serde(default)]
+    pub strict: bool,
 }
 
 fn default_undo_count() -> u32 {
@@ -164,6 +173,18 @@ pub struct GetUndoHistoryArgs {}
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct DiscardUndoHistoryArgs {}
 
+/// Arguments for the `list_pending_safeguards` tool (no required fields).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListPendingSafeguardsArgs {}
+
+/// Arguments for the `confirm_safeguard` tool.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfirmSafeguardArgs {
+    pub safeguard_id: String,
+    /// `allow_once`, `allow_step`, `allow_session` or `deny`.
+    pub action: String,
+}
+
 /// Arguments for the `edit_file` tool.
 #[derive(Debug, Clone, Deserialize)]
 pub struct EditFileArgs {
@@ -174,6 +195,16 @@ pub struct EditFileArgs {
     pub replace_all: bool,
 }
 
+/// Arguments for the `apply_patch` tool.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApplyPatchArgs {
+    /// A unified diff.
+    pub patch: String,
+    /// The file bare hunks (no `---`/`+++` headers) apply to.
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
 /// Arguments for the `glob` tool.
 #[derive(Debug, Clone, Deserialize)]
 pub struct GlobArgs {
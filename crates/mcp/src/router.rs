@@ -6,8 +6,9 @@ use crate::error::McpError;
 use crate::parser::extract_missing_field;
 use crate::path_validation::validate_path_multi;
 use crate::protocol::{
-    BashArgs, DiscardUndoHistoryArgs, EditFileArgs, GetUndoHistoryArgs, GlobArgs, GrepArgs,
-    JsonRpcRequest, JsonRpcResponse, ReadFileArgs, ToolCallParams,
+    ApplyPatchArgs, BashArgs, ConfirmSafeguardArgs, DiscardUndoHistoryArgs, EditFileArgs,
+    GetUndoHistoryArgs, GlobArgs, GrepArgs, JsonRpcRequest, JsonRpcResponse,
+    ListPendingSafeguardsArgs, ReadFileArgs, ToolCallParams,
     ToolCallResult, ToolDefinition, UndoArgs, WriteFileArgs,
 };
 
@@ -21,6 +22,7 @@ pub trait McpHandler: Send + Sync {
     fn read_file(&self, args: ReadFileArgs) -> Result<serde_json::Value, McpError>;
     fn write_file(&self, args: WriteFileArgs) -> Result<serde_json::Value, McpError>;
     fn edit_file(&self, args: EditFileArgs) -> Result<serde_json::Value, McpError>;
+    fn apply_patch(&self, args: ApplyPatchArgs) -> Result<serde_json::Value, McpError>;
     fn glob(&self, args: GlobArgs) -> Result<serde_json::Value, McpError>;
     fn grep(&self, args: GrepArgs) -> Result<serde_json::Value, McpError>;
     fn undo(&self, args: UndoArgs) -> Result<serde_json::Value, McpError>;
@@ -30,6 +32,11 @@ pub trait McpHandler: Send + Sync {
         &self,
         args: DiscardUndoHistoryArgs,
     ) -> Result<serde_json::Value, McpError>;
+    fn list_pending_safeguards(
+        &self,
+        args: ListPendingSafeguardsArgs,
+    ) -> Result<serde_json::Value, McpError>;
+    fn confirm_safeguard(&self, args: ConfirmSafeguardArgs) -> Result<serde_json::Value, McpError>;
 }
 
 /// Returns the server capabilities advertised in the `initialize` response.
@@ -37,7 +44,8 @@ fn server_info(instructions: &str) -> serde_json::Value {
     serde_json::json!({
         "protocolVersion": "2024-11-05",
         "capabilities": {
-            "tools": {}
+            "tools": {},
+            "logging": {}
         },
         "serverInfo": {
             "name": "codeagent-mcp",
@@ -47,7 +55,7 @@ fn server_info(instructions: &str) -> serde_json::Value {
     })
 }
 
-/// Returns the definitions for all 10 MCP tools.
+/// Returns the definitions for all 14 MCP tools.
 pub fn tool_definitions() -> Vec<ToolDefinition> {
     vec![
         ToolDefinition {
@@ -65,11 +73,19 @@ pub fn tool_definitions() -> Vec<ToolDefinition> {
         },
         ToolDefinition {
             name: "read_file".to_string(),
-            description: "Read a file's contents from the working folder".to_string(),
+            description: "Read a file's contents from the working folder, up to 1 MiB at a time"
+                .to_string(),
             input_schema: serde_json::json!({
                 "type": "object",
                 "properties": {
-                    "path": { "type": "string", "description": "Relative path to the file" }
+                    "path": { "type": "string", "description": "Relative path to the file" },
+                    "encoding": {
+                        "type": "string",
+                        "enum": ["utf8", "base64"],
+                        "description": "utf8 (default) for text, base64 for binary files"
+                    },
+                    "offset": { "type": "number", "description": "Byte offset to start reading at" },
+                    "length": { "type": "number", "description": "Most bytes to return (max 1 MiB)" }
                 },
                 "required": ["path"]
             }),
@@ -100,6 +116,18 @@ pub fn tool_definitions() -> Vec<ToolDefinition> {
                 "required": ["path", "old_string", "new_string"]
             }),
         },
+        ToolDefinition {
+            name: "apply_patch".to_string(),
+            description: "Apply a unified diff (as printed by diff -u or git diff) to one or more files. Nothing is written unless every hunk matches; rejected hunks are reported.".to_string(),
+            input_schema: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "patch": { "type": "string", "description": "Unified diff; /dev/null on one side creates or deletes a file" },
+                    "path": { "type": "string", "description": "Relative path of the file, for a patch of bare @@ hunks without ---/+++ headers" }
+                },
+                "required": ["patch"]
+            }),
+        },
         ToolDefinition {
             name: "glob".to_string(),
             description: "Find files matching a glob pattern. Results sorted by modification time (newest first).".to_string(),
@@ -136,7 +164,8 @@ pub fn tool_definitions() -> Vec<ToolDefinition> {
                 "type": "object",
                 "properties": {
                     "count": { "type": "integer", "description": "Number of steps to undo", "default": 1 },
-                    "force": { "type": "boolean", "description": "Force rollback across barriers", "default": false }
+                    "force": { "type": "boolean", "description": "Force rollback across barriers", "default": false },
+                    "strict": { "type": "boolean", "description": "Fail instead of undoing fewer steps when history is shorter than count", "default": false }
                 }
             }),
         },
@@ -164,6 +193,30 @@ pub fn tool_definitions() -> Vec<ToolDefinition> {
                 "properties": {}
             }),
         },
+        ToolDefinition {
+            name: "list_pending_safeguards".to_string(),
+            description: "List safeguards (mass deletes, large overwrites, protected paths) whose operation is paused waiting for confirm_safeguard".to_string(),
+            input_schema: serde_json::json!({
+                "type": "object",
+                "properties": {}
+            }),
+        },
+        ToolDefinition {
+            name: "confirm_safeguard".to_string(),
+            description: "Allow or deny the paused operation of a pending safeguard. Ask the user before allowing.".to_string(),
+            input_schema: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "safeguard_id": { "type": "string", "description": "ID from list_pending_safeguards or the safeguard notification" },
+                    "action": {
+                        "type": "string",
+                        "enum": ["allow_once", "allow_step", "allow_session", "deny"],
+                        "description": "allow_once lets this operation through, allow_step the rest of its step, allow_session every later one of its kind"
+                    }
+                },
+                "required": ["safeguard_id", "action"]
+            }),
+        },
         ToolDefinition {
             name: "get_working_directory".to_string(),
             description: "Get the sandbox working directory. This is the root directory for all file operations — NOT the project directory open in your editor.".to_string(),
@@ -355,6 +408,14 @@ impl McpRouter {
                 let value = self.handler.edit_file(args)?;
                 Ok(ToolCallResult::text(serde_json::to_string(&value).unwrap()))
             }
+            "apply_patch" => {
+                let args = parse_tool_args::<ApplyPatchArgs>(tool_params.arguments)?;
+                if let Some(ref path) = args.path {
+                    validate_path_multi(path, &self.working_dirs)?;
+                }
+                let value = self.handler.apply_patch(args)?;
+                Ok(ToolCallResult::text(serde_json::to_string(&value).unwrap()))
+            }
             "glob" => {
                 let args = parse_tool_args::<GlobArgs>(tool_params.arguments)?;
                 if let Some(ref path) = args.path {
@@ -391,6 +452,17 @@ impl McpRouter {
                 let value = self.handler.discard_undo_history(args)?;
                 Ok(ToolCallResult::text(serde_json::to_string(&value).unwrap()))
             }
+            "list_pending_safeguards" => {
+                let args =
+                    parse_tool_args::<ListPendingSafeguardsArgs>(tool_params.arguments)?;
+                let value = self.handler.list_pending_safeguards(args)?;
+                Ok(ToolCallResult::text(serde_json::to_string(&value).unwrap()))
+            }
+            "confirm_safeguard" => {
+                let args = parse_tool_args::<ConfirmSafeguardArgs>(tool_params.arguments)?;
+                let value = self.handler.confirm_safeguard(args)?;
+                Ok(ToolCallResult::text(serde_json::to_string(&value).unwrap()))
+            }
             "get_working_directory" => {
                 let dirs: Vec<serde_json::Value> = self
                     .working_dirs
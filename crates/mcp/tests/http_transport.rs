@@ -0,0 +1,221 @@
+#![cfg(feature = "http")]
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+
+use codeagent_mcp::http::serve_http;
+use codeagent_mcp::protocol::{
+    ApplyPatchArgs, BashArgs, ConfirmSafeguardArgs, DiscardUndoHistoryArgs, EditFileArgs,
+    GetUndoHistoryArgs, GlobArgs, GrepArgs, JsonRpcNotification, ListPendingSafeguardsArgs,
+    ReadFileArgs, UndoArgs, WriteFileArgs,
+};
+use codeagent_mcp::{McpError, McpHandler, McpRouter};
+
+struct StubMcpHandler;
+
+impl McpHandler for StubMcpHandler {
+    fn bash(&self, args: BashArgs) -> Result<Value, McpError> {
+        Ok(json!({ "stdout": format!("executed: {}", args.command) }))
+    }
+
+    fn read_file(&self, _args: ReadFileArgs) -> Result<Value, McpError> {
+        Ok(json!({ "content": "" }))
+    }
+
+    fn write_file(&self, _args: WriteFileArgs) -> Result<Value, McpError> {
+        Ok(json!({ "written": true }))
+    }
+
+    fn edit_file(&self, _args: EditFileArgs) -> Result<Value, McpError> {
+        Ok(json!("ok"))
+    }
+
+    fn apply_patch(&self, _args: ApplyPatchArgs) -> Result<Value, McpError> {
+        Ok(json!({ "applied": true }))
+    }
+
+    fn glob(&self, _args: GlobArgs) -> Result<Value, McpError> {
+        Ok(json!(""))
+    }
+
+    fn grep(&self, _args: GrepArgs) -> Result<Value, McpError> {
+        Ok(json!(""))
+    }
+
+    fn undo(&self, _args: UndoArgs) -> Result<Value, McpError> {
+        Ok(json!({ "steps_rolled_back": 0 }))
+    }
+
+    fn get_undo_history(&self, _args: GetUndoHistoryArgs) -> Result<Value, McpError> {
+        Ok(json!({ "steps": [] }))
+    }
+
+    fn get_session_status(&self) -> Result<Value, McpError> {
+        Ok(json!({ "state": "idle" }))
+    }
+
+    fn discard_undo_history(&self, _args: DiscardUndoHistoryArgs) -> Result<Value, McpError> {
+        Ok(json!({}))
+    }
+
+    fn list_pending_safeguards(&self, _args: ListPendingSafeguardsArgs) -> Result<Value, McpError> {
+        Ok(json!({ "pending": [] }))
+    }
+
+    fn confirm_safeguard(&self, _args: ConfirmSafeguardArgs) -> Result<Value, McpError> {
+        Ok(json!({}))
+    }
+}
+
+struct HttpHarness {
+    address: SocketAddr,
+    notifications: broadcast::Sender<JsonRpcNotification>,
+    shutdown: watch::Sender<bool>,
+    server: tokio::task::JoinHandle<std::io::Result<()>>,
+}
+
+impl HttpHarness {
+    async fn start(token: Option<&str>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = McpRouter::new(PathBuf::from("/tmp/work"), Arc::new(StubMcpHandler));
+        let (notifications, _) = broadcast::channel(16);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve_http(
+            listener,
+            Arc::new(router),
+            token.map(String::from),
+            notifications.clone(),
+            shutdown_rx,
+        ));
+        Self {
+            address,
+            notifications,
+            shutdown,
+            server,
+        }
+    }
+
+    /// POST `body` with the extra `headers` lines. Returns the status code
+    /// and the response body.
+    async fn post(&self, body: &str, headers: &[&str]) -> (u16, String) {
+        let mut stream = TcpStream::connect(self.address).await.unwrap();
+        let mut request = format!(
+            "POST /mcp HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/json\r\n\
+             Accept: application/json, text/event-stream\r\nContent-Length: {}\r\n\
+             Connection: close\r\n",
+            body.len()
+        );
+        for header in headers {
+            request.push_str(&format!("{header}\r\n"));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
+}
+
+const INITIALIZE: &str = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"protocolVersion":"2025-03-26","capabilities":{}}}"#;
+
+// ===========================================================================
+// MC-11: Streamable HTTP transport
+// ===========================================================================
+
+#[tokio::test]
+async fn mc11_post_dispatches_requests_and_accepts_notifications() {
+    let harness = HttpHarness::start(None).await;
+
+    let (status, body) = harness.post(INITIALIZE, &[]).await;
+    assert_eq!(status, 200);
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["id"], 1);
+    assert_eq!(response["result"]["serverInfo"]["name"], "codeagent-mcp");
+
+    let initialized = r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#;
+    assert_eq!(harness.post(initialized, &[]).await.0, 202);
+
+    let call = json!({
+        "jsonrpc": "2.0",
+        "id": 2,
+        "method": "tools/call",
+        "params": { "name": "Bash", "arguments": { "command": "ls" } }
+    });
+    let (status, body) = harness.post(&call.to_string(), &[]).await;
+    assert_eq!(status, 200);
+    let response: Value = serde_json::from_str(&body).unwrap();
+    let text = response["result"]["content"][0]["text"].as_str().unwrap();
+    assert_eq!(serde_json::from_str::<Value>(text).unwrap()["stdout"], "executed: ls");
+
+    let (status, body) = harness.post("[]", &[]).await;
+    assert_eq!(status, 400);
+    assert!(body.contains("batches"), "{body}");
+    assert_eq!(harness.post("not json", &[]).await.0, 400);
+}
+
+#[tokio::test]
+async fn mc11_foreign_origins_and_missing_tokens_are_refused() {
+    let harness = HttpHarness::start(Some("s3cret")).await;
+
+    assert_eq!(harness.post(INITIALIZE, &[]).await.0, 401);
+    assert_eq!(harness.post(INITIALIZE, &["Authorization: Bearer guess"]).await.0, 401);
+    let authorized = "Authorization: Bearer s3cret";
+    assert_eq!(harness.post(INITIALIZE, &[authorized]).await.0, 200);
+    let foreign = "Origin: https://evil.example";
+    assert_eq!(harness.post(INITIALIZE, &[authorized, foreign]).await.0, 403);
+    let local = "Origin: http://localhost:5173";
+    assert_eq!(harness.post(INITIALIZE, &[authorized, local]).await.0, 200);
+}
+
+#[tokio::test]
+async fn mc11_event_stream_carries_notifications_until_shutdown() {
+    let harness = HttpHarness::start(None).await;
+
+    let mut stream = BufReader::new(TcpStream::connect(harness.address).await.unwrap());
+    stream
+        .get_mut()
+        .write_all(b"GET /mcp HTTP/1.1\r\nHost: 127.0.0.1\r\nAccept: text/event-stream\r\n\r\n")
+        .await
+        .unwrap();
+    let mut line = String::new();
+    stream.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("HTTP/1.1 200"), "{line}");
+    while line != "\r\n" {
+        line.clear();
+        stream.read_line(&mut line).await.unwrap();
+    }
+
+    harness
+        .notifications
+        .send(JsonRpcNotification {
+            jsonrpc: "2.0".to_string(),
+            method: "notifications/message".to_string(),
+            params: Some(json!({ "level": "warning", "logger": "safeguard" })),
+        })
+        .unwrap();
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await.unwrap();
+        if line.starts_with("data: ") {
+            break;
+        }
+    }
+    let notification: Value = serde_json::from_str(&line["data: ".len()..]).unwrap();
+    assert_eq!(notification["params"]["logger"], "safeguard");
+
+    harness.shutdown.send(true).unwrap();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), harness.server).await;
+    assert!(stopped.expect("server did not stop").unwrap().is_ok());
+}
@@ -7,8 +7,9 @@ use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc;
 
 use codeagent_mcp::protocol::{
-    BashArgs, DiscardUndoHistoryArgs, EditFileArgs, GetUndoHistoryArgs, GlobArgs, GrepArgs,
-    JsonRpcNotification, ReadFileArgs, UndoArgs, WriteFileArgs,
+    ApplyPatchArgs, BashArgs, ConfirmSafeguardArgs, DiscardUndoHistoryArgs, EditFileArgs,
+    GetUndoHistoryArgs, GlobArgs, GrepArgs, JsonRpcNotification, ListPendingSafeguardsArgs,
+    ReadFileArgs, UndoArgs, WriteFileArgs,
 };
 use codeagent_mcp::{McpError, McpHandler, McpRouter, McpServer};
 
@@ -39,6 +40,10 @@ impl McpHandler for StubMcpHandler {
         Ok(json!(format!("The file {} has been updated successfully.", args.path)))
     }
 
+    fn apply_patch(&self, args: ApplyPatchArgs) -> Result<Value, McpError> {
+        Ok(json!({ "applied": true, "files": [{ "path": args.path, "hunks": 1 }] }))
+    }
+
     fn glob(&self, _args: GlobArgs) -> Result<Value, McpError> {
         Ok(json!(""))
     }
@@ -62,6 +67,14 @@ impl McpHandler for StubMcpHandler {
     fn discard_undo_history(&self, _args: DiscardUndoHistoryArgs) -> Result<Value, McpError> {
         Ok(json!({}))
     }
+
+    fn list_pending_safeguards(&self, _args: ListPendingSafeguardsArgs) -> Result<Value, McpError> {
+        Ok(json!({ "pending": [{ "safeguard_id": "1", "step_id": 2 }] }))
+    }
+
+    fn confirm_safeguard(&self, args: ConfirmSafeguardArgs) -> Result<Value, McpError> {
+        Ok(json!({ "safeguard_id": args.safeguard_id, "action": args.action }))
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -280,7 +293,7 @@ async fn mc01_tools_list_returns_seven_tools() {
 
     let resp = harness.send_request(2, "tools/list", json!({})).await;
     let tools = resp["result"]["tools"].as_array().unwrap();
-    assert_eq!(tools.len(), 11);
+    assert_eq!(tools.len(), 14);
 
     let names: Vec<&str> = tools.iter().map(|t| t["name"].as_str().unwrap()).collect();
     assert!(names.contains(&"Bash"));
@@ -291,6 +304,8 @@ async fn mc01_tools_list_returns_seven_tools() {
     assert!(names.contains(&"discard_undo_history"));
     assert!(names.contains(&"get_working_directory"));
     assert!(names.contains(&"get_session_status"));
+    assert!(names.contains(&"list_pending_safeguards"));
+    assert!(names.contains(&"confirm_safeguard"));
 }
 
 // ===========================================================================
@@ -415,15 +430,15 @@ async fn mc05_read_file_path_outside_root_returns_error() {
 }
 
 // ===========================================================================
-// MC-07: Connection without auth token (placeholder)
+// MC-07: Connection without auth token
 // ===========================================================================
 
 #[tokio::test]
-async fn mc07_auth_not_yet_implemented() {
-    // Authentication on the MCP socket is not yet implemented.
-    // When added, this test should verify that unauthenticated
-    // connections are rejected. For now, verify that a connection
-    // without any token succeeds (current behavior).
+async fn mc07_in_process_server_needs_no_token() {
+    // The token handshake belongs to the socket transport (the sandbox's
+    // `--mcp-listen`), which checks it before handing the connection to
+    // McpServer; its tests cover rejected clients. McpServer itself serves
+    // whoever holds its streams.
     let mut harness = McpTestHarness::new();
     let resp = harness
         .send_request(1, "initialize", json!({"protocolVersion": "2024-11-05", "capabilities": {}}))
@@ -582,6 +597,22 @@ impl McpHandler for UndoMcpHandler {
         self.interceptor.discard().map_err(to_internal)?;
         Ok(json!({}))
     }
+
+    fn apply_patch(&self, _args: ApplyPatchArgs) -> Result<Value, McpError> {
+        Err(McpError::InvalidParams {
+            message: "apply_patch is not supported by this handler".to_string(),
+        })
+    }
+
+    fn list_pending_safeguards(&self, _args: ListPendingSafeguardsArgs) -> Result<Value, McpError> {
+        Ok(json!({ "pending": [] }))
+    }
+
+    fn confirm_safeguard(&self, args: ConfirmSafeguardArgs) -> Result<Value, McpError> {
+        Err(McpError::InvalidParams {
+            message: format!("no pending safeguard with id '{}'", args.safeguard_id),
+        })
+    }
 }
 
 /// Create an UndoInterceptor + handler pair for a TempWorkspace.
@@ -1071,3 +1102,84 @@ async fn get_session_status_returns_state() {
     let result: Value = serde_json::from_str(result_text).unwrap();
     assert_eq!(result["state"], "idle");
 }
+
+// ===========================================================================
+// MC-09: Safeguard tools
+// ===========================================================================
+
+#[tokio::test]
+async fn mc09_safeguard_tools_route_to_handler() {
+    let mut harness = McpTestHarness::new();
+    harness.initialize().await;
+
+    let resp = harness
+        .send_request(
+            70,
+            "tools/call",
+            json!({"name": "list_pending_safeguards", "arguments": {}}),
+        )
+        .await;
+    let result_text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    let result: Value = serde_json::from_str(result_text).unwrap();
+    assert_eq!(result["pending"][0]["safeguard_id"], "1");
+
+    let resp = harness
+        .send_request(
+            71,
+            "tools/call",
+            json!({
+                "name": "confirm_safeguard",
+                "arguments": { "safeguard_id": "1", "action": "allow_once" }
+            }),
+        )
+        .await;
+    let result_text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    let result: Value = serde_json::from_str(result_text).unwrap();
+    assert_eq!(result["action"], "allow_once");
+
+    let resp = harness
+        .send_request(
+            72,
+            "tools/call",
+            json!({"name": "confirm_safeguard", "arguments": { "safeguard_id": "1" }}),
+        )
+        .await;
+    assert_eq!(resp["error"]["code"], -32602);
+    assert_eq!(resp["error"]["data"]["field"], "action");
+}
+
+// ===========================================================================
+// MC-10: apply_patch
+// ===========================================================================
+
+#[tokio::test]
+async fn mc10_apply_patch_routes_to_handler() {
+    let mut harness = McpTestHarness::new();
+    harness.initialize().await;
+
+    let patch = "@@ -1 +1 @@\n-a\n+b\n";
+    let resp = harness
+        .send_request(
+            80,
+            "tools/call",
+            json!({"name": "apply_patch", "arguments": { "patch": patch, "path": "a.txt" }}),
+        )
+        .await;
+    let result_text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    let result: Value = serde_json::from_str(result_text).unwrap();
+    assert_eq!(result["files"][0]["path"], "a.txt");
+
+    let resp = harness
+        .send_request(
+            81,
+            "tools/call",
+            json!({"name": "apply_patch", "arguments": { "patch": patch, "path": "../a.txt" }}),
+        )
+        .await;
+    assert!(resp.get("error").is_some());
+
+    let resp = harness
+        .send_request(82, "tools/call", json!({"name": "apply_patch", "arguments": {}}))
+        .await;
+    assert_eq!(resp["error"]["data"]["field"], "patch");
+}
@@ -351,9 +351,16 @@ impl P9Server {
                 let atime_only_mask = P9_SETATTR_ATIME | P9_SETATTR_ATIME_SET;
                 let modifies_beyond_atime = request.valid & !atime_only_mask != 0;
 
+                // Without a size change the contents stay as they are, so only
+                // the attributes need capturing.
                 if modifies_beyond_atime {
                     if let Some(ref interceptor) = self.interceptor {
-                        if interceptor.pre_setattr(&path).is_err() {
+                        let result = if request.valid & P9_SETATTR_SIZE != 0 {
+                            interceptor.pre_setattr(&path)
+                        } else {
+                            interceptor.pre_setattr_metadata(&path)
+                        };
+                        if result.is_err() {
                             return encode_error(tag, crate::error::errno::EACCES);
                         }
                     }
@@ -408,11 +415,19 @@ impl P9Server {
             Ok(request) => {
                 // Interceptor pre-hook.
                 if let Some(ref interceptor) = self.interceptor {
-                    let path = match self.fid_table.get(request.fid) {
-                        Ok(state) => state.path.clone(),
+                    let (path, open_flags) = match self.fid_table.get(request.fid) {
+                        Ok(state) => (state.path.clone(), state.open_flags),
                         Err(e) => return encode_error(tag, p9_error_to_errno(&e)),
                     };
-                    if interceptor.pre_write(&path).is_err() {
+                    // A FID opened with O_APPEND writes at the end of the
+                    // file, not at the offset given.
+                    let result = if open_flags & 0o2000 != 0 {
+                        interceptor.pre_append(&path)
+                    } else {
+                        let len = request.data.len() as u64;
+                        interceptor.pre_write_range(&path, request.offset, len)
+                    };
+                    if result.is_err() {
                         return encode_error(tag, crate::error::errno::EACCES);
                     }
                 }
@@ -528,7 +543,12 @@ impl P9Server {
                 }
 
                 match dir::handle_renameat(&request, &self.fid_table) {
-                    Ok(()) => encode_empty_response(RRENAMEAT, tag),
+                    Ok(()) => {
+                        if let Some(ref interceptor) = self.interceptor {
+                            let _ = interceptor.post_rename(&old_path, &new_path);
+                        }
+                        encode_empty_response(RRENAMEAT, tag)
+                    }
                     Err(e) => encode_error(tag, p9_error_to_errno(&e)),
                 }
             }
@@ -4,10 +4,11 @@
 //! `UndoInterceptor` correctly deletes the file, tracks in-flight operations,
 //! and captures the preimage for undo.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use codeagent_control::InFlightTracker;
+use codeagent_interceptor::preimage::{path_hash, read_preimage_metadata};
 use codeagent_interceptor::undo_interceptor::UndoInterceptor;
 use codeagent_interceptor::write_interceptor::WriteInterceptor;
 use codeagent_p9::messages::*;
@@ -28,7 +29,7 @@ struct InterceptorHarness {
     response_reader: tokio::io::DuplexStream,
     server_handle: tokio::task::JoinHandle<Result<(), codeagent_p9::error::P9Error>>,
     working_dir: TempDir,
-    _undo_dir: TempDir,
+    undo_dir: TempDir,
     interceptor: Arc<UndoInterceptor>,
     tracker: InFlightTracker,
 }
@@ -64,7 +65,7 @@ impl InterceptorHarness {
             response_reader: client_resp_read,
             server_handle,
             working_dir,
-            _undo_dir: undo_dir,
+            undo_dir,
             interceptor,
             tracker,
         }
@@ -231,3 +232,48 @@ async fn cp_09_p9_tunlinkat_nonexistent() {
     let _ = harness.interceptor.close_step(1);
     harness.shutdown().await.unwrap();
 }
+
+/// Verify that a TWRITE through a FID opened with O_APPEND lands at the end
+/// of the file whatever its offset, and that the interceptor records only
+/// the original length for rollback to truncate back to.
+#[tokio::test]
+async fn cp_10_p9_append_records_only_the_length() {
+    let mut harness = InterceptorHarness::new();
+    harness.create_file("app.log", "line 1\n");
+    harness.interceptor.open_step(1).expect("open_step");
+
+    harness.handshake().await;
+    harness.attach(0).await.unwrap();
+
+    let walk = Twalk {
+        fid: 0,
+        newfid: 1,
+        wnames: vec!["app.log".to_string()],
+    };
+    harness.send(&walk.to_wire(20)).await;
+    assert_eq!(harness.recv_raw().await.0, RWALK);
+    // O_WRONLY | O_APPEND
+    let lopen = Tlopen { fid: 1, flags: 0o2001 };
+    harness.send(&lopen.to_wire(21)).await;
+    assert_eq!(harness.recv_raw().await.0, RLOPEN);
+    for (tag, line) in [(22, "line 2\n"), (23, "line 3\n")] {
+        let write = Twrite {
+            fid: 1,
+            offset: 0,
+            data: line.as_bytes().to_vec(),
+        };
+        harness.send(&write.to_wire(tag)).await;
+        assert_eq!(harness.recv_raw().await.0, RWRITE);
+    }
+
+    let path = harness.root_path().join("app.log");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 1\nline 2\nline 3\n");
+    harness.interceptor.close_step(1).expect("close_step");
+    let preimages = harness.undo_dir.path().join("steps").join("1").join("preimages");
+    let meta = read_preimage_metadata(&preimages, &path_hash(Path::new("app.log"))).unwrap();
+    assert_eq!((meta.size, meta.range_patches), (7, Some(vec![])));
+
+    harness.interceptor.rollback(1, false).expect("rollback");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 1\n");
+    harness.shutdown().await.unwrap();
+}
@@ -0,0 +1,454 @@
+//! Backends that drive `agent.prompt`.
+//!
+//! `agent.prompt` runs a plan/execute loop: the backend is shown the
+//! transcript so far and answers with the next action, either a shell
+//! command or the final message. Commands run through `agent.execute`, so
+//! each one is its own undo step, and their results join the transcript.
+//!
+//! Two backends are available:
+//!
+//! - [`OpenAiBackend`], for any OpenAI-compatible `/chat/completions`
+//!   endpoint (`--agent-endpoint`). Commands are asked for as calls to
+//!   the `run_command` tool.
+//! - [`ProcessBackend`], for an external agent process (`--agent-command`).
+//!   It is sent `{"transcript":[...]}` as one JSON line and answers with
+//!   one [`AgentAction`] line, e.g. `{"action":"run","command":"ls"}` or
+//!   `{"action":"finish","message":"done"}`.
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::cli::CliArgs;
+use crate::error::AgentError;
+
+/// Command output beyond this many bytes per stream is cut from the start
+/// before it is shown to the backend.
+pub const MAX_OUTPUT_SHOWN: usize = 16 * 1024;
+
+/// How long a chat completion request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+const SYSTEM_PROMPT: &str = "You are a coding agent working in a sandboxed Linux VM. The \
+user's project is the current directory. Run shell commands with the run_command tool, one \
+at a time; every command can be undone by the user. When the task is complete, reply with a \
+short summary and no tool call.";
+
+/// One entry of an `agent.prompt` transcript, oldest first.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "role", rename_all = "snake_case")]
+pub enum AgentTurn {
+    /// The prompt the run started from.
+    Prompt { text: String },
+    /// A command the backend asked for, and how it ended.
+    Command {
+        id: String,
+        /// What the backend said alongside the command, if anything.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+        command: String,
+        /// `None` if the command was still running when `agent.execute`
+        /// stopped waiting.
+        exit_code: Option<i32>,
+        stdout: String,
+        stderr: String,
+    },
+}
+
+/// What a backend wants done next.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AgentAction {
+    /// Run `command` in the session's primary working directory.
+    Run {
+        /// Identifies the command in the transcript; assigned if absent.
+        #[serde(default)]
+        id: Option<String>,
+        command: String,
+        #[serde(default)]
+        note: Option<String>,
+    },
+    /// The task is done; `message` is the answer to the prompt.
+    Finish { message: String },
+}
+
+/// Decides the next step of an `agent.prompt` run.
+///
+/// Calls block until the backend answers.
+pub trait AgentBackend: Send + Sync {
+    fn next_action(&self, transcript: &[AgentTurn]) -> Result<AgentAction, AgentError>;
+}
+
+/// The backend the CLI configures, if any.
+pub fn from_cli(args: &CliArgs) -> Option<Arc<dyn AgentBackend>> {
+    if let Some(endpoint) = &args.agent_endpoint {
+        return Some(Arc::new(OpenAiBackend::new(
+            endpoint.clone(),
+            args.agent_model.clone().unwrap_or_default(),
+            args.agent_api_key_env.clone(),
+        )));
+    }
+    let program = args.agent_command.clone()?;
+    Some(Arc::new(ProcessBackend::new(program, args.agent_args.clone())))
+}
+
+fn backend_error(reason: impl Into<String>) -> AgentError {
+    AgentError::AgentBackend {
+        reason: reason.into(),
+    }
+}
+
+/// The last `max` bytes of `text`, at a character boundary, marked as cut.
+fn tail(text: &str, max: usize) -> String {
+    if text.len() <= max {
+        return text.to_string();
+    }
+    let mut start = text.len() - max;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[{start} bytes cut]\n{}", &text[start..])
+}
+
+/// A chat completion endpoint speaking the OpenAI API.
+pub struct OpenAiBackend {
+    /// Base URL, e.g. `https://api.openai.com/v1` or
+    /// `http://localhost:11434/v1`.
+    endpoint: String,
+    model: String,
+    /// Environment variable holding the API key; requests go without one
+    /// if it is unset.
+    api_key_env: String,
+    agent: ureq::Agent,
+}
+
+impl OpenAiBackend {
+    pub fn new(endpoint: String, model: String, api_key_env: String) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .timeout_global(Some(REQUEST_TIMEOUT))
+            .http_status_as_error(false)
+            .build()
+            .into();
+        Self {
+            endpoint,
+            model,
+            api_key_env,
+            agent,
+        }
+    }
+
+    fn request_body(&self, transcript: &[AgentTurn]) -> Value {
+        json!({
+            "model": self.model,
+            "messages": chat_messages(transcript),
+            "tools": [{
+                "type": "function",
+                "function": {
+                    "name": "run_command",
+                    "description": "Run a shell command in the project directory and return \
+                                    its exit code, stdout and stderr.",
+                    "parameters": {
+                        "type": "object",
+                        "properties": { "command": { "type": "string" } },
+                        "required": ["command"],
+                    },
+                },
+            }],
+        })
+    }
+}
+
+impl AgentBackend for OpenAiBackend {
+    fn next_action(&self, transcript: &[AgentTurn]) -> Result<AgentAction, AgentError> {
+        let url = format!("{}/chat/completions", self.endpoint.trim_end_matches('/'));
+        let mut request = self.agent.post(&url).content_type("application/json");
+        if let Ok(key) = std::env::var(&self.api_key_env) {
+            request = request.header("Authorization", format!("Bearer {key}"));
+        }
+        let mut response = request
+            .send(self.request_body(transcript).to_string())
+            .map_err(|e| backend_error(format!("{url}: {e}")))?;
+        let status = response.status();
+        let body = response
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| backend_error(format!("{url}: {e}")))?;
+        if !status.is_success() {
+            return Err(backend_error(format!("{url} answered {status}: {}", tail(&body, 512))));
+        }
+        let completion: Value = serde_json::from_str(&body)
+            .map_err(|e| backend_error(format!("{url} sent invalid JSON: {e}")))?;
+        parse_completion(&completion)
+    }
+}
+
+/// The transcript as chat messages, after the system prompt.
+fn chat_messages(transcript: &[AgentTurn]) -> Vec<Value> {
+    let mut messages = vec![json!({ "role": "system", "content": SYSTEM_PROMPT })];
+    for turn in transcript {
+        match turn {
+            AgentTurn::Prompt { text } => {
+                messages.push(json!({ "role": "user", "content": text }));
+            }
+            AgentTurn::Command {
+                id,
+                note,
+                command,
+                exit_code,
+                stdout,
+                stderr,
+            } => {
+                messages.push(json!({
+                    "role": "assistant",
+                    "content": note,
+                    "tool_calls": [{
+                        "id": id,
+                        "type": "function",
+                        "function": {
+                            "name": "run_command",
+                            "arguments": json!({ "command": command }).to_string(),
+                        },
+                    }],
+                }));
+                let exit_code = match exit_code {
+                    Some(code) => code.to_string(),
+                    None => "none (still running when the wait timed out)".to_string(),
+                };
+                let result = format!(
+                    "exit code: {exit_code}\n--- stdout ---\n{}\n--- stderr ---\n{}",
+                    tail(stdout, MAX_OUTPUT_SHOWN),
+                    tail(stderr, MAX_OUTPUT_SHOWN),
+                );
+                messages.push(json!({ "role": "tool", "tool_call_id": id, "content": result }));
+            }
+        }
+    }
+    messages
+}
+
+/// The action a chat completion asks for. Only its first tool call is
+/// taken; a reply without one finishes the run.
+fn parse_completion(completion: &Value) -> Result<AgentAction, AgentError> {
+    let message = &completion["choices"][0]["message"];
+    if message.is_null() {
+        return Err(backend_error("the completion has no choices"));
+    }
+    let content = message["content"].as_str().filter(|text| !text.is_empty());
+    let Some(call) = message["tool_calls"].as_array().and_then(|calls| calls.first()) else {
+        return Ok(AgentAction::Finish {
+            message: content.unwrap_or_default().to_string(),
+        });
+    };
+    let name = call["function"]["name"].as_str().unwrap_or_default();
+    if name != "run_command" {
+        return Err(backend_error(format!("the model called unknown tool '{name}'")));
+    }
+    let arguments: Value = call["function"]["arguments"]
+        .as_str()
+        .and_then(|arguments| serde_json::from_str(arguments).ok())
+        .unwrap_or_default();
+    let Some(command) = arguments["command"].as_str() else {
+        return Err(backend_error("run_command was called without a command"));
+    };
+    Ok(AgentAction::Run {
+        id: call["id"].as_str().map(String::from),
+        command: command.to_string(),
+        note: content.map(String::from),
+    })
+}
+
+/// An external agent process, spawned on first use and kept for later
+/// prompts. It is restarted after it fails.
+pub struct ProcessBackend {
+    program: PathBuf,
+    args: Vec<String>,
+    process: Mutex<Option<AgentProcess>>,
+}
+
+struct AgentProcess {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Drop for AgentProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl ProcessBackend {
+    pub fn new(program: PathBuf, args: Vec<String>) -> Self {
+        Self {
+            program,
+            args,
+            process: Mutex::new(None),
+        }
+    }
+
+    fn spawn(&self) -> Result<AgentProcess, AgentError> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| backend_error(format!("cannot start {}: {e}", self.program.display())))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        Ok(AgentProcess {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    fn exchange(process: &mut AgentProcess, request: &str) -> Result<AgentAction, AgentError> {
+        let gone = |e: std::io::Error| backend_error(format!("agent process: {e}"));
+        process.stdin.write_all(request.as_bytes()).map_err(gone)?;
+        process.stdin.flush().map_err(gone)?;
+        let mut line = String::new();
+        if process.stdout.read_line(&mut line).map_err(gone)? == 0 {
+            return Err(backend_error("the agent process exited"));
+        }
+        serde_json::from_str(&line)
+            .map_err(|e| backend_error(format!("the agent process sent an invalid action: {e}")))
+    }
+}
+
+impl AgentBackend for ProcessBackend {
+    fn next_action(&self, transcript: &[AgentTurn]) -> Result<AgentAction, AgentError> {
+        let mut request = json!({ "transcript": transcript }).to_string();
+        request.push('\n');
+        let mut process = self.process.lock().unwrap();
+        if process.is_none() {
+            *process = Some(self.spawn()?);
+        }
+        let result = Self::exchange(process.as_mut().unwrap(), &request);
+        if result.is_err() {
+            *process = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_turn() -> AgentTurn {
+        AgentTurn::Command {
+            id: "call_1".to_string(),
+            note: Some("Listing files.".to_string()),
+            command: "ls".to_string(),
+            exit_code: Some(0),
+            stdout: "a.txt\n".to_string(),
+            stderr: String::new(),
+        }
+    }
+
+    #[test]
+    fn transcript_becomes_tool_calls_and_results() {
+        let transcript = [
+            AgentTurn::Prompt {
+                text: "What is here?".to_string(),
+            },
+            command_turn(),
+        ];
+        let messages = chat_messages(&transcript);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(messages[1], json!({"role": "user", "content": "What is here?"}));
+        assert_eq!(messages[2]["content"], "Listing files.");
+        let call = &messages[2]["tool_calls"][0];
+        assert_eq!(call["id"], "call_1");
+        assert_eq!(call["function"]["arguments"], r#"{"command":"ls"}"#);
+        assert_eq!(messages[3]["tool_call_id"], "call_1");
+        assert!(messages[3]["content"].as_str().unwrap().starts_with("exit code: 0\n"));
+    }
+
+    #[test]
+    fn completions_become_actions() {
+        let run = json!({"choices": [{"message": {
+            "content": "",
+            "tool_calls": [{
+                "id": "call_7",
+                "type": "function",
+                "function": {"name": "run_command", "arguments": "{\"command\":\"cargo test\"}"},
+            }],
+        }}]});
+        assert_eq!(
+            parse_completion(&run).unwrap(),
+            AgentAction::Run {
+                id: Some("call_7".to_string()),
+                command: "cargo test".to_string(),
+                note: None,
+            }
+        );
+
+        let finish = json!({"choices": [{"message": {"content": "All tests pass."}}]});
+        assert_eq!(
+            parse_completion(&finish).unwrap(),
+            AgentAction::Finish {
+                message: "All tests pass.".to_string(),
+            }
+        );
+
+        assert!(parse_completion(&json!({"choices": []})).is_err());
+        let unknown = json!({"choices": [{"message": {"tool_calls": [{
+            "function": {"name": "rm_rf", "arguments": "{}"},
+        }]}}]});
+        assert!(parse_completion(&unknown).is_err());
+    }
+
+    #[test]
+    fn long_output_keeps_its_end() {
+        assert_eq!(tail("short", 10), "short");
+        let cut = tail("0123456789", 4);
+        assert_eq!(cut, "[6 bytes cut]\n6789");
+        // Never split a character.
+        assert_eq!(tail("ééé", 3), "[4 bytes cut]\né");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_backend_exchanges_json_lines() {
+        let script = r#"read line; case "$line" in
+            *'"role":"command"'*) echo '{"action":"finish","message":"done"}' ;;
+            *) echo '{"action":"run","command":"ls","note":"looking"}' ;;
+        esac; read line; echo '{"action":"finish","message":"done"}'"#;
+        let backend = ProcessBackend::new(
+            PathBuf::from("sh"),
+            vec!["-c".to_string(), script.to_string()],
+        );
+        let mut transcript = vec![AgentTurn::Prompt {
+            text: "look".to_string(),
+        }];
+        assert_eq!(
+            backend.next_action(&transcript).unwrap(),
+            AgentAction::Run {
+                id: None,
+                command: "ls".to_string(),
+                note: Some("looking".to_string()),
+            }
+        );
+        transcript.push(command_turn());
+        assert_eq!(
+            backend.next_action(&transcript).unwrap(),
+            AgentAction::Finish {
+                message: "done".to_string(),
+            }
+        );
+        // The script has ended; the next prompt starts it again.
+        assert!(backend.next_action(&transcript).is_err());
+        assert!(matches!(
+            backend.next_action(&transcript[..1]).unwrap(),
+            AgentAction::Run { .. }
+        ));
+    }
+}
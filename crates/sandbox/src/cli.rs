@@ -1,7 +1,12 @@
 use std::path::PathBuf;
 
+use std::time::Duration;
+
 use clap::Parser;
 
+use codeagent_control::QuiescenceConfig;
+use codeagent_stdio::protocol::LogLevel;
+
 #[derive(Debug, Clone, Parser)]
 #[command(name = "sandbox", about = "Sandboxed coding agent host")]
 pub struct CliArgs {
@@ -23,9 +28,10 @@ pub struct CliArgs {
     #[arg(long, default_value = "stdio")]
     pub protocol: String,
 
-    /// Logging level for stderr structured logs.
+    /// Logging level for structured logs: "error", "warn", "info", "debug"
+    /// or "trace". `log.configure` changes it while running.
     #[arg(long, default_value = "info")]
-    pub log_level: String,
+    pub log_level: LogLevel,
 
     /// Path to the QEMU binary (overrides auto-detection).
     #[arg(long)]
@@ -55,6 +61,63 @@ pub struct CliArgs {
     #[arg(long)]
     pub virtiofsd_binary: Option<PathBuf>,
 
+    /// Give each virtiofs share a DAX window of this many megabytes and
+    /// mount it with `dax=inode`. Files are mapped only where the backend
+    /// grants per-inode DAX, which the bundled virtiofsd does not yet do,
+    /// so reads and writes still go through FUSE.
+    #[arg(long)]
+    pub virtiofs_dax_window_mb: Option<u32>,
+
+    /// Threads serving the FUSE requests of each intercepted virtiofs share.
+    /// Requests on one inode stay in order on one thread.
+    #[arg(long, default_value = "4", value_parser = clap::value_parser!(u32).range(1..))]
+    pub virtiofs_request_workers: u32,
+
+    /// Launch a new VM when the session's VM crashes (at most 3 times per
+    /// session) instead of continuing without one.
+    #[arg(long)]
+    pub vm_auto_restart: bool,
+
+    /// VMs to boot at startup, without working directories, for
+    /// `session.start` to take instead of booting one (Unix hosts).
+    #[arg(long, default_value = "0")]
+    pub vm_pool_size: usize,
+
+    /// Close a command's step once the filesystem has been quiet this many
+    /// milliseconds after the command exits. `session.configure` changes it.
+    #[arg(long, default_value = "100", value_parser = clap::value_parser!(u64).range(1..))]
+    pub quiescence_idle_timeout_ms: u64,
+
+    /// Close a command's step at most this many milliseconds after the
+    /// command exits, even with filesystem operations still in flight.
+    #[arg(long, default_value = "2000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub quiescence_max_timeout_ms: u64,
+
+    /// Close an ambient step (writes outside any command) after this many
+    /// milliseconds without a write.
+    #[arg(long, default_value = "5000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub ambient_inactivity_timeout_ms: u64,
+
+    /// Close an ambient step this many milliseconds after it opened, even
+    /// while writes keep arriving; later writes go to the next one.
+    #[arg(long, default_value = "60000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub ambient_max_duration_ms: u64,
+
+    /// Close an ambient step once it holds this many files; a write to
+    /// another file goes to the next one.
+    #[arg(long, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub ambient_max_files: u64,
+
+    /// Sessions the STDIO API may run at once. Above 1, `session.start`
+    /// returns a `session_id` that later requests must carry, and each
+    /// session keeps its undo data under `<undo-dir>/sessions/<id>`.
+    #[arg(
+        long,
+        default_value = "1",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub max_sessions: usize,
+
     /// Path to a TOML configuration file.
     /// If not specified, the platform default path is used
     /// (`{config_dir}/CodeAgent/codeagent.toml`).
@@ -66,10 +129,79 @@ pub struct CliArgs {
     #[arg(long)]
     pub socket_path: Option<PathBuf>,
 
+    /// With `--protocol mcp`, serve MCP clients on `unix:<path>` or a loopback
+    /// `<ip>:<port>` instead of stdin/stdout, until interrupted. Clients must
+    /// first send `Authorization: Bearer <token>`.
+    #[cfg_attr(
+        feature = "mcp-http",
+        doc = "`http://<ip>:<port>` serves the streamable HTTP transport, with the token \
+               as a bearer header."
+    )]
+    #[arg(long, requires = "mcp_token_file")]
+    pub mcp_listen: Option<String>,
+
+    /// File holding the token `--mcp-listen` clients must present.
+    #[arg(long)]
+    pub mcp_token_file: Option<PathBuf>,
+
+    /// OpenAI-compatible API base URL (e.g. `http://localhost:11434/v1`)
+    /// whose chat completions drive `agent.prompt`.
+    #[arg(long, requires = "agent_model", conflicts_with = "agent_command")]
+    pub agent_endpoint: Option<String>,
+
+    /// Model requested from `--agent-endpoint`.
+    #[arg(long, requires = "agent_endpoint")]
+    pub agent_model: Option<String>,
+
+    /// Environment variable holding the `--agent-endpoint` API key.
+    #[arg(long, default_value = "OPENAI_API_KEY")]
+    pub agent_api_key_env: String,
+
+    /// External agent process driving `agent.prompt`, exchanging JSON Lines
+    /// on its stdin/stdout.
+    #[arg(long)]
+    pub agent_command: Option<PathBuf>,
+
+    /// Argument passed to `--agent-command` (repeatable).
+    #[arg(long = "agent-arg", requires = "agent_command", allow_hyphen_values = true)]
+    pub agent_args: Vec<String>,
+
+    /// Most commands one `agent.prompt` may run before it stops.
+    #[arg(long, default_value = "25")]
+    pub agent_max_turns: usize,
+
+    /// Path to a Unix domain socket serving readiness and liveness probes
+    /// (a port file on Windows). Combine with `--health-probe` to query it.
+    #[arg(long)]
+    pub health_socket: Option<PathBuf>,
+
+    /// Query the sandbox listening on `--health-socket` instead of starting
+    /// one: "ready" or "live". Exits 0 if healthy, 1 if unhealthy, 3 if the
+    /// socket is unreachable.
+    #[arg(long, requires = "health_socket")]
+    pub health_probe: Option<String>,
+
+    /// Print the JSON Schema of the "stdio" API or the "control" channel
+    /// instead of starting a sandbox, for frontends to generate types from.
+    #[arg(long, value_parser = ["stdio", "control"])]
+    pub print_schema: Option<String>,
+
+    /// Serve Prometheus metrics at `http://<addr>/metrics`. The address must
+    /// be a loopback one, such as `127.0.0.1:9464`: the endpoint has no
+    /// authentication.
+    #[arg(long, value_parser = crate::metrics_endpoint::parse_address)]
+    pub metrics_listen: Option<std::net::SocketAddr>,
+
     /// Path to a log file. When set, stderr output is also teed to this file.
     #[arg(long)]
     pub log_file: Option<PathBuf>,
 
+    /// Also write structured logs to `<undo-dir>/.logs/sandbox.log`, renamed
+    /// to `sandbox.log.1` when it reaches this many megabytes. Five renamed
+    /// files are kept.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub log_rotate_mb: Option<u64>,
+
     /// Block Claude Code's built-in file/command tools (Read, Edit, Write, Glob,
     /// Grep, Bash) while the sandbox is running, restoring them on exit.
     #[arg(long)]
@@ -86,6 +218,19 @@ pub struct CliArgs {
     pub server_name: String,
 }
 
+impl CliArgs {
+    /// The step closing timeouts given on the command line.
+    pub fn quiescence_config(&self) -> QuiescenceConfig {
+        QuiescenceConfig {
+            idle_timeout: Duration::from_millis(self.quiescence_idle_timeout_ms),
+            max_timeout: Duration::from_millis(self.quiescence_max_timeout_ms),
+            ambient_inactivity_timeout: Duration::from_millis(self.ambient_inactivity_timeout_ms),
+            ambient_max_duration: Duration::from_millis(self.ambient_max_duration_ms),
+            ambient_max_files: self.ambient_max_files as usize,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -104,9 +249,36 @@ mod tests {
         assert_eq!(args.undo_dir, Some(PathBuf::from("/tmp/undo")));
         assert_eq!(args.vm_mode, "ephemeral");
         assert_eq!(args.protocol, "stdio");
-        assert_eq!(args.log_level, "info");
+        assert_eq!(args.log_level, LogLevel::Info);
+        assert!(args.log_rotate_mb.is_none());
         assert!(args.socket_path.is_none());
         assert!(args.log_file.is_none());
+        assert!(args.health_socket.is_none());
+        assert!(args.metrics_listen.is_none());
+    }
+
+    #[test]
+    fn health_probe_requires_health_socket() {
+        let args = CliArgs::try_parse_from([
+            "sandbox",
+            "--health-socket",
+            "/tmp/health.sock",
+            "--health-probe",
+            "ready",
+        ])
+        .unwrap();
+        assert_eq!(args.health_socket, Some(PathBuf::from("/tmp/health.sock")));
+        assert_eq!(args.health_probe.as_deref(), Some("ready"));
+
+        assert!(CliArgs::try_parse_from(["sandbox", "--health-probe", "live"]).is_err());
+    }
+
+    #[test]
+    fn print_schema_takes_a_protocol() {
+        let args = CliArgs::try_parse_from(["sandbox", "--print-schema", "control"]).unwrap();
+        assert_eq!(args.print_schema.as_deref(), Some("control"));
+
+        assert!(CliArgs::try_parse_from(["sandbox", "--print-schema", "mcp"]).is_err());
     }
 
     #[test]
@@ -127,6 +299,61 @@ mod tests {
         assert_eq!(args.log_file, Some(PathBuf::from("/tmp/sandbox.log")));
     }
 
+    #[test]
+    fn mcp_listen_requires_a_token_file() {
+        let args = CliArgs::try_parse_from([
+            "sandbox",
+            "--mcp-listen",
+            "127.0.0.1:7000",
+            "--mcp-token-file",
+            "/tmp/mcp.token",
+        ])
+        .unwrap();
+        assert_eq!(args.mcp_listen.as_deref(), Some("127.0.0.1:7000"));
+        assert_eq!(args.mcp_token_file, Some(PathBuf::from("/tmp/mcp.token")));
+
+        let without_token = ["sandbox", "--mcp-listen", "unix:/tmp/mcp.sock"];
+        assert!(CliArgs::try_parse_from(without_token).is_err());
+    }
+
+    #[test]
+    fn agent_backend_flags() {
+        let args = CliArgs::try_parse_from([
+            "sandbox",
+            "--agent-endpoint",
+            "http://localhost:11434/v1",
+            "--agent-model",
+            "qwen2.5-coder",
+        ])
+        .unwrap();
+        assert_eq!(args.agent_endpoint.as_deref(), Some("http://localhost:11434/v1"));
+        assert_eq!(args.agent_api_key_env, "OPENAI_API_KEY");
+        assert_eq!(args.agent_max_turns, 25);
+
+        let args = CliArgs::try_parse_from([
+            "sandbox",
+            "--agent-command",
+            "/usr/bin/my-agent",
+            "--agent-arg",
+            "--json",
+        ])
+        .unwrap();
+        assert_eq!(args.agent_args, vec!["--json"]);
+
+        let without_model = ["sandbox", "--agent-endpoint", "http://localhost:8000/v1"];
+        assert!(CliArgs::try_parse_from(without_model).is_err());
+        let both = [
+            "sandbox",
+            "--agent-endpoint",
+            "http://localhost:8000/v1",
+            "--agent-model",
+            "m",
+            "--agent-command",
+            "agent",
+        ];
+        assert!(CliArgs::try_parse_from(both).is_err());
+    }
+
     #[test]
     fn multiple_working_dirs_parse() {
         let args = CliArgs::try_parse_from([
@@ -163,7 +390,7 @@ mod tests {
         .unwrap();
         assert_eq!(args.vm_mode, "persistent");
         assert_eq!(args.protocol, "mcp");
-        assert_eq!(args.log_level, "debug");
+        assert_eq!(args.log_level, LogLevel::Debug);
     }
 
     #[test]
@@ -197,6 +424,9 @@ mod tests {
             "4",
             "--virtiofsd-binary",
             "/usr/libexec/virtiofsd",
+            "--vm-auto-restart",
+            "--vm-pool-size",
+            "2",
         ])
         .unwrap();
         assert_eq!(
@@ -212,6 +442,8 @@ mod tests {
             args.virtiofsd_binary,
             Some(PathBuf::from("/usr/libexec/virtiofsd"))
         );
+        assert!(args.vm_auto_restart);
+        assert_eq!(args.vm_pool_size, 2);
     }
 
     #[test]
@@ -231,5 +463,66 @@ mod tests {
         assert_eq!(args.memory_mb, 512);
         assert_eq!(args.cpus, 2);
         assert!(args.virtiofsd_binary.is_none());
+        assert!(args.virtiofs_dax_window_mb.is_none());
+        assert_eq!(args.virtiofs_request_workers, 4);
+        assert!(!args.vm_auto_restart);
+        assert_eq!(args.vm_pool_size, 0);
+        assert_eq!(args.max_sessions, 1);
+    }
+
+    #[test]
+    fn max_sessions_must_be_positive() {
+        let base = ["sandbox", "--working-dir", "/tmp/work", "--undo-dir", "/tmp/undo"];
+        let args = CliArgs::try_parse_from(base.iter().chain(&["--max-sessions", "4"])).unwrap();
+        assert_eq!(args.max_sessions, 4);
+        assert!(CliArgs::try_parse_from(base.iter().chain(&["--max-sessions", "0"])).is_err());
+    }
+
+    #[test]
+    fn log_options_parse() {
+        let base = ["sandbox", "--working-dir", "/tmp/work"];
+        let options = ["--log-rotate-mb", "8", "--log-level", "trace"];
+        let args = CliArgs::try_parse_from(base.iter().chain(&options)).unwrap();
+        assert_eq!(args.log_rotate_mb, Some(8));
+        assert_eq!(args.log_level, LogLevel::Trace);
+        assert!(CliArgs::try_parse_from(base.iter().chain(&["--log-rotate-mb", "0"])).is_err());
+        assert!(CliArgs::try_parse_from(base.iter().chain(&["--log-level", "loud"])).is_err());
+    }
+
+    #[test]
+    fn metrics_listen_must_be_loopback() {
+        let base = ["sandbox", "--working-dir", "/tmp/work"];
+        let args =
+            CliArgs::try_parse_from(base.iter().chain(&["--metrics-listen", "127.0.0.1:9464"]))
+                .unwrap();
+        assert_eq!(args.metrics_listen, Some("127.0.0.1:9464".parse().unwrap()));
+        let public = ["--metrics-listen", "0.0.0.0:9464"];
+        assert!(CliArgs::try_parse_from(base.iter().chain(&public)).is_err());
+    }
+
+    #[test]
+    fn quiescence_timeouts_parse() {
+        let base = ["sandbox", "--working-dir", "/tmp/work"];
+        let config = CliArgs::try_parse_from(base).unwrap().quiescence_config();
+        assert_eq!(config.idle_timeout, QuiescenceConfig::default().idle_timeout);
+        assert_eq!(config.max_timeout, QuiescenceConfig::default().max_timeout);
+
+        let options = [
+            "--quiescence-idle-timeout-ms",
+            "750",
+            "--ambient-inactivity-timeout-ms",
+            "20000",
+            "--ambient-max-files",
+            "50",
+        ];
+        let config = CliArgs::try_parse_from(base.iter().chain(&options))
+            .unwrap()
+            .quiescence_config();
+        assert_eq!(config.idle_timeout, Duration::from_millis(750));
+        assert_eq!(config.ambient_inactivity_timeout, Duration::from_secs(20));
+        assert_eq!(config.ambient_max_files, 50);
+        assert_eq!(config.ambient_max_duration, QuiescenceConfig::default().ambient_max_duration);
+        let zero = ["--quiescence-max-timeout-ms", "0"];
+        assert!(CliArgs::try_parse_from(base.iter().chain(&zero)).is_err());
     }
 }
@@ -0,0 +1,121 @@
+//! In-process client for programs that embed the sandbox.
+//!
+//! [`SandboxClient`] drives an [`Orchestrator`] directly, without the JSON
+//! Lines server in between: requests take the typed payloads of the STDIO
+//! API and run on tokio's blocking pool, the way the server dispatches
+//! them, and events arrive as typed [`Event`]s on as many subscriptions as
+//! the program opens. Responses are the same JSON values the server would
+//! put in a response envelope.
+
+use std::sync::{Arc, Mutex};
+
+use codeagent_common::{ErrorCode, Unmonitored};
+use codeagent_stdio::protocol::{AgentExecutePayload, SessionStartPayload, UndoRollbackPayload};
+use codeagent_stdio::{Event, RequestHandler, StdioError};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::cli::CliArgs;
+use crate::config::SandboxTomlConfig;
+use crate::orchestrator::Orchestrator;
+
+/// What [`SandboxClient::start`] needs: the process settings, the TOML
+/// configuration and the session to start.
+#[derive(Debug, Clone)]
+pub struct SandboxConfig {
+    pub args: CliArgs,
+    pub settings: SandboxTomlConfig,
+    pub session: SessionStartPayload,
+}
+
+/// A started session of an embedded sandbox.
+pub struct SandboxClient {
+    orchestrator: Arc<Orchestrator>,
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<Event>>>>,
+    forwarder: JoinHandle<()>,
+}
+
+impl SandboxClient {
+    /// Create the orchestrator and start the session `config` describes.
+    pub async fn start(config: SandboxConfig) -> Result<Self, StdioError> {
+        let SandboxConfig { args, settings, session } = config;
+        let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+        let orchestrator = Orchestrator::new(
+            args,
+            event_sender,
+            settings.command_classifier,
+            settings.file_watcher,
+        )
+        .with_compaction(settings.compaction);
+
+        let subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<Event>>>> = Arc::default();
+        let forwarder = tokio::spawn({
+            let subscribers = Arc::clone(&subscribers);
+            async move {
+                while let Some(event) = event_receiver.recv().await {
+                    subscribers
+                        .lock()
+                        .unwrap()
+                        .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+                }
+            }
+        });
+
+        let client = Self { orchestrator: Arc::new(orchestrator), subscribers, forwarder };
+        client.call(move |orchestrator| orchestrator.session_start(session)).await?;
+        Ok(client)
+    }
+
+    /// A new stream of the session's events, from now on. Dropping the
+    /// receiver ends the subscription.
+    pub fn subscribe_events(&self) -> mpsc::UnboundedReceiver<Event> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// `agent.execute`.
+    pub async fn execute(
+        &self,
+        payload: AgentExecutePayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        self.call(move |orchestrator| orchestrator.agent_execute(payload)).await
+    }
+
+    /// `undo.rollback`.
+    pub async fn rollback(
+        &self,
+        payload: UndoRollbackPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        self.call(move |orchestrator| orchestrator.undo_rollback(payload, &Unmonitored)).await
+    }
+
+    /// Run any other request against the orchestrator, e.g.
+    /// `client.call(|o| o.undo_history(payload))`.
+    pub async fn call<F>(&self, request: F) -> Result<serde_json::Value, StdioError>
+    where
+        F: FnOnce(&Orchestrator) -> Result<serde_json::Value, StdioError> + Send + 'static,
+    {
+        let orchestrator = Arc::clone(&self.orchestrator);
+        tokio::task::spawn_blocking(move || request(&orchestrator))
+            .await
+            .unwrap_or_else(|e| {
+                Err(StdioError::Failed {
+                    code: ErrorCode::Internal,
+                    message: format!("request panicked: {e}"),
+                })
+            })
+    }
+
+    /// `session.stop`.
+    pub async fn stop(&self) -> Result<serde_json::Value, StdioError> {
+        self.call(|orchestrator| orchestrator.session_stop()).await
+    }
+}
+
+/// Subscriptions end with the client, losing any events not yet passed on.
+impl Drop for SandboxClient {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+    }
+}
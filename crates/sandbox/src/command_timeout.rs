@@ -0,0 +1,166 @@
+//! `timeout_seconds` for `agent.execute`.
+//!
+//! Each timed command gets a timer task. If the command's step has not closed
+//! when the timer fires, the command is cancelled in the guest. Once the
+//! cancelled step closes it is optionally rolled back, and
+//! `event.command_timed_out` reports what happened.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+
+use codeagent_common::{StepId, StepManager};
+use codeagent_control::{ControlChannelHandler, HostMessage, LaneSender};
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_stdio::{log_warn, Event};
+
+use crate::recent_writes::RecentBackendWrites;
+
+/// How long a cancelled command has to stop. The shim escalates from SIGTERM
+/// to SIGKILL after 5 seconds.
+pub const CANCEL_GRACE: Duration = Duration::from_secs(30);
+
+/// Step-closed notifications for commands with a timeout, fed by the event
+/// bridge.
+#[derive(Default)]
+pub struct CommandTimeouts {
+    closed: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+}
+
+impl CommandTimeouts {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Start tracking a command before it is sent. The receiver resolves when
+    /// the command's step closes.
+    pub fn watch(&self, command_id: u64) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.closed.lock().unwrap().insert(command_id, sender);
+        receiver
+    }
+
+    /// The step of `command_id` closed, normally or after a cancel.
+    pub fn step_closed(&self, command_id: u64) {
+        if let Some(sender) = self.closed.lock().unwrap().remove(&command_id) {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Stop tracking a command, e.g. one that could not be sent.
+    pub fn forget(&self, command_id: u64) {
+        self.closed.lock().unwrap().remove(&command_id);
+    }
+}
+
+/// A command to cancel, and optionally roll back, if it runs too long.
+pub struct CommandTimeout {
+    pub command_id: u64,
+    pub timeout_seconds: u64,
+    pub control_writer: LaneSender<HostMessage>,
+    pub control_handler: Arc<ControlChannelHandler<dyn StepManager>>,
+    /// Interceptors to roll the step back in; empty unless
+    /// `rollback_on_timeout` was requested.
+    pub rollback: Vec<Arc<UndoInterceptor>>,
+    pub recent_writes: Option<Arc<RecentBackendWrites>>,
+    pub event_sender: mpsc::UnboundedSender<Event>,
+}
+
+impl CommandTimeout {
+    /// Run the timer. Returns without doing anything if the step closes first.
+    pub async fn run(self, timeouts: Arc<CommandTimeouts>, closed: oneshot::Receiver<()>) {
+        let mut closed = closed;
+        tokio::select! {
+            _ = &mut closed => return,
+            _ = tokio::time::sleep(Duration::from_secs(self.timeout_seconds)) => {}
+        }
+
+        log_warn!(
+            "command_timeout",
+            "command {} exceeded {}s, cancelling",
+            self.command_id,
+            self.timeout_seconds
+        );
+        self.control_handler.cancel(self.command_id).await;
+        let _ = self.control_writer.send(HostMessage::Cancel {
+            id: self.command_id,
+        });
+
+        let (rolled_back, error) = match tokio::time::timeout(CANCEL_GRACE, closed).await {
+            Ok(_) if self.rollback.is_empty() => (false, None),
+            Ok(_) => {
+                let step_id = self.command_id as StepId;
+                let interceptors = self.rollback;
+                let recent_writes = self.recent_writes;
+                tokio::task::spawn_blocking(move || {
+                    rollback_step(&interceptors, step_id, recent_writes.as_deref())
+                })
+                .await
+                .unwrap_or_else(|error| Err(error.to_string()))
+                .map_or_else(|error| (false, Some(error)), |()| (true, None))
+            }
+            Err(_) => {
+                timeouts.forget(self.command_id);
+                (false, Some("command did not stop after cancel".to_string()))
+            }
+        };
+
+        let _ = self.event_sender.send(Event::CommandTimedOut {
+            command_id: self.command_id,
+            timeout_seconds: self.timeout_seconds,
+            rolled_back,
+            error,
+        });
+    }
+}
+
+/// Roll back the step `step_id` was opened as in every working directory
+/// that recorded it. The step must still be the newest one there.
+fn rollback_step(
+    interceptors: &[Arc<UndoInterceptor>],
+    step_id: StepId,
+    recent_writes: Option<&RecentBackendWrites>,
+) -> Result<(), String> {
+    if let Some(recent_writes) = recent_writes {
+        recent_writes.begin_suppression();
+    }
+    let mut result = Ok(());
+    for interceptor in interceptors {
+        // Empty steps are not stored, so there is nothing to roll back.
+        let Some(stored_id) = interceptor.history_step_id(step_id) else {
+            continue;
+        };
+        if interceptor.completed_steps().last() != Some(&stored_id) {
+            result = Err(format!("step {stored_id} is no longer the newest step"));
+            break;
+        }
+        if let Err(error) = interceptor.rollback(1, false) {
+            result = Err(error.to_string());
+            break;
+        }
+    }
+    if let Some(recent_writes) = recent_writes {
+        recent_writes.end_suppression();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn closed_steps_notify_their_watcher_once() {
+        let timeouts = CommandTimeouts::new();
+        let closed = timeouts.watch(4);
+        let unrelated = timeouts.watch(5);
+        timeouts.step_closed(4);
+        timeouts.step_closed(4);
+        assert!(closed.await.is_ok());
+
+        timeouts.forget(5);
+        assert!(unrelated.await.is_err());
+    }
+}
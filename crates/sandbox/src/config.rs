@@ -7,6 +7,7 @@
 
 use std::path::{Path, PathBuf};
 
+use codeagent_interceptor::compaction::CompactionOptions;
 use serde::{Deserialize, Serialize};
 
 use crate::command_classifier::CommandClassifierConfig;
@@ -22,17 +23,31 @@ pub struct SandboxTomlConfig {
     pub sandbox: SandboxSection,
     pub command_classifier: CommandClassifierConfig,
     pub file_watcher: FileWatcherConfig,
+    pub compaction: CompactionConfig,
 }
 
 /// Core sandbox settings: working directories and undo directory.
 ///
 /// These are the same fields the desktop app writes under `[sandbox]`.
 /// CLI args override these values when provided.
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct SandboxSection {
     pub working_dirs: Vec<String>,
     pub undo_dir: String,
+    /// Remove leftovers from a previous run at startup instead of only
+    /// reporting them via `event.stale_resources` (default: true).
+    pub auto_cleanup_stale_resources: bool,
+}
+
+impl Default for SandboxSection {
+    fn default() -> Self {
+        Self {
+            working_dirs: vec![],
+            undo_dir: String::new(),
+            auto_cleanup_stale_resources: true,
+        }
+    }
 }
 
 /// Configuration for the filesystem watcher, loaded from TOML.
@@ -63,6 +78,42 @@ impl Default for FileWatcherConfig {
     }
 }
 
+/// Configuration for idle-time compaction of the undo log, loaded from TOML.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompactionConfig {
+    /// Whether undo logs are compacted while the session is idle (default: true).
+    pub enabled: bool,
+    /// Seconds without an open step or filesystem operation before
+    /// compaction starts (default: 60).
+    pub idle_seconds: u64,
+    /// zstd level preimages are recompressed at (default: 19).
+    pub zstd_level: i32,
+    /// Newest steps left at the capture level (default: 1).
+    pub keep_recent_steps: usize,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        let options = CompactionOptions::default();
+        Self {
+            enabled: true,
+            idle_seconds: 60,
+            zstd_level: options.level,
+            keep_recent_steps: options.keep_recent_steps,
+        }
+    }
+}
+
+impl CompactionConfig {
+    pub fn options(&self) -> CompactionOptions {
+        CompactionOptions {
+            level: self.zstd_level,
+            keep_recent_steps: self.keep_recent_steps,
+        }
+    }
+}
+
 /// Return the platform-default config directory for CodeAgent.
 ///
 /// Uses the same path convention as the desktop app (`desktop/src-tauri/src/paths.rs`):
@@ -193,6 +244,20 @@ undo_dir = "/tmp/undo"
         let config = load_config(Some(&path));
         assert_eq!(config.sandbox.working_dirs, vec!["/tmp/project", "/tmp/other"]);
         assert_eq!(config.sandbox.undo_dir, "/tmp/undo");
+        assert!(config.sandbox.auto_cleanup_stale_resources);
+    }
+
+    #[test]
+    fn compaction_section_from_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compaction.toml");
+        std::fs::write(&path, "[compaction]\nidle_seconds = 300\nzstd_level = 12\n").unwrap();
+
+        let config = load_config(Some(&path));
+        assert!(config.compaction.enabled);
+        assert_eq!(config.compaction.idle_seconds, 300);
+        let options = config.compaction.options();
+        assert_eq!((options.level, options.keep_recent_steps), (12, 1));
     }
 
     #[test]
@@ -1,34 +1,125 @@
+use std::io;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
-use codeagent_control::{ControlChannelHandler, StepManager, parse_vm_message};
-use codeagent_stdio::Event;
+use codeagent_control::{
+    ControlChannelHandler, HostMessage, Incoming, LaneSender, Link, StepManager, VmMessage,
+    lane_channel,
+};
+use codeagent_stdio::{log_debug, log_error, Event};
+
+use crate::env_profile::EnvProfile;
+use crate::error::AgentError;
+
+/// How long QEMU has to start serving the control channel.
+pub const CONTROL_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The host's read half of a VM's control channel.
+pub type ControlReader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// The host's write half of a VM's control channel.
+pub type ControlWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Connect to the control channel QEMU serves for `control_socket_path`.
+///
+/// QEMU is the server on every host: a Unix socket at the path, or on
+/// Windows the named pipe [`control_pipe_name`](crate::qemu::control_pipe_name)
+/// derives from it. Either may not accept connections for a moment after
+/// QEMU starts, so connecting is retried for [`CONTROL_CONNECT_TIMEOUT`].
+/// Must be called within a Tokio runtime.
+pub fn connect_control_channel(
+    control_socket_path: &Path,
+) -> Result<(ControlReader, ControlWriter), AgentError> {
+    let failed = |error: io::Error| AgentError::ControlChannelFailed {
+        reason: format!("failed to connect to the control channel: {error}"),
+    };
+
+    #[cfg(unix)]
+    {
+        let stream = retry_connect(CONTROL_CONNECT_TIMEOUT, || {
+            std::os::unix::net::UnixStream::connect(control_socket_path)
+        })
+        .and_then(|stream| {
+            stream.set_nonblocking(true)?;
+            tokio::net::UnixStream::from_std(stream)
+        })
+        .map_err(failed)?;
+        let (reader, writer) = stream.into_split();
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+
+    #[cfg(windows)]
+    {
+        use tokio::net::windows::named_pipe::ClientOptions;
+
+        let pipe = format!(
+            r"\\.\pipe\{}",
+            crate::qemu::control_pipe_name(control_socket_path)
+        );
+        let client = retry_connect(CONTROL_CONNECT_TIMEOUT, || ClientOptions::new().open(&pipe))
+            .map_err(failed)?;
+        let (reader, writer) = tokio::io::split(client);
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+/// Call `connect` until it succeeds, fails for a reason other than the
+/// server not being up yet, or `timeout` has passed.
+pub fn retry_connect<T>(
+    timeout: Duration,
+    mut connect: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let started = Instant::now();
+    loop {
+        match connect() {
+            Err(error) if not_serving_yet(&error) && started.elapsed() < timeout => {
+                std::thread::sleep(CONNECT_RETRY_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `error` means nothing accepts connections yet: no socket or
+/// pipe, or (Windows) every instance of the pipe busy.
+fn not_serving_yet(error: &io::Error) -> bool {
+    #[cfg(windows)]
+    if error.raw_os_error() == Some(windows_sys::Win32::Foundation::ERROR_PIPE_BUSY as i32) {
+        return true;
+    }
+    matches!(
+        error.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+    )
+}
 
 /// Spawn a background task that writes host messages to the control channel.
 ///
-/// Returns a sender that the orchestrator uses to enqueue serialized messages.
-/// Each message is written as a JSON Line (with trailing newline and flush).
-pub fn spawn_control_writer<W>(writer: W) -> (mpsc::UnboundedSender<String>, JoinHandle<()>)
+/// Returns a sender that the orchestrator uses to enqueue messages. Each
+/// message is written as a JSON Line (with trailing newline and flush);
+/// everything but `input` goes ahead of queued `input` messages. The writer
+/// first offers protocol version 2 on `link`, and frames messages once the
+/// shim accepts it.
+pub fn spawn_control_writer<W>(
+    writer: W,
+    link: Link,
+) -> (LaneSender<HostMessage>, JoinHandle<()>)
 where
     W: tokio::io::AsyncWrite + Unpin + Send + 'static,
 {
-    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    let (sender, receiver) = lane_channel::<HostMessage>();
 
+    link.offer();
     let handle = tokio::spawn(async move {
-        let mut writer = tokio::io::BufWriter::new(writer);
-        while let Some(line) = receiver.recv().await {
-            if writer.write_all(line.as_bytes()).await.is_err() {
-                break;
-            }
-            if writer.write_all(b"\n").await.is_err() {
-                break;
-            }
-            if writer.flush().await.is_err() {
-                break;
-            }
+        if let Err(error) = link.run_writer(receiver, writer).await {
+            log_error!("control_writer", "control channel write failed: {error}");
         }
     });
 
@@ -39,28 +130,40 @@ where
 /// and dispatches them through the handler.
 ///
 /// On parse errors or channel close, emits error events via the event sender.
+/// Logged messages have the secrets of `env_profile` redacted. A restarted
+/// shim starts over at protocol version 1, so `link` offers version 2 again.
 pub fn spawn_control_reader<R, S>(
     reader: R,
+    link: Link,
     handler: Arc<ControlChannelHandler<S>>,
     event_sender: mpsc::UnboundedSender<Event>,
+    env_profile: Arc<EnvProfile>,
 ) -> JoinHandle<()>
 where
     R: tokio::io::AsyncRead + Unpin + Send + 'static,
-    S: StepManager + 'static,
+    S: StepManager + ?Sized + 'static,
 {
     tokio::spawn(async move {
         let mut lines = BufReader::new(reader).lines();
 
         while let Ok(Some(line)) = lines.next_line().await {
-            eprintln!(
-                "{{\"level\":\"debug\",\"component\":\"control_reader\",\"message\":\"vm message: {}\"}}",
-                line.chars().take(200).collect::<String>()
+            if line.is_empty() {
+                continue;
+            }
+            log_debug!(
+                "control_reader",
+                "vm message: {}",
+                env_profile.redact(&line).chars().take(200).collect::<String>()
             );
-            match parse_vm_message(&line) {
-                Ok(msg) => {
+            match link.decode::<VmMessage>(&line) {
+                Incoming::Message(msg) => {
+                    if matches!(msg, VmMessage::ShimRestarted { .. }) {
+                        link.offer();
+                    }
                     handler.handle_vm_message(msg).await;
                 }
-                Err(error) => {
+                Incoming::Nothing => {}
+                Incoming::Invalid(error) => {
                     let _ = event_sender.send(Event::Error {
                         code: "control_channel_parse_error".to_string(),
                         message: error.to_string(),
@@ -80,3 +183,70 @@ where
 pub fn serialize_host_message(msg: &codeagent_control::HostMessage) -> Result<String, serde_json::Error> {
     serde_json::to_string(msg)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn connecting_is_retried_until_the_server_is_up() {
+        let attempts = Cell::new(0);
+        let connected = retry_connect(Duration::from_secs(5), || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+            Ok(attempts.get())
+        });
+        assert_eq!(connected.unwrap(), 3);
+    }
+
+    #[test]
+    fn other_errors_and_timeouts_end_the_retries() {
+        let attempts = Cell::new(0);
+        let denied = retry_connect(Duration::from_secs(5), || -> io::Result<()> {
+            attempts.set(attempts.get() + 1);
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert_eq!(denied.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(attempts.get(), 1);
+
+        let missing = retry_connect(Duration::ZERO, || -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn control_channel_connects_to_a_socket_that_appears_late() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let server_path = path.clone();
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            let listener = std::os::unix::net::UnixListener::bind(&server_path).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            std::io::Write::write_all(&mut stream, b"{\"type\":\"ping\"}\n").unwrap();
+            let mut reply = String::new();
+            std::io::BufRead::read_line(&mut std::io::BufReader::new(stream), &mut reply)
+                .unwrap();
+            reply
+        });
+
+        let (reader, mut writer) =
+            tokio::task::spawn_blocking(move || connect_control_channel(&path))
+                .await
+                .unwrap()
+                .unwrap();
+        let line = BufReader::new(reader).lines().next_line().await.unwrap();
+        assert_eq!(line.as_deref(), Some("{\"type\":\"ping\"}"));
+        writer.write_all(b"pong\n").await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(server.join().unwrap(), "pong\n");
+    }
+}
@@ -0,0 +1,149 @@
+//! Session environment profile for guest commands.
+//!
+//! Variables set with `session.env.set` are merged into the `env` of every
+//! exec message the session sends, under whatever the individual call passes
+//! itself. Values marked secret are never echoed back by `session.env.list`,
+//! and the event bridge and control channel log replace them in command
+//! output with [`REDACTED`].
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+/// What a secret value is replaced with.
+pub const REDACTED: &str = "[REDACTED]";
+
+struct ProfileVariable {
+    value: String,
+    secret: bool,
+}
+
+/// The variables of one session, shared with its event bridge.
+#[derive(Default)]
+pub struct EnvProfile {
+    variables: RwLock<BTreeMap<String, ProfileVariable>>,
+}
+
+impl EnvProfile {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Set `name`, replacing any earlier value. Returns whether it replaced one.
+    pub fn set(&self, name: String, value: String, secret: bool) -> bool {
+        let variable = ProfileVariable { value, secret };
+        self.variables.write().unwrap().insert(name, variable).is_some()
+    }
+
+    /// Remove `name`. Returns whether it was set.
+    pub fn unset(&self, name: &str) -> bool {
+        self.variables.write().unwrap().remove(name).is_some()
+    }
+
+    /// `(name, value, secret)` for each variable in name order, with secret
+    /// values replaced by [`REDACTED`].
+    pub fn list(&self) -> Vec<(String, String, bool)> {
+        self.variables
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, variable)| {
+                let value = if variable.secret {
+                    REDACTED.to_string()
+                } else {
+                    variable.value.clone()
+                };
+                (name.clone(), value, variable.secret)
+            })
+            .collect()
+    }
+
+    /// The profile with `env` on top, or `env` itself if the profile is
+    /// empty.
+    pub fn merge(&self, env: Option<HashMap<String, String>>) -> Option<HashMap<String, String>> {
+        let variables = self.variables.read().unwrap();
+        if variables.is_empty() {
+            return env;
+        }
+        let mut merged: HashMap<String, String> = variables
+            .iter()
+            .map(|(name, variable)| (name.clone(), variable.value.clone()))
+            .collect();
+        merged.extend(env.unwrap_or_default());
+        Some(merged)
+    }
+
+    /// `text` with every secret value replaced by [`REDACTED`]. A value
+    /// split across two output chunks is not caught.
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let variables = self.variables.read().unwrap();
+        let mut text = Cow::Borrowed(text);
+        for variable in variables.values() {
+            if variable.secret && !variable.value.is_empty() && text.contains(&variable.value) {
+                text = Cow::Owned(text.replace(&variable.value, REDACTED));
+            }
+        }
+        text
+    }
+}
+
+/// Why `name` cannot be an environment variable name, if it cannot.
+pub fn invalid_name(name: &str) -> Option<&'static str> {
+    if name.is_empty() {
+        Some("must not be empty")
+    } else if name.contains(['=', '\0']) {
+        Some("must not contain '=' or NUL")
+    } else if name.starts_with(|c: char| c.is_ascii_digit()) {
+        Some("must not start with a digit")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_env_overrides_profile() {
+        let profile = EnvProfile::new();
+        assert_eq!(profile.merge(None), None);
+
+        profile.set("PATH".to_string(), "/opt/bin".to_string(), false);
+        profile.set("LANG".to_string(), "C".to_string(), false);
+        let call = HashMap::from([("PATH".to_string(), "/usr/bin".to_string())]);
+        let merged = profile.merge(Some(call)).unwrap();
+        assert_eq!(merged["PATH"], "/usr/bin");
+        assert_eq!(merged["LANG"], "C");
+
+        assert!(profile.unset("LANG"));
+        assert!(!profile.unset("LANG"));
+        assert_eq!(profile.merge(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let profile = EnvProfile::new();
+        profile.set("API_KEY".to_string(), "s3cr3t".to_string(), true);
+        profile.set("REGION".to_string(), "eu".to_string(), false);
+
+        assert_eq!(profile.redact("key=s3cr3t region=eu"), "key=[REDACTED] region=eu");
+        assert!(matches!(profile.redact("nothing here"), Cow::Borrowed(_)));
+        assert_eq!(
+            profile.list(),
+            vec![
+                ("API_KEY".to_string(), REDACTED.to_string(), true),
+                ("REGION".to_string(), "eu".to_string(), false),
+            ]
+        );
+        assert_eq!(profile.merge(None).unwrap()["API_KEY"], "s3cr3t");
+    }
+
+    #[test]
+    fn names_are_validated() {
+        assert_eq!(invalid_name("HTTP_PROXY"), None);
+        assert!(invalid_name("").is_some());
+        assert!(invalid_name("A=B").is_some());
+        assert!(invalid_name("1PATH").is_some());
+    }
+}
@@ -1,4 +1,4 @@
-use codeagent_common::CodeAgentError;
+use codeagent_common::{CodeAgentError, ErrorCode};
 
 #[derive(Debug, thiserror::Error)]
 pub enum AgentError {
@@ -8,12 +8,27 @@ pub enum AgentError {
     #[error("session already active")]
     SessionAlreadyActive,
 
+    #[error("no session to resume: {reason}")]
+    NoSessionToResume { reason: String },
+
     #[error("invalid working directory: {path}")]
     InvalidWorkingDir { path: String },
 
+    #[error("working directory {path} overlaps {other}, in use by another session")]
+    WorkingDirInUse { path: String, other: String },
+
     #[error("undo directory overlaps with working directory: undo={undo_dir}, working={working_dir}")]
     UndoDirectoryOverlap { working_dir: String, undo_dir: String },
 
+    #[error("invalid clone target {path}: {reason}")]
+    InvalidCloneTarget { path: String, reason: String },
+
+    #[error("undo is disabled for this session")]
+    UndoDisabled,
+
+    #[error("working directory {path} is read-only")]
+    ReadOnlyDirectory { path: String },
+
     #[error("VM not available: QEMU and guest image are not yet built")]
     QemuUnavailable,
 
@@ -23,12 +38,21 @@ pub enum AgentError {
     #[error("control channel connection failed: {reason}")]
     ControlChannelFailed { reason: String },
 
+    #[error("VM state: {reason}")]
+    VmState { reason: String },
+
     #[error("virtiofsd failed: {reason}")]
     VirtioFsFailed { reason: String },
 
+    #[error("mount backend {backend} is not available on this platform: {path}")]
+    UnsupportedBackend { backend: String, path: String },
+
     #[error("not implemented: {feature}")]
     NotImplemented { feature: String },
 
+    #[error("agent backend failed: {reason}")]
+    AgentBackend { reason: String },
+
     #[error(transparent)]
     Undo(#[from] CodeAgentError),
 
@@ -38,3 +62,30 @@ pub enum AgentError {
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 }
+
+impl AgentError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AgentError::SessionNotActive => ErrorCode::SessionNotActive,
+            AgentError::SessionAlreadyActive => ErrorCode::SessionAlreadyActive,
+            AgentError::NoSessionToResume { .. } => ErrorCode::NoSessionToResume,
+            AgentError::InvalidWorkingDir { .. } => ErrorCode::InvalidWorkingDir,
+            AgentError::WorkingDirInUse { .. } => ErrorCode::WorkingDirInUse,
+            AgentError::UndoDirectoryOverlap { .. } => ErrorCode::UndoDirectoryOverlap,
+            AgentError::InvalidCloneTarget { .. } => ErrorCode::InvalidCloneTarget,
+            AgentError::UndoDisabled => ErrorCode::UndoDisabled,
+            AgentError::ReadOnlyDirectory { .. } => ErrorCode::CapabilityUnavailable,
+            AgentError::QemuUnavailable => ErrorCode::QemuUnavailable,
+            AgentError::QemuSpawnFailed { .. } => ErrorCode::QemuSpawnFailed,
+            AgentError::ControlChannelFailed { .. } => ErrorCode::ControlChannelFailed,
+            AgentError::VmState { .. } => ErrorCode::VmState,
+            AgentError::VirtioFsFailed { .. } => ErrorCode::VirtiofsFailed,
+            AgentError::UnsupportedBackend { .. } => ErrorCode::UnsupportedBackend,
+            AgentError::NotImplemented { .. } => ErrorCode::NotImplemented,
+            AgentError::AgentBackend { .. } => ErrorCode::AgentBackendFailed,
+            AgentError::Undo(error) => error.code(),
+            AgentError::FileWatcherFailed { .. } => ErrorCode::FileWatcherFailed,
+            AgentError::Io(_) => ErrorCode::IoError,
+        }
+    }
+}
@@ -1,17 +1,68 @@
 use std::sync::Arc;
 
+use codeagent_common::{BarrierReason, SandboxWarning, StepId};
 use codeagent_control::HandlerEvent;
 use codeagent_control::OutputStream;
-use codeagent_stdio::Event;
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_stdio::{log_debug, log_error, Event};
 use tokio::sync::mpsc;
 
+use crate::command_timeout::CommandTimeouts;
 use crate::command_waiter::CommandWaiter;
+use crate::env_profile::EnvProfile;
+use crate::warnings::WarningReporter;
+
+/// Where the event bridge reports trouble in the guest: a restarted shim,
+/// shares that would not mount.
+pub struct GuestReporting {
+    pub warnings: WarningReporter,
+    /// Each gets a path-less barrier: the lost commands may have been part
+    /// way through writes when the shim died.
+    pub interceptors: Vec<Arc<UndoInterceptor>>,
+}
+
+impl GuestReporting {
+    fn report(&self, event: &HandlerEvent) {
+        if let HandlerEvent::MountsFailed { failed } = event {
+            for failure in failed {
+                self.warnings.report(SandboxWarning::ShareNotMounted {
+                    mount_name: failure.tag.clone(),
+                    reason: failure.reason.clone(),
+                });
+            }
+            return;
+        }
+        let HandlerEvent::ShimRestarted {
+            exit_code,
+            signal,
+            stderr,
+            restarts,
+            lost_steps: _,
+        } = event
+        else {
+            return;
+        };
+        for interceptor in &self.interceptors {
+            if let Err(error) =
+                interceptor.notify_external_modification(vec![], BarrierReason::ShimRestarted)
+            {
+                log_error!("event_bridge", "failed to place shim restart barrier: {error}");
+            }
+        }
+        self.warnings.report(SandboxWarning::ShimRestarted {
+            exit_code: *exit_code,
+            signal: *signal,
+            stderr: stderr.clone(),
+            restarts: *restarts,
+        });
+    }
+}
 
 /// Translates a `HandlerEvent` from the control channel into a STDIO `Event`.
 pub fn translate_handler_event(event: &HandlerEvent) -> Option<Event> {
     match event {
         HandlerEvent::Output {
-            step_id: _,
+            step_id,
             stream,
             data,
         } => {
@@ -20,6 +71,7 @@ pub fn translate_handler_event(event: &HandlerEvent) -> Option<Event> {
                 OutputStream::Stderr => "stderr",
             };
             Some(Event::TerminalOutput {
+                command_id: command_id(*step_id),
                 stream: stream_name.to_string(),
                 data: data.clone(),
             })
@@ -29,22 +81,37 @@ pub fn translate_handler_event(event: &HandlerEvent) -> Option<Event> {
             exit_code,
             evicted_steps: _,
             cancelled: _,
+            output_truncated,
+            limit_exceeded,
         } => Some(Event::StepCompleted {
             step_id: *step_id,
+            command_id: command_id(*step_id),
             affected_paths: vec![],
             exit_code: *exit_code,
+            output_truncated: *output_truncated,
+            limit_exceeded: limit_exceeded.map(|limit| limit.as_str().to_string()),
         }),
         HandlerEvent::ProtocolError { error } => Some(Event::Error {
             code: "control_channel_error".to_string(),
             message: error.clone(),
         }),
-        // Ambient step events are internal bookkeeping, not surfaced to the client.
+        // Ambient step events are internal bookkeeping, not surfaced to the
+        // client; shim restarts and failed mounts are reported as warnings
+        // instead.
         HandlerEvent::StepStarted { .. }
+        | HandlerEvent::ShimRestarted { .. }
+        | HandlerEvent::MountsFailed { .. }
         | HandlerEvent::AmbientStepOpened { .. }
         | HandlerEvent::AmbientStepClosed { .. } => None,
     }
 }
 
+/// Guest commands run in the step with the same ID as the command, so
+/// command steps map straight back to the `command_id` of `agent.execute`.
+fn command_id(step_id: StepId) -> Option<u64> {
+    u64::try_from(step_id).ok().filter(|&id| id > 0)
+}
+
 /// Forward a `HandlerEvent` to the `CommandWaiter` so that synchronous
 /// callers (MCP `Bash` tool) can collect output and wait for completion.
 fn forward_to_command_waiter(event: &HandlerEvent, waiter: &CommandWaiter) {
@@ -65,9 +132,9 @@ fn forward_to_command_waiter(event: &HandlerEvent, waiter: &CommandWaiter) {
             exit_code,
             ..
         } => {
-            eprintln!(
-                "{{\"level\":\"debug\",\"component\":\"event_bridge\",\"message\":\"forwarding StepCompleted step_id={} exit_code={} to command_waiter\"}}",
-                step_id, exit_code
+            log_debug!(
+                "event_bridge",
+                "forwarding StepCompleted step_id={step_id} exit_code={exit_code} to command_waiter"
             );
             waiter.mark_completed(*step_id as u64, *exit_code);
         }
@@ -79,16 +146,38 @@ fn forward_to_command_waiter(event: &HandlerEvent, waiter: &CommandWaiter) {
 /// translated events to the STDIO event stream. Run as a spawned tokio task.
 ///
 /// When a `CommandWaiter` is provided, command output and completion events
-/// are also forwarded to it for synchronous MCP callers.
+/// are also forwarded to it for synchronous MCP callers. Closed command steps
+/// are reported to `command_timeouts`, which stops their timers. Output is
+/// passed through the `env_profile`'s secret redaction first. Shim restarts
+/// become a warning and barriers, and failed mounts warnings, through
+/// `guest_reporting`.
 pub async fn run_event_bridge(
     mut handler_events: mpsc::UnboundedReceiver<HandlerEvent>,
     stdio_event_sender: mpsc::UnboundedSender<Event>,
     command_waiter: Option<Arc<CommandWaiter>>,
+    command_timeouts: Option<Arc<CommandTimeouts>>,
+    env_profile: Option<Arc<EnvProfile>>,
+    guest_reporting: Option<GuestReporting>,
 ) {
-    while let Some(event) = handler_events.recv().await {
+    while let Some(mut event) = handler_events.recv().await {
+        if let (Some(profile), HandlerEvent::Output { data, .. }) = (&env_profile, &mut event) {
+            if let std::borrow::Cow::Owned(redacted) = profile.redact(data) {
+                *data = redacted;
+            }
+        }
         if let Some(waiter) = &command_waiter {
             forward_to_command_waiter(&event, waiter);
         }
+        if let (Some(timeouts), HandlerEvent::StepCompleted { step_id, .. }) =
+            (&command_timeouts, &event)
+        {
+            if let Some(command_id) = command_id(*step_id) {
+                timeouts.step_closed(command_id);
+            }
+        }
+        if let Some(reporting) = &guest_reporting {
+            reporting.report(&event);
+        }
         if let Some(stdio_event) = translate_handler_event(&event) {
             let _ = stdio_event_sender.send(stdio_event);
         }
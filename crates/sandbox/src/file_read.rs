@@ -0,0 +1,263 @@
+//! File reads behind `fs.read`, `fs.stat`, `fs.hash` and the `read_file`
+//! tool.
+//!
+//! A read returns at most [`MAX_READ_LENGTH`] bytes from an offset, so a
+//! large file is fetched in pieces instead of in one response. Text comes
+//! back as is; binary files need `encoding: "base64"`. A UTF-8 read whose
+//! range ends inside a multi-byte character stops before it, so the next
+//! read can start there. `fs.stat` and `fs.hash` tell whether a file changed
+//! without reading it into a response.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use codeagent_common::ReadEncoding;
+use codeagent_common::time;
+use codeagent_stdio::terminal_output::base64_encode;
+use serde::Serialize;
+
+/// Most bytes one read returns.
+pub const MAX_READ_LENGTH: u64 = 1_048_576;
+
+/// What a read returns.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileRange {
+    pub content: String,
+    pub encoding: ReadEncoding,
+    /// Size of the whole file in bytes.
+    pub size: u64,
+    pub offset: u64,
+    /// Bytes of the file `content` holds.
+    pub length: u64,
+    /// Whether the file goes on past the returned range.
+    pub truncated: bool,
+}
+
+/// Read up to `length` bytes (capped at [`MAX_READ_LENGTH`]) of `path`
+/// from `offset`. Fails with [`io::ErrorKind::InvalidData`] if a UTF-8
+/// read meets bytes that are not UTF-8.
+pub fn read_range(
+    path: &Path,
+    encoding: ReadEncoding,
+    offset: u64,
+    length: Option<u64>,
+) -> io::Result<FileRange> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let wanted = length.unwrap_or(MAX_READ_LENGTH).min(MAX_READ_LENGTH);
+    let mut bytes = Vec::new();
+    if offset < size {
+        file.seek(SeekFrom::Start(offset))?;
+        file.take(wanted).read_to_end(&mut bytes)?;
+    }
+    let ends_early = offset.saturating_add(bytes.len() as u64) < size;
+
+    let content = match encoding {
+        ReadEncoding::Base64 => base64_encode(&bytes),
+        ReadEncoding::Utf8 => match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(error) => {
+                let utf8 = error.utf8_error();
+                // A character cut off by the end of the range, not bad bytes.
+                if utf8.error_len().is_none() && ends_early {
+                    let mut bytes = error.into_bytes();
+                    bytes.truncate(utf8.valid_up_to());
+                    String::from_utf8(bytes).expect("truncated to the valid prefix")
+                } else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "not UTF-8 at byte {}; read it with encoding \"base64\"",
+                            offset + utf8.valid_up_to() as u64
+                        ),
+                    ));
+                }
+            }
+        },
+    };
+    let length = match encoding {
+        ReadEncoding::Utf8 => content.len() as u64,
+        ReadEncoding::Base64 => bytes_in_base64(&content),
+    };
+    Ok(FileRange {
+        content,
+        encoding,
+        size,
+        offset,
+        length,
+        truncated: offset.saturating_add(length) < size,
+    })
+}
+
+/// What `fs.stat` reports about a path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileStat {
+    /// `file`, `directory` or `symlink`.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub size: u64,
+    /// Permission bits; `None` where the host has none.
+    pub mode: Option<u32>,
+    pub mtime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlink_target: Option<String>,
+}
+
+/// Describe `path` without following it if it is a symlink.
+pub fn stat(path: &Path) -> io::Result<FileStat> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_dir() {
+        "directory"
+    } else {
+        "file"
+    };
+    #[cfg(unix)]
+    let mode = Some(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777);
+    #[cfg(not(unix))]
+    let mode = None;
+    let symlink_target = if file_type.is_symlink() {
+        Some(std::fs::read_link(path)?.to_string_lossy().into_owned())
+    } else {
+        None
+    };
+    Ok(FileStat {
+        kind,
+        size: metadata.len(),
+        mode,
+        mtime: metadata
+            .modified()
+            .ok()
+            .map(|modified| time::format_timestamp(&modified.into())),
+        symlink_target,
+    })
+}
+
+/// What `fs.hash` reports about a file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileHash {
+    pub algorithm: &'static str,
+    /// Hex digest of the file's content.
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Hash the content of the file at `path` with blake3, streaming it.
+/// Fails with [`io::ErrorKind::IsADirectory`] for a directory.
+pub fn hash(path: &Path) -> io::Result<FileHash> {
+    let metadata = std::fs::metadata(path)?;
+    if metadata.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::IsADirectory,
+            format!("{} is a directory", path.display()),
+        ));
+    }
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(FileHash {
+        algorithm: "blake3",
+        hash: hasher.finalize().to_hex().to_string(),
+        size: metadata.len(),
+    })
+}
+
+fn bytes_in_base64(encoded: &str) -> u64 {
+    let padding = encoded.bytes().rev().take_while(|&b| b == b'=').count();
+    (encoded.len() / 4 * 3 - padding) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_with(bytes: &[u8]) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), bytes).unwrap();
+        file
+    }
+
+    #[test]
+    fn whole_text_file() {
+        let file = file_with(b"hello");
+        let read = read_range(file.path(), ReadEncoding::Utf8, 0, None).unwrap();
+        assert_eq!(read.content, "hello");
+        assert_eq!((read.size, read.length, read.truncated), (5, 5, false));
+    }
+
+    #[test]
+    fn range_reports_truncation() {
+        let file = file_with(b"0123456789");
+        let read = read_range(file.path(), ReadEncoding::Utf8, 2, Some(3)).unwrap();
+        assert_eq!(read.content, "234");
+        assert!(read.truncated);
+
+        let tail = read_range(file.path(), ReadEncoding::Utf8, 7, Some(100)).unwrap();
+        assert_eq!(tail.content, "789");
+        assert!(!tail.truncated);
+
+        let past_end = read_range(file.path(), ReadEncoding::Utf8, 50, None).unwrap();
+        assert_eq!((past_end.content.as_str(), past_end.length), ("", 0));
+    }
+
+    #[test]
+    fn binary_needs_base64() {
+        let file = file_with(&[0x89, b'P', b'N', b'G', 0x00]);
+        let error = read_range(file.path(), ReadEncoding::Utf8, 0, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("byte 0"));
+
+        let read = read_range(file.path(), ReadEncoding::Base64, 0, None).unwrap();
+        assert_eq!(read.content, "iVBORwA=");
+        assert_eq!(read.length, 5);
+    }
+
+    #[test]
+    fn utf8_range_stops_before_a_split_character() {
+        let file = file_with("aé".as_bytes());
+        let read = read_range(file.path(), ReadEncoding::Utf8, 0, Some(2)).unwrap();
+        assert_eq!(read.content, "a");
+        assert_eq!(read.length, 1);
+        assert!(read.truncated);
+    }
+
+    #[test]
+    fn stat_does_not_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "abc").unwrap();
+        let file = stat(&dir.path().join("a.txt")).unwrap();
+        assert_eq!((file.kind, file.size), ("file", 3));
+        assert!(file.mtime.unwrap().ends_with('Z'));
+        assert_eq!(stat(dir.path()).unwrap().kind, "directory");
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("a.txt", dir.path().join("link")).unwrap();
+            let link = stat(&dir.path().join("link")).unwrap();
+            assert_eq!(link.kind, "symlink");
+            assert_eq!(link.symlink_target.as_deref(), Some("a.txt"));
+        }
+    }
+
+    #[test]
+    fn hash_changes_with_content() {
+        let file = file_with(b"one");
+        let first = hash(file.path()).unwrap();
+        assert_eq!(first.hash, blake3::hash(b"one").to_hex().to_string());
+        std::fs::write(file.path(), b"two").unwrap();
+        assert_ne!(hash(file.path()).unwrap().hash, first.hash);
+
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(hash(dir.path()).unwrap_err().kind(), io::ErrorKind::IsADirectory);
+    }
+
+    #[test]
+    fn length_is_capped() {
+        let file = file_with(&vec![b'x'; MAX_READ_LENGTH as usize + 10]);
+        let read = read_range(file.path(), ReadEncoding::Utf8, 0, Some(u64::MAX)).unwrap();
+        assert_eq!(read.length, MAX_READ_LENGTH);
+        assert!(read.truncated);
+    }
+}
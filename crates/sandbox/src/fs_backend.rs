@@ -1,6 +1,9 @@
 use std::path::PathBuf;
 
+use codeagent_stdio::protocol::MountBackend;
+
 use crate::error::AgentError;
+use crate::qemu::FsTransport;
 
 /// Abstraction over the filesystem backend (virtiofsd or 9P server).
 pub trait FilesystemBackend: Send + Sync {
@@ -9,6 +12,40 @@ pub trait FilesystemBackend: Send + Sync {
     fn is_running(&self) -> bool;
 }
 
+/// Whether this build can serve a working directory with `backend`.
+pub fn backend_available(backend: MountBackend) -> bool {
+    match backend {
+        MountBackend::Intercepted | MountBackend::Virtiofs | MountBackend::VirtiofsReadOnly => {
+            cfg!(unix)
+        }
+        MountBackend::P9 => cfg!(target_os = "windows"),
+    }
+}
+
+/// How QEMU attaches the socket of a `backend` mount.
+pub fn transport(backend: MountBackend) -> FsTransport {
+    match backend {
+        MountBackend::Intercepted | MountBackend::Virtiofs | MountBackend::VirtiofsReadOnly => {
+            FsTransport::VhostUser
+        }
+        MountBackend::P9 => FsTransport::P9Serial,
+    }
+}
+
+/// The `backend` reported for the VM as a whole: `virtiofsd` or `9p`, or
+/// `mixed` when the mounts use both transports.
+pub fn vm_backend_name(backends: &[MountBackend]) -> &'static str {
+    let mut transports = backends.iter().map(|backend| transport(*backend));
+    let first = transports.next().unwrap_or_default();
+    if transports.any(|other| other != first) {
+        return "mixed";
+    }
+    match first {
+        FsTransport::VhostUser => "virtiofsd",
+        FsTransport::P9Serial => "9p",
+    }
+}
+
 /// Placeholder backend used when no VM is available.
 pub struct NullBackend;
 
@@ -28,20 +65,22 @@ impl FilesystemBackend for NullBackend {
 
 /// Filesystem backend that spawns a virtiofsd process.
 ///
-/// Available on Linux and macOS only. On Windows, the 9P server
-/// (Phase 3) will serve as the filesystem backend instead.
-///
-/// Currently launches the upstream (unmodified) virtiofsd. When the
-/// forked virtiofsd with `WriteInterceptor` hooks is built, it will
-/// be a drop-in replacement using the same CLI interface.
+/// Available on Linux and macOS only. Launches the upstream (unmodified)
+/// virtiofsd, so writes through it are not intercepted; it serves
+/// `virtiofs` and `virtiofs_read_only` mounts.
 #[cfg(not(target_os = "windows"))]
 pub struct VirtioFsBackend {
     shared_dir: PathBuf,
     socket_path: PathBuf,
     virtiofsd_binary: Option<PathBuf>,
+    read_only: bool,
     child: Option<std::process::Child>,
 }
 
+/// How long virtiofsd has to create its socket after it is spawned.
+#[cfg(not(target_os = "windows"))]
+const VIRTIOFSD_SOCKET_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[cfg(not(target_os = "windows"))]
 impl VirtioFsBackend {
     pub fn new(
@@ -53,10 +92,17 @@ impl VirtioFsBackend {
             shared_dir,
             socket_path,
             virtiofsd_binary,
+            read_only: false,
             child: None,
         }
     }
 
+    /// Export the directory read-only.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
     fn resolve_virtiofsd_binary(&self) -> Result<PathBuf, AgentError> {
         if let Some(path) = &self.virtiofsd_binary {
             return Ok(path.clone());
@@ -80,20 +126,45 @@ impl FilesystemBackend for VirtioFsBackend {
     fn start(&mut self) -> Result<(), AgentError> {
         let binary = self.resolve_virtiofsd_binary()?;
 
-        let child = std::process::Command::new(&binary)
+        let mut command = std::process::Command::new(&binary);
+        command
             .arg("--shared-dir")
             .arg(&self.shared_dir)
             .arg("--socket-path")
             .arg(&self.socket_path)
-            .arg("--cache=never")
+            .arg("--cache=never");
+        if self.read_only {
+            command.arg("--readonly");
+        }
+        let mut child = command
             .stdin(std::process::Stdio::null())
             .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
             .spawn()
             .map_err(|error| AgentError::VirtioFsFailed {
                 reason: format!("failed to start {}: {error}", binary.display()),
             })?;
 
+        // QEMU fails to start if it connects before the socket exists.
+        let deadline = std::time::Instant::now() + VIRTIOFSD_SOCKET_TIMEOUT;
+        while !self.socket_path.exists() {
+            let reason = match child.try_wait() {
+                Ok(Some(status)) => format!("{} exited with {status}", binary.display()),
+                _ if std::time::Instant::now() >= deadline => format!(
+                    "{} did not create {}",
+                    binary.display(),
+                    self.socket_path.display()
+                ),
+                _ => {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    continue;
+                }
+            };
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(AgentError::VirtioFsFailed { reason });
+        }
+
         self.child = Some(child);
         Ok(())
     }
@@ -135,6 +206,7 @@ impl InterceptedBackend {
         socket_path: PathBuf,
         interceptor: std::sync::Arc<dyn codeagent_interceptor::write_interceptor::WriteInterceptor>,
         in_flight: codeagent_control::InFlightTracker,
+        step_attributor: Option<std::sync::Arc<dyn codeagent_common::StepAttributor>>,
     ) -> Self {
         Self {
             inner: codeagent_virtiofs_backend::daemon::InterceptedVirtioFsBackend::new(
@@ -142,9 +214,37 @@ impl InterceptedBackend {
                 socket_path,
                 interceptor,
                 in_flight,
+                step_attributor,
             ),
         }
     }
+
+    /// Serve requests on `workers` threads.
+    pub fn with_request_workers(mut self, workers: usize) -> Self {
+        self.inner.set_request_workers(workers);
+        self
+    }
+
+    /// Refuse every change to the share with `EROFS`.
+    pub fn read_only(mut self) -> Self {
+        self.inner.set_read_only(true);
+        self
+    }
+
+    /// Hide the paths matching the glob `patterns` from the guest.
+    pub fn with_exclusions(mut self, patterns: Vec<String>) -> Self {
+        self.inner.set_exclusions(patterns);
+        self
+    }
+
+    /// Record the operations served into `trace`.
+    pub fn with_trace(
+        mut self,
+        trace: std::sync::Arc<codeagent_common::fs_trace::FsTrace>,
+    ) -> Self {
+        self.inner.set_trace(trace);
+        self
+    }
 }
 
 #[cfg(unix)]
@@ -228,7 +328,7 @@ impl FilesystemBackend for P9Backend {
             let listener = match tokio::net::TcpListener::bind("127.0.0.1:0").await {
                 Ok(l) => l,
                 Err(error) => {
-                    eprintln!("P9Backend: failed to bind listener: {error}");
+                    codeagent_stdio::log_error!("p9", "failed to bind listener: {error}");
                     return;
                 }
             };
@@ -238,7 +338,7 @@ impl FilesystemBackend for P9Backend {
             // not reliably available.
             let addr = listener.local_addr().unwrap();
             if let Err(error) = std::fs::write(&socket_path, addr.to_string()) {
-                eprintln!("P9Backend: failed to write socket address: {error}");
+                codeagent_stdio::log_error!("p9", "failed to write socket address: {error}");
                 return;
             }
 
@@ -250,11 +350,11 @@ impl FilesystemBackend for P9Backend {
                             let (reader, writer) = stream.into_split();
                             let mut server = server;
                             if let Err(error) = server.run(reader, writer).await {
-                                eprintln!("P9Backend: server error: {error}");
+                                codeagent_stdio::log_error!("p9", "server error: {error}");
                             }
                         }
                         Err(error) => {
-                            eprintln!("P9Backend: accept failed: {error}");
+                            codeagent_stdio::log_error!("p9", "accept failed: {error}");
                         }
                     }
                 }
@@ -293,3 +393,20 @@ impl Drop for P9Backend {
         let _ = self.stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directories that do not name a backend get one this build serves and
+    /// whose writes reach the undo log, macOS included.
+    #[test]
+    fn platform_default_backend_records_writes() {
+        let backend = MountBackend::platform_default();
+        assert!(backend_available(backend));
+        assert!(matches!(backend, MountBackend::Intercepted | MountBackend::P9));
+        if cfg!(target_os = "macos") {
+            assert_eq!(backend, MountBackend::Intercepted);
+        }
+    }
+}
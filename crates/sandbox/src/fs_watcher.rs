@@ -7,13 +7,15 @@ use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
-use codeagent_common::{AffectedPath, BarrierReason, FileChangeKind};
-use codeagent_interceptor::gitignore::build_gitignore;
-use ignore::gitignore::Gitignore;
+use codeagent_common::{
+    AffectedPath, BarrierReason, ExternalModificationPolicy, FileChangeKind, SandboxWarning,
+};
+use codeagent_interceptor::gitignore::{GitignoreFilter, is_ignore_source};
 use codeagent_interceptor::undo_interceptor::UndoInterceptor;
 use codeagent_stdio::Event;
 
 use crate::recent_writes::RecentBackendWrites;
+use crate::warnings::WarningReporter;
 
 /// An `AffectedPath` stamped with the instant the OS delivered the event to
 /// our notify callback. Used to compare against the suppression window rather
@@ -23,6 +25,14 @@ struct TimestampedEvent {
     observed_at: Instant,
 }
 
+/// What the notify callback forwards to the watcher loop.
+enum WatcherMessage {
+    Changes(Vec<TimestampedEvent>),
+    /// The OS dropped events (inotify queue overflow, FSEvents rescan), so
+    /// some host changes went unseen.
+    Overflow,
+}
+
 /// Configuration for the filesystem watcher.
 #[derive(Debug, Clone)]
 pub struct FsWatcherConfig {
@@ -34,6 +44,10 @@ pub struct FsWatcherConfig {
     pub enabled: bool,
     /// Whether to respect `.gitignore` rules when filtering external modifications.
     pub use_gitignore: bool,
+    /// Gitignore filters shared with the caller, one per working directory,
+    /// so that `undo.reload_ignores` reaches the watcher. Built here when
+    /// empty and `use_gitignore` is set.
+    pub gitignore_filters: Vec<Arc<GitignoreFilter>>,
 }
 
 impl Default for FsWatcherConfig {
@@ -46,6 +60,7 @@ impl Default for FsWatcherConfig {
             ],
             enabled: true,
             use_gitignore: true,
+            gitignore_filters: Vec::new(),
         }
     }
 }
@@ -59,6 +74,7 @@ pub fn spawn_fs_watcher(
     undo_dirs: Vec<PathBuf>,
     interceptors: Vec<Arc<UndoInterceptor>>,
     recent_writes: Arc<RecentBackendWrites>,
+    warnings: WarningReporter,
     event_sender: mpsc::UnboundedSender<Event>,
     config: FsWatcherConfig,
 ) -> Option<JoinHandle<()>> {
@@ -67,16 +83,16 @@ pub fn spawn_fs_watcher(
     }
 
     // Create a channel to bridge the synchronous notify callback to async tokio.
-    let (bridge_tx, bridge_rx) = std::sync::mpsc::channel::<Vec<TimestampedEvent>>();
+    let (bridge_tx, bridge_rx) = std::sync::mpsc::channel::<WatcherMessage>();
 
     // Build the watcher with a batching event handler.
     let watcher_result = build_watcher(bridge_tx);
     let mut watcher = match watcher_result {
         Ok(w) => w,
         Err(error) => {
-            let _ = event_sender.send(Event::Warning {
-                code: "file_watcher_failed".to_string(),
-                message: format!("Filesystem watcher failed to initialize: {error}"),
+            warnings.report(SandboxWarning::FileWatcherFailed {
+                path: None,
+                reason: error.to_string(),
             });
             return None;
         }
@@ -85,12 +101,9 @@ pub fn spawn_fs_watcher(
     // Watch each working directory recursively.
     for dir in &working_dirs {
         if let Err(error) = watcher.watch(dir, RecursiveMode::Recursive) {
-            let _ = event_sender.send(Event::Warning {
-                code: "file_watcher_failed".to_string(),
-                message: format!(
-                    "Failed to watch directory {}: {error}",
-                    dir.display()
-                ),
+            warnings.report(SandboxWarning::FileWatcherFailed {
+                path: Some(dir.display().to_string()),
+                reason: error.to_string(),
             });
         }
     }
@@ -102,13 +115,15 @@ pub fn spawn_fs_watcher(
         .collect();
 
     // Build gitignore filters per working directory if enabled.
-    let gitignore_filters: Vec<Option<Gitignore>> = if config.use_gitignore {
+    let gitignore_filters: Vec<Arc<GitignoreFilter>> = if !config.use_gitignore {
+        Vec::new()
+    } else if config.gitignore_filters.is_empty() {
         working_dirs
             .iter()
-            .map(|dir| build_gitignore(dir))
+            .map(|dir| Arc::new(GitignoreFilter::build(dir)))
             .collect()
     } else {
-        working_dirs.iter().map(|_| None).collect()
+        config.gitignore_filters.clone()
     };
 
     let exclude_patterns = config.exclude_patterns.clone();
@@ -125,6 +140,7 @@ pub fn spawn_fs_watcher(
             interceptors: &interceptors,
             recent_writes: &recent_writes,
             event_sender: &event_sender,
+            warnings: &warnings,
             undo_dir_prefixes: &undo_dir_prefixes,
             exclude_patterns: &exclude_patterns,
             gitignore_filters: &gitignore_filters,
@@ -140,7 +156,7 @@ pub fn spawn_fs_watcher(
 /// so the suppression check can compare against the event's arrival time
 /// rather than the (later) processing time.
 fn build_watcher(
-    bridge_tx: std::sync::mpsc::Sender<Vec<TimestampedEvent>>,
+    bridge_tx: std::sync::mpsc::Sender<WatcherMessage>,
 ) -> Result<RecommendedWatcher, notify::Error> {
     // Buffer for pairing consecutive From→To rename events (Windows delivers
     // renames as two separate events; Linux inotify uses RenameMode::Both).
@@ -148,6 +164,10 @@ fn build_watcher(
 
     notify::recommended_watcher(move |result: Result<notify::Event, notify::Error>| {
         if let Ok(event) = result {
+            if event.need_rescan() {
+                let _ = bridge_tx.send(WatcherMessage::Overflow);
+                return;
+            }
             // Only care about modification events — not access-only events.
             if is_mutation_event(&event) {
                 let observed_at = Instant::now();
@@ -157,7 +177,7 @@ fn build_watcher(
                         .into_iter()
                         .map(|affected| TimestampedEvent { affected, observed_at })
                         .collect();
-                    let _ = bridge_tx.send(timestamped);
+                    let _ = bridge_tx.send(WatcherMessage::Changes(timestamped));
                 }
             }
         }
@@ -283,15 +303,16 @@ fn is_mutation_event(event: &notify::Event) -> bool {
 
 /// Grouped parameters for `run_watcher_loop` to satisfy clippy's argument limit.
 struct WatcherLoopParams<'a> {
-    bridge_rx: std::sync::mpsc::Receiver<Vec<TimestampedEvent>>,
+    bridge_rx: std::sync::mpsc::Receiver<WatcherMessage>,
     debounce: Duration,
     working_dirs: &'a [PathBuf],
     interceptors: &'a [Arc<UndoInterceptor>],
     recent_writes: &'a RecentBackendWrites,
     event_sender: &'a mpsc::UnboundedSender<Event>,
+    warnings: &'a WarningReporter,
     undo_dir_prefixes: &'a [String],
     exclude_patterns: &'a [String],
-    gitignore_filters: &'a [Option<Gitignore>],
+    gitignore_filters: &'a [Arc<GitignoreFilter>],
 }
 
 /// Main watcher loop: reads events from the bridge channel, accumulates them,
@@ -305,17 +326,18 @@ async fn run_watcher_loop(params: WatcherLoopParams<'_>) {
         interceptors,
         recent_writes,
         event_sender,
+        warnings,
         undo_dir_prefixes,
         exclude_patterns,
         gitignore_filters,
     } = params;
     // Use a tokio mpsc to forward from blocking recv to async select.
-    let (async_tx, mut async_rx) = mpsc::unbounded_channel::<Vec<TimestampedEvent>>();
+    let (async_tx, mut async_rx) = mpsc::unbounded_channel::<WatcherMessage>();
 
     // Spawn a blocking task that reads from the sync channel and forwards.
     let _reader = tokio::task::spawn_blocking(move || {
-        while let Ok(entries) = bridge_rx.recv() {
-            if async_tx.send(entries).is_err() {
+        while let Ok(message) = bridge_rx.recv() {
+            if async_tx.send(message).is_err() {
                 break;
             }
         }
@@ -323,6 +345,7 @@ async fn run_watcher_loop(params: WatcherLoopParams<'_>) {
 
     let mut pending: Vec<TimestampedEvent> = Vec::new();
     let mut pending_seen: HashSet<PathBuf> = HashSet::new();
+    let mut overflowed = false;
     let mut debounce_timer = tokio::time::interval(debounce);
     debounce_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
     // Skip the immediate first tick.
@@ -335,7 +358,14 @@ async fn run_watcher_loop(params: WatcherLoopParams<'_>) {
 
     loop {
         tokio::select! {
-            Some(entries) = async_rx.recv() => {
+            Some(message) = async_rx.recv() => {
+                let entries = match message {
+                    WatcherMessage::Changes(entries) => entries,
+                    WatcherMessage::Overflow => {
+                        overflowed = true;
+                        continue;
+                    }
+                };
                 for te in entries {
                     if pending_seen.contains(&te.affected.path) {
                         // Same path seen again in this debounce window — keep the
@@ -358,6 +388,15 @@ async fn run_watcher_loop(params: WatcherLoopParams<'_>) {
                 // each event resets the timer.
             }
             _ = debounce_timer.tick() => {
+                if overflowed {
+                    // Whatever was lost may include the paths still pending;
+                    // the blanket barrier covers them too.
+                    overflowed = false;
+                    pending.clear();
+                    pending_seen.clear();
+                    report_overflow(interceptors, warnings, event_sender);
+                    continue;
+                }
                 if pending.is_empty() {
                     continue;
                 }
@@ -391,7 +430,7 @@ struct ProcessParams<'a> {
     event_sender: &'a mpsc::UnboundedSender<Event>,
     undo_dir_prefixes: &'a [String],
     exclude_patterns: &'a [String],
-    gitignore_filters: &'a [Option<Gitignore>],
+    gitignore_filters: &'a [Arc<GitignoreFilter>],
 }
 
 /// Process accumulated paths: filter, group by working dir, and emit events.
@@ -409,6 +448,8 @@ fn process_pending_paths(
         gitignore_filters,
     } = params;
 
+    reload_changed_ignores(pending, working_dirs, gitignore_filters, event_sender);
+
     // Group external paths by working directory index.
     let mut per_dir: Vec<Vec<AffectedPath>> = vec![vec![]; working_dirs.len()];
 
@@ -460,11 +501,11 @@ fn process_pending_paths(
         for (index, working_dir) in working_dirs.iter().enumerate() {
             if ap.path.starts_with(working_dir) {
                 // Check gitignore rules for this working directory.
-                if let Some(Some(filter)) = gitignore_filters.get(index) {
+                if let Some(filter) = gitignore_filters.get(index) {
                     if let Ok(relative) = ap.path.strip_prefix(working_dir) {
                         let relative_str = relative.to_string_lossy().replace('\\', "/");
                         let is_dir = ap.path.is_dir();
-                        if filter.matched_path_or_any_parents(&relative_str, is_dir).is_ignore() {
+                        if filter.is_ignored(&relative_str, is_dir) {
                             record_ancestors(&ap.path, &mut excluded_ancestors);
                             break;
                         }
@@ -491,7 +532,13 @@ fn process_pending_paths(
     }
 
     // Create barriers and emit events for each working directory with external changes.
-    for (index, external_paths) in per_dir.into_iter().enumerate() {
+    for (index, mut external_paths) in per_dir.into_iter().enumerate() {
+        // Paths under an `ignore` rule are neither recorded nor reported.
+        if let Some(interceptor) = interceptors.get(index) {
+            external_paths.retain(|ap| {
+                interceptor.external_modification_policy(ap) != ExternalModificationPolicy::Ignore
+            });
+        }
         if external_paths.is_empty() {
             continue;
         }
@@ -530,6 +577,63 @@ fn process_pending_paths(
     }
 }
 
+/// Rebuild the gitignore filter of each working directory whose ignore files
+/// are among the pending changes, before those changes are filtered. Writes
+/// made through the sandbox count as well: the rules change either way.
+fn reload_changed_ignores(
+    pending: &[TimestampedEvent],
+    working_dirs: &[PathBuf],
+    gitignore_filters: &[Arc<GitignoreFilter>],
+    event_sender: &mpsc::UnboundedSender<Event>,
+) {
+    for (working_dir, filter) in working_dirs.iter().zip(gitignore_filters) {
+        let mut sources: Vec<String> = pending
+            .iter()
+            .flat_map(|te| std::iter::once(&te.affected.path).chain(&te.affected.renamed_from))
+            .filter_map(|path| path.strip_prefix(working_dir).ok())
+            .map(|relative| relative.to_string_lossy().replace('\\', "/"))
+            .filter(|relative| is_ignore_source(relative))
+            .collect();
+        if sources.is_empty() {
+            continue;
+        }
+        sources.sort();
+        sources.dedup();
+        filter.reload();
+        let _ = event_sender.send(Event::IgnoresReloaded {
+            working_dir: working_dir.display().to_string(),
+            sources,
+        });
+    }
+}
+
+/// Record that host changes may have gone unseen in every working directory.
+///
+/// The affected paths are unknown, so each directory gets a path-less
+/// modification that follows its default external modification policy.
+fn report_overflow(
+    interceptors: &[Arc<UndoInterceptor>],
+    warnings: &WarningReporter,
+    event_sender: &mpsc::UnboundedSender<Event>,
+) {
+    warnings.report(SandboxWarning::FileWatcherOverflow);
+    for interceptor in interceptors {
+        if interceptor.external_modification_default_policy() == ExternalModificationPolicy::Ignore {
+            continue;
+        }
+        let barrier_id = match interceptor
+            .notify_external_modification(vec![], BarrierReason::ExternalModification)
+        {
+            Ok(Some(barrier)) => Some(barrier.barrier_id),
+            _ => None,
+        };
+        let _ = event_sender.send(Event::ExternalModification {
+            affected_paths: vec![],
+            barrier_id,
+        });
+    }
+}
+
 /// Format a `FileChangeKind` as a human-readable string for display.
 fn format_change_kind(kind: FileChangeKind) -> &'static str {
     match kind {
@@ -912,4 +1016,47 @@ mod tests {
         // Here both share /workspace/.git/objects as parent, so no new entries.
         assert_eq!(ancestors.len(), count_after_first);
     }
+
+    #[test]
+    fn overflow_places_barrier_per_directory_policy() {
+        use codeagent_interceptor::undo_interceptor::UndoConfig;
+
+        let working = tempfile::tempdir().unwrap();
+        let undo = tempfile::tempdir().unwrap();
+        let interceptor = |name: &str, policy: ExternalModificationPolicy| {
+            std::fs::create_dir(working.path().join(name)).unwrap();
+            Arc::new(UndoInterceptor::new(
+                working.path().join(name),
+                undo.path().join(name),
+                UndoConfig {
+                    external_modification: policy.into(),
+                    ..Default::default()
+                },
+            ))
+        };
+        let interceptors = vec![
+            interceptor("barrier", ExternalModificationPolicy::Barrier),
+            interceptor("ignore", ExternalModificationPolicy::Ignore),
+        ];
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+
+        report_overflow(
+            &interceptors,
+            &WarningReporter::new(event_sender.clone()),
+            &event_sender,
+        );
+
+        assert_eq!(interceptors[0].barriers().len(), 1);
+        assert!(interceptors[1].barriers().is_empty());
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            Event::Warning { warning: SandboxWarning::FileWatcherOverflow }
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            Event::ExternalModification { ref affected_paths, barrier_id: Some(_) }
+                if affected_paths.is_empty()
+        ));
+        assert!(events.try_recv().is_err());
+    }
 }
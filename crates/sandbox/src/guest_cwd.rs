@@ -0,0 +1,129 @@
+//! Where in the guest a command runs.
+//!
+//! Working directory `i` is mounted at `/mnt/working/{mount_names[i]}`. A
+//! `cwd` given to `agent.execute` may be relative to the selected working
+//! directory, a host path inside any working directory, or a guest path
+//! under one of the mounts; all three are turned into a guest path, and one
+//! that ends up outside every mount is rejected.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Where working directories are mounted in the guest.
+pub const GUEST_MOUNT_ROOT: &str = "/mnt/working";
+
+/// The guest mount point of the working directory named `mount_name`.
+pub fn mount_point(mount_name: &str) -> String {
+    format!("{GUEST_MOUNT_ROOT}/{mount_name}")
+}
+
+/// The guest directory for `cwd`, with working directory `index` selected.
+/// `working_dirs` and `mount_names` are the session's, in the same order.
+pub fn resolve(
+    working_dirs: &[PathBuf],
+    mount_names: &[String],
+    index: usize,
+    cwd: Option<&str>,
+) -> Result<String, String> {
+    let Some(selected) = mount_names.get(index) else {
+        return Err(format!("directory index {index} out of range"));
+    };
+    let Some(cwd) = cwd.filter(|cwd| !cwd.is_empty()) else {
+        return Ok(mount_point(selected));
+    };
+
+    if let Some(inside) = cwd.strip_prefix(GUEST_MOUNT_ROOT).and_then(|rest| rest.strip_prefix('/')) {
+        let (name, rest) = inside.split_once('/').unwrap_or((inside, ""));
+        if mount_names.iter().any(|mount_name| mount_name == name) {
+            return within(name, rest.split('/'));
+        }
+    }
+    let host = Path::new(cwd);
+    // A guest-style path is absolute even where host paths need a drive.
+    if host.is_absolute() || cwd.starts_with('/') {
+        for (working_dir, name) in working_dirs.iter().zip(mount_names) {
+            if let Ok(relative) = host.strip_prefix(working_dir) {
+                return within(name, components(relative)?);
+            }
+        }
+        return Err(format!("{cwd} is outside every working directory"));
+    }
+    within(selected, components(host)?)
+}
+
+/// The guest path of `relative` under mount `name`, or an error if its `..`
+/// components climb out of the mount.
+fn within<'a>(name: &str, relative: impl IntoIterator<Item = &'a str>) -> Result<String, String> {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in relative {
+        match part {
+            "" | "." => {}
+            ".." => {
+                if parts.pop().is_none() {
+                    return Err(format!("escapes the mount of working directory {name}"));
+                }
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut path = mount_point(name);
+    for part in parts {
+        path.push('/');
+        path.push_str(part);
+    }
+    Ok(path)
+}
+
+/// The components of a relative host path as guest path segments.
+fn components(path: &Path) -> Result<Vec<&str>, String> {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(part) => part
+                .to_str()
+                .ok_or_else(|| format!("{} is not valid UTF-8", path.display())),
+            Component::CurDir => Ok("."),
+            Component::ParentDir => Ok(".."),
+            Component::RootDir | Component::Prefix(_) => Ok(""),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> (Vec<PathBuf>, Vec<String>) {
+        let root = std::env::temp_dir();
+        (
+            vec![root.join("app"), root.join("lib")],
+            vec!["app".to_string(), "lib".to_string()],
+        )
+    }
+
+    #[test]
+    fn relative_cwds_follow_the_selected_directory() {
+        let (dirs, names) = session();
+        assert_eq!(resolve(&dirs, &names, 0, None).unwrap(), "/mnt/working/app");
+        assert_eq!(resolve(&dirs, &names, 1, Some("src/./x")).unwrap(), "/mnt/working/lib/src/x");
+        assert_eq!(resolve(&dirs, &names, 1, Some("src/..")).unwrap(), "/mnt/working/lib");
+        assert!(resolve(&dirs, &names, 0, Some("../lib")).is_err());
+        assert!(resolve(&dirs, &names, 2, None).is_err());
+    }
+
+    #[test]
+    fn absolute_cwds_pick_their_own_mount() {
+        let (dirs, names) = session();
+        let host = dirs[1].join("tests");
+        assert_eq!(
+            resolve(&dirs, &names, 0, host.to_str()).unwrap(),
+            "/mnt/working/lib/tests"
+        );
+        assert_eq!(
+            resolve(&dirs, &names, 0, Some("/mnt/working/lib/a/../b")).unwrap(),
+            "/mnt/working/lib/b"
+        );
+        assert!(resolve(&dirs, &names, 0, Some("/mnt/working/lib/../../etc")).is_err());
+        assert!(resolve(&dirs, &names, 0, Some("/mnt/working/other")).is_err());
+        let outside = std::env::temp_dir().join("elsewhere");
+        assert!(resolve(&dirs, &names, 0, outside.to_str()).is_err());
+    }
+}
@@ -0,0 +1,434 @@
+//! Readiness and liveness probes for supervised deployments.
+//!
+//! With `--health-socket <path>`, the sandbox serves a line-based probe
+//! endpoint: a client writes `ready` or `live` followed by a newline and
+//! receives one JSON line describing the result. `sandbox --health-socket
+//! <path> --health-probe <ready|live>` is the matching client, suitable for
+//! Kubernetes-style exec probes; it exits with [`EXIT_HEALTHY`],
+//! [`EXIT_UNHEALTHY`] or [`EXIT_UNREACHABLE`].
+//!
+//! - **ready**: a session is active, its VM process is running, and the
+//!   control channel reader and writer tasks are alive.
+//! - **live**: the async runtime is responsive, i.e. the heartbeat task has
+//!   ticked within [`LIVENESS_STALE_AFTER`].
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use codeagent_stdio::{log_error, log_info, log_warn};
+
+/// Probe succeeded.
+pub const EXIT_HEALTHY: i32 = 0;
+/// The sandbox answered that it is not ready/live, or did not answer in time.
+pub const EXIT_UNHEALTHY: i32 = 1;
+/// The health socket could not be reached (sandbox not running). Exit code 2
+/// is left to clap for command-line usage errors.
+pub const EXIT_UNREACHABLE: i32 = 3;
+
+/// How often the heartbeat task ticks.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// A heartbeat older than this fails the liveness probe.
+pub const LIVENESS_STALE_AFTER: Duration = Duration::from_secs(10);
+/// How long a probe connection may take to send its request line.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which check a probe request asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeKind {
+    Ready,
+    Live,
+}
+
+impl FromStr for ProbeKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "ready" => Ok(ProbeKind::Ready),
+            "live" => Ok(ProbeKind::Live),
+            other => Err(format!("unknown probe '{other}' (expected 'ready' or 'live')")),
+        }
+    }
+}
+
+impl ProbeKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ProbeKind::Ready => "ready",
+            ProbeKind::Live => "live",
+        }
+    }
+}
+
+/// Result of the readiness checks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Readiness {
+    pub vm_booted: bool,
+    pub control_channel_up: bool,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.vm_booted && self.control_channel_up
+    }
+}
+
+/// Supplies the readiness checks (implemented over the orchestrator's session state).
+pub trait ReadinessSource: Send + Sync {
+    fn readiness(&self) -> Readiness;
+}
+
+/// Timestamp of the last tick of a runtime task, used for liveness.
+pub struct Heartbeat {
+    started: Instant,
+    last_tick_ms: AtomicU64,
+}
+
+impl Heartbeat {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            started: Instant::now(),
+            last_tick_ms: AtomicU64::new(0),
+        })
+    }
+
+    pub fn tick(&self) {
+        let now_ms = self.started.elapsed().as_millis() as u64;
+        self.last_tick_ms.store(now_ms, Ordering::Relaxed);
+    }
+
+    /// Time since the last tick.
+    pub fn age(&self) -> Duration {
+        let last = Duration::from_millis(self.last_tick_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    /// Spawn a task on the current runtime that ticks every `interval`.
+    pub fn spawn(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let heartbeat = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                heartbeat.tick();
+            }
+        })
+    }
+}
+
+/// Evaluate one probe and build its JSON response.
+pub fn evaluate(
+    kind: ProbeKind,
+    source: &dyn ReadinessSource,
+    heartbeat: &Heartbeat,
+    stale_after: Duration,
+) -> serde_json::Value {
+    match kind {
+        ProbeKind::Ready => {
+            let readiness = source.readiness();
+            json!({
+                "probe": kind,
+                "healthy": readiness.is_ready(),
+                "vm_booted": readiness.vm_booted,
+                "control_channel_up": readiness.control_channel_up,
+            })
+        }
+        ProbeKind::Live => {
+            let age = heartbeat.age();
+            json!({
+                "probe": kind,
+                "healthy": age < stale_after,
+                "heartbeat_age_ms": age.as_millis() as u64,
+            })
+        }
+    }
+}
+
+/// Run the health probe endpoint.
+///
+/// On Unix: listens on a Unix domain socket at `socket_path`.
+/// On Windows: listens on TCP `127.0.0.1` and writes the port to `socket_path`,
+/// like the side-channel MCP socket.
+///
+/// Runs until `shutdown` receives a value or the task is dropped.
+pub async fn run_health_server(
+    socket_path: PathBuf,
+    source: Arc<dyn ReadinessSource>,
+    heartbeat: Arc<Heartbeat>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    #[cfg(unix)]
+    let listener = {
+        let _ = std::fs::remove_file(&socket_path);
+        if let Some(parent) = socket_path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        match tokio::net::UnixListener::bind(&socket_path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log_error!(
+                    "health",
+                    "failed to bind health socket {}: {e}",
+                    socket_path.display()
+                );
+                return;
+            }
+        }
+    };
+
+    #[cfg(windows)]
+    let listener = {
+        let listener = match tokio::net::TcpListener::bind("127.0.0.1:0").await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log_error!("health", "failed to bind health socket: {e}");
+                return;
+            }
+        };
+        let port = match listener.local_addr() {
+            Ok(addr) => addr.port(),
+            Err(e) => {
+                log_error!("health", "failed to get health socket address: {e}");
+                return;
+            }
+        };
+        if let Some(parent) = socket_path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        if let Err(e) = std::fs::write(&socket_path, port.to_string()) {
+            log_error!(
+                "health",
+                "failed to write health port file {}: {e}",
+                socket_path.display()
+            );
+            return;
+        }
+        listener
+    };
+
+    log_info!("health", "health probes listening on {}", socket_path.display());
+
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            result = listener.accept() => {
+                match result {
+                    Ok((stream, _addr)) => {
+                        let source = Arc::clone(&source);
+                        let heartbeat = Arc::clone(&heartbeat);
+                        tokio::spawn(async move {
+                            let (reader, writer) = tokio::io::split(stream);
+                            handle_probe(reader, writer, source, heartbeat).await;
+                        });
+                    }
+                    Err(e) => {
+                        log_warn!("health", "health socket accept error: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+async fn handle_probe<R, W>(
+    reader: R,
+    mut writer: W,
+    source: Arc<dyn ReadinessSource>,
+    heartbeat: Arc<Heartbeat>,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut line = String::new();
+    let mut reader = BufReader::new(reader);
+    let read = tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut line)).await;
+    if !matches!(read, Ok(Ok(_))) {
+        return;
+    }
+
+    let response = match line.parse::<ProbeKind>() {
+        // The readiness check takes the session lock, which may be held by a
+        // long-running request; keep it off the async workers.
+        Ok(kind) => tokio::task::spawn_blocking(move || {
+            evaluate(kind, source.as_ref(), &heartbeat, LIVENESS_STALE_AFTER)
+        })
+        .await
+        .unwrap_or_else(|e| json!({ "probe": kind, "healthy": false, "error": e.to_string() })),
+        Err(message) => json!({ "healthy": false, "error": message }),
+    };
+
+    let _ = writer.write_all(format!("{response}\n").as_bytes()).await;
+    let _ = writer.flush().await;
+}
+
+/// Send one probe to a running sandbox and map the answer to an exit code.
+///
+/// The response line is printed to stdout for the supervisor's logs.
+pub fn probe(socket_path: &Path, kind: ProbeKind, timeout: Duration) -> i32 {
+    use std::io::{BufRead, Write};
+
+    #[cfg(unix)]
+    let stream = std::os::unix::net::UnixStream::connect(socket_path).and_then(|stream| {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(stream)
+    });
+
+    #[cfg(windows)]
+    let stream = std::fs::read_to_string(socket_path)
+        .and_then(|port| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        })
+        .and_then(|port| {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            std::net::TcpStream::connect_timeout(&addr, timeout)
+        })
+        .and_then(|stream| {
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            Ok(stream)
+        });
+
+    let mut stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!(
+                "health socket {} unreachable: {e}",
+                socket_path.display()
+            );
+            return EXIT_UNREACHABLE;
+        }
+    };
+
+    if let Err(e) = writeln!(stream, "{}", kind.as_str()) {
+        eprintln!("failed to send {} probe: {e}", kind.as_str());
+        return EXIT_UNHEALTHY;
+    }
+
+    let mut line = String::new();
+    if let Err(e) = std::io::BufReader::new(&stream).read_line(&mut line) {
+        eprintln!("no answer to {} probe: {e}", kind.as_str());
+        return EXIT_UNHEALTHY;
+    }
+    println!("{}", line.trim_end());
+
+    let healthy = serde_json::from_str::<serde_json::Value>(&line)
+        .ok()
+        .and_then(|response| response["healthy"].as_bool())
+        .unwrap_or(false);
+    if healthy { EXIT_HEALTHY } else { EXIT_UNHEALTHY }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct FixedReadiness(Mutex<Readiness>);
+
+    impl ReadinessSource for FixedReadiness {
+        fn readiness(&self) -> Readiness {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn probe_kind_parses_request_lines() {
+        assert_eq!("ready\n".parse::<ProbeKind>(), Ok(ProbeKind::Ready));
+        assert_eq!("live".parse::<ProbeKind>(), Ok(ProbeKind::Live));
+        assert!("status".parse::<ProbeKind>().is_err());
+    }
+
+    #[test]
+    fn readiness_requires_vm_and_control_channel() {
+        let source = FixedReadiness(Mutex::new(Readiness {
+            vm_booted: true,
+            control_channel_up: false,
+        }));
+        let heartbeat = Heartbeat::new();
+
+        let response = evaluate(ProbeKind::Ready, &source, &heartbeat, LIVENESS_STALE_AFTER);
+        assert_eq!(response["healthy"], false);
+        assert_eq!(response["vm_booted"], true);
+
+        source.0.lock().unwrap().control_channel_up = true;
+        let response = evaluate(ProbeKind::Ready, &source, &heartbeat, LIVENESS_STALE_AFTER);
+        assert_eq!(response["healthy"], true);
+    }
+
+    #[test]
+    fn stale_heartbeat_fails_liveness() {
+        let source = FixedReadiness(Mutex::new(Readiness::default()));
+        let heartbeat = Heartbeat::new();
+        heartbeat.tick();
+
+        let response = evaluate(ProbeKind::Live, &source, &heartbeat, LIVENESS_STALE_AFTER);
+        assert_eq!(response["healthy"], true);
+
+        std::thread::sleep(Duration::from_millis(20));
+        let response = evaluate(ProbeKind::Live, &source, &heartbeat, Duration::from_millis(10));
+        assert_eq!(response["healthy"], false);
+        assert!(response["heartbeat_age_ms"].as_u64().unwrap() >= 10);
+    }
+
+    #[test]
+    fn unreachable_socket_exit_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let code = probe(&dir.path().join("missing.sock"), ProbeKind::Live, Duration::from_secs(1));
+        assert_eq!(code, EXIT_UNREACHABLE);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn health_server_answers_probes() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("health.sock");
+        let source = Arc::new(FixedReadiness(Mutex::new(Readiness::default())));
+        let heartbeat = Heartbeat::new();
+        let _ticker = heartbeat.spawn(Duration::from_millis(10));
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let server = tokio::spawn(run_health_server(
+            socket_path.clone(),
+            source.clone(),
+            Arc::clone(&heartbeat),
+            shutdown_rx,
+        ));
+        for _ in 0..50 {
+            if socket_path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let run_probe = |kind| {
+            let path = socket_path.clone();
+            tokio::task::spawn_blocking(move || probe(&path, kind, Duration::from_secs(2)))
+        };
+        assert_eq!(run_probe(ProbeKind::Live).await.unwrap(), EXIT_HEALTHY);
+        assert_eq!(run_probe(ProbeKind::Ready).await.unwrap(), EXIT_UNHEALTHY);
+
+        *source.0.lock().unwrap() = Readiness {
+            vm_booted: true,
+            control_channel_up: true,
+        };
+        assert_eq!(run_probe(ProbeKind::Ready).await.unwrap(), EXIT_HEALTHY);
+
+        let _ = shutdown_tx.send(true);
+        let _ = tokio::time::timeout(Duration::from_secs(2), server).await;
+        assert!(!socket_path.exists());
+    }
+}
@@ -0,0 +1,147 @@
+//! Plain-text rendering of undo history for `undo.history { format: "text" }`.
+//!
+//! Terminal frontends print the table as-is instead of each formatting the
+//! step list themselves.
+
+use codeagent_common::{StepInfo, StepType};
+
+/// Longest command shown before it is cut off with an ellipsis.
+const MAX_COMMAND_WIDTH: usize = 48;
+
+const HEADERS: [&str; 6] = ["STEP", "TIME", "TYPE", "COMMAND", "FILES", "SIZE"];
+
+/// Columns aligned to the right (numbers).
+const RIGHT_ALIGNED: [bool; 6] = [true, false, false, false, true, true];
+
+/// Render steps as an aligned table, one step per line after a header row.
+/// Times are UTC. An empty history renders as a single explanatory line.
+pub fn render_history_table(steps: &[StepInfo]) -> String {
+    if steps.is_empty() {
+        return "No undo history.\n".to_string();
+    }
+
+    let rows: Vec<[String; 6]> = steps.iter().map(row).collect();
+    let mut widths = HEADERS.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    push_line(&mut out, &HEADERS.map(String::from), &widths);
+    for row in &rows {
+        push_line(&mut out, row, &widths);
+    }
+    out
+}
+
+fn row(step: &StepInfo) -> [String; 6] {
+    let kind = match step.step_type {
+        StepType::Command => "command",
+        StepType::Ambient => "ambient",
+        StepType::Api => "api",
+    };
+    let command = match step.command.as_deref() {
+        Some(command) => truncate(command.lines().next().unwrap_or_default()),
+        None => "-".to_string(),
+    };
+    [
+        step.id.to_string(),
+        step.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+        kind.to_string(),
+        command,
+        step.file_count.to_string(),
+        format_size(step.preimage_bytes),
+    ]
+}
+
+fn push_line(out: &mut String, cells: &[String; 6], widths: &[usize; 6]) {
+    let mut line = String::new();
+    for (i, cell) in cells.iter().enumerate() {
+        if i > 0 {
+            line.push_str("  ");
+        }
+        let pad = widths[i] - cell.chars().count();
+        if RIGHT_ALIGNED[i] {
+            line.extend(std::iter::repeat_n(' ', pad));
+            line.push_str(cell);
+        } else {
+            line.push_str(cell);
+            line.extend(std::iter::repeat_n(' ', pad));
+        }
+    }
+    out.push_str(line.trim_end());
+    out.push('\n');
+}
+
+fn truncate(command: &str) -> String {
+    if command.chars().count() <= MAX_COMMAND_WIDTH {
+        return command.to_string();
+    }
+    let mut cut: String = command.chars().take(MAX_COMMAND_WIDTH - 1).collect();
+    cut.push('…');
+    cut
+}
+
+/// Format a byte count with a binary unit, e.g. `512 B` or `1.5 KiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn step(id: i64, step_type: StepType, command: Option<&str>, preimage_bytes: u64) -> StepInfo {
+        StepInfo {
+            id,
+            step_type,
+            timestamp: chrono::Utc.with_ymd_and_hms(2026, 3, 1, 9, 30, 0).unwrap(),
+            command: command.map(String::from),
+            affected_paths: Vec::new(),
+            duration_ms: None,
+            exit_code: None,
+            file_count: 3,
+            preimage_bytes,
+        }
+    }
+
+    #[test]
+    fn columns_are_aligned() {
+        let table = render_history_table(&[
+            step(1, StepType::Command, Some("cargo build"), 512),
+            step(12, StepType::Api, None, 1536),
+        ]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "STEP  TIME                 TYPE     COMMAND      FILES     SIZE");
+        assert_eq!(lines[1], "   1  2026-03-01 09:30:00  command  cargo build      3    512 B");
+        assert_eq!(lines[2], "  12  2026-03-01 09:30:00  api      -                3  1.5 KiB");
+    }
+
+    #[test]
+    fn long_commands_are_truncated_to_their_first_line() {
+        let long = format!("{}\necho done", "x".repeat(80));
+        let table = render_history_table(&[step(1, StepType::Command, Some(&long), 0)]);
+        let row = table.lines().nth(1).unwrap();
+        assert!(row.contains(&format!("{}…", "x".repeat(MAX_COMMAND_WIDTH - 1))));
+        assert!(!row.contains("echo"));
+    }
+
+    #[test]
+    fn empty_history() {
+        assert_eq!(render_history_table(&[]), "No undo history.\n");
+    }
+}
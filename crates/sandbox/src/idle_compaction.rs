@@ -0,0 +1,271 @@
+//! Compaction of the undo log while the session is idle.
+//!
+//! Preimages are captured at a fast compression level so steps stay quick.
+//! Once the session has had no open step and no filesystem operation in
+//! flight for `[compaction] idle_seconds`, this task compacts the undo log
+//! of each working directory in turn: old steps are recompressed at a
+//! higher level, identical blobs linked together and every checksum
+//! verified (see `codeagent_interceptor::compaction`). It emits
+//! `event.compaction_progress` as it goes and `event.compaction` when a
+//! directory is done.
+//!
+//! A step opening pauses the run after the file being compacted; the next
+//! idle period resumes it. A run that finished is not repeated until the
+//! session has been busy again.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use codeagent_common::OperationMonitor;
+use codeagent_control::Clock;
+use codeagent_interceptor::compaction::CompactionOptions;
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_stdio::{log_warn, Event};
+use tokio::sync::mpsc;
+
+use crate::config::CompactionConfig;
+
+/// How often the task checks whether the session is idle.
+pub const COMPACTION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the session is doing, as far as compaction is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    Idle,
+    /// A step is open or a filesystem operation is in flight.
+    Busy,
+    /// The session stopped.
+    Gone,
+}
+
+/// The session whose undo logs the task compacts.
+pub trait CompactionHost: Send + Sync + 'static {
+    /// Checked between files while compacting, so it must not block.
+    fn activity(&self) -> Activity;
+
+    /// The undo logs to compact, with the working directory of each.
+    fn undo_logs(&self) -> Vec<(String, Arc<UndoInterceptor>)>;
+}
+
+/// Compact the undo logs of `host` whenever it has been idle for
+/// `config.idle_seconds`, until it is gone.
+pub async fn run_idle_compaction(
+    host: Arc<dyn CompactionHost>,
+    clock: Arc<dyn Clock>,
+    event_sender: mpsc::UnboundedSender<Event>,
+    config: CompactionConfig,
+) {
+    let idle_after = Duration::from_secs(config.idle_seconds);
+    let mut idle_for = Duration::ZERO;
+    // Whether the last run finished with nothing since to compact.
+    let mut settled = false;
+    loop {
+        clock.sleep(COMPACTION_POLL_INTERVAL).await;
+        match host.activity() {
+            Activity::Gone => return,
+            Activity::Busy => {
+                idle_for = Duration::ZERO;
+                settled = false;
+                continue;
+            }
+            Activity::Idle => idle_for += COMPACTION_POLL_INTERVAL,
+        }
+        if settled || idle_for < idle_after {
+            continue;
+        }
+
+        let runner = Arc::clone(&host);
+        let sender = event_sender.clone();
+        let options = config.options();
+        settled =
+            tokio::task::spawn_blocking(move || compact_all(&*runner, &options, &sender))
+                .await
+                .unwrap_or(false);
+        idle_for = Duration::ZERO;
+    }
+}
+
+/// Compact each undo log of `host`. Returns false if a run paused.
+fn compact_all(
+    host: &dyn CompactionHost,
+    options: &CompactionOptions,
+    event_sender: &mpsc::UnboundedSender<Event>,
+) -> bool {
+    for (working_dir, interceptor) in host.undo_logs() {
+        let monitor = PauseWhenBusy {
+            host,
+            event_sender,
+            working_dir: &working_dir,
+            last_percent: AtomicU8::new(u8::MAX),
+        };
+        let report = match interceptor.compact(options, &monitor) {
+            Ok(report) => report,
+            Err(error) => {
+                log_warn!("compaction", "cannot compact the undo log of {working_dir}: {error}");
+                continue;
+            }
+        };
+        if report.steps_compacted > 0 || report.paused || !report.corrupt_paths.is_empty() {
+            let _ = event_sender.send(Event::Compaction {
+                working_dir: working_dir.clone(),
+                paused: report.paused,
+                steps_compacted: report.steps_compacted,
+                files_recompressed: report.files_recompressed,
+                blobs_deduplicated: report.blobs_deduplicated,
+                bytes_saved: report.bytes_saved,
+                corrupt_paths: report.corrupt_paths,
+            });
+        }
+        if report.paused {
+            return false;
+        }
+    }
+    true
+}
+
+/// Cancels compaction once the session is no longer idle, and reports its
+/// progress over files.
+struct PauseWhenBusy<'a> {
+    host: &'a dyn CompactionHost,
+    event_sender: &'a mpsc::UnboundedSender<Event>,
+    working_dir: &'a str,
+    last_percent: AtomicU8,
+}
+
+impl OperationMonitor for PauseWhenBusy<'_> {
+    fn is_cancelled(&self) -> bool {
+        self.host.activity() != Activity::Idle
+    }
+
+    fn progress(&self, percent: u8, current_path: Option<&str>) {
+        // Runs with nothing to compact report only their end.
+        if current_path.is_none() {
+            return;
+        }
+        if self.last_percent.swap(percent, Ordering::Relaxed) == percent {
+            return;
+        }
+        let _ = self.event_sender.send(Event::CompactionProgress {
+            working_dir: self.working_dir.to_string(),
+            percent,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use codeagent_control::ManualClock;
+    use codeagent_interceptor::write_interceptor::WriteInterceptor;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A session whose activity the test sets.
+    struct ScriptedSession {
+        activity: Mutex<Activity>,
+        interceptor: Arc<UndoInterceptor>,
+    }
+
+    impl CompactionHost for ScriptedSession {
+        fn activity(&self) -> Activity {
+            *self.activity.lock().unwrap()
+        }
+
+        fn undo_logs(&self) -> Vec<(String, Arc<UndoInterceptor>)> {
+            vec![("/work".to_string(), Arc::clone(&self.interceptor))]
+        }
+    }
+
+    /// A session with two closed steps, the first old enough to compact.
+    fn session(dir: &TempDir, activity: Activity) -> Arc<ScriptedSession> {
+        let working = dir.path().join("working");
+        std::fs::create_dir_all(&working).unwrap();
+        let file = working.join("notes.md");
+        let contents: String = (0..2000).map(|i| format!("note {i}: {}\n", i % 7)).collect();
+        std::fs::write(&file, contents).unwrap();
+        let interceptor = Arc::new(UndoInterceptor::new_default(working, dir.path().join("undo")));
+        for id in 1..=2 {
+            interceptor.open_step(id).unwrap();
+            interceptor.pre_write(&file).unwrap();
+            std::fs::write(&file, format!("version {id}")).unwrap();
+            interceptor.close_step(id).unwrap();
+        }
+        Arc::new(ScriptedSession {
+            activity: Mutex::new(activity),
+            interceptor,
+        })
+    }
+
+    /// Run the task over `session` for `polls` poll intervals, then end the
+    /// session and collect the events it sent.
+    async fn run(session: Arc<ScriptedSession>, idle_seconds: u64, polls: u32) -> Vec<Event> {
+        let clock = Arc::new(ManualClock::new());
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let config = CompactionConfig {
+            idle_seconds,
+            ..CompactionConfig::default()
+        };
+        let task = tokio::spawn(run_idle_compaction(
+            session.clone(),
+            clock.clone(),
+            event_sender,
+            config,
+        ));
+        let mut advanced = 0;
+        while !task.is_finished() {
+            if clock.sleepers() > 0 {
+                if advanced == polls {
+                    *session.activity.lock().unwrap() = Activity::Gone;
+                }
+                clock.advance(COMPACTION_POLL_INTERVAL);
+                advanced += 1;
+            }
+            tokio::task::yield_now().await;
+        }
+        let mut sent = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            sent.push(event);
+        }
+        sent
+    }
+
+    fn compactions(events: &[Event]) -> Vec<(bool, usize)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Compaction { paused, steps_compacted, .. } => {
+                    Some((*paused, *steps_compacted))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn idle_session_is_compacted_once() {
+        let dir = TempDir::new().unwrap();
+        let events = run(session(&dir, Activity::Idle), 3, 10).await;
+        assert_eq!(compactions(&events), vec![(false, 1)]);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Event::CompactionProgress { working_dir, .. } if working_dir == "/work"
+        )));
+    }
+
+    #[tokio::test]
+    async fn busy_session_is_left_alone() {
+        let dir = TempDir::new().unwrap();
+        let events = run(session(&dir, Activity::Busy), 0, 10).await;
+        assert!(events.is_empty(), "{events:?}");
+    }
+
+    #[tokio::test]
+    async fn compaction_waits_for_the_idle_period() {
+        let dir = TempDir::new().unwrap();
+        let events = run(session(&dir, Activity::Idle), 30, 10).await;
+        assert!(events.is_empty(), "{events:?}");
+    }
+}
@@ -0,0 +1,147 @@
+//! Guest toolchain inventory for `vm.inventory`.
+//!
+//! A detection script runs in the guest through the shim and prints one
+//! `name<TAB>version output` line per installed tool. The parsed list only
+//! changes when the guest images do, so results are cached by an image
+//! fingerprint.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+
+/// Shell script run in the guest. Tools that are not installed print nothing.
+pub const DETECTION_SCRIPT: &str = r#"for tool in node npm python3 pip3 cargo rustc go java javac gcc g++ clang make cmake git; do
+  command -v "$tool" >/dev/null 2>&1 || continue
+  case "$tool" in
+    go) out=$(go version 2>&1) ;;
+    java|javac) out=$("$tool" -version 2>&1) ;;
+    *) out=$("$tool" --version 2>&1) ;;
+  esac
+  printf '%s\t%s\n' "$tool" "$(printf '%s\n' "$out" | head -n 1)"
+done"#;
+
+/// One tool found in the guest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ToolVersion {
+    pub name: String,
+    /// Dotted version number, when one could be picked out of `detail`.
+    pub version: Option<String>,
+    /// First line of the tool's own version output.
+    pub detail: String,
+}
+
+/// Parse the output of [`DETECTION_SCRIPT`]. Lines without a tab are ignored.
+pub fn parse_inventory(output: &str) -> Vec<ToolVersion> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, detail) = line.split_once('\t')?;
+            let detail = detail.trim();
+            Some(ToolVersion {
+                name: name.trim().to_string(),
+                version: extract_version(detail),
+                detail: detail.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// First word that looks like a version number, e.g. `3.11.2` in
+/// `Python 3.11.2` or `1.21.0` in `go version go1.21.0 linux/amd64`.
+fn extract_version(detail: &str) -> Option<String> {
+    detail.split_whitespace().find_map(|word| {
+        let word = word.trim_matches(|c: char| matches!(c, '"' | '(' | ')' | ','));
+        let word = word
+            .strip_prefix("go")
+            .or_else(|| word.strip_prefix('v'))
+            .unwrap_or(word);
+        (word.starts_with(|c: char| c.is_ascii_digit()) && word.contains('.'))
+            .then(|| word.to_string())
+    })
+}
+
+/// Identify a set of guest images by path, size and modification time.
+///
+/// Cheaper than hashing multi-gigabyte images, and still changes whenever
+/// an image is rebuilt or swapped.
+pub fn image_fingerprint(images: &[&Path]) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    for image in images {
+        let metadata = std::fs::metadata(image)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        hasher.update(image.to_string_lossy().as_bytes());
+        hasher.update(&metadata.len().to_le_bytes());
+        hasher.update(&modified.as_nanos().to_le_bytes());
+    }
+    Ok(hasher.finalize().to_hex()[..16].to_string())
+}
+
+/// Inventories keyed by image fingerprint. Lives as long as the
+/// orchestrator, so it survives session restarts.
+#[derive(Default)]
+pub struct InventoryCache {
+    entries: Mutex<HashMap<String, Vec<ToolVersion>>>,
+}
+
+impl InventoryCache {
+    pub fn get(&self, fingerprint: &str) -> Option<Vec<ToolVersion>> {
+        self.entries.lock().unwrap().get(fingerprint).cloned()
+    }
+
+    pub fn insert(&self, fingerprint: String, tools: Vec<ToolVersion>) {
+        self.entries.lock().unwrap().insert(fingerprint, tools);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detection_output_is_parsed() {
+        let output = "node\tv20.11.1\n\
+                      python3\tPython 3.11.2\n\
+                      go\tgo version go1.21.0 linux/amd64\n\
+                      java\topenjdk version \"17.0.2\" 2022-01-18\n\
+                      gcc\tgcc (Debian 12.2.0-14) 12.2.0\n\
+                      make\tGNU Make\n\
+                      stray line\n";
+        let tools = parse_inventory(output);
+        let versions: Vec<(&str, Option<&str>)> = tools
+            .iter()
+            .map(|tool| (tool.name.as_str(), tool.version.as_deref()))
+            .collect();
+        assert_eq!(tools[1].detail, "Python 3.11.2");
+        assert_eq!(
+            versions,
+            vec![
+                ("node", Some("20.11.1")),
+                ("python3", Some("3.11.2")),
+                ("go", Some("1.21.0")),
+                ("java", Some("17.0.2")),
+                ("gcc", Some("12.2.0-14")),
+                ("make", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn fingerprint_changes_with_the_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("rootfs.img");
+        std::fs::write(&image, b"one").unwrap();
+        let first = image_fingerprint(&[&image]).unwrap();
+        assert_eq!(first, image_fingerprint(&[&image]).unwrap());
+
+        std::fs::write(&image, b"longer").unwrap();
+        assert_ne!(first, image_fingerprint(&[&image]).unwrap());
+        assert!(image_fingerprint(&[&dir.path().join("missing")]).is_err());
+    }
+}
@@ -1,18 +1,43 @@
+pub mod agent_backend;
 pub mod claude_settings;
 pub mod cli;
+pub mod client;
 pub mod command_classifier;
+pub mod command_timeout;
 pub mod command_waiter;
 pub mod config;
 pub mod control_bridge;
+pub mod env_profile;
 pub mod error;
 pub mod event_bridge;
+pub mod file_read;
 pub mod fs_backend;
 pub mod fs_watcher;
+pub mod guest_cwd;
+pub mod health;
+pub mod history_format;
+pub mod idle_compaction;
+pub mod inventory;
+pub mod mcp_listener;
+pub mod metrics_endpoint;
 pub mod orchestrator;
+pub mod overlay;
+pub mod patch;
 pub mod qemu;
+pub mod qmp;
 pub mod recent_writes;
 pub mod safeguard_bridge;
+pub mod safeguard_log;
 pub mod session;
+pub mod session_factory;
+pub mod session_record;
 pub mod singleton;
 pub mod socket_server;
+pub mod stale_resources;
+pub mod step_watchdog;
 pub mod tray;
+pub mod vm_monitor;
+pub mod vm_state;
+pub mod warm_pool;
+pub mod warnings;
+pub mod workspace_clone;
@@ -6,10 +6,43 @@ use tokio::sync::mpsc;
 
 use codeagent_sandbox::cli::CliArgs;
 use codeagent_sandbox::config::{load_config, SandboxTomlConfig};
+use codeagent_sandbox::health::{Heartbeat, ProbeKind, ReadinessSource};
+use codeagent_sandbox::mcp_listener::ListenAddress;
+use codeagent_sandbox::metrics_endpoint::MetricsSource;
 use codeagent_sandbox::orchestrator::Orchestrator;
+use codeagent_sandbox::session_factory::OrchestratorFactory;
 use codeagent_sandbox::tray::{TrayCommand, TrayConfig, TrayUpdate};
+use codeagent_stdio::{log_error, log_warn, logging};
 
 fn main() {
+    let mut args = CliArgs::parse();
+
+    if let Some(protocol) = &args.print_schema {
+        let schema = match protocol.as_str() {
+            "control" => codeagent_control::schema::control_schema(),
+            _ => codeagent_stdio::schema::protocol_schema(),
+        };
+        println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        return;
+    }
+
+    // Probe mode queries a running sandbox, so it must not take the
+    // instance lock that sandbox holds.
+    if let (Some(probe), Some(socket)) = (&args.health_probe, &args.health_socket) {
+        let kind = match probe.parse::<ProbeKind>() {
+            Ok(kind) => kind,
+            Err(msg) => {
+                eprintln!("{msg}");
+                std::process::exit(codeagent_sandbox::health::EXIT_UNHEALTHY);
+            }
+        };
+        std::process::exit(codeagent_sandbox::health::probe(
+            socket,
+            kind,
+            std::time::Duration::from_secs(5),
+        ));
+    }
+
     let _instance_lock = match codeagent_sandbox::singleton::try_acquire_instance_lock() {
         Ok(lock) => lock,
         Err(msg) => {
@@ -18,7 +51,6 @@ fn main() {
         }
     };
 
-    let mut args = CliArgs::parse();
     let config = load_config(args.config_file.as_deref());
 
     // Merge CLI args with TOML config: CLI overrides TOML.
@@ -39,9 +71,14 @@ fn main() {
         }
     }
 
+    let _log_guard = init_logging(&args);
+
     if args.working_dirs.is_empty() {
-        eprintln!("{{\"level\":\"error\",\"message\":\"No working directories specified. \
-            Provide --working-dir or set [sandbox].working_dirs in codeagent.toml.\"}}");
+        log_error!(
+            "sandbox",
+            "No working directories specified. \
+             Provide --working-dir or set [sandbox].working_dirs in codeagent.toml."
+        );
         std::process::exit(1);
     }
 
@@ -63,6 +100,24 @@ fn main() {
     }
 }
 
+/// Install the logger at `--log-level`, writing to the rotating log file
+/// too if `--log-rotate-mb` is set and there is an undo directory to put it
+/// in. The returned guard flushes the file when dropped.
+fn init_logging(args: &CliArgs) -> Option<logging::WorkerGuard> {
+    let (Some(megabytes), Some(undo_dir)) = (args.log_rotate_mb, &args.undo_dir) else {
+        return logging::init(args.log_level, None);
+    };
+    let dir = undo_dir.join(logging::LOG_DIR_NAME);
+    match logging::RotatingFile::open(&dir, megabytes * 1024 * 1024) {
+        Ok(file) => logging::init(args.log_level, Some(file)),
+        Err(e) => {
+            let guard = logging::init(args.log_level, None);
+            log_warn!("sandbox", "cannot open a log file in {}: {e}", dir.display());
+            guard
+        }
+    }
+}
+
 fn run_mcp_with_tray(args: CliArgs, config: SandboxTomlConfig) {
     let (tray_cmd_tx, tray_cmd_rx) = tokio::sync::mpsc::unbounded_channel::<TrayCommand>();
     let (tray_update_tx, tray_update_rx) = std::sync::mpsc::channel::<TrayUpdate>();
@@ -84,23 +139,97 @@ fn run_mcp_with_tray(args: CliArgs, config: SandboxTomlConfig) {
     let _ = server_thread.join();
 }
 
+/// Spawn the readiness/liveness endpoint and its heartbeat task.
+fn spawn_health_server(
+    socket_path: std::path::PathBuf,
+    source: Arc<dyn ReadinessSource>,
+) -> (tokio::task::JoinHandle<()>, tokio::sync::watch::Sender<bool>) {
+    let heartbeat = Heartbeat::new();
+    heartbeat.spawn(codeagent_sandbox::health::HEARTBEAT_INTERVAL);
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let handle = tokio::spawn(codeagent_sandbox::health::run_health_server(
+        socket_path,
+        source,
+        heartbeat,
+        shutdown_rx,
+    ));
+    (handle, shutdown_tx)
+}
+
+/// Bind `address` and serve the metrics on it. A failure to bind is logged
+/// and the sandbox runs without the endpoint.
+async fn spawn_metrics_server(
+    address: std::net::SocketAddr,
+    source: Arc<dyn MetricsSource>,
+) -> Option<(tokio::task::JoinHandle<()>, tokio::sync::watch::Sender<bool>)> {
+    let listener = match tokio::net::TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log_error!("metrics", "--metrics-listen: cannot bind {address}: {e}");
+            return None;
+        }
+    };
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let handle = tokio::spawn(codeagent_sandbox::metrics_endpoint::serve_metrics(
+        listener,
+        source,
+        shutdown_rx,
+    ));
+    Some((handle, shutdown_tx))
+}
+
 async fn run_stdio(args: CliArgs, config: SandboxTomlConfig) {
     use codeagent_stdio::{Router, StdioServer};
 
     let (event_sender, event_receiver) = mpsc::unbounded_channel();
     let working_dir = args.working_dirs[0].clone();
-    let orchestrator =
-        Orchestrator::new(args, event_sender, config.command_classifier, config.file_watcher);
+    let health_socket = args.health_socket.clone();
+    let metrics_listen = args.metrics_listen;
+    let auto_cleanup = config.sandbox.auto_cleanup_stale_resources;
+    let (router, readiness, metrics) = if args.max_sessions > 1 {
+        let max_sessions = args.max_sessions;
+        let factory = OrchestratorFactory::new(
+            args,
+            event_sender,
+            config.command_classifier,
+            config.file_watcher,
+            auto_cleanup,
+        )
+        .with_compaction(config.compaction);
+        let readiness = factory.readiness_source();
+        let metrics = factory.metrics_source();
+        (Router::with_sessions(Box::new(factory), max_sessions), readiness, metrics)
+    } else {
+        let orchestrator =
+            Orchestrator::new(args, event_sender, config.command_classifier, config.file_watcher)
+                .with_compaction(config.compaction);
+        orchestrator.audit_stale_resources(auto_cleanup);
+        orchestrator.start_warm_pool();
+        let readiness = orchestrator.readiness_source();
+        let metrics = orchestrator.metrics_source();
+        (Router::new(working_dir, Box::new(orchestrator)), readiness, metrics)
+    };
+    let health_handle = health_socket.map(|path| spawn_health_server(path, readiness));
+    let metrics_handle = match metrics_listen {
+        Some(address) => spawn_metrics_server(address, metrics).await,
+        None => None,
+    };
 
-    let router = Router::new(working_dir, Box::new(orchestrator));
     let mut server = StdioServer::new(router, event_receiver);
 
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
     let stderr = tokio::io::stderr();
 
-    if let Err(e) = server.run(stdin, stdout, stderr).await {
-        eprintln!("{{\"level\":\"error\",\"message\":\"{e}\"}}");
+    let server_result = server.run(stdin, stdout, stderr).await;
+
+    for (handle, shutdown_tx) in health_handle.into_iter().chain(metrics_handle) {
+        let _ = shutdown_tx.send(true);
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(2), handle).await;
+    }
+
+    if let Err(e) = server_result {
+        log_error!("stdio_api", "{e}");
         std::process::exit(1);
     }
 }
@@ -112,7 +241,7 @@ async fn run_mcp(
     tray_update_tx: Option<std::sync::mpsc::Sender<TrayUpdate>>,
 ) {
     use codeagent_mcp::{McpRouter, McpServer};
-    use codeagent_stdio::protocol::SessionStartPayload;
+    use codeagent_stdio::protocol::{SessionStartPayload, UndoMode};
     use codeagent_stdio::RequestHandler;
 
     let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
@@ -128,6 +257,20 @@ async fn run_mcp(
         .clone()
         .or_else(|| codeagent_sandbox::config::default_config_dir().map(|d| d.join("sandbox.log")));
     let server_name = args.server_name.clone();
+    let health_socket = args.health_socket.clone();
+    let metrics_listen = args.metrics_listen;
+    let listen = args.mcp_listen.as_deref().map(|address| {
+        let parsed = address.parse::<ListenAddress>().and_then(|address| {
+            let token_file = args.mcp_token_file.as_deref().expect("required by clap");
+            let token = codeagent_sandbox::mcp_listener::read_token(token_file)
+                .map_err(|e| format!("cannot read {}: {e}", token_file.display()))?;
+            Ok((address, token))
+        });
+        parsed.unwrap_or_else(|e| {
+            log_error!("mcp", "--mcp-listen: {e}");
+            std::process::exit(1);
+        })
+    });
 
     // Track toggle states with atomics so the tray command handler and
     // cleanup code can share them across tasks.
@@ -140,10 +283,23 @@ async fn run_mcp(
         .map(|d| codeagent_stdio::protocol::WorkingDirectoryConfig {
             path: d.display().to_string(),
             label: None,
+            backend: None,
+            read_only: false,
+            exclude: vec![],
+            overlay: false,
         })
         .collect();
     let orchestrator =
-        Orchestrator::new(args, event_sender, config.command_classifier, config.file_watcher);
+        Orchestrator::new(args, event_sender, config.command_classifier, config.file_watcher)
+            .with_compaction(config.compaction);
+    orchestrator.audit_stale_resources(config.sandbox.auto_cleanup_stale_resources);
+    orchestrator.start_warm_pool();
+    let health_handle = health_socket
+        .map(|path| spawn_health_server(path, orchestrator.readiness_source()));
+    let metrics_handle = match metrics_listen {
+        Some(address) => spawn_metrics_server(address, orchestrator.metrics_source()).await,
+        None => None,
+    };
 
     // MCP mode auto-starts the session from CLI args since MCP has no
     // session.start concept — the client expects tools to be ready immediately.
@@ -152,9 +308,15 @@ async fn run_mcp(
         vm_mode,
         network_policy: "disabled".to_string(),
         protocol_version: None,
+        symlink_policy: None,
+        coherent_capture: None,
+        case_sensitivity: None,
+        undo: UndoMode::Enabled,
+        message_limits: None,
+        terminal_output: None,
     };
     if let Err(e) = orchestrator.session_start(payload) {
-        eprintln!("{{\"level\":\"error\",\"message\":\"session auto-start failed: {e}\"}}");
+        log_error!("mcp", "session auto-start failed: {e}");
         std::process::exit(1);
     }
 
@@ -168,16 +330,38 @@ async fn run_mcp(
     // and log them to stderr so they're visible in diagnostic output.
     while let Ok(event) = event_receiver.try_recv() {
         match &event {
-            codeagent_stdio::Event::Warning { code, message }
-            | codeagent_stdio::Event::Error { code, message } => {
-                eprintln!(
-                    "{{\"level\":\"warn\",\"code\":\"{code}\",\"message\":\"{message}\"}}"
-                );
+            codeagent_stdio::Event::Warning { warning } => {
+                log_warn!("mcp", "{}: {}", warning.code(), warning.message());
+            }
+            codeagent_stdio::Event::Error { code, message } => {
+                log_warn!("mcp", "{code}: {message}");
+            }
+            codeagent_stdio::Event::StaleResources { resources } => {
+                for resource in resources {
+                    log_warn!("mcp", "stale_{}: {}", resource.kind, resource.message);
+                }
             }
             _ => {}
         }
     }
 
+    // Later events have no STDIO client to go to. Tell the MCP clients about
+    // safeguards so they can answer them with confirm_safeguard.
+    let (notification_sender, notification_receiver) = mpsc::unbounded_channel();
+    let (listener_notifications, _) = tokio::sync::broadcast::channel(64);
+    let broadcast = listener_notifications.clone();
+    tokio::spawn(async move {
+        let mut hub = codeagent_stdio::EventHub::new();
+        while let Some(event) = event_receiver.recv().await {
+            if let Some(notification) =
+                codeagent_sandbox::safeguard_bridge::mcp_notification(&event, &mut hub)
+            {
+                let _ = broadcast.send(notification.clone());
+                let _ = notification_sender.send(notification);
+            }
+        }
+    });
+
     // Wrap orchestrator in Arc for sharing between stdin/stdout and socket servers
     let orchestrator: Arc<dyn codeagent_mcp::McpHandler> = Arc::new(orchestrator);
 
@@ -243,18 +427,41 @@ async fn run_mcp(
         });
     }
 
-    let (_notification_sender, notification_receiver) = mpsc::unbounded_channel();
-    let mcp_router =
-        McpRouter::with_working_dirs(working_dir, &all_dirs, Arc::clone(&orchestrator));
-    let mut server = McpServer::new(mcp_router, notification_receiver);
+    let server_result = match listen {
+        Some((address, token)) => {
+            let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+            let mut listener = tokio::spawn(codeagent_sandbox::mcp_listener::run_mcp_listener(
+                address,
+                token,
+                Arc::clone(&orchestrator),
+                working_dir,
+                all_dirs,
+                listener_notifications,
+                shutdown_rx,
+            ));
+            let result = tokio::select! {
+                result = &mut listener => result.unwrap_or(Ok(())),
+                _ = tokio::signal::ctrl_c() => {
+                    let _ = shutdown_tx.send(true);
+                    listener.await.unwrap_or(Ok(()))
+                }
+            };
+            result.map_err(|source| codeagent_mcp::McpError::Io { source })
+        }
+        None => {
+            let mcp_router =
+                McpRouter::with_working_dirs(working_dir, &all_dirs, Arc::clone(&orchestrator));
+            let mut server = McpServer::new(mcp_router, notification_receiver);
 
-    let stdin = tokio::io::stdin();
-    let stdout = tokio::io::stdout();
+            let stdin = tokio::io::stdin();
+            let stdout = tokio::io::stdout();
 
-    let server_result = server.run(stdin, stdout).await;
+            server.run(stdin, stdout).await
+        }
+    };
 
     if let Err(ref e) = server_result {
-        eprintln!("{{\"level\":\"error\",\"message\":\"{e}\"}}");
+        log_error!("mcp", "{e}");
     }
 
     // Always restore Claude settings, even if the server exited with an error
@@ -275,6 +482,10 @@ async fn run_mcp(
         let _ = shutdown_tx.send(true);
         let _ = tokio::time::timeout(std::time::Duration::from_secs(2), handle).await;
     }
+    for (handle, shutdown_tx) in health_handle.into_iter().chain(metrics_handle) {
+        let _ = shutdown_tx.send(true);
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(2), handle).await;
+    }
 
     if server_result.is_err() {
         std::process::exit(1);
@@ -0,0 +1,481 @@
+//! MCP clients over a Unix socket or loopback TCP, for `--mcp-listen`.
+//!
+//! Instead of serving one client on stdin/stdout, the sandbox accepts any
+//! number of MCP clients. Each connection gets its own router and MCP
+//! handshake over the shared handler, so all clients work in the same
+//! session and undo log. Safeguard notifications go to every client.
+//!
+//! A connection must open with the line `Authorization: Bearer <token>`,
+//! the token being the content of `--mcp-token-file`. A wrong or missing
+//! token, none within [`AUTH_TIMEOUT`], or a line longer than
+//! [`MAX_AUTH_LINE`] gets a JSON-RPC error with code [`UNAUTHORIZED`] and
+//! the connection is closed.
+//!
+//! With the `mcp-http` feature, an `http://` address serves the streamable
+//! HTTP transport instead (see `codeagent_mcp::http`); there the token goes
+//! in each request's `Authorization` header.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::sync::{broadcast, mpsc, watch};
+
+use codeagent_mcp::protocol::{JsonRpcNotification, JsonRpcResponse};
+#[cfg(feature = "mcp-http")]
+use codeagent_mcp::http;
+use codeagent_mcp::{tokens_match, JsonRpcError, McpHandler, McpRouter, McpServer};
+use codeagent_stdio::{log_info, log_warn};
+
+/// JSON-RPC error code sent before closing a connection that failed the
+/// token handshake.
+pub const UNAUTHORIZED: i32 = -32004;
+
+/// How long a new connection may take to send its `Authorization` line.
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest `Authorization` line read, newline included. Reading stops
+/// there, so an unauthenticated client cannot make us buffer more.
+pub const MAX_AUTH_LINE: u64 = 4096;
+
+/// Where `--mcp-listen` accepts clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    /// `unix:<path>`.
+    Unix(PathBuf),
+    /// `<ip>:<port>` on a loopback address; port 0 picks a free one.
+    Tcp(SocketAddr),
+    /// `http://<ip>:<port>` on a loopback address, for web-based clients.
+    #[cfg(feature = "mcp-http")]
+    Http(SocketAddr),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix: needs a socket path".to_string());
+            }
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+        let (http, socket) = match value.strip_prefix("http://") {
+            Some(rest) => (true, rest.trim_end_matches('/')),
+            None => (false, value),
+        };
+        let address: SocketAddr = socket.parse().map_err(|_| {
+            format!("'{value}' is neither unix:<path>, <ip>:<port> nor http://<ip>:<port>")
+        })?;
+        if !address.ip().is_loopback() {
+            return Err(format!(
+                "{} is not a loopback address; MCP is only served to local clients",
+                address.ip()
+            ));
+        }
+        match http {
+            #[cfg(feature = "mcp-http")]
+            true => Ok(ListenAddress::Http(address)),
+            #[cfg(not(feature = "mcp-http"))]
+            true => Err(format!("{value}: serving MCP over HTTP needs the mcp-http feature")),
+            false => Ok(ListenAddress::Tcp(address)),
+        }
+    }
+}
+
+/// Read the token clients must present from `path`, ignoring surrounding
+/// whitespace.
+pub fn read_token(path: &Path) -> io::Result<String> {
+    let token = std::fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("token file {} is empty", path.display()),
+        ));
+    }
+    Ok(token)
+}
+
+/// Read the `Authorization` line from `reader` and check its token.
+async fn authenticate<R>(reader: &mut R, token: &str) -> Result<(), String>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    let mut limited = (&mut *reader).take(MAX_AUTH_LINE);
+    match tokio::time::timeout(AUTH_TIMEOUT, limited.read_line(&mut line)).await {
+        Err(_) => return Err("no Authorization line in time".to_string()),
+        Ok(Err(e)) => return Err(e.to_string()),
+        Ok(Ok(read)) if !line.ends_with('\n') => {
+            return Err(if read as u64 == MAX_AUTH_LINE {
+                format!("the Authorization line is longer than {MAX_AUTH_LINE} bytes")
+            } else {
+                "the connection closed before the Authorization line ended".to_string()
+            });
+        }
+        Ok(Ok(_)) => {}
+    }
+    let given = line
+        .trim_end()
+        .strip_prefix("Authorization: Bearer ")
+        .ok_or("the first line must be `Authorization: Bearer <token>`")?;
+    if !tokens_match(given, token) {
+        return Err("wrong token".to_string());
+    }
+    Ok(())
+}
+
+/// Serve MCP clients on `address` until `shutdown` receives a value.
+/// `notifications` reaches every connected client. Fails only if `address`
+/// cannot be listened on.
+pub async fn run_mcp_listener(
+    address: ListenAddress,
+    token: String,
+    handler: Arc<dyn McpHandler>,
+    root_dir: PathBuf,
+    working_dirs: Vec<PathBuf>,
+    notifications: broadcast::Sender<JsonRpcNotification>,
+    shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let client = Arc::new(Client {
+        token,
+        handler,
+        root_dir,
+        working_dirs,
+        notifications,
+    });
+    match address {
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            let _ = std::fs::remove_file(&path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            log_listening(&path.display().to_string());
+            accept_loop(|| async { Ok(listener.accept().await?.0) }, client, shutdown).await;
+            let _ = std::fs::remove_file(&path);
+        }
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix: addresses need a Unix host; listen on 127.0.0.1:<port>",
+            ));
+        }
+        ListenAddress::Tcp(address) => {
+            let listener = tokio::net::TcpListener::bind(address).await?;
+            log_listening(&listener.local_addr()?.to_string());
+            accept_loop(|| async { Ok(listener.accept().await?.0) }, client, shutdown).await;
+        }
+        #[cfg(feature = "mcp-http")]
+        ListenAddress::Http(address) => {
+            let listener = tokio::net::TcpListener::bind(address).await?;
+            log_listening(&format!("http://{}{}", listener.local_addr()?, http::ENDPOINT));
+            let router = McpRouter::with_working_dirs(
+                client.root_dir.clone(),
+                &client.working_dirs,
+                Arc::clone(&client.handler),
+            );
+            http::serve_http(
+                listener,
+                Arc::new(router),
+                Some(client.token.clone()),
+                client.notifications.clone(),
+                shutdown,
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+fn log_listening(address: &str) {
+    log_info!("mcp_listener", "MCP clients accepted on {address}");
+}
+
+/// What every connection shares.
+struct Client {
+    token: String,
+    handler: Arc<dyn McpHandler>,
+    root_dir: PathBuf,
+    working_dirs: Vec<PathBuf>,
+    notifications: broadcast::Sender<JsonRpcNotification>,
+}
+
+async fn accept_loop<A, F, S>(accept: A, client: Arc<Client>, mut shutdown: watch::Receiver<bool>)
+where
+    A: Fn() -> F,
+    F: std::future::Future<Output = io::Result<S>>,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            result = accept() => match result {
+                Ok(stream) => {
+                    tokio::spawn(serve_client(stream, Arc::clone(&client)));
+                }
+                Err(e) => {
+                    log_warn!("mcp_listener", "MCP accept error: {e}");
+                }
+            },
+        }
+    }
+}
+
+async fn serve_client<S>(stream: S, client: Arc<Client>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    if let Err(reason) = authenticate(&mut reader, &client.token).await {
+        let response = JsonRpcResponse::error(
+            None,
+            JsonRpcError {
+                code: UNAUTHORIZED,
+                message: format!("unauthorized: {reason}"),
+                data: None,
+            },
+        );
+        let mut line = serde_json::to_string(&response).unwrap();
+        line.push('\n');
+        let _ = writer.write_all(line.as_bytes()).await;
+        let _ = writer.shutdown().await;
+        return;
+    }
+
+    // The connection's server takes notifications from its own channel.
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let mut notifications = client.notifications.subscribe();
+    let forwarder = tokio::spawn(async move {
+        loop {
+            match notifications.recv().await {
+                Ok(notification) => {
+                    if sender.send(notification).is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let router = McpRouter::with_working_dirs(
+        client.root_dir.clone(),
+        &client.working_dirs,
+        Arc::clone(&client.handler),
+    );
+    let mut server = McpServer::new(router, receiver);
+    if let Err(e) = server.run(reader, writer).await {
+        log_warn!("mcp_listener", "MCP connection closed: {e}");
+    }
+    forwarder.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codeagent_mcp::protocol::{
+        ApplyPatchArgs, BashArgs, ConfirmSafeguardArgs, DiscardUndoHistoryArgs, EditFileArgs,
+        GetUndoHistoryArgs, GlobArgs, GrepArgs, ListPendingSafeguardsArgs, ReadFileArgs, UndoArgs,
+        WriteFileArgs,
+    };
+    use codeagent_mcp::McpError;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    struct StubHandler;
+
+    impl McpHandler for StubHandler {
+        fn bash(&self, args: BashArgs) -> Result<Value, McpError> {
+            Ok(json!({"stdout": args.command}))
+        }
+        fn read_file(&self, _: ReadFileArgs) -> Result<Value, McpError> {
+            Ok(json!({"content": ""}))
+        }
+        fn write_file(&self, _: WriteFileArgs) -> Result<Value, McpError> {
+            Ok(json!({"written": true}))
+        }
+        fn edit_file(&self, _: EditFileArgs) -> Result<Value, McpError> {
+            Ok(json!("ok"))
+        }
+        fn apply_patch(&self, _: ApplyPatchArgs) -> Result<Value, McpError> {
+            Ok(json!({"applied": true}))
+        }
+        fn glob(&self, _: GlobArgs) -> Result<Value, McpError> {
+            Ok(json!(""))
+        }
+        fn grep(&self, _: GrepArgs) -> Result<Value, McpError> {
+            Ok(json!(""))
+        }
+        fn undo(&self, _: UndoArgs) -> Result<Value, McpError> {
+            Ok(json!({"steps_rolled_back": 0}))
+        }
+        fn get_undo_history(&self, _: GetUndoHistoryArgs) -> Result<Value, McpError> {
+            Ok(json!({"steps": []}))
+        }
+        fn get_session_status(&self) -> Result<Value, McpError> {
+            Ok(json!({"state": "active"}))
+        }
+        fn discard_undo_history(&self, _: DiscardUndoHistoryArgs) -> Result<Value, McpError> {
+            Ok(json!({}))
+        }
+        fn list_pending_safeguards(&self, _: ListPendingSafeguardsArgs) -> Result<Value, McpError> {
+            Ok(json!({"pending": []}))
+        }
+        fn confirm_safeguard(&self, _: ConfirmSafeguardArgs) -> Result<Value, McpError> {
+            Ok(json!({}))
+        }
+    }
+
+    /// Start a listener on a free loopback port. Returns its address and the
+    /// senders for notifications and shutdown.
+    async fn start_listener() -> (
+        SocketAddr,
+        broadcast::Sender<JsonRpcNotification>,
+        watch::Sender<bool>,
+    ) {
+        // Bind first to learn a free port, then hand it to the listener.
+        let probe = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = probe.local_addr().unwrap();
+        drop(probe);
+        let (notifications, _) = broadcast::channel(16);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(run_mcp_listener(
+            ListenAddress::Tcp(address),
+            "s3cret".to_string(),
+            Arc::new(StubHandler),
+            PathBuf::from("/tmp"),
+            vec![PathBuf::from("/tmp")],
+            notifications.clone(),
+            shutdown_rx,
+        ));
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(address).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        (address, notifications, shutdown_tx)
+    }
+
+    async fn send(
+        stream: &mut BufReader<tokio::net::TcpStream>,
+        line: &str,
+    ) -> Value {
+        stream.get_mut().write_all(format!("{line}\n").as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_line(&mut response).await.unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    async fn connect(address: SocketAddr) -> BufReader<tokio::net::TcpStream> {
+        BufReader::new(tokio::net::TcpStream::connect(address).await.unwrap())
+    }
+
+    const INITIALIZE: &str = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"protocolVersion":"2024-11-05","capabilities":{}}}"#;
+
+    #[test]
+    fn listen_addresses_parse() {
+        assert_eq!(
+            "unix:/run/mcp.sock".parse(),
+            Ok(ListenAddress::Unix(PathBuf::from("/run/mcp.sock")))
+        );
+        assert_eq!(
+            "127.0.0.1:7000".parse(),
+            Ok(ListenAddress::Tcp("127.0.0.1:7000".parse().unwrap()))
+        );
+        assert!("[::1]:0".parse::<ListenAddress>().is_ok());
+        #[cfg(feature = "mcp-http")]
+        assert_eq!(
+            "http://127.0.0.1:7000/".parse(),
+            Ok(ListenAddress::Http("127.0.0.1:7000".parse().unwrap()))
+        );
+        #[cfg(not(feature = "mcp-http"))]
+        assert!("http://127.0.0.1:7000".parse::<ListenAddress>().unwrap_err().contains("mcp-http"));
+        assert!("http://10.0.0.1:7000".parse::<ListenAddress>().is_err());
+        assert!("0.0.0.0:7000".parse::<ListenAddress>().unwrap_err().contains("loopback"));
+        assert!("unix:".parse::<ListenAddress>().is_err());
+        assert!("localhost".parse::<ListenAddress>().is_err());
+    }
+
+    #[test]
+    fn tokens_are_read_trimmed_and_must_not_be_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        std::fs::write(&path, "abc\n").unwrap();
+        assert_eq!(read_token(&path).unwrap(), "abc");
+        std::fs::write(&path, " \n").unwrap();
+        assert!(read_token(&path).is_err());
+        assert!(tokens_match("abc", "abc"));
+        assert!(!tokens_match("abd", "abc"));
+        assert!(!tokens_match("ab", "abc"));
+    }
+
+    #[tokio::test]
+    async fn clients_without_the_token_are_rejected() {
+        let (address, _notifications, _shutdown) = start_listener().await;
+
+        let mut wrong = connect(address).await;
+        let response = send(&mut wrong, "Authorization: Bearer guess").await;
+        assert_eq!(response["error"]["code"], UNAUTHORIZED);
+        let mut rest = String::new();
+        assert_eq!(wrong.read_line(&mut rest).await.unwrap(), 0);
+
+        let mut missing = connect(address).await;
+        let response = send(&mut missing, INITIALIZE).await;
+        assert_eq!(response["error"]["code"], UNAUTHORIZED);
+
+        // Reading stops at the limit instead of waiting for a newline.
+        let mut endless = connect(address).await;
+        let mut line = "Authorization: Bearer ".to_string();
+        line.extend(std::iter::repeat_n('x', MAX_AUTH_LINE as usize - line.len()));
+        endless.get_mut().write_all(line.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        endless.read_line(&mut response).await.unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["error"]["code"], UNAUTHORIZED);
+        assert!(response["error"]["message"].as_str().unwrap().contains("longer than"));
+    }
+
+    #[tokio::test]
+    async fn authenticated_clients_share_the_handler_and_notifications() {
+        let (address, notifications, shutdown) = start_listener().await;
+
+        let mut first = connect(address).await;
+        let mut second = connect(address).await;
+        for client in [&mut first, &mut second] {
+            client.get_mut().write_all(b"Authorization: Bearer s3cret\n").await.unwrap();
+            let response = send(client, INITIALIZE).await;
+            assert_eq!(response["result"]["serverInfo"]["name"], "codeagent-mcp");
+        }
+        let call = r#"{"jsonrpc":"2.0","id":2,"method":"tools/call","params":{"name":"Bash","arguments":{"command":"ls"}}}"#;
+        let response = send(&mut second, call).await;
+        let text = response["result"]["content"][0]["text"].as_str().unwrap();
+        assert_eq!(serde_json::from_str::<Value>(text).unwrap()["stdout"], "ls");
+
+        notifications
+            .send(JsonRpcNotification {
+                jsonrpc: "2.0".to_string(),
+                method: "notifications/message".to_string(),
+                params: Some(json!({"level": "warning"})),
+            })
+            .unwrap();
+        for client in [&mut first, &mut second] {
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            assert!(line.contains("notifications/message"), "{line}");
+        }
+
+        shutdown.send(true).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+//! Prometheus metrics over HTTP.
+//!
+//! With `--metrics-listen <addr>`, `GET /metrics` on that loopback TCP
+//! address returns the process counters of [`codeagent_common::metrics`]
+//! and the gauges of the sessions in the Prometheus text format. Other
+//! paths get a 404 and other methods a 405. There is no authentication,
+//! hence the loopback requirement; STDIO clients use `session.metrics`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use codeagent_common::metrics::{self, Gauges};
+use codeagent_stdio::{log_info, log_warn};
+
+/// Path the metrics are served on.
+pub const ENDPOINT: &str = "/metrics";
+
+/// Request lines and headers longer than this end the connection.
+const MAX_HEADER_BYTES: usize = 8 * 1024;
+
+/// Supplies the gauges (implemented over the orchestrator's session state).
+pub trait MetricsSource: Send + Sync {
+    fn gauges(&self) -> Gauges;
+}
+
+/// Parse a `--metrics-listen` address, which must be on a loopback
+/// interface.
+pub fn parse_address(text: &str) -> Result<SocketAddr, String> {
+    let address: SocketAddr = text
+        .parse()
+        .map_err(|_| format!("'{text}' is not an address such as 127.0.0.1:9464"))?;
+    if !address.ip().is_loopback() {
+        return Err(format!("{address} is not a loopback address"));
+    }
+    Ok(address)
+}
+
+/// Serve the metrics on `listener` until `shutdown` changes.
+pub async fn serve_metrics(
+    listener: TcpListener,
+    source: Arc<dyn MetricsSource>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    if let Ok(address) = listener.local_addr() {
+        log_info!("metrics", "metrics served on http://{address}{ENDPOINT}");
+    }
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            result = listener.accept() => match result {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_connection(stream, Arc::clone(&source)));
+                }
+                Err(e) => log_warn!("metrics", "metrics accept error: {e}"),
+            },
+        }
+    }
+}
+
+/// Answer one request, then close the connection.
+async fn serve_connection(stream: TcpStream, source: Arc<dyn MetricsSource>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader).take(MAX_HEADER_BYTES as u64);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await.is_err() {
+        return;
+    }
+    // Skip the headers; the request has no body.
+    let mut header = String::new();
+    loop {
+        header.clear();
+        match reader.read_line(&mut header).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if header.trim_end().is_empty() => break,
+            Ok(_) => {}
+        }
+    }
+    let response = respond(&request_line, &*source);
+    let _ = writer.write_all(response.as_bytes()).await;
+    let _ = writer.shutdown().await;
+}
+
+/// The HTTP response to `request_line`.
+fn respond(request_line: &str, source: &dyn MetricsSource) -> String {
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (status, content_type, body) = match (method, path) {
+        ("GET", ENDPOINT) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics::prometheus_text(&source.gauges()),
+        ),
+        (_, ENDPOINT) => ("405 Method Not Allowed", "text/plain", "GET only\n".to_string()),
+        _ => ("404 Not Found", "text/plain", format!("metrics are at {ENDPOINT}\n")),
+    };
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedGauges;
+
+    impl MetricsSource for FixedGauges {
+        fn gauges(&self) -> Gauges {
+            Gauges {
+                undo_log_bytes: 1234,
+                ..Gauges::default()
+            }
+        }
+    }
+
+    #[test]
+    fn addresses_must_be_loopback() {
+        assert_eq!(parse_address("127.0.0.1:9464"), Ok("127.0.0.1:9464".parse().unwrap()));
+        assert!(parse_address("[::1]:9464").is_ok());
+        assert!(parse_address("0.0.0.0:9464").unwrap_err().contains("loopback"));
+        assert!(parse_address("localhost:9464").is_err());
+    }
+
+    #[test]
+    fn only_get_of_the_endpoint_returns_metrics() {
+        let response = respond("GET /metrics HTTP/1.1\r\n", &FixedGauges);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("codeagent_undo_log_bytes 1234\n"));
+
+        assert!(respond("POST /metrics HTTP/1.1", &FixedGauges).starts_with("HTTP/1.1 405"));
+        assert!(respond("GET / HTTP/1.1", &FixedGauges).starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve_metrics(listener, Arc::new(FixedGauges), shutdown_rx));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(body.contains("# TYPE codeagent_rollbacks_total counter\n"));
+
+        shutdown_tx.send(true).unwrap();
+        server.await.unwrap();
+    }
+}
@@ -1,37 +1,113 @@
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use serde_json::json;
 use tokio::sync::mpsc;
+use tracing::Instrument;
 
-use codeagent_common::{BarrierReason, SafeguardConfig, SafeguardDecision};
-use codeagent_control::InFlightTracker;
-use codeagent_interceptor::undo_interceptor::{UndoConfig, UndoInterceptor};
+use codeagent_common::fs_trace::{self, FsTrace};
+use codeagent_common::metrics::{self, Counter, Gauges};
+use codeagent_common::{
+    BarrierReason, CodeAgentError, Expectation, ExternalModificationPolicy, OperationMonitor,
+    RollbackResult, SafeguardConfig, SafeguardDecision, SandboxWarning, StepType, time,
+};
+use codeagent_control::{
+    Clock, ControlChannelHandler, HostMessage, InFlightTracker, LaneSender, Link,
+    QuiescenceConfig, ResourceLimits, TokioClock,
+};
+use codeagent_interceptor::passthrough::PassthroughInterceptor;
+use codeagent_interceptor::gitignore::{self, GitignoreFilter};
+use codeagent_interceptor::{coherent_capture, external_modification, safeguard};
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
 use codeagent_interceptor::write_interceptor::WriteInterceptor;
 use codeagent_mcp::McpError;
 use codeagent_mcp::protocol::{
-    BashArgs, DiscardUndoHistoryArgs, EditFileArgs, GetUndoHistoryArgs, GlobArgs, GrepArgs,
-    ReadFileArgs, UndoArgs, WriteFileArgs,
+    ApplyPatchArgs, BashArgs, ConfirmSafeguardArgs, DiscardUndoHistoryArgs, EditFileArgs,
+    GetUndoHistoryArgs, GlobArgs, GrepArgs, ListPendingSafeguardsArgs, ReadFileArgs, UndoArgs,
+    WriteFileArgs,
 };
 use codeagent_stdio::protocol::{
-    AgentExecutePayload, AgentPromptPayload, FsListPayload, FsReadPayload,
-    SafeguardConfirmPayload, SafeguardConfigurePayload, SessionStartPayload,
-    UndoConfigurePayload, UndoHistoryPayload, UndoRollbackPayload,
+    AgentExecutePayload, AgentInputPayload, AgentPromptPayload, FsCommitPayload, FsDeletePayload,
+    FsListPayload, FsHashPayload, FsPatchPayload, FsReadPayload, FsStatPayload, FsTracePayload,
+    FsWritePayload, HistoryFormat, SafeguardConfirmPayload, SafeguardConfigurePayload,
+    SafeguardHistoryPayload,
+    MountBackend, SessionClonePayload, SessionConfigurePayload, SessionDestroyPayload, SessionEnvSetPayload, SessionEnvUnsetPayload, SessionStartPayload, UndoAttestPayload, UndoConfigurePayload, UndoExpectPayload, UndoHistoryPayload, UndoMode, UndoRollbackPayload, UndoSquashPayload,
+    VmInventoryPayload, WorkingDirectoryConfig,
 };
-use codeagent_stdio::{Event, RequestHandler, StdioError};
+use codeagent_stdio::protocol::warning_payload;
+use codeagent_stdio::{log_debug, log_error, log_info, log_warn, Event, RequestHandler, StdioError};
 
+use crate::agent_backend::{self, AgentAction, AgentBackend, AgentTurn};
 use crate::cli::CliArgs;
 use crate::command_classifier::{self, CommandClassifier, CommandClassifierConfig, SanitizeResult};
-use crate::command_waiter::CommandWaiter;
-use crate::config::FileWatcherConfig;
+use crate::command_timeout::{CommandTimeout, CommandTimeouts};
+use crate::command_waiter::{CommandResult, CommandWaiter};
+use crate::config::{CompactionConfig, FileWatcherConfig};
 use crate::control_bridge;
+use crate::env_profile::{self, EnvProfile};
 use crate::error::AgentError;
+use crate::file_read;
+use crate::fs_backend;
 use crate::fs_watcher;
-use crate::qemu::{QemuConfig, QemuProcess};
+use crate::guest_cwd;
+use crate::health::{Readiness, ReadinessSource};
+use crate::history_format;
+use crate::idle_compaction::{self, Activity, CompactionHost};
+use crate::inventory::{self, InventoryCache};
+use crate::metrics_endpoint::MetricsSource;
+use crate::overlay::{self, Overlay, OverlayChange};
+use crate::patch::{self, PatchedContent};
+use crate::qemu::{FsTransport, QemuConfig, QemuProcess};
 use crate::recent_writes::RecentBackendWrites;
-use crate::safeguard_bridge::PendingSafeguard;
-use crate::session::{Session, SessionState};
+use crate::safeguard_bridge::{self, CommandCanceller, PendingSafeguard, PendingSafeguards, Verdict};
+use crate::safeguard_log::{self, DecidedBy};
+use crate::session::{self, Session, SessionState};
+use crate::session_factory::WorkingDirClaims;
+use crate::session_record::SessionRecord;
+use crate::stale_resources::{self, StaleResource};
+use crate::step_watchdog::{self, StepWatchdogHost};
+use crate::vm_monitor::{self, VmExit, VmHost, VmPoll};
+use crate::vm_state::SavedVmState;
+use crate::warm_pool::{self, BootVm, WarmPool, WarmVm};
+use crate::warnings::WarningReporter;
+use crate::workspace_clone;
+
+/// How long an API step waits for a command or ambient step to close before
+/// giving up. Longer than the default ambient inactivity timeout (5s) so a
+/// write that lands just after external activity still goes through.
+const API_STEP_WAIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Upper bound for the `vm.inventory` detection script. Each tool only
+/// prints its version, so this is generous even on a cold guest.
+const INVENTORY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Open an API step, queueing behind any command or ambient step in progress.
+///
+/// The wait blocks the calling thread, so on a multi-threaded tokio runtime
+/// it runs under `block_in_place` to let the tasks that close the other step
+/// keep running. On a current-thread runtime those tasks could never run
+/// while we block, so the step is opened without waiting instead.
+fn open_api_step(interceptor: &UndoInterceptor, step_id: i64) -> codeagent_common::Result<()> {
+    let multi_threaded = tokio::runtime::Handle::try_current()
+        .map(|handle| handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread);
+    let waited = match multi_threaded {
+        Ok(true) => tokio::task::block_in_place(|| {
+            interceptor.open_step_when_free(step_id, API_STEP_WAIT_TIMEOUT)
+        })?,
+        Ok(false) => interceptor.open_step_when_free(step_id, Duration::ZERO)?,
+        Err(_) => interceptor.open_step_when_free(step_id, API_STEP_WAIT_TIMEOUT)?,
+    };
+    if !waited.is_zero() {
+        log_debug!(
+            "mcp",
+            "API step {step_id} waited {}ms for the active step to close",
+            waited.as_millis()
+        );
+    }
+    Ok(())
+}
 
 /// Compute a stable subdirectory name for a working directory's undo data.
 ///
@@ -46,6 +122,21 @@ pub fn undo_subdir_name(working_dir: &Path) -> String {
     hash.to_hex()[..16].to_string()
 }
 
+/// A confirmation token for deleting `dirs`: a hash of the directories
+/// and the current time, so each request for one yields a new token.
+fn destroy_token(dirs: &[PathBuf]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for dir in dirs {
+        hasher.update(dir.to_string_lossy().as_bytes());
+        hasher.update(b"\0");
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.update(&now.as_nanos().to_le_bytes());
+    hasher.finalize().to_hex()[..16].to_string()
+}
+
 /// Check that two paths do not contain each other.
 /// Both paths must exist (so canonicalization works).
 fn check_paths_overlap(working_dir: &std::path::Path, undo_dir: &std::path::Path) -> Result<(), AgentError> {
@@ -68,6 +159,45 @@ fn check_paths_overlap(working_dir: &std::path::Path, undo_dir: &std::path::Path
     Ok(())
 }
 
+/// Resolve a path that may not exist yet through its nearest existing
+/// ancestor, so it can be compared against canonicalized directories.
+fn resolve_lexically(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors() {
+        if let Ok(canonical) = std::fs::canonicalize(ancestor) {
+            let rest = path.strip_prefix(ancestor).unwrap_or(Path::new(""));
+            return canonical.join(rest);
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Check that a `session.clone` target is a fresh location that does not
+/// nest with any directory the session already uses.
+fn check_clone_target(target: &Path, in_use: &[&Path]) -> Result<(), AgentError> {
+    let invalid = |reason: String| AgentError::InvalidCloneTarget {
+        path: target.display().to_string(),
+        reason,
+    };
+    if !target.is_absolute() {
+        return Err(invalid("path must be absolute".to_string()));
+    }
+    if target.exists() {
+        let mut entries = std::fs::read_dir(target)
+            .map_err(|_| invalid("not a directory".to_string()))?;
+        if entries.next().is_some() {
+            return Err(invalid("directory is not empty".to_string()));
+        }
+    }
+    let resolved = resolve_lexically(target);
+    for dir in in_use {
+        let dir = resolve_lexically(dir);
+        if resolved.starts_with(&dir) || dir.starts_with(&resolved) {
+            return Err(invalid(format!("overlaps {}", dir.display())));
+        }
+    }
+    Ok(())
+}
+
 /// RAII guard that suppresses all watcher events while held.
 ///
 /// On creation, increments the active suppression counter. On drop, decrements
@@ -81,6 +211,208 @@ impl Drop for WatcherSuppressGuard {
     }
 }
 
+/// Readiness of the active session: VM process running and control channel
+/// tasks alive.
+struct SessionReadiness(Arc<Mutex<SessionState>>);
+
+impl ReadinessSource for SessionReadiness {
+    fn readiness(&self) -> Readiness {
+        let mut state = self.0.lock().unwrap();
+        let SessionState::Active(session) = &mut *state else {
+            return Readiness::default();
+        };
+        let vm_booted = session
+            .qemu_process
+            .as_mut()
+            .is_some_and(|process| process.is_running());
+        let control_channel_up = session
+            .control_writer
+            .as_ref()
+            .is_some_and(|writer| !writer.is_closed())
+            && session
+                .control_reader_handle
+                .as_ref()
+                .is_some_and(|handle| !handle.is_finished());
+        Readiness {
+            vm_booted,
+            control_channel_up,
+        }
+    }
+}
+
+/// Gauges of the active session, for `session.metrics` and the metrics
+/// endpoint.
+struct SessionMetrics(Arc<Mutex<SessionState>>);
+
+impl MetricsSource for SessionMetrics {
+    fn gauges(&self) -> Gauges {
+        let (interceptors, in_flight_operations, vm_memory_bytes) = {
+            let state = self.0.lock().unwrap();
+            let SessionState::Active(session) = &*state else {
+                return Gauges::default();
+            };
+            (
+                session.interceptors.clone(),
+                session.in_flight_tracker.as_ref().map_or(0, |tracker| tracker.count()),
+                session.qemu_process.as_ref().map_or(0, QemuProcess::memory_bytes),
+            )
+        };
+        // Sizing the undo logs walks them, so not under the session lock.
+        let undo_log_bytes = interceptors
+            .iter()
+            .filter_map(|interceptor| interceptor.undo_log_size().ok())
+            .sum();
+        Gauges {
+            undo_log_bytes,
+            in_flight_operations: in_flight_operations as u64,
+            vm_memory_bytes,
+        }
+    }
+}
+
+/// The active session, as the step watchdog sees it.
+struct SessionSteps(Arc<Mutex<SessionState>>);
+
+impl StepWatchdogHost for SessionSteps {
+    fn undo_logs(&self) -> Option<Vec<Arc<UndoInterceptor>>> {
+        let state = self.0.lock().unwrap();
+        let SessionState::Active(session) = &*state else {
+            return None;
+        };
+        Some(session.interceptors.clone())
+    }
+}
+
+/// The active session, as idle compaction sees it.
+struct SessionCompaction(Arc<Mutex<SessionState>>);
+
+impl CompactionHost for SessionCompaction {
+    fn activity(&self) -> Activity {
+        // Called while compacting: a session busy enough to hold its lock
+        // is busy enough to pause for.
+        let Ok(state) = self.0.try_lock() else {
+            return Activity::Busy;
+        };
+        let SessionState::Active(session) = &*state else {
+            return Activity::Gone;
+        };
+        let step_open = session
+            .interceptors
+            .iter()
+            .any(|interceptor| interceptor.current_step().is_some());
+        let in_flight = session
+            .in_flight_tracker
+            .as_ref()
+            .is_some_and(|tracker| tracker.count() > 0);
+        if step_open || in_flight {
+            Activity::Busy
+        } else {
+            Activity::Idle
+        }
+    }
+
+    fn undo_logs(&self) -> Vec<(String, Arc<UndoInterceptor>)> {
+        let state = self.0.lock().unwrap();
+        let SessionState::Active(session) = &*state else {
+            return Vec::new();
+        };
+        session
+            .working_dirs
+            .iter()
+            .zip(&session.interceptors)
+            .map(|(dir, interceptor)| (dir.display().to_string(), Arc::clone(interceptor)))
+            .collect()
+    }
+}
+
+/// The session's VM, as the VM monitor sees it.
+struct SessionVm {
+    state: Arc<Mutex<SessionState>>,
+    launcher: VmLauncher,
+}
+
+impl VmHost for SessionVm {
+    fn poll(&self) -> VmPoll {
+        let mut state = self.state.lock().unwrap();
+        let SessionState::Active(session) = &mut *state else {
+            return VmPoll::Gone;
+        };
+        let Some(qemu) = session.qemu_process.as_mut() else {
+            return VmPoll::Gone;
+        };
+        let Some(status) = qemu.try_exit() else {
+            return VmPoll::Running;
+        };
+        let exit = VmExit::new(status, qemu.console_tail());
+        metrics::increment(Counter::VmCrashes);
+        detach_vm(session);
+        VmPoll::Exited(exit)
+    }
+
+    fn relaunch(&self) -> Result<(), AgentError> {
+        let mut state = self.state.lock().unwrap();
+        let SessionState::Active(session) = &mut *state else {
+            return Ok(());
+        };
+        let parts = self.launcher.launch()?;
+        session.qemu_process = parts.qemu_process;
+        session.fs_backends = parts.fs_backends;
+        session.in_flight_tracker = parts.in_flight_tracker;
+        session.control_writer = parts.control_writer;
+        session.control_handler = parts.control_handler;
+        session.event_bridge_handle = parts.event_bridge_handle;
+        session.control_reader_handle = parts.control_reader_handle;
+        session.control_writer_handle = parts.control_writer_handle;
+        Ok(())
+    }
+}
+
+/// Tear down what was attached to a VM that exited. Its commands complete
+/// with exit code -1 through the old event bridge, which ends by itself
+/// once they have, and each working directory gets a barrier: the commands
+/// may have been part way through writes.
+fn detach_vm(session: &mut Session) {
+    for handle in [
+        session.control_reader_handle.take(),
+        session.control_writer_handle.take(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        handle.abort();
+    }
+    session.control_writer.take();
+    session.event_bridge_handle.take();
+    session.in_flight_tracker.take();
+    if let Some(handler) = session.control_handler.take() {
+        tokio::spawn(async move {
+            handler.abandon_commands().await;
+        });
+    }
+    if let Some(mut qemu) = session.qemu_process.take() {
+        let _ = qemu.stop();
+    }
+    for backend in &mut session.fs_backends {
+        let _ = backend.stop();
+    }
+    session.fs_backends.clear();
+    for interceptor in &session.interceptors {
+        if let Err(error) =
+            interceptor.notify_external_modification(vec![], BarrierReason::VmCrashed)
+        {
+            log_error!("vm_monitor", "failed to place VM crash barrier: {error}");
+        }
+    }
+}
+
+/// A file an accepted patch writes or deletes.
+struct PatchedFile<'a> {
+    path: &'a str,
+    target: PathBuf,
+    content: PatchedContent,
+    hunks: usize,
+}
+
 /// Central orchestrator that implements both `RequestHandler` (STDIO API)
 /// and `McpHandler` (MCP server) by delegating to shared session state.
 pub struct Orchestrator {
@@ -92,10 +424,47 @@ pub struct Orchestrator {
     /// Shared with the event bridge so MCP `Bash` tool can block
     /// until a VM command completes and collect its output.
     command_waiter: Arc<CommandWaiter>,
+    /// Step-closed notifications for `agent.execute` commands with a
+    /// `timeout_seconds`, fed by the event bridge.
+    command_timeouts: Arc<CommandTimeouts>,
     /// Pre-computed command classifier from config.
     classifier: CommandClassifier,
     /// Filesystem watcher configuration from TOML config.
     file_watcher_config: FileWatcherConfig,
+    /// Idle-time undo log compaction, from TOML config.
+    compaction_config: CompactionConfig,
+    /// Step closing timeouts for the next VM launched, from the CLI and
+    /// `session.configure`.
+    quiescence: Arc<Mutex<QuiescenceConfig>>,
+    /// Persistent warnings of the current session, for `session.warnings`.
+    warnings: WarningReporter,
+    /// Guest toolchain reports for `vm.inventory`, keyed by image fingerprint.
+    inventory_cache: InventoryCache,
+    /// Token a `session.destroy` that deletes undo data must present, issued
+    /// by the one before it. Cleared whenever the session stops.
+    destroy_confirmation: Mutex<Option<String>>,
+    /// Times quiescence windows, ambient steps and safeguard timeouts.
+    clock: Arc<dyn Clock>,
+    /// VMs booted ahead of `session.start`, once started.
+    warm_pool: OnceLock<Arc<WarmPool<QemuProcess>>>,
+    /// Drives `agent.prompt`, if one is configured.
+    agent_backend: Option<Arc<dyn AgentBackend>>,
+    /// Working directories of this and any other session of the process.
+    dir_claims: WorkingDirClaims,
+    /// The steps of the atomic batch running, if any.
+    step_scope: Mutex<Option<StepScope>>,
+}
+
+/// The undo steps an atomic batch's own requests opened. Ambient steps,
+/// MCP listener calls and anything else completing meanwhile are not the
+/// batch's, and its merge or rollback leaves them alone.
+struct StepScope {
+    interceptors: Vec<Arc<UndoInterceptor>>,
+    /// The thread the batch runs its requests on, one at a time. Steps
+    /// opened on any other are someone else's.
+    thread: std::thread::ThreadId,
+    /// Ids the steps were opened under, API step and command ids alike.
+    opened: Vec<i64>,
 }
 
 impl Orchestrator {
@@ -107,15 +476,51 @@ impl Orchestrator {
     ) -> Self {
         Self {
             state: Arc::new(Mutex::new(SessionState::Idle)),
+            agent_backend: agent_backend::from_cli(&cli_args),
+            quiescence: Arc::new(Mutex::new(cli_args.quiescence_config())),
             cli_args,
+            warnings: WarningReporter::new(event_sender.clone()),
             event_sender,
             safeguard_receiver: Mutex::new(None),
             command_waiter: CommandWaiter::new(),
+            command_timeouts: CommandTimeouts::new(),
             classifier: CommandClassifier::new(classifier_config),
             file_watcher_config,
+            compaction_config: CompactionConfig::default(),
+            inventory_cache: InventoryCache::default(),
+            destroy_confirmation: Mutex::new(None),
+            clock: Arc::new(TokioClock),
+            warm_pool: OnceLock::new(),
+            dir_claims: WorkingDirClaims::default(),
+            step_scope: Mutex::new(None),
         }
     }
 
+    /// Run session timers on `clock`, e.g. a `ManualClock` in tests.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Drive `agent.prompt` with `backend` instead of the CLI's.
+    pub fn with_agent_backend(mut self, backend: Arc<dyn AgentBackend>) -> Self {
+        self.agent_backend = Some(backend);
+        self
+    }
+
+    /// Compact undo logs while sessions are idle as `config` says.
+    pub fn with_compaction(mut self, config: CompactionConfig) -> Self {
+        self.compaction_config = config;
+        self
+    }
+
+    /// Share working directory claims with the other sessions of the
+    /// process, so their sessions and this one cannot overlap.
+    pub fn with_dir_claims(mut self, claims: WorkingDirClaims) -> Self {
+        self.dir_claims = claims;
+        self
+    }
+
     /// Resolve guest image paths: CLI args first, then auto-detect next to the binary.
     fn resolve_guest_images(&self) -> (Option<PathBuf>, Option<PathBuf>) {
         let mut kernel = self.cli_args.kernel_path.clone();
@@ -146,6 +551,142 @@ impl Orchestrator {
         (kernel, initrd)
     }
 
+    /// Boot the `--vm-pool-size` VMs of the warm pool in the background.
+    /// Needs guest images and an undo directory, and a Unix host: the pool
+    /// hot-plugs shares over QMP.
+    pub fn start_warm_pool(&self) {
+        let size = self.cli_args.vm_pool_size;
+        if size == 0 || cfg!(target_os = "windows") || self.warm_pool.get().is_some() {
+            return;
+        }
+        let (Some(kernel_path), Some(initrd_path)) = self.resolve_guest_images() else {
+            return;
+        };
+        let Some(undo_dir) = &self.cli_args.undo_dir else {
+            return;
+        };
+        let template = QemuConfig {
+            qemu_binary: self.cli_args.qemu_binary.clone(),
+            kernel_path,
+            initrd_path,
+            rootfs_path: self.cli_args.rootfs_path.clone(),
+            memory_mb: self.cli_args.memory_mb,
+            cpus: self.cli_args.cpus,
+            working_dirs: vec![],
+            control_socket_path: PathBuf::new(),
+            fs_socket_paths: vec![],
+            fs_transports: vec![],
+            vm_mode: "ephemeral".to_string(),
+            mount_names: vec![],
+            qmp_socket_path: None,
+            incoming_state: None,
+            hotplug_ports: warm_pool::HOTPLUG_PORTS,
+            dax_window_mb: self.cli_args.virtiofs_dax_window_mb,
+            extra_args: vec![],
+        };
+        let boot: BootVm<QemuProcess> = Arc::new(move |dir: &Path| {
+            let mut config = template.clone();
+            config.control_socket_path = dir.join("control.sock");
+            config.qmp_socket_path = Some(dir.join("qmp.sock"));
+            QemuProcess::spawn(config)
+        });
+        let pool = WarmPool::new(size, undo_dir.join(".pool"), boot);
+        pool.fill();
+        let _ = self.warm_pool.set(pool);
+    }
+
+    /// Watch the session's VM for crashes, relaunching it with `launcher`
+    /// under `--vm-auto-restart`.
+    fn spawn_vm_monitor(&self, launcher: VmLauncher) -> tokio::task::JoinHandle<()> {
+        let host = Arc::new(SessionVm {
+            state: Arc::clone(&self.state),
+            launcher,
+        });
+        tokio::spawn(vm_monitor::run_vm_monitor(
+            host,
+            Arc::clone(&self.clock),
+            self.event_sender.clone(),
+            self.warnings.clone(),
+            self.cli_args.vm_auto_restart,
+        ))
+    }
+
+    /// Compact the session's undo logs whenever it is idle, unless
+    /// `[compaction]` is disabled. Needs a tokio runtime.
+    fn spawn_idle_compaction(&self) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.compaction_config.enabled {
+            return None;
+        }
+        let handle = tokio::runtime::Handle::try_current().ok()?;
+        Some(handle.spawn(idle_compaction::run_idle_compaction(
+            Arc::new(SessionCompaction(Arc::clone(&self.state))),
+            Arc::clone(&self.clock),
+            self.event_sender.clone(),
+            self.compaction_config.clone(),
+        )))
+    }
+
+    /// Time the session's open steps against `max_step_duration_seconds`.
+    /// Needs a tokio runtime.
+    fn spawn_step_watchdog(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let handle = tokio::runtime::Handle::try_current().ok()?;
+        Some(handle.spawn(step_watchdog::run_step_watchdog(
+            Arc::new(SessionSteps(Arc::clone(&self.state))),
+            Arc::clone(&self.clock),
+        )))
+    }
+
+    /// Readiness checks over this orchestrator's session, for the health socket.
+    pub fn readiness_source(&self) -> Arc<dyn ReadinessSource> {
+        Arc::new(SessionReadiness(Arc::clone(&self.state)))
+    }
+
+    /// Gauges of this orchestrator's session, for the metrics endpoint.
+    pub fn metrics_source(&self) -> Arc<dyn MetricsSource> {
+        Arc::new(SessionMetrics(Arc::clone(&self.state)))
+    }
+
+    /// Directory holding the VM control and filesystem sockets.
+    fn socket_dir(&self) -> Option<PathBuf> {
+        self.cli_args.undo_dir.as_ref().map(|dir| dir.join(".sockets"))
+    }
+
+    /// Audit for resources left behind by a previous run.
+    ///
+    /// Called once at startup, before any session is created. With
+    /// `auto_cleanup`, everything found is stopped or removed and only
+    /// failures are reported; otherwise all findings are reported via
+    /// `event.stale_resources` and left for a `system.cleanup` request.
+    pub fn audit_stale_resources(&self, auto_cleanup: bool) {
+        let Some(socket_dir) = self.socket_dir() else {
+            return;
+        };
+        let mut resources = stale_resources::audit(&socket_dir);
+        if resources.is_empty() {
+            return;
+        }
+
+        if auto_cleanup {
+            let outcome = stale_resources::cleanup(resources);
+            for resource in &outcome.cleaned {
+                log_info!(
+                    "orchestrator",
+                    "cleaned up stale {}: {}",
+                    resource.kind(),
+                    resource.describe()
+                );
+            }
+            resources = outcome.failed.into_iter().map(|(resource, _)| resource).collect();
+            if resources.is_empty() {
+                return;
+            }
+        }
+
+        let _ = self.event_sender.send(Event::StaleResources {
+            resources: resources.iter().map(StaleResource::to_report).collect(),
+        });
+    }
+
     /// Create a session from a `session.start` payload.
     fn do_session_start(
         &self,
@@ -155,6 +696,9 @@ impl Orchestrator {
         if matches!(*state, SessionState::Active(_)) {
             return Err(AgentError::SessionAlreadyActive);
         }
+        self.warnings.clear();
+        let undo_mode = payload.undo;
+        let undo_enabled = undo_mode == UndoMode::Enabled;
 
         let working_dirs: Vec<PathBuf> = if payload.working_directories.is_empty() {
             self.cli_args.working_dirs.clone()
@@ -174,10 +718,59 @@ impl Orchestrator {
                 });
             }
         }
+        self.dir_claims.check(&working_dirs)?;
 
         // Generate self-documenting mount names for each working directory.
         let mount_names = crate::qemu::generate_mount_names(&working_dirs);
 
+        // Directories from the command line get the platform default backend.
+        let mount_backends: Vec<MountBackend> = (0..working_dirs.len())
+            .map(|index| {
+                payload
+                    .working_directories
+                    .get(index)
+                    .and_then(|dir| dir.backend)
+                    .unwrap_or_else(MountBackend::platform_default)
+            })
+            .collect();
+        for (dir, backend) in working_dirs.iter().zip(&mount_backends) {
+            if !fs_backend::backend_available(*backend) {
+                return Err(AgentError::UnsupportedBackend {
+                    backend: backend.as_str().to_string(),
+                    path: dir.display().to_string(),
+                });
+            }
+        }
+        let read_only_dirs: Vec<bool> = (0..working_dirs.len())
+            .map(|index| payload.working_directories.get(index).is_some_and(|dir| dir.read_only))
+            .collect();
+        // The 9P server has no read-only mode.
+        if mount_backends.iter().zip(&read_only_dirs).any(|(backend, read_only)| {
+            *read_only && *backend == MountBackend::P9
+        }) {
+            return Err(AgentError::NotImplemented {
+                feature: "read-only p9 mounts".to_string(),
+            });
+        }
+        let exclusions: Vec<Vec<String>> = (0..working_dirs.len())
+            .map(|index| {
+                payload
+                    .working_directories
+                    .get(index)
+                    .map(|dir| dir.exclude.clone())
+                    .unwrap_or_default()
+            })
+            .collect();
+        // Only the intercepted backend sees every lookup to hide paths from.
+        let unsupported = mount_backends.iter().zip(&exclusions).find(|(backend, exclude)| {
+            !exclude.is_empty() && **backend != MountBackend::Intercepted
+        });
+        if let Some((backend, _)) = unsupported {
+            return Err(AgentError::NotImplemented {
+                feature: format!("exclude patterns on {} mounts", backend.as_str()),
+            });
+        }
+
         // Validate undo directory does not overlap with any working directory
         let undo_dir = self.cli_args.undo_dir.as_ref().ok_or_else(|| AgentError::Io(
             std::io::Error::new(std::io::ErrorKind::InvalidInput, "No undo directory configured"),
@@ -185,6 +778,31 @@ impl Orchestrator {
         for dir in &working_dirs {
             check_paths_overlap(dir, undo_dir)?;
         }
+        // Overlays live under the undo root even with undo disabled: the
+        // guest's changes have nowhere else to wait for fs.commit.
+        let overlay_dirs: Vec<Option<PathBuf>> = working_dirs
+            .iter()
+            .enumerate()
+            .map(|(index, dir)| {
+                let overlay = payload.working_directories.get(index).is_some_and(|d| d.overlay);
+                overlay.then(|| overlay::upper_dir(undo_dir, dir))
+            })
+            .collect();
+        for (dir, upper) in working_dirs.iter().zip(&overlay_dirs) {
+            if let Some(upper) = upper {
+                overlay::prepare(dir, upper)?;
+            }
+        }
+        // What the guest is served of each directory.
+        let shared_dirs: Vec<PathBuf> = working_dirs
+            .iter()
+            .zip(&overlay_dirs)
+            .map(|(dir, upper)| upper.clone().unwrap_or_else(|| dir.clone()))
+            .collect();
+        let fs_traces: Vec<Arc<FsTrace>> =
+            working_dirs.iter().map(|_| Arc::new(FsTrace::default())).collect();
+        let record = SessionRecord::new(&payload, &working_dirs);
+        let undo_root = undo_dir.clone();
 
         // Check VM availability early so we know whether to wire safeguards.
         // Safeguards use a blocking channel that would deadlock in host-only mode
@@ -194,8 +812,9 @@ impl Orchestrator {
         let vm_available = resolved_kernel.is_some() && resolved_initrd.is_some();
 
         // Create safeguard channel if VM is available (filesystem ops run on
-        // separate backend threads, so blocking is safe).
-        let safeguard_sender = if vm_available {
+        // separate backend threads, so blocking is safe). Safeguards are
+        // checked by the undo interceptors, so none without undo.
+        let safeguard_sender = if vm_available && undo_enabled {
             let (sender, receiver) = mpsc::unbounded_channel::<PendingSafeguard>();
             *self.safeguard_receiver.lock().unwrap() = Some(receiver);
             Some(sender)
@@ -203,38 +822,52 @@ impl Orchestrator {
             None
         };
 
+        let symlink_policy = payload.symlink_policy.unwrap_or_default();
+        let coherent_capture = payload.coherent_capture.clone().unwrap_or_default();
+        let case_sensitivity = payload.case_sensitivity.unwrap_or_default();
         let mut interceptors = Vec::with_capacity(working_dirs.len());
         let mut undo_dirs = Vec::with_capacity(working_dirs.len());
 
-        for working_dir in &working_dirs {
+        // With undo disabled no interceptor is built, so nothing is recovered,
+        // logged or barriered and the directories are used as they are.
+        let undo_working_dirs: &[PathBuf] = if undo_enabled { &working_dirs } else { &[] };
+        for working_dir in undo_working_dirs {
             let undo_dir = undo_dir.join(undo_subdir_name(working_dir));
-            let interceptor = if let Some(ref sender) = safeguard_sender {
+            let mut builder = UndoInterceptor::builder(working_dir.clone(), undo_dir.clone())
+                .symlink_policy(symlink_policy)
+                .coherent_capture(coherent_capture.clone())
+                .case_sensitivity(case_sensitivity);
+            if let Some(ref sender) = safeguard_sender {
                 use crate::safeguard_bridge::SafeguardBridge;
-                UndoInterceptor::new(
-                    working_dir.clone(),
-                    undo_dir.clone(),
-                    UndoConfig {
-                        policy: codeagent_common::ExternalModificationPolicy::Barrier,
-                        safeguard_config: SafeguardConfig::default(),
-                        safeguard_handler: Some(Box::new(SafeguardBridge::new(sender.clone()))),
-                        ..Default::default()
-                    },
-                )
-            } else {
-                UndoInterceptor::new_default(working_dir.clone(), undo_dir.clone())
-            };
+                builder = builder
+                    .external_modification(ExternalModificationPolicy::Barrier.into())
+                    .safeguards(
+                        SafeguardConfig::default(),
+                        Box::new(SafeguardBridge::new(sender.clone(), undo_dir.clone())),
+                    );
+            }
+            let interceptor = builder.build();
 
             // Run crash recovery
             if let Ok(Some(recovery)) = interceptor.recover() {
                 let _ = self.event_sender.send(Event::Recovery {
                     paths_restored: recovery.paths_restored,
                     paths_deleted: recovery.paths_deleted,
+                    rollbacks_reverted: recovery.rollbacks_reverted,
+                    steps_rolled_back: recovery.steps_rolled_back,
+                    steps_evicted: recovery.steps_evicted,
+                    steps_squashed: recovery.steps_squashed,
                 });
             }
 
             // Check for version mismatch
             if let Some((expected, found)) = interceptor.version_mismatch() {
                 let _ = self.event_sender.send(Event::UndoVersionMismatch {
+                    expected_version: expected.clone(),
+                    found_version: found.clone(),
+                });
+                self.warnings.report(SandboxWarning::UndoDisabled {
+                    working_dir: working_dir.display().to_string(),
                     expected_version: expected,
                     found_version: found,
                 });
@@ -277,12 +910,27 @@ impl Orchestrator {
             std::time::Duration::from_millis(self.file_watcher_config.debounce_ms);
         let recent_writes = Arc::new(RecentBackendWrites::new(recent_writes_ttl));
 
+        // Built here rather than by the watcher so that undo.reload_ignores
+        // can reach them.
+        let gitignore_filters: Vec<Arc<GitignoreFilter>> =
+            if undo_enabled
+                && self.file_watcher_config.enabled
+                && self.file_watcher_config.use_gitignore
+            {
+                working_dirs
+                    .iter()
+                    .map(|dir| Arc::new(GitignoreFilter::build(dir)))
+                    .collect()
+            } else {
+                Vec::new()
+            };
         let watcher_config = {
             let mut config = fs_watcher::FsWatcherConfig {
                 debounce: watcher_tick,
                 exclude_patterns: fs_watcher::FsWatcherConfig::default().exclude_patterns,
                 enabled: self.file_watcher_config.enabled,
                 use_gitignore: self.file_watcher_config.use_gitignore,
+                gitignore_filters: gitignore_filters.clone(),
             };
             config
                 .exclude_patterns
@@ -290,71 +938,115 @@ impl Orchestrator {
             config
         };
 
-        let fs_watcher_handle = fs_watcher::spawn_fs_watcher(
-            working_dirs.clone(),
-            undo_dirs.clone(),
-            interceptors.clone(),
-            recent_writes.clone(),
-            self.event_sender.clone(),
-            watcher_config,
-        );
+        // The watcher only exists to place barriers in the undo log.
+        let fs_watcher_handle = if undo_enabled {
+            fs_watcher::spawn_fs_watcher(
+                working_dirs.clone(),
+                undo_dirs.clone(),
+                interceptors.clone(),
+                recent_writes.clone(),
+                self.warnings.clone(),
+                self.event_sender.clone(),
+                watcher_config,
+            )
+        } else {
+            None
+        };
+        let recent_writes = undo_enabled.then_some(recent_writes);
 
         // Launch VM if available (guest images resolved above).
+        let mut vm_resumed = false;
         let (vm_status, backend_name) = if vm_available {
-            match self.launch_vm(
-                &working_dirs,
-                &mount_names,
-                &interceptors,
-                &recent_writes,
-                resolved_kernel.unwrap(),
-                resolved_initrd.unwrap(),
-            ) {
+            use crate::recent_writes::WriteTrackingInterceptor;
+            // Backends record into the undo interceptors, or pass writes
+            // straight through when undo is disabled.
+            let (mut write_interceptors, step_manager): (
+                Vec<Arc<dyn WriteInterceptor>>,
+                Arc<dyn codeagent_common::StepManager>,
+            ) = match (&recent_writes, interceptors.first()) {
+                (Some(recent_writes), Some(primary)) => (
+                    interceptors
+                        .iter()
+                        .map(|interceptor| {
+                            Arc::new(WriteTrackingInterceptor::new(
+                                interceptor.clone(),
+                                recent_writes.clone(),
+                            )) as Arc<dyn WriteInterceptor>
+                        })
+                        .collect(),
+                    primary.clone(),
+                ),
+                _ => {
+                    let passthrough = Arc::new(PassthroughInterceptor::new());
+                    (vec![passthrough.clone() as Arc<dyn WriteInterceptor>; working_dirs.len()], passthrough)
+                }
+            };
+            // Guest writes to an overlay are recorded by fs.commit instead.
+            for (index, upper) in overlay_dirs.iter().enumerate() {
+                if upper.is_some() {
+                    write_interceptors[index] = Arc::new(PassthroughInterceptor::new());
+                }
+            }
+            let env_profile = EnvProfile::new();
+            let launcher = VmLauncher {
+                cli_args: self.cli_args.clone(),
+                event_sender: self.event_sender.clone(),
+                command_waiter: self.command_waiter.clone(),
+                command_timeouts: self.command_timeouts.clone(),
+                warnings: self.warnings.clone(),
+                clock: Arc::clone(&self.clock),
+                working_dirs: shared_dirs,
+                mount_names: mount_names.clone(),
+                mount_backends: mount_backends.clone(),
+                read_only_dirs: read_only_dirs.clone(),
+                exclusions: exclusions.clone(),
+                traces: fs_traces.clone(),
+                write_interceptors,
+                interceptors: interceptors.clone(),
+                step_manager,
+                env_profile: Arc::clone(&env_profile),
+                kernel_path: resolved_kernel.unwrap(),
+                initrd_path: resolved_initrd.unwrap(),
+                vm_mode: payload.vm_mode.clone(),
+                warm_pool: self.warm_pool.get().cloned(),
+                quiescence: Arc::clone(&self.quiescence),
+            };
+            match launcher.launch() {
                 Ok(vm_session_parts) => {
+                    vm_resumed = vm_session_parts.resumed;
                     // Spawn the safeguard consumer task: receives safeguard
                     // events from interceptors (via SafeguardBridge) and
                     // forwards them as STDIO events. The responder is stored
                     // in session.pending_safeguards so safeguard.confirm can
                     // unblock the filesystem thread.
-                    let safeguard_bridge_handle = {
-                        let mut guard = self.safeguard_receiver.lock().unwrap();
-                        guard.take().map(|mut receiver| {
-                            let event_sender = self.event_sender.clone();
-                            let session_state = self.state.clone();
-                            tokio::spawn(async move {
-                                while let Some(pending) = receiver.recv().await {
-                                    let event = &pending.event;
-                                    let safeguard_id = event.safeguard_id.to_string();
-                                    let _ = event_sender.send(Event::SafeguardTriggered {
-                                        step_id: event.step_id,
-                                        safeguard_id: safeguard_id.clone(),
-                                        kind: format!("{:?}", event.kind),
-                                        sample_paths: event.sample_paths.clone(),
-                                        message: format!(
-                                            "Safeguard triggered: {:?} (step {})",
-                                            event.kind, event.step_id
-                                        ),
-                                    });
-                                    // Store the responder so safeguard.confirm can send the decision.
-                                    let mut state = session_state.lock().unwrap();
-                                    if let SessionState::Active(session) = &mut *state {
-                                        session.pending_safeguards.insert(
-                                            safeguard_id,
-                                            pending.responder,
-                                        );
-                                    }
-                                }
-                            })
-                        })
+                    let pending_safeguards =
+                        Arc::new(PendingSafeguards::with_clock(Arc::clone(&self.clock)));
+                    let canceller = match (
+                        &vm_session_parts.control_writer,
+                        &vm_session_parts.control_handler,
+                    ) {
+                        (Some(writer), Some(handler)) => Some(Arc::new(CommandCanceller {
+                            control_writer: writer.clone(),
+                            control_handler: Arc::clone(handler),
+                        })),
+                        _ => None,
                     };
+                    let safeguard_bridge_handle =
+                        self.spawn_safeguard_forwarder(&pending_safeguards, canceller);
 
                     let session = Session {
                         interceptors,
                         working_dirs: working_dirs.clone(),
                         mount_names: mount_names.clone(),
+                        overlay_dirs: overlay_dirs.clone(),
+                        read_only_dirs: read_only_dirs.clone(),
+                        fs_traces,
                         undo_dirs,
+                        undo: payload.undo,
                         vm_mode: payload.vm_mode.clone(),
                         safeguard_config: SafeguardConfig::default(),
-                        pending_safeguards: Default::default(),
+                        pending_safeguards,
+                        env_profile,
                         last_start_payload: Some(payload),
                         qemu_process: vm_session_parts.qemu_process,
                         fs_backends: vm_session_parts.fs_backends,
@@ -364,92 +1056,148 @@ impl Orchestrator {
                         event_bridge_handle: vm_session_parts.event_bridge_handle,
                         control_reader_handle: vm_session_parts.control_reader_handle,
                         control_writer_handle: vm_session_parts.control_writer_handle,
+                        vm_monitor_handle: Some(self.spawn_vm_monitor(launcher)),
+                        compaction_handle: None,
+                        step_watchdog_handle: None,
                         socket_dir: vm_session_parts.socket_dir,
                         next_command_id: Arc::new(AtomicU64::new(initial_command_id)),
                         next_api_step_id: Arc::new(AtomicI64::new(1_000_000)),
                         fs_watcher_handle,
-                        recent_writes: Some(recent_writes),
+                        recent_writes,
+                        gitignore_filters,
+                        mount_backends: mount_backends.clone(),
                         safeguard_bridge_handle,
                     };
 
                     *state = SessionState::Active(Box::new(session));
 
-                    let backend = if cfg!(target_os = "windows") { "9p" } else { "virtiofsd" };
-                    ("running", backend)
+                    ("running", fs_backend::vm_backend_name(&mount_backends))
                 }
                 Err(error) => {
                     // VM launch failed — fall back to non-VM mode and report
-                    let _ = self.event_sender.send(Event::Warning {
-                        code: "vm_launch_failed".to_string(),
-                        message: format!("VM launch failed, falling back to host-only mode: {error}"),
+                    self.warnings.report(SandboxWarning::VmLaunchFailed {
+                        reason: error.to_string(),
                     });
-                    let session = Self::create_non_vm_session(
-                        interceptors, working_dirs.clone(), mount_names.clone(), undo_dirs, payload,
-                        fs_watcher_handle, Some(recent_writes), initial_command_id,
+                    let mut session = Self::create_non_vm_session(
+                        interceptors, working_dirs.clone(), mount_names.clone(),
+                        overlay_dirs.clone(), read_only_dirs.clone(), fs_traces, undo_dirs, payload,
+                        fs_watcher_handle, recent_writes, gitignore_filters,
+                        mount_backends.clone(), initial_command_id,
                     );
+                    // The interceptors were built with a safeguard bridge,
+                    // which still needs its events forwarded and answered.
+                    let pending_safeguards =
+                        Arc::new(PendingSafeguards::with_clock(Arc::clone(&self.clock)));
+                    session.safeguard_bridge_handle =
+                        self.spawn_safeguard_forwarder(&pending_safeguards, None);
+                    session.pending_safeguards = pending_safeguards;
                     *state = SessionState::Active(Box::new(session));
                     ("unavailable", "none")
                 }
             }
         } else {
             // No VM components configured — run in host-only mode
-            let missing: Vec<&str> = [
+            let missing: Vec<String> = [
                 self.cli_args.kernel_path.is_none().then_some("kernel"),
                 self.cli_args.initrd_path.is_none().then_some("initrd"),
             ]
             .into_iter()
             .flatten()
+            .map(String::from)
             .collect();
-            let _ = self.event_sender.send(Event::Warning {
-                code: "vm_not_configured".to_string(),
-                message: format!(
-                    "VM not configured (missing: {}), running in host-only mode. \
-                     Pass --kernel-path and --initrd-path to enable VM mode.",
-                    missing.join(", ")
-                ),
-            });
+            self.warnings.report(SandboxWarning::VmNotConfigured { missing });
             let session = Self::create_non_vm_session(
-                interceptors, working_dirs.clone(), mount_names.clone(), undo_dirs, payload,
-                fs_watcher_handle, Some(recent_writes), initial_command_id,
+                interceptors, working_dirs.clone(), mount_names.clone(), overlay_dirs.clone(),
+                read_only_dirs.clone(), fs_traces, undo_dirs, payload, fs_watcher_handle, recent_writes, gitignore_filters,
+                mount_backends.clone(), initial_command_id,
             );
             *state = SessionState::Active(Box::new(session));
             ("unavailable", "none")
         };
+        if let SessionState::Active(session) = &mut *state {
+            if undo_enabled {
+                session.compaction_handle = self.spawn_idle_compaction();
+                session.step_watchdog_handle = self.spawn_step_watchdog();
+            }
+        }
+
+        self.dir_claims.claim(&working_dirs);
+
+        // Sessions without undo leave the undo directory untouched.
+        let recorded = if undo_enabled { record.save(&undo_root) } else { Ok(()) };
+        if let Err(error) = recorded {
+            log_warn!("orchestrator", "cannot record the session for session.resume: {error}");
+        }
 
         Ok(json!({
             "status": "ok",
             "vm_status": vm_status,
+            "vm_resumed": vm_resumed,
             "backend": backend_name,
+            "protection_level": session::protection_level(vm_status == "running", undo_mode),
             "mount_points": working_dirs.iter().enumerate().map(|(i, d)| {
                 json!({
                     "index": i,
                     "path": d.display().to_string(),
-                    "mount_path": format!("/mnt/working/{}", mount_names[i]),
+                    "mount_path": guest_cwd::mount_point(&mount_names[i]),
+                    "backend": if vm_status == "running" { mount_backends[i].as_str() } else { "none" },
+                    "read_only": read_only_dirs[i],
+                    "exclude": exclusions[i],
+                    "overlay": overlay_dirs[i],
                 })
             }).collect::<Vec<_>>(),
         }))
     }
 
+    /// Forward the events of the session's safeguard bridge to `pending`,
+    /// if its interceptors were built with one. Without a tokio runtime the
+    /// bridge is closed, so its safeguards are denied rather than left
+    /// waiting.
+    fn spawn_safeguard_forwarder(
+        &self,
+        pending: &Arc<PendingSafeguards>,
+        canceller: Option<Arc<CommandCanceller>>,
+    ) -> Option<tokio::task::JoinHandle<()>> {
+        let receiver = self.safeguard_receiver.lock().unwrap().take()?;
+        let handle = tokio::runtime::Handle::try_current().ok()?;
+        Some(handle.spawn(safeguard_bridge::forward_pending(
+            receiver,
+            Arc::clone(pending),
+            self.event_sender.clone(),
+            canceller,
+        )))
+    }
+
     /// Create a session without VM components.
     #[allow(clippy::too_many_arguments)]
     fn create_non_vm_session(
         interceptors: Vec<Arc<UndoInterceptor>>,
         working_dirs: Vec<PathBuf>,
         mount_names: Vec<String>,
+        overlay_dirs: Vec<Option<PathBuf>>,
+        read_only_dirs: Vec<bool>,
+        fs_traces: Vec<Arc<FsTrace>>,
         undo_dirs: Vec<PathBuf>,
         payload: SessionStartPayload,
         fs_watcher_handle: Option<tokio::task::JoinHandle<()>>,
         recent_writes: Option<Arc<RecentBackendWrites>>,
+        gitignore_filters: Vec<Arc<GitignoreFilter>>,
+        mount_backends: Vec<MountBackend>,
         initial_command_id: u64,
     ) -> Session {
         Session {
             interceptors,
             working_dirs,
             mount_names,
+            overlay_dirs,
+            read_only_dirs,
+            fs_traces,
             undo_dirs,
+            undo: payload.undo,
             vm_mode: payload.vm_mode.clone(),
             safeguard_config: SafeguardConfig::default(),
             pending_safeguards: Default::default(),
+            env_profile: EnvProfile::new(),
             last_start_payload: Some(payload),
             qemu_process: None,
             fs_backends: vec![],
@@ -459,267 +1207,79 @@ impl Orchestrator {
             event_bridge_handle: None,
             control_reader_handle: None,
             control_writer_handle: None,
+            vm_monitor_handle: None,
+            compaction_handle: None,
+            step_watchdog_handle: None,
             socket_dir: None,
             next_command_id: Arc::new(AtomicU64::new(initial_command_id)),
             next_api_step_id: Arc::new(AtomicI64::new(1_000_000)),
             fs_watcher_handle,
             recent_writes,
+            gitignore_filters,
+            mount_backends,
             safeguard_bridge_handle: None,
         }
     }
 
-    /// Launch VM components: filesystem backends, QEMU, control channel.
-    fn launch_vm(
-        &self,
-        working_dirs: &[PathBuf],
-        mount_names: &[String],
-        interceptors: &[Arc<UndoInterceptor>],
-        recent_writes: &Arc<RecentBackendWrites>,
-        kernel_path: PathBuf,
-        initrd_path: PathBuf,
-    ) -> Result<VmSessionParts, AgentError> {
-        let socket_dir = self.cli_args.undo_dir.as_ref()
-            .expect("undo_dir must be set before launching VM")
-            .join(".sockets");
-        std::fs::create_dir_all(&socket_dir)?;
+    fn do_session_stop(&self) -> Result<serde_json::Value, AgentError> {
+        self.stop_session(true)
+    }
 
-        let control_socket_path = socket_dir.join("control.sock");
+    /// Stop the active session, saving the state of a persistent session's
+    /// VM when `save_vm_state` is set.
+    fn stop_session(&self, save_vm_state: bool) -> Result<serde_json::Value, AgentError> {
+        let mut state = self.state.lock().unwrap();
+        match &mut *state {
+            SessionState::Idle => Err(AgentError::SessionNotActive),
+            SessionState::Active(session) => {
+                // Stop filesystem watcher
+                if let Some(handle) = session.fs_watcher_handle.take() {
+                    handle.abort();
+                }
 
-        // Create InFlightTracker before backends so they can share it with
-        // the control channel handler for quiescence detection.
-        let in_flight_tracker = InFlightTracker::new();
+                // Stop safeguard bridge task
+                if let Some(handle) = session.safeguard_bridge_handle.take() {
+                    handle.abort();
+                }
 
-        // 1. Start filesystem backends
-        let mut fs_backends: Vec<Box<dyn crate::fs_backend::FilesystemBackend>> = Vec::new();
-        let mut fs_socket_paths = Vec::new();
+                // Stop background tasks, the VM monitor first so it does not
+                // take the VM's exit below for a crash.
+                if let Some(handle) = session.vm_monitor_handle.take() {
+                    handle.abort();
+                }
+                if let Some(handle) = session.compaction_handle.take() {
+                    handle.abort();
+                }
+                if let Some(handle) = session.step_watchdog_handle.take() {
+                    handle.abort();
+                }
+                if let Some(handle) = session.control_reader_handle.take() {
+                    handle.abort();
+                }
+                if let Some(handle) = session.control_writer_handle.take() {
+                    handle.abort();
+                }
+                if let Some(handle) = session.event_bridge_handle.take() {
+                    handle.abort();
+                }
 
-        #[cfg(unix)]
-        {
-            use crate::fs_backend::{FilesystemBackend, InterceptedBackend};
-            use crate::recent_writes::WriteTrackingInterceptor;
-            for (index, working_dir) in working_dirs.iter().enumerate() {
-                let fs_socket = socket_dir.join(format!("vfs{index}.sock"));
-                let tracking_interceptor: Arc<dyn codeagent_interceptor::write_interceptor::WriteInterceptor> =
-                    Arc::new(WriteTrackingInterceptor::new(
-                        interceptors[index].clone(),
-                        recent_writes.clone(),
-                    ));
-                let mut backend = InterceptedBackend::new(
-                    working_dir.clone(),
-                    fs_socket.clone(),
-                    tracking_interceptor,
-                    in_flight_tracker.clone(),
-                );
-                backend.start()?;
-                fs_socket_paths.push(fs_socket);
-                fs_backends.push(Box::new(backend));
-            }
-        }
+                // Drop the control writer sender so the writer task exits
+                session.control_writer.take();
 
-        #[cfg(target_os = "windows")]
-        {
-            use crate::fs_backend::{FilesystemBackend, P9Backend};
-            use crate::recent_writes::WriteTrackingInterceptor;
-            for (index, working_dir) in working_dirs.iter().enumerate() {
-                let fs_socket = socket_dir.join(format!("p9fs{index}.addr"));
-                let tracking_interceptor: Arc<dyn codeagent_interceptor::write_interceptor::WriteInterceptor> =
-                    Arc::new(WriteTrackingInterceptor::new(
-                        interceptors[index].clone(),
-                        recent_writes.clone(),
-                    ));
-                let mut backend = P9Backend::new(
-                    working_dir.clone(),
-                    fs_socket.clone(),
-                    tracking_interceptor,
-                    in_flight_tracker.clone(),
-                );
-                backend.start()?;
-                fs_socket_paths.push(fs_socket);
-                fs_backends.push(Box::new(backend));
-            }
-        }
-
-        // 2. On Windows, bind a TCP listener for the control channel before
-        //    QEMU starts. QEMU will connect to this address as a client.
-        //    The address file must exist before build_args() reads it.
-        #[cfg(target_os = "windows")]
-        let control_listener = {
-            let listener = std::net::TcpListener::bind("127.0.0.1:0")
-                .map_err(|error| AgentError::ControlChannelFailed {
-                    reason: format!("failed to bind control channel listener: {error}"),
-                })?;
-            let addr = listener.local_addr()
-                .map_err(|error| AgentError::ControlChannelFailed {
-                    reason: format!("failed to get control listener address: {error}"),
-                })?;
-            std::fs::write(&control_socket_path, addr.to_string())?;
-            listener
-        };
-
-        // 3. Build QEMU config and spawn
-        let config = QemuConfig {
-            qemu_binary: self.cli_args.qemu_binary.clone(),
-            kernel_path,
-            initrd_path,
-            rootfs_path: self.cli_args.rootfs_path.clone(),
-            memory_mb: self.cli_args.memory_mb,
-            cpus: self.cli_args.cpus,
-            working_dirs: working_dirs.to_vec(),
-            control_socket_path: control_socket_path.clone(),
-            fs_socket_paths,
-            vm_mode: self.cli_args.vm_mode.clone(),
-            mount_names: mount_names.to_vec(),
-            extra_args: vec![],
-        };
-
-        let qemu_process = QemuProcess::spawn(config)?;
-
-        // 4. Connect to control channel (platform-specific transport)
-        //
-        // Unix: connect to the Unix domain socket that QEMU created (server mode).
-        // Windows: accept the TCP connection from QEMU (client mode).
-        // Both produce a (reader, writer) pair implementing AsyncRead/AsyncWrite.
-
-        #[cfg(unix)]
-        let (reader, writer) = {
-            let std_stream = std::os::unix::net::UnixStream::connect(&control_socket_path)
-                .map_err(|error| AgentError::ControlChannelFailed {
-                    reason: format!("failed to connect to control socket: {error}"),
-                })?;
-            std_stream
-                .set_nonblocking(true)
-                .map_err(|error| AgentError::ControlChannelFailed {
-                    reason: format!("failed to set socket non-blocking: {error}"),
-                })?;
-            let tokio_stream = tokio::net::UnixStream::from_std(std_stream)
-                .map_err(|error| AgentError::ControlChannelFailed {
-                    reason: format!("failed to convert socket: {error}"),
-                })?;
-            tokio_stream.into_split()
-        };
-
-        #[cfg(target_os = "windows")]
-        let (reader, writer) = {
-            // Accept one connection from QEMU with a polling timeout.
-            // QEMU connects to our TCP listener as a client (server=off).
-            control_listener
-                .set_nonblocking(true)
-                .map_err(|error| AgentError::ControlChannelFailed {
-                    reason: format!("failed to set listener non-blocking: {error}"),
-                })?;
-
-            let start = std::time::Instant::now();
-            let timeout = std::time::Duration::from_secs(30);
-            let poll_interval = std::time::Duration::from_millis(100);
-
-            let stream = loop {
-                match control_listener.accept() {
-                    Ok((stream, _addr)) => break stream,
-                    Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
-                        if start.elapsed() > timeout {
-                            return Err(AgentError::ControlChannelFailed {
-                                reason: "QEMU did not connect to the control channel within 30s"
-                                    .to_string(),
-                            });
+                // Stop QEMU, saving the state of a persistent session's VM
+                // first for the next session to resume.
+                if let Some(mut qemu) = session.qemu_process.take() {
+                    if save_vm_state && session.vm_mode == "persistent" {
+                        if let Some(undo_dir) = &self.cli_args.undo_dir {
+                            let saved = SavedVmState::in_undo_dir(undo_dir);
+                            if let Err(error) = qemu.save_state(&saved) {
+                                saved.discard();
+                                self.warnings.report(SandboxWarning::VmStateSaveFailed {
+                                    reason: error.to_string(),
+                                });
+                            }
                         }
-                        std::thread::sleep(poll_interval);
-                    }
-                    Err(error) => {
-                        return Err(AgentError::ControlChannelFailed {
-                            reason: format!(
-                                "failed to accept control channel connection: {error}"
-                            ),
-                        });
                     }
-                }
-            };
-
-            stream
-                .set_nonblocking(true)
-                .map_err(|error| AgentError::ControlChannelFailed {
-                    reason: format!("failed to set stream non-blocking: {error}"),
-                })?;
-            let tokio_stream = tokio::net::TcpStream::from_std(stream)
-                .map_err(|error| AgentError::ControlChannelFailed {
-                    reason: format!("failed to convert TCP stream: {error}"),
-                })?;
-            tokio_stream.into_split()
-        };
-
-        // 5. Create control channel handler
-        use codeagent_control::{ControlChannelHandler, QuiescenceConfig};
-        use crate::event_bridge::run_event_bridge;
-
-        let quiescence_config = QuiescenceConfig::default();
-
-        let (handler, handler_events) = ControlChannelHandler::new(
-            interceptors[0].clone(),
-            in_flight_tracker.clone(),
-            quiescence_config,
-        );
-        let handler = Arc::new(handler);
-
-        // 6. Spawn event bridge (control events → STDIO events + command waiter)
-        let event_bridge_handle = tokio::spawn(run_event_bridge(
-            handler_events,
-            self.event_sender.clone(),
-            Some(self.command_waiter.clone()),
-        ));
-
-        // 7. Spawn control channel writer and reader tasks
-        let (control_writer_sender, control_writer_handle) =
-            control_bridge::spawn_control_writer(writer);
-
-        let control_reader_handle = control_bridge::spawn_control_reader(
-            reader,
-            handler.clone(),
-            self.event_sender.clone(),
-        );
-
-        Ok(VmSessionParts {
-            qemu_process: Some(qemu_process),
-            fs_backends,
-            in_flight_tracker: Some(in_flight_tracker),
-            control_writer: Some(control_writer_sender),
-            control_handler: Some(handler),
-            event_bridge_handle: Some(event_bridge_handle),
-            control_reader_handle: Some(control_reader_handle),
-            control_writer_handle: Some(control_writer_handle),
-            socket_dir: Some(socket_dir),
-        })
-    }
-
-    fn do_session_stop(&self) -> Result<serde_json::Value, AgentError> {
-        let mut state = self.state.lock().unwrap();
-        match &mut *state {
-            SessionState::Idle => Err(AgentError::SessionNotActive),
-            SessionState::Active(session) => {
-                // Stop filesystem watcher
-                if let Some(handle) = session.fs_watcher_handle.take() {
-                    handle.abort();
-                }
-
-                // Stop safeguard bridge task
-                if let Some(handle) = session.safeguard_bridge_handle.take() {
-                    handle.abort();
-                }
-
-                // Stop background tasks
-                if let Some(handle) = session.control_reader_handle.take() {
-                    handle.abort();
-                }
-                if let Some(handle) = session.control_writer_handle.take() {
-                    handle.abort();
-                }
-                if let Some(handle) = session.event_bridge_handle.take() {
-                    handle.abort();
-                }
-
-                // Drop the control writer sender so the writer task exits
-                session.control_writer.take();
-
-                // Stop QEMU
-                if let Some(mut qemu) = session.qemu_process.take() {
                     let _ = qemu.stop();
                 }
 
@@ -733,12 +1293,105 @@ impl Orchestrator {
                     let _ = std::fs::remove_dir_all(socket_dir);
                 }
 
+                self.dir_claims.release(&session.working_dirs);
                 *state = SessionState::Idle;
+                self.warnings.clear();
+                self.destroy_confirmation.lock().unwrap().take();
                 Ok(json!({}))
             }
         }
     }
 
+    /// Stop the session for good, without saving its VM state, and with
+    /// `delete_undo_log` delete the undo directory and overlay of every
+    /// working directory. Deleting takes two calls: the first lists the
+    /// directories and returns a confirmation token, the second presents
+    /// it.
+    fn do_session_destroy(
+        &self,
+        payload: SessionDestroyPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        if !payload.delete_undo_log {
+            self.stop_session(false).map_err(Self::agent_error_to_stdio)?;
+            self.forget_session_record();
+            return Ok(json!({ "deleted": [], "failed": [] }));
+        }
+
+        let (mut dirs, overlay_dirs): (Vec<PathBuf>, Vec<PathBuf>) =
+            match &*self.state.lock().unwrap() {
+                SessionState::Idle => {
+                    return Err(Self::agent_error_to_stdio(AgentError::SessionNotActive));
+                }
+                SessionState::Active(session) => (
+                    session.undo_dirs.clone(),
+                    session.overlay_dirs.iter().flatten().cloned().collect(),
+                ),
+            };
+        dirs.extend(overlay_dirs.iter().cloned());
+        let paths: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+
+        let Some(confirmation) = payload.confirmation else {
+            let token = destroy_token(&dirs);
+            *self.destroy_confirmation.lock().unwrap() = Some(token.clone());
+            return Ok(json!({ "confirmation": token, "paths": paths }));
+        };
+        if self.destroy_confirmation.lock().unwrap().as_deref() != Some(confirmation.as_str()) {
+            return Err(StdioError::InvalidField {
+                field: "confirmation".to_string(),
+                message: "does not match the token issued for this session; \
+                          request a new one with a session.destroy without confirmation"
+                    .to_string(),
+            });
+        }
+
+        // Stopping drops the interceptors and backends, so nothing writes
+        // into the directories while they are removed.
+        self.stop_session(false).map_err(Self::agent_error_to_stdio)?;
+        self.forget_session_record();
+        let mut deleted = Vec::new();
+        let mut failed = Vec::new();
+        for (dir, path) in dirs.iter().zip(paths) {
+            match std::fs::remove_dir_all(dir) {
+                Ok(()) => deleted.push(path),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => failed.push(json!({ "path": path, "error": error.to_string() })),
+            }
+        }
+        // An overlay's base goes with it.
+        for dir in &overlay_dirs {
+            let _ = std::fs::remove_file(overlay::base_path(dir));
+        }
+        Ok(json!({ "deleted": deleted, "failed": failed }))
+    }
+
+    /// A destroyed session is not offered to `session.resume`, nor is its
+    /// saved VM state to the next start.
+    fn forget_session_record(&self) {
+        if let Some(undo_dir) = &self.cli_args.undo_dir {
+            SessionRecord::remove(undo_dir);
+            SavedVmState::in_undo_dir(undo_dir).discard();
+        }
+    }
+
+    /// Start the session `session.json` records, as `session.start` did.
+    fn do_session_resume(&self) -> Result<serde_json::Value, AgentError> {
+        let undo_dir = self.cli_args.undo_dir.as_ref().ok_or_else(|| {
+            AgentError::NoSessionToResume {
+                reason: "no undo directory configured".to_string(),
+            }
+        })?;
+        let record = SessionRecord::load(undo_dir)
+            .map_err(|error| AgentError::NoSessionToResume {
+                reason: format!("{}: {error}", SessionRecord::path(undo_dir).display()),
+            })?
+            .ok_or_else(|| AgentError::NoSessionToResume {
+                reason: format!("{} does not exist", SessionRecord::path(undo_dir).display()),
+            })?;
+        let mut response = self.do_session_start(record.payload)?;
+        response["resumed_from"] = json!(record.started_at);
+        Ok(response)
+    }
+
     fn do_session_reset(&self) -> Result<serde_json::Value, AgentError> {
         let payload = {
             let state = self.state.lock().unwrap();
@@ -773,6 +1426,8 @@ impl Orchestrator {
                     "state": "active",
                     "vm_mode": session.vm_mode,
                     "vm_status": vm_status,
+                    "undo": session.undo,
+                    "protection_level": session.protection_level(),
                     "working_directories": session.working_dirs.iter().enumerate().map(|(i, d)| {
                         json!({
                             "index": i,
@@ -782,11 +1437,161 @@ impl Orchestrator {
                     "undo_steps": session.interceptors.iter().map(|interceptor| {
                         interceptor.completed_steps().len()
                     }).collect::<Vec<_>>(),
+                    "step_waits": session.interceptors.iter().map(|interceptor| {
+                        interceptor.step_wait_stats()
+                    }).collect::<Vec<_>>(),
+                    "symlink_policy": session.interceptors.iter().map(|interceptor| {
+                        interceptor.symlink_policy()
+                    }).collect::<Vec<_>>(),
+                    "coherent_capture": session.interceptors.iter().map(|interceptor| {
+                        interceptor.coherent_capture_config()
+                    }).collect::<Vec<_>>(),
+                    "resource_limits": session.interceptors.iter().map(|interceptor| {
+                        interceptor.resource_limits()
+                    }).collect::<Vec<_>>(),
+                    "ignore_patterns": session.interceptors.iter().map(|interceptor| {
+                        interceptor.ignore_patterns()
+                    }).collect::<Vec<_>>(),
                 }))
             }
         }
     }
 
+    /// Copy one working directory and its undo history into a new directory.
+    ///
+    /// The copy is taken between steps; if a step opens or closes while the
+    /// tree is being copied, the half-made clone is removed and the request
+    /// fails. The returned `session_start` payload opens the clone with the
+    /// copied history, either after this session stops or in a sandbox that
+    /// shares the same undo root.
+    fn do_session_clone(
+        &self,
+        payload: SessionClonePayload,
+    ) -> Result<serde_json::Value, AgentError> {
+        let undo_root = self.cli_args.undo_dir.clone().ok_or_else(|| AgentError::Io(
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "No undo directory configured"),
+        ))?;
+
+        let (index, interceptor, working_dir, undo_dir, working_dirs, start_payload) = {
+            let state = self.state.lock().unwrap();
+            let session = match &*state {
+                SessionState::Idle => return Err(AgentError::SessionNotActive),
+                SessionState::Active(s) => s,
+            };
+            Self::require_undo(session)?;
+            let index = Self::directory_index(session, payload.directory.as_deref());
+            let (Some(interceptor), Some(working_dir), Some(undo_dir)) = (
+                session.interceptors.get(index),
+                session.working_dirs.get(index),
+                session.undo_dirs.get(index),
+            ) else {
+                return Err(AgentError::InvalidWorkingDir {
+                    path: format!("directory index {index} out of range"),
+                });
+            };
+            (
+                index,
+                Arc::clone(interceptor),
+                working_dir.clone(),
+                undo_dir.clone(),
+                session.working_dirs.clone(),
+                session.last_start_payload.clone().ok_or(AgentError::SessionNotActive)?,
+            )
+        };
+
+        let target = PathBuf::from(&payload.target_dir);
+        let in_use: Vec<&Path> = working_dirs
+            .iter()
+            .map(PathBuf::as_path)
+            .chain(std::iter::once(undo_root.as_path()))
+            .collect();
+        check_clone_target(&target, &in_use)?;
+        let target_undo = undo_root.join(undo_subdir_name(&resolve_lexically(&target)));
+        if target_undo.exists() {
+            return Err(AgentError::InvalidCloneTarget {
+                path: target.display().to_string(),
+                reason: format!("undo history already exists at {}", target_undo.display()),
+            });
+        }
+
+        if let Some(step_id) = interceptor.current_step() {
+            return Err(CodeAgentError::StepAlreadyActive { step_id }.into());
+        }
+        let steps = interceptor.completed_steps();
+
+        let copied = workspace_clone::clone_tree(&working_dir, &target)
+            .and_then(|stats| workspace_clone::clone_undo_dir(&undo_dir, &target_undo).map(|()| stats));
+        let after = interceptor.completed_steps();
+        let raced = interceptor
+            .current_step()
+            .or(if after == steps { None } else { after.last().copied() });
+        let stats = match (copied, raced) {
+            (Ok(stats), None) => stats,
+            (copied, raced) => {
+                let _ = std::fs::remove_dir_all(&target);
+                let _ = std::fs::remove_dir_all(&target_undo);
+                return Err(match raced {
+                    Some(step_id) => CodeAgentError::StepAlreadyActive { step_id }.into(),
+                    None => AgentError::Io(copied.unwrap_err()),
+                });
+            }
+        };
+
+        let session_start = SessionStartPayload {
+            working_directories: vec![WorkingDirectoryConfig {
+                path: target.display().to_string(),
+                label: None,
+                backend: None,
+                read_only: false,
+                exclude: vec![],
+                overlay: false,
+            }],
+            symlink_policy: Some(interceptor.symlink_policy()),
+            coherent_capture: Some(interceptor.coherent_capture_config()),
+            ..start_payload
+        };
+
+        Ok(json!({
+            "directory": index,
+            "working_dir": target.display().to_string(),
+            "undo_dir": target_undo.display().to_string(),
+            "steps": steps,
+            "files_reflinked": stats.reflinked,
+            "files_copied": stats.copied,
+            "session_start": session_start,
+        }))
+    }
+
+    /// Re-run the stale resource audit and clean up everything it finds.
+    ///
+    /// Refused while a VM session is running, since its live sockets and
+    /// processes would be indistinguishable from leftovers.
+    fn do_system_cleanup(&self) -> Result<serde_json::Value, AgentError> {
+        {
+            let state = self.state.lock().unwrap();
+            if let SessionState::Active(session) = &*state {
+                if session.socket_dir.is_some() {
+                    return Err(AgentError::SessionAlreadyActive);
+                }
+            }
+        }
+
+        let resources = match self.socket_dir() {
+            Some(socket_dir) => stale_resources::audit(&socket_dir),
+            None => Vec::new(),
+        };
+        let outcome = stale_resources::cleanup(resources);
+
+        Ok(json!({
+            "cleaned": outcome.cleaned.iter().map(StaleResource::to_report).collect::<Vec<_>>(),
+            "failed": outcome.failed.iter().map(|(resource, error)| {
+                let mut report = serde_json::to_value(resource.to_report()).unwrap_or_default();
+                report["error"] = json!(error);
+                report
+            }).collect::<Vec<_>>(),
+        }))
+    }
+
     /// Get the primary (index 0) interceptor, or the one matching the
     /// optional directory selector.
     fn resolve_interceptor(
@@ -798,27 +1603,9 @@ impl Orchestrator {
             SessionState::Idle => return Err(AgentError::SessionNotActive),
             SessionState::Active(s) => s,
         };
+        Self::require_undo(session)?;
 
-        let index = match directory {
-            None => 0,
-            Some(s) => {
-                if let Ok(i) = s.parse::<usize>() {
-                    i
-                } else {
-                    // Try matching by label
-                    session
-                        .working_dirs
-                        .iter()
-                        .position(|d| {
-                            d.file_name()
-                                .and_then(|n| n.to_str())
-                                .is_some_and(|n| n == s)
-                        })
-                        .unwrap_or(0)
-                }
-            }
-        };
-
+        let index = Self::directory_index(session, directory);
         session
             .interceptors
             .get(index)
@@ -828,6 +1615,29 @@ impl Orchestrator {
             })
     }
 
+    /// Resolve a directory selector (index or directory name) to an index.
+    /// Unknown names and `None` select the first directory.
+    fn directory_index(session: &Session, directory: Option<&str>) -> usize {
+        match directory {
+            None => 0,
+            Some(s) => Self::selected_directory_index(session, s).unwrap_or(0),
+        }
+    }
+
+    /// Resolve a directory selector (index or directory name) to an index,
+    /// or `None` for a name no working directory has.
+    fn selected_directory_index(session: &Session, directory: &str) -> Option<usize> {
+        if let Ok(i) = directory.parse::<usize>() {
+            return Some(i);
+        }
+        // Try matching by label
+        session.working_dirs.iter().position(|d| {
+            d.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n == directory)
+        })
+    }
+
     /// Get the interceptor for the working directory that contains the given path.
     ///
     /// For absolute paths, finds the working directory that contains the path.
@@ -841,95 +1651,444 @@ impl Orchestrator {
             SessionState::Idle => return Err(AgentError::SessionNotActive),
             SessionState::Active(s) => s,
         };
+        Self::require_undo(session)?;
+
+        let i = Self::path_directory_index(session, path);
+        session
+            .interceptors
+            .get(i)
+            .cloned()
+            .ok_or(AgentError::InvalidWorkingDir {
+                path: format!("directory index {i} out of range"),
+            })
+    }
 
+    /// The index of the working directory containing the absolute `path`.
+    /// Relative paths and paths outside every working directory select the
+    /// primary (index 0) directory.
+    fn path_directory_index(session: &Session, path: &str) -> usize {
         let requested = std::path::Path::new(path);
         if requested.is_absolute() {
             for (i, working_dir) in session.working_dirs.iter().enumerate() {
                 if requested.starts_with(working_dir) {
-                    return session
-                        .interceptors
-                        .get(i)
-                        .cloned()
-                        .ok_or(AgentError::InvalidWorkingDir {
-                            path: format!("directory index {i} out of range"),
-                        });
+                    return i;
                 }
                 if let Ok(canonical) = std::fs::canonicalize(working_dir) {
                     if requested.starts_with(&canonical) {
-                        return session
-                            .interceptors
-                            .get(i)
-                            .cloned()
-                            .ok_or(AgentError::InvalidWorkingDir {
-                                path: format!("directory index {i} out of range"),
-                            });
+                        return i;
                     }
                 }
             }
         }
-
-        session
-            .interceptors
-            .first()
-            .cloned()
-            .ok_or(AgentError::SessionNotActive)
+        0
     }
 
-    /// Get the primary working directory path.
-    fn primary_working_dir(&self) -> Result<PathBuf, AgentError> {
-        let state = self.state.lock().unwrap();
-        match &*state {
-            SessionState::Idle => Err(AgentError::SessionNotActive),
-            SessionState::Active(s) => {
-                Ok(s.working_dirs.first().cloned().unwrap_or_default())
-            }
+    /// Fail with a capability error if the session keeps no undo history.
+    fn require_undo(session: &Session) -> Result<(), AgentError> {
+        match session.undo {
+            UndoMode::Enabled => Ok(()),
+            UndoMode::Disabled => Err(AgentError::UndoDisabled),
         }
     }
 
-    /// Get all configured working directory paths.
-    fn all_working_dirs(&self) -> Result<Vec<PathBuf>, AgentError> {
-        let state = self.state.lock().unwrap();
-        match &*state {
-            SessionState::Idle => Err(AgentError::SessionNotActive),
-            SessionState::Active(s) => Ok(s.working_dirs.clone()),
+    /// Fail with a capability error if the working directory `index` is
+    /// shared read-only, so the host must not write it either.
+    fn require_writable(session: &Session, index: usize) -> Result<(), AgentError> {
+        match session.read_only_dirs.get(index) {
+            Some(true) => Err(AgentError::ReadOnlyDirectory {
+                path: session.working_dirs[index].display().to_string(),
+            }),
+            _ => Ok(()),
         }
     }
 
-    /// Resolve a tool path to an absolute filesystem path.
-    ///
-    /// Absolute paths are returned as-is; relative paths are joined with the
-    /// appropriate working directory.
-    fn resolve_target_path(&self, path: &str) -> Result<PathBuf, AgentError> {
-        let requested = std::path::Path::new(path);
-        if requested.is_absolute() {
-            Ok(requested.to_path_buf())
-        } else {
-            let working_dir = self.primary_working_dir()?;
-            Ok(working_dir.join(path))
-        }
+    /// [`Self::require_writable`] for every working directory.
+    fn require_no_read_only_dirs(&self) -> Result<(), AgentError> {
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Idle => return Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => s,
+        };
+        (0..session.working_dirs.len()).try_for_each(|index| Self::require_writable(session, index))
     }
 
-    fn require_active(&self) -> Result<(), AgentError> {
+    /// [`Self::require_writable`] for the working directory containing the
+    /// MCP tool path `path`.
+    fn require_writable_path(&self, path: &str) -> Result<(), AgentError> {
         let state = self.state.lock().unwrap();
-        match &*state {
-            SessionState::Idle => Err(AgentError::SessionNotActive),
-            SessionState::Active(_) => Ok(()),
-        }
+        let session = match &*state {
+            SessionState::Idle => return Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => s,
+        };
+        Self::require_writable(session, Self::path_directory_index(session, path))
     }
 
-    fn agent_error_to_stdio(err: AgentError) -> StdioError {
-        StdioError::InvalidField {
-            field: "session".to_string(),
-            message: err.to_string(),
-        }
-    }
+    /// [`Self::require_writable`] for the working directory `directory`
+    /// selects.
+    fn require_writable_directory(&self, directory: Option<&str>) -> Result<(), AgentError> {
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Idle => return Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => s,
+        };
+        Self::require_writable(session, Self::directory_index(session, directory))
+    }
+
+    /// The interceptor recording an MCP write to `path`, or `None` when the
+    /// session runs with undo disabled and the write is made directly.
+    fn resolve_api_interceptor(
+        &self,
+        path: &str,
+    ) -> Result<Option<Arc<UndoInterceptor>>, McpError> {
+        match self.resolve_interceptor_for_path(path) {
+            Ok(interceptor) => Ok(Some(interceptor)),
+            Err(AgentError::UndoDisabled) => Ok(None),
+            Err(error) => Err(Self::agent_error_to_mcp(error)),
+        }
+    }
+
+    /// The working directory `directory` selects for an `fs.*` write and,
+    /// unless the session runs with undo disabled, its interceptor. Fails if
+    /// the directory is read-only.
+    fn resolve_api_directory(
+        &self,
+        directory: Option<&str>,
+    ) -> Result<(PathBuf, Option<Arc<UndoInterceptor>>), AgentError> {
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Idle => return Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => s,
+        };
+        let index = Self::directory_index(session, directory);
+        let out_of_range = || AgentError::InvalidWorkingDir {
+            path: format!("directory index {index} out of range"),
+        };
+        let working_dir = session.working_dirs.get(index).cloned().ok_or_else(out_of_range)?;
+        Self::require_writable(session, index)?;
+        let interceptor = match session.undo {
+            UndoMode::Enabled => {
+                Some(session.interceptors.get(index).cloned().ok_or_else(out_of_range)?)
+            }
+            UndoMode::Disabled => None,
+        };
+        Ok((working_dir, interceptor))
+    }
+
+    /// The working directory `directory` selects for an `fs.*` read.
+    fn resolve_read_directory(&self, directory: Option<&str>) -> Result<PathBuf, AgentError> {
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Idle => return Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => s,
+        };
+        let index = Self::directory_index(session, directory);
+        session.working_dirs.get(index).cloned().ok_or(AgentError::InvalidWorkingDir {
+            path: format!("directory index {index} out of range"),
+        })
+    }
+
+    /// The upper directory of the overlay working directory `directory`
+    /// selects, `None` if it is shared in place.
+    fn overlay_dir(&self, directory: Option<&str>) -> Result<Option<PathBuf>, AgentError> {
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Idle => return Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => s,
+        };
+        let index = Self::directory_index(session, directory);
+        Ok(session.overlay_dirs.get(index).cloned().flatten())
+    }
+
+    /// The operation trace of the working directory `directory` selects.
+    fn fs_trace_for(&self, directory: Option<&str>) -> Result<Arc<FsTrace>, AgentError> {
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Idle => return Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => s,
+        };
+        let index = Self::directory_index(session, directory);
+        session.fs_traces.get(index).cloned().ok_or_else(|| AgentError::InvalidWorkingDir {
+            path: format!("directory index {index} out of range"),
+        })
+    }
+
+    /// Send the decision `action` names (`allow_once`, `allow_step` or its
+    /// alias `allow`, `allow_session`; anything else denies) to the pending
+    /// safeguard `safeguard_id`. Returns whether it was pending.
+    fn decide_safeguard(
+        &self,
+        safeguard_id: &str,
+        action: &str,
+        decided_by: DecidedBy,
+    ) -> Result<bool, AgentError> {
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Idle => return Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => s,
+        };
+        let decision = match action {
+            "allow_once" => SafeguardDecision::AllowOnce,
+            "allow" | "allow_step" => SafeguardDecision::AllowForStep,
+            "allow_session" => SafeguardDecision::AllowForSession,
+            _ => SafeguardDecision::Deny,
+        };
+        let Some(sender) = session.pending_safeguards.take(safeguard_id) else {
+            return Ok(false);
+        };
+        let _ = sender.send(Verdict {
+            decision,
+            decided_by,
+        });
+        Ok(true)
+    }
+
+    /// The env profile of the active session.
+    fn env_profile(&self) -> Result<Arc<EnvProfile>, AgentError> {
+        match &*self.state.lock().unwrap() {
+            SessionState::Idle => Err(AgentError::SessionNotActive),
+            SessionState::Active(session) => Ok(Arc::clone(&session.env_profile)),
+        }
+    }
+
+    /// Get the primary working directory path.
+    fn primary_working_dir(&self) -> Result<PathBuf, AgentError> {
+        let state = self.state.lock().unwrap();
+        match &*state {
+            SessionState::Idle => Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => {
+                Ok(s.working_dirs.first().cloned().unwrap_or_default())
+            }
+        }
+    }
+
+    /// Get all configured working directory paths.
+    fn all_working_dirs(&self) -> Result<Vec<PathBuf>, AgentError> {
+        let state = self.state.lock().unwrap();
+        match &*state {
+            SessionState::Idle => Err(AgentError::SessionNotActive),
+            SessionState::Active(s) => Ok(s.working_dirs.clone()),
+        }
+    }
+
+    /// Resolve a tool path to an absolute filesystem path.
+    ///
+    /// Absolute paths are returned as-is; relative paths are joined with the
+    /// appropriate working directory.
+    fn resolve_target_path(&self, path: &str) -> Result<PathBuf, AgentError> {
+        let requested = std::path::Path::new(path);
+        if requested.is_absolute() {
+            Ok(requested.to_path_buf())
+        } else {
+            let working_dir = self.primary_working_dir()?;
+            Ok(working_dir.join(path))
+        }
+    }
+
+    /// Run a command in the guest and block until it completes or `timeout`
+    /// elapses. A timed-out command comes back without an exit code; `None`
+    /// means the waiter never saw the command.
+    fn run_in_guest(
+        &self,
+        control_writer: &LaneSender<HostMessage>,
+        control_handler: &ControlChannelHandler<dyn codeagent_common::StepManager>,
+        command_id: u64,
+        command: String,
+        cwd: &str,
+        timeout: Duration,
+    ) -> Result<Option<CommandResult>, AgentError> {
+        let env = match &*self.state.lock().unwrap() {
+            SessionState::Active(session) => session.env_profile.merge(None),
+            SessionState::Idle => None,
+        };
+        // Register with the waiter before sending so early events are captured
+        self.command_waiter.register(command_id);
+        Self::send_to_guest(
+            control_writer,
+            control_handler,
+            command_id,
+            command,
+            env,
+            Some(cwd.to_string()),
+            false,
+            false,
+            None,
+            ResourceLimits::default(),
+        )?;
+
+        // Use block_in_place so tokio can spawn a replacement worker thread
+        // while this one is blocked on the Condvar — otherwise async tasks
+        // (control reader, event bridge, P9 server) may starve.
+        Ok(tokio::task::block_in_place(|| {
+            self.command_waiter.wait_for_completion(command_id, timeout)
+        }))
+    }
+
+    /// Send an exec message to the guest without waiting for it to finish.
+    #[allow(clippy::too_many_arguments)]
+    fn send_to_guest(
+        control_writer: &LaneSender<HostMessage>,
+        control_handler: &ControlChannelHandler<dyn codeagent_common::StepManager>,
+        command_id: u64,
+        command: String,
+        env: Option<std::collections::HashMap<String, String>>,
+        cwd: Option<String>,
+        isolate_fs: bool,
+        pty: bool,
+        max_output_bytes: Option<u64>,
+        limits: ResourceLimits,
+    ) -> Result<(), AgentError> {
+        // Register the command with the control channel handler's state machine
+        // (so it expects the StepStarted response from the VM) and get the
+        // serializable HostMessage back. Uses block_in_place because send_exec
+        // is async (may close an ambient step).
+        let host_msg = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current()
+                .block_on(control_handler.send_exec(
+                    command_id,
+                    command,
+                    env,
+                    cwd,
+                    isolate_fs,
+                    pty,
+                    max_output_bytes,
+                    limits,
+                ))
+        });
+
+        control_writer
+            .send(host_msg)
+            .map_err(|_| AgentError::ControlChannelFailed {
+                reason: "control channel closed".to_string(),
+            })
+    }
+
+    /// Report the toolchains installed in the guest, running the detection
+    /// script only when the guest images changed since the last report.
+    fn do_vm_inventory(
+        &self,
+        payload: VmInventoryPayload,
+    ) -> Result<serde_json::Value, AgentError> {
+        let (control_writer, control_handler, command_id) = {
+            let state = self.state.lock().unwrap();
+            let session = match &*state {
+                SessionState::Active(s) => s,
+                SessionState::Idle => return Err(AgentError::SessionNotActive),
+            };
+            match (&session.control_writer, &session.control_handler) {
+                (Some(writer), Some(handler)) => {
+                    let command_id = session.next_command_id.fetch_add(1, Ordering::Relaxed);
+                    self.claim_step(command_id as i64);
+                    (writer.clone(), Arc::clone(handler), command_id)
+                }
+                _ => return Err(AgentError::QemuUnavailable),
+            }
+        };
+
+        let (kernel, initrd) = self.resolve_guest_images();
+        let rootfs = self.cli_args.rootfs_path.as_deref();
+        let images: Vec<&Path> = [kernel.as_deref(), initrd.as_deref(), rootfs]
+            .into_iter()
+            .flatten()
+            .collect();
+        let fingerprint = inventory::image_fingerprint(&images)?;
+
+        if !payload.refresh {
+            if let Some(tools) = self.inventory_cache.get(&fingerprint) {
+                return Ok(json!({ "rootfs_hash": fingerprint, "cached": true, "tools": tools }));
+            }
+        }
+
+        let result = self.run_in_guest(
+            &control_writer,
+            &control_handler,
+            command_id,
+            inventory::DETECTION_SCRIPT.to_string(),
+            "/",
+            INVENTORY_TIMEOUT,
+        )?;
+        let output = match result {
+            Some(result) if result.exit_code.is_some() => result.stdout,
+            _ => {
+                return Err(AgentError::ControlChannelFailed {
+                    reason: "inventory script did not complete".to_string(),
+                });
+            }
+        };
+
+        let tools = inventory::parse_inventory(&output);
+        self.inventory_cache.insert(fingerprint.clone(), tools.clone());
+        Ok(json!({ "rootfs_hash": fingerprint, "cached": false, "tools": tools }))
+    }
+
+    fn require_active(&self) -> Result<(), AgentError> {
+        let state = self.state.lock().unwrap();
+        match &*state {
+            SessionState::Idle => Err(AgentError::SessionNotActive),
+            SessionState::Active(_) => Ok(()),
+        }
+    }
+
+    fn agent_error_to_stdio(err: AgentError) -> StdioError {
+        match err {
+            AgentError::UndoDisabled => StdioError::CapabilityUnavailable {
+                capability: "undo".to_string(),
+                reason: "the session was started with undo disabled".to_string(),
+            },
+            AgentError::AgentBackend { reason } => StdioError::CapabilityUnavailable {
+                capability: "agent.prompt".to_string(),
+                reason,
+            },
+            AgentError::ReadOnlyDirectory { path } => StdioError::CapabilityUnavailable {
+                capability: "write".to_string(),
+                reason: format!("working directory {path} is read-only"),
+            },
+            err => StdioError::Failed {
+                code: err.code(),
+                message: err.to_string(),
+            },
+        }
+    }
 
     fn agent_error_to_mcp(err: AgentError) -> McpError {
-        McpError::InternalError {
+        McpError::Failed {
+            code: err.code(),
             message: err.to_string(),
         }
     }
 
+    fn stdio_error_to_mcp(err: StdioError) -> McpError {
+        match err {
+            StdioError::PathOutsideRoot { path } => McpError::PathOutsideRoot { path },
+            StdioError::InvalidField { message, .. } => McpError::InvalidParams { message },
+            err => McpError::Failed {
+                code: err.code(),
+                message: err.to_string(),
+            },
+        }
+    }
+
+    /// `started_at` is when the rollback began; the response also reports
+    /// how long it took.
+    fn rollback_result_json(
+        result: &RollbackResult,
+        started_at: chrono::DateTime<chrono::Utc>,
+    ) -> serde_json::Value {
+        let elapsed = (chrono::Utc::now() - started_at).to_std().unwrap_or_default();
+        let mut response = json!({
+            "steps_requested": result.steps_requested,
+            "steps_rolled_back": result.steps_rolled_back,
+            "step_ids": result.rolled_back_step_ids,
+            "barriers_crossed": result.barriers_crossed.len(),
+            "cancelled": result.cancelled,
+            "started_at": time::format_timestamp(&started_at),
+            "duration_ms": time::duration_ms(elapsed),
+        });
+        if !result.merged.is_empty() {
+            response["merged"] = json!(result.merged);
+        }
+        response
+    }
+
     /// Get the recent writes tracker from the active session, if available.
     fn recent_writes(&self) -> Option<Arc<RecentBackendWrites>> {
         let state = self.state.lock().unwrap();
@@ -949,39 +2108,66 @@ impl Orchestrator {
         Some(WatcherSuppressGuard(rw))
     }
 
-    fn next_api_step_id(&self) -> Result<i64, McpError> {
+    /// Count the step about to open as `step_id` among the atomic batch's,
+    /// if one is running on this thread.
+    fn claim_step(&self, step_id: i64) {
+        if let Some(scope) = &mut *self.step_scope.lock().unwrap() {
+            if scope.thread == std::thread::current().id() {
+                scope.opened.push(step_id);
+            }
+        }
+    }
+
+    fn next_api_step_id(&self) -> Result<i64, AgentError> {
         let state = self.state.lock().unwrap();
         match &*state {
             SessionState::Active(session) => {
                 Ok(session.next_api_step_id.fetch_add(1, Ordering::Relaxed))
             }
-            _ => Err(Self::agent_error_to_mcp(AgentError::SessionNotActive)),
+            _ => Err(AgentError::SessionNotActive),
         }
     }
 
     /// Open a synthetic API step, run `f`, then close or rollback on error.
+    /// Failures to open or close the step become errors through `to_error`.
     ///
     /// Suppresses the filesystem watcher for the duration of the step so that
     /// writes made inside `f` are not misidentified as external modifications.
-    fn with_api_step<F>(
+    fn with_api_step<F, E>(
         &self,
         interceptor: &UndoInterceptor,
+        to_error: fn(AgentError) -> E,
         f: F,
-    ) -> Result<i64, McpError>
+    ) -> Result<i64, E>
     where
-        F: FnOnce(i64) -> Result<(), McpError>,
+        F: FnOnce(i64) -> Result<(), E>,
     {
         let _guard = self.suppress_watcher();
 
-        let step_id = self.next_api_step_id()?;
-        interceptor
-            .open_step(step_id)
-            .map_err(|e| McpError::InternalError { message: e.to_string() })?;
+        let step_id = self.next_api_step_id().map_err(to_error)?;
+        self.claim_step(step_id);
+        Self::run_api_step(interceptor, step_id, to_error, f)
+    }
+
+    /// Open API step `step_id`, run `f` in it, and close it, or roll it
+    /// back if `f` fails. What `f` logs carries the step's id.
+    #[tracing::instrument(name = "step", skip_all, fields(step_id = step_id))]
+    fn run_api_step<F, E>(
+        interceptor: &UndoInterceptor,
+        step_id: i64,
+        to_error: fn(AgentError) -> E,
+        f: F,
+    ) -> Result<i64, E>
+    where
+        F: FnOnce(i64) -> Result<(), E>,
+    {
+        open_api_step(interceptor, step_id).map_err(|e| to_error(e.into()))?;
+        interceptor.set_step_type(StepType::Api);
         match f(step_id) {
             Ok(()) => {
                 interceptor
                     .close_step(step_id)
-                    .map_err(|e| McpError::InternalError { message: e.to_string() })?;
+                    .map_err(|e| to_error(e.into()))?;
                 Ok(step_id)
             }
             Err(err) => {
@@ -992,17 +2178,18 @@ impl Orchestrator {
     }
 
     fn do_write_file(
-        interceptor: &UndoInterceptor,
+        interceptor: &dyn WriteInterceptor,
         target: &std::path::Path,
-        args: &WriteFileArgs,
+        content: &str,
         recent_writes: Option<&RecentBackendWrites>,
-    ) -> Result<(), McpError> {
-        interceptor.set_step_command(format!("write_file {}", args.path));
-
+    ) -> Result<(), StdioError> {
         let existed_before = target.exists();
 
         if existed_before {
-            let _ = interceptor.pre_write(target);
+            interceptor
+                .pre_write(target)
+                .map_err(AgentError::from)
+                .map_err(Self::agent_error_to_stdio)?;
         } else if let Some(parent) = target.parent() {
             let mut dirs_to_track = Vec::new();
             let mut ancestor = parent.to_path_buf();
@@ -1012,9 +2199,7 @@ impl Orchestrator {
                     break;
                 }
             }
-            std::fs::create_dir_all(parent).map_err(|e| McpError::InternalError {
-                message: e.to_string(),
-            })?;
+            std::fs::create_dir_all(parent)?;
             for dir in dirs_to_track.iter().rev() {
                 let _ = interceptor.post_mkdir(dir);
             }
@@ -1023,9 +2208,7 @@ impl Orchestrator {
         if let Some(rw) = recent_writes {
             rw.record(target);
         }
-        std::fs::write(target, &args.content).map_err(|e| McpError::InternalError {
-            message: e.to_string(),
-        })?;
+        std::fs::write(target, content)?;
 
         if !existed_before {
             let _ = interceptor.post_create(target);
@@ -1035,15 +2218,15 @@ impl Orchestrator {
     }
 
     fn do_edit_file(
-        interceptor: &UndoInterceptor,
+        interceptor: &dyn WriteInterceptor,
         target: &std::path::Path,
-        path: &str,
         new_content: &str,
         recent_writes: Option<&RecentBackendWrites>,
     ) -> Result<(), McpError> {
-        interceptor.set_step_command(format!("edit_file {}", path));
-
-        let _ = interceptor.pre_write(target);
+        interceptor
+            .pre_write(target)
+            .map_err(AgentError::from)
+            .map_err(Self::agent_error_to_mcp)?;
 
         if let Some(rw) = recent_writes {
             rw.record(target);
@@ -1055,6 +2238,144 @@ impl Orchestrator {
         Ok(())
     }
 
+    fn do_delete_path(
+        interceptor: &dyn WriteInterceptor,
+        target: &std::path::Path,
+        is_dir: bool,
+        recent_writes: Option<&RecentBackendWrites>,
+    ) -> Result<(), StdioError> {
+        interceptor
+            .pre_unlink(target, is_dir)
+            .map_err(AgentError::from)
+            .map_err(Self::agent_error_to_stdio)?;
+
+        if let Some(rw) = recent_writes {
+            rw.record(target);
+        }
+        if is_dir {
+            std::fs::remove_dir_all(target)?;
+        } else {
+            std::fs::remove_file(target)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the unified diff `patch` under `working_dir` in one API step,
+    /// whose command is `command` followed by the files and hunk counts.
+    /// `path` names the file of a patch of bare hunks. Nothing is written
+    /// unless every hunk applies; otherwise the rejected ones are returned.
+    fn apply_patch_in(
+        &self,
+        working_dir: &Path,
+        interceptor: Option<Arc<UndoInterceptor>>,
+        patch: &str,
+        path: Option<&str>,
+        command: &str,
+    ) -> Result<serde_json::Value, StdioError> {
+        let invalid = |field: &str, message: String| StdioError::InvalidField {
+            field: field.to_string(),
+            message,
+        };
+        let mut files = patch::parse(patch).map_err(|e| invalid("patch", e.to_string()))?;
+        if files.is_empty() {
+            return Err(invalid("patch", "no @@ hunks in the patch".to_string()));
+        }
+        if let Some(path) = path {
+            if files.len() > 1 {
+                return Err(invalid("path", "the patch changes more than one file".to_string()));
+            }
+            match &files[0].path {
+                Some(named) if named != path => {
+                    return Err(invalid("path", format!("the patch is for {named}")));
+                }
+                _ => files[0].path = Some(path.to_string()),
+            }
+        }
+
+        let mut patched = Vec::new();
+        let mut rejected = Vec::new();
+        for file in &files {
+            let Some(path) = file.path.as_deref() else {
+                return Err(invalid(
+                    "path",
+                    "the patch has no ---/+++ headers naming its file".to_string(),
+                ));
+            };
+            if patched.iter().any(|patched: &PatchedFile| patched.path == path) {
+                return Err(invalid("patch", format!("{path} is patched more than once")));
+            }
+            let target = codeagent_stdio::validate_path(path, working_dir)?;
+            let current = match std::fs::read_to_string(&target) {
+                Ok(current) => Some(current),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            match patch::apply_to_file(file, current.as_deref()) {
+                Ok(content) => patched.push(PatchedFile {
+                    path,
+                    target,
+                    content,
+                    hunks: file.hunks.len(),
+                }),
+                Err(hunks) => rejected.push(json!({ "path": path, "hunks": hunks })),
+            }
+        }
+        if !rejected.is_empty() {
+            return Ok(json!({ "applied": false, "rejected": rejected, "step_id": null }));
+        }
+
+        let summary: Vec<String> = patched
+            .iter()
+            .map(|file| {
+                let plural = if file.hunks == 1 { "" } else { "s" };
+                format!("{} ({} hunk{plural})", file.path, file.hunks)
+            })
+            .collect();
+        let rw = self.recent_writes();
+        let step_id = match interceptor {
+            Some(interceptor) => {
+                Some(self.with_api_step(&interceptor, Self::agent_error_to_stdio, |_| {
+                    interceptor.set_step_command(format!("{command} {}", summary.join(", ")));
+                    Self::write_patched(interceptor.as_ref(), &patched, rw.as_deref())
+                })?)
+            }
+            None => {
+                Self::write_patched(&PassthroughInterceptor::new(), &patched, None)?;
+                None
+            }
+        };
+
+        let files: Vec<serde_json::Value> = patched
+            .iter()
+            .map(|file| {
+                json!({
+                    "path": file.path,
+                    "hunks": file.hunks,
+                    "deleted": file.content == PatchedContent::Delete,
+                })
+            })
+            .collect();
+        Ok(json!({ "applied": true, "files": files, "step_id": step_id }))
+    }
+
+    fn write_patched(
+        interceptor: &dyn WriteInterceptor,
+        patched: &[PatchedFile],
+        recent_writes: Option<&RecentBackendWrites>,
+    ) -> Result<(), StdioError> {
+        for file in patched {
+            match &file.content {
+                PatchedContent::Write(content) => {
+                    Self::do_write_file(interceptor, &file.target, content, recent_writes)?
+                }
+                PatchedContent::Delete => {
+                    Self::do_delete_path(interceptor, &file.target, false, recent_writes)?
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Execute a shell command directly on the host (no VM).
     /// Creates an unprotected undo step so the action is recorded but cannot
     /// be rolled back.
@@ -1067,6 +2388,10 @@ impl Orchestrator {
         let working_dir = self
             .primary_working_dir()
             .map_err(Self::agent_error_to_mcp)?;
+        // A host command is not confined to a mount, so it could write any
+        // working directory.
+        self.require_no_read_only_dirs()
+            .map_err(Self::agent_error_to_mcp)?;
 
         // Suppress watcher events for the duration of the command (and a grace
         // period after) so that filesystem changes made by the command are not
@@ -1075,6 +2400,7 @@ impl Orchestrator {
         let _guard = self.suppress_watcher();
 
         let shell = if cfg!(windows) { "bash" } else { "sh" };
+        let started = Instant::now();
         let output = std::process::Command::new(shell)
             .arg("-c")
             .arg(&args.command)
@@ -1103,6 +2429,7 @@ impl Orchestrator {
             "exit_code": exit_code,
             "output": combined_output,
             "classification": classification.to_string(),
+            "duration_ms": time::duration_ms(started.elapsed()),
             "host_only": true,
         }))
     }
@@ -1129,21 +2456,326 @@ struct VmSessionParts {
     qemu_process: Option<QemuProcess>,
     fs_backends: Vec<Box<dyn crate::fs_backend::FilesystemBackend>>,
     in_flight_tracker: Option<InFlightTracker>,
-    control_writer: Option<mpsc::UnboundedSender<String>>,
-    control_handler: Option<Arc<codeagent_control::ControlChannelHandler<codeagent_interceptor::undo_interceptor::UndoInterceptor>>>,
+    control_writer: Option<LaneSender<HostMessage>>,
+    control_handler: Option<Arc<codeagent_control::ControlChannelHandler<dyn codeagent_common::StepManager>>>,
     event_bridge_handle: Option<tokio::task::JoinHandle<()>>,
     control_reader_handle: Option<tokio::task::JoinHandle<()>>,
     control_writer_handle: Option<tokio::task::JoinHandle<()>>,
     socket_dir: Option<PathBuf>,
+    /// Whether the VM resumed a saved state instead of booting.
+    resumed: bool,
 }
 
-impl RequestHandler for Orchestrator {
-    fn session_start(
-        &self,
-        payload: SessionStartPayload,
-    ) -> Result<serde_json::Value, StdioError> {
-        self.do_session_start(payload)
-            .map_err(Self::agent_error_to_stdio)
+/// Everything a session's VM is launched from, kept by the VM monitor to
+/// launch a new one after a crash.
+#[derive(Clone)]
+struct VmLauncher {
+    cli_args: CliArgs,
+    event_sender: mpsc::UnboundedSender<Event>,
+    command_waiter: Arc<CommandWaiter>,
+    command_timeouts: Arc<CommandTimeouts>,
+    warnings: WarningReporter,
+    clock: Arc<dyn Clock>,
+    /// Directories the backends serve: the upper directory for overlays,
+    /// the working directory otherwise.
+    working_dirs: Vec<PathBuf>,
+    mount_names: Vec<String>,
+    mount_backends: Vec<MountBackend>,
+    /// Directories the guest may read but not change.
+    read_only_dirs: Vec<bool>,
+    /// Glob patterns of the paths hidden from the guest, per directory.
+    exclusions: Vec<Vec<String>>,
+    /// Operation trace of each directory's intercepted backend.
+    traces: Vec<Arc<FsTrace>>,
+    write_interceptors: Vec<Arc<dyn WriteInterceptor>>,
+    interceptors: Vec<Arc<UndoInterceptor>>,
+    step_manager: Arc<dyn codeagent_common::StepManager>,
+    env_profile: Arc<EnvProfile>,
+    kernel_path: PathBuf,
+    initrd_path: PathBuf,
+    vm_mode: String,
+    warm_pool: Option<Arc<WarmPool<QemuProcess>>>,
+    quiescence: Arc<Mutex<QuiescenceConfig>>,
+}
+
+impl VmLauncher {
+    /// Launch VM components: filesystem backends, QEMU, control channel.
+    /// A persistent session resumes its saved VM state if it has one that
+    /// fits, and boots afresh if resuming fails.
+    fn launch(&self) -> Result<VmSessionParts, AgentError> {
+        let saved = (self.vm_mode == "persistent")
+            .then(|| self.cli_args.undo_dir.as_deref().map(SavedVmState::in_undo_dir))
+            .flatten();
+        if saved.is_none() {
+            if let Some(warm_vm) = self.take_warm_vm() {
+                match self.launch_from(Some(warm_vm), None) {
+                    Ok(parts) => return Ok(parts),
+                    Err(error) => {
+                        log_warn!("warm_pool", "pool VM unusable, booting one: {error}")
+                    }
+                }
+            }
+        }
+        let launched = self.launch_from(None, saved.as_ref());
+        let Some(saved) = saved else {
+            return launched;
+        };
+        match launched {
+            Err(error) if saved.exists() => {
+                saved.discard();
+                self.warnings.report(SandboxWarning::VmStateRestoreFailed {
+                    reason: error.to_string(),
+                });
+                self.launch_from(None, Some(&saved))
+            }
+            launched => {
+                // Used up: the resumed guest has moved on from it.
+                saved.discard();
+                launched
+            }
+        }
+    }
+
+    /// A VM from the warm pool, if there is one ready and the session's
+    /// shares can all be hot-plugged into it.
+    fn take_warm_vm(&self) -> Option<WarmVm<QemuProcess>> {
+        let pool = self.warm_pool.as_ref()?;
+        let pluggable = self.working_dirs.len() <= warm_pool::HOTPLUG_PORTS as usize
+            && self
+                .mount_backends
+                .iter()
+                .all(|&backend| fs_backend::transport(backend) == FsTransport::VhostUser);
+        if !pluggable {
+            return None;
+        }
+        pool.take()
+    }
+
+    /// Launch the session's VM: hot-plug its shares into `warm_vm` if given,
+    /// otherwise boot one, resuming the state in `saved` if it fits.
+    fn launch_from(
+        &self,
+        warm_vm: Option<WarmVm<QemuProcess>>,
+        saved: Option<&SavedVmState>,
+    ) -> Result<VmSessionParts, AgentError> {
+        let working_dirs = &self.working_dirs;
+        let mount_names = &self.mount_names;
+        let mount_backends = &self.mount_backends;
+        let write_interceptors = &self.write_interceptors;
+        let interceptors = &self.interceptors;
+        let env_profile = &self.env_profile;
+        let socket_dir = self.cli_args.undo_dir.as_ref()
+            .expect("undo_dir must be set before launching VM")
+            .join(".sockets");
+        std::fs::create_dir_all(&socket_dir)?;
+
+        let control_socket_path = socket_dir.join("control.sock");
+
+        // Create InFlightTracker before backends so they can share it with
+        // the control channel handler for quiescence detection. Each backend
+        // counts against its own root, so a step's quiescence window waits
+        // only for the directories it was active in.
+        let in_flight_tracker = InFlightTracker::new();
+
+        // Create the control channel handler before the backends too, so they
+        // can ask it which command a guest process belongs to.
+        use codeagent_control::ControlChannelHandler;
+        use crate::event_bridge::{GuestReporting, run_event_bridge};
+
+        let (handler, handler_events) = ControlChannelHandler::new(
+            Arc::clone(&self.step_manager),
+            in_flight_tracker.clone(),
+            self.quiescence.lock().unwrap().clone(),
+        );
+        // Ambient steps of writes whose working root is known are recorded
+        // in that root's undo log.
+        let root_step_managers = self
+            .interceptors
+            .iter()
+            .map(|interceptor| interceptor.clone() as Arc<dyn codeagent_common::StepManager>)
+            .collect();
+        let handler = handler
+            .with_clock(Arc::clone(&self.clock))
+            .with_root_step_managers(root_step_managers);
+        let handler = Arc::new(handler);
+        let attribution = handler.attribution();
+
+        // 1. Start filesystem backends, each directory with its own kind
+        use crate::fs_backend::FilesystemBackend;
+        let mut fs_backends: Vec<Box<dyn FilesystemBackend>> = Vec::new();
+        let mut fs_socket_paths = Vec::new();
+        let mut fs_transports = Vec::new();
+
+        for (index, working_dir) in working_dirs.iter().enumerate() {
+            let (fs_socket, mut backend): (PathBuf, Box<dyn FilesystemBackend>) =
+                match mount_backends[index] {
+                    #[cfg(unix)]
+                    MountBackend::Intercepted => {
+                        let fs_socket = socket_dir.join(format!("vfs{index}.sock"));
+                        let mut backend = fs_backend::InterceptedBackend::new(
+                            working_dir.clone(),
+                            fs_socket.clone(),
+                            write_interceptors[index].clone(),
+                            in_flight_tracker.for_root(index),
+                            Some(attribution.clone() as Arc<dyn codeagent_common::StepAttributor>),
+                        )
+                        .with_request_workers(self.cli_args.virtiofs_request_workers as usize)
+                        .with_exclusions(self.exclusions[index].clone())
+                        .with_trace(self.traces[index].clone());
+                        if self.read_only_dirs[index] {
+                            backend = backend.read_only();
+                        }
+                        (fs_socket, Box::new(backend))
+                    }
+                    #[cfg(not(target_os = "windows"))]
+                    kind @ (MountBackend::Virtiofs | MountBackend::VirtiofsReadOnly) => {
+                        let fs_socket = socket_dir.join(format!("vfs{index}.sock"));
+                        let mut backend = fs_backend::VirtioFsBackend::new(
+                            working_dir.clone(),
+                            fs_socket.clone(),
+                            self.cli_args.virtiofsd_binary.clone(),
+                        );
+                        if kind == MountBackend::VirtiofsReadOnly || self.read_only_dirs[index] {
+                            backend = backend.read_only();
+                        }
+                        (fs_socket, Box::new(backend))
+                    }
+                    #[cfg(target_os = "windows")]
+                    MountBackend::P9 => {
+                        let fs_socket = socket_dir.join(format!("p9fs{index}.addr"));
+                        let backend = fs_backend::P9Backend::new(
+                            working_dir.clone(),
+                            fs_socket.clone(),
+                            write_interceptors[index].clone(),
+                            in_flight_tracker.for_root(index),
+                        );
+                        (fs_socket, Box::new(backend))
+                    }
+                    // Rejected by session.start already.
+                    other => {
+                        return Err(AgentError::UnsupportedBackend {
+                            backend: other.as_str().to_string(),
+                            path: working_dir.display().to_string(),
+                        });
+                    }
+                };
+            backend.start()?;
+            fs_socket_paths.push(fs_socket);
+            fs_transports.push(fs_backend::transport(mount_backends[index]));
+            fs_backends.push(backend);
+        }
+
+        // 2. Hot-plug the shares into the pool VM, or build the QEMU config
+        //    and spawn
+        let mut resumed = false;
+        let (qemu_process, control_socket_path, pool_dir) = if let Some(warm_vm) = warm_vm {
+            let WarmVm { vm: mut qemu_process, dir } = warm_vm;
+            let shares: Vec<(PathBuf, String)> =
+                fs_socket_paths.into_iter().zip(mount_names.iter().cloned()).collect();
+            qemu_process.attach_shares(&shares)?;
+            (qemu_process, dir.join("control.sock"), Some(dir))
+        } else {
+            let mut config = QemuConfig {
+                qemu_binary: self.cli_args.qemu_binary.clone(),
+                kernel_path: self.kernel_path.clone(),
+                initrd_path: self.initrd_path.clone(),
+                rootfs_path: self.cli_args.rootfs_path.clone(),
+                memory_mb: self.cli_args.memory_mb,
+                cpus: self.cli_args.cpus,
+                working_dirs: working_dirs.to_vec(),
+                control_socket_path: control_socket_path.clone(),
+                fs_socket_paths,
+                fs_transports,
+                vm_mode: self.vm_mode.clone(),
+                mount_names: mount_names.to_vec(),
+                qmp_socket_path: saved.is_some().then(|| socket_dir.join("qmp.sock")),
+                incoming_state: None,
+                hotplug_ports: 0,
+                dax_window_mb: self.cli_args.virtiofs_dax_window_mb,
+                extra_args: vec![],
+            };
+            if let Some(saved) = saved {
+                config.incoming_state = saved.resumable_by(&config);
+                if config.incoming_state.is_none() {
+                    saved.discard();
+                }
+            }
+            resumed = config.incoming_state.is_some();
+
+            (QemuProcess::spawn(config)?, control_socket_path, None)
+        };
+
+        // 3. Connect to the control channel QEMU serves (Unix socket or
+        //    Windows named pipe)
+        let (reader, writer) = control_bridge::connect_control_channel(&control_socket_path)?;
+
+        // 4. Spawn event bridge (control events → STDIO events + command waiter)
+        let event_bridge_handle = tokio::spawn(run_event_bridge(
+            handler_events,
+            self.event_sender.clone(),
+            Some(self.command_waiter.clone()),
+            Some(self.command_timeouts.clone()),
+            Some(Arc::clone(env_profile)),
+            Some(GuestReporting {
+                warnings: self.warnings.clone(),
+                interceptors: interceptors.to_vec(),
+            }),
+        ));
+
+        // 5. Spawn control channel writer and reader tasks
+        let link = Link::new();
+        let (control_writer_sender, control_writer_handle) =
+            control_bridge::spawn_control_writer(writer, link.clone());
+        attribution.connect(control_writer_sender.clone());
+
+        // The pool VM's sockets are connected and not needed again; the
+        // shim mounts its shares before running any command.
+        if let Some(pool_dir) = pool_dir {
+            let _ = std::fs::remove_dir_all(pool_dir);
+            let _ = control_writer_sender.send(HostMessage::Mount {
+                tags: mount_names.to_vec(),
+            });
+        }
+
+        let control_reader_handle = control_bridge::spawn_control_reader(
+            reader,
+            link,
+            handler.clone(),
+            self.event_sender.clone(),
+            Arc::clone(env_profile),
+        );
+
+        metrics::increment(Counter::VmLaunches);
+        Ok(VmSessionParts {
+            qemu_process: Some(qemu_process),
+            fs_backends,
+            in_flight_tracker: Some(in_flight_tracker),
+            control_writer: Some(control_writer_sender),
+            control_handler: Some(handler),
+            event_bridge_handle: Some(event_bridge_handle),
+            control_reader_handle: Some(control_reader_handle),
+            control_writer_handle: Some(control_writer_handle),
+            socket_dir: Some(socket_dir),
+            resumed,
+        })
+    }
+}
+
+impl RequestHandler for Orchestrator {
+    fn session_start(
+        &self,
+        payload: SessionStartPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        if let Some(ref config) = payload.coherent_capture {
+            coherent_capture::validate_rules(config).map_err(|message| {
+                StdioError::InvalidField {
+                    field: "coherent_capture".to_string(),
+                    message,
+                }
+            })?;
+        }
+        self.do_session_start(payload)
+            .map_err(Self::agent_error_to_stdio)
     }
 
     fn session_stop(&self) -> Result<serde_json::Value, StdioError> {
@@ -1151,35 +2783,178 @@ impl RequestHandler for Orchestrator {
             .map_err(Self::agent_error_to_stdio)
     }
 
+    fn session_destroy(
+        &self,
+        payload: SessionDestroyPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        self.do_session_destroy(payload)
+    }
+
     fn session_reset(&self) -> Result<serde_json::Value, StdioError> {
         self.do_session_reset()
             .map_err(Self::agent_error_to_stdio)
     }
 
+    fn session_resume(&self) -> Result<serde_json::Value, StdioError> {
+        self.do_session_resume()
+            .map_err(Self::agent_error_to_stdio)
+    }
+
     fn session_status(&self) -> Result<serde_json::Value, StdioError> {
         self.do_session_status()
             .map_err(Self::agent_error_to_stdio)
     }
 
+    fn session_clone(
+        &self,
+        payload: SessionClonePayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        self.do_session_clone(payload)
+            .map_err(Self::agent_error_to_stdio)
+    }
+
+    fn session_metrics(&self) -> Result<serde_json::Value, StdioError> {
+        Ok(metrics::report(&self.metrics_source().gauges()))
+    }
+
+    fn session_warnings(&self) -> Result<serde_json::Value, StdioError> {
+        Ok(json!({
+            "warnings": self.warnings.active().iter().map(warning_payload).collect::<Vec<_>>(),
+        }))
+    }
+
+    fn session_env_set(
+        &self,
+        payload: SessionEnvSetPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        let profile = self.env_profile().map_err(Self::agent_error_to_stdio)?;
+        if let Some(message) = env_profile::invalid_name(&payload.name) {
+            return Err(StdioError::InvalidField {
+                field: "name".to_string(),
+                message: message.to_string(),
+            });
+        }
+        if payload.value.contains('\0') {
+            return Err(StdioError::InvalidField {
+                field: "value".to_string(),
+                message: "must not contain NUL".to_string(),
+            });
+        }
+        let replaced = profile.set(payload.name.clone(), payload.value, payload.secret);
+        Ok(json!({
+            "name": payload.name,
+            "secret": payload.secret,
+            "replaced": replaced,
+        }))
+    }
+
+    fn session_env_unset(
+        &self,
+        payload: SessionEnvUnsetPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        let profile = self.env_profile().map_err(Self::agent_error_to_stdio)?;
+        let removed = profile.unset(&payload.name);
+        Ok(json!({ "name": payload.name, "removed": removed }))
+    }
+
+    fn session_env_list(&self) -> Result<serde_json::Value, StdioError> {
+        let profile = self.env_profile().map_err(Self::agent_error_to_stdio)?;
+        let variables: Vec<_> = profile
+            .list()
+            .into_iter()
+            .map(|(name, value, secret)| json!({ "name": name, "value": value, "secret": secret }))
+            .collect();
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn session_configure(
+        &self,
+        payload: SessionConfigurePayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        let mut config = self.quiescence.lock().unwrap().clone();
+        for (field, value, timeout) in [
+            ("idle_timeout_ms", payload.idle_timeout_ms, &mut config.idle_timeout),
+            ("max_timeout_ms", payload.max_timeout_ms, &mut config.max_timeout),
+            (
+                "ambient_inactivity_timeout_ms",
+                payload.ambient_inactivity_timeout_ms,
+                &mut config.ambient_inactivity_timeout,
+            ),
+            (
+                "ambient_max_duration_ms",
+                payload.ambient_max_duration_ms,
+                &mut config.ambient_max_duration,
+            ),
+        ] {
+            match value {
+                Some(0) => {
+                    return Err(StdioError::InvalidField {
+                        field: field.to_string(),
+                        message: "must be positive".to_string(),
+                    });
+                }
+                Some(millis) => *timeout = Duration::from_millis(millis),
+                None => {}
+            }
+        }
+        match payload.ambient_max_files {
+            Some(0) => {
+                return Err(StdioError::InvalidField {
+                    field: "ambient_max_files".to_string(),
+                    message: "must be positive".to_string(),
+                });
+            }
+            Some(files) => config.ambient_max_files = files as usize,
+            None => {}
+        }
+        *self.quiescence.lock().unwrap() = config.clone();
+        // Not under the quiescence lock: launching a VM takes it under the
+        // session lock.
+        let handler = match &*self.state.lock().unwrap() {
+            SessionState::Active(session) => session.control_handler.clone(),
+            SessionState::Idle => None,
+        };
+        if let Some(handler) = handler {
+            handler.set_config(config.clone());
+        }
+        Ok(json!({
+            "idle_timeout_ms": config.idle_timeout.as_millis() as u64,
+            "max_timeout_ms": config.max_timeout.as_millis() as u64,
+            "ambient_inactivity_timeout_ms": config.ambient_inactivity_timeout.as_millis() as u64,
+            "ambient_max_duration_ms": config.ambient_max_duration.as_millis() as u64,
+            "ambient_max_files": config.ambient_max_files,
+        }))
+    }
+
     fn undo_rollback(
         &self,
         payload: UndoRollbackPayload,
+        monitor: &dyn OperationMonitor,
     ) -> Result<serde_json::Value, StdioError> {
         let interceptor = self
             .resolve_interceptor(payload.directory.as_deref())
             .map_err(Self::agent_error_to_stdio)?;
+        self.require_writable_directory(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
 
         let _guard = self.suppress_watcher();
 
+        let started_at = chrono::Utc::now();
+        let count = payload.count as usize;
         let result = interceptor
-            .rollback(payload.count as usize, payload.force)
-            .map_err(AgentError::from)
-            .map_err(Self::agent_error_to_stdio)?;
+            .rollback_monitored(count, payload.force, payload.strict, payload.mode, monitor)
+            .map_err(|e| match e {
+                CodeAgentError::InsufficientHistory {
+                    requested,
+                    available,
+                } => StdioError::InsufficientHistory {
+                    requested,
+                    available,
+                },
+                other => Self::agent_error_to_stdio(AgentError::from(other)),
+            })?;
 
-        Ok(json!({
-            "steps_rolled_back": result.steps_rolled_back,
-            "barriers_crossed": result.barriers_crossed.len(),
-        }))
+        Ok(Self::rollback_result_json(&result, started_at))
     }
 
     fn undo_history(
@@ -1191,18 +2966,188 @@ impl RequestHandler for Orchestrator {
             .map_err(Self::agent_error_to_stdio)?;
 
         let steps = interceptor.completed_steps();
-        Ok(json!({
+        let details = interceptor.completed_step_info();
+        let mut response = json!({
             "steps": steps,
-        }))
+            "details": details,
+        });
+        if payload.format == HistoryFormat::Text {
+            response["text"] = json!(history_format::render_history_table(&details));
+        }
+        Ok(response)
     }
 
     fn undo_configure(
         &self,
-        _payload: UndoConfigurePayload,
+        payload: UndoConfigurePayload,
+        monitor: &dyn OperationMonitor,
     ) -> Result<serde_json::Value, StdioError> {
-        self.require_active()
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Active(s) => s,
+            SessionState::Idle => {
+                return Err(Self::agent_error_to_stdio(AgentError::SessionNotActive))
+            }
+        };
+        Self::require_undo(session).map_err(Self::agent_error_to_stdio)?;
+
+        let indices: Vec<usize> = match payload.directory.as_deref() {
+            Some(directory) => {
+                let Some(index) = Self::selected_directory_index(session, directory) else {
+                    return Err(Self::agent_error_to_stdio(AgentError::InvalidWorkingDir {
+                        path: format!("no working directory named {directory}"),
+                    }));
+                };
+                if index >= session.interceptors.len() {
+                    return Err(Self::agent_error_to_stdio(AgentError::InvalidWorkingDir {
+                        path: format!("directory index {index} out of range"),
+                    }));
+                }
+                vec![index]
+            }
+            None => (0..session.interceptors.len()).collect(),
+        };
+
+        if let Some(ref patterns) = payload.ignore_patterns {
+            gitignore::validate_patterns(patterns).map_err(|message| {
+                StdioError::InvalidField {
+                    field: "ignore_patterns".to_string(),
+                    message,
+                }
+            })?;
+        }
+        if let Some(ref config) = payload.external_modification {
+            external_modification::validate_rules(config).map_err(|message| {
+                StdioError::InvalidField {
+                    field: "external_modification".to_string(),
+                    message,
+                }
+            })?;
+        }
+        if let Some(ref config) = payload.coherent_capture {
+            coherent_capture::validate_rules(config).map_err(|message| {
+                StdioError::InvalidField {
+                    field: "coherent_capture".to_string(),
+                    message,
+                }
+            })?;
+        }
+
+        let mut directories = Vec::new();
+        for index in indices {
+            let interceptor = &session.interceptors[index];
+            if let Some(policy) = payload.symlink_policy {
+                interceptor.set_symlink_policy(policy);
+            }
+            if let Some(ref config) = payload.external_modification {
+                interceptor.set_external_modification_config(config);
+            }
+            if let Some(ref config) = payload.coherent_capture {
+                interceptor.set_coherent_capture_config(config);
+            }
+            if let Some(ref patterns) = payload.ignore_patterns {
+                interceptor.set_ignore_patterns(patterns.clone());
+                // External modifications under the patterns are not
+                // reported either.
+                if let Some(filter) = session.gitignore_filters.get(index) {
+                    filter.set_patterns(patterns.clone());
+                }
+            }
+            if let Some(ref config) = payload.git_mirror {
+                interceptor
+                    .set_git_mirror(config)
+                    .map_err(AgentError::from)
+                    .map_err(Self::agent_error_to_stdio)?;
+            }
+
+            let mut limits = interceptor.resource_limits();
+            if let Some(max) = payload.max_log_size_bytes {
+                limits.max_log_size_bytes = Some(max);
+            }
+            if let Some(max) = payload.max_step_count {
+                limits.max_step_count = Some(max);
+            }
+            if let Some(max) = payload.max_single_step_size_bytes {
+                limits.max_single_step_size_bytes = Some(max);
+            }
+            let evicted_steps = if limits != interceptor.resource_limits() {
+                interceptor
+                    .set_resource_limits_monitored(limits, monitor)
+                    .map_err(AgentError::from)
+                    .map_err(Self::agent_error_to_stdio)?
+            } else {
+                Vec::new()
+            };
+            directories.push(json!({
+                "index": index,
+                "evicted_steps": evicted_steps,
+            }));
+        }
+        Ok(json!({ "directories": directories }))
+    }
+
+    fn undo_attest(
+        &self,
+        payload: UndoAttestPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        let interceptor = self
+            .resolve_interceptor(payload.directory.as_deref())
             .map_err(Self::agent_error_to_stdio)?;
-        Ok(json!({}))
+
+        let attestation = interceptor
+            .attest()
+            .map_err(AgentError::from)
+            .map_err(Self::agent_error_to_stdio)?;
+
+        Ok(json!(attestation))
+    }
+
+    fn undo_squash(
+        &self,
+        payload: UndoSquashPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        let interceptor = self
+            .resolve_interceptor(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
+
+        let result = interceptor
+            .squash(payload.from_step, payload.to_step)
+            .map_err(AgentError::from)
+            .map_err(Self::agent_error_to_stdio)?;
+
+        Ok(json!(result))
+    }
+
+    fn undo_expect(
+        &self,
+        payload: UndoExpectPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        if payload.paths.is_empty() {
+            return Err(StdioError::InvalidField {
+                field: "paths".to_string(),
+                message: "must name at least one path".to_string(),
+            });
+        }
+        let interceptor = self
+            .resolve_interceptor(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
+
+        let root = interceptor.working_root();
+        let mut paths = Vec::with_capacity(payload.paths.len());
+        for path in &payload.paths {
+            let resolved = codeagent_stdio::validate_path(path, root)?;
+            let relative = resolved
+                .strip_prefix(root)
+                .map_err(|_| StdioError::PathOutsideRoot { path: path.clone() })?;
+            paths.push(relative.to_string_lossy().replace('\\', "/"));
+        }
+
+        let pending = interceptor.expect(Expectation {
+            paths,
+            op: payload.op,
+            estimated_bytes: payload.estimated_bytes,
+        });
+        Ok(json!({ "pending_expectations": pending }))
     }
 
     fn undo_discard(&self) -> Result<serde_json::Value, StdioError> {
@@ -1218,65 +3163,288 @@ impl RequestHandler for Orchestrator {
         Ok(json!({}))
     }
 
+    fn undo_reload_ignores(&self) -> Result<serde_json::Value, StdioError> {
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Active(s) => s,
+            SessionState::Idle => {
+                return Err(Self::agent_error_to_stdio(AgentError::SessionNotActive));
+            }
+        };
+        Self::require_undo(session).map_err(Self::agent_error_to_stdio)?;
+
+        let mut reloaded = Vec::new();
+        for (index, working_dir) in session.working_dirs.iter().enumerate() {
+            let watcher_filter = session.gitignore_filters.get(index);
+            if let Some(filter) = watcher_filter {
+                filter.reload();
+            }
+            let undo_filter = session
+                .interceptors
+                .get(index)
+                .is_some_and(|interceptor| interceptor.reload_gitignore());
+            if watcher_filter.is_some() || undo_filter {
+                reloaded.push(working_dir.display().to_string());
+            }
+        }
+        Ok(json!({ "reloaded": reloaded }))
+    }
+
     fn agent_execute(
         &self,
         payload: AgentExecutePayload,
     ) -> Result<serde_json::Value, StdioError> {
         self.require_active()
             .map_err(Self::agent_error_to_stdio)?;
+        if payload.timeout_seconds == Some(0) {
+            return Err(StdioError::InvalidField {
+                field: "timeout_seconds".to_string(),
+                message: "must be at least 1".to_string(),
+            });
+        }
+        let limits = &payload.limits;
+        for (field, limit) in [
+            ("limits.cpu_seconds", limits.cpu_seconds),
+            ("limits.memory_bytes", limits.memory_bytes),
+            ("limits.max_processes", limits.max_processes),
+        ] {
+            if limit == Some(0) {
+                return Err(StdioError::InvalidField {
+                    field: field.to_string(),
+                    message: "must be at least 1".to_string(),
+                });
+            }
+        }
 
-        let state = self.state.lock().unwrap();
-        let session = match &*state {
-            SessionState::Active(s) => s,
-            _ => return Err(Self::agent_error_to_stdio(AgentError::SessionNotActive)),
+        let (control_writer, control_handler, command_id, cwd, rollback, recent_writes, env_profile) = {
+            let state = self.state.lock().unwrap();
+            let session = match &*state {
+                SessionState::Active(s) => s,
+                _ => return Err(Self::agent_error_to_stdio(AgentError::SessionNotActive)),
+            };
+            // Check if VM is available
+            let (writer, handler) = match (&session.control_writer, &session.control_handler) {
+                (Some(writer), Some(handler)) => (writer.clone(), Arc::clone(handler)),
+                _ => return Err(Self::agent_error_to_stdio(AgentError::QemuUnavailable)),
+            };
+            let index = Self::directory_index(session, payload.directory.as_deref());
+            let cwd = guest_cwd::resolve(
+                &session.working_dirs,
+                &session.mount_names,
+                index,
+                payload.cwd.as_deref(),
+            )
+            .map_err(|message| StdioError::InvalidField {
+                field: if index < session.mount_names.len() { "cwd" } else { "directory" }
+                    .to_string(),
+                message,
+            })?;
+            let command_id = session.next_command_id.fetch_add(1, Ordering::Relaxed);
+            self.claim_step(command_id as i64);
+            let rollback = if payload.rollback_on_timeout {
+                session.interceptors.clone()
+            } else {
+                Vec::new()
+            };
+            (
+                writer,
+                handler,
+                command_id,
+                cwd,
+                rollback,
+                session.recent_writes.clone(),
+                Arc::clone(&session.env_profile),
+            )
         };
 
-        // Check if VM is available
-        let control_writer = match &session.control_writer {
-            Some(writer) => writer.clone(),
-            None => return Err(Self::agent_error_to_stdio(AgentError::QemuUnavailable)),
+        let started_at = time::now_timestamp();
+        // Watch before sending so a step that closes immediately is not missed.
+        let closed = payload
+            .timeout_seconds
+            .map(|_| self.command_timeouts.watch(command_id));
+        let send = |command: String, env| {
+            let sent = Self::send_to_guest(
+                &control_writer,
+                &control_handler,
+                command_id,
+                command,
+                env,
+                Some(cwd),
+                payload.isolate_fs,
+                payload.pty,
+                payload.max_output_bytes,
+                ResourceLimits {
+                    cpu_seconds: payload.limits.cpu_seconds,
+                    memory_bytes: payload.limits.memory_bytes,
+                    max_processes: payload.limits.max_processes,
+                },
+            );
+            match (sent, closed, payload.timeout_seconds) {
+                (Err(error), _, _) => {
+                    self.command_timeouts.forget(command_id);
+                    Err(Self::agent_error_to_stdio(error))
+                }
+                (Ok(()), Some(closed), Some(timeout_seconds)) => {
+                    let timer = CommandTimeout {
+                        command_id,
+                        timeout_seconds,
+                        control_writer: control_writer.clone(),
+                        control_handler: Arc::clone(&control_handler),
+                        rollback,
+                        recent_writes,
+                        event_sender: self.event_sender.clone(),
+                    };
+                    let step = tracing::info_span!("step", step_id = command_id);
+                    tokio::spawn(
+                        timer.run(self.command_timeouts.clone(), closed).instrument(step),
+                    );
+                    Ok(())
+                }
+                (Ok(()), _, _) => Ok(()),
+            }
         };
 
-        let command_id = session.next_command_id.fetch_add(1, Ordering::Relaxed);
+        let env = env_profile.merge(payload.env);
+        if !payload.wait {
+            send(payload.command, env)?;
+            return Ok(json!({
+                "command_id": command_id,
+                "status": "started",
+                "started_at": started_at,
+            }));
+        }
+
+        // Output and completion are still sent as events, but the server
+        // handles one request at a time, so they follow this response.
+        let timeout_ms = payload.timeout_ms.unwrap_or(120_000).min(600_000);
+        let started = Instant::now();
+        self.command_waiter.register(command_id);
+        send(payload.command, env)?;
+        let result = tokio::task::block_in_place(|| {
+            self.command_waiter
+                .wait_for_completion(command_id, Duration::from_millis(timeout_ms))
+        })
+        .unwrap_or_default();
+
+        let mut response = json!({
+            "command_id": command_id,
+            "status": if result.exit_code.is_some() { "completed" } else { "timeout" },
+            "stdout": result.stdout,
+            "stderr": result.stderr,
+            "started_at": started_at,
+            "duration_ms": time::duration_ms(started.elapsed()),
+        });
+        if let Some(exit_code) = result.exit_code {
+            response["exit_code"] = json!(exit_code);
+        }
+        Ok(response)
+    }
 
-        // Build and serialize the exec message
-        let exec_msg = codeagent_control::HostMessage::Exec {
-            id: command_id,
-            command: payload.command.clone(),
-            cwd: payload.cwd.or_else(|| {
-                Some(format!("/mnt/working/{}", session.mount_names[0]))
-            }),
-            env: payload.env,
+    fn agent_input(&self, payload: AgentInputPayload) -> Result<serde_json::Value, StdioError> {
+        let (control_writer, control_handler) = {
+            let state = self.state.lock().unwrap();
+            let session = match &*state {
+                SessionState::Active(s) => s,
+                _ => return Err(Self::agent_error_to_stdio(AgentError::SessionNotActive)),
+            };
+            match (&session.control_writer, &session.control_handler) {
+                (Some(writer), Some(handler)) => (writer.clone(), Arc::clone(handler)),
+                _ => return Err(Self::agent_error_to_stdio(AgentError::QemuUnavailable)),
+            }
         };
 
-        let json_str = control_bridge::serialize_host_message(&exec_msg)
-            .map_err(|error| Self::agent_error_to_stdio(AgentError::Io(
-                std::io::Error::other(error),
-            )))?;
-
-        control_writer
-            .send(json_str)
-            .map_err(|_| Self::agent_error_to_stdio(
-                AgentError::ControlChannelFailed {
-                    reason: "control channel closed".to_string(),
-                },
-            ))?;
+        let bytes = payload.data.len();
+        let host_msg = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(control_handler.send_input(
+                payload.command_id,
+                payload.data,
+                payload.eof,
+            ))
+        })
+        .map_err(|error| StdioError::InvalidField {
+            field: "command_id".to_string(),
+            message: error.to_string(),
+        })?;
+        control_writer.send(host_msg).map_err(|_| {
+            Self::agent_error_to_stdio(AgentError::ControlChannelFailed {
+                reason: "control channel closed".to_string(),
+            })
+        })?;
 
         Ok(json!({
-            "command_id": command_id,
-            "status": "started",
+            "command_id": payload.command_id,
+            "bytes": bytes,
+            "eof": payload.eof,
         }))
     }
 
     fn agent_prompt(
         &self,
-        _payload: AgentPromptPayload,
+        payload: AgentPromptPayload,
     ) -> Result<serde_json::Value, StdioError> {
         self.require_active()
             .map_err(Self::agent_error_to_stdio)?;
+        let backend = self.agent_backend.clone().ok_or_else(|| {
+            Self::agent_error_to_stdio(AgentError::AgentBackend {
+                reason: "no agent backend is configured (--agent-endpoint or --agent-command)"
+                    .to_string(),
+            })
+        })?;
 
-        Err(Self::agent_error_to_stdio(AgentError::NotImplemented {
-            feature: "agent.prompt".to_string(),
+        // Each command is an `agent.execute` of its own, so it gets its own
+        // step and its usual events.
+        let mut transcript = vec![AgentTurn::Prompt {
+            text: payload.prompt,
+        }];
+        let mut command_ids = Vec::new();
+        for _ in 0..self.cli_args.agent_max_turns {
+            let action = backend
+                .next_action(&transcript)
+                .map_err(Self::agent_error_to_stdio)?;
+            let (id, command, note) = match action {
+                AgentAction::Finish { message } => {
+                    let _ = self.event_sender.send(Event::AgentOutput {
+                        data: message.clone(),
+                    });
+                    return Ok(json!({
+                        "status": "completed",
+                        "message": message,
+                        "command_ids": command_ids,
+                    }));
+                }
+                AgentAction::Run { id, command, note } => (id, command, note),
+            };
+            if let Some(note) = &note {
+                let _ = self.event_sender.send(Event::AgentOutput { data: note.clone() });
+            }
+            let result = self.agent_execute(AgentExecutePayload {
+                command: command.clone(),
+                env: None,
+                cwd: None,
+                directory: None,
+                wait: true,
+                timeout_ms: None,
+                isolate_fs: false,
+                timeout_seconds: None,
+                rollback_on_timeout: false,
+                pty: false,
+                max_output_bytes: None,
+                limits: Default::default(),
+            })?;
+            let command_id = result["command_id"].as_u64().unwrap_or_default();
+            command_ids.push(command_id);
+            transcript.push(AgentTurn::Command {
+                id: id.unwrap_or_else(|| format!("command-{command_id}")),
+                note,
+                command,
+                exit_code: result["exit_code"].as_i64().map(|code| code as i32),
+                stdout: result["stdout"].as_str().unwrap_or_default().to_string(),
+                stderr: result["stderr"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+        Ok(json!({
+            "status": "turn_limit",
+            "command_ids": command_ids,
         }))
     }
 
@@ -1317,10 +3485,231 @@ impl RequestHandler for Orchestrator {
             .map_err(Self::agent_error_to_stdio)?;
 
         let target = working_dir.join(&payload.path);
-        let content =
-            std::fs::read_to_string(&target).map_err(|e| StdioError::Io { source: e })?;
+        let read = file_read::read_range(&target, payload.encoding, payload.offset, payload.length)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::InvalidData => StdioError::InvalidField {
+                    field: "encoding".to_string(),
+                    message: e.to_string(),
+                },
+                _ => StdioError::Io { source: e },
+            })?;
+
+        Ok(json!(read))
+    }
+
+    fn fs_write(&self, payload: FsWritePayload) -> Result<serde_json::Value, StdioError> {
+        let (working_dir, interceptor) = self
+            .resolve_api_directory(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
+        let target = codeagent_stdio::validate_path(&payload.path, &working_dir)?;
+        if target.is_dir() {
+            return Err(StdioError::InvalidField {
+                field: "path".to_string(),
+                message: format!("{} is a directory", payload.path),
+            });
+        }
+        let content = &payload.content;
+        let rw = self.recent_writes();
+
+        let step_id = match interceptor {
+            Some(interceptor) => {
+                Some(self.with_api_step(&interceptor, Self::agent_error_to_stdio, |_| {
+                    interceptor.set_step_command(format!("fs.write {}", payload.path));
+                    Self::do_write_file(interceptor.as_ref(), &target, content, rw.as_deref())
+                })?)
+            }
+            None => {
+                Self::do_write_file(&PassthroughInterceptor::new(), &target, content, None)?;
+                None
+            }
+        };
+
+        Ok(json!({ "written": true, "step_id": step_id }))
+    }
+
+    fn fs_delete(&self, payload: FsDeletePayload) -> Result<serde_json::Value, StdioError> {
+        let (working_dir, interceptor) = self
+            .resolve_api_directory(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
+        let target = codeagent_stdio::validate_path(&payload.path, &working_dir)?;
+        if target == working_dir {
+            return Err(StdioError::InvalidField {
+                field: "path".to_string(),
+                message: "cannot delete the working directory itself".to_string(),
+            });
+        }
+        let is_dir = target.symlink_metadata()?.is_dir();
+        if is_dir && !payload.recursive {
+            return Err(StdioError::InvalidField {
+                field: "recursive".to_string(),
+                message: format!("{} is a directory; set recursive to delete it", payload.path),
+            });
+        }
+        let rw = self.recent_writes();
+
+        let step_id = match interceptor {
+            Some(interceptor) => {
+                Some(self.with_api_step(&interceptor, Self::agent_error_to_stdio, |_| {
+                    interceptor.set_step_command(format!("fs.delete {}", payload.path));
+                    Self::do_delete_path(interceptor.as_ref(), &target, is_dir, rw.as_deref())
+                })?)
+            }
+            None => {
+                Self::do_delete_path(&PassthroughInterceptor::new(), &target, is_dir, None)?;
+                None
+            }
+        };
+
+        Ok(json!({ "deleted": true, "step_id": step_id }))
+    }
+
+    fn fs_stat(&self, payload: FsStatPayload) -> Result<serde_json::Value, StdioError> {
+        let working_dir = self
+            .resolve_read_directory(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
+        let target = codeagent_stdio::validate_path(&payload.path, &working_dir)?;
+        Ok(json!(file_read::stat(&target)?))
+    }
+
+    fn fs_hash(&self, payload: FsHashPayload) -> Result<serde_json::Value, StdioError> {
+        let working_dir = self
+            .resolve_read_directory(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
+        let target = codeagent_stdio::validate_path(&payload.path, &working_dir)?;
+        let hash = file_read::hash(&target).map_err(|e| match e.kind() {
+            std::io::ErrorKind::IsADirectory => StdioError::InvalidField {
+                field: "path".to_string(),
+                message: format!("{} is a directory", payload.path),
+            },
+            _ => StdioError::Io { source: e },
+        })?;
+        Ok(json!(hash))
+    }
+
+    fn fs_patch(&self, payload: FsPatchPayload) -> Result<serde_json::Value, StdioError> {
+        let (working_dir, interceptor) = self
+            .resolve_api_directory(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
+        self.apply_patch_in(
+            &working_dir,
+            interceptor,
+            &payload.patch,
+            payload.path.as_deref(),
+            "fs.patch",
+        )
+    }
+
+    fn fs_commit(&self, payload: FsCommitPayload) -> Result<serde_json::Value, StdioError> {
+        let (working_dir, interceptor) = self
+            .resolve_api_directory(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
+        let upper = self
+            .overlay_dir(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?
+            .ok_or_else(|| StdioError::InvalidField {
+                field: "directory".to_string(),
+                message: format!("{} is not an overlay", working_dir.display()),
+            })?;
+        let mut overlay = Overlay::open(&working_dir, &upper)?;
+        let pending = overlay.changes()?;
+        if payload.dry_run {
+            return Ok(json!({
+                "committed": [],
+                "discarded": [],
+                "pending": pending,
+                "step_id": null,
+            }));
+        }
+
+        // Without `paths`, conflicts stay pending: committing one would
+        // lose the working directory's side of it.
+        let selected: Vec<OverlayChange> = match &payload.paths {
+            None => pending.iter().filter(|change| !change.conflict).cloned().collect(),
+            Some(paths) => paths
+                .iter()
+                .map(|path| {
+                    let change =
+                        pending.iter().find(|change| change.path == *path).ok_or_else(|| {
+                            StdioError::InvalidField {
+                                field: "paths".to_string(),
+                                message: format!("{path} has no pending change"),
+                            }
+                        })?;
+                    if change.conflict {
+                        return Err(StdioError::InvalidField {
+                            field: "paths".to_string(),
+                            message: format!(
+                                "{path} also changed in the working directory; discard it to \
+                                 keep that version"
+                            ),
+                        });
+                    }
+                    Ok(change.clone())
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        let mut commit_all =
+            |interceptor: &dyn WriteInterceptor, rw: Option<&RecentBackendWrites>| {
+                selected
+                    .iter()
+                    .try_for_each(|change| overlay.commit(change, interceptor, rw))
+                    .map_err(Self::agent_error_to_stdio)
+            };
+        let rw = self.recent_writes();
+        let step_id = match interceptor {
+            _ if selected.is_empty() => None,
+            Some(interceptor) => {
+                Some(self.with_api_step(&interceptor, Self::agent_error_to_stdio, |_| {
+                    let paths: Vec<&str> =
+                        selected.iter().map(|change| change.path.as_str()).collect();
+                    interceptor.set_step_command(format!("fs.commit {}", paths.join(", ")));
+                    commit_all(interceptor.as_ref(), rw.as_deref())
+                })?)
+            }
+            None => {
+                commit_all(&PassthroughInterceptor::new(), None)?;
+                None
+            }
+        };
+
+        let rest: Vec<OverlayChange> =
+            pending.into_iter().filter(|change| !selected.contains(change)).collect();
+        let (discarded, pending) = if payload.discard_rest {
+            for change in &rest {
+                overlay.discard(change)?;
+            }
+            (rest, Vec::new())
+        } else {
+            (Vec::new(), rest)
+        };
+        overlay.save()?;
+        Ok(json!({
+            "committed": selected,
+            "discarded": discarded,
+            "pending": pending,
+            "step_id": step_id,
+        }))
+    }
 
-        Ok(json!({ "content": content }))
+    fn fs_trace(&self, payload: FsTracePayload) -> Result<serde_json::Value, StdioError> {
+        let trace = self
+            .fs_trace_for(payload.directory.as_deref())
+            .map_err(Self::agent_error_to_stdio)?;
+        if let Some(enabled) = payload.enabled {
+            trace.set_enabled(enabled);
+        }
+        let limit = payload.limit.unwrap_or(fs_trace::DEFAULT_CAPACITY);
+        let records = trace.records_after(payload.after, limit);
+        let next_after = records.last().map_or(payload.after, |record| record.seq);
+        if payload.clear {
+            trace.clear();
+        }
+        Ok(json!({
+            "enabled": trace.is_enabled(),
+            "records": records,
+            "dropped": trace.dropped(),
+            "next_after": next_after,
+        }))
     }
 
     fn fs_status(&self) -> Result<serde_json::Value, StdioError> {
@@ -1334,10 +3723,24 @@ impl RequestHandler for Orchestrator {
         };
 
         if session.qemu_process.is_some() {
+            let mounts: Vec<serde_json::Value> = session
+                .working_dirs
+                .iter()
+                .zip(&session.mount_backends)
+                .enumerate()
+                .map(|(index, (dir, backend))| {
+                    json!({
+                        "index": index,
+                        "path": dir.display().to_string(),
+                        "backend": backend.as_str(),
+                    })
+                })
+                .collect();
             Ok(json!({
-                "backend": if cfg!(target_os = "windows") { "9p" } else { "virtiofsd" },
+                "backend": fs_backend::vm_backend_name(&session.mount_backends),
                 "vm_status": "running",
                 "vm_pid": session.qemu_process.as_ref().and_then(|p| p.pid()),
+                "mounts": mounts,
             }))
         } else {
             Ok(json!({
@@ -1351,6 +3754,14 @@ impl RequestHandler for Orchestrator {
         &self,
         payload: SafeguardConfigurePayload,
     ) -> Result<serde_json::Value, StdioError> {
+        if let Some(ref patterns) = payload.protected_paths {
+            safeguard::validate_protected_paths(patterns).map_err(|message| {
+                StdioError::InvalidField {
+                    field: "protected_paths".to_string(),
+                    message,
+                }
+            })?;
+        }
         let mut state = self.state.lock().unwrap();
         match &mut *state {
             SessionState::Idle => Err(Self::agent_error_to_stdio(AgentError::SessionNotActive)),
@@ -1362,6 +3773,32 @@ impl RequestHandler for Orchestrator {
                     session.safeguard_config.overwrite_file_size_threshold = Some(threshold);
                 }
                 session.safeguard_config.rename_over_existing = payload.rename_over_existing;
+                if let Some(threshold) = payload.overwrite_count_threshold {
+                    session.safeguard_config.overwrite_count_threshold = Some(threshold);
+                }
+                if let Some(patterns) = payload.protected_paths {
+                    session.safeguard_config.protected_paths = patterns;
+                }
+                if let Some(limit) = payload.max_deleted_bytes_per_step {
+                    session.safeguard_config.max_deleted_bytes_per_step = Some(limit);
+                }
+                if let Some(limit) = payload.max_overwritten_bytes_per_step {
+                    session.safeguard_config.max_overwritten_bytes_per_step = Some(limit);
+                }
+                if let Some(seconds) = payload.timeout_seconds {
+                    session
+                        .pending_safeguards
+                        .set_timeout(Duration::from_secs(seconds));
+                }
+                if let Some(seconds) = payload.max_step_duration_seconds {
+                    session.safeguard_config.max_step_duration_seconds = Some(seconds);
+                }
+                if let Some(operations) = payload.max_step_operations {
+                    session.safeguard_config.max_step_operations = Some(operations);
+                }
+                for interceptor in &session.interceptors {
+                    interceptor.set_safeguard_config(session.safeguard_config.clone());
+                }
                 Ok(json!({}))
             }
         }
@@ -1371,21 +3808,10 @@ impl RequestHandler for Orchestrator {
         &self,
         payload: SafeguardConfirmPayload,
     ) -> Result<serde_json::Value, StdioError> {
-        let mut state = self.state.lock().unwrap();
-        let session = match &mut *state {
-            SessionState::Idle => {
-                return Err(Self::agent_error_to_stdio(AgentError::SessionNotActive));
-            }
-            SessionState::Active(s) => s,
-        };
-
-        let decision = match payload.action.as_str() {
-            "allow" => SafeguardDecision::Allow,
-            _ => SafeguardDecision::Deny,
-        };
-
-        if let Some(sender) = session.pending_safeguards.remove(&payload.safeguard_id) {
-            let _ = sender.send(decision);
+        let decided = self
+            .decide_safeguard(&payload.safeguard_id, &payload.action, DecidedBy::User)
+            .map_err(Self::agent_error_to_stdio)?;
+        if decided {
             Ok(json!({}))
         } else {
             Err(StdioError::InvalidField {
@@ -1394,6 +3820,136 @@ impl RequestHandler for Orchestrator {
             })
         }
     }
+
+    fn safeguard_history(
+        &self,
+        payload: SafeguardHistoryPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        let undo_dir = {
+            let state = self.state.lock().unwrap();
+            let session = match &*state {
+                SessionState::Idle => {
+                    return Err(Self::agent_error_to_stdio(AgentError::SessionNotActive));
+                }
+                SessionState::Active(s) => s,
+            };
+            Self::require_undo(session).map_err(Self::agent_error_to_stdio)?;
+            let index = Self::directory_index(session, payload.directory.as_deref());
+            session.undo_dirs.get(index).cloned().ok_or_else(|| {
+                Self::agent_error_to_stdio(AgentError::InvalidWorkingDir {
+                    path: format!("directory index {index} out of range"),
+                })
+            })?
+        };
+
+        let mut entries = safeguard_log::read(&undo_dir)
+            .map_err(|e| Self::agent_error_to_stdio(AgentError::Io(e)))?;
+        if let Some(limit) = payload.limit {
+            entries.drain(..entries.len().saturating_sub(limit));
+        }
+        Ok(json!({ "entries": entries }))
+    }
+
+    fn system_cleanup(&self) -> Result<serde_json::Value, StdioError> {
+        self.do_system_cleanup()
+            .map_err(Self::agent_error_to_stdio)
+    }
+
+    fn vm_inventory(
+        &self,
+        payload: VmInventoryPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        self.do_vm_inventory(payload)
+            .map_err(Self::agent_error_to_stdio)
+    }
+
+    fn begin_step_scope(&self) -> Result<(), StdioError> {
+        let interceptors = {
+            let state = self.state.lock().unwrap();
+            let SessionState::Active(session) = &*state else {
+                return Err(Self::agent_error_to_stdio(AgentError::SessionNotActive));
+            };
+            Self::require_undo(session).map_err(Self::agent_error_to_stdio)?;
+            session.interceptors.clone()
+        };
+        let mut scope = self.step_scope.lock().unwrap();
+        if scope.is_some() {
+            return Err(StdioError::CapabilityUnavailable {
+                capability: "atomic batches".to_string(),
+                reason: "another atomic batch is running".to_string(),
+            });
+        }
+        *scope = Some(StepScope {
+            interceptors,
+            thread: std::thread::current().id(),
+            opened: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Merge each run of the batch's steps with no other step between them
+    /// into one, or roll back the batch's steps from the newest down to the
+    /// first step that is not the batch's. Steps below one of those stay:
+    /// rolling them back would undo it too.
+    fn end_step_scope(&self, commit: bool) -> Result<serde_json::Value, StdioError> {
+        let Some(scope) = self.step_scope.lock().unwrap().take() else {
+            return Ok(json!({ "directories": [] }));
+        };
+        let _guard = self.suppress_watcher();
+        let to_stdio = |e: CodeAgentError| Self::agent_error_to_stdio(AgentError::from(e));
+        let mut directories = Vec::new();
+        for (index, interceptor) in scope.interceptors.iter().enumerate() {
+            let own: Vec<i64> = scope
+                .opened
+                .iter()
+                .filter_map(|&id| interceptor.history_step_id(id))
+                .collect();
+            if own.is_empty() {
+                continue;
+            }
+            let history = interceptor.completed_steps();
+            let mut runs: Vec<Vec<i64>> = Vec::new();
+            let mut in_run = false;
+            for &id in &history {
+                if !own.contains(&id) {
+                    in_run = false;
+                } else if in_run {
+                    runs.last_mut().unwrap().push(id);
+                } else {
+                    runs.push(vec![id]);
+                    in_run = true;
+                }
+            }
+            if commit {
+                for run in &runs {
+                    let result = interceptor
+                        .squash(run[0], run[run.len() - 1])
+                        .map_err(to_stdio)?;
+                    directories.push(json!({
+                        "directory": index,
+                        "step_id": result.step_id,
+                        "squashed_step_ids": result.squashed_step_ids,
+                    }));
+                }
+            } else {
+                let newest = history.iter().rev().take_while(|id| own.contains(id)).count();
+                let rolled_back = match newest {
+                    0 => Vec::new(),
+                    count => {
+                        interceptor.rollback(count, false).map_err(to_stdio)?.rolled_back_step_ids
+                    }
+                };
+                let kept: Vec<i64> =
+                    own.into_iter().filter(|id| !rolled_back.contains(id)).collect();
+                directories.push(json!({
+                    "directory": index,
+                    "rolled_back_step_ids": rolled_back,
+                    "kept_step_ids": kept,
+                }));
+            }
+        }
+        Ok(json!({ "directories": directories }))
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -1428,7 +3984,7 @@ impl codeagent_mcp::McpHandler for Orchestrator {
             let writer = session.control_writer.clone();
             let handler = session.control_handler.clone();
             let id = session.next_command_id.fetch_add(1, Ordering::Relaxed);
-            let cwd = format!("/mnt/working/{}", session.mount_names[0]);
+            let cwd = guest_cwd::mount_point(&session.mount_names[0]);
             (writer, handler, id, cwd)
         };
 
@@ -1439,59 +3995,29 @@ impl codeagent_mcp::McpHandler for Orchestrator {
         let control_writer = control_writer.unwrap();
         let control_handler = control_handler.unwrap();
 
-        // Register with the waiter before sending so early events are captured
-        self.command_waiter.register(command_id);
-
-        // Register the command with the control channel handler's state machine
-        // (so it expects the StepStarted response from the VM) and get the
-        // serializable HostMessage back. Uses block_in_place because send_exec
-        // is async (may close an ambient step).
         let cwd = &default_cwd;
         let command = strip_cwd_prefix(&args.command, cwd);
 
-        let host_msg = tokio::task::block_in_place(|| {
-            tokio::runtime::Handle::current().block_on(
-                control_handler.send_exec(
-                    command_id,
-                    command,
-                    None,
-                    Some(cwd.to_string()),
-                ),
-            )
-        });
-
-        let json_str = control_bridge::serialize_host_message(&host_msg)
-            .map_err(|error| McpError::InternalError {
-                message: format!("failed to serialize exec message: {error}"),
-            })?;
-
-        control_writer
-            .send(json_str)
-            .map_err(|_| McpError::InternalError {
-                message: "control channel closed".to_string(),
-            })?;
-
-        // Block until the command completes or times out.
-        // Use block_in_place so tokio can spawn a replacement worker thread
-        // while this one is blocked on the Condvar — otherwise async tasks
-        // (control reader, event bridge, P9 server) may starve.
         let timeout_ms = args.timeout.unwrap_or(120_000).min(600_000);
-        let timeout = std::time::Duration::from_millis(timeout_ms);
-        eprintln!(
-            "{{\"level\":\"debug\",\"component\":\"mcp\",\"message\":\"bash: waiting for command {} (timeout {}ms)\"}}",
-            command_id,
-            timeout_ms
-        );
-        let result = tokio::task::block_in_place(|| {
-            self.command_waiter.wait_for_completion(command_id, timeout)
-        });
+        log_debug!("mcp", "bash: waiting for command {command_id} (timeout {timeout_ms}ms)");
+        let started = Instant::now();
+        let result = self
+            .run_in_guest(
+                &control_writer,
+                &control_handler,
+                command_id,
+                command,
+                cwd,
+                Duration::from_millis(timeout_ms),
+            )
+            .map_err(Self::agent_error_to_mcp)?;
 
         match result {
             Some(r) if r.exit_code.is_some() => {
                 let exit_code = r.exit_code.unwrap();
-                eprintln!(
-                    "{{\"level\":\"debug\",\"component\":\"mcp\",\"message\":\"bash: command {} completed with exit code {}\"}}",
-                    command_id, exit_code
+                log_debug!(
+                    "mcp",
+                    "bash: command {command_id} completed with exit code {exit_code}"
                 );
                 let mut output = r.stdout;
                 if !r.stderr.is_empty() {
@@ -1505,13 +4031,11 @@ impl codeagent_mcp::McpHandler for Orchestrator {
                     "exit_code": exit_code,
                     "output": output,
                     "classification": classification.to_string(),
+                    "duration_ms": time::duration_ms(started.elapsed()),
                 }))
             }
             Some(r) => {
-                eprintln!(
-                    "{{\"level\":\"warn\",\"component\":\"mcp\",\"message\":\"bash: command {} timed out\"}}",
-                    command_id
-                );
+                log_warn!("mcp", "bash: command {command_id} timed out");
                 let mut output = r.stdout;
                 if !r.stderr.is_empty() {
                     if !output.is_empty() {
@@ -1524,6 +4048,7 @@ impl codeagent_mcp::McpHandler for Orchestrator {
                     "status": "timeout",
                     "output": output,
                     "classification": classification.to_string(),
+                    "duration_ms": time::duration_ms(started.elapsed()),
                 }))
             }
             None => Err(McpError::InternalError {
@@ -1536,35 +4061,51 @@ impl codeagent_mcp::McpHandler for Orchestrator {
         let target = self
             .resolve_target_path(&args.path)
             .map_err(Self::agent_error_to_mcp)?;
-        let content = std::fs::read_to_string(&target)
-            .map_err(|e| McpError::InternalError {
-                message: e.to_string(),
+        let read = file_read::read_range(&target, args.encoding, args.offset, args.length)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::InvalidData => McpError::InvalidParams {
+                    message: e.to_string(),
+                },
+                _ => McpError::InternalError {
+                    message: e.to_string(),
+                },
             })?;
 
-        Ok(json!({ "content": content }))
+        Ok(json!(read))
     }
 
     fn write_file(&self, args: WriteFileArgs) -> Result<serde_json::Value, McpError> {
-        let interceptor = self
-            .resolve_interceptor_for_path(&args.path)
+        self.require_writable_path(&args.path)
             .map_err(Self::agent_error_to_mcp)?;
+        let interceptor = self.resolve_api_interceptor(&args.path)?;
 
         let target = self
             .resolve_target_path(&args.path)
             .map_err(Self::agent_error_to_mcp)?;
         let rw = self.recent_writes();
 
-        let step_id = self.with_api_step(&interceptor, |_| {
-            Self::do_write_file(&interceptor, &target, &args, rw.as_deref())
-        })?;
+        let step_id = match interceptor {
+            Some(interceptor) => {
+                Some(self.with_api_step(&interceptor, Self::agent_error_to_mcp, |_| {
+                    interceptor.set_step_command(format!("write_file {}", args.path));
+                    Self::do_write_file(interceptor.as_ref(), &target, &args.content, rw.as_deref())
+                        .map_err(Self::stdio_error_to_mcp)
+                })?)
+            }
+            None => {
+                Self::do_write_file(&PassthroughInterceptor::new(), &target, &args.content, None)
+                    .map_err(Self::stdio_error_to_mcp)?;
+                None
+            }
+        };
 
         Ok(json!({ "written": true, "step_id": step_id }))
     }
 
     fn edit_file(&self, args: EditFileArgs) -> Result<serde_json::Value, McpError> {
-        let interceptor = self
-            .resolve_interceptor_for_path(&args.path)
+        self.require_writable_path(&args.path)
             .map_err(Self::agent_error_to_mcp)?;
+        let interceptor = self.resolve_api_interceptor(&args.path)?;
 
         let target = self
             .resolve_target_path(&args.path)
@@ -1599,9 +4140,17 @@ impl codeagent_mcp::McpHandler for Orchestrator {
 
         let rw = self.recent_writes();
 
-        self.with_api_step(&interceptor, |_| {
-            Self::do_edit_file(&interceptor, &target, &args.path, &new_content, rw.as_deref())
-        })?;
+        match interceptor {
+            Some(interceptor) => {
+                self.with_api_step(&interceptor, Self::agent_error_to_mcp, |_| {
+                    interceptor.set_step_command(format!("edit_file {}", args.path));
+                    Self::do_edit_file(interceptor.as_ref(), &target, &new_content, rw.as_deref())
+                })?;
+            }
+            None => {
+                Self::do_edit_file(&PassthroughInterceptor::new(), &target, &new_content, None)?;
+            }
+        }
 
         Ok(json!(format!(
             "The file {} has been updated successfully.",
@@ -1609,6 +4158,20 @@ impl codeagent_mcp::McpHandler for Orchestrator {
         )))
     }
 
+    fn apply_patch(&self, args: ApplyPatchArgs) -> Result<serde_json::Value, McpError> {
+        let (working_dir, interceptor) = self
+            .resolve_api_directory(None)
+            .map_err(Self::agent_error_to_mcp)?;
+        self.apply_patch_in(
+            &working_dir,
+            interceptor,
+            &args.patch,
+            args.path.as_deref(),
+            "apply_patch",
+        )
+        .map_err(Self::stdio_error_to_mcp)
+    }
+
     fn glob(&self, args: GlobArgs) -> Result<serde_json::Value, McpError> {
         let search_dirs: Vec<PathBuf> = match &args.path {
             Some(p) => vec![self
@@ -1805,22 +4368,23 @@ impl codeagent_mcp::McpHandler for Orchestrator {
         let interceptor = self
             .resolve_interceptor(None)
             .map_err(Self::agent_error_to_mcp)?;
+        self.require_writable_directory(None)
+            .map_err(Self::agent_error_to_mcp)?;
 
         let count = args.count as usize;
         let force = args.force;
 
         let _guard = self.suppress_watcher();
 
-        let result = interceptor
-            .rollback(count, force)
-            .map_err(|e| McpError::InternalError {
-                message: e.to_string(),
-            })?;
+        let started_at = chrono::Utc::now();
+        let result = if args.strict {
+            interceptor.rollback_strict(count, force)
+        } else {
+            interceptor.rollback(count, force)
+        }
+        .map_err(|e| Self::agent_error_to_mcp(AgentError::from(e)))?;
 
-        Ok(json!({
-            "steps_rolled_back": result.steps_rolled_back,
-            "barriers_crossed": result.barriers_crossed.len(),
-        }))
+        Ok(Self::rollback_result_json(&result, started_at))
     }
 
     fn get_undo_history(
@@ -1832,7 +4396,10 @@ impl codeagent_mcp::McpHandler for Orchestrator {
             .map_err(Self::agent_error_to_mcp)?;
 
         let steps = interceptor.completed_steps();
-        Ok(json!({ "steps": steps }))
+        Ok(json!({
+            "steps": steps,
+            "details": interceptor.completed_step_info(),
+        }))
     }
 
     fn get_session_status(&self) -> Result<serde_json::Value, McpError> {
@@ -1851,15 +4418,40 @@ impl codeagent_mcp::McpHandler for Orchestrator {
                 return Err(Self::agent_error_to_mcp(AgentError::SessionNotActive))
             }
         };
+        Self::require_undo(session).map_err(Self::agent_error_to_mcp)?;
 
         for interceptor in &session.interceptors {
             interceptor
                 .discard()
-                .map_err(|e| McpError::InternalError {
-                    message: e.to_string(),
-                })?;
+                .map_err(|e| Self::agent_error_to_mcp(AgentError::from(e)))?;
         }
 
         Ok(json!({}))
     }
+
+    fn list_pending_safeguards(
+        &self,
+        _args: ListPendingSafeguardsArgs,
+    ) -> Result<serde_json::Value, McpError> {
+        let state = self.state.lock().unwrap();
+        let session = match &*state {
+            SessionState::Active(s) => s,
+            SessionState::Idle => {
+                return Err(Self::agent_error_to_mcp(AgentError::SessionNotActive))
+            }
+        };
+        Ok(json!({ "pending": session.pending_safeguards.list() }))
+    }
+
+    fn confirm_safeguard(&self, args: ConfirmSafeguardArgs) -> Result<serde_json::Value, McpError> {
+        let decided = self
+            .decide_safeguard(&args.safeguard_id, &args.action, DecidedBy::Mcp)
+            .map_err(Self::agent_error_to_mcp)?;
+        if !decided {
+            return Err(McpError::InvalidParams {
+                message: format!("no pending safeguard with id '{}'", args.safeguard_id),
+            });
+        }
+        Ok(json!({}))
+    }
 }
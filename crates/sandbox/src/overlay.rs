@@ -0,0 +1,616 @@
+//! Copy-on-write overlays of working directories.
+//!
+//! An overlay working directory is served to the guest from an upper
+//! directory, `<undo root>/overlays/<undo subdir name>`, taken as a copy of
+//! the working directory when a session first starts on it (file contents
+//! reflinked where the filesystem allows). The guest's writes land in the
+//! copy only; the working directory itself changes when `fs.commit` applies
+//! some of the overlay's changes, inside an API step of its undo log. The
+//! upper directory outlives the session, so pending changes carry over to
+//! the next one until they are committed or discarded.
+//!
+//! Next to the upper directory, `<subdir name>.base.json` records the tree
+//! both sides started from: types, modes, symlink targets and content
+//! hashes. The overlay's changes are its differences from that base, so
+//! the working directory changing on the host is never taken for one, and
+//! a change whose path also changed in the working directory is a conflict
+//! that `fs.commit` leaves for the caller to resolve. Committing or
+//! discarding a change moves the base along with it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use codeagent_interceptor::write_interceptor::WriteInterceptor;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AgentError;
+use crate::orchestrator::undo_subdir_name;
+use crate::recent_writes::RecentBackendWrites;
+use crate::workspace_clone;
+
+/// Subdirectory of the undo root holding the upper directories.
+pub const OVERLAYS_DIR: &str = "overlays";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    /// Added by the overlay.
+    Created,
+    /// Changed in type, target, mode or contents by the overlay.
+    Modified,
+    /// Removed by the overlay.
+    Deleted,
+}
+
+/// A change the overlay made to its base. A created or deleted directory
+/// is one change covering everything under it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OverlayChange {
+    /// Forward-slash path relative to the working directory.
+    pub path: String,
+    pub kind: ChangeKind,
+    /// The working directory changed the path too, to something other
+    /// than the overlay has. Committing the change would lose that.
+    pub conflict: bool,
+}
+
+/// The upper directory of `working_dir`'s overlay.
+pub fn upper_dir(undo_root: &Path, working_dir: &Path) -> PathBuf {
+    undo_root.join(OVERLAYS_DIR).join(undo_subdir_name(working_dir))
+}
+
+/// The base recorded for the overlay `upper`.
+pub fn base_path(upper: &Path) -> PathBuf {
+    upper.with_extension("base.json")
+}
+
+/// Create the upper directory as a copy of `working_dir`, with its base,
+/// unless it is left over from an earlier session. The copy is built aside
+/// and renamed into place, so an interrupted one is never taken for an
+/// overlay.
+pub fn prepare(working_dir: &Path, upper: &Path) -> io::Result<()> {
+    if upper.exists() {
+        return Ok(());
+    }
+    let partial = upper.with_extension("partial");
+    if partial.exists() {
+        fs::remove_dir_all(&partial)?;
+    }
+    workspace_clone::clone_tree(working_dir, &partial)?;
+    save_base(upper, &record_dir(&partial)?)?;
+    fs::rename(&partial, upper)
+}
+
+/// An entry of the base tree. Names that are not UTF-8, and entries other
+/// than files, directories and symlinks, are left out of it and out of
+/// the changes alike.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BaseEntry {
+    File {
+        len: u64,
+        mode: u32,
+        hash: String,
+        /// Modification time of the overlay's copy in nanoseconds, so an
+        /// unchanged one is not read again.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mtime: Option<u64>,
+    },
+    Dir {
+        mode: u32,
+        entries: BTreeMap<String, BaseEntry>,
+    },
+    Symlink {
+        target: PathBuf,
+    },
+}
+
+impl BaseEntry {
+    /// Record what `path` holds, `None` for nothing.
+    fn record(path: &Path) -> io::Result<Option<Self>> {
+        let Some(metadata) = entry_metadata(path)? else {
+            return Ok(None);
+        };
+        let entry = if metadata.is_symlink() {
+            BaseEntry::Symlink { target: fs::read_link(path)? }
+        } else if metadata.is_dir() {
+            BaseEntry::Dir { mode: mode(&metadata), entries: record_dir(path)? }
+        } else {
+            BaseEntry::File {
+                len: metadata.len(),
+                mode: mode(&metadata),
+                hash: hash_file(path)?,
+                mtime: mtime(&metadata),
+            }
+        };
+        Ok(Some(entry))
+    }
+
+    /// Whether `path` still matches the entry, not looking under a
+    /// directory. With `trust_mtime`, a file of the recorded size and
+    /// modification time is taken to be unchanged without reading it.
+    fn matches(
+        &self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        trust_mtime: bool,
+    ) -> io::Result<bool> {
+        Ok(match self {
+            BaseEntry::File { len, mode: file_mode, hash, mtime: file_mtime } => {
+                metadata.is_file()
+                    && *len == metadata.len()
+                    && *file_mode == mode(metadata)
+                    && ((trust_mtime && file_mtime.is_some() && *file_mtime == mtime(metadata))
+                        || *hash == hash_file(path)?)
+            }
+            BaseEntry::Dir { mode: dir_mode, .. } => {
+                metadata.is_dir() && *dir_mode == mode(metadata)
+            }
+            BaseEntry::Symlink { target } => {
+                metadata.is_symlink() && *target == fs::read_link(path)?
+            }
+        })
+    }
+}
+
+fn record_dir(dir: &Path) -> io::Result<BTreeMap<String, BaseEntry>> {
+    let mut entries = BTreeMap::new();
+    for name in entry_names(dir)? {
+        if let Some(entry) = BaseEntry::record(&dir.join(&name))? {
+            entries.insert(name, entry);
+        }
+    }
+    Ok(entries)
+}
+
+fn save_base(upper: &Path, base: &BTreeMap<String, BaseEntry>) -> io::Result<()> {
+    let path = base_path(upper);
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, serde_json::to_vec(base)?)?;
+    fs::rename(&temp, path)
+}
+
+/// An overlay working directory: its upper directory and the base both
+/// sides started from.
+pub struct Overlay {
+    working_dir: PathBuf,
+    upper: PathBuf,
+    base: BTreeMap<String, BaseEntry>,
+}
+
+impl Overlay {
+    /// The overlay `prepare` made of `working_dir` in `upper`. One left
+    /// over from before bases were recorded takes the working directory as
+    /// it is now for its base.
+    pub fn open(working_dir: &Path, upper: &Path) -> io::Result<Self> {
+        let base = match fs::read(base_path(upper)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let base = record_dir(working_dir)?;
+                save_base(upper, &base)?;
+                base
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self { working_dir: working_dir.to_path_buf(), upper: upper.to_path_buf(), base })
+    }
+
+    /// The overlay's changes to its base, sorted by path.
+    pub fn changes(&self) -> io::Result<Vec<OverlayChange>> {
+        let mut changes = Vec::new();
+        diff_dir(&self.base, &self.upper, "", &mut changes)?;
+        for change in &mut changes {
+            change.conflict = self.conflicts(change)?;
+        }
+        Ok(changes)
+    }
+
+    /// Whether the working directory changed `change`'s path since the
+    /// base, other than the way the overlay did.
+    fn conflicts(&self, change: &OverlayChange) -> io::Result<bool> {
+        let base = self.base_entry(&change.path);
+        let lower = self.working_dir.join(&change.path);
+        let upper = self.upper.join(&change.path);
+        if let Some(BaseEntry::Dir { mode: base_mode, .. }) = base {
+            // The mode of a directory in both; what changed under it are
+            // changes of their own.
+            if let Some(upper) = entry_metadata(&upper)?.filter(|m| m.is_dir()) {
+                return Ok(match entry_metadata(&lower)? {
+                    Some(lower) if lower.is_dir() => {
+                        mode(&lower) != *base_mode && mode(&lower) != mode(&upper)
+                    }
+                    _ => true,
+                });
+            }
+        }
+        // Both sides making the same change is no conflict either.
+        Ok(!tree_matches(base, &lower)?
+            && !tree_matches(BaseEntry::record(&upper)?.as_ref(), &lower)?)
+    }
+
+    /// Apply `change` to the working directory, telling `interceptor`
+    /// about each mutation first so the step can roll it back. The base
+    /// moves along; `save` keeps it.
+    pub fn commit(
+        &mut self,
+        change: &OverlayChange,
+        interceptor: &dyn WriteInterceptor,
+        recent_writes: Option<&RecentBackendWrites>,
+    ) -> Result<(), AgentError> {
+        let target = self.working_dir.join(&change.path);
+        let source = self.upper.join(&change.path);
+        if let Some(rw) = recent_writes {
+            rw.record(&target);
+        }
+        let old = target.symlink_metadata().ok();
+        let new = source.symlink_metadata().ok();
+
+        match (&old, &new) {
+            (Some(old), Some(new)) if old.is_dir() && new.is_dir() => {
+                interceptor.pre_setattr_metadata(&target)?;
+                fs::set_permissions(&target, new.permissions())?;
+            }
+            (Some(old), Some(new)) if old.is_file() && new.is_file() => {
+                interceptor.pre_write(&target)?;
+                fs::copy(&source, &target)?;
+            }
+            _ => {
+                if let Some(old) = &old {
+                    interceptor.pre_unlink(&target, old.is_dir())?;
+                    remove_entry(&target, old)?;
+                }
+                if new.is_some() {
+                    workspace_clone::clone_path(&source, &target)?;
+                    report_created(interceptor, &target)?;
+                }
+            }
+        }
+        self.advance_base(&change.path)?;
+        Ok(())
+    }
+
+    /// Undo `change` in the upper directory, making the path match the
+    /// working directory again. The base moves along; `save` keeps it.
+    pub fn discard(&mut self, change: &OverlayChange) -> io::Result<()> {
+        let lower_entry = self.working_dir.join(&change.path);
+        let upper_entry = self.upper.join(&change.path);
+        let old = lower_entry.symlink_metadata().ok();
+        let new = upper_entry.symlink_metadata().ok();
+
+        match (&old, &new) {
+            (Some(old), Some(new)) if old.is_dir() && new.is_dir() => {
+                fs::set_permissions(&upper_entry, old.permissions())?;
+            }
+            _ => {
+                if let Some(new) = &new {
+                    remove_entry(&upper_entry, new)?;
+                }
+                if old.is_some() {
+                    workspace_clone::clone_path(&lower_entry, &upper_entry)?;
+                }
+            }
+        }
+        self.advance_base(&change.path)
+    }
+
+    /// Write the base as commits and discards left it.
+    pub fn save(&self) -> io::Result<()> {
+        save_base(&self.upper, &self.base)
+    }
+
+    /// Make the base at `path` what both sides now hold there.
+    fn advance_base(&mut self, path: &str) -> io::Result<()> {
+        let upper = self.upper.join(path);
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let Some(entries) = self.base_dir_mut(parent) else {
+            return Ok(());
+        };
+        let entry = match (entries.remove(name), entry_metadata(&upper)?) {
+            // Whatever changed under a directory is a change of its own.
+            (Some(BaseEntry::Dir { entries: children, .. }), Some(metadata))
+                if metadata.is_dir() =>
+            {
+                Some(BaseEntry::Dir { mode: mode(&metadata), entries: children })
+            }
+            _ => BaseEntry::record(&upper)?,
+        };
+        if let Some(entry) = entry {
+            entries.insert(name.to_string(), entry);
+        }
+        Ok(())
+    }
+
+    fn base_entry(&self, path: &str) -> Option<&BaseEntry> {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let mut entries = &self.base;
+        for component in parent.split('/').filter(|c| !c.is_empty()) {
+            match entries.get(component) {
+                Some(BaseEntry::Dir { entries: children, .. }) => entries = children,
+                _ => return None,
+            }
+        }
+        entries.get(name)
+    }
+
+    fn base_dir_mut(&mut self, path: &str) -> Option<&mut BTreeMap<String, BaseEntry>> {
+        let mut entries = &mut self.base;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            match entries.get_mut(component) {
+                Some(BaseEntry::Dir { entries: children, .. }) => entries = children,
+                _ => return None,
+            }
+        }
+        Some(entries)
+    }
+}
+
+/// Push the differences of `dir` from `base` onto `changes`.
+fn diff_dir(
+    base: &BTreeMap<String, BaseEntry>,
+    dir: &Path,
+    relative: &str,
+    changes: &mut Vec<OverlayChange>,
+) -> io::Result<()> {
+    let mut names = entry_names(dir)?;
+    names.extend(base.keys().cloned());
+    names.sort();
+    names.dedup();
+
+    for name in names {
+        let path = if relative.is_empty() { name.clone() } else { format!("{relative}/{name}") };
+        let entry = dir.join(&name);
+        let kind = match (base.get(&name), entry_metadata(&entry)?) {
+            (None, None) => continue,
+            (None, Some(_)) => ChangeKind::Created,
+            (Some(_), None) => ChangeKind::Deleted,
+            (Some(BaseEntry::Dir { mode: dir_mode, entries }), Some(metadata))
+                if metadata.is_dir() =>
+            {
+                if *dir_mode != mode(&metadata) {
+                    changes.push(OverlayChange {
+                        path: path.clone(),
+                        kind: ChangeKind::Modified,
+                        conflict: false,
+                    });
+                }
+                diff_dir(entries, &entry, &path, changes)?;
+                continue;
+            }
+            (Some(old), Some(metadata)) => {
+                if old.matches(&entry, &metadata, true)? {
+                    continue;
+                }
+                ChangeKind::Modified
+            }
+        };
+        changes.push(OverlayChange { path, kind, conflict: false });
+    }
+    Ok(())
+}
+
+/// Whether `path` and everything under it match `base`, reading every file.
+fn tree_matches(base: Option<&BaseEntry>, path: &Path) -> io::Result<bool> {
+    let (base, metadata) = match (base, entry_metadata(path)?) {
+        (None, None) => return Ok(true),
+        (Some(base), Some(metadata)) => (base, metadata),
+        _ => return Ok(false),
+    };
+    if !base.matches(path, &metadata, false)? {
+        return Ok(false);
+    }
+    let BaseEntry::Dir { entries, .. } = base else {
+        return Ok(true);
+    };
+    let mut names = entry_names(path)?;
+    names.extend(entries.keys().cloned());
+    names.sort();
+    names.dedup();
+    for name in names {
+        if !tree_matches(entries.get(&name), &path.join(&name))? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn entry_names(dir: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        if let Ok(name) = entry?.file_name().into_string() {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// The metadata of a file, directory or symlink at `path`; `None` for
+/// nothing or anything else.
+fn entry_metadata(path: &Path) -> io::Result<Option<fs::Metadata>> {
+    match path.symlink_metadata() {
+        Ok(metadata) => {
+            let file_type = metadata.file_type();
+            Ok((file_type.is_file() || file_type.is_dir() || file_type.is_symlink())
+                .then_some(metadata))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    u32::from(metadata.permissions().readonly())
+}
+
+fn mtime(metadata: &fs::Metadata) -> Option<u64> {
+    let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Tell `interceptor` about the entry just created at `path` and, for a
+/// directory, everything under it.
+fn report_created(interceptor: &dyn WriteInterceptor, path: &Path) -> Result<(), AgentError> {
+    let metadata = path.symlink_metadata()?;
+    if metadata.is_symlink() {
+        interceptor.post_symlink(&fs::read_link(path)?, path)?;
+    } else if metadata.is_dir() {
+        interceptor.post_mkdir(path)?;
+        for entry in fs::read_dir(path)? {
+            report_created(interceptor, &entry?.path())?;
+        }
+    } else {
+        interceptor.post_create(path)?;
+    }
+    Ok(())
+}
+
+fn remove_entry(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codeagent_interceptor::passthrough::PassthroughInterceptor;
+
+    use super::*;
+
+    fn change(path: &str, kind: ChangeKind) -> OverlayChange {
+        OverlayChange { path: path.to_string(), kind, conflict: false }
+    }
+
+    fn overlay() -> (tempfile::TempDir, tempfile::TempDir, PathBuf) {
+        let working_dir = tempfile::tempdir().unwrap();
+        let undo_root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(working_dir.path().join("src/old")).unwrap();
+        fs::write(working_dir.path().join("src/lib.rs"), b"fn lib() {}").unwrap();
+        fs::write(working_dir.path().join("src/old/mod.rs"), b"mod old;").unwrap();
+        fs::write(working_dir.path().join("README.md"), b"readme").unwrap();
+        let upper = upper_dir(undo_root.path(), working_dir.path());
+        prepare(working_dir.path(), &upper).unwrap();
+        (working_dir, undo_root, upper)
+    }
+
+    fn changes(working_dir: &Path, upper: &Path) -> Vec<OverlayChange> {
+        Overlay::open(working_dir, upper).unwrap().changes().unwrap()
+    }
+
+    #[test]
+    fn changes_list_the_differences_from_the_base() {
+        let (working_dir, _undo_root, upper) = overlay();
+        assert_eq!(changes(working_dir.path(), &upper), vec![]);
+
+        fs::write(upper.join("src/lib.rs"), b"fn lib() { changed() }").unwrap();
+        fs::remove_dir_all(upper.join("src/old")).unwrap();
+        fs::create_dir_all(upper.join("docs/guide")).unwrap();
+        fs::write(upper.join("docs/guide/intro.md"), b"intro").unwrap();
+
+        assert_eq!(
+            changes(working_dir.path(), &upper),
+            vec![
+                change("docs", ChangeKind::Created),
+                change("src/lib.rs", ChangeKind::Modified),
+                change("src/old", ChangeKind::Deleted),
+            ],
+        );
+        // Preparing again keeps the pending changes.
+        prepare(working_dir.path(), &upper).unwrap();
+        assert_eq!(changes(working_dir.path(), &upper).len(), 3);
+    }
+
+    #[test]
+    fn committed_changes_reach_the_working_directory_and_discarded_ones_revert() {
+        let (working_dir, _undo_root, upper) = overlay();
+        fs::write(upper.join("src/lib.rs"), b"fn lib() { changed() }").unwrap();
+        fs::remove_dir_all(upper.join("src/old")).unwrap();
+        fs::create_dir_all(upper.join("docs")).unwrap();
+        fs::write(upper.join("docs/intro.md"), b"intro").unwrap();
+        fs::remove_file(upper.join("README.md")).unwrap();
+
+        let interceptor = PassthroughInterceptor::new();
+        let mut overlay = Overlay::open(working_dir.path(), &upper).unwrap();
+        for committed in [
+            change("docs", ChangeKind::Created),
+            change("src/lib.rs", ChangeKind::Modified),
+            change("src/old", ChangeKind::Deleted),
+        ] {
+            overlay.commit(&committed, &interceptor, None).unwrap();
+        }
+        overlay.save().unwrap();
+        assert_eq!(
+            fs::read(working_dir.path().join("src/lib.rs")).unwrap(),
+            b"fn lib() { changed() }",
+        );
+        assert_eq!(fs::read(working_dir.path().join("docs/intro.md")).unwrap(), b"intro");
+        assert!(!working_dir.path().join("src/old").exists());
+
+        let pending = changes(working_dir.path(), &upper);
+        assert_eq!(pending, vec![change("README.md", ChangeKind::Deleted)]);
+        let mut overlay = Overlay::open(working_dir.path(), &upper).unwrap();
+        overlay.discard(&pending[0]).unwrap();
+        overlay.save().unwrap();
+        assert_eq!(fs::read(upper.join("README.md")).unwrap(), b"readme");
+        assert_eq!(changes(working_dir.path(), &upper), vec![]);
+    }
+
+    #[test]
+    fn working_directory_changes_are_not_overlay_changes_and_clashing_ones_conflict() {
+        let (working_dir, _undo_root, upper) = overlay();
+        // The host edits a file the overlay leaves alone, and one the
+        // overlay edits too.
+        fs::write(working_dir.path().join("README.md"), b"host readme").unwrap();
+        fs::write(working_dir.path().join("src/lib.rs"), b"fn lib() { host() }").unwrap();
+        fs::write(upper.join("src/lib.rs"), b"fn lib() { guest() }").unwrap();
+        fs::remove_dir_all(upper.join("src/old")).unwrap();
+
+        let conflict =
+            OverlayChange { conflict: true, ..change("src/lib.rs", ChangeKind::Modified) };
+        let pending = changes(working_dir.path(), &upper);
+        assert_eq!(pending, vec![conflict.clone(), change("src/old", ChangeKind::Deleted)]);
+
+        let mut overlay = Overlay::open(working_dir.path(), &upper).unwrap();
+        overlay.commit(&pending[1], &PassthroughInterceptor::new(), None).unwrap();
+        overlay.save().unwrap();
+        assert_eq!(fs::read(working_dir.path().join("README.md")).unwrap(), b"host readme");
+        assert!(!working_dir.path().join("src/old").exists());
+        assert_eq!(changes(working_dir.path(), &upper), vec![conflict.clone()]);
+
+        // Both sides making the same change is no conflict.
+        fs::write(working_dir.path().join("src/lib.rs"), b"fn lib() { guest() }").unwrap();
+        assert_eq!(
+            changes(working_dir.path(), &upper),
+            vec![change("src/lib.rs", ChangeKind::Modified)],
+        );
+
+        // Discarding takes the working directory's version.
+        fs::write(working_dir.path().join("src/lib.rs"), b"fn lib() { host() }").unwrap();
+        let mut overlay = Overlay::open(working_dir.path(), &upper).unwrap();
+        overlay.discard(&conflict).unwrap();
+        overlay.save().unwrap();
+        assert_eq!(fs::read(upper.join("src/lib.rs")).unwrap(), b"fn lib() { host() }");
+        assert_eq!(changes(working_dir.path(), &upper), vec![]);
+    }
+}
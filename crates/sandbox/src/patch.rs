@@ -0,0 +1,396 @@
+//! Unified diffs behind MCP `apply_patch` and `fs.patch`.
+//!
+//! A patch holds one or more files, each introduced by `---`/`+++` headers
+//! (git's `a/` and `b/` prefixes are stripped; `/dev/null` on one side
+//! creates or deletes the file) and made of `@@ -l,s +l,s @@` hunks. Lines
+//! around the headers such as `diff --git` or `index` are skipped. A hunk
+//! applies where its context and removed lines match the file: at the line
+//! its header names or, if the file moved since the diff was made, the
+//! nearest line above or below. Callers write nothing unless every hunk of
+//! every file applies.
+
+use serde::Serialize;
+
+/// One file's part of a patch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePatch {
+    /// Path from the headers; `None` for bare hunks without headers.
+    pub path: Option<String>,
+    /// `--- /dev/null`: the file must not exist yet.
+    pub creates: bool,
+    /// `+++ /dev/null`: the hunks must remove all of the file.
+    pub deletes: bool,
+    pub hunks: Vec<Hunk>,
+}
+
+/// One `@@` hunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    /// The `@@ ... @@` line, for reporting.
+    pub header: String,
+    old_start: usize,
+    lines: Vec<HunkLine>,
+    /// `\ No newline at end of file` after the hunk's last new-side line.
+    new_missing_newline: bool,
+}
+
+impl Hunk {
+    /// A `\\ No newline at end of file` marker follows the last line read.
+    /// After a removed line it is about the old file, which does not matter.
+    fn note_missing_newline(&mut self) {
+        if !matches!(self.lines.last(), Some(HunkLine::Remove(_))) {
+            self.new_missing_newline = true;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+/// A patch that is not a unified diff.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("patch line {line}: {message}")]
+pub struct PatchParseError {
+    /// 1-based line of the patch.
+    pub line: usize,
+    pub message: String,
+}
+
+/// A hunk that does not match the file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RejectedHunk {
+    /// 1-based position of the hunk in its file's part of the patch.
+    pub hunk: usize,
+    pub header: String,
+    pub reason: String,
+}
+
+/// What applying a file's hunks leaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchedContent {
+    Write(String),
+    Delete,
+}
+
+/// Parse the unified diff `patch`.
+pub fn parse(patch: &str) -> Result<Vec<FilePatch>, PatchParseError> {
+    let mut lines: Vec<&str> = patch.split('\n').collect();
+    if lines.last() == Some(&"") {
+        lines.pop();
+    }
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        if let (Some(old), Some(new)) = (
+            line.strip_prefix("--- "),
+            lines.get(index + 1).and_then(|next| next.strip_prefix("+++ ")),
+        ) {
+            files.push(file_from_headers(old, new).map_err(|message| PatchParseError {
+                line: index + 1,
+                message,
+            })?);
+            index += 2;
+        } else if line.starts_with("@@") {
+            if files.is_empty() {
+                files.push(FilePatch {
+                    path: None,
+                    creates: false,
+                    deletes: false,
+                    hunks: Vec::new(),
+                });
+            }
+            let (hunk, next) = parse_hunk(&lines, index)?;
+            files.last_mut().expect("pushed above").hunks.push(hunk);
+            index = next;
+        } else {
+            index += 1;
+        }
+    }
+    if let Some(empty) = files.iter().position(|file| file.hunks.is_empty()) {
+        return Err(PatchParseError {
+            line: lines.len(),
+            message: format!(
+                "no hunks for {}",
+                files[empty].path.as_deref().unwrap_or("the file")
+            ),
+        });
+    }
+    Ok(files)
+}
+
+fn file_from_headers(old: &str, new: &str) -> Result<FilePatch, String> {
+    let header_path = |header: &str| {
+        let path = header.split('\t').next().unwrap_or_default().trim_end();
+        (path != "/dev/null").then(|| path.to_string())
+    };
+    let (mut old, mut new) = (header_path(old), header_path(new));
+    let prefixed = |path: &Option<String>, prefix: &str| {
+        path.as_ref().is_none_or(|path| path.starts_with(prefix))
+    };
+    if prefixed(&old, "a/") && prefixed(&new, "b/") {
+        for path in [&mut old, &mut new].into_iter().flatten() {
+            path.drain(..2);
+        }
+    }
+    match (old, new) {
+        (None, None) => Err("both sides are /dev/null".to_string()),
+        (Some(old), Some(new)) if old != new => {
+            Err(format!("renaming {old} to {new} is not supported"))
+        }
+        (old, new) => Ok(FilePatch {
+            creates: old.is_none(),
+            deletes: new.is_none(),
+            path: new.or(old),
+            hunks: Vec::new(),
+        }),
+    }
+}
+
+/// Parse the hunk whose header is `lines[start]`. Returns it and the index
+/// of the line after it.
+fn parse_hunk(lines: &[&str], start: usize) -> Result<(Hunk, usize), PatchParseError> {
+    let header = lines[start].trim_end_matches('\r');
+    let error = |line: usize, message: &str| PatchParseError {
+        line: line + 1,
+        message: message.to_string(),
+    };
+    let ranges = header
+        .strip_prefix("@@ -")
+        .and_then(|rest| rest.split_once(" @@"))
+        .map(|(ranges, _)| ranges)
+        .and_then(|ranges| ranges.split_once(" +"))
+        .ok_or_else(|| error(start, "hunk header is not `@@ -l,s +l,s @@`"))?;
+    let range = |range: &str| -> Option<(usize, usize)> {
+        match range.split_once(',') {
+            Some((line, count)) => Some((line.parse().ok()?, count.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+    let ((old_start, mut old_left), (_, mut new_left)) = range(ranges.0)
+        .zip(range(ranges.1))
+        .ok_or_else(|| error(start, "hunk header has a bad line range"))?;
+
+    let mut hunk = Hunk {
+        header: header.to_string(),
+        old_start,
+        lines: Vec::new(),
+        new_missing_newline: false,
+    };
+    let mut index = start + 1;
+    while old_left > 0 || new_left > 0 {
+        let line = *lines
+            .get(index)
+            .ok_or_else(|| error(index - 1, "hunk ends before its line counts"))?;
+        // The marker byte is ASCII, so the text starts right after it.
+        let text = line.get(1..).unwrap_or_default().to_string();
+        match line.as_bytes().first() {
+            // Editors often strip the space from empty context lines.
+            Some(b' ') | None if old_left > 0 && new_left > 0 => {
+                old_left -= 1;
+                new_left -= 1;
+                hunk.lines.push(HunkLine::Context(text));
+            }
+            Some(b'-') if old_left > 0 => {
+                old_left -= 1;
+                hunk.lines.push(HunkLine::Remove(text));
+            }
+            Some(b'+') if new_left > 0 => {
+                new_left -= 1;
+                hunk.lines.push(HunkLine::Add(text));
+            }
+            Some(b'\\') => hunk.note_missing_newline(),
+            _ => return Err(error(index, "line does not fit the hunk's line counts")),
+        }
+        index += 1;
+    }
+    while lines.get(index).is_some_and(|line| line.starts_with('\\')) {
+        hunk.note_missing_newline();
+        index += 1;
+    }
+    Ok((hunk, index))
+}
+
+/// Apply `file`'s hunks to `current`, the file's content (`None` if it does
+/// not exist). Returns every hunk that does not apply.
+pub fn apply_to_file(
+    file: &FilePatch,
+    current: Option<&str>,
+) -> Result<PatchedContent, Vec<RejectedHunk>> {
+    let whole_file = |reason: &str| {
+        vec![RejectedHunk {
+            hunk: 1,
+            header: file.hunks[0].header.clone(),
+            reason: reason.to_string(),
+        }]
+    };
+    let current = match (current, file.creates) {
+        (Some(_), true) => return Err(whole_file("the file already exists")),
+        (None, false) => return Err(whole_file("the file does not exist")),
+        (current, _) => current.unwrap_or_default(),
+    };
+    let patched = apply(current, &file.hunks)?;
+    if !file.deletes {
+        return Ok(PatchedContent::Write(patched));
+    }
+    if !patched.is_empty() {
+        return Err(whole_file("the hunks do not remove all of the file"));
+    }
+    Ok(PatchedContent::Delete)
+}
+
+/// Apply `hunks`, in order, to `content`.
+pub fn apply(content: &str, hunks: &[Hunk]) -> Result<String, Vec<RejectedHunk>> {
+    let mut original: Vec<&str> = content.split('\n').collect();
+    let mut ends_with_newline = true;
+    match original.last() {
+        Some(&"") => {
+            original.pop();
+        }
+        _ => ends_with_newline = false,
+    }
+
+    let mut patched: Vec<&str> = Vec::with_capacity(original.len());
+    let mut cursor = 0;
+    let mut rejected = Vec::new();
+    for (index, hunk) in hunks.iter().enumerate() {
+        let old: Vec<&str> = hunk
+            .lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Remove(text) => Some(text.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect();
+        let Some(at) = find(&original, &old, cursor, hunk.old_start) else {
+            rejected.push(RejectedHunk {
+                hunk: index + 1,
+                header: hunk.header.clone(),
+                reason: "context and removed lines do not match the file".to_string(),
+            });
+            continue;
+        };
+        patched.extend_from_slice(&original[cursor..at]);
+        patched.extend(hunk.lines.iter().filter_map(|line| match line {
+            HunkLine::Context(text) | HunkLine::Add(text) => Some(text.as_str()),
+            HunkLine::Remove(_) => None,
+        }));
+        cursor = at + old.len();
+        if cursor == original.len() {
+            ends_with_newline = !hunk.new_missing_newline;
+        }
+    }
+    if !rejected.is_empty() {
+        return Err(rejected);
+    }
+    patched.extend_from_slice(&original[cursor..]);
+
+    let mut text = patched.join("\n");
+    if ends_with_newline && !patched.is_empty() {
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// Where `old` occurs in `lines` at or after `from`, nearest to the 1-based
+/// line `old_start` (0 for a hunk at the start of an empty file).
+fn find(lines: &[&str], old: &[&str], from: usize, old_start: usize) -> Option<usize> {
+    let last = lines.len().checked_sub(old.len())?;
+    if from > last {
+        return None;
+    }
+    let wanted = old_start.saturating_sub(1).clamp(from, last);
+    let matches = |at: usize| lines[at..at + old.len()] == *old;
+    (0..=last - from).find_map(|distance| {
+        [wanted.checked_add(distance), wanted.checked_sub(distance)]
+            .into_iter()
+            .flatten()
+            .find(|&at| at >= from && at <= last && matches(at))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_patch(content: &str, patch: &str) -> Result<String, Vec<RejectedHunk>> {
+        let files = parse(patch).unwrap();
+        apply(content, &files[0].hunks)
+    }
+
+    #[test]
+    fn git_diff_headers_name_the_file() {
+        let patch = "diff --git a/src/lib.rs b/src/lib.rs\n\
+                     index 83db48f..bf269f4 100644\n\
+                     --- a/src/lib.rs\n\
+                     +++ b/src/lib.rs\n\
+                     @@ -1,2 +1,2 @@\n \
+                     fn a() {}\n\
+                     -fn b() {}\n\
+                     +fn c() {}\n";
+        let files = parse(patch).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.as_deref(), Some("src/lib.rs"));
+        assert!(!files[0].creates && !files[0].deletes);
+        assert_eq!(
+            apply("fn a() {}\nfn b() {}\n", &files[0].hunks).unwrap(),
+            "fn a() {}\nfn c() {}\n"
+        );
+    }
+
+    #[test]
+    fn hunks_apply_where_the_file_moved() {
+        let patch = "@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n@@ -8,1 +8,2 @@\n h\n+i\n";
+        let content = "new\na\nb\nc\nd\ne\nf\ng\nh\n";
+        assert_eq!(
+            apply_patch(content, patch).unwrap(),
+            "new\na\nb\nC\nd\ne\nf\ng\nh\ni\n"
+        );
+    }
+
+    #[test]
+    fn every_mismatched_hunk_is_rejected() {
+        let patch = "@@ -1 +1 @@\n-x\n+y\n@@ -2 +2 @@\n-b\n+B\n@@ -3 +3 @@\n-z\n+Z\n";
+        let rejected = apply_patch("a\nb\nc\n", patch).unwrap_err();
+        let hunks: Vec<_> = rejected.iter().map(|rejected| rejected.hunk).collect();
+        assert_eq!(hunks, [1, 3]);
+        assert_eq!(rejected[0].header, "@@ -1 +1 @@");
+    }
+
+    #[test]
+    fn missing_newline_markers_are_kept() {
+        let add_newline = "@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+a\n";
+        assert_eq!(apply_patch("a", add_newline).unwrap(), "a\n");
+        let drop_newline = "@@ -1 +1 @@\n-a\n+b\n\\ No newline at end of file\n";
+        assert_eq!(apply_patch("a\n", drop_newline).unwrap(), "b");
+    }
+
+    #[test]
+    fn dev_null_creates_and_deletes() {
+        let create = parse("--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1,2 @@\n+one\n+two\n").unwrap();
+        assert!(create[0].creates);
+        assert_eq!(
+            apply_to_file(&create[0], None).unwrap(),
+            PatchedContent::Write("one\ntwo\n".to_string())
+        );
+        assert!(apply_to_file(&create[0], Some("")).is_err());
+
+        let delete = parse("--- a/old.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-gone\n").unwrap();
+        assert_eq!(delete[0].path.as_deref(), Some("old.txt"));
+        assert_eq!(apply_to_file(&delete[0], Some("gone\n")).unwrap(), PatchedContent::Delete);
+        assert!(apply_to_file(&delete[0], Some("gone\nkept\n")).is_err());
+    }
+
+    #[test]
+    fn malformed_patches_are_errors() {
+        assert_eq!(parse("@@ -1 +1 @@\n-a\n").unwrap_err().line, 2);
+        assert!(parse("@@ garbage @@\n").is_err());
+        assert!(parse("--- a/x\n+++ b/y\n@@ -1 +1 @@\n-a\n+b\n").is_err());
+        assert!(parse("--- a/x\n+++ b/x\n").is_err());
+        assert_eq!(parse("just text\n").unwrap(), vec![]);
+    }
+}
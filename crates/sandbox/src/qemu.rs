@@ -1,10 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Read};
 use std::path::{Path, PathBuf};
-use std::process::Child;
+use std::process::{Child, ExitStatus};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use crate::error::AgentError;
+use crate::qmp::QmpClient;
+use crate::vm_state::{self, SavedVmState};
 
 /// Timeout for waiting for the control socket to appear after QEMU starts.
 #[cfg(not(target_os = "windows"))]
@@ -17,6 +21,9 @@ const SOCKET_POLL_INTERVAL: Duration = Duration::from_millis(100);
 /// Timeout for graceful QEMU shutdown before sending SIGKILL.
 const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Lines of QEMU output kept for crash reports.
+const CONSOLE_TAIL_LINES: usize = 50;
+
 /// Maximum length for virtiofs tags and virtio-serial port names.
 const MAX_MOUNT_NAME_LEN: usize = 36;
 
@@ -155,6 +162,35 @@ fn find_collisions(names: &[String]) -> Vec<usize> {
     colliding
 }
 
+/// Name of the named pipe QEMU serves the control channel on for a VM with
+/// `control_socket_path`, on Windows hosts. Pipes live in a namespace of
+/// their own, so the name is a hash of the path: stable for the VM, distinct
+/// between VMs and between sandboxes.
+pub fn control_pipe_name(control_socket_path: &Path) -> String {
+    let hash = blake3::hash(control_socket_path.as_os_str().as_encoded_bytes());
+    format!("codeagent-{}-control", &hash.to_hex()[..16])
+}
+
+/// How a filesystem backend's socket is attached to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsTransport {
+    /// vhost-user-fs-pci device on a virtiofsd socket, mounted as virtiofs.
+    VhostUser,
+    /// virtio-serial port on a P9 server's TCP address, mounted through
+    /// p9proxy.
+    P9Serial,
+}
+
+impl Default for FsTransport {
+    fn default() -> Self {
+        if cfg!(target_os = "windows") {
+            Self::P9Serial
+        } else {
+            Self::VhostUser
+        }
+    }
+}
+
 /// Configuration for launching a QEMU virtual machine.
 #[derive(Debug, Clone)]
 pub struct QemuConfig {
@@ -185,6 +221,10 @@ pub struct QemuConfig {
     /// Paths for filesystem sockets (one per working dir, host-side).
     pub fs_socket_paths: Vec<PathBuf>,
 
+    /// How each filesystem socket is attached (same order as
+    /// `fs_socket_paths`). Missing entries use the platform default.
+    pub fs_transports: Vec<FsTransport>,
+
     /// VM lifecycle mode ("ephemeral" or "persistent").
     pub vm_mode: String,
 
@@ -192,6 +232,22 @@ pub struct QemuConfig {
     /// Used as virtiofs tags (Unix) and virtio-serial port names (Windows).
     pub mount_names: Vec<String>,
 
+    /// Path for the QMP monitor socket (Unix only), used to save and resume
+    /// the VM state of persistent sessions. None leaves QMP off.
+    pub qmp_socket_path: Option<PathBuf>,
+
+    /// Saved VM state to resume instead of booting (`-incoming`).
+    pub incoming_state: Option<PathBuf>,
+
+    /// Empty PCIe root ports (`hp0`, `hp1`, ...) for shares hot-plugged
+    /// after boot, as in VMs of the warm pool.
+    pub hotplug_ports: u32,
+
+    /// DAX window of each vhost-user-fs device, in megabytes (`cache-size`).
+    /// The guest then mounts its shares with `dax=inode`, mapping only the
+    /// files the filesystem backend grants DAX. None gives no window.
+    pub dax_window_mb: Option<u32>,
+
     /// Extra QEMU command-line arguments.
     pub extra_args: Vec<String>,
 }
@@ -211,8 +267,10 @@ impl QemuConfig {
         self.add_platform_args(&mut args);
         self.add_common_args(&mut args);
         self.add_filesystem_args(&mut args, &mut extra_kernel_params);
+        self.add_hotplug_args(&mut args);
         self.add_control_channel_args(&mut args);
         self.add_boot_args(&mut args, &extra_kernel_params);
+        self.add_state_args(&mut args);
         self.add_extra_args(&mut args);
 
         Ok((binary, args))
@@ -269,27 +327,44 @@ impl QemuConfig {
         args.extend(["-device".into(), "virtio-net-pci,netdev=net0".into()]);
         args.push("-nographic".into());
 
-        // On Windows, both filesystem (virtserialport) and control channel
-        // (virtserialport) devices need the virtio-serial-pci bus. Add it
-        // here so it's available before any port devices are added.
+        // When filesystem ports (virtserialport) share the virtio-serial-pci
+        // bus with the control channel, add it here so it's available
+        // before any port devices are added.
+        if self.serial_bus_before_filesystems() {
+            args.extend(["-device".into(), "virtio-serial-pci".into()]);
+        }
         #[cfg(target_os = "windows")]
         {
-            args.extend(["-device".into(), "virtio-serial-pci".into()]);
             // Disable MSI-X for all virtio PCI devices to work around WHPX
             // MSI injection failures. Falls back to legacy INTx interrupts.
             args.extend(["-global".into(), "virtio-pci.vectors=0".into()]);
         }
     }
 
-    /// Filesystem sharing devices (virtiofs on Linux/macOS, 9P on Windows).
+    /// Whether the virtio-serial-pci bus goes in before the filesystem
+    /// devices: always on Windows, elsewhere only for 9P mounts.
+    fn serial_bus_before_filesystems(&self) -> bool {
+        cfg!(target_os = "windows")
+            || (0..self.fs_socket_paths.len())
+                .any(|index| self.fs_transport(index) == FsTransport::P9Serial)
+    }
+
+    fn fs_transport(&self, index: usize) -> FsTransport {
+        self.fs_transports.get(index).copied().unwrap_or_default()
+    }
+
+    /// Filesystem sharing devices, one per working directory, by transport.
+    ///
+    /// `VhostUser`: a vhost-user-fs-pci device with a socket chardev
+    /// (connects to virtiofsd).
     ///
-    /// On Linux/macOS: uses vhost-user-fs-pci with a socket chardev for
-    /// each working directory (connects to virtiofsd).
+    /// `P9Serial`: a virtio-serial chardev connecting to the host-side
+    /// P9Backend TCP listener, with a virtserialport device named after the
+    /// mount. Inside the guest, the p9proxy binary bridges the serial port
+    /// to a Unix socketpair for the kernel's 9P `trans=fd` transport.
     ///
-    /// On Windows: uses a virtio-serial chardev connecting to the host-side
-    /// P9Backend TCP listener, with a virtserialport device named `p9fsN`.
-    /// Inside the guest, the p9proxy binary bridges the serial port to a
-    /// Unix socketpair for the kernel's 9P `trans=fd` transport.
+    /// The guest init tries virtiofs first and p9proxy second for every
+    /// mount name, so the two can be mixed.
     fn add_filesystem_args(
         &self,
         args: &mut Vec<OsString>,
@@ -298,34 +373,35 @@ impl QemuConfig {
         for (index, socket_path) in self.fs_socket_paths.iter().enumerate() {
             let mount_name = &self.mount_names[index];
 
-            #[cfg(not(target_os = "windows"))]
-            {
-                let chardev_id = format!("vfs{index}");
-                args.extend([
-                    "-chardev".into(),
-                    format!("socket,id={chardev_id},path={}", socket_path.display()).into(),
-                ]);
-                args.extend([
-                    "-device".into(),
-                    format!("vhost-user-fs-pci,chardev={chardev_id},tag={mount_name}").into(),
-                ]);
-            }
-
-            #[cfg(target_os = "windows")]
-            {
-                let addr = std::fs::read_to_string(socket_path).unwrap_or_default();
-                let addr = addr.trim().to_string();
-                let (host, port) = addr.rsplit_once(':').unwrap_or((&addr, "0"));
-                let chardev_id = format!("p9fs{index}");
-
-                args.extend([
-                    "-chardev".into(),
-                    format!("socket,id={chardev_id},host={host},port={port},server=off").into(),
-                ]);
-                args.extend([
-                    "-device".into(),
-                    format!("virtserialport,chardev={chardev_id},name={mount_name}").into(),
-                ]);
+            match self.fs_transport(index) {
+                FsTransport::VhostUser => {
+                    let chardev_id = format!("vfs{index}");
+                    args.extend([
+                        "-chardev".into(),
+                        format!("socket,id={chardev_id},path={}", socket_path.display()).into(),
+                    ]);
+                    let mut device =
+                        format!("vhost-user-fs-pci,chardev={chardev_id},tag={mount_name}");
+                    if let Some(window_mb) = self.dax_window_mb {
+                        device.push_str(&format!(",cache-size={window_mb}M"));
+                    }
+                    args.extend(["-device".into(), device.into()]);
+                }
+                FsTransport::P9Serial => {
+                    let addr = std::fs::read_to_string(socket_path).unwrap_or_default();
+                    let addr = addr.trim().to_string();
+                    let (host, port) = addr.rsplit_once(':').unwrap_or((&addr, "0"));
+                    let chardev_id = format!("p9fs{index}");
+
+                    args.extend([
+                        "-chardev".into(),
+                        format!("socket,id={chardev_id},host={host},port={port},server=off").into(),
+                    ]);
+                    args.extend([
+                        "-device".into(),
+                        format!("virtserialport,chardev={chardev_id},name={mount_name}").into(),
+                    ]);
+                }
             }
         }
 
@@ -333,13 +409,28 @@ impl QemuConfig {
         if !self.mount_names.is_empty() {
             extra_kernel_params.push(format!("mount_names={}", self.mount_names.join(",")));
         }
+        // Also for VMs of the warm pool, whose shares are mounted by the shim
+        if self.dax_window_mb.is_some() {
+            extra_kernel_params.push("virtiofs_dax=inode".to_string());
+        }
+    }
+
+    /// Root ports to hot-plug vhost-user-fs devices into: PCIe buses take no
+    /// hot-plugged devices on the root complex itself.
+    fn add_hotplug_args(&self, args: &mut Vec<OsString>) {
+        for port in 0..self.hotplug_ports {
+            args.extend([
+                "-device".into(),
+                format!("pcie-root-port,id=hp{port},chassis={}", port + 1).into(),
+            ]);
+        }
     }
 
     /// Control channel: virtio-serial device connected via a chardev socket.
     ///
-    /// On Unix: QEMU creates a Unix domain socket (server mode).
-    /// On Windows: QEMU connects to a host-side TCP listener (client mode).
-    /// The TCP address is read from the file at `control_socket_path`.
+    /// QEMU is the server: on Unix of a socket at `control_socket_path`, on
+    /// Windows of the named pipe [`control_pipe_name`] gives (QEMU prefixes
+    /// `\\.\pipe\`).
     fn add_control_channel_args(&self, args: &mut Vec<OsString>) {
         #[cfg(not(target_os = "windows"))]
         {
@@ -355,23 +446,21 @@ impl QemuConfig {
 
         #[cfg(target_os = "windows")]
         {
-            // QEMU requires host and port as separate parameters:
-            //   socket,id=ctrl,host=IP,port=PORT,server=off
-            let addr = std::fs::read_to_string(&self.control_socket_path)
-                .unwrap_or_default();
-            let addr = addr.trim();
-            let (host, port) = addr.rsplit_once(':').unwrap_or((addr, "0"));
             args.extend([
                 "-chardev".into(),
-                format!("socket,id=ctrl,host={host},port={port},server=off").into(),
+                format!(
+                    "pipe,id=ctrl,path={}",
+                    control_pipe_name(&self.control_socket_path)
+                )
+                .into(),
             ]);
         }
 
-        // On Unix, the virtio-serial-pci bus is only needed for the control
-        // channel. On Windows it's added in add_common_args() since filesystem
-        // ports also use it.
-        #[cfg(not(target_os = "windows"))]
-        args.extend(["-device".into(), "virtio-serial-pci".into()]);
+        // Without 9P mounts the virtio-serial-pci bus is only needed for the
+        // control channel. Otherwise add_common_args() has added it already.
+        if !self.serial_bus_before_filesystems() {
+            args.extend(["-device".into(), "virtio-serial-pci".into()]);
+        }
 
         args.extend([
             "-device".into(),
@@ -418,6 +507,20 @@ impl QemuConfig {
         }
     }
 
+    /// QMP monitor socket and saved state to resume, when set.
+    fn add_state_args(&self, args: &mut Vec<OsString>) {
+        #[cfg(not(target_os = "windows"))]
+        if let Some(qmp_socket) = &self.qmp_socket_path {
+            args.extend([
+                "-qmp".into(),
+                format!("unix:{},server=on,wait=off", qmp_socket.display()).into(),
+            ]);
+        }
+        if let Some(state) = &self.incoming_state {
+            args.extend(["-incoming".into(), format!("file:{}", state.display()).into()]);
+        }
+    }
+
     /// User-provided extra arguments.
     fn add_extra_args(&self, args: &mut Vec<OsString>) {
         for arg in &self.extra_args {
@@ -489,10 +592,46 @@ fn common_qemu_paths(binary_name: &str) -> Vec<PathBuf> {
     paths
 }
 
+/// The last lines QEMU wrote to its serial console (stdout, with
+/// `-nographic`) and stderr, interleaved as they arrived.
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleTail(Arc<Mutex<VecDeque<String>>>);
+
+impl ConsoleTail {
+    fn push(&self, line: String) {
+        let mut lines = self.0.lock().unwrap();
+        if lines.len() == CONSOLE_TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// The kept lines, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Read `output` line by line into the tail on a thread of its own, so
+    /// QEMU never blocks on a full pipe. On Windows each line is logged too.
+    fn drain(&self, output: impl Read + Send + 'static, label: &'static str) {
+        let tail = self.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(output).lines() {
+                let Ok(line) = line else { break };
+                if cfg!(target_os = "windows") {
+                    codeagent_stdio::log_info!("qemu", "{label}: {line}");
+                }
+                tail.push(line);
+            }
+        });
+    }
+}
+
 /// Handle to a running QEMU process.
 pub struct QemuProcess {
     config: QemuConfig,
     child: Child,
+    console: ConsoleTail,
     /// On Windows, a job object that kills QEMU when the sandbox exits.
     /// Kept alive for the lifetime of QemuProcess; closing it kills the job.
     #[cfg(target_os = "windows")]
@@ -569,24 +708,17 @@ impl QemuProcess {
     /// Builds the platform-specific command line, spawns the process,
     /// and waits for readiness. On Unix, waits for the control socket
     /// file to appear. On Windows, verifies QEMU hasn't exited early
-    /// (the orchestrator handles TCP connection acceptance separately).
+    /// (connecting to the control pipe is retried separately).
     pub fn spawn(config: QemuConfig) -> Result<Self, AgentError> {
         let (binary, args) = config.build_args()?;
 
-        // On Windows, pipe stdout to capture serial console output
-        // (-nographic maps the serial port to stdout). On other platforms
-        // the virtio console (hvc0) is used and serial output is not needed.
-        let stdout_mode = if cfg!(target_os = "windows") {
-            std::process::Stdio::piped()
-        } else {
-            std::process::Stdio::null()
-        };
-
+        // -nographic maps the serial port to stdout. Its output and
+        // QEMU's stderr are kept in the console tail for crash reports.
         let mut command = std::process::Command::new(&binary);
         command
             .args(&args)
             .stdin(std::process::Stdio::null())
-            .stdout(stdout_mode)
+            .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped());
 
         #[cfg(target_os = "windows")]
@@ -597,7 +729,7 @@ impl QemuProcess {
             command.creation_flags(CREATE_NO_WINDOW | BELOW_NORMAL_PRIORITY_CLASS);
         }
 
-        let child = command.spawn().map_err(|error| AgentError::QemuSpawnFailed {
+        let mut child = command.spawn().map_err(|error| AgentError::QemuSpawnFailed {
             reason: format!(
                 "failed to start {}: {error}",
                 binary.display()
@@ -608,25 +740,104 @@ impl QemuProcess {
         let _job = {
             let job = create_kill_on_close_job(&child);
             if job.is_some() {
-                eprintln!("[sandbox] QEMU PID {} assigned to kill-on-close job object", child.id());
+                codeagent_stdio::log_debug!(
+                    "qemu",
+                    "QEMU PID {} assigned to kill-on-close job object",
+                    child.id()
+                );
             } else {
-                eprintln!("[sandbox] WARNING: failed to create kill-on-close job object for QEMU PID {}", child.id());
+                codeagent_stdio::log_warn!(
+                    "qemu",
+                    "failed to create kill-on-close job object for QEMU PID {}",
+                    child.id()
+                );
             }
             job
         };
 
+        let console = ConsoleTail::default();
+        if let Some(stdout) = child.stdout.take() {
+            console.drain(stdout, "serial");
+        }
+        if let Some(stderr) = child.stderr.take() {
+            console.drain(stderr, "stderr");
+        }
+
         let mut process = Self {
             config,
             child,
+            console,
             #[cfg(target_os = "windows")]
             _job,
         };
 
         process.wait_for_ready()?;
+        if process.config.incoming_state.is_some() {
+            process
+                .qmp()
+                .and_then(|mut qmp| vm_state::wait_for_resume(&mut qmp))
+                .map_err(|error| AgentError::VmState {
+                    reason: format!("resuming saved state: {error}"),
+                })?;
+        }
 
         Ok(process)
     }
 
+    /// Pause the VM and save its state into `saved`, for the next launch
+    /// with the same configuration to resume. The VM stays paused.
+    pub fn save_state(&mut self, saved: &SavedVmState) -> Result<(), AgentError> {
+        self.qmp()
+            .and_then(|mut qmp| saved.save(&mut qmp, &self.config))
+            .map_err(|error| AgentError::VmState {
+                reason: format!("saving state: {error}"),
+            })
+    }
+
+    /// Hot-plug a vhost-user-fs device for each `(socket, mount name)` into
+    /// the VM's root ports, as `add_filesystem_args` would have at boot.
+    pub fn attach_shares(&mut self, shares: &[(PathBuf, String)]) -> Result<(), AgentError> {
+        if shares.len() > self.config.hotplug_ports as usize {
+            return Err(AgentError::VirtioFsFailed {
+                reason: format!(
+                    "{} shares do not fit the VM's {} hot-plug ports",
+                    shares.len(),
+                    self.config.hotplug_ports
+                ),
+            });
+        }
+        let mut qmp = self.qmp().map_err(|error| AgentError::VirtioFsFailed {
+            reason: format!("hot-plugging shares: {error}"),
+        })?;
+        let window_mb = self.config.dax_window_mb;
+        for (index, (socket, mount_name)) in shares.iter().enumerate() {
+            hotplug_share(&mut qmp, index, socket, mount_name, window_mb).map_err(|error| {
+                AgentError::VirtioFsFailed {
+                    reason: format!("hot-plugging {mount_name}: {error}"),
+                }
+            })?;
+            self.config.fs_socket_paths.push(socket.clone());
+            self.config.mount_names.push(mount_name.clone());
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn qmp(&self) -> std::io::Result<QmpClient<std::os::unix::net::UnixStream>> {
+        let Some(path) = &self.config.qmp_socket_path else {
+            return Err(std::io::Error::other("QMP is not enabled for this VM"));
+        };
+        crate::qmp::connect(path)
+    }
+
+    #[cfg(not(unix))]
+    fn qmp(&self) -> std::io::Result<QmpClient<std::fs::File>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "QMP needs a Unix socket host",
+        ))
+    }
+
     /// Stop the QEMU VM.
     ///
     /// Kills the child process and waits for it to exit.
@@ -651,6 +862,9 @@ impl QemuProcess {
 
         // Clean up socket files
         let _ = std::fs::remove_file(&self.config.control_socket_path);
+        if let Some(qmp_socket) = &self.config.qmp_socket_path {
+            let _ = std::fs::remove_file(qmp_socket);
+        }
         for socket in &self.config.fs_socket_paths {
             let _ = std::fs::remove_file(socket);
         }
@@ -658,6 +872,26 @@ impl QemuProcess {
         Ok(())
     }
 
+    /// Whether the QEMU process has not exited yet.
+    pub fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// How the QEMU process exited, once it has.
+    pub fn try_exit(&mut self) -> Option<ExitStatus> {
+        self.child.try_wait().ok().flatten()
+    }
+
+    /// The last lines of QEMU's console and stderr output.
+    pub fn console_tail(&self) -> Vec<String> {
+        self.console.lines()
+    }
+
+    /// Memory given to the guest.
+    pub fn memory_bytes(&self) -> u64 {
+        u64::from(self.config.memory_mb) << 20
+    }
+
     /// Returns the process ID of the QEMU process.
     pub fn pid(&self) -> Option<u32> {
         Some(self.child.id())
@@ -668,11 +902,10 @@ impl QemuProcess {
     /// On Unix: waits for the control socket file to appear (QEMU creates it
     /// in server mode).
     ///
-    /// On Windows: the control socket address file is written by the
-    /// orchestrator BEFORE QEMU starts, so file existence is meaningless.
+    /// On Windows: the control pipe leaves nothing to poll for on disk.
     /// Instead, we briefly verify QEMU hasn't crashed on startup (e.g.,
-    /// due to invalid arguments). The actual connection is handled by the
-    /// orchestrator's TCP accept loop with its own timeout.
+    /// due to invalid arguments). Connecting to the pipe is retried by
+    /// `connect_control_channel` with its own timeout.
     fn wait_for_ready(&mut self) -> Result<(), AgentError> {
         #[cfg(not(target_os = "windows"))]
         {
@@ -694,33 +927,6 @@ impl QemuProcess {
 
         #[cfg(target_os = "windows")]
         {
-            // Spawn threads to drain QEMU's stdout (serial console) and
-            // stderr (QEMU diagnostics) for debugging.
-            if let Some(stdout) = self.child.stdout.take() {
-                std::thread::spawn(move || {
-                    use std::io::{BufRead, BufReader};
-                    let reader = BufReader::new(stdout);
-                    for line in reader.lines() {
-                        match line {
-                            Ok(line) => eprintln!("[qemu serial] {line}"),
-                            Err(_) => break,
-                        }
-                    }
-                });
-            }
-            if let Some(stderr) = self.child.stderr.take() {
-                std::thread::spawn(move || {
-                    use std::io::{BufRead, BufReader};
-                    let reader = BufReader::new(stderr);
-                    for line in reader.lines() {
-                        match line {
-                            Ok(line) => eprintln!("[qemu stderr] {line}"),
-                            Err(_) => break,
-                        }
-                    }
-                });
-            }
-
             // Give QEMU a moment to start, then check if it crashed immediately
             // (e.g., invalid arguments, missing files, accelerator failure).
             std::thread::sleep(Duration::from_millis(500));
@@ -742,6 +948,43 @@ impl QemuProcess {
     }
 }
 
+/// Add the chardev and vhost-user-fs device of share `index` over `qmp`,
+/// with a DAX window of `window_mb` if given.
+fn hotplug_share<S: Read + std::io::Write>(
+    qmp: &mut QmpClient<S>,
+    index: usize,
+    socket: &Path,
+    mount_name: &str,
+    window_mb: Option<u32>,
+) -> std::io::Result<()> {
+    let chardev = format!("vfs{index}");
+    qmp.execute(
+        "chardev-add",
+        Some(serde_json::json!({
+            "id": chardev,
+            "backend": {
+                "type": "socket",
+                "data": {
+                    "addr": { "type": "unix", "data": { "path": socket } },
+                    "server": false,
+                },
+            },
+        })),
+    )?;
+    let mut device = serde_json::json!({
+        "driver": "vhost-user-fs-pci",
+        "id": format!("fs{index}"),
+        "bus": format!("hp{index}"),
+        "chardev": chardev,
+        "tag": mount_name,
+    });
+    if let Some(window_mb) = window_mb {
+        device["cache-size"] = format!("{window_mb}M").into();
+    }
+    qmp.execute("device_add", Some(device))?;
+    Ok(())
+}
+
 impl Drop for QemuProcess {
     fn drop(&mut self) {
         let _ = self.stop();
@@ -765,8 +1008,13 @@ mod tests {
             working_dirs,
             control_socket_path: PathBuf::from("/tmp/control.sock"),
             fs_socket_paths: vec![PathBuf::from("/tmp/vfs0.sock")],
+            fs_transports: vec![],
             vm_mode: "ephemeral".to_string(),
             mount_names,
+            qmp_socket_path: None,
+            incoming_state: None,
+            hotplug_ports: 0,
+            dax_window_mb: None,
             extra_args: vec![],
         }
     }
@@ -894,6 +1142,41 @@ mod tests {
         }
     }
 
+    /// QC-12: transports can be mixed per working dir; 9P ports come after
+    /// the virtio-serial bus, which is added once.
+    #[test]
+    fn qc_12_mixed_filesystem_transports() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let p9_addr = dir.path().join("p9fs1.addr");
+        std::fs::write(&p9_addr, "127.0.0.1:5640").unwrap();
+
+        let mut config = test_config();
+        config.working_dirs = vec![PathBuf::from("/tmp/work0"), PathBuf::from("/tmp/work1")];
+        config.mount_names = generate_mount_names(&config.working_dirs);
+        config.fs_socket_paths = vec![PathBuf::from("/tmp/vfs0.sock"), p9_addr];
+        config.fs_transports = vec![FsTransport::VhostUser, FsTransport::P9Serial];
+
+        let (_binary, args) = config.build_args().unwrap();
+        let args = args_to_strings(&args);
+
+        assert!(args.contains(&"vhost-user-fs-pci,chardev=vfs0,tag=work0".to_string()));
+        assert!(args.contains(
+            &"socket,id=p9fs1,host=127.0.0.1,port=5640,server=off".to_string()
+        ));
+        let port = args
+            .iter()
+            .position(|a| a == "virtserialport,chardev=p9fs1,name=work1")
+            .expect("9P port device");
+        let buses: Vec<usize> = args
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| *a == "virtio-serial-pci")
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(buses.len(), 1, "{args:?}");
+        assert!(buses[0] < port, "{args:?}");
+    }
+
     /// QC-05: build_args includes correct memory and CPU settings.
     #[test]
     fn qc_05_memory_and_cpus() {
@@ -973,6 +1256,93 @@ mod tests {
         );
     }
 
+    /// QC-13: a QMP socket and a saved state to resume add `-qmp` and
+    /// `-incoming`; neither is there by default.
+    #[test]
+    fn qc_13_state_args() {
+        let config = test_config();
+        let (_binary, args) = config.build_args().unwrap();
+        let args = args_to_strings(&args);
+        assert!(!args.contains(&"-qmp".to_string()));
+        assert!(!args.contains(&"-incoming".to_string()));
+
+        let mut config = test_config();
+        config.qmp_socket_path = Some(PathBuf::from("/tmp/sockets/qmp.sock"));
+        config.incoming_state = Some(PathBuf::from("/tmp/undo/.vm-state/state"));
+        let (_binary, args) = config.build_args().unwrap();
+        let args = args_to_strings(&args);
+
+        let incoming = args.iter().position(|a| a == "-incoming").unwrap();
+        assert_eq!(args[incoming + 1], "file:/tmp/undo/.vm-state/state");
+        #[cfg(not(target_os = "windows"))]
+        {
+            let qmp = args.iter().position(|a| a == "-qmp").unwrap();
+            assert_eq!(args[qmp + 1], "unix:/tmp/sockets/qmp.sock,server=on,wait=off");
+        }
+    }
+
+    /// QC-14: hot-plug ports are root ports with distinct chassis numbers,
+    /// and shares are plugged into them one port each.
+    #[test]
+    fn qc_14_hotplug_ports() {
+        let mut config = test_config();
+        config.hotplug_ports = 2;
+        let (_binary, args) = config.build_args().unwrap();
+        let args = args_to_strings(&args);
+        assert!(args.contains(&"pcie-root-port,id=hp0,chassis=1".to_string()));
+        assert!(args.contains(&"pcie-root-port,id=hp1,chassis=2".to_string()));
+
+        #[cfg(unix)]
+        {
+            use crate::qmp::testing::fake_qemu;
+            let (stream, qemu) = fake_qemu(vec![
+                serde_json::json!({ "return": {} }),
+                serde_json::json!({ "return": {} }),
+            ]);
+            let mut qmp = QmpClient::handshake(stream).unwrap();
+            hotplug_share(&mut qmp, 1, Path::new("/tmp/vfs1.sock"), "app", None).unwrap();
+            drop(qmp);
+            assert_eq!(qemu.join().unwrap(), ["qmp_capabilities", "chardev-add", "device_add"]);
+        }
+    }
+
+    /// QC-15: control pipe names are stable per socket path, differ between
+    /// paths and are valid pipe names.
+    #[test]
+    fn qc_15_control_pipe_name() {
+        let first = Path::new(r"C:\Users\me\undo\.sockets\control.sock");
+        let second = Path::new(r"C:\Users\me\other\.sockets\control.sock");
+        let name = control_pipe_name(first);
+        assert_eq!(name, control_pipe_name(first));
+        assert_ne!(name, control_pipe_name(second));
+        assert!(name.starts_with("codeagent-") && name.ends_with("-control"));
+        assert!(!name.contains(['\\', '/', ',']));
+    }
+
+    /// QC-16: a DAX window sizes each vhost-user-fs device's cache and asks
+    /// the guest to mount with `dax=inode`; without one neither appears.
+    #[test]
+    fn qc_16_dax_window() {
+        let mut config = test_config();
+        config.working_dirs = vec![PathBuf::from("/tmp/work0")];
+        config.mount_names = generate_mount_names(&config.working_dirs);
+        config.fs_socket_paths = vec![PathBuf::from("/tmp/vfs0.sock")];
+        config.fs_transports = vec![FsTransport::VhostUser];
+        let (_binary, args) = config.build_args().unwrap();
+        let args = args_to_strings(&args);
+        assert!(args.contains(&"vhost-user-fs-pci,chardev=vfs0,tag=work0".to_string()));
+        assert!(!args.iter().any(|arg| arg.contains("virtiofs_dax")));
+
+        config.dax_window_mb = Some(256);
+        let (_binary, args) = config.build_args().unwrap();
+        let args = args_to_strings(&args);
+        assert!(args.contains(
+            &"vhost-user-fs-pci,chardev=vfs0,tag=work0,cache-size=256M".to_string()
+        ));
+        let append_idx = args.iter().position(|a| a == "-append").unwrap();
+        assert!(args[append_idx + 1].split(' ').any(|param| param == "virtiofs_dax=inode"));
+    }
+
     /// QC-08: extra_args are appended to the command line.
     #[test]
     fn qc_08_extra_args() {
@@ -987,7 +1357,7 @@ mod tests {
         assert!(args.contains(&"-S".to_string()));
     }
 
-    /// QC-11: Windows control chardev uses separate host/port; filesystem
+    /// QC-11: Windows control chardev is the VM's named pipe; filesystem
     /// uses virtio-serial chardev + virtserialport connecting to the P9Backend.
     /// Port names use the generated mount name (not index-based p9fsN).
     #[cfg(target_os = "windows")]
@@ -995,31 +1365,24 @@ mod tests {
     fn qc_11_windows_chardev_transport() {
         let dir = tempfile::tempdir().unwrap();
 
-        // Write a TCP address file for the control channel
-        let ctrl_addr_path = dir.path().join("control.addr");
-        std::fs::write(&ctrl_addr_path, "127.0.0.1:54321").unwrap();
+        let ctrl_socket_path = dir.path().join("control.sock");
 
         // Write a TCP address file for the filesystem channel
         let fs_addr_path = dir.path().join("p9fs0.addr");
         std::fs::write(&fs_addr_path, "127.0.0.1:54322").unwrap();
 
         let mut config = test_config();
-        config.control_socket_path = ctrl_addr_path;
+        config.control_socket_path = ctrl_socket_path.clone();
         config.fs_socket_paths = vec![fs_addr_path];
         // mount_names already set by test_config() from working_dirs
 
         let (_binary, args) = config.build_args().unwrap();
         let args = args_to_strings(&args);
 
-        // Control channel chardev should have host=...,port=... separately
         let ctrl_chardev = args.iter().find(|a| a.contains("id=ctrl")).unwrap();
-        assert!(
-            ctrl_chardev.contains("host=127.0.0.1,port=54321"),
-            "expected separate host and port in control chardev: {ctrl_chardev}"
-        );
-        assert!(
-            !ctrl_chardev.contains("host=127.0.0.1:54321"),
-            "should not use colon-separated host:port format: {ctrl_chardev}"
+        assert_eq!(
+            ctrl_chardev,
+            &format!("pipe,id=ctrl,path={}", control_pipe_name(&ctrl_socket_path))
         );
 
         // Filesystem chardev should connect to the P9Backend TCP address
@@ -1150,4 +1513,24 @@ mod tests {
             "expected mount_names=alpha,beta in append: {append_val}"
         );
     }
+
+    #[test]
+    fn console_tail_keeps_the_last_lines() {
+        let tail = ConsoleTail::default();
+        for n in 0..CONSOLE_TAIL_LINES + 10 {
+            tail.push(format!("line {n}"));
+        }
+        let lines = tail.lines();
+        assert_eq!(lines.len(), CONSOLE_TAIL_LINES);
+        assert_eq!(lines[0], "line 10");
+
+        let drained = ConsoleTail::default();
+        let console = b"[    0.000000] Linux version\nKernel panic\n".to_vec();
+        drained.drain(std::io::Cursor::new(console), "serial");
+        let start = std::time::Instant::now();
+        while drained.lines().len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(drained.lines(), vec!["[    0.000000] Linux version", "Kernel panic"]);
+    }
 }
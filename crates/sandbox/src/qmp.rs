@@ -0,0 +1,120 @@
+//! A minimal QMP client.
+//!
+//! QMP is QEMU's JSON monitor protocol: one JSON object per line, a greeting
+//! from QEMU, then commands answered by `return` or `error`, with
+//! asynchronous events mixed in. The sandbox uses it to save and resume VM
+//! state and to hot-plug shares into pre-booted VMs.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::time::Duration;
+
+use serde_json::{Value, json};
+
+/// How long to wait for QEMU to answer a command.
+#[cfg(unix)]
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A QMP connection, past capabilities negotiation.
+pub struct QmpClient<S: Read + Write> {
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> QmpClient<S> {
+    /// Read QEMU's greeting and leave capabilities negotiation mode.
+    pub fn handshake(stream: S) -> io::Result<Self> {
+        let mut client = Self {
+            stream: BufReader::new(stream),
+        };
+        if client.read_message()?.get("QMP").is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a QMP greeting",
+            ));
+        }
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    /// Run `command` and return what it returned. Events that arrive
+    /// before the reply are skipped.
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> io::Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut line = request.to_string();
+        line.push('\n');
+        self.stream.get_mut().write_all(line.as_bytes())?;
+        loop {
+            let reply = self.read_message()?;
+            if let Some(value) = reply.get("return") {
+                return Ok(value.clone());
+            }
+            if let Some(error) = reply.get("error") {
+                let description = error["desc"].as_str().unwrap_or("no description");
+                return Err(io::Error::other(format!("{command}: {description}")));
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> io::Result<Value> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "QMP connection closed",
+            ));
+        }
+        serde_json::from_str(&line)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+/// Connect to the QMP socket at `path`.
+#[cfg(unix)]
+pub fn connect(path: &Path) -> io::Result<QmpClient<std::os::unix::net::UnixStream>> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    QmpClient::handshake(stream)
+}
+
+#[cfg(all(test, unix))]
+pub(crate) mod testing {
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread::JoinHandle;
+
+    use serde_json::{Value, json};
+
+    /// A QMP server answering each command with the next of `replies`, and
+    /// writing a `file:` migration target it is given. Returns the commands
+    /// it received.
+    pub fn fake_qemu(replies: Vec<Value>) -> (UnixStream, JoinHandle<Vec<String>>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut writer = server.try_clone().unwrap();
+            let mut reader = BufReader::new(server);
+            writeln!(writer, r#"{{"QMP": {{"version": {{}}, "capabilities": []}}}}"#).unwrap();
+            let mut commands = Vec::new();
+            for reply in std::iter::once(json!({ "return": {} })).chain(replies) {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let request: Value = serde_json::from_str(&line).unwrap();
+                commands.push(request["execute"].as_str().unwrap().to_string());
+                if let Some(target) = request["arguments"]["uri"].as_str() {
+                    fs::write(target.trim_start_matches("file:"), "ram").unwrap();
+                }
+                writeln!(writer, r#"{{"event": "STOP"}}"#).unwrap();
+                writeln!(writer, "{reply}").unwrap();
+            }
+            commands
+        });
+        (client, handle)
+    }
+}
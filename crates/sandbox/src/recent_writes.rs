@@ -194,6 +194,16 @@ impl WriteInterceptor for WriteTrackingInterceptor {
         self.inner.pre_write(path)
     }
 
+    fn pre_write_range(&self, path: &Path, offset: u64, len: u64) -> Result<()> {
+        self.recent_writes.record(path);
+        self.inner.pre_write_range(path, offset, len)
+    }
+
+    fn pre_append(&self, path: &Path) -> Result<()> {
+        self.recent_writes.record(path);
+        self.inner.pre_append(path)
+    }
+
     fn pre_unlink(&self, path: &Path, is_dir: bool) -> Result<()> {
         self.recent_writes.record(path);
         self.inner.pre_unlink(path, is_dir)
@@ -205,6 +215,10 @@ impl WriteInterceptor for WriteTrackingInterceptor {
         self.inner.pre_rename(from, to)
     }
 
+    fn post_rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.post_rename(from, to)
+    }
+
     fn post_create(&self, path: &Path) -> Result<()> {
         self.recent_writes.record(path);
         self.inner.post_create(path)
@@ -220,6 +234,11 @@ impl WriteInterceptor for WriteTrackingInterceptor {
         self.inner.pre_setattr(path)
     }
 
+    fn pre_setattr_metadata(&self, path: &Path) -> Result<()> {
+        self.recent_writes.record(path);
+        self.inner.pre_setattr_metadata(path)
+    }
+
     fn pre_link(&self, target: &Path, link_path: &Path) -> Result<()> {
         self.recent_writes.record(link_path);
         self.inner.pre_link(target, link_path)
@@ -253,6 +272,10 @@ impl WriteInterceptor for WriteTrackingInterceptor {
     fn current_step(&self) -> Option<StepId> {
         self.inner.current_step()
     }
+
+    fn is_case_insensitive(&self) -> bool {
+        self.inner.is_case_insensitive()
+    }
 }
 
 #[cfg(test)]
@@ -1,11 +1,34 @@
-use codeagent_common::{SafeguardDecision, SafeguardEvent};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use codeagent_common::{SafeguardDecision, SafeguardEvent, StepId, StepManager};
+use codeagent_control::{Clock, ControlChannelHandler, HostMessage, LaneSender, TokioClock};
 use codeagent_interceptor::safeguard::SafeguardHandler;
+use codeagent_mcp::JsonRpcNotification;
+use codeagent_stdio::{log_warn, Event, EventHub};
+use serde::Serialize;
 use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+
+use crate::safeguard_log::{self, DecidedBy, SafeguardRecord};
+
+/// How long a safeguard waits for `safeguard.confirm` before it is denied,
+/// unless `safeguard.configure` sets `timeout_seconds`.
+pub const DEFAULT_SAFEGUARD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A decision on a safeguard and who made it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    pub decision: SafeguardDecision,
+    pub decided_by: DecidedBy,
+}
 
 /// A pending safeguard event awaiting a user decision.
 pub struct PendingSafeguard {
     pub event: SafeguardEvent,
-    pub responder: oneshot::Sender<SafeguardDecision>,
+    pub responder: oneshot::Sender<Verdict>,
 }
 
 /// Bridges the synchronous `SafeguardHandler` trait (called on the filesystem
@@ -18,31 +41,538 @@ pub struct PendingSafeguard {
 /// The orchestrator emits an `Event::SafeguardTriggered` to the STDIO/MCP
 /// client, stores the response channel, and sends the decision when
 /// `safeguard.confirm` arrives.
+///
+/// Each decision is then appended to the audit log in the interceptor's
+/// undo directory.
 pub struct SafeguardBridge {
     sender: mpsc::UnboundedSender<PendingSafeguard>,
+    undo_dir: PathBuf,
 }
 
 impl SafeguardBridge {
-    pub fn new(sender: mpsc::UnboundedSender<PendingSafeguard>) -> Self {
-        Self { sender }
+    pub fn new(sender: mpsc::UnboundedSender<PendingSafeguard>, undo_dir: PathBuf) -> Self {
+        Self { sender, undo_dir }
+    }
+}
+
+/// A safeguard waiting for a decision, as MCP `list_pending_safeguards`
+/// reports it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PendingSafeguardInfo {
+    pub safeguard_id: String,
+    pub step_id: StepId,
+    pub kind: String,
+    pub sample_paths: Vec<String>,
+    pub message: String,
+    pub triggered_at: String,
+}
+
+impl PendingSafeguardInfo {
+    fn new(event: &SafeguardEvent) -> Self {
+        Self {
+            safeguard_id: event.safeguard_id.to_string(),
+            step_id: event.step_id,
+            kind: format!("{:?}", event.kind),
+            sample_paths: event.sample_paths.clone(),
+            message: format!("Safeguard triggered: {:?} (step {})", event.kind, event.step_id),
+            triggered_at: codeagent_common::time::now_timestamp(),
+        }
+    }
+}
+
+/// Responders of the safeguards of one session that are waiting for a
+/// decision, keyed by safeguard ID, and how long they may wait.
+pub struct PendingSafeguards {
+    responders: Mutex<HashMap<String, (PendingSafeguardInfo, oneshot::Sender<Verdict>)>>,
+    timeout: Mutex<Duration>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for PendingSafeguards {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(TokioClock))
+    }
+}
+
+impl PendingSafeguards {
+    /// Safeguards whose timeouts are measured by `clock`.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            responders: Mutex::new(HashMap::new()),
+            timeout: Mutex::new(DEFAULT_SAFEGUARD_TIMEOUT),
+            clock,
+        }
+    }
+
+    pub fn insert(&self, info: PendingSafeguardInfo, responder: oneshot::Sender<Verdict>) {
+        self.responders
+            .lock()
+            .unwrap()
+            .insert(info.safeguard_id.clone(), (info, responder));
+    }
+
+    /// Remove a pending safeguard so exactly one decision reaches it.
+    pub fn take(&self, safeguard_id: &str) -> Option<oneshot::Sender<Verdict>> {
+        let (_, responder) = self.responders.lock().unwrap().remove(safeguard_id)?;
+        Some(responder)
+    }
+
+    /// The safeguards still waiting, oldest first.
+    pub fn list(&self) -> Vec<PendingSafeguardInfo> {
+        let mut pending: Vec<_> = self
+            .responders
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(info, _)| info.clone())
+            .collect();
+        pending.sort_by_key(|info| info.safeguard_id.parse::<u64>().ok());
+        pending
+    }
+
+    /// Applies to safeguards triggered from now on.
+    pub fn set_timeout(&self, timeout: Duration) {
+        *self.timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn timeout(&self) -> Duration {
+        *self.timeout.lock().unwrap()
+    }
+}
+
+/// Cancels the guest command of a step whose step-limit safeguard was
+/// denied, so the command stops instead of failing on every later write.
+pub struct CommandCanceller {
+    pub control_writer: LaneSender<HostMessage>,
+    pub control_handler: Arc<ControlChannelHandler<dyn StepManager>>,
+}
+
+impl CommandCanceller {
+    /// Cancel the command running as `step_id`. Ambient steps have no
+    /// command and are left alone.
+    async fn cancel(&self, step_id: StepId) {
+        if step_id <= 0 {
+            return;
+        }
+        let id = step_id as u64;
+        self.control_handler.cancel(id).await;
+        let _ = self.control_writer.send(HostMessage::Cancel { id });
     }
 }
 
+/// Consume safeguards raised by [`SafeguardBridge`]: emit
+/// `event.safeguard_triggered` and park each responder in `pending` for
+/// `safeguard.confirm`. A safeguard still pending when its timeout elapses
+/// is denied, which unblocks the filesystem thread, and reported with
+/// `event.safeguard_timed_out`. Denying a step duration or operation count
+/// safeguard, by either path, also cancels the step's command through
+/// `canceller`.
+///
+/// Timers run inside this task, so aborting it cancels them.
+pub async fn forward_pending(
+    mut receiver: mpsc::UnboundedReceiver<PendingSafeguard>,
+    pending: Arc<PendingSafeguards>,
+    event_sender: mpsc::UnboundedSender<Event>,
+    canceller: Option<Arc<CommandCanceller>>,
+) {
+    let mut timers = JoinSet::new();
+    loop {
+        tokio::select! {
+            next = receiver.recv() => {
+                let Some(safeguard) = next else { break };
+                let info = PendingSafeguardInfo::new(&safeguard.event);
+                let triggered = Event::SafeguardTriggered {
+                    step_id: info.step_id,
+                    safeguard_id: info.safeguard_id.clone(),
+                    kind: info.kind.clone(),
+                    sample_paths: info.sample_paths.clone(),
+                    message: info.message.clone(),
+                };
+                let step_id = info.step_id;
+                let safeguard_id = info.safeguard_id.clone();
+                let timeout = pending.timeout();
+                let responder = match &canceller {
+                    Some(canceller) if safeguard.event.kind.limits_step() => {
+                        let (decided, verdict) = oneshot::channel::<Verdict>();
+                        let canceller = Arc::clone(canceller);
+                        let responder = safeguard.responder;
+                        timers.spawn(async move {
+                            let Ok(verdict) = verdict.await else { return };
+                            let denied = verdict.decision == SafeguardDecision::Deny;
+                            let _ = responder.send(verdict);
+                            if denied {
+                                canceller.cancel(step_id).await;
+                            }
+                        });
+                        decided
+                    }
+                    _ => safeguard.responder,
+                };
+                // Pending before it is announced, so a client answering the
+                // event straight away finds it.
+                pending.insert(info, responder);
+                let _ = event_sender.send(triggered);
+
+                let expired = pending.clock.sleep(timeout);
+                let pending = Arc::clone(&pending);
+                let event_sender = event_sender.clone();
+                timers.spawn(async move {
+                    expired.await;
+                    if let Some(responder) = pending.take(&safeguard_id) {
+                        let _ = responder.send(Verdict {
+                            decision: SafeguardDecision::Deny,
+                            decided_by: DecidedBy::Timeout,
+                        });
+                        let _ = event_sender.send(Event::SafeguardTimedOut {
+                            step_id,
+                            safeguard_id,
+                            timeout_seconds: timeout.as_secs(),
+                        });
+                    }
+                });
+            }
+            Some(_) = timers.join_next(), if !timers.is_empty() => {}
+        }
+    }
+}
+
+/// The MCP notification telling a client that a safeguard is waiting for
+/// `confirm_safeguard`, for `event.safeguard_triggered`; `None` for other
+/// events. Sent as a `notifications/message` log entry, which MCP clients
+/// show to the user. `hub` stamps it with the `seq`, `emitted_at` and
+/// `origin` a STDIO event carries, in its `data`.
+pub fn mcp_notification(event: &Event, hub: &mut EventHub) -> Option<JsonRpcNotification> {
+    let Event::SafeguardTriggered {
+        step_id,
+        safeguard_id,
+        kind,
+        sample_paths,
+        message,
+    } = event
+    else {
+        return None;
+    };
+    let mut envelope = event.to_envelope();
+    hub.stamp(&mut envelope);
+    Some(JsonRpcNotification {
+        jsonrpc: "2.0".to_string(),
+        method: "notifications/message".to_string(),
+        params: Some(serde_json::json!({
+            "level": "warning",
+            "logger": "safeguard",
+            "data": {
+                "message": format!(
+                    "{message}. Paused until confirm_safeguard is called with safeguard_id \
+                     {safeguard_id}."
+                ),
+                "safeguard_id": safeguard_id,
+                "step_id": step_id,
+                "kind": kind,
+                "sample_paths": sample_paths,
+                "seq": envelope.seq,
+                "emitted_at": envelope.emitted_at,
+                "origin": envelope.origin,
+            },
+        })),
+    })
+}
+
 impl SafeguardHandler for SafeguardBridge {
     fn on_safeguard_triggered(&self, event: SafeguardEvent) -> SafeguardDecision {
+        let triggered_at = codeagent_common::time::now_timestamp();
+        let unanswered = Verdict {
+            decision: SafeguardDecision::Deny,
+            decided_by: DecidedBy::Sandbox,
+        };
         let (responder, receiver) = oneshot::channel();
-        let pending = PendingSafeguard { event, responder };
-
-        if self.sender.send(pending).is_err() {
-            return SafeguardDecision::Deny;
-        }
+        let pending = PendingSafeguard {
+            event: event.clone(),
+            responder,
+        };
 
         // Block this thread until the orchestrator sends a decision.
         // This is intentional — the filesystem backend thread must wait
         // for user confirmation before proceeding.
-        match receiver.blocking_recv() {
-            Ok(decision) => decision,
-            Err(_) => SafeguardDecision::Deny,
+        let verdict = if self.sender.send(pending).is_err() {
+            unanswered
+        } else {
+            receiver.blocking_recv().unwrap_or(unanswered)
+        };
+
+        let record =
+            SafeguardRecord::new(&event, triggered_at, verdict.decision, verdict.decided_by);
+        if let Err(error) = safeguard_log::append(&self.undo_dir, &record) {
+            log_warn!("safeguard", "failed to append to safeguard log: {error}");
+        }
+        verdict.decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codeagent_common::{PathOperation, SafeguardKind};
+
+    #[test]
+    fn event_is_forwarded_and_decision_returned() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let undo_dir = tempfile::tempdir().unwrap();
+        let bridge = SafeguardBridge::new(sender, undo_dir.path().to_path_buf());
+        let kind = SafeguardKind::ProtectedPath {
+            path: ".git/HEAD".to_string(),
+            pattern: ".git/**".to_string(),
+            operation: PathOperation::Write,
+        };
+        let event = SafeguardEvent {
+            safeguard_id: 1,
+            step_id: 4,
+            kind: kind.clone(),
+            sample_paths: vec![".git/HEAD".to_string()],
+        };
+
+        let filesystem_thread =
+            std::thread::spawn(move || bridge.on_safeguard_triggered(event));
+        let pending = receiver.blocking_recv().unwrap();
+        assert_eq!(pending.event.kind, kind);
+        pending
+            .responder
+            .send(Verdict {
+                decision: SafeguardDecision::AllowForStep,
+                decided_by: DecidedBy::User,
+            })
+            .unwrap();
+
+        assert_eq!(filesystem_thread.join().unwrap(), SafeguardDecision::AllowForStep);
+        let records = safeguard_log::read(undo_dir.path()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].decision, "allow_step");
+        assert_eq!(records[0].decided_by, DecidedBy::User);
+        assert_eq!(records[0].paths, vec![".git/HEAD".to_string()]);
+    }
+
+    fn delete_event(safeguard_id: u64) -> SafeguardEvent {
+        SafeguardEvent {
+            safeguard_id,
+            step_id: 2,
+            kind: SafeguardKind::DeleteThreshold {
+                count: 10,
+                threshold: 10,
+            },
+            sample_paths: Vec::new(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_safeguard_is_denied_after_timeout() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let pending = Arc::new(PendingSafeguards::default());
+        pending.set_timeout(Duration::from_secs(30));
+        let consumer = tokio::spawn(forward_pending(
+            receiver,
+            Arc::clone(&pending),
+            event_sender,
+            None,
+        ));
+
+        let (responder, decision) = oneshot::channel();
+        sender.send(PendingSafeguard { event: delete_event(1), responder }).unwrap();
+        assert!(matches!(events.recv().await, Some(Event::SafeguardTriggered { .. })));
+
+        tokio::time::sleep(Duration::from_secs(29)).await;
+        assert!(pending.responders.lock().unwrap().contains_key("1"));
+
+        let verdict = decision.await.unwrap();
+        assert_eq!(
+            (verdict.decision, verdict.decided_by),
+            (SafeguardDecision::Deny, DecidedBy::Timeout)
+        );
+        match events.recv().await {
+            Some(Event::SafeguardTimedOut {
+                step_id,
+                safeguard_id,
+                timeout_seconds,
+            }) => assert_eq!((step_id, safeguard_id.as_str(), timeout_seconds), (2, "1", 30)),
+            other => panic!("expected SafeguardTimedOut, got {other:?}"),
+        }
+        assert!(pending.take("1").is_none());
+        consumer.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answered_safeguard_does_not_time_out() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let pending = Arc::new(PendingSafeguards::default());
+        let consumer = tokio::spawn(forward_pending(
+            receiver,
+            Arc::clone(&pending),
+            event_sender,
+            None,
+        ));
+
+        let (responder, decision) = oneshot::channel();
+        sender.send(PendingSafeguard { event: delete_event(7), responder }).unwrap();
+        events.recv().await.unwrap();
+        let verdict = Verdict {
+            decision: SafeguardDecision::AllowOnce,
+            decided_by: DecidedBy::User,
+        };
+        pending.take("7").unwrap().send(verdict).unwrap();
+        assert_eq!(decision.await.unwrap(), verdict);
+
+        tokio::time::sleep(DEFAULT_SAFEGUARD_TIMEOUT * 2).await;
+        assert!(events.try_recv().is_err());
+        consumer.abort();
+    }
+
+    #[tokio::test]
+    async fn pending_safeguards_are_listed_until_decided() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let pending = Arc::new(PendingSafeguards::default());
+        let consumer = tokio::spawn(forward_pending(
+            receiver,
+            Arc::clone(&pending),
+            event_sender,
+            None,
+        ));
+
+        let mut decisions = Vec::new();
+        for safeguard_id in [10, 9] {
+            let (responder, decision) = oneshot::channel();
+            sender.send(PendingSafeguard { event: delete_event(safeguard_id), responder }).unwrap();
+            events.recv().await.unwrap();
+            decisions.push(decision);
+        }
+        let listed = pending.list();
+        let ids: Vec<_> = listed.iter().map(|info| info.safeguard_id.as_str()).collect();
+        assert_eq!(ids, ["9", "10"]);
+        assert_eq!(listed[0].step_id, 2);
+        assert!(listed[0].kind.starts_with("DeleteThreshold"));
+
+        drop(pending.take("9"));
+        assert_eq!(pending.list().len(), 1);
+        consumer.abort();
+    }
+
+    #[test]
+    fn only_triggered_safeguards_become_mcp_notifications() {
+        let triggered = Event::SafeguardTriggered {
+            step_id: 3,
+            safeguard_id: "5".to_string(),
+            kind: "DeleteThreshold".to_string(),
+            sample_paths: vec!["a.txt".to_string()],
+            message: "Safeguard triggered".to_string(),
+        };
+        let mut hub = EventHub::new();
+        let notification = mcp_notification(&triggered, &mut hub).unwrap();
+        assert_eq!(notification.method, "notifications/message");
+        let params = notification.params.unwrap();
+        assert_eq!(params["data"]["safeguard_id"], "5");
+        assert!(params["data"]["message"].as_str().unwrap().contains("confirm_safeguard"));
+        assert_eq!(params["data"]["seq"], 1);
+        assert_eq!(params["data"]["origin"], "safeguard");
+        assert!(params["data"]["emitted_at"].is_string());
+
+        let timed_out = Event::SafeguardTimedOut {
+            step_id: 3,
+            safeguard_id: "5".to_string(),
+            timeout_seconds: 300,
+        };
+        assert!(mcp_notification(&timed_out, &mut hub).is_none());
+        assert_eq!(hub.last_seq(), 1);
+    }
+
+    #[tokio::test]
+    async fn timeout_follows_the_injected_clock() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let clock = Arc::new(codeagent_control::ManualClock::new());
+        let pending = Arc::new(PendingSafeguards::with_clock(clock.clone()));
+        let consumer = tokio::spawn(forward_pending(
+            receiver,
+            Arc::clone(&pending),
+            event_sender,
+            None,
+        ));
+
+        let (responder, mut decision) = oneshot::channel();
+        sender.send(PendingSafeguard { event: delete_event(3), responder }).unwrap();
+        events.recv().await.unwrap();
+        clock.advance(DEFAULT_SAFEGUARD_TIMEOUT - Duration::from_secs(1));
+        tokio::task::yield_now().await;
+        assert!(decision.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(decision.await.unwrap().decided_by, DecidedBy::Timeout);
+        assert!(matches!(events.recv().await, Some(Event::SafeguardTimedOut { .. })));
+        consumer.abort();
+    }
+
+    #[tokio::test]
+    async fn denied_step_limit_cancels_the_command() {
+        use codeagent_control::{lane_channel, HandlerEvent, InFlightTracker, QuiescenceConfig};
+        use codeagent_interceptor::passthrough::PassthroughInterceptor;
+
+        let step_manager: Arc<dyn StepManager> = Arc::new(PassthroughInterceptor::new());
+        let (handler, mut handler_events) = ControlChannelHandler::new(
+            step_manager,
+            InFlightTracker::new(),
+            QuiescenceConfig::default(),
+        );
+        let control_handler = Arc::new(handler);
+        control_handler
+            .send_exec(5, "make".to_string(), None, None, false, false, None, Default::default())
+            .await;
+        let (control_writer, mut sent) = lane_channel();
+        let canceller = Arc::new(CommandCanceller {
+            control_writer,
+            control_handler,
+        });
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let pending = Arc::new(PendingSafeguards::default());
+        let consumer = tokio::spawn(forward_pending(
+            receiver,
+            Arc::clone(&pending),
+            event_sender,
+            Some(canceller),
+        ));
+        let deny = Verdict {
+            decision: SafeguardDecision::Deny,
+            decided_by: DecidedBy::User,
+        };
+
+        // Other safeguards leave the command running.
+        let (responder, decision) = oneshot::channel();
+        let event = SafeguardEvent { step_id: 5, ..delete_event(1) };
+        sender.send(PendingSafeguard { event, responder }).unwrap();
+        events.recv().await.unwrap();
+        pending.take("1").unwrap().send(deny).unwrap();
+        assert_eq!(decision.await.unwrap(), deny);
+        assert!(sent.try_recv().is_none());
+
+        let (responder, decision) = oneshot::channel();
+        let event = SafeguardEvent {
+            safeguard_id: 2,
+            step_id: 5,
+            kind: SafeguardKind::StepOperationCount { count: 11, threshold: 10 },
+            sample_paths: Vec::new(),
+        };
+        sender.send(PendingSafeguard { event, responder }).unwrap();
+        events.recv().await.unwrap();
+        pending.take("2").unwrap().send(deny).unwrap();
+        assert_eq!(decision.await.unwrap(), deny);
+
+        assert!(matches!(sent.recv().await, Some(HostMessage::Cancel { id: 5 })));
+        match handler_events.recv().await {
+            Some(HandlerEvent::StepCompleted { step_id, cancelled, .. }) => {
+                assert_eq!((step_id, cancelled), (5, true));
+            }
+            other => panic!("expected StepCompleted, got {other:?}"),
         }
+        consumer.abort();
     }
 }
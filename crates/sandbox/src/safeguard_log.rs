@@ -0,0 +1,134 @@
+//! Audit log of safeguard decisions for `safeguard.history`.
+//!
+//! Every trigger is appended to `{undo_dir}/safeguards.log` as one JSON line
+//! once it has been decided. The file is only ever appended to and lives
+//! outside `steps/`, so rollback and eviction leave it alone; `undo.discard`
+//! removes it with the rest of the undo directory.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use codeagent_common::{SafeguardDecision, SafeguardEvent, SafeguardId, StepId};
+
+pub const LOG_FILE: &str = "safeguards.log";
+
+/// Who made a safeguard decision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecidedBy {
+    /// Answered with `safeguard.confirm`.
+    User,
+    /// Answered with the MCP `confirm_safeguard` tool.
+    Mcp,
+    /// Denied because `safeguard.confirm` did not arrive in time.
+    Timeout,
+    /// Denied because no client could be asked.
+    Sandbox,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafeguardRecord {
+    pub triggered_at: String,
+    pub decided_at: String,
+    pub step_id: StepId,
+    pub safeguard_id: SafeguardId,
+    pub kind: String,
+    pub paths: Vec<String>,
+    /// `allow_once`, `allow_step`, `allow_session` or `deny`.
+    pub decision: String,
+    pub decided_by: DecidedBy,
+}
+
+impl SafeguardRecord {
+    pub fn new(
+        event: &SafeguardEvent,
+        triggered_at: String,
+        decision: SafeguardDecision,
+        decided_by: DecidedBy,
+    ) -> Self {
+        let decision = match decision {
+            SafeguardDecision::AllowOnce => "allow_once",
+            SafeguardDecision::AllowForStep => "allow_step",
+            SafeguardDecision::AllowForSession => "allow_session",
+            SafeguardDecision::Deny => "deny",
+        };
+        Self {
+            triggered_at,
+            decided_at: codeagent_common::time::now_timestamp(),
+            step_id: event.step_id,
+            safeguard_id: event.safeguard_id,
+            kind: format!("{:?}", event.kind),
+            paths: event.sample_paths.clone(),
+            decision: decision.to_string(),
+            decided_by,
+        }
+    }
+}
+
+/// Append a record to the log in `undo_dir`, creating it if needed.
+pub fn append(undo_dir: &Path, record: &SafeguardRecord) -> io::Result<()> {
+    let mut line = serde_json::to_string(record).map_err(io::Error::other)?;
+    line.push('\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(undo_dir.join(LOG_FILE))?;
+    file.write_all(line.as_bytes())
+}
+
+/// All records in the log, oldest first. A missing log is empty, and lines
+/// that do not parse (such as one torn by a crash) are skipped.
+pub fn read(undo_dir: &Path) -> io::Result<Vec<SafeguardRecord>> {
+    let contents = match std::fs::read_to_string(undo_dir.join(LOG_FILE)) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codeagent_common::SafeguardKind;
+
+    #[test]
+    fn records_round_trip_and_torn_lines_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read(dir.path()).unwrap().is_empty());
+
+        let event = SafeguardEvent {
+            safeguard_id: 3,
+            step_id: 5,
+            kind: SafeguardKind::DeleteThreshold {
+                count: 12,
+                threshold: 10,
+            },
+            sample_paths: vec!["build/a.o".to_string()],
+        };
+        let now = codeagent_common::time::now_timestamp();
+        let denied =
+            SafeguardRecord::new(&event, now.clone(), SafeguardDecision::Deny, DecidedBy::Timeout);
+        let allowed =
+            SafeguardRecord::new(&event, now, SafeguardDecision::AllowForSession, DecidedBy::User);
+        append(dir.path(), &denied).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(LOG_FILE))
+            .unwrap()
+            .write_all(b"{\"triggered_at\":\n")
+            .unwrap();
+        append(dir.path(), &allowed).unwrap();
+
+        let records = read(dir.path()).unwrap();
+        assert_eq!(records, vec![denied, allowed]);
+        assert_eq!(records[1].decision, "allow_session");
+    }
+}
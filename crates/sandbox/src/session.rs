@@ -1,17 +1,19 @@
-use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicI64, AtomicU64};
 
-use codeagent_common::SafeguardConfig;
+use codeagent_common::fs_trace::FsTrace;
+use codeagent_common::{SafeguardConfig, StepManager};
+use codeagent_interceptor::gitignore::GitignoreFilter;
 use codeagent_interceptor::undo_interceptor::UndoInterceptor;
-use tokio::sync::{mpsc, oneshot};
+use codeagent_stdio::protocol::{MountBackend, UndoMode};
 use tokio::task::JoinHandle;
 
+use crate::env_profile::EnvProfile;
 use crate::recent_writes::RecentBackendWrites;
+use crate::safeguard_bridge::PendingSafeguards;
 
-use codeagent_common::SafeguardDecision;
-use codeagent_control::{ControlChannelHandler, InFlightTracker};
+use codeagent_control::{ControlChannelHandler, HostMessage, InFlightTracker, LaneSender};
 
 use crate::fs_backend::FilesystemBackend;
 use crate::qemu::QemuProcess;
@@ -27,6 +29,7 @@ pub enum SessionState {
 /// An active session with all per-session resources.
 pub struct Session {
     /// Per-working-directory undo interceptors (indexed by directory position).
+    /// Empty when the session runs with undo disabled.
     pub interceptors: Vec<Arc<UndoInterceptor>>,
 
     /// Absolute paths of shared working directories.
@@ -35,17 +38,36 @@ pub struct Session {
     /// Sanitized mount names for each working directory (same order as `working_dirs`).
     pub mount_names: Vec<String>,
 
+    /// Upper directory of each overlay working directory (same order as
+    /// `working_dirs`), `None` for directories shared in place.
+    pub overlay_dirs: Vec<Option<PathBuf>>,
+
+    /// Whether each working directory is shared read-only (same order as
+    /// `working_dirs`). Host-side writes to one are refused.
+    pub read_only_dirs: Vec<bool>,
+
+    /// Operation trace of each working directory (same order as
+    /// `working_dirs`). Only intercepted backends record into it.
+    pub fs_traces: Vec<Arc<FsTrace>>,
+
     /// Absolute paths of per-directory undo log directories.
     pub undo_dirs: Vec<PathBuf>,
 
+    /// Whether this session records undo history.
+    pub undo: UndoMode,
+
     /// VM lifecycle mode for this session.
     pub vm_mode: String,
 
     /// Current safeguard configuration.
     pub safeguard_config: SafeguardConfig,
 
-    /// Pending safeguard confirmations: safeguard_id → oneshot sender.
-    pub pending_safeguards: HashMap<String, oneshot::Sender<SafeguardDecision>>,
+    /// Safeguard confirmations awaiting `safeguard.confirm`, shared with the
+    /// safeguard consumer task that times them out.
+    pub pending_safeguards: Arc<PendingSafeguards>,
+
+    /// Variables from `session.env.set`, merged into every command's env.
+    pub env_profile: Arc<EnvProfile>,
 
     /// The last `SessionStartPayload` used, stored for `session.reset`.
     pub last_start_payload: Option<codeagent_stdio::protocol::SessionStartPayload>,
@@ -62,10 +84,10 @@ pub struct Session {
     pub in_flight_tracker: Option<InFlightTracker>,
 
     /// Sender for enqueuing host messages to the control channel writer task.
-    pub control_writer: Option<mpsc::UnboundedSender<String>>,
+    pub control_writer: Option<LaneSender<HostMessage>>,
 
     /// Control channel handler for registering outgoing commands.
-    pub control_handler: Option<Arc<ControlChannelHandler<UndoInterceptor>>>,
+    pub control_handler: Option<Arc<ControlChannelHandler<dyn StepManager>>>,
 
     /// Background task for the event bridge (control events → STDIO events).
     pub event_bridge_handle: Option<JoinHandle<()>>,
@@ -76,6 +98,15 @@ pub struct Session {
     /// Background task for writing host messages to the control channel.
     pub control_writer_handle: Option<JoinHandle<()>>,
 
+    /// Background task watching the VM for crashes.
+    pub vm_monitor_handle: Option<JoinHandle<()>>,
+
+    /// Background task compacting the undo logs while the session is idle.
+    pub compaction_handle: Option<JoinHandle<()>>,
+
+    /// Background task enforcing the step duration limit.
+    pub step_watchdog_handle: Option<JoinHandle<()>>,
+
     /// Path to the temporary socket directory (cleaned up on stop).
     pub socket_dir: Option<PathBuf>,
 
@@ -93,8 +124,34 @@ pub struct Session {
     /// Shared tracker for paths recently written by this sandbox's backends.
     pub recent_writes: Option<Arc<RecentBackendWrites>>,
 
+    /// Gitignore filters the watcher applies, one per working directory.
+    /// Empty when it does not respect `.gitignore`.
+    pub gitignore_filters: Vec<Arc<GitignoreFilter>>,
+
+    /// Filesystem backend of each working directory (same order as
+    /// `working_dirs`). Only in use while the VM runs.
+    pub mount_backends: Vec<MountBackend>,
+
     // --- Safeguard bridge fields ---
 
     /// Background task consuming safeguard events from interceptors.
     pub safeguard_bridge_handle: Option<JoinHandle<()>>,
 }
+
+impl Session {
+    /// See [`protection_level`].
+    pub fn protection_level(&self) -> &'static str {
+        protection_level(self.qemu_process.is_some(), self.undo)
+    }
+}
+
+/// What a session protects the host against: `full` (VM isolation and
+/// undo), `isolation_only`, `undo_only` (host-only mode), or `none`.
+pub fn protection_level(vm_running: bool, undo: UndoMode) -> &'static str {
+    match (vm_running, undo) {
+        (true, UndoMode::Enabled) => "full",
+        (true, UndoMode::Disabled) => "isolation_only",
+        (false, UndoMode::Enabled) => "undo_only",
+        (false, UndoMode::Disabled) => "none",
+    }
+}
@@ -0,0 +1,226 @@
+//! Orchestrators for a STDIO API that serves several sessions.
+//!
+//! With `--max-sessions` above 1, the router hands every session an
+//! orchestrator of its own, made by [`OrchestratorFactory`] for the
+//! `session_id` it will get:
+//!
+//! - its undo root is `<undo-dir>/sessions/<id>`, so VM sockets, saved VM
+//!   state and `session.json` are per session;
+//! - its events reach the client wrapped in `Event::Session`, which adds
+//!   `session_id` to their payload;
+//! - it audits its own socket directory for stale resources when created;
+//! - its working directories are claimed in a [`WorkingDirClaims`] shared
+//!   by all sessions, so no two sessions mount overlapping trees.
+//!
+//! The warm pool is not used: its VMs belong to one orchestrator.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+
+use codeagent_common::metrics::Gauges;
+use codeagent_stdio::{Event, RequestHandler, SessionFactory};
+
+use crate::cli::CliArgs;
+use crate::command_classifier::CommandClassifierConfig;
+use crate::config::{CompactionConfig, FileWatcherConfig};
+use crate::error::AgentError;
+use crate::health::{Readiness, ReadinessSource};
+use crate::metrics_endpoint::MetricsSource;
+use crate::orchestrator::Orchestrator;
+
+/// Working directories in use by the sessions of one sandbox process.
+#[derive(Clone, Default)]
+pub struct WorkingDirClaims(Arc<Mutex<Vec<PathBuf>>>);
+
+impl WorkingDirClaims {
+    /// Fail if any of `dirs` contains, or is inside, a claimed directory.
+    pub fn check(&self, dirs: &[PathBuf]) -> Result<(), AgentError> {
+        let claimed = self.0.lock().unwrap();
+        for dir in dirs {
+            let dir = canonical(dir);
+            if let Some(other) = claimed
+                .iter()
+                .find(|other| dir.starts_with(other) || other.starts_with(&dir))
+            {
+                return Err(AgentError::WorkingDirInUse {
+                    path: dir.display().to_string(),
+                    other: other.display().to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn claim(&self, dirs: &[PathBuf]) {
+        self.0.lock().unwrap().extend(dirs.iter().map(|dir| canonical(dir)));
+    }
+
+    pub fn release(&self, dirs: &[PathBuf]) {
+        let mut claimed = self.0.lock().unwrap();
+        for dir in dirs {
+            let dir = canonical(dir);
+            if let Some(index) = claimed.iter().position(|other| *other == dir) {
+                claimed.remove(index);
+            }
+        }
+    }
+}
+
+fn canonical(dir: &Path) -> PathBuf {
+    std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf())
+}
+
+/// Makes the orchestrator of each session; see the module docs.
+pub struct OrchestratorFactory {
+    cli_args: CliArgs,
+    classifier_config: CommandClassifierConfig,
+    file_watcher_config: FileWatcherConfig,
+    compaction_config: CompactionConfig,
+    event_sender: mpsc::UnboundedSender<Event>,
+    auto_cleanup_stale_resources: bool,
+    claims: WorkingDirClaims,
+    readiness: Arc<SessionsReadiness>,
+    metrics: Arc<SessionsMetrics>,
+}
+
+impl OrchestratorFactory {
+    /// Must be called within a Tokio runtime, as must `create`: each
+    /// session's events are forwarded by a task of their own.
+    pub fn new(
+        cli_args: CliArgs,
+        event_sender: mpsc::UnboundedSender<Event>,
+        classifier_config: CommandClassifierConfig,
+        file_watcher_config: FileWatcherConfig,
+        auto_cleanup_stale_resources: bool,
+    ) -> Self {
+        Self {
+            cli_args,
+            classifier_config,
+            file_watcher_config,
+            compaction_config: CompactionConfig::default(),
+            event_sender,
+            auto_cleanup_stale_resources,
+            claims: WorkingDirClaims::default(),
+            readiness: Arc::default(),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Compact the undo logs of each session while it is idle as `config`
+    /// says.
+    pub fn with_compaction(mut self, config: CompactionConfig) -> Self {
+        self.compaction_config = config;
+        self
+    }
+
+    /// Readiness of the sessions, for the health socket: it reports a VM
+    /// booted or a control channel up if any session has one.
+    pub fn readiness_source(&self) -> Arc<dyn ReadinessSource> {
+        Arc::clone(&self.readiness) as Arc<dyn ReadinessSource>
+    }
+
+    /// Gauges of the sessions, summed, for the metrics endpoint.
+    pub fn metrics_source(&self) -> Arc<dyn MetricsSource> {
+        Arc::clone(&self.metrics) as Arc<dyn MetricsSource>
+    }
+}
+
+impl SessionFactory for OrchestratorFactory {
+    fn create(&self, session_id: &str) -> Box<dyn RequestHandler> {
+        let mut cli_args = self.cli_args.clone();
+        cli_args.undo_dir = cli_args
+            .undo_dir
+            .map(|dir| dir.join("sessions").join(session_id));
+        cli_args.vm_pool_size = 0;
+
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let forward_to = self.event_sender.clone();
+        let session = session_id.to_string();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let tagged = Event::Session {
+                    session_id: session.clone(),
+                    event: Box::new(event),
+                };
+                if forward_to.send(tagged).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let orchestrator = Orchestrator::new(
+            cli_args,
+            event_sender,
+            self.classifier_config.clone(),
+            self.file_watcher_config.clone(),
+        )
+        .with_compaction(self.compaction_config.clone())
+        .with_dir_claims(self.claims.clone());
+        orchestrator.audit_stale_resources(self.auto_cleanup_stale_resources);
+        self.readiness
+            .0
+            .lock()
+            .unwrap()
+            .push(orchestrator.readiness_source());
+        self.metrics
+            .0
+            .lock()
+            .unwrap()
+            .push(orchestrator.metrics_source());
+        Box::new(orchestrator)
+    }
+}
+
+#[derive(Default)]
+struct SessionsReadiness(Mutex<Vec<Arc<dyn ReadinessSource>>>);
+
+impl ReadinessSource for SessionsReadiness {
+    fn readiness(&self) -> Readiness {
+        let sources = self.0.lock().unwrap();
+        sources.iter().fold(Readiness::default(), |all, source| {
+            let one = source.readiness();
+            Readiness {
+                vm_booted: all.vm_booted || one.vm_booted,
+                control_channel_up: all.control_channel_up || one.control_channel_up,
+            }
+        })
+    }
+}
+
+#[derive(Default)]
+struct SessionsMetrics(Mutex<Vec<Arc<dyn MetricsSource>>>);
+
+impl MetricsSource for SessionsMetrics {
+    fn gauges(&self) -> Gauges {
+        let sources = self.0.lock().unwrap().clone();
+        sources
+            .iter()
+            .fold(Gauges::default(), |all, source| all.sum(source.gauges()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_refuse_nested_directories_until_released() {
+        let root = tempfile::tempdir().unwrap();
+        let project = root.path().join("project");
+        let nested = project.join("src");
+        std::fs::create_dir_all(&nested).unwrap();
+        let sibling = root.path().join("other");
+        std::fs::create_dir_all(&sibling).unwrap();
+
+        let claims = WorkingDirClaims::default();
+        claims.claim(std::slice::from_ref(&project));
+        assert!(claims.check(std::slice::from_ref(&nested)).is_err());
+        assert!(claims.check(&[root.path().to_path_buf()]).is_err());
+        assert!(claims.check(std::slice::from_ref(&sibling)).is_ok());
+
+        claims.release(std::slice::from_ref(&project));
+        assert!(claims.check(&[nested]).is_ok());
+    }
+}
@@ -0,0 +1,147 @@
+//! The last session's start parameters, kept in the undo directory.
+//!
+//! Every successful `session.start` with undo enabled records its payload,
+//! with the working directories it resolved to, in `session.json`. A
+//! sandbox started again over the same undo directory can then
+//! `session.resume` it: the undo interceptors reattach to their logs and
+//! run crash recovery as for any start, and a persistent session resumes
+//! its saved VM state if it has one.
+//!
+//! A VM that outlived the sandbox process is not reattached. Its filesystem
+//! backends and control channel state lived in that process, so the guest
+//! can no longer reach the working directories; the stale-resource audit
+//! reports it and the resumed session launches a VM of its own.
+//!
+//! `session.destroy` removes the record.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use codeagent_common::time;
+use codeagent_stdio::protocol::{SessionStartPayload, WorkingDirectoryConfig};
+
+/// File name under the undo directory.
+const FILE_NAME: &str = "session.json";
+
+/// Format version of `session.json`.
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub version: u32,
+    /// When the recorded session started.
+    pub started_at: String,
+    pub payload: SessionStartPayload,
+}
+
+impl SessionRecord {
+    /// A record of `payload`, whose working directories (the CLI's, if it
+    /// named none) are `working_dirs`.
+    pub fn new(payload: &SessionStartPayload, working_dirs: &[PathBuf]) -> Self {
+        let mut payload = payload.clone();
+        if payload.working_directories.is_empty() {
+            payload.working_directories = working_dirs
+                .iter()
+                .map(|dir| WorkingDirectoryConfig {
+                    path: dir.display().to_string(),
+                    label: None,
+                    backend: None,
+                    read_only: false,
+                    exclude: vec![],
+                    overlay: false,
+                })
+                .collect();
+        }
+        Self {
+            version: VERSION,
+            started_at: time::now_timestamp(),
+            payload,
+        }
+    }
+
+    pub fn path(undo_dir: &Path) -> PathBuf {
+        undo_dir.join(FILE_NAME)
+    }
+
+    /// Write the record, replacing the previous one atomically.
+    pub fn save(&self, undo_dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(undo_dir)?;
+        let temp = undo_dir.join(format!("{FILE_NAME}.tmp"));
+        fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&temp, Self::path(undo_dir))
+    }
+
+    /// The record in `undo_dir`, or `None` if there is none.
+    pub fn load(undo_dir: &Path) -> io::Result<Option<Self>> {
+        let bytes = match fs::read(Self::path(undo_dir)) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let record: Self = serde_json::from_slice(&bytes)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        if record.version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("session.json has version {}, expected {VERSION}", record.version),
+            ));
+        }
+        Ok(Some(record))
+    }
+
+    /// Delete the record in `undo_dir`, if any.
+    pub fn remove(undo_dir: &Path) {
+        let _ = fs::remove_file(Self::path(undo_dir));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codeagent_stdio::protocol::UndoMode;
+
+    fn payload(working_directories: Vec<WorkingDirectoryConfig>) -> SessionStartPayload {
+        SessionStartPayload {
+            working_directories,
+            network_policy: "disabled".to_string(),
+            vm_mode: "persistent".to_string(),
+            protocol_version: None,
+            symlink_policy: None,
+            coherent_capture: None,
+            case_sensitivity: None,
+            undo: UndoMode::Enabled,
+            message_limits: None,
+            terminal_output: None,
+        }
+    }
+
+    #[test]
+    fn records_round_trip_with_resolved_working_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(SessionRecord::load(dir.path()).unwrap(), None);
+
+        let record = SessionRecord::new(&payload(vec![]), &[PathBuf::from("/work/a")]);
+        assert_eq!(record.payload.working_directories[0].path, "/work/a");
+        record.save(dir.path()).unwrap();
+        assert_eq!(SessionRecord::load(dir.path()).unwrap(), Some(record.clone()));
+
+        SessionRecord::remove(dir.path());
+        assert_eq!(SessionRecord::load(dir.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn unreadable_records_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(SessionRecord::path(dir.path()), "{").unwrap();
+        assert!(SessionRecord::load(dir.path()).is_err());
+
+        let mut record = SessionRecord::new(&payload(vec![]), &[]);
+        record.version = VERSION + 1;
+        fs::write(SessionRecord::path(dir.path()), serde_json::to_vec(&record).unwrap()).unwrap();
+        let error = SessionRecord::load(dir.path()).unwrap_err();
+        assert!(error.to_string().contains("version"));
+    }
+}
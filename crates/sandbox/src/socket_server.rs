@@ -5,6 +5,7 @@ use tokio::sync::{mpsc, watch};
 
 use codeagent_mcp::protocol::{JsonRpcNotification, JsonRpcResponse};
 use codeagent_mcp::{McpHandler, McpRouter, McpServer};
+use codeagent_stdio::{log_error, log_info, log_warn};
 
 use crate::config::{load_config, SandboxTomlConfig};
 
@@ -53,18 +54,12 @@ async fn run_unix_socket_server(
     let listener = match UnixListener::bind(&socket_path) {
         Ok(l) => l,
         Err(e) => {
-            eprintln!(
-                "{{\"level\":\"error\",\"message\":\"failed to bind socket {}: {e}\"}}",
-                socket_path.display()
-            );
+            log_error!("socket_server", "failed to bind socket {}: {e}", socket_path.display());
             return;
         }
     };
 
-    eprintln!(
-        "{{\"level\":\"info\",\"message\":\"socket server listening on {}\"}}",
-        socket_path.display()
-    );
+    log_info!("socket_server", "socket server listening on {}", socket_path.display());
 
     loop {
         tokio::select! {
@@ -81,9 +76,7 @@ async fn run_unix_socket_server(
                         });
                     }
                     Err(e) => {
-                        eprintln!(
-                            "{{\"level\":\"warn\",\"message\":\"socket accept error: {e}\"}}"
-                        );
+                        log_warn!("socket_server", "socket accept error: {e}");
                     }
                 }
             }
@@ -107,9 +100,7 @@ async fn run_tcp_socket_server(
     let listener = match TcpListener::bind("127.0.0.1:0").await {
         Ok(l) => l,
         Err(e) => {
-            eprintln!(
-                "{{\"level\":\"error\",\"message\":\"failed to bind TCP socket: {e}\"}}"
-            );
+            log_error!("socket_server", "failed to bind TCP socket: {e}");
             return;
         }
     };
@@ -117,9 +108,7 @@ async fn run_tcp_socket_server(
     let local_addr = match listener.local_addr() {
         Ok(addr) => addr,
         Err(e) => {
-            eprintln!(
-                "{{\"level\":\"error\",\"message\":\"failed to get local address: {e}\"}}"
-            );
+            log_error!("socket_server", "failed to get local address: {e}");
             return;
         }
     };
@@ -129,15 +118,13 @@ async fn run_tcp_socket_server(
         let _ = std::fs::create_dir_all(parent);
     }
     if let Err(e) = std::fs::write(&socket_path, local_addr.port().to_string()) {
-        eprintln!(
-            "{{\"level\":\"error\",\"message\":\"failed to write port file {}: {e}\"}}",
-            socket_path.display()
-        );
+        log_error!("socket_server", "failed to write port file {}: {e}", socket_path.display());
         return;
     }
 
-    eprintln!(
-        "{{\"level\":\"info\",\"message\":\"socket server listening on {} (port file: {})\"}}",
+    log_info!(
+        "socket_server",
+        "socket server listening on {} (port file: {})",
         local_addr,
         socket_path.display()
     );
@@ -157,9 +144,7 @@ async fn run_tcp_socket_server(
                         });
                     }
                     Err(e) => {
-                        eprintln!(
-                            "{{\"level\":\"warn\",\"message\":\"socket accept error: {e}\"}}"
-                        );
+                        log_warn!("socket_server", "socket accept error: {e}");
                     }
                 }
             }
@@ -186,7 +171,7 @@ async fn handle_connection<R, W>(
     let mut server = McpServer::new(router, notification_receiver);
 
     if let Err(e) = server.run(reader, writer).await {
-        eprintln!("{{\"level\":\"warn\",\"message\":\"socket connection closed: {e}\"}}");
+        log_warn!("socket_server", "socket connection closed: {e}");
     }
 }
 
@@ -210,11 +195,10 @@ fn handle_sandbox_method(
                 Ok(value) => JsonRpcResponse::success(id, value),
                 Err(e) => JsonRpcResponse::error(
                     id,
-                    codeagent_mcp::JsonRpcError {
-                        code: -32603,
+                    codeagent_mcp::McpError::InternalError {
                         message: format!("failed to serialize config: {e}"),
-                        data: None,
-                    },
+                    }
+                    .to_jsonrpc_error(),
                 ),
             }
         }
@@ -226,11 +210,10 @@ fn handle_sandbox_method(
                 Err(e) => {
                     return Some(JsonRpcResponse::error(
                         id,
-                        codeagent_mcp::JsonRpcError {
-                            code: -32602,
+                        codeagent_mcp::McpError::InvalidParams {
                             message: format!("invalid config: {e}"),
-                            data: None,
-                        },
+                        }
+                        .to_jsonrpc_error(),
                     ));
                 }
             };
@@ -244,11 +227,10 @@ fn handle_sandbox_method(
             let Some(path) = config_path else {
                 return Some(JsonRpcResponse::error(
                     id,
-                    codeagent_mcp::JsonRpcError {
-                        code: -32603,
+                    codeagent_mcp::McpError::InternalError {
                         message: "cannot determine config file path".into(),
-                        data: None,
-                    },
+                    }
+                    .to_jsonrpc_error(),
                 ));
             };
 
@@ -263,20 +245,18 @@ fn handle_sandbox_method(
                     }
                     Err(e) => JsonRpcResponse::error(
                         id,
-                        codeagent_mcp::JsonRpcError {
-                            code: -32603,
+                        codeagent_mcp::McpError::InternalError {
                             message: format!("failed to write config: {e}"),
-                            data: None,
-                        },
+                        }
+                        .to_jsonrpc_error(),
                     ),
                 },
                 Err(e) => JsonRpcResponse::error(
                     id,
-                    codeagent_mcp::JsonRpcError {
-                        code: -32603,
+                    codeagent_mcp::McpError::InternalError {
                         message: format!("failed to serialize config: {e}"),
-                        data: None,
-                    },
+                    }
+                    .to_jsonrpc_error(),
                 ),
             }
         }
@@ -289,8 +269,9 @@ fn handle_sandbox_method(
 mod tests {
     use super::*;
     use codeagent_mcp::protocol::{
-        BashArgs, DiscardUndoHistoryArgs, EditFileArgs, GetUndoHistoryArgs, GlobArgs, GrepArgs,
-        ReadFileArgs, UndoArgs, WriteFileArgs,
+        ApplyPatchArgs, BashArgs, ConfirmSafeguardArgs, DiscardUndoHistoryArgs, EditFileArgs,
+        GetUndoHistoryArgs, GlobArgs, GrepArgs, ListPendingSafeguardsArgs, ReadFileArgs, UndoArgs,
+        WriteFileArgs,
     };
     use codeagent_mcp::McpError;
     use serde_json::json;
@@ -311,6 +292,9 @@ mod tests {
         fn edit_file(&self, _: EditFileArgs) -> Result<serde_json::Value, McpError> {
             Ok(json!("ok"))
         }
+        fn apply_patch(&self, _: ApplyPatchArgs) -> Result<serde_json::Value, McpError> {
+            Ok(json!({"applied": true}))
+        }
         fn glob(&self, _: GlobArgs) -> Result<serde_json::Value, McpError> {
             Ok(json!(""))
         }
@@ -332,6 +316,18 @@ mod tests {
         ) -> Result<serde_json::Value, McpError> {
             Ok(json!({}))
         }
+        fn list_pending_safeguards(
+            &self,
+            _: ListPendingSafeguardsArgs,
+        ) -> Result<serde_json::Value, McpError> {
+            Ok(json!({"pending": []}))
+        }
+        fn confirm_safeguard(
+            &self,
+            _: ConfirmSafeguardArgs,
+        ) -> Result<serde_json::Value, McpError> {
+            Ok(json!({}))
+        }
     }
 
     #[tokio::test]
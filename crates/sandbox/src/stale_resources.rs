@@ -0,0 +1,366 @@
+//! Startup audit for resources left behind by a previous sandbox run.
+//!
+//! A sandbox that is killed abruptly can leave its `.sockets` directory,
+//! still-running QEMU/virtiofsd children, and virtiofsd `.pid` lock files
+//! behind. The next VM launch then fails with "address in use" errors that
+//! are hard to trace back to the earlier run. Because the singleton instance
+//! lock is held before the audit runs, anything found here cannot belong to
+//! another live sandbox.
+
+use std::path::{Path, PathBuf};
+
+use codeagent_stdio::protocol::StaleResourceReport;
+
+/// How long to wait for a killed orphan process to disappear.
+#[cfg(unix)]
+const PROCESS_EXIT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Executable name prefixes of the helper processes the sandbox spawns.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const SANDBOX_PROCESS_PREFIXES: &[&str] = &["qemu-system", "virtiofsd"];
+
+/// A leftover resource found by [`audit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StaleResource {
+    /// The socket directory of a previous VM launch.
+    SocketDir { path: PathBuf },
+    /// A QEMU or virtiofsd process that still references the socket directory.
+    OrphanProcess { pid: u32, command: String },
+    /// A lock file in the socket directory that no process holds.
+    AbandonedLock { path: PathBuf },
+}
+
+impl StaleResource {
+    /// Wire name used in `event.stale_resources`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StaleResource::SocketDir { .. } => "socket_dir",
+            StaleResource::OrphanProcess { .. } => "orphan_process",
+            StaleResource::AbandonedLock { .. } => "abandoned_lock",
+        }
+    }
+
+    /// Short human-readable identifier for logs.
+    pub fn describe(&self) -> String {
+        match self {
+            StaleResource::SocketDir { path } | StaleResource::AbandonedLock { path } => {
+                path.display().to_string()
+            }
+            StaleResource::OrphanProcess { pid, command } => format!("{command} (pid {pid})"),
+        }
+    }
+
+    /// Convert to the STDIO API representation.
+    pub fn to_report(&self) -> StaleResourceReport {
+        let (path, pid, message) = match self {
+            StaleResource::SocketDir { path } => (
+                Some(path.display().to_string()),
+                None,
+                "socket directory left by a previous run".to_string(),
+            ),
+            StaleResource::OrphanProcess { pid, command } => (
+                None,
+                Some(*pid),
+                format!("{command} is still running from a previous run"),
+            ),
+            StaleResource::AbandonedLock { path } => (
+                Some(path.display().to_string()),
+                None,
+                "lock file is not held by any process".to_string(),
+            ),
+        };
+        StaleResourceReport {
+            kind: self.kind().to_string(),
+            path,
+            pid,
+            message,
+        }
+    }
+}
+
+/// Result of [`cleanup`].
+#[derive(Debug, Default)]
+pub struct CleanupOutcome {
+    pub cleaned: Vec<StaleResource>,
+    pub failed: Vec<(StaleResource, String)>,
+}
+
+/// Scan for leftovers associated with `socket_dir`.
+///
+/// Orphan processes are listed first so [`cleanup`] stops them before
+/// removing the files they may still have open.
+pub fn audit(socket_dir: &Path) -> Vec<StaleResource> {
+    let mut resources = find_orphan_processes(socket_dir);
+
+    if !socket_dir.is_dir() {
+        return resources;
+    }
+
+    if let Ok(entries) = std::fs::read_dir(socket_dir) {
+        let mut locks: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| is_lock_file(path) && !is_lock_held(path))
+            .collect();
+        locks.sort();
+        resources.extend(locks.into_iter().map(|path| StaleResource::AbandonedLock { path }));
+    }
+
+    resources.push(StaleResource::SocketDir {
+        path: socket_dir.to_path_buf(),
+    });
+    resources
+}
+
+/// Stop or remove every resource in `resources`, in order.
+pub fn cleanup(resources: Vec<StaleResource>) -> CleanupOutcome {
+    let mut outcome = CleanupOutcome::default();
+    for resource in resources {
+        let result = match &resource {
+            StaleResource::OrphanProcess { pid, .. } => kill_process(*pid),
+            StaleResource::AbandonedLock { path } => ignore_not_found(std::fs::remove_file(path)),
+            StaleResource::SocketDir { path } => ignore_not_found(std::fs::remove_dir_all(path)),
+        };
+        match result {
+            Ok(()) => outcome.cleaned.push(resource),
+            Err(error) => outcome.failed.push((resource, error)),
+        }
+    }
+    outcome
+}
+
+/// Treat an already-removed resource as cleaned.
+fn ignore_not_found(result: std::io::Result<()>) -> Result<(), String> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+fn is_lock_file(path: &Path) -> bool {
+    path.is_file()
+        && matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("pid") | Some("lock")
+        )
+}
+
+/// Whether `argv` belongs to a helper process launched for `socket_dir`.
+///
+/// The executable name must match one of the sandbox's helpers and one of
+/// the arguments must reference a path inside the socket directory (QEMU's
+/// `-chardev` and virtiofsd's `--socket-path` both do).
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn is_sandbox_process(argv: &[String], socket_dir: &str) -> bool {
+    let Some(program) = argv.first() else {
+        return false;
+    };
+    let name = Path::new(program)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    SANDBOX_PROCESS_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+        && argv.iter().skip(1).any(|arg| arg.contains(socket_dir))
+}
+
+/// Find helper processes from a previous run by scanning `/proc`.
+#[cfg(target_os = "linux")]
+fn find_orphan_processes(socket_dir: &Path) -> Vec<StaleResource> {
+    let socket_dir = socket_dir.to_string_lossy();
+    let own_pid = std::process::id();
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    let mut orphans: Vec<(u32, String)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            if pid == own_pid {
+                return None;
+            }
+            let cmdline = std::fs::read(entry.path().join("cmdline")).ok()?;
+            let argv: Vec<String> = cmdline
+                .split(|byte| *byte == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect();
+            if !is_sandbox_process(&argv, &socket_dir) {
+                return None;
+            }
+            let command = Path::new(&argv[0])
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| argv[0].clone());
+            Some((pid, command))
+        })
+        .collect();
+    orphans.sort_unstable();
+    orphans
+        .into_iter()
+        .map(|(pid, command)| StaleResource::OrphanProcess { pid, command })
+        .collect()
+}
+
+/// Process enumeration is only implemented via `/proc`. On Windows, QEMU
+/// runs in a kill-on-close job object and cannot outlive the sandbox.
+#[cfg(not(target_os = "linux"))]
+fn find_orphan_processes(_socket_dir: &Path) -> Vec<StaleResource> {
+    Vec::new()
+}
+
+/// Whether another open file description holds an exclusive `flock` on `path`.
+#[cfg(unix)]
+fn is_lock_held(path: &Path) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+    // SAFETY: the fd is valid for the lifetime of `file`; the lock (if
+    // acquired) is released when `file` is closed at the end of this scope.
+    let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) };
+    result != 0
+}
+
+#[cfg(not(unix))]
+fn is_lock_held(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn kill_process(pid: u32) -> Result<(), String> {
+    let pid = pid as libc::pid_t;
+    // SAFETY: kill(2) has no memory-safety preconditions.
+    if unsafe { libc::kill(pid, libc::SIGKILL) } != 0 {
+        let error = std::io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(libc::ESRCH) => Ok(()),
+            _ => Err(error.to_string()),
+        };
+    }
+
+    let start = std::time::Instant::now();
+    // SAFETY: signal 0 only checks whether the process still exists.
+    while unsafe { libc::kill(pid, 0) } == 0 {
+        if start.elapsed() > PROCESS_EXIT_TIMEOUT {
+            return Err(format!("process {pid} did not exit after SIGKILL"));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn kill_process(pid: u32) -> Result<(), String> {
+    Err(format!("stopping process {pid} is not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn missing_socket_dir_reports_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(audit(&dir.path().join(".sockets")).is_empty());
+    }
+
+    #[test]
+    fn leftover_socket_dir_is_reported_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_dir = dir.path().join(".sockets");
+        std::fs::create_dir_all(&socket_dir).unwrap();
+        std::fs::write(socket_dir.join("vfs0.sock"), b"").unwrap();
+
+        let resources = audit(&socket_dir);
+        assert_eq!(
+            resources,
+            vec![StaleResource::SocketDir {
+                path: socket_dir.clone()
+            }]
+        );
+
+        let outcome = cleanup(resources);
+        assert_eq!(outcome.cleaned.len(), 1);
+        assert!(outcome.failed.is_empty());
+        assert!(!socket_dir.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_unheld_lock_files_are_abandoned() {
+        use std::os::unix::io::AsRawFd;
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket_dir = dir.path().join(".sockets");
+        std::fs::create_dir_all(&socket_dir).unwrap();
+        let abandoned = socket_dir.join("vfs0.sock.pid");
+        let held = socket_dir.join("vfs1.sock.pid");
+        std::fs::write(&abandoned, b"4242\n").unwrap();
+        std::fs::write(&held, b"4243\n").unwrap();
+
+        let holder = std::fs::File::open(&held).unwrap();
+        let locked = unsafe { libc::flock(holder.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        assert_eq!(locked, 0);
+
+        let resources = audit(&socket_dir);
+        assert!(resources.contains(&StaleResource::AbandonedLock { path: abandoned }));
+        assert!(!resources.contains(&StaleResource::AbandonedLock { path: held }));
+    }
+
+    #[test]
+    fn sandbox_process_matching() {
+        let socket_dir = "/home/user/.local/share/CodeAgent/undo/.sockets";
+        assert!(is_sandbox_process(
+            &argv(&[
+                "/usr/bin/qemu-system-x86_64",
+                "-chardev",
+                "socket,id=control,path=/home/user/.local/share/CodeAgent/undo/.sockets/control.sock",
+            ]),
+            socket_dir,
+        ));
+        assert!(is_sandbox_process(
+            &argv(&[
+                "virtiofsd",
+                "--socket-path",
+                "/home/user/.local/share/CodeAgent/undo/.sockets/vfs0.sock",
+            ]),
+            socket_dir,
+        ));
+        // Same helper binary, but launched for a different socket directory.
+        assert!(!is_sandbox_process(
+            &argv(&["qemu-system-aarch64", "-chardev", "socket,path=/tmp/other/control.sock"]),
+            socket_dir,
+        ));
+        // Unrelated program that happens to mention the socket directory.
+        assert!(!is_sandbox_process(&argv(&["ls", socket_dir]), socket_dir));
+        assert!(!is_sandbox_process(&[], socket_dir));
+    }
+
+    #[test]
+    fn report_fields_by_kind() {
+        let report = StaleResource::OrphanProcess {
+            pid: 7,
+            command: "virtiofsd".to_string(),
+        }
+        .to_report();
+        assert_eq!(report.kind, "orphan_process");
+        assert_eq!(report.pid, Some(7));
+        assert!(report.path.is_none());
+
+        let report = StaleResource::AbandonedLock {
+            path: PathBuf::from("/tmp/.sockets/vfs0.sock.pid"),
+        }
+        .to_report();
+        assert_eq!(report.kind, "abandoned_lock");
+        assert_eq!(report.path.as_deref(), Some("/tmp/.sockets/vfs0.sock.pid"));
+    }
+}
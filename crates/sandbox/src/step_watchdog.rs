@@ -0,0 +1,191 @@
+//! The step duration limit, timed on the session's clock.
+//!
+//! `max_step_duration_seconds` has to trip for a step that stays open
+//! without touching the filesystem, such as a command waiting on input, so
+//! it cannot wait for the step's next operation. This task polls the open
+//! steps of each undo log, notes when it first saw each one and runs the
+//! step duration safeguard with the time since. A step no longer open at a
+//! poll is forgotten.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use codeagent_common::{CodeAgentError, StepId};
+use codeagent_control::Clock;
+use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+use codeagent_stdio::log_warn;
+use tokio::time::Instant;
+
+/// How often the task checks the open steps.
+pub const STEP_WATCHDOG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The session whose steps the task times.
+pub trait StepWatchdogHost: Send + Sync + 'static {
+    /// The undo logs of the session, or `None` once it stopped.
+    fn undo_logs(&self) -> Option<Vec<Arc<UndoInterceptor>>>;
+}
+
+/// Run the step duration safeguard of every open step of `host` each poll
+/// interval, until it is gone.
+pub async fn run_step_watchdog(host: Arc<dyn StepWatchdogHost>, clock: Arc<dyn Clock>) {
+    let mut timer = StepTimer::default();
+    loop {
+        clock.sleep(STEP_WATCHDOG_POLL_INTERVAL).await;
+        let now = clock.now();
+        let host = Arc::clone(&host);
+        // A triggered safeguard blocks until it is answered.
+        let polled = tokio::task::spawn_blocking(move || {
+            let live = timer.check(&*host, now);
+            live.then_some(timer)
+        })
+        .await;
+        match polled {
+            Ok(Some(next)) => timer = next,
+            _ => return,
+        }
+    }
+}
+
+/// When the task first saw each open step, by undo log and step ID.
+#[derive(Default)]
+struct StepTimer {
+    first_seen: HashMap<(usize, StepId), Instant>,
+}
+
+impl StepTimer {
+    /// Check the open steps of `host` at `now`. False once it is gone.
+    fn check(&mut self, host: &dyn StepWatchdogHost, now: Instant) -> bool {
+        let Some(undo_logs) = host.undo_logs() else {
+            return false;
+        };
+        let mut open = HashMap::new();
+        for (index, interceptor) in undo_logs.iter().enumerate() {
+            for step_id in interceptor.open_step_ids() {
+                let first_seen = *self.first_seen.get(&(index, step_id)).unwrap_or(&now);
+                open.insert((index, step_id), first_seen);
+                let elapsed = now.saturating_duration_since(first_seen);
+                match interceptor.check_step_duration(step_id, elapsed) {
+                    Ok(()) | Err(CodeAgentError::SafeguardDenied { .. }) => {}
+                    Err(error) => log_warn!(
+                        "step_watchdog",
+                        "cannot enforce the duration limit of step {step_id}: {error}"
+                    ),
+                }
+            }
+        }
+        self.first_seen = open;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use codeagent_common::{SafeguardConfig, SafeguardDecision, SafeguardEvent, SafeguardKind};
+    use codeagent_control::ManualClock;
+    use codeagent_interceptor::safeguard::SafeguardHandler;
+    use codeagent_interceptor::write_interceptor::WriteInterceptor;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A session the test ends by taking its undo log.
+    struct ScriptedSession(Mutex<Option<Arc<UndoInterceptor>>>);
+
+    impl StepWatchdogHost for ScriptedSession {
+        fn undo_logs(&self) -> Option<Vec<Arc<UndoInterceptor>>> {
+            self.0
+                .lock()
+                .unwrap()
+                .clone()
+                .map(|interceptor| vec![interceptor])
+        }
+    }
+
+    /// Denies every safeguard and keeps what it was asked about.
+    #[derive(Default)]
+    struct Denier(Mutex<Vec<SafeguardKind>>);
+
+    impl SafeguardHandler for Denier {
+        fn on_safeguard_triggered(&self, event: SafeguardEvent) -> SafeguardDecision {
+            self.0.lock().unwrap().push(event.kind);
+            SafeguardDecision::Deny
+        }
+    }
+
+    /// A session with step 1 open after one write to `notes.md`, and a
+    /// duration limit of 3 seconds.
+    fn session(
+        dir: &TempDir,
+        denier: &Arc<Denier>,
+    ) -> (Arc<ScriptedSession>, Arc<UndoInterceptor>) {
+        let working = dir.path().join("working");
+        std::fs::create_dir_all(&working).unwrap();
+        let file = working.join("notes.md");
+        std::fs::write(&file, "draft").unwrap();
+        let interceptor = Arc::new(UndoInterceptor::new_default(
+            working,
+            dir.path().join("undo"),
+        ));
+        interceptor.set_safeguard_config(SafeguardConfig {
+            max_step_duration_seconds: Some(3),
+            ..SafeguardConfig::default()
+        });
+        interceptor.set_safeguard_handler(Some(Arc::clone(denier) as Arc<dyn SafeguardHandler>));
+        interceptor.open_step(1).unwrap();
+        interceptor.pre_write(&file).unwrap();
+        std::fs::write(&file, "final").unwrap();
+        let session = ScriptedSession(Mutex::new(Some(Arc::clone(&interceptor))));
+        (Arc::new(session), interceptor)
+    }
+
+    /// Run the task over `session` for `polls` poll intervals, then end the
+    /// session.
+    async fn run(session: Arc<ScriptedSession>, polls: u32) {
+        let clock = Arc::new(ManualClock::new());
+        let task = tokio::spawn(run_step_watchdog(session.clone(), clock.clone()));
+        let mut advanced = 0;
+        while !task.is_finished() {
+            if clock.sleepers() > 0 {
+                if advanced == polls {
+                    session.0.lock().unwrap().take();
+                }
+                clock.advance(STEP_WATCHDOG_POLL_INTERVAL);
+                advanced += 1;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_step_past_its_limit_is_denied() {
+        let dir = TempDir::new().unwrap();
+        let denier = Arc::new(Denier::default());
+        let (session, interceptor) = session(&dir, &denier);
+        run(session, 6).await;
+
+        assert_eq!(
+            *denier.0.lock().unwrap(),
+            vec![SafeguardKind::StepDuration {
+                elapsed_seconds: 4,
+                limit_seconds: 3
+            }]
+        );
+        assert!(interceptor.open_step_ids().is_empty());
+        let notes = dir.path().join("working/notes.md");
+        assert_eq!(std::fs::read_to_string(notes).unwrap(), "draft");
+    }
+
+    #[tokio::test]
+    async fn step_within_its_limit_is_left_alone() {
+        let dir = TempDir::new().unwrap();
+        let denier = Arc::new(Denier::default());
+        let (session, interceptor) = session(&dir, &denier);
+        run(session, 3).await;
+
+        assert!(denier.0.lock().unwrap().is_empty());
+        assert_eq!(interceptor.open_step_ids(), vec![1]);
+    }
+}
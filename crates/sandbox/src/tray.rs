@@ -12,6 +12,8 @@ use std::time::Duration;
 use tray_icon::menu::{CheckMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem};
 use tray_icon::{Icon, TrayIconBuilder};
 
+use codeagent_stdio::log_warn;
+
 /// Commands sent from the tray UI to the server.
 pub enum TrayCommand {
     ToggleBuiltinTools(bool),
@@ -86,9 +88,7 @@ pub fn run_tray(
     {
         Ok(tray) => tray,
         Err(e) => {
-            eprintln!(
-                "{{\"level\":\"warn\",\"message\":\"failed to create tray icon: {e}\"}}"
-            );
+            log_warn!("tray", "failed to create tray icon: {e}");
             wait_for_shutdown(&update_rx);
             return;
         }
@@ -211,7 +211,7 @@ pub fn should_show_tray() -> bool {
 /// in workspace target directories, and then on PATH.
 pub fn open_desktop_app() {
     let Some(path) = find_desktop_binary() else {
-        eprintln!("{{\"level\":\"warn\",\"message\":\"desktop app not found\"}}");
+        log_warn!("tray", "desktop app not found");
         return;
     };
 
@@ -0,0 +1,256 @@
+//! Crash detection for the session's VM.
+//!
+//! QEMU is started once per session and nothing else notices if it dies:
+//! commands sent to it would just never complete. The monitor task checks
+//! on the process every [`VM_POLL_INTERVAL`]. When it has exited, the host
+//! (the orchestrator's session) tears down the control channel and
+//! filesystem backends attached to it and ends the lost commands, and the
+//! monitor emits `event.vm_crashed` with the exit status and the tail of
+//! the VM's console. With `--vm-auto-restart` it then launches a new VM in
+//! the same session, up to [`MAX_VM_RESTARTS`] times.
+
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::time::Duration;
+
+use codeagent_common::SandboxWarning;
+use codeagent_control::Clock;
+use codeagent_stdio::Event;
+use tokio::sync::mpsc;
+
+use crate::error::AgentError;
+use crate::warnings::WarningReporter;
+
+/// How often the monitor checks whether QEMU is still running.
+pub const VM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Relaunches per session before a crashed VM is left down.
+pub const MAX_VM_RESTARTS: u32 = 3;
+
+/// How the VM process ended, and what it printed last.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmExit {
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub serial_tail: Vec<String>,
+}
+
+impl VmExit {
+    pub fn new(status: ExitStatus, serial_tail: Vec<String>) -> Self {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal = None;
+        Self {
+            exit_code: status.code(),
+            signal,
+            serial_tail,
+        }
+    }
+}
+
+/// What a check on the VM found.
+#[derive(Debug)]
+pub enum VmPoll {
+    Running,
+    /// The VM exited; everything attached to it has been torn down.
+    Exited(VmExit),
+    /// There is no VM to watch: the session stopped or runs without one.
+    Gone,
+}
+
+/// The VM the monitor watches.
+pub trait VmHost: Send + Sync + 'static {
+    /// Check on the VM. Returns [`VmPoll::Exited`] once per exit, after
+    /// tearing down what was attached to the VM.
+    fn poll(&self) -> VmPoll;
+
+    /// Launch a new VM in place of the one that exited. Blocks until it is
+    /// up, like the launch at `session.start`.
+    fn relaunch(&self) -> Result<(), AgentError>;
+}
+
+/// Watch `host` until its VM is gone, emitting `event.vm_crashed` for each
+/// exit and, with `auto_restart`, relaunching it. A relaunch that fails is
+/// reported as a `vm_launch_failed` warning and leaves the session without
+/// a VM.
+pub async fn run_vm_monitor(
+    host: Arc<dyn VmHost>,
+    clock: Arc<dyn Clock>,
+    event_sender: mpsc::UnboundedSender<Event>,
+    warnings: WarningReporter,
+    auto_restart: bool,
+) {
+    let mut restarts = 0;
+    loop {
+        clock.sleep(VM_POLL_INTERVAL).await;
+        let exit = match host.poll() {
+            VmPoll::Running => continue,
+            VmPoll::Gone => return,
+            VmPoll::Exited(exit) => exit,
+        };
+        let restarting = auto_restart && restarts < MAX_VM_RESTARTS;
+        let _ = event_sender.send(Event::VmCrashed {
+            exit_code: exit.exit_code,
+            signal: exit.signal,
+            serial_tail: exit.serial_tail,
+            restarting,
+        });
+        if !restarting {
+            return;
+        }
+        restarts += 1;
+
+        let relauncher = Arc::clone(&host);
+        let relaunched = tokio::task::spawn_blocking(move || relauncher.relaunch())
+            .await
+            .unwrap_or_else(|error| {
+                Err(AgentError::QemuSpawnFailed {
+                    reason: error.to_string(),
+                })
+            });
+        if let Err(error) = relaunched {
+            warnings.report(SandboxWarning::VmLaunchFailed {
+                reason: format!("relaunch after crash: {error}"),
+            });
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use codeagent_control::ManualClock;
+
+    use super::*;
+
+    /// A VM whose checks return a script, then `Gone`.
+    struct ScriptedVm {
+        polls: Mutex<VecDeque<VmPoll>>,
+        relaunches: AtomicU32,
+        relaunch_fails: bool,
+    }
+
+    impl ScriptedVm {
+        fn new(polls: Vec<VmPoll>, relaunch_fails: bool) -> Arc<Self> {
+            Arc::new(Self {
+                polls: Mutex::new(polls.into()),
+                relaunches: AtomicU32::new(0),
+                relaunch_fails,
+            })
+        }
+    }
+
+    impl VmHost for ScriptedVm {
+        fn poll(&self) -> VmPoll {
+            self.polls.lock().unwrap().pop_front().unwrap_or(VmPoll::Gone)
+        }
+
+        fn relaunch(&self) -> Result<(), AgentError> {
+            self.relaunches.fetch_add(1, Ordering::SeqCst);
+            if self.relaunch_fails {
+                return Err(AgentError::QemuUnavailable);
+            }
+            Ok(())
+        }
+    }
+
+    fn crashed() -> VmPoll {
+        VmPoll::Exited(VmExit {
+            exit_code: Some(1),
+            signal: None,
+            serial_tail: vec!["Kernel panic".to_string()],
+        })
+    }
+
+    /// Run the monitor over `vm` to its end, advancing the clock whenever it
+    /// waits. Returns the events it sent and the warnings reported.
+    async fn monitor(
+        vm: Arc<ScriptedVm>,
+        auto_restart: bool,
+    ) -> (Vec<Event>, Vec<SandboxWarning>) {
+        let clock = Arc::new(ManualClock::new());
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let warnings = WarningReporter::new(event_sender.clone());
+        let task = tokio::spawn(run_vm_monitor(
+            vm,
+            clock.clone(),
+            event_sender,
+            warnings.clone(),
+            auto_restart,
+        ));
+        while !task.is_finished() {
+            if clock.sleepers() > 0 {
+                clock.advance(VM_POLL_INTERVAL);
+            }
+            tokio::task::yield_now().await;
+        }
+        let mut sent = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            sent.push(event);
+        }
+        (sent, warnings.active())
+    }
+
+    fn restarting(event: &Event) -> Option<bool> {
+        match event {
+            Event::VmCrashed { restarting, .. } => Some(*restarting),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn crash_is_reported_and_relaunched() {
+        let vm = ScriptedVm::new(vec![VmPoll::Running, crashed(), VmPoll::Running], false);
+        let (events, warnings) = monitor(vm.clone(), true).await;
+        assert_eq!(
+            events,
+            vec![Event::VmCrashed {
+                exit_code: Some(1),
+                signal: None,
+                serial_tail: vec!["Kernel panic".to_string()],
+                restarting: true,
+            }]
+        );
+        assert!(warnings.is_empty());
+        assert_eq!(vm.relaunches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn crash_without_auto_restart_ends_monitoring() {
+        let vm = ScriptedVm::new(vec![crashed(), crashed()], false);
+        let (events, _) = monitor(vm.clone(), false).await;
+        let flags: Vec<_> = events.iter().filter_map(restarting).collect();
+        assert_eq!(flags, vec![false]);
+        assert_eq!(vm.relaunches.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn restarts_stop_after_the_limit() {
+        let crashes = (0..=MAX_VM_RESTARTS).map(|_| crashed()).collect();
+        let vm = ScriptedVm::new(crashes, false);
+        let (events, _) = monitor(vm.clone(), true).await;
+        let flags: Vec<_> = events.iter().filter_map(restarting).collect();
+        let mut expected = vec![true; MAX_VM_RESTARTS as usize];
+        expected.push(false);
+        assert_eq!(flags, expected);
+        assert_eq!(vm.relaunches.load(Ordering::SeqCst), MAX_VM_RESTARTS);
+    }
+
+    #[tokio::test]
+    async fn failed_relaunch_is_a_warning() {
+        let vm = ScriptedVm::new(vec![crashed(), crashed()], true);
+        let (events, warnings) = monitor(vm.clone(), true).await;
+        assert_eq!(events.iter().filter_map(restarting).count(), 1);
+        assert!(matches!(
+            warnings.as_slice(),
+            [SandboxWarning::VmLaunchFailed { reason }]
+                if reason.starts_with("relaunch after crash")
+        ));
+        assert_eq!(vm.relaunches.load(Ordering::SeqCst), 1);
+    }
+}
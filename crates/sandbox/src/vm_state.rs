@@ -0,0 +1,284 @@
+//! Saved VM state for `vm_mode: "persistent"` sessions.
+//!
+//! At `session.stop` the VM is paused and its RAM and device state are
+//! migrated into a file over QMP (QEMU's JSON monitor protocol, `file:`
+//! migration needs QEMU 8.2 or later). The rootfs image is flushed in place
+//! then, so file and image describe the same moment. The next
+//! `session.start` of a persistent session passes the file to QEMU as
+//! `-incoming`, and the guest carries on where it stopped, toolchains and
+//! caches included, instead of booting.
+//!
+//! A saved state is only resumed by a VM with the same command line and an
+//! untouched rootfs image, which is what the fingerprint recorded next to it
+//! covers, and only once: the guest moves on from it as soon as it runs.
+//! QEMU refuses to migrate a VM whose vhost-user-fs devices do not support
+//! migration; the save then fails with QEMU's reason and the next start
+//! boots afresh.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::qemu::QemuConfig;
+use crate::qmp::QmpClient;
+
+/// Directory under the undo directory holding the saved state.
+const STATE_DIR: &str = ".vm-state";
+
+/// How long saving or resuming the state may take.
+pub const STATE_TRANSFER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often migration progress is checked.
+const MIGRATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where a persistent session keeps its VM state between sessions.
+#[derive(Debug, Clone)]
+pub struct SavedVmState {
+    dir: PathBuf,
+}
+
+impl SavedVmState {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The saved state of sessions using `undo_dir`.
+    pub fn in_undo_dir(undo_dir: &Path) -> Self {
+        Self::new(undo_dir.join(STATE_DIR))
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.dir.join("state")
+    }
+
+    fn fingerprint_path(&self) -> PathBuf {
+        self.dir.join("fingerprint")
+    }
+
+    /// Whether a state has been saved, resumable or not.
+    pub fn exists(&self) -> bool {
+        self.state_path().is_file()
+    }
+
+    /// The saved state file, if a VM launched from `config` can resume it.
+    pub fn resumable_by(&self, config: &QemuConfig) -> Option<PathBuf> {
+        let recorded = fs::read_to_string(self.fingerprint_path()).ok()?;
+        let state = self.state_path();
+        (state.is_file() && fingerprint(config).ok()? == recorded.trim()).then_some(state)
+    }
+
+    /// Delete the saved state.
+    pub fn discard(&self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+
+    /// Pause the VM behind `qmp` and migrate its state into the file, for a
+    /// VM launched from `config` to resume. The VM stays paused.
+    pub fn save<S: Read + Write>(
+        &self,
+        qmp: &mut QmpClient<S>,
+        config: &QemuConfig,
+    ) -> io::Result<()> {
+        self.discard();
+        fs::create_dir_all(&self.dir)?;
+        let partial = self.dir.join("state.partial");
+        qmp.execute("stop", None)?;
+        qmp.execute(
+            "migrate",
+            Some(json!({ "uri": format!("file:{}", partial.display()) })),
+        )?;
+
+        let started = Instant::now();
+        loop {
+            let progress = qmp.execute("query-migrate", None)?;
+            match progress["status"].as_str() {
+                Some("completed") => break,
+                Some(status @ ("failed" | "cancelled")) => {
+                    let reason = progress["error-desc"].as_str().unwrap_or(status);
+                    return Err(io::Error::other(format!("migration {status}: {reason}")));
+                }
+                _ if started.elapsed() > STATE_TRANSFER_TIMEOUT => {
+                    let _ = qmp.execute("migrate_cancel", None);
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "migration did not finish within {}s",
+                            STATE_TRANSFER_TIMEOUT.as_secs()
+                        ),
+                    ));
+                }
+                _ => std::thread::sleep(MIGRATION_POLL_INTERVAL),
+            }
+        }
+
+        let fingerprint = fingerprint(config).map_err(io::Error::other)?;
+        fs::rename(&partial, self.state_path())?;
+        fs::write(self.fingerprint_path(), fingerprint)
+    }
+}
+
+/// Wait until the VM behind `qmp`, launched with `-incoming`, has loaded
+/// its saved state and runs.
+pub fn wait_for_resume<S: Read + Write>(qmp: &mut QmpClient<S>) -> io::Result<()> {
+    let started = Instant::now();
+    loop {
+        let status = qmp.execute("query-status", None)?;
+        match status["status"].as_str() {
+            Some("running") => return Ok(()),
+            Some("inmigrate") if started.elapsed() <= STATE_TRANSFER_TIMEOUT => {
+                std::thread::sleep(MIGRATION_POLL_INTERVAL);
+            }
+            Some("inmigrate") => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "saved state did not load within {}s",
+                        STATE_TRANSFER_TIMEOUT.as_secs()
+                    ),
+                ));
+            }
+            other => {
+                return Err(io::Error::other(format!(
+                    "VM is {} after loading the saved state",
+                    other.unwrap_or("in an unknown state")
+                )));
+            }
+        }
+    }
+}
+
+/// What a saved state must match to be resumed: the QEMU command line
+/// (minus `-incoming`) and the rootfs image's size and modification time.
+fn fingerprint(config: &QemuConfig) -> Result<String, crate::error::AgentError> {
+    let mut config = config.clone();
+    config.incoming_state = None;
+    let (binary, args) = config.build_args()?;
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(binary.as_os_str().as_encoded_bytes());
+    for arg in &args {
+        hasher.update(b"\0");
+        hasher.update(arg.as_encoded_bytes());
+    }
+    if let Some(rootfs) = &config.rootfs_path {
+        let metadata = fs::metadata(rootfs)?;
+        hasher.update(&metadata.len().to_le_bytes());
+        if let Ok(elapsed) = metadata
+            .modified()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).map_err(io::Error::other))
+        {
+            hasher.update(&elapsed.as_nanos().to_le_bytes());
+        }
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::qemu::generate_mount_names;
+    use crate::qmp::testing::fake_qemu;
+
+    fn config(dir: &Path) -> QemuConfig {
+        let working_dirs = vec![dir.join("work")];
+        QemuConfig {
+            qemu_binary: Some(PathBuf::from("/usr/bin/qemu-system-x86_64")),
+            kernel_path: PathBuf::from("/boot/vmlinuz"),
+            initrd_path: PathBuf::from("/boot/initrd.img"),
+            rootfs_path: None,
+            memory_mb: 512,
+            cpus: 1,
+            mount_names: generate_mount_names(&working_dirs),
+            working_dirs,
+            control_socket_path: dir.join("control.sock"),
+            fs_socket_paths: vec![dir.join("vfs0.sock")],
+            fs_transports: vec![],
+            vm_mode: "persistent".to_string(),
+            qmp_socket_path: Some(dir.join("qmp.sock")),
+            incoming_state: None,
+            hotplug_ports: 0,
+            dax_window_mb: None,
+            extra_args: vec![],
+        }
+    }
+
+    #[test]
+    fn save_migrates_into_the_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let saved = SavedVmState::in_undo_dir(dir.path());
+        let (stream, qemu) = fake_qemu(vec![
+            json!({ "return": {} }),
+            json!({ "return": {} }),
+            json!({ "return": { "status": "active" } }),
+            json!({ "return": { "status": "completed" } }),
+        ]);
+        let mut qmp = QmpClient::handshake(stream).unwrap();
+        saved.save(&mut qmp, &config).unwrap();
+        drop(qmp);
+
+        assert_eq!(
+            qemu.join().unwrap(),
+            ["qmp_capabilities", "stop", "migrate", "query-migrate", "query-migrate"]
+        );
+        assert_eq!(saved.resumable_by(&config), Some(saved.state_path()));
+    }
+
+    #[test]
+    fn failed_migration_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = SavedVmState::in_undo_dir(dir.path());
+        let (stream, _qemu) = fake_qemu(vec![
+            json!({ "return": {} }),
+            json!({
+                "error": { "class": "GenericError", "desc": "vhost-user-fs blocks migration" }
+            }),
+        ]);
+        let mut qmp = QmpClient::handshake(stream).unwrap();
+        let error = saved.save(&mut qmp, &config(dir.path())).unwrap_err();
+        assert_eq!(error.to_string(), "migrate: vhost-user-fs blocks migration");
+        assert!(!saved.exists());
+    }
+
+    #[test]
+    fn resume_waits_for_the_vm_to_run() {
+        let (stream, qemu) = fake_qemu(vec![
+            json!({ "return": { "status": "inmigrate" } }),
+            json!({ "return": { "status": "running" } }),
+        ]);
+        let mut qmp = QmpClient::handshake(stream).unwrap();
+        wait_for_resume(&mut qmp).unwrap();
+        drop(qmp);
+        assert_eq!(qemu.join().unwrap().len(), 3);
+
+        let (stream, _qemu) = fake_qemu(vec![json!({ "return": { "status": "paused" } })]);
+        let mut qmp = QmpClient::handshake(stream).unwrap();
+        assert!(wait_for_resume(&mut qmp).is_err());
+    }
+
+    #[test]
+    fn state_is_only_resumable_by_the_same_vm() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let saved = SavedVmState::in_undo_dir(dir.path());
+        fs::create_dir_all(&saved.dir).unwrap();
+        fs::write(saved.state_path(), "ram").unwrap();
+        fs::write(saved.fingerprint_path(), fingerprint(&config).unwrap()).unwrap();
+        assert!(saved.resumable_by(&config).is_some());
+
+        let mut resuming = config.clone();
+        resuming.incoming_state = Some(saved.state_path());
+        assert!(saved.resumable_by(&resuming).is_some());
+
+        let mut bigger = config.clone();
+        bigger.memory_mb = 1024;
+        assert_eq!(saved.resumable_by(&bigger), None);
+
+        saved.discard();
+        assert!(!saved.exists());
+        assert_eq!(saved.resumable_by(&config), None);
+    }
+}
@@ -0,0 +1,188 @@
+//! VMs booted ahead of `session.start`, for `--vm-pool-size`.
+//!
+//! Booting the guest kernel takes seconds. A pool boots VMs without working
+//! directories when the sandbox starts, each from its own directory under
+//! `{undo_dir}/.pool/`. `session.start` takes one, starts the session's
+//! filesystem backends, hot-plugs a vhost-user-fs device per working
+//! directory into the VM's spare PCIe root ports and has the shim mount
+//! them. A VM never returns to the pool: it stops with its session, and
+//! taking it starts a replacement booting in the background.
+//!
+//! Sessions whose mounts are not all vhost-user (9P on Windows), that have
+//! more than [`HOTPLUG_PORTS`] working directories, or that resume a saved
+//! VM state boot their own VM, as without a pool.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use codeagent_stdio::log_error;
+
+use crate::error::AgentError;
+
+/// Root ports each pooled VM gets, and so the most working directories a
+/// session on one can have.
+pub const HOTPLUG_PORTS: u32 = 8;
+
+/// Boots a pool VM whose sockets go in the given directory.
+pub type BootVm<V> = Arc<dyn Fn(&Path) -> Result<V, AgentError> + Send + Sync>;
+
+/// A booted VM and the directory holding its sockets.
+pub struct WarmVm<V> {
+    pub vm: V,
+    pub dir: PathBuf,
+}
+
+struct PoolState<V> {
+    ready: VecDeque<WarmVm<V>>,
+    booting: usize,
+    next_dir: u64,
+    /// A boot failed; booting more would most likely fail the same way.
+    failed: bool,
+}
+
+/// Keeps up to `size` booted VMs ready to take.
+pub struct WarmPool<V> {
+    size: usize,
+    root: PathBuf,
+    boot: BootVm<V>,
+    state: Mutex<PoolState<V>>,
+}
+
+impl<V: Send + 'static> WarmPool<V> {
+    /// A pool booting its VMs with `boot` under `root`, which is cleared of
+    /// whatever a previous run left there. Nothing boots until
+    /// [`fill`](Self::fill).
+    pub fn new(size: usize, root: PathBuf, boot: BootVm<V>) -> Arc<Self> {
+        let _ = std::fs::remove_dir_all(&root);
+        Arc::new(Self {
+            size,
+            root,
+            boot,
+            state: Mutex::new(PoolState {
+                ready: VecDeque::new(),
+                booting: 0,
+                next_dir: 0,
+                failed: false,
+            }),
+        })
+    }
+
+    /// Start booting VMs in the background until `size` are ready or
+    /// booting. Does nothing once a boot has failed.
+    pub fn fill(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while !state.failed && state.ready.len() + state.booting < self.size {
+            state.booting += 1;
+            let dir = self.root.join(state.next_dir.to_string());
+            state.next_dir += 1;
+            let pool = Arc::clone(self);
+            std::thread::spawn(move || pool.boot_into(dir));
+        }
+    }
+
+    fn boot_into(&self, dir: PathBuf) {
+        let booted = std::fs::create_dir_all(&dir)
+            .map_err(AgentError::from)
+            .and_then(|()| (self.boot)(&dir));
+        let mut state = self.state.lock().unwrap();
+        state.booting -= 1;
+        match booted {
+            Ok(vm) => state.ready.push_back(WarmVm { vm, dir }),
+            Err(error) => {
+                state.failed = true;
+                let _ = std::fs::remove_dir_all(&dir);
+                log_error!("warm_pool", "failed to boot a pool VM, not booting more: {error}");
+            }
+        }
+    }
+
+    /// The longest-ready VM, if any is ready. Taking one starts booting its
+    /// replacement.
+    pub fn take(self: &Arc<Self>) -> Option<WarmVm<V>> {
+        let taken = self.state.lock().unwrap().ready.pop_front();
+        if taken.is_some() {
+            self.fill();
+        }
+        taken
+    }
+
+    /// How many VMs are ready to take.
+    pub fn ready(&self) -> usize {
+        self.state.lock().unwrap().ready.len()
+    }
+}
+
+impl<V> Drop for WarmPool<V> {
+    fn drop(&mut self) {
+        // Stop the VMs before their directories go.
+        self.state.lock().unwrap().ready.clear();
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// Wait until `pool` has `count` VMs ready and nothing booting.
+    fn wait_for_ready<V: Send + 'static>(pool: &WarmPool<V>, count: usize) {
+        let started = Instant::now();
+        loop {
+            {
+                let state = pool.state.lock().unwrap();
+                if state.ready.len() == count && state.booting == 0 {
+                    return;
+                }
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "pool never filled");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn taking_a_vm_boots_its_replacement() {
+        let root = tempfile::tempdir().unwrap();
+        let boots = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&boots);
+        let pool = WarmPool::new(
+            2,
+            root.path().join(".pool"),
+            Arc::new(move |dir: &Path| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(dir.to_path_buf())
+            }),
+        );
+        pool.fill();
+        wait_for_ready(&pool, 2);
+
+        let taken = pool.take().unwrap();
+        assert_eq!(taken.vm, taken.dir);
+        assert!(taken.dir.is_dir());
+        wait_for_ready(&pool, 2);
+        assert_eq!(boots.load(Ordering::SeqCst), 3);
+
+        drop(pool);
+        assert!(!root.path().join(".pool").exists());
+    }
+
+    #[test]
+    fn a_failed_boot_stops_the_pool() {
+        let root = tempfile::tempdir().unwrap();
+        let pool: Arc<WarmPool<()>> = WarmPool::new(
+            3,
+            root.path().join(".pool"),
+            Arc::new(|_: &Path| Err(AgentError::QemuUnavailable)),
+        );
+        pool.fill();
+        wait_for_ready(&pool, 0);
+        assert!(pool.state.lock().unwrap().failed);
+        assert!(pool.take().is_none());
+
+        pool.fill();
+        assert_eq!(pool.state.lock().unwrap().booting, 0);
+    }
+}
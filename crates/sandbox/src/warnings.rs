@@ -0,0 +1,76 @@
+use std::sync::{Arc, Mutex};
+
+use codeagent_common::SandboxWarning;
+use codeagent_stdio::Event;
+use tokio::sync::mpsc;
+
+/// Emits `event.warning` and keeps the persistent warnings of the current
+/// session for `session.warnings`.
+///
+/// Clones share the same list, so the filesystem watcher and the
+/// orchestrator report into one place.
+#[derive(Clone)]
+pub struct WarningReporter {
+    event_sender: mpsc::UnboundedSender<Event>,
+    active: Arc<Mutex<Vec<SandboxWarning>>>,
+}
+
+impl WarningReporter {
+    pub fn new(event_sender: mpsc::UnboundedSender<Event>) -> Self {
+        Self {
+            event_sender,
+            active: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Send the warning as an event, remembering it if it is persistent.
+    /// A persistent warning equal to one already active is not listed twice.
+    pub fn report(&self, warning: SandboxWarning) {
+        if warning.is_persistent() {
+            let mut active = self.active.lock().unwrap();
+            if !active.contains(&warning) {
+                active.push(warning.clone());
+            }
+        }
+        let _ = self.event_sender.send(Event::Warning { warning });
+    }
+
+    /// Persistent warnings reported since the last [`clear`](Self::clear),
+    /// in report order.
+    pub fn active(&self) -> Vec<SandboxWarning> {
+        self.active.lock().unwrap().clone()
+    }
+
+    /// Forget all persistent warnings (the session they described ended).
+    pub fn clear(&self) {
+        self.active.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_persistent_warnings_are_kept() {
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let reporter = WarningReporter::new(event_sender);
+        let failed = SandboxWarning::VmLaunchFailed {
+            reason: "no kvm".to_string(),
+        };
+
+        reporter.report(failed.clone());
+        reporter.clone().report(failed.clone());
+        reporter.report(SandboxWarning::FileWatcherOverflow);
+
+        assert_eq!(reporter.active(), vec![failed]);
+        let mut sent = 0;
+        while events.try_recv().is_ok() {
+            sent += 1;
+        }
+        assert_eq!(sent, 3);
+
+        reporter.clear();
+        assert!(reporter.active().is_empty());
+    }
+}
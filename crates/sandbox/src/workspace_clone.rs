@@ -0,0 +1,187 @@
+//! Copy a working directory and its undo history for `session.clone`.
+//!
+//! The clone is a plain directory tree plus an undo subdirectory laid out
+//! exactly as the interceptor writes it, so a later `session.start` on the
+//! clone picks up the copied steps like any restarted session. File contents
+//! are shared with the source through a reflink when the filesystem supports
+//! it and copied otherwise.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What [`clone_tree`] did with the regular files it visited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CloneStats {
+    /// Files whose contents were shared with the source.
+    pub reflinked: u64,
+    /// Files whose contents were copied byte for byte.
+    pub copied: u64,
+}
+
+/// Recursively copy `source` into `target`, which must not exist yet or be
+/// an empty directory. Symlinks are recreated as links rather than followed,
+/// and file permissions and modification times are preserved.
+pub fn clone_tree(source: &Path, target: &Path) -> io::Result<CloneStats> {
+    let mut stats = CloneStats::default();
+    clone_tree_into(source, target, &[], &mut stats)?;
+    Ok(stats)
+}
+
+/// Copy the single entry `source` to `target`, which must not exist: a
+/// symlink as a link, a directory with everything under it, or a file.
+pub fn clone_path(source: &Path, target: &Path) -> io::Result<CloneStats> {
+    let mut stats = CloneStats::default();
+    let file_type = fs::symlink_metadata(source)?.file_type();
+    clone_entry(source, target, file_type, &mut stats)?;
+    Ok(stats)
+}
+
+/// Copy a working directory's undo data, leaving out the write-ahead log.
+///
+/// The clone is only taken between steps, so the WAL holds nothing worth
+/// keeping; the interceptor recreates an empty one when it opens the copy.
+pub fn clone_undo_dir(source: &Path, target: &Path) -> io::Result<()> {
+    if !source.exists() {
+        return Ok(());
+    }
+    clone_tree_into(source, target, &["wal"], &mut CloneStats::default())
+}
+
+fn clone_tree_into(
+    source: &Path,
+    target: &Path,
+    skip: &[&str],
+    stats: &mut CloneStats,
+) -> io::Result<()> {
+    fs::create_dir_all(target)?;
+
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if skip.iter().any(|s| name == *s) {
+            continue;
+        }
+        clone_entry(&entry.path(), &target.join(&name), entry.file_type()?, stats)?;
+    }
+    // Applied last so a read-only source directory can still be filled.
+    fs::set_permissions(target, fs::metadata(source)?.permissions())
+}
+
+/// Copy one entry of type `file_type`. Sockets, FIFOs and devices are
+/// skipped.
+fn clone_entry(
+    from: &Path,
+    to: &Path,
+    file_type: fs::FileType,
+    stats: &mut CloneStats,
+) -> io::Result<()> {
+    if file_type.is_symlink() {
+        copy_symlink(from, to)?;
+    } else if file_type.is_dir() {
+        clone_tree_into(from, to, &[], stats)?;
+    } else if file_type.is_file() {
+        if clone_file(from, to)? {
+            stats.reflinked += 1;
+        } else {
+            stats.copied += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Copy one regular file, returning whether its contents were reflinked.
+fn clone_file(from: &Path, to: &Path) -> io::Result<bool> {
+    let metadata = fs::metadata(from)?;
+    let reflinked = reflink(from, to).is_ok();
+    if !reflinked {
+        fs::copy(from, to)?;
+    }
+    if let Ok(modified) = metadata.modified() {
+        fs::File::options().write(true).open(to)?.set_modified(modified)?;
+    }
+    fs::set_permissions(to, metadata.permissions())?;
+    Ok(reflinked)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source = fs::File::open(from)?;
+    let destination = fs::File::create_new(to)?;
+    let result = unsafe { libc::ioctl(destination.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    if result == 0 {
+        return Ok(());
+    }
+    let error = io::Error::last_os_error();
+    drop(destination);
+    let _ = fs::remove_file(to);
+    Err(error)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(windows)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    let target = fs::read_link(from)?;
+    if fs::metadata(from).is_ok_and(|m| m.is_dir()) {
+        std::os::windows::fs::symlink_dir(target, to)
+    } else {
+        std::os::windows::fs::symlink_file(target, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_contents_and_links_are_copied() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        fs::create_dir_all(source.path().join("src/nested")).unwrap();
+        fs::write(source.path().join("README.md"), b"readme").unwrap();
+        fs::write(source.path().join("src/nested/lib.rs"), b"fn main() {}").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("README.md", source.path().join("link")).unwrap();
+
+        let clone = target.path().join("clone");
+        let stats = clone_tree(source.path(), &clone).unwrap();
+
+        assert_eq!(stats.reflinked + stats.copied, 2);
+        assert_eq!(fs::read(clone.join("README.md")).unwrap(), b"readme");
+        assert_eq!(fs::read(clone.join("src/nested/lib.rs")).unwrap(), b"fn main() {}");
+        #[cfg(unix)]
+        assert_eq!(fs::read_link(clone.join("link")).unwrap(), Path::new("README.md"));
+        assert_eq!(
+            fs::metadata(clone.join("README.md")).unwrap().modified().unwrap(),
+            fs::metadata(source.path().join("README.md")).unwrap().modified().unwrap(),
+        );
+    }
+
+    #[test]
+    fn undo_clone_skips_the_wal() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("version"), b"1").unwrap();
+        fs::create_dir_all(source.path().join("wal/in_progress")).unwrap();
+        fs::create_dir_all(source.path().join("steps/1")).unwrap();
+        fs::write(source.path().join("steps/1/manifest.json"), b"{}").unwrap();
+
+        let clone = target.path().join("undo");
+        clone_undo_dir(source.path(), &clone).unwrap();
+
+        assert!(clone.join("version").exists());
+        assert!(clone.join("steps/1/manifest.json").exists());
+        assert!(!clone.join("wal").exists());
+    }
+}
@@ -11,9 +11,10 @@ use tokio::sync::mpsc;
 
 use codeagent_common::StepId;
 use codeagent_control::{
-    ControlChannelHandler, HandlerEvent, InFlightTracker, OutputStream, QuiescenceConfig,
-    StepManager, VmMessage,
+    ControlChannelHandler, HandlerEvent, HostMessage, InFlightTracker, OutputStream,
+    QuiescenceConfig, StepManager, VmMessage, lane_channel,
 };
+use codeagent_sandbox::command_timeout::{CommandTimeout, CommandTimeouts};
 use codeagent_sandbox::command_waiter::CommandWaiter;
 use codeagent_sandbox::event_bridge::run_event_bridge;
 
@@ -124,7 +125,7 @@ async fn cp_04_event_bridge_forwards_completion() {
     let (stdio_tx, _stdio_rx) = mpsc::unbounded_channel::<codeagent_stdio::Event>();
 
     // Spawn the event bridge with the command waiter.
-    tokio::spawn(run_event_bridge(event_rx, stdio_tx, Some(waiter.clone())));
+    tokio::spawn(run_event_bridge(event_rx, stdio_tx, Some(waiter.clone()), None, None, None));
 
     // Send output followed by completion.
     event_tx
@@ -140,6 +141,8 @@ async fn cp_04_event_bridge_forwards_completion() {
             exit_code: 0,
             cancelled: false,
             evicted_steps: vec![],
+            output_truncated: false,
+            limit_exceeded: None,
         })
         .unwrap();
 
@@ -173,6 +176,10 @@ async fn run_exec_cycle(
             "rm file.txt".to_string(),
             None,
             None,
+            false,
+            false,
+            None,
+            Default::default(),
         )
         .await;
 
@@ -187,6 +194,8 @@ async fn run_exec_cycle(
         .handle_vm_message(VmMessage::StepCompleted {
             id: command_id,
             exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
         })
         .await;
 }
@@ -314,6 +323,9 @@ async fn cp_07_full_pipeline_simulation() {
         handler_events,
         stdio_tx,
         Some(waiter.clone()),
+        None,
+        None,
+        None,
     ));
 
     // Step 1: Register the command with the waiter (orchestrator does this).
@@ -321,7 +333,16 @@ async fn cp_07_full_pipeline_simulation() {
 
     // Step 2: Register with handler state machine (orchestrator does this).
     let _host_msg = handler
-        .send_exec(1, "rm -f /tmp/file".to_string(), None, None)
+        .send_exec(
+            1,
+            "rm -f /tmp/file".to_string(),
+            None,
+            None,
+            false,
+            false,
+            None,
+            Default::default(),
+        )
         .await;
 
     // Step 3: Simulate VM responses (control reader task does this).
@@ -349,6 +370,8 @@ async fn cp_07_full_pipeline_simulation() {
         .handle_vm_message(VmMessage::StepCompleted {
             id: 1,
             exit_code: 0,
+            output_truncated: false,
+            limit_exceeded: None,
         })
         .await;
 
@@ -371,3 +394,283 @@ async fn cp_07_full_pipeline_simulation() {
         vec![StepManagerCall::OpenStep(1), StepManagerCall::CloseStep(1)]
     );
 }
+
+// ===========================================================================
+// Test 6: STDIO events carry the command ID
+// ===========================================================================
+
+/// Output and completion of command steps are tagged with the `command_id`
+/// of `agent.execute`; output of ambient steps is not.
+#[test]
+fn cp_08_events_are_tagged_with_command_id() {
+    use codeagent_sandbox::event_bridge::translate_handler_event;
+    use codeagent_stdio::Event;
+
+    let output = |step_id| {
+        translate_handler_event(&HandlerEvent::Output {
+            step_id,
+            stream: OutputStream::Stderr,
+            data: "warning\n".to_string(),
+        })
+    };
+    match output(7) {
+        Some(Event::TerminalOutput {
+            command_id, stream, ..
+        }) => assert_eq!((command_id, stream.as_str()), (Some(7), "stderr")),
+        other => panic!("expected terminal output, got {other:?}"),
+    }
+    assert!(matches!(
+        output(-1),
+        Some(Event::TerminalOutput { command_id: None, .. })
+    ));
+
+    let completed = translate_handler_event(&HandlerEvent::StepCompleted {
+        step_id: 7,
+        exit_code: 2,
+        cancelled: false,
+        evicted_steps: vec![],
+        output_truncated: false,
+        limit_exceeded: None,
+    });
+    assert!(matches!(
+        completed,
+        Some(Event::StepCompleted {
+            command_id: Some(7),
+            exit_code: 2,
+            ..
+        })
+    ));
+}
+
+// ===========================================================================
+// Test 7: Command timeouts
+// ===========================================================================
+
+/// A command still running at its timeout is cancelled in the guest, and
+/// `event.command_timed_out` follows once its step closes.
+#[tokio::test(start_paused = true)]
+async fn cp_09_timed_out_command_is_cancelled() {
+    let step_manager: Arc<dyn StepManager> = Arc::new(MockStepManager::default());
+    let (handler, handler_events) = ControlChannelHandler::new(
+        step_manager,
+        InFlightTracker::new(),
+        QuiescenceConfig::default(),
+    );
+    let handler = Arc::new(handler);
+    let (stdio_tx, mut stdio_rx) = mpsc::unbounded_channel::<codeagent_stdio::Event>();
+    let (control_tx, mut control_rx) = lane_channel::<HostMessage>();
+    let timeouts = CommandTimeouts::new();
+    tokio::spawn(run_event_bridge(
+        handler_events,
+        stdio_tx.clone(),
+        None,
+        Some(timeouts.clone()),
+        None,
+        None,
+    ));
+
+    let closed = timeouts.watch(3);
+    handler
+        .send_exec(3, "sleep 100".to_string(), None, None, false, false, None, Default::default())
+        .await;
+    handler
+        .handle_vm_message(VmMessage::StepStarted { id: 3 })
+        .await;
+    let timer = CommandTimeout {
+        command_id: 3,
+        timeout_seconds: 10,
+        control_writer: control_tx,
+        control_handler: Arc::clone(&handler),
+        rollback: Vec::new(),
+        recent_writes: None,
+        event_sender: stdio_tx,
+    };
+    let timer = tokio::spawn(timer.run(timeouts.clone(), closed));
+
+    let cancel = control_rx.recv().await.unwrap();
+    assert_eq!(cancel, HostMessage::Cancel { id: 3 });
+    handler
+        .handle_vm_message(VmMessage::StepCompleted {
+            id: 3,
+            exit_code: -1,
+            output_truncated: false,
+            limit_exceeded: None,
+        })
+        .await;
+    timer.await.unwrap();
+
+    let timed_out = loop {
+        match stdio_rx.recv().await.unwrap() {
+            codeagent_stdio::Event::CommandTimedOut {
+                command_id,
+                rolled_back,
+                error,
+                ..
+            } => break (command_id, rolled_back, error),
+            _ => continue,
+        }
+    };
+    assert_eq!(timed_out, (3, false, None));
+}
+
+// ===========================================================================
+// Test 8: Secret redaction
+// ===========================================================================
+
+/// Secret values of the session's env profile are redacted from output
+/// before it reaches the STDIO events or the command waiter.
+#[tokio::test]
+async fn cp_10_event_bridge_redacts_secrets() {
+    use codeagent_sandbox::env_profile::EnvProfile;
+
+    let waiter = CommandWaiter::new();
+    waiter.register(5);
+    let profile = EnvProfile::new();
+    profile.set("API_KEY".to_string(), "hunter2".to_string(), true);
+
+    let (event_tx, event_rx) = mpsc::unbounded_channel::<HandlerEvent>();
+    let (stdio_tx, mut stdio_rx) = mpsc::unbounded_channel::<codeagent_stdio::Event>();
+    tokio::spawn(run_event_bridge(
+        event_rx,
+        stdio_tx,
+        Some(waiter.clone()),
+        None,
+        Some(profile),
+        None,
+    ));
+
+    event_tx
+        .send(HandlerEvent::Output {
+            step_id: 5,
+            stream: OutputStream::Stdout,
+            data: "token=hunter2\n".to_string(),
+        })
+        .unwrap();
+    match stdio_rx.recv().await.unwrap() {
+        codeagent_stdio::Event::TerminalOutput { data, .. } => {
+            assert_eq!(data, "token=[REDACTED]\n");
+        }
+        other => panic!("expected terminal output, got {other:?}"),
+    }
+    waiter.mark_completed(5, 0);
+    let result = waiter.wait_for_completion(5, Duration::from_secs(1)).unwrap();
+    assert_eq!(result.stdout, "token=[REDACTED]\n");
+}
+
+// ===========================================================================
+// Test 9: Shim restarts
+// ===========================================================================
+
+/// A shim restart is reported as a warning and places a barrier in each
+/// working directory's undo log.
+#[tokio::test]
+async fn cp_11_shim_restart_warns_and_places_barrier() {
+    use codeagent_common::{BarrierReason, SandboxWarning};
+    use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+    use codeagent_sandbox::event_bridge::GuestReporting;
+    use codeagent_sandbox::warnings::WarningReporter;
+
+    let working = tempfile::TempDir::new().unwrap();
+    let undo = tempfile::TempDir::new().unwrap();
+    let interceptor = Arc::new(UndoInterceptor::new_default(
+        working.path().to_path_buf(),
+        undo.path().to_path_buf(),
+    ));
+
+    let (event_tx, event_rx) = mpsc::unbounded_channel::<HandlerEvent>();
+    let (stdio_tx, mut stdio_rx) = mpsc::unbounded_channel::<codeagent_stdio::Event>();
+    tokio::spawn(run_event_bridge(
+        event_rx,
+        stdio_tx.clone(),
+        None,
+        None,
+        None,
+        Some(GuestReporting {
+            warnings: WarningReporter::new(stdio_tx),
+            interceptors: vec![interceptor.clone()],
+        }),
+    ));
+
+    event_tx
+        .send(HandlerEvent::ShimRestarted {
+            exit_code: None,
+            signal: Some(6),
+            stderr: vec!["fatal runtime error: stack overflow".to_string()],
+            restarts: 1,
+            lost_steps: vec![4],
+        })
+        .unwrap();
+    match stdio_rx.recv().await.unwrap() {
+        codeagent_stdio::Event::Warning { warning } => {
+            assert_eq!(
+                warning,
+                SandboxWarning::ShimRestarted {
+                    exit_code: None,
+                    signal: Some(6),
+                    stderr: vec!["fatal runtime error: stack overflow".to_string()],
+                    restarts: 1,
+                }
+            );
+            assert!(warning.message().contains("signal 6"), "{}", warning.message());
+        }
+        other => panic!("expected a warning, got {other:?}"),
+    }
+    let barriers = interceptor.barriers();
+    assert_eq!(barriers.len(), 1);
+    assert_eq!(barriers[0].reason, BarrierReason::ShimRestarted);
+    assert!(barriers[0].affected_paths.is_empty());
+}
+
+/// Shares the shim could not mount are reported as warnings, one per share,
+/// without touching the undo log.
+#[tokio::test]
+async fn cp_12_failed_mounts_warn() {
+    use codeagent_common::SandboxWarning;
+    use codeagent_control::MountFailure;
+    use codeagent_interceptor::undo_interceptor::UndoInterceptor;
+    use codeagent_sandbox::event_bridge::GuestReporting;
+    use codeagent_sandbox::warnings::WarningReporter;
+
+    let working = tempfile::TempDir::new().unwrap();
+    let undo = tempfile::TempDir::new().unwrap();
+    let interceptor = Arc::new(UndoInterceptor::new_default(
+        working.path().to_path_buf(),
+        undo.path().to_path_buf(),
+    ));
+
+    let (event_tx, event_rx) = mpsc::unbounded_channel::<HandlerEvent>();
+    let (stdio_tx, mut stdio_rx) = mpsc::unbounded_channel::<codeagent_stdio::Event>();
+    let warnings = WarningReporter::new(stdio_tx.clone());
+    tokio::spawn(run_event_bridge(
+        event_rx,
+        stdio_tx,
+        None,
+        None,
+        None,
+        Some(GuestReporting {
+            warnings: warnings.clone(),
+            interceptors: vec![interceptor.clone()],
+        }),
+    ));
+
+    event_tx
+        .send(HandlerEvent::MountsFailed {
+            failed: vec![MountFailure {
+                tag: "app".to_string(),
+                reason: "No such file or directory (os error 2)".to_string(),
+            }],
+        })
+        .unwrap();
+    match stdio_rx.recv().await.unwrap() {
+        codeagent_stdio::Event::Warning { warning } => assert_eq!(
+            warning,
+            SandboxWarning::ShareNotMounted {
+                mount_name: "app".to_string(),
+                reason: "No such file or directory (os error 2)".to_string(),
+            }
+        ),
+        other => panic!("expected a warning, got {other:?}"),
+    }
+    assert_eq!(warnings.active().len(), 1);
+    assert!(interceptor.barriers().is_empty());
+}
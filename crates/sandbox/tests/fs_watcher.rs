@@ -7,6 +7,7 @@ use tokio::sync::mpsc;
 use codeagent_sandbox::config::FileWatcherConfig;
 use codeagent_sandbox::fs_watcher::{self, FsWatcherConfig};
 use codeagent_sandbox::recent_writes::RecentBackendWrites;
+use codeagent_sandbox::warnings::WarningReporter;
 use codeagent_stdio::Event;
 
 /// Helper: drain events from the receiver with a short timeout.
@@ -48,6 +49,7 @@ async fn fw_01_external_creation_detected() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes,
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -109,6 +111,7 @@ async fn fw_02_backend_writes_suppressed() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes.clone(),
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -158,6 +161,7 @@ async fn fw_03_disabled_watcher_returns_none() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes,
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -186,6 +190,7 @@ async fn fw_04_excluded_patterns_filtered() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes,
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -235,6 +240,7 @@ async fn fw_05_undo_dir_changes_filtered() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes,
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -277,6 +283,7 @@ async fn fw_06_events_emitted_without_completed_steps() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes,
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -324,6 +331,7 @@ async fn fw_07_ttl_expiry_allows_detection() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes.clone(),
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -380,6 +388,7 @@ async fn fw_08_multiple_working_dirs() {
         vec![undo.path().join("dir1"), undo.path().join("dir2")],
         vec![],
         recent_writes,
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -521,6 +530,7 @@ async fn fw_13_gitignored_paths_filtered() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes,
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -608,6 +618,7 @@ async fn fw_14_gitignore_disabled() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes,
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -669,6 +680,7 @@ async fn fw_15_parent_dir_deduplication() {
         vec![undo.path().to_path_buf()],
         vec![],
         recent_writes,
+        WarningReporter::new(event_sender.clone()),
         event_sender,
         config,
     );
@@ -718,3 +730,163 @@ async fn fw_15_parent_dir_deduplication() {
         "parent directory should be deduplicated out, got: {external_paths:?}"
     );
 }
+
+// -----------------------------------------------------------------------
+// FW-16: external modification rules drop ignored paths and pick barrier paths
+// -----------------------------------------------------------------------
+#[tokio::test]
+async fn fw_16_external_modification_rules_applied() {
+    use codeagent_common::{
+        ExternalModificationConfig, ExternalModificationPolicy, ExternalModificationRule,
+    };
+    use codeagent_interceptor::undo_interceptor::{UndoConfig, UndoInterceptor};
+
+    let working = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    let target_dir = working.path().join("target");
+    std::fs::create_dir_all(&target_dir).unwrap();
+
+    let interceptor = Arc::new(UndoInterceptor::new(
+        working.path().to_path_buf(),
+        undo.path().to_path_buf(),
+        UndoConfig {
+            external_modification: ExternalModificationConfig {
+                default_policy: ExternalModificationPolicy::Barrier,
+                rules: vec![ExternalModificationRule {
+                    pattern: "target*".to_string(),
+                    policy: ExternalModificationPolicy::Ignore,
+                }],
+            },
+            ..Default::default()
+        },
+    ));
+
+    let recent_writes = Arc::new(RecentBackendWrites::new(Duration::from_secs(5)));
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+
+    let config = FsWatcherConfig {
+        debounce: Duration::from_millis(200),
+        exclude_patterns: vec![],
+        ..FsWatcherConfig::default()
+    };
+
+    let handle = fs_watcher::spawn_fs_watcher(
+        vec![working.path().to_path_buf()],
+        vec![undo.path().to_path_buf()],
+        vec![interceptor.clone()],
+        recent_writes,
+        WarningReporter::new(event_sender.clone()),
+        event_sender,
+        config,
+    );
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    std::fs::write(target_dir.join("app.rlib"), "build output").unwrap();
+    std::fs::write(working.path().join("main.rs"), "fn main() {}").unwrap();
+
+    let events = collect_events(&mut event_receiver, Duration::from_secs(3)).await;
+
+    if let Some(h) = handle {
+        h.abort();
+    }
+
+    let reported: Vec<String> = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::ExternalModification { affected_paths, .. } => Some(affected_paths.clone()),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    assert!(
+        reported.iter().any(|p| p.contains("main.rs")),
+        "main.rs should be reported, got: {reported:?}"
+    );
+    assert!(
+        !reported.iter().any(|p| p.contains("app.rlib")),
+        "ignored build output should not be reported, got: {reported:?}"
+    );
+
+    let barriers = interceptor.barriers();
+    assert!(!barriers.is_empty(), "main.rs should create a barrier");
+    for barrier in &barriers {
+        assert!(
+            barrier.affected_paths.iter().all(|ap| !ap.path.starts_with(&target_dir)),
+            "ignored paths should not be recorded in a barrier"
+        );
+    }
+}
+
+// -----------------------------------------------------------------------
+// FW-17: editing .gitignore reloads the shared filter and reports it
+// -----------------------------------------------------------------------
+#[tokio::test]
+async fn fw_17_gitignore_edit_reloads_filter() {
+    use codeagent_interceptor::gitignore::GitignoreFilter;
+
+    let working = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    std::fs::write(working.path().join(".gitignore"), "*.log\n").unwrap();
+
+    let filter = Arc::new(GitignoreFilter::build(working.path()));
+    let recent_writes = Arc::new(RecentBackendWrites::new(Duration::from_secs(5)));
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+
+    let config = FsWatcherConfig {
+        debounce: Duration::from_millis(200),
+        exclude_patterns: vec![],
+        gitignore_filters: vec![filter.clone()],
+        ..FsWatcherConfig::default()
+    };
+
+    let handle = fs_watcher::spawn_fs_watcher(
+        vec![working.path().to_path_buf()],
+        vec![undo.path().to_path_buf()],
+        vec![],
+        recent_writes,
+        WarningReporter::new(event_sender.clone()),
+        event_sender,
+        config,
+    );
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    std::fs::write(working.path().join(".gitignore"), "*.tmp\n").unwrap();
+    let events = collect_events(&mut event_receiver, Duration::from_secs(1)).await;
+    let reloads: Vec<&Vec<String>> = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::IgnoresReloaded { sources, .. } => Some(sources),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(reloads, vec![&vec![".gitignore".to_string()]], "events: {events:?}");
+    assert!(filter.is_ignored("scratch.tmp", false));
+    assert!(!filter.is_ignored("debug.log", false));
+
+    std::fs::write(working.path().join("scratch.tmp"), "scratch").unwrap();
+    std::fs::write(working.path().join("debug.log"), "log data").unwrap();
+    let events = collect_events(&mut event_receiver, Duration::from_secs(2)).await;
+
+    if let Some(h) = handle {
+        h.abort();
+    }
+
+    let reported: Vec<String> = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::ExternalModification { affected_paths, .. } => Some(affected_paths.clone()),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    assert!(
+        reported.iter().any(|p| p.contains("debug.log")),
+        "no longer ignored, got: {reported:?}"
+    );
+    assert!(
+        !reported.iter().any(|p| p.contains("scratch.tmp")),
+        "ignored by the new rules, got: {reported:?}"
+    );
+}
@@ -2,12 +2,21 @@ use serde_json::json;
 use tempfile::TempDir;
 use tokio::sync::mpsc;
 
+use codeagent_common::{
+    CoherentCaptureConfig, CoherentCaptureRule, CoherentCaptureStrategy, GitMirrorConfig,
+    ReadEncoding, RollbackMode, SafeguardDecision, SafeguardEvent, SafeguardKind, SymlinkPolicy,
+    Unmonitored,
+};
 use codeagent_sandbox::cli::CliArgs;
 use codeagent_sandbox::command_classifier::CommandClassifierConfig;
 use codeagent_sandbox::config::FileWatcherConfig;
+use codeagent_sandbox::health::Readiness;
 use codeagent_sandbox::orchestrator::{undo_subdir_name, Orchestrator};
+use codeagent_sandbox::safeguard_log::{self, DecidedBy, SafeguardRecord};
 use codeagent_stdio::protocol::{
-    FsListPayload, FsReadPayload, SessionStartPayload, UndoHistoryPayload, UndoRollbackPayload,
+    FsListPayload, FsReadPayload, LogLevel, SafeguardHistoryPayload, SessionClonePayload,
+    SessionConfigurePayload, SessionStartPayload, UndoAttestPayload, UndoConfigurePayload, UndoExpectPayload,
+    UndoHistoryPayload, UndoMode, UndoRollbackPayload, UndoSquashPayload, VmInventoryPayload,
     WorkingDirectoryConfig,
 };
 use codeagent_stdio::{Event, RequestHandler};
@@ -18,7 +27,7 @@ fn make_args(working_dir: &std::path::Path, undo_dir: &std::path::Path) -> CliAr
         undo_dir: Some(undo_dir.to_path_buf()),
         vm_mode: "ephemeral".to_string(),
         protocol: "stdio".to_string(),
-        log_level: "info".to_string(),
+        log_level: LogLevel::Info,
         qemu_binary: None,
         kernel_path: None,
         initrd_path: None,
@@ -26,9 +35,32 @@ fn make_args(working_dir: &std::path::Path, undo_dir: &std::path::Path) -> CliAr
         memory_mb: 2048,
         cpus: 2,
         virtiofsd_binary: None,
+        virtiofs_dax_window_mb: None,
+        virtiofs_request_workers: 4,
+        vm_auto_restart: false,
+        vm_pool_size: 0,
+        quiescence_idle_timeout_ms: 100,
+        quiescence_max_timeout_ms: 2000,
+        ambient_inactivity_timeout_ms: 5000,
+        ambient_max_duration_ms: 60_000,
+        ambient_max_files: 1000,
+        max_sessions: 1,
         config_file: None,
         socket_path: None,
+        mcp_listen: None,
+        mcp_token_file: None,
+        agent_endpoint: None,
+        agent_model: None,
+        agent_api_key_env: "OPENAI_API_KEY".into(),
+        agent_command: None,
+        agent_args: Vec::new(),
+        agent_max_turns: 25,
+        health_socket: None,
+        health_probe: None,
+        print_schema: None,
         log_file: None,
+        log_rotate_mb: None,
+        metrics_listen: None,
         disable_builtin_tools: false,
         auto_allow_write_tools: false,
         server_name: "codeagent-sandbox".into(),
@@ -40,10 +72,20 @@ fn make_start_payload(path: &str) -> SessionStartPayload {
         working_directories: vec![WorkingDirectoryConfig {
             path: path.to_string(),
             label: None,
+            backend: None,
+            read_only: false,
+            exclude: vec![],
+            overlay: false,
         }],
         network_policy: "disabled".to_string(),
         vm_mode: "ephemeral".to_string(),
         protocol_version: None,
+        symlink_policy: None,
+        coherent_capture: None,
+        case_sensitivity: None,
+        undo: UndoMode::Enabled,
+        message_limits: None,
+        terminal_output: None,
     }
 }
 
@@ -113,11 +155,13 @@ fn ao_04_operations_before_start() {
         .undo_rollback(UndoRollbackPayload {
             count: 1,
             force: false,
+            strict: false,
+            mode: RollbackMode::Restore,
             directory: None,
-        })
+        }, &Unmonitored)
         .is_err());
     assert!(orchestrator
-        .undo_history(UndoHistoryPayload { directory: None })
+        .undo_history(UndoHistoryPayload::default())
         .is_err());
 }
 
@@ -185,7 +229,7 @@ fn ao_08_undo_history_empty() {
     let _ = orchestrator.session_start(payload);
 
     let history = orchestrator
-        .undo_history(UndoHistoryPayload { directory: None })
+        .undo_history(UndoHistoryPayload::default())
         .unwrap();
     assert_eq!(history["steps"], json!([]));
 }
@@ -203,8 +247,10 @@ fn ao_09_undo_rollback_empty() {
         .undo_rollback(UndoRollbackPayload {
             count: 1,
             force: false,
+            strict: false,
+            mode: RollbackMode::Restore,
             directory: None,
-        })
+        }, &Unmonitored)
         .unwrap();
     assert_eq!(result["steps_rolled_back"], 0);
 }
@@ -223,6 +269,9 @@ fn ao_10_fs_read() {
     let result = orchestrator
         .fs_read(FsReadPayload {
             path: "hello.txt".to_string(),
+            encoding: ReadEncoding::Utf8,
+            offset: 0,
+            length: None,
             directory: None,
         })
         .unwrap();
@@ -289,6 +338,7 @@ fn ao_12_crash_recovery_event() {
     if let Ok(Event::Recovery {
         paths_restored,
         paths_deleted,
+        ..
     }) = event_receiver.try_recv()
     {
         assert_eq!(paths_restored, 0);
@@ -310,6 +360,18 @@ fn ao_13_agent_execute_unavailable() {
             command: "echo hello".to_string(),
             env: None,
             cwd: None,
+            directory: None,
+            wait: true,
+            timeout_ms: None,
+            isolate_fs: true,
+            timeout_seconds: Some(30),
+            rollback_on_timeout: true,
+            pty: true,
+            max_output_bytes: Some(4096),
+            limits: codeagent_stdio::protocol::CommandLimits {
+                memory_bytes: Some(1 << 30),
+                ..Default::default()
+            },
         },
     );
     assert!(result.is_err());
@@ -319,7 +381,7 @@ fn ao_13_agent_execute_unavailable() {
 // MCP handler tests
 // -----------------------------------------------------------------------
 
-use codeagent_mcp::McpHandler;
+use codeagent_mcp::{McpError, McpHandler};
 use codeagent_mcp::protocol::{
     BashArgs, EditFileArgs, GetUndoHistoryArgs, GlobArgs, GrepArgs,
     ReadFileArgs, UndoArgs, WriteFileArgs,
@@ -336,6 +398,9 @@ fn mcp_01_read_file() {
     let result = orchestrator
         .read_file(ReadFileArgs {
             path: "test.txt".to_string(),
+            encoding: ReadEncoding::Utf8,
+            offset: 0,
+            length: None,
         })
         .unwrap();
     assert_eq!(result["content"], "mcp content");
@@ -363,6 +428,7 @@ fn mcp_04_undo_no_steps() {
         .undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         })
         .unwrap();
     assert_eq!(result["steps_rolled_back"], 0);
@@ -426,6 +492,7 @@ fn mcp_07_edit_file_undo() {
         .undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         })
         .unwrap();
 
@@ -609,6 +676,9 @@ fn ao_14_no_vm_components_falls_back_to_host_mode() {
     // Session should be fully functional for host-only operations
     let read_result = orchestrator.fs_read(FsReadPayload {
         path: "nonexistent.txt".to_string(),
+        encoding: ReadEncoding::Utf8,
+        offset: 0,
+        length: None,
         directory: None,
     });
     assert!(read_result.is_err()); // File doesn't exist, but no crash
@@ -650,7 +720,7 @@ fn ao_16_undo_inside_working_dir_rejected() {
         undo_dir: Some(undo.clone()),
         vm_mode: "ephemeral".to_string(),
         protocol: "stdio".to_string(),
-        log_level: "info".to_string(),
+        log_level: LogLevel::Info,
         qemu_binary: None,
         kernel_path: None,
         initrd_path: None,
@@ -658,9 +728,32 @@ fn ao_16_undo_inside_working_dir_rejected() {
         memory_mb: 2048,
         cpus: 2,
         virtiofsd_binary: None,
+        virtiofs_dax_window_mb: None,
+        virtiofs_request_workers: 4,
+        vm_auto_restart: false,
+        vm_pool_size: 0,
+        quiescence_idle_timeout_ms: 100,
+        quiescence_max_timeout_ms: 2000,
+        ambient_inactivity_timeout_ms: 5000,
+        ambient_max_duration_ms: 60_000,
+        ambient_max_files: 1000,
+        max_sessions: 1,
         config_file: None,
         socket_path: None,
+        mcp_listen: None,
+        mcp_token_file: None,
+        agent_endpoint: None,
+        agent_model: None,
+        agent_api_key_env: "OPENAI_API_KEY".into(),
+        agent_command: None,
+        agent_args: Vec::new(),
+        agent_max_turns: 25,
+        health_socket: None,
+        health_probe: None,
+        print_schema: None,
         log_file: None,
+        log_rotate_mb: None,
+        metrics_listen: None,
         disable_builtin_tools: false,
         auto_allow_write_tools: false,
         server_name: "codeagent-sandbox".into(),
@@ -689,7 +782,7 @@ fn ao_17_working_inside_undo_dir_rejected() {
         undo_dir: Some(undo.path().to_path_buf()),
         vm_mode: "ephemeral".to_string(),
         protocol: "stdio".to_string(),
-        log_level: "info".to_string(),
+        log_level: LogLevel::Info,
         qemu_binary: None,
         kernel_path: None,
         initrd_path: None,
@@ -697,9 +790,32 @@ fn ao_17_working_inside_undo_dir_rejected() {
         memory_mb: 2048,
         cpus: 2,
         virtiofsd_binary: None,
+        virtiofs_dax_window_mb: None,
+        virtiofs_request_workers: 4,
+        vm_auto_restart: false,
+        vm_pool_size: 0,
+        quiescence_idle_timeout_ms: 100,
+        quiescence_max_timeout_ms: 2000,
+        ambient_inactivity_timeout_ms: 5000,
+        ambient_max_duration_ms: 60_000,
+        ambient_max_files: 1000,
+        max_sessions: 1,
         config_file: None,
         socket_path: None,
+        mcp_listen: None,
+        mcp_token_file: None,
+        agent_endpoint: None,
+        agent_model: None,
+        agent_api_key_env: "OPENAI_API_KEY".into(),
+        agent_command: None,
+        agent_args: Vec::new(),
+        agent_max_turns: 25,
+        health_socket: None,
+        health_probe: None,
+        print_schema: None,
         log_file: None,
+        log_rotate_mb: None,
+        metrics_listen: None,
         disable_builtin_tools: false,
         auto_allow_write_tools: false,
         server_name: "codeagent-sandbox".into(),
@@ -863,6 +979,7 @@ fn mcp_14_write_file_new_file_undo() {
         .undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         })
         .unwrap();
     assert!(
@@ -896,6 +1013,7 @@ fn mcp_15_write_file_overwrite_undo() {
         .undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         })
         .unwrap();
     assert_eq!(
@@ -929,6 +1047,7 @@ fn mcp_16_write_file_creates_parent_dirs_undo() {
         .undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         })
         .unwrap();
     assert!(!target.exists(), "file should be removed after undo");
@@ -1092,6 +1211,7 @@ fn ao_18_session_boundary_barrier() {
         let result = orch.undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         });
         assert!(result.is_err(), "rollback should be blocked by session boundary barrier");
 
@@ -1099,6 +1219,7 @@ fn ao_18_session_boundary_barrier() {
         let result = orch.undo(UndoArgs {
             count: 1,
             force: true,
+            strict: false,
         });
         assert!(result.is_ok(), "forced rollback should cross the barrier");
         assert!(
@@ -1164,12 +1285,18 @@ fn ao_21_reordered_dirs_stable_mapping() {
         let orch = Orchestrator::new(args, event_sender, CommandClassifierConfig::default(), FileWatcherConfig { enabled: false, ..FileWatcherConfig::default() });
         let payload = SessionStartPayload {
             working_directories: vec![
-                WorkingDirectoryConfig { path: dir_a.path().display().to_string(), label: None },
-                WorkingDirectoryConfig { path: dir_b.path().display().to_string(), label: None },
+                WorkingDirectoryConfig { path: dir_a.path().display().to_string(), label: None, backend: None, read_only: false, exclude: vec![], overlay: false },
+                WorkingDirectoryConfig { path: dir_b.path().display().to_string(), label: None, backend: None, read_only: false, exclude: vec![], overlay: false },
             ],
             network_policy: "disabled".to_string(),
             vm_mode: "ephemeral".to_string(),
             protocol_version: None,
+            symlink_policy: None,
+            coherent_capture: None,
+            case_sensitivity: None,
+            undo: UndoMode::Enabled,
+            message_limits: None,
+            terminal_output: None,
         };
         let _ = orch.session_start(payload);
 
@@ -1195,20 +1322,2404 @@ fn ao_21_reordered_dirs_stable_mapping() {
         let orch = Orchestrator::new(args, event_sender, CommandClassifierConfig::default(), FileWatcherConfig { enabled: false, ..FileWatcherConfig::default() });
         let payload = SessionStartPayload {
             working_directories: vec![
-                WorkingDirectoryConfig { path: dir_b.path().display().to_string(), label: None },
-                WorkingDirectoryConfig { path: dir_a.path().display().to_string(), label: None },
+                WorkingDirectoryConfig { path: dir_b.path().display().to_string(), label: None, backend: None, read_only: false, exclude: vec![], overlay: false },
+                WorkingDirectoryConfig { path: dir_a.path().display().to_string(), label: None, backend: None, read_only: false, exclude: vec![], overlay: false },
             ],
             network_policy: "disabled".to_string(),
             vm_mode: "ephemeral".to_string(),
             protocol_version: None,
+            symlink_policy: None,
+            coherent_capture: None,
+            case_sensitivity: None,
+            undo: UndoMode::Enabled,
+            message_limits: None,
+            terminal_output: None,
         };
         let result = orch.session_start(payload);
         assert!(result.is_ok(), "session with reordered dirs should succeed");
 
         // dir_a's undo history should be found (barrier placed).
         // dir_a is at index 1 in this session (reversed order).
-        let history = orch.undo_history(UndoHistoryPayload { directory: Some("1".to_string()) }).unwrap();
+        let history = orch.undo_history(UndoHistoryPayload { directory: Some("1".to_string()), ..Default::default() }).unwrap();
         let steps = history["steps"].as_array().unwrap();
         assert!(!steps.is_empty(), "should find previous undo steps for dir_a");
     }
 }
+
+// -----------------------------------------------------------------------
+// AO-22: startup audit reports a leftover socket directory
+// -----------------------------------------------------------------------
+#[test]
+fn ao_22_startup_audit_reports_stale_sockets() {
+    let (orchestrator, mut rx, _working, undo) = setup();
+    let socket_dir = undo.path().join(".sockets");
+    std::fs::create_dir_all(&socket_dir).unwrap();
+    std::fs::write(socket_dir.join("control.sock"), b"").unwrap();
+    std::fs::write(socket_dir.join("vfs0.sock.pid"), b"4242\n").unwrap();
+
+    orchestrator.audit_stale_resources(false);
+
+    let resources = match rx.try_recv() {
+        Ok(Event::StaleResources { resources }) => resources,
+        other => panic!("expected StaleResources event, got: {other:?}"),
+    };
+    let kinds: Vec<&str> = resources.iter().map(|r| r.kind.as_str()).collect();
+    assert_eq!(kinds, vec!["abandoned_lock", "socket_dir"]);
+    assert!(socket_dir.exists(), "report-only audit must not remove anything");
+
+    let result = orchestrator.system_cleanup().unwrap();
+    assert_eq!(result["cleaned"].as_array().unwrap().len(), 2);
+    assert!(result["failed"].as_array().unwrap().is_empty());
+    assert!(!socket_dir.exists());
+}
+
+// -----------------------------------------------------------------------
+// AO-23: startup audit with auto cleanup removes leftovers silently
+// -----------------------------------------------------------------------
+#[test]
+fn ao_23_startup_audit_auto_cleanup() {
+    let (orchestrator, mut rx, _working, undo) = setup();
+    let socket_dir = undo.path().join(".sockets");
+    std::fs::create_dir_all(&socket_dir).unwrap();
+    std::fs::write(socket_dir.join("vfs0.sock"), b"").unwrap();
+
+    orchestrator.audit_stale_resources(true);
+
+    assert!(!socket_dir.exists());
+    assert!(rx.try_recv().is_err(), "no event expected when cleanup succeeds");
+}
+
+// -----------------------------------------------------------------------
+// AO-24: session.start and undo.configure set the symlink policy
+// -----------------------------------------------------------------------
+#[test]
+fn ao_24_symlink_policy_configurable() {
+    let (orchestrator, _rx, working, _undo) = setup();
+    let payload = SessionStartPayload {
+        symlink_policy: Some(SymlinkPolicy::ReadOnly),
+        ..make_start_payload(&working.path().display().to_string())
+    };
+    orchestrator.session_start(payload).unwrap();
+
+    let status = orchestrator.session_status().unwrap();
+    assert_eq!(status["symlink_policy"], json!(["read_only"]));
+
+    orchestrator
+        .undo_configure(UndoConfigurePayload {
+            symlink_policy: Some(SymlinkPolicy::ReadWrite),
+            ..Default::default()
+        }, &Unmonitored)
+        .unwrap();
+    let status = orchestrator.session_status().unwrap();
+    assert_eq!(status["symlink_policy"], json!(["read_write"]));
+
+    // Omitting the field leaves the policy unchanged.
+    orchestrator.undo_configure(UndoConfigurePayload::default(), &Unmonitored).unwrap();
+    let status = orchestrator.session_status().unwrap();
+    assert_eq!(status["symlink_policy"], json!(["read_write"]));
+}
+
+// -----------------------------------------------------------------------
+// AO-25: strict rollback rejects a count larger than the history
+// -----------------------------------------------------------------------
+#[test]
+fn ao_25_strict_rollback_insufficient_history() {
+    let (orchestrator, _rx, working, _undo) = setup();
+    let payload = make_start_payload(&working.path().display().to_string());
+    orchestrator.session_start(payload).unwrap();
+    for name in ["a.txt", "b.txt"] {
+        orchestrator
+            .write_file(WriteFileArgs {
+                path: name.to_string(),
+                content: "content".to_string(),
+            })
+            .unwrap();
+    }
+    let history = orchestrator
+        .undo_history(UndoHistoryPayload::default())
+        .unwrap();
+    let mut step_ids = history["steps"].as_array().unwrap().clone();
+    step_ids.reverse();
+
+    let error = orchestrator
+        .undo_rollback(UndoRollbackPayload {
+            count: 3,
+            force: false,
+            strict: true,
+            mode: RollbackMode::Restore,
+            directory: None,
+        }, &Unmonitored)
+        .unwrap_err();
+    let detail = error.to_error_detail();
+    assert_eq!(detail.code, "insufficient_history");
+    assert_eq!(detail.field.as_deref(), Some("count"));
+    assert!(detail.message.contains("2 available"), "{}", detail.message);
+    assert!(orchestrator
+        .undo(UndoArgs {
+            count: 3,
+            force: false,
+            strict: true,
+        })
+        .is_err());
+    assert!(working.path().join("a.txt").exists());
+
+    let result = orchestrator
+        .undo_rollback(UndoRollbackPayload {
+            count: 3,
+            force: false,
+            strict: false,
+            mode: RollbackMode::Restore,
+            directory: None,
+        }, &Unmonitored)
+        .unwrap();
+    assert_eq!(result["steps_requested"], 3);
+    assert_eq!(result["steps_rolled_back"], 2);
+    assert_eq!(result["step_ids"], json!(step_ids));
+    assert!(!working.path().join("a.txt").exists());
+}
+
+// -----------------------------------------------------------------------
+// AO-26: readiness stays false without a booted VM
+// -----------------------------------------------------------------------
+#[test]
+fn ao_26_readiness_requires_vm() {
+    let (orchestrator, _rx, working, _undo) = setup();
+    let source = orchestrator.readiness_source();
+    assert_eq!(source.readiness(), Readiness::default());
+
+    let payload = make_start_payload(&working.path().display().to_string());
+    orchestrator.session_start(payload).unwrap();
+    let readiness = source.readiness();
+    assert!(!readiness.vm_booted);
+    assert!(!readiness.control_channel_up);
+    assert!(!readiness.is_ready());
+}
+
+// -----------------------------------------------------------------------
+// AO-27: undo.configure applies resource limits to the selected directory
+// -----------------------------------------------------------------------
+#[test]
+fn ao_27_per_directory_resource_limits() {
+    let dir_a = TempDir::new().unwrap();
+    let dir_b = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    let (event_sender, _rx) = mpsc::unbounded_channel();
+    let args = CliArgs {
+        working_dirs: vec![dir_a.path().to_path_buf(), dir_b.path().to_path_buf()],
+        ..make_args(dir_a.path(), undo.path())
+    };
+    let orch = Orchestrator::new(args, event_sender, CommandClassifierConfig::default(), FileWatcherConfig { enabled: false, ..FileWatcherConfig::default() });
+    let payload = SessionStartPayload {
+        working_directories: vec![
+            WorkingDirectoryConfig { path: dir_a.path().display().to_string(), label: None, backend: None, read_only: false, exclude: vec![], overlay: false },
+            WorkingDirectoryConfig { path: dir_b.path().display().to_string(), label: None, backend: None, read_only: false, exclude: vec![], overlay: false },
+        ],
+        ..make_start_payload(&dir_a.path().display().to_string())
+    };
+    orch.session_start(payload).unwrap();
+
+    let write_into = |dir: &TempDir, name: &str| {
+        orch.write_file(WriteFileArgs {
+            path: dir.path().join(name).display().to_string(),
+            content: name.to_string(),
+        })
+        .unwrap();
+    };
+    for name in ["one.txt", "two.txt"] {
+        write_into(&dir_a, name);
+        write_into(&dir_b, name);
+    }
+    let history_len = |directory: &str| {
+        orch.undo_history(UndoHistoryPayload { directory: Some(directory.to_string()), ..Default::default() })
+            .unwrap()["steps"]
+            .as_array()
+            .unwrap()
+            .len()
+    };
+    assert_eq!((history_len("0"), history_len("1")), (2, 2));
+
+    // Lowering dir B's step budget evicts its oldest step immediately.
+    let result = orch
+        .undo_configure(UndoConfigurePayload {
+            max_step_count: Some(1),
+            directory: Some("1".to_string()),
+            ..Default::default()
+        }, &Unmonitored)
+        .unwrap();
+    let directories = result["directories"].as_array().unwrap();
+    assert_eq!(directories.len(), 1);
+    assert_eq!(directories[0]["index"], 1);
+    assert_eq!(directories[0]["evicted_steps"].as_array().unwrap().len(), 1);
+    assert_eq!((history_len("0"), history_len("1")), (2, 1));
+
+    write_into(&dir_a, "three.txt");
+    write_into(&dir_b, "three.txt");
+    assert_eq!((history_len("0"), history_len("1")), (3, 1));
+
+    let status = orch.session_status().unwrap();
+    assert_eq!(status["resource_limits"][0]["max_step_count"], json!(null));
+    assert_eq!(status["resource_limits"][1]["max_step_count"], 1);
+
+    assert!(orch
+        .undo_configure(UndoConfigurePayload {
+            max_step_count: Some(1),
+            directory: Some("5".to_string()),
+            ..Default::default()
+        }, &Unmonitored)
+        .is_err());
+
+    // A directory is also selected by name; an unknown name changes nothing.
+    let name_b = dir_b.path().file_name().unwrap().to_str().unwrap().to_string();
+    let result = orch
+        .undo_configure(UndoConfigurePayload {
+            max_step_count: Some(2),
+            directory: Some(name_b),
+            ..Default::default()
+        }, &Unmonitored)
+        .unwrap();
+    assert_eq!(result["directories"][0]["index"], 1);
+    let detail = orch
+        .undo_configure(UndoConfigurePayload {
+            max_step_count: Some(1),
+            directory: Some("no-such-dir".to_string()),
+            ..Default::default()
+        }, &Unmonitored)
+        .unwrap_err()
+        .to_error_detail();
+    assert_eq!(detail.code, "invalid_working_dir");
+    let status = orch.session_status().unwrap();
+    assert_eq!(status["resource_limits"][0]["max_step_count"], json!(null));
+    assert_eq!(status["resource_limits"][1]["max_step_count"], 2);
+}
+
+// -----------------------------------------------------------------------
+// AO-28: session.clone copies the tree and undo history into a new directory
+// -----------------------------------------------------------------------
+#[test]
+fn ao_28_session_clone_branches_workspace() {
+    let (orch, _rx, working, undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    for name in ["one.txt", "two.txt"] {
+        orch.write_file(WriteFileArgs {
+            path: working.path().join(name).display().to_string(),
+            content: name.to_string(),
+        })
+        .unwrap();
+    }
+
+    let branches = TempDir::new().unwrap();
+    let target = branches.path().join("branch");
+    let result = orch
+        .session_clone(SessionClonePayload {
+            target_dir: target.display().to_string(),
+            directory: None,
+        })
+        .unwrap();
+    assert_eq!(result["steps"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        result["files_reflinked"].as_u64().unwrap() + result["files_copied"].as_u64().unwrap(),
+        2
+    );
+    assert_eq!(std::fs::read_to_string(target.join("two.txt")).unwrap(), "two.txt");
+    assert!(undo.path().join(undo_subdir_name(&target)).join("steps").exists());
+
+    // Occupied or nested targets are refused.
+    let clone_to = |path: &std::path::Path| {
+        orch.session_clone(SessionClonePayload {
+            target_dir: path.display().to_string(),
+            directory: None,
+        })
+    };
+    assert!(clone_to(&target).is_err());
+    assert!(clone_to(&working.path().join("nested")).is_err());
+    assert!(clone_to(&undo.path().join("nested")).is_err());
+
+    // The returned payload opens the clone with the copied history, and
+    // rolling it back leaves the source untouched.
+    orch.session_stop().unwrap();
+    let payload: SessionStartPayload =
+        serde_json::from_value(result["session_start"].clone()).unwrap();
+    orch.session_start(payload).unwrap();
+    let history = orch.undo_history(UndoHistoryPayload::default()).unwrap();
+    assert_eq!(history["steps"].as_array().unwrap().len(), 2);
+    orch.undo_rollback(UndoRollbackPayload {
+        count: 1,
+        force: true,
+        strict: false,
+        mode: RollbackMode::Restore,
+        directory: None,
+    }, &Unmonitored)
+    .unwrap();
+    assert!(!target.join("two.txt").exists());
+    assert!(working.path().join("two.txt").exists());
+}
+
+// -----------------------------------------------------------------------
+// AO-29: session.warnings lists persistent warnings until the session stops
+// -----------------------------------------------------------------------
+#[test]
+fn ao_29_session_warnings_lists_persistent_warnings() {
+    let (orch, mut rx, working, _undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+
+    let warning_event = std::iter::from_fn(|| rx.try_recv().ok())
+        .find_map(|event| match event {
+            Event::Warning { warning } => Some(warning),
+            _ => None,
+        })
+        .expect("host-only start should emit a warning");
+    assert_eq!(warning_event.code(), "vm_not_configured");
+
+    let warnings = orch.session_warnings().unwrap()["warnings"].clone();
+    let warnings = warnings.as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["code"], "vm_not_configured");
+    assert_eq!(warnings[0]["severity"], "info");
+    assert_eq!(warnings[0]["missing"], json!(["kernel", "initrd"]));
+    assert!(warnings[0]["message"].as_str().unwrap().contains("host-only"));
+
+    orch.session_stop().unwrap();
+    assert_eq!(orch.session_warnings().unwrap()["warnings"], json!([]));
+}
+
+// -----------------------------------------------------------------------
+// AO-30: undo.rollback in merge mode keeps edits made after the step
+// -----------------------------------------------------------------------
+#[test]
+fn ao_30_undo_rollback_merge_mode() {
+    let (orch, _rx, working, _undo) = setup();
+    let notes = working.path().join("notes.txt");
+    std::fs::write(&notes, "title\nbody\n\nfooter\n").unwrap();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    orch.write_file(WriteFileArgs {
+        path: notes.display().to_string(),
+        content: "title\nagent body\n\nfooter\n".to_string(),
+    })
+    .unwrap();
+
+    std::fs::write(&notes, "title\nagent body\n\nuser footer\n").unwrap();
+    let result = orch
+        .undo_rollback(UndoRollbackPayload {
+            count: 1,
+            force: true,
+            strict: false,
+            mode: RollbackMode::Merge,
+            directory: None,
+        }, &Unmonitored)
+        .unwrap();
+
+    assert_eq!(result["steps_rolled_back"], 1);
+    assert_eq!(result["merged"], json!([{ "path": "notes.txt", "conflicts": 0 }]));
+    assert_eq!(std::fs::read_to_string(&notes).unwrap(), "title\nbody\n\nuser footer\n");
+}
+
+// -----------------------------------------------------------------------
+// AO-31: undo: "disabled" writes directly and rejects undo requests
+// -----------------------------------------------------------------------
+#[test]
+fn ao_31_undo_disabled_session() {
+    let (orch, _rx, working, undo) = setup();
+    let result = orch
+        .session_start(SessionStartPayload {
+            undo: UndoMode::Disabled,
+            message_limits: None,
+            terminal_output: None,
+            ..make_start_payload(&working.path().display().to_string())
+        })
+        .unwrap();
+    assert_eq!(result["protection_level"], "none");
+
+    let written = orch
+        .write_file(WriteFileArgs {
+            path: "plain.txt".to_string(),
+            content: "one".to_string(),
+        })
+        .unwrap();
+    assert_eq!(written["step_id"], json!(null));
+    orch.edit_file(EditFileArgs {
+        path: "plain.txt".to_string(),
+        old_string: "one".to_string(),
+        new_string: "two".to_string(),
+        replace_all: false,
+    })
+    .unwrap();
+    assert_eq!(std::fs::read_to_string(working.path().join("plain.txt")).unwrap(), "two");
+    assert_eq!(std::fs::read_dir(undo.path()).unwrap().count(), 0);
+
+    let status = orch.session_status().unwrap();
+    assert_eq!(status["undo"], "disabled");
+    assert_eq!(status["protection_level"], "none");
+    assert_eq!(status["undo_steps"], json!([]));
+
+    let error = orch.undo_history(UndoHistoryPayload::default()).unwrap_err();
+    assert_eq!(error.to_error_detail().code, "capability_unavailable");
+    let error = orch
+        .undo_rollback(UndoRollbackPayload {
+            count: 1,
+            force: false,
+            strict: false,
+            mode: RollbackMode::Restore,
+            directory: None,
+        }, &Unmonitored)
+        .unwrap_err();
+    assert_eq!(error.to_error_detail().code, "capability_unavailable");
+    assert!(orch.undo_configure(UndoConfigurePayload::default(), &Unmonitored).is_err());
+    assert!(orch.undo(UndoArgs { count: 1, force: false, strict: false }).is_err());
+
+    orch.session_stop().unwrap();
+    let result = orch
+        .session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    assert_eq!(result["protection_level"], "undo_only");
+}
+
+// -----------------------------------------------------------------------
+// AO-32: vm.inventory needs a session with a running VM
+// -----------------------------------------------------------------------
+#[test]
+fn ao_32_vm_inventory_requires_vm() {
+    let (orch, _rx, working, _undo) = setup();
+    assert!(orch.vm_inventory(VmInventoryPayload::default()).is_err());
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let error = orch
+        .vm_inventory(VmInventoryPayload { refresh: true })
+        .unwrap_err();
+    assert!(error.to_string().contains("VM not available"));
+}
+
+// -----------------------------------------------------------------------
+// AO-33: undo.attest verifies the chain and reports the newest step
+// -----------------------------------------------------------------------
+#[test]
+fn ao_33_undo_attest_reports_chain_head() {
+    let (orch, _rx, working, undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    for content in ["one", "two"] {
+        orch.write_file(WriteFileArgs {
+            path: "chained.txt".to_string(),
+            content: content.to_string(),
+        })
+        .unwrap();
+    }
+
+    let attestation = orch.undo_attest(UndoAttestPayload::default()).unwrap();
+    assert_eq!(attestation["verified"], true);
+    assert_eq!(attestation["chained_steps"], 2);
+    assert_eq!(attestation["head_step"], 2);
+
+    let step_dir = undo
+        .path()
+        .join(undo_subdir_name(working.path()))
+        .join("steps")
+        .join("1");
+    let manifest = std::fs::read_to_string(step_dir.join("manifest.json")).unwrap();
+    std::fs::write(step_dir.join("manifest.json"), manifest.replace("chained", "other")).unwrap();
+    let attestation = orch.undo_attest(UndoAttestPayload::default()).unwrap();
+    assert_eq!(attestation["verified"], false);
+    assert_eq!(attestation["broken_at"], 2);
+}
+
+// -----------------------------------------------------------------------
+// AO-34: safeguard.history returns the newest audit log entries
+// -----------------------------------------------------------------------
+#[test]
+fn ao_34_safeguard_history_reads_audit_log() {
+    let (orch, _rx, working, undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let history = orch.safeguard_history(SafeguardHistoryPayload::default()).unwrap();
+    assert_eq!(history["entries"], json!([]));
+
+    let undo_dir = undo.path().join(undo_subdir_name(working.path()));
+    for (safeguard_id, decided_by) in [(1, DecidedBy::Timeout), (2, DecidedBy::User)] {
+        let event = SafeguardEvent {
+            safeguard_id,
+            step_id: 4,
+            kind: SafeguardKind::DeleteThreshold {
+                count: 20,
+                threshold: 10,
+            },
+            sample_paths: vec!["src/lib.rs".to_string()],
+        };
+        let decision = match decided_by {
+            DecidedBy::User => SafeguardDecision::AllowOnce,
+            _ => SafeguardDecision::Deny,
+        };
+        let triggered_at = "2026-01-01T00:00:00Z".to_string();
+        let record = SafeguardRecord::new(&event, triggered_at, decision, decided_by);
+        safeguard_log::append(&undo_dir, &record).unwrap();
+    }
+
+    let history = orch
+        .safeguard_history(SafeguardHistoryPayload {
+            directory: None,
+            limit: Some(1),
+        })
+        .unwrap();
+    let entries = history["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["safeguard_id"], 2);
+    assert_eq!(entries[0]["decision"], "allow_once");
+    assert_eq!(entries[0]["decided_by"], "user");
+    assert_eq!(entries[0]["paths"], json!(["src/lib.rs"]));
+}
+
+// -----------------------------------------------------------------------
+// AO-35: rollback and history report RFC 3339 UTC timestamps and durations
+// -----------------------------------------------------------------------
+#[test]
+fn ao_35_responses_report_timing_consistently() {
+    let (orch, _rx, working, _undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    orch.write_file(WriteFileArgs {
+        path: "timed.txt".to_string(),
+        content: "content".to_string(),
+    })
+    .unwrap();
+
+    let is_protocol_timestamp = |value: &serde_json::Value| {
+        let text = value.as_str().unwrap_or_default();
+        // e.g. 2026-10-14T09:30:00.123Z
+        text.len() == 24 && text.ends_with('Z') && text.as_bytes()[19] == b'.'
+    };
+    let history = orch.undo_history(UndoHistoryPayload::default()).unwrap();
+    let step = &history["details"][0];
+    assert!(is_protocol_timestamp(&step["timestamp"]), "{step}");
+
+    let result = orch
+        .undo_rollback(UndoRollbackPayload {
+            count: 1,
+            force: false,
+            strict: false,
+            mode: RollbackMode::Restore,
+            directory: None,
+        }, &Unmonitored)
+        .unwrap();
+    assert!(is_protocol_timestamp(&result["started_at"]), "{result}");
+    assert!(result["duration_ms"].is_u64(), "{result}");
+}
+
+// -----------------------------------------------------------------------
+// AO-36: undo.expect accepts paths inside the working directory only
+// -----------------------------------------------------------------------
+#[test]
+fn ao_36_undo_expect_queues_announcements_for_next_step() {
+    let (orch, _rx, working, _undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let expect = |paths: Vec<String>| {
+        orch.undo_expect(UndoExpectPayload {
+            paths,
+            op: codeagent_common::ExpectedOperation::Delete,
+            estimated_bytes: 1 << 20,
+            directory: None,
+        })
+    };
+
+    assert!(expect(Vec::new()).is_err());
+    assert!(expect(vec!["../elsewhere".to_string()]).is_err());
+
+    let result = expect(vec!["target".to_string()]).unwrap();
+    assert_eq!(result["pending_expectations"], 1);
+    let absolute = working.path().join("build").display().to_string();
+    let result = expect(vec![absolute, ".".to_string()]).unwrap();
+    assert_eq!(result["pending_expectations"], 2);
+}
+
+// -----------------------------------------------------------------------
+// AO-37: undo.reload_ignores needs a session and reports what it rebuilt
+// -----------------------------------------------------------------------
+#[test]
+fn ao_37_undo_reload_ignores_reports_reloaded_directories() {
+    let (orch, _rx, working, _undo) = setup();
+    assert!(orch.undo_reload_ignores().is_err());
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    // The watcher is off and the undo log does not filter by .gitignore.
+    let result = orch.undo_reload_ignores().unwrap();
+    assert_eq!(result["reloaded"], serde_json::json!([]));
+}
+
+// -----------------------------------------------------------------------
+// AO-38: each working directory names its own mount backend
+// -----------------------------------------------------------------------
+#[test]
+fn ao_38_per_directory_mount_backends_are_validated() {
+    use codeagent_stdio::protocol::MountBackend;
+
+    let (orch, _rx, working, _undo) = setup();
+    let other = TempDir::new().unwrap();
+    let unavailable = if cfg!(target_os = "windows") {
+        MountBackend::VirtiofsReadOnly
+    } else {
+        MountBackend::P9
+    };
+    let start = |backend: MountBackend| {
+        let mut payload = make_start_payload(&working.path().display().to_string());
+        payload.working_directories.push(WorkingDirectoryConfig {
+            path: other.path().display().to_string(),
+            label: None,
+            backend: Some(backend),
+            read_only: false,
+            exclude: vec![],
+            overlay: false,
+        });
+        orch.session_start(payload)
+    };
+
+    let error = start(unavailable).unwrap_err();
+    assert!(error.to_string().contains(unavailable.as_str()), "{error}");
+
+    // Without a VM nothing is mounted, whatever the backend.
+    let result = start(MountBackend::platform_default()).unwrap();
+    let mounts = result["mount_points"].as_array().unwrap();
+    assert_eq!(mounts.len(), 2);
+    assert!(mounts.iter().all(|mount| mount["backend"] == "none"), "{result}");
+}
+
+// -----------------------------------------------------------------------
+// AO-39: agent.input needs a running VM
+// -----------------------------------------------------------------------
+#[test]
+fn ao_39_agent_input_needs_vm() {
+    let (orch, _rx, working, _undo) = setup();
+    let payload = || codeagent_stdio::protocol::AgentInputPayload {
+        command_id: 1,
+        data: "y\n".to_string(),
+        eof: false,
+    };
+    assert!(orch.agent_input(payload()).is_err());
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let error = orch.agent_input(payload()).unwrap_err();
+    assert!(error.to_string().contains("VM not available"), "{error}");
+}
+
+// -----------------------------------------------------------------------
+// AO-40: session.env.* manages the env profile and hides secret values
+// -----------------------------------------------------------------------
+#[test]
+fn ao_40_session_env_profile() {
+    use codeagent_stdio::protocol::{SessionEnvSetPayload, SessionEnvUnsetPayload};
+
+    let (orch, _rx, working, _undo) = setup();
+    let set = |name: &str, value: &str, secret| {
+        orch.session_env_set(SessionEnvSetPayload {
+            name: name.to_string(),
+            value: value.to_string(),
+            secret,
+        })
+    };
+    assert!(set("PATH", "/opt/bin", false).is_err());
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    assert_eq!(set("PATH", "/opt/bin", false).unwrap()["replaced"], false);
+    assert_eq!(set("API_KEY", "hunter2", true).unwrap()["replaced"], false);
+    assert_eq!(set("PATH", "/usr/local/bin", false).unwrap()["replaced"], true);
+    let error = set("BAD=NAME", "x", false).unwrap_err();
+    assert!(error.to_string().contains("'='"), "{error}");
+
+    let list = orch.session_env_list().unwrap();
+    assert_eq!(
+        list["variables"],
+        serde_json::json!([
+            {"name": "API_KEY", "value": "[REDACTED]", "secret": true},
+            {"name": "PATH", "value": "/usr/local/bin", "secret": false},
+        ])
+    );
+
+    let unset = |name: &str| {
+        orch.session_env_unset(SessionEnvUnsetPayload {
+            name: name.to_string(),
+        })
+        .unwrap()["removed"]
+            .clone()
+    };
+    assert_eq!(unset("API_KEY"), true);
+    assert_eq!(unset("API_KEY"), false);
+    assert_eq!(orch.session_env_list().unwrap()["variables"].as_array().unwrap().len(), 1);
+}
+
+// -----------------------------------------------------------------------
+// AO-41: session.destroy deletes undo data and overlays only with a matching
+// token, and never keeps a saved VM state
+// -----------------------------------------------------------------------
+#[test]
+fn ao_41_session_destroy_deletes_undo_log() {
+    use codeagent_stdio::protocol::SessionDestroyPayload;
+
+    let (orch, _rx, working, undo) = setup();
+    let destroy = |confirmation: Option<&str>| {
+        orch.session_destroy(SessionDestroyPayload {
+            delete_undo_log: true,
+            confirmation: confirmation.map(str::to_string),
+        })
+    };
+    assert!(destroy(None).is_err());
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let preview = destroy(None).unwrap();
+    let token = preview["confirmation"].as_str().unwrap().to_string();
+    let paths = preview["paths"].as_array().unwrap().clone();
+    assert_eq!(paths.len(), 1);
+    let undo_dir = std::path::PathBuf::from(paths[0].as_str().unwrap());
+    assert!(undo_dir.starts_with(undo.path()) && undo_dir.exists());
+
+    let error = destroy(Some("not-the-token")).unwrap_err();
+    assert!(error.to_string().contains("confirmation"), "{error}");
+    assert!(undo_dir.exists());
+
+    let result = destroy(Some(&token)).unwrap();
+    assert_eq!(result["deleted"], serde_json::json!(paths));
+    assert!(!undo_dir.exists());
+    assert!(working.path().exists());
+    assert!(orch.session_stop().is_err());
+
+    // Without delete_undo_log it stops the session, keeping the undo log
+    // but not a saved VM state.
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let saved_state = undo.path().join(".vm-state");
+    std::fs::create_dir_all(&saved_state).unwrap();
+    std::fs::write(saved_state.join("state"), "saved").unwrap();
+    let stopped = orch
+        .session_destroy(SessionDestroyPayload::default())
+        .unwrap();
+    assert_eq!(stopped, serde_json::json!({ "deleted": [], "failed": [] }));
+    assert!(undo_dir.exists());
+    assert!(!saved_state.exists());
+
+    // An overlay is deleted along with the undo log.
+    let mut payload = make_start_payload(&working.path().display().to_string());
+    payload.working_directories[0].overlay = true;
+    let started = orch.session_start(payload).unwrap();
+    let upper = std::path::PathBuf::from(started["mount_points"][0]["overlay"].as_str().unwrap());
+    let preview = destroy(None).unwrap();
+    let paths = preview["paths"].as_array().unwrap().clone();
+    assert_eq!(paths.len(), 2);
+    assert!(paths.contains(&serde_json::json!(upper.display().to_string())));
+    let result = destroy(Some(preview["confirmation"].as_str().unwrap())).unwrap();
+    assert_eq!(result["deleted"], serde_json::json!(paths));
+    assert_eq!(result["failed"], serde_json::json!([]));
+    assert!(!upper.exists());
+    assert!(!upper.with_extension("base.json").exists());
+}
+
+// -----------------------------------------------------------------------
+// AO-42: fs.write records an API step that undo.rollback reverts
+// -----------------------------------------------------------------------
+#[test]
+fn ao_42_fs_write_is_undoable() {
+    use codeagent_stdio::protocol::FsWritePayload;
+
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::write(working.path().join("existing.txt"), "original").unwrap();
+    let write = |path: &str, content: &str| {
+        orch.fs_write(FsWritePayload {
+            path: path.to_string(),
+            content: content.to_string(),
+            directory: None,
+        })
+    };
+    assert!(write("new.txt", "x").is_err());
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let created = write("nested/new.txt", "hello").unwrap();
+    assert_eq!(created["written"], true);
+    let rewritten = write("existing.txt", "changed").unwrap();
+    assert!(rewritten["step_id"].as_i64().unwrap() > created["step_id"].as_i64().unwrap());
+    assert_eq!(
+        std::fs::read_to_string(working.path().join("nested/new.txt")).unwrap(),
+        "hello"
+    );
+
+    let error = write("nested", "not a file").unwrap_err();
+    assert!(error.to_string().contains("directory"), "{error}");
+
+    orch.undo_rollback(UndoRollbackPayload {
+        count: 2,
+        force: false,
+        strict: false,
+        mode: RollbackMode::Restore,
+        directory: None,
+    }, &Unmonitored)
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(working.path().join("existing.txt")).unwrap(),
+        "original"
+    );
+    assert!(!working.path().join("nested").exists());
+}
+
+// -----------------------------------------------------------------------
+// AO-43: fs.delete removes files and, with recursive, directories as one
+// undoable step
+// -----------------------------------------------------------------------
+#[test]
+fn ao_43_fs_delete_is_undoable() {
+    use codeagent_stdio::protocol::FsDeletePayload;
+
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::create_dir_all(working.path().join("build/out")).unwrap();
+    std::fs::write(working.path().join("build/out/app"), "binary").unwrap();
+    std::fs::write(working.path().join("notes.md"), "notes").unwrap();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let delete = |path: &str, recursive: bool| {
+        orch.fs_delete(FsDeletePayload {
+            path: path.to_string(),
+            recursive,
+            directory: None,
+        })
+    };
+
+    let error = delete("build", false).unwrap_err();
+    assert!(error.to_string().contains("recursive"), "{error}");
+    assert!(delete("missing.txt", false).is_err());
+    assert!(delete(".", true).is_err());
+
+    let deleted = delete("notes.md", false).unwrap();
+    assert_eq!(deleted["deleted"], true);
+    assert!(deleted["step_id"].is_i64());
+    delete("build", true).unwrap();
+    assert!(!working.path().join("build").exists());
+
+    orch.undo_rollback(UndoRollbackPayload {
+        count: 2,
+        force: false,
+        strict: false,
+        mode: RollbackMode::Restore,
+        directory: None,
+    }, &Unmonitored)
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(working.path().join("build/out/app")).unwrap(),
+        "binary"
+    );
+    assert_eq!(std::fs::read_to_string(working.path().join("notes.md")).unwrap(), "notes");
+}
+
+// -----------------------------------------------------------------------
+// AO-44: fs.read and read_file return binary files as base64 and large
+// files in ranges
+// -----------------------------------------------------------------------
+#[test]
+fn ao_44_fs_read_binary_and_ranges() {
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::write(working.path().join("logo.png"), [0x89, b'P', b'N', b'G']).unwrap();
+    std::fs::write(working.path().join("log.txt"), "line 1\nline 2\n").unwrap();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+
+    let error = orch
+        .fs_read(FsReadPayload {
+            path: "logo.png".to_string(),
+            encoding: ReadEncoding::Utf8,
+            offset: 0,
+            length: None,
+            directory: None,
+        })
+        .unwrap_err();
+    assert!(error.to_string().contains("base64"), "{error}");
+    let binary = orch
+        .fs_read(FsReadPayload {
+            path: "logo.png".to_string(),
+            encoding: ReadEncoding::Base64,
+            offset: 0,
+            length: None,
+            directory: None,
+        })
+        .unwrap();
+    assert_eq!(binary["content"], "iVBORw==");
+    assert_eq!(binary["encoding"], "base64");
+    assert_eq!(binary["size"], 4);
+
+    let range = orch
+        .read_file(ReadFileArgs {
+            path: "log.txt".to_string(),
+            encoding: ReadEncoding::Utf8,
+            offset: 7,
+            length: Some(4),
+        })
+        .unwrap();
+    assert_eq!(range["content"], "line");
+    assert_eq!(range["size"], 14);
+    assert_eq!(range["truncated"], true);
+    assert!(matches!(
+        orch.read_file(ReadFileArgs {
+            path: "logo.png".to_string(),
+            encoding: ReadEncoding::Utf8,
+            offset: 0,
+            length: None,
+        }),
+        Err(McpError::InvalidParams { .. })
+    ));
+}
+
+// -----------------------------------------------------------------------
+// AO-45: fs.stat describes a path and fs.hash changes with the content
+// -----------------------------------------------------------------------
+#[test]
+fn ao_45_fs_stat_and_hash() {
+    use codeagent_stdio::protocol::{FsHashPayload, FsStatPayload};
+
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::create_dir(working.path().join("src")).unwrap();
+    std::fs::write(working.path().join("src/lib.rs"), "fn a() {}").unwrap();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let stat = |path: &str| {
+        orch.fs_stat(FsStatPayload {
+            path: path.to_string(),
+            directory: None,
+        })
+    };
+    let hash = |path: &str| {
+        orch.fs_hash(FsHashPayload {
+            path: path.to_string(),
+            directory: None,
+        })
+    };
+
+    let file = stat("src/lib.rs").unwrap();
+    assert_eq!(file["type"], "file");
+    assert_eq!(file["size"], 9);
+    assert!(file["mtime"].is_string());
+    assert_eq!(stat("src").unwrap()["type"], "directory");
+    assert!(stat("missing").is_err());
+
+    let before = hash("src/lib.rs").unwrap();
+    assert_eq!(before["algorithm"], "blake3");
+    assert_eq!(hash("src/lib.rs").unwrap()["hash"], before["hash"]);
+    std::fs::write(working.path().join("src/lib.rs"), "fn b() {}").unwrap();
+    assert_ne!(hash("src/lib.rs").unwrap()["hash"], before["hash"]);
+    assert!(hash("src").unwrap_err().to_string().contains("directory"));
+}
+
+// -----------------------------------------------------------------------
+// AO-46: MCP safeguard tools need an active session and a pending ID
+// -----------------------------------------------------------------------
+#[test]
+fn ao_46_mcp_safeguard_tools() {
+    use codeagent_mcp::protocol::{ConfirmSafeguardArgs, ListPendingSafeguardsArgs};
+
+    let (orch, _rx, working, _undo) = setup();
+    let confirm = || {
+        orch.confirm_safeguard(ConfirmSafeguardArgs {
+            safeguard_id: "1".to_string(),
+            action: "allow_once".to_string(),
+        })
+    };
+    assert!(orch.list_pending_safeguards(ListPendingSafeguardsArgs {}).is_err());
+    assert!(confirm().is_err());
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let listed = orch.list_pending_safeguards(ListPendingSafeguardsArgs {}).unwrap();
+    assert_eq!(listed["pending"], json!([]));
+    assert!(matches!(
+        confirm(),
+        Err(McpError::InvalidParams { message }) if message.contains("'1'")
+    ));
+}
+
+// -----------------------------------------------------------------------
+// AO-47: fs.patch and apply_patch apply all hunks in one undoable step or
+// nothing
+// -----------------------------------------------------------------------
+#[test]
+fn ao_47_patches_apply_atomically() {
+    use codeagent_mcp::protocol::ApplyPatchArgs;
+    use codeagent_stdio::protocol::FsPatchPayload;
+
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::write(working.path().join("a.txt"), "one\ntwo\nthree\n").unwrap();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let fs_patch = |patch: &str| {
+        orch.fs_patch(FsPatchPayload {
+            patch: patch.to_string(),
+            path: None,
+            directory: None,
+        })
+    };
+
+    let rejected = fs_patch(
+        "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-one\n+ONE\n@@ -3 +3 @@\n-four\n+FOUR\n\
+         --- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+new\n",
+    )
+    .unwrap();
+    assert_eq!(rejected["applied"], false);
+    assert_eq!(rejected["rejected"][0]["path"], "a.txt");
+    assert_eq!(rejected["rejected"][0]["hunks"][0]["hunk"], 2);
+    assert_eq!(std::fs::read_to_string(working.path().join("a.txt")).unwrap(), "one\ntwo\nthree\n");
+    assert!(!working.path().join("new.txt").exists());
+
+    let applied = fs_patch(
+        "--- a/a.txt\n+++ b/a.txt\n@@ -2 +2 @@\n-two\n+TWO\n\
+         --- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+new\n",
+    )
+    .unwrap();
+    assert_eq!(applied["applied"], true);
+    assert_eq!(applied["files"][1], json!({"path": "new.txt", "hunks": 1, "deleted": false}));
+    assert_eq!(std::fs::read_to_string(working.path().join("a.txt")).unwrap(), "one\nTWO\nthree\n");
+    let history = orch.undo_history(UndoHistoryPayload::default()).unwrap();
+    assert_eq!(history["steps"].as_array().unwrap().len(), 1);
+    assert_eq!(
+        history["details"][0]["command"],
+        "fs.patch a.txt (1 hunk), new.txt (1 hunk)"
+    );
+
+    orch.undo_rollback(UndoRollbackPayload {
+        count: 1,
+        force: false,
+        strict: false,
+        mode: RollbackMode::Restore,
+        directory: None,
+    }, &Unmonitored)
+    .unwrap();
+    assert_eq!(std::fs::read_to_string(working.path().join("a.txt")).unwrap(), "one\ntwo\nthree\n");
+    assert!(!working.path().join("new.txt").exists());
+
+    let applied = orch
+        .apply_patch(ApplyPatchArgs {
+            patch: "@@ -3 +3 @@\n-three\n+3\n".to_string(),
+            path: Some("a.txt".to_string()),
+        })
+        .unwrap();
+    assert!(applied["step_id"].is_i64());
+    assert_eq!(std::fs::read_to_string(working.path().join("a.txt")).unwrap(), "one\ntwo\n3\n");
+    assert!(matches!(
+        orch.apply_patch(ApplyPatchArgs {
+            patch: "@@ -1 +1 @@\n-one\n".to_string(),
+            path: Some("a.txt".to_string()),
+        }),
+        Err(McpError::InvalidParams { .. })
+    ));
+}
+
+// -----------------------------------------------------------------------
+// AO-48: agent.prompt runs the backend's plan through agent.execute
+// -----------------------------------------------------------------------
+#[test]
+fn ao_48_agent_prompt_drives_backend() {
+    use codeagent_sandbox::agent_backend::{AgentAction, AgentBackend, AgentTurn};
+    use codeagent_sandbox::error::AgentError;
+    use codeagent_stdio::protocol::AgentPromptPayload;
+
+    /// Answers with `actions` in turn, recording how long each transcript was.
+    struct ScriptedBackend {
+        actions: std::sync::Mutex<Vec<AgentAction>>,
+        transcript_lengths: std::sync::Mutex<Vec<usize>>,
+    }
+
+    impl AgentBackend for ScriptedBackend {
+        fn next_action(&self, transcript: &[AgentTurn]) -> Result<AgentAction, AgentError> {
+            self.transcript_lengths.lock().unwrap().push(transcript.len());
+            Ok(self.actions.lock().unwrap().remove(0))
+        }
+    }
+
+    let prompt = || AgentPromptPayload {
+        prompt: "Fix the tests".to_string(),
+    };
+    let (orch, mut rx, working, _undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let unconfigured = orch.agent_prompt(prompt()).unwrap_err();
+    assert_eq!(unconfigured.to_error_detail().code, "capability_unavailable");
+
+    let backend = std::sync::Arc::new(ScriptedBackend {
+        actions: std::sync::Mutex::new(vec![
+            AgentAction::Finish {
+                message: "Nothing to fix.".to_string(),
+            },
+            AgentAction::Run {
+                id: None,
+                command: "cargo test".to_string(),
+                note: Some("Running the tests.".to_string()),
+            },
+        ]),
+        transcript_lengths: std::sync::Mutex::new(Vec::new()),
+    });
+    let orch = orch.with_agent_backend(backend.clone());
+    while rx.try_recv().is_ok() {}
+
+    let finished = orch.agent_prompt(prompt()).unwrap();
+    assert_eq!(finished["status"], "completed");
+    assert_eq!(finished["message"], "Nothing to fix.");
+    assert_eq!(finished["command_ids"], json!([]));
+    assert!(matches!(
+        rx.try_recv(),
+        Ok(Event::AgentOutput { data }) if data == "Nothing to fix."
+    ));
+
+    // Without a VM the command cannot run, which ends the prompt.
+    assert!(orch.agent_prompt(prompt()).is_err());
+    assert!(matches!(
+        rx.try_recv(),
+        Ok(Event::AgentOutput { data }) if data == "Running the tests."
+    ));
+    assert_eq!(*backend.transcript_lengths.lock().unwrap(), vec![1, 1]);
+}
+
+// -----------------------------------------------------------------------
+// AO-49: session.resume restarts the recorded session after a restart
+// -----------------------------------------------------------------------
+#[test]
+fn ao_49_session_resume_after_restart() {
+    use codeagent_sandbox::session_record::SessionRecord;
+    use codeagent_stdio::protocol::{FsWritePayload, SessionDestroyPayload};
+
+    let (orch, _rx, working, undo) = setup();
+    assert!(orch.session_resume().is_err());
+
+    let mut payload = make_start_payload(&working.path().display().to_string());
+    payload.vm_mode = "persistent".to_string();
+    orch.session_start(payload).unwrap();
+    orch.fs_write(FsWritePayload {
+        path: "notes.md".to_string(),
+        content: "draft".to_string(),
+        directory: None,
+    })
+    .unwrap();
+    let record = SessionRecord::load(undo.path()).unwrap().unwrap();
+    assert_eq!(record.payload.vm_mode, "persistent");
+    assert!(orch.session_resume().is_err(), "the session is still active");
+
+    // A new process over the same undo directory.
+    drop(orch);
+    let (event_sender, _rx) = mpsc::unbounded_channel();
+    let orch = Orchestrator::new(
+        make_args(working.path(), undo.path()),
+        event_sender,
+        CommandClassifierConfig::default(),
+        FileWatcherConfig {
+            enabled: false,
+            ..FileWatcherConfig::default()
+        },
+    );
+    let resumed = orch.session_resume().unwrap();
+    assert_eq!(resumed["status"], "ok");
+    assert_eq!(resumed["resumed_from"], record.started_at.as_str());
+    let status = orch.session_status().unwrap();
+    assert_eq!(status["vm_mode"], "persistent");
+    let history = orch.undo_history(UndoHistoryPayload::default()).unwrap();
+    assert!(
+        history["details"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|step| step["command"].as_str().is_some_and(|c| c.contains("notes.md"))),
+        "{history}"
+    );
+
+    orch.session_destroy(SessionDestroyPayload::default()).unwrap();
+    assert!(SessionRecord::load(undo.path()).unwrap().is_none());
+    let error = orch.session_resume().unwrap_err();
+    assert!(error.to_string().contains("session.json"), "{error}");
+}
+
+// -----------------------------------------------------------------------
+// AO-50: Several sessions in one process
+// -----------------------------------------------------------------------
+#[tokio::test]
+async fn ao_50_sessions_have_their_own_dirs_and_never_overlap() {
+    use codeagent_sandbox::session_factory::OrchestratorFactory;
+    use codeagent_stdio::protocol::{FsWritePayload, Request};
+    use codeagent_stdio::Router;
+
+    let first = TempDir::new().unwrap();
+    let second = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    let (event_sender, _rx) = mpsc::unbounded_channel();
+    let mut args = make_args(first.path(), undo.path());
+    args.max_sessions = 2;
+    let factory = OrchestratorFactory::new(
+        args,
+        event_sender,
+        CommandClassifierConfig::default(),
+        FileWatcherConfig {
+            enabled: false,
+            ..FileWatcherConfig::default()
+        },
+        false,
+    );
+    let router = Router::with_sessions(Box::new(factory), 2);
+    let start = |dir: &std::path::Path| Request::SessionStart {
+        request_id: "start".to_string(),
+        payload: make_start_payload(&dir.display().to_string()),
+    };
+    let write = |session: &str| {
+        let request = Request::FsWrite {
+            request_id: "write".to_string(),
+            payload: FsWritePayload {
+                path: "notes.md".to_string(),
+                content: session.to_string(),
+                directory: None,
+            },
+        };
+        router.dispatch_addressed(Some(session), request)
+    };
+
+    let s1 = router.dispatch(start(first.path()));
+    assert_eq!(s1.payload.as_ref().unwrap()["session_id"], "s1");
+    let nested = first.path().join("nested");
+    std::fs::create_dir(&nested).unwrap();
+    let refused = router.dispatch(start(&nested));
+    assert_eq!(refused.status, "error");
+    assert!(refused.error.unwrap().message.contains("another session"));
+    let s2 = router.dispatch(start(second.path()));
+    assert_eq!(s2.payload.as_ref().unwrap()["session_id"], "s2");
+
+    assert_eq!(write("s1").status, "ok");
+    assert_eq!(write("s2").status, "ok");
+    assert_eq!(std::fs::read_to_string(first.path().join("notes.md")).unwrap(), "s1");
+    assert_eq!(std::fs::read_to_string(second.path().join("notes.md")).unwrap(), "s2");
+    for (session, dir) in [("s1", first.path()), ("s2", second.path())] {
+        let session_undo = undo.path().join("sessions").join(session);
+        assert!(session_undo.join(undo_subdir_name(dir)).is_dir(), "{session}");
+    }
+
+    let stop = Request::SessionStop {
+        request_id: "stop".to_string(),
+    };
+    assert_eq!(router.dispatch_addressed(Some("s1"), stop).status, "ok");
+    let s3 = router.dispatch(start(&nested));
+    assert_eq!(s3.payload.as_ref().unwrap()["session_id"], "s3");
+}
+
+// -----------------------------------------------------------------------
+// AO-51: undo.rollback reports progress and stops when cancelled
+// -----------------------------------------------------------------------
+#[test]
+fn ao_51_rollback_reports_progress_and_can_be_cancelled() {
+    use codeagent_common::OperationMonitor;
+    use codeagent_stdio::protocol::FsWritePayload;
+
+    /// Cancelled once the first step has been rolled back.
+    #[derive(Default)]
+    struct CancelAfterOneStep(std::sync::Mutex<Vec<(u8, Option<String>)>>);
+
+    impl OperationMonitor for CancelAfterOneStep {
+        fn is_cancelled(&self) -> bool {
+            self.0.lock().unwrap().iter().any(|(_, path)| path.is_none())
+        }
+
+        fn progress(&self, percent: u8, current_path: Option<&str>) {
+            self.0.lock().unwrap().push((percent, current_path.map(String::from)));
+        }
+    }
+
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::write(working.path().join("notes.md"), "v0").unwrap();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    for version in ["v1", "v2", "v3"] {
+        orch.fs_write(FsWritePayload {
+            path: "notes.md".to_string(),
+            content: version.to_string(),
+            directory: None,
+        })
+        .unwrap();
+    }
+
+    let monitor = CancelAfterOneStep::default();
+    let result = orch
+        .undo_rollback(UndoRollbackPayload {
+            count: 3,
+            force: false,
+            strict: false,
+            mode: RollbackMode::Restore,
+            directory: None,
+        }, &monitor)
+        .unwrap();
+    assert_eq!(result["cancelled"], true);
+    assert_eq!(result["steps_rolled_back"], 1);
+    assert_eq!(std::fs::read_to_string(working.path().join("notes.md")).unwrap(), "v2");
+    let reports = monitor.0.lock().unwrap().clone();
+    assert_eq!(reports, vec![(0, Some("notes.md".to_string())), (33, None)]);
+
+    let history = orch.undo_history(UndoHistoryPayload::default()).unwrap();
+    assert_eq!(history["steps"].as_array().unwrap().len(), 2);
+}
+
+// -----------------------------------------------------------------------
+// AO-52: Failures carry a stable error code on both APIs
+// -----------------------------------------------------------------------
+#[test]
+fn ao_52_errors_carry_stable_codes() {
+    let (orch, _rx, working, _undo) = setup();
+
+    let detail = orch.session_stop().unwrap_err().to_error_detail();
+    assert_eq!(detail.code, "session_not_active");
+    assert!(!detail.retryable);
+    let rpc = orch
+        .undo(UndoArgs { count: 1, force: false, strict: false })
+        .unwrap_err()
+        .to_jsonrpc_error();
+    assert_eq!(rpc.data.unwrap()["code"], "session_not_active");
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let payload = make_start_payload(&working.path().display().to_string());
+    let detail = orch.session_start(payload).unwrap_err().to_error_detail();
+    assert_eq!(detail.code, "session_already_active");
+
+    let detail = orch
+        .undo_rollback(UndoRollbackPayload {
+            count: 2,
+            force: false,
+            strict: true,
+            mode: RollbackMode::Restore,
+            directory: None,
+        }, &Unmonitored)
+        .unwrap_err()
+        .to_error_detail();
+    assert_eq!(detail.code, "insufficient_history");
+}
+
+// -----------------------------------------------------------------------
+// AO-53: session.metrics reports the counters and the session's gauges
+// -----------------------------------------------------------------------
+#[test]
+fn ao_53_session_metrics_counts_steps_and_sizes_the_undo_log() {
+    let (orch, _rx, working, _undo) = setup();
+    let idle = orch.session_metrics().unwrap();
+    assert_eq!(idle["gauges"]["undo_log_bytes"], 0);
+    let opened_before = idle["counters"]["steps_opened"].as_u64().unwrap();
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    orch.write_file(WriteFileArgs {
+        path: "counted.txt".to_string(),
+        content: "counted".to_string(),
+    })
+    .unwrap();
+
+    // Counters are process-wide and other tests run alongside.
+    let report = orch.session_metrics().unwrap();
+    assert!(report["counters"]["steps_opened"].as_u64().unwrap() > opened_before);
+    assert!(report["gauges"]["undo_log_bytes"].as_u64().unwrap() > 0);
+    assert_eq!(report["gauges"]["vm_memory_bytes"], 0);
+}
+
+// -----------------------------------------------------------------------
+// AO-54: undo.configure cannot turn on the git mirror outside a git repository
+// -----------------------------------------------------------------------
+#[test]
+fn ao_54_git_mirror_needs_a_git_repository() {
+    let (orch, _rx, working, _undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+
+    let mirror = GitMirrorConfig {
+        enabled: true,
+        ..GitMirrorConfig::default()
+    };
+    let detail = orch
+        .undo_configure(UndoConfigurePayload {
+            git_mirror: Some(mirror),
+            ..Default::default()
+        }, &Unmonitored)
+        .unwrap_err()
+        .to_error_detail();
+    assert_eq!(detail.code, "capability_unavailable");
+
+    orch.undo_configure(UndoConfigurePayload {
+        git_mirror: Some(GitMirrorConfig::default()),
+        ..Default::default()
+    }, &Unmonitored)
+    .unwrap();
+}
+
+// -----------------------------------------------------------------------
+// AO-55: undo.configure sets extra ignore patterns, rejecting invalid ones
+// -----------------------------------------------------------------------
+#[test]
+fn ao_55_ignore_patterns_configurable() {
+    let (orch, _rx, working, _undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+
+    let detail = orch
+        .undo_configure(UndoConfigurePayload {
+            ignore_patterns: Some(vec!["ok/".to_string(), "[z-a]".to_string()]),
+            ..Default::default()
+        }, &Unmonitored)
+        .unwrap_err()
+        .to_error_detail();
+    assert_eq!(detail.code, "invalid_field");
+    assert_eq!(orch.session_status().unwrap()["ignore_patterns"], json!([[]]));
+
+    orch.undo_configure(UndoConfigurePayload {
+        ignore_patterns: Some(vec!["scratch/".to_string()]),
+        ..Default::default()
+    }, &Unmonitored)
+    .unwrap();
+    assert_eq!(orch.session_status().unwrap()["ignore_patterns"], json!([["scratch/"]]));
+}
+
+// -----------------------------------------------------------------------
+// AO-56: undo.squash merges consecutive steps, which then roll back as one
+// -----------------------------------------------------------------------
+#[test]
+fn ao_56_undo_squash_merges_steps() {
+    use codeagent_stdio::protocol::FsWritePayload;
+
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::write(working.path().join("notes.md"), "v0").unwrap();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    for version in ["v1", "v2", "v3"] {
+        orch.fs_write(FsWritePayload {
+            path: "notes.md".to_string(),
+            content: version.to_string(),
+            directory: None,
+        })
+        .unwrap();
+    }
+    let history = orch.undo_history(UndoHistoryPayload::default()).unwrap();
+    let steps: Vec<i64> = history["steps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|step| step.as_i64().unwrap())
+        .collect();
+
+    let detail = orch
+        .undo_squash(UndoSquashPayload {
+            from_step: steps[2],
+            to_step: steps[0],
+            directory: None,
+        })
+        .unwrap_err()
+        .to_error_detail();
+    assert_eq!(detail.code, "invalid_step_range");
+
+    let result = orch
+        .undo_squash(UndoSquashPayload {
+            from_step: steps[1],
+            to_step: steps[2],
+            directory: None,
+        })
+        .unwrap();
+    assert_eq!(result["step_id"], steps[1]);
+    assert_eq!(result["squashed_step_ids"], json!(&steps[1..]));
+    let history = orch.undo_history(UndoHistoryPayload::default()).unwrap();
+    assert_eq!(history["steps"].as_array().unwrap().len(), 2);
+
+    orch.undo_rollback(UndoRollbackPayload {
+        count: 1,
+        force: false,
+        strict: false,
+        mode: RollbackMode::Restore,
+        directory: None,
+    }, &Unmonitored)
+    .unwrap();
+    assert_eq!(std::fs::read_to_string(working.path().join("notes.md")).unwrap(), "v1");
+}
+
+// -----------------------------------------------------------------------
+// AO-57: session.configure changes the step closing timeouts
+// -----------------------------------------------------------------------
+#[test]
+fn ao_57_session_configure_timeouts() {
+    let (orch, _rx, _working, _undo) = setup();
+
+    let config = orch
+        .session_configure(SessionConfigurePayload {
+            idle_timeout_ms: Some(500),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(
+        config,
+        json!({
+            "idle_timeout_ms": 500,
+            "max_timeout_ms": 2000,
+            "ambient_inactivity_timeout_ms": 5000,
+            "ambient_max_duration_ms": 60_000,
+            "ambient_max_files": 1000,
+        })
+    );
+
+    let detail = orch
+        .session_configure(SessionConfigurePayload {
+            max_timeout_ms: Some(0),
+            ambient_inactivity_timeout_ms: Some(1000),
+            ..Default::default()
+        })
+        .unwrap_err()
+        .to_error_detail();
+    assert_eq!(detail.code, "invalid_field");
+
+    let config = orch
+        .session_configure(SessionConfigurePayload {
+            max_timeout_ms: Some(10_000),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(config["idle_timeout_ms"], 500);
+    assert_eq!(config["max_timeout_ms"], 10_000);
+    assert_eq!(config["ambient_inactivity_timeout_ms"], 5000);
+
+    let config = orch
+        .session_configure(SessionConfigurePayload {
+            ambient_max_files: Some(20),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(config["ambient_max_files"], 20);
+    let zero = SessionConfigurePayload {
+        ambient_max_files: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(orch.session_configure(zero).unwrap_err().to_error_detail().code, "invalid_field");
+}
+
+// -----------------------------------------------------------------------
+// AO-58: read-only working directories are reported in mount_points
+// -----------------------------------------------------------------------
+#[test]
+fn ao_58_read_only_directories_in_mount_points() {
+    let (orch, _rx, working, _undo) = setup();
+    let reference = TempDir::new().unwrap();
+    let mut payload = make_start_payload(&working.path().display().to_string());
+    payload.working_directories.push(WorkingDirectoryConfig {
+        path: reference.path().display().to_string(),
+        label: None,
+        backend: None,
+        read_only: true,
+        exclude: vec![],
+        overlay: false,
+    });
+
+    let result = orch.session_start(payload).unwrap();
+    let read_only: Vec<&serde_json::Value> = result["mount_points"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|mount| &mount["read_only"])
+        .collect();
+    assert_eq!(read_only, [&json!(false), &json!(true)], "{result}");
+}
+
+// -----------------------------------------------------------------------
+// AO-59: exclude patterns need the intercepted backend
+// -----------------------------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn ao_59_exclude_patterns_need_intercepted_backend() {
+    use codeagent_stdio::protocol::MountBackend;
+
+    let (orch, _rx, working, _undo) = setup();
+    let start = |backend: MountBackend| {
+        let mut payload = make_start_payload(&working.path().display().to_string());
+        let dir = &mut payload.working_directories[0];
+        dir.backend = Some(backend);
+        dir.exclude = vec!["target".to_string(), ".git/objects".to_string()];
+        orch.session_start(payload)
+    };
+
+    let detail = start(MountBackend::Virtiofs).unwrap_err().to_error_detail();
+    assert_eq!(detail.code, "not_implemented");
+    assert!(detail.message.contains("virtiofs"), "{}", detail.message);
+
+    let result = start(MountBackend::Intercepted).unwrap();
+    assert_eq!(result["mount_points"][0]["exclude"], json!(["target", ".git/objects"]));
+}
+
+// -----------------------------------------------------------------------
+// AO-60: overlay changes reach the working directory only through fs.commit
+// -----------------------------------------------------------------------
+#[test]
+fn ao_60_overlay_changes_wait_for_fs_commit() {
+    use codeagent_stdio::protocol::FsCommitPayload;
+    use codeagent_stdio::StdioError;
+
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::write(working.path().join("a.txt"), "one\n").unwrap();
+    std::fs::write(working.path().join("b.txt"), "two\n").unwrap();
+    let mut payload = make_start_payload(&working.path().display().to_string());
+    payload.working_directories[0].overlay = true;
+    let result = orch.session_start(payload).unwrap();
+    let upper = std::path::PathBuf::from(result["mount_points"][0]["overlay"].as_str().unwrap());
+    assert_eq!(std::fs::read_to_string(upper.join("a.txt")).unwrap(), "one\n");
+
+    // What the guest would write through the backend.
+    std::fs::write(upper.join("a.txt"), "ONE\n").unwrap();
+    std::fs::write(upper.join("new.txt"), "new\n").unwrap();
+    std::fs::remove_file(upper.join("b.txt")).unwrap();
+
+    let dry_run = orch
+        .fs_commit(FsCommitPayload { dry_run: true, ..Default::default() })
+        .unwrap();
+    assert_eq!(
+        dry_run["pending"],
+        json!([
+            {"path": "a.txt", "kind": "modified", "conflict": false},
+            {"path": "b.txt", "kind": "deleted", "conflict": false},
+            {"path": "new.txt", "kind": "created", "conflict": false},
+        ]),
+    );
+    assert_eq!(std::fs::read_to_string(working.path().join("a.txt")).unwrap(), "one\n");
+
+    let unknown = orch
+        .fs_commit(FsCommitPayload {
+            paths: Some(vec!["c.txt".to_string()]),
+            ..Default::default()
+        })
+        .unwrap_err();
+    assert!(
+        matches!(&unknown, StdioError::InvalidField { field, .. } if field == "paths"),
+        "{unknown}",
+    );
+
+    let committed = orch
+        .fs_commit(FsCommitPayload {
+            paths: Some(vec!["a.txt".to_string()]),
+            ..Default::default()
+        })
+        .unwrap();
+    assert!(committed["step_id"].is_i64(), "{committed}");
+    assert_eq!(committed["pending"].as_array().unwrap().len(), 2);
+    assert_eq!(std::fs::read_to_string(working.path().join("a.txt")).unwrap(), "ONE\n");
+    assert!(!working.path().join("new.txt").exists());
+    let history = orch.undo_history(UndoHistoryPayload::default()).unwrap();
+    assert_eq!(history["details"][0]["command"], "fs.commit a.txt");
+
+    let discarded = orch
+        .fs_commit(FsCommitPayload {
+            paths: Some(vec![]),
+            discard_rest: true,
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(discarded["step_id"], json!(null));
+    assert_eq!(discarded["discarded"].as_array().unwrap().len(), 2);
+    assert_eq!(std::fs::read_to_string(upper.join("b.txt")).unwrap(), "two\n");
+    assert!(!upper.join("new.txt").exists());
+    assert!(working.path().join("b.txt").exists());
+}
+
+// -----------------------------------------------------------------------
+// AO-61: fs.trace turns a directory's operation trace on and reads it back
+// -----------------------------------------------------------------------
+#[test]
+fn ao_61_fs_trace_toggles_and_reads_the_trace() {
+    use codeagent_stdio::protocol::FsTracePayload;
+
+    let (orch, _rx, working, _undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+
+    let idle = orch.fs_trace(FsTracePayload::default()).unwrap();
+    assert_eq!(idle["enabled"], false);
+
+    let enabled = orch
+        .fs_trace(FsTracePayload { enabled: Some(true), ..Default::default() })
+        .unwrap();
+    assert_eq!(enabled["enabled"], true);
+    // Host-only sessions have no backend recording into the trace.
+    assert_eq!(enabled["records"], json!([]));
+    assert_eq!(enabled["dropped"], 0);
+    assert_eq!(enabled["next_after"], 0);
+
+    let out_of_range = orch.fs_trace(FsTracePayload {
+        directory: Some("3".to_string()),
+        ..Default::default()
+    });
+    assert!(out_of_range.is_err());
+}
+
+// -----------------------------------------------------------------------
+// AO-62: SandboxClient starts a session, rolls back and streams events
+// -----------------------------------------------------------------------
+#[tokio::test(flavor = "multi_thread")]
+async fn ao_62_sandbox_client_drives_a_session() {
+    use codeagent_sandbox::client::{SandboxClient, SandboxConfig};
+    use codeagent_stdio::protocol::FsWritePayload;
+
+    let working = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    let start = make_start_payload(&working.path().display().to_string());
+    let mut config = SandboxConfig {
+        args: make_args(working.path(), undo.path()),
+        settings: Default::default(),
+        session: start.clone(),
+    };
+    config.settings.file_watcher.enabled = false;
+    let client = SandboxClient::start(config).await.unwrap();
+    let mut events = client.subscribe_events();
+
+    let write = |content: &'static str| {
+        client.call(move |orch| {
+            orch.fs_write(FsWritePayload {
+                path: "notes.md".to_string(),
+                content: content.to_string(),
+                directory: None,
+            })
+        })
+    };
+    write("draft").await.unwrap();
+    client
+        .rollback(UndoRollbackPayload {
+            count: 1,
+            force: false,
+            strict: false,
+            mode: RollbackMode::Restore,
+            directory: None,
+        })
+        .await
+        .unwrap();
+    assert!(!working.path().join("notes.md").exists());
+
+    // Restarting over a recorded step places a barrier and says so.
+    write("final").await.unwrap();
+    client.stop().await.unwrap();
+    let execute = serde_json::from_value(json!({ "command": "true" })).unwrap();
+    assert!(client.execute(execute).await.is_err());
+    client.call(move |orch| orch.session_start(start)).await.unwrap();
+    let barrier = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match events.recv().await.unwrap() {
+                Event::ExternalModification { barrier_id, .. } => break barrier_id,
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(barrier.is_some());
+}
+
+// -----------------------------------------------------------------------
+// AO-63: an atomic batch leaves one undo step, or none when it fails
+// -----------------------------------------------------------------------
+#[test]
+fn ao_63_atomic_batch_records_a_single_step() {
+    use codeagent_stdio::{parse_request, Router};
+
+    let (orch, _rx, working, _undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let router = Router::new(working.path().to_path_buf(), Box::new(orch));
+    let batch = |requests: &str| {
+        let payload = format!(r#"{{"atomic":true,"requests":[{requests}]}}"#);
+        let line = format!(r#"{{"type":"batch","request_id":"b","payload":{payload}}}"#);
+        router.dispatch(parse_request(&line).unwrap())
+    };
+
+    let response = batch(
+        r#"{"type":"fs.write","request_id":"1","payload":{"path":"a.txt","content":"a"}},
+        {"type":"fs.write","request_id":"2","payload":{"path":"b.txt","content":"b"}},
+        {"type":"fs.delete","request_id":"3","payload":{"path":"a.txt"}}"#,
+    );
+    assert_eq!(response.status, "ok", "{:?}", response.error);
+    let payload = response.payload.unwrap();
+    assert_eq!(payload["committed"], true);
+    assert_eq!(payload["responses"].as_array().unwrap().len(), 3);
+    let squashed = &payload["steps"]["directories"][0]["squashed_step_ids"];
+    assert_eq!(squashed.as_array().unwrap().len(), 3);
+    assert!(!working.path().join("a.txt").exists());
+    assert!(working.path().join("b.txt").exists());
+
+    let history = r#"{"type":"undo.history","request_id":"h"}"#;
+    let history = router.dispatch(parse_request(history).unwrap()).payload.unwrap();
+    assert_eq!(history["steps"].as_array().unwrap().len(), 1);
+    let rollback = r#"{"type":"undo.rollback","request_id":"r","payload":{"count":1}}"#;
+    assert_eq!(router.dispatch(parse_request(rollback).unwrap()).status, "ok");
+    assert!(!working.path().join("b.txt").exists());
+
+    // A failing request stops the batch and undoes what ran before it.
+    let response = batch(
+        r#"{"type":"fs.write","request_id":"1","payload":{"path":"c.txt","content":"c"}},
+        {"type":"fs.read","request_id":"2","payload":{"path":"missing.txt"}},
+        {"type":"fs.write","request_id":"3","payload":{"path":"d.txt","content":"d"}}"#,
+    );
+    let payload = response.payload.unwrap();
+    assert_eq!(payload["committed"], false);
+    let responses = payload["responses"].as_array().unwrap();
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[1]["status"], "error");
+    let rolled_back = &payload["steps"]["directories"][0]["rolled_back_step_ids"];
+    assert_eq!(rolled_back.as_array().unwrap().len(), 1);
+    assert!(!working.path().join("c.txt").exists());
+    assert!(!working.path().join("d.txt").exists());
+}
+
+/// Create an Orchestrator whose VM launch fails, so the session falls back
+/// to the host with its safeguards still answered through the API.
+fn setup_with_safeguards() -> (
+    std::sync::Arc<Orchestrator>,
+    mpsc::UnboundedReceiver<Event>,
+    TempDir,
+    TempDir,
+) {
+    let working = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    let images = undo.path().join("images");
+    std::fs::create_dir(&images).unwrap();
+    std::fs::write(images.join("vmlinuz"), "").unwrap();
+    std::fs::write(images.join("initrd.img"), "").unwrap();
+
+    let (event_sender, event_receiver) = mpsc::unbounded_channel();
+    let mut args = make_args(working.path(), &undo.path().join("undo"));
+    args.kernel_path = Some(images.join("vmlinuz"));
+    args.initrd_path = Some(images.join("initrd.img"));
+    args.qemu_binary = Some(images.join("missing-qemu"));
+    let orchestrator = Orchestrator::new(
+        args,
+        event_sender,
+        CommandClassifierConfig::default(),
+        FileWatcherConfig { enabled: false, ..FileWatcherConfig::default() },
+    );
+
+    (std::sync::Arc::new(orchestrator), event_receiver, working, undo)
+}
+
+/// Wait for the next `event.safeguard_triggered`, returning its id and kind.
+async fn next_safeguard(rx: &mut mpsc::UnboundedReceiver<Event>) -> (String, String) {
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            if let Event::SafeguardTriggered { safeguard_id, kind, .. } = rx.recv().await.unwrap() {
+                break (safeguard_id, kind);
+            }
+        }
+    })
+    .await
+    .unwrap()
+}
+
+// -----------------------------------------------------------------------
+// AO-64: safeguard.configure's overwrite count threshold reaches the session
+// -----------------------------------------------------------------------
+#[tokio::test(flavor = "multi_thread")]
+async fn ao_64_configured_overwrite_count_triggers_a_safeguard() {
+    use codeagent_stdio::protocol::{
+        FsWritePayload, SafeguardConfigurePayload, SafeguardConfirmPayload,
+    };
+
+    let (orch, mut rx, working, _undo) = setup_with_safeguards();
+    std::fs::write(working.path().join("a.txt"), "original").unwrap();
+    let start = make_start_payload(&working.path().display().to_string());
+    let started = {
+        let orch = std::sync::Arc::clone(&orch);
+        tokio::task::spawn_blocking(move || orch.session_start(start))
+            .await
+            .unwrap()
+            .unwrap()
+    };
+    assert_eq!(started["vm_status"], "unavailable");
+
+    orch.safeguard_configure(SafeguardConfigurePayload {
+        overwrite_count_threshold: Some(1),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let write = {
+        let orch = std::sync::Arc::clone(&orch);
+        tokio::task::spawn_blocking(move || {
+            orch.fs_write(FsWritePayload {
+                path: "a.txt".to_string(),
+                content: "replaced".to_string(),
+                directory: None,
+            })
+        })
+    };
+    let (safeguard_id, kind) = next_safeguard(&mut rx).await;
+    assert!(kind.starts_with("OverwriteCountThreshold"), "{kind}");
+    orch.safeguard_confirm(SafeguardConfirmPayload {
+        safeguard_id,
+        action: "deny".to_string(),
+    })
+    .unwrap();
+
+    assert!(write.await.unwrap().is_err());
+    assert_eq!(
+        std::fs::read_to_string(working.path().join("a.txt")).unwrap(),
+        "original",
+    );
+}
+
+// -----------------------------------------------------------------------
+// AO-65: safeguard.configure's protected paths reach the session
+// -----------------------------------------------------------------------
+#[tokio::test(flavor = "multi_thread")]
+async fn ao_65_configured_protected_path_triggers_a_safeguard() {
+    use codeagent_stdio::protocol::{
+        FsDeletePayload, SafeguardConfigurePayload, SafeguardConfirmPayload,
+    };
+
+    let (orch, mut rx, working, _undo) = setup_with_safeguards();
+    std::fs::write(working.path().join("prod.env"), "KEY=1").unwrap();
+    let start = make_start_payload(&working.path().display().to_string());
+    {
+        let orch = std::sync::Arc::clone(&orch);
+        tokio::task::spawn_blocking(move || orch.session_start(start))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    orch.safeguard_configure(SafeguardConfigurePayload {
+        protected_paths: Some(vec!["*.env".to_string()]),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let delete = {
+        let orch = std::sync::Arc::clone(&orch);
+        tokio::task::spawn_blocking(move || {
+            orch.fs_delete(FsDeletePayload {
+                path: "prod.env".to_string(),
+                recursive: false,
+                directory: None,
+            })
+        })
+    };
+    let (safeguard_id, kind) = next_safeguard(&mut rx).await;
+    assert!(kind.starts_with("ProtectedPath"), "{kind}");
+    assert!(kind.contains("*.env"), "{kind}");
+    orch.safeguard_confirm(SafeguardConfirmPayload {
+        safeguard_id,
+        action: "allow_once".to_string(),
+    })
+    .unwrap();
+
+    delete.await.unwrap().unwrap();
+    assert!(!working.path().join("prod.env").exists());
+}
+
+// -----------------------------------------------------------------------
+// AO-66: safeguard.configure's byte budgets roll API steps back unasked
+// -----------------------------------------------------------------------
+#[test]
+fn ao_66_configured_byte_budgets_roll_back_api_steps() {
+    use codeagent_common::ErrorCode;
+    use codeagent_stdio::protocol::{FsDeletePayload, FsWritePayload, SafeguardConfigurePayload};
+
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::write(working.path().join("a.txt"), "0123456789").unwrap();
+    std::fs::write(working.path().join("b.txt"), "0123456789").unwrap();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+
+    orch.safeguard_configure(SafeguardConfigurePayload {
+        max_deleted_bytes_per_step: Some(4),
+        max_overwritten_bytes_per_step: Some(4),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let deleted = orch
+        .fs_delete(FsDeletePayload {
+            path: "a.txt".to_string(),
+            recursive: false,
+            directory: None,
+        })
+        .unwrap_err();
+    assert_eq!(deleted.code(), ErrorCode::StepBudgetExceeded, "{deleted}");
+    assert!(working.path().join("a.txt").exists());
+
+    let overwritten = orch
+        .fs_write(FsWritePayload {
+            path: "b.txt".to_string(),
+            content: "replaced".to_string(),
+            directory: None,
+        })
+        .unwrap_err();
+    assert_eq!(overwritten.code(), ErrorCode::StepBudgetExceeded, "{overwritten}");
+    assert_eq!(
+        std::fs::read_to_string(working.path().join("b.txt")).unwrap(),
+        "0123456789",
+    );
+
+    // A new file overwrites nothing, so it stays within the budget.
+    orch.fs_write(FsWritePayload {
+        path: "c.txt".to_string(),
+        content: "0123456789".to_string(),
+        directory: None,
+    })
+    .unwrap();
+}
+
+// -----------------------------------------------------------------------
+// AO-67: session.start and undo.configure set the coherent capture rules
+// -----------------------------------------------------------------------
+#[test]
+fn ao_67_coherent_capture_configurable() {
+    let rules = |pattern: &str, strategy| CoherentCaptureConfig {
+        rules: vec![CoherentCaptureRule {
+            pattern: pattern.to_string(),
+            strategy,
+        }],
+        max_attempts: 2,
+        retry_interval_ms: 1,
+    };
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::write(working.path().join("app.db"), "before").unwrap();
+    let payload = SessionStartPayload {
+        coherent_capture: Some(rules("*.db", CoherentCaptureStrategy::AdvisoryLock)),
+        ..make_start_payload(&working.path().display().to_string())
+    };
+    orch.session_start(payload).unwrap();
+
+    let status = orch.session_status().unwrap();
+    assert_eq!(status["coherent_capture"][0]["rules"][0]["pattern"], "*.db");
+    assert_eq!(status["coherent_capture"][0]["rules"][0]["strategy"], "advisory_lock");
+
+    // A matching file is still captured, so its write rolls back.
+    orch.write_file(WriteFileArgs {
+        path: working.path().join("app.db").display().to_string(),
+        content: "after".to_string(),
+    })
+    .unwrap();
+    orch.undo_rollback(UndoRollbackPayload {
+        count: 1,
+        force: false,
+        strict: false,
+        mode: RollbackMode::Restore,
+        directory: None,
+    }, &Unmonitored)
+    .unwrap();
+    assert_eq!(std::fs::read_to_string(working.path().join("app.db")).unwrap(), "before");
+
+    orch.undo_configure(UndoConfigurePayload {
+        coherent_capture: Some(rules("*.ldb", CoherentCaptureStrategy::RetryUntilQuiescent)),
+        ..Default::default()
+    }, &Unmonitored)
+    .unwrap();
+    let status = orch.session_status().unwrap();
+    assert_eq!(status["coherent_capture"][0]["rules"][0]["pattern"], "*.ldb");
+    assert_eq!(status["coherent_capture"][0]["max_attempts"], 2);
+
+    // Omitting the field leaves the rules unchanged, and a clone keeps them.
+    orch.undo_configure(UndoConfigurePayload::default(), &Unmonitored).unwrap();
+    let branches = TempDir::new().unwrap();
+    let result = orch
+        .session_clone(SessionClonePayload {
+            target_dir: branches.path().join("branch").display().to_string(),
+            directory: None,
+        })
+        .unwrap();
+    let clone: SessionStartPayload =
+        serde_json::from_value(result["session_start"].clone()).unwrap();
+    assert_eq!(
+        clone.coherent_capture,
+        Some(rules("*.ldb", CoherentCaptureStrategy::RetryUntilQuiescent))
+    );
+}
+
+// -----------------------------------------------------------------------
+// AO-68: invalid rule and protected path globs are rejected, not skipped
+// -----------------------------------------------------------------------
+#[test]
+fn ao_68_invalid_globs_rejected() {
+    use codeagent_common::{
+        ExternalModificationConfig, ExternalModificationPolicy, ExternalModificationRule,
+    };
+    use codeagent_stdio::protocol::SafeguardConfigurePayload;
+
+    let coherent = |pattern: &str| CoherentCaptureConfig {
+        rules: vec![CoherentCaptureRule {
+            pattern: pattern.to_string(),
+            strategy: CoherentCaptureStrategy::AdvisoryLock,
+        }],
+        ..Default::default()
+    };
+    let (orch, _rx, working, _undo) = setup();
+    let payload = SessionStartPayload {
+        coherent_capture: Some(coherent("[")),
+        ..make_start_payload(&working.path().display().to_string())
+    };
+    let detail = orch.session_start(payload).unwrap_err().to_error_detail();
+    assert_eq!(detail.code, "invalid_field");
+    assert_eq!(detail.field.as_deref(), Some("coherent_capture"));
+
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+
+    let detail = orch
+        .undo_configure(UndoConfigurePayload {
+            symlink_policy: Some(SymlinkPolicy::ReadWrite),
+            external_modification: Some(ExternalModificationConfig {
+                default_policy: ExternalModificationPolicy::Barrier,
+                rules: vec![ExternalModificationRule {
+                    pattern: "target/[".to_string(),
+                    policy: ExternalModificationPolicy::Ignore,
+                }],
+            }),
+            ..Default::default()
+        }, &Unmonitored)
+        .unwrap_err()
+        .to_error_detail();
+    assert_eq!(detail.code, "invalid_field");
+    assert_eq!(detail.field.as_deref(), Some("external_modification"));
+    // Nothing in the rejected request is applied.
+    assert_eq!(orch.session_status().unwrap()["symlink_policy"], json!(["ignore"]));
+
+    let detail = orch
+        .undo_configure(UndoConfigurePayload {
+            coherent_capture: Some(coherent("*.db[")),
+            ..Default::default()
+        }, &Unmonitored)
+        .unwrap_err()
+        .to_error_detail();
+    assert_eq!(detail.code, "invalid_field");
+    assert_eq!(detail.field.as_deref(), Some("coherent_capture"));
+    assert_eq!(orch.session_status().unwrap()["coherent_capture"][0]["rules"], json!([]));
+
+    let detail = orch
+        .safeguard_configure(SafeguardConfigurePayload {
+            protected_paths: Some(vec!["*.env".to_string(), "secrets/[".to_string()]),
+            ..Default::default()
+        })
+        .unwrap_err()
+        .to_error_detail();
+    assert_eq!(detail.code, "invalid_field");
+    assert_eq!(detail.field.as_deref(), Some("protected_paths"));
+}
+
+// -----------------------------------------------------------------------
+// AO-69: host-side writes to a read-only working directory are refused
+// -----------------------------------------------------------------------
+#[test]
+fn ao_69_read_only_directories_refuse_host_writes() {
+    use codeagent_common::ErrorCode;
+    use codeagent_mcp::protocol::ApplyPatchArgs;
+    use codeagent_stdio::StdioError;
+    use codeagent_stdio::protocol::{
+        FsDeletePayload, FsPatchPayload, FsStatPayload, FsWritePayload,
+    };
+
+    let (orch, _rx, reference, _undo) = setup();
+    let scratch = TempDir::new().unwrap();
+    std::fs::write(reference.path().join("spec.md"), "v1\n").unwrap();
+    let mut payload = make_start_payload(&reference.path().display().to_string());
+    payload.working_directories[0].read_only = true;
+    payload.working_directories.push(WorkingDirectoryConfig {
+        path: scratch.path().display().to_string(),
+        label: None,
+        backend: None,
+        read_only: false,
+        exclude: vec![],
+        overlay: false,
+    });
+    orch.session_start(payload).unwrap();
+
+    let refused = |error: StdioError| {
+        let detail = error.to_error_detail();
+        assert_eq!(detail.code, "capability_unavailable", "{}", detail.message);
+    };
+    refused(
+        orch.fs_write(FsWritePayload {
+            path: "spec.md".to_string(),
+            content: "v2\n".to_string(),
+            directory: None,
+        })
+        .unwrap_err(),
+    );
+    refused(
+        orch.fs_delete(FsDeletePayload {
+            path: "spec.md".to_string(),
+            recursive: false,
+            directory: None,
+        })
+        .unwrap_err(),
+    );
+    refused(
+        orch.fs_patch(FsPatchPayload {
+            patch: "@@ -1 +1 @@\n-v1\n+v2\n".to_string(),
+            path: Some("spec.md".to_string()),
+            directory: None,
+        })
+        .unwrap_err(),
+    );
+    refused(
+        orch.undo_rollback(UndoRollbackPayload {
+            count: 1,
+            force: true,
+            strict: false,
+            mode: RollbackMode::Restore,
+            directory: None,
+        }, &Unmonitored)
+        .unwrap_err(),
+    );
+
+    let refused_tool = |error: McpError| {
+        assert_eq!(error.code(), ErrorCode::CapabilityUnavailable, "{error}");
+    };
+    let spec = reference.path().join("spec.md").display().to_string();
+    refused_tool(
+        orch.write_file(WriteFileArgs { path: spec.clone(), content: "v2\n".to_string() })
+            .unwrap_err(),
+    );
+    refused_tool(
+        orch.edit_file(EditFileArgs {
+            path: spec,
+            old_string: "v1".to_string(),
+            new_string: "v2".to_string(),
+            replace_all: false,
+        })
+        .unwrap_err(),
+    );
+    refused_tool(
+        orch.apply_patch(ApplyPatchArgs {
+            patch: "@@ -1 +1 @@\n-v1\n+v2\n".to_string(),
+            path: Some("spec.md".to_string()),
+        })
+        .unwrap_err(),
+    );
+    refused_tool(orch.undo(UndoArgs { count: 1, force: true, strict: false }).unwrap_err());
+    // Without a VM the command would run on the host, unconfined.
+    refused_tool(
+        orch.bash(BashArgs {
+            command: "echo v2 > spec.md".to_string(),
+            description: None,
+            timeout: None,
+        })
+        .unwrap_err(),
+    );
+    assert_eq!(std::fs::read_to_string(reference.path().join("spec.md")).unwrap(), "v1\n");
+
+    // Reads still work, and so do writes to the other directory.
+    orch.fs_stat(FsStatPayload { path: "spec.md".to_string(), directory: None }).unwrap();
+    orch.fs_write(FsWritePayload {
+        path: "notes.md".to_string(),
+        content: "ok".to_string(),
+        directory: Some("1".to_string()),
+    })
+    .unwrap();
+    assert!(scratch.path().join("notes.md").exists());
+}
+
+// -----------------------------------------------------------------------
+// AO-70: fs.commit applies only the overlay's changes and holds back
+// paths the host changed too
+// -----------------------------------------------------------------------
+#[test]
+fn ao_70_fs_commit_keeps_host_changes_and_holds_back_conflicts() {
+    use codeagent_stdio::protocol::FsCommitPayload;
+    use codeagent_stdio::StdioError;
+
+    let (orch, _rx, working, _undo) = setup();
+    std::fs::write(working.path().join("a.txt"), "one\n").unwrap();
+    std::fs::write(working.path().join("b.txt"), "two\n").unwrap();
+    std::fs::write(working.path().join("c.txt"), "three\n").unwrap();
+    let mut payload = make_start_payload(&working.path().display().to_string());
+    payload.working_directories[0].overlay = true;
+    let result = orch.session_start(payload).unwrap();
+    let upper = std::path::PathBuf::from(result["mount_points"][0]["overlay"].as_str().unwrap());
+
+    // The host edits a.txt and b.txt after the overlay was taken; the
+    // guest edits b.txt and c.txt.
+    std::fs::write(working.path().join("a.txt"), "host one\n").unwrap();
+    std::fs::write(working.path().join("b.txt"), "host two\n").unwrap();
+    std::fs::write(upper.join("b.txt"), "guest two\n").unwrap();
+    std::fs::write(upper.join("c.txt"), "guest three\n").unwrap();
+
+    let conflict = orch
+        .fs_commit(FsCommitPayload {
+            paths: Some(vec!["b.txt".to_string()]),
+            ..Default::default()
+        })
+        .unwrap_err();
+    assert!(
+        matches!(&conflict, StdioError::InvalidField { field, .. } if field == "paths"),
+        "{conflict}",
+    );
+
+    let committed = orch.fs_commit(FsCommitPayload::default()).unwrap();
+    assert_eq!(
+        committed["committed"],
+        json!([{"path": "c.txt", "kind": "modified", "conflict": false}]),
+    );
+    assert_eq!(
+        committed["pending"],
+        json!([{"path": "b.txt", "kind": "modified", "conflict": true}]),
+    );
+    assert_eq!(std::fs::read_to_string(working.path().join("a.txt")).unwrap(), "host one\n");
+    assert_eq!(std::fs::read_to_string(working.path().join("b.txt")).unwrap(), "host two\n");
+    assert_eq!(std::fs::read_to_string(working.path().join("c.txt")).unwrap(), "guest three\n");
+
+    // Discarding the conflict takes the host's version into the overlay.
+    let discarded = orch
+        .fs_commit(FsCommitPayload {
+            paths: Some(vec![]),
+            discard_rest: true,
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(discarded["discarded"].as_array().unwrap().len(), 1);
+    assert_eq!(std::fs::read_to_string(upper.join("b.txt")).unwrap(), "host two\n");
+    let dry_run = orch
+        .fs_commit(FsCommitPayload { dry_run: true, ..Default::default() })
+        .unwrap();
+    assert_eq!(dry_run["pending"], json!([]));
+}
+
+// -----------------------------------------------------------------------
+// AO-71: an atomic batch merges or rolls back only its own steps
+// -----------------------------------------------------------------------
+#[test]
+fn ao_71_atomic_batch_leaves_outside_steps_alone() {
+    use codeagent_stdio::protocol::FsWritePayload;
+
+    let (orch, _rx, working, _undo) = setup();
+    orch.session_start(make_start_payload(&working.path().display().to_string()))
+        .unwrap();
+    let write = |path: &str| {
+        orch.fs_write(FsWritePayload {
+            path: path.to_string(),
+            content: path.to_string(),
+            directory: None,
+        })
+        .unwrap();
+    };
+    // A step made on another thread while the batch runs, as the MCP
+    // listener would.
+    let outside = |path: &str| std::thread::scope(|s| s.spawn(|| write(path)).join().unwrap());
+
+    orch.begin_step_scope().unwrap();
+    write("a.txt");
+    write("b.txt");
+    outside("outside.txt");
+    write("c.txt");
+    let steps = orch.end_step_scope(true).unwrap();
+    let directories = steps["directories"].as_array().unwrap();
+    assert_eq!(directories.len(), 2, "{steps}");
+    assert_eq!(directories[0]["squashed_step_ids"].as_array().unwrap().len(), 2);
+    assert_eq!(directories[1]["squashed_step_ids"].as_array().unwrap().len(), 1);
+    let history = orch.undo_history(UndoHistoryPayload::default()).unwrap();
+    let details = history["details"].as_array().unwrap();
+    assert_eq!(details.len(), 3, "{history}");
+    assert_eq!(details[1]["command"], "fs.write outside.txt");
+
+    // Rolling back stops at the outside step, keeping what lies under it.
+    orch.begin_step_scope().unwrap();
+    write("d.txt");
+    outside("outside2.txt");
+    write("e.txt");
+    let steps = orch.end_step_scope(false).unwrap();
+    let directory = &steps["directories"][0];
+    assert_eq!(directory["rolled_back_step_ids"].as_array().unwrap().len(), 1, "{steps}");
+    assert_eq!(directory["kept_step_ids"].as_array().unwrap().len(), 1);
+    assert!(!working.path().join("e.txt").exists());
+    assert!(working.path().join("d.txt").exists());
+    assert!(working.path().join("outside2.txt").exists());
+}
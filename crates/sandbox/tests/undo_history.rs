@@ -17,16 +17,18 @@ use std::path::Path;
 use tempfile::TempDir;
 use tokio::sync::mpsc;
 
+use codeagent_common::ReadEncoding;
 use codeagent_sandbox::cli::CliArgs;
 use codeagent_sandbox::command_classifier::CommandClassifierConfig;
 use codeagent_sandbox::config::FileWatcherConfig;
 use codeagent_sandbox::orchestrator::{undo_subdir_name, Orchestrator};
 use codeagent_mcp::McpHandler;
 use codeagent_mcp::protocol::{
-    EditFileArgs, ReadFileArgs, UndoArgs, WriteFileArgs,
+    EditFileArgs, GetUndoHistoryArgs, ReadFileArgs, UndoArgs, WriteFileArgs,
 };
 use codeagent_stdio::protocol::{
-    SessionStartPayload, UndoHistoryPayload, WorkingDirectoryConfig,
+    HistoryFormat, LogLevel, SessionStartPayload, UndoHistoryPayload, UndoMode,
+    WorkingDirectoryConfig,
 };
 use codeagent_stdio::{Event, RequestHandler};
 
@@ -40,7 +42,7 @@ fn make_args(working_dir: &Path, undo_dir: &Path) -> CliArgs {
         undo_dir: Some(undo_dir.to_path_buf()),
         vm_mode: "ephemeral".to_string(),
         protocol: "stdio".to_string(),
-        log_level: "info".to_string(),
+        log_level: LogLevel::Info,
         qemu_binary: None,
         kernel_path: None,
         initrd_path: None,
@@ -48,9 +50,32 @@ fn make_args(working_dir: &Path, undo_dir: &Path) -> CliArgs {
         memory_mb: 2048,
         cpus: 2,
         virtiofsd_binary: None,
+        virtiofs_dax_window_mb: None,
+        virtiofs_request_workers: 4,
+        vm_auto_restart: false,
+        vm_pool_size: 0,
+        quiescence_idle_timeout_ms: 100,
+        quiescence_max_timeout_ms: 2000,
+        ambient_inactivity_timeout_ms: 5000,
+        ambient_max_duration_ms: 60_000,
+        ambient_max_files: 1000,
+        max_sessions: 1,
         config_file: None,
         socket_path: None,
+        mcp_listen: None,
+        mcp_token_file: None,
+        agent_endpoint: None,
+        agent_model: None,
+        agent_api_key_env: "OPENAI_API_KEY".into(),
+        agent_command: None,
+        agent_args: Vec::new(),
+        agent_max_turns: 25,
+        health_socket: None,
+        health_probe: None,
+        print_schema: None,
         log_file: None,
+        log_rotate_mb: None,
+        metrics_listen: None,
         disable_builtin_tools: false,
         auto_allow_write_tools: false,
         server_name: "codeagent-sandbox".into(),
@@ -62,10 +87,29 @@ fn make_start_payload(path: &str) -> SessionStartPayload {
         working_directories: vec![WorkingDirectoryConfig {
             path: path.to_string(),
             label: None,
+            backend: None,
+            read_only: false,
+            exclude: vec![],
+            overlay: false,
         }],
         network_policy: "disabled".to_string(),
         vm_mode: "ephemeral".to_string(),
         protocol_version: None,
+        symlink_policy: None,
+        coherent_capture: None,
+        case_sensitivity: None,
+        undo: UndoMode::Enabled,
+        message_limits: None,
+        terminal_output: None,
+    }
+}
+
+fn read_args(path: &str) -> ReadFileArgs {
+    ReadFileArgs {
+        path: path.to_string(),
+        encoding: ReadEncoding::Utf8,
+        offset: 0,
+        length: None,
     }
 }
 
@@ -385,6 +429,7 @@ fn uh_06_multi_session_rollback_with_force() {
         let result = orchestrator.undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         });
         assert!(result.is_err(), "non-forced rollback should be blocked by session barrier");
 
@@ -392,6 +437,7 @@ fn uh_06_multi_session_rollback_with_force() {
         let result = orchestrator.undo(UndoArgs {
             count: 1,
             force: true,
+            strict: false,
         });
         assert!(result.is_ok(), "forced rollback should cross barrier");
 
@@ -496,6 +542,7 @@ fn uh_08_rollback_multiple_steps_restores_files() {
         .undo(UndoArgs {
             count: 2,
             force: false,
+            strict: false,
         })
         .unwrap();
 
@@ -554,7 +601,7 @@ fn uh_09_previous_session_steps_visible_in_history() {
         let _ = orchestrator.session_start(make_start_payload(&path_str));
 
         let history = orchestrator
-            .undo_history(UndoHistoryPayload { directory: None })
+            .undo_history(UndoHistoryPayload::default())
             .unwrap();
         let steps = history["steps"].as_array().unwrap();
         assert_eq!(
@@ -609,6 +656,7 @@ fn uh_10_overwrite_file_captures_preimage_and_restores() {
         .undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         })
         .unwrap();
 
@@ -658,6 +706,7 @@ fn uh_11_write_then_delete_then_rollback() {
         .undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         })
         .unwrap();
     assert_eq!(
@@ -670,6 +719,7 @@ fn uh_11_write_then_delete_then_rollback() {
         .undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         })
         .unwrap();
     assert!(
@@ -773,6 +823,7 @@ fn uh_13_nested_write_tracks_parent_directories() {
         .undo(UndoArgs {
             count: 1,
             force: false,
+            strict: false,
         })
         .unwrap();
 
@@ -880,6 +931,9 @@ fn uh_15_read_file_does_not_produce_step() {
     // Read the file via MCP
     let result = orchestrator.read_file(ReadFileArgs {
         path: "readme.txt".to_string(),
+        encoding: ReadEncoding::Utf8,
+        offset: 0,
+        length: None,
     });
     assert!(result.is_ok(), "read_file should succeed");
 
@@ -916,6 +970,9 @@ fn uh_16_write_then_read_only_write_produces_step() {
     // Read the file back
     let _ = orchestrator.read_file(ReadFileArgs {
         path: "data.txt".to_string(),
+        encoding: ReadEncoding::Utf8,
+        offset: 0,
+        length: None,
     });
 
     // Only the write should produce a step
@@ -962,6 +1019,7 @@ fn uh_17_step_ids_unique_after_rollback() {
         .undo(UndoArgs {
             count: 2,
             force: false,
+            strict: false,
         })
         .unwrap();
 
@@ -1021,6 +1079,9 @@ fn uh_18_read_between_writes_no_id_gap() {
     // Read (should not create a step)
     let _ = orchestrator.read_file(ReadFileArgs {
         path: "existing.txt".to_string(),
+        encoding: ReadEncoding::Utf8,
+        offset: 0,
+        length: None,
     });
 
     // Write B → step 2
@@ -1060,9 +1121,9 @@ fn uh_19_multiple_reads_no_id_consumption() {
     ));
 
     // Read 3 files
-    let _ = orchestrator.read_file(ReadFileArgs { path: "r1.txt".to_string() });
-    let _ = orchestrator.read_file(ReadFileArgs { path: "r2.txt".to_string() });
-    let _ = orchestrator.read_file(ReadFileArgs { path: "r3.txt".to_string() });
+    let _ = orchestrator.read_file(read_args("r1.txt"));
+    let _ = orchestrator.read_file(read_args("r2.txt"));
+    let _ = orchestrator.read_file(read_args("r3.txt"));
 
     // Write one file — should get the first step ID, not the 4th
     orchestrator
@@ -1094,15 +1155,15 @@ fn uh_20_interleaved_read_write_no_gaps() {
     ));
 
     // read → write → read → write → read → write
-    let _ = orchestrator.read_file(ReadFileArgs { path: "src.txt".to_string() });
+    let _ = orchestrator.read_file(read_args("src.txt"));
     orchestrator
         .write_file(WriteFileArgs { path: "w1.txt".to_string(), content: "1".to_string() })
         .unwrap();
-    let _ = orchestrator.read_file(ReadFileArgs { path: "src.txt".to_string() });
+    let _ = orchestrator.read_file(read_args("src.txt"));
     orchestrator
         .write_file(WriteFileArgs { path: "w2.txt".to_string(), content: "2".to_string() })
         .unwrap();
-    let _ = orchestrator.read_file(ReadFileArgs { path: "src.txt".to_string() });
+    let _ = orchestrator.read_file(read_args("src.txt"));
     orchestrator
         .write_file(WriteFileArgs { path: "w3.txt".to_string(), content: "3".to_string() })
         .unwrap();
@@ -1140,7 +1201,7 @@ fn uh_21_undo_history_matches_disk() {
 
     let disk_steps = read_steps_from_disk(undo.path());
     let history = orchestrator
-        .undo_history(UndoHistoryPayload { directory: None })
+        .undo_history(UndoHistoryPayload::default())
         .unwrap();
     let api_steps = history["steps"].as_array().unwrap();
 
@@ -1244,7 +1305,7 @@ fn uh_23_rollback_does_not_decrement_counter() {
 
     // Rollback all 3 steps
     orchestrator
-        .undo(UndoArgs { count: 3, force: false })
+        .undo(UndoArgs { count: 3, force: false, strict: false })
         .unwrap();
 
     assert!(
@@ -1292,7 +1353,7 @@ fn uh_24_step_id_after_restart_with_prior_rollback() {
                 .unwrap();
         }
         orchestrator
-            .undo(UndoArgs { count: 3, force: false })
+            .undo(UndoArgs { count: 3, force: false, strict: false })
             .unwrap();
 
         let _ = orchestrator.session_stop();
@@ -1478,7 +1539,7 @@ fn uh_28_error_after_rollback_allows_new_steps() {
         .write_file(WriteFileArgs { path: "b.txt".to_string(), content: "b".to_string() })
         .unwrap();
     orchestrator
-        .undo(UndoArgs { count: 1, force: false })
+        .undo(UndoArgs { count: 1, force: false, strict: false })
         .unwrap();
 
     // Trigger an error
@@ -1529,7 +1590,7 @@ fn uh_29_step_ids_strictly_increasing_after_partial_rollback() {
 
     // Rollback 2 → steps 3,4 removed, steps 1,2 remain
     orchestrator
-        .undo(UndoArgs { count: 2, force: false })
+        .undo(UndoArgs { count: 2, force: false, strict: false })
         .unwrap();
 
     // Create 1 new step — ID must be > max_before (the old step 4's ID)
@@ -1584,7 +1645,7 @@ fn uh_30_step_ids_survive_complete_rollback() {
 
     // Rollback ALL steps
     orchestrator
-        .undo(UndoArgs { count: 3, force: false })
+        .undo(UndoArgs { count: 3, force: false, strict: false })
         .unwrap();
     assert!(read_steps_from_disk(undo.path()).is_empty());
 
@@ -1646,15 +1707,15 @@ fn uh_31_rollback_past_barrier_blocked() {
             .unwrap();
 
         // Rollback 1 (session 2's step) should succeed
-        let result = orchestrator.undo(UndoArgs { count: 1, force: false });
+        let result = orchestrator.undo(UndoArgs { count: 1, force: false, strict: false });
         assert!(result.is_ok(), "rolling back current session's step should work");
 
         // Rollback 1 more (session 1's step 2) should fail — barrier
-        let result = orchestrator.undo(UndoArgs { count: 1, force: false });
+        let result = orchestrator.undo(UndoArgs { count: 1, force: false, strict: false });
         assert!(result.is_err(), "rolling back past session barrier should fail");
 
         // Force rollback should succeed
-        let result = orchestrator.undo(UndoArgs { count: 1, force: true });
+        let result = orchestrator.undo(UndoArgs { count: 1, force: true, strict: false });
         assert!(result.is_ok(), "force rollback should cross barrier");
 
         let _ = orchestrator.session_stop();
@@ -1690,15 +1751,15 @@ fn uh_32_multiple_session_barriers() {
 
         // Rolling back 1 step (session 3's) should be blocked by the barrier
         // placed at session 4 start
-        let result = orchestrator.undo(UndoArgs { count: 1, force: false });
+        let result = orchestrator.undo(UndoArgs { count: 1, force: false, strict: false });
         assert!(result.is_err(), "rollback should be blocked by session 4 barrier");
 
         // Force rollback 1 → removes session 3 step + barrier
-        let result = orchestrator.undo(UndoArgs { count: 1, force: true });
+        let result = orchestrator.undo(UndoArgs { count: 1, force: true, strict: false });
         assert!(result.is_ok());
 
         // Try another — blocked by session 3 barrier
-        let result = orchestrator.undo(UndoArgs { count: 1, force: false });
+        let result = orchestrator.undo(UndoArgs { count: 1, force: false, strict: false });
         assert!(result.is_err(), "should be blocked by session 3 barrier");
 
         let _ = orchestrator.session_stop();
@@ -1754,7 +1815,7 @@ fn uh_33_barrier_persists_across_restart() {
         let (orchestrator, _rx) = create_orchestrator(working.path(), undo.path());
         let _ = orchestrator.session_start(make_start_payload(&path_str));
 
-        let result = orchestrator.undo(UndoArgs { count: 1, force: false });
+        let result = orchestrator.undo(UndoArgs { count: 1, force: false, strict: false });
         assert!(
             result.is_err(),
             "barrier from session 2 should persist and block rollback in session 3"
@@ -1979,7 +2040,7 @@ fn uh_38_cross_session_overwrite_correct_preimage() {
         let (orchestrator, _rx) = create_orchestrator(working.path(), undo.path());
         let _ = orchestrator.session_start(make_start_payload(&path_str));
         orchestrator
-            .undo(UndoArgs { count: 1, force: true })
+            .undo(UndoArgs { count: 1, force: true, strict: false })
             .unwrap();
         let _ = orchestrator.session_stop();
     }
@@ -1994,7 +2055,7 @@ fn uh_38_cross_session_overwrite_correct_preimage() {
         let (orchestrator, _rx) = create_orchestrator(working.path(), undo.path());
         let _ = orchestrator.session_start(make_start_payload(&path_str));
         orchestrator
-            .undo(UndoArgs { count: 1, force: true })
+            .undo(UndoArgs { count: 1, force: true, strict: false })
             .unwrap();
         let _ = orchestrator.session_stop();
     }
@@ -2004,3 +2065,81 @@ fn uh_38_cross_session_overwrite_correct_preimage() {
         "rolling back session 1 should restore original v0 content"
     );
 }
+
+// -----------------------------------------------------------------------
+// UH-39: undo history reports per-step metadata
+// -----------------------------------------------------------------------
+#[test]
+fn uh_39_history_reports_step_metadata() {
+    let working = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    let path_str = working.path().display().to_string();
+    fs::write(working.path().join("existing.txt"), "original contents").unwrap();
+
+    let (orchestrator, _rx) = create_orchestrator(working.path(), undo.path());
+    orchestrator.session_start(make_start_payload(&path_str)).unwrap();
+    orchestrator
+        .write_file(WriteFileArgs {
+            path: "existing.txt".to_string(),
+            content: "new contents".to_string(),
+        })
+        .unwrap();
+
+    let history = orchestrator
+        .undo_history(UndoHistoryPayload::default())
+        .unwrap();
+    let details = history["details"].as_array().unwrap();
+    assert_eq!(details.len(), 1);
+    let step = &details[0];
+    assert_eq!(step["step_type"], "api");
+    assert_eq!(step["command"], "write_file existing.txt");
+    assert_eq!(step["file_count"], 1);
+    assert!(step["preimage_bytes"].as_u64().unwrap() > 0);
+    assert!(step["duration_ms"].is_u64());
+    assert!(step.get("exit_code").is_none());
+
+    let mcp_history = orchestrator.get_undo_history(GetUndoHistoryArgs {}).unwrap();
+    assert_eq!(mcp_history["details"], history["details"]);
+
+    let _ = orchestrator.session_stop();
+}
+
+// -----------------------------------------------------------------------
+// UH-40: undo history renders a text table on request
+// -----------------------------------------------------------------------
+#[test]
+fn uh_40_history_text_format() {
+    let working = TempDir::new().unwrap();
+    let undo = TempDir::new().unwrap();
+    let path_str = working.path().display().to_string();
+
+    let (orchestrator, _rx) = create_orchestrator(working.path(), undo.path());
+    orchestrator.session_start(make_start_payload(&path_str)).unwrap();
+    orchestrator
+        .write_file(WriteFileArgs {
+            path: "notes.txt".to_string(),
+            content: "hello".to_string(),
+        })
+        .unwrap();
+
+    let json_only = orchestrator
+        .undo_history(UndoHistoryPayload::default())
+        .unwrap();
+    assert!(json_only.get("text").is_none());
+
+    let history = orchestrator
+        .undo_history(UndoHistoryPayload {
+            format: HistoryFormat::Text,
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(history["details"], json_only["details"]);
+    let text = history["text"].as_str().unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("STEP"));
+    assert!(lines[1].contains("api"));
+    assert!(lines[1].contains("write_file notes.txt"));
+
+    let _ = orchestrator.session_stop();
+}
@@ -0,0 +1,93 @@
+//! Answers `resolve_pid`: which running command a guest process belongs to,
+//! and its name.
+//!
+//! Every command is spawned as the leader of its own process group, so a
+//! process belongs to the command whose leader pid is its process group, or
+//! the process group of one of its ancestors (for processes that start a
+//! group of their own). Threads the shim itself runs on behalf of a command,
+//! such as the `isolate_fs` merge, are registered by thread id instead.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Shim threads doing filesystem work for a command, by thread id.
+pub type CommandThreads = Arc<Mutex<HashMap<u32, u64>>>;
+
+/// How far up the process tree to look before giving up.
+const MAX_ANCESTORS: usize = 64;
+
+/// The command `pid` belongs to. `leaders` maps the pid of each running
+/// command's process group leader to the command id.
+pub fn resolve(pid: u32, leaders: &HashMap<u32, u64>, threads: &CommandThreads) -> Option<u64> {
+    if let Some(&id) = threads.lock().unwrap().get(&pid) {
+        return Some(id);
+    }
+    let mut current = pid;
+    for _ in 0..MAX_ANCESTORS {
+        if let Some(&id) = leaders.get(&current) {
+            return Some(id);
+        }
+        let stat = std::fs::read_to_string(format!("/proc/{current}/stat")).ok()?;
+        let (parent, group) = parse_stat(&stat)?;
+        if let Some(&id) = leaders.get(&group) {
+            return Some(id);
+        }
+        // Orphans are reparented to the supervisor, which is pid 1.
+        if parent <= 1 {
+            return None;
+        }
+        current = parent;
+    }
+    None
+}
+
+/// The command name of `pid` (`/proc/<pid>/comm`), if it still runs.
+pub fn process_name(pid: u32) -> Option<String> {
+    let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    Some(comm.trim_end_matches('\n').to_string())
+}
+
+/// The thread id of the calling thread, as the host sees it in filesystem
+/// requests.
+pub fn current_thread_id() -> u32 {
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: gettid has no preconditions.
+        unsafe { libc::gettid() as u32 }
+    }
+    #[cfg(not(target_os = "linux"))]
+    0
+}
+
+/// Parent pid and process group from the contents of `/proc/<pid>/stat`.
+fn parse_stat(stat: &str) -> Option<(u32, u32)> {
+    // The command name is in parentheses and may itself contain spaces or
+    // parentheses, so the fixed fields start after the last ')'.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    let mut fields = rest.split_whitespace().skip(1);
+    let parent = fields.next()?.parse().ok()?;
+    let group = fields.next()?.parse().ok()?;
+    Some((parent, group))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat_fields_follow_the_command_name() {
+        let stat = "4242 (npm run (dev)) S 4100 4099 4099 0 -1 4194560 1234 0 0 0";
+        assert_eq!(parse_stat(stat), Some((4100, 4099)));
+        assert_eq!(parse_stat("4242 (truncated"), None);
+    }
+
+    #[test]
+    fn registered_threads_and_leaders_resolve_directly() {
+        let threads = CommandThreads::default();
+        threads.lock().unwrap().insert(77, 3);
+        let leaders = HashMap::from([(4099, 5)]);
+        assert_eq!(resolve(77, &leaders, &threads), Some(3));
+        assert_eq!(resolve(4099, &leaders, &threads), Some(5));
+        assert_eq!(resolve(u32::MAX, &leaders, &threads), None);
+    }
+}
@@ -14,4 +14,10 @@ pub enum ShimError {
 
     #[error("malformed message: {reason}")]
     MalformedMessage { reason: String },
+
+    #[error("cannot isolate command: {reason}")]
+    Isolation { reason: String },
+
+    #[error("cannot allocate a terminal: {reason}")]
+    Terminal { reason: String },
 }
@@ -3,15 +3,20 @@ use std::collections::HashMap;
 #[cfg(unix)]
 use std::time::Duration;
 
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::process::{Child, Command};
 use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
 
-use codeagent_control::{OutputStream, VmMessage};
+use codeagent_control::{LaneSender, OutputStream, ResourceLimits, VmMessage};
 
+use crate::attribution::{self, CommandThreads};
 use crate::error::ShimError;
-use crate::output_buffer::OutputBufferConfig;
+use crate::isolation::Overlay;
+use crate::limits::CommandLimits;
+use crate::output_buffer::{OutputBufferConfig, OutputLimit};
+#[cfg(unix)]
+use crate::pty::{self, Pty};
 
 /// Timeout between SIGTERM and SIGKILL during cancellation.
 #[cfg(unix)]
@@ -23,13 +28,37 @@ pub struct CommandHandle {
     cancel_sender: Option<oneshot::Sender<()>>,
     /// Join handle for the command task (sends StepCompleted on exit).
     task_handle: JoinHandle<()>,
+    /// Pid of the shell, which leads the command's process group.
+    pid: Option<u32>,
+    /// Input for the command's stdin; `None` once stdin was closed.
+    input: Option<mpsc::UnboundedSender<String>>,
+    /// Whether stdin is a terminal rather than a pipe.
+    pty: bool,
 }
 
 impl CommandHandle {
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
     /// Returns true if the command task has finished.
     pub fn is_finished(&self) -> bool {
         self.task_handle.is_finished()
     }
+
+    /// Write `data` to the command's stdin. With `eof`, stdin is closed
+    /// afterwards, or on a terminal the end-of-file character (^D) follows.
+    /// Input after stdin was closed, or after the command exited, is dropped.
+    pub fn send_input(&mut self, mut data: String, eof: bool) {
+        let Some(input) = &self.input else { return };
+        if eof && self.pty {
+            data.push('\u{4}');
+        }
+        let _ = input.send(data);
+        if eof && !self.pty {
+            self.input = None;
+        }
+    }
 }
 
 /// Spawn a shell command and stream output as `VmMessage`s.
@@ -37,13 +66,27 @@ impl CommandHandle {
 /// Immediately sends `StepStarted`, then streams `Output` messages for
 /// stdout and stderr, and finally sends `StepCompleted` when the process
 /// exits. Returns a `CommandHandle` that allows cancellation.
+///
+/// With `isolate_fs` the command's writes to its working directory only
+/// reach the shared mount if it succeeds (see [`crate::isolation`]); the
+/// thread merging them is registered in `threads` meanwhile. With `pty` it
+/// runs on a terminal (see [`crate::pty`]) and all its output is stdout.
+/// Output past `buffer_config.max_output_bytes` is read and dropped, and
+/// `StepCompleted` then reports `output_truncated`. `limits` caps the
+/// command's CPU time, memory and processes (see [`crate::limits`]), and
+/// `StepCompleted` names the one that stopped it.
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_command(
     id: u64,
     command: &str,
     cwd: Option<&str>,
     env: Option<&HashMap<String, String>>,
-    message_sender: mpsc::UnboundedSender<VmMessage>,
+    isolate_fs: bool,
+    pty: bool,
+    threads: CommandThreads,
+    message_sender: LaneSender<VmMessage>,
     buffer_config: OutputBufferConfig,
+    limits: ResourceLimits,
 ) -> Result<CommandHandle, ShimError> {
     let mut cmd = Command::new("bash");
     cmd.arg("-c").arg(command);
@@ -56,16 +99,57 @@ pub fn spawn_command(
         cmd.envs(env_vars);
     }
 
-    cmd.stdout(std::process::Stdio::piped());
-    cmd.stderr(std::process::Stdio::piped());
+    #[cfg(unix)]
+    let terminal = if pty { Some(Pty::open()?) } else { None };
+    #[cfg(not(unix))]
+    if pty {
+        return Err(ShimError::Terminal {
+            reason: "pty needs a Unix guest".to_string(),
+        });
+    }
+
+    #[cfg(unix)]
+    if let Some(terminal) = &terminal {
+        cmd.stdin(terminal.slave()?);
+        cmd.stdout(terminal.slave()?);
+        cmd.stderr(terminal.slave()?);
+    }
+    if !pty {
+        cmd.stdin(std::process::Stdio::piped());
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+    }
+
+    let overlay = if isolate_fs {
+        let root = match cwd {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => std::env::current_dir()?,
+        };
+        Some(Overlay::prepare(id, &root)?)
+    } else {
+        None
+    };
+    let limits = CommandLimits::prepare(id, limits);
 
     // Spawn in a new process group so cancel can kill the whole tree,
     // and drop to the unprivileged sandbox user (uid/gid 1000).
     #[cfg(unix)]
     unsafe {
-        cmd.pre_exec(|| {
-            libc::setpgid(0, 0);
-            // Drop privileges: the shim runs as root (PID 1) but commands
+        let mount = overlay.as_ref().map(Overlay::mount_spec);
+        let enforcer = limits.enforcer();
+        cmd.pre_exec(move || {
+            if pty {
+                pty::attach_controlling_terminal()?;
+            } else {
+                libc::setpgid(0, 0);
+            }
+            // Mounting needs root, so isolate before dropping privileges.
+            if let Some(mount) = &mount {
+                mount.enter()?;
+            }
+            // So does joining the command's cgroup.
+            enforcer.enter()?;
+            // Drop privileges: the shim runs as root (under PID 1) but commands
             // should not. Set gid before uid (setuid drops the ability to
             // call setgid).
             libc::setgid(1000);
@@ -74,11 +158,56 @@ pub fn spawn_command(
         });
     }
 
-    let mut child = cmd.spawn()?;
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(error) => {
+            if let Some(overlay) = overlay {
+                let _ = overlay.finish(false);
+            }
+            limits.finish();
+            return Err(error.into());
+        }
+    };
+
+    let pid = child.id();
+    let (input, input_receiver) = mpsc::unbounded_channel();
+    let limit = OutputLimit::new(buffer_config.max_output_bytes);
+    let mut outputs = Vec::new();
+    let mut output = |stream, reader: Box<dyn OutputReader>| {
+        let task = stream_output(
+            id,
+            stream,
+            reader,
+            message_sender.clone(),
+            buffer_config.clone(),
+            limit.clone(),
+        );
+        outputs.push(tokio::spawn(task));
+    };
 
-    // Take the output handles before moving child into the task.
-    let stdout = child.stdout.take();
-    let stderr = child.stderr.take();
+    #[cfg(unix)]
+    let master = terminal.map(Pty::into_master).transpose()?;
+    #[cfg(not(unix))]
+    let master: Option<(tokio::fs::File, tokio::fs::File)> = None;
+
+    // Take the stdio handles before moving child into the task.
+    match master {
+        Some((reader, writer)) => {
+            output(OutputStream::Stdout, Box::new(reader));
+            tokio::spawn(forward_input(writer, input_receiver));
+        }
+        None => {
+            if let Some(stdout) = child.stdout.take() {
+                output(OutputStream::Stdout, Box::new(stdout));
+            }
+            if let Some(stderr) = child.stderr.take() {
+                output(OutputStream::Stderr, Box::new(stderr));
+            }
+            if let Some(stdin) = child.stdin.take() {
+                tokio::spawn(forward_input(stdin, input_receiver));
+            }
+        }
+    }
 
     let (cancel_sender, cancel_receiver) = oneshot::channel::<()>();
 
@@ -88,54 +217,66 @@ pub fn spawn_command(
     let task_handle = tokio::spawn(run_command(
         id,
         child,
-        stdout,
-        stderr,
+        outputs,
+        limit,
+        overlay,
+        limits,
+        threads,
         message_sender,
         cancel_receiver,
-        buffer_config,
     ));
 
     Ok(CommandHandle {
         cancel_sender: Some(cancel_sender),
         task_handle,
+        pid,
+        input: Some(input),
+        pty,
     })
 }
 
-/// Core command lifecycle: stream output, wait for exit, handle cancel.
+/// A command output stream: a pipe, or the master side of its terminal.
+trait OutputReader: tokio::io::AsyncRead + Unpin + Send {}
+
+impl<R: tokio::io::AsyncRead + Unpin + Send> OutputReader for R {}
+
+/// Write input from the host to the command until the command exits or the
+/// handle closes its stdin.
+async fn forward_input<W: AsyncWrite + Unpin>(
+    mut stdin: W,
+    mut input: mpsc::UnboundedReceiver<String>,
+) {
+    while let Some(data) = input.recv().await {
+        if stdin.write_all(data.as_bytes()).await.is_err() || stdin.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Core command lifecycle: wait for exit or cancel, then for the `outputs`
+/// streaming the command's output.
+#[allow(clippy::too_many_arguments)]
 async fn run_command(
     id: u64,
     mut child: Child,
-    stdout: Option<tokio::process::ChildStdout>,
-    stderr: Option<tokio::process::ChildStderr>,
-    message_sender: mpsc::UnboundedSender<VmMessage>,
+    outputs: Vec<JoinHandle<()>>,
+    limit: OutputLimit,
+    overlay: Option<Overlay>,
+    limits: CommandLimits,
+    threads: CommandThreads,
+    message_sender: LaneSender<VmMessage>,
     cancel_receiver: oneshot::Receiver<()>,
-    buffer_config: OutputBufferConfig,
 ) {
     // Capture the PID before the child is consumed (needed for process group kill on Unix).
     #[cfg(unix)]
     let child_pid = child.id();
-    // Spawn output reader tasks
-    let stdout_handle = stdout.map(|out| {
-        let sender = message_sender.clone();
-        let config = buffer_config.clone();
-        tokio::spawn(stream_output(id, OutputStream::Stdout, out, sender, config))
-    });
-
-    let stderr_handle = stderr.map(|err| {
-        let sender = message_sender.clone();
-        let config = buffer_config.clone();
-        tokio::spawn(stream_output(id, OutputStream::Stderr, err, sender, config))
-    });
 
     // Wait for either child exit or cancel signal
     let cancelled;
-    let exit_code = tokio::select! {
+    let status = tokio::select! {
         status = child.wait() => {
             cancelled = false;
-            match status {
-                Ok(s) => s.code().unwrap_or(-1),
-                Err(_) => -1,
-            }
+            status.ok()
         }
         _ = cancel_receiver => {
             cancelled = true;
@@ -145,33 +286,61 @@ async fn run_command(
 
             // Cross-platform: kill the direct child process
             let _ = child.kill().await;
-            match child.wait().await {
-                Ok(s) => s.code().unwrap_or(-1),
-                Err(_) => -1,
-            }
+            child.wait().await.ok()
         }
     };
+    let exit_code = status.and_then(|status| status.code()).unwrap_or(-1);
 
     if cancelled {
         // On cancel, abort output readers immediately — orphaned subprocesses
         // (e.g., MSYS2 sleep on Windows) may keep pipes open indefinitely.
-        if let Some(handle) = stdout_handle {
-            handle.abort();
-        }
-        if let Some(handle) = stderr_handle {
+        for handle in outputs {
             handle.abort();
         }
     } else {
         // Normal exit — wait for output streams to drain.
-        if let Some(handle) = stdout_handle {
-            let _ = handle.await;
-        }
-        if let Some(handle) = stderr_handle {
+        for handle in outputs {
             let _ = handle.await;
         }
     }
 
-    let _ = message_sender.send(VmMessage::StepCompleted { id, exit_code });
+    // Merge before reporting completion, so the merge lands in the step.
+    let exit_code = match overlay {
+        Some(overlay) => {
+            let success = !cancelled && exit_code == 0;
+            let merged = tokio::task::spawn_blocking(move || {
+                let thread_id = attribution::current_thread_id();
+                threads.lock().unwrap().insert(thread_id, id);
+                let merged = overlay.finish(success);
+                threads.lock().unwrap().remove(&thread_id);
+                merged
+            })
+            .await
+                .unwrap_or_else(|error| Err(std::io::Error::other(error)));
+            match merged {
+                Ok(()) => exit_code,
+                Err(error) => {
+                    let _ = message_sender.send(VmMessage::Output {
+                        id,
+                        stream: OutputStream::Stderr,
+                        data: format!("isolate_fs: could not merge changes: {error}\n"),
+                    });
+                    -1
+                }
+            }
+        }
+        None => exit_code,
+    };
+
+    let limit_exceeded = if cancelled { None } else { limits.exceeded(status) };
+    limits.finish();
+
+    let _ = message_sender.send(VmMessage::StepCompleted {
+        id,
+        exit_code,
+        output_truncated: limit.truncated(),
+        limit_exceeded,
+    });
 }
 
 /// Read from a child output stream and send buffered output messages.
@@ -179,8 +348,9 @@ async fn stream_output<R: AsyncReadExt + Unpin>(
     id: u64,
     stream: OutputStream,
     mut reader: R,
-    sender: mpsc::UnboundedSender<VmMessage>,
+    sender: LaneSender<VmMessage>,
     config: OutputBufferConfig,
+    limit: OutputLimit,
 ) {
     let mut buffer = vec![0u8; config.max_buffer_size];
     let mut pending = Vec::new();
@@ -195,19 +365,19 @@ async fn stream_output<R: AsyncReadExt + Unpin>(
                     Ok(0) => {
                         // EOF: flush remaining data and exit
                         if !pending.is_empty() {
-                            flush_output(id, stream, &mut pending, &sender);
+                            flush_output(id, stream, &mut pending, &sender, &limit);
                         }
                         return;
                     }
                     Ok(n) => {
                         pending.extend_from_slice(&buffer[..n]);
                         if pending.len() >= config.max_buffer_size {
-                            flush_output(id, stream, &mut pending, &sender);
+                            flush_output(id, stream, &mut pending, &sender, &limit);
                         }
                     }
                     Err(_) => {
                         if !pending.is_empty() {
-                            flush_output(id, stream, &mut pending, &sender);
+                            flush_output(id, stream, &mut pending, &sender, &limit);
                         }
                         return;
                     }
@@ -215,23 +385,28 @@ async fn stream_output<R: AsyncReadExt + Unpin>(
             }
             _ = flush_interval.tick() => {
                 if !pending.is_empty() {
-                    flush_output(id, stream, &mut pending, &sender);
+                    flush_output(id, stream, &mut pending, &sender, &limit);
                 }
             }
         }
     }
 }
 
-/// Flush the pending buffer as a single output message.
+/// Flush the pending buffer as a single output message, or drop it once the
+/// command's output limit is used up.
 fn flush_output(
     id: u64,
     stream: OutputStream,
     pending: &mut Vec<u8>,
-    sender: &mpsc::UnboundedSender<VmMessage>,
+    sender: &LaneSender<VmMessage>,
+    limit: &OutputLimit,
 ) {
-    let data = String::from_utf8_lossy(pending).into_owned();
+    limit.admit(pending);
+    if !pending.is_empty() {
+        let data = String::from_utf8_lossy(pending).into_owned();
+        let _ = sender.send(VmMessage::Output { id, stream, data });
+    }
     pending.clear();
-    let _ = sender.send(VmMessage::Output { id, stream, data });
 }
 
 /// Terminate a process group: SIGTERM first, then SIGKILL after timeout.
@@ -0,0 +1,226 @@
+//! Transactional execution for `isolate_fs` commands.
+//!
+//! The command runs in its own mount namespace with a private overlay over
+//! its working directory, so its writes land in an upper layer in scratch
+//! space instead of on the shared mount. If it exits with status 0 the upper
+//! layer is merged into the shared mount, which the host records as the
+//! command's undo step. Otherwise — including when it is cancelled or the
+//! shell is killed — the layer is thrown away and the shared mount is never
+//! touched. Writes outside the working directory are not isolated.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::ShimError;
+
+/// Scratch space and mount parameters for one isolated command.
+pub struct Overlay {
+    root: PathBuf,
+    scratch: PathBuf,
+    mount: MountSpec,
+}
+
+/// What the forked child needs to mount the overlay. Built before the fork,
+/// since the child may not allocate.
+#[derive(Clone)]
+pub struct MountSpec {
+    target: CString,
+    options: CString,
+}
+
+impl Overlay {
+    /// Create the upper and work directories for command `id`, which will
+    /// isolate `root`.
+    pub fn prepare(id: u64, root: &Path) -> Result<Self, ShimError> {
+        if !cfg!(target_os = "linux") {
+            return Err(ShimError::Isolation {
+                reason: "isolate_fs needs Linux mount namespaces".to_string(),
+            });
+        }
+        let root = root.canonicalize()?;
+        let scratch = std::env::temp_dir()
+            .join(format!("codeagent-isolate-{}-{id}", std::process::id()));
+        let upper = scratch.join("upper");
+        let work = scratch.join("work");
+
+        // overlayfs splits its options on commas and layers on colons.
+        let paths = [&root, &upper, &work].map(|path| path.to_string_lossy().into_owned());
+        if let Some(path) = paths.iter().find(|path| path.contains([',', ':'])) {
+            return Err(ShimError::Isolation {
+                reason: format!("cannot isolate a path containing ',' or ':': {path}"),
+            });
+        }
+
+        let _ = fs::remove_dir_all(&scratch);
+        fs::create_dir_all(&upper)?;
+        fs::create_dir(&work)?;
+        // The overlay's root directory takes its owner and mode from the
+        // upper directory, so give it those of the directory it covers.
+        copy_metadata(&fs::metadata(&root)?, &upper)?;
+
+        let [root_text, upper_text, work_text] = paths;
+        let options = format!("lowerdir={root_text},upperdir={upper_text},workdir={work_text}");
+        let mount = MountSpec {
+            target: CString::new(root_text).map_err(io::Error::other)?,
+            options: CString::new(options).map_err(io::Error::other)?,
+        };
+        Ok(Self {
+            root,
+            scratch,
+            mount,
+        })
+    }
+
+    pub fn mount_spec(&self) -> MountSpec {
+        self.mount.clone()
+    }
+
+    /// Merge the upper layer into the shared mount if the command succeeded,
+    /// then remove the scratch space either way.
+    pub fn finish(self, success: bool) -> io::Result<()> {
+        let merged = if success {
+            merge(&self.scratch.join("upper"), &self.root)
+        } else {
+            Ok(())
+        };
+        let _ = fs::remove_dir_all(&self.scratch);
+        merged
+    }
+}
+
+impl MountSpec {
+    /// Run in the forked child before exec, while it is still root: move into
+    /// a new mount namespace and mount the overlay over the working directory.
+    pub fn enter(&self) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let check = |result: libc::c_int| {
+                if result == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            };
+            // SAFETY: only syscalls on strings allocated before the fork.
+            unsafe {
+                check(libc::unshare(libc::CLONE_NEWNS))?;
+                // Keep the overlay from propagating back to the shim's namespace.
+                check(libc::mount(
+                    c"none".as_ptr(),
+                    c"/".as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                ))?;
+                check(libc::mount(
+                    c"overlay".as_ptr(),
+                    self.target.as_ptr(),
+                    c"overlay".as_ptr(),
+                    0,
+                    self.options.as_ptr().cast(),
+                ))?;
+                // The working directory was entered before the mount; enter it
+                // again to land on the overlay.
+                check(libc::chdir(self.target.as_ptr()))
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+/// Apply an overlayfs upper layer to the directory it covered.
+///
+/// Whiteouts delete, opaque directories replace, everything else is copied
+/// with its owner and mode. Hard links are copied as separate files; FIFOs,
+/// sockets and device nodes are not carried over.
+#[cfg(target_os = "linux")]
+fn merge(upper: &Path, lower: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    for entry in fs::read_dir(upper)? {
+        let entry = entry?;
+        let from = entry.path();
+        let to = lower.join(entry.file_name());
+        let metadata = fs::symlink_metadata(&from)?;
+        let file_type = metadata.file_type();
+        let existing = fs::symlink_metadata(&to).ok();
+
+        if file_type.is_char_device() && std::os::unix::fs::MetadataExt::rdev(&metadata) == 0 {
+            remove(&to)?;
+            continue;
+        }
+        if file_type.is_dir() {
+            if is_opaque(&from) || existing.as_ref().is_some_and(|m| !m.is_dir()) {
+                remove(&to)?;
+            }
+            if !fs::symlink_metadata(&to).is_ok_and(|m| m.is_dir()) {
+                fs::create_dir(&to)?;
+            }
+            merge(&from, &to)?;
+        } else if file_type.is_symlink() {
+            remove(&to)?;
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+        } else if file_type.is_file() {
+            if existing.as_ref().is_some_and(|m| !m.is_file()) {
+                remove(&to)?;
+            }
+            fs::copy(&from, &to)?;
+        } else {
+            continue;
+        }
+        copy_metadata(&metadata, &to)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn merge(_upper: &Path, _lower: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Whether overlayfs marked the directory as replacing the one below it.
+#[cfg(target_os = "linux")]
+fn is_opaque(dir: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut value = [0u8; 1];
+    // SAFETY: `path` and `value` outlive the call and the length matches.
+    let len = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            c"trusted.overlay.opaque".as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    len == 1 && value[0] == b'y'
+}
+
+/// Remove a file, symlink or directory tree if it exists.
+#[cfg(target_os = "linux")]
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+fn copy_metadata(metadata: &fs::Metadata, path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        std::os::unix::fs::lchown(path, Some(metadata.uid()), Some(metadata.gid()))?;
+    }
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(path, metadata.permissions())?;
+    }
+    Ok(())
+}
@@ -1,15 +1,26 @@
+pub mod attribution;
 pub mod error;
 pub mod executor;
+pub mod isolation;
+pub mod limits;
+pub mod mounts;
 pub mod output_buffer;
+#[cfg(unix)]
+pub mod pty;
+#[cfg(unix)]
+pub mod supervisor;
 
 use std::collections::HashMap;
+use std::path::Path;
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::mpsc;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::task::JoinHandle;
 
-use codeagent_control::{HostMessage, VmMessage, parse_host_message, MAX_MESSAGE_SIZE};
+use codeagent_control::{
+    HostMessage, Incoming, LaneSender, Link, VmMessage, lane_channel,
+};
 
+use attribution::CommandThreads;
 use error::ShimError;
 use executor::CommandHandle;
 use output_buffer::OutputBufferConfig;
@@ -17,17 +28,19 @@ use output_buffer::OutputBufferConfig;
 /// The shim's runtime state, tracking currently executing commands.
 struct Shim {
     running_commands: HashMap<u64, CommandHandle>,
-    message_sender: mpsc::UnboundedSender<VmMessage>,
+    command_threads: CommandThreads,
+    message_sender: LaneSender<VmMessage>,
     buffer_config: OutputBufferConfig,
 }
 
 impl Shim {
     fn new(
-        message_sender: mpsc::UnboundedSender<VmMessage>,
+        message_sender: LaneSender<VmMessage>,
         buffer_config: OutputBufferConfig,
     ) -> Self {
         Self {
             running_commands: HashMap::new(),
+            command_threads: CommandThreads::default(),
             message_sender,
             buffer_config,
         }
@@ -41,18 +54,38 @@ impl Shim {
                 command,
                 cwd,
                 env,
+                isolate_fs,
+                pty,
+                max_output_bytes,
+                limits,
             } => {
+                let buffer_config = OutputBufferConfig {
+                    max_output_bytes,
+                    ..self.buffer_config.clone()
+                };
                 let handle = executor::spawn_command(
                     id,
                     &command,
                     cwd.as_deref(),
                     env.as_ref(),
+                    isolate_fs,
+                    pty,
+                    self.command_threads.clone(),
                     self.message_sender.clone(),
-                    self.buffer_config.clone(),
+                    buffer_config,
+                    limits,
                 )?;
                 self.running_commands.insert(id, handle);
                 Ok(())
             }
+            HostMessage::Input { id, data, eof } => {
+                let handle = self
+                    .running_commands
+                    .get_mut(&id)
+                    .ok_or(ShimError::CommandNotFound { id })?;
+                handle.send_input(data, eof);
+                Ok(())
+            }
             HostMessage::Cancel { id } => {
                 if let Some(handle) = self.running_commands.remove(&id) {
                     // Spawn cancel as a task so we don't block the message loop.
@@ -64,6 +97,25 @@ impl Shim {
                 // Informational only — the host already rolled back the filesystem.
                 Ok(())
             }
+            HostMessage::ResolvePid { pid } => {
+                let leaders: HashMap<u32, u64> = self
+                    .running_commands
+                    .iter()
+                    .filter(|(_, handle)| !handle.is_finished())
+                    .filter_map(|(&id, handle)| Some((handle.pid()?, id)))
+                    .collect();
+                let id = attribution::resolve(pid, &leaders, &self.command_threads);
+                let name = attribution::process_name(pid);
+                let _ = self.message_sender.send(VmMessage::PidResolved { pid, id, name });
+                Ok(())
+            }
+            HostMessage::Mount { tags } => {
+                // Mounted inline, holding up the messages behind it, so the
+                // commands the host sends next find their directories.
+                let failed = mounts::mount_shares(Path::new(mounts::MOUNT_ROOT), &tags);
+                let _ = self.message_sender.send(VmMessage::Mounted { failed });
+                Ok(())
+            }
         }
     }
 
@@ -92,36 +144,33 @@ where
     R: tokio::io::AsyncRead + Unpin,
     W: tokio::io::AsyncWrite + Unpin + Send + 'static,
 {
-    let (message_sender, mut message_receiver) = mpsc::unbounded_channel::<VmMessage>();
+    let (message_sender, message_receiver) = lane_channel::<VmMessage>();
     let mut shim = Shim::new(message_sender, OutputBufferConfig::default());
 
     let mut lines = BufReader::new(reader).lines();
 
-    // Writer task: serialize VmMessages as JSON Lines to the output.
+    // The host offers protocol version 2 when it opens the channel; until
+    // then the link writes bare JSON Lines.
+    let link = Link::new();
+
+    // Writer task: serialize VmMessages as JSON Lines to the output, step
+    // starts and pid answers ahead of queued output.
+    let writer_link = link.clone();
     let writer_handle: JoinHandle<Result<(), ShimError>> = tokio::spawn(async move {
-        let mut writer = tokio::io::BufWriter::new(writer);
-        while let Some(msg) = message_receiver.recv().await {
-            let json = serde_json::to_string(&msg)?;
-            writer.write_all(json.as_bytes()).await?;
-            writer.write_all(b"\n").await?;
-            writer.flush().await?;
-        }
+        writer_link.run_writer(message_receiver, writer).await?;
         Ok(())
     });
 
     // Reader loop: parse host messages and dispatch.
     while let Ok(Some(line)) = lines.next_line().await {
-        if line.len() > MAX_MESSAGE_SIZE {
-            eprintln!("skipping oversized message ({} bytes)", line.len());
-            continue;
-        }
-        match parse_host_message(&line) {
-            Ok(msg) => {
+        match link.decode::<HostMessage>(&line) {
+            Incoming::Message(msg) => {
                 if let Err(error) = shim.handle_message(msg) {
                     eprintln!("error handling message: {error}");
                 }
             }
-            Err(error) => {
+            Incoming::Nothing => {}
+            Incoming::Invalid(error) => {
                 eprintln!("parse error: {error}");
             }
         }
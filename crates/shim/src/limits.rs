@@ -0,0 +1,256 @@
+//! Resource limits for one command.
+//!
+//! Memory and process limits go on a cgroup v2 group of the command's own
+//! under `/sys/fs/cgroup/codeagent/`, which covers every process the command
+//! starts, and tells afterwards whether a limit was hit. Where the guest has
+//! no usable cgroup v2 hierarchy they fall back to `setrlimit`: `RLIMIT_AS`
+//! per process, and `RLIMIT_NPROC`, which counts every process of the
+//! sandbox user rather than just the command's. CPU time has no cgroup cap,
+//! so `cpu_seconds` is always `RLIMIT_CPU` on each process; one that runs out
+//! gets `SIGXCPU`.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use codeagent_control::{ResourceLimit, ResourceLimits};
+
+/// Where the cgroup v2 hierarchy is mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The limits of one command and the cgroup enforcing them, if any.
+pub struct CommandLimits {
+    limits: ResourceLimits,
+    cgroup: Option<PathBuf>,
+    enforcer: LimitEnforcer,
+}
+
+/// What the forked child needs to put itself under the limits. Built before
+/// the fork, since the child may not allocate.
+#[derive(Clone, Default)]
+pub struct LimitEnforcer {
+    /// `cgroup.procs` of the command's cgroup.
+    cgroup_procs: Option<CString>,
+    /// `(resource, limit)` pairs for `setrlimit`.
+    #[cfg(unix)]
+    rlimits: Vec<(libc::c_int, libc::rlim_t)>,
+}
+
+impl CommandLimits {
+    /// Set up `limits` for command `id`. Never fails: whatever the cgroup
+    /// cannot enforce falls back to `setrlimit`.
+    pub fn prepare(id: u64, limits: ResourceLimits) -> Self {
+        let wants_cgroup = limits.memory_bytes.is_some() || limits.max_processes.is_some();
+        let cgroup = if wants_cgroup {
+            match create_cgroup(Path::new(CGROUP_ROOT), id, &limits) {
+                Ok(group) => Some(group),
+                Err(error) => {
+                    eprintln!("cgroup limits unavailable, using setrlimit: {error}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let cgroup_procs = cgroup.as_ref().and_then(|group| {
+            let procs = group.join("cgroup.procs").into_os_string();
+            CString::new(procs.into_encoded_bytes()).ok()
+        });
+        #[cfg(unix)]
+        let rlimits = {
+            let mut rlimits = Vec::new();
+            if let Some(seconds) = limits.cpu_seconds {
+                rlimits.push((libc::RLIMIT_CPU as libc::c_int, seconds as libc::rlim_t));
+            }
+            if cgroup_procs.is_none() {
+                if let Some(bytes) = limits.memory_bytes {
+                    rlimits.push((libc::RLIMIT_AS as libc::c_int, bytes as libc::rlim_t));
+                }
+                if let Some(processes) = limits.max_processes {
+                    rlimits.push((libc::RLIMIT_NPROC as libc::c_int, processes as libc::rlim_t));
+                }
+            }
+            rlimits
+        };
+        Self {
+            limits,
+            cgroup,
+            enforcer: LimitEnforcer {
+                cgroup_procs,
+                #[cfg(unix)]
+                rlimits,
+            },
+        }
+    }
+
+    pub fn enforcer(&self) -> LimitEnforcer {
+        self.enforcer.clone()
+    }
+
+    /// The limit that stopped the command, judging by the cgroup's event
+    /// counters and by how the shell exited.
+    pub fn exceeded(&self, status: Option<ExitStatus>) -> Option<ResourceLimit> {
+        if let Some(group) = &self.cgroup {
+            if self.limits.memory_bytes.is_some()
+                && event_count(&group.join("memory.events"), "oom_kill") > 0
+            {
+                return Some(ResourceLimit::Memory);
+            }
+            if self.limits.max_processes.is_some()
+                && event_count(&group.join("pids.events"), "max") > 0
+            {
+                return Some(ResourceLimit::Processes);
+            }
+        }
+        #[cfg(unix)]
+        if self.limits.cpu_seconds.is_some() {
+            use std::os::unix::process::ExitStatusExt;
+            let status = status?;
+            // The shell reports a child killed by a signal as 128 + signal.
+            if status.signal() == Some(libc::SIGXCPU)
+                || status.code() == Some(128 + libc::SIGXCPU)
+            {
+                return Some(ResourceLimit::Cpu);
+            }
+        }
+        let _ = status;
+        None
+    }
+
+    /// Remove the command's cgroup, killing anything still left in it.
+    pub fn finish(self) {
+        if let Some(group) = &self.cgroup {
+            let _ = fs::write(group.join("cgroup.kill"), "1");
+            let _ = fs::remove_dir(group);
+        }
+    }
+}
+
+impl LimitEnforcer {
+    /// Run in the forked child before exec, while it is still root: join the
+    /// command's cgroup and set the rlimits.
+    pub fn enter(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            // SAFETY: only syscalls on memory allocated before the fork.
+            unsafe {
+                if let Some(procs) = &self.cgroup_procs {
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    // "0" moves the writing process.
+                    let written = libc::write(fd, c"0".as_ptr().cast(), 1);
+                    libc::close(fd);
+                    if written != 1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                for &(resource, limit) in &self.rlimits {
+                    // A second of grace past the soft limit turns SIGXCPU
+                    // into SIGKILL for a process that ignores it.
+                    let hard = if resource == libc::RLIMIT_CPU as libc::c_int {
+                        limit.saturating_add(1)
+                    } else {
+                        limit
+                    };
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit,
+                        rlim_max: hard,
+                    };
+                    if libc::setrlimit(resource as _, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create the cgroup for command `id` under `root` with the memory and
+/// process limits of `limits`.
+fn create_cgroup(root: &Path, id: u64, limits: &ResourceLimits) -> io::Result<PathBuf> {
+    if !root.join("cgroup.controllers").is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no cgroup v2 hierarchy",
+        ));
+    }
+    let mut controllers = Vec::new();
+    if limits.memory_bytes.is_some() {
+        controllers.push("+memory");
+    }
+    if limits.max_processes.is_some() {
+        controllers.push("+pids");
+    }
+    let controllers = controllers.join(" ");
+
+    let parent = root.join("codeagent");
+    fs::create_dir_all(&parent)?;
+    fs::write(root.join("cgroup.subtree_control"), &controllers)?;
+    fs::write(parent.join("cgroup.subtree_control"), &controllers)?;
+
+    let group = parent.join(format!("{}-{id}", std::process::id()));
+    let _ = fs::remove_dir(&group);
+    fs::create_dir(&group)?;
+    let configured = (|| {
+        if let Some(bytes) = limits.memory_bytes {
+            fs::write(group.join("memory.max"), bytes.to_string())?;
+            // Swapping out would let the command use more than its limit.
+            let _ = fs::write(group.join("memory.swap.max"), "0");
+        }
+        if let Some(processes) = limits.max_processes {
+            fs::write(group.join("pids.max"), processes.to_string())?;
+        }
+        Ok(())
+    })();
+    if let Err(error) = configured {
+        let _ = fs::remove_dir(&group);
+        return Err(error);
+    }
+    Ok(group)
+}
+
+/// The value of `key` in a cgroup `*.events` file, 0 if missing.
+fn event_count(path: &Path, key: &str) -> u64 {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|events| {
+            events.lines().find_map(|line| {
+                let (name, count) = line.split_once(' ')?;
+                (name == key).then(|| count.trim().parse().ok()).flatten()
+            })
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_counts_are_read_by_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let events = dir.path().join("memory.events");
+        fs::write(&events, "low 0\nhigh 0\nmax 3\noom 1\noom_kill 1\n").unwrap();
+        assert_eq!(event_count(&events, "oom_kill"), 1);
+        assert_eq!(event_count(&events, "max"), 3);
+        assert_eq!(event_count(&events, "oom_group_kill"), 0);
+        assert_eq!(event_count(&dir.path().join("missing"), "max"), 0);
+    }
+
+    #[test]
+    fn cgroups_need_a_v2_hierarchy() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = ResourceLimits {
+            memory_bytes: Some(1 << 20),
+            ..Default::default()
+        };
+        let error = create_cgroup(dir.path(), 1, &limits).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -1,11 +1,22 @@
 use tokio::fs::OpenOptions;
 
-#[tokio::main]
-async fn main() {
-    let device_path = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "/dev/virtio-ports/control".to_string());
+const DEFAULT_DEVICE: &str = "/dev/virtio-ports/control";
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let first = args.next();
+
+    #[cfg(unix)]
+    if first.as_deref() == Some("--supervise") {
+        let device_path = args.next().unwrap_or_else(|| DEFAULT_DEVICE.to_string());
+        codeagent_shim::supervisor::run(&device_path);
+    }
 
+    run_shim(first.unwrap_or_else(|| DEFAULT_DEVICE.to_string()));
+}
+
+#[tokio::main]
+async fn run_shim(device_path: String) {
     let file = OpenOptions::new()
         .read(true)
         .write(true)
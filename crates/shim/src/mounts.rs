@@ -0,0 +1,133 @@
+//! Mounts working directories the host shares after boot.
+//!
+//! The guest init mounts the shares named on the kernel command line. A VM
+//! booted ahead of its session has none; the host hot-plugs a vhost-user-fs
+//! device per working directory when the session starts and sends `mount`
+//! with their tags. The new PCI devices take a moment to probe, so a tag the
+//! kernel does not know yet is retried for up to [`TAG_WAIT`].
+//!
+//! When the host gives the devices a DAX window it passes `virtiofs_dax=` on
+//! the kernel command line, and shares are mounted with that `dax=` mode,
+//! falling back to a plain mount where the kernel refuses it.
+
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use codeagent_control::MountFailure;
+
+/// Where working directories are mounted, one directory per tag.
+pub const MOUNT_ROOT: &str = "/mnt/working";
+
+/// How long a hot-plugged share may take to show up.
+const TAG_WAIT: Duration = Duration::from_secs(5);
+
+const TAG_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The `virtiofs_dax=` mode on `cmdline`, if the host set one.
+fn dax_mode(cmdline: &str) -> Option<&str> {
+    cmdline
+        .split_whitespace()
+        .find_map(|param| param.strip_prefix("virtiofs_dax="))
+        .filter(|mode| !mode.is_empty())
+}
+
+/// Mount each of `tags` under `root`. Returns the ones that failed.
+pub fn mount_shares(root: &Path, tags: &[String]) -> Vec<MountFailure> {
+    tags.iter()
+        .filter_map(|tag| {
+            let reason = mount_share(root, tag).err()?.to_string();
+            Some(MountFailure {
+                tag: tag.clone(),
+                reason,
+            })
+        })
+        .collect()
+}
+
+fn mount_share(root: &Path, tag: &str) -> io::Result<()> {
+    if tag.is_empty() || tag.starts_with('.') || tag.contains('/') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a mount name",
+        ));
+    }
+    let target = root.join(tag);
+    std::fs::create_dir_all(&target)?;
+    let cmdline = std::fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    let options = dax_mode(&cmdline).map(|mode| format!("dax={mode}"));
+    let started = Instant::now();
+    loop {
+        let result = match mount_virtiofs(tag, &target, options.as_deref()) {
+            Err(error) if options.is_some() && error.kind() != io::ErrorKind::NotFound => {
+                mount_virtiofs(tag, &target, None)
+            }
+            result => result,
+        };
+        match result {
+            Err(error)
+                if error.kind() == io::ErrorKind::NotFound && started.elapsed() < TAG_WAIT =>
+            {
+                std::thread::sleep(TAG_POLL_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn mount_virtiofs(tag: &str, target: &Path, options: Option<&str>) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let source = CString::new(tag).map_err(io::Error::other)?;
+    let target = CString::new(target.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let options = options.map(CString::new).transpose().map_err(io::Error::other)?;
+    let data = options.as_ref().map_or(std::ptr::null(), |options| options.as_ptr().cast());
+    // SAFETY: all pointers are NUL-terminated strings or null, and outlive
+    // the call.
+    let result = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            c"virtiofs".as_ptr(),
+            0,
+            data,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_virtiofs(_tag: &str, _target: &Path, _options: Option<&str>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "virtiofs mounts need Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_that_are_not_mount_names_are_refused() {
+        let root = tempfile::tempdir().unwrap();
+        let tags = ["".to_string(), "../etc".to_string(), ".hidden".to_string()];
+        let failed = mount_shares(root.path(), &tags);
+        assert_eq!(failed.len(), 3);
+        assert!(failed.iter().all(|failure| failure.reason == "not a mount name"));
+        assert_eq!(std::fs::read_dir(root.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn dax_mode_is_read_from_the_command_line() {
+        let cmdline = "console=hvc0 virtiofs_dax=inode mount_names=work\n";
+        assert_eq!(dax_mode(cmdline), Some("inode"));
+        assert_eq!(dax_mode("console=hvc0 virtiofs_dax="), None);
+        assert_eq!(dax_mode("console=hvc0"), None);
+    }
+}
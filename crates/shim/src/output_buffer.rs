@@ -1,3 +1,5 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Duration;
 
 /// Configuration for output buffering between the child process and
@@ -9,6 +11,9 @@ pub struct OutputBufferConfig {
     pub max_buffer_size: usize,
     /// Maximum time between flushes. Default: 50ms.
     pub flush_interval: Duration,
+    /// Most bytes a command may send across stdout and stderr, set per
+    /// command by `exec`. Default: unlimited.
+    pub max_output_bytes: Option<u64>,
 }
 
 impl Default for OutputBufferConfig {
@@ -16,6 +21,92 @@ impl Default for OutputBufferConfig {
         Self {
             max_buffer_size: 4096,
             flush_interval: Duration::from_millis(50),
+            max_output_bytes: None,
         }
     }
 }
+
+/// What is left of one command's `max_output_bytes`, shared by the tasks
+/// streaming its stdout and stderr. Clones share the same budget.
+#[derive(Debug, Clone, Default)]
+pub struct OutputLimit {
+    remaining: Option<Arc<AtomicU64>>,
+    truncated: Arc<AtomicBool>,
+}
+
+impl OutputLimit {
+    pub fn new(max_output_bytes: Option<u64>) -> Self {
+        Self {
+            remaining: max_output_bytes.map(|max| Arc::new(AtomicU64::new(max))),
+            truncated: Arc::default(),
+        }
+    }
+
+    /// Cut `chunk` down to what the budget still allows, using that much of
+    /// it. A chunk cut short ends at a UTF-8 character boundary, so the
+    /// last character sent is never split.
+    pub fn admit(&self, chunk: &mut Vec<u8>) {
+        let Some(remaining) = &self.remaining else {
+            return;
+        };
+        let wanted = chunk.len() as u64;
+        let mut allowed = 0;
+        let _ = remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+            allowed = left.min(wanted);
+            Some(left - allowed)
+        });
+        if allowed < wanted {
+            chunk.truncate(allowed as usize);
+            if let Err(error) = std::str::from_utf8(chunk) {
+                if error.error_len().is_none() {
+                    chunk.truncate(error.valid_up_to());
+                }
+            }
+            self.truncated.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether any output was dropped.
+    pub fn truncated(&self) -> bool {
+        self.truncated.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_share_one_budget() {
+        let limit = OutputLimit::new(Some(8));
+        let stderr = limit.clone();
+
+        let mut chunk = b"hello".to_vec();
+        limit.admit(&mut chunk);
+        assert_eq!(chunk, b"hello");
+        assert!(!limit.truncated());
+
+        let mut chunk = b"world".to_vec();
+        stderr.admit(&mut chunk);
+        assert_eq!(chunk, b"wor");
+        assert!(limit.truncated());
+
+        let mut chunk = b"more".to_vec();
+        limit.admit(&mut chunk);
+        assert!(chunk.is_empty());
+    }
+
+    #[test]
+    fn truncation_keeps_whole_characters() {
+        let limit = OutputLimit::new(Some(2));
+        let mut chunk = "aé".repeat(2).into_bytes();
+        limit.admit(&mut chunk);
+        assert_eq!(chunk, b"a");
+
+        let unlimited = OutputLimit::new(None);
+        let mut chunk = vec![b'x'; 1 << 20];
+        unlimited.admit(&mut chunk);
+        assert_eq!(chunk.len(), 1 << 20);
+        assert!(!unlimited.truncated());
+    }
+}
@@ -0,0 +1,107 @@
+//! Pseudo-terminals for `exec` commands with `pty`.
+//!
+//! The command's stdin, stdout and stderr are all the slave side of the
+//! terminal, and the shell starts a new session with the terminal as its
+//! controlling terminal, so REPLs, prompts and anything else that checks
+//! `isatty` behave as they would for a user. The shim keeps the master side:
+//! what it reads there is the command's output, what it writes is typed
+//! input.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::Stdio;
+
+use crate::error::ShimError;
+
+/// Window size the command sees. Nothing resizes it afterwards.
+const ROWS: u16 = 24;
+const COLUMNS: u16 = 80;
+
+/// Both sides of a freshly allocated terminal.
+pub struct Pty {
+    master: OwnedFd,
+    slave: OwnedFd,
+}
+
+impl Pty {
+    pub fn open() -> Result<Self, ShimError> {
+        let mut master = -1;
+        let mut slave = -1;
+        let size = libc::winsize {
+            ws_row: ROWS,
+            ws_col: COLUMNS,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: the descriptor pointers are valid for writes, the name and
+        // termios pointers may be null, and `size` outlives the call.
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+        if result != 0 {
+            return Err(ShimError::Terminal {
+                reason: io::Error::last_os_error().to_string(),
+            });
+        }
+        // SAFETY: openpty returned two new descriptors that nothing else owns.
+        let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        // The command gets the slave side as its stdio only; neither
+        // descriptor itself should survive exec.
+        for fd in [&master, &slave] {
+            set_cloexec(fd)?;
+        }
+        Ok(Self { master, slave })
+    }
+
+    /// A handle to the slave side, for one of the command's stdio streams.
+    pub fn slave(&self) -> io::Result<Stdio> {
+        Ok(Stdio::from(self.slave.try_clone()?))
+    }
+
+    /// Close the shim's copy of the slave side and return the master side
+    /// twice, once to read output from and once to write input to.
+    ///
+    /// Reads fail with `EIO` once every process holding the slave side has
+    /// exited, which ends the output stream.
+    pub fn into_master(self) -> io::Result<(tokio::fs::File, tokio::fs::File)> {
+        let Self { master, slave } = self;
+        drop(slave);
+        let writer = master.try_clone()?;
+        Ok((
+            tokio::fs::File::from_std(File::from(master)),
+            tokio::fs::File::from_std(File::from(writer)),
+        ))
+    }
+}
+
+/// Run in the forked child before exec: start a new session, whose process
+/// group the command's id refers to as with `setpgid`, and make the terminal
+/// on stdin its controlling terminal.
+pub fn attach_controlling_terminal() -> io::Result<()> {
+    // SAFETY: plain syscalls on the child's own descriptors.
+    unsafe {
+        if libc::setsid() < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn set_cloexec(fd: &OwnedFd) -> Result<(), ShimError> {
+    // SAFETY: `fd` is an open descriptor for the duration of the call.
+    let result = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+    if result < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
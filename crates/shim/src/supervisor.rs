@@ -0,0 +1,262 @@
+//! Keeps the shim running as the guest's init process.
+//!
+//! `shim --supervise <device>` runs as PID 1 and starts the shim proper as
+//! its child, reaping every orphan the guest leaves behind in the meantime.
+//! When the shim exits abnormally the supervisor kills whatever its commands
+//! left running, tells the host with a `shim_restarted` message and starts a
+//! new shim. A clean exit means the control channel closed, and the
+//! supervisor exits with it.
+
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use codeagent_control::VmMessage;
+
+/// How many of the shim's last stderr lines go into `shim_restarted`.
+pub const STDERR_TAIL_LINES: usize = 20;
+
+/// A shim that ran for less than this before crashing counts as a rapid
+/// crash. Restarts are delayed longer after each one in a row.
+const RAPID_CRASH_WINDOW: Duration = Duration::from_secs(10);
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+
+/// After this many rapid crashes in a row the supervisor gives up, so a
+/// shim that cannot start does not spin forever.
+const MAX_RAPID_CRASHES: u32 = 5;
+
+/// How a shim process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ShimExit {
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+}
+
+impl ShimExit {
+    fn from_wait_status(status: libc::c_int) -> Self {
+        if libc::WIFSIGNALED(status) {
+            Self {
+                exit_code: None,
+                signal: Some(libc::WTERMSIG(status)),
+            }
+        } else {
+            Self {
+                exit_code: Some(libc::WEXITSTATUS(status)),
+                signal: None,
+            }
+        }
+    }
+
+    fn is_clean(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// The last [`STDERR_TAIL_LINES`] lines of a stream.
+#[derive(Debug, Default)]
+struct StderrTail {
+    lines: VecDeque<String>,
+}
+
+impl StderrTail {
+    fn push(&mut self, line: String) {
+        if self.lines.len() == STDERR_TAIL_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    fn take(&mut self) -> Vec<String> {
+        self.lines.drain(..).collect()
+    }
+}
+
+/// Supervise shims on `device_path` until one exits cleanly, then exit.
+pub fn run(device_path: &str) -> ! {
+    let mut restarts = 0;
+    let mut rapid_crashes = 0;
+    loop {
+        let started = Instant::now();
+        let (exit, stderr) = match run_once(device_path) {
+            Ok(result) => result,
+            Err(error) => {
+                eprintln!("supervisor: failed to start shim: {error}");
+                std::process::exit(1);
+            }
+        };
+        if exit.is_clean() {
+            std::process::exit(0);
+        }
+
+        restarts += 1;
+        eprintln!("supervisor: shim ended with {exit:?}, restarting (restart {restarts})");
+        let message = VmMessage::ShimRestarted {
+            exit_code: exit.exit_code,
+            signal: exit.signal,
+            stderr,
+            restarts,
+        };
+        if let Err(error) = report(device_path, &message) {
+            eprintln!("supervisor: failed to report shim crash: {error}");
+        }
+
+        if started.elapsed() < RAPID_CRASH_WINDOW {
+            rapid_crashes += 1;
+        } else {
+            rapid_crashes = 0;
+        }
+        if rapid_crashes > MAX_RAPID_CRASHES {
+            eprintln!("supervisor: shim crashed {rapid_crashes} times in a row, giving up");
+            std::process::exit(1);
+        }
+        std::thread::sleep(RESTART_DELAY * rapid_crashes);
+    }
+}
+
+/// Run one shim to completion. Returns how it ended and the tail of its
+/// stderr, which is also copied to the console as it arrives.
+fn run_once(device_path: &str) -> io::Result<(ShimExit, Vec<String>)> {
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg(device_path)
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let pid = child.id() as libc::pid_t;
+    // Read while the shim still exists; its /proc entry goes once reaped.
+    let start_time = read_start_time(pid as u32);
+
+    let tail = Arc::new(Mutex::new(StderrTail::default()));
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let tail_writer = Arc::clone(&tail);
+    let copier = std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            eprintln!("{line}");
+            tail_writer.lock().unwrap().push(line);
+        }
+    });
+    // The child is reaped below with waitpid, not through `Child`.
+    drop(child);
+
+    let exit = loop {
+        let mut status = 0;
+        // SAFETY: `status` is valid for writes for the duration of the call.
+        let reaped = unsafe { libc::waitpid(-1, &mut status, 0) };
+        if reaped == pid {
+            break ShimExit::from_wait_status(status);
+        }
+        if reaped < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+        // Anything else was an orphan reparented to PID 1.
+    };
+
+    if !exit.is_clean() {
+        if let Some(start_time) = start_time {
+            kill_started_since(start_time);
+        }
+    }
+    // Commands do not inherit the shim's stderr, so this ends promptly once
+    // the shim is gone.
+    let _ = copier.join();
+    let stderr = tail.lock().unwrap().take();
+    Ok((exit, stderr))
+}
+
+/// Kill every process that started at or after `start_time` (in clock
+/// ticks since boot), which is what is left of the crashed shim's commands.
+/// Daemons started before the shim, such as `p9proxy`, are left alone.
+fn kill_started_since(start_time: u64) {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return;
+    };
+    let own_pid = std::process::id();
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+        if pid == own_pid {
+            continue;
+        }
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat")) else {
+            continue;
+        };
+        // Kernel threads are children of kthreadd (pid 2).
+        let Some((parent, started)) = parse_stat(&stat) else {
+            continue;
+        };
+        if pid != 2 && parent != 2 && started >= start_time {
+            // SAFETY: kill has no memory-safety preconditions.
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+    // Reap those already gone; the rest are reaped as orphans while the next
+    // shim runs.
+    // SAFETY: a null status pointer is allowed.
+    while unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) } > 0 {}
+}
+
+fn read_start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    parse_stat(&stat).map(|(_, started)| started)
+}
+
+/// Parent pid and start time from the contents of `/proc/<pid>/stat`.
+fn parse_stat(stat: &str) -> Option<(u32, u64)> {
+    // As in `attribution`, the fixed fields start after the last ')'; the
+    // start time is the 22nd field overall.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    let mut fields = rest.split_whitespace();
+    let parent = fields.nth(1)?.parse().ok()?;
+    let started = fields.nth(17)?.parse().ok()?;
+    Some((parent, started))
+}
+
+/// Write `message` to the control device. The leading newline ends any line
+/// the crashed shim left half-written, so the host can parse this one.
+fn report(device_path: &str, message: &VmMessage) -> io::Result<()> {
+    let json = serde_json::to_string(message)?;
+    let mut device = OpenOptions::new().write(true).open(device_path)?;
+    device.write_all(format!("\n{json}\n").as_bytes())?;
+    device.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_time_is_the_twenty_second_field() {
+        let stat = "4242 (npm run (dev)) S 4100 4099 4099 0 -1 4194560 1234 0 0 0 \
+                    5 3 0 0 20 0 1 0 98765 12345678 300";
+        assert_eq!(parse_stat(stat), Some((4100, 98765)));
+        assert_eq!(parse_stat("4242 (sh) S 1 4242"), None);
+    }
+
+    #[test]
+    fn stderr_tail_keeps_the_last_lines() {
+        let mut tail = StderrTail::default();
+        for line in 0..STDERR_TAIL_LINES + 5 {
+            tail.push(line.to_string());
+        }
+        let lines = tail.take();
+        assert_eq!(lines.len(), STDERR_TAIL_LINES);
+        assert_eq!(lines[0], "5");
+        assert!(tail.take().is_empty());
+    }
+
+    #[test]
+    fn wait_status_decoding() {
+        assert!(ShimExit::from_wait_status(0).is_clean());
+        let crashed = ShimExit::from_wait_status(101 << 8);
+        assert_eq!(crashed, ShimExit { exit_code: Some(101), signal: None });
+        let killed = ShimExit::from_wait_status(libc::SIGSEGV);
+        assert_eq!(killed, ShimExit { exit_code: None, signal: Some(libc::SIGSEGV) });
+    }
+}
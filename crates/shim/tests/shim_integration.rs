@@ -9,7 +9,7 @@ use std::time::Duration;
 
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 
-use codeagent_control::{HostMessage, VmMessage};
+use codeagent_control::{HostMessage, ResourceLimit, ResourceLimits, VmMessage};
 
 /// Send a `HostMessage` as a JSON Line to the writer.
 async fn send_message<W: AsyncWriteExt + Unpin>(writer: &mut W, msg: &HostMessage) {
@@ -48,6 +48,16 @@ async fn collect_until_completed(
     }
 }
 
+/// The `StepCompleted` of a command whose output was not truncated.
+fn step_completed(id: u64, exit_code: i32) -> VmMessage {
+    VmMessage::StepCompleted {
+        id,
+        exit_code,
+        output_truncated: false,
+        limit_exceeded: None,
+    }
+}
+
 /// Spawn the shim on a duplex pair and return (host_writer, host_reader_lines).
 fn spawn_shim() -> (
     tokio::io::DuplexStream,
@@ -77,6 +87,10 @@ async fn sh_01_echo_hello() {
         command: "echo hello".to_string(),
         cwd: None,
         env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
     };
     send_message(&mut writer, &msg).await;
 
@@ -103,7 +117,7 @@ async fn sh_01_echo_hello() {
     );
 
     // StepCompleted with exit_code 0
-    assert_eq!(completed, VmMessage::StepCompleted { id: 1, exit_code: 0 });
+    assert_eq!(completed, step_completed(1, 0));
 }
 
 /// SH-02: Failing command returns correct exit code.
@@ -116,6 +130,10 @@ async fn sh_02_failing_command() {
         command: "exit 42".to_string(),
         cwd: None,
         env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
     };
     send_message(&mut writer, &msg).await;
 
@@ -124,7 +142,9 @@ async fn sh_02_failing_command() {
         completed,
         VmMessage::StepCompleted {
             id: 1,
-            exit_code: 42
+            exit_code: 42,
+            output_truncated: false,
+            limit_exceeded: None,
         }
     );
 }
@@ -139,6 +159,10 @@ async fn sh_03_stderr_output() {
         command: "echo err >&2".to_string(),
         cwd: None,
         env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
     };
     send_message(&mut writer, &msg).await;
 
@@ -175,13 +199,17 @@ async fn sh_04_exec_with_cwd() {
         command: "ls shim_test_marker.txt".to_string(),
         cwd: Some(cwd_path),
         env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
     };
     send_message(&mut writer, &msg).await;
 
     let (messages, completed) = collect_until_completed(&mut lines, 1).await;
 
     // The command should succeed (exit code 0), meaning the file was found
-    assert_eq!(completed, VmMessage::StepCompleted { id: 1, exit_code: 0 });
+    assert_eq!(completed, step_completed(1, 0));
 
     let output_data: String = messages
         .iter()
@@ -210,6 +238,10 @@ async fn sh_05_exec_with_env() {
         command: "echo $MY_TEST_VAR".to_string(),
         cwd: None,
         env: Some(env),
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
     };
     send_message(&mut writer, &msg).await;
 
@@ -245,6 +277,10 @@ async fn sh_06_cancel_running_command() {
         command: "sleep 100".to_string(),
         cwd: None,
         env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
     };
     send_message(&mut writer, &exec_msg).await;
 
@@ -265,7 +301,7 @@ async fn sh_06_cancel_running_command() {
     // Should get StepCompleted with non-zero exit code
     let (_messages, completed) = collect_until_completed(&mut lines, 1).await;
     match completed {
-        VmMessage::StepCompleted { id, exit_code } => {
+        VmMessage::StepCompleted { id, exit_code, .. } => {
             assert_eq!(id, 1);
             assert_ne!(exit_code, 0, "cancelled command should have non-zero exit code");
         }
@@ -284,12 +320,20 @@ async fn sh_07_concurrent_commands() {
         command: "echo first".to_string(),
         cwd: None,
         env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
     };
     let msg2 = HostMessage::Exec {
         id: 2,
         command: "echo second".to_string(),
         cwd: None,
         env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
     };
     send_message(&mut writer, &msg1).await;
     send_message(&mut writer, &msg2).await;
@@ -305,14 +349,17 @@ async fn sh_07_concurrent_commands() {
             .expect("timed out waiting for both commands to complete");
 
         match &msg {
-            VmMessage::StepCompleted { id, exit_code } => {
+            VmMessage::StepCompleted { id, exit_code, .. } => {
                 assert_eq!(*exit_code, 0);
                 completed_ids.push(*id);
             }
             VmMessage::Output { id, data, .. } => {
                 all_output.entry(*id).or_default().push_str(data);
             }
-            VmMessage::StepStarted { .. } => {}
+            VmMessage::StepStarted { .. }
+            | VmMessage::PidResolved { .. }
+            | VmMessage::ShimRestarted { .. }
+            | VmMessage::Mounted { .. } => {}
         }
     }
 
@@ -337,3 +384,294 @@ async fn sh_08_graceful_shutdown() {
     let result = tokio::time::timeout(Duration::from_secs(5), handle).await;
     assert!(result.is_ok(), "shim should exit within timeout");
 }
+
+/// Whether this process may create mount namespaces, which `isolate_fs`
+/// needs.
+#[cfg(target_os = "linux")]
+fn can_isolate() -> bool {
+    // SAFETY: geteuid has no preconditions.
+    let root = unsafe { libc::geteuid() } == 0;
+    root && std::process::Command::new("unshare")
+            .args(["-m", "true"])
+            .status()
+            .is_ok_and(|status| status.success())
+}
+
+/// SH-09: isolate_fs applies a successful command's changes and discards a
+/// failed command's.
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn sh_09_isolate_fs_is_transactional() {
+    use std::os::unix::fs::PermissionsExt;
+
+    if !can_isolate() {
+        eprintln!("skipping: cannot create mount namespaces");
+        return;
+    }
+    let temp_dir = tempfile::tempdir().unwrap();
+    let root = temp_dir.path();
+    std::fs::set_permissions(root, std::fs::Permissions::from_mode(0o777)).unwrap();
+    for name in ["kept.txt", "doomed.txt"] {
+        std::fs::write(root.join(name), "original").unwrap();
+    }
+    std::fs::set_permissions(root.join("kept.txt"), std::fs::Permissions::from_mode(0o666))
+        .unwrap();
+
+    let (mut writer, mut lines, _handle) = spawn_shim();
+    let exec = |id, command: &str| HostMessage::Exec {
+        id,
+        command: command.to_string(),
+        cwd: Some(root.to_string_lossy().into_owned()),
+        env: None,
+        isolate_fs: true,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
+    };
+
+    send_message(
+        &mut writer,
+        &exec(1, "echo partial > new.txt && echo changed > kept.txt && exit 3"),
+    )
+    .await;
+    let (_, completed) = collect_until_completed(&mut lines, 1).await;
+    assert_eq!(completed, step_completed(1, 3));
+    assert!(!root.join("new.txt").exists());
+    assert_eq!(std::fs::read_to_string(root.join("kept.txt")).unwrap(), "original");
+
+    send_message(
+        &mut writer,
+        &exec(2, "mkdir -p out/nested && echo built > out/nested/new.txt \
+                  && echo changed > kept.txt && rm doomed.txt"),
+    )
+    .await;
+    let (messages, completed) = collect_until_completed(&mut lines, 2).await;
+    assert_eq!(completed, step_completed(2, 0), "{messages:?}");
+    assert_eq!(
+        std::fs::read_to_string(root.join("out/nested/new.txt")).unwrap(),
+        "built\n"
+    );
+    assert_eq!(std::fs::read_to_string(root.join("kept.txt")).unwrap(), "changed\n");
+    assert!(!root.join("doomed.txt").exists());
+}
+
+/// SH-10: `resolve_pid` names the command a background child belongs to,
+/// and the child.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn sh_10_resolve_pid_of_command_child() {
+    let (mut writer, mut lines, _handle) = spawn_shim();
+
+    let msg = HostMessage::Exec {
+        id: 4,
+        command: "sleep 1 & echo $!; wait".to_string(),
+        cwd: None,
+        env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
+    };
+    send_message(&mut writer, &msg).await;
+    let child_pid = loop {
+        if let VmMessage::Output { data, .. } = recv_message(&mut lines).await {
+            break data.trim().parse::<u32>().unwrap();
+        }
+    };
+
+    let sleep = Some("sleep".to_string());
+    for (pid, id, name) in [(child_pid, Some(4), sleep), (u32::MAX, None, None)] {
+        send_message(&mut writer, &HostMessage::ResolvePid { pid }).await;
+        let answer = loop {
+            let msg = recv_message(&mut lines).await;
+            if matches!(msg, VmMessage::PidResolved { .. }) {
+                break msg;
+            }
+        };
+        assert_eq!(answer, VmMessage::PidResolved { pid, id, name });
+    }
+
+    let (_, completed) = collect_until_completed(&mut lines, 4).await;
+    assert_eq!(completed, step_completed(4, 0));
+}
+
+/// Concatenated stdout of the output messages of command `id`.
+fn stdout_of(messages: &[VmMessage], expected_id: u64) -> String {
+    messages
+        .iter()
+        .filter_map(|msg| match msg {
+            VmMessage::Output { id, stream: codeagent_control::OutputStream::Stdout, data }
+                if *id == expected_id =>
+            {
+                Some(data.as_str())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// SH-11: `input` feeds a command's stdin, and `eof` closes it.
+#[tokio::test]
+async fn sh_11_input_reaches_stdin() {
+    let (mut writer, mut lines, _handle) = spawn_shim();
+
+    let msg = HostMessage::Exec {
+        id: 5,
+        command: "read first; echo \"got $first\"; cat".to_string(),
+        cwd: None,
+        env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
+    };
+    send_message(&mut writer, &msg).await;
+    for (data, eof) in [("one\n", false), ("two\n", true)] {
+        let input = HostMessage::Input {
+            id: 5,
+            data: data.to_string(),
+            eof,
+        };
+        send_message(&mut writer, &input).await;
+    }
+
+    let (messages, completed) = collect_until_completed(&mut lines, 5).await;
+    assert_eq!(completed, step_completed(5, 0));
+    assert_eq!(stdout_of(&messages, 5), "got one\ntwo\n");
+}
+
+/// SH-12: with `pty` the command runs on a terminal and reads typed input.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn sh_12_pty_command_reads_terminal() {
+    let (mut writer, mut lines, _handle) = spawn_shim();
+
+    let msg = HostMessage::Exec {
+        id: 6,
+        command: "test -t 0 && test -t 1 && stty -echo && read answer && echo \"answer=$answer\""
+            .to_string(),
+        cwd: None,
+        env: None,
+        isolate_fs: false,
+        pty: true,
+        max_output_bytes: None,
+        limits: ResourceLimits::default(),
+    };
+    send_message(&mut writer, &msg).await;
+    let input = HostMessage::Input {
+        id: 6,
+        data: "yes\n".to_string(),
+        eof: false,
+    };
+    send_message(&mut writer, &input).await;
+
+    let (messages, completed) = collect_until_completed(&mut lines, 6).await;
+    assert_eq!(completed, step_completed(6, 0), "{messages:?}");
+    assert!(stdout_of(&messages, 6).contains("answer=yes\r\n"), "{messages:?}");
+}
+
+/// SH-13: output past `max_output_bytes` is dropped and reported on completion.
+#[tokio::test]
+async fn sh_13_output_is_truncated_at_limit() {
+    let (mut writer, mut lines, _handle) = spawn_shim();
+
+    let msg = HostMessage::Exec {
+        id: 7,
+        command: "head -c 100000 /dev/zero | tr '\\0' x; echo; echo done >&2".to_string(),
+        cwd: None,
+        env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: Some(1000),
+        limits: ResourceLimits::default(),
+    };
+    send_message(&mut writer, &msg).await;
+
+    let (messages, completed) = collect_until_completed(&mut lines, 7).await;
+    assert_eq!(
+        completed,
+        VmMessage::StepCompleted {
+            id: 7,
+            exit_code: 0,
+            output_truncated: true,
+            limit_exceeded: None,
+        }
+    );
+    let sent: usize = messages
+        .iter()
+        .filter_map(|msg| match msg {
+            VmMessage::Output { id: 7, data, .. } => Some(data.len()),
+            _ => None,
+        })
+        .sum();
+    assert_eq!(sent, 1000, "{messages:?}");
+
+    // A command within its limit is not flagged.
+    let msg = HostMessage::Exec {
+        id: 8,
+        command: "echo short".to_string(),
+        cwd: None,
+        env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: Some(1000),
+        limits: ResourceLimits::default(),
+    };
+    send_message(&mut writer, &msg).await;
+    let (_, completed) = collect_until_completed(&mut lines, 8).await;
+    assert_eq!(completed, step_completed(8, 0));
+}
+
+/// SH-14: a command that runs out of CPU time is reported as such.
+#[cfg(unix)]
+#[tokio::test]
+async fn sh_14_cpu_limit_is_reported() {
+    let (mut writer, mut lines, _handle) = spawn_shim();
+
+    let msg = HostMessage::Exec {
+        id: 9,
+        command: "while :; do :; done".to_string(),
+        cwd: None,
+        env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits {
+            cpu_seconds: Some(1),
+            ..Default::default()
+        },
+    };
+    send_message(&mut writer, &msg).await;
+
+    let (messages, completed) = collect_until_completed(&mut lines, 9).await;
+    match completed {
+        VmMessage::StepCompleted {
+            exit_code,
+            limit_exceeded,
+            ..
+        } => {
+            assert_ne!(exit_code, 0, "{messages:?}");
+            assert_eq!(limit_exceeded, Some(ResourceLimit::Cpu));
+        }
+        other => panic!("expected StepCompleted, got {other:?}"),
+    }
+
+    // A command that stays within its limits is not flagged.
+    let msg = HostMessage::Exec {
+        id: 10,
+        command: "echo quick".to_string(),
+        cwd: None,
+        env: None,
+        isolate_fs: false,
+        pty: false,
+        max_output_bytes: None,
+        limits: ResourceLimits {
+            cpu_seconds: Some(5),
+            max_processes: Some(64),
+            ..Default::default()
+        },
+    };
+    send_message(&mut writer, &msg).await;
+    let (_, completed) = collect_until_completed(&mut lines, 10).await;
+    assert_eq!(completed, step_completed(10, 0));
+}
@@ -1,12 +1,18 @@
+use codeagent_common::ErrorCode;
 use serde::{Deserialize, Serialize};
 
 /// Structured error detail included in error responses.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ErrorDetail {
+    /// An [`ErrorCode`], as serialized.
     pub code: String,
     pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub field: Option<String>,
+    /// Whether sending the request again unchanged may succeed; see
+    /// [`ErrorCode::is_retryable`].
+    #[serde(default)]
+    pub retryable: bool,
 }
 
 /// Errors that can occur during STDIO API message processing.
@@ -39,6 +45,16 @@ pub enum StdioError {
     #[error("missing request_id")]
     MissingRequestId,
 
+    #[error("insufficient history: requested {requested} step(s), {available} available")]
+    InsufficientHistory { requested: usize, available: usize },
+
+    #[error("{capability} is unavailable: {reason}")]
+    CapabilityUnavailable { capability: String, reason: String },
+
+    /// The handler failed the request; `code` says why.
+    #[error("{message}")]
+    Failed { code: ErrorCode, message: String },
+
     #[error("I/O error: {source}")]
     Io {
         #[from]
@@ -47,61 +63,96 @@ pub enum StdioError {
 }
 
 impl StdioError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            StdioError::MalformedJson { .. } => ErrorCode::MalformedJson,
+            StdioError::UnknownOperation { .. } => ErrorCode::UnknownOperation,
+            StdioError::MissingField { .. } => ErrorCode::MissingField,
+            StdioError::InvalidField { .. } => ErrorCode::InvalidField,
+            StdioError::OversizedMessage { .. } => ErrorCode::OversizedMessage,
+            StdioError::UnsupportedProtocolVersion { .. } => ErrorCode::UnsupportedProtocolVersion,
+            StdioError::PathOutsideRoot { .. } => ErrorCode::PathOutsideRoot,
+            StdioError::MissingRequestId => ErrorCode::MissingRequestId,
+            StdioError::InsufficientHistory { .. } => ErrorCode::InsufficientHistory,
+            StdioError::CapabilityUnavailable { .. } => ErrorCode::CapabilityUnavailable,
+            StdioError::Failed { code, .. } => *code,
+            StdioError::Io { .. } => ErrorCode::IoError,
+        }
+    }
+
     /// Convert this error to a structured `ErrorDetail` for inclusion in responses.
     pub fn to_error_detail(&self) -> ErrorDetail {
-        match self {
-            StdioError::MalformedJson { source } => ErrorDetail {
-                code: "malformed_json".to_string(),
-                message: source.to_string(),
-                field: None,
-            },
-            StdioError::UnknownOperation { operation } => ErrorDetail {
-                code: "unknown_operation".to_string(),
-                message: format!("unknown operation type: {operation}"),
-                field: None,
-            },
-            StdioError::MissingField { field } => ErrorDetail {
-                code: "missing_field".to_string(),
-                message: format!("missing required field: {field}"),
-                field: Some(field.clone()),
-            },
-            StdioError::InvalidField { field, message } => ErrorDetail {
-                code: "invalid_field".to_string(),
-                message: message.clone(),
-                field: Some(field.clone()),
-            },
+        let (message, field) = match self {
+            StdioError::MalformedJson { source } => (source.to_string(), None),
+            StdioError::UnknownOperation { operation } => {
+                (format!("unknown operation type: {operation}"), None)
+            }
+            StdioError::MissingField { field } => {
+                (format!("missing required field: {field}"), Some(field.clone()))
+            }
+            StdioError::InvalidField { field, message } => (message.clone(), Some(field.clone())),
             StdioError::OversizedMessage {
                 max_size,
                 actual_size,
-            } => ErrorDetail {
-                code: "oversized_message".to_string(),
-                message: format!(
-                    "message exceeds maximum size of {max_size} bytes (got {actual_size})"
-                ),
-                field: None,
-            },
-            StdioError::UnsupportedProtocolVersion { version, min, max } => ErrorDetail {
-                code: "unsupported_protocol_version".to_string(),
-                message: format!(
-                    "unsupported protocol version {version} (supported: {min}..={max})"
-                ),
-                field: None,
-            },
-            StdioError::PathOutsideRoot { path } => ErrorDetail {
-                code: "path_outside_root".to_string(),
-                message: format!("path is outside working directory root: {path}"),
-                field: None,
-            },
-            StdioError::MissingRequestId => ErrorDetail {
-                code: "missing_request_id".to_string(),
-                message: "missing required field: request_id".to_string(),
-                field: Some("request_id".to_string()),
-            },
-            StdioError::Io { source } => ErrorDetail {
-                code: "io_error".to_string(),
-                message: source.to_string(),
-                field: None,
-            },
+            } => (
+                format!("message exceeds maximum size of {max_size} bytes (got {actual_size})"),
+                None,
+            ),
+            StdioError::UnsupportedProtocolVersion { version, min, max } => (
+                format!("unsupported protocol version {version} (supported: {min}..={max})"),
+                None,
+            ),
+            StdioError::PathOutsideRoot { path } => {
+                (format!("path is outside working directory root: {path}"), None)
+            }
+            StdioError::MissingRequestId => (
+                "missing required field: request_id".to_string(),
+                Some("request_id".to_string()),
+            ),
+            StdioError::InsufficientHistory {
+                requested,
+                available,
+            } => (
+                format!("requested {requested} step(s) but only {available} available"),
+                Some("count".to_string()),
+            ),
+            StdioError::CapabilityUnavailable { capability, reason } => {
+                (format!("{capability} is unavailable: {reason}"), None)
+            }
+            StdioError::Failed { message, .. } => (message.clone(), None),
+            StdioError::Io { source } => (source.to_string(), None),
+        };
+        let code = self.code();
+        ErrorDetail {
+            code: code.as_str().to_string(),
+            message,
+            field,
+            retryable: code.is_retryable(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_requests_keep_their_code() {
+        let detail = StdioError::Failed {
+            code: ErrorCode::SessionNotActive,
+            message: "no active session".to_string(),
+        }
+        .to_error_detail();
+        assert_eq!(detail.code, "session_not_active");
+        assert_eq!(detail.message, "no active session");
+        assert!(!detail.retryable);
+
+        let detail = StdioError::Failed {
+            code: ErrorCode::StepWaitTimeout,
+            message: "timed out".to_string(),
         }
+        .to_error_detail();
+        assert!(detail.retryable);
+        assert_eq!(StdioError::MissingRequestId.to_error_detail().code, "missing_request_id");
     }
 }
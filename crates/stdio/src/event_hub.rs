@@ -0,0 +1,73 @@
+//! Ordering metadata for outbound events.
+//!
+//! Every event written to a client passes through one [`EventHub`], which
+//! gives it the next `seq` and its `emitted_at` time. The STDIO server owns
+//! the hub of its stream; the MCP notification forwarder owns another, so
+//! each surface numbers its own events without gaps.
+
+use codeagent_common::time;
+
+use crate::protocol::EventEnvelope;
+
+/// Allocates `seq` and `emitted_at` for the events of one output stream.
+#[derive(Debug)]
+pub struct EventHub {
+    next_seq: u64,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHub {
+    /// A hub whose first event gets `seq` 1.
+    pub fn new() -> Self {
+        Self { next_seq: 1 }
+    }
+
+    /// Give `envelope` the next `seq` and the current time. `origin` is left
+    /// as the event set it.
+    pub fn stamp(&mut self, envelope: &mut EventEnvelope) {
+        envelope.seq = Some(self.next_seq);
+        envelope.emitted_at = Some(time::now_timestamp());
+        self.next_seq += 1;
+    }
+
+    /// The `seq` of the last stamped event, 0 before the first.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Event, EventOrigin};
+
+    #[test]
+    fn stamps_consecutive_seqs_and_keeps_origin() {
+        let mut hub = EventHub::new();
+        assert_eq!(hub.last_seq(), 0);
+
+        let mut first = Event::AgentOutput { data: "a".to_string() }.to_envelope();
+        let mut second = Event::Recovery {
+            paths_restored: 1,
+            paths_deleted: 0,
+            rollbacks_reverted: 0,
+            steps_rolled_back: 0,
+            steps_evicted: 0,
+            steps_squashed: 0,
+        }
+        .to_envelope();
+        hub.stamp(&mut first);
+        hub.stamp(&mut second);
+
+        assert_eq!((first.seq, second.seq), (Some(1), Some(2)));
+        assert_eq!(hub.last_seq(), 2);
+        assert!(first.emitted_at.as_deref().is_some_and(|at| at.ends_with('Z')));
+        assert_eq!(first.origin, Some(EventOrigin::Agent));
+        assert_eq!(second.origin, Some(EventOrigin::Undo));
+    }
+}
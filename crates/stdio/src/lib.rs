@@ -1,15 +1,25 @@
 mod error;
+pub mod event_hub;
+pub mod logging;
 mod parser;
 mod path_validation;
 pub mod protocol;
+mod request_monitor;
 pub mod router;
+pub mod schema;
 pub mod server;
+pub mod terminal_output;
 mod version;
 
 pub use error::{ErrorDetail, StdioError};
-pub use parser::{parse_request, MAX_MESSAGE_SIZE};
+pub use event_hub::EventHub;
+pub use parser::{
+    parse_addressed_request_with_limits, parse_request, parse_request_with_limits,
+    AddressedRequest, MessageLimits, MAX_MESSAGE_SIZE, MAX_NEGOTIABLE_MESSAGE_SIZE,
+    SESSION_MESSAGE_SIZE,
+};
 pub use path_validation::validate_path;
-pub use protocol::{Event, EventEnvelope, Request, RequestEnvelope, ResponseEnvelope};
-pub use router::{RequestHandler, Router};
+pub use protocol::{Event, EventEnvelope, EventOrigin, Request, RequestEnvelope, ResponseEnvelope};
+pub use router::{Negotiated, RequestHandler, Router, SessionFactory};
 pub use server::StdioServer;
-pub use version::{MAX_SUPPORTED_VERSION, MIN_SUPPORTED_VERSION, PROTOCOL_VERSION};
+pub use version::{FEATURES, MAX_SUPPORTED_VERSION, MIN_SUPPORTED_VERSION, PROTOCOL_VERSION};
@@ -0,0 +1,515 @@
+//! Structured logging for the sandbox and the STDIO API, on `tracing`.
+//!
+//! [`init`] installs the process-wide subscriber: a level filter behind a
+//! reload handle, a layer writing each event to stderr as a JSON Lines
+//! [`LogEntry`], and optionally the same to a [`RotatingFile`], written on a
+//! `tracing-appender` worker thread. `--log-level` sets the level at
+//! startup and `log.configure` changes it while running ([`set_level`]).
+//!
+//! An entry takes `request_id` and `step_id` from the innermost spans that
+//! have them. The router dispatches every request in a `request` span and
+//! API steps run in a `step` span, so what a handler logs carries both.
+//! Spans follow futures instrumented with [`tracing::Instrument`], so a task
+//! spawned with `.in_current_span()` logs under the request that spawned it.
+//!
+//! Log with the `log_error!` .. `log_trace!` macros, which take the
+//! component first and format the message only if its level is enabled.
+
+use std::fmt::{self, Debug};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use codeagent_common::StepId;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::NonBlocking;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Registry, reload};
+
+use crate::protocol::{LogEntry, LogLevel};
+
+pub use tracing_appender::non_blocking::WorkerGuard;
+
+/// Directory of the rotated log, under the undo directory.
+pub const LOG_DIR_NAME: &str = ".logs";
+
+/// File name of the rotated log.
+pub const LOG_FILE_NAME: &str = "sandbox.log";
+
+/// Rotated files kept besides the current one, as `sandbox.log.1` (the
+/// newest) to `sandbox.log.5`.
+pub const ROTATED_FILES_KEPT: usize = 5;
+
+/// Target of the events [`forward`] emits, which only the file layer writes.
+const FORWARDED_TARGET: &str = "codeagent::forwarded";
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static LEVEL_HANDLE: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// Install the process-wide subscriber at `level`, writing to stderr and,
+/// if given, to `file`. Keep the returned guard until exit: dropping it
+/// flushes the entries still queued for the file. Does nothing but set the
+/// level if a subscriber is already installed.
+#[must_use = "entries queued for the file are lost if the guard is dropped early"]
+pub fn init(level: LogLevel, file: Option<RotatingFile>) -> Option<WorkerGuard> {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    let (file, guard) = match file {
+        Some(file) => {
+            let (writer, guard) = tracing_appender::non_blocking(file);
+            (Some(writer), Some(guard))
+        }
+        None => (None, None),
+    };
+    let (subscriber, handle) = subscriber(level, io::stderr, file);
+    if tracing::subscriber::set_global_default(subscriber).is_ok() {
+        let _ = LEVEL_HANDLE.set(handle);
+    }
+    guard
+}
+
+/// The registry with the level filter, the JSON Lines layer for `stderr`,
+/// and the one for `file` if given.
+fn subscriber<W>(
+    level: LogLevel,
+    stderr: W,
+    file: Option<NonBlocking>,
+) -> (impl Subscriber + Send + Sync, reload::Handle<LevelFilter, Registry>)
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let (filter, handle) = reload::Layer::new(level_filter(level));
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(SpanFieldsLayer)
+        .with(LogEntryLayer {
+            make_writer: stderr,
+            forwarded: false,
+        })
+        .with(file.map(|file| LogEntryLayer {
+            make_writer: file,
+            forwarded: true,
+        }));
+    (subscriber, handle)
+}
+
+pub fn level() -> LogLevel {
+    LogLevel::ALL[LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+/// Change the process-wide level, through the reload handle once [`init`]
+/// has installed the subscriber.
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    if let Some(handle) = LEVEL_HANDLE.get() {
+        let _ = handle.reload(level_filter(level));
+    }
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level <= self::level()
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    LevelFilter::from_level(match level {
+        LogLevel::Error => Level::ERROR,
+        LogLevel::Warn => Level::WARN,
+        LogLevel::Info => Level::INFO,
+        LogLevel::Debug => Level::DEBUG,
+        LogLevel::Trace => Level::TRACE,
+    })
+}
+
+fn log_level(level: Level) -> LogLevel {
+    match level {
+        Level::ERROR => LogLevel::Error,
+        Level::WARN => LogLevel::Warn,
+        Level::INFO => LogLevel::Info,
+        Level::DEBUG => LogLevel::Debug,
+        Level::TRACE => LogLevel::Trace,
+    }
+}
+
+/// Emit `message` from `component` as a `tracing` event; the macros call
+/// this.
+pub fn log(level: LogLevel, component: &str, message: fmt::Arguments<'_>) {
+    macro_rules! emit {
+        ($level:expr) => {
+            tracing::event!($level, component, "{message}")
+        };
+    }
+    match level {
+        LogLevel::Error => emit!(Level::ERROR),
+        LogLevel::Warn => emit!(Level::WARN),
+        LogLevel::Info => emit!(Level::INFO),
+        LogLevel::Debug => emit!(Level::DEBUG),
+        LogLevel::Trace => emit!(Level::TRACE),
+    }
+}
+
+/// Emit `entry` for the file layer only, for an entry its caller wrote to
+/// stderr itself. Its tags are kept over those of the current spans.
+pub fn forward(entry: &LogEntry) {
+    let component = entry.component.as_str();
+    let request_id = entry.request_id.as_deref();
+    let step_id = entry.step_id;
+    let message = &entry.message;
+    macro_rules! emit {
+        ($level:expr) => {
+            tracing::event!(
+                target: FORWARDED_TARGET,
+                $level,
+                component,
+                request_id,
+                step_id,
+                "{message}"
+            )
+        };
+    }
+    match entry.level {
+        LogLevel::Error => emit!(Level::ERROR),
+        LogLevel::Warn => emit!(Level::WARN),
+        LogLevel::Info => emit!(Level::INFO),
+        LogLevel::Debug => emit!(Level::DEBUG),
+        LogLevel::Trace => emit!(Level::TRACE),
+    }
+}
+
+/// The fields a [`LogEntry`] is made of, recorded from an event or a span.
+#[derive(Default)]
+struct Fields {
+    component: Option<String>,
+    request_id: Option<String>,
+    step_id: Option<StepId>,
+    message: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "component" => self.component = Some(value.to_string()),
+            "request_id" => self.request_id = Some(value.to_string()),
+            "message" => self.message = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == "step_id" {
+            self.step_id = Some(value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "step_id" {
+            self.step_id = StepId::try_from(value).ok();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        }
+    }
+}
+
+/// Keeps the `request_id` and `step_id` of every span in its extensions,
+/// for [`LogEntryLayer`] to tag entries with.
+struct SpanFieldsLayer;
+
+impl<S> Layer<S> for SpanFieldsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+}
+
+/// Writes each event as a JSON Lines [`LogEntry`] to what `make_writer`
+/// makes. Events from [`forward`] are written only if `forwarded`.
+struct LogEntryLayer<W> {
+    make_writer: W,
+    forwarded: bool,
+}
+
+impl<S, W> Layer<S> for LogEntryLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if !self.forwarded && metadata.target() == FORWARDED_TARGET {
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        for span in ctx.event_scope(event).into_iter().flatten() {
+            let extensions = span.extensions();
+            if let Some(tags) = extensions.get::<Fields>() {
+                fields.request_id = fields.request_id.or_else(|| tags.request_id.clone());
+                fields.step_id = fields.step_id.or(tags.step_id);
+            }
+        }
+        let entry = LogEntry {
+            timestamp: codeagent_common::time::now_timestamp(),
+            level: log_level(*metadata.level()),
+            component: fields.component.unwrap_or_else(|| metadata.target().to_string()),
+            request_id: fields.request_id,
+            step_id: fields.step_id,
+            message: fields.message,
+        };
+        let Ok(mut line) = serde_json::to_string(&entry) else {
+            return;
+        };
+        line.push('\n');
+        let _ = self.make_writer.make_writer().write_all(line.as_bytes());
+    }
+}
+
+/// Appends to `<dir>/sandbox.log`, renaming it to `<name>.1` once it would
+/// grow past `max_bytes` and keeping [`ROTATED_FILES_KEPT`] such files.
+/// Each write is one entry, and is never split across files.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Open `<dir>/sandbox.log` for appending, creating `dir` if needed.
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE_NAME);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> io::Result<File> {
+        for index in (1..ROTATED_FILES_KEPT).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        OpenOptions::new().create(true).append(true).open(&self.path)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            // If rotating fails, keep writing to the current file rather
+            // than lose entries.
+            if let Ok(rotated) = self.rotate() {
+                self.file = rotated;
+                self.size = 0;
+            }
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Log at `$level` through `tracing`; see the module docs.
+#[macro_export]
+macro_rules! log_event {
+    ($level:expr, $component:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::logging::enabled(level) {
+            $crate::logging::log(level, $component, format_args!($($arg)+));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($component:expr, $($arg:tt)+) => {
+        $crate::log_event!($crate::protocol::LogLevel::Error, $component, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($component:expr, $($arg:tt)+) => {
+        $crate::log_event!($crate::protocol::LogLevel::Warn, $component, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($component:expr, $($arg:tt)+) => {
+        $crate::log_event!($crate::protocol::LogLevel::Info, $component, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($component:expr, $($arg:tt)+) => {
+        $crate::log_event!($crate::protocol::LogLevel::Debug, $component, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($component:expr, $($arg:tt)+) => {
+        $crate::log_event!($crate::protocol::LogLevel::Trace, $component, $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::Instrument;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn entries(&self) -> Vec<LogEntry> {
+            let bytes = self.0.lock().unwrap();
+            serde_json::Deserializer::from_slice(&bytes)
+                .into_iter()
+                .map(Result::unwrap)
+                .collect()
+        }
+    }
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn captured_subscriber(
+        level: LogLevel,
+    ) -> (Captured, impl Subscriber + Send + Sync, reload::Handle<LevelFilter, Registry>) {
+        let captured = Captured::default();
+        let stderr = captured.clone();
+        let (subscriber, handle) = subscriber(level, move || stderr.clone(), None);
+        (captured, subscriber, handle)
+    }
+
+    #[test]
+    fn entries_below_the_level_are_dropped_until_it_is_reloaded() {
+        let (captured, subscriber, handle) = captured_subscriber(LogLevel::Info);
+        tracing::subscriber::with_default(subscriber, || {
+            log(LogLevel::Debug, "test", format_args!("hidden"));
+            log(LogLevel::Warn, "test", format_args!("shown"));
+            handle.reload(level_filter(LogLevel::Trace)).unwrap();
+            log(LogLevel::Debug, "test", format_args!("now shown"));
+            forward(&LogEntry {
+                timestamp: String::new(),
+                level: LogLevel::Error,
+                component: "test".to_string(),
+                request_id: None,
+                step_id: None,
+                message: "written to stderr by the caller".to_string(),
+            });
+        });
+
+        let entries = captured.entries();
+        let messages: Vec<&str> = entries.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, ["shown", "now shown"]);
+        assert_eq!(entries[0].level, LogLevel::Warn);
+        assert_eq!(entries[0].component, "test");
+    }
+
+    #[test]
+    fn spans_tag_entries_and_follow_spawned_tasks() {
+        let (captured, subscriber, _handle) = captured_subscriber(LogLevel::Info);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            log(LogLevel::Info, "test", format_args!("untagged"));
+            let request = tracing::info_span!("request", request_id = "r1");
+            runtime.block_on(
+                async {
+                    let step = tracing::info_span!("step", step_id = 4_i64);
+                    step.in_scope(|| log(LogLevel::Info, "test", format_args!("in step")));
+                    let task = tokio::spawn(
+                        async { log(LogLevel::Info, "test", format_args!("in task")) }
+                            .in_current_span(),
+                    );
+                    task.await.unwrap();
+                }
+                .instrument(request),
+            );
+        });
+
+        let tags: Vec<(String, Option<String>, Option<StepId>)> = captured
+            .entries()
+            .into_iter()
+            .map(|e| (e.message, e.request_id, e.step_id))
+            .collect();
+        assert_eq!(
+            tags,
+            [
+                ("untagged".to_string(), None, None),
+                ("in step".to_string(), Some("r1".to_string()), Some(4)),
+                ("in task".to_string(), Some("r1".to_string()), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn file_rotates_and_keeps_a_bounded_number_of_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = RotatingFile::open(dir.path(), 200).unwrap();
+        for i in 0..40 {
+            let line = format!("entry {i:03} {}\n", "x".repeat(80));
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        let current = fs::read_to_string(dir.path().join(LOG_FILE_NAME)).unwrap();
+        assert!(current.contains("entry 039"));
+        assert!(current.len() <= 200);
+        let newest_rotated = fs::read_to_string(dir.path().join("sandbox.log.1")).unwrap();
+        assert!(newest_rotated.contains("entry 03"));
+        assert!(dir.path().join(format!("sandbox.log.{ROTATED_FILES_KEPT}")).exists());
+        assert!(!dir.path().join(format!("sandbox.log.{}", ROTATED_FILES_KEPT + 1)).exists());
+    }
+}
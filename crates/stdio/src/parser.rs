@@ -1,31 +1,147 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
 use crate::error::StdioError;
 use crate::protocol::{
-    AgentExecutePayload, AgentPromptPayload, FsListPayload, FsReadPayload, Request,
-    RequestEnvelope, SafeguardConfirmPayload, SafeguardConfigurePayload, SessionStartPayload,
-    UndoConfigurePayload, UndoHistoryPayload, UndoRollbackPayload,
+    AgentExecutePayload, AgentInputPayload, AgentPromptPayload, BatchPayload, EventsReplayPayload,
+    EventsSubscribePayload, FsCommitPayload, FsDeletePayload, FsListPayload, FsHashPayload,
+    FsPatchPayload, FsTracePayload,
+    FsReadPayload, FsStatPayload, FsWritePayload, LogConfigurePayload, ProtocolHelloPayload,
+    Request, RequestCancelPayload, RequestEnvelope, SafeguardConfirmPayload,
+    SafeguardConfigurePayload, SafeguardHistoryPayload,
+    SessionClonePayload, SessionConfigurePayload, SessionDestroyPayload, SessionEnvSetPayload, SessionEnvUnsetPayload, SessionStartPayload,
+    UndoAttestPayload, UndoConfigurePayload, UndoExpectPayload, UndoHistoryPayload,
+    UndoRollbackPayload, UndoSquashPayload, VmInventoryPayload,
 };
 
-/// Maximum allowed message size in bytes (1 MB).
+/// Default maximum message size in bytes (1 MB), for request types without
+/// a limit of their own.
 pub const MAX_MESSAGE_SIZE: usize = 1_048_576;
 
+/// Default limit for `session.*` requests (64 KB).
+pub const SESSION_MESSAGE_SIZE: usize = 65_536;
+
+/// Largest limit `session.start` can negotiate for any request type (16 MB).
+pub const MAX_NEGOTIABLE_MESSAGE_SIZE: usize = 16 * 1_048_576;
+
+/// Per-request-type message size limits, in bytes.
+///
+/// `per_type` keys are either a request type (`agent.execute`) or a
+/// namespace wildcard (`session.*`); an exact type wins over its namespace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MessageLimits {
+    pub default: usize,
+    pub per_type: BTreeMap<String, usize>,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            default: MAX_MESSAGE_SIZE,
+            per_type: BTreeMap::from([("session.*".to_string(), SESSION_MESSAGE_SIZE)]),
+        }
+    }
+}
+
+impl MessageLimits {
+    /// Limit for a request of the given type.
+    pub fn limit_for(&self, message_type: &str) -> usize {
+        if let Some(&limit) = self.per_type.get(message_type) {
+            return limit;
+        }
+        message_type
+            .split_once('.')
+            .and_then(|(namespace, _)| self.per_type.get(&format!("{namespace}.*")))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Largest limit of any request type. Longer lines are rejected before
+    /// their envelope is parsed.
+    pub fn largest(&self) -> usize {
+        self.per_type.values().copied().fold(self.default, usize::max)
+    }
+
+    /// Apply the limits a client asked for in `session.start` on top of
+    /// these. The `default` key replaces the default limit; other keys are
+    /// request types or namespace wildcards. Requests above
+    /// [`MAX_NEGOTIABLE_MESSAGE_SIZE`] are capped to it.
+    pub fn negotiate(&self, requested: &BTreeMap<String, usize>) -> Result<Self, StdioError> {
+        let mut limits = self.clone();
+        for (key, &size) in requested {
+            if size == 0 {
+                return Err(StdioError::InvalidField {
+                    field: "message_limits".to_string(),
+                    message: format!("limit for '{key}' must be positive"),
+                });
+            }
+            let size = size.min(MAX_NEGOTIABLE_MESSAGE_SIZE);
+            if key == "default" {
+                limits.default = size;
+            } else {
+                limits.per_type.insert(key.clone(), size);
+            }
+        }
+        Ok(limits)
+    }
+}
+
+/// Parse a single JSONL line into a typed `Request`, under the default
+/// [`MessageLimits`].
+pub fn parse_request(line: &str) -> Result<Request, StdioError> {
+    parse_request_with_limits(line, &MessageLimits::default())
+}
+
+/// A request with the session it is addressed to, if it names one.
+#[derive(Debug)]
+pub struct AddressedRequest {
+    pub session_id: Option<String>,
+    pub request: Request,
+}
+
 /// Parse a single JSONL line into a typed `Request`.
 ///
-/// 1. Rejects oversized messages before any JSON parsing.
+/// 1. Rejects messages over the largest limit before any JSON parsing.
 /// 2. Parses the envelope to extract `type` and `request_id`.
-/// 3. Dispatches on `type` to parse the typed payload.
-/// 4. Returns structured errors for unknown types, missing fields, etc.
-pub fn parse_request(line: &str) -> Result<Request, StdioError> {
-    if line.len() > MAX_MESSAGE_SIZE {
-        return Err(StdioError::OversizedMessage {
-            max_size: MAX_MESSAGE_SIZE,
-            actual_size: line.len(),
-        });
+/// 3. Rejects messages over the limit for that `type`.
+/// 4. Dispatches on `type` to parse the typed payload.
+/// 5. Returns structured errors for unknown types, missing fields, etc.
+pub fn parse_request_with_limits(
+    line: &str,
+    limits: &MessageLimits,
+) -> Result<Request, StdioError> {
+    parse_addressed_request_with_limits(line, limits).map(|addressed| addressed.request)
+}
+
+/// Parse a single JSONL line as [`parse_request_with_limits`] does, keeping
+/// the envelope's `session_id`.
+pub fn parse_addressed_request_with_limits(
+    line: &str,
+    limits: &MessageLimits,
+) -> Result<AddressedRequest, StdioError> {
+    let oversized = |max_size| StdioError::OversizedMessage {
+        max_size,
+        actual_size: line.len(),
+    };
+    if line.len() > limits.largest() {
+        return Err(oversized(limits.largest()));
     }
 
     let envelope: RequestEnvelope =
         serde_json::from_str(line).map_err(|source| classify_envelope_error(line, source))?;
 
-    parse_typed_request(envelope)
+    let limit = limits.limit_for(&envelope.message_type);
+    if line.len() > limit {
+        return Err(oversized(limit));
+    }
+
+    let session_id = envelope.session_id.clone();
+    let request = parse_typed_request(envelope)?;
+    Ok(AddressedRequest {
+        session_id,
+        request,
+    })
 }
 
 /// Attempt to extract a `request_id` from a raw JSON line, even if parsing
@@ -61,8 +177,47 @@ fn parse_typed_request(envelope: RequestEnvelope) -> Result<Request, StdioError>
             })
         }
         "session.stop" => Ok(Request::SessionStop { request_id }),
+        "session.destroy" => {
+            let p = parse_payload::<SessionDestroyPayload>(payload, "session.destroy")?;
+            Ok(Request::SessionDestroy {
+                request_id,
+                payload: p,
+            })
+        }
         "session.reset" => Ok(Request::SessionReset { request_id }),
+        "session.resume" => Ok(Request::SessionResume { request_id }),
         "session.status" => Ok(Request::SessionStatus { request_id }),
+        "session.warnings" => Ok(Request::SessionWarnings { request_id }),
+        "session.metrics" => Ok(Request::SessionMetrics { request_id }),
+        "session.env.set" => {
+            let p = parse_payload::<SessionEnvSetPayload>(payload, "session.env.set")?;
+            Ok(Request::SessionEnvSet {
+                request_id,
+                payload: p,
+            })
+        }
+        "session.env.unset" => {
+            let p = parse_payload::<SessionEnvUnsetPayload>(payload, "session.env.unset")?;
+            Ok(Request::SessionEnvUnset {
+                request_id,
+                payload: p,
+            })
+        }
+        "session.env.list" => Ok(Request::SessionEnvList { request_id }),
+        "session.configure" => {
+            let p = parse_payload::<SessionConfigurePayload>(payload, "session.configure")?;
+            Ok(Request::SessionConfigure {
+                request_id,
+                payload: p,
+            })
+        }
+        "session.clone" => {
+            let p = parse_payload::<SessionClonePayload>(payload, "session.clone")?;
+            Ok(Request::SessionClone {
+                request_id,
+                payload: p,
+            })
+        }
 
         "undo.rollback" => {
             let p = parse_payload::<UndoRollbackPayload>(payload, "undo.rollback")?;
@@ -86,6 +241,28 @@ fn parse_typed_request(envelope: RequestEnvelope) -> Result<Request, StdioError>
             })
         }
         "undo.discard" => Ok(Request::UndoDiscard { request_id }),
+        "undo.reload_ignores" => Ok(Request::UndoReloadIgnores { request_id }),
+        "undo.attest" => {
+            let p = parse_payload_or_default::<UndoAttestPayload>(payload);
+            Ok(Request::UndoAttest {
+                request_id,
+                payload: p,
+            })
+        }
+        "undo.expect" => {
+            let p = parse_payload::<UndoExpectPayload>(payload, "undo.expect")?;
+            Ok(Request::UndoExpect {
+                request_id,
+                payload: p,
+            })
+        }
+        "undo.squash" => {
+            let p = parse_payload::<UndoSquashPayload>(payload, "undo.squash")?;
+            Ok(Request::UndoSquash {
+                request_id,
+                payload: p,
+            })
+        }
 
         "agent.execute" => {
             let p = parse_payload::<AgentExecutePayload>(payload, "agent.execute")?;
@@ -94,6 +271,13 @@ fn parse_typed_request(envelope: RequestEnvelope) -> Result<Request, StdioError>
                 payload: p,
             })
         }
+        "agent.input" => {
+            let p = parse_payload::<AgentInputPayload>(payload, "agent.input")?;
+            Ok(Request::AgentInput {
+                request_id,
+                payload: p,
+            })
+        }
         "agent.prompt" => {
             let p = parse_payload::<AgentPromptPayload>(payload, "agent.prompt")?;
             Ok(Request::AgentPrompt {
@@ -116,6 +300,55 @@ fn parse_typed_request(envelope: RequestEnvelope) -> Result<Request, StdioError>
                 payload: p,
             })
         }
+        "fs.write" => {
+            let p = parse_payload::<FsWritePayload>(payload, "fs.write")?;
+            Ok(Request::FsWrite {
+                request_id,
+                payload: p,
+            })
+        }
+        "fs.delete" => {
+            let p = parse_payload::<FsDeletePayload>(payload, "fs.delete")?;
+            Ok(Request::FsDelete {
+                request_id,
+                payload: p,
+            })
+        }
+        "fs.stat" => {
+            let p = parse_payload::<FsStatPayload>(payload, "fs.stat")?;
+            Ok(Request::FsStat {
+                request_id,
+                payload: p,
+            })
+        }
+        "fs.hash" => {
+            let p = parse_payload::<FsHashPayload>(payload, "fs.hash")?;
+            Ok(Request::FsHash {
+                request_id,
+                payload: p,
+            })
+        }
+        "fs.patch" => {
+            let p = parse_payload::<FsPatchPayload>(payload, "fs.patch")?;
+            Ok(Request::FsPatch {
+                request_id,
+                payload: p,
+            })
+        }
+        "fs.commit" => {
+            let p = parse_payload_or_default::<FsCommitPayload>(payload);
+            Ok(Request::FsCommit {
+                request_id,
+                payload: p,
+            })
+        }
+        "fs.trace" => {
+            let p = parse_payload_or_default::<FsTracePayload>(payload);
+            Ok(Request::FsTrace {
+                request_id,
+                payload: p,
+            })
+        }
         "fs.status" => Ok(Request::FsStatus { request_id }),
 
         "safeguard.configure" => {
@@ -132,6 +365,72 @@ fn parse_typed_request(envelope: RequestEnvelope) -> Result<Request, StdioError>
                 payload: p,
             })
         }
+        "safeguard.history" => {
+            let p = parse_payload_or_default::<SafeguardHistoryPayload>(payload);
+            Ok(Request::SafeguardHistory {
+                request_id,
+                payload: p,
+            })
+        }
+
+        "system.cleanup" => Ok(Request::SystemCleanup { request_id }),
+        "request.cancel" => {
+            let p = parse_payload::<RequestCancelPayload>(payload, "request.cancel")?;
+            Ok(Request::RequestCancel {
+                request_id,
+                payload: p,
+            })
+        }
+        "events.subscribe" => {
+            let p = parse_payload::<EventsSubscribePayload>(payload, "events.subscribe")?;
+            Ok(Request::EventsSubscribe {
+                request_id,
+                payload: p,
+            })
+        }
+        "events.replay" => {
+            let p = parse_payload::<EventsReplayPayload>(payload, "events.replay")?;
+            Ok(Request::EventsReplay {
+                request_id,
+                payload: p,
+            })
+        }
+        "log.configure" => {
+            let p = parse_payload::<LogConfigurePayload>(payload, "log.configure")?;
+            Ok(Request::LogConfigure {
+                request_id,
+                payload: p,
+            })
+        }
+        "batch" => {
+            let p = parse_payload::<BatchPayload>(payload, "batch")?;
+            let requests = p
+                .requests
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| parse_batch_item(index, item, p.atomic))
+                .collect::<Result<_, _>>()?;
+            Ok(Request::Batch {
+                request_id,
+                atomic: p.atomic,
+                requests,
+            })
+        }
+        "protocol.hello" => {
+            let p = parse_payload_or_default::<ProtocolHelloPayload>(payload);
+            Ok(Request::ProtocolHello {
+                request_id,
+                payload: p,
+            })
+        }
+
+        "vm.inventory" => {
+            let p = parse_payload_or_default::<VmInventoryPayload>(payload);
+            Ok(Request::VmInventory {
+                request_id,
+                payload: p,
+            })
+        }
 
         unknown => Err(StdioError::UnknownOperation {
             operation: unknown.to_string(),
@@ -139,6 +438,39 @@ fn parse_typed_request(envelope: RequestEnvelope) -> Result<Request, StdioError>
     }
 }
 
+/// Parse request `index` of a batch. Batches do not nest, a request in one
+/// runs in the batch's session, and an atomic one leaves the session and
+/// its undo history alone.
+fn parse_batch_item(
+    index: usize,
+    item: serde_json::Value,
+    atomic: bool,
+) -> Result<Request, StdioError> {
+    let invalid = |message: String| StdioError::InvalidField {
+        field: format!("requests[{index}]"),
+        message,
+    };
+    let envelope: RequestEnvelope =
+        serde_json::from_value(item).map_err(|e| invalid(e.to_string()))?;
+    if envelope.message_type == "batch" {
+        return Err(invalid("batches cannot be nested".to_string()));
+    }
+    if envelope.session_id.is_some() {
+        return Err(invalid("requests run in the session of the batch".to_string()));
+    }
+    let request_type = envelope.message_type.as_str();
+    if matches!(
+        request_type,
+        "session.start" | "session.stop" | "session.destroy" | "session.resume"
+    ) {
+        return Err(invalid(format!("{request_type} cannot be part of a batch")));
+    }
+    if atomic && (request_type.starts_with("session.") || request_type.starts_with("undo.")) {
+        return Err(invalid(format!("{request_type} cannot be part of an atomic batch")));
+    }
+    parse_typed_request(envelope).map_err(|e| invalid(e.to_error_detail().message))
+}
+
 /// Parse a payload from a JSON value, returning a `MissingField` error
 /// when serde reports a missing required field.
 fn parse_payload<T: serde::de::DeserializeOwned>(
@@ -213,6 +545,7 @@ mod tests {
                 assert_eq!(request_id, "9");
                 assert_eq!(payload.command, "npm install");
                 assert_eq!(payload.cwd, Some("/mnt".to_string()));
+                assert!(!payload.wait);
             }
             other => panic!("Expected AgentExecute, got: {other:?}"),
         }
@@ -281,6 +614,32 @@ mod tests {
         assert_eq!(extract_missing_field(msg), Some("command".to_string()));
     }
 
+    #[test]
+    fn limits_resolve_exact_type_then_namespace() {
+        let limits = MessageLimits::default()
+            .negotiate(&BTreeMap::from([
+                ("agent.*".to_string(), 100),
+                ("agent.execute".to_string(), 50),
+                ("default".to_string(), 200),
+            ]))
+            .unwrap();
+        assert_eq!(limits.limit_for("agent.execute"), 50);
+        assert_eq!(limits.limit_for("agent.prompt"), 100);
+        assert_eq!(limits.limit_for("session.stop"), SESSION_MESSAGE_SIZE);
+        assert_eq!(limits.limit_for("fs.read"), 200);
+        assert_eq!(limits.largest(), SESSION_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn negotiated_limits_are_capped_and_positive() {
+        let requested = BTreeMap::from([("agent.prompt".to_string(), usize::MAX)]);
+        let limits = MessageLimits::default().negotiate(&requested).unwrap();
+        assert_eq!(limits.limit_for("agent.prompt"), MAX_NEGOTIABLE_MESSAGE_SIZE);
+
+        let requested = BTreeMap::from([("default".to_string(), 0)]);
+        assert!(MessageLimits::default().negotiate(&requested).is_err());
+    }
+
     #[test]
     fn extract_missing_field_no_match() {
         let msg = "unexpected token";
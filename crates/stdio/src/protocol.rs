@@ -1,6 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use codeagent_common::{BarrierId, StepId};
+use codeagent_common::{
+    BarrierId, CaseSensitivity, CoherentCaptureConfig, ExpectedOperation,
+    ExternalModificationConfig, GitMirrorConfig, ReadEncoding, RollbackMode, SandboxWarning,
+    StepId, SymlinkPolicy,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::error::ErrorDetail;
@@ -15,11 +19,14 @@ use crate::error::ErrorDetail;
 /// the `type` and `request_id`, then dispatches on `type` to parse the typed
 /// payload. This allows producing useful error responses that include the
 /// `request_id` even when the payload is malformed.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestEnvelope {
     #[serde(rename = "type")]
     pub message_type: String,
     pub request_id: String,
+    /// The session a request is for, when the server runs several.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
     #[serde(default)]
     pub payload: serde_json::Value,
 }
@@ -34,12 +41,46 @@ pub enum Request {
     SessionStop {
         request_id: String,
     },
+    SessionDestroy {
+        request_id: String,
+        payload: SessionDestroyPayload,
+    },
     SessionReset {
         request_id: String,
     },
+    /// Start the session recorded in the undo directory again, e.g. after
+    /// the sandbox restarted.
+    SessionResume {
+        request_id: String,
+    },
     SessionStatus {
         request_id: String,
     },
+    SessionClone {
+        request_id: String,
+        payload: SessionClonePayload,
+    },
+    SessionWarnings {
+        request_id: String,
+    },
+    SessionMetrics {
+        request_id: String,
+    },
+    SessionEnvSet {
+        request_id: String,
+        payload: SessionEnvSetPayload,
+    },
+    SessionEnvUnset {
+        request_id: String,
+        payload: SessionEnvUnsetPayload,
+    },
+    SessionEnvList {
+        request_id: String,
+    },
+    SessionConfigure {
+        request_id: String,
+        payload: SessionConfigurePayload,
+    },
     UndoRollback {
         request_id: String,
         payload: UndoRollbackPayload,
@@ -55,10 +96,29 @@ pub enum Request {
     UndoDiscard {
         request_id: String,
     },
+    UndoAttest {
+        request_id: String,
+        payload: UndoAttestPayload,
+    },
+    UndoExpect {
+        request_id: String,
+        payload: UndoExpectPayload,
+    },
+    UndoReloadIgnores {
+        request_id: String,
+    },
+    UndoSquash {
+        request_id: String,
+        payload: UndoSquashPayload,
+    },
     AgentExecute {
         request_id: String,
         payload: AgentExecutePayload,
     },
+    AgentInput {
+        request_id: String,
+        payload: AgentInputPayload,
+    },
     AgentPrompt {
         request_id: String,
         payload: AgentPromptPayload,
@@ -71,6 +131,34 @@ pub enum Request {
         request_id: String,
         payload: FsReadPayload,
     },
+    FsWrite {
+        request_id: String,
+        payload: FsWritePayload,
+    },
+    FsDelete {
+        request_id: String,
+        payload: FsDeletePayload,
+    },
+    FsStat {
+        request_id: String,
+        payload: FsStatPayload,
+    },
+    FsHash {
+        request_id: String,
+        payload: FsHashPayload,
+    },
+    FsPatch {
+        request_id: String,
+        payload: FsPatchPayload,
+    },
+    FsCommit {
+        request_id: String,
+        payload: FsCommitPayload,
+    },
+    FsTrace {
+        request_id: String,
+        payload: FsTracePayload,
+    },
     FsStatus {
         request_id: String,
     },
@@ -82,6 +170,43 @@ pub enum Request {
         request_id: String,
         payload: SafeguardConfirmPayload,
     },
+    SafeguardHistory {
+        request_id: String,
+        payload: SafeguardHistoryPayload,
+    },
+    SystemCleanup {
+        request_id: String,
+    },
+    VmInventory {
+        request_id: String,
+        payload: VmInventoryPayload,
+    },
+    RequestCancel {
+        request_id: String,
+        payload: RequestCancelPayload,
+    },
+    EventsSubscribe {
+        request_id: String,
+        payload: EventsSubscribePayload,
+    },
+    EventsReplay {
+        request_id: String,
+        payload: EventsReplayPayload,
+    },
+    LogConfigure {
+        request_id: String,
+        payload: LogConfigurePayload,
+    },
+    ProtocolHello {
+        request_id: String,
+        payload: ProtocolHelloPayload,
+    },
+    /// Parsed from a [`BatchPayload`], with its requests parsed too.
+    Batch {
+        request_id: String,
+        atomic: bool,
+        requests: Vec<Request>,
+    },
 }
 
 impl Request {
@@ -90,19 +215,49 @@ impl Request {
         match self {
             Request::SessionStart { request_id, .. }
             | Request::SessionStop { request_id }
+            | Request::SessionDestroy { request_id, .. }
             | Request::SessionReset { request_id }
+            | Request::SessionResume { request_id }
             | Request::SessionStatus { request_id }
+            | Request::SessionClone { request_id, .. }
+            | Request::SessionWarnings { request_id }
+            | Request::SessionMetrics { request_id }
+            | Request::SessionEnvSet { request_id, .. }
+            | Request::SessionEnvUnset { request_id, .. }
+            | Request::SessionConfigure { request_id, .. }
+            | Request::SessionEnvList { request_id }
             | Request::UndoRollback { request_id, .. }
             | Request::UndoHistory { request_id, .. }
             | Request::UndoConfigure { request_id, .. }
             | Request::UndoDiscard { request_id }
+            | Request::UndoAttest { request_id, .. }
+            | Request::UndoExpect { request_id, .. }
+            | Request::UndoReloadIgnores { request_id }
+            | Request::UndoSquash { request_id, .. }
             | Request::AgentExecute { request_id, .. }
+            | Request::AgentInput { request_id, .. }
             | Request::AgentPrompt { request_id, .. }
             | Request::FsList { request_id, .. }
             | Request::FsRead { request_id, .. }
+            | Request::FsWrite { request_id, .. }
+            | Request::FsDelete { request_id, .. }
+            | Request::FsStat { request_id, .. }
+            | Request::FsHash { request_id, .. }
+            | Request::FsPatch { request_id, .. }
+            | Request::FsCommit { request_id, .. }
+            | Request::FsTrace { request_id, .. }
             | Request::FsStatus { request_id }
             | Request::SafeguardConfigure { request_id, .. }
-            | Request::SafeguardConfirm { request_id, .. } => request_id,
+            | Request::SafeguardConfirm { request_id, .. }
+            | Request::SafeguardHistory { request_id, .. }
+            | Request::SystemCleanup { request_id }
+            | Request::VmInventory { request_id, .. }
+            | Request::RequestCancel { request_id, .. }
+            | Request::EventsSubscribe { request_id, .. }
+            | Request::EventsReplay { request_id, .. }
+            | Request::LogConfigure { request_id, .. }
+            | Request::ProtocolHello { request_id, .. }
+            | Request::Batch { request_id, .. } => request_id,
         }
     }
 }
@@ -116,6 +271,60 @@ pub struct WorkingDirectoryConfig {
     pub path: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
+    /// How the directory is served to the VM. Defaults to
+    /// [`MountBackend::platform_default`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<MountBackend>,
+    /// Serve the directory read-only: the guest gets `EROFS` for every
+    /// change and nothing is recorded for undo.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Glob patterns, relative to the directory, of paths hidden from the
+    /// guest: it can neither see nor create them, so they are never
+    /// exported or captured. A matching directory hides its contents.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Serve the guest a copy-on-write overlay: its writes go to an upper
+    /// directory and reach this one only through `fs.commit`.
+    #[serde(default)]
+    pub overlay: bool,
+}
+
+/// Filesystem backend serving a working directory to the VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MountBackend {
+    /// In-process virtiofs daemon that records writes in the undo log.
+    /// Linux and macOS.
+    Intercepted,
+    /// The upstream virtiofsd binary. Guest writes bypass the undo log and
+    /// reach it as external modifications. Linux and macOS.
+    Virtiofs,
+    /// `virtiofs` exported read-only.
+    VirtiofsReadOnly,
+    /// 9P server that records writes in the undo log. Windows.
+    P9,
+}
+
+impl MountBackend {
+    /// The backend of directories that do not name one.
+    pub fn platform_default() -> Self {
+        if cfg!(target_os = "windows") {
+            Self::P9
+        } else {
+            Self::Intercepted
+        }
+    }
+
+    /// The wire name, as reported in `mount_points` and `fs.status`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Intercepted => "intercepted",
+            Self::Virtiofs => "virtiofs",
+            Self::VirtiofsReadOnly => "virtiofs_read_only",
+            Self::P9 => "p9",
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -127,6 +336,140 @@ pub struct SessionStartPayload {
     pub vm_mode: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub protocol_version: Option<u32>,
+    /// Symlink handling for every working directory's undo log. Defaults to
+    /// `ignore`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink_policy: Option<SymlinkPolicy>,
+    /// Files read under a coherent capture strategy when every working
+    /// directory's undo log captures them. Defaults to no rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coherent_capture: Option<CoherentCaptureConfig>,
+    /// Whether the working directories compare paths case-insensitively.
+    /// Defaults to `auto`, probing each one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub case_sensitivity: Option<CaseSensitivity>,
+    /// `disabled` runs without undo logging: writes go straight to the
+    /// working directories and `undo.*` requests are rejected.
+    #[serde(default)]
+    pub undo: UndoMode,
+    /// Per-request-type message size limits in bytes, keyed by request type,
+    /// namespace wildcard (`agent.*`) or `default`. The limits granted are
+    /// reported under `capabilities.message_limits` in the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_limits: Option<BTreeMap<String, usize>>,
+    /// Opt into batched and/or compressed `event.terminal_output`. Absent
+    /// sends every chunk as plain text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terminal_output: Option<TerminalOutputOptions>,
+}
+
+/// Compression applied to the `data` of `event.terminal_output`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputEncoding {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// How `event.terminal_output` is delivered.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerminalOutputOptions {
+    /// Encoded data is base64 of the compressed UTF-8 text, and the payload
+    /// also carries `encoding` and the uncompressed `size`.
+    #[serde(default)]
+    pub encoding: OutputEncoding,
+    /// Coalesce consecutive output of one stream for up to this many
+    /// milliseconds. `0` sends every chunk as it arrives.
+    #[serde(default)]
+    pub batch_ms: u64,
+    /// Send a batch early once it holds this many bytes.
+    #[serde(default = "default_batch_max_bytes")]
+    pub batch_max_bytes: usize,
+}
+
+impl Default for TerminalOutputOptions {
+    fn default() -> Self {
+        Self {
+            encoding: OutputEncoding::None,
+            batch_ms: 0,
+            batch_max_bytes: default_batch_max_bytes(),
+        }
+    }
+}
+
+fn default_batch_max_bytes() -> usize {
+    65_536
+}
+
+/// Whether a session records undo history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UndoMode {
+    #[default]
+    Enabled,
+    Disabled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionClonePayload {
+    /// Directory to copy the working tree into. Must not exist yet or be
+    /// empty, and must lie outside every working and undo directory.
+    pub target_dir: String,
+    /// Working directory (index or name) to clone, as for `undo.rollback`.
+    /// Defaults to the first one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct SessionDestroyPayload {
+    /// Also delete each working directory's undo data (steps, barriers,
+    /// the safeguard log and cached preimage blobs) and overlay. Requires
+    /// `confirmation`.
+    #[serde(default)]
+    pub delete_undo_log: bool,
+    /// Token returned by a `session.destroy` with `delete_undo_log` and no
+    /// token, which only lists what would be deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirmation: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionEnvSetPayload {
+    pub name: String,
+    pub value: String,
+    /// Keep the value out of `session.env.list` and redact it from
+    /// command output.
+    #[serde(default)]
+    pub secret: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionEnvUnsetPayload {
+    pub name: String,
+}
+
+/// Changes how long a step stays open after its command, in milliseconds.
+/// Omitted fields keep their current values.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct SessionConfigurePayload {
+    /// Quiet time after a command that closes its step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_ms: Option<u64>,
+    /// Longest a step waits for filesystem operations after its command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_timeout_ms: Option<u64>,
+    /// Quiet time that closes an ambient step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ambient_inactivity_timeout_ms: Option<u64>,
+    /// Longest an ambient step stays open while writes keep arriving.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ambient_max_duration_ms: Option<u64>,
+    /// Most distinct files one ambient step records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ambient_max_files: Option<u64>,
 }
 
 fn default_network_policy() -> String {
@@ -142,6 +485,13 @@ pub struct UndoRollbackPayload {
     pub count: u32,
     #[serde(default)]
     pub force: bool,
+    /// Fail with `insufficient_history` instead of rolling back fewer steps.
+    #[serde(default)]
+    pub strict: bool,
+    /// `merge` keeps edits made to text files after a step instead of
+    /// overwriting them.
+    #[serde(default)]
+    pub mode: RollbackMode,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub directory: Option<String>,
 }
@@ -150,16 +500,86 @@ pub struct UndoRollbackPayload {
 pub struct UndoHistoryPayload {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub directory: Option<String>,
+    /// `text` adds a rendered table of the steps to the response.
+    #[serde(default)]
+    pub format: HistoryFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct UndoAttestPayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+/// Merges the steps from `from_step` to `to_step` (ids from `undo.history`)
+/// into one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UndoSquashPayload {
+    pub from_step: StepId,
+    pub to_step: StepId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+/// Announces a heavy operation of the next step; see
+/// `codeagent_common::Expectation`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UndoExpectPayload {
+    /// Paths the operation deletes or rewrites, relative to the working
+    /// directory.
+    pub paths: Vec<String>,
+    pub op: ExpectedOperation,
+    #[serde(default)]
+    pub estimated_bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+/// Output format of `undo.history`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryFormat {
+    /// Structured step list only.
+    #[default]
+    Json,
+    /// Structured step list plus an aligned, human-readable table in `text`.
+    Text,
 }
 
 #[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct UndoConfigurePayload {
+    /// Resource limits given here replace the current value; omitted limits
+    /// are left unchanged.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub max_log_size_bytes: Option<u64>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub max_step_count: Option<usize>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub max_single_step_size_bytes: Option<u64>,
+    /// Replaces the symlink policy of the selected undo logs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink_policy: Option<SymlinkPolicy>,
+    /// Replaces the per-path external modification rules of the selected
+    /// undo logs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_modification: Option<ExternalModificationConfig>,
+    /// Replaces the coherent capture rules of the selected undo logs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coherent_capture: Option<CoherentCaptureConfig>,
+    /// Replaces the extra ignore patterns of the selected undo logs:
+    /// gitignore syntax relative to the working directory, applied after
+    /// the ignore files (so `!path` re-includes), whether or not gitignore
+    /// filtering is on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore_patterns: Option<Vec<String>>,
+    /// Turns the git mirror of closed steps on or off for the selected
+    /// undo logs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_mirror: Option<GitMirrorConfig>,
+    /// Working directory (index or name) to configure, as for
+    /// `undo.rollback`. When omitted, every working directory is configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -167,8 +587,75 @@ pub struct AgentExecutePayload {
     pub command: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub env: Option<HashMap<String, String>>,
+    /// Relative to `directory`, a host path inside any working directory, or
+    /// a guest path under `/mnt/working`.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub cwd: Option<String>,
+    /// Working directory (index or name) a relative `cwd` is resolved in,
+    /// as for `undo.rollback`. Defaults to the first.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+    /// Hold the response until the command completes and return its exit
+    /// code and output, instead of returning as soon as it is sent.
+    #[serde(default)]
+    pub wait: bool,
+    /// How long `wait` holds the response (default 120s, at most 600s).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Keep the command's writes to its working directory in a private
+    /// overlay in the guest, applied only if it exits with status 0.
+    #[serde(default)]
+    pub isolate_fs: bool,
+    /// Cancel the command if it is still running after this many seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u64>,
+    /// Roll back the command's step after a timeout cancels it.
+    #[serde(default)]
+    pub rollback_on_timeout: bool,
+    /// Run the command on a terminal, for REPLs and other interactive
+    /// tools. Its output then all arrives as `stdout`.
+    #[serde(default)]
+    pub pty: bool,
+    /// Stop streaming output after this many bytes of stdout and stderr
+    /// together. `event.step_completed` then has `output_truncated: true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<u64>,
+    /// Resources the command may use in the guest. A command stopped by
+    /// one has `limit_exceeded` in `event.step_completed`.
+    #[serde(default, skip_serializing_if = "CommandLimits::is_empty")]
+    pub limits: CommandLimits,
+}
+
+/// Resource limits for one `agent.execute` command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandLimits {
+    /// CPU time each of the command's processes may use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<u64>,
+    /// Memory the command's processes may use together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    /// How many processes the command may run at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_processes: Option<u64>,
+}
+
+impl CommandLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentInputPayload {
+    /// The `command_id` returned by `agent.execute`.
+    pub command_id: u64,
+    #[serde(default)]
+    pub data: String,
+    /// Close the command's stdin after `data`; for a `pty` command, send
+    /// the end-of-file character instead.
+    #[serde(default)]
+    pub eof: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -186,6 +673,96 @@ pub struct FsListPayload {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FsReadPayload {
     pub path: String,
+    #[serde(default)]
+    pub encoding: ReadEncoding,
+    /// Byte offset to start reading at.
+    #[serde(default)]
+    pub offset: u64,
+    /// Most bytes to return; the sandbox caps it either way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FsWritePayload {
+    pub path: String,
+    /// The file's new content. Missing parent directories are created.
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FsDeletePayload {
+    pub path: String,
+    /// Needed to delete a directory, which goes with everything in it.
+    #[serde(default)]
+    pub recursive: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FsStatPayload {
+    /// Not followed if it is a symlink.
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FsHashPayload {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FsPatchPayload {
+    /// A unified diff, as `diff -u` or `git diff` print it.
+    pub patch: String,
+    /// The file bare hunks (no `---`/`+++` headers) apply to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+/// Applies pending changes of an overlay working directory to it.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct FsCommitPayload {
+    /// Paths of the changes to apply, as `pending` lists them. All but the
+    /// conflicts when omitted; naming a conflict is an error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<Vec<String>>,
+    /// List the pending changes without applying or discarding any.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Drop the changes not applied from the overlay, so it matches the
+    /// working directory again.
+    #[serde(default)]
+    pub discard_rest: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+/// Turns the filesystem operation trace of a working directory on or off
+/// and reads it back.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct FsTracePayload {
+    /// Start (`true`) or stop (`false`) recording. Left as is when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Return only the records numbered above this, as `next_after` gave.
+    #[serde(default)]
+    pub after: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// Forget the records kept once they are returned.
+    #[serde(default)]
+    pub clear: bool,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub directory: Option<String>,
 }
@@ -199,15 +776,131 @@ pub struct SafeguardConfigurePayload {
     #[serde(default)]
     pub rename_over_existing: bool,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overwrite_count_threshold: Option<u64>,
+    /// Replaces the protected path patterns when given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protected_paths: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub timeout_seconds: Option<u64>,
+    /// Per-step time and operation limits. A step going over one asks
+    /// whether to continue; denying cancels its command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_step_duration_seconds: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_step_operations: Option<u64>,
+    /// Per-step byte budgets. A step going over one is rolled back without
+    /// a confirmation prompt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_deleted_bytes_per_step: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_overwritten_bytes_per_step: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct VmInventoryPayload {
+    /// Run the detection script again even if the guest images are unchanged.
+    #[serde(default)]
+    pub refresh: bool,
+}
+
+/// Cancels the request in flight with `request_id`. Handlers that support
+/// it (`undo.rollback`, `undo.configure`) stop at their next step.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestCancelPayload {
+    pub request_id: String,
+}
+
+/// Groups of events a client can subscribe to; see [`Event::category`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventCategory {
+    /// Command and agent output, and commands that timed out.
+    Output,
+    /// Safeguards triggered and timed out.
+    Safeguards,
+    /// Steps completed, external modifications, ignore reloads, recovery,
+    /// undo version mismatches and compaction.
+    Undo,
+    /// VM crashes and stale resources.
+    Vm,
+}
+
+impl EventCategory {
+    pub const ALL: [EventCategory; 4] = [
+        EventCategory::Output,
+        EventCategory::Safeguards,
+        EventCategory::Undo,
+        EventCategory::Vm,
+    ];
+}
+
+/// Replaces the categories of events the server writes. Until a client
+/// subscribes it gets all of them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventsSubscribePayload {
+    pub categories: Vec<EventCategory>,
+}
+
+/// Asks for the events written after `since_seq` again, from the server's
+/// replay buffer. `since_seq: 0` asks for every buffered event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventsReplayPayload {
+    pub since_seq: u64,
+}
+
+/// Sets the level below which log entries are dropped, for the whole
+/// process.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogConfigurePayload {
+    pub level: LogLevel,
+}
+
+/// Runs `requests`, whole request objects of any other type, one after the
+/// other in the batch's session, and answers with all their responses.
+///
+/// An `atomic` batch is one undo step: it stops at the first request that
+/// fails and rolls back what the batch changed, and otherwise merges the
+/// steps its requests made into one. Its `agent.execute` requests wait for
+/// their command, and it cannot hold `session.*` or `undo.*` requests.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchPayload {
+    #[serde(default)]
+    pub atomic: bool,
+    pub requests: Vec<serde_json::Value>,
+}
+
+/// Opens a connection: the client offers the protocol `versions` it speaks
+/// and the optional `features` (see [`FEATURES`](crate::version::FEATURES))
+/// it uses. Once a client has sent it, requests of other features fail;
+/// a client that never does may use them all.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolHelloPayload {
+    /// Empty to take the server's current version.
+    #[serde(default)]
+    pub versions: Vec<u32>,
+    #[serde(default)]
+    pub features: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SafeguardConfirmPayload {
     pub safeguard_id: String,
+    /// `allow_once`, `allow_step` (or `allow`), `allow_session`; anything
+    /// else denies.
     pub action: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct SafeguardHistoryPayload {
+    /// Working directory (index or name) whose audit log to read. Defaults
+    /// to the first one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+    /// Return only the newest `limit` entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
 // ---------------------------------------------------------------------------
 // Outbound: responses and events from agent
 // ---------------------------------------------------------------------------
@@ -254,27 +947,101 @@ impl ResponseEnvelope {
 pub struct EventEnvelope {
     #[serde(rename = "type")]
     pub event_type: String,
+    /// Position in the output stream, counting from 1. Set by the
+    /// [`EventHub`](crate::EventHub) that writes the event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// When the event was written, RFC 3339 UTC. Set with `seq`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emitted_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<EventOrigin>,
     pub payload: serde_json::Value,
 }
 
+impl EventEnvelope {
+    /// An envelope not yet stamped by an event hub.
+    pub fn new(event_type: &str, payload: serde_json::Value) -> Self {
+        Self {
+            event_type: event_type.to_string(),
+            seq: None,
+            emitted_at: None,
+            origin: None,
+            payload,
+        }
+    }
+}
+
+/// The part of the sandbox an event comes from; see [`Event::origin`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventOrigin {
+    /// Commands in the VM: their output and completed steps.
+    Guest,
+    /// The `agent.prompt` backend.
+    Agent,
+    /// The VM process itself.
+    Vm,
+    /// The undo log: recovery and version checks.
+    Undo,
+    /// Safeguard prompts.
+    Safeguard,
+    /// The host filesystem watcher.
+    Watcher,
+    /// A request in flight, reporting its progress.
+    Request,
+    /// The sandbox host process: warnings, errors, timeouts and cleanup.
+    Sandbox,
+}
+
+/// A resource left behind by a previous sandbox run, as reported in
+/// `event.stale_resources` and the `system.cleanup` response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StaleResourceReport {
+    /// One of `socket_dir`, `orphan_process`, or `abandoned_lock`.
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    pub message: String,
+}
+
+/// Wire form of a warning, shared by `event.warning` and `session.warnings`:
+/// the catalog entry's `code` and fields plus `severity` and `message`.
+pub fn warning_payload(warning: &SandboxWarning) -> serde_json::Value {
+    let mut payload = serde_json::to_value(warning).unwrap_or_default();
+    payload["severity"] = serde_json::json!(warning.severity());
+    payload["message"] = serde_json::json!(warning.message());
+    payload
+}
+
 /// Typed event variants for internal construction.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Event {
     StepCompleted {
         step_id: StepId,
+        /// The `agent.execute` command that ran in the step, if any.
+        command_id: Option<u64>,
         affected_paths: Vec<String>,
         exit_code: i32,
+        /// The guest dropped output past the command's `max_output_bytes`.
+        output_truncated: bool,
+        /// The resource limit (`cpu`, `memory` or `processes`) the guest
+        /// stopped the command for, if any.
+        limit_exceeded: Option<String>,
     },
     AgentOutput {
         data: String,
     },
     TerminalOutput {
+        /// The `agent.execute` command that produced the output, if known.
+        command_id: Option<u64>,
         stream: String,
         data: String,
     },
     Warning {
-        code: String,
-        message: String,
+        warning: SandboxWarning,
     },
     Error {
         code: String,
@@ -287,107 +1054,418 @@ pub enum Event {
         sample_paths: Vec<String>,
         message: String,
     },
+    /// No `safeguard.confirm` arrived in time; the safeguard was denied.
+    SafeguardTimedOut {
+        step_id: StepId,
+        safeguard_id: String,
+        timeout_seconds: u64,
+    },
+    /// An `agent.execute` command ran past its `timeout_seconds` and was
+    /// cancelled.
+    CommandTimedOut {
+        command_id: u64,
+        timeout_seconds: u64,
+        /// Whether the command's changes were rolled back.
+        rolled_back: bool,
+        /// Why the command could not be stopped or rolled back.
+        error: Option<String>,
+    },
+    /// The VM process exited during the session. Commands it was running
+    /// completed with exit code -1.
+    VmCrashed {
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        /// The last lines of the VM's serial console and QEMU's stderr,
+        /// oldest first.
+        serial_tail: Vec<String>,
+        /// Whether a new VM is being launched in its place.
+        restarting: bool,
+    },
     ExternalModification {
         affected_paths: Vec<String>,
         barrier_id: Option<BarrierId>,
     },
+    /// An ignore file of a working directory changed and its gitignore rules
+    /// were rebuilt.
+    IgnoresReloaded {
+        working_dir: String,
+        /// The ignore files that changed, relative to the working directory.
+        sources: Vec<String>,
+    },
     Recovery {
         paths_restored: usize,
         paths_deleted: usize,
+        /// Step rollbacks the crash cut short, reverted.
+        rollbacks_reverted: usize,
+        /// Steps rolled back to complete an interrupted rollback.
+        steps_rolled_back: usize,
+        /// Steps removed to complete an interrupted eviction.
+        steps_evicted: usize,
+        /// Steps merged away to complete an interrupted squash.
+        steps_squashed: usize,
     },
     UndoVersionMismatch {
         expected_version: String,
         found_version: String,
     },
+    /// Idle-time compaction of a working directory's undo log is `percent`
+    /// done.
+    CompactionProgress {
+        working_dir: String,
+        percent: u8,
+    },
+    /// A compaction run over a working directory's undo log finished, or
+    /// paused because a step opened.
+    Compaction {
+        working_dir: String,
+        paused: bool,
+        steps_compacted: usize,
+        files_recompressed: usize,
+        blobs_deduplicated: usize,
+        bytes_saved: u64,
+        /// Paths whose stored preimages failed their checksum.
+        corrupt_paths: Vec<String>,
+    },
+    StaleResources {
+        resources: Vec<StaleResourceReport>,
+    },
+    /// Progress of the long-running request `request_id`.
+    Progress {
+        request_id: String,
+        percent: u8,
+        /// The path the request is working on, if it works on files.
+        current_path: Option<String>,
+    },
+    /// An event of one of several sessions. Its payload gains `session_id`.
+    Session {
+        session_id: String,
+        event: Box<Event>,
+    },
 }
 
 impl Event {
-    /// Convert this typed event into a serializable envelope.
-    pub fn to_envelope(&self) -> EventEnvelope {
+    /// The category a client subscribes to for this event. Warnings, errors
+    /// and request progress have none: they are always written.
+    pub fn category(&self) -> Option<EventCategory> {
+        match self {
+            Event::AgentOutput { .. }
+            | Event::TerminalOutput { .. }
+            | Event::CommandTimedOut { .. } => Some(EventCategory::Output),
+            Event::SafeguardTriggered { .. } | Event::SafeguardTimedOut { .. } => {
+                Some(EventCategory::Safeguards)
+            }
+            Event::StepCompleted { .. }
+            | Event::ExternalModification { .. }
+            | Event::IgnoresReloaded { .. }
+            | Event::Recovery { .. }
+            | Event::UndoVersionMismatch { .. }
+            | Event::CompactionProgress { .. }
+            | Event::Compaction { .. } => Some(EventCategory::Undo),
+            Event::VmCrashed { .. } | Event::StaleResources { .. } => Some(EventCategory::Vm),
+            Event::Warning { .. } | Event::Error { .. } | Event::Progress { .. } => None,
+            Event::Session { event, .. } => event.category(),
+        }
+    }
+
+    /// The part of the sandbox this event comes from.
+    pub fn origin(&self) -> EventOrigin {
         match self {
+            Event::StepCompleted { .. } | Event::TerminalOutput { .. } => EventOrigin::Guest,
+            Event::AgentOutput { .. } => EventOrigin::Agent,
+            Event::VmCrashed { .. } => EventOrigin::Vm,
+            Event::Recovery { .. }
+            | Event::UndoVersionMismatch { .. }
+            | Event::CompactionProgress { .. }
+            | Event::Compaction { .. } => EventOrigin::Undo,
+            Event::SafeguardTriggered { .. } | Event::SafeguardTimedOut { .. } => {
+                EventOrigin::Safeguard
+            }
+            Event::ExternalModification { .. } | Event::IgnoresReloaded { .. } => {
+                EventOrigin::Watcher
+            }
+            Event::Progress { .. } => EventOrigin::Request,
+            Event::Warning { .. }
+            | Event::Error { .. }
+            | Event::CommandTimedOut { .. }
+            | Event::StaleResources { .. } => EventOrigin::Sandbox,
+            Event::Session { event, .. } => event.origin(),
+        }
+    }
+
+    /// Convert this typed event into a serializable envelope, with its
+    /// `origin`. The event hub adds `seq` and `emitted_at`.
+    pub fn to_envelope(&self) -> EventEnvelope {
+        let mut envelope = match self {
             Event::StepCompleted {
                 step_id,
+                command_id,
                 affected_paths,
                 exit_code,
-            } => EventEnvelope {
-                event_type: "event.step_completed".to_string(),
-                payload: serde_json::json!({
+                output_truncated,
+                limit_exceeded,
+            } => {
+                let mut payload = serde_json::json!({
                     "step_id": step_id,
                     "affected_paths": affected_paths,
                     "exit_code": exit_code,
-                }),
-            },
-            Event::AgentOutput { data } => EventEnvelope {
-                event_type: "event.agent_output".to_string(),
-                payload: serde_json::json!({ "data": data }),
-            },
-            Event::TerminalOutput { stream, data } => EventEnvelope {
-                event_type: "event.terminal_output".to_string(),
-                payload: serde_json::json!({ "stream": stream, "data": data }),
-            },
-            Event::Warning { code, message } => EventEnvelope {
-                event_type: "event.warning".to_string(),
-                payload: serde_json::json!({ "code": code, "message": message }),
-            },
-            Event::Error { code, message } => EventEnvelope {
-                event_type: "event.error".to_string(),
-                payload: serde_json::json!({ "code": code, "message": message }),
-            },
+                });
+                if let Some(command_id) = command_id {
+                    payload["command_id"] = serde_json::json!(command_id);
+                }
+                if *output_truncated {
+                    payload["output_truncated"] = serde_json::json!(true);
+                }
+                if let Some(limit) = limit_exceeded {
+                    payload["limit_exceeded"] = serde_json::json!(limit);
+                }
+                EventEnvelope::new("event.step_completed", payload)
+            }
+            Event::AgentOutput { data } => {
+                EventEnvelope::new("event.agent_output", serde_json::json!({ "data": data }))
+            }
+            Event::TerminalOutput {
+                command_id,
+                stream,
+                data,
+            } => crate::terminal_output::encode(
+                &TerminalOutputOptions::default(),
+                None,
+                *command_id,
+                stream,
+                data,
+            ),
+            Event::Warning { warning } => {
+                EventEnvelope::new("event.warning", warning_payload(warning))
+            }
+            Event::Error { code, message } => EventEnvelope::new(
+                "event.error",
+                serde_json::json!({ "code": code, "message": message }),
+            ),
             Event::SafeguardTriggered {
                 step_id,
                 safeguard_id,
                 kind,
                 sample_paths,
                 message,
-            } => EventEnvelope {
-                event_type: "event.safeguard_triggered".to_string(),
-                payload: serde_json::json!({
+            } => EventEnvelope::new(
+                "event.safeguard_triggered",
+                serde_json::json!({
                     "step_id": step_id,
                     "safeguard_id": safeguard_id,
                     "kind": kind,
                     "sample_paths": sample_paths,
                     "message": message,
                 }),
-            },
+            ),
+            Event::SafeguardTimedOut {
+                step_id,
+                safeguard_id,
+                timeout_seconds,
+            } => EventEnvelope::new(
+                "event.safeguard_timed_out",
+                serde_json::json!({
+                    "step_id": step_id,
+                    "safeguard_id": safeguard_id,
+                    "timeout_seconds": timeout_seconds,
+                }),
+            ),
+            Event::CommandTimedOut {
+                command_id,
+                timeout_seconds,
+                rolled_back,
+                error,
+            } => {
+                let mut payload = serde_json::json!({
+                    "command_id": command_id,
+                    "timeout_seconds": timeout_seconds,
+                    "rolled_back": rolled_back,
+                });
+                if let Some(error) = error {
+                    payload["error"] = serde_json::json!(error);
+                }
+                EventEnvelope::new("event.command_timed_out", payload)
+            }
+            Event::VmCrashed {
+                exit_code,
+                signal,
+                serial_tail,
+                restarting,
+            } => {
+                let mut payload = serde_json::json!({
+                    "serial_tail": serial_tail,
+                    "restarting": restarting,
+                });
+                if let Some(exit_code) = exit_code {
+                    payload["exit_code"] = serde_json::json!(exit_code);
+                }
+                if let Some(signal) = signal {
+                    payload["signal"] = serde_json::json!(signal);
+                }
+                EventEnvelope::new("event.vm_crashed", payload)
+            }
             Event::ExternalModification {
                 affected_paths,
                 barrier_id,
-            } => EventEnvelope {
-                event_type: "event.external_modification".to_string(),
-                payload: serde_json::json!({
+            } => EventEnvelope::new(
+                "event.external_modification",
+                serde_json::json!({
                     "affected_paths": affected_paths,
                     "barrier_id": barrier_id,
                 }),
-            },
+            ),
+            Event::IgnoresReloaded {
+                working_dir,
+                sources,
+            } => EventEnvelope::new(
+                "event.ignores_reloaded",
+                serde_json::json!({
+                    "working_dir": working_dir,
+                    "sources": sources,
+                }),
+            ),
             Event::Recovery {
                 paths_restored,
                 paths_deleted,
-            } => EventEnvelope {
-                event_type: "event.recovery".to_string(),
-                payload: serde_json::json!({
+                rollbacks_reverted,
+                steps_rolled_back,
+                steps_evicted,
+                steps_squashed,
+            } => EventEnvelope::new(
+                "event.recovery",
+                serde_json::json!({
                     "paths_restored": paths_restored,
                     "paths_deleted": paths_deleted,
+                    "rollbacks_reverted": rollbacks_reverted,
+                    "steps_rolled_back": steps_rolled_back,
+                    "steps_evicted": steps_evicted,
+                    "steps_squashed": steps_squashed,
                 }),
-            },
+            ),
             Event::UndoVersionMismatch {
                 expected_version,
                 found_version,
-            } => EventEnvelope {
-                event_type: "event.undo_version_mismatch".to_string(),
-                payload: serde_json::json!({
+            } => EventEnvelope::new(
+                "event.undo_version_mismatch",
+                serde_json::json!({
                     "expected_version": expected_version,
                     "found_version": found_version,
                 }),
-            },
+            ),
+            Event::CompactionProgress {
+                working_dir,
+                percent,
+            } => EventEnvelope::new(
+                "event.compaction_progress",
+                serde_json::json!({
+                    "working_dir": working_dir,
+                    "percent": percent,
+                }),
+            ),
+            Event::Compaction {
+                working_dir,
+                paused,
+                steps_compacted,
+                files_recompressed,
+                blobs_deduplicated,
+                bytes_saved,
+                corrupt_paths,
+            } => EventEnvelope::new(
+                "event.compaction",
+                serde_json::json!({
+                    "working_dir": working_dir,
+                    "paused": paused,
+                    "steps_compacted": steps_compacted,
+                    "files_recompressed": files_recompressed,
+                    "blobs_deduplicated": blobs_deduplicated,
+                    "bytes_saved": bytes_saved,
+                    "corrupt_paths": corrupt_paths,
+                }),
+            ),
+            Event::StaleResources { resources } => EventEnvelope::new(
+                "event.stale_resources",
+                serde_json::json!({ "resources": resources }),
+            ),
+            Event::Progress {
+                request_id,
+                percent,
+                current_path,
+            } => {
+                let mut payload = serde_json::json!({
+                    "request_id": request_id,
+                    "percent": percent,
+                });
+                if let Some(path) = current_path {
+                    payload["current_path"] = serde_json::json!(path);
+                }
+                EventEnvelope::new("event.progress", payload)
+            }
+            Event::Session { session_id, event } => {
+                let mut envelope = event.to_envelope();
+                envelope.payload["session_id"] = serde_json::json!(session_id);
+                envelope
+            }
+        };
+        envelope.origin = Some(self.origin());
+        envelope
+    }
+}
+
+/// Severity of a [`LogEntry`], most severe first: a level enables itself
+/// and the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 5] = [
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
         }
     }
 }
 
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LogLevel::ALL
+            .into_iter()
+            .find(|level| level.as_str() == s)
+            .ok_or_else(|| {
+                format!("unknown log level '{s}': expected error, warn, info, debug or trace")
+            })
+    }
+}
+
 /// Structured log entry written to stderr.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
     pub timestamp: String,
-    pub level: String,
+    pub level: LogLevel,
     pub component: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub request_id: Option<String>,
@@ -418,6 +1496,7 @@ mod tests {
             code: "unknown_operation".to_string(),
             message: "unknown operation type: foo.bar".to_string(),
             field: None,
+            retryable: false,
         };
         let response = ResponseEnvelope::error("1".to_string(), error);
         let json = serde_json::to_string(&response).unwrap();
@@ -431,35 +1510,164 @@ mod tests {
     fn event_step_completed_envelope() {
         let event = Event::StepCompleted {
             step_id: 7,
+            command_id: Some(7),
             affected_paths: vec!["package-lock.json".to_string()],
             exit_code: 0,
+            output_truncated: true,
+            limit_exceeded: Some("memory".to_string()),
         };
         let envelope = event.to_envelope();
         assert_eq!(envelope.event_type, "event.step_completed");
         assert_eq!(envelope.payload["step_id"], 7);
+        assert_eq!(envelope.payload["command_id"], 7);
         assert_eq!(envelope.payload["exit_code"], 0);
+        assert_eq!(envelope.payload["output_truncated"], true);
+        assert_eq!(envelope.payload["limit_exceeded"], "memory");
     }
 
     #[test]
     fn event_terminal_output_envelope() {
         let event = Event::TerminalOutput {
+            command_id: None,
             stream: "stdout".to_string(),
             data: "hello world\n".to_string(),
         };
         let envelope = event.to_envelope();
         assert_eq!(envelope.event_type, "event.terminal_output");
         assert_eq!(envelope.payload["stream"], "stdout");
+        assert!(envelope.payload.get("command_id").is_none());
+    }
+
+    #[test]
+    fn event_command_timed_out_envelope() {
+        let event = Event::CommandTimedOut {
+            command_id: 3,
+            timeout_seconds: 30,
+            rolled_back: false,
+            error: Some("command did not stop after cancel".to_string()),
+        };
+        let envelope = event.to_envelope();
+        assert_eq!(envelope.event_type, "event.command_timed_out");
+        assert_eq!(envelope.payload["command_id"], 3);
+        assert_eq!(envelope.payload["rolled_back"], false);
+        assert_eq!(envelope.payload["error"], "command did not stop after cancel");
+    }
+
+    #[test]
+    fn event_vm_crashed_envelope() {
+        let event = Event::VmCrashed {
+            exit_code: None,
+            signal: Some(9),
+            serial_tail: vec!["Kernel panic - not syncing".to_string()],
+            restarting: true,
+        };
+        let envelope = event.to_envelope();
+        assert_eq!(envelope.event_type, "event.vm_crashed");
+        assert_eq!(envelope.payload["signal"], 9);
+        assert!(envelope.payload.get("exit_code").is_none());
+        assert_eq!(envelope.payload["serial_tail"][0], "Kernel panic - not syncing");
+        assert_eq!(envelope.payload["restarting"], true);
+    }
+
+    #[test]
+    fn session_events_carry_their_session_id() {
+        let event = Event::Session {
+            session_id: "s2".to_string(),
+            event: Box::new(Event::AgentOutput {
+                data: "done".to_string(),
+            }),
+        };
+        let envelope = event.to_envelope();
+        assert_eq!(envelope.event_type, "event.agent_output");
+        assert_eq!(envelope.payload["session_id"], "s2");
+        assert_eq!(envelope.payload["data"], "done");
+    }
+
+    #[test]
+    fn event_progress_envelope() {
+        let event = Event::Progress {
+            request_id: "9".to_string(),
+            percent: 40,
+            current_path: Some("src/lib.rs".to_string()),
+        };
+        let envelope = event.to_envelope();
+        assert_eq!(envelope.event_type, "event.progress");
+        assert_eq!(envelope.payload["request_id"], "9");
+        assert_eq!(envelope.payload["percent"], 40);
+        assert_eq!(envelope.payload["current_path"], "src/lib.rs");
+
+        let event = Event::Progress {
+            request_id: "9".to_string(),
+            percent: 100,
+            current_path: None,
+        };
+        assert!(event.to_envelope().payload.get("current_path").is_none());
+    }
+
+    #[test]
+    fn seq_is_omitted_until_set() {
+        let mut envelope = Event::AgentOutput {
+            data: "done".to_string(),
+        }
+        .to_envelope();
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert!(json.get("seq").is_none());
+
+        envelope.seq = Some(7);
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json["seq"], 7);
+        let back: EventEnvelope = serde_json::from_value(json).unwrap();
+        assert_eq!(back.seq, Some(7));
+    }
+
+    #[test]
+    fn events_of_sessions_keep_their_category() {
+        let output = Event::AgentOutput {
+            data: "done".to_string(),
+        };
+        assert_eq!(output.category(), Some(EventCategory::Output));
+        let event = Event::Session {
+            session_id: "s1".to_string(),
+            event: Box::new(output),
+        };
+        assert_eq!(event.category(), Some(EventCategory::Output));
+        let progress = Event::Progress {
+            request_id: "1".to_string(),
+            percent: 5,
+            current_path: None,
+        };
+        assert_eq!(progress.category(), None);
+    }
+
+    #[test]
+    fn event_ignores_reloaded_envelope() {
+        let event = Event::IgnoresReloaded {
+            working_dir: "/work".to_string(),
+            sources: vec!["src/.gitignore".to_string()],
+        };
+        let envelope = event.to_envelope();
+        assert_eq!(envelope.event_type, "event.ignores_reloaded");
+        assert_eq!(envelope.payload["working_dir"], "/work");
+        assert_eq!(envelope.payload["sources"][0], "src/.gitignore");
     }
 
     #[test]
     fn event_warning_envelope() {
         let event = Event::Warning {
-            code: "undo_eviction".to_string(),
-            message: "oldest step evicted".to_string(),
+            warning: SandboxWarning::FileWatcherFailed {
+                path: Some("/work".to_string()),
+                reason: "watch limit reached".to_string(),
+            },
         };
         let envelope = event.to_envelope();
         assert_eq!(envelope.event_type, "event.warning");
-        assert_eq!(envelope.payload["code"], "undo_eviction");
+        assert_eq!(envelope.payload["code"], "file_watcher_failed");
+        assert_eq!(envelope.payload["severity"], "warning");
+        assert_eq!(envelope.payload["path"], "/work");
+        assert_eq!(
+            envelope.payload["message"],
+            "Failed to watch directory /work: watch limit reached"
+        );
     }
 
     #[test]
@@ -467,6 +1675,10 @@ mod tests {
         let event = Event::Recovery {
             paths_restored: 5,
             paths_deleted: 2,
+            rollbacks_reverted: 1,
+            steps_rolled_back: 0,
+            steps_evicted: 3,
+            steps_squashed: 0,
         };
         let envelope = event.to_envelope();
         let json = serde_json::to_string(&envelope).unwrap();
@@ -474,13 +1686,71 @@ mod tests {
         assert_eq!(parsed.event_type, "event.recovery");
         assert_eq!(parsed.payload["paths_restored"], 5);
         assert_eq!(parsed.payload["paths_deleted"], 2);
+        assert_eq!(parsed.payload["rollbacks_reverted"], 1);
+        assert_eq!(parsed.payload["steps_evicted"], 3);
+    }
+
+    #[test]
+    fn event_compaction_envelopes() {
+        let progress = Event::CompactionProgress {
+            working_dir: "/work".to_string(),
+            percent: 40,
+        };
+        assert_eq!(progress.category(), Some(EventCategory::Undo));
+        let envelope = progress.to_envelope();
+        assert_eq!(envelope.event_type, "event.compaction_progress");
+        assert_eq!(envelope.payload["percent"], 40);
+
+        let finished = Event::Compaction {
+            working_dir: "/work".to_string(),
+            paused: true,
+            steps_compacted: 2,
+            files_recompressed: 5,
+            blobs_deduplicated: 1,
+            bytes_saved: 4096,
+            corrupt_paths: vec!["src/lib.rs".to_string()],
+        };
+        let envelope = finished.to_envelope();
+        assert_eq!(envelope.event_type, "event.compaction");
+        assert_eq!(envelope.payload["paused"], true);
+        assert_eq!(envelope.payload["bytes_saved"], 4096);
+        assert_eq!(envelope.payload["corrupt_paths"][0], "src/lib.rs");
+    }
+
+    #[test]
+    fn envelope_has_origin_but_no_stamp_until_written() {
+        let envelope = Event::AgentOutput {
+            data: "done".to_string(),
+        }
+        .to_envelope();
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json["origin"], "agent");
+        assert!(json.get("seq").is_none());
+        assert!(json.get("emitted_at").is_none());
+    }
+
+    #[test]
+    fn event_stale_resources_envelope() {
+        let event = Event::StaleResources {
+            resources: vec![StaleResourceReport {
+                kind: "orphan_process".to_string(),
+                path: None,
+                pid: Some(4242),
+                message: "qemu-system-x86_64 still references the socket directory".to_string(),
+            }],
+        };
+        let envelope = event.to_envelope();
+        assert_eq!(envelope.event_type, "event.stale_resources");
+        assert_eq!(envelope.payload["resources"][0]["kind"], "orphan_process");
+        assert_eq!(envelope.payload["resources"][0]["pid"], 4242);
+        assert!(envelope.payload["resources"][0].get("path").is_none());
     }
 
     #[test]
     fn log_entry_serialization() {
         let entry = LogEntry {
-            timestamp: "2025-03-01T12:00:01.234567Z".to_string(),
-            level: "info".to_string(),
+            timestamp: "2025-03-01T12:00:01.234Z".to_string(),
+            level: LogLevel::Info,
             component: "stdio_api".to_string(),
             request_id: Some("1".to_string()),
             step_id: None,
@@ -488,7 +1758,7 @@ mod tests {
         };
         let json = serde_json::to_string(&entry).unwrap();
         let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
-        assert_eq!(parsed["timestamp"], "2025-03-01T12:00:01.234567Z");
+        assert_eq!(parsed["timestamp"], "2025-03-01T12:00:01.234Z");
         assert_eq!(parsed["level"], "info");
         assert_eq!(parsed["component"], "stdio_api");
         assert_eq!(parsed["request_id"], "1");
@@ -517,10 +1787,16 @@ mod tests {
         let config = WorkingDirectoryConfig {
             path: "/tmp/project".to_string(),
             label: Some("main".to_string()),
+            backend: Some(MountBackend::VirtiofsReadOnly),
+            read_only: true,
+            exclude: vec!["target".to_string()],
+            overlay: false,
         };
         let json = serde_json::to_string(&config).unwrap();
         let parsed: WorkingDirectoryConfig = serde_json::from_str(&json).unwrap();
         assert_eq!(config, parsed);
+        assert!(json.contains(r#""backend":"virtiofs_read_only""#), "{json}");
+        assert!(json.contains(r#""exclude":["target"]"#), "{json}");
     }
 
     #[test]
@@ -530,5 +1806,63 @@ mod tests {
         assert_eq!(payload.network_policy, "disabled");
         assert_eq!(payload.vm_mode, "ephemeral");
         assert_eq!(payload.protocol_version, None);
+        assert_eq!(payload.symlink_policy, None);
+        assert_eq!(payload.coherent_capture, None);
+        assert_eq!(payload.case_sensitivity, None);
+    }
+
+    #[test]
+    fn undo_history_payload_format() {
+        let payload: UndoHistoryPayload = serde_json::from_str("{}").unwrap();
+        assert_eq!(payload.format, HistoryFormat::Json);
+        let payload: UndoHistoryPayload = serde_json::from_str(r#"{"format":"text"}"#).unwrap();
+        assert_eq!(payload.format, HistoryFormat::Text);
+    }
+
+    #[test]
+    fn undo_configure_payload_symlink_policy() {
+        let json = r#"{"symlink_policy":"read_only"}"#;
+        let payload: UndoConfigurePayload = serde_json::from_str(json).unwrap();
+        assert_eq!(payload.symlink_policy, Some(SymlinkPolicy::ReadOnly));
+        assert_eq!(payload.max_step_count, None);
+        assert_eq!(payload.external_modification, None);
+        assert_eq!(payload.directory, None);
+    }
+
+    #[test]
+    fn undo_configure_payload_directory_and_limits() {
+        let json = r#"{"directory":"1","max_step_count":5,"max_log_size_bytes":1048576}"#;
+        let payload: UndoConfigurePayload = serde_json::from_str(json).unwrap();
+        assert_eq!(payload.directory.as_deref(), Some("1"));
+        assert_eq!(payload.max_step_count, Some(5));
+        assert_eq!(payload.max_log_size_bytes, Some(1_048_576));
+        assert_eq!(payload.max_single_step_size_bytes, None);
+    }
+
+    #[test]
+    fn undo_configure_payload_external_modification_rules() {
+        let json = r#"{"external_modification":{"default_policy":"warn","rules":[{"pattern":"target/**","policy":"ignore"}]}}"#;
+        let payload: UndoConfigurePayload = serde_json::from_str(json).unwrap();
+        let config = payload.external_modification.unwrap();
+        assert_eq!(config.default_policy, codeagent_common::ExternalModificationPolicy::Warn);
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(config.rules[0].policy, codeagent_common::ExternalModificationPolicy::Ignore);
+    }
+
+    #[test]
+    fn undo_configure_payload_coherent_capture_rules() {
+        let json = r#"{"coherent_capture":{"rules":[{"pattern":"*.db","strategy":"advisory_lock"}],"max_attempts":2}}"#;
+        let payload: UndoConfigurePayload = serde_json::from_str(json).unwrap();
+        let config = payload.coherent_capture.unwrap();
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(
+            config.rules[0].strategy,
+            codeagent_common::CoherentCaptureStrategy::AdvisoryLock
+        );
+        assert_eq!(config.max_attempts, 2);
+        assert_eq!(
+            config.retry_interval_ms,
+            codeagent_common::DEFAULT_COHERENT_CAPTURE_RETRY_INTERVAL_MS
+        );
     }
 }
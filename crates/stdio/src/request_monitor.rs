@@ -0,0 +1,152 @@
+//! Cancellation and progress of requests in flight.
+//!
+//! The router registers a [`RequestMonitor`] for every request it
+//! dispatches. `request.cancel` flags the one with the given `request_id`;
+//! handlers of long-running requests check it between steps and report
+//! progress through it, which reaches the client as `event.progress`.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use codeagent_common::OperationMonitor;
+use tokio::sync::mpsc;
+
+use crate::protocol::Event;
+
+/// The monitor of one request.
+pub struct RequestMonitor {
+    request_id: String,
+    cancelled: AtomicBool,
+    /// Percent last reported, so a request working through thousands of
+    /// files sends one event per percent rather than one per file.
+    last_percent: Mutex<Option<u8>>,
+    events: Option<mpsc::UnboundedSender<Event>>,
+}
+
+impl RequestMonitor {
+    pub fn new(request_id: &str, events: Option<mpsc::UnboundedSender<Event>>) -> Self {
+        Self {
+            request_id: request_id.to_string(),
+            cancelled: AtomicBool::new(false),
+            last_percent: Mutex::new(None),
+            events,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+impl OperationMonitor for RequestMonitor {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn progress(&self, percent: u8, current_path: Option<&str>) {
+        let Some(events) = &self.events else {
+            return;
+        };
+        let percent = percent.min(100);
+        {
+            let mut last = self.last_percent.lock().unwrap();
+            if *last == Some(percent) {
+                return;
+            }
+            *last = Some(percent);
+        }
+        let _ = events.send(Event::Progress {
+            request_id: self.request_id.clone(),
+            percent,
+            current_path: current_path.map(String::from),
+        });
+    }
+}
+
+/// Monitors of the requests in flight, by `request_id`.
+#[derive(Default)]
+pub struct InFlightRequests {
+    monitors: Mutex<HashMap<String, Arc<RequestMonitor>>>,
+    events: Mutex<Option<mpsc::UnboundedSender<Event>>>,
+}
+
+impl InFlightRequests {
+    /// Send progress events to `events`.
+    pub fn set_event_sender(&self, events: mpsc::UnboundedSender<Event>) {
+        *self.events.lock().unwrap() = Some(events);
+    }
+
+    /// Register a monitor for `request_id`, until [`finish`](Self::finish).
+    pub fn start(&self, request_id: &str) -> Arc<RequestMonitor> {
+        let events = self.events.lock().unwrap().clone();
+        let monitor = Arc::new(RequestMonitor::new(request_id, events));
+        self.monitors
+            .lock()
+            .unwrap()
+            .insert(request_id.to_string(), Arc::clone(&monitor));
+        monitor
+    }
+
+    pub fn finish(&self, monitor: &Arc<RequestMonitor>) {
+        let mut monitors = self.monitors.lock().unwrap();
+        if monitors
+            .get(&monitor.request_id)
+            .is_some_and(|registered| Arc::ptr_eq(registered, monitor))
+        {
+            monitors.remove(&monitor.request_id);
+        }
+    }
+
+    /// Flag the request in flight with `request_id`. Returns whether there
+    /// was one.
+    pub fn cancel(&self, request_id: &str) -> bool {
+        match self.monitors.lock().unwrap().get(request_id) {
+            Some(monitor) => {
+                monitor.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_is_sent_once_per_percent() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let requests = InFlightRequests::default();
+        requests.set_event_sender(sender);
+        let monitor = requests.start("7");
+        monitor.progress(10, Some("a.txt"));
+        monitor.progress(10, Some("b.txt"));
+        monitor.progress(250, None);
+
+        let first = receiver.try_recv().unwrap();
+        assert_eq!(
+            first,
+            Event::Progress {
+                request_id: "7".to_string(),
+                percent: 10,
+                current_path: Some("a.txt".to_string()),
+            }
+        );
+        assert!(matches!(receiver.try_recv().unwrap(), Event::Progress { percent: 100, .. }));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn only_requests_in_flight_can_be_cancelled() {
+        let requests = InFlightRequests::default();
+        let monitor = requests.start("1");
+        assert!(!requests.cancel("2"));
+        assert!(requests.cancel("1"));
+        assert!(monitor.is_cancelled());
+
+        requests.finish(&monitor);
+        assert!(!requests.cancel("1"));
+    }
+}
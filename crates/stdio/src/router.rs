@@ -1,13 +1,30 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use codeagent_common::{ErrorCode, OperationMonitor};
+use tokio::sync::mpsc;
 
 use crate::error::StdioError;
+use crate::parser::MessageLimits;
+use crate::request_monitor::InFlightRequests;
+use crate::terminal_output::SUPPORTED_ENCODINGS;
 use crate::path_validation::validate_path;
 use crate::protocol::{
-    AgentExecutePayload, AgentPromptPayload, FsListPayload, FsReadPayload, Request,
-    ResponseEnvelope, SafeguardConfirmPayload, SafeguardConfigurePayload, SessionStartPayload,
-    UndoConfigurePayload, UndoHistoryPayload, UndoRollbackPayload,
+    AgentExecutePayload, AgentInputPayload, AgentPromptPayload, Event, EventCategory,
+    FsCommitPayload, FsDeletePayload, FsListPayload, FsHashPayload, FsPatchPayload, FsReadPayload,
+    FsStatPayload, FsTracePayload, FsWritePayload, ProtocolHelloPayload,
+    Request, ResponseEnvelope, SafeguardConfirmPayload, SafeguardConfigurePayload,
+    SafeguardHistoryPayload,
+    SessionClonePayload, SessionConfigurePayload, SessionDestroyPayload, SessionEnvSetPayload, SessionEnvUnsetPayload, SessionStartPayload,
+    TerminalOutputOptions,
+    UndoAttestPayload, UndoConfigurePayload, UndoExpectPayload, UndoHistoryPayload,
+    UndoRollbackPayload, UndoSquashPayload, VmInventoryPayload,
+};
+use crate::server::request_type_name;
+use crate::version::{
+    feature_of, FEATURES, MAX_SUPPORTED_VERSION, MIN_SUPPORTED_VERSION, PROTOCOL_VERSION,
 };
-use crate::version::{MAX_SUPPORTED_VERSION, MIN_SUPPORTED_VERSION};
 
 /// Trait abstracting the handling of parsed STDIO API requests.
 ///
@@ -15,35 +32,80 @@ use crate::version::{MAX_SUPPORTED_VERSION, MIN_SUPPORTED_VERSION};
 /// or a `StdioError`. For TDD Step 10, a `StubHandler` implements this trait
 /// with minimal canned responses. Real implementations will be added in later
 /// TDD steps.
+///
+/// Long-running requests get the [`OperationMonitor`] of the request: they
+/// report progress to it and stop early once it is cancelled.
 pub trait RequestHandler: Send + Sync {
     fn session_start(
         &self,
         payload: SessionStartPayload,
     ) -> Result<serde_json::Value, StdioError>;
     fn session_stop(&self) -> Result<serde_json::Value, StdioError>;
+    fn session_destroy(
+        &self,
+        payload: SessionDestroyPayload,
+    ) -> Result<serde_json::Value, StdioError>;
     fn session_reset(&self) -> Result<serde_json::Value, StdioError>;
+    fn session_resume(&self) -> Result<serde_json::Value, StdioError>;
     fn session_status(&self) -> Result<serde_json::Value, StdioError>;
+    fn session_clone(
+        &self,
+        payload: SessionClonePayload,
+    ) -> Result<serde_json::Value, StdioError>;
+    fn session_warnings(&self) -> Result<serde_json::Value, StdioError>;
+    fn session_metrics(&self) -> Result<serde_json::Value, StdioError>;
+    fn session_env_set(
+        &self,
+        payload: SessionEnvSetPayload,
+    ) -> Result<serde_json::Value, StdioError>;
+    fn session_env_unset(
+        &self,
+        payload: SessionEnvUnsetPayload,
+    ) -> Result<serde_json::Value, StdioError>;
+    fn session_env_list(&self) -> Result<serde_json::Value, StdioError>;
+    fn session_configure(
+        &self,
+        payload: SessionConfigurePayload,
+    ) -> Result<serde_json::Value, StdioError>;
     fn undo_rollback(
         &self,
         payload: UndoRollbackPayload,
+        monitor: &dyn OperationMonitor,
     ) -> Result<serde_json::Value, StdioError>;
     fn undo_history(&self, payload: UndoHistoryPayload)
         -> Result<serde_json::Value, StdioError>;
     fn undo_configure(
         &self,
         payload: UndoConfigurePayload,
+        monitor: &dyn OperationMonitor,
     ) -> Result<serde_json::Value, StdioError>;
     fn undo_discard(&self) -> Result<serde_json::Value, StdioError>;
+    fn undo_attest(&self, payload: UndoAttestPayload)
+        -> Result<serde_json::Value, StdioError>;
+    fn undo_expect(&self, payload: UndoExpectPayload)
+        -> Result<serde_json::Value, StdioError>;
+    fn undo_reload_ignores(&self) -> Result<serde_json::Value, StdioError>;
+    fn undo_squash(&self, payload: UndoSquashPayload)
+        -> Result<serde_json::Value, StdioError>;
     fn agent_execute(
         &self,
         payload: AgentExecutePayload,
     ) -> Result<serde_json::Value, StdioError>;
+    fn agent_input(&self, payload: AgentInputPayload)
+        -> Result<serde_json::Value, StdioError>;
     fn agent_prompt(
         &self,
         payload: AgentPromptPayload,
     ) -> Result<serde_json::Value, StdioError>;
     fn fs_list(&self, payload: FsListPayload) -> Result<serde_json::Value, StdioError>;
     fn fs_read(&self, payload: FsReadPayload) -> Result<serde_json::Value, StdioError>;
+    fn fs_write(&self, payload: FsWritePayload) -> Result<serde_json::Value, StdioError>;
+    fn fs_delete(&self, payload: FsDeletePayload) -> Result<serde_json::Value, StdioError>;
+    fn fs_stat(&self, payload: FsStatPayload) -> Result<serde_json::Value, StdioError>;
+    fn fs_hash(&self, payload: FsHashPayload) -> Result<serde_json::Value, StdioError>;
+    fn fs_patch(&self, payload: FsPatchPayload) -> Result<serde_json::Value, StdioError>;
+    fn fs_commit(&self, payload: FsCommitPayload) -> Result<serde_json::Value, StdioError>;
+    fn fs_trace(&self, payload: FsTracePayload) -> Result<serde_json::Value, StdioError>;
     fn fs_status(&self) -> Result<serde_json::Value, StdioError>;
     fn safeguard_configure(
         &self,
@@ -53,34 +115,404 @@ pub trait RequestHandler: Send + Sync {
         &self,
         payload: SafeguardConfirmPayload,
     ) -> Result<serde_json::Value, StdioError>;
+    fn safeguard_history(
+        &self,
+        payload: SafeguardHistoryPayload,
+    ) -> Result<serde_json::Value, StdioError>;
+    fn system_cleanup(&self) -> Result<serde_json::Value, StdioError>;
+    fn vm_inventory(
+        &self,
+        payload: VmInventoryPayload,
+    ) -> Result<serde_json::Value, StdioError>;
+
+    /// Start gathering the undo steps of the requests that follow, for an
+    /// atomic `batch`. Handlers that record no steps cannot.
+    fn begin_step_scope(&self) -> Result<(), StdioError> {
+        Err(StdioError::CapabilityUnavailable {
+            capability: "atomic batches".to_string(),
+            reason: "this handler records no undo steps".to_string(),
+        })
+    }
+
+    /// Close the scope [`begin_step_scope`](Self::begin_step_scope) opened:
+    /// merge the steps the batch's requests made since if `commit`, roll
+    /// them back otherwise, leaving other steps alone. Returns what became
+    /// of each working directory's steps.
+    fn end_step_scope(&self, _commit: bool) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::Value::Null)
+    }
+}
+
+/// Creates the handlers of a [`Router`] that serves several sessions.
+pub trait SessionFactory: Send + Sync {
+    /// A handler for the session that will be `session_id` once started.
+    fn create(&self, session_id: &str) -> Box<dyn RequestHandler>;
 }
 
 /// Routes parsed requests to a `RequestHandler`, performing path validation
 /// for filesystem operations and protocol version checks for `session.start`.
+///
+/// The router also owns the message size limits and terminal output options
+/// negotiated by `session.start`; they last until `session.stop`. What
+/// `protocol.hello` negotiates lasts as long as the router.
+///
+/// A router made with [`Router::with_sessions`] serves several sessions,
+/// each with a handler of its own: `session.start` answers with the new
+/// session's `session_id`, which every later request for it must carry.
+/// Limits and terminal output options are the connection's and last until
+/// no session remains.
 pub struct Router {
     root_dir: PathBuf,
-    handler: Box<dyn RequestHandler>,
+    handlers: Handlers,
+    message_limits: Mutex<MessageLimits>,
+    terminal_output: Mutex<TerminalOutputOptions>,
+    event_categories: Mutex<Vec<EventCategory>>,
+    in_flight: InFlightRequests,
+    negotiated: Mutex<Option<Negotiated>>,
+}
+
+/// What a `protocol.hello` settled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u32,
+    /// The features of [`FEATURES`] the client asked for.
+    pub features: Vec<&'static str>,
+}
+
+impl Negotiated {
+    /// Pick the newest of `payload.versions` this side speaks, and the
+    /// features asked for that it knows.
+    fn from_hello(payload: &ProtocolHelloPayload) -> Result<Self, StdioError> {
+        let supported = MIN_SUPPORTED_VERSION..=MAX_SUPPORTED_VERSION;
+        let version = if payload.versions.is_empty() {
+            PROTOCOL_VERSION
+        } else {
+            let common = payload.versions.iter().filter(|v| supported.contains(v)).max();
+            *common.ok_or(StdioError::UnsupportedProtocolVersion {
+                version: payload.versions.iter().copied().max().unwrap_or_default(),
+                min: MIN_SUPPORTED_VERSION,
+                max: MAX_SUPPORTED_VERSION,
+            })?
+        };
+        let features = FEATURES
+            .iter()
+            .map(|(feature, _)| *feature)
+            .filter(|feature| payload.features.iter().any(|asked| asked == feature))
+            .collect();
+        Ok(Self { version, features })
+    }
+}
+
+enum Handlers {
+    Single(Box<dyn RequestHandler>),
+    Sessions(Sessions),
+}
+
+struct Sessions {
+    factory: Box<dyn SessionFactory>,
+    max_sessions: usize,
+    table: Mutex<SessionTable>,
+}
+
+struct SessionTable {
+    active: BTreeMap<String, Arc<dyn RequestHandler>>,
+    /// The handler the next `session.start` goes to, and the id it gets.
+    idle_id: String,
+    idle: Arc<dyn RequestHandler>,
+    next_id: u64,
 }
 
 impl Router {
     pub fn new(root_dir: PathBuf, handler: Box<dyn RequestHandler>) -> Self {
-        Self { root_dir, handler }
+        Self {
+            root_dir,
+            handlers: Handlers::Single(handler),
+            message_limits: Mutex::new(MessageLimits::default()),
+            terminal_output: Mutex::new(TerminalOutputOptions::default()),
+            event_categories: Mutex::new(EventCategory::ALL.to_vec()),
+            in_flight: InFlightRequests::default(),
+            negotiated: Mutex::new(None),
+        }
+    }
+
+    /// A router for up to `max_sessions` sessions, with handlers made by
+    /// `factory`. Each handler validates paths against its own session's
+    /// working directories, so the router does not.
+    pub fn with_sessions(factory: Box<dyn SessionFactory>, max_sessions: usize) -> Self {
+        let idle_id = "s1".to_string();
+        let idle = Arc::from(factory.create(&idle_id));
+        Self {
+            root_dir: PathBuf::new(),
+            handlers: Handlers::Sessions(Sessions {
+                factory,
+                max_sessions,
+                table: Mutex::new(SessionTable {
+                    active: BTreeMap::new(),
+                    idle_id,
+                    idle,
+                    next_id: 2,
+                }),
+            }),
+            message_limits: Mutex::new(MessageLimits::default()),
+            terminal_output: Mutex::new(TerminalOutputOptions::default()),
+            event_categories: Mutex::new(EventCategory::ALL.to_vec()),
+            in_flight: InFlightRequests::default(),
+            negotiated: Mutex::new(None),
+        }
+    }
+
+    /// Limits the server should parse incoming lines against.
+    pub fn message_limits(&self) -> MessageLimits {
+        self.message_limits.lock().unwrap().clone()
+    }
+
+    /// The connection's `protocol.hello`, if the client sent one.
+    pub fn negotiated(&self) -> Option<Negotiated> {
+        self.negotiated.lock().unwrap().clone()
+    }
+
+    /// Fail `request` if it belongs to a feature the client's
+    /// `protocol.hello` left out.
+    fn check_feature(&self, request: &Request) -> Result<(), StdioError> {
+        let request_type = request_type_name(request);
+        let (Some(feature), Some(negotiated)) =
+            (feature_of(request_type), &*self.negotiated.lock().unwrap())
+        else {
+            return Ok(());
+        };
+        if negotiated.features.contains(&feature) {
+            return Ok(());
+        }
+        Err(StdioError::CapabilityUnavailable {
+            capability: request_type.to_string(),
+            reason: format!("feature {feature} was not asked for in protocol.hello"),
+        })
+    }
+
+    /// Send `event.progress` of requests in flight to `events`.
+    pub fn send_progress_to(&self, events: mpsc::UnboundedSender<Event>) {
+        self.in_flight.set_event_sender(events);
+    }
+
+    /// How the server should deliver `event.terminal_output`.
+    pub fn terminal_output_options(&self) -> TerminalOutputOptions {
+        self.terminal_output.lock().unwrap().clone()
+    }
+
+    /// Whether the client's `events.subscribe` covers `event`.
+    pub fn subscribed(&self, event: &Event) -> bool {
+        event
+            .category()
+            .is_none_or(|category| self.event_categories.lock().unwrap().contains(&category))
+    }
+
+    /// Add `capabilities` to a `session.start` or `session.status` response.
+    fn with_capabilities(&self, mut response: serde_json::Value) -> serde_json::Value {
+        if let Some(object) = response.as_object_mut() {
+            object.insert(
+                "capabilities".to_string(),
+                serde_json::json!({
+                    "message_limits": self.message_limits(),
+                    "terminal_output": {
+                        "encodings": SUPPORTED_ENCODINGS,
+                        "options": self.terminal_output_options(),
+                    },
+                }),
+            );
+        }
+        response
     }
 
     /// Dispatch a parsed request, returning a response envelope.
     pub fn dispatch(&self, request: Request) -> ResponseEnvelope {
+        self.dispatch_addressed(None, request)
+    }
+
+    /// Dispatch a request for `session_id`. A router for a single session
+    /// ignores the id. What the handlers log carries the request's id.
+    #[tracing::instrument(name = "request", skip_all, fields(request_id = request.request_id()))]
+    pub fn dispatch_addressed(
+        &self,
+        session_id: Option<&str>,
+        request: Request,
+    ) -> ResponseEnvelope {
         let request_id = request.request_id().to_string();
-        let result = self.dispatch_inner(request);
+        let monitor = self.in_flight.start(&request_id);
+        let result = match (self.check_feature(&request), &self.handlers) {
+            (Err(error), _) => Err(error),
+            (Ok(()), Handlers::Single(handler)) => {
+                self.dispatch_inner(&**handler, request, &*monitor)
+            }
+            (Ok(()), Handlers::Sessions(sessions)) => {
+                self.dispatch_session(sessions, session_id, request, &*monitor)
+            }
+        };
+        self.in_flight.finish(&monitor);
         match result {
             Ok(payload) => ResponseEnvelope::ok(request_id, payload),
             Err(error) => ResponseEnvelope::error(request_id, error.to_error_detail()),
         }
     }
 
+    fn dispatch_session(
+        &self,
+        sessions: &Sessions,
+        session_id: Option<&str>,
+        request: Request,
+        monitor: &dyn OperationMonitor,
+    ) -> Result<Option<serde_json::Value>, StdioError> {
+        let starts = matches!(
+            request,
+            Request::SessionStart { .. } | Request::SessionResume { .. }
+        );
+        let stops = matches!(request, Request::SessionStop { .. } | Request::SessionDestroy { .. });
+
+        if starts {
+            if session_id.is_some() {
+                return Err(StdioError::InvalidField {
+                    field: "session_id".to_string(),
+                    message: "a new session is given its id in the response".to_string(),
+                });
+            }
+            let (id, handler) = {
+                let table = sessions.table.lock().unwrap();
+                if table.active.len() >= sessions.max_sessions {
+                    return Err(StdioError::Failed {
+                        code: ErrorCode::SessionLimitReached,
+                        message: format!(
+                            "all {} sessions are in use; stop one first",
+                            sessions.max_sessions
+                        ),
+                    });
+                }
+                (table.idle_id.clone(), Arc::clone(&table.idle))
+            };
+            let mut response = self.dispatch_inner(&*handler, request, monitor)?;
+            if let Some(object) = response.as_mut().and_then(|r| r.as_object_mut()) {
+                object.insert("session_id".to_string(), serde_json::json!(id));
+            }
+            let mut table = sessions.table.lock().unwrap();
+            let next_id = format!("s{}", table.next_id);
+            table.next_id += 1;
+            table.idle = Arc::from(sessions.factory.create(&next_id));
+            table.idle_id = next_id;
+            table.active.insert(id, handler);
+            return Ok(response);
+        }
+
+        let handler = {
+            let table = sessions.table.lock().unwrap();
+            match session_id {
+                Some(id) => table.active.get(id).cloned().ok_or_else(|| {
+                    StdioError::InvalidField {
+                        field: "session_id".to_string(),
+                        message: format!("no session {id}"),
+                    }
+                })?,
+                // Requests about the host rather than a session, and any
+                // request while there is no session, go to the idle handler.
+                None if table.active.is_empty()
+                    || matches!(
+                        request,
+                        Request::SystemCleanup { .. }
+                            | Request::VmInventory { .. }
+                            | Request::RequestCancel { .. }
+                            | Request::EventsSubscribe { .. }
+                            | Request::EventsReplay { .. }
+                            | Request::LogConfigure { .. }
+                            | Request::ProtocolHello { .. }
+                    ) =>
+                {
+                    Arc::clone(&table.idle)
+                }
+                None => {
+                    return Err(StdioError::MissingField {
+                        field: "session_id".to_string(),
+                    });
+                }
+            }
+        };
+        let response = self.dispatch_inner(&*handler, request, monitor)?;
+
+        // A request that only handed out a token leaves the session up.
+        let stopped = stops
+            && response
+                .as_ref()
+                .is_none_or(|response| response.get("confirmation").is_none());
+        if let (true, Some(id)) = (stopped, session_id) {
+            let mut table = sessions.table.lock().unwrap();
+            table.active.remove(id);
+            if table.active.is_empty() {
+                self.reset_options();
+            }
+        }
+        Ok(response)
+    }
+
+    /// Back to the default limits and terminal output options.
+    fn reset_options(&self) {
+        *self.message_limits.lock().unwrap() = MessageLimits::default();
+        *self.terminal_output.lock().unwrap() = TerminalOutputOptions::default();
+    }
+
+    /// Check a filesystem path against the root directory. A router for
+    /// several sessions leaves that to each session's handler.
+    fn validate(&self, path: &str) -> Result<(), StdioError> {
+        match self.handlers {
+            Handlers::Single(_) => validate_path(path, &self.root_dir).map(|_| ()),
+            Handlers::Sessions(_) => Ok(()),
+        }
+    }
+
+    /// Run the requests of a `batch` in order, collecting their responses.
+    /// An atomic batch stops at the first failure and has the handler roll
+    /// back or merge the steps its requests made.
+    fn dispatch_batch(
+        &self,
+        handler: &dyn RequestHandler,
+        atomic: bool,
+        requests: Vec<Request>,
+        monitor: &dyn OperationMonitor,
+    ) -> Result<serde_json::Value, StdioError> {
+        if atomic {
+            handler.begin_step_scope()?;
+        }
+        let mut responses = Vec::with_capacity(requests.len());
+        let mut failed = false;
+        for mut request in requests {
+            // The command's writes must land before the scope closes.
+            if let (true, Request::AgentExecute { payload, .. }) = (atomic, &mut request) {
+                payload.wait = true;
+            }
+            let request_id = request.request_id().to_string();
+            let result = self
+                .check_feature(&request)
+                .and_then(|()| self.dispatch_inner(handler, request, monitor));
+            responses.push(match result {
+                Ok(payload) => ResponseEnvelope::ok(request_id, payload),
+                Err(error) => {
+                    failed = true;
+                    ResponseEnvelope::error(request_id, error.to_error_detail())
+                }
+            });
+            if atomic && failed {
+                break;
+            }
+        }
+        if !atomic {
+            return Ok(serde_json::json!({ "responses": responses }));
+        }
+        let steps = handler.end_step_scope(!failed)?;
+        Ok(serde_json::json!({ "responses": responses, "committed": !failed, "steps": steps }))
+    }
+
     fn dispatch_inner(
         &self,
+        handler: &dyn RequestHandler,
         request: Request,
+        monitor: &dyn OperationMonitor,
     ) -> Result<Option<serde_json::Value>, StdioError> {
+        let single = matches!(self.handlers, Handlers::Single(_));
         match request {
             Request::SessionStart { payload, .. } => {
                 if let Some(version) = payload.protocol_version {
@@ -91,47 +523,200 @@ impl Router {
                             max: MAX_SUPPORTED_VERSION,
                         });
                     }
+                    if let Some(negotiated) = self.negotiated() {
+                        if negotiated.version != version {
+                            return Err(StdioError::InvalidField {
+                                field: "protocol_version".to_string(),
+                                message: format!(
+                                    "protocol.hello settled on version {}",
+                                    negotiated.version
+                                ),
+                            });
+                        }
+                    }
+                }
+                let limits = match &payload.message_limits {
+                    Some(requested) => MessageLimits::default().negotiate(requested)?,
+                    None => MessageLimits::default(),
+                };
+                let terminal_output = payload.terminal_output.clone().unwrap_or_default();
+                if terminal_output.batch_max_bytes == 0 {
+                    return Err(StdioError::InvalidField {
+                        field: "terminal_output".to_string(),
+                        message: "batch_max_bytes must be positive".to_string(),
+                    });
+                }
+                let response = handler.session_start(payload)?;
+                *self.message_limits.lock().unwrap() = limits;
+                *self.terminal_output.lock().unwrap() = terminal_output;
+                Ok(Some(self.with_capabilities(response)))
+            }
+            Request::SessionStop { .. } => {
+                let response = handler.session_stop()?;
+                if single {
+                    self.reset_options();
+                }
+                Ok(Some(response))
+            }
+            Request::SessionDestroy { payload, .. } => {
+                let response = handler.session_destroy(payload)?;
+                // A request that only handed out a token leaves the session up.
+                if single && response.get("confirmation").is_none() {
+                    self.reset_options();
                 }
-                self.handler.session_start(payload).map(Some)
+                Ok(Some(response))
+            }
+            Request::SessionReset { .. } => handler.session_reset().map(Some),
+            // Message limits and terminal output options belong to the
+            // connection that asked for them; a resumed session has the defaults.
+            Request::SessionResume { .. } => {
+                let response = handler.session_resume()?;
+                Ok(Some(self.with_capabilities(response)))
+            }
+            Request::SessionStatus { .. } => {
+                let response = handler.session_status()?;
+                Ok(Some(self.with_capabilities(response)))
+            }
+            Request::SessionClone { payload, .. } => {
+                handler.session_clone(payload).map(Some)
+            }
+            Request::SessionWarnings { .. } => handler.session_warnings().map(Some),
+            Request::SessionMetrics { .. } => handler.session_metrics().map(Some),
+            Request::SessionEnvSet { payload, .. } => {
+                handler.session_env_set(payload).map(Some)
+            }
+            Request::SessionEnvUnset { payload, .. } => {
+                handler.session_env_unset(payload).map(Some)
+            }
+            Request::SessionEnvList { .. } => handler.session_env_list().map(Some),
+            Request::SessionConfigure { payload, .. } => {
+                handler.session_configure(payload).map(Some)
             }
-            Request::SessionStop { .. } => self.handler.session_stop().map(Some),
-            Request::SessionReset { .. } => self.handler.session_reset().map(Some),
-            Request::SessionStatus { .. } => self.handler.session_status().map(Some),
 
             Request::UndoRollback { payload, .. } => {
-                self.handler.undo_rollback(payload).map(Some)
+                handler.undo_rollback(payload, monitor).map(Some)
             }
             Request::UndoHistory { payload, .. } => {
-                self.handler.undo_history(payload).map(Some)
+                handler.undo_history(payload).map(Some)
             }
             Request::UndoConfigure { payload, .. } => {
-                self.handler.undo_configure(payload).map(Some)
+                handler.undo_configure(payload, monitor).map(Some)
+            }
+            Request::UndoDiscard { .. } => handler.undo_discard().map(Some),
+            Request::UndoAttest { payload, .. } => {
+                handler.undo_attest(payload).map(Some)
+            }
+            Request::UndoExpect { payload, .. } => {
+                handler.undo_expect(payload).map(Some)
+            }
+            Request::UndoReloadIgnores { .. } => handler.undo_reload_ignores().map(Some),
+            Request::UndoSquash { payload, .. } => {
+                handler.undo_squash(payload).map(Some)
             }
-            Request::UndoDiscard { .. } => self.handler.undo_discard().map(Some),
 
             Request::AgentExecute { payload, .. } => {
-                self.handler.agent_execute(payload).map(Some)
+                handler.agent_execute(payload).map(Some)
+            }
+            Request::AgentInput { payload, .. } => {
+                handler.agent_input(payload).map(Some)
             }
             Request::AgentPrompt { payload, .. } => {
-                self.handler.agent_prompt(payload).map(Some)
+                handler.agent_prompt(payload).map(Some)
             }
 
             Request::FsList { payload, .. } => {
-                validate_path(&payload.path, &self.root_dir)?;
-                self.handler.fs_list(payload).map(Some)
+                self.validate(&payload.path)?;
+                handler.fs_list(payload).map(Some)
             }
             Request::FsRead { payload, .. } => {
-                validate_path(&payload.path, &self.root_dir)?;
-                self.handler.fs_read(payload).map(Some)
+                self.validate(&payload.path)?;
+                handler.fs_read(payload).map(Some)
+            }
+            Request::FsWrite { payload, .. } => {
+                self.validate(&payload.path)?;
+                handler.fs_write(payload).map(Some)
+            }
+            Request::FsDelete { payload, .. } => {
+                self.validate(&payload.path)?;
+                handler.fs_delete(payload).map(Some)
+            }
+            Request::FsStat { payload, .. } => {
+                self.validate(&payload.path)?;
+                handler.fs_stat(payload).map(Some)
+            }
+            Request::FsHash { payload, .. } => {
+                self.validate(&payload.path)?;
+                handler.fs_hash(payload).map(Some)
+            }
+            Request::FsPatch { payload, .. } => {
+                if let Some(path) = &payload.path {
+                    self.validate(path)?;
+                }
+                handler.fs_patch(payload).map(Some)
             }
-            Request::FsStatus { .. } => self.handler.fs_status().map(Some),
+            Request::FsCommit { payload, .. } => {
+                for path in payload.paths.iter().flatten() {
+                    self.validate(path)?;
+                }
+                handler.fs_commit(payload).map(Some)
+            }
+            Request::FsTrace { payload, .. } => handler.fs_trace(payload).map(Some),
+            Request::FsStatus { .. } => handler.fs_status().map(Some),
 
             Request::SafeguardConfigure { payload, .. } => {
-                self.handler.safeguard_configure(payload).map(Some)
+                handler.safeguard_configure(payload).map(Some)
             }
             Request::SafeguardConfirm { payload, .. } => {
-                self.handler.safeguard_confirm(payload).map(Some)
+                handler.safeguard_confirm(payload).map(Some)
+            }
+            Request::SafeguardHistory { payload, .. } => {
+                handler.safeguard_history(payload).map(Some)
             }
+
+            Request::SystemCleanup { .. } => handler.system_cleanup().map(Some),
+
+            Request::VmInventory { payload, .. } => {
+                handler.vm_inventory(payload).map(Some)
+            }
+
+            Request::RequestCancel { payload, .. } => {
+                let cancelled = self.in_flight.cancel(&payload.request_id);
+                Ok(Some(serde_json::json!({ "cancelled": cancelled })))
+            }
+
+            Request::EventsSubscribe { payload, .. } => {
+                let categories = payload.categories;
+                *self.event_categories.lock().unwrap() = categories.clone();
+                Ok(Some(serde_json::json!({ "categories": categories })))
+            }
+
+            Request::LogConfigure { payload, .. } => {
+                crate::logging::set_level(payload.level);
+                Ok(Some(serde_json::json!({ "level": payload.level })))
+            }
+
+            Request::Batch { atomic, requests, .. } => {
+                self.dispatch_batch(handler, atomic, requests, monitor).map(Some)
+            }
+
+            Request::ProtocolHello { payload, .. } => {
+                let negotiated = Negotiated::from_hello(&payload)?;
+                let response = serde_json::json!({
+                    "protocol_version": negotiated.version,
+                    "min_version": MIN_SUPPORTED_VERSION,
+                    "max_version": MAX_SUPPORTED_VERSION,
+                    "features": FEATURES.iter().map(|(feature, _)| feature).collect::<Vec<_>>(),
+                    "enabled": negotiated.features,
+                });
+                *self.negotiated.lock().unwrap() = Some(negotiated);
+                Ok(Some(response))
+            }
+
+            // The server answers it from the events it wrote.
+            Request::EventsReplay { .. } => Err(StdioError::CapabilityUnavailable {
+                capability: "events.replay".to_string(),
+                reason: "only the STDIO server keeps written events".to_string(),
+            }),
         }
     }
 }
@@ -0,0 +1,694 @@
+//! JSON Schema of the STDIO API.
+//!
+//! [`protocol_schema`] describes every line a client sends or reads: one
+//! definition per request type with its payload, the response envelope and
+//! one definition per event type. Response payloads differ by request and
+//! are left open. `sandbox --print-schema stdio` prints the document.
+
+use std::collections::{BTreeMap, HashMap};
+
+use codeagent_common::schema::{self, DRAFT, JsonSchema, Value, constant, object};
+use codeagent_common::{
+    CaseSensitivity, CoherentCaptureConfig, ExpectedOperation, ExternalModificationConfig,
+    GitMirrorConfig, ReadEncoding, RollbackMode, StepId, SymlinkPolicy,
+};
+use codeagent_common::{enum_schema, object_schema};
+use serde_json::json;
+
+use crate::protocol::{
+    AgentExecutePayload, AgentInputPayload, AgentPromptPayload, BatchPayload, CommandLimits,
+    EventCategory, EventOrigin, EventsReplayPayload, EventsSubscribePayload, FsCommitPayload,
+    FsDeletePayload, FsHashPayload, FsListPayload, FsPatchPayload, FsReadPayload, FsStatPayload,
+    FsTracePayload, FsWritePayload, HistoryFormat, LogConfigurePayload, LogLevel, MountBackend,
+    OutputEncoding, ProtocolHelloPayload, RequestCancelPayload, SafeguardConfigurePayload,
+    SafeguardConfirmPayload, SafeguardHistoryPayload, SessionClonePayload, SessionConfigurePayload,
+    SessionDestroyPayload, SessionEnvSetPayload, SessionEnvUnsetPayload, SessionStartPayload,
+    StaleResourceReport, TerminalOutputOptions, UndoAttestPayload, UndoConfigurePayload,
+    UndoExpectPayload, UndoHistoryPayload, UndoMode, UndoRollbackPayload, UndoSquashPayload,
+    VmInventoryPayload, WorkingDirectoryConfig,
+};
+use crate::{ErrorDetail, PROTOCOL_VERSION};
+
+enum_schema!(MountBackend { Intercepted, Virtiofs, VirtiofsReadOnly, P9 });
+enum_schema!(OutputEncoding { None, Gzip, Zstd });
+enum_schema!(UndoMode { Enabled, Disabled });
+enum_schema!(HistoryFormat { Json, Text });
+enum_schema!(EventCategory { Output, Safeguards, Undo, Vm });
+enum_schema!(EventOrigin { Guest, Agent, Vm, Undo, Safeguard, Watcher, Request, Sandbox });
+enum_schema!(LogLevel { Error, Warn, Info, Debug, Trace });
+
+object_schema!(WorkingDirectoryConfig {
+    required { path: String }
+    optional {
+        label: Option<String>,
+        backend: Option<MountBackend>,
+        read_only: bool,
+        exclude: Vec<String>,
+        overlay: bool,
+    }
+});
+
+object_schema!(TerminalOutputOptions {
+    optional { encoding: OutputEncoding, batch_ms: u64, batch_max_bytes: usize }
+});
+
+object_schema!(SessionStartPayload {
+    required { working_directories: Vec<WorkingDirectoryConfig> }
+    optional {
+        network_policy: String,
+        vm_mode: String,
+        protocol_version: Option<u32>,
+        symlink_policy: Option<SymlinkPolicy>,
+        coherent_capture: Option<CoherentCaptureConfig>,
+        case_sensitivity: Option<CaseSensitivity>,
+        undo: UndoMode,
+        message_limits: Option<BTreeMap<String, usize>>,
+        terminal_output: Option<TerminalOutputOptions>,
+    }
+});
+
+object_schema!(SessionClonePayload {
+    required { target_dir: String }
+    optional { directory: Option<String> }
+});
+
+object_schema!(SessionDestroyPayload {
+    optional { delete_undo_log: bool, confirmation: Option<String> }
+});
+
+object_schema!(SessionEnvSetPayload {
+    required { name: String, value: String }
+    optional { secret: bool }
+});
+
+object_schema!(SessionEnvUnsetPayload {
+    required { name: String }
+});
+
+object_schema!(SessionConfigurePayload {
+    optional {
+        idle_timeout_ms: Option<u64>,
+        max_timeout_ms: Option<u64>,
+        ambient_inactivity_timeout_ms: Option<u64>,
+        ambient_max_duration_ms: Option<u64>,
+        ambient_max_files: Option<u64>,
+    }
+});
+
+object_schema!(UndoRollbackPayload {
+    required { count: u32 }
+    optional { force: bool, strict: bool, mode: RollbackMode, directory: Option<String> }
+});
+
+object_schema!(UndoHistoryPayload {
+    optional { directory: Option<String>, format: HistoryFormat }
+});
+
+object_schema!(UndoAttestPayload {
+    optional { directory: Option<String> }
+});
+
+object_schema!(UndoSquashPayload {
+    required { from_step: StepId, to_step: StepId }
+    optional { directory: Option<String> }
+});
+
+object_schema!(UndoExpectPayload {
+    required { paths: Vec<String>, op: ExpectedOperation }
+    optional { estimated_bytes: u64, directory: Option<String> }
+});
+
+object_schema!(UndoConfigurePayload {
+    optional {
+        max_log_size_bytes: Option<u64>,
+        max_step_count: Option<usize>,
+        max_single_step_size_bytes: Option<u64>,
+        symlink_policy: Option<SymlinkPolicy>,
+        external_modification: Option<ExternalModificationConfig>,
+        coherent_capture: Option<CoherentCaptureConfig>,
+        ignore_patterns: Option<Vec<String>>,
+        git_mirror: Option<GitMirrorConfig>,
+        directory: Option<String>,
+    }
+});
+
+object_schema!(CommandLimits {
+    optional { cpu_seconds: Option<u64>, memory_bytes: Option<u64>, max_processes: Option<u64> }
+});
+
+object_schema!(AgentExecutePayload {
+    required { command: String }
+    optional {
+        env: Option<HashMap<String, String>>,
+        cwd: Option<String>,
+        directory: Option<String>,
+        wait: bool,
+        timeout_ms: Option<u64>,
+        isolate_fs: bool,
+        timeout_seconds: Option<u64>,
+        rollback_on_timeout: bool,
+        pty: bool,
+        max_output_bytes: Option<u64>,
+        limits: CommandLimits,
+    }
+});
+
+object_schema!(AgentInputPayload {
+    required { command_id: u64 }
+    optional { data: String, eof: bool }
+});
+
+object_schema!(AgentPromptPayload {
+    required { prompt: String }
+});
+
+object_schema!(FsListPayload {
+    required { path: String }
+    optional { directory: Option<String> }
+});
+
+object_schema!(FsReadPayload {
+    required { path: String }
+    optional {
+        encoding: ReadEncoding,
+        offset: u64,
+        length: Option<u64>,
+        directory: Option<String>,
+    }
+});
+
+object_schema!(FsWritePayload {
+    required { path: String, content: String }
+    optional { directory: Option<String> }
+});
+
+object_schema!(FsDeletePayload {
+    required { path: String }
+    optional { recursive: bool, directory: Option<String> }
+});
+
+object_schema!(FsStatPayload {
+    required { path: String }
+    optional { directory: Option<String> }
+});
+
+object_schema!(FsHashPayload {
+    required { path: String }
+    optional { directory: Option<String> }
+});
+
+object_schema!(FsPatchPayload {
+    required { patch: String }
+    optional { path: Option<String>, directory: Option<String> }
+});
+
+object_schema!(FsCommitPayload {
+    optional {
+        paths: Option<Vec<String>>,
+        dry_run: bool,
+        discard_rest: bool,
+        directory: Option<String>,
+    }
+});
+
+object_schema!(FsTracePayload {
+    optional {
+        enabled: Option<bool>,
+        after: u64,
+        limit: Option<usize>,
+        clear: bool,
+        directory: Option<String>,
+    }
+});
+
+object_schema!(SafeguardConfigurePayload {
+    optional {
+        delete_threshold: Option<u64>,
+        overwrite_file_size_threshold: Option<u64>,
+        rename_over_existing: bool,
+        overwrite_count_threshold: Option<u64>,
+        protected_paths: Option<Vec<String>>,
+        timeout_seconds: Option<u64>,
+        max_step_duration_seconds: Option<u64>,
+        max_step_operations: Option<u64>,
+        max_deleted_bytes_per_step: Option<u64>,
+        max_overwritten_bytes_per_step: Option<u64>,
+    }
+});
+
+object_schema!(SafeguardConfirmPayload {
+    required { safeguard_id: String, action: String }
+});
+
+object_schema!(SafeguardHistoryPayload {
+    optional { directory: Option<String>, limit: Option<usize> }
+});
+
+object_schema!(VmInventoryPayload {
+    optional { refresh: bool }
+});
+
+object_schema!(RequestCancelPayload {
+    required { request_id: String }
+});
+
+object_schema!(EventsSubscribePayload {
+    required { categories: Vec<EventCategory> }
+});
+
+object_schema!(EventsReplayPayload {
+    required { since_seq: u64 }
+});
+
+object_schema!(LogConfigurePayload {
+    required { level: LogLevel }
+});
+
+object_schema!(ProtocolHelloPayload {
+    optional { versions: Vec<u32>, features: Vec<String> }
+});
+
+object_schema!(BatchPayload {
+    required { requests: Vec<Value> }
+    optional { atomic: bool }
+});
+
+object_schema!(ErrorDetail {
+    required { code: String, message: String }
+    optional { field: Option<String>, retryable: bool }
+});
+
+object_schema!(StaleResourceReport {
+    required { kind: String, message: String }
+    optional { path: Option<String>, pid: Option<u32> }
+});
+
+/// The payload a request type takes.
+#[derive(Clone, Copy)]
+pub enum Payload {
+    /// Ignored if present.
+    None,
+    /// Defaults when absent.
+    Optional(fn() -> Value),
+    Required(fn() -> Value),
+}
+
+/// Every request type with its payload.
+pub const REQUESTS: &[(&str, Payload)] = &[
+    ("session.start", Payload::Required(SessionStartPayload::json_schema)),
+    ("session.stop", Payload::None),
+    ("session.destroy", Payload::Required(SessionDestroyPayload::json_schema)),
+    ("session.reset", Payload::None),
+    ("session.resume", Payload::None),
+    ("session.status", Payload::None),
+    ("session.clone", Payload::Required(SessionClonePayload::json_schema)),
+    ("session.warnings", Payload::None),
+    ("session.metrics", Payload::None),
+    ("session.env.set", Payload::Required(SessionEnvSetPayload::json_schema)),
+    ("session.env.unset", Payload::Required(SessionEnvUnsetPayload::json_schema)),
+    ("session.env.list", Payload::None),
+    ("session.configure", Payload::Required(SessionConfigurePayload::json_schema)),
+    ("undo.rollback", Payload::Required(UndoRollbackPayload::json_schema)),
+    ("undo.history", Payload::Optional(UndoHistoryPayload::json_schema)),
+    ("undo.configure", Payload::Optional(UndoConfigurePayload::json_schema)),
+    ("undo.discard", Payload::None),
+    ("undo.attest", Payload::Optional(UndoAttestPayload::json_schema)),
+    ("undo.expect", Payload::Required(UndoExpectPayload::json_schema)),
+    ("undo.reload_ignores", Payload::None),
+    ("undo.squash", Payload::Required(UndoSquashPayload::json_schema)),
+    ("agent.execute", Payload::Required(AgentExecutePayload::json_schema)),
+    ("agent.input", Payload::Required(AgentInputPayload::json_schema)),
+    ("agent.prompt", Payload::Required(AgentPromptPayload::json_schema)),
+    ("fs.list", Payload::Required(FsListPayload::json_schema)),
+    ("fs.read", Payload::Required(FsReadPayload::json_schema)),
+    ("fs.write", Payload::Required(FsWritePayload::json_schema)),
+    ("fs.delete", Payload::Required(FsDeletePayload::json_schema)),
+    ("fs.stat", Payload::Required(FsStatPayload::json_schema)),
+    ("fs.hash", Payload::Required(FsHashPayload::json_schema)),
+    ("fs.patch", Payload::Required(FsPatchPayload::json_schema)),
+    ("fs.commit", Payload::Optional(FsCommitPayload::json_schema)),
+    ("fs.trace", Payload::Optional(FsTracePayload::json_schema)),
+    ("fs.status", Payload::None),
+    ("safeguard.configure", Payload::Optional(SafeguardConfigurePayload::json_schema)),
+    ("safeguard.confirm", Payload::Required(SafeguardConfirmPayload::json_schema)),
+    ("safeguard.history", Payload::Optional(SafeguardHistoryPayload::json_schema)),
+    ("system.cleanup", Payload::None),
+    ("vm.inventory", Payload::Optional(VmInventoryPayload::json_schema)),
+    ("request.cancel", Payload::Required(RequestCancelPayload::json_schema)),
+    ("events.subscribe", Payload::Required(EventsSubscribePayload::json_schema)),
+    ("events.replay", Payload::Required(EventsReplayPayload::json_schema)),
+    ("log.configure", Payload::Required(LogConfigurePayload::json_schema)),
+    ("protocol.hello", Payload::Optional(ProtocolHelloPayload::json_schema)),
+    ("batch", Payload::Required(BatchPayload::json_schema)),
+];
+
+/// Payload schemas of every event type, as [`Event::to_envelope`] writes
+/// them. An event of one of several sessions also carries `session_id`.
+///
+/// [`Event::to_envelope`]: crate::Event::to_envelope
+pub fn event_payloads() -> Vec<(&'static str, Value)> {
+    let string = String::json_schema;
+    let count = usize::json_schema;
+    let strings = Vec::<String>::json_schema;
+    let percent = u8::json_schema;
+    vec![
+        ("event.step_completed", object("StepCompleted", vec![
+            ("step_id", StepId::json_schema()),
+            ("command_id", u64::json_schema()),
+            ("affected_paths", strings()),
+            ("exit_code", i32::json_schema()),
+            ("output_truncated", bool::json_schema()),
+            ("limit_exceeded", string()),
+        ], &["step_id", "affected_paths", "exit_code"])),
+        ("event.agent_output", object("AgentOutput", vec![("data", string())], &["data"])),
+        ("event.terminal_output", object("TerminalOutput", vec![
+            ("command_id", u64::json_schema()),
+            ("stream", string()),
+            ("data", string()),
+            ("encoding", OutputEncoding::json_schema()),
+            ("size", count()),
+        ], &["stream", "data"])),
+        // The warning catalog entry's own fields come along.
+        ("event.warning", json!({
+            "title": "Warning",
+            "type": "object",
+            "properties": { "code": string(), "severity": string(), "message": string() },
+            "required": ["code", "severity", "message"],
+            "additionalProperties": true,
+        })),
+        ("event.error", object("Error", vec![
+            ("code", string()),
+            ("message", string()),
+        ], &["code", "message"])),
+        ("event.safeguard_triggered", object("SafeguardTriggered", vec![
+            ("step_id", StepId::json_schema()),
+            ("safeguard_id", string()),
+            ("kind", string()),
+            ("sample_paths", strings()),
+            ("message", string()),
+        ], &["step_id", "safeguard_id", "kind", "sample_paths", "message"])),
+        ("event.safeguard_timed_out", object("SafeguardTimedOut", vec![
+            ("step_id", StepId::json_schema()),
+            ("safeguard_id", string()),
+            ("timeout_seconds", u64::json_schema()),
+        ], &["step_id", "safeguard_id", "timeout_seconds"])),
+        ("event.command_timed_out", object("CommandTimedOut", vec![
+            ("command_id", u64::json_schema()),
+            ("timeout_seconds", u64::json_schema()),
+            ("rolled_back", bool::json_schema()),
+            ("error", string()),
+        ], &["command_id", "timeout_seconds", "rolled_back"])),
+        ("event.vm_crashed", object("VmCrashed", vec![
+            ("exit_code", i32::json_schema()),
+            ("signal", i32::json_schema()),
+            ("serial_tail", strings()),
+            ("restarting", bool::json_schema()),
+        ], &["serial_tail", "restarting"])),
+        ("event.external_modification", object("ExternalModification", vec![
+            ("affected_paths", strings()),
+            ("barrier_id", Option::<u64>::json_schema()),
+        ], &["affected_paths", "barrier_id"])),
+        ("event.ignores_reloaded", object("IgnoresReloaded", vec![
+            ("working_dir", string()),
+            ("sources", strings()),
+        ], &["working_dir", "sources"])),
+        ("event.recovery", object("Recovery", vec![
+            ("paths_restored", count()),
+            ("paths_deleted", count()),
+            ("rollbacks_reverted", count()),
+            ("steps_rolled_back", count()),
+            ("steps_evicted", count()),
+            ("steps_squashed", count()),
+        ], &[
+            "paths_restored",
+            "paths_deleted",
+            "rollbacks_reverted",
+            "steps_rolled_back",
+            "steps_evicted",
+            "steps_squashed",
+        ])),
+        ("event.undo_version_mismatch", object("UndoVersionMismatch", vec![
+            ("expected_version", string()),
+            ("found_version", string()),
+        ], &["expected_version", "found_version"])),
+        ("event.compaction_progress", object("CompactionProgress", vec![
+            ("working_dir", string()),
+            ("percent", percent()),
+        ], &["working_dir", "percent"])),
+        ("event.compaction", object("Compaction", vec![
+            ("working_dir", string()),
+            ("paused", bool::json_schema()),
+            ("steps_compacted", count()),
+            ("files_recompressed", count()),
+            ("blobs_deduplicated", count()),
+            ("bytes_saved", u64::json_schema()),
+            ("corrupt_paths", strings()),
+        ], &[
+            "working_dir",
+            "paused",
+            "steps_compacted",
+            "files_recompressed",
+            "blobs_deduplicated",
+            "bytes_saved",
+            "corrupt_paths",
+        ])),
+        ("event.stale_resources", object("StaleResources", vec![
+            ("resources", Vec::<StaleResourceReport>::json_schema()),
+        ], &["resources"])),
+        ("event.progress", object("Progress", vec![
+            ("request_id", string()),
+            ("percent", percent()),
+            ("current_path", string()),
+        ], &["request_id", "percent"])),
+    ]
+}
+
+/// A whole request line of type `message_type`.
+fn request_line(message_type: &str, payload: Payload) -> Value {
+    let mut required = vec!["type", "request_id"];
+    let payload = match payload {
+        Payload::None => json!({}),
+        Payload::Optional(schema) => schema(),
+        Payload::Required(schema) => {
+            required.push("payload");
+            schema()
+        }
+    };
+    object(message_type, vec![
+        ("type", constant(message_type)),
+        ("request_id", String::json_schema()),
+        ("session_id", String::json_schema()),
+        ("payload", payload),
+    ], &required)
+}
+
+fn response_line() -> Value {
+    object("response", vec![
+        ("type", constant("response")),
+        ("request_id", String::json_schema()),
+        ("status", json!({ "enum": ["ok", "error"] })),
+        ("payload", json!({})),
+        ("error", ErrorDetail::json_schema()),
+    ], &["type", "request_id", "status"])
+}
+
+fn event_line(event_type: &str, payload: Value) -> Value {
+    object(event_type, vec![
+        ("type", constant(event_type)),
+        ("seq", u64::json_schema()),
+        ("emitted_at", String::json_schema()),
+        ("origin", EventOrigin::json_schema()),
+        ("payload", payload),
+    ], &["type", "payload"])
+}
+
+/// The schema document: `$defs` holds one definition per request type,
+/// `response`, and one per event type, and the document matches any of
+/// them.
+pub fn protocol_schema() -> Value {
+    let mut defs = serde_json::Map::new();
+    for (message_type, payload) in REQUESTS {
+        defs.insert(message_type.to_string(), request_line(message_type, *payload));
+    }
+    defs.insert("response".to_string(), response_line());
+    for (event_type, payload) in event_payloads() {
+        defs.insert(event_type.to_string(), event_line(event_type, payload));
+    }
+    let refs: Vec<Value> = defs
+        .keys()
+        .map(|name| json!({ "$ref": format!("#/$defs/{name}") }))
+        .collect();
+    json!({
+        "$schema": DRAFT,
+        "title": "codeagent-sandbox STDIO API",
+        "protocol_version": PROTOCOL_VERSION,
+        "$defs": defs,
+        "oneOf": refs,
+    })
+}
+
+/// Check `line`, a parsed request, response or event, against the
+/// definition for its `type`.
+pub fn validate_line(line: &Value, strict: bool) -> Result<(), String> {
+    let message_type = line["type"].as_str().ok_or("no `type`")?;
+    let document = protocol_schema();
+    let definition = document["$defs"]
+        .get(message_type)
+        .ok_or_else(|| format!("unknown type `{message_type}`"))?;
+    schema::validate(definition, line, strict)
+}
+
+#[cfg(test)]
+mod tests {
+    use codeagent_common::schema::example;
+
+    use super::*;
+    use crate::protocol::Event;
+    use crate::EventHub;
+    use crate::{parse_request, server};
+
+    #[test]
+    fn every_request_type_parses_from_its_smallest_example() {
+        for (message_type, payload) in REQUESTS {
+            let mut line = example(&request_line(message_type, *payload));
+            line["request_id"] = json!("1");
+            let request = parse_request(&line.to_string())
+                .unwrap_or_else(|e| panic!("{message_type}: {e}"));
+            assert_eq!(server::request_type_name(&request), *message_type);
+        }
+    }
+
+    #[test]
+    fn payloads_serialize_within_their_schemas() {
+        let start: SessionStartPayload = serde_json::from_value(json!({
+            "working_directories": [{ "path": "/work", "backend": "p9", "exclude": ["target"] }],
+            "symlink_policy": "read_write",
+            "message_limits": { "default": 1024 },
+            "terminal_output": { "encoding": "zstd", "batch_ms": 20 },
+        }))
+        .unwrap();
+        let configure = UndoConfigurePayload {
+            external_modification: Some(ExternalModificationConfig::default()),
+            coherent_capture: Some(CoherentCaptureConfig::default()),
+            git_mirror: Some(GitMirrorConfig::default()),
+            ignore_patterns: Some(vec!["*.log".to_string()]),
+            ..Default::default()
+        };
+        for (message_type, payload) in [
+            ("session.start", serde_json::to_value(start).unwrap()),
+            ("undo.configure", serde_json::to_value(configure).unwrap()),
+        ] {
+            let line = json!({ "type": message_type, "request_id": "1", "payload": payload });
+            validate_line(&line, true).unwrap_or_else(|e| panic!("{message_type}: {e}"));
+        }
+    }
+
+    #[test]
+    fn every_event_and_the_response_fit_their_schemas() {
+        let events = [
+            Event::StepCompleted {
+                step_id: 3,
+                command_id: Some(7),
+                affected_paths: vec!["a.txt".to_string()],
+                exit_code: 0,
+                output_truncated: true,
+                limit_exceeded: Some("memory".to_string()),
+            },
+            Event::AgentOutput { data: "done".to_string() },
+            Event::TerminalOutput {
+                command_id: Some(7),
+                stream: "stdout".to_string(),
+                data: "hello".to_string(),
+            },
+            Event::Warning {
+                warning: codeagent_common::SandboxWarning::VmNotConfigured {
+                    missing: vec!["kernel".to_string()],
+                },
+            },
+            Event::Error { code: "internal".to_string(), message: "oops".to_string() },
+            Event::SafeguardTriggered {
+                step_id: 3,
+                safeguard_id: "9".to_string(),
+                kind: "delete_threshold".to_string(),
+                sample_paths: vec![],
+                message: "many deletes".to_string(),
+            },
+            Event::SafeguardTimedOut {
+                step_id: 3,
+                safeguard_id: "9".to_string(),
+                timeout_seconds: 30,
+            },
+            Event::CommandTimedOut {
+                command_id: 7,
+                timeout_seconds: 5,
+                rolled_back: false,
+                error: Some("gone".to_string()),
+            },
+            Event::VmCrashed {
+                exit_code: Some(1),
+                signal: Some(9),
+                serial_tail: vec![],
+                restarting: true,
+            },
+            Event::ExternalModification { affected_paths: vec![], barrier_id: None },
+            Event::IgnoresReloaded { working_dir: "/work".to_string(), sources: vec![] },
+            Event::Recovery {
+                paths_restored: 1,
+                paths_deleted: 0,
+                rollbacks_reverted: 0,
+                steps_rolled_back: 0,
+                steps_evicted: 0,
+                steps_squashed: 0,
+            },
+            Event::UndoVersionMismatch {
+                expected_version: "2".to_string(),
+                found_version: "1".to_string(),
+            },
+            Event::CompactionProgress { working_dir: "/work".to_string(), percent: 50 },
+            Event::Compaction {
+                working_dir: "/work".to_string(),
+                paused: false,
+                steps_compacted: 1,
+                files_recompressed: 2,
+                blobs_deduplicated: 3,
+                bytes_saved: 4,
+                corrupt_paths: vec![],
+            },
+            Event::StaleResources {
+                resources: vec![StaleResourceReport {
+                    kind: "orphan_process".to_string(),
+                    path: None,
+                    pid: Some(42),
+                    message: "left over".to_string(),
+                }],
+            },
+            Event::Progress {
+                request_id: "1".to_string(),
+                percent: 10,
+                current_path: Some("a.txt".to_string()),
+            },
+        ];
+        let mut covered: Vec<String> = Vec::new();
+        for event in &events {
+            let mut envelope = event.to_envelope();
+            EventHub::new().stamp(&mut envelope);
+            let event_type = envelope.event_type.clone();
+            let line = serde_json::to_value(envelope).unwrap();
+            // Warnings carry the fields of their catalog entry.
+            let strict = event_type != "event.warning";
+            validate_line(&line, strict).unwrap_or_else(|e| panic!("{event_type}: {e}"));
+            covered.push(event_type);
+        }
+        let mut listed: Vec<String> =
+            event_payloads().into_iter().map(|(name, _)| name.to_string()).collect();
+        covered.sort();
+        listed.sort();
+        assert_eq!(covered, listed);
+
+        let error = crate::ResponseEnvelope::error(
+            "1".to_string(),
+            crate::StdioError::MissingRequestId.to_error_detail(),
+        );
+        validate_line(&serde_json::to_value(error).unwrap(), true).unwrap();
+    }
+}
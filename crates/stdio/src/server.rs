@@ -1,29 +1,75 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
 use crate::error::StdioError;
-use crate::parser::{extract_request_id, parse_request};
-use crate::protocol::{Event, LogEntry, ResponseEnvelope};
+use crate::event_hub::EventHub;
+use crate::parser::{extract_request_id, parse_addressed_request_with_limits, AddressedRequest};
+use crate::protocol::{Event, EventEnvelope, LogEntry, LogLevel, Request, ResponseEnvelope};
 use crate::router::Router;
+use crate::terminal_output::TerminalOutputBatcher;
 
 /// Async STDIO API server that reads JSON Lines from an input, dispatches
 /// through a `Router`, and writes responses/events to an output.
 ///
 /// Log messages are written to a separate output (stderr in production).
+/// Terminal output is batched and encoded as negotiated in `session.start`;
+/// a held batch is written before any other response or event.
+///
+/// Requests are dispatched one at a time, on a blocking thread. While one
+/// is in flight the server keeps reading input: `request.cancel` is
+/// handled at once, other requests wait their turn (up to
+/// [`MAX_QUEUED_REQUESTS`], after which input is left unread), and the
+/// request's `event.progress` is written as it comes. Other events wait
+/// for its response, so they never overtake it.
+///
+/// Every event written is stamped by the server's [`EventHub`] with the
+/// next `seq` and `emitted_at`, and the last
+/// [`EVENT_REPLAY_CAPACITY`] are kept for `events.replay`, which the
+/// server answers itself, at once.
 pub struct StdioServer {
-    router: Router,
+    router: Arc<Router>,
     event_receiver: mpsc::UnboundedReceiver<Event>,
+    progress_receiver: mpsc::UnboundedReceiver<Event>,
     log_sender: Option<LogSender>,
+    batcher: TerminalOutputBatcher,
+    hub: EventHub,
+    replay: VecDeque<EventEnvelope>,
 }
 
 type LogSender = Box<dyn Fn(LogEntry) + Send + Sync>;
 
+/// Requests read while another is in flight, beyond which the server stops
+/// reading input until the queue drains.
+pub const MAX_QUEUED_REQUESTS: usize = 64;
+
+/// Events kept for `events.replay`.
+pub const EVENT_REPLAY_CAPACITY: usize = 1024;
+
+/// What woke the server loop.
+enum Wake {
+    Line(std::io::Result<Option<String>>),
+    Response(Result<ResponseEnvelope, tokio::task::JoinError>),
+    Progress(Event),
+    Event(Event),
+    FlushDeadline,
+}
+
 impl StdioServer {
     pub fn new(router: Router, event_receiver: mpsc::UnboundedReceiver<Event>) -> Self {
+        let (progress_sender, progress_receiver) = mpsc::unbounded_channel();
+        router.send_progress_to(progress_sender);
         Self {
-            router,
+            router: Arc::new(router),
             event_receiver,
+            progress_receiver,
             log_sender: None,
+            batcher: TerminalOutputBatcher::default(),
+            hub: EventHub::new(),
+            replay: VecDeque::new(),
         }
     }
 
@@ -52,74 +98,258 @@ impl StdioServer {
         L: tokio::io::AsyncWrite + Unpin,
     {
         let mut lines = BufReader::new(input).lines();
+        let mut in_flight: Option<JoinHandle<ResponseEnvelope>> = None;
+        let mut queued: VecDeque<String> = VecDeque::new();
+        let mut input_open = true;
 
         loop {
-            tokio::select! {
-                line_result = lines.next_line() => {
-                    match line_result {
-                        Ok(Some(line)) => {
-                            self.emit_log(
-                                &mut log_output,
-                                "debug",
-                                "stdio_api",
-                                None,
-                                &format!("received: {}", truncate_for_log(&line)),
-                            ).await;
-
-                            let response = match parse_request(&line) {
-                                Ok(request) => {
-                                    let request_id = request.request_id().to_string();
-                                    self.emit_log(
-                                        &mut log_output,
-                                        "info",
-                                        "stdio_api",
-                                        Some(&request_id),
-                                        &format!("dispatching request type: {}", request_type_name(&request)),
-                                    ).await;
-                                    self.router.dispatch(request)
-                                }
-                                Err(error) => {
-                                    let request_id = extract_request_id(&line)
-                                        .unwrap_or_default();
-                                    self.emit_log(
-                                        &mut log_output,
-                                        "warn",
-                                        "stdio_api",
-                                        if request_id.is_empty() { None } else { Some(&request_id) },
-                                        &format!("parse error: {error}"),
-                                    ).await;
-                                    ResponseEnvelope::error(request_id, error.to_error_detail())
-                                }
-                            };
-
-                            write_jsonl(&mut output, &response).await?;
-                        }
-                        Ok(None) => break, // EOF
-                        Err(e) => return Err(StdioError::Io { source: e }),
+            if in_flight.is_none() {
+                if let Some(line) = queued.pop_front() {
+                    in_flight = self.start_request(&line, &mut output, &mut log_output).await?;
+                    continue;
+                }
+                if !input_open {
+                    break;
+                }
+            }
+
+            let deadline = if in_flight.is_none() { self.batcher.deadline() } else { None };
+            let reading = input_open && queued.len() < MAX_QUEUED_REQUESTS;
+            let wake = tokio::select! {
+                line_result = lines.next_line(), if reading => Wake::Line(line_result),
+
+                joined = async { in_flight.as_mut().unwrap().await }, if in_flight.is_some() => {
+                    Wake::Response(joined)
+                }
+
+                Some(event) = self.progress_receiver.recv() => Wake::Progress(event),
+
+                Some(event) = self.event_receiver.recv(), if in_flight.is_none() => {
+                    Wake::Event(event)
+                }
+
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if deadline.is_some() => Wake::FlushDeadline,
+            };
+
+            match wake {
+                Wake::Line(Ok(Some(line))) => {
+                    self.emit_log(
+                        &mut log_output,
+                        LogLevel::Debug,
+                        "stdio_api",
+                        None,
+                        &format!("received: {}", truncate_for_log(&line)),
+                    ).await;
+
+                    if in_flight.is_none() {
+                        in_flight = self.start_request(&line, &mut output, &mut log_output).await?;
+                    } else if let Some(response) = self.answer_at_once(&line) {
+                        self.flush_terminal_output(&mut output).await?;
+                        write_jsonl(&mut output, &response).await?;
+                    } else {
+                        queued.push_back(line);
                     }
                 }
+                Wake::Line(Ok(None)) => input_open = false, // EOF
+                Wake::Line(Err(e)) => return Err(StdioError::Io { source: e }),
+
+                Wake::Response(joined) => {
+                    in_flight = None;
+                    let response = joined.map_err(|error| StdioError::Io {
+                        source: std::io::Error::other(error),
+                    })?;
+                    self.flush_terminal_output(&mut output).await?;
+                    write_jsonl(&mut output, &response).await?;
+                }
 
-                Some(event) = self.event_receiver.recv() => {
-                    let envelope = event.to_envelope();
-                    write_jsonl(&mut output, &envelope).await?;
+                Wake::Progress(event) => {
+                    self.flush_terminal_output(&mut output).await?;
+                    self.write_event(&mut output, event.to_envelope()).await?;
+                }
+
+                Wake::Event(event) if !self.router.subscribed(&event) => {}
+                Wake::Event(event) => {
+                    let options = self.router.terminal_output_options();
+                    if options != *self.batcher.options() {
+                        if let Some(envelope) = self.batcher.set_options(options) {
+                            self.write_event(&mut output, envelope).await?;
+                        }
+                    }
+                    let (session_id, inner) = match &event {
+                        Event::Session { session_id, event } => {
+                            (Some(session_id.as_str()), &**event)
+                        }
+                        _ => (None, &event),
+                    };
+                    match inner {
+                        Event::TerminalOutput {
+                            command_id,
+                            stream,
+                            data,
+                        } => {
+                            let ready = self.batcher.push(session_id, *command_id, stream, data);
+                            for envelope in ready {
+                                self.write_event(&mut output, envelope).await?;
+                            }
+                        }
+                        _ => {
+                            self.flush_terminal_output(&mut output).await?;
+                            self.write_event(&mut output, event.to_envelope()).await?;
+                        }
+                    }
                 }
+
+                Wake::FlushDeadline => self.flush_terminal_output(&mut output).await?,
+            }
+        }
+
+        self.flush_terminal_output(&mut output).await?;
+        Ok(())
+    }
+
+    /// Parse `line` and dispatch it on a blocking thread. A line that does
+    /// not parse, or that the server answers itself, is answered here, and
+    /// `None` returned.
+    ///
+    /// Parsing waits until the request's turn, so the limits negotiated by
+    /// a `session.start` ahead of it apply.
+    async fn start_request<W, L>(
+        &mut self,
+        line: &str,
+        output: &mut W,
+        log_output: &mut L,
+    ) -> Result<Option<JoinHandle<ResponseEnvelope>>, StdioError>
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+        L: tokio::io::AsyncWrite + Unpin,
+    {
+        let limits = self.router.message_limits();
+        match parse_addressed_request_with_limits(line, &limits) {
+            Ok(AddressedRequest {
+                request: Request::EventsReplay { request_id, payload },
+                ..
+            }) => {
+                let response = self.replay_since(request_id, payload.since_seq);
+                self.flush_terminal_output(output).await?;
+                write_jsonl(output, &response).await?;
+                Ok(None)
+            }
+            Ok(AddressedRequest { session_id, request }) => {
+                let request_id = request.request_id().to_string();
+                self.emit_log(
+                    log_output,
+                    LogLevel::Info,
+                    "stdio_api",
+                    Some(&request_id),
+                    &format!("dispatching request type: {}", request_type_name(&request)),
+                ).await;
+                let router = Arc::clone(&self.router);
+                Ok(Some(tokio::task::spawn_blocking(move || {
+                    router.dispatch_addressed(session_id.as_deref(), request)
+                })))
             }
+            Err(error) => {
+                let request_id = extract_request_id(line).unwrap_or_default();
+                self.emit_log(
+                    log_output,
+                    LogLevel::Warn,
+                    "stdio_api",
+                    if request_id.is_empty() { None } else { Some(&request_id) },
+                    &format!("parse error: {error}"),
+                ).await;
+                let response = ResponseEnvelope::error(request_id, error.to_error_detail());
+                self.flush_terminal_output(output).await?;
+                write_jsonl(output, &response).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Answer `line` at once if it is a `request.cancel` or an
+    /// `events.replay`, returning its response.
+    fn answer_at_once(&self, line: &str) -> Option<ResponseEnvelope> {
+        if !line.contains("request.cancel") && !line.contains("events.replay") {
+            return None;
         }
+        let limits = self.router.message_limits();
+        match parse_addressed_request_with_limits(line, &limits) {
+            Ok(AddressedRequest {
+                session_id,
+                request: request @ Request::RequestCancel { .. },
+            }) => Some(self.router.dispatch_addressed(session_id.as_deref(), request)),
+            Ok(AddressedRequest {
+                request: Request::EventsReplay { request_id, payload },
+                ..
+            }) => Some(self.replay_since(request_id, payload.since_seq)),
+            _ => None,
+        }
+    }
+
+    /// The `events.replay` response: the buffered events after `since_seq`.
+    /// `truncated` says some of them are no longer buffered.
+    fn replay_since(&self, request_id: String, since_seq: u64) -> ResponseEnvelope {
+        let events: Vec<&EventEnvelope> = self
+            .replay
+            .iter()
+            .filter(|envelope| envelope.seq.is_some_and(|seq| seq > since_seq))
+            .collect();
+        let oldest = self.replay.front().and_then(|envelope| envelope.seq);
+        let last_seq = self.hub.last_seq();
+        let truncated = since_seq + 1 < oldest.unwrap_or(last_seq + 1);
+        ResponseEnvelope::ok(
+            request_id,
+            Some(serde_json::json!({
+                "events": events,
+                "last_seq": last_seq,
+                "truncated": truncated,
+            })),
+        )
+    }
 
+    /// Write `envelope` as the next event of the stream, keeping it for
+    /// `events.replay`.
+    async fn write_event<W: tokio::io::AsyncWrite + Unpin>(
+        &mut self,
+        output: &mut W,
+        mut envelope: EventEnvelope,
+    ) -> Result<(), StdioError> {
+        self.hub.stamp(&mut envelope);
+        if self.replay.len() == EVENT_REPLAY_CAPACITY {
+            self.replay.pop_front();
+        }
+        write_jsonl(output, &envelope).await?;
+        self.replay.push_back(envelope);
         Ok(())
     }
 
+    /// Write the held terminal output batch, if any.
+    async fn flush_terminal_output<W: tokio::io::AsyncWrite + Unpin>(
+        &mut self,
+        output: &mut W,
+    ) -> Result<(), StdioError> {
+        match self.batcher.flush() {
+            Some(envelope) => self.write_event(output, envelope).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Write a log entry to `log_output` and the process-wide log file, if
+    /// the process-wide level enables it.
     async fn emit_log<L: tokio::io::AsyncWrite + Unpin>(
         &self,
         log_output: &mut L,
-        level: &str,
+        level: LogLevel,
         component: &str,
         request_id: Option<&str>,
         message: &str,
     ) {
+        if !crate::logging::enabled(level) {
+            return;
+        }
         let entry = LogEntry {
-            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
-            level: level.to_string(),
+            timestamp: codeagent_common::time::now_timestamp(),
+            level,
             component: component.to_string(),
             request_id: request_id.map(String::from),
             step_id: None,
@@ -135,6 +365,7 @@ impl StdioServer {
             let _ = log_output.write_all(b"\n").await;
             let _ = log_output.flush().await;
         }
+        crate::logging::forward(&entry);
     }
 }
 
@@ -158,23 +389,53 @@ async fn write_jsonl<W: tokio::io::AsyncWrite + Unpin, T: serde::Serialize>(
     Ok(())
 }
 
-fn request_type_name(request: &crate::protocol::Request) -> &'static str {
+pub(crate) fn request_type_name(request: &crate::protocol::Request) -> &'static str {
     match request {
         crate::protocol::Request::SessionStart { .. } => "session.start",
         crate::protocol::Request::SessionStop { .. } => "session.stop",
+        crate::protocol::Request::SessionDestroy { .. } => "session.destroy",
         crate::protocol::Request::SessionReset { .. } => "session.reset",
+        crate::protocol::Request::SessionResume { .. } => "session.resume",
         crate::protocol::Request::SessionStatus { .. } => "session.status",
+        crate::protocol::Request::SessionClone { .. } => "session.clone",
+        crate::protocol::Request::SessionWarnings { .. } => "session.warnings",
+        crate::protocol::Request::SessionMetrics { .. } => "session.metrics",
+        crate::protocol::Request::SessionEnvSet { .. } => "session.env.set",
+        crate::protocol::Request::SessionEnvUnset { .. } => "session.env.unset",
+        crate::protocol::Request::SessionEnvList { .. } => "session.env.list",
+        crate::protocol::Request::SessionConfigure { .. } => "session.configure",
         crate::protocol::Request::UndoRollback { .. } => "undo.rollback",
         crate::protocol::Request::UndoHistory { .. } => "undo.history",
         crate::protocol::Request::UndoConfigure { .. } => "undo.configure",
         crate::protocol::Request::UndoDiscard { .. } => "undo.discard",
+        crate::protocol::Request::UndoAttest { .. } => "undo.attest",
+        crate::protocol::Request::UndoExpect { .. } => "undo.expect",
+        crate::protocol::Request::UndoReloadIgnores { .. } => "undo.reload_ignores",
+        crate::protocol::Request::UndoSquash { .. } => "undo.squash",
         crate::protocol::Request::AgentExecute { .. } => "agent.execute",
+        crate::protocol::Request::AgentInput { .. } => "agent.input",
         crate::protocol::Request::AgentPrompt { .. } => "agent.prompt",
         crate::protocol::Request::FsList { .. } => "fs.list",
         crate::protocol::Request::FsRead { .. } => "fs.read",
+        crate::protocol::Request::FsWrite { .. } => "fs.write",
+        crate::protocol::Request::FsDelete { .. } => "fs.delete",
+        crate::protocol::Request::FsStat { .. } => "fs.stat",
+        crate::protocol::Request::FsHash { .. } => "fs.hash",
+        crate::protocol::Request::FsPatch { .. } => "fs.patch",
+        crate::protocol::Request::FsCommit { .. } => "fs.commit",
+        crate::protocol::Request::FsTrace { .. } => "fs.trace",
         crate::protocol::Request::FsStatus { .. } => "fs.status",
         crate::protocol::Request::SafeguardConfigure { .. } => "safeguard.configure",
         crate::protocol::Request::SafeguardConfirm { .. } => "safeguard.confirm",
+        crate::protocol::Request::SafeguardHistory { .. } => "safeguard.history",
+        crate::protocol::Request::SystemCleanup { .. } => "system.cleanup",
+        crate::protocol::Request::VmInventory { .. } => "vm.inventory",
+        crate::protocol::Request::RequestCancel { .. } => "request.cancel",
+        crate::protocol::Request::EventsSubscribe { .. } => "events.subscribe",
+        crate::protocol::Request::EventsReplay { .. } => "events.replay",
+        crate::protocol::Request::LogConfigure { .. } => "log.configure",
+        crate::protocol::Request::ProtocolHello { .. } => "protocol.hello",
+        crate::protocol::Request::Batch { .. } => "batch",
     }
 }
 
@@ -0,0 +1,276 @@
+//! Batching and compression of `event.terminal_output`.
+//!
+//! Verbose commands produce thousands of small chunks, each of which would
+//! otherwise become its own JSON line. A client that opts in through
+//! `session.start` gets consecutive chunks of one stream coalesced, and
+//! optionally compressed and base64-encoded. Chunks of different commands,
+//! or of different sessions of one server, are never coalesced.
+
+use std::io::Write;
+
+use tokio::time::{Duration, Instant};
+
+use crate::protocol::{EventEnvelope, EventOrigin, OutputEncoding, TerminalOutputOptions};
+
+/// Encodings this server can produce, reported in `capabilities`.
+pub const SUPPORTED_ENCODINGS: [OutputEncoding; 3] =
+    [OutputEncoding::None, OutputEncoding::Gzip, OutputEncoding::Zstd];
+
+struct Batch {
+    session_id: Option<String>,
+    command_id: Option<u64>,
+    stream: String,
+    data: String,
+    deadline: Instant,
+}
+
+/// Holds output until it is flushed, a batch fills up, or its stream,
+/// command or session changes, so output of different streams stays in
+/// order.
+#[derive(Default)]
+pub struct TerminalOutputBatcher {
+    options: TerminalOutputOptions,
+    batch: Option<Batch>,
+}
+
+impl TerminalOutputBatcher {
+    pub fn options(&self) -> &TerminalOutputOptions {
+        &self.options
+    }
+
+    /// Switch to new options. Returns the batch held under the old ones.
+    pub fn set_options(&mut self, options: TerminalOutputOptions) -> Option<EventEnvelope> {
+        let flushed = self.flush();
+        self.options = options;
+        flushed
+    }
+
+    /// Add a chunk of `session_id`'s output, if the server runs several
+    /// sessions. Returns the envelopes to send now, oldest first.
+    pub fn push(
+        &mut self,
+        session_id: Option<&str>,
+        command_id: Option<u64>,
+        stream: &str,
+        data: &str,
+    ) -> Vec<EventEnvelope> {
+        if self.options.batch_ms == 0 {
+            return vec![encode(&self.options, session_id, command_id, stream, data)];
+        }
+
+        let mut ready = Vec::new();
+        if self.batch.as_ref().is_some_and(|batch| {
+            batch.stream != stream
+                || batch.command_id != command_id
+                || batch.session_id.as_deref() != session_id
+        }) {
+            ready.extend(self.flush());
+        }
+        let batch = self.batch.get_or_insert_with(|| Batch {
+            session_id: session_id.map(String::from),
+            command_id,
+            stream: stream.to_string(),
+            data: String::new(),
+            deadline: Instant::now() + Duration::from_millis(self.options.batch_ms),
+        });
+        batch.data.push_str(data);
+        if batch.data.len() >= self.options.batch_max_bytes {
+            ready.extend(self.flush());
+        }
+        ready
+    }
+
+    /// When the held batch is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.batch.as_ref().map(|batch| batch.deadline)
+    }
+
+    /// Take the held batch, if any.
+    pub fn flush(&mut self) -> Option<EventEnvelope> {
+        let batch = self.batch.take()?;
+        Some(encode(
+            &self.options,
+            batch.session_id.as_deref(),
+            batch.command_id,
+            &batch.stream,
+            &batch.data,
+        ))
+    }
+}
+
+/// Build an `event.terminal_output` envelope. If compression fails the
+/// chunk is sent as plain text, which clients must accept anyway.
+pub fn encode(
+    options: &TerminalOutputOptions,
+    session_id: Option<&str>,
+    command_id: Option<u64>,
+    stream: &str,
+    data: &str,
+) -> EventEnvelope {
+    let compressed = match options.encoding {
+        OutputEncoding::None => None,
+        OutputEncoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(data.as_bytes())
+                .and_then(|()| encoder.finish())
+                .ok()
+        }
+        OutputEncoding::Zstd => zstd::encode_all(data.as_bytes(), 0).ok(),
+    };
+    let mut payload = match compressed {
+        Some(bytes) => serde_json::json!({
+            "stream": stream,
+            "encoding": options.encoding,
+            "size": data.len(),
+            "data": base64_encode(&bytes),
+        }),
+        None => serde_json::json!({ "stream": stream, "data": data }),
+    };
+    if let Some(command_id) = command_id {
+        payload["command_id"] = serde_json::json!(command_id);
+    }
+    if let Some(session_id) = session_id {
+        payload["session_id"] = serde_json::json!(session_id);
+    }
+    let mut envelope = EventEnvelope::new("event.terminal_output", payload);
+    envelope.origin = Some(EventOrigin::Guest);
+    envelope
+}
+
+/// Standard base64 with padding.
+pub fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn base64_decode(text: &str) -> Vec<u8> {
+        let value = |c: u8| match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            _ => 63,
+        };
+        let mut out = Vec::new();
+        for chunk in text.as_bytes().chunks(4) {
+            let digits: Vec<u8> = chunk.iter().copied().filter(|&c| c != b'=').collect();
+            let n = digits
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &c)| n | (value(c) as u32) << (18 - 6 * i));
+            for i in 0..digits.len() - 1 {
+                out.push((n >> (16 - 8 * i)) as u8);
+            }
+        }
+        out
+    }
+
+    fn options(encoding: OutputEncoding, batch_ms: u64) -> TerminalOutputOptions {
+        TerminalOutputOptions {
+            encoding,
+            batch_ms,
+            batch_max_bytes: 16,
+        }
+    }
+
+    #[test]
+    fn base64_matches_known_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+        ];
+        for (input, expected) in vectors {
+            assert_eq!(base64_encode(input.as_bytes()), expected);
+            assert_eq!(base64_decode(expected), input.as_bytes());
+        }
+    }
+
+    #[test]
+    fn compressed_output_round_trips() {
+        let data = "Compiling codeagent-stdio v0.1.0\n".repeat(50);
+        let zstd = encode(&options(OutputEncoding::Zstd, 0), None, None, "stdout", &data);
+        assert_eq!(zstd.payload["encoding"], "zstd");
+        assert_eq!(zstd.payload["size"], data.len());
+        let bytes = base64_decode(zstd.payload["data"].as_str().unwrap());
+        assert!(bytes.len() < data.len());
+        assert_eq!(zstd::decode_all(bytes.as_slice()).unwrap(), data.as_bytes());
+
+        let gzip = encode(&options(OutputEncoding::Gzip, 0), None, None, "stderr", &data);
+        let bytes = base64_decode(gzip.payload["data"].as_str().unwrap());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(bytes.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let plain = encode(&TerminalOutputOptions::default(), None, Some(7), "stdout", "hi\n");
+        assert_eq!(
+            plain.payload,
+            serde_json::json!({ "stream": "stdout", "data": "hi\n", "command_id": 7 })
+        );
+        let tagged = encode(&TerminalOutputOptions::default(), Some("s1"), None, "stdout", "");
+        assert_eq!(tagged.payload["session_id"], "s1");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_flush_on_stream_or_command_change_and_size() {
+        let mut batcher = TerminalOutputBatcher::default();
+        assert_eq!(batcher.push(None, None, "stdout", "a").len(), 1);
+
+        assert!(batcher.set_options(options(OutputEncoding::None, 50)).is_none());
+        assert!(batcher.push(None, Some(1), "stdout", "one ").is_empty());
+        assert!(batcher.push(None, Some(1), "stdout", "two ").is_empty());
+        assert_eq!(batcher.deadline(), Some(Instant::now() + Duration::from_millis(50)));
+
+        let ready = batcher.push(None, Some(1), "stderr", "oops");
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].payload["data"], "one two ");
+
+        let ready = batcher.push(None, Some(1), "stderr", " and more than sixteen bytes");
+        assert_eq!(ready[0].payload["data"], "oops and more than sixteen bytes");
+        assert!(batcher.flush().is_none());
+
+        assert!(batcher.push(None, Some(1), "stdout", "first").is_empty());
+        let ready = batcher.push(None, Some(2), "stdout", "second");
+        assert_eq!((ready[0].payload["command_id"].as_u64(), ready.len()), (Some(1), 1));
+        assert_eq!(batcher.flush().unwrap().payload["command_id"], 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_of_different_sessions_stay_apart() {
+        let mut batcher = TerminalOutputBatcher::default();
+        batcher.set_options(options(OutputEncoding::None, 50));
+        assert!(batcher.push(Some("s1"), Some(1), "stdout", "one").is_empty());
+        let ready = batcher.push(Some("s2"), Some(1), "stdout", "two");
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].payload["session_id"], "s1");
+        assert_eq!(ready[0].payload["data"], "one");
+        assert_eq!(batcher.flush().unwrap().payload["session_id"], "s2");
+    }
+}
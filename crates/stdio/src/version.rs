@@ -6,3 +6,22 @@ pub const MIN_SUPPORTED_VERSION: u32 = 1;
 
 /// Maximum protocol version this agent supports.
 pub const MAX_SUPPORTED_VERSION: u32 = 1;
+
+/// Optional parts of the API, each a feature a client can ask for in
+/// `protocol.hello`, with the request types it covers.
+pub const FEATURES: &[(&str, &[&str])] = &[
+    ("agent_input", &["agent.input"]),
+    ("fs_overlay", &["fs.commit"]),
+    ("fs_trace", &["fs.trace"]),
+    ("session_env", &["session.env.set", "session.env.unset", "session.env.list"]),
+    ("undo_expect", &["undo.expect"]),
+    ("undo_squash", &["undo.squash"]),
+];
+
+/// The feature covering `request_type`, if it belongs to one.
+pub fn feature_of(request_type: &str) -> Option<&'static str> {
+    FEATURES
+        .iter()
+        .find(|(_, request_types)| request_types.contains(&request_type))
+        .map(|(feature, _)| *feature)
+}
@@ -4,14 +4,23 @@ use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc;
 
+use codeagent_common::{percent_of, OperationMonitor, ReadEncoding, RollbackMode, SandboxWarning};
 use codeagent_stdio::protocol::{
-    AgentExecutePayload, AgentPromptPayload, FsListPayload, FsReadPayload,
-    SafeguardConfirmPayload, SafeguardConfigurePayload, SessionStartPayload,
-    UndoConfigurePayload, UndoHistoryPayload, UndoRollbackPayload,
+    AgentExecutePayload, AgentInputPayload, AgentPromptPayload, FsDeletePayload, FsListPayload,
+    FsCommitPayload, FsHashPayload, FsPatchPayload, FsReadPayload, FsStatPayload, FsTracePayload,
+    FsWritePayload,
+    SafeguardConfirmPayload, SafeguardConfigurePayload, SafeguardHistoryPayload,
+    SessionClonePayload, SessionConfigurePayload, SessionDestroyPayload, SessionEnvSetPayload, SessionEnvUnsetPayload, SessionStartPayload,
+    UndoAttestPayload, UndoConfigurePayload, UndoExpectPayload, UndoHistoryPayload, UndoMode,
+    UndoRollbackPayload, UndoSquashPayload,
+    VmInventoryPayload,
+};
+use codeagent_stdio::router::{RequestHandler, Router, SessionFactory};
+use codeagent_stdio::server::{StdioServer, EVENT_REPLAY_CAPACITY};
+use codeagent_stdio::{
+    parse_addressed_request_with_limits, parse_request, validate_path, Event, MessageLimits,
+    StdioError, MAX_MESSAGE_SIZE, SESSION_MESSAGE_SIZE,
 };
-use codeagent_stdio::router::{RequestHandler, Router};
-use codeagent_stdio::server::StdioServer;
-use codeagent_stdio::{parse_request, validate_path, Event, StdioError, MAX_MESSAGE_SIZE};
 
 // ---------------------------------------------------------------------------
 // StubHandler — minimal implementation for contract testing
@@ -29,16 +38,71 @@ impl RequestHandler for StubHandler {
     fn session_stop(&self) -> Result<serde_json::Value, StdioError> {
         Ok(serde_json::json!({"state": "stopped"}))
     }
+    fn session_destroy(
+        &self,
+        _payload: SessionDestroyPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"deleted": []}))
+    }
     fn session_reset(&self) -> Result<serde_json::Value, StdioError> {
         Ok(serde_json::json!({"state": "reset"}))
     }
+    fn session_resume(&self) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"status": "ok"}))
+    }
     fn session_status(&self) -> Result<serde_json::Value, StdioError> {
         Ok(serde_json::json!({"state": "idle"}))
     }
+    fn session_clone(
+        &self,
+        _payload: SessionClonePayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({}))
+    }
+    fn session_warnings(&self) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"warnings": []}))
+    }
+    fn session_metrics(&self) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"counters": {}, "gauges": {}}))
+    }
+    fn session_env_set(
+        &self,
+        _payload: SessionEnvSetPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"replaced": false}))
+    }
+    fn session_env_unset(
+        &self,
+        _payload: SessionEnvUnsetPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"removed": true}))
+    }
+    fn session_env_list(&self) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"variables": []}))
+    }
+    fn session_configure(
+        &self,
+        _payload: SessionConfigurePayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"idle_timeout_ms": 100}))
+    }
+    /// A rollback of more than one step takes 10ms a step, reporting
+    /// progress, so that tests can cancel it.
     fn undo_rollback(
         &self,
-        _payload: UndoRollbackPayload,
+        payload: UndoRollbackPayload,
+        monitor: &dyn OperationMonitor,
     ) -> Result<serde_json::Value, StdioError> {
+        let count = payload.count as usize;
+        if count > 1 {
+            for step in 0..count {
+                if monitor.is_cancelled() {
+                    return Ok(serde_json::json!({"rolled_back": [], "cancelled": true}));
+                }
+                monitor.progress(percent_of(step, count), Some("src/main.rs"));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
         Ok(serde_json::json!({"rolled_back": []}))
     }
     fn undo_history(
@@ -50,18 +114,46 @@ impl RequestHandler for StubHandler {
     fn undo_configure(
         &self,
         _payload: UndoConfigurePayload,
+        _monitor: &dyn OperationMonitor,
     ) -> Result<serde_json::Value, StdioError> {
         Ok(serde_json::json!({}))
     }
+    fn undo_attest(
+        &self,
+        _payload: UndoAttestPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"verified": true}))
+    }
+    fn undo_expect(
+        &self,
+        _payload: UndoExpectPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"pending_expectations": 1}))
+    }
     fn undo_discard(&self) -> Result<serde_json::Value, StdioError> {
         Ok(serde_json::json!({}))
     }
+    fn undo_squash(
+        &self,
+        _payload: UndoSquashPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"step_id": 2}))
+    }
+    fn undo_reload_ignores(&self) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"reloaded": ["/work"]}))
+    }
     fn agent_execute(
         &self,
         _payload: AgentExecutePayload,
     ) -> Result<serde_json::Value, StdioError> {
         Ok(serde_json::json!({}))
     }
+    fn agent_input(
+        &self,
+        _payload: AgentInputPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"bytes": 0}))
+    }
     fn agent_prompt(
         &self,
         _payload: AgentPromptPayload,
@@ -74,6 +166,27 @@ impl RequestHandler for StubHandler {
     fn fs_read(&self, _payload: FsReadPayload) -> Result<serde_json::Value, StdioError> {
         Ok(serde_json::json!({"content": ""}))
     }
+    fn fs_write(&self, _payload: FsWritePayload) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"written": true, "step_id": 1_000_000}))
+    }
+    fn fs_delete(&self, _payload: FsDeletePayload) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"deleted": true, "step_id": 1_000_000}))
+    }
+    fn fs_stat(&self, _payload: FsStatPayload) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"type": "file", "size": 0}))
+    }
+    fn fs_hash(&self, _payload: FsHashPayload) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"algorithm": "blake3", "hash": "", "size": 0}))
+    }
+    fn fs_patch(&self, _payload: FsPatchPayload) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"applied": true, "files": [], "step_id": 1_000_000}))
+    }
+    fn fs_commit(&self, _payload: FsCommitPayload) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"committed": [], "discarded": [], "pending": [], "step_id": null}))
+    }
+    fn fs_trace(&self, _payload: FsTracePayload) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"enabled": true, "records": [], "dropped": 0, "next_after": 0}))
+    }
     fn fs_status(&self) -> Result<serde_json::Value, StdioError> {
         Ok(serde_json::json!({"warnings": []}))
     }
@@ -89,6 +202,21 @@ impl RequestHandler for StubHandler {
     ) -> Result<serde_json::Value, StdioError> {
         Ok(serde_json::json!({}))
     }
+    fn safeguard_history(
+        &self,
+        _payload: SafeguardHistoryPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"entries": []}))
+    }
+    fn system_cleanup(&self) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"cleaned": [], "failed": []}))
+    }
+    fn vm_inventory(
+        &self,
+        _payload: VmInventoryPayload,
+    ) -> Result<serde_json::Value, StdioError> {
+        Ok(serde_json::json!({"tools": []}))
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -206,6 +334,36 @@ fn sa01_each_request_type_parses() {
         r#"{"type":"fs.status","request_id":"13"}"#,
         r#"{"type":"safeguard.configure","request_id":"14","payload":{"delete_threshold":50}}"#,
         r#"{"type":"safeguard.confirm","request_id":"15","payload":{"safeguard_id":"sg_001","action":"allow"}}"#,
+        r#"{"type":"system.cleanup","request_id":"16"}"#,
+        r#"{"type":"session.clone","request_id":"17","payload":{"target_dir":"/tmp/branch"}}"#,
+        r#"{"type":"session.warnings","request_id":"18"}"#,
+        r#"{"type":"vm.inventory","request_id":"19"}"#,
+        r#"{"type":"undo.attest","request_id":"20","payload":{"directory":"0"}}"#,
+        r#"{"type":"safeguard.history","request_id":"21","payload":{"limit":10}}"#,
+        r#"{"type":"undo.expect","request_id":"22","payload":{"paths":["target"],"op":"delete","estimated_bytes":1048576}}"#,
+        r#"{"type":"undo.reload_ignores","request_id":"23"}"#,
+        r#"{"type":"agent.input","request_id":"24","payload":{"command_id":3,"data":"y\n"}}"#,
+        r#"{"type":"session.env.set","request_id":"25","payload":{"name":"API_KEY","value":"k","secret":true}}"#,
+        r#"{"type":"session.env.unset","request_id":"26","payload":{"name":"API_KEY"}}"#,
+        r#"{"type":"session.env.list","request_id":"27"}"#,
+        r#"{"type":"session.destroy","request_id":"28","payload":{"delete_undo_log":true,"confirmation":"c0ffee"}}"#,
+        r#"{"type":"fs.write","request_id":"29","payload":{"path":"notes.md","content":"todo"}}"#,
+        r#"{"type":"fs.delete","request_id":"30","payload":{"path":"build","recursive":true}}"#,
+        r#"{"type":"fs.stat","request_id":"31","payload":{"path":"src/main.rs"}}"#,
+        r#"{"type":"fs.hash","request_id":"32","payload":{"path":"src/main.rs","directory":"1"}}"#,
+        r#"{"type":"fs.patch","request_id":"33","payload":{"patch":"@@ -1 +1 @@\n-a\n+b\n","path":"a.txt"}}"#,
+        r#"{"type":"session.resume","request_id":"34"}"#,
+        r#"{"type":"request.cancel","request_id":"35","payload":{"request_id":"5"}}"#,
+        r#"{"type":"events.subscribe","request_id":"36","payload":{"categories":["safeguards","vm"]}}"#,
+        r#"{"type":"events.replay","request_id":"37","payload":{"since_seq":12}}"#,
+        r#"{"type":"log.configure","request_id":"38","payload":{"level":"debug"}}"#,
+        r#"{"type":"session.metrics","request_id":"39"}"#,
+        r#"{"type":"undo.squash","request_id":"40","payload":{"from_step":2,"to_step":5}}"#,
+        r#"{"type":"session.configure","request_id":"41","payload":{"idle_timeout_ms":500}}"#,
+        r#"{"type":"fs.commit","request_id":"42","payload":{"paths":["src/lib.rs"]}}"#,
+        r#"{"type":"fs.trace","request_id":"43","payload":{"enabled":true}}"#,
+        r#"{"type":"protocol.hello","request_id":"44","payload":{"versions":[1],"features":["fs_trace"]}}"#,
+        r#"{"type":"batch","request_id":"45","payload":{"atomic":true,"requests":[{"type":"fs.write","request_id":"45.1","payload":{"path":"a","content":""}}]}}"#,
     ];
 
     for (i, json) in test_cases.iter().enumerate() {
@@ -234,11 +392,59 @@ fn sa01_session_start_payload_fields() {
             assert_eq!(payload.network_policy, "open");
             assert_eq!(payload.vm_mode, "persistent");
             assert_eq!(payload.protocol_version, Some(1));
+            assert_eq!(payload.undo, UndoMode::Enabled);
+        }
+        other => panic!("Expected SessionStart, got: {other:?}"),
+    }
+
+    let json = r#"{"type":"session.start","request_id":"2","payload":{"working_directories":[],"undo":"disabled"}}"#;
+    match parse_request(json).unwrap() {
+        codeagent_stdio::Request::SessionStart { payload, .. } => {
+            assert_eq!(payload.undo, UndoMode::Disabled);
         }
         other => panic!("Expected SessionStart, got: {other:?}"),
     }
 }
 
+#[test]
+fn sa01_fs_read_range_fields() {
+    let json = r#"{"type":"fs.read","request_id":"1","payload":{"path":"a.bin","encoding":"base64","offset":10,"length":20}}"#;
+    match parse_request(json).unwrap() {
+        codeagent_stdio::Request::FsRead { payload, .. } => {
+            assert_eq!(payload.encoding, ReadEncoding::Base64);
+            assert_eq!((payload.offset, payload.length), (10, Some(20)));
+        }
+        other => panic!("Expected FsRead, got: {other:?}"),
+    }
+
+    let json = r#"{"type":"fs.read","request_id":"2","payload":{"path":"a.txt"}}"#;
+    match parse_request(json).unwrap() {
+        codeagent_stdio::Request::FsRead { payload, .. } => {
+            assert_eq!(payload.encoding, ReadEncoding::Utf8);
+            assert_eq!((payload.offset, payload.length), (0, None));
+        }
+        other => panic!("Expected FsRead, got: {other:?}"),
+    }
+
+    let json = r#"{"type":"fs.read","request_id":"3","payload":{"path":"a","encoding":"hex"}}"#;
+    assert!(parse_request(json).is_err());
+}
+
+#[test]
+fn sa01_session_clone_payload_fields() {
+    let json = r#"{"type":"session.clone","request_id":"1","payload":{"target_dir":"/tmp/branch","directory":"1"}}"#;
+    match parse_request(json).unwrap() {
+        codeagent_stdio::Request::SessionClone { payload, .. } => {
+            assert_eq!(payload.target_dir, "/tmp/branch");
+            assert_eq!(payload.directory, Some("1".to_string()));
+        }
+        other => panic!("Expected SessionClone, got: {other:?}"),
+    }
+
+    let missing = r#"{"type":"session.clone","request_id":"2","payload":{}}"#;
+    assert!(parse_request(missing).is_err());
+}
+
 #[test]
 fn sa01_undo_rollback_payload_fields() {
     let json = r#"{"type":"undo.rollback","request_id":"1","payload":{"count":3,"force":true,"directory":"project-a"}}"#;
@@ -248,6 +454,15 @@ fn sa01_undo_rollback_payload_fields() {
             assert_eq!(payload.count, 3);
             assert!(payload.force);
             assert_eq!(payload.directory, Some("project-a".to_string()));
+            assert_eq!(payload.mode, RollbackMode::Restore);
+        }
+        other => panic!("Expected UndoRollback, got: {other:?}"),
+    }
+
+    let json = r#"{"type":"undo.rollback","request_id":"2","payload":{"count":1,"force":true,"mode":"merge"}}"#;
+    match parse_request(json).unwrap() {
+        codeagent_stdio::Request::UndoRollback { payload, .. } => {
+            assert_eq!(payload.mode, RollbackMode::Merge);
         }
         other => panic!("Expected UndoRollback, got: {other:?}"),
     }
@@ -255,7 +470,7 @@ fn sa01_undo_rollback_payload_fields() {
 
 #[test]
 fn sa01_agent_execute_with_env() {
-    let json = r#"{"type":"agent.execute","request_id":"1","payload":{"command":"echo $PATH","env":{"PATH":"/usr/bin"},"cwd":"/home"}}"#;
+    let json = r#"{"type":"agent.execute","request_id":"1","payload":{"command":"echo $PATH","env":{"PATH":"/usr/bin"},"cwd":"src","directory":"1","wait":true,"timeout_ms":5000,"isolate_fs":true,"timeout_seconds":30,"rollback_on_timeout":true,"pty":true}}"#;
     let request = parse_request(json).unwrap();
     match request {
         codeagent_stdio::Request::AgentExecute { payload, .. } => {
@@ -264,27 +479,69 @@ fn sa01_agent_execute_with_env() {
                 payload.env.as_ref().unwrap().get("PATH").unwrap(),
                 "/usr/bin"
             );
-            assert_eq!(payload.cwd, Some("/home".to_string()));
+            assert_eq!(payload.cwd, Some("src".to_string()));
+            assert_eq!(payload.directory, Some("1".to_string()));
+            assert!(payload.wait);
+            assert_eq!(payload.timeout_ms, Some(5000));
+            assert!(payload.isolate_fs);
+            assert_eq!(payload.timeout_seconds, Some(30));
+            assert!(payload.rollback_on_timeout);
+            assert!(payload.pty);
         }
         other => panic!("Expected AgentExecute, got: {other:?}"),
     }
+
+    let json = r#"{"type":"agent.input","request_id":"2","payload":{"command_id":4,"eof":true}}"#;
+    let request = parse_request(json).unwrap();
+    assert_eq!(
+        request,
+        codeagent_stdio::Request::AgentInput {
+            request_id: "2".to_string(),
+            payload: AgentInputPayload {
+                command_id: 4,
+                data: String::new(),
+                eof: true,
+            },
+        }
+    );
 }
 
 #[test]
 fn sa01_safeguard_configure_payload_fields() {
-    let json = r#"{"type":"safeguard.configure","request_id":"1","payload":{"delete_threshold":50,"overwrite_file_size_threshold":1048576,"rename_over_existing":true,"timeout_seconds":60}}"#;
+    let json = r#"{"type":"safeguard.configure","request_id":"1","payload":{"delete_threshold":50,"overwrite_file_size_threshold":1048576,"rename_over_existing":true,"protected_paths":[".git/**","*.env"],"timeout_seconds":60}}"#;
     let request = parse_request(json).unwrap();
     match request {
         codeagent_stdio::Request::SafeguardConfigure { payload, .. } => {
             assert_eq!(payload.delete_threshold, Some(50));
             assert_eq!(payload.overwrite_file_size_threshold, Some(1_048_576));
             assert!(payload.rename_over_existing);
+            assert_eq!(
+                payload.protected_paths,
+                Some(vec![".git/**".to_string(), "*.env".to_string()])
+            );
             assert_eq!(payload.timeout_seconds, Some(60));
         }
         other => panic!("Expected SafeguardConfigure, got: {other:?}"),
     }
 }
 
+#[test]
+fn sa01_vm_inventory_refresh_defaults_to_false() {
+    let request = parse_request(r#"{"type":"vm.inventory","request_id":"1"}"#).unwrap();
+    assert_eq!(
+        request,
+        codeagent_stdio::Request::VmInventory {
+            request_id: "1".to_string(),
+            payload: VmInventoryPayload { refresh: false },
+        }
+    );
+    let json = r#"{"type":"vm.inventory","request_id":"2","payload":{"refresh":true}}"#;
+    match parse_request(json).unwrap() {
+        codeagent_stdio::Request::VmInventory { payload, .. } => assert!(payload.refresh),
+        other => panic!("Expected VmInventory, got: {other:?}"),
+    }
+}
+
 // ===========================================================================
 // SA-02: Unknown request type
 // ===========================================================================
@@ -517,8 +774,7 @@ async fn sa06_events_interleave_with_responses() {
 
     // Inject an event before sending a request
     harness.inject_event(Event::Warning {
-        code: "test_warning".to_string(),
-        message: "test warning message".to_string(),
+        warning: SandboxWarning::FileWatcherOverflow,
     });
 
     // Give the event a moment to be processed
@@ -588,8 +844,11 @@ async fn sa06_event_appears_on_stdout() {
     // Inject event
     harness.inject_event(Event::StepCompleted {
         step_id: 42,
+        command_id: None,
         affected_paths: vec!["test.txt".to_string()],
         exit_code: 0,
+        output_truncated: false,
+        limit_exceeded: None,
     });
 
     // Send a request to ensure the server is processing
@@ -636,6 +895,47 @@ async fn sa06_event_appears_on_stdout() {
     assert!(has_response, "Expected response on stdout, got: {lines:?}");
 }
 
+#[tokio::test]
+async fn sa06_terminal_output_is_batched_and_compressed_on_request() {
+    let mut harness = ServerHarness::new();
+    harness
+        .send_line(
+            r#"{"type":"session.start","request_id":"1","payload":{"working_directories":[],"terminal_output":{"encoding":"zstd","batch_ms":60000}}}"#,
+        )
+        .await;
+    let start: serde_json::Value =
+        serde_json::from_str(&harness.recv_stdout_line().await).unwrap();
+    let capabilities = &start["payload"]["capabilities"]["terminal_output"];
+    assert_eq!(capabilities["encodings"], serde_json::json!(["none", "gzip", "zstd"]));
+    assert_eq!(capabilities["options"]["encoding"], "zstd");
+
+    for data in ["Compiling a\n", "Compiling b\n"] {
+        harness.inject_event(Event::TerminalOutput {
+            command_id: Some(1),
+            stream: "stdout".to_string(),
+            data: data.to_string(),
+        });
+    }
+    harness.inject_event(Event::StepCompleted {
+        step_id: 1,
+        command_id: Some(1),
+        affected_paths: vec![],
+        exit_code: 0,
+        output_truncated: false,
+        limit_exceeded: None,
+    });
+
+    let output: serde_json::Value =
+        serde_json::from_str(&harness.recv_stdout_line().await).unwrap();
+    assert_eq!(output["type"], "event.terminal_output");
+    assert_eq!(output["payload"]["encoding"], "zstd");
+    assert_eq!(output["payload"]["size"], 24);
+    assert_eq!(output["payload"]["command_id"], 1);
+    let completed: serde_json::Value =
+        serde_json::from_str(&harness.recv_stdout_line().await).unwrap();
+    assert_eq!(completed["type"], "event.step_completed");
+}
+
 // ===========================================================================
 // SA-07: Stderr is valid JSONL logs
 // ===========================================================================
@@ -679,6 +979,37 @@ async fn sa07_stderr_is_valid_jsonl_logs() {
     }
 }
 
+#[tokio::test]
+async fn sa07_log_configure_changes_the_level_at_runtime() {
+    let mut harness = ServerHarness::new();
+    harness
+        .send_line(r#"{"type":"log.configure","request_id":"1","payload":{"level":"debug"}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["status"], "ok");
+    assert_eq!(response["payload"]["level"], "debug");
+
+    harness.send_line(r#"{"type":"session.status","request_id":"2"}"#).await;
+    let _response = harness.recv_stdout_line().await;
+    let debug_lines = harness
+        .drain_stderr()
+        .await
+        .into_iter()
+        .filter(|line| line.contains(r#""level":"debug""#))
+        .count();
+    assert!(debug_lines > 0, "debug entries are written once enabled");
+
+    // Other tests share the level; leave it as they expect.
+    harness
+        .send_line(r#"{"type":"log.configure","request_id":"3","payload":{"level":"info"}}"#)
+        .await;
+    assert_eq!(recv_json(&mut harness).await["status"], "ok");
+    harness
+        .send_line(r#"{"type":"log.configure","request_id":"4","payload":{"level":"loud"}}"#)
+        .await;
+    assert_eq!(recv_json(&mut harness).await["status"], "error");
+}
+
 // ===========================================================================
 // SA-08: Stdout contains no log lines
 // ===========================================================================
@@ -795,6 +1126,23 @@ fn sa10_valid_relative_path_accepted() {
     assert!(result.unwrap().starts_with(&root));
 }
 
+#[tokio::test]
+async fn sa10_fs_write_delete_stat_hash_patch_traversal_rejected_by_router() {
+    let mut harness = ServerHarness::new();
+    for request in [
+        r#"{"type":"fs.write","request_id":"1","payload":{"path":"../x","content":""}}"#,
+        r#"{"type":"fs.delete","request_id":"2","payload":{"path":"../../etc"}}"#,
+        r#"{"type":"fs.stat","request_id":"3","payload":{"path":"../x"}}"#,
+        r#"{"type":"fs.hash","request_id":"4","payload":{"path":"../../etc/passwd"}}"#,
+        r#"{"type":"fs.patch","request_id":"5","payload":{"patch":"","path":"../x"}}"#,
+    ] {
+        harness.send_line(request).await;
+        let line = harness.recv_stdout_line().await;
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["error"]["code"], "path_outside_root", "{line}");
+    }
+}
+
 #[tokio::test]
 async fn sa10_fs_read_traversal_rejected_by_router() {
     let mut harness = ServerHarness::new();
@@ -891,3 +1239,482 @@ async fn sa12_oversized_through_server() {
     assert_eq!(parsed["status"], "error");
     assert_eq!(parsed["error"]["code"], "oversized_message");
 }
+
+#[test]
+fn sa12_session_requests_have_a_smaller_limit() {
+    let padding = "a".repeat(SESSION_MESSAGE_SIZE);
+    let json = format!(r#"{{"type":"session.stop","request_id":"1","pad":"{padding}"}}"#);
+    match parse_request(&json).unwrap_err() {
+        StdioError::OversizedMessage { max_size, .. } => {
+            assert_eq!(max_size, SESSION_MESSAGE_SIZE);
+        }
+        other => panic!("expected OversizedMessage, got: {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn sa12_negotiated_limits_last_until_session_stop() {
+    let mut harness = ServerHarness::new();
+    let limit = 2 * MAX_MESSAGE_SIZE;
+    harness
+        .send_line(&format!(
+            r#"{{"type":"session.start","request_id":"1","payload":{{"working_directories":[],"message_limits":{{"agent.execute":{limit}}}}}}}"#
+        ))
+        .await;
+    let start: serde_json::Value =
+        serde_json::from_str(&harness.recv_stdout_line().await).unwrap();
+    let limits = &start["payload"]["capabilities"]["message_limits"];
+    assert_eq!(limits["default"], MAX_MESSAGE_SIZE);
+    assert_eq!(limits["per_type"]["agent.execute"], limit);
+    assert_eq!(limits["per_type"]["session.*"], SESSION_MESSAGE_SIZE);
+
+    let padding = "a".repeat(MAX_MESSAGE_SIZE);
+    let execute =
+        format!(r#"{{"type":"agent.execute","request_id":"2","payload":{{"command":"{padding}"}}}}"#);
+    harness.send_line(&execute).await;
+    let parsed: serde_json::Value =
+        serde_json::from_str(&harness.recv_stdout_line().await).unwrap();
+    assert_eq!(parsed["status"], "ok");
+
+    harness
+        .send_line(r#"{"type":"session.stop","request_id":"3"}"#)
+        .await;
+    harness.recv_stdout_line().await;
+    harness.send_line(&execute).await;
+    let parsed: serde_json::Value =
+        serde_json::from_str(&harness.recv_stdout_line().await).unwrap();
+    assert_eq!(parsed["error"]["code"], "oversized_message");
+}
+
+// ===========================================================================
+// SA-13: Several sessions in one server
+// ===========================================================================
+
+/// Hands out `StubHandler`s, recording the session ids they are made for.
+#[derive(Clone, Default)]
+struct StubFactory {
+    created: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl SessionFactory for StubFactory {
+    fn create(&self, session_id: &str) -> Box<dyn RequestHandler> {
+        self.created.lock().unwrap().push(session_id.to_string());
+        Box::new(StubHandler)
+    }
+}
+
+fn dispatch_line(router: &Router, line: &str) -> serde_json::Value {
+    let addressed = parse_addressed_request_with_limits(line, &MessageLimits::default()).unwrap();
+    let response = router.dispatch_addressed(addressed.session_id.as_deref(), addressed.request);
+    serde_json::to_value(response).unwrap()
+}
+
+const START: &str = r#"{"type":"session.start","request_id":"1","payload":{"working_directories":[]}}"#;
+
+#[test]
+fn sa13_session_start_hands_out_session_ids() {
+    let factory = StubFactory::default();
+    let router = Router::with_sessions(Box::new(factory.clone()), 2);
+    assert_eq!(*factory.created.lock().unwrap(), ["s1"]);
+
+    let first = dispatch_line(&router, START);
+    assert_eq!(first["payload"]["session_id"], "s1");
+    assert!(first["payload"]["capabilities"].is_object());
+    let second = dispatch_line(&router, START);
+    assert_eq!(second["payload"]["session_id"], "s2");
+    assert_eq!(*factory.created.lock().unwrap(), ["s1", "s2", "s3"]);
+
+    let third = dispatch_line(&router, START);
+    assert_eq!(third["status"], "error");
+    assert_eq!(third["error"]["code"], "session_limit_reached");
+    assert_eq!(third["error"]["retryable"], true);
+    assert!(third["error"]["message"].as_str().unwrap().contains("2 sessions"));
+
+    let named = r#"{"type":"session.start","request_id":"1","session_id":"s9","payload":{"working_directories":[]}}"#;
+    assert_eq!(dispatch_line(&router, named)["status"], "error");
+}
+
+#[test]
+fn sa13_requests_must_name_an_active_session() {
+    let router = Router::with_sessions(Box::new(StubFactory::default()), 2);
+    let status = r#"{"type":"session.status","request_id":"2"}"#;
+    assert_eq!(dispatch_line(&router, status)["status"], "ok");
+
+    dispatch_line(&router, START);
+    let missing = dispatch_line(&router, status);
+    assert_eq!(missing["error"]["code"], "missing_field");
+    assert!(missing["error"]["message"].as_str().unwrap().contains("session_id"));
+
+    let unknown = r#"{"type":"session.status","request_id":"2","session_id":"s7"}"#;
+    assert_eq!(dispatch_line(&router, unknown)["error"]["code"], "invalid_field");
+    let addressed = r#"{"type":"session.status","request_id":"2","session_id":"s1"}"#;
+    assert_eq!(dispatch_line(&router, addressed)["status"], "ok");
+
+    // Host-wide requests need no session.
+    let cleanup = r#"{"type":"system.cleanup","request_id":"3"}"#;
+    assert_eq!(dispatch_line(&router, cleanup)["status"], "ok");
+}
+
+#[test]
+fn sa13_stopping_a_session_frees_its_slot() {
+    let router = Router::with_sessions(Box::new(StubFactory::default()), 1);
+    let limited = r#"{"type":"session.start","request_id":"1","payload":{"working_directories":[],"message_limits":{"agent.execute":2097152}}}"#;
+    assert_eq!(dispatch_line(&router, limited)["payload"]["session_id"], "s1");
+    assert_eq!(dispatch_line(&router, START)["status"], "error");
+
+    let stop = r#"{"type":"session.stop","request_id":"2","session_id":"s1"}"#;
+    assert_eq!(dispatch_line(&router, stop)["status"], "ok");
+    assert_eq!(router.message_limits(), MessageLimits::default());
+    let status = r#"{"type":"session.status","request_id":"3","session_id":"s1"}"#;
+    assert_eq!(dispatch_line(&router, status)["error"]["code"], "invalid_field");
+    assert_eq!(dispatch_line(&router, START)["payload"]["session_id"], "s2");
+}
+
+#[tokio::test]
+async fn sa13_session_events_carry_session_id() {
+    let mut harness = ServerHarness::new();
+    harness.inject_event(Event::Session {
+        session_id: "s2".to_string(),
+        event: Box::new(Event::TerminalOutput {
+            command_id: Some(4),
+            stream: "stdout".to_string(),
+            data: "hello\n".to_string(),
+        }),
+    });
+    let event: serde_json::Value =
+        serde_json::from_str(&harness.recv_stdout_line().await).unwrap();
+    assert_eq!(event["type"], "event.terminal_output");
+    assert_eq!(event["payload"]["session_id"], "s2");
+    assert_eq!(event["payload"]["command_id"], 4);
+}
+
+// ===========================================================================
+// SA-14: Cancelling requests in flight, and their progress
+// ===========================================================================
+
+async fn recv_json(harness: &mut ServerHarness) -> serde_json::Value {
+    serde_json::from_str(&harness.recv_stdout_line().await).unwrap()
+}
+
+#[tokio::test]
+async fn sa14_request_in_flight_reports_progress_and_can_be_cancelled() {
+    let mut harness = ServerHarness::new();
+    harness
+        .send_line(r#"{"type":"undo.rollback","request_id":"r1","payload":{"count":1000}}"#)
+        .await;
+
+    let progress = recv_json(&mut harness).await;
+    assert_eq!(progress["type"], "event.progress");
+    assert_eq!(progress["payload"]["request_id"], "r1");
+    assert_eq!(progress["payload"]["percent"], 0);
+    assert_eq!(progress["payload"]["current_path"], "src/main.rs");
+
+    // A request read while r1 is in flight waits for it; the cancel does not.
+    harness.send_line(r#"{"type":"session.status","request_id":"r2"}"#).await;
+    harness
+        .send_line(r#"{"type":"request.cancel","request_id":"c1","payload":{"request_id":"r1"}}"#)
+        .await;
+
+    let mut responses = Vec::new();
+    while responses.len() < 3 {
+        let message = recv_json(&mut harness).await;
+        if message["type"] == "response" {
+            responses.push(message);
+        }
+    }
+    assert_eq!(responses[0]["request_id"], "c1");
+    assert_eq!(responses[0]["payload"]["cancelled"], true);
+    assert_eq!(responses[1]["request_id"], "r1");
+    assert_eq!(responses[1]["payload"]["cancelled"], true);
+    assert_eq!(responses[2]["request_id"], "r2");
+}
+
+#[tokio::test]
+async fn sa14_cancelling_a_finished_request_reports_nothing_cancelled() {
+    let mut harness = ServerHarness::new();
+    harness.send_line(r#"{"type":"session.status","request_id":"1"}"#).await;
+    assert_eq!(recv_json(&mut harness).await["request_id"], "1");
+
+    harness
+        .send_line(r#"{"type":"request.cancel","request_id":"2","payload":{"request_id":"1"}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["request_id"], "2");
+    assert_eq!(response["status"], "ok");
+    assert_eq!(response["payload"]["cancelled"], false);
+}
+
+// ===========================================================================
+// SA-15: Event subscriptions
+// ===========================================================================
+
+#[tokio::test]
+async fn sa15_only_subscribed_events_are_written() {
+    let mut harness = ServerHarness::new();
+    harness
+        .send_line(r#"{"type":"events.subscribe","request_id":"1","payload":{"categories":["safeguards"]}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["status"], "ok");
+    assert_eq!(response["payload"]["categories"], serde_json::json!(["safeguards"]));
+
+    harness.inject_event(Event::TerminalOutput {
+        command_id: Some(1),
+        stream: "stdout".to_string(),
+        data: "noise\n".to_string(),
+    });
+    harness.inject_event(Event::AgentOutput {
+        data: "more noise".to_string(),
+    });
+    harness.inject_event(Event::SafeguardTriggered {
+        step_id: 3,
+        safeguard_id: "sg_1".to_string(),
+        kind: "delete_threshold".to_string(),
+        sample_paths: vec![],
+        message: "many deletes".to_string(),
+    });
+    harness.inject_event(Event::Warning {
+        warning: SandboxWarning::FileWatcherOverflow,
+    });
+
+    assert_eq!(recv_json(&mut harness).await["type"], "event.safeguard_triggered");
+    assert_eq!(recv_json(&mut harness).await["type"], "event.warning");
+}
+
+#[tokio::test]
+async fn sa15_unknown_categories_are_rejected() {
+    let mut harness = ServerHarness::new();
+    harness
+        .send_line(r#"{"type":"events.subscribe","request_id":"1","payload":{"categories":["all"]}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["status"], "error");
+
+    // The subscription is unchanged.
+    harness.inject_event(Event::AgentOutput {
+        data: "hello".to_string(),
+    });
+    assert_eq!(recv_json(&mut harness).await["type"], "event.agent_output");
+}
+
+// ===========================================================================
+// SA-16: Event sequence numbers and replay
+// ===========================================================================
+
+fn agent_output(data: &str) -> Event {
+    Event::AgentOutput {
+        data: data.to_string(),
+    }
+}
+
+#[tokio::test]
+async fn sa16_missed_events_are_replayed_by_seq() {
+    let mut harness = ServerHarness::new();
+    for data in ["a", "b", "c"] {
+        harness.inject_event(agent_output(data));
+    }
+    for seq in 1..=3 {
+        assert_eq!(recv_json(&mut harness).await["seq"], seq);
+    }
+
+    harness
+        .send_line(r#"{"type":"events.replay","request_id":"1","payload":{"since_seq":1}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["status"], "ok");
+    assert_eq!(response["payload"]["last_seq"], 3);
+    assert_eq!(response["payload"]["truncated"], false);
+    let events = response["payload"]["events"].as_array().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["seq"], 2);
+    assert_eq!(events[0]["payload"]["data"], "b");
+    assert_eq!(events[1]["seq"], 3);
+
+    // Responses take no seq of their own.
+    assert!(response.get("seq").is_none());
+    harness.inject_event(agent_output("d"));
+    assert_eq!(recv_json(&mut harness).await["seq"], 4);
+}
+
+#[tokio::test]
+async fn sa16_replay_reports_events_no_longer_buffered() {
+    let mut harness = ServerHarness::new();
+    let written = EVENT_REPLAY_CAPACITY + 5;
+    for i in 0..written {
+        harness.inject_event(agent_output(&i.to_string()));
+    }
+    for _ in 0..written {
+        recv_json(&mut harness).await;
+    }
+
+    harness
+        .send_line(r#"{"type":"events.replay","request_id":"1","payload":{"since_seq":0}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["payload"]["truncated"], true);
+    assert_eq!(response["payload"]["last_seq"], written);
+    let events = response["payload"]["events"].as_array().unwrap();
+    assert_eq!(events.len(), EVENT_REPLAY_CAPACITY);
+    assert_eq!(events[0]["seq"], 6);
+
+    harness
+        .send_line(r#"{"type":"events.replay","request_id":"2","payload":{"since_seq":5}}"#)
+        .await;
+    assert_eq!(recv_json(&mut harness).await["payload"]["truncated"], false);
+}
+
+// ===========================================================================
+// SA-17: protocol.hello negotiates a version and the features in use
+// ===========================================================================
+
+#[tokio::test]
+async fn sa17_hello_negotiates_and_gates_features() {
+    let mut harness = ServerHarness::new();
+    // Before a hello, every feature is available.
+    harness
+        .send_line(r#"{"type":"undo.squash","request_id":"1","payload":{"from_step":1,"to_step":2}}"#)
+        .await;
+    assert_eq!(recv_json(&mut harness).await["status"], "ok");
+
+    harness
+        .send_line(r#"{"type":"protocol.hello","request_id":"2","payload":{"versions":[1,7],"features":["fs_trace","redo"]}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["status"], "ok");
+    let hello = &response["payload"];
+    assert_eq!(hello["protocol_version"], 1);
+    assert_eq!(hello["min_version"], 1);
+    assert_eq!(hello["max_version"], 1);
+    assert!(hello["features"].as_array().unwrap().contains(&"undo_squash".into()));
+    assert_eq!(hello["enabled"], serde_json::json!(["fs_trace"]));
+
+    harness
+        .send_line(r#"{"type":"fs.trace","request_id":"3","payload":{"enabled":true}}"#)
+        .await;
+    assert_eq!(recv_json(&mut harness).await["status"], "ok");
+    harness
+        .send_line(r#"{"type":"undo.squash","request_id":"4","payload":{"from_step":1,"to_step":2}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["error"]["code"], "capability_unavailable");
+    // Requests outside any feature are unaffected.
+    harness.send_line(r#"{"type":"session.status","request_id":"5"}"#).await;
+    assert_eq!(recv_json(&mut harness).await["status"], "ok");
+
+    // session.start must agree with the hello.
+    harness
+        .send_line(r#"{"type":"session.start","request_id":"6","payload":{"working_directories":[],"protocol_version":1}}"#)
+        .await;
+    assert_eq!(recv_json(&mut harness).await["status"], "ok");
+}
+
+#[tokio::test]
+async fn sa17_hello_without_a_common_version_fails() {
+    let mut harness = ServerHarness::new();
+    harness
+        .send_line(r#"{"type":"protocol.hello","request_id":"1","payload":{"versions":[7,8]}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["error"]["code"], "unsupported_protocol_version");
+
+    // A failed hello negotiates nothing.
+    harness
+        .send_line(r#"{"type":"undo.squash","request_id":"2","payload":{"from_step":1,"to_step":2}}"#)
+        .await;
+    assert_eq!(recv_json(&mut harness).await["status"], "ok");
+}
+
+// ===========================================================================
+// SA-18: Batches of requests
+// ===========================================================================
+
+#[tokio::test]
+async fn sa18_batch_runs_every_request_in_order() {
+    let mut harness = ServerHarness::new();
+    harness
+        .send_line(r#"{"type":"batch","request_id":"1","payload":{"requests":[
+            {"type":"fs.write","request_id":"1.1","payload":{"path":"a.txt","content":"a"}},
+            {"type":"fs.read","request_id":"1.2","payload":{"path":"../../etc/passwd"}},
+            {"type":"session.status","request_id":"1.3"}
+        ]}}"#
+            .replace('\n', "")
+            .as_str())
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["request_id"], "1");
+    assert_eq!(response["status"], "ok");
+    let responses = response["payload"]["responses"].as_array().unwrap();
+    let statuses: Vec<_> = responses.iter().map(|r| (&r["request_id"], &r["status"])).collect();
+    assert_eq!(statuses.len(), 3);
+    assert_eq!(statuses[0], (&"1.1".into(), &"ok".into()));
+    assert_eq!(statuses[1], (&"1.2".into(), &"error".into()));
+    assert_eq!(responses[1]["error"]["code"], "path_outside_root");
+    assert_eq!(responses[2]["payload"]["state"], "idle");
+    assert!(response["payload"].get("committed").is_none());
+}
+
+#[tokio::test]
+async fn sa18_atomic_batch_needs_a_handler_with_undo_steps() {
+    let mut harness = ServerHarness::new();
+    harness
+        .send_line(r#"{"type":"batch","request_id":"1","payload":{"atomic":true,"requests":[{"type":"fs.write","request_id":"1.1","payload":{"path":"a.txt","content":"a"}}]}}"#)
+        .await;
+    let response = recv_json(&mut harness).await;
+    assert_eq!(response["error"]["code"], "capability_unavailable");
+}
+
+#[test]
+fn sa18_batch_requests_are_checked_when_parsed() {
+    let rejected = [
+        (r#"{"type":"batch","request_id":"1","payload":{"requests":[{"type":"batch","request_id":"2","payload":{"requests":[]}}]}}"#, "requests[0]"),
+        (r#"{"type":"batch","request_id":"1","payload":{"requests":[{"type":"fs.stat","request_id":"2","payload":{"path":"a"}},{"type":"session.stop","request_id":"3"}]}}"#, "requests[1]"),
+        (r#"{"type":"batch","request_id":"1","payload":{"atomic":true,"requests":[{"type":"undo.rollback","request_id":"2","payload":{"count":1}}]}}"#, "requests[0]"),
+        (r#"{"type":"batch","request_id":"1","payload":{"requests":[{"type":"fs.stat","request_id":"2","session_id":"s2","payload":{"path":"a"}}]}}"#, "requests[0]"),
+        (r#"{"type":"batch","request_id":"1","payload":{"requests":[{"type":"fs.stat","request_id":"2"}]}}"#, "requests[0]"),
+    ];
+    for (json, field) in rejected {
+        match parse_request(json) {
+            Err(StdioError::InvalidField { field: got, .. }) => assert_eq!(got, field, "{json}"),
+            other => panic!("{json}: {other:?}"),
+        }
+    }
+
+    // Outside an atomic batch, undo requests are fine.
+    let json = r#"{"type":"batch","request_id":"1","payload":{"requests":[{"type":"undo.rollback","request_id":"2","payload":{"count":1}}]}}"#;
+    match parse_request(json).unwrap() {
+        codeagent_stdio::Request::Batch { atomic, requests, .. } => {
+            assert!(!atomic);
+            assert_eq!(requests.len(), 1);
+        }
+        other => panic!("Expected Batch, got: {other:?}"),
+    }
+}
+
+// ===========================================================================
+// SA-19: Every event carries seq, emitted_at and origin
+// ===========================================================================
+
+#[tokio::test]
+async fn sa19_events_are_stamped_with_time_and_origin() {
+    let mut harness = ServerHarness::new();
+    harness.inject_event(Event::TerminalOutput {
+        command_id: Some(1),
+        stream: "stdout".to_string(),
+        data: "hello".to_string(),
+    });
+    harness.inject_event(Event::ExternalModification {
+        affected_paths: vec!["a.txt".to_string()],
+        barrier_id: None,
+    });
+
+    let mut last_emitted_at = String::new();
+    for (seq, origin) in [(1, "guest"), (2, "watcher")] {
+        let event = recv_json(&mut harness).await;
+        assert_eq!(event["seq"], seq);
+        assert_eq!(event["origin"], origin);
+        let emitted_at = event["emitted_at"].as_str().unwrap().to_string();
+        assert!(codeagent_common::time::parse_timestamp(&emitted_at).is_some());
+        assert!(emitted_at >= last_emitted_at);
+        last_emitted_at = emitted_at;
+    }
+}
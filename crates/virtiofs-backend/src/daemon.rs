@@ -1,20 +1,90 @@
+use std::io;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, mpsc};
 use std::thread::JoinHandle;
 
 use log::{error, info};
 use vhost::vhost_user::Listener;
 use vhost_user_backend::VhostUserDaemon;
 use virtiofsd::passthrough::{CachePolicy, Config, PassthroughFs};
-use virtiofsd::vhost_user::VhostUserFsBackendBuilder;
+use virtiofsd::vhost_user::{RequestDispatcher, RequestJob, VhostUserFsBackendBuilder};
 use vm_memory::{GuestMemoryAtomic, GuestMemoryMmap};
 
+use codeagent_common::StepAttributor;
+use codeagent_common::fs_trace::FsTrace;
 use codeagent_control::InFlightTracker;
 use codeagent_interceptor::write_interceptor::WriteInterceptor;
 
 use crate::error::VirtioFsBackendError;
 use crate::intercepted_fs::InterceptedFs;
 
+/// Threads serving the requests of one share by default.
+pub const DEFAULT_REQUEST_WORKERS: usize = 4;
+
+/// Runs FUSE requests on a fixed set of worker threads.
+///
+/// Requests on the same inode always go to the same worker, which handles
+/// them in the order the guest queued them, so writes to one file reach the
+/// interceptor and the disk in order. Requests on other inodes run in
+/// parallel, as do their preimage captures. Workers exit once the
+/// dispatcher is dropped.
+pub struct InodeOrderedDispatcher {
+    workers: Vec<mpsc::Sender<RequestJob>>,
+}
+
+impl InodeOrderedDispatcher {
+    /// Start `workers` threads (at least one).
+    pub fn new(workers: usize) -> io::Result<Self> {
+        let workers = (0..workers.max(1))
+            .map(|index| {
+                let (sender, jobs) = mpsc::channel::<RequestJob>();
+                let (started, start_result) = mpsc::sync_channel(1);
+                std::thread::Builder::new()
+                    .name(format!("virtiofs-worker-{index}"))
+                    .spawn(move || {
+                        // PassthroughFs changes the working directory for
+                        // some operations; keep that to this thread.
+                        let _ = started.send(unshare_fs_attributes());
+                        for job in jobs {
+                            job();
+                        }
+                    })?;
+                start_result.recv().map_err(io::Error::other)??;
+                Ok(sender)
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { workers })
+    }
+
+    /// Index of the worker handling requests on `nodeid`.
+    pub fn worker_for(&self, nodeid: u64) -> usize {
+        (nodeid % self.workers.len() as u64) as usize
+    }
+}
+
+impl RequestDispatcher for InodeOrderedDispatcher {
+    fn dispatch(&self, nodeid: u64, job: RequestJob) {
+        // A worker only stops when its sender is dropped.
+        let _ = self.workers[self.worker_for(nodeid)].send(job);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unshare_fs_attributes() -> io::Result<()> {
+    // SAFETY: unshare has no memory-safety preconditions.
+    if unsafe { libc::unshare(libc::CLONE_FS) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Other Unix hosts have no `unshare`: their workers share one working
+/// directory.
+#[cfg(not(target_os = "linux"))]
+fn unshare_fs_attributes() -> io::Result<()> {
+    Ok(())
+}
+
 /// In-process virtiofsd daemon with WriteInterceptor hooks.
 ///
 /// Replaces the external `VirtioFsBackend` (which spawns upstream virtiofsd
@@ -28,6 +98,11 @@ pub struct InterceptedVirtioFsBackend {
     socket_path: PathBuf,
     interceptor: Arc<dyn WriteInterceptor>,
     in_flight: InFlightTracker,
+    step_attributor: Option<Arc<dyn StepAttributor>>,
+    request_workers: usize,
+    read_only: bool,
+    exclude: Vec<String>,
+    trace: Option<Arc<FsTrace>>,
     daemon_handle: Option<JoinHandle<()>>,
 }
 
@@ -37,16 +112,45 @@ impl InterceptedVirtioFsBackend {
         socket_path: PathBuf,
         interceptor: Arc<dyn WriteInterceptor>,
         in_flight: InFlightTracker,
+        step_attributor: Option<Arc<dyn StepAttributor>>,
     ) -> Self {
         Self {
             shared_dir,
             socket_path,
             interceptor,
             in_flight,
+            step_attributor,
+            request_workers: DEFAULT_REQUEST_WORKERS,
+            read_only: false,
+            exclude: Vec::new(),
+            trace: None,
             daemon_handle: None,
         }
     }
 
+    /// Serve requests on `workers` threads (see [`InodeOrderedDispatcher`])
+    /// from the next start.
+    pub fn set_request_workers(&mut self, workers: usize) {
+        self.request_workers = workers;
+    }
+
+    /// Refuse every change to the share with `EROFS` from the next start,
+    /// before it reaches the interceptor.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Hide the paths matching the glob `patterns` from the guest from the
+    /// next start (see [`crate::exclusions::PathExclusions`]).
+    pub fn set_exclusions(&mut self, patterns: Vec<String>) {
+        self.exclude = patterns;
+    }
+
+    /// Record the operations served into `trace` from the next start.
+    pub fn set_trace(&mut self, trace: Arc<FsTrace>) {
+        self.trace = Some(trace);
+    }
+
     /// Build the virtiofsd Config for the shared directory.
     fn build_config(&self) -> Config {
         Config {
@@ -82,12 +186,22 @@ impl InterceptedVirtioFsBackend {
         })?;
 
         // 3. Wrap in InterceptedFs
-        let intercepted = InterceptedFs::new(
+        let mut intercepted = InterceptedFs::new(
             passthrough,
             self.interceptor.clone(),
             self.in_flight.clone(),
+            self.step_attributor.clone(),
             self.shared_dir.clone(),
         );
+        if self.read_only {
+            intercepted = intercepted.read_only();
+        }
+        if !self.exclude.is_empty() {
+            intercepted = intercepted.with_exclusions(&self.exclude);
+        }
+        if let Some(trace) = &self.trace {
+            intercepted = intercepted.with_trace(trace.clone());
+        }
 
         // 4. Create vhost-user socket listener
         let listener = Listener::new(&self.socket_path, true).map_err(|error| {
@@ -96,10 +210,15 @@ impl InterceptedVirtioFsBackend {
             }
         })?;
 
-        // 5. Build VhostUserFsBackend
+        // 5. Build VhostUserFsBackend, serving requests on the workers
+        let dispatcher = InodeOrderedDispatcher::new(self.request_workers).map_err(|error| {
+            VirtioFsBackendError::Daemon {
+                reason: format!("failed to start request workers: {error}"),
+            }
+        })?;
         let fs_backend = Arc::new(
             VhostUserFsBackendBuilder::default()
-                .set_thread_pool_size(0)
+                .set_request_dispatcher(Arc::new(dispatcher))
                 .build(intercepted)
                 .map_err(|error| VirtioFsBackendError::Daemon {
                     reason: format!("failed to build vhost-user backend: {error}"),
@@ -162,3 +281,51 @@ impl Drop for InterceptedVirtioFsBackend {
         let _ = self.stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn requests_on_one_inode_run_in_order() {
+        let dispatcher = InodeOrderedDispatcher::new(4).unwrap();
+        assert_eq!(dispatcher.worker_for(5), dispatcher.worker_for(9));
+        assert_ne!(dispatcher.worker_for(5), dispatcher.worker_for(6));
+
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let (done, finished) = mpsc::channel();
+        for index in 0..200 {
+            let (handled, done) = (Arc::clone(&handled), done.clone());
+            dispatcher.dispatch(5, Box::new(move || {
+                handled.lock().unwrap().push(index);
+                let _ = done.send(());
+            }));
+        }
+        for _ in 0..200 {
+            finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(*handled.lock().unwrap(), (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn requests_on_other_inodes_run_in_parallel() {
+        let dispatcher = InodeOrderedDispatcher::new(2).unwrap();
+        let (to_second, from_first) = mpsc::channel();
+        let (to_first, from_second) = mpsc::channel();
+        let (done, finished) = mpsc::channel();
+        let first_done = done.clone();
+        // Each waits for the other, so they deadlock unless both run at once.
+        dispatcher.dispatch(2, Box::new(move || {
+            to_second.send(()).unwrap();
+            let _ = first_done.send(from_second.recv_timeout(Duration::from_secs(5)).is_ok());
+        }));
+        dispatcher.dispatch(3, Box::new(move || {
+            to_first.send(()).unwrap();
+            let _ = done.send(from_first.recv_timeout(Duration::from_secs(5)).is_ok());
+        }));
+        assert!(finished.recv().unwrap() && finished.recv().unwrap());
+    }
+}
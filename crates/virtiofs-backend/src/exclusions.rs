@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+
+/// Paths of a shared directory hidden from the guest.
+///
+/// Patterns are globs matched against the forward-slash path relative to
+/// the shared directory root (`target`, `.git/objects`, `**/.cache`). A path
+/// is excluded if it or one of its ancestors matches, so excluding a
+/// directory excludes everything under it.
+pub struct PathExclusions {
+    root: PathBuf,
+    patterns: Vec<Pattern>,
+    options: MatchOptions,
+}
+
+impl PathExclusions {
+    /// Compile `patterns` for the shared directory `root`. Invalid glob
+    /// patterns are skipped.
+    pub fn new(root: PathBuf, patterns: &[String], case_insensitive: bool) -> Self {
+        let mut compiled = Vec::with_capacity(patterns.len());
+        for pattern in patterns {
+            match Pattern::new(pattern.trim_end_matches('/')) {
+                Ok(pattern) => compiled.push(pattern),
+                Err(error) => eprintln!(
+                    "{{\"level\":\"warn\",\"component\":\"virtiofs\",\"message\":\"ignoring invalid exclude pattern '{pattern}': {error}\"}}"
+                ),
+            }
+        }
+        Self {
+            root,
+            patterns: compiled,
+            options: MatchOptions {
+                case_sensitive: !case_insensitive,
+                require_literal_separator: true,
+                require_literal_leading_dot: false,
+            },
+        }
+    }
+
+    /// Whether nothing is excluded.
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether the host path `path` is excluded. Paths outside the root
+    /// never are.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        let mut prefix = String::new();
+        for component in relative.components() {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(&component.as_os_str().to_string_lossy());
+            if self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.matches_with(&prefix, self.options))
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(list: &[&str]) -> Vec<String> {
+        list.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn matching_paths_and_their_contents_are_excluded() {
+        let root = PathBuf::from("/work");
+        let exclusions = PathExclusions::new(
+            root.clone(),
+            &patterns(&["target/", ".git/objects", "**/.cache", "[invalid"]),
+            false,
+        );
+        assert!(!exclusions.is_empty());
+        for excluded in [
+            "target",
+            "target/debug/build.rs",
+            ".git/objects/ab/cdef",
+            "node_modules/.cache",
+            "web/node_modules/.cache/babel",
+        ] {
+            assert!(exclusions.is_excluded(&root.join(excluded)), "{excluded}");
+        }
+        for visible in ["", "src/target.rs", "crates/target", ".git/HEAD", "Target"] {
+            assert!(!exclusions.is_excluded(&root.join(visible)), "{visible}");
+        }
+        assert!(!exclusions.is_excluded(Path::new("/elsewhere/target")));
+    }
+
+    #[test]
+    fn case_insensitive_directories_match_any_case() {
+        let root = PathBuf::from("/work");
+        let exclusions = PathExclusions::new(root.clone(), &patterns(&["target"]), true);
+        assert!(exclusions.is_excluded(&root.join("Target/debug")));
+        assert!(PathExclusions::new(root, &[], false).is_empty());
+    }
+}
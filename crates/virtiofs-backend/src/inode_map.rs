@@ -3,10 +3,16 @@ use std::ffi::CStr;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use codeagent_interceptor::path_case;
 
 /// FUSE root inode ID (kernel convention).
 pub const FUSE_ROOT_ID: u64 = 1;
 
+/// Inodes a map tracks before it evicts the least recently used ones.
+pub const DEFAULT_MAX_ENTRIES: usize = 1 << 18;
+
 /// Maps FUSE inode numbers to host filesystem paths.
 ///
 /// virtiofsd's `FileSystem` trait uses inode numbers for all operations.
@@ -17,36 +23,78 @@ pub const FUSE_ROOT_ID: u64 = 1;
 ///
 /// Thread-safe via `RwLock` — virtiofsd's thread pool handles FUSE requests
 /// concurrently, and all of them may update the map.
+///
+/// On a case-insensitive shared directory the guest may reach one inode
+/// under several spellings, so renames match path prefixes case-folded.
+///
+/// The map holds at most `max_entries` inodes besides the root. Past that,
+/// an insert evicts the least recently used tenth of them, so a miss on an
+/// inode the guest still holds does not mean it is gone: the caller
+/// rebuilds its path (see [`InodePathMap::rebase`]) and inserts it again.
 pub struct InodePathMap {
-    map: RwLock<HashMap<u64, PathBuf>>,
+    map: RwLock<HashMap<u64, Tracked>>,
     root: PathBuf,
+    /// `root` with symlinks resolved, as paths read back from the host
+    /// spell it.
+    canonical_root: PathBuf,
+    case_insensitive: bool,
+    max_entries: usize,
+    /// Source of the `last_used` stamps.
+    clock: AtomicU64,
+}
+
+struct Tracked {
+    path: PathBuf,
+    last_used: AtomicU64,
 }
 
 impl InodePathMap {
     /// Create a new map with the root inode pre-populated.
     pub fn new(root: PathBuf) -> Self {
+        Self::with_case_insensitive(root, false)
+    }
+
+    /// Like [`InodePathMap::new`], for a shared directory that compares
+    /// paths case-insensitively when `case_insensitive` is set.
+    pub fn with_case_insensitive(root: PathBuf, case_insensitive: bool) -> Self {
         let mut map = HashMap::new();
-        map.insert(FUSE_ROOT_ID, root.clone());
+        map.insert(FUSE_ROOT_ID, Tracked::new(root.clone(), 0));
         Self {
             map: RwLock::new(map),
+            canonical_root: std::fs::canonicalize(&root).unwrap_or_else(|_| root.clone()),
             root,
+            case_insensitive,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            clock: AtomicU64::new(1),
         }
     }
 
+    /// Track at most `max_entries` inodes besides the root.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
     /// The shared directory root.
     pub fn root(&self) -> &Path {
         &self.root
     }
 
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
     /// Get the host path for an inode. Returns `ENOENT` if not tracked.
     pub fn get(&self, inode: u64) -> io::Result<PathBuf> {
         let map = self.map.read().unwrap();
-        map.get(&inode).cloned().ok_or_else(|| {
+        let tracked = map.get(&inode).ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::NotFound,
                 format!("inode {inode} not tracked"),
             )
-        })
+        })?;
+        tracked.last_used.store(self.tick(), Ordering::Relaxed);
+        Ok(tracked.path.clone())
     }
 
     /// Resolve parent inode + child name to a host path.
@@ -54,7 +102,11 @@ impl InodePathMap {
     /// Looks up the parent inode's path and appends the child name.
     /// Returns `ENOENT` if the parent inode is not tracked.
     pub fn resolve(&self, parent: u64, name: &CStr) -> io::Result<PathBuf> {
-        let parent_path = self.get(parent)?;
+        Self::child_path(&self.get(parent)?, name)
+    }
+
+    /// `name` under the directory `parent_path`.
+    pub fn child_path(parent_path: &Path, name: &CStr) -> io::Result<PathBuf> {
         let name_str = name.to_str().map_err(|_| {
             io::Error::new(io::ErrorKind::InvalidInput, "non-UTF-8 filename")
         })?;
@@ -67,7 +119,28 @@ impl InodePathMap {
     /// If the inode already exists (e.g., re-lookup), the path is updated.
     pub fn insert(&self, inode: u64, path: PathBuf) {
         let mut map = self.map.write().unwrap();
-        map.insert(inode, path);
+        map.insert(inode, Tracked::new(path, self.tick()));
+        if map.len() > self.max_entries + 1 {
+            self.evict(&mut map);
+        }
+    }
+
+    /// Drop the least recently used tenth of the inodes, never the root.
+    fn evict(&self, map: &mut HashMap<u64, Tracked>) {
+        let mut stamps: Vec<(u64, u64)> = map
+            .iter()
+            .filter(|(inode, _)| **inode != FUSE_ROOT_ID)
+            .map(|(&inode, tracked)| (tracked.last_used.load(Ordering::Relaxed), inode))
+            .collect();
+        let keep = self.max_entries - self.max_entries / 10;
+        let evicted = stamps.len().saturating_sub(keep);
+        if evicted == 0 {
+            return;
+        }
+        stamps.select_nth_unstable(evicted - 1);
+        for &(_, inode) in &stamps[..evicted] {
+            map.remove(&inode);
+        }
     }
 
     /// Remove a mapping.
@@ -105,22 +178,46 @@ impl InodePathMap {
     /// to replace the old prefix with `new_prefix`.
     pub fn rename_subtree(&self, old_prefix: &Path, new_prefix: &Path) {
         let mut map = self.map.write().unwrap();
-        let updates: Vec<(u64, PathBuf)> = map
-            .iter()
-            .filter_map(|(&inode, path)| {
-                if path == old_prefix {
-                    Some((inode, new_prefix.to_path_buf()))
-                } else if let Ok(suffix) = path.strip_prefix(old_prefix) {
-                    Some((inode, new_prefix.join(suffix)))
-                } else {
-                    None
-                }
-            })
-            .collect();
+        for tracked in map.values_mut() {
+            let Some(suffix) = self.strip_prefix(&tracked.path, old_prefix) else {
+                continue;
+            };
+            tracked.path = if suffix.as_os_str().is_empty() {
+                new_prefix.to_path_buf()
+            } else {
+                new_prefix.join(suffix)
+            };
+        }
+    }
 
-        for (inode, new_path) in updates {
-            map.insert(inode, new_path);
+    /// `path` without its leading `prefix` components, compared case-folded
+    /// on a case-insensitive shared directory.
+    fn strip_prefix<'a>(&self, path: &'a Path, prefix: &Path) -> Option<&'a Path> {
+        if !self.case_insensitive {
+            return path.strip_prefix(prefix).ok();
         }
+        let mut components = path.components();
+        for expected in prefix.components() {
+            let actual = components.next()?;
+            let fold = |component: std::path::Component<'_>| {
+                path_case::fold(&component.as_os_str().to_string_lossy())
+            };
+            if fold(actual) != fold(expected) {
+                return None;
+            }
+        }
+        Some(components.as_path())
+    }
+
+    /// The map's spelling of `host_path`, a path read back from the host
+    /// (through `/proc/self/fd`, say) that may spell the root with its
+    /// symlinks resolved. `None` for a path outside the root.
+    pub fn rebase(&self, host_path: &Path) -> Option<PathBuf> {
+        let relative = host_path
+            .strip_prefix(&self.canonical_root)
+            .or_else(|_| host_path.strip_prefix(&self.root))
+            .ok()?;
+        Some(self.root.join(relative))
     }
 
     /// Number of tracked inodes (including the root).
@@ -132,6 +229,60 @@ impl InodePathMap {
     pub fn is_empty(&self) -> bool {
         self.len() <= 1
     }
+
+    /// The tracked inodes, with paths relative to the root, for a
+    /// migration stream: per inode its number and the length of its path
+    /// (little-endian `u64` and `u32`), then the path in UTF-8.
+    pub fn serialize(&self) -> Vec<u8> {
+        let map = self.map.read().unwrap();
+        let mut state = Vec::new();
+        for (&inode, tracked) in map.iter() {
+            if inode == FUSE_ROOT_ID {
+                continue;
+            }
+            let Some(relative) = tracked.path.strip_prefix(&self.root).ok().and_then(Path::to_str)
+            else {
+                continue;
+            };
+            state.extend_from_slice(&inode.to_le_bytes());
+            state.extend_from_slice(&(relative.len() as u32).to_le_bytes());
+            state.extend_from_slice(relative.as_bytes());
+        }
+        state
+    }
+
+    /// Track the inodes of a [`serialize`](Self::serialize)d map, under
+    /// this map's root.
+    pub fn restore(&self, mut state: &[u8]) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "truncated inode map state");
+        let mut entries = Vec::new();
+        while !state.is_empty() {
+            let (inode, rest) = state.split_first_chunk::<8>().ok_or_else(invalid)?;
+            let (len, rest) = rest.split_first_chunk::<4>().ok_or_else(invalid)?;
+            let len = u32::from_le_bytes(*len) as usize;
+            if rest.len() < len {
+                return Err(invalid());
+            }
+            let (relative, rest) = rest.split_at(len);
+            let relative = std::str::from_utf8(relative)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            entries.push((u64::from_le_bytes(*inode), self.root.join(relative)));
+            state = rest;
+        }
+        for (inode, path) in entries {
+            self.insert(inode, path);
+        }
+        Ok(())
+    }
+}
+
+impl Tracked {
+    fn new(path: PathBuf, last_used: u64) -> Self {
+        Self {
+            path,
+            last_used: AtomicU64::new(last_used),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -271,6 +422,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn case_insensitive_rename_matches_other_spellings() {
+        let map = InodePathMap::with_case_insensitive(PathBuf::from("/shared"), true);
+        map.insert(10, PathBuf::from("/shared/Src"));
+        map.insert(20, PathBuf::from("/shared/SRC/Main.rs"));
+        map.insert(30, PathBuf::from("/shared/srcs"));
+
+        map.rename(FUSE_ROOT_ID, &cstr("src"), FUSE_ROOT_ID, &cstr("lib"))
+            .unwrap();
+
+        assert_eq!(map.get(10).unwrap(), PathBuf::from("/shared/lib"));
+        assert_eq!(map.get(20).unwrap(), PathBuf::from("/shared/lib/Main.rs"));
+        assert_eq!(map.get(30).unwrap(), PathBuf::from("/shared/srcs"));
+
+        // Case-sensitive maps leave other spellings alone.
+        let map = InodePathMap::new(PathBuf::from("/shared"));
+        map.insert(20, PathBuf::from("/shared/SRC/Main.rs"));
+        map.rename(FUSE_ROOT_ID, &cstr("src"), FUSE_ROOT_ID, &cstr("lib"))
+            .unwrap();
+        assert_eq!(map.get(20).unwrap(), PathBuf::from("/shared/SRC/Main.rs"));
+    }
+
     #[test]
     fn insert_overwrites_existing_mapping() {
         let map = InodePathMap::new(PathBuf::from("/shared"));
@@ -363,4 +536,51 @@ mod tests {
         assert!(map.get(2).is_err());
         assert!(map.get(3).is_ok());
     }
+
+    #[test]
+    fn least_recently_used_inodes_are_evicted_past_the_limit() {
+        let map = InodePathMap::new(PathBuf::from("/shared")).with_max_entries(10);
+        for i in 2..=11 {
+            map.insert(i, PathBuf::from(format!("/shared/file_{i}")));
+        }
+        assert_eq!(map.len(), 11);
+        // Touched last, so kept.
+        map.get(2).unwrap();
+
+        map.insert(12, PathBuf::from("/shared/file_12"));
+        assert_eq!(map.len(), 10);
+        assert!(map.get(FUSE_ROOT_ID).is_ok());
+        assert!(map.get(2).is_ok());
+        assert!(map.get(12).is_ok());
+        assert!(map.get(3).is_err());
+        assert!(map.get(4).is_err());
+        assert!(map.get(5).is_ok());
+    }
+
+    #[test]
+    fn host_paths_are_rebased_onto_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let canonical = std::fs::canonicalize(dir.path()).unwrap();
+        let map = InodePathMap::new(dir.path().to_path_buf());
+        assert_eq!(map.rebase(&canonical.join("src/lib.rs")), Some(dir.path().join("src/lib.rs")));
+        assert_eq!(map.rebase(&dir.path().join("a")), Some(dir.path().join("a")));
+        assert_eq!(map.rebase(Path::new("/elsewhere/a")), None);
+    }
+
+    #[test]
+    fn serialized_map_restores_under_another_root() {
+        let map = InodePathMap::new(PathBuf::from("/source"));
+        map.insert(2, PathBuf::from("/source/src"));
+        map.insert(3, PathBuf::from("/source/src/main.rs"));
+        let state = map.serialize();
+
+        let restored = InodePathMap::new(PathBuf::from("/destination"));
+        restored.restore(&state).unwrap();
+        assert_eq!(restored.len(), 3);
+        assert_eq!(restored.get(3).unwrap(), PathBuf::from("/destination/src/main.rs"));
+        assert_eq!(restored.get(FUSE_ROOT_ID).unwrap(), PathBuf::from("/destination"));
+
+        let err = restored.restore(&state[..state.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
 }
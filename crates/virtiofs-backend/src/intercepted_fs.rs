@@ -1,21 +1,30 @@
-use std::ffi::CStr;
-use std::io;
+use std::ffi::{CStr, CString, OsStr};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use virtiofsd::filesystem::{
-    Context, Entry, Extensions, FileSystem, FsOptions, GetxattrReply, ListxattrReply, OpenOptions,
-    SetattrValid, SerializableFileSystem, SetxattrFlags, ZeroCopyReader, ZeroCopyWriter,
+    Context, DirEntry, DirectoryIterator, Entry, Extensions, FileSystem, FsOptions, GetxattrReply,
+    ListxattrReply, OpenOptions, SetattrValid, SerializableFileSystem, SetxattrFlags,
+    ZeroCopyReader, ZeroCopyWriter,
 };
 use virtiofsd::fuse::{Attr, SetattrIn};
 use virtiofsd::passthrough::PassthroughFs;
+use virtiofsd::soft_idmap::Id;
 
+use codeagent_common::StepAttributor;
+use codeagent_common::fs_trace::{FsTrace, TraceRecord};
 use codeagent_control::InFlightTracker;
+use codeagent_interceptor::step_attribution::{self, AttributionScope, GuestProcess};
 use codeagent_interceptor::write_interceptor::WriteInterceptor;
 
+use crate::exclusions::PathExclusions;
 use crate::inode_map::InodePathMap;
 
+type PassthroughDirIter = <PassthroughFs as FileSystem>::DirIter;
+
 /// Drop guard that calls `InFlightTracker::end_operation()` on drop.
 ///
 /// Ensures the in-flight count is always decremented, even on early return
@@ -37,6 +46,39 @@ impl Drop for InFlightGuard<'_> {
     }
 }
 
+/// A directory entry kept past the `readdir` buffer it was read from.
+pub struct OwnedDirEntry {
+    ino: u64,
+    offset: u64,
+    type_: u32,
+    name: CString,
+}
+
+/// The entries of a `readdir`: all of them when nothing is excluded,
+/// otherwise those left after filtering.
+pub enum VisibleEntries {
+    All(PassthroughDirIter),
+    Filtered { entries: Vec<OwnedDirEntry>, next: usize },
+}
+
+impl DirectoryIterator for VisibleEntries {
+    fn next(&mut self) -> Option<DirEntry<'_>> {
+        match self {
+            Self::All(entries) => entries.next(),
+            Self::Filtered { entries, next } => {
+                let entry = entries.get(*next)?;
+                *next += 1;
+                Some(DirEntry {
+                    ino: entry.ino,
+                    offset: entry.offset,
+                    type_: entry.type_,
+                    name: &entry.name,
+                })
+            }
+        }
+    }
+}
+
 /// Wraps `PassthroughFs` to intercept mutating filesystem operations.
 ///
 /// Implements virtiofsd's `FileSystem` trait by delegating all operations to
@@ -46,18 +88,33 @@ impl Drop for InFlightGuard<'_> {
 /// Read-only methods are delegated directly (except `lookup` which also updates
 /// the inode map). Mutating methods follow this pattern:
 ///
-/// 1. `in_flight.begin_operation()` (via InFlightGuard)
+/// 1. `in_flight.begin_operation()` (via InFlightGuard), and attribute the
+///    operation to the step of the calling guest process (via the
+///    `StepAttributor`, if any)
 /// 2. Call `WriteInterceptor` pre-hook (if applicable)
 /// 3. If pre-hook returns error -> convert to `io::Error(EACCES)` and return
 /// 4. Delegate to `inner.method()`
 /// 5. Call `WriteInterceptor` post-hook (if applicable)
 /// 6. Update `InodePathMap`
 /// 7. `in_flight.end_operation()` (via InFlightGuard drop)
+///
+/// A read-only share fails every mutating method, and opens for writing or
+/// truncation, with `EROFS` before any of this: nothing reaches the
+/// interceptor or the undo log.
+///
+/// Excluded paths (see [`PathExclusions`]) are hidden: `lookup` answers
+/// `ENOENT`, `readdir` leaves them out, and creating or renaming onto one
+/// fails with `EACCES`. The guest gets no inode under them, so no other
+/// request can reach them.
 pub struct InterceptedFs {
     inner: PassthroughFs,
     interceptor: Arc<dyn WriteInterceptor>,
     in_flight: InFlightTracker,
+    step_attributor: Option<Arc<dyn StepAttributor>>,
     inode_map: InodePathMap,
+    read_only: bool,
+    exclusions: PathExclusions,
+    trace: Option<Arc<FsTrace>>,
 }
 
 impl InterceptedFs {
@@ -65,13 +122,100 @@ impl InterceptedFs {
         inner: PassthroughFs,
         interceptor: Arc<dyn WriteInterceptor>,
         in_flight: InFlightTracker,
+        step_attributor: Option<Arc<dyn StepAttributor>>,
         root_dir: PathBuf,
     ) -> Self {
+        let case_insensitive = interceptor.is_case_insensitive();
         Self {
             inner,
             interceptor,
             in_flight,
-            inode_map: InodePathMap::new(root_dir),
+            step_attributor,
+            exclusions: PathExclusions::new(root_dir.clone(), &[], case_insensitive),
+            inode_map: InodePathMap::with_case_insensitive(root_dir, case_insensitive),
+            read_only: false,
+            trace: None,
+        }
+    }
+
+    /// Record the operations served in `trace` while it is enabled.
+    pub fn with_trace(mut self, trace: Arc<FsTrace>) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Hide the paths matching the glob `patterns` from the guest.
+    pub fn with_exclusions(mut self, patterns: &[String]) -> Self {
+        let root = self.inode_map.root().to_path_buf();
+        let case_insensitive = self.interceptor.is_case_insensitive();
+        self.exclusions = PathExclusions::new(root, patterns, case_insensitive);
+        self
+    }
+
+    /// Refuse every change to the share with `EROFS`.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// `EROFS` if the share is read-only.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    /// `EACCES` if the guest may not create `name` in `parent`.
+    fn check_creatable(&self, parent: u64, name: &CStr) -> io::Result<()> {
+        if self.exclusions.is_empty() {
+            return Ok(());
+        }
+        match self.resolve_child_path(parent, name) {
+            Ok(path) if self.exclusions.is_excluded(&path) => {
+                Err(io::Error::from_raw_os_error(libc::EACCES))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The entries `read` returns from `offset` on in the directory `inode`,
+    /// without the excluded ones. Batches holding nothing but excluded
+    /// entries are skipped, as an empty one reads as the end of the
+    /// directory.
+    fn visible_entries(
+        &self,
+        inode: u64,
+        mut offset: u64,
+        read: impl Fn(u64) -> io::Result<PassthroughDirIter>,
+    ) -> io::Result<VisibleEntries> {
+        if self.exclusions.is_empty() {
+            return read(offset).map(VisibleEntries::All);
+        }
+        let dir_path = self.resolve_path(inode)?;
+        loop {
+            let mut batch = read(offset)?;
+            let mut entries = Vec::new();
+            let mut read_any = false;
+            while let Some(entry) = batch.next() {
+                read_any = true;
+                offset = entry.offset;
+                let name = entry.name.to_bytes();
+                let hidden = name != b"."
+                    && name != b".."
+                    && self.exclusions.is_excluded(&dir_path.join(OsStr::from_bytes(name)));
+                if !hidden {
+                    entries.push(OwnedDirEntry {
+                        ino: entry.ino,
+                        offset: entry.offset,
+                        type_: entry.type_,
+                        name: entry.name.to_owned(),
+                    });
+                }
+            }
+            if !entries.is_empty() || !read_any {
+                return Ok(VisibleEntries::Filtered { entries, next: 0 });
+            }
         }
     }
 
@@ -80,31 +224,131 @@ impl InterceptedFs {
         io::Error::new(io::ErrorKind::PermissionDenied, err.to_string())
     }
 
-    /// Resolve an inode to its host path.
+    /// Record the hooks of this operation in the step of the guest process
+    /// that issued it, and as issued by it, until the returned scope is
+    /// dropped. Requests the kernel issues on its own carry pid 0 and name
+    /// no process.
+    fn attribute(&self, ctx: &Context) -> AttributionScope {
+        let pid = ctx.pid as u32;
+        let attributor = self.step_attributor.as_ref();
+        let step = attributor.and_then(|attributor| attributor.step_for_pid(pid));
+        let process = (pid != 0).then(|| GuestProcess {
+            pid,
+            uid: ctx.uid.into_inner(),
+            gid: ctx.gid.into_inner(),
+            name: attributor.and_then(|attributor| attributor.process_name(pid)),
+        });
+        step_attribution::attribute_operation(step, process)
+    }
+
+    /// A guard recording operation `op` on `inode` (or its child `name`) in
+    /// the trace when dropped. `None` while tracing is off.
+    fn trace(
+        &self,
+        ctx: &Context,
+        op: &'static str,
+        inode: u64,
+        name: Option<&CStr>,
+        size: Option<u64>,
+    ) -> Option<TraceGuard<'_>> {
+        let trace = self.trace.as_deref().filter(|trace| trace.is_enabled())?;
+        let path = match name {
+            Some(name) => self.inode_map.resolve(inode, name),
+            None => self.inode_map.get(inode),
+        };
+        let path = path.ok().and_then(|path| {
+            let relative = path.strip_prefix(self.inode_map.root()).ok()?;
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            Some(if relative.is_empty() { ".".to_string() } else { relative })
+        });
+        let pid = ctx.pid as u32;
+        let step_id = self
+            .step_attributor
+            .as_ref()
+            .and_then(|attributor| attributor.step_for_pid(pid))
+            .or_else(|| self.interceptor.current_step());
+        Some(TraceGuard {
+            trace,
+            started: Instant::now(),
+            record: Some(TraceRecord {
+                seq: 0,
+                op,
+                path,
+                size,
+                latency_us: 0,
+                pid,
+                step_id,
+            }),
+        })
+    }
+
+    /// Resolve an inode to its host path. An inode the map evicted, or
+    /// lost across a restart, has its path read back from the passthrough's
+    /// `O_PATH` fd and is tracked again.
     fn resolve_path(&self, inode: u64) -> io::Result<PathBuf> {
-        self.inode_map.get(inode)
+        if let Ok(path) = self.inode_map.get(inode) {
+            return Ok(path);
+        }
+        let path = self
+            .inner
+            .inode_path(inode)
+            .ok()
+            .and_then(|host_path| self.inode_map.rebase(&host_path))
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        self.inode_map.insert(inode, path.clone());
+        Ok(path)
     }
 
     /// Resolve parent inode + child name to host path.
     fn resolve_child_path(&self, parent: u64, name: &CStr) -> io::Result<PathBuf> {
-        self.inode_map.resolve(parent, name)
+        InodePathMap::child_path(&self.resolve_path(parent)?, name)
+    }
+}
+
+/// Records an operation in the trace when the operation's scope ends.
+struct TraceGuard<'a> {
+    trace: &'a FsTrace,
+    started: Instant,
+    record: Option<TraceRecord>,
+}
+
+impl Drop for TraceGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            record.latency_us = self.started.elapsed().as_micros() as u64;
+            self.trace.record(record);
+        }
     }
 }
 
 /// O_TRUNC flag value (matches Linux kernel definition).
 const O_TRUNC: u32 = 0o1000;
 
+/// Leads a migration stream that carries the inode map before the
+/// passthrough's state.
+const MIGRATION_MAGIC: &[u8] = b"codeagent-inode-map-v1\0";
+
+/// O_APPEND flag value (matches Linux kernel definition).
+const O_APPEND: u32 = 0o2000;
+
+/// Access mode bits of open flags; anything but O_RDONLY (0) writes.
+const O_ACCMODE: u32 = 0o3;
+
 impl FileSystem for InterceptedFs {
     type Inode = <PassthroughFs as FileSystem>::Inode;
     type Handle = <PassthroughFs as FileSystem>::Handle;
-    type DirIter = <PassthroughFs as FileSystem>::DirIter;
+    type DirIter = VisibleEntries;
 
     // -----------------------------------------------------------------------
     // Lifecycle
     // -----------------------------------------------------------------------
 
     fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
-        self.inner.init(capable)
+        // A DAX-mapped file is read and written through guest memory, out of
+        // reach of the write interceptor, and the daemon cannot serve the
+        // mappings anyway. Never grant per-inode DAX, so a share mounted
+        // with `dax=inode` keeps every write on FUSE.
+        self.inner.init(capable).map(|options| options - FsOptions::HAS_INODE_DAX)
     }
 
     fn destroy(&self) {
@@ -125,10 +369,12 @@ impl FileSystem for InterceptedFs {
         inode: Self::Inode,
         handle: Option<Self::Handle>,
     ) -> io::Result<(Attr, Duration)> {
+        let _trace = self.trace(&ctx, "getattr", inode, None, None);
         self.inner.getattr(ctx, inode, handle)
     }
 
     fn readlink(&self, ctx: Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        let _trace = self.trace(&ctx, "readlink", inode, None, None);
         self.inner.readlink(ctx, inode)
     }
 
@@ -139,9 +385,14 @@ impl FileSystem for InterceptedFs {
         kill_priv: bool,
         flags: u32,
     ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        let _trace = self.trace(&ctx, "open", inode, None, None);
+        if flags & (O_ACCMODE | O_TRUNC) != 0 {
+            self.check_writable()?;
+        }
         // If O_TRUNC is set, this is a mutating operation.
         if flags & O_TRUNC != 0 {
             let _guard = InFlightGuard::new(&self.in_flight);
+            let _scope = self.attribute(&ctx);
             if let Ok(path) = self.resolve_path(inode) {
                 self.interceptor
                     .pre_open_trunc(&path)
@@ -163,6 +414,7 @@ impl FileSystem for InterceptedFs {
         lock_owner: Option<u64>,
         flags: u32,
     ) -> io::Result<usize> {
+        let _trace = self.trace(&ctx, "read", inode, None, Some(u64::from(size)));
         self.inner
             .read(ctx, inode, handle, w, size, offset, lock_owner, flags)
     }
@@ -174,6 +426,7 @@ impl FileSystem for InterceptedFs {
         handle: Self::Handle,
         lock_owner: u64,
     ) -> io::Result<()> {
+        let _trace = self.trace(&ctx, "flush", inode, None, None);
         self.inner.flush(ctx, inode, handle, lock_owner)
     }
 
@@ -198,6 +451,7 @@ impl FileSystem for InterceptedFs {
         datasync: bool,
         handle: Self::Handle,
     ) -> io::Result<()> {
+        let _trace = self.trace(&ctx, "fsync", inode, None, None);
         self.inner.fsync(ctx, inode, datasync, handle)
     }
 
@@ -207,6 +461,7 @@ impl FileSystem for InterceptedFs {
         inode: Self::Inode,
         flags: u32,
     ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        let _trace = self.trace(&ctx, "opendir", inode, None, None);
         self.inner.opendir(ctx, inode, flags)
     }
 
@@ -218,7 +473,10 @@ impl FileSystem for InterceptedFs {
         size: u32,
         offset: u64,
     ) -> io::Result<Self::DirIter> {
-        self.inner.readdir(ctx, inode, handle, size, offset)
+        let _trace = self.trace(&ctx, "readdir", inode, None, None);
+        self.visible_entries(inode, offset, |offset| {
+            self.inner.readdir(ctx, inode, handle, size, offset)
+        })
     }
 
     fn readdirplus(
@@ -229,7 +487,10 @@ impl FileSystem for InterceptedFs {
         size: u32,
         offset: u64,
     ) -> io::Result<Self::DirIter> {
-        self.inner.readdirplus(ctx, inode, handle, size, offset)
+        let _trace = self.trace(&ctx, "readdirplus", inode, None, None);
+        self.visible_entries(inode, offset, |offset| {
+            self.inner.readdirplus(ctx, inode, handle, size, offset)
+        })
     }
 
     fn releasedir(
@@ -259,6 +520,7 @@ impl FileSystem for InterceptedFs {
         name: &CStr,
         size: u32,
     ) -> io::Result<GetxattrReply> {
+        let _trace = self.trace(&ctx, "getxattr", inode, None, None);
         self.inner.getxattr(ctx, inode, name, size)
     }
 
@@ -268,6 +530,7 @@ impl FileSystem for InterceptedFs {
         inode: Self::Inode,
         size: u32,
     ) -> io::Result<ListxattrReply> {
+        let _trace = self.trace(&ctx, "listxattr", inode, None, None);
         self.inner.listxattr(ctx, inode, size)
     }
 
@@ -291,9 +554,14 @@ impl FileSystem for InterceptedFs {
     // -----------------------------------------------------------------------
 
     fn lookup(&self, ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
+        let _trace = self.trace(&ctx, "lookup", parent, Some(name), None);
+        let path = self.resolve_child_path(parent, name);
+        if path.as_ref().is_ok_and(|path| self.exclusions.is_excluded(path)) {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
         let entry = self.inner.lookup(ctx, parent, name)?;
         if entry.inode != 0 {
-            if let Ok(path) = self.resolve_child_path(parent, name) {
+            if let Ok(path) = path {
                 self.inode_map.insert(entry.inode, path);
             }
         }
@@ -329,11 +597,19 @@ impl FileSystem for InterceptedFs {
         kill_priv: bool,
         flags: u32,
     ) -> io::Result<usize> {
+        let _trace = self.trace(&ctx, "write", inode, None, Some(u64::from(size)));
+        self.check_writable()?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         if let Ok(path) = self.resolve_path(inode) {
-            self.interceptor
-                .pre_write(&path)
-                .map_err(Self::interceptor_error_to_io)?;
+            // `flags` are those of the handle: an append-only write goes to
+            // the end of the file, not to `offset`.
+            let result = if flags & O_APPEND != 0 {
+                self.interceptor.pre_append(&path)
+            } else {
+                self.interceptor.pre_write_range(&path, offset, u64::from(size))
+            };
+            result.map_err(Self::interceptor_error_to_io)?;
         }
         self.inner.write(
             ctx,
@@ -360,7 +636,11 @@ impl FileSystem for InterceptedFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        let _trace = self.trace(&ctx, "create", parent, Some(name), None);
+        self.check_writable()?;
+        self.check_creatable(parent, name)?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         let child_path = self.resolve_child_path(parent, name)?;
 
         // If the file exists and O_TRUNC is set, it's an overwrite.
@@ -395,7 +675,11 @@ impl FileSystem for InterceptedFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        let _trace = self.trace(&ctx, "mkdir", parent, Some(name), None);
+        self.check_writable()?;
+        self.check_creatable(parent, name)?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         let entry = self
             .inner
             .mkdir(ctx, parent, name, mode, umask, extensions)?;
@@ -418,7 +702,11 @@ impl FileSystem for InterceptedFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        let _trace = self.trace(&ctx, "mknod", parent, Some(name), None);
+        self.check_writable()?;
+        self.check_creatable(parent, name)?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         let entry = self
             .inner
             .mknod(ctx, parent, name, mode, rdev, umask, extensions)?;
@@ -432,7 +720,10 @@ impl FileSystem for InterceptedFs {
     }
 
     fn unlink(&self, ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        let _trace = self.trace(&ctx, "unlink", parent, Some(name), None);
+        self.check_writable()?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         if let Ok(path) = self.resolve_child_path(parent, name) {
             self.interceptor
                 .pre_unlink(&path, false)
@@ -444,7 +735,10 @@ impl FileSystem for InterceptedFs {
     }
 
     fn rmdir(&self, ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        let _trace = self.trace(&ctx, "rmdir", parent, Some(name), None);
+        self.check_writable()?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         if let Ok(path) = self.resolve_child_path(parent, name) {
             self.interceptor
                 .pre_unlink(&path, true)
@@ -463,7 +757,12 @@ impl FileSystem for InterceptedFs {
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
+        let _trace = self.trace(&ctx, "rename", olddir, Some(oldname), None);
+        self.check_writable()?;
+        self.check_creatable(olddir, oldname)?;
+        self.check_creatable(newdir, newname)?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         let old_path = self.resolve_child_path(olddir, oldname)?;
         let new_path = self.resolve_child_path(newdir, newname)?;
 
@@ -474,6 +773,7 @@ impl FileSystem for InterceptedFs {
         self.inner
             .rename(ctx, olddir, oldname, newdir, newname, flags)?;
         let _ = self.inode_map.rename(olddir, oldname, newdir, newname);
+        let _ = self.interceptor.post_rename(&old_path, &new_path);
         Ok(())
     }
 
@@ -485,17 +785,31 @@ impl FileSystem for InterceptedFs {
         handle: Option<Self::Handle>,
         valid: SetattrValid,
     ) -> io::Result<(Attr, Duration)> {
+        let _trace = self.trace(
+            &ctx,
+            "setattr",
+            inode,
+            None,
+            valid.contains(SetattrValid::SIZE).then_some(attr.size),
+        );
+        self.check_writable()?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         // Only invoke the interceptor when the setattr changes attributes
         // beyond just atime. Atime-only updates are triggered by read
         // operations and should not create undo steps.
         let atime_only_mask = SetattrValid::ATIME | SetattrValid::ATIME_NOW;
         let modifies_beyond_atime = !(valid - atime_only_mask).is_empty();
+        // Without a size change the contents stay as they are, so only the
+        // attributes need capturing.
         if modifies_beyond_atime {
             if let Ok(path) = self.resolve_path(inode) {
-                self.interceptor
-                    .pre_setattr(&path)
-                    .map_err(Self::interceptor_error_to_io)?;
+                let result = if valid.contains(SetattrValid::SIZE) {
+                    self.interceptor.pre_setattr(&path)
+                } else {
+                    self.interceptor.pre_setattr_metadata(&path)
+                };
+                result.map_err(Self::interceptor_error_to_io)?;
             }
         }
         self.inner.setattr(ctx, inode, attr, handle, valid)
@@ -509,7 +823,11 @@ impl FileSystem for InterceptedFs {
         name: &CStr,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        let _trace = self.trace(&ctx, "symlink", parent, Some(name), None);
+        self.check_writable()?;
+        self.check_creatable(parent, name)?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         let entry = self
             .inner
             .symlink(ctx, linkname, parent, name, extensions)?;
@@ -531,7 +849,11 @@ impl FileSystem for InterceptedFs {
         newparent: Self::Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
+        let _trace = self.trace(&ctx, "link", newparent, Some(newname), None);
+        self.check_writable()?;
+        self.check_creatable(newparent, newname)?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         let target_path = self.resolve_path(inode)?;
         let link_path = self.resolve_child_path(newparent, newname)?;
 
@@ -555,7 +877,10 @@ impl FileSystem for InterceptedFs {
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
+        let _trace = self.trace(&ctx, "fallocate", inode, None, Some(length));
+        self.check_writable()?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         if let Ok(path) = self.resolve_path(inode) {
             self.interceptor
                 .pre_fallocate(&path)
@@ -574,7 +899,10 @@ impl FileSystem for InterceptedFs {
         flags: u32,
         extra_flags: SetxattrFlags,
     ) -> io::Result<()> {
+        let _trace = self.trace(&ctx, "setxattr", inode, None, None);
+        self.check_writable()?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         if let Ok(path) = self.resolve_path(inode) {
             self.interceptor
                 .pre_xattr(&path)
@@ -585,7 +913,10 @@ impl FileSystem for InterceptedFs {
     }
 
     fn removexattr(&self, ctx: Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+        let _trace = self.trace(&ctx, "removexattr", inode, None, None);
+        self.check_writable()?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         if let Ok(path) = self.resolve_path(inode) {
             self.interceptor
                 .pre_xattr(&path)
@@ -606,7 +937,10 @@ impl FileSystem for InterceptedFs {
         len: u64,
         flags: u64,
     ) -> io::Result<usize> {
+        let _trace = self.trace(&ctx, "copyfilerange", inode_out, None, Some(len));
+        self.check_writable()?;
         let _guard = InFlightGuard::new(&self.in_flight);
+        let _scope = self.attribute(&ctx);
         if let Ok(dst_path) = self.resolve_path(inode_out) {
             self.interceptor
                 .pre_copy_file_range(&dst_path)
@@ -625,6 +959,7 @@ impl FileSystem for InterceptedFs {
         flags: u32,
         umask: u32,
     ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        self.check_writable()?;
         let _guard = InFlightGuard::new(&self.in_flight);
         self.inner.tmpfile(ctx, parent, mode, flags, umask)
     }
@@ -639,11 +974,45 @@ impl SerializableFileSystem for InterceptedFs {
         self.inner.prepare_serialization(cancel)
     }
 
-    fn serialize(&self, state_pipe: std::fs::File) -> io::Result<()> {
-        self.inner.serialize(state_pipe)
+    /// The inode map, framed by [`MIGRATION_MAGIC`] and its length, then
+    /// the passthrough's own state.
+    fn serialize(&self, mut state_pipe: std::fs::File) -> io::Result<()> {
+        let mut inner_state = tempfile::tempfile()?;
+        self.inner.serialize(inner_state.try_clone()?)?;
+        inner_state.rewind()?;
+
+        let inode_map = self.inode_map.serialize();
+        state_pipe.write_all(MIGRATION_MAGIC)?;
+        state_pipe.write_all(&(inode_map.len() as u64).to_le_bytes())?;
+        state_pipe.write_all(&inode_map)?;
+        io::copy(&mut inner_state, &mut state_pipe)?;
+        Ok(())
     }
 
-    fn deserialize_and_apply(&self, state_pipe: std::fs::File) -> io::Result<()> {
-        self.inner.deserialize_and_apply(state_pipe)
+    /// Restores the inode map [`serialize`](Self::serialize) framed, and
+    /// hands the rest to the passthrough. A stream without the frame is
+    /// all passthrough state.
+    fn deserialize_and_apply(&self, mut state_pipe: std::fs::File) -> io::Result<()> {
+        let mut state = Vec::new();
+        state_pipe.read_to_end(&mut state)?;
+        let inner_state = match state.strip_prefix(MIGRATION_MAGIC) {
+            Some(framed) => {
+                let invalid = || io::Error::new(io::ErrorKind::InvalidData, "truncated state");
+                let (len, rest) = framed.split_first_chunk::<8>().ok_or_else(invalid)?;
+                let len = usize::try_from(u64::from_le_bytes(*len)).map_err(|_| invalid())?;
+                if rest.len() < len {
+                    return Err(invalid());
+                }
+                let (inode_map, inner_state) = rest.split_at(len);
+                self.inode_map.restore(inode_map)?;
+                inner_state
+            }
+            None => &state[..],
+        };
+
+        let mut inner_pipe = tempfile::tempfile()?;
+        inner_pipe.write_all(inner_state)?;
+        inner_pipe.rewind()?;
+        self.inner.deserialize_and_apply(inner_pipe)
     }
 }
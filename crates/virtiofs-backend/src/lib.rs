@@ -1,3 +1,4 @@
+pub mod exclusions;
 pub mod inode_map;
 
 #[cfg(unix)]
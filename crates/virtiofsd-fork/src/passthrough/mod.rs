@@ -589,6 +589,16 @@ impl PassthroughFs {
         vec![self.proc_self_fd.as_raw_fd()]
     }
 
+    /// Host path of the file `inode` currently refers to, read back from its `O_PATH` fd.
+    /// Returns `EBADF` for an inode that is not in the store.
+    pub fn inode_path(&self, inode: Inode) -> io::Result<std::path::PathBuf> {
+        use std::os::unix::ffi::OsStringExt;
+
+        let data = self.inodes.get(inode).ok_or_else(ebadf)?;
+        let path = data.get_path(&self.proc_self_fd)?;
+        Ok(std::ffi::OsString::from_vec(path.into_bytes()).into())
+    }
+
     fn open_relative_to(
         &self,
         dir: &impl AsRawFd,
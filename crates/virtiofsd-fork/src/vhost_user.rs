@@ -30,6 +30,7 @@ use vmm_sys_util::eventfd::EventFd;
 
 use crate::descriptor_utils::{Error as VufDescriptorError, Reader, Writer};
 use crate::filesystem::{FileSystem, SerializableFileSystem};
+use crate::fuse::InHeader;
 use crate::server::Server;
 use crate::util::other_io_error;
 use crate::Error as VhostUserFsError;
@@ -113,6 +114,21 @@ impl convert::From<Error> for io::Error {
     }
 }
 
+/// One request, ready to be handled on any thread.
+pub type RequestJob = Box<dyn FnOnce() + Send>;
+
+/// Runs requests on threads of the embedder's choosing, in place of the
+/// built-in thread pool.
+///
+/// Threads running requests must have unshared their filesystem attributes
+/// (`unshare(CLONE_FS)`), as the pool's threads do: some operations change
+/// the working directory.
+pub trait RequestDispatcher: Send + Sync {
+    /// Run `job`, which handles a request on the inode `nodeid` (0 for
+    /// requests on no inode).
+    fn dispatch(&self, nodeid: u64, job: RequestJob);
+}
+
 struct VhostUserFsThread<F: FileSystem + Send + Sync + 'static> {
     mem: Option<LoggedMemoryAtomic>,
     kill_evt: EventFd,
@@ -121,11 +137,16 @@ struct VhostUserFsThread<F: FileSystem + Send + Sync + 'static> {
     vu_req: Option<Backend>,
     event_idx: bool,
     pool: Option<ThreadPool>,
+    dispatcher: Option<Arc<dyn RequestDispatcher>>,
 }
 
 impl<F: FileSystem + SerializableFileSystem + Send + Sync + 'static> VhostUserFsThread<F> {
-    fn new(fs: F, thread_pool_size: usize) -> Result<Self> {
-        let pool = if thread_pool_size > 0 {
+    fn new(
+        fs: F,
+        thread_pool_size: usize,
+        dispatcher: Option<Arc<dyn RequestDispatcher>>,
+    ) -> Result<Self> {
+        let pool = if thread_pool_size > 0 && dispatcher.is_none() {
             // Test that unshare(CLONE_FS) works, it will be called for each thread.
             // It's an unprivileged system call but some Docker/Moby versions are
             // known to reject it via seccomp when CAP_SYS_ADMIN is not given.
@@ -159,6 +180,7 @@ impl<F: FileSystem + SerializableFileSystem + Send + Sync + 'static> VhostUserFs
             vu_req: None,
             event_idx: false,
             pool,
+            dispatcher,
         })
     }
 
@@ -213,12 +235,13 @@ impl<F: FileSystem + SerializableFileSystem + Send + Sync + 'static> VhostUserFs
             // Prepare a set of objects that can be moved to the worker thread.
             let atomic_mem = atomic_mem.clone();
             let server = self.server.clone();
-            let mut vu_req = self.vu_req.clone();
+            let vu_req = self.vu_req.clone();
             let event_idx = self.event_idx;
             let worker_vring = vring.clone();
             let worker_desc = avail_desc.clone();
 
-            self.pool.as_ref().unwrap().spawn_ok(async move {
+            let job = move || {
+                let mut vu_req = vu_req;
                 let mem = atomic_mem.memory();
                 let head_index = worker_desc.head_index();
 
@@ -235,7 +258,18 @@ impl<F: FileSystem + SerializableFileSystem + Send + Sync + 'static> VhostUserFs
                     .unwrap();
 
                 Self::return_descriptor(&mut worker_vring.get_mut(), head_index, event_idx, len);
-            });
+            };
+            match &self.dispatcher {
+                Some(dispatcher) => {
+                    let mem = self.mem.as_ref().unwrap().memory();
+                    let nodeid = Reader::new(&mem, avail_desc.clone())
+                        .ok()
+                        .and_then(|mut reader| reader.read_obj::<InHeader>().ok())
+                        .map_or(0, |header| header.nodeid);
+                    dispatcher.dispatch(nodeid, Box::new(job));
+                }
+                None => self.pool.as_ref().unwrap().spawn_ok(async move { job() }),
+            }
         }
 
         Ok(used_any)
@@ -389,9 +423,10 @@ struct PremigrationThread {
 }
 
 /// A builder for configurable creation of [`VhostUserFsBackend`] objects.
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct VhostUserFsBackendBuilder {
     thread_pool_size: usize,
+    dispatcher: Option<Arc<dyn RequestDispatcher>>,
     tag: Option<String>,
 }
 
@@ -404,6 +439,13 @@ impl VhostUserFsBackendBuilder {
         self
     }
 
+    /// Hand requests to `dispatcher` instead of a thread pool. Takes
+    /// precedence over [`Self::set_thread_pool_size`].
+    pub fn set_request_dispatcher(mut self, dispatcher: Arc<dyn RequestDispatcher>) -> Self {
+        self.dispatcher = Some(dispatcher);
+        self
+    }
+
     /// Set the tag to use for the file system.
     ///
     /// The tag length must not exceed [`MAX_TAG_LEN`] bytes.
@@ -417,7 +459,11 @@ impl VhostUserFsBackendBuilder {
     where
         F: FileSystem + SerializableFileSystem + Send + Sync + 'static,
     {
-        let thread = RwLock::new(VhostUserFsThread::new(fs, self.thread_pool_size)?);
+        let thread = RwLock::new(VhostUserFsThread::new(
+            fs,
+            self.thread_pool_size,
+            self.dispatcher,
+        )?);
         Ok(VhostUserFsBackend {
             thread,
             premigration_thread: None.into(),
@@ -572,7 +618,7 @@ impl<F: FileSystem + SerializableFileSystem + Send + Sync + 'static> VhostUserBa
 
         let thread = self.thread.read().unwrap();
 
-        if thread.pool.is_some() {
+        if thread.pool.is_some() || thread.dispatcher.is_some() {
             thread.handle_event_pool(device_event, vrings)
         } else {
             thread.handle_event_serial(device_event, vrings)
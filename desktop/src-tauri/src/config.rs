@@ -23,6 +23,7 @@ pub struct SandboxSection {
     pub vm_mode: String,
     pub protocol: String,
     pub log_level: String,
+    pub auto_cleanup_stale_resources: bool,
 }
 
 impl Default for SandboxSection {
@@ -33,6 +34,7 @@ impl Default for SandboxSection {
             vm_mode: "ephemeral".into(),
             protocol: "mcp".into(),
             log_level: "info".into(),
+            auto_cleanup_stale_resources: true,
         }
     }
 }
@@ -21,6 +21,10 @@ import { useVmStore } from "../../hooks/useVmStatus";
 import { useToastStore } from "../../hooks/useToastStore";
 import type { UndoStepDetail, BarrierDetail, UndoHistoryData } from "../../lib/types";
 
+function formatDuration(ms: number): string {
+  return ms < 1000 ? `${ms} ms` : `${(ms / 1000).toFixed(1)} s`;
+}
+
 function StepTypeBadge({ step }: { step: UndoStepDetail }) {
   if (step.unprotected) {
     return (
@@ -103,6 +107,10 @@ function StepCard({
           <div className="mt-0.5 flex items-center gap-1 text-xs text-[var(--color-text-secondary)]">
             <FileText size={10} />
             {step.file_count} file{step.file_count !== 1 ? "s" : ""} affected
+            {step.duration_ms !== undefined && <span>· {formatDuration(step.duration_ms)}</span>}
+            {step.exit_code !== undefined && step.exit_code !== 0 && (
+              <span className="text-[var(--color-error)]">· exit {step.exit_code}</span>
+            )}
           </div>
         </div>
 
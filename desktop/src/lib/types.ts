@@ -17,6 +17,7 @@ export interface SandboxSection {
   vm_mode: string;
   protocol: string;
   log_level: string;
+  auto_cleanup_stale_resources: boolean;
 }
 
 export interface VmSection {
@@ -151,6 +152,16 @@ export interface UndoStepDetail {
   file_count: number;
   files: ManifestEntryDetail[];
   unprotected: boolean;
+  duration_ms?: number;
+  exit_code?: number;
+  preimage_bytes: number;
+  warnings?: ManifestWarningDetail[];
+}
+
+export interface ManifestWarningDetail {
+  path: string;
+  code: string;
+  message: string;
 }
 
 export interface AffectedPathDetail {
@@ -193,6 +204,7 @@ export function defaultConfig(): SandboxConfig {
       vm_mode: "ephemeral",
       protocol: "mcp",
       log_level: "info",
+      auto_cleanup_stale_resources: true,
     },
     vm: {
       memory_mb: 512,
@@ -46,15 +46,20 @@ setup_virtio_ports() {
 sleep 0.5
 setup_virtio_ports
 
-# Parse mount_names= from kernel cmdline.
-# Returns comma-separated names in MOUNT_NAMES variable.
+# Parse mount_names= and virtiofs_dax= from kernel cmdline.
+# Returns comma-separated names in MOUNT_NAMES variable and the DAX mode
+# (empty without a DAX window) in VIRTIOFS_DAX.
 parse_mount_names() {
     MOUNT_NAMES=""
+    VIRTIOFS_DAX=""
     for param in $(cat /proc/cmdline); do
         case "$param" in
             mount_names=*)
                 MOUNT_NAMES="${param#mount_names=}"
                 ;;
+            virtiofs_dax=*)
+                VIRTIOFS_DAX="${param#virtiofs_dax=}"
+                ;;
         esac
     done
 }
@@ -67,7 +72,13 @@ mount_working_dir() {
 
     mkdir -p "$mount_point"
 
-    # Try virtiofs first (Linux/macOS hosts)
+    # Try virtiofs first (Linux/macOS hosts), with DAX when the device
+    # has a window; kernels without DAX support refuse the option.
+    if [ -n "$VIRTIOFS_DAX" ] && \
+        mount -t virtiofs -o "dax=$VIRTIOFS_DAX" "$name" "$mount_point" 2>/dev/null; then
+        echo "init: mounted $name at $mount_point (virtiofs, dax=$VIRTIOFS_DAX)"
+        return 0
+    fi
     if mount -t virtiofs "$name" "$mount_point" 2>/dev/null; then
         echo "init: mounted $name at $mount_point (virtiofs)"
         return 0
@@ -92,7 +103,8 @@ mount_working_dir() {
 parse_mount_names
 
 if [ -z "$MOUNT_NAMES" ]; then
-    echo "init: WARNING: no mount_names= found in kernel cmdline"
+    # Pooled VMs boot without shares; the shim mounts them on `mount`.
+    echo "init: no mount_names= in kernel cmdline, waiting for the host to mount shares"
 else
     # Save and restore IFS to split on commas
     OLD_IFS="$IFS"
@@ -106,7 +118,7 @@ else
 fi
 
 # Create unprivileged user for command execution.
-# The shim runs as root (PID 1) but drops to this user when spawning
+# The shim runs as root (under PID 1) but drops to this user when spawning
 # commands via setuid/setgid in the executor.
 echo "sandbox:x:1000:1000:sandbox:/home/sandbox:/bin/sh" >> /etc/passwd
 echo "sandbox:x:1000:" >> /etc/group
@@ -129,6 +141,7 @@ if [ ! -e /dev/virtio-ports/control ]; then
     exec /bin/sh
 fi
 
-# Start the shim (replaces PID 1)
+# Start the shim under its supervisor (replaces PID 1), which restarts it
+# and tells the host if it crashes
 echo "init: starting shim..."
-exec /bin/shim /dev/virtio-ports/control
+exec /bin/shim --supervise /dev/virtio-ports/control